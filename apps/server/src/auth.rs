@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use axum::{
+	extract::State,
+	http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use sd_core::{
+	api::tokens::{ApiScope, ApiTokenError, ProcedureKind, API_SCOPE},
+	Node,
+};
+
+/// Name of the cookie holding the token for browsers, as they can't set headers on WebSockets
+/// or images
+const TOKEN_COOKIE: &str = "sd_api_token";
+
+/// Checks the API token of each request to the rspc endpoint against the procedure it calls,
+/// then runs the request within the scope of the token so the procedures can check it again.
+///
+/// The token is sent as a bearer token, or in the cookie set by [`create_session`].
+pub async fn require_api_token<B>(
+	State(node): State<Arc<Node>>,
+	req: Request<B>,
+	next: Next<B>,
+) -> Response {
+	let scope = match authenticate(&node, req.headers()).await {
+		Ok(scope) => scope,
+		Err(e) => return error_response(e),
+	};
+
+	let path = req.uri().path().trim_start_matches('/');
+
+	let result = if path == "ws" {
+		// A WebSocket connection can call any procedure
+		if scope == ApiScope::Admin {
+			Ok(())
+		} else {
+			Err(ApiTokenError::Forbidden("WebSocket".to_string()))
+		}
+	} else {
+		let kind = if req.method() == Method::POST {
+			ProcedureKind::Mutation
+		} else {
+			ProcedureKind::Query
+		};
+
+		// Batched requests carry multiple procedures, all of them must be allowed
+		path.split(',')
+			.try_for_each(|key| scope.authorize(kind, key))
+	};
+
+	match result {
+		Ok(()) => API_SCOPE.scope(scope, next.run(req)).await,
+		Err(e) => error_response(e),
+	}
+}
+
+/// Checks the API token of requests to the custom URI endpoint, any valid token can load
+/// thumbnails and files. Share links and inboxes carry their own credentials, so they're left
+/// to anyone who has them.
+pub async fn require_api_token_for_custom_uri<B>(
+	State(node): State<Arc<Node>>,
+	req: Request<B>,
+	next: Next<B>,
+) -> Response {
+	let path = req.uri().path().trim_start_matches('/');
+	if path.starts_with("share/") || path.starts_with("inbox/") {
+		return next.run(req).await;
+	}
+
+	match authenticate(&node, req.headers()).await {
+		Ok(scope) => API_SCOPE.scope(scope, next.run(req)).await,
+		Err(e) => error_response(e),
+	}
+}
+
+/// Exchanges a bearer token for a cookie holding it, which browsers then send along by themselves
+pub async fn create_session(State(node): State<Arc<Node>>, headers: HeaderMap) -> Response {
+	let Some(token) = bearer_token(&headers) else {
+		return error_response(ApiTokenError::Missing);
+	};
+
+	if let Err(e) = node
+		.api_tokens()
+		.authenticate(Some(token), user_agent(&headers))
+		.await
+	{
+		return error_response(e);
+	}
+
+	match HeaderValue::from_str(&format!(
+		"{TOKEN_COOKIE}={token}; Path=/; HttpOnly; SameSite=Strict"
+	)) {
+		Ok(cookie) => (StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response(),
+		Err(_) => error_response(ApiTokenError::Invalid),
+	}
+}
+
+pub async fn delete_session() -> Response {
+	(
+		StatusCode::NO_CONTENT,
+		[(
+			header::SET_COOKIE,
+			format!("{TOKEN_COOKIE}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0"),
+		)],
+	)
+		.into_response()
+}
+
+async fn authenticate(node: &Node, headers: &HeaderMap) -> Result<ApiScope, ApiTokenError> {
+	let token = bearer_token(headers).or_else(|| cookie_token(headers));
+
+	node.api_tokens()
+		.authenticate(token, user_agent(headers))
+		.await
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+	headers
+		.get(header::AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<&str> {
+	headers
+		.get_all(header::COOKIE)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|cookies| cookies.split(';'))
+		.find_map(|cookie| {
+			cookie
+				.trim()
+				.strip_prefix(TOKEN_COOKIE)
+				.and_then(|cookie| cookie.strip_prefix('='))
+		})
+}
+
+fn user_agent(headers: &HeaderMap) -> Option<&str> {
+	headers
+		.get(header::USER_AGENT)
+		.and_then(|value| value.to_str().ok())
+}
+
+fn error_response(e: ApiTokenError) -> Response {
+	match e {
+		ApiTokenError::Missing | ApiTokenError::Invalid => {
+			(StatusCode::UNAUTHORIZED, e.to_string()).into_response()
+		}
+		ApiTokenError::Forbidden(_) => (StatusCode::FORBIDDEN, e.to_string()).into_response(),
+	}
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+	body::Bytes,
+	extract::{Path, Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Response},
+	routing::get,
+	Json,
+};
+use sd_core::{
+	api::{
+		gateway::{self, GatewayError},
+		tokens::ProcedureKind,
+		Router,
+	},
+	Node,
+};
+use serde_json::Value;
+
+type GatewayState = (Arc<Node>, Arc<Router>);
+
+/// The REST gateway to the API, documented at `/openapi.json`
+pub fn router(node: Arc<Node>, router: Arc<Router>) -> axum::Router {
+	axum::Router::new()
+		.route(
+			"/openapi.json",
+			get(|State((_, router)): State<GatewayState>| async move {
+				Json(gateway::openapi(&router))
+			}),
+		)
+		.route("/:key", get(query).post(mutation))
+		.with_state((node, router))
+}
+
+async fn query(
+	State((node, router)): State<GatewayState>,
+	Path(key): Path<String>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Response {
+	let input = match params.get("input").map(|input| serde_json::from_str(input)) {
+		Some(Ok(input)) => input,
+		Some(Err(e)) => {
+			return (StatusCode::BAD_REQUEST, format!("invalid input: {e}")).into_response()
+		}
+		None => Value::Null,
+	};
+
+	respond(gateway::call(node, &router, ProcedureKind::Query, &key, input).await)
+}
+
+async fn mutation(
+	State((node, router)): State<GatewayState>,
+	Path(key): Path<String>,
+	body: Bytes,
+) -> Response {
+	let input = if body.is_empty() {
+		Value::Null
+	} else {
+		match serde_json::from_slice(&body) {
+			Ok(input) => input,
+			Err(e) => {
+				return (StatusCode::BAD_REQUEST, format!("invalid input: {e}")).into_response()
+			}
+		}
+	};
+
+	respond(gateway::call(node, &router, ProcedureKind::Mutation, &key, input).await)
+}
+
+fn respond(result: Result<Value, GatewayError>) -> Response {
+	match result {
+		Ok(result) => Json(result).into_response(),
+		Err(GatewayError { status, message }) => (
+			StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+			message,
+		)
+			.into_response(),
+	}
+}
@@ -1,9 +1,14 @@
 use std::{env, net::SocketAddr, path::Path};
 
-use axum::routing::get;
+use axum::{
+	middleware,
+	routing::{get, post},
+};
 use sd_core::{custom_uri::create_custom_uri_endpoint, Node};
 use tracing::info;
 
+mod auth;
+mod gateway;
 mod utils;
 
 #[cfg(feature = "assets")]
@@ -42,13 +47,60 @@ async fn main() {
 	};
 	let signal = utils::axum_shutdown_signal(node.clone());
 
+	// The REST gateway is opt-in, as most nodes are only reached by our own clients
+	let gateway_router = env::var("API_GATEWAY")
+		.map_or(false, |value| value == "true" || value == "1")
+		.then(|| gateway::router(node.clone(), router.clone()));
+
 	let app = axum::Router::new()
 		.route("/health", get(|| async { "OK" }))
+		.nest(
+			"/auth",
+			axum::Router::new()
+				.route(
+					"/session",
+					post(auth::create_session).delete(auth::delete_session),
+				)
+				.with_state(node.clone()),
+		)
 		.nest(
 			"/spacedrive",
-			create_custom_uri_endpoint(node.clone()).axum(),
+			create_custom_uri_endpoint(node.clone())
+				.axum()
+				.layer(middleware::from_fn(utils::client_addr))
+				.layer(middleware::from_fn(utils::limit_body_size))
+				.layer(middleware::from_fn_with_state(
+					node.clone(),
+					auth::require_api_token_for_custom_uri,
+				)),
 		)
-		.nest("/rspc", router.endpoint(move || node.clone()).axum());
+		.nest(
+			"/rspc",
+			router
+				.endpoint({
+					let node = node.clone();
+					move || node.clone()
+				})
+				.axum()
+				.layer(middleware::from_fn_with_state(
+					node.clone(),
+					auth::require_api_token,
+				)),
+		);
+
+	let app = match gateway_router {
+		Some(gateway_router) => {
+			info!("Serving the API gateway at '/api/v1'");
+			app.nest(
+				"/api/v1",
+				gateway_router.layer(middleware::from_fn_with_state(
+					node.clone(),
+					auth::require_api_token,
+				)),
+			)
+		}
+		None => app,
+	};
 
 	#[cfg(feature = "assets")]
 	let app = app
@@ -128,7 +180,7 @@ async fn main() {
 	addr.set_port(port);
 	info!("Listening on http://localhost:{}", port);
 	axum::Server::bind(&addr)
-		.serve(app.into_make_service())
+		.serve(app.into_make_service_with_connect_info::<SocketAddr>())
 		.with_graceful_shutdown(signal)
 		.await
 		.expect("Error with HTTP server!");
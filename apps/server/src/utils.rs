@@ -1,8 +1,56 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
-use sd_core::Node;
+use axum::{
+	body::{Body, HttpBody},
+	extract::ConnectInfo,
+	http::{header, Request, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use sd_core::{custom_uri::ClientAddr, Node};
 use tokio::signal;
 
+/// Largest request body the custom URI endpoint takes, as it holds bodies in memory
+pub const MAX_CUSTOM_URI_BODY_SIZE: usize = 512 * 1024 * 1024;
+
+/// Refuses request bodies larger than [`MAX_CUSTOM_URI_BODY_SIZE`], by their `Content-Length`
+/// when they declare one and otherwise while they're read.
+pub async fn limit_body_size(req: Request<Body>, next: Next<Body>) -> Response {
+	let declared_size = req
+		.headers()
+		.get(header::CONTENT_LENGTH)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<u64>().ok());
+	if declared_size.map_or(false, |size| size > MAX_CUSTOM_URI_BODY_SIZE as u64) {
+		return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+	}
+
+	let (parts, mut body) = req.into_parts();
+	let mut bytes = Vec::new();
+	while let Some(chunk) = body.data().await {
+		let Ok(chunk) = chunk else {
+			return StatusCode::BAD_REQUEST.into_response();
+		};
+		if bytes.len() + chunk.len() > MAX_CUSTOM_URI_BODY_SIZE {
+			return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+		}
+		bytes.extend_from_slice(&chunk);
+	}
+
+	next.run(Request::from_parts(parts, Body::from(bytes)))
+		.await
+}
+
+/// Tells the custom URI endpoint which address a request came from, so it can limit each client
+pub async fn client_addr(
+	ConnectInfo(addr): ConnectInfo<SocketAddr>,
+	mut req: Request<Body>,
+	next: Next<Body>,
+) -> Response {
+	req.extensions_mut().insert(ClientAddr(addr));
+	next.run(req).await
+}
+
 /// shutdown_signal will inform axum to gracefully shutdown when the process is asked to shutdown.
 pub async fn axum_shutdown_signal(node: Arc<Node>) {
 	let ctrl_c = async {
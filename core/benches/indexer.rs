@@ -0,0 +1,109 @@
+use std::{io::Write, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sd_core::bench::{generate_cas_id, memory_tree, walk, IsolatedFilePathData};
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+
+const KB: u64 = 1024;
+const MB: u64 = 1024 * KB;
+
+// (directories, files per directory)
+const TREES: [(usize, usize); 3] = [(10, 100), (100, 100), (100, 1000)];
+
+// Files under 100 KiB are hashed whole, bigger ones are sampled
+const FILE_SIZES: [u64; 5] = [16 * KB, 64 * KB, MB, 64 * MB, 512 * MB];
+
+const PATHS: usize = 10_000;
+
+fn bench_walk(c: &mut Criterion) {
+	let runtime = Runtime::new().expect("failed to start the runtime");
+	let mut group = c.benchmark_group("walk");
+
+	for (dirs, files_per_dir) in TREES {
+		let fs = memory_tree("/location", dirs, files_per_dir);
+		let entries = dirs * (files_per_dir + 1);
+
+		group.throughput(Throughput::Elements(entries as u64));
+		group.bench_with_input(BenchmarkId::from_parameter(entries), &fs, |b, fs| {
+			b.to_async(&runtime)
+				.iter(|| async { walk(fs, "/location", &[]).await.expect("walk failed") })
+		});
+	}
+
+	group.finish();
+}
+
+fn bench_isolated_file_path_data(c: &mut Criterion) {
+	let paths = (0..PATHS)
+		.map(|i| {
+			PathBuf::from(format!(
+				"/location/dir {}/sub dir {}/file {i}.JPG",
+				i % 100,
+				i % 7
+			))
+		})
+		.collect::<Vec<_>>();
+
+	let mut group = c.benchmark_group("isolated_file_path_data");
+	group.throughput(Throughput::Elements(PATHS as u64));
+
+	group.bench_function("new", |b| {
+		b.iter(|| {
+			for path in &paths {
+				IsolatedFilePathData::new(1, "/location", path, false).expect("invalid path");
+			}
+		})
+	});
+
+	group.bench_function("parent", |b| {
+		let iso_file_paths = paths
+			.iter()
+			.map(|path| IsolatedFilePathData::new(1, "/location", path, false))
+			.collect::<Result<Vec<_>, _>>()
+			.expect("invalid path");
+
+		b.iter(|| {
+			for iso_file_path in &iso_file_paths {
+				iso_file_path.parent();
+			}
+		})
+	});
+
+	group.finish();
+}
+
+fn bench_cas_id(c: &mut Criterion) {
+	let runtime = Runtime::new().expect("failed to start the runtime");
+	let mut group = c.benchmark_group("cas_id");
+
+	for size in FILE_SIZES {
+		let mut file = NamedTempFile::new().expect("failed to create a temporary file");
+		let chunk = vec![0xA5; MB as usize];
+		let mut written = 0;
+		while written < size {
+			let len = (size - written).min(MB) as usize;
+			file.write_all(&chunk[..len])
+				.expect("failed to write the temporary file");
+			written += len as u64;
+		}
+
+		group.bench_with_input(BenchmarkId::from_parameter(size), file.path(), |b, path| {
+			b.to_async(&runtime).iter(|| async {
+				generate_cas_id(path, size)
+					.await
+					.expect("failed to hash the file")
+			})
+		});
+	}
+
+	group.finish();
+}
+
+criterion_group!(
+	name = benches;
+	config = Criterion::default();
+	targets = bench_walk, bench_isolated_file_path_data, bench_cas_id
+);
+
+criterion_main!(benches);
@@ -0,0 +1,105 @@
+//! Search latency against a generated library, 1M files by default or `SYNTHETIC_FILES` of them
+
+use std::env;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use sd_core::{
+	api::{gateway, tokens::ProcedureKind},
+	bench::{generate_library, SyntheticLibrary},
+	Node,
+};
+use serde_json::json;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+fn bench_search(c: &mut Criterion) {
+	let runtime = Runtime::new().expect("failed to start the runtime");
+	let data_dir = tempfile::tempdir().expect("failed to create a temporary directory");
+	let spec = SyntheticLibrary {
+		files_per_location: env::var("SYNTHETIC_FILES")
+			.ok()
+			.and_then(|files| files.parse().ok())
+			.unwrap_or(SyntheticLibrary::default().files_per_location),
+		..Default::default()
+	};
+	// A directory in the middle of the location
+	let directory = format!("/dir {}/", spec.files_per_location / spec.files_per_dir / 2);
+
+	let (node, router, library_id, location_id) = runtime.block_on(async {
+		let (node, router) = Node::new(data_dir.path())
+			.await
+			.expect("failed to start the node");
+
+		let library = gateway::call(
+			node.clone(),
+			&router,
+			ProcedureKind::Mutation,
+			"library.create",
+			json!({ "name": "Benchmarks" }),
+		)
+		.await
+		.expect("failed to create the library");
+		let library_id =
+			serde_json::from_value::<Uuid>(library["uuid"].clone()).expect("library without an id");
+
+		let library = node
+			.library_manager
+			.get_library(library_id)
+			.await
+			.expect("library not loaded");
+		let location_ids = generate_library(&library, &spec)
+			.await
+			.expect("failed to generate the library");
+
+		(node, router, library_id, location_ids[0])
+	});
+
+	let searches = [
+		("first_page", json!({ "take": 100, "filter": {} })),
+		(
+			"by_name",
+			json!({ "take": 100, "filter": { "search": "invoice" } }),
+		),
+		(
+			"by_extension",
+			json!({ "take": 100, "filter": { "extension": "pdf" } }),
+		),
+		(
+			"in_directory",
+			json!({
+				"take": 100,
+				"filter": { "locationId": location_id, "path": directory }
+			}),
+		),
+	];
+
+	let mut group = c.benchmark_group("search");
+	for (name, arg) in searches {
+		let input = json!({ "library_id": library_id, "arg": arg });
+
+		group.bench_function(name, |b| {
+			b.to_async(&runtime).iter(|| async {
+				gateway::call(
+					node.clone(),
+					&router,
+					ProcedureKind::Query,
+					"search.paths",
+					input.clone(),
+				)
+				.await
+				.expect("search failed")
+			})
+		});
+	}
+	group.finish();
+
+	runtime.block_on(node.shutdown());
+}
+
+criterion_group!(
+	name = benches;
+	config = Criterion::default().sample_size(20);
+	targets = bench_search
+);
+
+criterion_main!(benches);
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "statistics" ADD COLUMN "total_bytes_allocated" TEXT NOT NULL DEFAULT '0';
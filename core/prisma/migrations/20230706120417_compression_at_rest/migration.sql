@@ -0,0 +1,6 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "compress_at_rest" BOOLEAN;
+ALTER TABLE "location" ADD COLUMN "compress_after_days" INTEGER;
+
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "compressed_at" DATETIME;
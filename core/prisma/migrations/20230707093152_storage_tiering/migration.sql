@@ -0,0 +1,8 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "tier_to_location_id" INTEGER;
+ALTER TABLE "location" ADD COLUMN "tier_after_days" INTEGER;
+ALTER TABLE "location" ADD COLUMN "tier_min_size_in_mb" INTEGER;
+
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "tiered_at" DATETIME;
+ALTER TABLE "file_path" ADD COLUMN "tiered_to_location_id" INTEGER;
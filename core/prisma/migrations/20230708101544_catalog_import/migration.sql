@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "object" ADD COLUMN "rating" INTEGER;
+ALTER TABLE "object" ADD COLUMN "edit_metadata" TEXT;
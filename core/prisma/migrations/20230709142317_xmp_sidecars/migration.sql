@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "xmp_sidecars" BOOLEAN;
+ALTER TABLE "location" ADD COLUMN "xmp_conflict_strategy" INTEGER;
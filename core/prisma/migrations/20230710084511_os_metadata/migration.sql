@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "sync_finder_tags" BOOLEAN;
+
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "os_attributes" INTEGER;
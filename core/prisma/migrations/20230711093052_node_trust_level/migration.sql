@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "node" ADD COLUMN "trust_level" INTEGER;
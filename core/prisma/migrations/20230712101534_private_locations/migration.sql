@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "is_private" BOOLEAN;
+ALTER TABLE "location" ADD COLUMN "private_passphrase" BLOB;
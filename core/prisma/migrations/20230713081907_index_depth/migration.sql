@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "index_depth" INTEGER;
+
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "deferred" BOOLEAN;
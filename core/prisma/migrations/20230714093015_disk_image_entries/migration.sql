@@ -0,0 +1,19 @@
+-- CreateTable
+CREATE TABLE "disk_image_entry" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "file_path_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "parent_path" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "is_dir" BOOLEAN NOT NULL,
+    "size_in_bytes" TEXT NOT NULL,
+    "date_modified" DATETIME,
+    "date_indexed" DATETIME,
+    CONSTRAINT "disk_image_entry_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "disk_image_entry_file_path_id_path_key" ON "disk_image_entry"("file_path_id", "path");
+
+-- CreateIndex
+CREATE INDEX "disk_image_entry_file_path_id_parent_path_idx" ON "disk_image_entry"("file_path_id", "parent_path");
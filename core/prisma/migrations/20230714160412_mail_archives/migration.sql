@@ -0,0 +1,41 @@
+-- CreateTable
+CREATE TABLE "mail_archive" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "file_path_id" INTEGER NOT NULL,
+    "date_modified" DATETIME,
+    "date_indexed" DATETIME,
+    CONSTRAINT "mail_archive_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "mail_message" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "archive_id" INTEGER NOT NULL,
+    "offset" BIGINT NOT NULL,
+    "message_id" TEXT,
+    "subject" TEXT,
+    "sender" TEXT,
+    "recipients" TEXT,
+    "date" DATETIME,
+    CONSTRAINT "mail_message_archive_id_fkey" FOREIGN KEY ("archive_id") REFERENCES "mail_archive" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "mail_attachment" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "mail_message_id" INTEGER NOT NULL,
+    "name" TEXT NOT NULL,
+    "extension" TEXT,
+    "mime_type" TEXT,
+    "size_in_bytes" TEXT NOT NULL,
+    CONSTRAINT "mail_attachment_mail_message_id_fkey" FOREIGN KEY ("mail_message_id") REFERENCES "mail_message" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "mail_archive_file_path_id_key" ON "mail_archive"("file_path_id");
+
+-- CreateIndex
+CREATE INDEX "mail_message_archive_id_idx" ON "mail_message"("archive_id");
+
+-- CreateIndex
+CREATE INDEX "mail_attachment_mail_message_id_idx" ON "mail_attachment"("mail_message_id");
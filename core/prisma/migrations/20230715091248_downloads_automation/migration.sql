@@ -0,0 +1,21 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "downloads_automation" BOOLEAN;
+
+-- CreateTable
+CREATE TABLE "download_rule" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "name" TEXT,
+    "position" INTEGER NOT NULL DEFAULT 0,
+    "kind" INTEGER,
+    "extension" TEXT,
+    "source_domain" TEXT,
+    "destination" TEXT NOT NULL,
+    "tag_id" INTEGER,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "download_rule_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "download_rule_tag_id_fkey" FOREIGN KEY ("tag_id") REFERENCES "tag" ("id") ON DELETE SET NULL ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "download_rule_location_id_idx" ON "download_rule"("location_id");
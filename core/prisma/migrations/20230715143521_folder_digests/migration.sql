@@ -0,0 +1,17 @@
+-- CreateTable
+CREATE TABLE "folder_digest" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "file_path_id" INTEGER NOT NULL,
+    "digest" BLOB NOT NULL,
+    "parent_digest" BLOB,
+    "size_in_bytes" TEXT NOT NULL,
+    "files_count" INTEGER NOT NULL,
+    "date_computed" DATETIME NOT NULL,
+    CONSTRAINT "folder_digest_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "folder_digest_file_path_id_key" ON "folder_digest"("file_path_id");
+
+-- CreateIndex
+CREATE INDEX "folder_digest_digest_idx" ON "folder_digest"("digest");
@@ -0,0 +1,21 @@
+-- AlterTable
+ALTER TABLE "media_data" ADD COLUMN "date_taken" DATETIME;
+ALTER TABLE "media_data" ADD COLUMN "perceptual_hash" BLOB;
+
+-- CreateTable
+CREATE TABLE "photo_stack" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateTable
+CREATE TABLE "photo_stack_item" (
+    "object_id" INTEGER NOT NULL PRIMARY KEY,
+    "stack_id" INTEGER NOT NULL,
+    "is_best" BOOLEAN NOT NULL DEFAULT false,
+    CONSTRAINT "photo_stack_item_object_id_fkey" FOREIGN KEY ("object_id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "photo_stack_item_stack_id_fkey" FOREIGN KEY ("stack_id") REFERENCES "photo_stack" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "photo_stack_item_stack_id_idx" ON "photo_stack_item"("stack_id");
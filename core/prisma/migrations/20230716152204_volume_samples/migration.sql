@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "volume_sample" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "volume_id" INTEGER NOT NULL,
+    "total_bytes_capacity" TEXT NOT NULL,
+    "total_bytes_available" TEXT NOT NULL,
+    "date_captured" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "volume_sample_volume_id_fkey" FOREIGN KEY ("volume_id") REFERENCES "volume" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "volume_sample_volume_id_date_captured_idx" ON "volume_sample"("volume_id", "date_captured");
@@ -0,0 +1,18 @@
+-- CreateTable
+CREATE TABLE "volume_health" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "volume_id" INTEGER NOT NULL,
+    "device" TEXT NOT NULL,
+    "passed" BOOLEAN,
+    "temperature_celsius" INTEGER,
+    "reallocated_sectors" INTEGER,
+    "pending_sectors" INTEGER,
+    "media_errors" INTEGER,
+    "percentage_used" INTEGER,
+    "power_on_hours" INTEGER,
+    "date_captured" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "volume_health_volume_id_fkey" FOREIGN KEY ("volume_id") REFERENCES "volume" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "volume_health_volume_id_date_captured_idx" ON "volume_health"("volume_id", "date_captured");
@@ -0,0 +1,21 @@
+-- CreateTable
+CREATE TABLE "custom_kind" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "name" TEXT NOT NULL,
+    "icon" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateTable
+CREATE TABLE "custom_kind_extension" (
+    "extension" TEXT NOT NULL PRIMARY KEY,
+    "kind_id" INTEGER NOT NULL,
+    CONSTRAINT "custom_kind_extension_kind_id_fkey" FOREIGN KEY ("kind_id") REFERENCES "custom_kind" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "custom_kind_pub_id_key" ON "custom_kind"("pub_id");
+
+-- CreateIndex
+CREATE INDEX "custom_kind_extension_kind_id_idx" ON "custom_kind_extension"("kind_id");
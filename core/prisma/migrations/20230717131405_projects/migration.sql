@@ -0,0 +1,15 @@
+-- CreateTable
+CREATE TABLE "project" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "file_path_id" INTEGER NOT NULL,
+    "markers" TEXT NOT NULL,
+    "languages" TEXT NOT NULL,
+    "size_in_bytes" TEXT NOT NULL,
+    "files_count" INTEGER NOT NULL,
+    "last_commit" DATETIME,
+    "date_scanned" DATETIME NOT NULL,
+    CONSTRAINT "project_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "project_file_path_id_key" ON "project"("file_path_id");
@@ -0,0 +1,13 @@
+-- CreateTable
+CREATE TABLE "font_data" (
+    "id" INTEGER NOT NULL PRIMARY KEY,
+    "family" TEXT,
+    "subfamily" TEXT,
+    "full_name" TEXT,
+    "weight" INTEGER,
+    "italic" BOOLEAN,
+    "monospaced" BOOLEAN,
+    "glyphs_count" INTEGER,
+    "scripts" TEXT,
+    CONSTRAINT "font_data_id_fkey" FOREIGN KEY ("id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
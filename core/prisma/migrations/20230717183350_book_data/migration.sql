@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "book_data" (
+    "id" INTEGER NOT NULL PRIMARY KEY,
+    "title" TEXT,
+    "authors" TEXT,
+    "series" TEXT,
+    "series_index" REAL,
+    "publisher" TEXT,
+    "language" TEXT,
+    "pages_count" INTEGER,
+    CONSTRAINT "book_data_id_fkey" FOREIGN KEY ("id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
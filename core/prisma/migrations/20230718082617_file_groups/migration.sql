@@ -0,0 +1,18 @@
+-- CreateTable
+CREATE TABLE "file_group" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "rule" TEXT NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateTable
+CREATE TABLE "file_group_member" (
+    "file_path_id" INTEGER NOT NULL PRIMARY KEY,
+    "group_id" INTEGER NOT NULL,
+    "is_primary" BOOLEAN NOT NULL DEFAULT false,
+    CONSTRAINT "file_group_member_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "file_group_member_group_id_fkey" FOREIGN KEY ("group_id") REFERENCES "file_group" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "file_group_member_group_id_idx" ON "file_group_member"("group_id");
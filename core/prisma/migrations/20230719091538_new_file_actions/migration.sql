@@ -0,0 +1,27 @@
+-- CreateTable
+CREATE TABLE "new_file_action" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "action" INTEGER NOT NULL,
+    "extension" TEXT,
+    "tag_id" INTEGER,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "new_file_action_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "new_file_action_tag_id_fkey" FOREIGN KEY ("tag_id") REFERENCES "tag" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "activity_log" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER,
+    "action" TEXT NOT NULL,
+    "path" TEXT,
+    "error" TEXT,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE INDEX "new_file_action_location_id_idx" ON "new_file_action"("location_id");
+
+-- CreateIndex
+CREATE INDEX "activity_log_date_created_idx" ON "activity_log"("date_created");
@@ -0,0 +1,34 @@
+-- CreateTable
+CREATE TABLE "custom_field" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "name" TEXT,
+    "field_type" INTEGER,
+    "options" TEXT,
+    "date_created" DATETIME
+);
+
+-- CreateTable
+CREATE TABLE "custom_field_value" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "text" TEXT,
+    "number" REAL,
+    "date" DATETIME,
+    "field_id" INTEGER,
+    "object_id" INTEGER,
+    CONSTRAINT "custom_field_value_field_id_fkey" FOREIGN KEY ("field_id") REFERENCES "custom_field" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "custom_field_value_object_id_fkey" FOREIGN KEY ("object_id") REFERENCES "object" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "custom_field_pub_id_key" ON "custom_field"("pub_id");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "custom_field_value_pub_id_key" ON "custom_field_value"("pub_id");
+
+-- CreateIndex
+CREATE INDEX "custom_field_value_object_id_idx" ON "custom_field_value"("object_id");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "custom_field_value_field_id_object_id_key" ON "custom_field_value"("field_id", "object_id");
@@ -0,0 +1,14 @@
+-- AlterTable
+ALTER TABLE "object" ADD COLUMN "nsfw" BOOLEAN;
+ALTER TABLE "object" ADD COLUMN "nsfw_score" REAL;
+
+-- CreateTable
+CREATE TABLE "content_restriction" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "pin" BLOB,
+    "date_created" DATETIME
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "content_restriction_pub_id_key" ON "content_restriction"("pub_id");
@@ -0,0 +1,15 @@
+-- CreateTable
+CREATE TABLE "session_state" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "client" TEXT,
+    "key" TEXT,
+    "value" TEXT,
+    "date_modified" DATETIME
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "session_state_pub_id_key" ON "session_state"("pub_id");
+
+-- CreateIndex
+CREATE INDEX "session_state_client_idx" ON "session_state"("client");
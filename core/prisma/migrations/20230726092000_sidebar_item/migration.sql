@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "sidebar_item" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "target" TEXT,
+    "name" TEXT,
+    "position" INTEGER,
+    "date_created" DATETIME
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "sidebar_item_pub_id_key" ON "sidebar_item"("pub_id");
@@ -0,0 +1,13 @@
+-- CreateTable
+CREATE TABLE "folder_mirror" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "settings" TEXT NOT NULL,
+    "baseline" TEXT,
+    "last_run_at" DATETIME,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "date_modified" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "folder_mirror_name_key" ON "folder_mirror"("name");
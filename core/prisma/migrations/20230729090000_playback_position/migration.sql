@@ -0,0 +1,4 @@
+-- AlterTable
+ALTER TABLE "object" ADD COLUMN "playback_position" REAL;
+ALTER TABLE "object" ADD COLUMN "playback_duration" REAL;
+ALTER TABLE "object" ADD COLUMN "date_played" DATETIME;
@@ -0,0 +1,14 @@
+-- CreateTable
+CREATE TABLE "shelf_item" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "client" TEXT NOT NULL,
+    "file_path_id" INTEGER NOT NULL,
+    "date_added" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "shelf_item_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE INDEX "shelf_item_client_idx" ON "shelf_item"("client");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "shelf_item_client_file_path_id_key" ON "shelf_item"("client", "file_path_id");
@@ -0,0 +1,10 @@
+-- CreateTable
+CREATE TABLE "cleanup_suggestion" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "kind" INTEGER NOT NULL,
+    "file_path_ids" TEXT NOT NULL,
+    "reclaimable_size_in_bytes" TEXT NOT NULL,
+    "location_id" INTEGER,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "cleanup_suggestion_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "broken_link" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "file_path_id" INTEGER NOT NULL,
+    "kind" INTEGER NOT NULL,
+    "target" TEXT NOT NULL,
+    "date_checked" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "broken_link_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "broken_link_file_path_id_key" ON "broken_link"("file_path_id");
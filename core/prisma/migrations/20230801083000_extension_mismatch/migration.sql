@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "extension_mismatch" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "file_path_id" INTEGER NOT NULL,
+    "detected_extension" TEXT NOT NULL,
+    "date_checked" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "extension_mismatch_file_path_id_fkey" FOREIGN KEY ("file_path_id") REFERENCES "file_path" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "extension_mismatch_file_path_id_key" ON "extension_mismatch"("file_path_id");
@@ -0,0 +1,6 @@
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "color" TEXT;
+ALTER TABLE "file_path" ADD COLUMN "emoji" TEXT;
+
+-- AlterTable
+ALTER TABLE "tag" ADD COLUMN "emoji" TEXT;
@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "file_template" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "file_name" TEXT NOT NULL,
+    "is_dir" BOOLEAN NOT NULL,
+    "date_created" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "file_template_name_key" ON "file_template"("name");
@@ -0,0 +1,14 @@
+-- CreateTable
+CREATE TABLE "file_version" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "name" TEXT NOT NULL,
+    "mirror_id" INTEGER NOT NULL,
+    "location_id" INTEGER NOT NULL,
+    "path" TEXT NOT NULL,
+    "size_in_bytes_bytes" BLOB NOT NULL,
+    "date_kept" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT "file_version_mirror_id_fkey" FOREIGN KEY ("mirror_id") REFERENCES "folder_mirror" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "file_version_name_key" ON "file_version"("name");
@@ -16,7 +16,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				let category = Category::from_str(category_str)
 					.expect("it's alright this category string exists");
 
-				data.insert(category, get_category_count(&library.db, category).await);
+				data.insert(category, get_category_count(&library, category).await);
 			}
 
 			Ok(data)
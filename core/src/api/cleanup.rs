@@ -0,0 +1,40 @@
+use crate::{
+	object::cleanup_suggestions::{
+		apply_cleanup_suggestion, cleanup_suggestions, dismiss_cleanup_suggestion,
+		CleanupAnalyzerJobInit,
+	},
+	prisma::cleanup_suggestion,
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("suggestions", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(cleanup_suggestions(&library).await?)
+			})
+		})
+		.procedure("analyze", {
+			R.with2(library())
+				.mutation(|(_, library), args: CleanupAnalyzerJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("apply", {
+			R.with2(library()).mutation(
+				|(_, library), id: cleanup_suggestion::id::Type| async move {
+					Ok(apply_cleanup_suggestion(&library, id).await?)
+				},
+			)
+		})
+		.procedure("dismiss", {
+			R.with2(library()).mutation(
+				|(_, library), id: cleanup_suggestion::id::Type| async move {
+					Ok(dismiss_cleanup_suggestion(&library, id).await?)
+				},
+			)
+		})
+}
@@ -0,0 +1,50 @@
+use crate::{
+	object::custom_field::{
+		delete_custom_field, list_custom_fields, object_custom_fields, CustomFieldCreateArgs,
+		CustomFieldUpdateArgs, SetCustomFieldArgs,
+	},
+	prisma::{custom_field, object},
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_custom_fields(&library.db).await?)
+			})
+		})
+		.procedure("getForObject", {
+			R.with2(library())
+				.query(|(_, library), object_id: object::id::Type| async move {
+					Ok(object_custom_fields(&library.db, object_id).await?)
+				})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: CustomFieldCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: CustomFieldUpdateArgs| async move {
+					Ok(args.update(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: custom_field::id::Type| async move {
+					Ok(delete_custom_field(&library, id).await?)
+				})
+		})
+		.procedure("setValue", {
+			R.with2(library())
+				.mutation(|(_, library), args: SetCustomFieldArgs| async move {
+					Ok(args.set(&library).await?)
+				})
+		})
+}
@@ -0,0 +1,14 @@
+use rspc::alpha::AlphaRouter;
+
+use crate::util::log_buffer::{LogFilter, LOG_BUFFER};
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router().procedure("logs", {
+		R.with2(library())
+			.query(|(_, library), filter: LogFilter| async move {
+				Ok(LOG_BUFFER.query(library.id, &filter))
+			})
+	})
+}
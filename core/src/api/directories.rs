@@ -0,0 +1,34 @@
+use crate::{
+	object::directory_description::{directory_description, set_directory_note},
+	prisma::file_path,
+};
+
+use rspc::alpha::AlphaRouter;
+use serde::Deserialize;
+use specta::Type;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("description", {
+			R.with2(library())
+				.query(|(_, library), id: file_path::id::Type| async move {
+					Ok(directory_description(&library, id).await?)
+				})
+		})
+		.procedure("setNote", {
+			#[derive(Type, Deserialize)]
+			pub struct SetNoteArgs {
+				pub id: file_path::id::Type,
+				/// Clears the note when left out or blank
+				#[specta(optional)]
+				pub note: Option<String>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: SetNoteArgs| async move {
+					Ok(set_directory_note(&library, args.id, args.note).await?)
+				})
+		})
+}
@@ -0,0 +1,44 @@
+use crate::{
+	object::fs::export::{
+		delete_export_profile, list_export_profiles, ExportProfileCreateArgs,
+		ExportProfileUpdateArgs, ImageExportArgs,
+	},
+	prisma::export_profile,
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_export_profiles(&library.db).await?)
+			})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: ExportProfileCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: ExportProfileUpdateArgs| async move {
+					Ok(args.update(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: export_profile::id::Type| async move {
+					Ok(delete_export_profile(&library, id).await?)
+				})
+		})
+		.procedure("export", {
+			R.with2(library())
+				.mutation(|(_, library), args: ImageExportArgs| async move {
+					Ok(args.spawn(&library).await?)
+				})
+		})
+}
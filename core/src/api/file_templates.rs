@@ -0,0 +1,29 @@
+use crate::{
+	object::fs::template::{delete_file_template, list_file_templates, FileTemplateCreateArgs},
+	prisma::file_template,
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_file_templates(&library.db).await?)
+			})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: FileTemplateCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: file_template::id::Type| async move {
+					Ok(delete_file_template(&library, id).await?)
+				})
+		})
+}
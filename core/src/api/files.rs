@@ -1,27 +1,71 @@
 use crate::{
-	api::utils::library,
+	api::{
+		utils::{authorized, library},
+		CoreEvent,
+	},
 	invalidate_query,
+	job::{ChainFailurePolicy, Job},
 	library::Library,
 	location::{
 		file_path_helper::{
-			file_path_to_isolate, file_path_to_isolate_with_id, FilePathError, IsolatedFilePathData,
+			file_path_to_isolate, file_path_to_isolate_with_id, platforms_rejecting_file_name,
+			FilePathError, IsolatedFilePathData,
 		},
-		find_location, LocationError,
+		find_location,
+		redaction::Redaction,
+		LocationError,
 	},
-	object::fs::{
-		copy::FileCopierJobInit, cut::FileCutterJobInit, delete::FileDeleterJobInit,
-		erase::FileEraserJobInit,
+	node::{resolve_os_path, Platform},
+	object::{
+		accent::{set_file_path_accents, Accent},
+		access::{purge_access, record_access},
+		duplicate_folders::{dedupe_folders, duplicate_folder_groups, DuplicateFoldersJobInit},
+		fs::{
+			archive::ArchiveCreatorJobInit,
+			broken_links::{
+				broken_links, delete_broken_links, retarget_broken_link, BrokenLinkScannerJobInit,
+				BrokenLinksFilter,
+			},
+			compress::restore_compressed_file,
+			convert::MediaConverterJobInit,
+			copy::FileCopierJobInit,
+			cut::FileCutterJobInit,
+			delete::FileDeleterJobInit,
+			diff::{copy_missing, DiffDirection, DiffSide, DirectoryDiffJobInit},
+			disk_image::{self, DiskImageError},
+			erase::FileEraserJobInit,
+			error::FileSystemJobsError,
+			extension_mismatch::{
+				extension_mismatches, fix_extension_mismatches, ExtensionMismatchScannerJobInit,
+				ExtensionMismatchesFilter,
+			},
+			extract::ArchiveExtractorJobInit,
+			ghost::FileRetrieverJobInit,
+			import::ImportExternalFilesJobInit,
+			offline::{list_offline_pins, offline_cache_usage, pin_offline, unpin_offline},
+			pdf::PdfEditorJobInit,
+			scrub::{preview_scrub, MetadataField},
+			template::CreateFromTemplateArgs,
+			time_shift::{preview_time_shift, PhotoTimeShifterJobInit},
+			transcode::VideoTranscoderJobInit,
+		},
+		playback::{clear_playback, record_playback},
+		preview::{
+			text::{text_preview, DEFAULT_PREVIEW_KB},
+			thumbnailer_job::ThumbnailerJobInit,
+		},
+		stacks::{pick_best_shot, PhotoStackerJobInit},
+		xmp::write_object_sidecars_or_log,
 	},
 	prisma::{file_path, location, object},
 };
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use chrono::Utc;
 use futures::future::try_join_all;
 use regex::Regex;
 use rspc::{alpha::AlphaRouter, ErrorCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use specta::Type;
 use tokio::fs;
 use tracing::error;
@@ -37,13 +81,68 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 			}
 			R.with2(library())
 				.query(|(_, library), args: GetArgs| async move {
-					Ok(library
+					let Some(mut object) = library
 						.db
 						.object()
 						.find_unique(object::id::equals(args.id))
-						.include(object::include!({ file_paths media_data }))
+						.include(object::include!({ file_paths media_data font_data book_data }))
 						.exec()
-						.await?)
+						.await?
+					else {
+						return Ok(None);
+					};
+
+					let had_file_paths = !object.file_paths.is_empty();
+					library
+						.private_locations
+						.retain_visible(&mut object.file_paths)
+						.await;
+
+					let redaction = Redaction::fetch(&library).await?;
+					for file_path in &mut object.file_paths {
+						redaction.redact_name(
+							file_path.location_id,
+							file_path.is_dir,
+							&mut file_path.name,
+						);
+					}
+
+					// Objects only found in locked locations are hidden as well
+					Ok((!had_file_paths || !object.file_paths.is_empty()).then_some(object))
+				})
+		})
+		.procedure("revealFromOsPath", {
+			R.with2(authorized())
+				.query(|node, path: PathBuf| async move {
+					let (_, resolved) = resolve_os_path(&node.library_manager, path).await?;
+
+					Ok(resolved)
+				})
+		})
+		.procedure("revealRequests", {
+			R.with2(authorized())
+				.subscription(|node, _: ()| async move {
+					let mut event_bus_rx = node.event_bus.0.subscribe();
+					async_stream::stream! {
+						while let Ok(event) = event_bus_rx.recv().await {
+							if let CoreEvent::RevealPath(resolved) = event {
+								yield resolved;
+							}
+						}
+					}
+				})
+		})
+		.procedure("malwareDetections", {
+			R.with2(authorized())
+				.subscription(|node, _: ()| async move {
+					let mut event_bus_rx = node.event_bus.0.subscribe();
+					async_stream::stream! {
+						while let Ok(event) = event_bus_rx.recv().await {
+							if let CoreEvent::MalwareDetected(detection) = event {
+								yield detection;
+							}
+						}
+					}
 				})
 		})
 		.procedure("setNote", {
@@ -96,40 +195,93 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					Ok(())
 				})
 		})
-		.procedure("updateAccessTime", {
+		.procedure("setRating", {
+			#[derive(Type, Deserialize)]
+			pub struct SetRatingArgs {
+				pub id: i32,
+				pub rating: Option<i32>,
+			}
+
 			R.with2(library())
-				.mutation(|(_, library), id: i32| async move {
+				.mutation(|(_, library), args: SetRatingArgs| async move {
+					if matches!(args.rating, Some(rating) if !(0..=5).contains(&rating)) {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"Ratings go from 0 to 5".to_string(),
+						));
+					}
+
 					library
 						.db
 						.object()
 						.update(
-							object::id::equals(id),
-							vec![object::date_accessed::set(Some(Utc::now().into()))],
+							object::id::equals(args.id),
+							vec![object::rating::set(args.rating)],
 						)
 						.exec()
 						.await?;
 
+					write_object_sidecars_or_log(&library, vec![args.id]).await;
+
 					invalidate_query!(library, "search.paths");
+					invalidate_query!(library, "search.objects");
+
 					Ok(())
 				})
 		})
+		.procedure("setAccent", {
+			#[derive(Type, Deserialize)]
+			pub struct SetAccentArgs {
+				pub ids: Vec<file_path::id::Type>,
+				#[serde(flatten)]
+				pub accent: Accent,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: SetAccentArgs| async move {
+					Ok(set_file_path_accents(&library, args.ids, args.accent).await? as u32)
+				})
+		})
+		.procedure("setPlaybackPosition", {
+			#[derive(Type, Deserialize)]
+			pub struct SetPlaybackPositionArgs {
+				pub id: i32,
+				/// Seconds from the start
+				pub position: f64,
+				#[specta(optional)]
+				pub duration: Option<f64>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: SetPlaybackPositionArgs| async move {
+					Ok(record_playback(&library, args.id, args.position, args.duration).await?)
+				})
+		})
+		.procedure("clearPlaybackPosition", {
+			R.with2(library())
+				.mutation(
+					|(_, library), id: i32| async move { Ok(clear_playback(&library, id).await?) },
+				)
+		})
+		.procedure("updateAccessTime", {
+			R.with2(library())
+				.mutation(
+					|(_, library), id: i32| async move { Ok(record_access(&library, id).await?) },
+				)
+		})
 		.procedure("removeAccessTime", {
 			R.with2(library())
 				.mutation(|(_, library), object_ids: Vec<i32>| async move {
-					library
-						.db
-						.object()
-						.update_many(
-							vec![object::id::in_vec(object_ids)],
-							vec![object::date_accessed::set(None)],
-						)
-						.exec()
-						.await?;
-
-					invalidate_query!(library, "search.paths");
+					purge_access(&library, Some(object_ids)).await?;
 					Ok(())
 				})
 		})
+		.procedure("purgeAccessHistory", {
+			R.with2(library())
+				.mutation(
+					|(_, library), _: ()| async move { Ok(purge_access(&library, None).await?) },
+				)
+		})
 		// .procedure("encryptFiles", {
 		// 	R.with2(library())
 		// 		.mutation(|(_, library), args: FileEncryptorJobInit| async move {
@@ -154,12 +306,275 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					library.spawn_job(args).await.map_err(Into::into)
 				})
 		})
+		.procedure("retrieveFiles", {
+			R.with2(library())
+				.mutation(|(_, library), args: FileRetrieverJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("offlinePins", {
+			R.with2(library())
+				.query(|(_, library), location_id: location::id::Type| async move {
+					Ok(list_offline_pins(&library.db, location_id).await?)
+				})
+		})
+		.procedure("pinOffline", {
+			#[derive(Type, Deserialize)]
+			pub struct PinOfflineArgs {
+				pub location_id: location::id::Type,
+				pub file_path_ids: Vec<file_path::id::Type>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: PinOfflineArgs| async move {
+					Ok(pin_offline(&library, args.location_id, args.file_path_ids).await?)
+				})
+		})
+		.procedure("unpinOffline", {
+			#[derive(Type, Deserialize)]
+			pub struct UnpinOfflineArgs {
+				pub location_id: location::id::Type,
+				pub file_path_ids: Vec<file_path::id::Type>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: UnpinOfflineArgs| async move {
+					Ok(unpin_offline(&library, args.location_id, args.file_path_ids).await?)
+				})
+		})
+		.procedure("offlineCacheUsage", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(offline_cache_usage(&library).await?)
+			})
+		})
+		.procedure("decompressFiles", {
+			#[derive(Type, Deserialize)]
+			pub struct DecompressFilesArgs {
+				pub location_id: location::id::Type,
+				pub file_path_ids: Vec<file_path::id::Type>,
+			}
+
+			R.with2(library()).mutation(
+				|(_, library),
+				 DecompressFilesArgs {
+				     location_id,
+				     file_path_ids,
+				 }: DecompressFilesArgs| async move {
+					let location_path = find_location(&library, location_id)
+						.select(location::select!({ path }))
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(location_id))?
+						.path
+						.ok_or(LocationError::MissingPath(location_id))?;
+
+					let file_paths = library
+						.db
+						.file_path()
+						.find_many(vec![
+							file_path::location_id::equals(Some(location_id)),
+							file_path::id::in_vec(file_path_ids),
+							file_path::compressed_at::not(None),
+						])
+						.exec()
+						.await?;
+
+					for file_path in &file_paths {
+						restore_compressed_file(&library, &location_path, file_path)
+							.await
+							.map_err(|e| {
+								rspc::Error::with_cause(
+									ErrorCode::InternalServerError,
+									"Failed to decompress file".to_string(),
+									e,
+								)
+							})?;
+					}
+
+					invalidate_query!(library, "search.paths");
+					invalidate_query!(library, "library.statistics");
+
+					Ok(())
+				},
+			)
+		})
 		.procedure("duplicateFiles", {
 			R.with2(library())
 				.mutation(|(_, library), args: FileCopierJobInit| async move {
 					library.spawn_job(args).await.map_err(Into::into)
 				})
 		})
+		.procedure("findDuplicateFolders", {
+			R.with2(library())
+				.mutation(|(_, library), args: DuplicateFoldersJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("duplicateFolders", {
+			R.with2(library()).query(
+				|(_, library), location_id: Option<location::id::Type>| async move {
+					if let Some(location_id) = location_id {
+						library
+							.private_locations
+							.ensure_unlocked(location_id)
+							.await?;
+					}
+
+					let visible = library.private_locations.visible_file_paths().await;
+
+					Ok(duplicate_folder_groups(&library.db, location_id, visible).await?)
+				},
+			)
+		})
+		.procedure("scanBrokenLinks", {
+			R.with2(library())
+				.mutation(|(_, library), args: BrokenLinkScannerJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("brokenLinks", {
+			R.with2(library())
+				.query(|(_, library), filter: BrokenLinksFilter| async move {
+					Ok(broken_links(&library, filter).await?)
+				})
+		})
+		.procedure("deleteBrokenLinks", {
+			R.with2(library())
+				.mutation(|(_, library), ids: Vec<i32>| async move {
+					Ok(delete_broken_links(&library, ids).await?)
+				})
+		})
+		.procedure("retargetBrokenLink", {
+			#[derive(Type, Deserialize)]
+			pub struct RetargetBrokenLinkArgs {
+				pub id: i32,
+				/// Relative to the directory of the link, unless absolute
+				pub target: PathBuf,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: RetargetBrokenLinkArgs| async move {
+					Ok(retarget_broken_link(&library, args.id, args.target).await?)
+				})
+		})
+		.procedure("scanExtensionMismatches", {
+			R.with2(library()).mutation(
+				|(_, library), args: ExtensionMismatchScannerJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				},
+			)
+		})
+		.procedure("extensionMismatches", {
+			R.with2(library()).query(
+				|(_, library), filter: ExtensionMismatchesFilter| async move {
+					Ok(extension_mismatches(&library, filter).await?)
+				},
+			)
+		})
+		.procedure("fixExtensions", {
+			R.with2(library())
+				.mutation(|(_, library), ids: Vec<i32>| async move {
+					Ok(fix_extension_mismatches(&library, ids).await?)
+				})
+		})
+		.procedure("previewTimeShift", {
+			#[derive(Type, Deserialize)]
+			pub struct PreviewTimeShiftArgs {
+				pub location_id: location::id::Type,
+				pub file_path_ids: Vec<file_path::id::Type>,
+				pub offset_seconds: i32,
+			}
+
+			R.with2(library())
+				.query(|(_, library), args: PreviewTimeShiftArgs| async move {
+					Ok(preview_time_shift(
+						&library,
+						args.location_id,
+						&args.file_path_ids,
+						args.offset_seconds,
+					)
+					.await?)
+				})
+		})
+		.procedure("shiftPhotoTimes", {
+			R.with2(library())
+				.mutation(|(_, library), args: PhotoTimeShifterJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("previewScrub", {
+			#[derive(Type, Deserialize)]
+			pub struct PreviewScrubArgs {
+				pub location_id: location::id::Type,
+				pub file_path_ids: Vec<file_path::id::Type>,
+				pub fields: Vec<MetadataField>,
+			}
+
+			R.with2(library())
+				.query(|(_, library), args: PreviewScrubArgs| async move {
+					Ok(preview_scrub(
+						&library,
+						args.location_id,
+						&args.file_path_ids,
+						&args.fields,
+					)
+					.await?)
+				})
+		})
+		.procedure("dedupeFolders", {
+			#[derive(Type, Deserialize)]
+			pub struct DedupeFoldersArgs {
+				pub keep_file_path_id: file_path::id::Type,
+				pub remove_file_path_ids: Vec<file_path::id::Type>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: DedupeFoldersArgs| async move {
+					Ok(
+						dedupe_folders(&library, args.keep_file_path_id, args.remove_file_path_ids)
+							.await?,
+					)
+				})
+		})
+		.procedure("stackPhotos", {
+			R.with2(library())
+				.mutation(|(_, library), args: PhotoStackerJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("pickStackBest", {
+			#[derive(Type, Deserialize)]
+			pub struct PickStackBestArgs {
+				pub object_id: object::id::Type,
+				/// Hides the other shots of the stack
+				#[serde(default)]
+				pub archive_rest: bool,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: PickStackBestArgs| async move {
+					Ok(pick_best_shot(&library, args.object_id, args.archive_rest).await?)
+				})
+		})
+		.procedure("diffDirectories", {
+			R.with2(library())
+				.mutation(|(_, library), args: DirectoryDiffJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("copyMissing", {
+			#[derive(Type, Deserialize)]
+			pub struct CopyMissingArgs {
+				pub left: DiffSide,
+				pub right: DiffSide,
+				pub direction: DiffDirection,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: CopyMissingArgs| async move {
+					Ok(copy_missing(&library, args.left, args.right, args.direction).await?)
+				})
+		})
 		.procedure("copyFiles", {
 			R.with2(library())
 				.mutation(|(_, library), args: FileCopierJobInit| async move {
@@ -172,6 +587,180 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					library.spawn_job(args).await.map_err(Into::into)
 				})
 		})
+		.procedure("createArchive", {
+			R.with2(library())
+				.mutation(|(_, library), args: ArchiveCreatorJobInit| async move {
+					if !IsolatedFilePathData::accept_file_name(
+						&args.name,
+						library.config.file_name_policy,
+					) {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"Invalid file name".to_string(),
+						));
+					}
+
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("importExternalFiles", {
+			R.with2(library()).mutation(
+				|(_, library), args: ImportExternalFilesJobInit| async move {
+					if args.sources.iter().any(|source| !source.is_absolute()) {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"Imported paths must be absolute".to_string(),
+						));
+					}
+
+					let Some(location) = find_location(&library, args.location_id).exec().await?
+					else {
+						return Err(LocationError::IdNotFound(args.location_id).into());
+					};
+
+					let sub_path = (args.target_location_relative_directory_path != Path::new(""))
+						.then(|| args.target_location_relative_directory_path.clone());
+
+					// Files imported before a failure are worth their thumbnails all the same
+					library
+						.spawn_job(
+							Job::new_with_action(args, "import_external_files")
+								.with_failure_policy(ChainFailurePolicy::Continue)
+								.queue_next(ThumbnailerJobInit { location, sub_path }),
+						)
+						.await
+						.map_err(Into::into)
+				},
+			)
+		})
+		.procedure("extractArchive", {
+			R.with2(library())
+				.mutation(|(_, library), args: ArchiveExtractorJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("convertImages", {
+			R.with2(library())
+				.mutation(|(_, library), args: MediaConverterJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("transcodeVideos", {
+			R.with2(library())
+				.mutation(|(_, library), args: VideoTranscoderJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("editPdf", {
+			R.with2(library())
+				.mutation(|(_, library), args: PdfEditorJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("browseDiskImage", {
+			#[derive(Type, Deserialize)]
+			pub struct BrowseDiskImageArgs {
+				pub file_path_id: file_path::id::Type,
+				/// The directory inside the image, its root when empty
+				#[serde(default)]
+				#[specta(optional)]
+				pub path: String,
+			}
+
+			R.with2(library()).query(
+				|(_, library), BrowseDiskImageArgs { file_path_id, path }: BrowseDiskImageArgs| async move {
+					let location_id = library
+						.db
+						.file_path()
+						.find_unique(file_path::id::equals(file_path_id))
+						.select(file_path::select!({ location_id }))
+						.exec()
+						.await?
+						.and_then(|file_path| file_path.location_id)
+						.ok_or_else(|| {
+							rspc::Error::new(
+								ErrorCode::NotFound,
+								"Disk image not found".to_string(),
+							)
+						})?;
+
+					library
+						.private_locations
+						.ensure_unlocked(location_id)
+						.await?;
+
+					disk_image::browse(&library, file_path_id, &path)
+						.await
+						.map_err(|e| {
+							let code = match &e {
+								FileSystemJobsError::DiskImage(DiskImageError::EntryNotFound(
+									_,
+								)) => ErrorCode::NotFound,
+								FileSystemJobsError::DiskImage(
+									DiskImageError::NotADirectory(_)
+									| DiskImageError::NotADiskImage(_)
+									| DiskImageError::UnsupportedFilesystem(_),
+								) => ErrorCode::BadRequest,
+								_ => ErrorCode::InternalServerError,
+							};
+
+							rspc::Error::with_cause(code, e.to_string(), e)
+						})
+				},
+			)
+		})
+		.procedure("textPreview", {
+			#[derive(Type, Deserialize)]
+			pub struct TextPreviewArgs {
+				pub location_id: location::id::Type,
+				pub file_path_id: file_path::id::Type,
+				/// How much of the beginning of the file is previewed, 64KB by default
+				#[specta(optional)]
+				pub max_kb: Option<u32>,
+			}
+
+			R.with2(library())
+				.query(|(_, library), args: TextPreviewArgs| async move {
+					library
+						.private_locations
+						.ensure_unlocked(args.location_id)
+						.await?;
+
+					Ok(text_preview(
+						&library,
+						args.location_id,
+						args.file_path_id,
+						args.max_kb.unwrap_or(DEFAULT_PREVIEW_KB),
+					)
+					.await?)
+				})
+		})
+		.procedure("createFromTemplate", {
+			R.with2(library())
+				.mutation(|(_, library), args: CreateFromTemplateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("validateName", {
+			#[derive(Type, Serialize)]
+			pub struct ValidateNameResult {
+				/// If the library's file name policy allows this name
+				pub accepted: bool,
+				/// Platforms where a file with this name can't be created, so it won't sync there
+				pub rejected_on: Vec<Platform>,
+			}
+
+			R.with2(library())
+				.query(|(_, library), name: String| async move {
+					Ok(ValidateNameResult {
+						accepted: IsolatedFilePathData::accept_file_name(
+							&name,
+							library.config.file_name_policy,
+						),
+						rejected_on: platforms_rejecting_file_name(&name),
+					})
+				})
+		})
 		.procedure("renameFile", {
 			#[derive(Type, Deserialize)]
 			pub struct FromPattern {
@@ -232,6 +821,14 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 						return Ok(());
 					}
 
+					if !IsolatedFilePathData::accept_file_name(&to, library.config.file_name_policy)
+					{
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"Invalid file name".to_string(),
+						));
+					}
+
 					let (new_file_name, new_extension) =
 						IsolatedFilePathData::separate_name_and_extension_from_str(&to)
 							.map_err(LocationError::FilePath)?;
@@ -332,8 +929,10 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 								to.push(&replaced_full_name);
 
 								async move {
-									if !IsolatedFilePathData::accept_file_name(&replaced_full_name)
-									{
+									if !IsolatedFilePathData::accept_file_name(
+										&replaced_full_name,
+										library.config.file_name_policy,
+									) {
 										Err(rspc::Error::new(
 											ErrorCode::BadRequest,
 											"Invalid file name".to_string(),
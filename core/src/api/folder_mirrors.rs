@@ -0,0 +1,60 @@
+use crate::{
+	object::fs::{
+		mirror::{
+			delete_folder_mirror, list_folder_mirrors, FolderMirrorCreateArgs, FolderMirrorJobInit,
+			FolderMirrorUpdateArgs,
+		},
+		versions::{list_file_versions, restore_file_version},
+	},
+	prisma::{file_version, folder_mirror},
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_folder_mirrors(&library.db).await?)
+			})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: FolderMirrorCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: FolderMirrorUpdateArgs| async move {
+					Ok(args.update(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: folder_mirror::id::Type| async move {
+					Ok(delete_folder_mirror(&library, id).await?)
+				})
+		})
+		.procedure("run", {
+			R.with2(library())
+				.mutation(|(_, library), args: FolderMirrorJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("versions", {
+			R.with2(library()).query(
+				|(_, library), mirror_id: folder_mirror::id::Type| async move {
+					Ok(list_file_versions(&library.db, mirror_id).await?)
+				},
+			)
+		})
+		.procedure("restoreVersion", {
+			R.with2(library())
+				.mutation(|(_, library), id: file_version::id::Type| async move {
+					Ok(restore_file_version(&library, id).await?)
+				})
+		})
+}
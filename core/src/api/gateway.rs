@@ -0,0 +1,224 @@
+//! A plain HTTP gateway to the API, for integrations which don't speak rspc (scripts, home
+//! automation, ...). It's mounted by the `server` app, see there for how it's enabled.
+//!
+//! Queries are called with `GET /<key>?input=<json>` and mutations with `POST /<key>` and the input
+//! as the JSON body, the result is returned as is. Subscriptions need a WebSocket, so they aren't
+//! exposed. The OpenAPI document describing all of it is generated from the router's types, so it
+//! never drifts from the procedures.
+
+use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
+
+use rspc::internal::{
+	jsonrpc::{handle_json_rpc, Request, Response},
+	specta::{DataType, PrimitiveType},
+};
+use serde_json::{json, Map, Value};
+
+use super::{tokens::ProcedureKind, Router};
+use crate::Node;
+
+const SCHEMAS_REF: &str = "#/components/schemas/";
+
+/// An error returned by a procedure, with its rspc error code which are HTTP status codes
+#[derive(Debug)]
+pub struct GatewayError {
+	pub status: u16,
+	pub message: String,
+}
+
+/// Calls the procedure at `key`, like a client of the rspc endpoint would
+pub async fn call(
+	node: Arc<Node>,
+	router: &Router,
+	kind: ProcedureKind,
+	key: &str,
+	input: Value,
+) -> Result<Value, GatewayError> {
+	let method = match kind {
+		ProcedureKind::Query => "query",
+		ProcedureKind::Mutation => "mutation",
+		ProcedureKind::Subscription => {
+			return Err(GatewayError {
+				status: 400,
+				message: "subscriptions aren't available over the gateway".to_string(),
+			})
+		}
+	};
+
+	let request = serde_json::from_value::<Request>(json!({
+		"jsonrpc": "2.0",
+		"id": null,
+		"method": method,
+		"params": { "path": key, "input": input },
+	}))
+	.map_err(|e| GatewayError {
+		status: 400,
+		message: format!("invalid request: {e}"),
+	})?;
+
+	let mut resp = Option::<Response>::None;
+	handle_json_rpc(node, request, Cow::Borrowed(router), &mut resp).await;
+
+	let result = resp
+		.and_then(|resp| serde_json::to_value(resp).ok())
+		.and_then(|mut resp| resp.get_mut("result").map(Value::take))
+		.unwrap_or(Value::Null);
+
+	match result.get("type").and_then(Value::as_str) {
+		Some("response") => Ok(result.get("data").cloned().unwrap_or(Value::Null)),
+		Some("error") => Err(GatewayError {
+			status: result["data"]["code"]
+				.as_u64()
+				.and_then(|code| u16::try_from(code).ok())
+				.unwrap_or(500),
+			message: result["data"]["message"]
+				.as_str()
+				.unwrap_or("unknown error")
+				.to_string(),
+		}),
+		_ => Err(GatewayError {
+			status: 500,
+			message: "the procedure didn't respond".to_string(),
+		}),
+	}
+}
+
+/// Generates the OpenAPI 3 document of the gateway from the procedures of the router
+pub fn openapi(router: &Router) -> Value {
+	let mut paths = Map::new();
+
+	for (key, procedure) in router.queries().iter() {
+		let input = schema(&procedure.ty.input);
+		paths.insert(
+			format!("/{key}"),
+			json!({
+				"get": operation(key, &procedure.ty.result, json!({
+					"parameters": [{
+						"name": "input",
+						"in": "query",
+						"required": false,
+						"description": "The input of the query, as JSON",
+						"content": { "application/json": { "schema": input } },
+					}],
+				})),
+			}),
+		);
+	}
+
+	for (key, procedure) in router.mutations().iter() {
+		let input = schema(&procedure.ty.input);
+		paths.insert(
+			format!("/{key}"),
+			json!({
+				"post": operation(key, &procedure.ty.result, json!({
+					"requestBody": {
+						"required": false,
+						"content": { "application/json": { "schema": input } },
+					},
+				})),
+			}),
+		);
+	}
+
+	let schemas = router
+		.typ_store()
+		.iter()
+		.map(|(name, ty)| (name.to_string(), schema(ty)))
+		.collect::<BTreeMap<_, _>>();
+
+	json!({
+		"openapi": "3.0.3",
+		"info": {
+			"title": "Spacedrive",
+			"version": env!("CARGO_PKG_VERSION"),
+		},
+		"paths": paths,
+		"components": {
+			"schemas": schemas,
+			"securitySchemes": {
+				"apiToken": { "type": "http", "scheme": "bearer" },
+			},
+		},
+		"security": [{ "apiToken": [] }],
+	})
+}
+
+fn operation(key: &str, result: &DataType, mut operation: Value) -> Value {
+	operation["operationId"] = json!(key);
+	operation["tags"] = json!([key.split('.').next().unwrap_or(key)]);
+	operation["responses"] = json!({
+		"200": {
+			"description": "The result of the procedure",
+			"content": { "application/json": { "schema": schema(result) } },
+		},
+		"default": {
+			"description": "The error of the procedure",
+			"content": { "text/plain": { "schema": { "type": "string" } } },
+		},
+	});
+
+	operation
+}
+
+/// Converts a type of the router into a JSON Schema, types without an equivalent accept anything
+fn schema(ty: &DataType) -> Value {
+	match ty {
+		DataType::Primitive(primitive) => primitive_schema(primitive),
+		DataType::List(item) => json!({ "type": "array", "items": schema(item) }),
+		DataType::Nullable(inner) => {
+			let mut inner = schema(inner);
+			if let Some(inner) = inner.as_object_mut() {
+				inner.insert("nullable".to_string(), json!(true));
+			}
+			inner
+		}
+		DataType::Record(record) => json!({
+			"type": "object",
+			"additionalProperties": schema(&record.1),
+		}),
+		DataType::Reference { name, .. } => json!({ "$ref": format!("{SCHEMAS_REF}{name}") }),
+		DataType::Object(object) => {
+			let mut properties = Map::new();
+			let mut required = vec![];
+			for field in &object.fields {
+				properties.insert(field.key.to_string(), schema(&field.ty));
+				if !field.optional {
+					required.push(field.key);
+				}
+			}
+
+			json!({
+				"type": "object",
+				"properties": properties,
+				"required": required,
+			})
+		}
+		_ => json!({}),
+	}
+}
+
+fn primitive_schema(primitive: &PrimitiveType) -> Value {
+	use PrimitiveType::*;
+
+	match primitive {
+		i8 | i16 | i32 | u8 | u16 | u32 => json!({ "type": "integer", "format": "int32" }),
+		i64 | u64 | i128 | u128 | isize | usize => json!({ "type": "integer", "format": "int64" }),
+		f32 | f64 => json!({ "type": "number" }),
+		bool => json!({ "type": "boolean" }),
+		char | String => json!({ "type": "string" }),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn documents_procedures() {
+		let doc = openapi(&super::super::mount());
+
+		assert!(doc["paths"]["/buildInfo"]["get"].is_object());
+		assert!(doc["paths"]["/apiTokens.issue"]["post"].is_object());
+		assert!(doc["paths"]["/jobs.progress"].is_null());
+	}
+}
@@ -0,0 +1,44 @@
+use crate::{
+	job::{
+		delete_job_template, list_job_templates, JobTemplateCreateArgs, JobTemplateRunArgs,
+		JobTemplateUpdateArgs,
+	},
+	prisma::job_template,
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_job_templates(&library.db).await?)
+			})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: JobTemplateCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: JobTemplateUpdateArgs| async move {
+					Ok(args.update(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: job_template::id::Type| async move {
+					Ok(delete_job_template(&library, id).await?)
+				})
+		})
+		.procedure("run", {
+			R.with2(library())
+				.mutation(|(_, library), args: JobTemplateRunArgs| async move {
+					Ok(args.run(&library).await?)
+				})
+		})
+}
@@ -5,7 +5,7 @@ use std::{
 
 use crate::{
 	invalidate_query,
-	job::{job_without_data, JobManager, JobReport, JobStatus},
+	job::{job_without_data, JobManager, JobReport, JobSchedulePolicy, JobStatus},
 	location::{find_location, LocationError},
 	object::{
 		file_identifier::file_identifier_job::FileIdentifierJobInit,
@@ -192,6 +192,67 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					JobManager::resume(&ctx.jobs, id).await.map_err(Into::into)
 				})
 		})
+		// revert the changes made by a failed copy or cut job
+		.procedure("rollback", {
+			R.with2(library())
+				.mutation(|(ctx, library), id: Uuid| async move {
+					ctx.jobs.rollback(&library, id).await?;
+
+					invalidate_query!(library, "jobs.reports");
+					invalidate_query!(library, "search.paths");
+					Ok(())
+				})
+		})
+		// run a background job now, even if the schedule policies would hold it back
+		.procedure("forceRun", {
+			R.with2(library())
+				.mutation(|(ctx, library), id: Uuid| async move {
+					ctx.jobs.clone().force_run(id).await?;
+
+					invalidate_query!(library, "jobs.reports");
+					Ok(())
+				})
+		})
+		// operations that were queued when the node stopped, for the user to resume or discard
+		.procedure("pendingOperations", {
+			R.with2(library())
+				.query(|(ctx, library), _: ()| async move {
+					Ok(ctx.jobs.get_pending_reports(library.id).await)
+				})
+		})
+		.procedure("resumePending", {
+			R.with2(library())
+				.mutation(|(ctx, library), ids: Vec<Uuid>| async move {
+					ctx.jobs.clone().resume_pending(library.id, &ids).await;
+
+					invalidate_query!(library, "jobs.pendingOperations");
+					invalidate_query!(library, "jobs.reports");
+					Ok(())
+				})
+		})
+		.procedure("discardPending", {
+			R.with2(library())
+				.mutation(|(ctx, library), ids: Vec<Uuid>| async move {
+					ctx.jobs.discard_pending(library.id, &ids).await;
+
+					invalidate_query!(library, "jobs.pendingOperations");
+					invalidate_query!(library, "jobs.reports");
+					Ok(())
+				})
+		})
+		.procedure("setSchedulePolicy", {
+			R.with2(library())
+				.mutation(|(ctx, library), policy: JobSchedulePolicy| async move {
+					let library = ctx
+						.library_manager
+						.update_job_schedule(library.id, policy)
+						.await?;
+
+					ctx.jobs.clone().update_deferred_library(&library).await;
+
+					Ok(())
+				})
+		})
 		.procedure("generateThumbsForLocation", {
 			#[derive(Type, Deserialize)]
 			pub struct GenerateThumbsForLocationArgs {
@@ -0,0 +1,37 @@
+use crate::{
+	object::custom_kind::{
+		delete_custom_kind, list_custom_kinds, CustomKindCreateArgs, CustomKindUpdateArgs,
+	},
+	prisma::custom_kind,
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_custom_kinds(&library.db).await?)
+			})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: CustomKindCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: CustomKindUpdateArgs| async move {
+					Ok(args.update(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: custom_kind::id::Type| async move {
+					Ok(delete_custom_kind(&library, id).await?)
+				})
+		})
+}
@@ -1,30 +1,46 @@
 use crate::{
-	library::LibraryConfig,
-	prisma::statistics,
+	library::{
+		activity::list_activity,
+		session::{clear_session, get_session, set_session_state},
+		LibraryConfig,
+	},
+	location::file_path_helper::{
+		normalizer_job::FilePathNormalizerJobInit, FileNameNormalization, FileNamePolicy,
+	},
+	object::groups::{FileGrouperJobInit, FileGroupingRule},
+	prisma::{activity_log, location, statistics},
 	util::MaybeUndefined,
 	volume::{get_volumes, save_volume},
 };
 
 use chrono::Utc;
+use prisma_client_rust::{raw, PrismaValue};
 use rspc::alpha::AlphaRouter;
 use serde::Deserialize;
+use serde_json::json;
 use specta::Type;
 use tracing::debug;
 use uuid::Uuid;
 
 use super::{
-	utils::{get_size, library},
+	utils::{authorized, get_size, library},
 	Ctx, R,
 };
 
 pub(crate) fn mount() -> AlphaRouter<Ctx> {
 	R.router()
 		.procedure("list", {
-			R.query(
-				|ctx, _: ()| async move { ctx.library_manager.get_all_libraries_config().await },
-			)
+			R.with2(authorized()).query(|ctx, _: ()| async move {
+				ctx.library_manager.get_all_libraries_config().await
+			})
 		})
 		.procedure("statistics", {
+			#[derive(Deserialize, Default)]
+			struct FilePathSizes {
+				total_bytes_used: Option<String>,
+				total_bytes_allocated: Option<String>,
+			}
+
 			R.with2(library()).query(|(_, library), _: ()| async move {
 				let _statistics = library
 					.db
@@ -47,6 +63,25 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					}
 				}
 
+				let locked_locations = library.private_locations.locked().await;
+
+				// Files indexed before we tracked the allocated size count with their logical size
+				let file_path_sizes = library
+					.db
+					._query_raw::<FilePathSizes>(raw!(
+						"SELECT \
+							CAST(SUM(CAST(size_in_bytes AS INTEGER)) AS TEXT) AS total_bytes_used, \
+							CAST(SUM(CAST(COALESCE(allocated_size_in_bytes, size_in_bytes) AS INTEGER)) AS TEXT) \
+								AS total_bytes_allocated \
+						FROM file_path WHERE is_dir = 0 \
+							AND (location_id IS NULL OR location_id NOT IN (SELECT value FROM json_each({})))",
+						PrismaValue::String(json!(locked_locations).to_string())
+					))
+					.exec()
+					.await?
+					.pop()
+					.unwrap_or_default();
+
 				let library_db_size = get_size(
 					library
 						.config()
@@ -68,7 +103,16 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					date_captured::set(Utc::now().into()),
 					total_object_count::set(0),
 					library_db_size::set(library_db_size.to_string()),
-					total_bytes_used::set(0.to_string()),
+					total_bytes_used::set(
+						file_path_sizes
+							.total_bytes_used
+							.unwrap_or_else(|| 0.to_string()),
+					),
+					total_bytes_allocated::set(
+						file_path_sizes
+							.total_bytes_allocated
+							.unwrap_or_else(|| 0.to_string()),
+					),
 					total_bytes_capacity::set(total_capacity.to_string()),
 					total_unique_bytes::set(0.to_string()),
 					total_bytes_free::set(available_capacity.to_string()),
@@ -93,19 +137,20 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				name: String,
 			}
 
-			R.mutation(|ctx, args: CreateLibraryArgs| async move {
-				debug!("Creating library");
+			R.with2(authorized())
+				.mutation(|ctx, args: CreateLibraryArgs| async move {
+					debug!("Creating library");
 
-				let new_library = ctx
-					.library_manager
-					.create(
-						LibraryConfig::new(args.name.to_string(), ctx.config.get().await.id),
-						ctx.config.get().await,
-					)
-					.await?;
+					let new_library = ctx
+						.library_manager
+						.create(
+							LibraryConfig::new(args.name.to_string(), ctx.config.get().await.id),
+							ctx.config.get().await,
+						)
+						.await?;
 
-				Ok(new_library)
-			})
+					Ok(new_library)
+				})
 		})
 		.procedure("edit", {
 			#[derive(Type, Deserialize)]
@@ -113,18 +158,143 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				pub id: Uuid,
 				pub name: Option<String>,
 				pub description: MaybeUndefined<String>,
+				pub file_name_policy: Option<FileNamePolicy>,
+				pub file_grouping_rules: Option<Vec<FileGroupingRule>>,
 			}
 
-			R.mutation(|ctx, args: EditLibraryArgs| async move {
-				Ok(ctx
-					.library_manager
-					.edit(args.id, args.name, args.description)
-					.await?)
-			})
+			R.with2(authorized())
+				.mutation(|ctx, args: EditLibraryArgs| async move {
+					let regroup = args.file_grouping_rules.is_some();
+
+					ctx.library_manager
+						.edit(
+							args.id,
+							args.name,
+							args.description,
+							args.file_name_policy,
+							args.file_grouping_rules,
+						)
+						.await?;
+
+					// Files are regrouped by the new rules right away instead of on the next scan
+					if regroup {
+						if let Some(library) = ctx.library_manager.get_library(args.id).await {
+							for location in library
+								.db
+								.location()
+								.find_many(vec![location::node_id::equals(Some(
+									library.node_local_id,
+								))])
+								.select(location::select!({ id }))
+								.exec()
+								.await?
+							{
+								library
+									.spawn_job(FileGrouperJobInit {
+										location_id: location.id,
+									})
+									.await?;
+							}
+						}
+					}
+
+					Ok(())
+				})
+		})
+		.procedure("setAccessTracking", {
+			#[derive(Type, Deserialize)]
+			pub struct SetAccessTrackingArgs {
+				pub id: Uuid,
+				pub enabled: bool,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: SetAccessTrackingArgs| async move {
+					Ok(ctx
+						.library_manager
+						.update_access_tracking(args.id, args.enabled)
+						.await?)
+				})
+		})
+		.procedure("setOfflineCacheBudget", {
+			#[derive(Type, Deserialize)]
+			pub struct SetOfflineCacheBudgetArgs {
+				pub id: Uuid,
+				pub budget_in_mb: u32,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: SetOfflineCacheBudgetArgs| async move {
+					Ok(ctx
+						.library_manager
+						.update_offline_cache_budget(args.id, args.budget_in_mb)
+						.await?)
+				})
+		})
+		.procedure("setFileNameNormalization", {
+			#[derive(Type, Deserialize)]
+			pub struct SetFileNameNormalizationArgs {
+				pub id: Uuid,
+				pub normalization: FileNameNormalization,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: SetFileNameNormalizationArgs| async move {
+					let library = ctx
+						.library_manager
+						.update_file_name_normalization(args.id, args.normalization)
+						.await?;
+
+					if args.normalization != FileNameNormalization::Preserve {
+						library
+							.spawn_job(FilePathNormalizerJobInit {
+								normalization: args.normalization,
+							})
+							.await?;
+					}
+
+					Ok(())
+				})
+		})
+		.procedure("activity", {
+			R.with2(library()).query(
+				|(_, library), cursor: Option<activity_log::id::Type>| async move {
+					Ok(list_activity(&library.db, cursor).await?)
+				},
+			)
+		})
+		.procedure("session", {
+			R.with2(library())
+				.query(|(_, library), client: String| async move {
+					Ok(get_session(&library.db, client).await?)
+				})
+		})
+		.procedure("setSessionState", {
+			#[derive(Type, Deserialize)]
+			pub struct SetSessionStateArgs {
+				/// Which client the state is for, like `desktop`
+				pub client: String,
+				pub key: String,
+				/// JSON, `None` removes the key
+				pub value: Option<String>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: SetSessionStateArgs| async move {
+					Ok(set_session_state(&library, args.client, args.key, args.value).await?)
+				})
+		})
+		.procedure("clearSession", {
+			R.with2(library())
+				.mutation(|(_, library), client: String| async move {
+					clear_session(&library, client).await?;
+					Ok(())
+				})
 		})
 		.procedure(
 			"delete",
-			R.mutation(|ctx, id: Uuid| async move { Ok(ctx.library_manager.delete(id).await?) }),
+			R.with2(authorized())
+				.mutation(|ctx, id: Uuid| async move { Ok(ctx.library_manager.delete(id).await?) }),
 		)
 	// .yolo_merge("peer.guest.", peer_guest_router())
 	// .yolo_merge("peer.host.", peer_host_router())
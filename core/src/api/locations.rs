@@ -1,11 +1,29 @@
 use crate::{
 	invalidate_query,
+	library::Library,
 	location::{
-		delete_location, find_location, indexer::rules::IndexerRuleCreateArgs, light_scan_location,
-		location_with_indexer_rules, relink_location, scan_location, LocationCreateArgs,
+		actions::{delete_new_file_action, new_file_actions, NewFileActionCreateArgs},
+		delete_location,
+		downloads::{delete_download_rule, download_rules, DownloadRuleCreateArgs},
+		find_location, full_rescan_location,
+		indexer::rules::IndexerRuleCreateArgs,
+		light_scan_location, location_with_indexer_rules,
+		nested::{find_overlapping_locations, merge_location, split_location},
+		pinned::{pin_directory, pinned_directories, scan_pinned_directory, unpin_directory},
+		privacy, relink_location, relocate_location, scan_location, LocationCreateArgs,
 		LocationError, LocationUpdateArgs,
 	},
-	prisma::{file_path, indexer_rule, indexer_rules_in_location, location, object, tag},
+	object::{
+		catalog::CatalogImporterJobInit,
+		fs::{compress::FileCompressorJobInit, tiering::FileTieringJobInit},
+		groups::{FileGroupSummary, FileGrouperJobInit},
+		projects::{list_projects, ProjectDetectorJobInit},
+		xmp::XmpSidecarSyncJobInit,
+	},
+	prisma::{
+		download_rule, file_path, indexer_rule, indexer_rules_in_location, location,
+		new_file_action, object, pinned_directory, tag,
+	},
 	util::AbortOnDrop,
 };
 
@@ -15,7 +33,10 @@ use rspc::{self, alpha::AlphaRouter, ErrorCode};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
-use super::{utils::library, Ctx, R};
+use super::{
+	utils::{authorized, library},
+	Ctx, R,
+};
 
 #[derive(Serialize, Deserialize, Type, Debug)]
 #[serde(tag = "type")]
@@ -34,11 +55,17 @@ pub enum ExplorerItem {
 		// thumbnail_key is present if there is a cas_id
 		// it includes the shard hex formatted as (["f0", "cab34a76fbf3469f"])
 		thumbnail_key: Option<Vec<String>>,
+		// is_ghost is true when the content isn't reachable from this device, see `ReachableLocations`
+		is_ghost: bool,
+		// the files listed along this one when grouping them, see `object::groups`
+		group: Option<FileGroupSummary>,
 		item: file_path_with_object::Data,
 	},
 	Object {
 		has_local_thumbnail: bool,
 		thumbnail_key: Option<Vec<String>>,
+		// an object is a ghost when none of its file paths are reachable
+		is_ghost: bool,
 		item: object_with_file_paths::Data,
 	},
 }
@@ -123,6 +150,166 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 						.map_err(Into::into)
 				})
 		})
+		.procedure("relocate", {
+			R.with2(library())
+				.mutation(|(_, library), args: LocationRelocateArgs| async move {
+					relocate_location(&library, args.id, args.new_path)
+						.await
+						.map_err(Into::into)
+				})
+		})
+		.procedure("overlaps", {
+			R.with2(library())
+				.query(|(_, library), path: PathBuf| async move {
+					Ok(find_overlapping_locations(&library.db, path).await?)
+				})
+		})
+		.procedure("split", {
+			R.with2(library())
+				.mutation(|(_, library), args: LocationSplitArgs| async move {
+					let location_id = split_location(&library, args.id, args.sub_path).await?;
+					invalidate_query!(library, "locations.list");
+
+					Ok(location_id)
+				})
+		})
+		.procedure("merge", {
+			R.with2(library()).mutation(
+				|(_, library), location_id: location::id::Type| async move {
+					let location = find_location(&library, location_id)
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(location_id))?;
+					let parent_id = merge_location(&library, location_id).await?;
+
+					// The parent has no file path for the root of the merged location yet
+					if let Some(parent_dir) = location
+						.path
+						.as_ref()
+						.and_then(|path| PathBuf::from(path).parent().map(PathBuf::from))
+					{
+						let parent = find_location(&library, parent_id)
+							.include(location_with_indexer_rules::include())
+							.exec()
+							.await?
+							.ok_or(LocationError::IdNotFound(parent_id))?;
+						light_scan_location(library, parent, parent_dir)
+							.await
+							.map_err(|e| {
+								rspc::Error::with_cause(
+									ErrorCode::InternalServerError,
+									e.to_string(),
+									e,
+								)
+							})?;
+					}
+
+					Ok(parent_id)
+				},
+			)
+		})
+		.procedure("pinnedDirectories", {
+			R.with2(library())
+				.query(|(_, library), location_id: location::id::Type| async move {
+					Ok(pinned_directories(&library.db, location_id).await?)
+				})
+		})
+		.procedure("pinDirectory", {
+			R.with2(library())
+				.mutation(|(_, library), args: PinDirectoryArgs| async move {
+					let pinned = pin_directory(&library, args.id, args.sub_path).await?;
+
+					let location = find_location(&library, args.id)
+						.include(location_with_indexer_rules::include())
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(args.id))?;
+					scan_pinned_directory(&library, &location, &pinned)
+						.await
+						.map_err(|e| {
+							rspc::Error::with_cause(
+								ErrorCode::InternalServerError,
+								e.to_string(),
+								e,
+							)
+						})?;
+
+					Ok(pinned)
+				})
+		})
+		.procedure("unpinDirectory", {
+			R.with2(library()).mutation(
+				|(_, library), pinned_directory_id: pinned_directory::id::Type| async move {
+					Ok(unpin_directory(&library, pinned_directory_id).await?)
+				},
+			)
+		})
+		.procedure("projects", {
+			R.with2(library()).query(
+				|(_, library), location_id: Option<location::id::Type>| async move {
+					if let Some(location_id) = location_id {
+						library
+							.private_locations
+							.ensure_unlocked(location_id)
+							.await?;
+					}
+
+					let visible = library.private_locations.visible_file_paths().await;
+
+					Ok(list_projects(&library.db, location_id, visible).await?)
+				},
+			)
+		})
+		.procedure("detectProjects", {
+			R.with2(library())
+				.mutation(|(_, library), args: ProjectDetectorJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("groupFiles", {
+			R.with2(library())
+				.mutation(|(_, library), args: FileGrouperJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("downloadRules", {
+			R.with2(library())
+				.query(|(_, library), location_id: location::id::Type| async move {
+					Ok(download_rules(&library.db, location_id).await?)
+				})
+		})
+		.procedure("createDownloadRule", {
+			R.with2(library())
+				.mutation(|(_, library), args: DownloadRuleCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("deleteDownloadRule", {
+			R.with2(library()).mutation(
+				|(_, library), download_rule_id: download_rule::id::Type| async move {
+					Ok(delete_download_rule(&library, download_rule_id).await?)
+				},
+			)
+		})
+		.procedure("newFileActions", {
+			R.with2(library())
+				.query(|(_, library), location_id: location::id::Type| async move {
+					Ok(new_file_actions(&library.db, location_id).await?)
+				})
+		})
+		.procedure("createNewFileAction", {
+			R.with2(library())
+				.mutation(|(_, library), args: NewFileActionCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("deleteNewFileAction", {
+			R.with2(library()).mutation(
+				|(_, library), new_file_action_id: new_file_action::id::Type| async move {
+					Ok(delete_new_file_action(&library, new_file_action_id).await?)
+				},
+			)
+		})
 		.procedure("addLibrary", {
 			R.with2(library())
 				.mutation(|(_, library), args: LocationCreateArgs| async move {
@@ -136,8 +323,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 		.procedure("fullRescan", {
 			R.with2(library()).mutation(
 				|(_, library), location_id: location::id::Type| async move {
-					// rescan location
-					scan_location(
+					full_rescan_location(
 						&library,
 						find_location(&library, location_id)
 							.include(location_with_indexer_rules::include())
@@ -150,6 +336,80 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				},
 			)
 		})
+		.procedure("compressColdFiles", {
+			R.with2(library()).mutation(
+				|(_, library), location_id: location::id::Type| async move {
+					library
+						.spawn_job(FileCompressorJobInit { location_id })
+						.await
+						.map_err(Into::into)
+				},
+			)
+		})
+		.procedure("importCatalog", {
+			R.with2(library())
+				.mutation(|(_, library), args: CatalogImporterJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("tierColdFiles", {
+			R.with2(library()).mutation(
+				|(_, library), location_id: location::id::Type| async move {
+					library
+						.spawn_job(FileTieringJobInit { location_id })
+						.await
+						.map_err(Into::into)
+				},
+			)
+		})
+		.procedure("syncXmpSidecars", {
+			R.with2(library()).mutation(
+				|(_, library), location_id: location::id::Type| async move {
+					library
+						.spawn_job(XmpSidecarSyncJobInit { location_id })
+						.await
+						.map_err(Into::into)
+				},
+			)
+		})
+		.procedure("makePrivate", {
+			R.with2(library())
+				.mutation(|(_, library), args: LocationPassphraseArgs| async move {
+					privacy::make_private(&library, args.id, args.passphrase).await?;
+					invalidate_privacy_queries(&library);
+					Ok(())
+				})
+		})
+		.procedure("makePublic", {
+			R.with2(library())
+				.mutation(|(_, library), args: LocationPassphraseArgs| async move {
+					privacy::make_public(&library, args.id, args.passphrase).await?;
+					invalidate_privacy_queries(&library);
+					Ok(())
+				})
+		})
+		.procedure("unlock", {
+			R.with2(library())
+				.mutation(|(_, library), args: LocationPassphraseArgs| async move {
+					privacy::unlock(&library, args.id, args.passphrase).await?;
+					invalidate_privacy_queries(&library);
+					Ok(())
+				})
+		})
+		.procedure("lock", {
+			R.with2(library()).mutation(
+				|(_, library), location_id: location::id::Type| async move {
+					library.private_locations.lock(location_id).await;
+					invalidate_privacy_queries(&library);
+					Ok(())
+				},
+			)
+		})
+		.procedure("locked", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(library.private_locations.locked().await)
+			})
+		})
 		.procedure("quickRescan", {
 			#[derive(Clone, Serialize, Deserialize, Type, Debug)]
 			pub struct LightScanArgs {
@@ -173,7 +433,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 		})
 		.procedure(
 			"online",
-			R.subscription(|ctx, _: ()| async move {
+			R.with2(authorized()).subscription(|ctx, _: ()| async move {
 				let location_manager = ctx.location_manager.clone();
 
 				let mut rx = location_manager.online_rx();
@@ -192,6 +452,41 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 		.merge("indexer_rules.", mount_indexer_rule_routes())
 }
 
+#[derive(Deserialize, Type)]
+pub struct LocationRelocateArgs {
+	pub id: location::id::Type,
+	pub new_path: PathBuf,
+}
+
+#[derive(Deserialize, Type)]
+pub struct LocationSplitArgs {
+	pub id: location::id::Type,
+	/// The directory to turn into a location, relative to the location
+	pub sub_path: PathBuf,
+}
+
+#[derive(Deserialize, Type)]
+pub struct PinDirectoryArgs {
+	pub id: location::id::Type,
+	/// The directory to pin, relative to the location
+	pub sub_path: PathBuf,
+}
+
+#[derive(Deserialize, Type)]
+pub struct LocationPassphraseArgs {
+	pub id: location::id::Type,
+	pub passphrase: String,
+}
+
+fn invalidate_privacy_queries(library: &Library) {
+	invalidate_query!(library, "locations.locked");
+	invalidate_query!(library, "locations.list");
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+	invalidate_query!(library, "categories.list");
+	invalidate_query!(library, "library.statistics");
+}
+
 fn mount_indexer_rule_routes() -> AlphaRouter<Ctx> {
 	R.router()
 		.procedure("create", {
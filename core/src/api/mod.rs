@@ -1,10 +1,16 @@
-use crate::{job::JobProgressEvent, node::SanitisedNodeConfig, Node};
+use crate::{
+	job::JobProgressEvent,
+	node::{ResolvedOsPath, SanitisedNodeConfig},
+	object::malware_scan::MalwareDetection,
+	volume::{health::DriveHealthAlert, StorageAlert},
+	Node,
+};
 use rspc::{alpha::Rspc, Config};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 use std::sync::Arc;
 
-use utils::{InvalidRequests, InvalidateOperationEvent};
+use utils::{authorized, InvalidRequests, InvalidateOperationEvent};
 
 #[allow(non_upper_case_globals)]
 pub(self) const R: Rspc<Ctx> = Rspc::new();
@@ -18,19 +24,38 @@ pub enum CoreEvent {
 	NewThumbnail { thumb_key: Vec<String> },
 	JobProgress(JobProgressEvent),
 	InvalidateOperation(InvalidateOperationEvent),
+	RevealPath(ResolvedOsPath),
+	StorageAlert(StorageAlert),
+	DriveHealthAlert(DriveHealthAlert),
+	MalwareDetected(MalwareDetection),
 }
 
 mod categories;
+mod cleanup;
+mod custom_fields;
+mod diagnostics;
+mod directories;
+mod export_profiles;
+mod file_templates;
 mod files;
+mod folder_mirrors;
+pub mod gateway;
+mod job_templates;
 mod jobs;
 mod keys;
+mod kinds;
 mod libraries;
 mod locations;
 mod nodes;
+mod objects;
 mod p2p;
 mod search;
+mod shelf;
+mod sidebar;
+mod statistics;
 mod sync;
 mod tags;
+pub mod tokens;
 pub mod utils;
 pub mod volumes;
 
@@ -51,13 +76,13 @@ pub(crate) fn mount() -> Arc<Router> {
 				commit: &'static str,
 			}
 
-			R.query(|_, _: ()| BuildInfo {
+			R.with2(authorized()).query(|_, _: ()| BuildInfo {
 				version: env!("CARGO_PKG_VERSION"),
 				commit: env!("GIT_HASH"),
 			})
 		})
 		.procedure("nodeState", {
-			R.query(|ctx, _: ()| async move {
+			R.with2(authorized()).query(|ctx, _: ()| async move {
 				Ok(NodeState {
 					config: ctx.config.get().await.into(),
 					// We are taking the assumption here that this value is only used on the frontend for display purposes
@@ -74,14 +99,28 @@ pub(crate) fn mount() -> Arc<Router> {
 		.merge("library.", libraries::mount())
 		.merge("volumes.", volumes::mount())
 		.merge("tags.", tags::mount())
+		.merge("sidebar.", sidebar::mount())
+		.merge("shelf.", shelf::mount())
 		.merge("categories.", categories::mount())
+		.merge("kinds.", kinds::mount())
+		.merge("customFields.", custom_fields::mount())
 		// .merge("keys.", keys::mount())
 		.merge("locations.", locations::mount())
 		.merge("files.", files::mount())
+		.merge("directories.", directories::mount())
+		.merge("cleanup.", cleanup::mount())
+		.merge("objects.", objects::mount())
 		.merge("jobs.", jobs::mount())
+		.merge("jobTemplates.", job_templates::mount())
+		.merge("exportProfiles.", export_profiles::mount())
+		.merge("fileTemplates.", file_templates::mount())
+		.merge("folderMirrors.", folder_mirrors::mount())
 		.merge("p2p.", p2p::mount())
 		.merge("nodes.", nodes::mount())
 		.merge("sync.", sync::mount())
+		.merge("diagnostics.", diagnostics::mount())
+		.merge("statistics.", statistics::mount())
+		.merge("apiTokens.", tokens::mount())
 		.merge("invalidation.", utils::mount_invalidate())
 		.build(
 			#[allow(clippy::let_and_return)]
@@ -1,34 +1,287 @@
 use rspc::{alpha::AlphaRouter, ErrorCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use specta::Type;
 use tracing::error;
 
-use crate::api::R;
+use crate::{
+	api::R,
+	invalidate_query,
+	node::{CustomUriLimits, ResourceLimits, ResourceProfile, StorageAlertThresholds},
+	object::{malware_scan::MalwareScanner, nsfw::NsfwClassifier},
+};
 
-use super::Ctx;
+use super::{utils::authorized, Ctx};
 
 pub(crate) fn mount() -> AlphaRouter<Ctx> {
-	R.router().procedure("changeNodeName", {
-		#[derive(Deserialize, Type)]
-		pub struct ChangeNodeNameArgs {
-			pub name: String,
-		}
-		// TODO: validate name isn't empty or too long
-
-		R.mutation(|ctx, args: ChangeNodeNameArgs| async move {
-			ctx.config
-				.write(|mut config| {
-					config.name = args.name;
-				})
-				.await
-				.map_err(|err| {
-					error!("Failed to write config: {}", err);
-					rspc::Error::new(
-						ErrorCode::InternalServerError,
-						"error updating config".into(),
-					)
+	R.router()
+		.procedure("changeNodeName", {
+			#[derive(Deserialize, Type)]
+			pub struct ChangeNodeNameArgs {
+				pub name: String,
+			}
+			// TODO: validate name isn't empty or too long
+
+			R.with2(authorized()).mutation(|ctx, args: ChangeNodeNameArgs| async move {
+				ctx.config
+					.write(|mut config| {
+						config.name = args.name;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setMemoryBudget", {
+			#[derive(Deserialize, Type)]
+			pub struct SetMemoryBudgetArgs {
+				/// Budget in MiB, `None` lets the node derive it from the system memory
+				pub memory_budget_mb: Option<u32>,
+			}
+
+			R.with2(authorized()).mutation(|ctx, args: SetMemoryBudgetArgs| async move {
+				if args.memory_budget_mb == Some(0) {
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"memory budget must be greater than zero".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| {
+						config.memory_budget_mb = args.memory_budget_mb;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})?;
+
+				ctx.memory_budget.resize(args.memory_budget_mb).await;
+
+				Ok(())
+			})
+		})
+		.procedure("setMetricsEnabled", {
+			R.with2(authorized()).mutation(|ctx, enabled: bool| async move {
+				ctx.config
+					.write(|mut config| {
+						config.metrics_enabled = enabled;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setShareLinksBaseUrl", {
+			R.with2(authorized()).mutation(|ctx, base_url: Option<String>| async move {
+				let base_url = base_url.map(|url| url.trim_end_matches('/').to_string());
+
+				if matches!(&base_url, Some(url) if !url.starts_with("https://")) {
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"share links must be served over HTTPS".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| {
+						config.share_links_base_url = base_url;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setCustomUriLimits", {
+			R.with2(authorized()).mutation(|ctx, limits: CustomUriLimits| async move {
+				if limits.requests_per_minute == Some(0) || limits.max_concurrent_streams == Some(0)
+				{
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"limits must be greater than zero".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| {
+						config.custom_uri_limits = limits;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setStorageAlerts", {
+			R.with2(authorized()).mutation(|ctx, thresholds: StorageAlertThresholds| async move {
+				if thresholds
+					.min_free_percent
+					.map_or(false, |percent| percent > 100)
+				{
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"free space percentage can't be over 100".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| {
+						config.storage_alerts = thresholds;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setMalwareScanner", {
+			R.with2(authorized()).mutation(|ctx, scanner: Option<MalwareScanner>| async move {
+				if matches!(&scanner, Some(MalwareScanner::ClamAv { address }) if address.trim().is_empty())
+				{
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"the address of clamd can't be empty".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| {
+						config.malware_scanner = scanner;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setNsfwClassifier", {
+			R.with2(authorized()).mutation(|ctx, classifier: Option<NsfwClassifier>| async move {
+				if matches!(&classifier, Some(NsfwClassifier { threshold, .. }) if !(0.0..=1.0).contains(threshold))
+				{
+					return Err(rspc::Error::new(
+						ErrorCode::BadRequest,
+						"the threshold must be from 0 to 1".into(),
+					));
+				}
+
+				ctx.config
+					.write(|mut config| {
+						config.nsfw_classifier = classifier;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
+		})
+		.procedure("setRedactionMode", {
+			R.with2(authorized()).mutation(|ctx, enabled: bool| async move {
+				ctx.config
+					.write(|mut config| {
+						config.redaction_mode = enabled;
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})?;
+
+				for library in ctx.library_manager.get_all_libraries().await {
+					invalidate_query!(library, "search.paths");
+					invalidate_query!(library, "search.objects");
+					invalidate_query!(library, "files.get");
+				}
+
+				Ok(())
+			})
+		})
+		.procedure("resources", {
+			#[derive(Serialize, Type)]
+			pub struct NodeResources {
+				/// The profile currently in effect, can differ from the configured one while on battery
+				pub profile: ResourceProfile,
+				pub on_battery: bool,
+				pub limits: ResourceLimits,
+			}
+
+			R.with2(authorized()).query(|ctx, _: ()| async move {
+				let profile = ctx.resources.profile().await;
+
+				Ok(NodeResources {
+					profile,
+					on_battery: ctx.resources.on_battery(),
+					limits: profile.limits(),
 				})
-				.map(|_| ())
+			})
+		})
+		.procedure("setResourceProfile", {
+			#[derive(Deserialize, Type)]
+			pub struct SetResourceProfileArgs {
+				pub profile: ResourceProfile,
+				pub low_power_on_battery: Option<bool>,
+			}
+
+			R.with2(authorized()).mutation(|ctx, args: SetResourceProfileArgs| async move {
+				ctx.config
+					.write(|mut config| {
+						config.resource_profile = args.profile;
+						if let Some(low_power_on_battery) = args.low_power_on_battery {
+							config.low_power_on_battery = low_power_on_battery;
+						}
+					})
+					.await
+					.map_err(|err| {
+						error!("Failed to write config: {}", err);
+						rspc::Error::new(
+							ErrorCode::InternalServerError,
+							"error updating config".into(),
+						)
+					})
+					.map(|_| ())
+			})
 		})
-	})
 }
@@ -0,0 +1,91 @@
+use crate::{
+	object::{
+		metadata_io::{export_metadata, MetadataFormat, MetadataImportArgs},
+		nsfw::{
+			is_restricted, lift_restriction, list_restrictions, restrict, NsfwClassifierJobInit,
+		},
+		patch::{patch_objects, ObjectPatch},
+	},
+	prisma::object,
+};
+
+use rspc::alpha::AlphaRouter;
+use serde::Deserialize;
+use specta::Type;
+use uuid::Uuid;
+
+use super::{search::ObjectFilterArgs, utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("patchMany", {
+			#[derive(Type, Deserialize)]
+			pub struct PatchManyArgs {
+				pub object_ids: Vec<object::id::Type>,
+				pub patch: ObjectPatch,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: PatchManyArgs| async move {
+					Ok(patch_objects(&library, args.object_ids, args.patch).await?)
+				})
+		})
+		.procedure("exportMetadata", {
+			#[derive(Type, Deserialize)]
+			pub struct ExportMetadataArgs {
+				#[serde(default)]
+				filter: ObjectFilterArgs,
+				format: MetadataFormat,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: ExportMetadataArgs| async move {
+					Ok(export_metadata(&library, args.filter.into_params(), args.format).await?)
+				})
+		})
+		.procedure("importMetadata", {
+			R.with2(library())
+				.mutation(|(_, library), args: MetadataImportArgs| async move {
+					Ok(args.import(&library).await?)
+				})
+		})
+		.procedure("classifyNsfw", {
+			R.with2(library())
+				.mutation(|(_, library), args: NsfwClassifierJobInit| async move {
+					library.spawn_job(args).await.map_err(Into::into)
+				})
+		})
+		.procedure("contentRestrictions", {
+			R.with2(library())
+				.query(|(_, library), _: ()| async move { Ok(list_restrictions(&library).await?) })
+		})
+		.procedure("isRestricted", {
+			R.with2(library())
+				.query(|(_, library), _: ()| async move { Ok(is_restricted(&library).await?) })
+		})
+		.procedure("restrictDevice", {
+			#[derive(Type, Deserialize)]
+			pub struct RestrictDeviceArgs {
+				pub node_id: Uuid,
+				/// Needed to lift the restriction
+				pub pin: String,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: RestrictDeviceArgs| async move {
+					Ok(restrict(&library, args.node_id, args.pin).await?)
+				})
+		})
+		.procedure("liftRestriction", {
+			#[derive(Type, Deserialize)]
+			pub struct LiftRestrictionArgs {
+				pub node_id: Uuid,
+				pub pin: String,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: LiftRestrictionArgs| async move {
+					Ok(lift_restriction(&library, args.node_id, args.pin).await?)
+				})
+		})
+}
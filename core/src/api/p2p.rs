@@ -1,18 +1,29 @@
 use rspc::{alpha::AlphaRouter, ErrorCode};
 use sd_p2p::PeerId;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use specta::Type;
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 use uuid::Uuid;
 
-use crate::p2p::P2PEvent;
+use crate::{
+	invalidate_query,
+	object::fs::scrub::MetadataField,
+	p2p::{
+		AddressBookEntryArgs, Handover, InboxArgs, P2PEvent, ShareLinkInfo, ShareManifest,
+		SharedFile, TrustLevel,
+	},
+	prisma::{file_path, node},
+};
 
-use super::{utils::library, Ctx, R};
+use super::{
+	utils::{authorized, library},
+	Ctx, R,
+};
 
 pub(crate) fn mount() -> AlphaRouter<Ctx> {
 	R.router()
 		.procedure("events", {
-			R.subscription(|ctx, _: ()| async move {
+			R.with2(authorized()).subscription(|ctx, _: ()| async move {
 				let mut rx = ctx.p2p.subscribe();
 				async_stream::stream! {
 					// TODO: Don't block subscription start
@@ -40,11 +51,16 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 			pub struct SpacedropArgs {
 				peer_id: PeerId,
 				file_path: Vec<String>,
+				/// Sends a copy with this metadata scrubbed out of it, the file staying as it is
+				#[serde(default)]
+				#[specta(optional)]
+				scrub_metadata: Vec<MetadataField>,
 			}
 
-			R.mutation(|ctx, args: SpacedropArgs| async move {
-				// TODO: Handle multiple files path and error if zero paths
-				ctx.p2p
+			R.with2(authorized())
+				.mutation(|ctx, args: SpacedropArgs| async move {
+					// TODO: Handle multiple files path and error if zero paths
+					ctx.p2p
 					.big_bad_spacedrop(
 						args.peer_id,
 						PathBuf::from(
@@ -52,30 +68,411 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 								.first()
 								.expect("https://linear.app/spacedriveapp/issue/ENG-625/spacedrop-multiple-files"),
 						),
+						&args.scrub_metadata,
 					)
 					.await
 					.map_err(|_| {
 						rspc::Error::new(ErrorCode::InternalServerError, "todo".to_string())
 					})
-			})
+				})
 		})
 		.procedure("acceptSpacedrop", {
-			R.mutation(|ctx, (id, path): (Uuid, Option<String>)| async move {
-				match path {
-					Some(path) => ctx.p2p.accept_spacedrop(id, path).await,
-					None => ctx.p2p.reject_spacedrop(id).await,
-				}
-			})
+			R.with2(authorized())
+				.mutation(|ctx, (id, path): (Uuid, Option<String>)| async move {
+					match path {
+						Some(path) => ctx.p2p.accept_spacedrop(id, path).await,
+						None => ctx.p2p.reject_spacedrop(id).await,
+					}
+				})
 		})
 		.procedure("spacedropProgress", {
-			R.subscription(|ctx, id: Uuid| async move {
-				ctx.p2p.spacedrop_progress(id).await.ok_or_else(|| {
-					rspc::Error::new(ErrorCode::BadRequest, "Spacedrop not found!".into())
+			R.with2(authorized())
+				.subscription(|ctx, id: Uuid| async move {
+					ctx.p2p.spacedrop_progress(id).await.ok_or_else(|| {
+						rspc::Error::new(ErrorCode::BadRequest, "Spacedrop not found!".into())
+					})
 				})
-			})
 		})
 		.procedure("pair", {
 			R.with2(library())
 				.mutation(|(ctx, lib), id: PeerId| async move { ctx.p2p.pair(id, lib) })
 		})
+		.procedure("startQrPairing", {
+			R.with2(library()).mutation(
+				|(ctx, library), trust_level: Option<TrustLevel>| async move {
+					ctx.p2p
+						.start_qr_pairing(library.id, trust_level.unwrap_or_default())
+						.await
+				},
+			)
+		})
+		.procedure("cancelQrPairing", {
+			R.with2(library())
+				.mutation(|(ctx, library), _: ()| async move {
+					ctx.p2p.cancel_qr_pairing(library.id).await
+				})
+		})
+		.procedure("acceptQrPairing", {
+			R.with2(authorized())
+				.mutation(
+					|ctx, code: String| async move { Ok(ctx.p2p.accept_qr_pairing(&code).await?) },
+				)
+		})
+		.procedure("pairedNodes", {
+			#[derive(Type, Serialize)]
+			pub struct PairedNode {
+				id: node::id::Type,
+				name: String,
+				platform: i32,
+				peer_id: Option<String>,
+				trust_level: TrustLevel,
+			}
+
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(library
+					.db
+					.node()
+					.find_many(vec![node::id::not(library.node_local_id)])
+					.select(node::select!({ id name platform node_peer_id trust_level }))
+					.exec()
+					.await?
+					.into_iter()
+					.map(|node| PairedNode {
+						id: node.id,
+						name: node.name,
+						platform: node.platform,
+						peer_id: node.node_peer_id,
+						trust_level: TrustLevel::from_db(node.trust_level),
+					})
+					.collect::<Vec<_>>())
+			})
+		})
+		.procedure("setTrustLevel", {
+			#[derive(Type, Deserialize)]
+			pub struct SetTrustLevelArgs {
+				node_id: node::id::Type,
+				trust_level: TrustLevel,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: SetTrustLevelArgs| async move {
+					if args.node_id == library.node_local_id {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"can't change the trust level of this node".into(),
+						));
+					}
+
+					library
+						.db
+						.node()
+						.update(
+							node::id::equals(args.node_id),
+							vec![node::trust_level::set(Some(args.trust_level as i32))],
+						)
+						.exec()
+						.await?;
+
+					invalidate_query!(library, "p2p.pairedNodes");
+
+					Ok(())
+				})
+		})
+		.procedure("retireNode", {
+			#[derive(Type, Deserialize)]
+			pub struct RetireNodeArgs {
+				node_id: node::id::Type,
+				handover: Handover,
+			}
+
+			R.with2(library())
+				.mutation(|(ctx, library), args: RetireNodeArgs| async move {
+					Ok(ctx
+						.p2p
+						.retire_node(&library, args.node_id, args.handover)
+						.await?)
+				})
+		})
+		.procedure("createShareLink", {
+			#[derive(Type, Deserialize)]
+			pub struct CreateShareLinkArgs {
+				file_path_ids: Vec<file_path::id::Type>,
+				expires_in_secs: u32,
+				password: Option<String>,
+				max_downloads: Option<u32>,
+			}
+
+			#[derive(Type, Serialize)]
+			pub struct CreatedShareLink {
+				link: ShareLinkInfo,
+				url: String,
+			}
+
+			R.with2(library())
+				.mutation(|(ctx, library), args: CreateShareLinkArgs| async move {
+					let Some(base_url) = ctx.config.get().await.share_links_base_url else {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"no base URL configured for share links".into(),
+						));
+					};
+
+					if args.file_path_ids.is_empty() {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"no files to share".into(),
+						));
+					}
+
+					let file_paths = library
+						.db
+						.file_path()
+						.find_many(vec![file_path::id::in_vec(args.file_path_ids.clone())])
+						.exec()
+						.await?;
+					let mut full_paths = library.get_file_paths(args.file_path_ids.clone()).await?;
+
+					let mut files = Vec::with_capacity(args.file_path_ids.len());
+					for id in args.file_path_ids {
+						let not_a_file = || {
+							rspc::Error::new(
+								ErrorCode::BadRequest,
+								format!("can't share file path <id='{id}'>, it isn't a file on this node"),
+							)
+						};
+
+						let file_path = file_paths
+							.iter()
+							.find(|file_path| file_path.id == id)
+							.ok_or_else(not_a_file)?;
+						let location_id = file_path.location_id.ok_or_else(not_a_file)?;
+						if file_path.is_dir.unwrap_or(false) {
+							return Err(not_a_file());
+						}
+
+						// They would be downloadable by anyone with the link, whether they're locked or not
+						if library.private_locations.is_private(location_id).await {
+							return Err(rspc::Error::new(
+								ErrorCode::Forbidden,
+								format!(
+									"can't share file path <id='{id}'>, its location is private"
+								),
+							));
+						}
+
+						let full_path = full_paths.remove(&id).flatten().ok_or_else(not_a_file)?;
+
+						files.push(SharedFile {
+							library_id: library.id,
+							location_id,
+							file_path_id: id,
+							name: full_path
+								.file_name()
+								.map(|name| name.to_string_lossy().into_owned())
+								.unwrap_or_default(),
+						});
+					}
+
+					let link = ctx
+						.p2p
+						.share_links
+						.create(
+							files,
+							Duration::from_secs(args.expires_in_secs.into()),
+							args.password.filter(|password| !password.is_empty()),
+							args.max_downloads,
+						)
+						.await
+						.map_err(|e| {
+							rspc::Error::with_cause(
+								ErrorCode::InternalServerError,
+								"failed to create the share link".into(),
+								e,
+							)
+						})?;
+
+					Ok(CreatedShareLink {
+						url: format!("{base_url}/share/{}", link.token),
+						link,
+					})
+				})
+		})
+		.procedure("compressionStats", {
+			R.with2(authorized())
+				.query(|ctx, _: ()| async move { ctx.p2p.connection_stats.list().await })
+		})
+		.procedure("connectionSecurity", {
+			R.with2(authorized()).query(|ctx, _: ()| async move {
+				ctx.p2p.verified_peers.summary(&ctx.p2p.manager).await
+			})
+		})
+		.procedure("listAddressBook", {
+			R.with2(authorized())
+				.query(|ctx, _: ()| async move { ctx.p2p.address_book.list().await })
+		})
+		.procedure("addToAddressBook", {
+			R.with2(authorized())
+				.mutation(|ctx, args: AddressBookEntryArgs| async move {
+					Ok(ctx.p2p.address_book.add(args).await?)
+				})
+		})
+		.procedure("updateAddressBookEntry", {
+			#[derive(Type, Deserialize)]
+			pub struct UpdateAddressBookEntryArgs {
+				id: Uuid,
+				entry: AddressBookEntryArgs,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: UpdateAddressBookEntryArgs| async move {
+					Ok(ctx.p2p.address_book.update(args.id, args.entry).await?)
+				})
+		})
+		.procedure("removeFromAddressBook", {
+			R.with2(authorized())
+				.mutation(|ctx, id: Uuid| async move { Ok(ctx.p2p.address_book.remove(id).await?) })
+		})
+		.procedure("listShareLinks", {
+			R.with2(authorized())
+				.query(|ctx, _: ()| async move { ctx.p2p.share_links.list().await })
+		})
+		.procedure("createManifest", {
+			#[derive(Type, Deserialize)]
+			pub struct CreateManifestArgs {
+				name: String,
+				file_path_ids: Vec<file_path::id::Type>,
+				/// Where the manifest file is written
+				destination: PathBuf,
+			}
+
+			R.with2(library())
+				.mutation(|(ctx, library), args: CreateManifestArgs| async move {
+					let manifest = ctx
+						.p2p
+						.create_manifest(&library, args.name, args.file_path_ids)
+						.await?;
+					manifest.write(&args.destination).await?;
+
+					invalidate_query!(library, "p2p.publishedManifests");
+
+					Ok(manifest)
+				})
+		})
+		.procedure("verifyManifest", {
+			R.with2(authorized()).query(|_, path: PathBuf| async move {
+				Ok(ShareManifest::read(&path).await?.summary()?)
+			})
+		})
+		.procedure("publishManifest", {
+			R.with2(authorized())
+				.mutation(|ctx, path: PathBuf| async move {
+					let manifest = ShareManifest::read(&path).await?;
+					ctx.p2p.publish_manifest(&manifest).await?;
+
+					Ok(manifest.summary()?)
+				})
+		})
+		.procedure("fetchManifest", {
+			#[derive(Type, Deserialize)]
+			pub struct FetchManifestArgs {
+				path: PathBuf,
+				destination: PathBuf,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: FetchManifestArgs| async move {
+					let manifest = ShareManifest::read(&args.path).await?;
+
+					Ok(ctx.p2p.fetch_manifest(&manifest, &args.destination).await?)
+				})
+		})
+		.procedure("publishedManifests", {
+			R.with2(authorized())
+				.query(|ctx, _: ()| async move { ctx.p2p.published_manifests.list().await })
+		})
+		.procedure("revokeManifest", {
+			R.with2(authorized()).mutation(|ctx, id: Uuid| async move {
+				if ctx.p2p.published_manifests.revoke(id).await {
+					Ok(())
+				} else {
+					Err(rspc::Error::new(
+						ErrorCode::NotFound,
+						"Manifest not found!".into(),
+					))
+				}
+			})
+		})
+		.procedure("inboxes", {
+			R.with2(library())
+				.query(
+					|(ctx, library), _: ()| async move { Ok(ctx.p2p.inboxes.list(library.id).await) },
+				)
+		})
+		.procedure("createInbox", {
+			R.with2(library())
+				.mutation(|(ctx, library), args: InboxArgs| async move {
+					let inbox = ctx.p2p.inboxes.create(&library, args).await?;
+
+					invalidate_query!(library, "p2p.inboxes");
+
+					Ok(inbox)
+				})
+		})
+		.procedure("updateInbox", {
+			#[derive(Type, Deserialize)]
+			pub struct UpdateInboxArgs {
+				id: Uuid,
+				inbox: InboxArgs,
+			}
+
+			R.with2(library())
+				.mutation(|(ctx, library), args: UpdateInboxArgs| async move {
+					let inbox = ctx
+						.p2p
+						.inboxes
+						.update(&library, args.id, args.inbox)
+						.await?;
+					// The name of the receiving inbox is advertised to other devices
+					ctx.p2p.update_metadata(&ctx.config).await;
+
+					invalidate_query!(library, "p2p.inboxes");
+
+					Ok(inbox)
+				})
+		})
+		.procedure("deleteInbox", {
+			R.with2(library())
+				.mutation(|(ctx, library), id: Uuid| async move {
+					ctx.p2p.inboxes.delete(library.id, id).await?;
+					ctx.p2p.update_metadata(&ctx.config).await;
+
+					invalidate_query!(library, "p2p.inboxes");
+
+					Ok(())
+				})
+		})
+		.procedure("setReceiveInbox", {
+			R.with2(library())
+				.mutation(|(ctx, library), id: Option<Uuid>| async move {
+					ctx.p2p.inboxes.set_receiving(id).await?;
+					ctx.p2p.update_metadata(&ctx.config).await;
+
+					invalidate_query!(library, "p2p.inboxes");
+
+					Ok(())
+				})
+		})
+		.procedure("revokeShareLink", {
+			R.with2(authorized())
+				.mutation(|ctx, token: String| async move {
+					if ctx.p2p.share_links.revoke(&token).await {
+						Ok(())
+					} else {
+						Err(rspc::Error::new(
+							ErrorCode::NotFound,
+							"Share link not found!".into(),
+						))
+					}
+				})
+		})
 }
@@ -3,17 +3,27 @@ use crate::{
 		locations::{file_path_with_object, object_with_file_paths, ExplorerItem},
 		utils::library,
 	},
-	library::{Category, Library},
+	library::{quick_open::quick_open, Category, Library},
 	location::{
 		file_path_helper::{check_file_path_exists, IsolatedFilePathData},
-		find_location, LocationError,
+		find_location,
+		redaction::Redaction,
+		LocationError,
 	},
-	object::preview::get_thumb_key,
-	prisma::{self, file_path, location, object, tag, tag_on_object},
+	object::{
+		access::frecent_object_ids,
+		custom_field::CustomFieldFilter,
+		fs::ghost::ReachableLocations,
+		groups::{companions_hidden, groups_of},
+		mail::{search_messages, MailSearchArgs},
+		nsfw,
+		stacks::{best_shots_only, stacks_of, PhotoStackSummary},
+	},
+	prisma::{self, file_path, location, media_data, object, tag, tag_on_object},
 	util::db::chain_optional_iter,
 };
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 
 use chrono::{DateTime, FixedOffset, Utc};
 use prisma_client_rust::{operator, or};
@@ -120,6 +130,9 @@ struct FilePathFilterArgs {
 	path: Option<String>,
 	#[specta(optional)]
 	object: Option<ObjectFilterArgs>,
+	/// Lists each group of files as its primary file alone
+	#[serde(default)]
+	group_files: bool,
 }
 
 #[derive(Deserialize, Type, Debug)]
@@ -139,12 +152,21 @@ struct FilePathSearchArgs {
 #[serde(rename_all = "camelCase")]
 enum ObjectSearchOrdering {
 	DateAccessed(SortOrder),
+	DatePlayed(SortOrder),
+	BookTitle(SortOrder),
+	BookAuthors(SortOrder),
+	/// Books of a series are kept in order within it
+	BookSeries(SortOrder),
 }
 
 impl ObjectSearchOrdering {
 	fn get_sort_order(&self) -> prisma::SortOrder {
 		(*match self {
 			Self::DateAccessed(v) => v,
+			Self::DatePlayed(v) => v,
+			Self::BookTitle(v) => v,
+			Self::BookAuthors(v) => v,
+			Self::BookSeries(v) => v,
 		})
 		.into()
 	}
@@ -154,6 +176,13 @@ impl ObjectSearchOrdering {
 		use object::*;
 		match self {
 			Self::DateAccessed(_) => date_accessed::order(dir),
+			Self::DatePlayed(_) => date_played::order(dir),
+			Self::BookTitle(_) => book_data::order(vec![prisma::book_data::title::order(dir)]),
+			Self::BookAuthors(_) => book_data::order(vec![prisma::book_data::authors::order(dir)]),
+			Self::BookSeries(_) => book_data::order(vec![
+				prisma::book_data::series::order(dir),
+				prisma::book_data::series_index::order(dir),
+			]),
 		}
 	}
 }
@@ -180,27 +209,39 @@ impl ObjectHiddenFilter {
 
 #[derive(Deserialize, Type, Debug, Default)]
 #[serde(rename_all = "camelCase")]
-struct ObjectFilterArgs {
+pub(super) struct ObjectFilterArgs {
 	#[specta(optional)]
 	favorite: Option<bool>,
 	#[serde(default)]
 	hidden: ObjectHiddenFilter,
 	#[specta(optional)]
 	date_accessed: Option<MaybeNot<Option<chrono::DateTime<FixedOffset>>>>,
+	/// `ObjectKind`s and custom kinds, see `object::custom_kind`
 	#[serde(default)]
 	kind: BTreeSet<i32>,
 	#[serde(default)]
 	tags: Vec<i32>,
 	#[specta(optional)]
 	category: Option<Category>,
+	/// Conditions on custom fields, which must all hold, see `object::custom_field`
+	#[serde(default)]
+	custom_fields: Vec<CustomFieldFilter>,
+	/// Objects never flagged count as not NSFW, see `object::nsfw`
+	#[specta(optional)]
+	nsfw: Option<bool>,
+	/// Videos and audio with a position to resume playback from, see `object::playback`
+	#[specta(optional)]
+	in_progress: Option<bool>,
 }
 
 impl ObjectFilterArgs {
-	fn into_params(self) -> Vec<object::WhereParam> {
+	pub(super) fn into_params(self) -> Vec<object::WhereParam> {
 		use object::*;
 
 		chain_optional_iter(
-			[],
+			self.custom_fields
+				.into_iter()
+				.map(CustomFieldFilter::to_param),
 			[
 				self.hidden.to_param(),
 				self.favorite.map(Some).map(favorite::equals),
@@ -214,6 +255,20 @@ impl ObjectFilterArgs {
 					tags::some(vec![tags_on_object])
 				}),
 				self.category.map(Category::to_where_param),
+				self.nsfw.map(|flagged| {
+					if flagged {
+						nsfw::equals(Some(true))
+					} else {
+						or![nsfw::equals(None), nsfw::equals(Some(false))]
+					}
+				}),
+				self.in_progress.map(|in_progress| {
+					if in_progress {
+						playback_position::not(None)
+					} else {
+						playback_position::equals(None)
+					}
+				}),
 			],
 		)
 	}
@@ -232,6 +287,31 @@ struct ObjectSearchArgs {
 	filter: ObjectFilterArgs,
 }
 
+#[derive(Deserialize, Type, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MediaTimelineArgs {
+	#[specta(optional)]
+	location_id: Option<location::id::Type>,
+	#[specta(optional)]
+	take: Option<i32>,
+	#[specta(optional)]
+	cursor: Option<object::id::Type>,
+}
+
+#[derive(Serialize, Type, Debug)]
+struct MediaTimelineItem {
+	date_taken: Option<DateTime<FixedOffset>>,
+	/// The stack this photo stands for, if it's the best shot of one
+	stack: Option<PhotoStackSummary>,
+	item: ExplorerItem,
+}
+
+#[derive(Serialize, Type, Debug)]
+struct MediaTimelineData {
+	items: Vec<MediaTimelineItem>,
+	cursor: Option<object::id::Type>,
+}
+
 pub fn mount() -> AlphaRouter<Ctx> {
 	R.router()
 		.procedure("paths", {
@@ -246,6 +326,11 @@ pub fn mount() -> AlphaRouter<Ctx> {
 					let Library { db, .. } = &library;
 
 					let location = if let Some(location_id) = filter.location_id {
+						library
+							.private_locations
+							.ensure_unlocked(location_id)
+							.await?;
+
 						Some(
 							find_location(&library, location_id)
 								.exec()
@@ -259,7 +344,8 @@ pub fn mount() -> AlphaRouter<Ctx> {
 					let directory_materialized_path_str = match (filter.path, location) {
 						(Some(path), Some(location)) if !path.is_empty() && path != "/" => {
 							let parent_iso_file_path =
-								IsolatedFilePathData::from_relative_str(location.id, &path);
+								IsolatedFilePathData::from_relative_str(location.id, &path)
+									.normalized(library.config.file_name_normalization);
 							if !check_file_path_exists::<LocationError>(&parent_iso_file_path, db)
 								.await?
 							{
@@ -275,6 +361,9 @@ pub fn mount() -> AlphaRouter<Ctx> {
 						_ => None,
 					};
 
+					let visible = library.private_locations.visible_file_paths().await;
+					let visible_content = nsfw::visible_file_paths(&library).await?;
+
 					use file_path::*;
 
 					let params = chain_optional_iter(
@@ -286,6 +375,8 @@ pub fn mount() -> AlphaRouter<Ctx> {
 							.map(name::contains),
 						[
 							filter.location_id.map(Some).map(location_id::equals),
+							// Skips the file paths of deleted locations, until they're cleaned up
+							filter.location_id.is_none().then(|| location_id::not(None)),
 							filter.extension.map(Some).map(extension::equals),
 							filter.created_at.from.map(|v| date_created::gte(v.into())),
 							filter.created_at.to.map(|v| date_created::lte(v.into())),
@@ -297,6 +388,9 @@ pub fn mount() -> AlphaRouter<Ctx> {
 
 								(!params.is_empty()).then(|| object::is(params))
 							}),
+							filter.group_files.then(companions_hidden),
+							visible,
+							visible_content,
 						],
 					);
 
@@ -326,9 +420,25 @@ pub fn mount() -> AlphaRouter<Ctx> {
 						(paths, cursor)
 					};
 
+					let mut groups = groups_of(
+						db,
+						file_paths.iter().map(|file_path| file_path.id).collect(),
+					)
+					.await?;
+
+					let reachable = ReachableLocations::fetch(&library).await?;
+					let redaction = Redaction::fetch(&library).await?;
+
 					let mut items = Vec::with_capacity(file_paths.len());
 
-					for file_path in file_paths {
+					for mut file_path in file_paths {
+						let redacted = redaction.is_redacted(file_path.location_id);
+						redaction.redact_name(
+							file_path.location_id,
+							file_path.is_dir,
+							&mut file_path.name,
+						);
+
 						let thumbnail_exists_locally = if let Some(cas_id) = &file_path.cas_id {
 							library
 								.thumbnail_exists(cas_id)
@@ -340,7 +450,16 @@ pub fn mount() -> AlphaRouter<Ctx> {
 
 						items.push(ExplorerItem::Path {
 							has_local_thumbnail: thumbnail_exists_locally,
-							thumbnail_key: file_path.cas_id.as_ref().map(|i| get_thumb_key(i)),
+							thumbnail_key: file_path
+								.cas_id
+								.as_ref()
+								.map(|i| redaction.thumbnail_key(redacted, i)),
+							is_ghost: reachable.is_ghost(
+								file_path.location_id,
+								file_path.tiered_to_location_id,
+								file_path.compressed_at.is_some(),
+							),
+							group: groups.remove(&file_path.id),
 							item: file_path,
 						})
 					}
@@ -362,10 +481,11 @@ pub fn mount() -> AlphaRouter<Ctx> {
 
 					let take = take.unwrap_or(100);
 
-					let mut query = db
-						.object()
-						.find_many(filter.into_params())
-						.take(take as i64 + 1);
+					let mut params = filter.into_params();
+					params.extend(library.private_locations.visible_objects().await);
+					params.extend(nsfw::visible_objects(&library).await?);
+
+					let mut query = db.object().find_many(params).take(take as i64 + 1);
 
 					if let Some(order) = order {
 						query = query.order_by(order.into_param());
@@ -389,36 +509,241 @@ pub fn mount() -> AlphaRouter<Ctx> {
 						(objects, cursor)
 					};
 
+					let reachable = ReachableLocations::fetch(&library).await?;
+					let redaction = Redaction::fetch(&library).await?;
+
 					let mut items = Vec::with_capacity(objects.len());
 
 					for object in objects {
-						let cas_id = object
-							.file_paths
-							.iter()
-							.map(|fp| fp.cas_id.as_ref())
-							.find_map(|c| c);
-
-						let thumbnail_exists_locally = if let Some(cas_id) = cas_id {
-							library.thumbnail_exists(cas_id).await.map_err(|e| {
-								rspc::Error::with_cause(
-									ErrorCode::InternalServerError,
-									"Failed to check that thumbnail exists".to_string(),
-									e,
-								)
-							})?
-						} else {
-							false
+						items.push(
+							object_explorer_item(&library, &reachable, &redaction, object).await?,
+						);
+					}
+
+					Ok(SearchData { items, cursor })
+				},
+			)
+		})
+		.procedure("frecent", {
+			#[derive(Deserialize, Type, Debug)]
+			#[serde(rename_all = "camelCase")]
+			struct FrecentArgs {
+				#[specta(optional)]
+				take: Option<u32>,
+				#[serde(default)]
+				filter: ObjectFilterArgs,
+			}
+
+			R.with2(library())
+				.query(|(_, library), args: FrecentArgs| async move {
+					let mut params = args.filter.into_params();
+					params.extend(library.private_locations.visible_objects().await);
+					params.extend(nsfw::visible_objects(&library).await?);
+
+					let ids =
+						frecent_object_ids(&library, params, args.take.unwrap_or(20) as usize)
+							.await?;
+
+					let mut objects = library
+						.db
+						.object()
+						.find_many(vec![object::id::in_vec(ids.clone())])
+						.include(object_with_file_paths::include())
+						.exec()
+						.await?;
+
+					objects.sort_by_key(|object| ids.iter().position(|id| *id == object.id));
+
+					let reachable = ReachableLocations::fetch(&library).await?;
+					let redaction = Redaction::fetch(&library).await?;
+
+					let mut items = Vec::with_capacity(objects.len());
+
+					for object in objects {
+						items.push(
+							object_explorer_item(&library, &reachable, &redaction, object).await?,
+						);
+					}
+
+					Ok(items)
+				})
+		})
+		.procedure("quickOpen", {
+			#[derive(Deserialize, Type, Debug)]
+			struct QuickOpenArgs {
+				query: String,
+				#[specta(optional)]
+				take: Option<u32>,
+			}
+
+			R.with2(library())
+				.query(|(_, library), args: QuickOpenArgs| async move {
+					Ok(quick_open(&library, &args.query, args.take.unwrap_or(20) as usize).await?)
+				})
+		})
+		.procedure("mail", {
+			R.with2(library())
+				.query(|(_, library), args: MailSearchArgs| async move {
+					if let Some(location_id) = args.location_id {
+						library
+							.private_locations
+							.ensure_unlocked(location_id)
+							.await?;
+					}
+
+					let visible = library.private_locations.visible_file_paths().await;
+
+					Ok(search_messages(&library, args, visible).await?)
+				})
+		})
+		.procedure("mediaTimeline", {
+			R.with2(library()).query(
+				|(_, library),
+				 MediaTimelineArgs {
+				     location_id,
+				     take,
+				     cursor,
+				 }| async move {
+					let Library { db, .. } = &library;
+
+					if let Some(location_id) = location_id {
+						library
+							.private_locations
+							.ensure_unlocked(location_id)
+							.await?;
+					}
+
+					let take = take.unwrap_or(100);
+
+					// Shots of a stack other than its best one only show through the stack
+					let object_params = chain_optional_iter(
+						[best_shots_only()],
+						[
+							ObjectHiddenFilter::Exclude.to_param(),
+							location_id.map(|location_id| {
+								object::file_paths::some(vec![file_path::location_id::equals(
+									Some(location_id),
+								)])
+							}),
+							library.private_locations.visible_objects().await,
+							nsfw::visible_objects(&library).await?,
+						],
+					);
+
+					let mut query = db
+						.media_data()
+						.find_many(vec![
+							media_data::date_taken::not(None),
+							media_data::object::is(object_params),
+						])
+						.order_by(media_data::date_taken::order(prisma::SortOrder::Desc))
+						.order_by(media_data::id::order(prisma::SortOrder::Desc))
+						.select(media_data::select!({ id date_taken }))
+						.take(take as i64 + 1);
+
+					if let Some(cursor) = cursor {
+						query = query.cursor(media_data::id::equals(cursor));
+					}
+
+					let (photos, cursor) = {
+						let mut photos = query.exec().await?;
+
+						let cursor = (photos.len() as i32 > take)
+							.then(|| photos.pop())
+							.flatten()
+							.map(|photo| photo.id);
+
+						(photos, cursor)
+					};
+
+					let object_ids = photos.iter().map(|photo| photo.id).collect::<Vec<_>>();
+
+					let mut objects = db
+						.object()
+						.find_many(vec![object::id::in_vec(object_ids.clone())])
+						.include(object_with_file_paths::include())
+						.exec()
+						.await?
+						.into_iter()
+						.map(|object| (object.id, object))
+						.collect::<HashMap<_, _>>();
+					let mut stacks = stacks_of(db, object_ids).await?;
+
+					let reachable = ReachableLocations::fetch(&library).await?;
+					let redaction = Redaction::fetch(&library).await?;
+
+					let mut items = Vec::with_capacity(photos.len());
+
+					for photo in photos {
+						let Some(object) = objects.remove(&photo.id) else {
+							continue;
 						};
 
-						items.push(ExplorerItem::Object {
-							has_local_thumbnail: thumbnail_exists_locally,
-							thumbnail_key: cas_id.map(|i| get_thumb_key(i)),
-							item: object,
+						items.push(MediaTimelineItem {
+							date_taken: photo.date_taken,
+							stack: stacks.remove(&photo.id),
+							item: object_explorer_item(&library, &reachable, &redaction, object)
+								.await?,
 						});
 					}
 
-					Ok(SearchData { items, cursor })
+					Ok(MediaTimelineData { items, cursor })
 				},
 			)
 		})
 }
+
+async fn object_explorer_item(
+	library: &Library,
+	reachable: &ReachableLocations,
+	redaction: &Redaction,
+	mut object: object_with_file_paths::Data,
+) -> Result<ExplorerItem, rspc::Error> {
+	// The object is visible through another location, its private paths aren't
+	library
+		.private_locations
+		.retain_visible(&mut object.file_paths)
+		.await;
+
+	// Blurred as soon as one of its paths is in a sensitive location
+	let redacted = object
+		.file_paths
+		.iter()
+		.any(|file_path| redaction.is_redacted(file_path.location_id));
+	for file_path in &mut object.file_paths {
+		redaction.redact_name(file_path.location_id, file_path.is_dir, &mut file_path.name);
+	}
+
+	let cas_id = object
+		.file_paths
+		.iter()
+		.map(|fp| fp.cas_id.as_ref())
+		.find_map(|c| c);
+
+	let thumbnail_exists_locally = if let Some(cas_id) = cas_id {
+		library.thumbnail_exists(cas_id).await.map_err(|e| {
+			rspc::Error::with_cause(
+				ErrorCode::InternalServerError,
+				"Failed to check that thumbnail exists".to_string(),
+				e,
+			)
+		})?
+	} else {
+		false
+	};
+
+	let is_ghost = object.file_paths.iter().all(|file_path| {
+		reachable.is_ghost(
+			file_path.location_id,
+			file_path.tiered_to_location_id,
+			file_path.compressed_at.is_some(),
+		)
+	});
+
+	Ok(ExplorerItem::Object {
+		has_local_thumbnail: thumbnail_exists_locally,
+		thumbnail_key: cas_id.map(|i| redaction.thumbnail_key(redacted, i)),
+		is_ghost,
+		item: object,
+	})
+}
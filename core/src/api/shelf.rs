@@ -0,0 +1,62 @@
+use crate::{
+	library::shelf::{add_to_shelf, apply_shelf, list_shelf, remove_from_shelf, ShelfOperation},
+	prisma::file_path,
+};
+
+use rspc::alpha::AlphaRouter;
+use serde::Deserialize;
+use specta::Type;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library())
+				.query(|(_, library), client: String| async move {
+					Ok(list_shelf(&library, client).await?)
+				})
+		})
+		.procedure("add", {
+			#[derive(Type, Deserialize)]
+			pub struct ShelfAddArgs {
+				pub client: String,
+				pub file_path_ids: Vec<file_path::id::Type>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: ShelfAddArgs| async move {
+					Ok(add_to_shelf(&library, args.client, args.file_path_ids).await? as u32)
+				})
+		})
+		.procedure("remove", {
+			#[derive(Type, Deserialize)]
+			pub struct ShelfRemoveArgs {
+				pub client: String,
+				/// Empties the shelf when left out
+				#[specta(optional)]
+				pub file_path_ids: Option<Vec<file_path::id::Type>>,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: ShelfRemoveArgs| async move {
+					Ok(remove_from_shelf(&library, args.client, args.file_path_ids).await?)
+				})
+		})
+		.procedure("apply", {
+			#[derive(Type, Deserialize)]
+			pub struct ShelfApplyArgs {
+				pub client: String,
+				pub operation: ShelfOperation,
+				/// Empties the shelf once the operation is applied
+				#[serde(default)]
+				#[specta(optional)]
+				pub clear: bool,
+			}
+
+			R.with2(library())
+				.mutation(|(_, library), args: ShelfApplyArgs| async move {
+					Ok(apply_shelf(&library, args.client, args.operation, args.clear).await?)
+				})
+		})
+}
@@ -0,0 +1,45 @@
+use crate::{
+	library::sidebar::{
+		delete_sidebar_item, list_sidebar_items, reorder_sidebar_items, SidebarItemCreateArgs,
+		SidebarItemUpdateArgs,
+	},
+	prisma::sidebar_item,
+};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				Ok(list_sidebar_items(&library.db).await?)
+			})
+		})
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: SidebarItemCreateArgs| async move {
+					Ok(args.create(&library).await?)
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: SidebarItemUpdateArgs| async move {
+					Ok(args.update(&library).await?)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), id: sidebar_item::id::Type| async move {
+					Ok(delete_sidebar_item(&library, id).await?)
+				})
+		})
+		.procedure("reorder", {
+			R.with2(library()).mutation(
+				|(_, library), ids: Vec<sidebar_item::id::Type>| async move {
+					Ok(reorder_sidebar_items(&library, ids).await?)
+				},
+			)
+		})
+}
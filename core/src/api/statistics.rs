@@ -0,0 +1,19 @@
+use crate::location::treemap::{location_treemap, TreemapArgs};
+
+use rspc::alpha::AlphaRouter;
+
+use super::{utils::library, Ctx, R};
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router().procedure("treemap", {
+		R.with2(library())
+			.query(|(_, library), args: TreemapArgs| async move {
+				library
+					.private_locations
+					.ensure_unlocked(args.location_id)
+					.await?;
+
+				Ok(location_treemap(&library, args).await?)
+			})
+	})
+}
@@ -1,8 +1,14 @@
 use rspc::alpha::AlphaRouter;
+use serde::Deserialize;
+use specta::Type;
+use uuid::Uuid;
 
-use crate::sync::SyncMessage;
+use crate::sync::{SyncLogRetention, SyncMessage};
 
-use super::{utils::library, Ctx, R};
+use super::{
+	utils::{authorized, library},
+	Ctx, R,
+};
 
 pub(crate) fn mount() -> AlphaRouter<Ctx> {
 	R.router()
@@ -25,4 +31,29 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 			R.with2(library())
 				.query(|(_, library), _: ()| async move { Ok(library.sync.get_ops().await?) })
 		})
+		.procedure("setLogRetention", {
+			#[derive(Type, Deserialize)]
+			pub struct SetLogRetentionArgs {
+				pub id: Uuid,
+				pub retention: SyncLogRetention,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: SetLogRetentionArgs| async move {
+					Ok(ctx
+						.library_manager
+						.update_sync_log_retention(args.id, args.retention)
+						.await?)
+				})
+		})
+		.procedure("compact", {
+			R.with2(library())
+				.mutation(|(_, library), _: ()| async move {
+					let Some(max_age) = library.config.sync_log_retention.max_age() else {
+						return Ok(Default::default());
+					};
+
+					Ok(library.sync.compact(max_age).await?)
+				})
+		})
 }
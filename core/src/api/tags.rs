@@ -8,8 +8,13 @@ use uuid::Uuid;
 use crate::{
 	invalidate_query,
 	library::Library,
+	object::{
+		accent::normalize_emoji, os_metadata::write_object_finder_tags_or_log,
+		xmp::write_object_sidecars_or_log,
+	},
 	prisma::{tag, tag_on_object},
 	sync,
+	util::db::chain_optional_iter,
 };
 
 use super::{utils::library, Ctx, R};
@@ -50,12 +55,16 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 			pub struct TagCreateArgs {
 				pub name: String,
 				pub color: String,
+				#[specta(optional)]
+				pub emoji: Option<String>,
 			}
 
 			R.with2(library())
 				.mutation(|(_, library), args: TagCreateArgs| async move {
 					let Library { db, sync, .. } = &library;
 
+					let emoji = args.emoji.as_deref().map(normalize_emoji).transpose()?;
+
 					let pub_id = Uuid::new_v4().as_bytes().to_vec();
 
 					let created_tag = sync
@@ -68,6 +77,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 								[
 									(tag::name::NAME, json!(args.name)),
 									(tag::color::NAME, json!(args.color)),
+									(tag::emoji::NAME, json!(emoji)),
 								],
 							),
 							db.tag().create(
@@ -75,6 +85,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 								vec![
 									tag::name::set(Some(args.name)),
 									tag::color::set(Some(args.color)),
+									tag::emoji::set(emoji),
 								],
 							),
 						)
@@ -127,6 +138,9 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 							.await?;
 					}
 
+					write_object_finder_tags_or_log(&library, args.object_ids.clone()).await;
+					write_object_sidecars_or_log(&library, args.object_ids).await;
+
 					invalidate_query!(library, "tags.getForObject");
 
 					Ok(())
@@ -138,6 +152,9 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				pub id: i32,
 				pub name: Option<String>,
 				pub color: Option<String>,
+				/// Left as it is when left out, an empty one clearing it
+				#[specta(optional)]
+				pub emoji: Option<String>,
 			}
 
 			R.with2(library())
@@ -155,12 +172,22 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 							"Error finding tag in db".into(),
 						))?;
 
+					let emoji = args
+						.emoji
+						.map(|emoji| {
+							(!emoji.trim().is_empty())
+								.then(|| normalize_emoji(&emoji))
+								.transpose()
+						})
+						.transpose()?;
+
 					sync.write_ops(
 						db,
 						(
 							[
 								args.name.as_ref().map(|v| (tag::name::NAME, json!(v))),
 								args.color.as_ref().map(|v| (tag::color::NAME, json!(v))),
+								emoji.as_ref().map(|v| (tag::emoji::NAME, json!(v))),
 							]
 							.into_iter()
 							.flatten()
@@ -176,7 +203,10 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 							.collect(),
 							db.tag().update(
 								tag::id::equals(args.id),
-								vec![tag::name::set(args.name), tag::color::set(args.color)],
+								chain_optional_iter(
+									[tag::name::set(args.name), tag::color::set(args.color)],
+									[emoji.map(tag::emoji::set)],
+								),
 							),
 						),
 					)
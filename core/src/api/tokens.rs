@@ -0,0 +1,447 @@
+//! Scoped tokens for clients reaching a headless node over the network.
+//!
+//! Each token is a session of a device, which can only call the procedures its scope allows. The
+//! tokens themselves are never stored, only their hash, so they're shown once when issued. Until
+//! the first token is issued the API stays open, like it was before tokens existed. From then on
+//! it stays closed until an admin opens it again, even once every token is revoked.
+//!
+//! Tokens are checked where the requests come in, see the `server` app, which then runs the
+//! request within the scope of its token. The procedures check that scope again in their
+//! middleware, see `authorize_procedure`. Subscriptions are multiplexed over a single WebSocket
+//! connection, so only admin tokens can open one.
+
+use std::{
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rspc::{alpha::AlphaRouter, ErrorCode};
+use sd_crypto::types::Key;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, io, sync::RwLock};
+use uuid::Uuid;
+
+use super::{utils::authorized, Ctx, R};
+
+const API_TOKENS_FILE_NAME: &str = "api_tokens.json";
+const TOKEN_PREFIX: &str = "sdt_";
+
+/// Mutations of already running or queued jobs, starting new ones needs an admin token
+const JOB_CONTROL_PROCEDURES: &[&str] = &[
+	"jobs.pause",
+	"jobs.resume",
+	"jobs.forceRun",
+	"jobs.clear",
+	"jobs.clearAll",
+	"jobs.setSchedulePolicy",
+];
+
+tokio::task_local! {
+	/// The scope of the token a request came in with, set by the transports reaching the node over
+	/// the network. Requests made within the node itself, like the ones of the desktop app, have none.
+	pub static API_SCOPE: ApiScope;
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+	/// Queries and subscriptions only
+	ReadOnly,
+	/// Read access, plus pausing, resuming and scheduling jobs
+	JobControl,
+	/// Everything, including managing the tokens themselves
+	Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcedureKind {
+	Query,
+	Mutation,
+	Subscription,
+}
+
+impl ApiScope {
+	pub fn allows(self, kind: ProcedureKind, key: &str) -> bool {
+		if key.starts_with("apiTokens.") {
+			return self == Self::Admin;
+		}
+
+		match (self, kind) {
+			(Self::Admin, _) => true,
+			(_, ProcedureKind::Query | ProcedureKind::Subscription) => true,
+			(Self::JobControl, ProcedureKind::Mutation) => JOB_CONTROL_PROCEDURES.contains(&key),
+			(Self::ReadOnly, ProcedureKind::Mutation) => false,
+		}
+	}
+
+	pub fn authorize(self, kind: ProcedureKind, key: &str) -> Result<(), ApiTokenError> {
+		if !self.allows(kind, key) {
+			return Err(ApiTokenError::Forbidden(key.to_string()));
+		}
+
+		Ok(())
+	}
+}
+
+/// Checks the scope of the request calling the procedure at `key` allows it, it's called by the
+/// middleware of every procedure so a transport can't skip it
+pub(crate) fn authorize_procedure(
+	kind: &rspc::internal::ProcedureKind,
+	key: &str,
+) -> Result<(), rspc::Error> {
+	let kind = match kind {
+		rspc::internal::ProcedureKind::Query => ProcedureKind::Query,
+		rspc::internal::ProcedureKind::Mutation => ProcedureKind::Mutation,
+		rspc::internal::ProcedureKind::Subscription => ProcedureKind::Subscription,
+	};
+
+	match API_SCOPE.try_with(|scope| scope.authorize(kind, key)) {
+		Ok(result) => result.map_err(Into::into),
+		// Not called over the network, or over a WebSocket which only admin tokens can open
+		Err(_) => Ok(()),
+	}
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ApiTokenError {
+	#[error("missing API token")]
+	Missing,
+	#[error("invalid, expired or revoked API token")]
+	Invalid,
+	#[error("the token's scope doesn't allow calling '{0}'")]
+	Forbidden(String),
+}
+
+impl From<ApiTokenError> for rspc::Error {
+	fn from(err: ApiTokenError) -> Self {
+		let code = match err {
+			ApiTokenError::Missing | ApiTokenError::Invalid => ErrorCode::Unauthorized,
+			ApiTokenError::Forbidden(_) => ErrorCode::Forbidden,
+		};
+
+		rspc::Error::new(code, err.to_string())
+	}
+}
+
+/// A session, as listed to the user, it never contains the token
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct ApiSession {
+	pub id: Uuid,
+	pub scope: ApiScope,
+	/// Name given to the device when the token was issued
+	pub device_name: String,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: Option<DateTime<Utc>>,
+	/// Last use since the node started
+	#[serde(skip_deserializing)]
+	pub last_used_at: Option<DateTime<Utc>>,
+	/// The `User-Agent` of the last request
+	#[serde(skip_deserializing)]
+	pub last_user_agent: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredToken {
+	#[serde(flatten)]
+	session: ApiSession,
+	hash: String,
+}
+
+impl StoredToken {
+	fn is_expired(&self, now: DateTime<Utc>) -> bool {
+		self.session
+			.expires_at
+			.map_or(false, |expires_at| expires_at <= now)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StoredTokens {
+	/// Set when the first token is issued, the API is open to anyone while it's unset
+	enabled: bool,
+	tokens: Vec<StoredToken>,
+}
+
+#[derive(Debug)]
+pub struct ApiTokens {
+	path: PathBuf,
+	stored: RwLock<StoredTokens>,
+}
+
+impl ApiTokens {
+	/// Fails if the tokens can't be read, as the node must not start with its API open by mistake
+	pub(crate) async fn load(data_dir: &Path) -> io::Result<Self> {
+		let path = data_dir.join(API_TOKENS_FILE_NAME);
+
+		let stored = match fs::read(&path).await {
+			Ok(bytes) => serde_json::from_slice(&bytes)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => StoredTokens::default(),
+			Err(e) => return Err(e),
+		};
+
+		Ok(Self {
+			path,
+			stored: RwLock::new(stored),
+		})
+	}
+
+	pub async fn is_enabled(&self) -> bool {
+		self.stored.read().await.enabled
+	}
+
+	/// Opens the API to anyone or closes it to the holders of a token
+	pub async fn set_enabled(&self, enabled: bool) -> io::Result<()> {
+		let mut stored = self.stored.write().await;
+		stored.enabled = enabled;
+
+		self.save(&stored).await
+	}
+
+	/// Issues a new token, returned alongside its session as it can't be retrieved later
+	pub async fn issue(
+		&self,
+		scope: ApiScope,
+		device_name: String,
+		expires_in: Option<Duration>,
+	) -> io::Result<(String, ApiSession)> {
+		let token = format!("{TOKEN_PREFIX}{}", hex::encode(Key::generate().expose()));
+		let created_at = Utc::now();
+
+		let session = ApiSession {
+			id: Uuid::new_v4(),
+			scope,
+			device_name,
+			created_at,
+			expires_at: expires_in
+				.and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+				.map(|expires_in| created_at + expires_in),
+			last_used_at: None,
+			last_user_agent: None,
+		};
+
+		let mut stored = self.stored.write().await;
+		stored.enabled = true;
+		stored.tokens.retain(|token| !token.is_expired(created_at));
+		stored.tokens.push(StoredToken {
+			session: session.clone(),
+			hash: hash_token(&token),
+		});
+		self.save(&stored).await?;
+
+		Ok((token, session))
+	}
+
+	pub async fn list(&self) -> Vec<ApiSession> {
+		let now = Utc::now();
+
+		self.stored
+			.read()
+			.await
+			.tokens
+			.iter()
+			.filter(|token| !token.is_expired(now))
+			.map(|token| token.session.clone())
+			.collect()
+	}
+
+	pub async fn revoke(&self, id: Uuid) -> io::Result<bool> {
+		let mut stored = self.stored.write().await;
+
+		let len = stored.tokens.len();
+		stored.tokens.retain(|token| token.session.id != id);
+		if stored.tokens.len() == len {
+			return Ok(false);
+		}
+
+		self.save(&stored).await?;
+
+		Ok(true)
+	}
+
+	/// Gives the scope of `token`, recording the use in its session. Every request has the admin
+	/// scope while the API is open.
+	pub async fn authenticate(
+		&self,
+		token: Option<&str>,
+		user_agent: Option<&str>,
+	) -> Result<ApiScope, ApiTokenError> {
+		let mut stored = self.stored.write().await;
+		if !stored.enabled {
+			return Ok(ApiScope::Admin);
+		}
+
+		let hash = hash_token(token.ok_or(ApiTokenError::Missing)?);
+		let now = Utc::now();
+
+		let token = stored
+			.tokens
+			.iter_mut()
+			.find(|stored| stored.hash == hash)
+			.filter(|stored| !stored.is_expired(now))
+			.ok_or(ApiTokenError::Invalid)?;
+
+		token.session.last_used_at = Some(now);
+		token.session.last_user_agent = user_agent.map(ToString::to_string);
+
+		Ok(token.session.scope)
+	}
+
+	async fn save(&self, stored: &StoredTokens) -> io::Result<()> {
+		fs::write(
+			&self.path,
+			serde_json::to_vec(stored).expect("tokens are always serializable"),
+		)
+		.await
+	}
+}
+
+// Tokens are random, so a fast hash is enough
+fn hash_token(token: &str) -> String {
+	blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+pub(crate) fn mount() -> AlphaRouter<Ctx> {
+	R.router()
+		.procedure("list", {
+			R.with2(authorized())
+				.query(|ctx, _: ()| async move { Ok(ctx.api_tokens.list().await) })
+		})
+		.procedure("isEnabled", {
+			R.with2(authorized())
+				.query(|ctx, _: ()| async move { Ok(ctx.api_tokens.is_enabled().await) })
+		})
+		.procedure("setEnabled", {
+			R.with2(authorized())
+				.mutation(|ctx, enabled: bool| async move {
+					ctx.api_tokens.set_enabled(enabled).await.map_err(|e| {
+						rspc::Error::with_cause(
+							ErrorCode::InternalServerError,
+							"failed to save the API tokens".into(),
+							e,
+						)
+					})
+				})
+		})
+		.procedure("issue", {
+			#[derive(Deserialize, Type)]
+			pub struct IssueApiTokenArgs {
+				pub scope: ApiScope,
+				pub device_name: String,
+				/// `None` for a token which only expires when revoked
+				pub expires_in_secs: Option<u64>,
+			}
+
+			#[derive(Serialize, Type)]
+			pub struct IssuedApiToken {
+				/// Only ever shown here
+				pub token: String,
+				pub session: ApiSession,
+			}
+
+			R.with2(authorized())
+				.mutation(|ctx, args: IssueApiTokenArgs| async move {
+					let (token, session) = ctx
+						.api_tokens
+						.issue(
+							args.scope,
+							args.device_name,
+							args.expires_in_secs.map(Duration::from_secs),
+						)
+						.await
+						.map_err(|e| {
+							rspc::Error::with_cause(
+								ErrorCode::InternalServerError,
+								"failed to save the API token".into(),
+								e,
+							)
+						})?;
+
+					Ok(IssuedApiToken { token, session })
+				})
+		})
+		.procedure("revoke", {
+			R.with2(authorized()).mutation(|ctx, id: Uuid| async move {
+				let revoked = ctx.api_tokens.revoke(id).await.map_err(|e| {
+					rspc::Error::with_cause(
+						ErrorCode::InternalServerError,
+						"failed to save the API tokens".into(),
+						e,
+					)
+				})?;
+
+				if !revoked {
+					return Err(rspc::Error::new(
+						ErrorCode::NotFound,
+						"API token not found".into(),
+					));
+				}
+
+				Ok(())
+			})
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scopes() {
+		use ProcedureKind::*;
+
+		assert!(ApiScope::ReadOnly.allows(Query, "search.paths"));
+		assert!(ApiScope::ReadOnly.allows(Subscription, "jobs.progress"));
+		assert!(!ApiScope::ReadOnly.allows(Mutation, "jobs.pause"));
+		assert!(!ApiScope::ReadOnly.allows(Query, "apiTokens.list"));
+
+		assert!(ApiScope::JobControl.allows(Mutation, "jobs.pause"));
+		assert!(!ApiScope::JobControl.allows(Mutation, "jobs.rollback"));
+		assert!(!ApiScope::JobControl.allows(Mutation, "files.deleteFiles"));
+		assert!(!ApiScope::JobControl.allows(Mutation, "apiTokens.issue"));
+
+		assert!(ApiScope::Admin.allows(Mutation, "files.deleteFiles"));
+		assert!(ApiScope::Admin.allows(Mutation, "apiTokens.revoke"));
+	}
+
+	#[tokio::test]
+	async fn revoking_every_token_keeps_the_api_closed() {
+		let dir = tempfile::tempdir().unwrap();
+
+		let tokens = ApiTokens::load(dir.path()).await.unwrap();
+		assert_eq!(tokens.authenticate(None, None).await, Ok(ApiScope::Admin));
+
+		let (token, session) = tokens
+			.issue(ApiScope::ReadOnly, "phone".to_string(), None)
+			.await
+			.unwrap();
+		assert_eq!(
+			tokens.authenticate(Some(&token), None).await,
+			Ok(ApiScope::ReadOnly)
+		);
+		assert!(tokens.revoke(session.id).await.unwrap());
+
+		let tokens = ApiTokens::load(dir.path()).await.unwrap();
+		assert_eq!(
+			tokens.authenticate(None, None).await,
+			Err(ApiTokenError::Missing)
+		);
+		assert_eq!(
+			tokens.authenticate(Some(&token), None).await,
+			Err(ApiTokenError::Invalid)
+		);
+
+		tokens.set_enabled(false).await.unwrap();
+		assert_eq!(tokens.authenticate(None, None).await, Ok(ApiScope::Admin));
+	}
+
+	#[tokio::test]
+	async fn unreadable_tokens_fail_to_load() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::write(dir.path().join(API_TOKENS_FILE_NAME), b"not json").unwrap();
+
+		assert!(ApiTokens::load(dir.path()).await.is_err());
+	}
+}
@@ -0,0 +1,30 @@
+use rspc::alpha::{
+	unstable::{MwArgMapper, MwArgMapperMiddleware},
+	MwV3,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use specta::Type;
+
+use crate::api::{tokens::authorize_procedure, Ctx};
+
+pub(crate) struct NoArgs;
+impl MwArgMapper for NoArgs {
+	type Input<T> = T where T: Type + DeserializeOwned + 'static;
+	type State = ();
+
+	fn map<T: Serialize + DeserializeOwned + Type + 'static>(
+		arg: Self::Input<T>,
+	) -> (T, Self::State) {
+		(arg, ())
+	}
+}
+
+/// Checks the scope of the request allows calling the procedure, for the procedures which don't
+/// need a library. [`library()`](super::library) does the same for the ones which do.
+pub(crate) fn authorized() -> impl MwV3<Ctx, NewCtx = Ctx> {
+	MwArgMapperMiddleware::<NoArgs>::new().mount(|mw, ctx: Ctx, ()| async move {
+		authorize_procedure(&mw.req.kind, &mw.req.path)?;
+
+		Ok(mw.next(ctx))
+	})
+}
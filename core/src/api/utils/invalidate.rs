@@ -1,5 +1,7 @@
 use crate::api::{CoreEvent, Ctx, Router, R};
 
+use super::authorized;
+
 use async_stream::stream;
 use rspc::alpha::AlphaRouter;
 use serde::Serialize;
@@ -245,7 +247,7 @@ pub(crate) fn mount_invalidate() -> AlphaRouter<Ctx> {
 	}
 
 	r.procedure("listen", {
-		R.subscription(move |ctx, _: ()| {
+		R.with2(authorized()).subscription(move |ctx, _: ()| {
 			// This thread is used to deal with batching and deduplication.
 			// Their is only ever one of these management threads per Node but we spawn it like this so we can steal the event bus from the rspc context.
 			// Batching is important because when refetching data on the frontend rspc can fetch all invalidated queries in a single round trip.
@@ -9,7 +9,10 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use specta::Type;
 use uuid::Uuid;
 
-use crate::{api::Ctx, library::Library};
+use crate::{
+	api::{tokens::authorize_procedure, Ctx},
+	library::Library,
+};
 
 /// Can wrap a query argument to require it to contain a `library_id` and provide helpers for working with libraries.
 #[derive(Clone, Serialize, Deserialize, Type)]
@@ -32,6 +35,8 @@ impl MwArgMapper for LibraryArgsLike {
 
 pub(crate) fn library() -> impl MwV3<Ctx, NewCtx = (Ctx, Library)> {
 	MwArgMapperMiddleware::<LibraryArgsLike>::new().mount(|mw, ctx: Ctx, library_id| async move {
+		authorize_procedure(&mw.req.kind, &mw.req.path)?;
+
 		let library = ctx
 			.library_manager
 			.get_library(library_id)
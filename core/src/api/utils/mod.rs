@@ -2,9 +2,11 @@ use std::path::Path;
 
 use tokio::{fs, io};
 
+mod authorized;
 mod invalidate;
 mod library;
 
+pub(crate) use authorized::*;
 pub use invalidate::*;
 pub(crate) use library::*;
 
@@ -1,11 +1,66 @@
 use rspc::alpha::AlphaRouter;
 
-use crate::volume::get_volumes;
+use crate::{
+	api::CoreEvent,
+	volume::{
+		capabilities::{volume_capabilities, VolumeCapabilitiesArgs},
+		get_volumes,
+		health::{volume_health_history, VolumeHealthArgs},
+		storage_forecasts,
+	},
+};
 
-use super::{Ctx, R};
+use super::{
+	utils::{authorized, library},
+	Ctx, R,
+};
 
 pub(crate) fn mount() -> AlphaRouter<Ctx> {
-	R.router().procedure("list", {
-		R.query(|_, _: ()| async move { Ok(get_volumes()?) })
-	})
+	R.router()
+		.procedure("list", {
+			R.with2(authorized())
+				.query(|_, _: ()| async move { Ok(get_volumes()?) })
+		})
+		.procedure("capabilities", {
+			R.with2(authorized())
+				.query(|_, args: VolumeCapabilitiesArgs| async move {
+					Ok(volume_capabilities(args).await?)
+				})
+		})
+		.procedure("forecast", {
+			R.with2(library())
+				.query(|(_, library), _: ()| async move { Ok(storage_forecasts(&library).await?) })
+		})
+		.procedure("health", {
+			R.with2(library())
+				.query(|(_, library), args: VolumeHealthArgs| async move {
+					Ok(volume_health_history(&library.db, args).await?)
+				})
+		})
+		.procedure("storageAlerts", {
+			R.with2(authorized())
+				.subscription(|node, _: ()| async move {
+					let mut event_bus_rx = node.event_bus.0.subscribe();
+					async_stream::stream! {
+						while let Ok(event) = event_bus_rx.recv().await {
+							if let CoreEvent::StorageAlert(alert) = event {
+								yield alert;
+							}
+						}
+					}
+				})
+		})
+		.procedure("healthAlerts", {
+			R.with2(authorized())
+				.subscription(|node, _: ()| async move {
+					let mut event_bus_rx = node.event_bus.0.subscribe();
+					async_stream::stream! {
+						while let Ok(event) = event_bus_rx.recv().await {
+							if let CoreEvent::DriveHealthAlert(alert) = event {
+								yield alert;
+							}
+						}
+					}
+				})
+		})
 }
@@ -0,0 +1,238 @@
+//! What the benchmarks in `core/benches` need from the core, which they can't reach otherwise as
+//! they're built as a separate crate. Only compiled with the `bench` feature.
+//!
+//! The benchmarks are run with `cargo bench -p sd-core --features bench`. To compare a release
+//! against the previous one, save a baseline on the old one with `-- --save-baseline <version>`
+//! and run the new one with `-- --baseline <version>`, criterion then reports every change
+//! bigger than its noise threshold as a regression or an improvement.
+
+use crate::{
+	library::Library,
+	location::indexer::walk::walk as walk_location,
+	prisma::{file_path, location, node},
+	util::db::uuid_to_bytes,
+};
+
+use std::{mem, path::Path};
+
+use chrono::{TimeZone, Utc};
+use prisma_client_rust::QueryError;
+use uuid::Uuid;
+
+pub use crate::{
+	location::{
+		file_path_helper::IsolatedFilePathData,
+		indexer::{
+			rules::{IndexerRule, RulePerKind},
+			IndexerError,
+		},
+	},
+	object::cas::generate_cas_id,
+	util::vfs::{Filesystem, LocalFs, MemoryFs},
+};
+
+/// How many rows go in each insert, the same as a save step of the indexer
+const INSERT_BATCH_SIZE: usize = 1000;
+
+const NAMES: &[&str] = &[
+	"holiday", "invoice", "report", "IMG", "DSC", "notes", "backup", "draft", "final", "scan",
+];
+const EXTENSIONS: &[&str] = &["jpg", "png", "pdf", "txt", "mp4", "rs", "zip", "heic"];
+
+/// Walks `root` with the indexer's walker, without a database to compare against, and returns
+/// how many entries were accepted by the rules
+pub async fn walk(
+	fs: &impl Filesystem,
+	root: impl AsRef<Path>,
+	rules: &[IndexerRule],
+) -> Result<usize, IndexerError> {
+	let root = root.as_ref();
+
+	let walk_result = walk_location(
+		fs,
+		root,
+		rules,
+		|_, _| {},
+		|_| async { Ok(vec![]) },
+		|_, _| async { Ok(vec![]) },
+		|path, is_dir| IsolatedFilePathData::new(0, root, path, is_dir).map_err(Into::into),
+		u64::MAX,
+		None,
+	)
+	.await?;
+
+	Ok(walk_result.walked.count())
+}
+
+/// A [`MemoryFs`] with `dirs` directories under `root`, each holding `files_per_dir` small files
+pub fn memory_tree(root: impl AsRef<Path>, dirs: usize, files_per_dir: usize) -> MemoryFs {
+	let root = root.as_ref();
+	let mut names = Names::new(0);
+
+	let fs = MemoryFs::default();
+	fs.add_dir(root);
+	for dir in 0..dirs {
+		let dir_path = root.join(format!("dir {dir}"));
+		fs.add_dir(&dir_path);
+		for _ in 0..files_per_dir {
+			let (name, extension, size) = names.next_file();
+			fs.add_file(
+				dir_path.join(format!("{name}.{extension}")),
+				vec![0; size as usize % 4096],
+			);
+		}
+	}
+
+	fs
+}
+
+/// The shape of a library made up by [`generate_library`]
+#[derive(Debug, Clone)]
+pub struct SyntheticLibrary {
+	pub locations: usize,
+	pub files_per_location: usize,
+	pub files_per_dir: usize,
+	/// The same seed always generates the same names and sizes
+	pub seed: u64,
+}
+
+impl Default for SyntheticLibrary {
+	fn default() -> Self {
+		Self {
+			locations: 1,
+			files_per_location: 1_000_000,
+			files_per_dir: 1000,
+			seed: 0x5eed,
+		}
+	}
+}
+
+/// Fills a library with locations and file paths that don't exist on disk, to measure the queries
+/// of a big library without indexing one. The rows are written without sync operations, so they
+/// never leave this node. Returns the ids of the new locations.
+pub async fn generate_library(
+	library: &Library,
+	spec: &SyntheticLibrary,
+) -> Result<Vec<location::id::Type>, QueryError> {
+	let db = &library.db;
+	let mut names = Names::new(spec.seed);
+	let mut location_ids = Vec::with_capacity(spec.locations);
+
+	for location_idx in 0..spec.locations {
+		let location = db
+			.location()
+			.create(
+				uuid_to_bytes(Uuid::new_v4()),
+				vec![
+					location::name::set(Some(format!("Synthetic {location_idx}"))),
+					location::path::set(Some(format!("/synthetic/{location_idx}"))),
+					location::node::connect(node::id::equals(library.node_local_id)),
+				],
+			)
+			.exec()
+			.await?;
+
+		let files_per_dir = spec.files_per_dir.max(1);
+		let dirs = (spec.files_per_location + files_per_dir - 1) / files_per_dir;
+		let mut rows = Vec::with_capacity(INSERT_BATCH_SIZE);
+		let mut idx = 0;
+		for dir in 0..dirs {
+			let files = files_per_dir.min(spec.files_per_location - dir * files_per_dir);
+			for file in 0..=files {
+				// The directory itself first, then its files
+				rows.push(if file == 0 {
+					file_path_row(location.id, "/", format!("dir {dir}"), "", 0, true, idx)
+				} else {
+					let (name, extension, size) = names.next_file();
+					file_path_row(
+						location.id,
+						&format!("/dir {dir}/"),
+						name,
+						extension,
+						size,
+						false,
+						idx,
+					)
+				});
+				idx += 1;
+
+				if rows.len() == INSERT_BATCH_SIZE {
+					db.file_path()
+						.create_many(mem::take(&mut rows))
+						.exec()
+						.await?;
+				}
+			}
+		}
+
+		if !rows.is_empty() {
+			db.file_path().create_many(rows).exec().await?;
+		}
+
+		location_ids.push(location.id);
+	}
+
+	Ok(location_ids)
+}
+
+fn file_path_row(
+	location_id: location::id::Type,
+	materialized_path: &str,
+	name: String,
+	extension: &str,
+	size: u64,
+	is_dir: bool,
+	idx: i64,
+) -> file_path::CreateUnchecked {
+	// Spread over a year, so sorting by date isn't sorting by insertion order
+	let date = Utc
+		.timestamp_opt(1_600_000_000 + (idx * 7919) % 31_536_000, 0)
+		.single()
+		.unwrap_or_default();
+
+	file_path::create_unchecked(
+		uuid_to_bytes(Uuid::new_v4()),
+		vec![
+			file_path::location_id::set(Some(location_id)),
+			file_path::materialized_path::set(Some(materialized_path.to_string())),
+			file_path::name::set(Some(name)),
+			file_path::extension::set(Some(extension.to_string())),
+			file_path::is_dir::set(Some(is_dir)),
+			file_path::size_in_bytes::set(Some(size.to_string())),
+			file_path::date_created::set(Some(date.into())),
+			file_path::date_modified::set(Some(date.into())),
+		],
+	)
+}
+
+/// File names, extensions and sizes from a xorshift generator, so they're the same on every run
+struct Names {
+	state: u64,
+	count: u64,
+}
+
+impl Names {
+	fn new(seed: u64) -> Self {
+		Self {
+			// xorshift gets stuck on 0
+			state: seed | 1,
+			count: 0,
+		}
+	}
+
+	fn next(&mut self) -> u64 {
+		self.state ^= self.state << 13;
+		self.state ^= self.state >> 7;
+		self.state ^= self.state << 17;
+		self.state
+	}
+
+	fn next_file(&mut self) -> (String, &'static str, u64) {
+		let name = NAMES[self.next() as usize % NAMES.len()];
+		let extension = EXTENSIONS[self.next() as usize % EXTENSIONS.len()];
+		let size = self.next() % (64 * 1024 * 1024);
+		self.count += 1;
+
+		(format!("{name} {}", self.count), extension, size)
+	}
+}
@@ -1,13 +1,27 @@
 use crate::{
-	location::file_path_helper::{file_path_to_handle_custom_uri, IsolatedFilePathData},
+	library::{Library, LibraryManagerError},
+	location::{
+		file_path_helper::{file_path_to_handle_custom_uri, IsolatedFilePathData},
+		redaction::{blur_thumbnail, REDACTED_THUMBNAIL},
+	},
+	object::fs::{
+		compress::decompress_to_cache,
+		ghost::{retrieved_file_path, FileRetrieverJobInit, ReachableLocations},
+	},
+	p2p::{FileRequest, InboxError, InboxRefusal, RemoteFileError, ShareLinkError},
 	prisma::{file_path, location},
-	util::{db::*, error::FileIOError},
+	util::{
+		db::*,
+		error::FileIOError,
+		rate_limit::{ClientLimiter, LimitExceeded},
+	},
 	Node,
 };
 
 use std::{
 	io,
 	mem::take,
+	net::SocketAddr,
 	path::{Path, PathBuf},
 	str::FromStr,
 	sync::Arc,
@@ -23,22 +37,34 @@ use httpz::{
 };
 use mini_moka::sync::Cache;
 use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
 use prisma_client_rust::QueryError;
+use sd_p2p::PeerId;
 use thiserror::Error;
 use tokio::{
-	fs::File,
+	fs::{self, File},
 	io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+	task::block_in_place,
 };
-use tracing::error;
+use tracing::{debug, error};
 use uuid::Uuid;
 
 // This LRU cache allows us to avoid doing a DB lookup on every request.
 // The main advantage of this LRU Cache is for video files. Video files are fetch in multiple chunks and the cache prevents a DB lookup on every chunk reducing the request time from 15-25ms to 1-10ms.
-type MetadataCacheKey = (Uuid, file_path::id::Type);
+type MetadataCacheKey = (Uuid, location::id::Type, file_path::id::Type);
 type NameAndExtension = (PathBuf, String);
 static FILE_METADATA_CACHE: Lazy<Cache<MetadataCacheKey, NameAndExtension>> =
 	Lazy::new(|| Cache::new(100));
 
+/// How much of a remote file is sent for each range request, so seeking in media stays responsive
+const MAX_REMOTE_CHUNK_LEN: u64 = 2 * 1024 * 1024;
+
+static CLIENT_LIMITER: Lazy<ClientLimiter> = Lazy::new(ClientLimiter::default);
+
+/// Address of the peer a request came from, servers put it in the extensions of each request
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
 // TODO: We should listen to events when deleting or moving a location and evict the cache accordingly.
 // TODO: Probs use this cache in rspc queries too!
 
@@ -51,13 +77,51 @@ async fn handler(node: Arc<Node>, req: Request) -> Result<Response<Vec<u8>>, Han
 		.split('/')
 		.collect::<Vec<_>>();
 
+	let limits = node.config.get().await.custom_uri_limits;
+	let client = client_key(&req, limits.trust_forwarded_for);
+
+	if let Some(requests_per_minute) = limits.requests_per_minute {
+		CLIENT_LIMITER.check_request(&client, requests_per_minute)?;
+	}
+
+	// Held until the whole response is built, as that's when the content is read
+	let _stream = match (path.first(), limits.max_concurrent_streams) {
+		(Some(&"file" | &"share" | &"inbox"), Some(max_concurrent_streams)) => {
+			Some(CLIENT_LIMITER.open_stream(&client, max_concurrent_streams)?)
+		}
+		_ => None,
+	};
+
 	match path.first() {
 		Some(&"thumbnail") => handle_thumbnail(&node, &path, &req).await,
 		Some(&"file") => handle_file(&node, &path, &req).await,
+		Some(&"metrics") => handle_metrics(&node, &req).await,
+		Some(&"share") => handle_share(&node, &path, &req).await,
+		Some(&"inbox") => handle_inbox(&node, &path, &req).await,
 		_ => Err(HandleCustomUriError::BadRequest("Invalid operation!")),
 	}
 }
 
+/// Clients are told apart by the address they connect from, or by what a trusted proxy says it
+/// is. Requests without one come from the app itself.
+fn client_key(req: &Request, trust_forwarded_for: bool) -> String {
+	trust_forwarded_for
+		.then(|| {
+			req.headers()
+				.get("X-Forwarded-For")
+				.and_then(|value| value.to_str().ok())
+				.and_then(|value| value.split(',').next())
+				.map(|client| client.trim().to_string())
+		})
+		.flatten()
+		.or_else(|| {
+			req.extensions()
+				.get::<ClientAddr>()
+				.map(|ClientAddr(addr)| addr.ip().to_string())
+		})
+		.unwrap_or_else(|| "local".to_string())
+}
+
 async fn read_file(mut file: File, length: u64, start: Option<u64>) -> io::Result<Vec<u8>> {
 	let mut buf = Vec::with_capacity(length as usize);
 	if let Some(start) = start {
@@ -106,13 +170,38 @@ async fn handle_thumbnail(
 		));
 	}
 
+	// Thumbnails of sensitive locations are blurred while in redaction mode
+	let (redacted, key) = match &path[1..] {
+		[REDACTED_THUMBNAIL, key @ ..] => (true, key),
+		key => (false, key),
+	};
+
 	let mut thumbnail_path = node.config.data_directory().join("thumbnails");
 	// if we ever wish to support multiple levels of sharding, we need only supply more params here
-	for path_part in &path[1..] {
+	for path_part in key {
 		thumbnail_path = thumbnail_path.join(path_part);
 	}
 	let filename = thumbnail_path.with_extension("webp");
 
+	if redacted {
+		let webp = fs::read(&filename).await.map_err(|err| {
+			if err.kind() == io::ErrorKind::NotFound {
+				HandleCustomUriError::NotFound("file")
+			} else {
+				FileIOError::from((&filename, err)).into()
+			}
+		})?;
+
+		let body = block_in_place(|| blur_thumbnail(&webp))
+			.map_err(|e| FileIOError::from((&filename, e)))?;
+
+		return Ok(builder
+			.header("Content-Type", "image/webp")
+			.header("Content-Length", body.len())
+			.status(StatusCode::OK)
+			.body(if method == Method::HEAD { vec![] } else { body })?);
+	}
+
 	let file = File::open(&filename).await.map_err(|err| {
 		if err.kind() == io::ErrorKind::NotFound {
 			HandleCustomUriError::NotFound("file")
@@ -140,6 +229,370 @@ async fn handle_thumbnail(
 		})?)
 }
 
+async fn handle_metrics(
+	node: &Node,
+	req: &Request,
+) -> Result<Response<Vec<u8>>, HandleCustomUriError> {
+	let method = req.method();
+	let mut builder = Response::builder();
+	if let Some(response) = cors(method, &mut builder) {
+		return Ok(response?);
+	}
+
+	// Metrics are opt-in, so we pretend the endpoint doesn't exist until the user enables them
+	if !node.config.get().await.metrics_enabled {
+		return Err(HandleCustomUriError::NotFound("metrics"));
+	}
+
+	let body = node.metrics.render().into_bytes();
+
+	Ok(builder
+		.header("Content-Type", "text/plain; version=0.0.4")
+		.header("Content-Length", body.len())
+		.status(StatusCode::OK)
+		.body(if method == Method::HEAD { vec![] } else { body })?)
+}
+
+/// Serves the files of a share link, `share/<token>` downloads the file directly if the link only
+/// has one or lists them otherwise, with each one being at `share/<token>/<index>`.
+async fn handle_share(
+	node: &Node,
+	path: &[&str],
+	req: &Request,
+) -> Result<Response<Vec<u8>>, HandleCustomUriError> {
+	let method = req.method();
+	let mut builder = Response::builder();
+	if let Some(response) = cors(method, &mut builder) {
+		return Ok(response?);
+	}
+
+	let token = path
+		.get(1)
+		.ok_or(HandleCustomUriError::BadRequest("Missing share token!"))?;
+
+	let share_links = &node.p2p.share_links;
+
+	// The password form of browsers is exchanged for a session cookie, then they're sent back
+	if method == Method::POST {
+		let password = String::from_utf8_lossy(req.body())
+			.split('&')
+			.find_map(|pair| pair.strip_prefix("password="))
+			// Forms encode spaces as `+`
+			.map(|password| {
+				percent_decode_str(&password.replace('+', " "))
+					.decode_utf8_lossy()
+					.into_owned()
+			})
+			.unwrap_or_default();
+
+		return match share_links.start_session(token, password).await {
+			Ok(session) => {
+				if let Some(session) = session {
+					builder = builder.header(
+						"Set-Cookie",
+						format!(
+							"{}={session}; HttpOnly; Secure; SameSite=Strict",
+							share_cookie_name(token)
+						),
+					);
+				}
+
+				Ok(builder
+					.header("Location", *path.last().unwrap_or(token))
+					.status(StatusCode::SEE_OTHER)
+					.body(vec![])?)
+			}
+			Err(e) => share_link_error(builder, e),
+		};
+	}
+
+	// Other clients can send the password along with each request instead
+	let session = match req
+		.headers()
+		.get("X-Share-Password")
+		.and_then(|value| value.to_str().ok())
+	{
+		Some(password) => match share_links.start_session(token, password.to_string()).await {
+			Ok(session) => session,
+			Err(e) => return share_link_error(builder, e),
+		},
+		None => share_session(req, token),
+	};
+
+	let index = match path.get(2) {
+		Some(index) => index
+			.parse::<usize>()
+			.map_err(|_| HandleCustomUriError::BadRequest("Invalid file index!"))?,
+		None => {
+			let file_names = match share_links.file_names(token, session.as_deref()).await {
+				Ok(file_names) => file_names,
+				Err(e) => return share_link_error(builder, e),
+			};
+
+			if file_names.len() == 1 {
+				0
+			} else {
+				let items = file_names
+					.iter()
+					.enumerate()
+					.map(|(index, name)| {
+						format!(
+							"<li><a href=\"{token}/{index}\">{}</a></li>",
+							escape_html(name)
+						)
+					})
+					.collect::<String>();
+
+				return Ok(builder
+					.header("Content-Type", "text/html; charset=utf-8")
+					.status(StatusCode::OK)
+					.body(
+						format!("<!DOCTYPE html><title>Shared files</title><ul>{items}</ul>")
+							.into_bytes(),
+					)?);
+			}
+		}
+	};
+
+	// HEAD requests don't download anything, so they aren't counted
+	if method == Method::HEAD {
+		return match share_links.file_names(token, session.as_deref()).await {
+			Ok(_) => Ok(builder.status(StatusCode::OK).body(vec![])?),
+			Err(e) => share_link_error(builder, e),
+		};
+	}
+
+	let shared_file = match share_links
+		.take_download(token, index, session.as_deref())
+		.await
+	{
+		Ok(shared_file) => shared_file,
+		Err(e) => return share_link_error(builder, e),
+	};
+
+	let library = node
+		.library_manager
+		.get_library(shared_file.library_id)
+		.await
+		.ok_or(HandleCustomUriError::NotFound("file"))?;
+
+	// The location may have been made private since the link was created
+	if library
+		.private_locations
+		.is_private(shared_file.location_id)
+		.await
+	{
+		return Err(HandleCustomUriError::NotFound("file"));
+	}
+
+	let file_path = library
+		.get_file_paths(vec![shared_file.file_path_id])
+		.await?
+		.remove(&shared_file.file_path_id)
+		.flatten()
+		.ok_or(HandleCustomUriError::NotFound("file"))?;
+
+	let file = File::open(&file_path).await.map_err(|err| {
+		if err.kind() == io::ErrorKind::NotFound {
+			HandleCustomUriError::NotFound("file")
+		} else {
+			FileIOError::from((&file_path, err)).into()
+		}
+	})?;
+
+	let content_length = file
+		.metadata()
+		.await
+		.map_err(|e| FileIOError::from((&file_path, e)))?
+		.len();
+
+	Ok(builder
+		.header("Content-Type", "application/octet-stream")
+		.header(
+			"Content-Disposition",
+			format!(
+				"attachment; filename=\"{}\"",
+				shared_file.name.replace('"', "'")
+			),
+		)
+		.header("Content-Length", content_length)
+		.status(StatusCode::OK)
+		.body(
+			read_file(file, content_length, None)
+				.await
+				.map_err(|e| FileIOError::from((&file_path, e)))?,
+		)?)
+}
+
+fn share_cookie_name(token: &str) -> String {
+	format!("sd_share_{token}")
+}
+
+/// The session given to a browser for the password of a share link
+fn share_session(req: &Request, token: &str) -> Option<String> {
+	let name = share_cookie_name(token);
+
+	req.headers()
+		.get_all("Cookie")
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|cookies| cookies.split(';'))
+		.find_map(|cookie| {
+			cookie
+				.trim()
+				.strip_prefix(name.as_str())
+				.and_then(|cookie| cookie.strip_prefix('='))
+				.map(ToString::to_string)
+		})
+}
+
+fn share_link_error(
+	builder: Builder,
+	error: ShareLinkError,
+) -> Result<Response<Vec<u8>>, HandleCustomUriError> {
+	match error {
+		ShareLinkError::NotFound => Err(HandleCustomUriError::NotFound("share link")),
+		// People opening the link in a browser get a prompt for the password, which is posted so
+		// it never ends up in the URL
+		ShareLinkError::PasswordRequired | ShareLinkError::WrongPassword => Ok(builder
+			.header("Content-Type", "text/html; charset=utf-8")
+			.status(StatusCode::UNAUTHORIZED)
+			.body(
+				format!(
+					"<!DOCTYPE html><title>Password required</title><p>{error}</p>\
+					<form method=\"post\"><input type=\"password\" name=\"password\" autofocus>\
+					<button type=\"submit\">Open</button></form>"
+				)
+				.into_bytes(),
+			)?),
+		ShareLinkError::TooManyAttempts => Ok(builder
+			.header("Content-Type", "text/plain")
+			.status(StatusCode::TOO_MANY_REQUESTS)
+			.body(error.to_string().into_bytes())?),
+		ShareLinkError::Hashing => Ok(builder
+			.header("Content-Type", "text/plain")
+			.status(StatusCode::INTERNAL_SERVER_ERROR)
+			.body(b"Internal Server Error".to_vec())?),
+	}
+}
+
+/// Lets anyone with the link of an inbox upload files into it, `GET inbox/<token>` is a form for
+/// browsers and `POST inbox/<token>?name=<file name>` takes the content of the file as the body.
+async fn handle_inbox(
+	node: &Node,
+	path: &[&str],
+	req: &Request,
+) -> Result<Response<Vec<u8>>, HandleCustomUriError> {
+	let method = req.method();
+	let mut builder = Response::builder();
+	if let Some(response) = cors(method, &mut builder) {
+		return Ok(response?);
+	}
+
+	let token = path
+		.get(1)
+		.ok_or(HandleCustomUriError::BadRequest("Missing inbox token!"))?;
+
+	if !node.p2p.inboxes.has_upload_token(token).await {
+		return Err(HandleCustomUriError::NotFound("inbox"));
+	}
+
+	if method != Method::POST {
+		return Ok(builder
+			.header("Content-Type", "text/html; charset=utf-8")
+			.status(StatusCode::OK)
+			.body(if method == Method::HEAD {
+				vec![]
+			} else {
+				INBOX_UPLOAD_FORM.as_bytes().to_vec()
+			})?);
+	}
+
+	let name = req
+		.uri()
+		.query()
+		.and_then(|query| {
+			query
+				.split('&')
+				.find_map(|pair| pair.strip_prefix("name="))
+				.map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+		})
+		.or_else(|| {
+			req.headers()
+				.get("X-File-Name")
+				.and_then(|value| value.to_str().ok())
+				.map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+		})
+		.ok_or(HandleCustomUriError::BadRequest("Missing file name!"))?;
+
+	// The policy is checked against the declared size, before the body is looked at
+	let Some(content_length) = req
+		.headers()
+		.get("Content-Length")
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<u64>().ok())
+	else {
+		return Ok(builder.status(StatusCode::LENGTH_REQUIRED).body(vec![])?);
+	};
+
+	let drop = match node
+		.p2p
+		.inboxes
+		.for_upload(token, &name, content_length)
+		.await
+	{
+		Ok(drop) => drop,
+		Err(refusal) => {
+			let status = match refusal {
+				InboxRefusal::NotFound => StatusCode::NOT_FOUND,
+				InboxRefusal::InvalidName => StatusCode::BAD_REQUEST,
+				InboxRefusal::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+				InboxRefusal::NotAllowed(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+				InboxRefusal::Staging(e) => return Err(e.into()),
+			};
+
+			return Ok(builder
+				.header("Content-Type", "text/plain")
+				.status(status)
+				.body(refusal.to_string().into_bytes())?);
+		}
+	};
+
+	let body = req.body();
+	if body.len() as u64 != content_length {
+		return Err(HandleCustomUriError::BadRequest(
+			"Body doesn't match its Content-Length!",
+		));
+	}
+
+	fs::write(drop.staged_path(), body)
+		.await
+		.map_err(|e| FileIOError::from((drop.staged_path(), e)))?;
+
+	node.p2p.inboxes.receive(drop).await?;
+
+	Ok(builder.status(StatusCode::CREATED).body(vec![])?)
+}
+
+const INBOX_UPLOAD_FORM: &str = "<!DOCTYPE html><title>Upload files</title>\
+	<input type=\"file\" id=\"files\" multiple><p id=\"status\"></p>\
+	<script>document.getElementById('files').onchange = async (e) => {\
+		const status = document.getElementById('status');\
+		for (const file of e.target.files) {\
+			status.textContent = 'Uploading ' + file.name + '...';\
+			const res = await fetch('?name=' + encodeURIComponent(file.name), { method: 'POST', body: file });\
+			status.textContent = file.name + ': ' + (res.ok ? 'uploaded' : await res.text());\
+		}\
+	};</script>";
+
+fn escape_html(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
 async fn handle_file(
 	node: &Node,
 	path: &[&str],
@@ -172,18 +625,23 @@ async fn handle_file(
 			HandleCustomUriError::BadRequest("Invalid number of parameters. Missing file_path_id!")
 		})?;
 
-	let lru_cache_key = (library_id, file_path_id);
+	let library = node
+		.library_manager
+		.get_library(library_id)
+		.await
+		.ok_or_else(|| HandleCustomUriError::NotFound("library"))?;
+
+	// Checked before the cache, so paths cached while a location was unlocked stop being served
+	if library.private_locations.is_locked(location_id).await {
+		return Err(HandleCustomUriError::NotFound("object"));
+	}
+
+	let lru_cache_key = (library_id, location_id, file_path_id);
 
 	let (file_path_full_path, extension) =
 		if let Some(entry) = FILE_METADATA_CACHE.get(&lru_cache_key) {
 			entry
 		} else {
-			let library = node
-				.library_manager
-				.get_library(library_id)
-				.await
-				.ok_or_else(|| HandleCustomUriError::NotFound("library"))?;
-
 			let file_path = library
 				.db
 				.file_path()
@@ -194,13 +652,79 @@ async fn handle_file(
 				.ok_or_else(|| HandleCustomUriError::NotFound("object"))?;
 
 			let location = maybe_missing(&file_path.location, "file_path.location")?;
+			if location.id != location_id {
+				return Err(HandleCustomUriError::NotFound("object"));
+			}
 			let path = maybe_missing(&location.path, "file_path.location.path")?;
 
-			let lru_entry = (
-				Path::new(path).join(IsolatedFilePathData::try_from((location_id, &file_path))?),
-				maybe_missing(file_path.extension, "extension")?,
+			let iso_file_path = IsolatedFilePathData::try_from((location_id, &file_path))?;
+
+			let reachable = ReachableLocations::fetch(&library).await?;
+			let is_ghost = reachable.is_ghost(
+				Some(location.id),
+				file_path.tiered_to_location_id,
+				file_path.compressed_at.is_some(),
 			);
 
+			let full_path = if file_path.compressed_at.is_some() {
+				decompress_to_cache(&library, &file_path.pub_id).await?
+			} else if is_ghost {
+				let cache_path = retrieved_file_path(&library, &file_path.pub_id);
+
+				if fs::metadata(&cache_path).await.is_err() {
+					// While the device owning the content is online we stream it from there, instead
+					// of waiting for the whole file to be retrieved
+					let owner_location_id = file_path.tiered_to_location_id.unwrap_or(location.id);
+					if let Some(peer_id) = location_owner(&library, owner_location_id).await? {
+						match handle_remote_file(node, req, peer_id, library_id, &file_path).await {
+							Err(HandleCustomUriError::RemoteFile(e)) => {
+								debug!("Failed to stream from '{peer_id}', retrieving instead: {e}")
+							}
+							response => return response,
+						}
+					}
+
+					let object_id = file_path
+						.object_id
+						.ok_or(HandleCustomUriError::NotFound("file"))?;
+
+					if reachable.find_source(&library, object_id).await?.is_none() {
+						return Err(HandleCustomUriError::NotFound("file"));
+					}
+
+					// The client is expected to retry once the retrieval job is done
+					if let Err(e) = library
+						.spawn_job(FileRetrieverJobInit {
+							location_id,
+							file_path_ids: vec![file_path_id],
+						})
+						.await
+					{
+						debug!("Failed to spawn the file retriever job: {e}");
+					}
+
+					return Err(HandleCustomUriError::Retrieving);
+				}
+
+				cache_path
+			} else if let Some(tiered_to_location_id) = file_path.tiered_to_location_id {
+				// Tiered files keep their relative path in the location they were moved to
+				let tier_location = library
+					.db
+					.location()
+					.find_unique(location::id::equals(tiered_to_location_id))
+					.select(location::select!({ path }))
+					.exec()
+					.await?
+					.ok_or_else(|| HandleCustomUriError::NotFound("location"))?;
+
+				Path::new(maybe_missing(&tier_location.path, "location.path")?).join(iso_file_path)
+			} else {
+				Path::new(path).join(iso_file_path)
+			};
+
+			let lru_entry = (full_path, maybe_missing(file_path.extension, "extension")?);
+
 			FILE_METADATA_CACHE.insert(lru_cache_key, lru_entry.clone());
 
 			lru_entry
@@ -214,9 +738,167 @@ async fn handle_file(
 		}
 	})?;
 
+	let mime_type = mime_type(&extension)?;
+
+	let mut content_lenght = file
+		.metadata()
+		.await
+		.map_err(|e| FileIOError::from((&file_path_full_path, e)))?
+		.len();
+
+	let range = parse_range(req, content_lenght)?;
+
+	let mut status_code = 200;
+	let buf = match range {
+		Some(range) => {
+			let file_size = content_lenght;
+			content_lenght = range.length;
+
+			// TODO: For some reason webkit2gtk doesn't like this at all.
+			// It causes it to only stream random pieces of any given audio file.
+			// TODO: This causes macOS to freeze streaming mp4
+			#[cfg(windows)]
+			// prevent max_length;
+			// specially on webview2
+			if mime_type != "application/pdf" && range.length > file_size / 3 {
+				// max size sent (400kb / request)
+				// as it's local file system we can afford to read more often
+				content_lenght = min(file_size - range.start, 1024 * 400);
+			}
+
+			// last byte we are reading, the length of the range include the last byte
+			// who should be skipped on the header
+			let last_byte = range.start + content_lenght - 1;
+
+			// if the webview sent a range header, we need to send a 206 in return
+			status_code = 206;
+
+			// macOS and Windows supports audio and video, linux only supports audio
+			builder = builder
+				.header("Connection", "Keep-Alive")
+				.header("Accept-Ranges", "bytes")
+				.header(
+					"Content-Range",
+					format!("bytes {}-{}/{}", range.start, last_byte, file_size),
+				);
+
+			// FIXME: Add ETag support (caching on the webview)
+
+			read_file(file, content_lenght, Some(range.start))
+				.await
+				.map_err(|e| FileIOError::from((&file_path_full_path, e)))?
+		}
+		_ if method == Method::HEAD => {
+			builder = builder.header("Accept-Ranges", "bytes");
+			vec![]
+		}
+		_ => read_file(file, content_lenght, None)
+			.await
+			.map_err(|e| FileIOError::from((&file_path_full_path, e)))?,
+	};
+
+	Ok(builder
+		.header("Content-type", mime_type)
+		.header("Content-Length", content_lenght)
+		.status(status_code)
+		.body(buf)?)
+}
+
+/// The peer of the device owning a location, if it isn't this one
+async fn location_owner(
+	library: &Library,
+	location_id: location::id::Type,
+) -> Result<Option<PeerId>, QueryError> {
+	Ok(library
+		.db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.select(location::select!({ node: select { id node_peer_id } }))
+		.exec()
+		.await?
+		.and_then(|location| location.node)
+		.filter(|node| node.id != library.node_local_id)
+		.and_then(|node| node.node_peer_id)
+		.and_then(|peer_id| PeerId::from_str(&peer_id).ok()))
+}
+
+/// Proxies the request to the device owning the content, which only sends the requested range
+async fn handle_remote_file(
+	node: &Node,
+	req: &Request,
+	peer_id: PeerId,
+	library_id: Uuid,
+	file_path: &file_path_to_handle_custom_uri::Data,
+) -> Result<Response<Vec<u8>>, HandleCustomUriError> {
+	let mime_type = mime_type(maybe_missing(&file_path.extension, "extension")?)?;
+	let mut builder = Response::builder().header("Accept-Ranges", "bytes");
+
+	// The size we know can be outdated, the owner clamps the range to the actual one
+	let size_hint = file_path
+		.size_in_bytes
+		.as_deref()
+		.and_then(|size| size.parse().ok())
+		.unwrap_or_default();
+
+	let range = parse_range(req, size_hint)?;
+
+	let request = FileRequest {
+		library_id,
+		file_path_pub_id: Uuid::from_slice(&file_path.pub_id)
+			.map_err(|_| HandleCustomUriError::NotFound("file"))?,
+		range: match range {
+			Some(range) => Some((range.start, range.length.min(MAX_REMOTE_CHUNK_LEN))),
+			// We only need the size of the file
+			None if req.method() == Method::HEAD => Some((0, 0)),
+			None => None,
+		},
+	};
+
+	let chunk = node.p2p.request_file(peer_id, request).await?;
+
+	let mut status_code = StatusCode::OK;
+	if range.is_some() {
+		if chunk.data.is_empty() {
+			return Err(HandleCustomUriError::RangeNotSatisfiable(
+				"Range is past the end of the file!",
+			));
+		}
+
+		status_code = StatusCode::PARTIAL_CONTENT;
+		builder = builder.header(
+			"Content-Range",
+			format!(
+				"bytes {}-{}/{}",
+				chunk.start,
+				chunk.start + chunk.data.len() as u64 - 1,
+				chunk.total_size
+			),
+		);
+	}
+
+	let content_length = if req.method() == Method::HEAD {
+		chunk.total_size
+	} else {
+		chunk.data.len() as u64
+	};
+
+	Ok(builder
+		.header("Content-type", mime_type)
+		.header("Content-Length", content_length)
+		.status(status_code)
+		.body(chunk.data)?)
+}
+
+fn mime_type(extension: &str) -> Result<&'static str, HandleCustomUriError> {
+	mime_type_of(extension).ok_or(HandleCustomUriError::BadRequest(
+		"TODO: This filetype is not supported because of the missing mime type!",
+	))
+}
+
+pub(crate) fn mime_type_of(extension: &str) -> Option<&'static str> {
 	// TODO: This should be determined from magic bytes when the file is indexed and stored it in the DB on the file path
 	// https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/MIME_types/Common_types
-	let mime_type = match extension.as_str() {
+	Some(match extension {
 		// AAC audio
 		"aac" => "audio/aac",
 		// Musical Instrument Digital Interface (MIDI)
@@ -278,27 +960,22 @@ async fn handle_file(
 		"heic" | "heics" => "image/heic,image/heic-sequence",
 		// AVIF images
 		"avif" | "avci" | "avcs" => "image/avif",
-		_ => {
-			return Err(HandleCustomUriError::BadRequest(
-				"TODO: This filetype is not supported because of the missing mime type!",
-			));
-		}
-	};
-
-	let mut content_lenght = file
-		.metadata()
-		.await
-		.map_err(|e| FileIOError::from((&file_path_full_path, e)))?
-		.len();
+		_ => return None,
+	})
+}
 
+fn parse_range(
+	req: &Request,
+	content_length: u64,
+) -> Result<Option<HttpRange>, HandleCustomUriError> {
 	// GET is the only method for which range handling is defined, according to the spec
 	// https://httpwg.org/specs/rfc9110.html#field.range
-	let range = if method == Method::GET {
+	Ok(if req.method() == Method::GET {
 		if let Some(range) = req.headers().get("range") {
 			range
 				.to_str()
 				.ok()
-				.and_then(|range| HttpRange::parse(range, content_lenght).ok())
+				.and_then(|range| HttpRange::parse(range, content_length).ok())
 				.ok_or_else(|| {
 					HandleCustomUriError::RangeNotSatisfiable("Error decoding range header!")
 				})
@@ -317,62 +994,7 @@ async fn handle_file(
 		}
 	} else {
 		None
-	};
-
-	let mut status_code = 200;
-	let buf = match range {
-		Some(range) => {
-			let file_size = content_lenght;
-			content_lenght = range.length;
-
-			// TODO: For some reason webkit2gtk doesn't like this at all.
-			// It causes it to only stream random pieces of any given audio file.
-			// TODO: This causes macOS to freeze streaming mp4
-			#[cfg(windows)]
-			// prevent max_length;
-			// specially on webview2
-			if mime_type != "application/pdf" && range.length > file_size / 3 {
-				// max size sent (400kb / request)
-				// as it's local file system we can afford to read more often
-				content_lenght = min(file_size - range.start, 1024 * 400);
-			}
-
-			// last byte we are reading, the length of the range include the last byte
-			// who should be skipped on the header
-			let last_byte = range.start + content_lenght - 1;
-
-			// if the webview sent a range header, we need to send a 206 in return
-			status_code = 206;
-
-			// macOS and Windows supports audio and video, linux only supports audio
-			builder = builder
-				.header("Connection", "Keep-Alive")
-				.header("Accept-Ranges", "bytes")
-				.header(
-					"Content-Range",
-					format!("bytes {}-{}/{}", range.start, last_byte, file_size),
-				);
-
-			// FIXME: Add ETag support (caching on the webview)
-
-			read_file(file, content_lenght, Some(range.start))
-				.await
-				.map_err(|e| FileIOError::from((&file_path_full_path, e)))?
-		}
-		_ if method == Method::HEAD => {
-			builder = builder.header("Accept-Ranges", "bytes");
-			vec![]
-		}
-		_ => read_file(file, content_lenght, None)
-			.await
-			.map_err(|e| FileIOError::from((&file_path_full_path, e)))?,
-	};
-
-	Ok(builder
-		.header("Content-type", mime_type)
-		.header("Content-Length", content_lenght)
-		.status(status_code)
-		.body(buf)?)
+	})
 }
 
 pub fn create_custom_uri_endpoint(node: Arc<Node>) -> Endpoint<impl HttpEndpoint> {
@@ -402,6 +1024,16 @@ pub enum HandleCustomUriError {
 	NotFound(&'static str),
 	#[error("HandleCustomUriError::MissingField - '{0}'")]
 	MissingField(#[from] MissingFieldError),
+	#[error("HandleCustomUriError::Retrieving - content is being retrieved")]
+	Retrieving,
+	#[error("HandleCustomUriError::RemoteFile - {0}")]
+	RemoteFile(#[from] RemoteFileError),
+	#[error("HandleCustomUriError::TooManyRequests - {0:?}")]
+	TooManyRequests(#[from] LimitExceeded),
+	#[error("HandleCustomUriError::Inbox - {0}")]
+	Inbox(#[from] InboxError),
+	#[error("HandleCustomUriError::Library - {0}")]
+	Library(#[from] LibraryManagerError),
 }
 
 impl From<HandleCustomUriError> for Response<Vec<u8>> {
@@ -450,6 +1082,37 @@ impl From<HandleCustomUriError> for Response<Vec<u8>> {
 					.status(StatusCode::INTERNAL_SERVER_ERROR)
 					.body(b"Internal Server Error".to_vec())
 			}
+			HandleCustomUriError::Retrieving => builder
+				.status(StatusCode::SERVICE_UNAVAILABLE)
+				.header("Retry-After", "5")
+				.body(b"Content is being retrieved".to_vec()),
+			HandleCustomUriError::RemoteFile(err) => {
+				error!("Error streaming remote file: {:#?}", err);
+				builder
+					.status(StatusCode::BAD_GATEWAY)
+					.body(b"Bad Gateway".to_vec())
+			}
+			HandleCustomUriError::TooManyRequests(LimitExceeded::Requests { retry_after }) => {
+				builder
+					.status(StatusCode::TOO_MANY_REQUESTS)
+					.header("Retry-After", retry_after.as_secs().max(1))
+					.body(b"Too Many Requests".to_vec())
+			}
+			HandleCustomUriError::TooManyRequests(LimitExceeded::Streams) => builder
+				.status(StatusCode::TOO_MANY_REQUESTS)
+				.body(b"Too many concurrent streams".to_vec()),
+			HandleCustomUriError::Inbox(err) => {
+				error!("Error receiving a file into an inbox: {:#?}", err);
+				builder
+					.status(StatusCode::INTERNAL_SERVER_ERROR)
+					.body(b"Internal Server Error".to_vec())
+			}
+			HandleCustomUriError::Library(err) => {
+				error!("Library error: {:#?}", err);
+				builder
+					.status(StatusCode::INTERNAL_SERVER_ERROR)
+					.body(b"Internal Server Error".to_vec())
+			}
 		})
 		// SAFETY: This unwrap is ok as we have an hardcoded the response builders.
 		.expect("internal error building hardcoded HTTP error response")
@@ -1,8 +1,8 @@
 use crate::{
 	location::{indexer::IndexerError, LocationError},
 	object::{
-		file_identifier::FileIdentifierJobError, fs::error::FileSystemJobsError,
-		preview::ThumbnailerError,
+		catalog::CatalogImportError, file_identifier::FileIdentifierJobError,
+		fs::error::FileSystemJobsError, preview::ThumbnailerError, xmp::XmpError,
 	},
 	util::{db::MissingFieldError, error::FileIOError},
 };
@@ -66,6 +66,10 @@ pub enum JobError {
 	FileSystemJobsError(#[from] FileSystemJobsError),
 	#[error(transparent)]
 	CryptoError(#[from] CryptoError),
+	#[error(transparent)]
+	CatalogImport(#[from] CatalogImportError),
+	#[error(transparent)]
+	XmpSidecar(#[from] XmpError),
 	#[error("missing-field: {0}")]
 	MissingField(#[from] MissingFieldError),
 	#[error("item of type '{0}' with id '{1}' is missing from the db")]
@@ -99,6 +103,12 @@ pub enum JobManagerError {
 
 	#[error("missing-field: {0}")]
 	MissingField(#[from] MissingFieldError),
+
+	#[error("job can't be rolled back: {0}")]
+	NotRevertible(Uuid),
+
+	#[error("failed to roll back job: {0}")]
+	RollbackFailed(String),
 }
 
 impl From<JobManagerError> for rspc::Error {
@@ -124,6 +134,16 @@ impl From<JobManagerError> for rspc::Error {
 				"Missing field".to_string(),
 				value,
 			),
+			JobManagerError::NotRevertible(_) => Self::with_cause(
+				rspc::ErrorCode::BadRequest,
+				"Job can't be rolled back".to_string(),
+				value,
+			),
+			JobManagerError::RollbackFailed(_) => Self::with_cause(
+				rspc::ErrorCode::InternalServerError,
+				"Failed to roll back job".to_string(),
+				value,
+			),
 		}
 	}
 }
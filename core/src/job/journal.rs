@@ -0,0 +1,790 @@
+use crate::{
+	library::Library,
+	location::{light_scan_location, location_with_indexer_rules},
+	object::{
+		fs::{move_across_volumes, staging_path},
+		special::remove_file_or_link,
+	},
+	prisma::{job, location},
+	util::{error::FileIOError, long_path::strip_extended_length_prefix},
+};
+
+use std::{
+	collections::HashSet,
+	fs::Metadata,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+	fs::{self, OpenOptions},
+	io::{self, AsyncWriteExt},
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::{JobError, JobStatus};
+
+/// Directory inside the node data directory holding the journals, with a sub directory per library
+const JOURNALS_DIR: &str = "journals";
+
+/// A filesystem change made by a job step, recorded in the journal before being applied
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum JournalOperation {
+	CreateDir {
+		path: PathBuf,
+	},
+	Copy {
+		source: PathBuf,
+		target: PathBuf,
+	},
+	Move {
+		source: PathBuf,
+		target: PathBuf,
+	},
+	/// A move to another volume, copying the source to a staging path next to the target which is
+	/// then renamed to the target, before removing the source
+	MoveAcrossVolumes {
+		source: PathBuf,
+		target: PathBuf,
+	},
+	/// A copy of a whole directory, like a bundle, staged next to the target like
+	/// [`JournalOperation::MoveAcrossVolumes`]
+	CopyTree {
+		source: PathBuf,
+		target: PathBuf,
+	},
+	Delete {
+		path: PathBuf,
+		is_dir: bool,
+	},
+	Erase {
+		path: PathBuf,
+	},
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+	/// The operation never happened or its leftovers were removed, so the step must run again
+	RolledBack,
+	/// The operation is fully applied now, so the step must not run again
+	RolledForward,
+}
+
+impl JournalOperation {
+	/// Brings the filesystem back to a consistent state after a crash in the middle of this operation
+	async fn recover(&self) -> Result<Recovery, FileIOError> {
+		match self {
+			Self::CreateDir { path } => Ok(if metadata(path).await?.is_some() {
+				Recovery::RolledForward
+			} else {
+				Recovery::RolledBack
+			}),
+			Self::Copy { source, target } => {
+				let Some(target_metadata) = metadata(target).await? else {
+					return Ok(Recovery::RolledBack);
+				};
+
+				// We never copy over an existing file, so a target with a different size
+				// than the source can only be a partial copy
+				if metadata(source).await?.map(|source| source.len()) == Some(target_metadata.len())
+				{
+					Ok(Recovery::RolledForward)
+				} else {
+					fs::remove_file(target)
+						.await
+						.map_err(|e| FileIOError::from((target, e)))?;
+
+					Ok(Recovery::RolledBack)
+				}
+			}
+			// Renames are atomic, so either the source or the target exists
+			Self::Move { source, target } => Ok(
+				if metadata(source).await?.is_none() && metadata(target).await?.is_some() {
+					Recovery::RolledForward
+				} else {
+					Recovery::RolledBack
+				},
+			),
+			// The target only shows up once the copy is complete, so from there we finish the
+			// job, and before that we remove whatever was staged
+			Self::MoveAcrossVolumes { source, target } => {
+				if metadata(target).await?.is_some() {
+					if let Some(source_metadata) = metadata(source).await? {
+						if source_metadata.is_dir() {
+							fs::remove_dir_all(source).await
+						} else {
+							fs::remove_file(source).await
+						}
+						.map_err(|e| FileIOError::from((source, e)))?;
+					}
+
+					return Ok(Recovery::RolledForward);
+				}
+
+				remove_staged(target).await?;
+
+				Ok(Recovery::RolledBack)
+			}
+			Self::CopyTree { target, .. } => {
+				if metadata(target).await?.is_some() {
+					return Ok(Recovery::RolledForward);
+				}
+
+				remove_staged(target).await?;
+
+				Ok(Recovery::RolledBack)
+			}
+			// A half deleted or half erased file can't be restored, so we finish the job
+			Self::Delete { path, is_dir } => {
+				if metadata(path).await?.is_some() {
+					if *is_dir {
+						fs::remove_dir_all(path).await
+					} else {
+						remove_file_or_link(path).await
+					}
+					.map_err(|e| FileIOError::from((path, e)))?;
+				}
+
+				Ok(Recovery::RolledForward)
+			}
+			Self::Erase { path } => {
+				if metadata(path).await?.is_some() {
+					remove_file_or_link(path)
+						.await
+						.map_err(|e| FileIOError::from((path, e)))?;
+				}
+
+				Ok(Recovery::RolledForward)
+			}
+		}
+	}
+
+	/// Only operations that didn't destroy data can be reverted
+	fn is_revertible(&self) -> bool {
+		matches!(
+			self,
+			Self::CreateDir { .. }
+				| Self::Copy { .. }
+				| Self::Move { .. }
+				| Self::MoveAcrossVolumes { .. }
+				| Self::CopyTree { .. }
+		)
+	}
+
+	/// Undoes a fully applied operation
+	async fn revert(&self) -> Result<(), FileIOError> {
+		match self {
+			Self::CreateDir { path } => ignore_not_found(fs::remove_dir(path).await)
+				.map_err(|e| FileIOError::from((path, e))),
+			Self::Copy { target, .. } => ignore_not_found(fs::remove_file(target).await)
+				.map_err(|e| FileIOError::from((target, e))),
+			Self::Move { source, target } => {
+				// Something else took the original place of the file, and we won't overwrite it
+				if metadata(source).await?.is_some() {
+					return Err(FileIOError::from((
+						source,
+						io::Error::new(
+							io::ErrorKind::AlreadyExists,
+							"can't move the file back to its original path",
+						),
+					)));
+				}
+
+				fs::rename(target, source)
+					.await
+					.map_err(|e| FileIOError::from((target, e)))
+			}
+			Self::MoveAcrossVolumes { source, target } => {
+				if metadata(source).await?.is_some() {
+					return Err(FileIOError::from((
+						source,
+						io::Error::new(
+							io::ErrorKind::AlreadyExists,
+							"can't move the file back to its original path",
+						),
+					)));
+				}
+
+				move_across_volumes(target, source, true).await
+			}
+			Self::CopyTree { target, .. } => ignore_not_found(fs::remove_dir_all(target).await)
+				.map_err(|e| FileIOError::from((target, e))),
+			// `is_revertible` keeps these from ever getting here
+			Self::Delete { .. } | Self::Erase { .. } => Ok(()),
+		}
+	}
+
+	/// Directories whose contents may have changed because of this operation
+	fn affected_directories(&self) -> impl Iterator<Item = &Path> {
+		match self {
+			Self::CreateDir { path } | Self::Delete { path, .. } | Self::Erase { path } => {
+				[Some(path), None]
+			}
+			Self::Copy { source, target }
+			| Self::Move { source, target }
+			| Self::MoveAcrossVolumes { source, target }
+			| Self::CopyTree { source, target } => [Some(source), Some(target)],
+		}
+		.into_iter()
+		.flatten()
+		.filter_map(|path| path.parent())
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum JournalEntry {
+	Intent {
+		step: usize,
+		operation: JournalOperation,
+	},
+	Completed {
+		step: usize,
+	},
+	Reverted {
+		step: usize,
+	},
+}
+
+/// `JobJournal` is a write-ahead log of the filesystem changes made by a job.
+/// Each step records its intent before touching the filesystem and its completion afterwards,
+/// so if the node dies in between, `JobJournal::recover_all` can roll the step forward or back
+/// on the next start and a resumed job never applies the same step twice.
+pub struct JobJournal {
+	path: PathBuf,
+	completed_steps: HashSet<usize>,
+}
+
+impl JobJournal {
+	fn dir(library: &Library) -> PathBuf {
+		library
+			.config()
+			.data_directory()
+			.join(JOURNALS_DIR)
+			.join(library.id.to_string())
+	}
+
+	fn path(library: &Library, job_id: Uuid) -> PathBuf {
+		Self::dir(library).join(format!("{job_id}.journal"))
+	}
+
+	pub(super) async fn open(library: &Library, job_id: Uuid) -> Result<Self, JobError> {
+		let dir = Self::dir(library);
+		fs::create_dir_all(&dir)
+			.await
+			.map_err(|e| FileIOError::from((&dir, e)))?;
+
+		let path = Self::path(library, job_id);
+
+		Ok(Self {
+			completed_steps: read_entries(&path)
+				.await?
+				.into_iter()
+				.filter_map(|entry| match entry {
+					JournalEntry::Completed { step } => Some(step),
+					JournalEntry::Intent { .. } | JournalEntry::Reverted { .. } => None,
+				})
+				.collect(),
+			path,
+		})
+	}
+
+	/// Applies `operation` as the step `step` of the job, unless a previous run already did it
+	pub async fn execute<Fut>(
+		&mut self,
+		step: usize,
+		operation: JournalOperation,
+		apply: impl FnOnce() -> Fut,
+	) -> Result<(), JobError>
+	where
+		Fut: std::future::Future<Output = Result<(), JobError>>,
+	{
+		if self.completed_steps.contains(&step) {
+			info!("Skipping step {step} as it was already applied: {operation:?}");
+			return Ok(());
+		}
+
+		self.append(&JournalEntry::Intent { step, operation })
+			.await?;
+
+		apply().await?;
+
+		self.append(&JournalEntry::Completed { step }).await?;
+		self.completed_steps.insert(step);
+
+		Ok(())
+	}
+
+	async fn append(&self, entry: &JournalEntry) -> Result<(), JobError> {
+		append_entry(&self.path, entry).await
+	}
+
+	/// Deletes the journal of a job, called when the job won't be resumed anymore
+	pub(super) async fn remove(library: &Library, job_id: Uuid) {
+		let path = Self::path(library, job_id);
+
+		if let Err(e) = ignore_not_found(fs::remove_file(&path).await) {
+			error!(
+				"Failed to remove job journal: {:#?}",
+				FileIOError::from((path, e))
+			);
+		}
+	}
+
+	/// Goes through the journals left behind by the last run of the node, rolling forward or back
+	/// every step that was interrupted, and rescans the touched directories so the `file_path`s
+	/// in the database match the filesystem again.
+	pub(super) async fn recover_all(library: &Library) -> Result<(), JobError> {
+		let dir = Self::dir(library);
+
+		let mut read_dir = match fs::read_dir(&dir).await {
+			Ok(read_dir) => read_dir,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(FileIOError::from((dir, e)).into()),
+		};
+
+		let mut directories_to_rescan = HashSet::new();
+
+		while let Some(entry) = read_dir
+			.next_entry()
+			.await
+			.map_err(|e| FileIOError::from((&dir, e)))?
+		{
+			let path = entry.path();
+			let Some(job_id) = path
+				.file_stem()
+				.and_then(|stem| stem.to_str())
+				.and_then(|stem| Uuid::parse_str(stem).ok())
+			else {
+				continue;
+			};
+
+			let entries = read_entries(&path).await?;
+			let completed_steps = entries
+				.iter()
+				.filter_map(|entry| match entry {
+					JournalEntry::Completed { step } => Some(*step),
+					JournalEntry::Intent { .. } | JournalEntry::Reverted { .. } => None,
+				})
+				.collect::<HashSet<_>>();
+
+			for entry in &entries {
+				let JournalEntry::Intent { step, operation } = entry else {
+					continue;
+				};
+				if completed_steps.contains(step) {
+					continue;
+				}
+
+				match operation.recover().await {
+					Ok(Recovery::RolledForward) => {
+						info!("Job<id='{job_id}'> rolled forward interrupted step {step}: {operation:?}");
+						append_entry(&path, &JournalEntry::Completed { step: *step }).await?;
+					}
+					Ok(Recovery::RolledBack) => {
+						info!(
+							"Job<id='{job_id}'> rolled back interrupted step {step}: {operation:?}"
+						);
+					}
+					Err(e) => {
+						error!("Job<id='{job_id}'> failed to recover step {step}: {e:#?}");
+					}
+				}
+
+				directories_to_rescan
+					.extend(operation.affected_directories().map(Path::to_path_buf));
+			}
+
+			// Paused jobs are resumed and failed ones can still be rolled back,
+			// every other job is done with its journal
+			let keep_journal = library
+				.db
+				.job()
+				.find_unique(job::id::equals(job_id.as_bytes().to_vec()))
+				.exec()
+				.await?
+				.and_then(|job| job.status)
+				.map_or(false, |status| {
+					status == JobStatus::Paused as i32 || status == JobStatus::Failed as i32
+				});
+
+			if !keep_journal {
+				Self::remove(library, job_id).await;
+			}
+		}
+
+		rescan_directories(library, directories_to_rescan).await
+	}
+
+	/// Checks if the changes made by a job can be rolled back, which is the case when every
+	/// operation it applied is revertible
+	pub(super) async fn can_rollback(library: &Library, job_id: Uuid) -> bool {
+		let entries = match read_entries(&Self::path(library, job_id)).await {
+			Ok(entries) => entries,
+			Err(e) => {
+				error!("Failed to read job journal: {e:#?}");
+				return false;
+			}
+		};
+
+		let mut operations = entries
+			.iter()
+			.filter_map(|entry| match entry {
+				JournalEntry::Intent { operation, .. } => Some(operation),
+				JournalEntry::Completed { .. } | JournalEntry::Reverted { .. } => None,
+			})
+			.peekable();
+
+		operations.peek().is_some() && operations.all(JournalOperation::is_revertible)
+	}
+
+	/// Reverts every step applied by a job, newest first, so directories are emptied before being
+	/// removed. Each reverted step is recorded, so a rollback that fails halfway can be retried.
+	pub(super) async fn rollback(library: &Library, job_id: Uuid) -> Result<(), JobError> {
+		let path = Self::path(library, job_id);
+		let entries = read_entries(&path).await?;
+
+		let mut completed_steps = HashSet::new();
+		let mut reverted_steps = HashSet::new();
+		for entry in &entries {
+			match entry {
+				JournalEntry::Completed { step } => {
+					completed_steps.insert(*step);
+				}
+				JournalEntry::Reverted { step } => {
+					reverted_steps.insert(*step);
+				}
+				JournalEntry::Intent { .. } => {}
+			}
+		}
+
+		let mut directories_to_rescan = HashSet::new();
+
+		let result = async {
+			for entry in entries.iter().rev() {
+				let JournalEntry::Intent { step, operation } = entry else {
+					continue;
+				};
+
+				directories_to_rescan
+					.extend(operation.affected_directories().map(Path::to_path_buf));
+
+				if reverted_steps.contains(step) {
+					continue;
+				}
+
+				// The step that failed may be partially applied, `recover` cleans it up
+				if completed_steps.contains(step)
+					|| operation.recover().await? == Recovery::RolledForward
+				{
+					operation.revert().await?;
+				}
+
+				info!("Job<id='{job_id}'> reverted step {step}: {operation:?}");
+				append_entry(&path, &JournalEntry::Reverted { step: *step }).await?;
+			}
+
+			Ok::<_, JobError>(())
+		}
+		.await;
+
+		rescan_directories(library, directories_to_rescan).await?;
+
+		result?;
+
+		Self::remove(library, job_id).await;
+
+		Ok(())
+	}
+}
+
+async fn rescan_directories(
+	library: &Library,
+	directories: HashSet<PathBuf>,
+) -> Result<(), JobError> {
+	if directories.is_empty() {
+		return Ok(());
+	}
+
+	let locations = library
+		.db
+		.location()
+		.find_many(vec![location::node_id::equals(Some(library.node_local_id))])
+		.include(location_with_indexer_rules::include())
+		.exec()
+		.await?;
+
+	for directory in directories {
+		if metadata(&directory).await?.is_none() {
+			continue;
+		}
+
+		// Locations paths are stored without the Windows extended-length prefix
+		let directory = strip_extended_length_prefix(&directory);
+
+		// With nested locations, the deepest one is where the directory is indexed
+		let Some((location, sub_path)) = locations
+			.iter()
+			.filter_map(|location| {
+				let location_path = location.path.as_deref()?;
+				directory
+					.strip_prefix(location_path)
+					.ok()
+					.map(|sub_path| (location, location_path.len(), sub_path))
+			})
+			.max_by_key(|(_, location_path_len, _)| *location_path_len)
+			.map(|(location, _, sub_path)| (location, sub_path))
+		else {
+			continue;
+		};
+
+		if let Err(e) = light_scan_location(library.clone(), location.clone(), sub_path).await {
+			error!(
+				"Failed to rescan directory after job recovery: <path='{}'>, error: {e:#?}",
+				directory.display()
+			);
+		}
+	}
+
+	Ok(())
+}
+
+async fn append_entry(path: &Path, entry: &JournalEntry) -> Result<(), JobError> {
+	let mut line = serde_json::to_vec(entry)?;
+	line.push(b'\n');
+
+	let mut file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	file.write_all(&line)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	// The entry must be on disk before we touch the files it talks about
+	file.sync_data()
+		.await
+		.map_err(|e| FileIOError::from((path, e)).into())
+}
+
+async fn read_entries(path: &Path) -> Result<Vec<JournalEntry>, FileIOError> {
+	let contents = match fs::read_to_string(path).await {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(FileIOError::from((path, e))),
+	};
+
+	Ok(contents
+		.lines()
+		.filter_map(|line| {
+			// The last line can be torn if the node died while writing it
+			serde_json::from_str(line)
+				.map_err(|e| warn!("Ignoring corrupted job journal entry: {e:#?}"))
+				.ok()
+		})
+		.collect())
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+	match result {
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+		result => result,
+	}
+}
+
+/// Removes whatever was staged for a copy to `target` which didn't complete
+async fn remove_staged(target: &Path) -> Result<(), FileIOError> {
+	let staging = staging_path(target);
+	if let Some(staging_metadata) = metadata(&staging).await? {
+		if staging_metadata.is_dir() {
+			fs::remove_dir_all(&staging).await
+		} else {
+			fs::remove_file(&staging).await
+		}
+		.map_err(|e| FileIOError::from((&staging, e)))?;
+	}
+
+	Ok(())
+}
+
+/// Links aren't followed, as operations apply to the links themselves
+async fn metadata(path: &Path) -> Result<Option<Metadata>, FileIOError> {
+	match fs::symlink_metadata(path).await {
+		Ok(metadata) => Ok(Some(metadata)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(FileIOError::from((path, e))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::object::fs::copy_tree_staged;
+
+	use tempfile::tempdir;
+
+	#[tokio::test]
+	async fn recover_partial_copy_and_move() {
+		let dir = tempdir().unwrap();
+		let source = dir.path().join("source.txt");
+		let partial = dir.path().join("partial.txt");
+		let complete = dir.path().join("complete.txt");
+		fs::write(&source, b"some file contents").await.unwrap();
+		fs::write(&partial, b"some").await.unwrap();
+		fs::write(&complete, b"some file contents").await.unwrap();
+
+		let partial_copy = JournalOperation::Copy {
+			source: source.clone(),
+			target: partial.clone(),
+		};
+		assert_eq!(partial_copy.recover().await.unwrap(), Recovery::RolledBack);
+		assert!(metadata(&partial).await.unwrap().is_none());
+
+		let complete_copy = JournalOperation::Copy {
+			source: source.clone(),
+			target: complete.clone(),
+		};
+		assert_eq!(
+			complete_copy.recover().await.unwrap(),
+			Recovery::RolledForward
+		);
+
+		let pending_move = JournalOperation::Move {
+			source: source.clone(),
+			target: dir.path().join("moved.txt"),
+		};
+		assert_eq!(pending_move.recover().await.unwrap(), Recovery::RolledBack);
+
+		let done_move = JournalOperation::Move {
+			source: dir.path().join("gone.txt"),
+			target: source,
+		};
+		assert_eq!(done_move.recover().await.unwrap(), Recovery::RolledForward);
+	}
+
+	#[tokio::test]
+	async fn recovers_moves_across_volumes() {
+		let dir = tempdir().unwrap();
+		let source = dir.path().join("album");
+		let target = dir.path().join("moved");
+		fs::create_dir(&source).await.unwrap();
+		fs::write(source.join("photo.jpg"), b"contents")
+			.await
+			.unwrap();
+
+		// Interrupted while copying, the staged copy goes away and the source stays
+		fs::create_dir(staging_path(&target)).await.unwrap();
+		let operation = JournalOperation::MoveAcrossVolumes {
+			source: source.clone(),
+			target: target.clone(),
+		};
+		assert_eq!(operation.recover().await.unwrap(), Recovery::RolledBack);
+		assert!(metadata(&staging_path(&target)).await.unwrap().is_none());
+		assert!(metadata(&source).await.unwrap().is_some());
+
+		// Interrupted while removing the source, which is finished off
+		move_across_volumes(&source, &target, true).await.unwrap();
+		fs::create_dir(&source).await.unwrap();
+		assert_eq!(operation.recover().await.unwrap(), Recovery::RolledForward);
+		assert!(metadata(&source).await.unwrap().is_none());
+		assert_eq!(
+			fs::read(target.join("photo.jpg")).await.unwrap(),
+			b"contents"
+		);
+	}
+
+	#[cfg(target_family = "unix")]
+	#[tokio::test]
+	async fn copied_trees_keep_their_links() {
+		let dir = tempdir().unwrap();
+		let source = dir.path().join("Notes.app");
+		let target = dir.path().join("Copy.app");
+		fs::create_dir(&source).await.unwrap();
+		fs::write(source.join("Info.plist"), b"plist")
+			.await
+			.unwrap();
+		fs::symlink("Info.plist", source.join("current"))
+			.await
+			.unwrap();
+
+		copy_tree_staged(&source, &target, true).await.unwrap();
+		assert_eq!(
+			fs::read_link(target.join("current")).await.unwrap(),
+			Path::new("Info.plist")
+		);
+
+		let operation = JournalOperation::CopyTree {
+			source: source.clone(),
+			target: target.clone(),
+		};
+		assert_eq!(operation.recover().await.unwrap(), Recovery::RolledForward);
+		operation.revert().await.unwrap();
+		assert!(metadata(&target).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn revert_undoes_copies_and_moves() {
+		let dir = tempdir().unwrap();
+		let target_dir = dir.path().join("target");
+		let source = dir.path().join("source.txt");
+		let copied = target_dir.join("copied.txt");
+		let moved = target_dir.join("moved.txt");
+		fs::create_dir(&target_dir).await.unwrap();
+		fs::write(&copied, b"contents").await.unwrap();
+		fs::write(&moved, b"contents").await.unwrap();
+
+		let operations = [
+			JournalOperation::CreateDir {
+				path: target_dir.clone(),
+			},
+			JournalOperation::Copy {
+				source: dir.path().join("original.txt"),
+				target: copied.clone(),
+			},
+			JournalOperation::Move {
+				source: source.clone(),
+				target: moved.clone(),
+			},
+		];
+		assert!(operations.iter().all(JournalOperation::is_revertible));
+
+		for operation in operations.iter().rev() {
+			operation.revert().await.unwrap();
+		}
+
+		assert!(metadata(&source).await.unwrap().is_some());
+		assert!(metadata(&target_dir).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn torn_entries_are_ignored() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("job.journal");
+
+		append_entry(
+			&path,
+			&JournalEntry::Intent {
+				step: 0,
+				operation: JournalOperation::Erase {
+					path: dir.path().join("file"),
+				},
+			},
+		)
+		.await
+		.unwrap();
+		append_entry(&path, &JournalEntry::Completed { step: 0 })
+			.await
+			.unwrap();
+
+		let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+		file.write_all(br#"{"Intent":{"step":1,"#).await.unwrap();
+
+		let entries = read_entries(&path).await.unwrap();
+		assert_eq!(entries.len(), 2);
+		assert!(matches!(entries[1], JournalEntry::Completed { step: 0 }));
+	}
+}
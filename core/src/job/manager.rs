@@ -1,14 +1,46 @@
 use crate::{
-	job::{worker::Worker, DynJob, Job, JobError},
+	job::{worker::Worker, DynJob, Job, JobError, JobJournal},
 	library::Library,
-	location::indexer::indexer_job::IndexerJob,
+	location::{
+		actions::NewFileActionsJob, cleanup::LocationCleanupJob,
+		file_path_helper::normalizer_job::FilePathNormalizerJob, indexer::indexer_job::IndexerJob,
+	},
+	node::ResourceManager,
 	object::{
+		catalog::CatalogImporterJob,
+		cleanup_suggestions::CleanupAnalyzerJob,
+		duplicate_folders::DuplicateFoldersJob,
 		file_identifier::file_identifier_job::FileIdentifierJob,
 		fs::{
-			copy::FileCopierJob, cut::FileCutterJob, delete::FileDeleterJob, erase::FileEraserJob,
+			archive::ArchiveCreatorJob,
+			broken_links::BrokenLinkScannerJob,
+			compress::FileCompressorJob,
+			convert::MediaConverterJob,
+			copy::FileCopierJob,
+			cut::FileCutterJob,
+			delete::FileDeleterJob,
+			diff::DirectoryDiffJob,
+			erase::FileEraserJob,
+			export::ImageExporterJob,
+			extension_mismatch::{ExtensionFixerJob, ExtensionMismatchScannerJob},
+			extract::ArchiveExtractorJob,
+			ghost::FileRetrieverJob,
+			import::ImportExternalFilesJob,
+			mirror::FolderMirrorJob,
+			pdf::PdfEditorJob,
+			tiering::FileTieringJob,
+			time_shift::PhotoTimeShifterJob,
+			transcode::VideoTranscoderJob,
 		},
+		groups::FileGrouperJob,
+		mail::MailIndexerJob,
+		nsfw::NsfwClassifierJob,
 		preview::thumbnailer_job::ThumbnailerJob,
+		projects::ProjectDetectorJob,
+		stacks::PhotoStackerJob,
+		tag::TagAssignerJob,
 		validation::validator_job::ObjectValidatorJob,
+		xmp::XmpSidecarSyncJob,
 	},
 	prisma::job,
 };
@@ -16,20 +48,27 @@ use prisma_client_rust::operator::or;
 
 use std::{
 	collections::{HashMap, HashSet, VecDeque},
+	mem,
 	sync::Arc,
+	time::Duration,
 };
 
-use tokio::sync::{
-	mpsc::{self, UnboundedSender},
-	Mutex, RwLock,
+use tokio::{
+	sync::{
+		mpsc::{self, UnboundedSender},
+		Mutex, RwLock,
+	},
+	time::interval,
 };
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use super::{JobManagerError, JobReport, JobStatus, WorkerCommand};
+use super::{JobManagerError, JobReport, JobRollbackStatus, JobStatus, WorkerCommand};
 
 // db is single threaded, nerd
 const MAX_WORKERS: usize = 1;
+/// How often we check if deferred background jobs are allowed to run
+const DEFERRED_JOBS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub enum JobManagerEvent {
 	IngestJob(Library, Box<dyn DynJob>),
@@ -42,6 +81,13 @@ pub struct JobManager {
 	current_jobs_hashes: RwLock<HashSet<u64>>,
 	job_queue: RwLock<VecDeque<Box<dyn DynJob>>>,
 	running_workers: RwLock<HashMap<Uuid, Arc<Mutex<Worker>>>>,
+	// background jobs waiting for the resource profile to allow them to run
+	deferred_jobs: RwLock<VecDeque<(Library, Box<dyn DynJob>)>>,
+	// background jobs that the user asked to run regardless of the schedule
+	forced_jobs: RwLock<HashSet<Uuid>>,
+	// jobs that were queued when the node stopped, waiting for the user to resume or discard them
+	pending_jobs: RwLock<VecDeque<(Library, Box<dyn DynJob>)>>,
+	resources: Arc<ResourceManager>,
 	internal_sender: UnboundedSender<JobManagerEvent>,
 	// pub external_receiver: UnboundedReceiver<JobManagerUpdate>,
 	// external_sender: UnboundedSender<JobManagerUpdate>,
@@ -49,7 +95,7 @@ pub struct JobManager {
 
 impl JobManager {
 	/// Initializes the JobManager and spawns the internal event loop to listen for ingest.
-	pub fn new() -> Arc<Self> {
+	pub fn new(resources: Arc<ResourceManager>) -> Arc<Self> {
 		// allow the job manager to control its workers
 		let (internal_sender, mut internal_receiver) = mpsc::unbounded_channel();
 		// // emit realtime events to the rest of the application
@@ -59,6 +105,10 @@ impl JobManager {
 			current_jobs_hashes: RwLock::new(HashSet::new()),
 			job_queue: RwLock::new(VecDeque::new()),
 			running_workers: RwLock::new(HashMap::new()),
+			deferred_jobs: RwLock::new(VecDeque::new()),
+			forced_jobs: RwLock::new(HashSet::new()),
+			pending_jobs: RwLock::new(VecDeque::new()),
+			resources,
 			internal_sender,
 			// external_receiver,
 			// external_sender,
@@ -66,25 +116,38 @@ impl JobManager {
 
 		let this2 = this.clone();
 		tokio::spawn(async move {
+			let mut deferred_jobs_check = interval(DEFERRED_JOBS_CHECK_INTERVAL);
+
 			// FIXME: if this task crashes, the entire application is unusable
-			while let Some(event) = internal_receiver.recv().await {
-				match event {
-					JobManagerEvent::IngestJob(library, job) => {
-						this2.clone().dispatch(&library, job).await
-					}
-					// When the app shuts down, we need to gracefully shutdown all
-					// active workers and preserve their state
-					JobManagerEvent::Shutdown => {
-						info!("Shutting down job manager");
-						let mut running_workers = this2.running_workers.write().await;
-						for (_, worker) in running_workers.iter_mut() {
-							worker
-								.lock()
-								.await
-								.command(WorkerCommand::Shutdown)
-								.expect("Failed to send shutdown command to worker");
+			loop {
+				tokio::select! {
+					maybe_event = internal_receiver.recv() => {
+						let Some(event) = maybe_event else {
+							break;
+						};
+
+						match event {
+							JobManagerEvent::IngestJob(library, job) => {
+								this2.clone().dispatch(&library, job).await
+							}
+							// When the app shuts down, we need to gracefully shutdown all
+							// active workers and preserve their state
+							JobManagerEvent::Shutdown => {
+								info!("Shutting down job manager");
+								let mut running_workers = this2.running_workers.write().await;
+								for (_, worker) in running_workers.iter_mut() {
+									worker
+										.lock()
+										.await
+										.command(WorkerCommand::Shutdown)
+										.expect("Failed to send shutdown command to worker");
+								}
+
+								this2.pause_deferred().await;
+							}
 						}
 					}
+					_ = deferred_jobs_check.tick() => this2.clone().dispatch_deferred().await,
 				}
 			}
 		});
@@ -121,6 +184,11 @@ impl JobManager {
 
 	/// Dispatches a job to a worker if under MAX_WORKERS limit, queues it otherwise.
 	async fn dispatch(self: Arc<Self>, library: &Library, mut job: Box<dyn DynJob>) {
+		if job.is_background() && !self.can_run_background_job(library, job.id()).await {
+			self.defer(library, job).await;
+			return;
+		}
+
 		let mut running_workers = self.running_workers.write().await;
 
 		if running_workers.len() < MAX_WORKERS {
@@ -152,7 +220,119 @@ impl JobManager {
 				job.name(),
 				job.hash()
 			);
-			self.job_queue.write().await.push_back(job);
+			persist_queued(library, &mut job).await;
+
+			let mut job_queue = self.job_queue.write().await;
+			if job.is_priority() {
+				// Behind the other priority jobs, so they keep their order
+				let idx = job_queue
+					.iter()
+					.position(|queued| !queued.is_priority())
+					.unwrap_or(job_queue.len());
+				job_queue.insert(idx, job);
+			} else {
+				job_queue.push_back(job);
+			}
+		}
+	}
+
+	/// Checks the node resource profile and the library schedule policy to know if a
+	/// background job can run right now.
+	async fn can_run_background_job(&self, library: &Library, job_id: Uuid) -> bool {
+		self.forced_jobs.read().await.contains(&job_id)
+			|| (library.config.job_schedule.allows_now(&self.resources)
+				&& self.resources.background_jobs_allowed().await)
+	}
+
+	/// Holds a background job until the current resource profile allows it to run.
+	async fn defer(&self, library: &Library, mut job: Box<dyn DynJob>) {
+		info!(
+			"Deferring background job: <name='{}', hash='{}'>",
+			job.name(),
+			job.hash()
+		);
+
+		// Creating the report so the job shows up as queued for the user
+		persist_queued(library, &mut job).await;
+
+		self.deferred_jobs
+			.write()
+			.await
+			.push_back((library.clone(), job));
+	}
+
+	/// Dispatches the deferred jobs that are allowed to run right now, the others are deferred again.
+	async fn dispatch_deferred(self: Arc<Self>) {
+		if self.deferred_jobs.read().await.is_empty() {
+			return;
+		}
+
+		let deferred_jobs = mem::take(&mut *self.deferred_jobs.write().await);
+		for (library, job) in deferred_jobs {
+			if self.can_run_background_job(&library, job.id()).await {
+				debug!("Dispatching deferred job: <name='{}'>", job.name());
+				Arc::clone(&self).dispatch(&library, job).await;
+			} else {
+				self.deferred_jobs.write().await.push_back((library, job));
+			}
+		}
+	}
+
+	/// Runs a deferred background job right away, ignoring the schedule policies.
+	pub async fn force_run(self: Arc<Self>, job_id: Uuid) -> Result<(), JobManagerError> {
+		let job = {
+			let mut deferred_jobs = self.deferred_jobs.write().await;
+			deferred_jobs
+				.iter()
+				.position(|(_, job)| job.id() == job_id)
+				.and_then(|idx| deferred_jobs.remove(idx))
+		};
+
+		let Some((library, job)) = job else {
+			return Err(JobManagerError::NotFound(job_id));
+		};
+
+		info!(
+			"Force running deferred job: <name='{}', id='{job_id}'>",
+			job.name()
+		);
+
+		self.forced_jobs.write().await.insert(job_id);
+		self.dispatch(&library, job).await;
+
+		Ok(())
+	}
+
+	/// Deferred jobs hold a copy of their library, so we refresh it when its config changes
+	/// and check if the new schedule allows them to run.
+	pub async fn update_deferred_library(self: Arc<Self>, library: &Library) {
+		for (deferred_library, _) in self.deferred_jobs.write().await.iter_mut() {
+			if deferred_library.id == library.id {
+				*deferred_library = library.clone();
+			}
+		}
+
+		self.dispatch_deferred().await;
+	}
+
+	/// Saves the state of deferred jobs as paused, so `cold_resume` picks them up on the next start.
+	async fn pause_deferred(&self) {
+		for (library, mut job) in mem::take(&mut *self.deferred_jobs.write().await) {
+			let state = match job.serialize_state() {
+				Ok(state) => state,
+				Err(e) => {
+					error!("Failed to serialize state of deferred job: {e:#?}");
+					continue;
+				}
+			};
+
+			if let Some(report) = job.report_mut() {
+				report.status = JobStatus::Paused;
+				report.data = Some(state);
+				if let Err(e) = report.update(&library).await {
+					error!("Failed to update report of deferred job: {e:#?}");
+				}
+			}
 		}
 	}
 
@@ -160,6 +340,7 @@ impl JobManager {
 		// remove worker from running workers and from current jobs hashes
 		self.current_jobs_hashes.write().await.remove(&job_hash);
 		self.running_workers.write().await.remove(&job_id);
+		self.forced_jobs.write().await.remove(&job_id);
 		// continue queue
 		let job = self.job_queue.write().await.pop_front();
 		if let Some(job) = job {
@@ -222,11 +403,54 @@ impl JobManager {
 		}
 	}
 
+	/// Reverts the filesystem changes made by a failed file operation job, using its journal.
+	/// A rollback that failed can be retried, as the steps already reverted are skipped.
+	pub async fn rollback(&self, library: &Library, job_id: Uuid) -> Result<(), JobManagerError> {
+		let mut report = JobReport::try_from(
+			library
+				.db
+				.job()
+				.find_unique(job::id::equals(job_id.as_bytes().to_vec()))
+				.exec()
+				.await?
+				.ok_or(JobManagerError::NotFound(job_id))?,
+		)?;
+
+		if report.status != JobStatus::Failed
+			|| !matches!(
+				report.rollback_status(),
+				Some(JobRollbackStatus::Available | JobRollbackStatus::Failed)
+			) {
+			return Err(JobManagerError::NotRevertible(job_id));
+		}
+
+		info!("Rolling back job: {} with uuid {}", report.name, job_id);
+
+		let result = JobJournal::rollback(library, job_id).await;
+
+		report.set_rollback_status(if result.is_ok() {
+			JobRollbackStatus::RolledBack
+		} else {
+			JobRollbackStatus::Failed
+		});
+		report
+			.update(library)
+			.await
+			.map_err(|e| JobManagerError::RollbackFailed(e.to_string()))?;
+
+		result.map_err(|e| JobManagerError::RollbackFailed(e.to_string()))
+	}
+
 	/// This is called at startup to resume all paused jobs or jobs that were running
 	/// when the core was shut down.
 	/// - It will resume jobs that contain data and cancel jobs that do not.
 	/// - Prevents jobs from being stuck in a paused/running state
 	pub async fn cold_resume(self: Arc<Self>, library: &Library) -> Result<(), JobManagerError> {
+		// Interrupted filesystem operations must be settled before any job touches those files again
+		if let Err(e) = JobJournal::recover_all(library).await {
+			error!("Failed to recover job journals: {e:#?}");
+		}
+
 		// Include the Queued status in the initial find condition
 		let find_condition = vec![or(vec![
 			job::status::equals(Some(JobStatus::Paused as i32)),
@@ -247,6 +471,17 @@ impl JobManager {
 			let job = job?;
 
 			match initialize_resumable_job(job.clone(), None) {
+				// Operations the user asked for but that never started may not be wanted anymore,
+				// so they wait for the user to resume them. Background jobs just go on.
+				Ok(resumable_job)
+					if job.status == JobStatus::Queued && !resumable_job.is_background() =>
+				{
+					info!("Holding pending job: {} with uuid {}", job.name, job.id);
+					self.pending_jobs
+						.write()
+						.await
+						.push_back((library.clone(), resumable_job));
+				}
 				Ok(resumable_job) => {
 					info!("Resuming job: {} with uuid {}", job.name, job.id);
 					Arc::clone(&self).dispatch(library, resumable_job).await;
@@ -272,6 +507,61 @@ impl JobManager {
 		Ok(())
 	}
 
+	/// Reports of the jobs restored from the queue of the last run, see `cold_resume`
+	pub async fn get_pending_reports(&self, library_id: Uuid) -> Vec<JobReport> {
+		self.pending_jobs
+			.read()
+			.await
+			.iter()
+			.filter(|(library, _)| library.id == library_id)
+			.filter_map(|(_, job)| job.report().clone())
+			// The serialized state is of no use to the client
+			.map(|report| JobReport {
+				data: None,
+				..report
+			})
+			.collect()
+	}
+
+	/// Dispatches the pending jobs of a library with the given ids, in the order they were queued
+	pub async fn resume_pending(self: Arc<Self>, library_id: Uuid, job_ids: &[Uuid]) {
+		for (library, job) in self.take_pending(library_id, job_ids).await {
+			info!("Resuming pending job: <name='{}'>", job.name());
+			Arc::clone(&self).dispatch(&library, job).await;
+		}
+	}
+
+	/// Cancels the pending jobs of a library with the given ids, they won't be restored again
+	pub async fn discard_pending(&self, library_id: Uuid, job_ids: &[Uuid]) {
+		for (library, mut job) in self.take_pending(library_id, job_ids).await {
+			info!("Discarding pending job: <name='{}'>", job.name());
+
+			if let Some(report) = job.report_mut() {
+				report.status = JobStatus::Canceled;
+				report.data = None;
+				if let Err(e) = report.update(&library).await {
+					error!("Failed to update report of discarded job: {e:#?}");
+				}
+			}
+		}
+	}
+
+	async fn take_pending(
+		&self,
+		library_id: Uuid,
+		job_ids: &[Uuid],
+	) -> Vec<(Library, Box<dyn DynJob>)> {
+		let mut pending_jobs = self.pending_jobs.write().await;
+
+		let (taken, kept): (Vec<_>, VecDeque<_>) = mem::take(&mut *pending_jobs)
+			.into_iter()
+			.partition(|(library, job)| library.id == library_id && job_ids.contains(&job.id()));
+
+		*pending_jobs = kept;
+
+		taken
+	}
+
 	// get all active jobs, including paused jobs
 	pub async fn get_active_reports(&self) -> HashMap<String, JobReport> {
 		let mut active_reports = HashMap::new();
@@ -295,6 +585,34 @@ impl JobManager {
 	}
 }
 
+/// Saves a job that is waiting to run, with its init and the choices it carries, like the
+/// conflict policy of a copy, so it can be restored if the node stops before running it.
+async fn persist_queued(library: &Library, job: &mut Box<dyn DynJob>) {
+	let state = match job.serialize_state() {
+		Ok(state) => state,
+		Err(e) => {
+			error!("Failed to serialize state of queued job: {e:#?}");
+			return;
+		}
+	};
+
+	let Some(report) = job.report_mut() else {
+		return;
+	};
+
+	report.data = Some(state);
+
+	let result = if report.created_at.is_none() {
+		report.create(library).await
+	} else {
+		report.update(library).await
+	};
+
+	if let Err(e) = result {
+		error!("Failed to persist queued job: {e:#?}");
+	}
+}
+
 #[macro_use]
 mod macros {
 	macro_rules! dispatch_call_to_job_by_name {
@@ -333,6 +651,35 @@ fn initialize_resumable_job(
 			FileCopierJob,
 			FileDeleterJob,
 			FileEraserJob,
+			FilePathNormalizerJob,
+			ArchiveCreatorJob,
+			ArchiveExtractorJob,
+			FileCompressorJob,
+			FileTieringJob,
+			FileRetrieverJob,
+			CatalogImporterJob,
+			XmpSidecarSyncJob,
+			MailIndexerJob,
+			DuplicateFoldersJob,
+			PhotoStackerJob,
+			ProjectDetectorJob,
+			FileGrouperJob,
+			ImportExternalFilesJob,
+			LocationCleanupJob,
+			TagAssignerJob,
+			NewFileActionsJob,
+			MediaConverterJob,
+			VideoTranscoderJob,
+			PdfEditorJob,
+			ImageExporterJob,
+			NsfwClassifierJob,
+			DirectoryDiffJob,
+			FolderMirrorJob,
+			CleanupAnalyzerJob,
+			BrokenLinkScannerJob,
+			ExtensionMismatchScannerJob,
+			ExtensionFixerJob,
+			PhotoTimeShifterJob,
 		]
 	)
 }
@@ -1,4 +1,4 @@
-use crate::library::Library;
+use crate::{library::Library, prisma::location};
 
 use std::{
 	collections::{hash_map::DefaultHasher, VecDeque},
@@ -14,18 +14,34 @@ use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 mod error;
+mod journal;
 mod manager;
 mod report;
+mod schedule;
+mod template;
 mod worker;
 
 pub use error::*;
+pub use journal::*;
 pub use manager::*;
 pub use report::*;
+pub use schedule::*;
+pub use template::*;
 pub use worker::*;
 
 pub type JobResult = Result<JobMetadata, JobError>;
 pub type JobMetadata = Option<serde_json::Value>;
 pub type JobRunErrors = Vec<String>;
+
+/// What happens to the jobs queued after a job with [`Job::queue_next`] when it fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChainFailurePolicy {
+	/// The jobs after it are canceled
+	#[default]
+	Abort,
+	/// The jobs after it run anyway, for chains where they don't depend on each other's success
+	Continue,
+}
 /// `JobInitData` is a trait to represent the data being passed to initialize a `Job`
 pub trait JobInitData: Serialize + DeserializeOwned + Send + Sync + Hash {
 	type Job: StatefulJob;
@@ -36,6 +52,11 @@ pub trait JobInitData: Serialize + DeserializeOwned + Send + Sync + Hash {
 		<Self as Hash>::hash(self, &mut s);
 		s.finish()
 	}
+
+	/// The location this job works on, used to tag its logs
+	fn location_id(&self) -> Option<location::id::Type> {
+		None
+	}
 }
 
 #[async_trait::async_trait]
@@ -77,6 +98,10 @@ pub trait DynJob: Send + Sync {
 	fn report(&self) -> &Option<JobReport>;
 	fn report_mut(&mut self) -> &mut Option<JobReport>;
 	fn name(&self) -> &'static str;
+	fn is_background(&self) -> bool;
+	/// Priority jobs skip ahead of the others waiting in the queue
+	fn is_priority(&self) -> bool;
+	fn location_id(&self) -> Option<location::id::Type>;
 	async fn run(
 		&mut self,
 		job_manager: Arc<JobManager>,
@@ -96,6 +121,9 @@ pub struct Job<SJob: StatefulJob> {
 	state: JobState<SJob>,
 	stateful_job: SJob,
 	next_jobs: VecDeque<Box<dyn DynJob>>,
+	priority: bool,
+	background: bool,
+	failure_policy: ChainFailurePolicy,
 }
 
 pub trait IntoJob<SJob: StatefulJob + 'static> {
@@ -140,6 +168,9 @@ where
 			},
 			stateful_job: SJob::new(),
 			next_jobs: VecDeque::new(),
+			priority: false,
+			background: false,
+			failure_policy: ChainFailurePolicy::default(),
 		})
 	}
 
@@ -160,6 +191,9 @@ where
 			},
 			stateful_job: SJob::new(),
 			next_jobs: VecDeque::new(),
+			priority: false,
+			background: false,
+			failure_policy: ChainFailurePolicy::default(),
 		})
 	}
 
@@ -169,7 +203,7 @@ where
 		NextInit: JobInitData<Job = NextSJob>,
 	{
 		let next_job_order = self.next_jobs.len() + 1;
-		self.next_jobs.push_back(Job::new_dependent(
+		let mut next_job = Job::new_dependent(
 			init,
 			self.id,
 			// SAFETY: If we're queueing a next job then we should still have a report
@@ -179,8 +213,37 @@ where
 					.as_ref()
 					.map(|parent_action| format!("{parent_action}-{next_job_order}"))
 			}),
-		));
+		);
+		next_job.priority = self.priority;
+		next_job.background = self.background;
+		next_job.failure_policy = self.failure_policy;
+		self.next_jobs.push_back(next_job);
+
+		self
+	}
+
+	/// Makes this job skip ahead of the ones waiting in the queue, along with the jobs queued after
+	/// it with [`Job::queue_next`] from now on
+	pub fn with_priority(mut self: Box<Self>) -> Box<Self> {
+		self.priority = true;
+		self
+	}
 
+	/// Makes this job wait for the schedule of the background jobs of its library like
+	/// [`StatefulJob::IS_BACKGROUND`] ones, along with the jobs queued after it with
+	/// [`Job::queue_next`] from now on
+	pub fn as_background(mut self: Box<Self>) -> Box<Self> {
+		self.background = true;
+		self
+	}
+
+	/// Sets what happens to the rest of the chain when this job fails, for the jobs queued after it
+	/// with [`Job::queue_next`] from now on too
+	pub fn with_failure_policy(
+		mut self: Box<Self>,
+		failure_policy: ChainFailurePolicy,
+	) -> Box<Self> {
+		self.failure_policy = failure_policy;
 		self
 	}
 
@@ -201,6 +264,9 @@ where
 			report: Some(report),
 			stateful_job,
 			next_jobs: next_jobs.unwrap_or_default(),
+			priority: false,
+			background: false,
+			failure_policy: ChainFailurePolicy::default(),
 		}))
 	}
 
@@ -222,6 +288,9 @@ where
 			},
 			stateful_job: SJob::new(),
 			next_jobs: VecDeque::new(),
+			priority: false,
+			background: false,
+			failure_policy: ChainFailurePolicy::default(),
 		})
 	}
 }
@@ -260,10 +329,117 @@ impl<SJob: StatefulJob> DynJob for Job<SJob> {
 		<SJob as StatefulJob>::NAME
 	}
 
+	fn is_background(&self) -> bool {
+		<SJob as StatefulJob>::IS_BACKGROUND || self.background
+	}
+
+	fn is_priority(&self) -> bool {
+		self.priority
+	}
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		self.state.init.location_id()
+	}
+
 	async fn run(
 		&mut self,
 		job_manager: Arc<JobManager>,
 		ctx: &mut WorkerContext,
+	) -> Result<(JobMetadata, JobRunErrors), JobError> {
+		let result = self.run_steps(ctx).await;
+
+		match &result {
+			// Paused and canceled jobs keep their chain as it is
+			Err(JobError::Paused(_) | JobError::Canceled(_)) => {}
+			Err(e) if self.failure_policy == ChainFailurePolicy::Abort => {
+				if !self.next_jobs.is_empty() {
+					warn!(
+						"Job '{}' failed, canceling the {} jobs queued after it: {e}",
+						self.name(),
+						self.next_jobs.len()
+					);
+				}
+
+				if let Err(e) = self.cancel_children(&ctx.library).await {
+					error!("Failed to cancel next jobs: {e:#?}");
+				}
+			}
+			_ => self.spawn_next_jobs(job_manager, &ctx.library).await,
+		}
+
+		result
+	}
+
+	fn hash(&self) -> u64 {
+		<SJob::Init as JobInitData>::hash(&self.state.init)
+	}
+
+	fn set_next_jobs(&mut self, next_jobs: VecDeque<Box<dyn DynJob>>) {
+		self.next_jobs = next_jobs;
+	}
+
+	fn serialize_state(&self) -> Result<Vec<u8>, JobError> {
+		rmp_serde::to_vec_named(&self.state).map_err(Into::into)
+	}
+
+	async fn register_children(&mut self, library: &Library) -> Result<(), JobError> {
+		for next_job in self.next_jobs.iter_mut() {
+			if let Some(next_job_report) = next_job.report_mut() {
+				if next_job_report.created_at.is_none() {
+					next_job_report.create(library).await?
+				}
+			} else {
+				return Err(JobError::MissingReport {
+					id: next_job.id(),
+					name: next_job.name().to_string(),
+				});
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn pause_children(&mut self, library: &Library) -> Result<(), JobError> {
+		for next_job in self.next_jobs.iter_mut() {
+			let state = next_job.serialize_state()?;
+			if let Some(next_job_report) = next_job.report_mut() {
+				next_job_report.status = JobStatus::Paused;
+				next_job_report.data = Some(state);
+				next_job_report.update(library).await?;
+			} else {
+				return Err(JobError::MissingReport {
+					id: next_job.id(),
+					name: next_job.name().to_string(),
+				});
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn cancel_children(&mut self, library: &Library) -> Result<(), JobError> {
+		for next_job in self.next_jobs.iter_mut() {
+			let state = next_job.serialize_state()?;
+			if let Some(next_job_report) = next_job.report_mut() {
+				next_job_report.status = JobStatus::Canceled;
+				next_job_report.data = Some(state);
+				next_job_report.update(library).await?;
+			} else {
+				return Err(JobError::MissingReport {
+					id: next_job.id(),
+					name: next_job.name().to_string(),
+				});
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl<SJob: StatefulJob> Job<SJob> {
+	async fn run_steps(
+		&mut self,
+		ctx: &mut WorkerContext,
 	) -> Result<(JobMetadata, JobRunErrors), JobError> {
 		let mut job_should_run = true;
 		let mut errors = vec![];
@@ -336,91 +512,29 @@ impl<SJob: StatefulJob> DynJob for Job<SJob> {
 			// remove the step from the queue
 			self.state.steps.pop_front();
 			self.state.step_number += 1;
+			ctx.library.metrics().job_steps.inc(SJob::NAME);
 		}
 
 		let metadata = self.stateful_job.finalize(ctx, &mut self.state).await?;
 
+		Ok((metadata, errors))
+	}
+
+	async fn spawn_next_jobs(&mut self, job_manager: Arc<JobManager>, library: &Library) {
 		let mut next_jobs = mem::take(&mut self.next_jobs);
 
 		if let Some(mut next_job) = next_jobs.pop_front() {
 			debug!(
-				"Job '{}' requested to spawn '{}' now that it's complete!",
+				"Job '{}' requested to spawn '{}' now that it's done!",
 				self.name(),
 				next_job.name()
 			);
 			next_job.set_next_jobs(next_jobs);
 
-			if let Err(e) = job_manager.clone().ingest(&ctx.library, next_job).await {
+			if let Err(e) = job_manager.ingest(library, next_job).await {
 				error!("Failed to ingest next job: {e}");
 			}
 		}
-
-		Ok((metadata, errors))
-	}
-
-	fn hash(&self) -> u64 {
-		<SJob::Init as JobInitData>::hash(&self.state.init)
-	}
-
-	fn set_next_jobs(&mut self, next_jobs: VecDeque<Box<dyn DynJob>>) {
-		self.next_jobs = next_jobs;
-	}
-
-	fn serialize_state(&self) -> Result<Vec<u8>, JobError> {
-		rmp_serde::to_vec_named(&self.state).map_err(Into::into)
-	}
-
-	async fn register_children(&mut self, library: &Library) -> Result<(), JobError> {
-		for next_job in self.next_jobs.iter_mut() {
-			if let Some(next_job_report) = next_job.report_mut() {
-				if next_job_report.created_at.is_none() {
-					next_job_report.create(library).await?
-				}
-			} else {
-				return Err(JobError::MissingReport {
-					id: next_job.id(),
-					name: next_job.name().to_string(),
-				});
-			}
-		}
-
-		Ok(())
-	}
-
-	async fn pause_children(&mut self, library: &Library) -> Result<(), JobError> {
-		for next_job in self.next_jobs.iter_mut() {
-			let state = next_job.serialize_state()?;
-			if let Some(next_job_report) = next_job.report_mut() {
-				next_job_report.status = JobStatus::Paused;
-				next_job_report.data = Some(state);
-				next_job_report.update(library).await?;
-			} else {
-				return Err(JobError::MissingReport {
-					id: next_job.id(),
-					name: next_job.name().to_string(),
-				});
-			}
-		}
-
-		Ok(())
-	}
-
-	async fn cancel_children(&mut self, library: &Library) -> Result<(), JobError> {
-		for next_job in self.next_jobs.iter_mut() {
-			let state = next_job.serialize_state()?;
-			if let Some(next_job_report) = next_job.report_mut() {
-				next_job_report.status = JobStatus::Canceled;
-				next_job_report.data = Some(state);
-				next_job_report.update(library).await?;
-			} else {
-				return Err(JobError::MissingReport {
-					id: next_job.id(),
-					name: next_job.name().to_string(),
-				});
-			}
-		}
-
-		Ok(())
 	}
 }
 
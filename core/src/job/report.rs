@@ -27,6 +27,15 @@ pub enum JobReportUpdate {
 
 job::select!(job_without_data { id name action status parent_id errors_text metadata date_created date_started date_completed task_count completed_task_count date_estimated_completion });
 
+/// Where a failed file operation job stands on reverting the changes it made,
+/// kept in the `rollback` field of the report metadata
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub enum JobRollbackStatus {
+	Available,
+	RolledBack,
+	Failed,
+}
+
 #[derive(Debug, Serialize, Deserialize, Type, Clone)]
 pub struct JobReport {
 	pub id: Uuid,
@@ -187,6 +196,24 @@ impl JobReport {
 		report
 	}
 
+	pub fn rollback_status(&self) -> Option<JobRollbackStatus> {
+		self.metadata
+			.as_ref()?
+			.get("rollback")
+			.and_then(|status| serde_json::from_value(status.clone()).ok())
+	}
+
+	pub fn set_rollback_status(&mut self, status: JobRollbackStatus) {
+		let status = serde_json::to_value(status).expect("enum serialization can't fail");
+
+		match &mut self.metadata {
+			Some(serde_json::Value::Object(metadata)) => {
+				metadata.insert("rollback".to_string(), status);
+			}
+			metadata => *metadata = Some(serde_json::json!({ "rollback": status })),
+		}
+	}
+
 	pub fn get_meta(&self) -> (String, Option<String>) {
 		// actions are formatted like "added_location" or "added_location-1"
 		let action_name = match self.action {
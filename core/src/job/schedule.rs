@@ -0,0 +1,31 @@
+use crate::node::{ResourceManager, ScheduleWindow};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+/// `JobSchedulePolicy` controls when the heavy background jobs of a library (like integrity checks)
+/// are allowed to run. A policy without windows and without `when_idle_on_ac` never holds jobs back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub struct JobSchedulePolicy {
+	/// Background jobs can run while the local time is inside any of these windows
+	pub windows: Vec<ScheduleWindow>,
+	/// Background jobs can also run outside the windows while the machine is idle and on AC power
+	pub when_idle_on_ac: bool,
+}
+
+impl JobSchedulePolicy {
+	pub fn is_unrestricted(&self) -> bool {
+		self.windows.is_empty() && !self.when_idle_on_ac
+	}
+
+	pub fn is_valid(&self) -> bool {
+		self.windows.iter().all(ScheduleWindow::is_valid)
+	}
+
+	/// Checks if background jobs are allowed to run right now under this policy
+	pub fn allows_now(&self, resources: &ResourceManager) -> bool {
+		self.is_unrestricted()
+			|| self.windows.iter().any(ScheduleWindow::contains_now)
+			|| (self.when_idle_on_ac && resources.is_idle() && !resources.on_battery())
+	}
+}
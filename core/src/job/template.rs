@@ -0,0 +1,401 @@
+//! Pipelines users save under a name, like "Ingest SD card" importing the files of a card, tagging
+//! them and generating their thumbnails, to run them again in one go. A template only holds its
+//! steps, the location, directory and sources it works on are given each time it runs.
+//!
+//! The steps become a chain of jobs, see [`Job::queue_next`], shown as a single group of jobs.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	location::find_location,
+	object::{
+		file_identifier::file_identifier_job::FileIdentifierJobInit,
+		fs::{
+			import::{ImportExternalFilesJobInit, ImportMode},
+			ConflictPolicy,
+		},
+		groups::FileGrouperJobInit,
+		preview::thumbnailer_job::ThumbnailerJobInit,
+		stacks::PhotoStackerJobInit,
+		tag::TagAssignerJobInit,
+		validation::validator_job::ObjectValidatorJobInit,
+	},
+	prisma::{job_template, location, PrismaClient},
+	util::db::{maybe_missing, MissingFieldError},
+};
+
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+
+use super::{ChainFailurePolicy, Job, JobInitData, JobManagerError, StatefulJob};
+
+/// The action of the jobs run from a template, grouping them in the jobs list
+const JOB_TEMPLATE_ACTION: &str = "run_job_template";
+
+#[derive(Error, Debug)]
+pub enum JobTemplateError {
+	#[error("job template not found <id='{0}'>")]
+	NotFound(job_template::id::Type),
+	#[error("a job template needs a name")]
+	MissingName,
+	#[error("a job template needs at least one step")]
+	NoSteps,
+	#[error("the import step needs absolute source paths")]
+	InvalidSources,
+	#[error("location not found <id='{0}'>")]
+	LocationNotFound(location::id::Type),
+	#[error("invalid steps of job template <id='{0}'>: {1}")]
+	InvalidSteps(job_template::id::Type, serde_json::Error),
+	#[error(transparent)]
+	MissingField(#[from] MissingFieldError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<JobTemplateError> for rspc::Error {
+	fn from(err: JobTemplateError) -> Self {
+		match err {
+			JobTemplateError::NotFound(_) | JobTemplateError::LocationNotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			JobTemplateError::MissingName
+			| JobTemplateError::NoSteps
+			| JobTemplateError::InvalidSources => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			JobTemplateError::JobManager(e) => e.into(),
+			JobTemplateError::InvalidSteps(..)
+			| JobTemplateError::MissingField(_)
+			| JobTemplateError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// A step of a template, run on the location and directory given when running it
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum JobTemplateStep {
+	/// Brings the sources given when running the template into the directory
+	Import {
+		mode: ImportMode,
+		conflict_policy: ConflictPolicy,
+		#[serde(default)]
+		#[specta(optional)]
+		verify: bool,
+	},
+	/// Identifies the files of the directory that aren't yet
+	Identify,
+	/// Tags the files of the directory, creating the tag if there's none with this name
+	Tag {
+		tag_name: String,
+	},
+	GenerateThumbnails,
+	/// Computes the checksums the files of the location are missing
+	ValidateChecksums,
+	StackPhotos,
+	GroupFiles,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct JobTemplate {
+	pub id: job_template::id::Type,
+	pub name: String,
+	pub steps: Vec<JobTemplateStep>,
+	/// The steps after a failed one still run, instead of being canceled
+	pub continue_on_failure: bool,
+}
+
+impl TryFrom<job_template::Data> for JobTemplate {
+	type Error = JobTemplateError;
+
+	fn try_from(data: job_template::Data) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: data.id,
+			steps: serde_json::from_str(&data.steps)
+				.map_err(|e| JobTemplateError::InvalidSteps(data.id, e))?,
+			name: data.name,
+			continue_on_failure: data.continue_on_failure,
+		})
+	}
+}
+
+pub async fn list_job_templates(db: &PrismaClient) -> Result<Vec<JobTemplate>, JobTemplateError> {
+	db.job_template()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(TryInto::try_into)
+		.collect()
+}
+
+#[derive(Type, Deserialize)]
+pub struct JobTemplateCreateArgs {
+	pub name: String,
+	pub steps: Vec<JobTemplateStep>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub continue_on_failure: bool,
+}
+
+impl JobTemplateCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<JobTemplate, JobTemplateError> {
+		let name = self.name.trim().to_string();
+		if name.is_empty() {
+			return Err(JobTemplateError::MissingName);
+		}
+
+		if self.steps.is_empty() {
+			return Err(JobTemplateError::NoSteps);
+		}
+
+		let created = library
+			.db
+			.job_template()
+			.create(
+				name,
+				serialize_steps(&self.steps),
+				vec![job_template::continue_on_failure::set(
+					self.continue_on_failure,
+				)],
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "jobTemplates.list");
+
+		created.try_into()
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct JobTemplateUpdateArgs {
+	pub id: job_template::id::Type,
+	#[specta(optional)]
+	pub name: Option<String>,
+	/// Replaces the steps of the template
+	#[specta(optional)]
+	pub steps: Option<Vec<JobTemplateStep>>,
+	#[specta(optional)]
+	pub continue_on_failure: Option<bool>,
+}
+
+impl JobTemplateUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<JobTemplate, JobTemplateError> {
+		let name = match self.name.map(|name| name.trim().to_string()) {
+			Some(name) if name.is_empty() => return Err(JobTemplateError::MissingName),
+			name => name,
+		};
+
+		if self.steps.as_ref().map_or(false, Vec::is_empty) {
+			return Err(JobTemplateError::NoSteps);
+		}
+
+		find_job_template(&library.db, self.id).await?;
+
+		let updated = library
+			.db
+			.job_template()
+			.update(
+				job_template::id::equals(self.id),
+				[
+					name.map(job_template::name::set),
+					self.steps
+						.as_deref()
+						.map(serialize_steps)
+						.map(job_template::steps::set),
+					self.continue_on_failure
+						.map(job_template::continue_on_failure::set),
+					Some(job_template::date_modified::set(Utc::now().into())),
+				]
+				.into_iter()
+				.flatten()
+				.collect(),
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "jobTemplates.list");
+
+		updated.try_into()
+	}
+}
+
+pub async fn delete_job_template(
+	library: &Library,
+	id: job_template::id::Type,
+) -> Result<(), JobTemplateError> {
+	find_job_template(&library.db, id).await?;
+
+	library
+		.db
+		.job_template()
+		.delete(job_template::id::equals(id))
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "jobTemplates.list");
+
+	Ok(())
+}
+
+#[derive(Type, Deserialize)]
+pub struct JobTemplateRunArgs {
+	pub id: job_template::id::Type,
+	pub location_id: location::id::Type,
+	/// The directory the steps work on, relative to the location, the location itself when empty
+	pub path: PathBuf,
+	/// The files and directories to import, for templates with an import step
+	#[serde(default)]
+	#[specta(optional)]
+	pub sources: Vec<PathBuf>,
+}
+
+impl JobTemplateRunArgs {
+	pub async fn run(self, library: &Library) -> Result<(), JobTemplateError> {
+		let template = find_job_template(&library.db, self.id).await?;
+
+		let has_import = template
+			.steps
+			.iter()
+			.any(|step| matches!(step, JobTemplateStep::Import { .. }));
+		if has_import
+			&& (self.sources.is_empty() || self.sources.iter().any(|source| !source.is_absolute()))
+		{
+			return Err(JobTemplateError::InvalidSources);
+		}
+
+		let location = find_location(library, self.location_id)
+			.exec()
+			.await?
+			.ok_or(JobTemplateError::LocationNotFound(self.location_id))?;
+
+		let location_path = PathBuf::from(maybe_missing(&location.path, "location.path")?);
+		let sub_path = (self.path != Path::new("")).then(|| self.path.clone());
+
+		let mut inits = template.steps.into_iter().map(|step| match step {
+			JobTemplateStep::Import {
+				mode,
+				conflict_policy,
+				verify,
+			} => StepInit::Import(ImportExternalFilesJobInit {
+				location_id: location.id,
+				target_location_relative_directory_path: self.path.clone(),
+				sources: self.sources.clone(),
+				mode,
+				conflict_policy,
+				verify,
+			}),
+			JobTemplateStep::Identify => StepInit::Identify(FileIdentifierJobInit {
+				location: location.clone(),
+				sub_path: sub_path.clone(),
+			}),
+			JobTemplateStep::Tag { tag_name } => StepInit::Tag(TagAssignerJobInit {
+				location_id: location.id,
+				sub_path: sub_path.clone(),
+				tag_name,
+			}),
+			JobTemplateStep::GenerateThumbnails => StepInit::Thumbnails(ThumbnailerJobInit {
+				location: location.clone(),
+				sub_path: sub_path.clone(),
+			}),
+			JobTemplateStep::ValidateChecksums => StepInit::Validate(ObjectValidatorJobInit {
+				location_id: location.id,
+				path: location_path.clone(),
+				background: false,
+			}),
+			JobTemplateStep::StackPhotos => StepInit::StackPhotos(PhotoStackerJobInit {
+				location_id: location.id,
+			}),
+			JobTemplateStep::GroupFiles => StepInit::GroupFiles(FileGrouperJobInit {
+				location_id: location.id,
+			}),
+		});
+
+		let failure_policy = if template.continue_on_failure {
+			ChainFailurePolicy::Continue
+		} else {
+			ChainFailurePolicy::Abort
+		};
+
+		// Templates can't be saved without steps
+		let Some(first) = inits.next() else {
+			return Err(JobTemplateError::NoSteps);
+		};
+		let rest = inits.collect::<Vec<_>>();
+
+		match first {
+			StepInit::Import(init) => spawn_chain(library, init, rest, failure_policy).await,
+			StepInit::Identify(init) => spawn_chain(library, init, rest, failure_policy).await,
+			StepInit::Tag(init) => spawn_chain(library, init, rest, failure_policy).await,
+			StepInit::Thumbnails(init) => spawn_chain(library, init, rest, failure_policy).await,
+			StepInit::Validate(init) => spawn_chain(library, init, rest, failure_policy).await,
+			StepInit::StackPhotos(init) => spawn_chain(library, init, rest, failure_policy).await,
+			StepInit::GroupFiles(init) => spawn_chain(library, init, rest, failure_policy).await,
+		}
+		.map_err(Into::into)
+	}
+}
+
+/// The init of the job each step runs
+enum StepInit {
+	Import(ImportExternalFilesJobInit),
+	Identify(FileIdentifierJobInit),
+	Tag(TagAssignerJobInit),
+	Thumbnails(ThumbnailerJobInit),
+	Validate(ObjectValidatorJobInit),
+	StackPhotos(PhotoStackerJobInit),
+	GroupFiles(FileGrouperJobInit),
+}
+
+async fn spawn_chain<SJob, Init>(
+	library: &Library,
+	first: Init,
+	rest: Vec<StepInit>,
+	failure_policy: ChainFailurePolicy,
+) -> Result<(), JobManagerError>
+where
+	SJob: StatefulJob<Init = Init> + 'static,
+	Init: JobInitData<Job = SJob> + 'static,
+{
+	let job = rest.into_iter().fold(
+		Job::new_with_action(first, JOB_TEMPLATE_ACTION).with_failure_policy(failure_policy),
+		|job, init| match init {
+			StepInit::Import(init) => job.queue_next(init),
+			StepInit::Identify(init) => job.queue_next(init),
+			StepInit::Tag(init) => job.queue_next(init),
+			StepInit::Thumbnails(init) => job.queue_next(init),
+			StepInit::Validate(init) => job.queue_next(init),
+			StepInit::StackPhotos(init) => job.queue_next(init),
+			StepInit::GroupFiles(init) => job.queue_next(init),
+		},
+	);
+
+	library.spawn_job(job).await
+}
+
+async fn find_job_template(
+	db: &PrismaClient,
+	id: job_template::id::Type,
+) -> Result<JobTemplate, JobTemplateError> {
+	db.job_template()
+		.find_unique(job_template::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(JobTemplateError::NotFound(id))?
+		.try_into()
+}
+
+fn serialize_steps(steps: &[JobTemplateStep]) -> String {
+	serde_json::to_string(steps).expect("job template steps are always serializable")
+}
@@ -1,7 +1,9 @@
 use super::JobReport;
 use crate::api::CoreEvent;
 use crate::invalidate_query;
-use crate::job::{DynJob, JobError, JobManager, JobReportUpdate, JobStatus};
+use crate::job::{
+	DynJob, JobError, JobJournal, JobManager, JobReportUpdate, JobRollbackStatus, JobStatus,
+};
 use crate::library::Library;
 use chrono::{DateTime, Utc};
 use serde::Serialize;
@@ -12,7 +14,7 @@ use tokio::sync::{
 	mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
 	Mutex,
 };
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Type)]
@@ -43,9 +45,23 @@ pub struct WorkerContext {
 	events_tx: UnboundedSender<WorkerEvent>,
 	pub command_rx: Arc<Mutex<UnboundedReceiver<WorkerCommand>>>,
 	pub paused: Arc<AtomicBool>,
+	job_id: Uuid,
+	journal: Option<JobJournal>,
 }
 
 impl WorkerContext {
+	/// The journal of the running job, opened on first use as only jobs touching the filesystem need it
+	pub async fn journal(&mut self) -> Result<&mut JobJournal, JobError> {
+		if self.journal.is_none() {
+			self.journal = Some(JobJournal::open(&self.library, self.job_id).await?);
+		}
+
+		Ok(self
+			.journal
+			.as_mut()
+			.expect("journal was opened right above"))
+	}
+
 	pub fn progress(&self, updates: Vec<JobReportUpdate>) {
 		self.events_tx
 			.send(WorkerEvent::Progressed(updates))
@@ -166,6 +182,18 @@ impl Worker {
 
 		let worker = Arc::clone(&worker_mutex);
 
+		// Every log emitted while running the job is tagged with these ids
+		let span = info_span!(
+			"job",
+			library_id = %library.id,
+			job_id = %job_id,
+			job_name = job.name(),
+			location_id = field::Empty,
+		);
+		if let Some(location_id) = job.location_id() {
+			span.record("location_id", location_id);
+		}
+
 		// spawn task to handle running the job
 		tokio::spawn(async move {
 			let mut worker_ctx = WorkerContext {
@@ -173,6 +201,8 @@ impl Worker {
 				events_tx,
 				command_rx,
 				paused,
+				job_id,
+				journal: None,
 			};
 
 			// This oneshot is used to signal job completion, whether successful, failed, or paused,
@@ -180,7 +210,11 @@ impl Worker {
 			// let (done_tx, done_rx) = oneshot::channel::<()>();
 
 			// Run the job and handle the result
-			match job.run(job_manager.clone(), &mut worker_ctx).await {
+			match job
+				.run(job_manager.clone(), &mut worker_ctx)
+				.instrument(span)
+				.await
+			{
 				// -> Job completed successfully
 				Ok((metadata, errors)) if errors.is_empty() => {
 					// worker_ctx
@@ -256,6 +290,11 @@ impl Worker {
 
 					worker.report.status = JobStatus::Failed;
 					worker.report.data = None;
+					if JobJournal::can_rollback(&library, job_id).await {
+						worker
+							.report
+							.set_rollback_status(JobRollbackStatus::Available);
+					}
 					if let Err(e) = worker.report.update(&library).await {
 						error!("failed to update job report: {:#?}", e);
 					}
@@ -267,6 +306,18 @@ impl Worker {
 
 			println!("Worker completed job: {:?}", job_hash);
 
+			let (status, rollback_status) = {
+				let worker = worker.lock().await;
+				(worker.report.status, worker.report.rollback_status())
+			};
+			library.metrics().job_runs.inc(&format!("{status:?}"));
+
+			// Paused jobs will resume from their journal and failed ones may be rolled back with it,
+			// every other job is done with it
+			if status != JobStatus::Paused && rollback_status.is_none() {
+				JobJournal::remove(&library, job_id).await;
+			}
+
 			job_manager.complete(&library, job_id, job_hash).await;
 		});
 
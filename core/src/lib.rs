@@ -1,12 +1,13 @@
 #![warn(clippy::unwrap_used, clippy::panic)]
 
 use crate::{
-	api::{CoreEvent, Router},
+	api::{tokens::ApiTokens, CoreEvent, Router},
 	job::JobManager,
 	library::LibraryManager,
 	location::{LocationManager, LocationManagerError},
-	node::NodeConfigManager,
+	node::{Metrics, NodeConfigManager, ResourceManager},
 	p2p::P2PManager,
+	util::{log_buffer::LogBufferSubscriber, memory_budget::MemoryBudget},
 };
 
 pub use sd_prisma::*;
@@ -25,6 +26,8 @@ use tracing_appender::{
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 pub mod api;
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod custom_uri;
 pub(crate) mod job;
 pub mod library;
@@ -42,6 +45,9 @@ pub struct NodeContext {
 	pub jobs: Arc<JobManager>,
 	pub location_manager: Arc<LocationManager>,
 	pub event_bus_tx: broadcast::Sender<CoreEvent>,
+	pub memory_budget: Arc<MemoryBudget>,
+	pub resources: Arc<ResourceManager>,
+	pub metrics: Arc<Metrics>,
 }
 
 pub struct Node {
@@ -51,6 +57,10 @@ pub struct Node {
 	location_manager: Arc<LocationManager>,
 	jobs: Arc<JobManager>,
 	p2p: Arc<P2PManager>,
+	memory_budget: Arc<MemoryBudget>,
+	resources: Arc<ResourceManager>,
+	metrics: Arc<Metrics>,
+	api_tokens: ApiTokens,
 	event_bus: (broadcast::Sender<CoreEvent>, broadcast::Receiver<CoreEvent>),
 	// peer_request: tokio::sync::Mutex<Option<PeerRequest>>,
 }
@@ -70,8 +80,11 @@ impl Node {
 			.await
 			.map_err(NodeError::FailedToInitializeConfig)?;
 
-		let jobs = JobManager::new();
+		let metrics = Metrics::new();
+		let resources = ResourceManager::new(config.clone());
+		let jobs = JobManager::new(resources.clone());
 		let location_manager = LocationManager::new();
+		let memory_budget = MemoryBudget::new(config.get().await.memory_budget_mb);
 		let library_manager = LibraryManager::new(
 			data_dir.join("libraries"),
 			NodeContext {
@@ -80,10 +93,16 @@ impl Node {
 				location_manager: location_manager.clone(),
 				// p2p: p2p.clone(),
 				event_bus_tx: event_bus.0.clone(),
+				memory_budget: memory_budget.clone(),
+				resources: resources.clone(),
+				metrics: metrics.clone(),
 			},
 		)
 		.await?;
-		let p2p = P2PManager::new(config.clone(), library_manager.clone()).await?;
+		let p2p = P2PManager::new(config.clone(), library_manager.clone(), metrics.clone()).await?;
+		let api_tokens = ApiTokens::load(data_dir)
+			.await
+			.map_err(NodeError::FailedToLoadApiTokens)?;
 
 		#[cfg(debug_assertions)]
 		if let Some(init_data) = init_data {
@@ -100,12 +119,33 @@ impl Node {
 			location_manager,
 			jobs,
 			p2p,
+			memory_budget,
+			resources,
+			metrics,
+			api_tokens,
 			event_bus,
 			// peer_request: tokio::sync::Mutex::new(None),
 		};
 
+		let node = Arc::new(node);
+
+		#[cfg(not(feature = "mobile"))]
+		tokio::spawn({
+			let node = node.clone();
+			async move {
+				if let Err(e) = node::listen(node).await {
+					error!("Failed to start the shell integration endpoint: {e:#?}");
+				}
+			}
+		});
+
+		tokio::spawn(volume::monitor_storage(node.clone()));
+		tokio::spawn(sync::compact_logs(node.clone()));
+		tokio::spawn(object::fs::mirror::run_scheduled_mirrors(node.clone()));
+		tokio::spawn(object::fs::offline::maintain_offline_caches(node.clone()));
+
 		info!("Spacedrive online.");
-		Ok((Arc::new(node), router))
+		Ok((node, router))
 	}
 
 	pub fn init_logger(data_dir: impl AsRef<Path>) -> WorkerGuard {
@@ -125,6 +165,7 @@ impl Node {
 
 		let collector = tracing_subscriber::registry()
 			.with(fmt::Subscriber::new().with_ansi(false).with_writer(logfile))
+			.with(LogBufferSubscriber)
 			.with(
 				fmt::Subscriber::new()
 					.with_writer(std::io::stdout.with_max_level(log_filter))
@@ -180,6 +221,11 @@ impl Node {
 		guard
 	}
 
+	/// Tokens of the clients allowed to call the API, checked by the transports exposing it
+	pub fn api_tokens(&self) -> &ApiTokens {
+		&self.api_tokens
+	}
+
 	pub async fn shutdown(&self) {
 		info!("Spacedrive shutting down...");
 		self.jobs.clone().shutdown().await;
@@ -214,6 +260,8 @@ pub enum NodeError {
 	LocationManager(#[from] LocationManagerError),
 	#[error("failed to initialize p2p manager: {0}")]
 	P2PManager(#[from] sd_p2p::ManagerError),
+	#[error("failed to load the API tokens: {0}")]
+	FailedToLoadApiTokens(std::io::Error),
 	#[error("invalid platform integer: {0}")]
 	InvalidPlatformInt(u8),
 	#[cfg(debug_assertions)]
@@ -0,0 +1,59 @@
+//! A record of what automations did on their own, like the actions run on new files, so users can
+//! tell why a file was tagged or where a converted copy came from.
+
+use crate::{
+	invalidate_query,
+	prisma::{activity_log, location, PrismaClient, SortOrder},
+};
+
+use std::{fmt::Display, path::Path};
+
+use prisma_client_rust::QueryError;
+use tracing::error;
+
+use super::Library;
+
+/// How many entries are returned when listing the activity log
+const ACTIVITY_PAGE_SIZE: i64 = 200;
+
+/// Records the outcome of an action, logging instead of failing as the action itself already ran
+pub async fn record_activity<E: Display>(
+	library: &Library,
+	location_id: Option<location::id::Type>,
+	action: &str,
+	path: Option<&Path>,
+	result: Result<(), E>,
+) {
+	if let Err(e) = library
+		.db
+		.activity_log()
+		.create(
+			action.to_string(),
+			vec![
+				activity_log::location_id::set(location_id),
+				activity_log::path::set(path.map(|path| path.to_string_lossy().to_string())),
+				activity_log::error::set(result.err().map(|e| e.to_string())),
+			],
+		)
+		.exec()
+		.await
+	{
+		error!("Failed to record activity '{action}': {e:#?}");
+		return;
+	}
+
+	invalidate_query!(library, "library.activity");
+}
+
+/// The latest entries first, the ones before `cursor` when given
+pub async fn list_activity(
+	db: &PrismaClient,
+	cursor: Option<activity_log::id::Type>,
+) -> Result<Vec<activity_log::Data>, QueryError> {
+	db.activity_log()
+		.find_many(cursor.map(activity_log::id::lt).into_iter().collect())
+		.order_by(activity_log::id::order(SortOrder::Desc))
+		.take(ACTIVITY_PAGE_SIZE)
+		.exec()
+		.await
+}
@@ -1,9 +1,9 @@
-use crate::prisma::{object, PrismaClient};
+use crate::{library::Library, prisma::object};
 use prisma_client_rust::not;
 use sd_file_ext::kind::ObjectKind;
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use std::{sync::Arc, vec};
+use std::vec;
 
 use strum_macros::{EnumString, EnumVariantNames};
 
@@ -69,10 +69,9 @@ impl Category {
 	}
 }
 
-pub async fn get_category_count(db: &Arc<PrismaClient>, category: Category) -> i32 {
-	db.object()
-		.count(vec![category.to_where_param()])
-		.exec()
-		.await
-		.unwrap_or(0) as i32
+pub async fn get_category_count(library: &Library, category: Category) -> i32 {
+	let mut params = vec![category.to_where_param()];
+	params.extend(library.private_locations.visible_objects().await);
+
+	library.db.object().count(params).exec().await.unwrap_or(0) as i32
 }
@@ -8,7 +8,11 @@ use specta::Type;
 use uuid::Uuid;
 
 use crate::{
+	job::JobSchedulePolicy,
+	location::file_path_helper::{rederive_dotted_names, FileNameNormalization, FileNamePolicy},
+	object::groups::{default_grouping_rules, FileGroupingRule},
 	prisma::{indexer_rule, PrismaClient},
+	sync::SyncLogRetention,
 	util::{
 		db::uuid_to_bytes,
 		migrator::{Migrate, MigratorError},
@@ -26,6 +30,28 @@ pub struct LibraryConfig {
 	pub identity: Vec<u8>,
 	/// Id of the current node
 	pub node_id: Uuid,
+	/// job_schedule controls when the heavy background jobs of this library are allowed to run.
+	#[serde(default)]
+	pub job_schedule: JobSchedulePolicy,
+	/// file_name_normalization is the unicode normalization form file names are stored with.
+	#[serde(default)]
+	pub file_name_normalization: FileNameNormalization,
+	/// file_name_policy decides which names are accepted when files are created or renamed.
+	#[serde(default)]
+	pub file_name_policy: FileNamePolicy,
+	/// file_grouping_rules decides which files the explorer lists as one, tried in order.
+	#[serde(default = "default_grouping_rules")]
+	pub file_grouping_rules: Vec<FileGroupingRule>,
+	/// sync_log_retention decides when the operations of the library are compacted.
+	#[serde(default)]
+	pub sync_log_retention: SyncLogRetention,
+	/// track_access decides if this device records when objects are opened and how often.
+	#[serde(default = "default_track_access")]
+	pub track_access: bool,
+	/// offline_cache_budget_in_mb is how much of the content of remote files this device keeps,
+	/// beyond which what isn't pinned offline is evicted.
+	#[serde(default = "default_offline_cache_budget_in_mb")]
+	pub offline_cache_budget_in_mb: u32,
 	// /// is_encrypted is a flag that is set to true if the library is encrypted.
 	// #[serde(default)]
 	// pub is_encrypted: bool,
@@ -36,6 +62,13 @@ pub struct SanitisedLibraryConfig {
 	pub name: String,
 	pub description: Option<String>,
 	pub node_id: Uuid,
+	pub job_schedule: JobSchedulePolicy,
+	pub file_name_normalization: FileNameNormalization,
+	pub file_name_policy: FileNamePolicy,
+	pub file_grouping_rules: Vec<FileGroupingRule>,
+	pub sync_log_retention: SyncLogRetention,
+	pub track_access: bool,
+	pub offline_cache_budget_in_mb: u32,
 }
 
 impl From<LibraryConfig> for SanitisedLibraryConfig {
@@ -44,6 +77,13 @@ impl From<LibraryConfig> for SanitisedLibraryConfig {
 			name: config.name,
 			description: config.description,
 			node_id: config.node_id,
+			job_schedule: config.job_schedule,
+			file_name_normalization: config.file_name_normalization,
+			file_name_policy: config.file_name_policy,
+			file_grouping_rules: config.file_grouping_rules,
+			sync_log_retention: config.sync_log_retention,
+			track_access: config.track_access,
+			offline_cache_budget_in_mb: config.offline_cache_budget_in_mb,
 		}
 	}
 }
@@ -55,13 +95,28 @@ impl LibraryConfig {
 			description: None,
 			identity: Identity::new().to_bytes().to_vec(),
 			node_id,
+			job_schedule: JobSchedulePolicy::default(),
+			file_name_normalization: FileNameNormalization::default(),
+			file_name_policy: FileNamePolicy::default(),
+			file_grouping_rules: default_grouping_rules(),
+			sync_log_retention: SyncLogRetention::default(),
+			track_access: default_track_access(),
+			offline_cache_budget_in_mb: default_offline_cache_budget_in_mb(),
 		}
 	}
 }
 
+fn default_track_access() -> bool {
+	true
+}
+
+fn default_offline_cache_budget_in_mb() -> u32 {
+	10 * 1024
+}
+
 #[async_trait::async_trait]
 impl Migrate for LibraryConfig {
-	const CURRENT_VERSION: u32 = 4;
+	const CURRENT_VERSION: u32 = 5;
 
 	type Ctx = (Uuid, PeerId, Arc<PrismaClient>);
 
@@ -135,6 +190,18 @@ impl Migrate for LibraryConfig {
 				config.insert("node_id".into(), Value::String(node_id.to_string()));
 			}
 			4 => {} // -_-
+			// Directories with dots in their names and files ending with one were indexed under
+			// the wrong name
+			5 => {
+				let normalization = config
+					.get("file_name_normalization")
+					.cloned()
+					.map(serde_json::from_value::<FileNameNormalization>)
+					.transpose()?
+					.unwrap_or_default();
+
+				rederive_dotted_names(db, node_id.as_bytes().to_vec(), normalization).await?;
+			}
 			v => unreachable!("Missing migration for library version {}", v),
 		}
 
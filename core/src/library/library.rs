@@ -3,13 +3,16 @@ use crate::{
 	job::{IntoJob, JobInitData, JobManagerError, StatefulJob},
 	location::{
 		file_path_helper::{file_path_to_full_path, IsolatedFilePathData},
+		privacy::PrivateLocations,
 		LocationManager,
 	},
-	node::NodeConfigManager,
-	object::{orphan_remover::OrphanRemoverActor, preview::get_thumbnail_path},
+	node::{Metrics, NodeConfigManager, ResourceManager},
+	object::{
+		fs::versions::FileVersions, orphan_remover::OrphanRemoverActor, preview::get_thumbnail_path,
+	},
 	prisma::{file_path, location, PrismaClient},
 	sync::SyncManager,
-	util::{db::maybe_missing, error::FileIOError},
+	util::{db::maybe_missing, error::FileIOError, memory_budget::MemoryBudget},
 	NodeContext,
 };
 
@@ -48,6 +51,10 @@ pub struct Library {
 	/// p2p identity
 	pub identity: Arc<Identity>,
 	pub orphan_remover: OrphanRemoverActor,
+	/// private locations and which of them were unlocked
+	pub private_locations: Arc<PrivateLocations>,
+	/// versions of the files folder mirrors overwrote or deleted
+	pub file_versions: Arc<FileVersions>,
 }
 
 impl Debug for Library {
@@ -93,6 +100,18 @@ impl Library {
 		&self.node_context.location_manager
 	}
 
+	pub(crate) fn memory_budget(&self) -> &Arc<MemoryBudget> {
+		&self.node_context.memory_budget
+	}
+
+	pub(crate) fn resources(&self) -> &Arc<ResourceManager> {
+		&self.node_context.resources
+	}
+
+	pub(crate) fn metrics(&self) -> &Arc<Metrics> {
+		&self.node_context.metrics
+	}
+
 	pub async fn thumbnail_exists(&self, cas_id: &str) -> Result<bool, FileIOError> {
 		let thumb_path = get_thumbnail_path(self, cas_id);
 
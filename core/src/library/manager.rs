@@ -1,13 +1,24 @@
 use crate::{
 	invalidate_query,
-	location::{indexer::rules, LocationManagerError},
+	job::JobSchedulePolicy,
+	location::{
+		file_path_helper::{FileNameNormalization, FileNamePolicy},
+		indexer::rules,
+		privacy::PrivateLocations,
+		LocationManagerError,
+	},
 	node::{NodeConfig, Platform},
-	object::orphan_remover::OrphanRemoverActor,
+	object::{
+		fs::{offline::enforce_budget, versions::FileVersions},
+		groups::FileGroupingRule,
+		orphan_remover::OrphanRemoverActor,
+	},
 	prisma::{location, node},
-	sync::{SyncManager, SyncMessage},
+	sync::{SyncLogRetention, SyncManager, SyncMessage},
 	util::{
 		db::{self, MissingFieldError},
 		error::{FileIOError, NonUtf8PathError},
+		log_buffer::LOG_BUFFER,
 		migrator::{Migrate, MigratorError},
 		MaybeUndefined,
 	},
@@ -267,6 +278,10 @@ impl LibraryManager {
 		})
 	}
 
+	pub(crate) async fn get_all_libraries(&self) -> Vec<Library> {
+		self.libraries.read().await.clone()
+	}
+
 	pub(crate) async fn get_all_libraries_config(&self) -> Vec<LibraryConfigWrapped> {
 		self.libraries
 			.read()
@@ -284,6 +299,8 @@ impl LibraryManager {
 		id: Uuid,
 		name: Option<String>,
 		description: MaybeUndefined<String>,
+		file_name_policy: Option<FileNamePolicy>,
+		file_grouping_rules: Option<Vec<FileGroupingRule>>,
 	) -> Result<(), LibraryManagerError> {
 		// check library is valid
 		let mut libraries = self.libraries.write().await;
@@ -301,6 +318,12 @@ impl LibraryManager {
 			MaybeUndefined::Null => library.config.description = None,
 			MaybeUndefined::Value(description) => library.config.description = Some(description),
 		}
+		if let Some(file_name_policy) = file_name_policy {
+			library.config.file_name_policy = file_name_policy;
+		}
+		if let Some(file_grouping_rules) = file_grouping_rules {
+			library.config.file_grouping_rules = file_grouping_rules;
+		}
 
 		LibraryConfig::save(
 			&library.config,
@@ -337,6 +360,177 @@ impl LibraryManager {
 		Ok(())
 	}
 
+	/// Updates the policy controlling when the background jobs of a library can run,
+	/// returning the updated library.
+	pub(crate) async fn update_job_schedule(
+		&self,
+		id: Uuid,
+		job_schedule: JobSchedulePolicy,
+	) -> Result<Library, LibraryManagerError> {
+		if !job_schedule.is_valid() {
+			return Err(LibraryManagerError::InvalidConfig(
+				"schedule windows hours must be between 0 and 23".to_string(),
+			));
+		}
+
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.job_schedule = job_schedule;
+
+		LibraryConfig::save(
+			&library.config,
+			&self.libraries_dir.join(format!("{id}.sdlibrary")),
+		)?;
+
+		invalidate_query!(library, "library.list");
+
+		Ok(library.clone())
+	}
+
+	/// Updates when the operations of a library are compacted, which happens on the next run of
+	/// the compaction
+	pub(crate) async fn update_sync_log_retention(
+		&self,
+		id: Uuid,
+		retention: SyncLogRetention,
+	) -> Result<(), LibraryManagerError> {
+		if retention.compact_after_days == Some(0) {
+			return Err(LibraryManagerError::InvalidConfig(
+				"operations must be kept for at least a day".to_string(),
+			));
+		}
+
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.sync_log_retention = retention;
+
+		LibraryConfig::save(
+			&library.config,
+			&self.libraries_dir.join(format!("{id}.sdlibrary")),
+		)?;
+
+		invalidate_query!(library, "library.list");
+
+		Ok(())
+	}
+
+	/// Turns the tracking of opened objects of a library on or off, the history already recorded is
+	/// kept until it's purged
+	pub(crate) async fn update_access_tracking(
+		&self,
+		id: Uuid,
+		track_access: bool,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.track_access = track_access;
+
+		LibraryConfig::save(
+			&library.config,
+			&self.libraries_dir.join(format!("{id}.sdlibrary")),
+		)?;
+
+		invalidate_query!(library, "library.list");
+
+		Ok(())
+	}
+
+	/// Sets how much content of remote files a library keeps on this device, evicting right away
+	/// what no longer fits
+	pub(crate) async fn update_offline_cache_budget(
+		&self,
+		id: Uuid,
+		budget_in_mb: u32,
+	) -> Result<(), LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.offline_cache_budget_in_mb = budget_in_mb;
+
+		LibraryConfig::save(
+			&library.config,
+			&self.libraries_dir.join(format!("{id}.sdlibrary")),
+		)?;
+
+		invalidate_query!(library, "library.list");
+
+		let library = library.clone();
+		tokio::spawn(async move {
+			if let Err(e) = enforce_budget(&library).await {
+				error!(
+					"Failed to evict the offline cache of library {}: {e:#?}",
+					library.id
+				);
+			}
+		});
+
+		Ok(())
+	}
+
+	/// Updates the unicode normalization applied to the file names of a library, returning the
+	/// updated library. Existing file paths are only renormalized by the `FilePathNormalizerJob`.
+	pub(crate) async fn update_file_name_normalization(
+		&self,
+		id: Uuid,
+		normalization: FileNameNormalization,
+	) -> Result<Library, LibraryManagerError> {
+		let mut libraries = self.libraries.write().await;
+		let library = libraries
+			.iter_mut()
+			.find(|lib| lib.id == id)
+			.ok_or(LibraryManagerError::LibraryNotFound)?;
+
+		library.config.file_name_normalization = normalization;
+
+		LibraryConfig::save(
+			&library.config,
+			&self.libraries_dir.join(format!("{id}.sdlibrary")),
+		)?;
+
+		invalidate_query!(library, "library.list");
+
+		// Watchers hold their own copy of the library, so they're restarted to pick up the new policy
+		for location in library
+			.db
+			.location()
+			.find_many(vec![])
+			.exec()
+			.await
+			.unwrap_or_else(|e| {
+				error!(
+					"Failed to get locations from database for location manager: {:#?}",
+					e
+				);
+				vec![]
+			}) {
+			if let Err(e) = self
+				.node_context
+				.location_manager
+				.add(location.id, library.clone())
+				.await
+			{
+				error!("Failed to add location to location manager: {:#?}", e);
+			}
+		}
+
+		Ok(library.clone())
+	}
+
 	pub async fn delete(&self, id: Uuid) -> Result<(), LibraryManagerError> {
 		let mut libraries = self.libraries.write().await;
 
@@ -361,8 +555,17 @@ impl LibraryManager {
 			},
 		)?;
 
+		let versions_path = library.file_versions.root();
+		match fs::remove_dir_all(versions_path).await {
+			Ok(()) => {}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+			Err(e) => return Err(FileIOError::from((versions_path, e)).into()),
+		}
+
 		invalidate_query!(library, "library.list");
 
+		LOG_BUFFER.remove_library(id);
+
 		libraries.retain(|l| l.id != id);
 
 		Ok(())
@@ -460,7 +663,7 @@ impl LibraryManager {
 
 		rules::seeder(&db).await?;
 
-		let (sync_manager, sync_rx) = SyncManager::new(&db, id);
+		let (sync_manager, sync_rx) = SyncManager::new(&db, id, node_context.metrics.clone());
 
 		Self::emit(
 			subscribers,
@@ -475,6 +678,8 @@ impl LibraryManager {
 			// key_manager,
 			sync: Arc::new(sync_manager),
 			orphan_remover: OrphanRemoverActor::spawn(db.clone()),
+			private_locations: Arc::new(PrivateLocations::load(&db).await?),
+			file_versions: Arc::new(FileVersions::new(&node_context.config.data_directory(), id)),
 			db,
 			node_local_id: node_data.id,
 			node_context,
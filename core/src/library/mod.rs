@@ -1,8 +1,13 @@
+pub mod activity;
 pub(crate) mod cat;
 mod config;
 #[allow(clippy::module_inception)]
 mod library;
 mod manager;
+pub mod quick_open;
+pub mod session;
+pub mod shelf;
+pub mod sidebar;
 
 pub use cat::*;
 pub use config::*;
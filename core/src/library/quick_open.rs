@@ -0,0 +1,392 @@
+//! Opening anything from a few typed characters, for a launcher like Spotlight. A query is looked up
+//! in file names, recently opened files, locations, tags and saved searches at once, and whatever
+//! answered within [`QUICK_OPEN_BUDGET`] is ranked together, so the launcher never waits on a slow
+//! source. Names are ranked by how they match, whole, by their start, by the start of a word, or
+//! anywhere, while locations, tags and saved searches also match the characters in order.
+
+use crate::{
+	location::{
+		file_path_helper::{file_path_for_quick_open, IsolatedFilePathData},
+		redaction::Redaction,
+	},
+	object::{access::frecent_object_ids, nsfw},
+	prisma::{file_path, location, sidebar_item, tag},
+	util::db::chain_optional_iter,
+};
+
+use std::collections::HashSet;
+
+use prisma_client_rust::QueryError;
+use serde::Serialize;
+use specta::Type;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use super::{
+	sidebar::{list_sidebar_items, SidebarTarget},
+	Library,
+};
+
+/// Sources still running past it are left out of the results
+pub const QUICK_OPEN_BUDGET: Duration = Duration::from_millis(50);
+const FILE_CANDIDATES: i64 = 50;
+const RECENT_CANDIDATES: usize = 50;
+
+// Between sources matching as well, the ones with fewer and more sought after items come first
+const RECENT_WEIGHT: f64 = 1.3;
+const LOCATION_WEIGHT: f64 = 1.2;
+const SAVED_SEARCH_WEIGHT: f64 = 1.1;
+const TAG_WEIGHT: f64 = 1.1;
+const FILE_WEIGHT: f64 = 1.0;
+
+#[derive(Serialize, Type, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type")]
+pub enum QuickOpenTarget {
+	FilePath {
+		id: file_path::id::Type,
+		location_id: Option<location::id::Type>,
+		materialized_path: Option<String>,
+		is_dir: bool,
+	},
+	Location {
+		id: location::id::Type,
+	},
+	Tag {
+		id: tag::id::Type,
+	},
+	SavedSearch {
+		id: sidebar_item::id::Type,
+		filter: String,
+	},
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct QuickOpenItem {
+	pub title: String,
+	/// Where the item is, like the location and directory of a file
+	pub subtitle: Option<String>,
+	pub score: f64,
+	/// Opened lately, which the launcher may show apart
+	pub recent: bool,
+	pub target: QuickOpenTarget,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct QuickOpenResults {
+	pub items: Vec<QuickOpenItem>,
+	/// Some sources didn't answer in time and are missing
+	pub partial: bool,
+}
+
+/// Looks the query up in every source, the best matches first. An empty query lists the files
+/// opened lately.
+pub async fn quick_open(
+	library: &Library,
+	query: &str,
+	take: usize,
+) -> Result<QuickOpenResults, QueryError> {
+	let deadline = Instant::now() + QUICK_OPEN_BUDGET;
+	let query = query.trim().to_lowercase();
+
+	let (files, recents, locations, tags, searches) = tokio::join!(
+		timeout_at(deadline, file_items(library, &query)),
+		timeout_at(deadline, recent_items(library, &query)),
+		timeout_at(deadline, location_items(library, &query)),
+		timeout_at(deadline, tag_items(library, &query)),
+		timeout_at(deadline, saved_search_items(library, &query)),
+	);
+
+	let mut partial = false;
+	let mut items = Vec::new();
+
+	for source in [files, recents, locations, tags, searches] {
+		match source {
+			Ok(source) => items.extend(source?),
+			Err(_) => partial = true,
+		}
+	}
+
+	items.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+	// Recent files are found by their name too, the best ranked one is kept
+	let mut seen = HashSet::new();
+	items.retain(|item| seen.insert(item.target.clone()));
+	items.truncate(take);
+
+	Ok(QuickOpenResults { items, partial })
+}
+
+impl file_path_for_quick_open::Data {
+	fn into_item(self, title: String, score: f64, recent: bool) -> QuickOpenItem {
+		QuickOpenItem {
+			subtitle: self.location.as_ref().and_then(|location| {
+				Some(format!(
+					"{}{}",
+					location.name.as_deref()?,
+					self.materialized_path.as_deref().unwrap_or("/")
+				))
+			}),
+			title,
+			score,
+			recent,
+			target: QuickOpenTarget::FilePath {
+				id: self.id,
+				location_id: self.location_id,
+				materialized_path: self.materialized_path,
+				is_dir: self.is_dir.unwrap_or(false),
+			},
+		}
+	}
+}
+
+/// Files in redacted locations would give their name away, so only their directories are found
+fn is_findable(redaction: &Redaction, file_path: &file_path_for_quick_open::Data) -> bool {
+	file_path.is_dir == Some(true) || !redaction.is_redacted(file_path.location_id)
+}
+
+async fn file_items(library: &Library, query: &str) -> Result<Vec<QuickOpenItem>, QueryError> {
+	if query.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let params = chain_optional_iter(
+		query
+			.split_whitespace()
+			.map(str::to_string)
+			.map(file_path::name::contains),
+		[
+			Some(file_path::location_id::not(None)),
+			library.private_locations.visible_file_paths().await,
+			nsfw::visible_file_paths(library).await?,
+		],
+	);
+
+	let redaction = Redaction::fetch(library).await?;
+
+	Ok(library
+		.db
+		.file_path()
+		.find_many(params)
+		.take(FILE_CANDIDATES)
+		.select(file_path_for_quick_open::select())
+		.exec()
+		.await?
+		.into_iter()
+		.filter(|file_path| is_findable(&redaction, file_path))
+		.filter_map(|file_path| {
+			let title = IsolatedFilePathData::try_from(&file_path).ok()?.full_name();
+			let score = match_score(query, &title, false)? * FILE_WEIGHT;
+
+			Some(file_path.into_item(title, score, false))
+		})
+		.collect())
+}
+
+async fn recent_items(library: &Library, query: &str) -> Result<Vec<QuickOpenItem>, QueryError> {
+	let params = [
+		library.private_locations.visible_objects().await,
+		nsfw::visible_objects(library).await?,
+	]
+	.into_iter()
+	.flatten()
+	.collect();
+
+	let ids = frecent_object_ids(library, params, RECENT_CANDIDATES).await?;
+	if ids.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let redaction = Redaction::fetch(library).await?;
+
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(chain_optional_iter(
+			[file_path::object_id::in_vec(ids.clone())],
+			[library.private_locations.visible_file_paths().await],
+		))
+		.select(file_path_for_quick_open::select())
+		.exec()
+		.await?;
+
+	Ok(file_paths
+		.into_iter()
+		.filter(|file_path| is_findable(&redaction, file_path))
+		.filter_map(|file_path| {
+			let rank = ids
+				.iter()
+				.position(|id| Some(*id) == file_path.object_id)
+				.unwrap_or(ids.len());
+			let title = IsolatedFilePathData::try_from(&file_path).ok()?.full_name();
+			let score = if query.is_empty() {
+				1.0
+			} else {
+				match_score(query, &title, false)?
+			};
+			// The more often and lately opened, the higher, up to a tenth
+			let frecency = 0.1 * (1.0 - rank as f64 / ids.len() as f64);
+
+			Some(file_path.into_item(title, (score + frecency) * RECENT_WEIGHT, true))
+		})
+		.collect())
+}
+
+async fn location_items(library: &Library, query: &str) -> Result<Vec<QuickOpenItem>, QueryError> {
+	if query.is_empty() {
+		return Ok(vec![]);
+	}
+
+	Ok(library
+		.db
+		.location()
+		.find_many(vec![])
+		.select(location::select!({ id name path }))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|location| {
+			let title = location.name?;
+			let score = match_score(query, &title, true)? * LOCATION_WEIGHT;
+
+			Some(QuickOpenItem {
+				title,
+				subtitle: location.path,
+				score,
+				recent: false,
+				target: QuickOpenTarget::Location { id: location.id },
+			})
+		})
+		.collect())
+}
+
+async fn tag_items(library: &Library, query: &str) -> Result<Vec<QuickOpenItem>, QueryError> {
+	if query.is_empty() {
+		return Ok(vec![]);
+	}
+
+	Ok(library
+		.db
+		.tag()
+		.find_many(vec![])
+		.select(tag::select!({ id name }))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|tag| {
+			let title = tag.name?;
+			let score = match_score(query, &title, true)? * TAG_WEIGHT;
+
+			Some(QuickOpenItem {
+				title,
+				subtitle: None,
+				score,
+				recent: false,
+				target: QuickOpenTarget::Tag { id: tag.id },
+			})
+		})
+		.collect())
+}
+
+async fn saved_search_items(
+	library: &Library,
+	query: &str,
+) -> Result<Vec<QuickOpenItem>, QueryError> {
+	if query.is_empty() {
+		return Ok(vec![]);
+	}
+
+	Ok(list_sidebar_items(&library.db)
+		.await?
+		.into_iter()
+		.filter_map(|item| {
+			let SidebarTarget::SavedSearch { filter } = item.target else {
+				return None;
+			};
+			let title = item.name?;
+			let score = match_score(query, &title, true)? * SAVED_SEARCH_WEIGHT;
+
+			Some(QuickOpenItem {
+				title,
+				subtitle: None,
+				score,
+				recent: false,
+				target: QuickOpenTarget::SavedSearch {
+					id: item.id,
+					filter,
+				},
+			})
+		})
+		.collect())
+}
+
+/// How well a name matches the lowercased query, from 0 to 1, every word of the query having to
+/// match. `in_order` also matches words whose characters are in the name in order, like `dl` for
+/// `Downloads`.
+fn match_score(query: &str, name: &str, in_order: bool) -> Option<f64> {
+	let name = name.to_lowercase();
+	let words = query.split_whitespace().collect::<Vec<_>>();
+	if words.is_empty() {
+		return None;
+	}
+
+	let mut total = 0.0;
+	for word in &words {
+		total += if name == *word {
+			1.0
+		} else if name.starts_with(word) {
+			0.9
+		} else if name
+			.match_indices(word)
+			.any(|(i, _)| is_word_start(&name, i))
+		{
+			0.8
+		} else if name.contains(word) {
+			0.7
+		} else if in_order && is_subsequence(word, &name) {
+			0.4
+		} else {
+			return None;
+		};
+	}
+
+	// Of names matching the same way, the ones with less left untyped are closer to what's sought
+	let typed = words.iter().map(|word| word.chars().count()).sum::<usize>() as f64;
+	let coverage = (typed / name.chars().count().max(1) as f64).min(1.0);
+
+	Some(total / words.len() as f64 * (0.9 + 0.1 * coverage))
+}
+
+fn is_word_start(name: &str, i: usize) -> bool {
+	name[..i]
+		.chars()
+		.next_back()
+		.map_or(true, |c| !c.is_alphanumeric())
+}
+
+fn is_subsequence(word: &str, name: &str) -> bool {
+	let mut chars = name.chars();
+	word.chars().all(|c| chars.any(|n| n == c))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_are_ranked_by_how_they_match() {
+		let score = |query, name| match_score(query, name, true).unwrap_or(0.0);
+
+		assert!(score("notes", "Notes") > score("notes", "notes.txt"));
+		assert!(score("notes", "notes.txt") > score("notes", "my notes.txt"));
+		assert!(score("notes", "my notes.txt") > score("notes", "footnotes.txt"));
+		assert!(score("notes", "footnotes.txt") > score("dl", "Downloads"));
+		assert!(score("dl", "Downloads") > 0.0);
+	}
+
+	#[test]
+	fn every_word_has_to_match() {
+		assert!(match_score("tax 2023", "Tax return 2023.pdf", false).is_some());
+		assert!(match_score("tax 2022", "Tax return 2023.pdf", false).is_none());
+		assert!(match_score("dl", "Downloads", false).is_none());
+		assert!(match_score("", "Downloads", true).is_none());
+	}
+}
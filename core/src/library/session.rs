@@ -0,0 +1,196 @@
+//! What the clients of a library store to restore their explorer, like the directory each tab is
+//! in, the layout and the sort choices, so opening the library on another device picks up where it
+//! was left. Core doesn't look into the values, they're whatever JSON the client stored.
+//!
+//! Entries are synced and identified by the client and their key, see [`entry_pub_id`], so two
+//! devices storing the same key update the same record and the last one wins.
+
+use crate::{
+	invalidate_query,
+	prisma::{session_state, PrismaClient},
+	sync,
+};
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde_json::json;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::Library;
+
+const MAX_KEY_LEN: usize = 256;
+/// Values are restored on startup, so they're kept small
+const MAX_VALUE_LEN: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum SessionStateError {
+	#[error("session state keys can't be empty")]
+	EmptyKey,
+	#[error("session state key is too long <len='{0}'>")]
+	KeyTooLong(usize),
+	#[error("session state value is too large <len='{0}'>")]
+	ValueTooLarge(usize),
+	#[error("session state value isn't valid JSON: {0}")]
+	InvalidValue(#[from] serde_json::Error),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<SessionStateError> for rspc::Error {
+	fn from(err: SessionStateError) -> Self {
+		let code = match err {
+			SessionStateError::Database(_) => ErrorCode::InternalServerError,
+			_ => ErrorCode::BadRequest,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+/// The pub_id of the entry of a client for a key
+fn entry_pub_id(client: &str, key: &str) -> Vec<u8> {
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(client.as_bytes());
+	hasher.update(&[0]);
+	hasher.update(key.as_bytes());
+
+	let mut bytes = [0; 16];
+	bytes.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+
+	Uuid::from_bytes(bytes).as_bytes().to_vec()
+}
+
+/// The values stored by a client, by their key
+pub async fn get_session(
+	db: &PrismaClient,
+	client: String,
+) -> Result<BTreeMap<String, String>, QueryError> {
+	Ok(db
+		.session_state()
+		.find_many(vec![session_state::client::equals(Some(client))])
+		.select(session_state::select!({ key value }))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|entry| Some((entry.key?, entry.value?)))
+		.collect())
+}
+
+/// Stores the value of a client for a key, `None` removing it
+pub async fn set_session_state(
+	library: &Library,
+	client: String,
+	key: String,
+	value: Option<String>,
+) -> Result<(), SessionStateError> {
+	let Library { db, sync, .. } = library;
+
+	if key.is_empty() {
+		return Err(SessionStateError::EmptyKey);
+	}
+	if key.len() > MAX_KEY_LEN {
+		return Err(SessionStateError::KeyTooLong(key.len()));
+	}
+
+	let pub_id = entry_pub_id(&client, &key);
+	let sync_id = || sync::session_state::SyncId {
+		pub_id: pub_id.clone(),
+	};
+
+	match value {
+		Some(value) => {
+			if value.len() > MAX_VALUE_LEN {
+				return Err(SessionStateError::ValueTooLarge(value.len()));
+			}
+			serde_json::from_str::<serde::de::IgnoredAny>(&value)?;
+
+			let date_modified = Utc::now();
+
+			sync.write_op(
+				db,
+				sync.unique_shared_create(
+					sync_id(),
+					[
+						(session_state::client::NAME, json!(&client)),
+						(session_state::key::NAME, json!(&key)),
+						(session_state::value::NAME, json!(&value)),
+						(session_state::date_modified::NAME, json!(date_modified)),
+					],
+				),
+				db.session_state().upsert(
+					session_state::pub_id::equals(pub_id.clone()),
+					session_state::create(
+						pub_id.clone(),
+						vec![
+							session_state::client::set(Some(client)),
+							session_state::key::set(Some(key)),
+							session_state::value::set(Some(value.clone())),
+							session_state::date_modified::set(Some(date_modified.into())),
+						],
+					),
+					vec![
+						session_state::value::set(Some(value)),
+						session_state::date_modified::set(Some(date_modified.into())),
+					],
+				),
+			)
+			.await?;
+		}
+		None => {
+			sync.write_op(
+				db,
+				sync.shared_delete(sync_id()),
+				db.session_state()
+					.delete_many(vec![session_state::pub_id::equals(pub_id.clone())]),
+			)
+			.await?;
+		}
+	}
+
+	invalidate_query!(library, "library.session");
+
+	Ok(())
+}
+
+/// Removes every value stored by a client, returning how many there were
+pub async fn clear_session(library: &Library, client: String) -> Result<usize, QueryError> {
+	let Library { db, sync, .. } = library;
+
+	let entries = db
+		.session_state()
+		.find_many(vec![session_state::client::equals(Some(client))])
+		.select(session_state::select!({ pub_id }))
+		.exec()
+		.await?;
+
+	if entries.is_empty() {
+		return Ok(0);
+	}
+
+	let count = entries.len();
+
+	sync.write_ops(
+		db,
+		entries
+			.into_iter()
+			.map(|entry| {
+				(
+					sync.shared_delete(sync::session_state::SyncId {
+						pub_id: entry.pub_id.clone(),
+					}),
+					db.session_state()
+						.delete(session_state::pub_id::equals(entry.pub_id)),
+				)
+			})
+			.unzip::<_, _, Vec<_>, Vec<_>>(),
+	)
+	.await?;
+
+	invalidate_query!(library, "library.session");
+
+	Ok(count)
+}
@@ -0,0 +1,352 @@
+//! A shelf where a client collects files and directories from anywhere in the library, to move,
+//! copy, archive, delete or edit all of them at once afterwards. Every client has its own, named
+//! like the clients of `library::session`, and it's stored so it outlasts navigating away and
+//! restarting. It stays on this device, as the files on it are the ones indexed here.
+//!
+//! Operations on files start a job for each location the items are in, as file jobs act within a
+//! single one. Items whose file is gone from the index fall off the shelf.
+
+use crate::{
+	invalidate_query,
+	job::JobManagerError,
+	location::file_path_helper::IsolatedFilePathData,
+	object::{
+		fs::{
+			archive::{ArchiveCreatorJobInit, ArchiveFormat},
+			copy::FileCopierJobInit,
+			cut::FileCutterJobInit,
+			delete::FileDeleterJobInit,
+		},
+		patch::{patch_objects, ObjectPatch, ObjectPatchError},
+	},
+	prisma::{file_path, location, shelf_item, PrismaClient, SortOrder},
+	util::db::chain_optional_iter,
+};
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+
+use super::Library;
+
+#[derive(Error, Debug)]
+pub enum ShelfError {
+	#[error("the shelf is empty")]
+	Empty,
+	#[error("invalid archive name <name='{0}'>")]
+	InvalidName(String),
+	#[error(transparent)]
+	Patch(#[from] ObjectPatchError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<ShelfError> for rspc::Error {
+	fn from(err: ShelfError) -> Self {
+		match err {
+			ShelfError::Patch(e) => e.into(),
+			ShelfError::JobManager(e) => e.into(),
+			ShelfError::Empty | ShelfError::InvalidName(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ShelfError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+shelf_item::include!(shelf_item_with_file_path { file_path });
+
+/// What to do with everything on a shelf
+#[derive(Deserialize, Type, Debug)]
+#[serde(tag = "type")]
+pub enum ShelfOperation {
+	Move {
+		target_location_id: location::id::Type,
+		target_location_relative_directory_path: PathBuf,
+	},
+	Copy {
+		target_location_id: location::id::Type,
+		target_location_relative_directory_path: PathBuf,
+	},
+	/// Items of different locations go to an archive for each location, named after it
+	Archive {
+		target_location_id: location::id::Type,
+		target_location_relative_directory_path: PathBuf,
+		/// Without the extension
+		name: String,
+		format: ArchiveFormat,
+	},
+	Delete,
+	/// Tags, ratings and the other metadata of the objects of the items, see `object::patch`
+	Patch {
+		patch: ObjectPatch,
+	},
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ShelfApplyReport {
+	/// Jobs started for file operations
+	pub jobs: u32,
+	/// Objects edited by a patch
+	pub objects: u32,
+	/// Items without an object, which a patch skips
+	pub skipped: u32,
+}
+
+/// The items on the shelf of a client, in the order they were put there
+pub async fn list_shelf(
+	library: &Library,
+	client: String,
+) -> Result<Vec<shelf_item_with_file_path::Data>, QueryError> {
+	library
+		.db
+		.shelf_item()
+		.find_many(chain_optional_iter(
+			[shelf_item::client::equals(client)],
+			[library
+				.private_locations
+				.visible_file_paths()
+				.await
+				.map(|param| shelf_item::file_path::is(vec![param]))],
+		))
+		.order_by(shelf_item::date_added::order(SortOrder::Asc))
+		.include(shelf_item_with_file_path::include())
+		.exec()
+		.await
+}
+
+/// Puts files and directories on the shelf of a client, returning how many weren't already on it.
+/// Ids of paths which don't exist, or are in a locked private location, are left out.
+pub async fn add_to_shelf(
+	library: &Library,
+	client: String,
+	file_path_ids: Vec<file_path::id::Type>,
+) -> Result<usize, QueryError> {
+	let db = &library.db;
+
+	let file_paths = db
+		.file_path()
+		.find_many(chain_optional_iter(
+			[file_path::id::in_vec(file_path_ids)],
+			[library.private_locations.visible_file_paths().await],
+		))
+		.select(file_path::select!({ id }))
+		.exec()
+		.await?;
+
+	let added = db
+		.shelf_item()
+		.create_many(
+			file_paths
+				.into_iter()
+				.map(|file_path| shelf_item::create_unchecked(client.clone(), file_path.id, vec![]))
+				.collect(),
+		)
+		.skip_duplicates()
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "shelf.list");
+
+	Ok(added as usize)
+}
+
+/// Takes items off the shelf of a client, every one of them when `file_path_ids` is `None`
+pub async fn remove_from_shelf(
+	library: &Library,
+	client: String,
+	file_path_ids: Option<Vec<file_path::id::Type>>,
+) -> Result<(), QueryError> {
+	remove_items(&library.db, client, file_path_ids).await?;
+
+	invalidate_query!(library, "shelf.list");
+
+	Ok(())
+}
+
+async fn remove_items(
+	db: &PrismaClient,
+	client: String,
+	file_path_ids: Option<Vec<file_path::id::Type>>,
+) -> Result<i64, QueryError> {
+	db.shelf_item()
+		.delete_many(chain_optional_iter(
+			[shelf_item::client::equals(client)],
+			[file_path_ids.map(shelf_item::file_path_id::in_vec)],
+		))
+		.exec()
+		.await
+}
+
+/// Applies an operation to everything on the shelf of a client, emptying it afterwards when
+/// `clear` is set. File operations are only started, the shelf doesn't wait for their jobs.
+pub async fn apply_shelf(
+	library: &Library,
+	client: String,
+	operation: ShelfOperation,
+	clear: bool,
+) -> Result<ShelfApplyReport, ShelfError> {
+	let items = list_shelf(library, client.clone()).await?;
+	if items.is_empty() {
+		return Err(ShelfError::Empty);
+	}
+
+	let mut report = ShelfApplyReport {
+		jobs: 0,
+		objects: 0,
+		skipped: 0,
+	};
+
+	if let ShelfOperation::Patch { patch } = operation {
+		let object_ids = items
+			.iter()
+			.filter_map(|item| item.file_path.object_id)
+			.collect::<Vec<_>>();
+		report.skipped = (items.len() - object_ids.len()) as u32;
+
+		if !object_ids.is_empty() {
+			report.objects = patch_objects(library, object_ids, patch).await?.objects;
+		}
+	} else {
+		let by_location =
+			items
+				.iter()
+				.fold(BTreeMap::<_, Vec<_>>::new(), |mut by_location, item| {
+					if let Some(location_id) = item.file_path.location_id {
+						by_location
+							.entry(location_id)
+							.or_default()
+							.push(item.file_path.id);
+					}
+					by_location
+				});
+
+		report.jobs = spawn_jobs(library, by_location, operation).await?;
+	}
+
+	if clear {
+		remove_items(&library.db, client, None).await?;
+		invalidate_query!(library, "shelf.list");
+	}
+
+	Ok(report)
+}
+
+/// Starts a job for the items of each location, returning how many were started
+async fn spawn_jobs(
+	library: &Library,
+	by_location: BTreeMap<location::id::Type, Vec<file_path::id::Type>>,
+	operation: ShelfOperation,
+) -> Result<u32, ShelfError> {
+	// Archives are named after their location when there are several
+	let location_names =
+		if by_location.len() > 1 && matches!(operation, ShelfOperation::Archive { .. }) {
+			library
+				.db
+				.location()
+				.find_many(vec![location::id::in_vec(
+					by_location.keys().copied().collect(),
+				)])
+				.select(location::select!({ id name }))
+				.exec()
+				.await?
+				.into_iter()
+				.filter_map(|location| Some((location.id, location.name?)))
+				.collect()
+		} else {
+			BTreeMap::new()
+		};
+
+	if let ShelfOperation::Archive { name, .. } = &operation {
+		if !IsolatedFilePathData::accept_file_name(name, library.config.file_name_policy) {
+			return Err(ShelfError::InvalidName(name.clone()));
+		}
+	}
+
+	let mut jobs = 0;
+
+	for (location_id, file_path_ids) in by_location {
+		match &operation {
+			ShelfOperation::Move {
+				target_location_id,
+				target_location_relative_directory_path,
+			} => {
+				library
+					.spawn_job(FileCutterJobInit {
+						source_location_id: location_id,
+						target_location_id: *target_location_id,
+						sources_file_path_ids: file_path_ids,
+						target_location_relative_directory_path:
+							target_location_relative_directory_path.clone(),
+						with_groups: false,
+					})
+					.await?
+			}
+			ShelfOperation::Copy {
+				target_location_id,
+				target_location_relative_directory_path,
+			} => {
+				library
+					.spawn_job(FileCopierJobInit {
+						source_location_id: location_id,
+						target_location_id: *target_location_id,
+						sources_file_path_ids: file_path_ids,
+						target_location_relative_directory_path:
+							target_location_relative_directory_path.clone(),
+						target_file_name_suffix: None,
+						disk_image_entries: vec![],
+						with_groups: false,
+						scrub_metadata: vec![],
+					})
+					.await?
+			}
+			ShelfOperation::Archive {
+				target_location_id,
+				target_location_relative_directory_path,
+				name,
+				format,
+			} => {
+				let name = match location_names.get(&location_id) {
+					Some(location_name) => format!("{name} ({location_name})"),
+					None if location_names.is_empty() => name.clone(),
+					None => format!("{name} ({location_id})"),
+				};
+
+				library
+					.spawn_job(ArchiveCreatorJobInit {
+						source_location_id: location_id,
+						sources_file_path_ids: file_path_ids,
+						target_location_id: *target_location_id,
+						target_location_relative_directory_path:
+							target_location_relative_directory_path.clone(),
+						name,
+						format: *format,
+						password: None,
+					})
+					.await?
+			}
+			ShelfOperation::Delete => {
+				library
+					.spawn_job(FileDeleterJobInit {
+						location_id,
+						file_path_ids,
+						with_groups: false,
+					})
+					.await?
+			}
+			ShelfOperation::Patch { .. } => continue,
+		}
+
+		jobs += 1;
+	}
+
+	Ok(jobs)
+}
@@ -0,0 +1,454 @@
+//! The quick access list of the sidebar: directories, tags, saved searches and devices pinned by
+//! users, in the order they put them. Items are synced, so the list is the same on every device of
+//! the library. They point to their target by its pub_id, and are listed with the id of the target
+//! on this device, which is `None` once the target is gone.
+
+use crate::{
+	invalidate_query,
+	prisma::{location, node, sidebar_item, tag, PrismaClient, SortOrder},
+	sync,
+	util::db::uuid_to_bytes,
+};
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::Library;
+
+#[derive(Error, Debug)]
+pub enum SidebarError {
+	#[error("sidebar item not found <id='{0}'>")]
+	NotFound(sidebar_item::id::Type),
+	#[error("the target of the sidebar item doesn't exist")]
+	TargetNotFound,
+	#[error("a saved search needs a name")]
+	MissingName,
+	#[error("the filter of a saved search must be JSON: {0}")]
+	InvalidFilter(#[from] serde_json::Error),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<SidebarError> for rspc::Error {
+	fn from(err: SidebarError) -> Self {
+		let code = match err {
+			SidebarError::NotFound(_) | SidebarError::TargetNotFound => ErrorCode::NotFound,
+			SidebarError::MissingName | SidebarError::InvalidFilter(_) => ErrorCode::BadRequest,
+			SidebarError::Database(_) => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum SidebarTarget {
+	/// A directory of a location, relative to it, the location itself when `path` is empty
+	Directory {
+		location_id: Uuid,
+		path: String,
+	},
+	Tag {
+		tag_id: Uuid,
+	},
+	/// The filter of a search, as JSON only the client reads
+	SavedSearch {
+		filter: String,
+	},
+	Device {
+		node_id: Uuid,
+	},
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct SidebarItem {
+	pub id: sidebar_item::id::Type,
+	pub name: Option<String>,
+	pub target: SidebarTarget,
+	/// The id of the location, tag or node on this device, `None` for saved searches and targets
+	/// which don't exist anymore
+	pub local_id: Option<i32>,
+}
+
+/// The items of the sidebar, in their order
+pub async fn list_sidebar_items(db: &PrismaClient) -> Result<Vec<SidebarItem>, QueryError> {
+	let items = db
+		.sidebar_item()
+		.find_many(vec![])
+		.order_by(sidebar_item::position::order(SortOrder::Asc))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|item| {
+			let target = serde_json::from_str::<SidebarTarget>(item.target.as_deref()?).ok()?;
+			Some((item.id, item.name, target))
+		})
+		.collect::<Vec<_>>();
+
+	let pub_ids = |f: fn(&SidebarTarget) -> Option<Uuid>| {
+		items
+			.iter()
+			.filter_map(|(_, _, target)| f(target))
+			.map(uuid_to_bytes)
+			.collect::<Vec<_>>()
+	};
+
+	let locations = db
+		.location()
+		.find_many(vec![location::pub_id::in_vec(pub_ids(
+			|target| match target {
+				SidebarTarget::Directory { location_id, .. } => Some(*location_id),
+				_ => None,
+			},
+		))])
+		.select(location::select!({ id pub_id }))
+		.exec()
+		.await?;
+
+	let tags = db
+		.tag()
+		.find_many(vec![tag::pub_id::in_vec(pub_ids(|target| match target {
+			SidebarTarget::Tag { tag_id } => Some(*tag_id),
+			_ => None,
+		}))])
+		.select(tag::select!({ id pub_id }))
+		.exec()
+		.await?;
+
+	let nodes = db
+		.node()
+		.find_many(vec![node::pub_id::in_vec(pub_ids(|target| match target {
+			SidebarTarget::Device { node_id } => Some(*node_id),
+			_ => None,
+		}))])
+		.select(node::select!({ id pub_id }))
+		.exec()
+		.await?;
+
+	Ok(items
+		.into_iter()
+		.map(|(id, name, target)| {
+			let local_id = match &target {
+				SidebarTarget::Directory { location_id, .. } => locations
+					.iter()
+					.find(|location| location.pub_id == location_id.as_bytes())
+					.map(|location| location.id),
+				SidebarTarget::Tag { tag_id } => tags
+					.iter()
+					.find(|tag| tag.pub_id == tag_id.as_bytes())
+					.map(|tag| tag.id),
+				SidebarTarget::SavedSearch { .. } => None,
+				SidebarTarget::Device { node_id } => nodes
+					.iter()
+					.find(|node| node.pub_id == node_id.as_bytes())
+					.map(|node| node.id),
+			};
+
+			SidebarItem {
+				id,
+				name,
+				target,
+				local_id,
+			}
+		})
+		.collect())
+}
+
+#[derive(Type, Deserialize)]
+pub struct SidebarItemCreateArgs {
+	pub target: SidebarTarget,
+	/// Shown instead of the name of the target, required for saved searches
+	#[specta(optional)]
+	pub name: Option<String>,
+}
+
+impl SidebarItemCreateArgs {
+	/// Pins the target at the end of the sidebar
+	pub async fn create(self, library: &Library) -> Result<(), SidebarError> {
+		let Library { db, sync, .. } = library;
+
+		let name = self
+			.name
+			.map(|name| name.trim().to_string())
+			.filter(|name| !name.is_empty());
+		let target = normalize_target(db, self.target, name.is_some()).await?;
+		let target = serde_json::to_string(&target)?;
+
+		let position = db
+			.sidebar_item()
+			.find_first(vec![])
+			.order_by(sidebar_item::position::order(SortOrder::Desc))
+			.select(sidebar_item::select!({ position }))
+			.exec()
+			.await?
+			.and_then(|item| item.position)
+			.map_or(0, |position| position + 1);
+
+		let pub_id = Uuid::new_v4().as_bytes().to_vec();
+		let date_created = Utc::now();
+
+		sync.write_op(
+			db,
+			sync.unique_shared_create(
+				sync::sidebar_item::SyncId {
+					pub_id: pub_id.clone(),
+				},
+				[
+					(sidebar_item::target::NAME, json!(target)),
+					(sidebar_item::name::NAME, json!(name)),
+					(sidebar_item::position::NAME, json!(position)),
+					(sidebar_item::date_created::NAME, json!(date_created)),
+				],
+			),
+			db.sidebar_item().create(
+				pub_id,
+				vec![
+					sidebar_item::target::set(Some(target)),
+					sidebar_item::name::set(name),
+					sidebar_item::position::set(Some(position)),
+					sidebar_item::date_created::set(Some(date_created.into())),
+				],
+			),
+		)
+		.await?;
+
+		invalidate_query!(library, "sidebar.list");
+
+		Ok(())
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct SidebarItemUpdateArgs {
+	pub id: sidebar_item::id::Type,
+	/// An empty name goes back to the name of the target
+	#[specta(optional)]
+	pub name: Option<String>,
+	#[specta(optional)]
+	pub target: Option<SidebarTarget>,
+}
+
+impl SidebarItemUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<(), SidebarError> {
+		let Library { db, sync, .. } = library;
+
+		let item = find_sidebar_item(db, self.id).await?;
+
+		let name = self
+			.name
+			.map(|name| Some(name.trim().to_string()).filter(|name| !name.is_empty()));
+		let has_name = name.clone().unwrap_or(item.name).is_some();
+
+		let target = match self.target {
+			Some(target) => Some(serde_json::to_string(
+				&normalize_target(db, target, has_name).await?,
+			)?),
+			None => {
+				// Clearing the name of a saved search would leave nothing to show
+				if !has_name
+					&& matches!(
+						item.target
+							.as_deref()
+							.map(serde_json::from_str::<SidebarTarget>),
+						Some(Ok(SidebarTarget::SavedSearch { .. }))
+					) {
+					return Err(SidebarError::MissingName);
+				}
+
+				None
+			}
+		};
+
+		let (ops, params): (Vec<_>, Vec<_>) = [
+			name.map(|v| {
+				(
+					(sidebar_item::name::NAME, json!(v)),
+					sidebar_item::name::set(v),
+				)
+			}),
+			target.map(|v| {
+				(
+					(sidebar_item::target::NAME, json!(v)),
+					sidebar_item::target::set(Some(v)),
+				)
+			}),
+		]
+		.into_iter()
+		.flatten()
+		.map(|((k, v), param)| {
+			(
+				sync.shared_update(
+					sync::sidebar_item::SyncId {
+						pub_id: item.pub_id.clone(),
+					},
+					k,
+					v,
+				),
+				param,
+			)
+		})
+		.unzip();
+
+		if ops.is_empty() {
+			return Ok(());
+		}
+
+		sync.write_ops(
+			db,
+			(
+				ops,
+				db.sidebar_item()
+					.update(sidebar_item::id::equals(self.id), params),
+			),
+		)
+		.await?;
+
+		invalidate_query!(library, "sidebar.list");
+
+		Ok(())
+	}
+}
+
+pub async fn delete_sidebar_item(
+	library: &Library,
+	id: sidebar_item::id::Type,
+) -> Result<(), SidebarError> {
+	let Library { db, sync, .. } = library;
+
+	let item = find_sidebar_item(db, id).await?;
+
+	sync.write_op(
+		db,
+		sync.shared_delete(sync::sidebar_item::SyncId {
+			pub_id: item.pub_id,
+		}),
+		db.sidebar_item().delete(sidebar_item::id::equals(id)),
+	)
+	.await?;
+
+	invalidate_query!(library, "sidebar.list");
+
+	Ok(())
+}
+
+/// Puts the items in the given order, the ones left out keeping their place after them
+pub async fn reorder_sidebar_items(
+	library: &Library,
+	ids: Vec<sidebar_item::id::Type>,
+) -> Result<(), SidebarError> {
+	let Library { db, sync, .. } = library;
+
+	let items = db
+		.sidebar_item()
+		.find_many(vec![])
+		.order_by(sidebar_item::position::order(SortOrder::Asc))
+		.select(sidebar_item::select!({ id pub_id position }))
+		.exec()
+		.await?;
+
+	if let Some(id) = ids
+		.iter()
+		.find(|id| !items.iter().any(|item| item.id == **id))
+	{
+		return Err(SidebarError::NotFound(*id));
+	}
+
+	let ordered = ids
+		.iter()
+		.filter_map(|id| items.iter().find(|item| item.id == *id))
+		.chain(items.iter().filter(|item| !ids.contains(&item.id)));
+
+	// Only the items changing place are written, so concurrent reorders touch as few as possible
+	let (ops, queries): (Vec<_>, Vec<_>) = ordered
+		.enumerate()
+		.map(|(position, item)| (position as i32, item))
+		.filter(|(position, item)| item.position != Some(*position))
+		.map(|(position, item)| {
+			(
+				sync.shared_update(
+					sync::sidebar_item::SyncId {
+						pub_id: item.pub_id.clone(),
+					},
+					sidebar_item::position::NAME,
+					json!(position),
+				),
+				db.sidebar_item().update(
+					sidebar_item::id::equals(item.id),
+					vec![sidebar_item::position::set(Some(position))],
+				),
+			)
+		})
+		.unzip();
+
+	if !queries.is_empty() {
+		sync.write_ops(db, (ops, queries)).await?;
+	}
+
+	invalidate_query!(library, "sidebar.list");
+
+	Ok(())
+}
+
+async fn normalize_target(
+	db: &PrismaClient,
+	target: SidebarTarget,
+	has_name: bool,
+) -> Result<SidebarTarget, SidebarError> {
+	let exists = match &target {
+		SidebarTarget::Directory { location_id, .. } => {
+			db.location()
+				.count(vec![location::pub_id::equals(uuid_to_bytes(*location_id))])
+				.exec()
+				.await? > 0
+		}
+		SidebarTarget::Tag { tag_id } => {
+			db.tag()
+				.count(vec![tag::pub_id::equals(uuid_to_bytes(*tag_id))])
+				.exec()
+				.await? > 0
+		}
+		SidebarTarget::SavedSearch { filter } => {
+			if !has_name {
+				return Err(SidebarError::MissingName);
+			}
+			serde_json::from_str::<serde::de::IgnoredAny>(filter)?;
+			true
+		}
+		SidebarTarget::Device { node_id } => {
+			db.node()
+				.count(vec![node::pub_id::equals(uuid_to_bytes(*node_id))])
+				.exec()
+				.await? > 0
+		}
+	};
+
+	if !exists {
+		return Err(SidebarError::TargetNotFound);
+	}
+
+	Ok(match target {
+		SidebarTarget::Directory { location_id, path } => SidebarTarget::Directory {
+			location_id,
+			path: path.trim_matches('/').to_string(),
+		},
+		target => target,
+	})
+}
+
+async fn find_sidebar_item(
+	db: &PrismaClient,
+	id: sidebar_item::id::Type,
+) -> Result<sidebar_item::Data, SidebarError> {
+	db.sidebar_item()
+		.find_unique(sidebar_item::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(SidebarError::NotFound(id))
+}
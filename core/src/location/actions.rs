@@ -0,0 +1,417 @@
+//! Actions run on the files showing up in a location, like converting HEIC photos to JPEG for apps
+//! that can't open them, computing checksums right away or tagging everything landing in an inbox.
+//!
+//! The watcher gathers the files created in the location and, once they stop changing, hands them
+//! to a [`NewFileActionsJob`] running in the background. Every action taken lands in the activity
+//! log of the library, see `library::activity`.
+
+use crate::{
+	extract_job_data, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::{activity::record_activity, Library},
+	object::{
+		fs::{
+			convert::{
+				convert_image, ImageConversionFormat, ImageConversionOptions, MediaConversionError,
+			},
+			extract::available_path,
+			get_location_path_from_location_id,
+		},
+		os_metadata::write_object_finder_tags_or_log,
+		validation::hash::file_checksum,
+		xmp::write_object_sidecars_or_log,
+	},
+	prisma::{file_path, location, new_file_action, object, tag, tag_on_object, PrismaClient},
+	sync,
+	util::error::FileIOError,
+};
+
+use std::{
+	hash::{Hash, Hasher},
+	path::{Path, PathBuf},
+};
+
+use prisma_client_rust::QueryError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tokio::fs;
+use tracing::debug;
+
+use super::{
+	file_path_helper::{
+		file_path_with_object, filter_existing_file_path_params, IsolatedFilePathData,
+	},
+	find_location, LocationError,
+};
+
+/// Extensions of the photos the conversion to JPEG applies to
+const HEIF_PHOTO_EXTENSIONS: [&str; 4] = ["heic", "heif", "heics", "heifs"];
+
+#[repr(i32)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Type, Eq, PartialEq)]
+pub enum NewFileActionKind {
+	/// Writes a JPEG copy next to HEIC and HEIF photos, keeping the original
+	ConvertHeicToJpeg = 0,
+	GenerateChecksum = 1,
+	ApplyTag = 2,
+}
+
+impl NewFileActionKind {
+	pub fn from_db(value: i32) -> Option<Self> {
+		match value {
+			0 => Some(Self::ConvertHeicToJpeg),
+			1 => Some(Self::GenerateChecksum),
+			2 => Some(Self::ApplyTag),
+			_ => None,
+		}
+	}
+
+	/// How the action is named in the activity log
+	fn name(self) -> &'static str {
+		match self {
+			Self::ConvertHeicToJpeg => "convert_heic_to_jpeg",
+			Self::GenerateChecksum => "generate_checksum",
+			Self::ApplyTag => "apply_tag",
+		}
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum NewFileActionError {
+	#[error(transparent)]
+	Conversion(#[from] MediaConversionError),
+	#[error("the file wasn't identified yet")]
+	NotIdentified,
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+pub async fn new_file_actions(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<Vec<new_file_action::Data>, QueryError> {
+	db.new_file_action()
+		.find_many(vec![new_file_action::location_id::equals(location_id)])
+		.exec()
+		.await
+}
+
+#[derive(Type, Deserialize)]
+pub struct NewFileActionCreateArgs {
+	pub location_id: location::id::Type,
+	pub action: NewFileActionKind,
+	/// Only the files with this extension, any file when empty
+	pub extension: Option<String>,
+	/// The tag applied by [`NewFileActionKind::ApplyTag`]
+	pub tag_id: Option<tag::id::Type>,
+}
+
+impl NewFileActionCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<new_file_action::Data, LocationError> {
+		find_location(library, self.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(self.location_id))?;
+
+		if self.action == NewFileActionKind::ApplyTag && self.tag_id.is_none() {
+			return Err(LocationError::MissingActionTag);
+		}
+
+		let extension = self
+			.extension
+			.map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+			.filter(|extension| !extension.is_empty());
+
+		let action = library
+			.db
+			.new_file_action()
+			.create(
+				location::id::equals(self.location_id),
+				self.action as i32,
+				vec![
+					new_file_action::extension::set(extension),
+					new_file_action::tag_id::set(
+						self.tag_id
+							.filter(|_| self.action == NewFileActionKind::ApplyTag),
+					),
+				],
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "locations.newFileActions");
+
+		Ok(action)
+	}
+}
+
+pub async fn delete_new_file_action(
+	library: &Library,
+	new_file_action_id: new_file_action::id::Type,
+) -> Result<(), LocationError> {
+	library
+		.db
+		.new_file_action()
+		.delete_many(vec![new_file_action::id::equals(new_file_action_id)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.newFileActions");
+
+	Ok(())
+}
+
+pub struct NewFileActionsJob {}
+
+/// `NewFileActionsJobInit` runs the actions of a location on files the watcher saw being created
+#[derive(Serialize, Deserialize)]
+pub struct NewFileActionsJobInit {
+	pub location_id: location::id::Type,
+	pub paths: Vec<PathBuf>,
+}
+
+impl Hash for NewFileActionsJobInit {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.location_id.hash(state);
+		self.paths.hash(state);
+	}
+}
+
+impl JobInitData for NewFileActionsJobInit {
+	type Job = NewFileActionsJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewFileActionsJobData {
+	location_path: PathBuf,
+	actions: Vec<new_file_action::Data>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for NewFileActionsJob {
+	type Init = NewFileActionsJobInit;
+	type Data = NewFileActionsJobData;
+	type Step = PathBuf;
+
+	const NAME: &'static str = "new_file_actions";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+
+		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
+		let actions = new_file_actions(db, state.init.location_id).await?;
+
+		// Actions removed since the files were seen have nothing left to do
+		if !actions.is_empty() {
+			state.steps = state.init.paths.iter().cloned().collect();
+		}
+
+		state.data = Some(NewFileActionsJobData {
+			location_path,
+			actions,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let library = &ctx.library;
+		let location_id = state.init.location_id;
+		let path = &state.steps[0];
+		let data = extract_job_data!(state);
+
+		match fs::metadata(path).await {
+			Ok(metadata) if metadata.is_file() => {
+				run_new_file_actions(
+					library,
+					(location_id, &data.location_path),
+					&data.actions,
+					path,
+				)
+				.await?;
+			}
+			// Moved or deleted in the meantime, or a directory
+			_ => debug!("Skipping new file actions for '{}'", path.display()),
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, _: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		Ok(Some(json!({ "files": state.init.paths.len() })))
+	}
+}
+
+/// Runs the actions matching a file, recording each of them in the activity log. An action failing
+/// doesn't stop the others.
+async fn run_new_file_actions(
+	library: &Library,
+	(location_id, location_path): (location::id::Type, &Path),
+	actions: &[new_file_action::Data],
+	path: &Path,
+) -> Result<(), JobError> {
+	let iso_file_path = IsolatedFilePathData::new(location_id, location_path, path, false)
+		.map_err(LocationError::from)?
+		.normalized(library.config.file_name_normalization);
+
+	let Some(file_path) = library
+		.db
+		.file_path()
+		.find_first(filter_existing_file_path_params(&iso_file_path))
+		.include(file_path_with_object::include())
+		.exec()
+		.await?
+	else {
+		// Ignored by the indexer rules of the location
+		debug!("New file '{}' isn't in the library", path.display());
+		return Ok(());
+	};
+
+	let extension = iso_file_path.extension().to_lowercase();
+
+	for action in actions {
+		if action
+			.extension
+			.as_ref()
+			.map_or(false, |action_extension| *action_extension != extension)
+		{
+			continue;
+		}
+
+		let Some(kind) = NewFileActionKind::from_db(action.action) else {
+			continue;
+		};
+
+		let result = match kind {
+			NewFileActionKind::ConvertHeicToJpeg => {
+				if !HEIF_PHOTO_EXTENSIONS.contains(&extension.as_str()) {
+					continue;
+				}
+
+				convert_to_jpeg(path).await.map(|target| {
+					debug!("Converted '{}' to '{}'", path.display(), target.display());
+				})
+			}
+			NewFileActionKind::GenerateChecksum => {
+				if file_path.integrity_checksum.is_some() {
+					continue;
+				}
+
+				write_checksum(library, &file_path.pub_id, path).await
+			}
+			NewFileActionKind::ApplyTag => {
+				let Some(tag_id) = action.tag_id else {
+					continue;
+				};
+
+				match &file_path.object {
+					Some(object) => apply_tag(library, tag_id, object.id).await,
+					None => Err(NewFileActionError::NotIdentified),
+				}
+			}
+		};
+
+		record_activity(library, Some(location_id), kind.name(), Some(path), result).await;
+	}
+
+	Ok(())
+}
+
+/// Writes a JPEG copy of a photo next to it, the watcher adding it to the library like any new file
+async fn convert_to_jpeg(path: &Path) -> Result<PathBuf, NewFileActionError> {
+	let mut target = path.with_extension("jpg");
+	if fs::symlink_metadata(&target).await.is_ok() {
+		target = available_path(&target);
+	}
+
+	convert_image(
+		path,
+		&target,
+		ImageConversionOptions::new(ImageConversionFormat::Jpeg),
+	)
+	.await?;
+
+	Ok(target)
+}
+
+async fn write_checksum(
+	library @ Library { db, sync, .. }: &Library,
+	pub_id: &[u8],
+	path: &Path,
+) -> Result<(), NewFileActionError> {
+	let checksum = file_checksum(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	sync.write_op(
+		db,
+		sync.shared_update(
+			sync::file_path::SyncId {
+				pub_id: pub_id.to_vec(),
+			},
+			file_path::integrity_checksum::NAME,
+			json!(&checksum),
+		),
+		db.file_path().update(
+			file_path::pub_id::equals(pub_id.to_vec()),
+			vec![file_path::integrity_checksum::set(Some(checksum))],
+		),
+	)
+	.await?;
+
+	invalidate_query!(library, "search.paths");
+
+	Ok(())
+}
+
+async fn apply_tag(
+	library: &Library,
+	tag_id: tag::id::Type,
+	object_id: object::id::Type,
+) -> Result<(), NewFileActionError> {
+	library
+		.db
+		.tag_on_object()
+		.upsert(
+			tag_on_object::tag_id_object_id(tag_id, object_id),
+			tag_on_object::create_unchecked(tag_id, object_id, vec![]),
+			vec![],
+		)
+		.exec()
+		.await?;
+
+	write_object_finder_tags_or_log(library, vec![object_id]).await;
+	write_object_sidecars_or_log(library, vec![object_id]).await;
+	invalidate_query!(library, "tags.getForObject");
+
+	Ok(())
+}
@@ -0,0 +1,239 @@
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	object::preview::get_thumbnail_path,
+	prisma::{
+		book_data, file_path, font_data, label_on_object, location, media_data, object,
+		object_in_space, tag_on_object,
+	},
+	util::error::FileIOError,
+};
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{fs, io};
+use tracing::info;
+
+/// How many file paths are removed in each step
+const BATCH_SIZE: i64 = 1000;
+
+/// Removes what a deleted location leaves behind: its file paths, the objects that only they
+/// pointed to with their media data, tags, labels and spaces, and the thumbnails nothing else
+/// uses anymore.
+///
+/// [`super::delete_location`] detaches the file paths of the location before deleting it, so this
+/// job cleans up every file path without a location, including the ones left by an interrupted run.
+pub struct LocationCleanupJob {}
+
+#[derive(Serialize, Deserialize, Debug, Hash)]
+pub struct LocationCleanupJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for LocationCleanupJobInit {
+	type Job = LocationCleanupJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LocationCleanupJobData {
+	removed_file_paths: usize,
+	removed_objects: usize,
+	removed_thumbnails: usize,
+	/// Bytes freed on disk by the removed thumbnails
+	reclaimed_bytes: u64,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for LocationCleanupJob {
+	type Init = LocationCleanupJobInit;
+	type Data = LocationCleanupJobData;
+	type Step = ();
+
+	const NAME: &'static str = "location_cleanup";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let count = ctx
+			.library
+			.db
+			.file_path()
+			.count(vec![file_path::location_id::equals(None)])
+			.exec()
+			.await?;
+
+		// At least one step, to also clean what was left by a cleanup that got interrupted
+		let batches = ((count + BATCH_SIZE - 1) / BATCH_SIZE).max(1) as usize;
+		state.steps = (0..batches).map(|_| ()).collect();
+		state.data = Some(LocationCleanupJobData::default());
+
+		ctx.progress(vec![
+			JobReportUpdate::TaskCount(batches),
+			JobReportUpdate::Message(format!("Removing {count} file paths")),
+		]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let file_paths = db
+			.file_path()
+			.find_many(vec![file_path::location_id::equals(None)])
+			.take(BATCH_SIZE)
+			.select(file_path::select!({ id object_id cas_id }))
+			.exec()
+			.await?;
+
+		let file_path_ids = file_paths.iter().map(|file_path| file_path.id).collect();
+		let object_ids = file_paths
+			.iter()
+			.filter_map(|file_path| file_path.object_id)
+			.collect::<HashSet<_>>();
+		let cas_ids = file_paths
+			.into_iter()
+			.filter_map(|file_path| file_path.cas_id)
+			.collect::<HashSet<_>>();
+
+		let removed_file_paths = db
+			.file_path()
+			.delete_many(vec![file_path::id::in_vec(file_path_ids)])
+			.exec()
+			.await? as usize;
+
+		// Objects still pointed to by a file path of another location are kept
+		let orphan_object_ids = db
+			.object()
+			.find_many(vec![
+				object::id::in_vec(object_ids.into_iter().collect()),
+				object::file_paths::none(vec![]),
+			])
+			.select(object::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|object| object.id)
+			.collect::<Vec<_>>();
+
+		// The relations to tags, labels and spaces don't cascade, so they go first
+		let (_, _, _, _, removed_objects) = db
+			._batch((
+				db.tag_on_object()
+					.delete_many(vec![tag_on_object::object_id::in_vec(
+						orphan_object_ids.clone(),
+					)]),
+				db.label_on_object()
+					.delete_many(vec![label_on_object::object_id::in_vec(
+						orphan_object_ids.clone(),
+					)]),
+				db.object_in_space()
+					.delete_many(vec![object_in_space::object_id::in_vec(
+						orphan_object_ids.clone(),
+					)]),
+				db.media_data()
+					.delete_many(vec![media_data::id::in_vec(orphan_object_ids.clone())]),
+				db.font_data()
+					.delete_many(vec![font_data::id::in_vec(orphan_object_ids.clone())]),
+				db.book_data()
+					.delete_many(vec![book_data::id::in_vec(orphan_object_ids.clone())]),
+				db.object()
+					.delete_many(vec![object::id::in_vec(orphan_object_ids)]),
+			))
+			.await?;
+
+		// Thumbnails are shared by every file path with the same content
+		let used_cas_ids = db
+			.file_path()
+			.find_many(vec![file_path::cas_id::in_vec(
+				cas_ids.iter().cloned().collect(),
+			)])
+			.select(file_path::select!({ cas_id }))
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|file_path| file_path.cas_id)
+			.collect::<HashSet<_>>();
+
+		let mut removed_thumbnails = 0;
+		let mut reclaimed_bytes = 0;
+		for cas_id in cas_ids.difference(&used_cas_ids) {
+			let thumbnail_path = get_thumbnail_path(&ctx.library, cas_id);
+
+			let size = match fs::metadata(&thumbnail_path).await {
+				Ok(metadata) => metadata.len(),
+				Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(FileIOError::from((thumbnail_path, e)).into()),
+			};
+
+			match fs::remove_file(&thumbnail_path).await {
+				Ok(()) => {
+					removed_thumbnails += 1;
+					reclaimed_bytes += size;
+				}
+				Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+				Err(e) => return Err(FileIOError::from((thumbnail_path, e)).into()),
+			}
+		}
+
+		let data = extract_job_data_mut!(state);
+		data.removed_file_paths += removed_file_paths;
+		data.removed_objects += removed_objects as usize;
+		data.removed_thumbnails += removed_thumbnails;
+		data.reclaimed_bytes += reclaimed_bytes;
+
+		ctx.progress(vec![
+			JobReportUpdate::CompletedTaskCount(state.step_number + 1),
+			JobReportUpdate::Message(format!(
+				"Removed {} file paths, reclaimed {} bytes",
+				data.removed_file_paths, data.reclaimed_bytes
+			)),
+		]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let data = extract_job_data!(state);
+
+		info!(
+			"Cleaned up deleted location {}: {} file paths, {} objects and {} thumbnails removed, \
+				{} bytes reclaimed",
+			state.init.location_id,
+			data.removed_file_paths,
+			data.removed_objects,
+			data.removed_thumbnails,
+			data.reclaimed_bytes,
+		);
+
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(json!({
+			"location_id": state.init.location_id,
+			"removed_file_paths": data.removed_file_paths,
+			"removed_objects": data.removed_objects,
+			"removed_thumbnails": data.removed_thumbnails,
+			"reclaimed_bytes": data.reclaimed_bytes,
+		})))
+	}
+}
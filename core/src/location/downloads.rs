@@ -0,0 +1,384 @@
+//! Downloads automation, for a location holding the downloads directory of a browser. Files landing
+//! in it are moved to subdirectories and tagged by rules matching their kind, their extension or
+//! the site they were downloaded from.
+//!
+//! The watcher hands over the files showing up in the root of the location once they stop
+//! changing. Partial downloads (`.part`, `.crdownload` and the like) are left alone, a download is
+//! done when the browser renames it to its final name. Files in subdirectories, where the rules
+//! move downloads to, are never taken for new downloads.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	object::{fs::extract::available_path, os_metadata::write_object_finder_tags_or_log},
+	prisma::{download_rule, location, tag, tag_on_object, PrismaClient, SortOrder},
+	util::error::FileIOError,
+};
+
+use std::path::{Component, Path};
+
+use prisma_client_rust::QueryError;
+use serde::Deserialize;
+use specta::Type;
+use tokio::fs;
+use tracing::{debug, info};
+
+use super::{
+	file_path_helper::{
+		file_path_with_object, filter_existing_file_path_params, IsolatedFilePathData,
+	},
+	find_location, LocationError,
+};
+
+/// Extensions browsers and torrent clients give to downloads still in progress
+const PARTIAL_DOWNLOAD_EXTENSIONS: [&str; 9] = [
+	"part",
+	"crdownload",
+	"download",
+	"partial",
+	"opdownload",
+	"tmp",
+	"!ut",
+	"!qb",
+	"bc!",
+];
+
+pub async fn download_rules(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<Vec<download_rule::Data>, QueryError> {
+	db.download_rule()
+		.find_many(vec![download_rule::location_id::equals(location_id)])
+		.order_by(download_rule::position::order(SortOrder::Asc))
+		.order_by(download_rule::id::order(SortOrder::Asc))
+		.exec()
+		.await
+}
+
+/// The rules the watcher applies, none while the automation is disabled for the location
+pub async fn active_download_rules(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<Vec<download_rule::Data>, QueryError> {
+	db.download_rule()
+		.find_many(vec![
+			download_rule::location_id::equals(location_id),
+			download_rule::location::is(vec![location::downloads_automation::equals(Some(true))]),
+		])
+		.order_by(download_rule::position::order(SortOrder::Asc))
+		.order_by(download_rule::id::order(SortOrder::Asc))
+		.exec()
+		.await
+}
+
+#[derive(Type, Deserialize)]
+pub struct DownloadRuleCreateArgs {
+	pub location_id: location::id::Type,
+	pub name: Option<String>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub position: Option<i32>,
+	/// An `ObjectKind`, or the `kind` of a custom kind
+	pub kind: Option<i32>,
+	pub extension: Option<String>,
+	/// Matches its subdomains too
+	pub source_domain: Option<String>,
+	/// Directory relative to the location, created when a download is first moved to it
+	pub destination: String,
+	pub tag_id: Option<tag::id::Type>,
+}
+
+impl DownloadRuleCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<download_rule::Data, LocationError> {
+		find_location(library, self.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(self.location_id))?;
+
+		// Downloads moved to the root would be taken for new ones, over and over
+		if self.destination.trim().is_empty()
+			|| !Path::new(&self.destination)
+				.components()
+				.all(|component| matches!(component, Component::Normal(_)))
+		{
+			return Err(LocationError::InvalidDownloadDestination(self.destination));
+		}
+
+		let rule = library
+			.db
+			.download_rule()
+			.create(
+				location::id::equals(self.location_id),
+				self.destination,
+				vec![
+					download_rule::name::set(self.name),
+					download_rule::position::set(self.position.unwrap_or_default()),
+					download_rule::kind::set(self.kind),
+					download_rule::extension::set(self.extension.as_deref().and_then(normalize)),
+					download_rule::source_domain::set(
+						self.source_domain.as_deref().and_then(normalize),
+					),
+					download_rule::tag_id::set(self.tag_id),
+				],
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "locations.downloadRules");
+
+		Ok(rule)
+	}
+}
+
+pub async fn delete_download_rule(
+	library: &Library,
+	download_rule_id: download_rule::id::Type,
+) -> Result<(), LocationError> {
+	library
+		.db
+		.download_rule()
+		.delete_many(vec![download_rule::id::equals(download_rule_id)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.downloadRules");
+
+	Ok(())
+}
+
+/// Lowercased, without the leading dot of an extension or the one some people put before domains
+fn normalize(value: &str) -> Option<String> {
+	let value = value.trim().trim_start_matches('.').to_lowercase();
+
+	(!value.is_empty()).then_some(value)
+}
+
+pub fn is_partial_download(path: impl AsRef<Path>) -> bool {
+	path.as_ref()
+		.extension()
+		.and_then(|extension| extension.to_str())
+		.map_or(false, |extension| {
+			PARTIAL_DOWNLOAD_EXTENSIONS
+				.iter()
+				.any(|partial| extension.eq_ignore_ascii_case(partial))
+		})
+}
+
+#[derive(Debug)]
+pub(crate) enum DownloadOutcome {
+	Moved,
+	Unmatched,
+	/// The watcher didn't add the file to the library yet, it's tried again a bit later
+	NotIndexed,
+	/// Moved or deleted before being organized
+	Gone,
+}
+
+/// Tags and moves a download with the first rule matching it
+pub(crate) async fn organize_download(
+	library: &Library,
+	(location_id, location_path): (location::id::Type, &Path),
+	rules: &[download_rule::Data],
+	path: &Path,
+) -> Result<DownloadOutcome, LocationError> {
+	let Ok(metadata) = fs::metadata(path).await else {
+		return Ok(DownloadOutcome::Gone);
+	};
+	let (true, Some(file_name)) = (metadata.is_file(), path.file_name()) else {
+		return Ok(DownloadOutcome::Unmatched);
+	};
+
+	let iso_file_path = IsolatedFilePathData::new(location_id, location_path, path, false)?
+		.normalized(library.config.file_name_normalization);
+
+	let Some(file_path) = library
+		.db
+		.file_path()
+		.find_first(filter_existing_file_path_params(&iso_file_path))
+		.include(file_path_with_object::include())
+		.exec()
+		.await?
+	else {
+		return Ok(DownloadOutcome::NotIndexed);
+	};
+	let Some(object) = file_path.object else {
+		return Ok(DownloadOutcome::NotIndexed);
+	};
+
+	let extension = iso_file_path.extension().to_lowercase();
+	let source_domain = if rules.iter().any(|rule| rule.source_domain.is_some()) {
+		read_source_url(path)
+			.await
+			.as_deref()
+			.and_then(host_from_url)
+	} else {
+		None
+	};
+
+	let Some(rule) = rules.iter().find(|rule| {
+		rule.kind.map_or(true, |kind| object.kind == Some(kind))
+			&& rule
+				.extension
+				.as_ref()
+				.map_or(true, |rule_extension| *rule_extension == extension)
+			&& rule.source_domain.as_ref().map_or(true, |domain| {
+				source_domain
+					.as_deref()
+					.map_or(false, |host| domain_matches(host, domain))
+			})
+	}) else {
+		return Ok(DownloadOutcome::Unmatched);
+	};
+
+	debug!(
+		"Download '{}' matched rule {} of location {location_id}",
+		path.display(),
+		rule.id
+	);
+
+	// Tagged before being moved, the library still knows the file by its current path, which
+	// Finder tags are written to
+	if let Some(tag_id) = rule.tag_id {
+		library
+			.db
+			.tag_on_object()
+			.upsert(
+				tag_on_object::tag_id_object_id(tag_id, object.id),
+				tag_on_object::create_unchecked(tag_id, object.id, vec![]),
+				vec![],
+			)
+			.exec()
+			.await?;
+
+		write_object_finder_tags_or_log(library, vec![object.id]).await;
+		invalidate_query!(library, "tags.getForObject");
+	}
+
+	let destination = location_path.join(&rule.destination);
+	fs::create_dir_all(&destination)
+		.await
+		.map_err(|e| FileIOError::from((&destination, e)))?;
+
+	let mut target = destination.join(file_name);
+	if fs::symlink_metadata(&target).await.is_ok() {
+		target = available_path(&target);
+	}
+
+	// The watcher sees the move and updates the file path like any other
+	fs::rename(path, &target)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	info!(
+		"Moved download '{}' to '{}'",
+		path.display(),
+		target.display()
+	);
+
+	Ok(DownloadOutcome::Moved)
+}
+
+/// Browsers record where a download came from: in the `kMDItemWhereFroms` extended attribute on
+/// macOS, in `user.xdg.origin.url` on Linux and in the `Zone.Identifier` stream on Windows
+#[cfg(target_os = "macos")]
+async fn read_source_url(path: &Path) -> Option<String> {
+	let path = path.to_path_buf();
+
+	tokio::task::spawn_blocking(move || {
+		let data = xattr::get(path, "com.apple.metadata:kMDItemWhereFroms").ok()??;
+
+		plist::from_bytes::<Vec<String>>(&data)
+			.ok()?
+			.into_iter()
+			.next()
+	})
+	.await
+	.ok()?
+}
+
+#[cfg(target_os = "linux")]
+async fn read_source_url(path: &Path) -> Option<String> {
+	let path = path.to_path_buf();
+
+	tokio::task::spawn_blocking(move || {
+		xattr::get(path, "user.xdg.origin.url")
+			.ok()?
+			.and_then(|data| String::from_utf8(data).ok())
+	})
+	.await
+	.ok()?
+}
+
+#[cfg(target_os = "windows")]
+async fn read_source_url(path: &Path) -> Option<String> {
+	let mut stream = path.as_os_str().to_owned();
+	stream.push(":Zone.Identifier");
+
+	let zone_identifier = fs::read_to_string(stream).await.ok()?;
+
+	// The referrer is only a fallback, it's usually the page holding the link
+	["HostUrl=", "ReferrerUrl="].into_iter().find_map(|key| {
+		zone_identifier
+			.lines()
+			.find_map(|line| line.trim().strip_prefix(key))
+			.map(str::to_string)
+	})
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+async fn read_source_url(_: &Path) -> Option<String> {
+	None
+}
+
+fn host_from_url(url: &str) -> Option<String> {
+	let (_, rest) = url.split_once("://")?;
+	let authority = rest.split(['/', '?', '#']).next()?;
+	let host = authority
+		.rsplit_once('@')
+		.map_or(authority, |(_, host)| host);
+	let host = if host.starts_with('[') {
+		host.split_inclusive(']').next()?
+	} else {
+		host.split(':').next()?
+	};
+
+	normalize(host)
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+	host == domain
+		|| host
+			.strip_suffix(domain)
+			.map_or(false, |subdomain| subdomain.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn partial_downloads() {
+		assert!(is_partial_download("report.pdf.crdownload"));
+		assert!(is_partial_download("report.pdf.PART"));
+		assert!(is_partial_download("Unconfirmed 12345.crdownload"));
+		assert!(!is_partial_download("report.pdf"));
+		assert!(!is_partial_download("part"));
+	}
+
+	#[test]
+	fn source_hosts() {
+		assert_eq!(
+			host_from_url("https://user@Downloads.Example.com:8443/file.zip?x=1").as_deref(),
+			Some("downloads.example.com")
+		);
+		assert_eq!(
+			host_from_url("http://[::1]:8080/file").as_deref(),
+			Some("[::1]")
+		);
+		assert_eq!(host_from_url("not a url"), None);
+
+		assert!(domain_matches("example.com", "example.com"));
+		assert!(domain_matches("downloads.example.com", "example.com"));
+		assert!(!domain_matches("badexample.com", "example.com"));
+	}
+}
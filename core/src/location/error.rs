@@ -1,4 +1,5 @@
 use crate::{
+	job::JobManagerError,
 	prisma::location,
 	util::{db::MissingFieldError, error::FileIOError},
 };
@@ -47,8 +48,29 @@ pub enum LocationError {
 	MetadataNotFound(PathBuf),
 	#[error("location already exists in database <path='{}'>", .0.display())]
 	LocationAlreadyExists(PathBuf),
-	#[error("nested location currently not supported <path='{}'>", .0.display())]
+	#[error("location is inside another location or holds one <path='{}'>", .0.display())]
 	NestedLocation(PathBuf),
+	#[error("location isn't inside another location <path='{}'>", .0.display())]
+	NotNested(PathBuf),
+	#[error("the root of a location can't be pinned <id='{0}'>")]
+	PinnedRoot(location::id::Type),
+	#[error(
+		"downloads can only be moved to a directory inside their location <destination='{0}'>"
+	)]
+	InvalidDownloadDestination(String),
+	#[error("actions applying a tag need one")]
+	MissingActionTag,
+	#[error("location can't move its cold files to itself <id='{0}'>")]
+	TieringToItself(location::id::Type),
+	#[error(
+		"only {matched} of {sampled} sampled files of the location were found in the new path <path='{}'>",
+		.path.display()
+	)]
+	RelocationMismatch {
+		path: PathBuf,
+		matched: i64,
+		sampled: i64,
+	},
 
 	// Internal Errors
 	#[error(transparent)]
@@ -66,6 +88,8 @@ pub enum LocationError {
 	#[error(transparent)]
 	LocationManager(#[from] LocationManagerError),
 	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error(transparent)]
 	FilePath(#[from] FilePathError),
 	#[error(transparent)]
 	FileIO(#[from] FileIOError),
@@ -88,6 +112,12 @@ impl From<LocationError> for rspc::Error {
 			// User's fault errors
 			LocationError::NotDirectory(_)
 			| LocationError::NestedLocation(_)
+			| LocationError::NotNested(_)
+			| LocationError::PinnedRoot(_)
+			| LocationError::InvalidDownloadDestination(_)
+			| LocationError::MissingActionTag
+			| LocationError::TieringToItself(_)
+			| LocationError::RelocationMismatch { .. }
 			| LocationError::LocationAlreadyExists(_) => {
 				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
 			}
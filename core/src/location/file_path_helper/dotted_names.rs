@@ -0,0 +1,216 @@
+use crate::prisma::{file_path, location, node, PrismaClient};
+
+use std::{
+	borrow::Cow,
+	path::{Path, PathBuf},
+};
+
+use prisma_client_rust::QueryError;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+use super::{FileNameNormalization, IsolatedFilePathData};
+
+/// Fixes the file paths indexed while the names of directories lost what came after their last
+/// dot, and the trailing dots of file names were dropped: `photos.2019` was stored as a directory
+/// named `photos`, and `notes.` as a file named `notes`.
+///
+/// Those rows are found among the ones without an extension whose name doesn't exist on disk, by
+/// listing their parent directory for the entry they were derived from. Only the locations of this
+/// node which are online can be checked, the others are fixed by their next scan.
+pub(crate) async fn rederive_dotted_names(
+	db: &PrismaClient,
+	node_pub_id: Vec<u8>,
+	normalization: FileNameNormalization,
+) -> Result<usize, QueryError> {
+	let Some(node) = db
+		.node()
+		.find_unique(node::pub_id::equals(node_pub_id))
+		.exec()
+		.await?
+	else {
+		return Ok(0);
+	};
+
+	let locations = db
+		.location()
+		.find_many(vec![location::node_id::equals(Some(node.id))])
+		.select(location::select!({ id path }))
+		.exec()
+		.await?;
+
+	let mut fixed = 0;
+
+	for location in locations {
+		let Some(location_path) = location.path.map(PathBuf::from) else {
+			continue;
+		};
+
+		if fs::metadata(&location_path).await.is_err() {
+			debug!(
+				"Location <id='{}'> is offline, its dotted names are left to its next scan",
+				location.id
+			);
+			continue;
+		}
+
+		let candidates = db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(location.id)),
+				file_path::extension::equals(Some(String::new())),
+			])
+			.select(file_path::select!({ id materialized_path is_dir name }))
+			.exec()
+			.await?;
+
+		for candidate in candidates {
+			let (Some(materialized_path), Some(name)) =
+				(candidate.materialized_path, candidate.name)
+			else {
+				continue;
+			};
+			// The location itself
+			if name.is_empty() {
+				continue;
+			}
+
+			let is_dir = candidate.is_dir.unwrap_or(false);
+			let parent = location_path.join(materialized_path.trim_start_matches('/'));
+
+			if fs::symlink_metadata(parent.join(&name)).await.is_ok() {
+				continue;
+			}
+
+			let Some(full_path) = find_entry_named(&parent, &name, is_dir, normalization).await
+			else {
+				continue;
+			};
+
+			let Ok(iso_file_path) =
+				IsolatedFilePathData::new(location.id, &location_path, &full_path, is_dir)
+			else {
+				continue;
+			};
+			let iso_file_path = iso_file_path.normalized(normalization);
+
+			if iso_file_path.name == name && iso_file_path.extension.is_empty() {
+				continue;
+			}
+
+			let taken = db
+				.file_path()
+				.count(vec![
+					file_path::location_id::equals(Some(location.id)),
+					file_path::materialized_path::equals(Some(materialized_path.clone())),
+					file_path::name::equals(Some(iso_file_path.name.to_string())),
+					file_path::extension::equals(Some(iso_file_path.extension.to_string())),
+				])
+				.exec()
+				.await? > 0;
+
+			if taken {
+				warn!(
+					"Can't rename file_path <id='{}'> to {}, it's already indexed",
+					candidate.id,
+					full_path.display()
+				);
+				continue;
+			}
+
+			db.file_path()
+				.update(
+					file_path::id::equals(candidate.id),
+					vec![
+						file_path::name::set(Some(iso_file_path.name.to_string())),
+						file_path::extension::set(Some(iso_file_path.extension.to_string())),
+					],
+				)
+				.exec()
+				.await?;
+
+			fixed += 1;
+		}
+	}
+
+	if fixed > 0 {
+		info!("Fixed the names of {fixed} file paths with dots");
+	}
+
+	Ok(fixed)
+}
+
+/// The only entry of `parent` which had `name` under the old derivation, the file stem of
+/// directories and of files without an extension
+async fn find_entry_named(
+	parent: &Path,
+	name: &str,
+	is_dir: bool,
+	normalization: FileNameNormalization,
+) -> Option<PathBuf> {
+	let mut read_dir = fs::read_dir(parent).await.ok()?;
+	let mut found = None;
+
+	while let Ok(Some(entry)) = read_dir.next_entry().await {
+		let Ok(file_type) = entry.file_type().await else {
+			continue;
+		};
+		if file_type.is_dir() != is_dir {
+			continue;
+		}
+
+		let path = entry.path();
+		let has_extension = path
+			.extension()
+			.map_or(false, |extension| !extension.is_empty());
+		if !is_dir && has_extension {
+			continue;
+		}
+
+		let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+			continue;
+		};
+
+		if normalization.normalize(Cow::Borrowed(stem)) == name {
+			if found.is_some() {
+				// Both were stored under the same name, only one of them is this row
+				return None;
+			}
+			found = Some(path);
+		}
+	}
+
+	found
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	use tempfile::tempdir;
+
+	#[tokio::test]
+	async fn finds_entries_by_their_old_name() {
+		let dir = tempdir().unwrap();
+		let root = dir.path();
+
+		fs::create_dir(root.join("photos.2019")).await.unwrap();
+		fs::create_dir(root.join("backup.a")).await.unwrap();
+		fs::create_dir(root.join("backup.b")).await.unwrap();
+		fs::write(root.join("notes."), b"").await.unwrap();
+		fs::write(root.join("report.pdf"), b"").await.unwrap();
+
+		let find = |name: &'static str, is_dir: bool| {
+			find_entry_named(root, name, is_dir, FileNameNormalization::Preserve)
+		};
+
+		assert_eq!(find("photos", true).await, Some(root.join("photos.2019")));
+		assert_eq!(find("notes", false).await, Some(root.join("notes.")));
+		// Files with an extension were always stored right
+		assert_eq!(find("report", false).await, None);
+		assert_eq!(find("photos", false).await, None);
+		// Can't tell which one the row was
+		assert_eq!(find("backup", true).await, None);
+	}
+}
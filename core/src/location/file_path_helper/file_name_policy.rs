@@ -0,0 +1,124 @@
+use crate::node::Platform;
+
+use std::sync::OnceLock;
+
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+/// Every platform a library can be synced to, used to report where a name can't be created
+const TARGET_PLATFORMS: [Platform; 5] = [
+	Platform::Windows,
+	Platform::MacOS,
+	Platform::Linux,
+	Platform::IOS,
+	Platform::Android,
+];
+
+/// Most filesystems limit a name to 255 bytes (or UTF-16 code units on Windows)
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+static WINDOWS_FORBIDDEN_FILE_NAMES: OnceLock<RegexSet> = OnceLock::new();
+static UNIX_FORBIDDEN_FILE_NAMES: OnceLock<RegexSet> = OnceLock::new();
+
+/// `FileNamePolicy` decides which names are accepted when files are created or renamed in a library.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub enum FileNamePolicy {
+	/// Only the rules of the platform we're running on are enforced
+	#[default]
+	Native,
+	/// Windows rules are enforced on every platform, so names can always be synced to Windows nodes
+	WindowsCompatible,
+}
+
+impl FileNamePolicy {
+	pub fn accepts(self, name: &str) -> bool {
+		let current = Platform::current();
+
+		is_file_name_valid_on(name, current)
+			&& (self == Self::Native || is_file_name_valid_on(name, Platform::Windows))
+	}
+}
+
+/// Returns the platforms where a file with this name can't be created
+pub fn platforms_rejecting_file_name(name: &str) -> Vec<Platform> {
+	TARGET_PLATFORMS
+		.into_iter()
+		.filter(|platform| !is_file_name_valid_on(name, *platform))
+		.collect()
+}
+
+pub fn is_file_name_valid_on(name: &str, platform: Platform) -> bool {
+	if name.is_empty() || name == "." || name == ".." {
+		return false;
+	}
+
+	match platform {
+		Platform::Windows => {
+			name.encode_utf16().count() <= MAX_FILE_NAME_LENGTH
+				&& !WINDOWS_FORBIDDEN_FILE_NAMES
+					.get_or_init(|| {
+						RegexSet::new([
+							// Reserved device names, even with an extension
+							r"(?i)^(CON|PRN|AUX|NUL|COM[1-9]|LPT[1-9])(\.[^.]*)*$",
+							r#"[<>:"/\\|?*\x00-\x1F]"#,
+							// Windows silently strips trailing dots and spaces
+							r"[. ]$",
+						])
+						.expect("this regex should always be valid")
+					})
+					.is_match(name)
+		}
+		// Apple and Android filesystems share the unix rules, and an unknown platform is
+		// probably unix-like as well
+		Platform::MacOS
+		| Platform::IOS
+		| Platform::Linux
+		| Platform::Android
+		| Platform::Unknown => {
+			name.len() <= MAX_FILE_NAME_LENGTH
+				&& !UNIX_FORBIDDEN_FILE_NAMES
+					.get_or_init(|| {
+						RegexSet::new([r"/|\x00"]).expect("this regex should always be valid")
+					})
+					.is_match(name)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn windows_rules() {
+		for name in [
+			"CON",
+			"con.txt",
+			"LPT1.tar.gz",
+			"what?",
+			"a:b",
+			"ends with dot.",
+			"ends with space ",
+			"tab\there",
+		] {
+			assert!(!is_file_name_valid_on(name, Platform::Windows), "{name}");
+			assert!(is_file_name_valid_on(name, Platform::Linux), "{name}");
+		}
+
+		for name in ["CONSOLE.txt", "file 01.txt", "nul_file", ".hidden"] {
+			assert!(is_file_name_valid_on(name, Platform::Windows), "{name}");
+		}
+	}
+
+	#[test]
+	fn rejected_platforms() {
+		assert_eq!(
+			platforms_rejecting_file_name("report: 2023.pdf"),
+			vec![Platform::Windows]
+		);
+		assert_eq!(platforms_rejecting_file_name("a/b").len(), 5);
+		assert!(platforms_rejecting_file_name("résumé.pdf").is_empty());
+		assert!(!platforms_rejecting_file_name(&"a".repeat(256)).is_empty());
+	}
+}
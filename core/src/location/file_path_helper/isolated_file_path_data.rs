@@ -1,26 +1,25 @@
 use crate::{
 	prisma::{file_path, location},
-	util::error::NonUtf8PathError,
+	util::{error::NonUtf8PathError, long_path::strip_extended_length_prefix},
 };
 
 use std::{
 	borrow::Cow,
 	fmt,
 	path::{Path, MAIN_SEPARATOR},
-	sync::OnceLock,
 };
 
-use regex::RegexSet;
 use serde::{Deserialize, Serialize};
 
 use super::{
-	file_path_for_file_identifier, file_path_for_object_validator, file_path_for_thumbnailer,
-	file_path_to_full_path, file_path_to_handle_custom_uri, file_path_to_isolate,
-	file_path_to_isolate_with_id, file_path_with_object, FilePathError,
+	file_path_for_cleanup, file_path_for_compressor, file_path_for_file_identifier,
+	file_path_for_folder_digest, file_path_for_mail_indexer, file_path_for_object_validator,
+	file_path_for_photo_stacker, file_path_for_quick_open, file_path_for_thumbnailer,
+	file_path_for_treemap, file_path_for_xmp_sidecar, file_path_to_full_path,
+	file_path_to_handle_custom_uri, file_path_to_isolate, file_path_to_isolate_with_id,
+	file_path_with_object, FileNameNormalization, FileNamePolicy, FilePathError,
 };
 
-static FORBIDDEN_FILE_NAMES: OnceLock<RegexSet> = OnceLock::new();
-
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq)]
 #[non_exhaustive]
 pub struct IsolatedFilePathData<'a> {
@@ -39,7 +38,9 @@ impl IsolatedFilePathData<'static> {
 		full_path: impl AsRef<Path>,
 		is_dir: bool,
 	) -> Result<Self, FilePathError> {
+		let full_path = strip_extended_length_prefix(full_path.as_ref());
 		let full_path = full_path.as_ref();
+		let location_path = strip_extended_length_prefix(location_path.as_ref());
 		let location_path = location_path.as_ref();
 
 		let extension = (!is_dir)
@@ -64,7 +65,7 @@ impl IsolatedFilePathData<'static> {
 			)?),
 			name: Cow::Owned(
 				(location_path != full_path)
-					.then(|| Self::prepare_name(full_path).to_string())
+					.then(|| Self::prepare_name(full_path, is_dir).to_string())
 					.unwrap_or_default(),
 			),
 			extension: Cow::Owned(extension),
@@ -146,6 +147,17 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 	}
 
+	/// Applies a library's [`FileNameNormalization`] to every name in this path
+	pub fn normalized(self, normalization: FileNameNormalization) -> Self {
+		Self {
+			materialized_path: normalization.normalize(self.materialized_path),
+			name: normalization.normalize(self.name),
+			extension: normalization.normalize(self.extension),
+			relative_path: normalization.normalize(self.relative_path),
+			..self
+		}
+	}
+
 	pub fn full_name(&self) -> String {
 		if self.extension.is_empty() {
 			self.name.to_string()
@@ -164,6 +176,90 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 	}
 
+	/// Checks that this path can be taken apart and put back together, for tests and fuzzing.
+	/// The error describes the first broken invariant.
+	pub fn check_invariants(&self) -> Result<(), String> {
+		let materialized_path = self.materialized_path.as_ref();
+
+		if !materialized_path.starts_with('/') || !materialized_path.ends_with('/') {
+			return Err(format!(
+				"materialized path '{materialized_path}' must start and end with '/'"
+			));
+		}
+
+		if self.relative_path.is_empty() {
+			return if self.is_root() {
+				Ok(())
+			} else {
+				Err("an empty relative path must be the location root".to_string())
+			};
+		}
+
+		if self.is_dir && !self.extension.is_empty() {
+			return Err(format!(
+				"directory '{}' has an extension",
+				self.relative_path
+			));
+		}
+
+		let full_name = self.full_name();
+		if self.name.is_empty() || full_name.contains('/') {
+			return Err(format!("invalid name '{full_name}'"));
+		}
+
+		let (relative_dir, file_name) = self
+			.relative_path
+			.rsplit_once('/')
+			.map_or(("", self.relative_path.as_ref()), |(dir, name)| (dir, name));
+		let expected_dir = materialized_path.trim_matches('/');
+		if relative_dir != expected_dir {
+			return Err(format!(
+				"relative path '{}' isn't in '{materialized_path}'",
+				self.relative_path
+			));
+		}
+
+		// The extension is stored in lowercase, so the file name on disk may differ from it
+		let (name, extension) = if self.is_dir {
+			(file_name, String::new())
+		} else {
+			IsolatedFilePathData::separate_name_and_extension_from_str(file_name)
+				.map(|(name, extension)| (name, extension.to_lowercase()))
+				.map_err(|e| e.to_string())?
+		};
+		if name != self.name || extension != self.extension {
+			return Err(format!(
+				"file name '{file_name}' doesn't match '{full_name}'",
+			));
+		}
+
+		let parent = self.parent();
+		if parent.materialized_path_for_children().as_deref() != Some(materialized_path) {
+			return Err(format!(
+				"parent of '{}' doesn't reproduce its materialized path '{materialized_path}'",
+				self.relative_path
+			));
+		}
+
+		let source = format!(
+			"{materialized_path}{full_name}{}",
+			if self.is_dir { "/" } else { "" }
+		);
+		let parsed = IsolatedFilePathData::from_relative_str(self.location_id, &source);
+		if parsed.is_dir != self.is_dir
+			|| parsed.materialized_path != self.materialized_path
+			|| parsed.name != self.name
+			|| parsed.extension != self.extension
+		{
+			return Err(format!(
+				"'{source}' parses as {parsed:?} instead of {:?}",
+				self
+			));
+		}
+
+		Ok(())
+	}
+
 	pub fn separate_name_and_extension_from_str(
 		source: &'a str,
 	) -> Result<(&'a str, &'a str), FilePathError> {
@@ -174,8 +270,9 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 
 		if let Some(last_dot_idx) = source.rfind('.') {
-			if last_dot_idx == 0 {
-				// The dot is the first character, so it's a hidden file
+			if last_dot_idx == 0 || last_dot_idx == source.len() - 1 {
+				// The dot is the first character, so it's a hidden file, or the last one, which
+				// makes it part of the name
 				Ok((source, ""))
 			} else {
 				Ok((&source[..last_dot_idx], &source[last_dot_idx + 1..]))
@@ -186,29 +283,8 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 	}
 
-	pub fn accept_file_name(name: &str) -> bool {
-		let reg = {
-			// Maybe we should enforce windows more restrictive rules on all platforms?
-			#[cfg(target_os = "windows")]
-			{
-				FORBIDDEN_FILE_NAMES.get_or_init(|| {
-					RegexSet::new([
-						r"(?i)^(CON|PRN|AUX|NUL|COM[1-9]|LPT[1-9])(\.\w+)*$",
-						r#"[<>:"/\\|?*\u0000-\u0031]"#,
-					])
-					.expect("this regex should always be valid")
-				})
-			}
-
-			#[cfg(not(target_os = "windows"))]
-			{
-				FORBIDDEN_FILE_NAMES.get_or_init(|| {
-					RegexSet::new([r"/|\x00"]).expect("this regex should always be valid")
-				})
-			}
-		};
-
-		!reg.is_match(name)
+	pub fn accept_file_name(name: &str, policy: FileNamePolicy) -> bool {
+		policy.accepts(name)
 	}
 
 	pub fn separate_path_name_and_extension_from_str(
@@ -238,31 +314,41 @@ impl<'a> IsolatedFilePathData<'a> {
 				None,
 			)
 		} else {
-			let first_name_char_idx = source.rfind('/').unwrap_or(0) + 1;
-			let end_idx = first_name_char_idx - 1;
-			if let Some(last_dot_relative_idx) = source[first_name_char_idx..].rfind('.') {
+			let first_name_char_idx = source.rfind('/').map_or(0, |idx| idx + 1);
+			let name = &source[first_name_char_idx..];
+			// Same rules as `separate_name_and_extension_from_str`, for hidden files and trailing dots
+			if let Some(last_dot_relative_idx) = name
+				.rfind('.')
+				.filter(|idx| *idx != 0 && *idx != name.len() - 1)
+			{
 				let last_dot_idx = first_name_char_idx + last_dot_relative_idx;
 				(
-					&source[..end_idx],
+					&source[..first_name_char_idx],
 					Some(&source[first_name_char_idx..last_dot_idx]),
 					Some(&source[last_dot_idx + 1..]),
 				)
 			} else {
-				(
-					&source[..end_idx],
-					Some(&source[first_name_char_idx..]),
-					None,
-				)
+				(&source[..first_name_char_idx], Some(name), None)
 			}
 		}
 	}
 
-	fn prepare_name(path: &Path) -> &str {
+	fn prepare_name(path: &Path, is_dir: bool) -> &str {
 		// Not using `impl AsRef<Path>` here because it's an private method
-		path.file_stem()
-			.unwrap_or_default()
-			.to_str()
-			.unwrap_or_default()
+		let has_extension = path
+			.extension()
+			.map_or(false, |extension| !extension.is_empty());
+
+		// Directories don't have extensions, and a trailing dot is part of the name, as
+		// `file_stem` would drop it
+		if is_dir || !has_extension {
+			path.file_name()
+		} else {
+			path.file_stem()
+		}
+		.unwrap_or_default()
+		.to_str()
+		.unwrap_or_default()
 	}
 
 	pub fn from_db_data(
@@ -451,7 +537,10 @@ impl_from_db!(
 	file_path,
 	file_path_to_isolate,
 	file_path_to_isolate_with_id,
-	file_path_with_object
+	file_path_with_object,
+	file_path_for_folder_digest,
+	file_path_for_treemap,
+	file_path_for_quick_open
 );
 
 impl_from_db_without_location_id!(
@@ -459,7 +548,12 @@ impl_from_db_without_location_id!(
 	file_path_to_full_path,
 	file_path_for_thumbnailer,
 	file_path_for_object_validator,
-	file_path_to_handle_custom_uri
+	file_path_to_handle_custom_uri,
+	file_path_for_compressor,
+	file_path_for_xmp_sidecar,
+	file_path_for_mail_indexer,
+	file_path_for_photo_stacker,
+	file_path_for_cleanup
 );
 
 fn extract_relative_path(
@@ -467,9 +561,10 @@ fn extract_relative_path(
 	location_path: impl AsRef<Path>,
 	path: impl AsRef<Path>,
 ) -> Result<String, FilePathError> {
+	let path = strip_extended_length_prefix(path.as_ref());
 	let path = path.as_ref();
 
-	path.strip_prefix(location_path)
+	path.strip_prefix(strip_extended_length_prefix(location_path.as_ref()))
 		.map_err(|_| FilePathError::UnableToExtractMaterializedPath {
 			location_id,
 			path: path.into(),
@@ -483,15 +578,17 @@ fn extract_relative_path(
 }
 
 /// This function separates a file path from a location path, and normalizes replacing '\' with '/'
-/// to be consistent between Windows and Unix like systems
+/// to be consistent between Windows and Unix like systems. Windows extended-length prefixes (`\\?\`)
+/// are ignored, so it doesn't matter if only one of the paths has it.
 pub fn extract_normalized_materialized_path_str(
 	location_id: location::id::Type,
 	location_path: impl AsRef<Path>,
 	path: impl AsRef<Path>,
 ) -> Result<String, FilePathError> {
+	let path = strip_extended_length_prefix(path.as_ref());
 	let path = path.as_ref();
 
-	path.strip_prefix(location_path)
+	path.strip_prefix(strip_extended_length_prefix(location_path.as_ref()))
 		.map_err(|_| FilePathError::UnableToExtractMaterializedPath {
 			location_id,
 			path: path.into(),
@@ -532,6 +629,8 @@ fn assemble_relative_path(
 mod tests {
 	use super::*;
 
+	use proptest::prelude::*;
+
 	fn expected(
 		materialized_path: &'static str,
 		is_dir: bool,
@@ -713,4 +812,194 @@ mod tests {
 			"a file inside a third level directory",
 		);
 	}
+
+	#[cfg(target_os = "windows")]
+	#[test]
+	fn extract_normalized_materialized_path_on_windows() {
+		let tester = |location_path: &str, path: &str, expected: &str, msg: &str| {
+			let actual = extract_normalized_materialized_path_str(1, location_path, path).unwrap();
+			assert_eq!(actual, expected, "{msg}");
+		};
+
+		tester(
+			r"C:\spacedrive\location",
+			r"C:\spacedrive\location\dir\file.txt",
+			"/dir/",
+			"a drive path",
+		);
+		tester(
+			r"C:\spacedrive\location",
+			r"\\?\C:\spacedrive\location\dir\dir2\file.txt",
+			"/dir/dir2/",
+			"an extended-length path inside a location with a drive path",
+		);
+		tester(
+			r"\\?\C:\spacedrive\location",
+			r"C:\spacedrive\location\dir\file.txt",
+			"/dir/",
+			"a drive path inside a location with an extended-length path",
+		);
+		tester(
+			r"\\server\share\location",
+			r"\\server\share\location\dir\file.txt",
+			"/dir/",
+			"an UNC share path",
+		);
+		tester(
+			r"\\server\share\location",
+			r"\\?\UNC\server\share\location\dir\dir2\file.txt",
+			"/dir/dir2/",
+			"an extended-length UNC path inside a location on a share",
+		);
+		tester(
+			r"\\?\UNC\server\share\location",
+			r"\\server\share\location\file.txt",
+			"/",
+			"an UNC path in the root of a location with an extended-length path",
+		);
+
+		let deep_dir = "a".repeat(200);
+		tester(
+			r"C:\spacedrive\location",
+			&format!(r"\\?\C:\spacedrive\location\{deep_dir}\{deep_dir}\file.txt"),
+			&format!("/{deep_dir}/{deep_dir}/"),
+			"a path longer than MAX_PATH",
+		);
+	}
+
+	/// Random trees of names with spaces, unicode, hidden files and multiple dots, from a fixed
+	/// seed so failures can be reproduced
+	#[test]
+	fn generated_paths_are_consistent() {
+		const PIECES: &[&str] = &["a", "Z", "0", "é", "日本", " ", "-", "_"];
+		const EXTENSIONS: &[&str] = &["", "txt", "JPG", "tar.gz", "rs"];
+
+		let mut seed = 0x5eed_u64;
+		let mut next = |max: usize| {
+			// xorshift64
+			seed ^= seed << 13;
+			seed ^= seed >> 7;
+			seed ^= seed << 17;
+			(seed % max as u64) as usize
+		};
+
+		let location_path = Path::new("/spacedrive/location");
+
+		for _ in 0..500 {
+			let mut full_path = location_path.to_path_buf();
+			let depth = next(6) + 1;
+			for level in 0..depth {
+				let mut name = if next(8) == 0 { "." } else { "" }.to_string();
+				for _ in 0..next(6) + 1 {
+					name.push_str(PIECES[next(PIECES.len())]);
+				}
+
+				let is_last = level == depth - 1;
+				if is_last {
+					let extension = EXTENSIONS[next(EXTENSIONS.len())];
+					if !extension.is_empty() {
+						name = format!("{name}.{extension}");
+					}
+				}
+
+				full_path.push(name);
+			}
+
+			let is_dir = next(2) == 0;
+			let iso = IsolatedFilePathData::new(1, location_path, &full_path, is_dir).unwrap();
+			let relative = full_path
+				.strip_prefix(location_path)
+				.unwrap()
+				.to_str()
+				.unwrap()
+				.replace('\\', "/");
+
+			assert_eq!(iso.to_string(), relative);
+			assert!(iso.materialized_path().starts_with('/'), "{relative}");
+			assert!(iso.materialized_path().ends_with('/'), "{relative}");
+
+			let mut child = iso;
+			for _ in 0..depth {
+				let parent = child.parent();
+				assert_eq!(
+					parent.materialized_path_for_children().as_deref(),
+					Some(child.materialized_path()),
+					"{relative}"
+				);
+				child = IsolatedFilePathData::new(
+					1,
+					location_path,
+					location_path.join(parent.to_string()),
+					true,
+				)
+				.unwrap();
+			}
+			assert!(child.is_root(), "{relative}");
+		}
+	}
+
+	/// Names that aren't a single `.` or `..`, and are valid on every platform we support
+	fn name_strategy() -> impl Strategy<Value = String> {
+		prop_oneof![
+			"[^/\\\\:*?\"<>|\\p{C}]{1,12}",
+			// Trailing dots and spaces, which Windows trims but other systems keep
+			"[a-zA-Z0-9é日本]{1,6}(\\.[a-zA-Z]{0,3})?[. ]{1,3}",
+			// Hidden files and names with many dots
+			"\\.?[a-z]{1,6}(\\.[a-zA-Z]{1,4}){0,3}",
+			// Windows reserved names
+			"(CON|PRN|AUX|NUL|COM[1-9]|LPT[1-9])(\\.[a-z]{1,3})?",
+		]
+		.prop_filter("not a relative path component", |name| {
+			name != "." && name != ".."
+		})
+	}
+
+	proptest! {
+		#[test]
+		fn invariants_hold_for_generated_paths(
+			names in prop::collection::vec(name_strategy(), 1..6),
+			is_dir in any::<bool>(),
+		) {
+			let location_path = Path::new("/spacedrive/location");
+			let full_path = names.iter().fold(location_path.to_path_buf(), |path, name| path.join(name));
+
+			let iso = IsolatedFilePathData::new(1, location_path, &full_path, is_dir).unwrap();
+			prop_assert_eq!(iso.check_invariants(), Ok(()));
+
+			let file_name = names.last().unwrap();
+			if is_dir {
+				prop_assert_eq!(iso.name(), file_name.as_str());
+			} else {
+				let (name, extension) =
+					IsolatedFilePathData::separate_name_and_extension_from_str(file_name).unwrap();
+				prop_assert_eq!(iso.name(), name);
+				prop_assert_eq!(iso.extension(), extension.to_lowercase());
+			}
+
+			let mut current = iso;
+			while !current.is_root() {
+				let parent = current.parent();
+				prop_assert_eq!(parent.check_invariants(), Ok(()));
+				current = IsolatedFilePathData::new(
+					1,
+					location_path,
+					location_path.join(parent.to_string()),
+					true,
+				)
+				.unwrap();
+			}
+		}
+
+		#[test]
+		fn name_and_extension_round_trip(name in name_strategy()) {
+			let (name_part, extension) =
+				IsolatedFilePathData::separate_name_and_extension_from_str(&name).unwrap();
+			let rebuilt = if extension.is_empty() {
+				name_part.to_string()
+			} else {
+				format!("{name_part}.{extension}")
+			};
+			prop_assert_eq!(rebuilt, name);
+		}
+	}
 }
@@ -1,6 +1,9 @@
 use crate::{
 	prisma::{file_path, location, PrismaClient},
-	util::error::{FileIOError, NonUtf8PathError},
+	util::{
+		error::{FileIOError, NonUtf8PathError},
+		long_path::{strip_extended_length_prefix, to_extended_length},
+	},
 };
 
 use std::{
@@ -16,9 +19,16 @@ use thiserror::Error;
 use tokio::{fs, io};
 use tracing::error;
 
+mod dotted_names;
+mod file_name_policy;
 pub mod isolated_file_path_data;
+mod normalization;
+pub mod normalizer_job;
 
+pub(crate) use dotted_names::rederive_dotted_names;
+pub use file_name_policy::*;
 pub use isolated_file_path_data::IsolatedFilePathData;
+pub use normalization::*;
 
 // File Path selectables!
 file_path::select!(file_path_just_pub_id { pub_id });
@@ -53,6 +63,7 @@ file_path::select!(file_path_for_thumbnailer {
 	name
 	extension
 	cas_id
+	object_id
 });
 file_path::select!(file_path_to_isolate {
 	location_id
@@ -70,10 +81,15 @@ file_path::select!(file_path_to_isolate_with_id {
 	extension
 });
 file_path::select!(file_path_to_handle_custom_uri {
+	pub_id
 	materialized_path
 	is_dir
 	name
 	extension
+	size_in_bytes
+	compressed_at
+	tiered_to_location_id
+	object_id
 	location: select {
 		id
 		path
@@ -90,6 +106,93 @@ file_path::select!(file_path_to_full_path {
 		path
 	}
 });
+file_path::select!(file_path_for_xmp_sidecar {
+	id
+	materialized_path
+	is_dir
+	name
+	extension
+	object_id
+});
+file_path::select!(file_path_for_mail_indexer {
+	id
+	materialized_path
+	is_dir
+	name
+	extension
+	date_modified
+	mail_archive: select {
+		date_modified
+	}
+});
+file_path::select!(file_path_for_folder_digest {
+	id
+	location_id
+	materialized_path
+	is_dir
+	name
+	extension
+	cas_id
+	size_in_bytes
+});
+file_path::select!(file_path_for_treemap {
+	id
+	location_id
+	materialized_path
+	is_dir
+	name
+	extension
+	size_in_bytes
+});
+file_path::select!(file_path_for_grouping {
+	id
+	materialized_path
+	name
+	extension
+});
+file_path::select!(file_path_for_cleanup {
+	id
+	is_dir
+	materialized_path
+	name
+	extension
+	size_in_bytes
+	date_created
+	date_modified
+	object: select { kind date_accessed }
+});
+file_path::select!(file_path_for_quick_open {
+	id
+	name
+	extension
+	is_dir
+	materialized_path
+	location_id
+	object_id
+	location: select { name }
+});
+file_path::select!(file_path_for_photo_stacker {
+	id
+	materialized_path
+	is_dir
+	name
+	extension
+	cas_id
+	object_id
+	date_modified
+});
+file_path::select!(file_path_for_compressor {
+	id
+	pub_id
+	materialized_path
+	is_dir
+	name
+	extension
+	size_in_bytes
+	object: select {
+		date_accessed
+	}
+});
 
 // File Path includes!
 file_path::include!(file_path_with_object { object });
@@ -99,6 +202,7 @@ pub struct FilePathMetadata {
 	pub inode: u64,
 	pub device: u64,
 	pub size_in_bytes: u64,
+	pub allocated_size_in_bytes: u64,
 	pub created_at: DateTime<Utc>,
 	pub modified_at: DateTime<Utc>,
 }
@@ -189,6 +293,10 @@ pub async fn create_file_path(
 				size_in_bytes::NAME,
 				json!(metadata.size_in_bytes.to_string()),
 			),
+			(
+				allocated_size_in_bytes::NAME,
+				json!(metadata.allocated_size_in_bytes.to_string()),
+			),
 			(inode::NAME, json!(metadata.inode.to_le_bytes())),
 			(device::NAME, json!(metadata.device.to_le_bytes())),
 			(is_dir::NAME, json!(is_dir)),
@@ -220,6 +328,9 @@ pub async fn create_file_path(
 					cas_id::set(cas_id),
 					is_dir::set(Some(is_dir)),
 					size_in_bytes::set(Some(metadata.size_in_bytes.to_string())),
+					allocated_size_in_bytes::set(Some(
+						metadata.allocated_size_in_bytes.to_string(),
+					)),
 					date_created::set(Some(metadata.created_at.into())),
 					date_modified::set(Some(metadata.modified_at.into())),
 				]
@@ -295,12 +406,14 @@ pub async fn ensure_sub_path_is_in_location(
 	}
 	let location_path = location_path.as_ref();
 
-	if !sub_path.starts_with(location_path) {
+	if !strip_extended_length_prefix(sub_path)
+		.starts_with(strip_extended_length_prefix(location_path))
+	{
 		// If the sub_path doesn't start with the location_path, we have to check if it's a
 		// materialized path received from the frontend, then we check if the full path exists
 		let full_path = location_path.join(sub_path);
 
-		match fs::metadata(&full_path).await {
+		match fs::metadata(to_extended_length(&full_path)).await {
 			Ok(_) => Ok(full_path),
 			Err(e) if e.kind() == io::ErrorKind::NotFound => Err(FilePathError::InvalidSubPath {
 				sub_path: sub_path.into(),
@@ -350,7 +463,7 @@ pub async fn ensure_sub_path_is_directory(
 ) -> Result<(), FilePathError> {
 	let mut sub_path = sub_path.as_ref();
 
-	match fs::metadata(sub_path).await {
+	match fs::metadata(to_extended_length(sub_path)).await {
 		Ok(meta) => {
 			if meta.is_file() {
 				Err(FilePathError::SubPathNotDirectory(sub_path.into()))
@@ -368,7 +481,7 @@ pub async fn ensure_sub_path_is_directory(
 
 			let location_path = location_path.as_ref();
 			let full_path = location_path.join(sub_path);
-			match fs::metadata(&full_path).await {
+			match fs::metadata(to_extended_length(&full_path)).await {
 				Ok(meta) => {
 					if meta.is_file() {
 						Err(FilePathError::SubPathNotDirectory(sub_path.into()))
@@ -435,7 +548,7 @@ pub async fn get_inode_and_device_from_path(
 	{
 		use winapi_util::{file::information, Handle};
 
-		let info = Handle::from_path_any(path.as_ref())
+		let info = Handle::from_path_any(to_extended_length(path.as_ref()))
 			.and_then(|ref handle| information(handle))
 			.map_err(|e| FileIOError::from((path, e)))?;
 
@@ -443,6 +556,51 @@ pub async fn get_inode_and_device_from_path(
 	}
 }
 
+/// Returns the size a file actually takes on disk, which is smaller than its logical size for
+/// sparse files and for compressed ones on ZFS, APFS and NTFS. Btrfs reports compressed extents
+/// with their uncompressed size, so there only holes are accounted for.
+/// Falls back to the logical size when the platform can't tell us.
+pub fn get_allocated_size(path: impl AsRef<Path>, metadata: &Metadata) -> u64 {
+	#[cfg(target_family = "unix")]
+	{
+		use std::os::unix::fs::MetadataExt;
+
+		let _ = path;
+
+		// `st_blocks` is always counted in 512 bytes units, regardless of the filesystem block size
+		metadata.blocks() * 512
+	}
+
+	#[cfg(target_family = "windows")]
+	{
+		use std::{io, iter::once, os::windows::ffi::OsStrExt};
+
+		use windows_sys::Win32::Storage::FileSystem::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+
+		if metadata.is_dir() {
+			return metadata.len();
+		}
+
+		let wide_path = to_extended_length(path.as_ref())
+			.as_os_str()
+			.encode_wide()
+			.chain(once(0))
+			.collect::<Vec<_>>();
+
+		// Despite the name, this accounts for both NTFS compression and sparse files
+		let mut high = 0;
+		// SAFETY: `wide_path` is a nul terminated UTF-16 string that outlives the call
+		let low = unsafe { GetCompressedFileSizeW(wide_path.as_ptr(), &mut high) };
+
+		// INVALID_FILE_SIZE is also a valid low part, so we must check the last error
+		if low == INVALID_FILE_SIZE && io::Error::last_os_error().raw_os_error() != Some(0) {
+			return metadata.len();
+		}
+
+		(u64::from(high) << 32) | u64::from(low)
+	}
+}
+
 pub trait MetadataExt {
 	fn created_or_now(&self) -> SystemTime;
 
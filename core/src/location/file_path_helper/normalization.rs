@@ -0,0 +1,70 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+/// `FileNameNormalization` is the unicode normalization form file names are stored with in a library.
+/// macOS hands us names decomposed (NFD) while Linux and Windows usually hand them composed (NFC),
+/// so without a policy the same file synced between them ends up as two different `file_path`s.
+///
+/// Names are only normalized in the database, so a normalized policy should only be picked when
+/// the filesystems holding the locations treat both forms as the same name (like APFS and NTFS),
+/// or never hold names in the other form.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Type, Eq, PartialEq, Hash)]
+pub enum FileNameNormalization {
+	/// Names are stored exactly as the filesystem returns them
+	#[default]
+	Preserve,
+	Nfc,
+	Nfd,
+}
+
+impl FileNameNormalization {
+	pub fn is_normalized(self, name: &str) -> bool {
+		match self {
+			Self::Preserve => true,
+			Self::Nfc => is_nfc(name),
+			Self::Nfd => is_nfd(name),
+		}
+	}
+
+	pub fn normalize(self, name: Cow<'_, str>) -> Cow<'_, str> {
+		match self {
+			Self::Nfc if !is_nfc(&name) => Cow::Owned(name.nfc().collect()),
+			Self::Nfd if !is_nfd(&name) => Cow::Owned(name.nfd().collect()),
+			_ => name,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn normalize_names() {
+		let composed = "r\u{e9}sum\u{e9}";
+		let decomposed = "re\u{301}sume\u{301}";
+
+		assert_eq!(
+			FileNameNormalization::Nfc.normalize(Cow::Borrowed(decomposed)),
+			composed
+		);
+		assert_eq!(
+			FileNameNormalization::Nfd.normalize(Cow::Borrowed(composed)),
+			decomposed
+		);
+		assert_eq!(
+			FileNameNormalization::Preserve.normalize(Cow::Borrowed(decomposed)),
+			decomposed
+		);
+
+		assert!(matches!(
+			FileNameNormalization::Nfc.normalize(Cow::Borrowed(composed)),
+			Cow::Borrowed(_)
+		));
+		assert!(!FileNameNormalization::Nfc.is_normalized(decomposed));
+		assert!(FileNameNormalization::Nfd.is_normalized(decomposed));
+	}
+}
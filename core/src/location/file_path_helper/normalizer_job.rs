@@ -0,0 +1,184 @@
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	prisma::{file_path, SortOrder},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, trace};
+
+use super::{file_path_to_isolate_with_id, FileNameNormalization, IsolatedFilePathData};
+
+const BATCH_SIZE: i64 = 1000;
+
+/// `FilePathNormalizerJob` goes through every `file_path` of a library, rewriting the names that
+/// aren't in the form required by the library's [`FileNameNormalization`] policy.
+/// When the normalized path already exists, both rows are the same file seen in different forms,
+/// so the one we're normalizing is removed.
+pub struct FilePathNormalizerJob {}
+
+#[derive(Serialize, Deserialize, Hash)]
+pub struct FilePathNormalizerJobInit {
+	pub normalization: FileNameNormalization,
+}
+
+impl JobInitData for FilePathNormalizerJobInit {
+	type Job = FilePathNormalizerJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FilePathNormalizerJobReport {
+	total_file_paths: usize,
+	renormalized: usize,
+	merged_duplicates: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FilePathNormalizerJobState {
+	cursor: file_path::id::Type,
+	report: FilePathNormalizerJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FilePathNormalizerJob {
+	type Init = FilePathNormalizerJobInit;
+	type Data = FilePathNormalizerJobState;
+	type Step = ();
+
+	const NAME: &'static str = "file_path_normalizer";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let total_file_paths = ctx.library.db.file_path().count(vec![]).exec().await? as usize;
+
+		state.data = Some(FilePathNormalizerJobState {
+			cursor: 0,
+			report: FilePathNormalizerJobReport {
+				total_file_paths,
+				..Default::default()
+			},
+		});
+
+		if state.init.normalization == FileNameNormalization::Preserve || total_file_paths == 0 {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "No file paths to normalize".to_string(),
+			});
+		}
+
+		let task_count = (total_file_paths as f64 / BATCH_SIZE as f64).ceil() as usize;
+		state.steps.extend((0..task_count).map(|_| ()));
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(task_count)]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+		let normalization = state.init.normalization;
+		let FilePathNormalizerJobState { cursor, report } = extract_job_data_mut!(state);
+
+		let file_paths = db
+			.file_path()
+			.find_many(vec![file_path::id::gt(*cursor)])
+			.order_by(file_path::id::order(SortOrder::Asc))
+			.take(BATCH_SIZE)
+			.select(file_path_to_isolate_with_id::select())
+			.exec()
+			.await?;
+
+		let Some(last_file_path) = file_paths.last() else {
+			return Ok(());
+		};
+		*cursor = last_file_path.id;
+
+		for file_path in &file_paths {
+			if [
+				&file_path.materialized_path,
+				&file_path.name,
+				&file_path.extension,
+			]
+			.into_iter()
+			.flatten()
+			.all(|name| normalization.is_normalized(name))
+			{
+				continue;
+			}
+
+			let normalized = IsolatedFilePathData::try_from(file_path)?.normalized(normalization);
+
+			let already_exists = db
+				.file_path()
+				.count(vec![
+					file_path::location_id::equals(Some(normalized.location_id())),
+					file_path::materialized_path::equals(Some(
+						normalized.materialized_path().to_string(),
+					)),
+					file_path::name::equals(Some(normalized.name().to_string())),
+					file_path::extension::equals(Some(normalized.extension().to_string())),
+				])
+				.exec()
+				.await? > 0;
+
+			if already_exists {
+				trace!(
+					"Removing file_path <id='{}'> as a duplicate of its normalized form",
+					file_path.id
+				);
+
+				db.file_path()
+					.delete(file_path::id::equals(file_path.id))
+					.exec()
+					.await?;
+
+				report.merged_duplicates += 1;
+			} else {
+				db.file_path()
+					.update(
+						file_path::id::equals(file_path.id),
+						vec![
+							file_path::materialized_path::set(Some(
+								normalized.materialized_path().to_string(),
+							)),
+							file_path::name::set(Some(normalized.name().to_string())),
+							file_path::extension::set(Some(normalized.extension().to_string())),
+						],
+					)
+					.exec()
+					.await?;
+
+				report.renormalized += 1;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing file path normalizer job: {report:?}");
+
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
@@ -0,0 +1,93 @@
+//! Locations with an index depth only get their first levels of directories indexed when they're
+//! added, so adding a huge one is quick. The directories at the last level are flagged as deferred,
+//! and the first time one of them is browsed its contents are indexed, the same depth below it.
+
+use crate::{
+	location::file_path_helper::{filter_existing_file_path_params, IsolatedFilePathData},
+	prisma::{file_path, location, PrismaClient},
+	util::db::chain_optional_iter,
+};
+
+use prisma_client_rust::{operator::or, QueryError};
+
+use super::{location_with_indexer_rules, IndexerError};
+
+/// How many directories are flagged in each query
+const FLAG_BATCH_SIZE: usize = 100;
+
+/// How many levels of entries are indexed at once in the location, `None` for all of them
+pub fn max_depth(location: &location_with_indexer_rules::Data) -> Option<usize> {
+	location
+		.index_depth
+		.filter(|depth| *depth > 0)
+		.map(|depth| depth as usize)
+}
+
+pub async fn is_deferred(
+	db: &PrismaClient,
+	iso_file_path: &IsolatedFilePathData<'_>,
+) -> Result<bool, QueryError> {
+	let mut params = filter_existing_file_path_params(iso_file_path);
+	params.push(file_path::deferred::equals(Some(true)));
+
+	Ok(db.file_path().count(params).exec().await? > 0)
+}
+
+/// Flags the directories left unwalked by the indexer, once their file paths were saved
+pub(super) async fn flag_deferred_dirs(
+	db: &PrismaClient,
+	dirs: &[IsolatedFilePathData<'static>],
+) -> Result<(), IndexerError> {
+	for chunk in dirs.chunks(FLAG_BATCH_SIZE) {
+		db.file_path()
+			.update_many(
+				vec![or(chunk.iter().map(Into::into).collect())],
+				vec![file_path::deferred::set(Some(true))],
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
+
+/// Clears the flag of the directory an indexing starts from, as its contents are being indexed.
+/// Without a depth limit every directory below it is indexed too, so their flags are cleared as
+/// well. With one, the flags of directories that used to be at the last level are left behind when
+/// the depth grows, which only costs an indexing of what's already indexed when they're browsed.
+pub(super) async fn clear_deferred_dirs(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+	root: Option<&IsolatedFilePathData<'_>>,
+	max_depth: Option<usize>,
+) -> Result<(), IndexerError> {
+	if let Some(root) = root {
+		db.file_path()
+			.update_many(
+				filter_existing_file_path_params(root),
+				vec![file_path::deferred::set(None)],
+			)
+			.exec()
+			.await?;
+	}
+
+	if max_depth.is_none() {
+		db.file_path()
+			.update_many(
+				chain_optional_iter(
+					[
+						file_path::location_id::equals(Some(location_id)),
+						file_path::deferred::equals(Some(true)),
+					],
+					[root
+						.and_then(IsolatedFilePathData::materialized_path_for_children)
+						.map(file_path::materialized_path::starts_with)],
+				),
+				vec![file_path::deferred::set(None)],
+			)
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
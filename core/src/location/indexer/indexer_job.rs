@@ -1,12 +1,13 @@
 use crate::{
-	extract_job_data_mut, file_paths_db_fetcher_fn,
+	extract_job_data, extract_job_data_mut, file_paths_db_fetcher_fn,
 	job::{JobError, JobInitData, JobResult, JobState, StatefulJob, WorkerContext},
 	location::file_path_helper::{
 		ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
 		IsolatedFilePathData,
 	},
+	prisma::location,
 	to_remove_db_fetcher_fn,
-	util::db::maybe_missing,
+	util::{db::maybe_missing, vfs::LocalFs},
 };
 
 use std::{path::Path, sync::Arc};
@@ -16,10 +17,9 @@ use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 
 use super::{
-	execute_indexer_save_step, finalize_indexer, iso_file_path_factory,
-	remove_non_existing_file_paths,
-	rules::IndexerRule,
-	update_notifier_fn,
+	deferred::{clear_deferred_dirs, flag_deferred_dirs, max_depth},
+	execute_indexer_save_step, finalize_indexer, iso_file_path_factory, location_indexer_rules,
+	remove_non_existing_file_paths, update_notifier_fn,
 	walk::{keep_walking, walk, ToWalkEntry, WalkResult},
 	IndexerError, IndexerJobData, IndexerJobInit, IndexerJobSaveStep, ScanProgress,
 };
@@ -34,6 +34,10 @@ pub struct IndexerJob;
 
 impl JobInitData for IndexerJobInit {
 	type Job = IndexerJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location.id)
+	}
 }
 
 /// `IndexerJobStepInput` defines the action that should be executed in the current step
@@ -67,17 +71,14 @@ impl StatefulJob for IndexerJob {
 			maybe_missing(&state.init.location.path, "location.path").map(Path::new)?;
 
 		let db = Arc::clone(&ctx.library.db);
+		let normalization = ctx.library.config.file_name_normalization;
+
+		let indexer_rules =
+			location_indexer_rules(&state.init.location, location_path, &db).await?;
 
-		let indexer_rules = state
-			.init
-			.location
-			.indexer_rules
-			.iter()
-			.map(|rule| IndexerRule::try_from(&rule.indexer_rule))
-			.collect::<Result<Vec<_>, _>>()
-			.map_err(IndexerError::from)?;
+		let max_depth = max_depth(&state.init.location);
 
-		let to_walk_path = if let Some(ref sub_path) = state.init.sub_path {
+		let (to_walk_path, root) = if let Some(ref sub_path) = state.init.sub_path {
 			let full_path = ensure_sub_path_is_in_location(location_path, sub_path)
 				.await
 				.map_err(IndexerError::from)?;
@@ -85,35 +86,39 @@ impl StatefulJob for IndexerJob {
 				.await
 				.map_err(IndexerError::from)?;
 
-			ensure_file_path_exists(
-				sub_path,
-				&IsolatedFilePathData::new(location_id, location_path, &full_path, true)
-					.map_err(IndexerError::from)?,
-				&db,
-				IndexerError::SubPathNotFound,
-			)
-			.await?;
+			let root = IsolatedFilePathData::new(location_id, location_path, &full_path, true)
+				.map_err(IndexerError::from)?
+				.normalized(normalization);
+
+			ensure_file_path_exists(sub_path, &root, &db, IndexerError::SubPathNotFound).await?;
 
-			full_path
+			(full_path, Some(root))
 		} else {
-			location_path.to_path_buf()
+			(location_path.to_path_buf(), None)
 		};
 
+		clear_deferred_dirs(&db, location_id, root.as_ref(), max_depth).await?;
+
 		let scan_start = Instant::now();
 		let WalkResult {
 			walked,
 			to_walk,
 			to_remove,
 			errors,
+			skipped,
+			deferred,
 		} = {
 			walk(
+				&LocalFs,
 				&to_walk_path,
 				&indexer_rules,
 				update_notifier_fn(BATCH_SIZE, ctx),
 				file_paths_db_fetcher_fn!(&db),
 				to_remove_db_fetcher_fn!(location_id, location_path, &db),
-				iso_file_path_factory(location_id, location_path),
+				iso_file_path_factory(location_id, location_path, normalization),
 				50_000,
+				max_depth,
+				state.init.location.bundles_as_files.unwrap_or_default(),
 			)
 			.await?
 		};
@@ -153,7 +158,7 @@ impl StatefulJob for IndexerJob {
 			))],
 		);
 
-		state.data = Some(IndexerJobData {
+		let mut data = IndexerJobData {
 			indexed_path: to_walk_path,
 			indexer_rules,
 			db_write_time: db_delete_time,
@@ -162,7 +167,13 @@ impl StatefulJob for IndexerJob {
 			indexed_count: 0,
 			removed_count,
 			total_save_steps: state.steps.len() as u64 - to_walk_count as u64,
-		});
+			skipped_count: 0,
+			skipped: vec![],
+			deferred_dirs: deferred,
+		};
+		data.add_skipped(skipped);
+
+		state.data = Some(data);
 
 		if !errors.is_empty() {
 			Err(JobError::StepCompletedWithErrors(
@@ -209,6 +220,7 @@ impl StatefulJob for IndexerJob {
 					maybe_missing(&state.init.location.path, "location.path").map(Path::new)?;
 
 				let db = Arc::clone(&ctx.library.db);
+				let normalization = ctx.library.config.file_name_normalization;
 
 				let scan_start = Instant::now();
 
@@ -217,19 +229,24 @@ impl StatefulJob for IndexerJob {
 					to_walk,
 					to_remove,
 					errors,
+					skipped,
+					deferred,
 				} = {
 					keep_walking(
+						&LocalFs,
 						to_walk_entry,
 						&data.indexer_rules,
 						update_notifier_fn(BATCH_SIZE, ctx),
 						file_paths_db_fetcher_fn!(&db),
 						to_remove_db_fetcher_fn!(location_id, location_path, &db),
-						iso_file_path_factory(location_id, location_path),
+						iso_file_path_factory(location_id, location_path, normalization),
 					)
 					.await?
 				};
 
 				data.scan_read_time += scan_start.elapsed();
+				data.add_skipped(skipped);
+				data.deferred_dirs.extend(deferred);
 
 				let db_delete_time = Instant::now();
 				// TODO pass these uuids to sync system
@@ -280,6 +297,9 @@ impl StatefulJob for IndexerJob {
 		let location_path =
 			maybe_missing(&state.init.location.path, "location.path").map(Path::new)?;
 
+		// Only now, as they might have been saved by any of the steps
+		flag_deferred_dirs(&ctx.library.db, &extract_job_data!(state).deferred_dirs).await?;
+
 		finalize_indexer(location_path, state, ctx)
 	}
 }
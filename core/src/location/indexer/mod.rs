@@ -18,23 +18,30 @@ use sd_prisma::prisma_sync;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
-use tracing::info;
+use tracing::{info, warn};
 
 use super::{
-	file_path_helper::{file_path_just_pub_id, FilePathError, IsolatedFilePathData},
+	file_path_helper::{
+		file_path_just_pub_id, FileNameNormalization, FilePathError, IsolatedFilePathData,
+	},
 	location_with_indexer_rules,
+	nested::{exclude_nested_locations_rule, nested_location_paths},
 };
 
+pub mod deferred;
 pub mod indexer_job;
 pub mod rules;
 mod shallow;
-mod walk;
+pub(crate) mod walk;
 
 use rules::IndexerRuleError;
-use walk::WalkedEntry;
+use walk::{SkippedEntry, WalkedEntry};
 
 pub use shallow::*;
 
+/// We keep only a sample of the skipped entries in the job report, the full count is kept apart
+const MAX_SKIPPED_ENTRIES_IN_REPORT: usize = 100;
+
 /// `IndexerJobInit` receives a `location::Data` object to be indexed
 /// and possibly a `sub_path` to be indexed. The `sub_path` is used when
 /// we want do index just a part of a location.
@@ -65,9 +72,25 @@ pub struct IndexerJobData {
 	total_save_steps: u64,
 	indexed_count: u64,
 	removed_count: u64,
+	/// How many entries were skipped for exceeding the platform limits on path depth and length
+	#[serde(default)]
+	skipped_count: u64,
+	/// The first of the skipped entries, capped at [`MAX_SKIPPED_ENTRIES_IN_REPORT`]
+	#[serde(default)]
+	skipped: Vec<SkippedEntry>,
+	/// Directories at the index depth of the location, flagged once the walked entries are saved
+	#[serde(default)]
+	deferred_dirs: Vec<IsolatedFilePathData<'static>>,
 }
 
 impl IndexerJobData {
+	fn add_skipped(&mut self, skipped: Vec<SkippedEntry>) {
+		self.skipped_count += skipped.len() as u64;
+
+		let remaining = MAX_SKIPPED_ENTRIES_IN_REPORT.saturating_sub(self.skipped.len());
+		self.skipped.extend(skipped.into_iter().take(remaining));
+	}
+
 	fn on_scan_progress(ctx: &mut WorkerContext, progress: Vec<ScanProgress>) {
 		ctx.progress(
 			progress
@@ -184,6 +207,15 @@ async fn execute_indexer_save_step(
 					),
 					size_in_bytes::set(Some(entry.metadata.size_in_bytes.to_string())),
 				),
+				(
+					(
+						allocated_size_in_bytes::NAME,
+						json!(entry.metadata.allocated_size_in_bytes.to_string()),
+					),
+					allocated_size_in_bytes::set(Some(
+						entry.metadata.allocated_size_in_bytes.to_string(),
+					)),
+				),
 				(
 					(inode::NAME, json!(entry.metadata.inode.to_le_bytes())),
 					inode::set(Some(entry.metadata.inode.to_le_bytes().into())),
@@ -253,6 +285,14 @@ where
 		data.db_write_time,
 	);
 
+	if data.skipped_count > 0 {
+		warn!(
+			"{} entries of {} were skipped for exceeding the platform limits",
+			data.skipped_count,
+			location_path.as_ref().display()
+		);
+	}
+
 	if data.indexed_count > 0 || data.removed_count > 0 {
 		invalidate_query!(ctx.library, "search.paths");
 	}
@@ -278,12 +318,35 @@ fn update_notifier_fn(batch_size: usize, ctx: &mut WorkerContext) -> impl FnMut(
 fn iso_file_path_factory(
 	location_id: location::id::Type,
 	location_path: &Path,
+	normalization: FileNameNormalization,
 ) -> impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError> + '_ {
 	move |path, is_dir| {
-		IsolatedFilePathData::new(location_id, location_path, path, is_dir).map_err(Into::into)
+		IsolatedFilePathData::new(location_id, location_path, path, is_dir)
+			.map(|iso_file_path| iso_file_path.normalized(normalization))
+			.map_err(Into::into)
 	}
 }
 
+/// The indexer rules of a location, plus one skipping the locations nested inside of it, which
+/// index their own files
+async fn location_indexer_rules(
+	location: &location_with_indexer_rules::Data,
+	location_path: &Path,
+	db: &PrismaClient,
+) -> Result<Vec<rules::IndexerRule>, IndexerError> {
+	let mut indexer_rules = location
+		.indexer_rules
+		.iter()
+		.map(|rule| rules::IndexerRule::try_from(&rule.indexer_rule))
+		.collect::<Result<Vec<_>, _>>()?;
+
+	indexer_rules.extend(exclude_nested_locations_rule(
+		&nested_location_paths(db, location_path).await?,
+	)?);
+
+	Ok(indexer_rules)
+}
+
 async fn remove_non_existing_file_paths(
 	to_remove: impl IntoIterator<Item = file_path_just_pub_id::Data>,
 	db: &PrismaClient,
@@ -333,6 +396,9 @@ macro_rules! to_remove_db_fetcher_fn {
 							.materialized_path_for_children()
 							.expect("the received isolated file path must be from a directory"),
 					)),
+					// Compressed and tiered files are missing from disk on purpose
+					$crate::prisma::file_path::compressed_at::equals(None),
+					$crate::prisma::file_path::tiered_at::equals(None),
 					::prisma_client_rust::operator::not(
 						unique_location_id_materialized_path_name_extension_params,
 					),
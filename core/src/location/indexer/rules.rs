@@ -4,6 +4,7 @@ use crate::{
 	util::{
 		db::{maybe_missing, uuid_to_bytes, MissingFieldError},
 		error::{FileIOError, NonUtf8PathError},
+		vfs::Filesystem,
 	},
 };
 
@@ -21,7 +22,6 @@ use rspc::ErrorCode;
 use serde::{de, ser, Deserialize, Serialize};
 use specta::Type;
 use thiserror::Error;
-use tokio::fs;
 use tracing::debug;
 use uuid::Uuid;
 
@@ -426,15 +426,19 @@ impl<'de> Deserialize<'de> for RulePerKind {
 }
 
 impl RulePerKind {
-	async fn apply(&self, source: impl AsRef<Path>) -> Result<(RuleKind, bool), IndexerRuleError> {
+	async fn apply(
+		&self,
+		fs: &impl Filesystem,
+		source: impl AsRef<Path>,
+	) -> Result<(RuleKind, bool), IndexerRuleError> {
 		match self {
 			RulePerKind::AcceptIfChildrenDirectoriesArePresent(children) => {
-				accept_dir_for_its_children(source, children)
+				accept_dir_for_its_children(fs, source, children)
 					.await
 					.map(|accepted| (RuleKind::AcceptIfChildrenDirectoriesArePresent, accepted))
 			}
 			RulePerKind::RejectIfChildrenDirectoriesArePresent(children) => {
-				reject_dir_for_its_children(source, children)
+				reject_dir_for_its_children(fs, source, children)
 					.await
 					.map(|rejected| (RuleKind::RejectIfChildrenDirectoriesArePresent, rejected))
 			}
@@ -464,16 +468,23 @@ pub struct IndexerRule {
 impl IndexerRule {
 	pub async fn apply(
 		&self,
+		fs: &impl Filesystem,
 		source: impl AsRef<Path>,
 	) -> Result<Vec<(RuleKind, bool)>, IndexerRuleError> {
-		try_join_all(self.rules.iter().map(|rule| rule.apply(source.as_ref()))).await
+		try_join_all(
+			self.rules
+				.iter()
+				.map(|rule| rule.apply(fs, source.as_ref())),
+		)
+		.await
 	}
 
 	pub async fn apply_all(
 		rules: &[IndexerRule],
+		fs: &impl Filesystem,
 		source: impl AsRef<Path>,
 	) -> Result<HashMap<RuleKind, Vec<bool>>, IndexerRuleError> {
-		try_join_all(rules.iter().map(|rule| rule.apply(source.as_ref())))
+		try_join_all(rules.iter().map(|rule| rule.apply(fs, source.as_ref())))
 			.await
 			.map(|results| {
 				results.into_iter().flatten().fold(
@@ -522,41 +533,44 @@ fn reject_by_glob(source: impl AsRef<Path>, reject_glob_set: &GlobSet) -> bool {
 }
 
 async fn accept_dir_for_its_children(
+	fs: &impl Filesystem,
 	source: impl AsRef<Path>,
 	children: &HashSet<String>,
 ) -> Result<bool, IndexerRuleError> {
 	let source = source.as_ref();
 
 	// FIXME(fogodev): Just check for io::ErrorKind::NotADirectory error instead (feature = "io_error_more", issue = "86442")
-	if !fs::metadata(source)
+	if !fs
+		.metadata(source)
 		.await
 		.map_err(|e| IndexerRuleError::AcceptByItsChildrenFileIO(FileIOError::from((source, e))))?
-		.is_dir()
+		.is_dir
 	{
 		return Ok(false);
 	}
 
-	let mut read_dir = fs::read_dir(source)
+	let entries = fs
+		.read_dir(source)
 		.await // TODO: Check NotADirectory error here when available
 		.map_err(|e| IndexerRuleError::AcceptByItsChildrenFileIO(FileIOError::from((source, e))))?;
-	while let Some(entry) = read_dir
-		.next_entry()
-		.await
-		.map_err(|e| IndexerRuleError::AcceptByItsChildrenFileIO(FileIOError::from((source, e))))?
-	{
+	for entry in entries {
+		let entry = entry.map_err(|e| {
+			IndexerRuleError::AcceptByItsChildrenFileIO(FileIOError::from((source, e)))
+		})?;
+
 		let entry_name = entry
 			.file_name()
-			.to_str()
-			.ok_or_else(|| NonUtf8PathError(entry.path().into()))?
+			.and_then(|name| name.to_str())
+			.ok_or_else(|| NonUtf8PathError(entry.clone().into()))?
 			.to_string();
 
-		if entry
-			.metadata()
+		if fs
+			.symlink_metadata(&entry)
 			.await
 			.map_err(|e| {
 				IndexerRuleError::AcceptByItsChildrenFileIO(FileIOError::from((source, e)))
 			})?
-			.is_dir() && children.contains(&entry_name)
+			.is_dir && children.contains(&entry_name)
 		{
 			return Ok(true);
 		}
@@ -566,39 +580,42 @@ async fn accept_dir_for_its_children(
 }
 
 async fn reject_dir_for_its_children(
+	fs: &impl Filesystem,
 	source: impl AsRef<Path>,
 	children: &HashSet<String>,
 ) -> Result<bool, IndexerRuleError> {
 	let source = source.as_ref();
 
 	// FIXME(fogodev): Just check for io::ErrorKind::NotADirectory error instead (feature = "io_error_more", issue = "86442")
-	if !fs::metadata(source)
+	if !fs
+		.metadata(source)
 		.await
 		.map_err(|e| IndexerRuleError::AcceptByItsChildrenFileIO(FileIOError::from((source, e))))?
-		.is_dir()
+		.is_dir
 	{
 		return Ok(true);
 	}
 
-	let mut read_dir = fs::read_dir(source)
+	let entries = fs
+		.read_dir(source)
 		.await // TODO: Check NotADirectory error here when available
 		.map_err(|e| IndexerRuleError::RejectByItsChildrenFileIO(FileIOError::from((source, e))))?;
-	while let Some(entry) = read_dir
-		.next_entry()
-		.await
-		.map_err(|e| IndexerRuleError::RejectByItsChildrenFileIO(FileIOError::from((source, e))))?
-	{
-		if entry
-			.metadata()
+	for entry in entries {
+		let entry = entry.map_err(|e| {
+			IndexerRuleError::RejectByItsChildrenFileIO(FileIOError::from((source, e)))
+		})?;
+
+		if fs
+			.symlink_metadata(&entry)
 			.await
 			.map_err(|e| {
 				IndexerRuleError::RejectByItsChildrenFileIO(FileIOError::from((source, e)))
 			})?
-			.is_dir() && children.contains(
+			.is_dir && children.contains(
 			entry
 				.file_name()
-				.to_str()
-				.ok_or_else(|| NonUtf8PathError(entry.path().into()))?,
+				.and_then(|name| name.to_str())
+				.ok_or_else(|| NonUtf8PathError(entry.clone().into()))?,
 		) {
 			return Ok(false);
 		}
@@ -820,6 +837,7 @@ pub use seeder::*;
 #[allow(clippy::unwrap_used)]
 mod tests {
 	use super::*;
+	use crate::util::vfs::LocalFs;
 	use tempfile::tempdir;
 	use tokio::fs;
 
@@ -838,7 +856,7 @@ mod tests {
 
 	async fn check_rule(indexer_rule: &IndexerRule, path: impl AsRef<Path>) -> bool {
 		indexer_rule
-			.apply(path)
+			.apply(&LocalFs, path)
 			.await
 			.unwrap()
 			.into_iter()
@@ -10,17 +10,19 @@ use crate::{
 		LocationError,
 	},
 	to_remove_db_fetcher_fn,
+	util::vfs::LocalFs,
 };
-use tracing::error;
+use tracing::{error, warn};
 
 use std::path::{Path, PathBuf};
 
 use itertools::Itertools;
 
 use super::{
-	execute_indexer_save_step, iso_file_path_factory, location_with_indexer_rules,
-	remove_non_existing_file_paths, rules::IndexerRule, walk::walk_single_dir, IndexerError,
-	IndexerJobSaveStep,
+	execute_indexer_save_step, iso_file_path_factory, location_indexer_rules,
+	location_with_indexer_rules, remove_non_existing_file_paths,
+	walk::{walk_single_dir, SkippedEntry},
+	IndexerError, IndexerJobSaveStep,
 };
 
 /// BATCH_SIZE is the number of files to index at each step, writing the chunk of files metadata in the database.
@@ -38,12 +40,7 @@ pub async fn shallow(
 
 	let db = library.db.clone();
 
-	let indexer_rules = location
-		.indexer_rules
-		.iter()
-		.map(|rule| IndexerRule::try_from(&rule.indexer_rule))
-		.collect::<Result<Vec<_>, _>>()
-		.map_err(IndexerError::from)?;
+	let indexer_rules = location_indexer_rules(location, &location_path, &db).await?;
 
 	let (add_root, to_walk_path) = if sub_path != Path::new("") {
 		let full_path = ensure_sub_path_is_in_location(&location_path, &sub_path)
@@ -56,7 +53,8 @@ pub async fn shallow(
 		(
 			!check_file_path_exists::<IndexerError>(
 				&IsolatedFilePathData::new(location_id, &location_path, &full_path, true)
-					.map_err(IndexerError::from)?,
+					.map_err(IndexerError::from)?
+					.normalized(library.config.file_name_normalization),
 				&db,
 			)
 			.await?,
@@ -66,20 +64,33 @@ pub async fn shallow(
 		(false, location_path.to_path_buf())
 	};
 
-	let (walked, to_remove, errors) = {
+	let (walked, to_remove, errors, skipped) = {
 		walk_single_dir(
+			&LocalFs,
 			&to_walk_path,
 			&indexer_rules,
 			|_, _| {},
 			file_paths_db_fetcher_fn!(&db),
 			to_remove_db_fetcher_fn!(location_id, location_path, &db),
-			iso_file_path_factory(location_id, &location_path),
+			iso_file_path_factory(
+				location_id,
+				&location_path,
+				library.config.file_name_normalization,
+			),
 			add_root,
 		)
 		.await?
 	};
 
 	errors.into_iter().for_each(|e| error!("{e}"));
+	skipped
+		.into_iter()
+		.for_each(|SkippedEntry { path, reason }| {
+			warn!(
+				"Skipped {} for exceeding the platform limits: {reason:?}",
+				path.display()
+			)
+		});
 
 	// TODO pass these uuids to sync system
 	remove_non_existing_file_paths(to_remove, &db).await?;
@@ -1,20 +1,19 @@
 use crate::{
 	location::file_path_helper::{
 		file_path_just_pub_id, file_path_to_isolate, FilePathMetadata, IsolatedFilePathData,
-		MetadataExt,
 	},
+	object::special::is_bundle,
 	prisma::file_path,
-	util::error::FileIOError,
+	util::{
+		error::FileIOError,
+		long_path::{strip_extended_length_prefix, to_extended_length},
+		vfs::Filesystem,
+	},
 };
 
-#[cfg(target_family = "unix")]
-use crate::location::file_path_helper::get_inode_and_device;
-
-#[cfg(target_family = "windows")]
-use crate::location::file_path_helper::get_inode_and_device_from_path;
-
 use std::{
 	collections::{HashSet, VecDeque},
+	ffi::OsStr,
 	future::Future,
 	hash::{Hash, Hasher},
 	path::{Path, PathBuf},
@@ -22,7 +21,6 @@ use std::{
 
 use prisma_client_rust::operator;
 use serde::{Deserialize, Serialize};
-use tokio::fs;
 use tracing::trace;
 use uuid::Uuid;
 
@@ -35,6 +33,17 @@ const TO_WALK_QUEUE_INITIAL_CAPACITY: usize = 32;
 const WALKER_PATHS_BUFFER_INITIAL_CAPACITY: usize = 256;
 const WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY: usize = 32;
 
+/// Paths longer than this can't be used on filesystem calls, it's `PATH_MAX` on unix and the
+/// extended-length paths limit on Windows
+#[cfg(target_family = "unix")]
+const MAX_PATH_LENGTH: usize = 4096;
+#[cfg(target_family = "windows")]
+const MAX_PATH_LENGTH: usize = 32_767;
+/// Names longer than this can't be created on most filesystems, so they wouldn't sync anywhere
+const MAX_NAME_LENGTH: usize = 255;
+/// Trees deeper than this are most likely loops made by bind mounts or junctions
+const MAX_PATH_DEPTH: usize = 512;
+
 /// `WalkEntry` represents a single path in the filesystem, for any comparison purposes, we only
 /// consider the path itself, not the metadata.
 #[derive(Debug, Serialize, Deserialize)]
@@ -48,6 +57,78 @@ pub struct WalkedEntry {
 pub struct ToWalkEntry {
 	path: PathBuf,
 	parent_dir_accepted_by_its_children: Option<bool>,
+	/// How many levels of entries may still be indexed below this directory, `None` for all of them
+	#[serde(default)]
+	remaining_depth: Option<usize>,
+	/// Inode and device of this directory and the ones above it, so a directory mounted or hard
+	/// linked inside itself is only walked once
+	#[serde(default)]
+	ancestors: Vec<(u64, u64)>,
+	/// Bundles are indexed without their contents
+	#[serde(default)]
+	bundles_as_files: bool,
+}
+
+/// Why an entry was left out of the index, besides the indexer rules
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SkipReason {
+	PathTooDeep {
+		depth: usize,
+	},
+	PathTooLong {
+		length: usize,
+	},
+	NameTooLong {
+		length: usize,
+	},
+	/// The directory is one of its own ancestors
+	Loop,
+}
+
+impl SkipReason {
+	fn check_limits(path: &Path, depth: usize) -> Option<Self> {
+		if depth > MAX_PATH_DEPTH {
+			return Some(Self::PathTooDeep { depth });
+		}
+
+		let name_length = path.file_name().map_or(0, os_str_length);
+		if name_length > MAX_NAME_LENGTH {
+			return Some(Self::NameTooLong {
+				length: name_length,
+			});
+		}
+
+		let path_length = os_str_length(path.as_os_str());
+		if path_length > MAX_PATH_LENGTH {
+			return Some(Self::PathTooLong {
+				length: path_length,
+			});
+		}
+
+		None
+	}
+}
+
+/// `SkippedEntry` is a path found while walking that exceeds the platform limits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedEntry {
+	pub path: PathBuf,
+	pub reason: SkipReason,
+}
+
+/// Length in the unit the platform limits are defined with, bytes on unix and UTF-16 code units on Windows
+fn os_str_length(s: &OsStr) -> usize {
+	#[cfg(target_family = "unix")]
+	{
+		s.len()
+	}
+
+	#[cfg(target_family = "windows")]
+	{
+		use std::os::windows::ffi::OsStrExt;
+
+		s.encode_wide().count()
+	}
 }
 
 struct WalkingEntry {
@@ -78,12 +159,22 @@ where
 	pub to_walk: VecDeque<ToWalkEntry>,
 	pub to_remove: ToRemove,
 	pub errors: Vec<IndexerError>,
+	pub skipped: Vec<SkippedEntry>,
+	/// Directories left unwalked for being as deep as the walk may go
+	pub deferred: Vec<IsolatedFilePathData<'static>>,
 }
 
 /// This function walks through the filesystem, applying the rules to each entry and then returning
 /// a list of accepted entries. There are some useful comments in the implementation of this function
 /// in case of doubts.
-pub(super) async fn walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+///
+/// With a `max_depth`, only that many levels of entries below `root` are indexed, and the
+/// directories at the last level are returned as deferred instead of being walked.
+///
+/// Links are indexed but never followed. With `bundles_as_files`, bundles are indexed like files,
+/// leaving their contents out.
+pub(crate) async fn walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+	fs: &impl Filesystem,
 	root: impl AsRef<Path>,
 	indexer_rules: &[IndexerRule],
 	mut update_notifier: impl FnMut(&Path, usize),
@@ -94,6 +185,8 @@ pub(super) async fn walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
 	) -> ToRemoveDbFetcherFut,
 	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
 	limit: u64,
+	max_depth: Option<usize>,
+	bundles_as_files: bool,
 ) -> Result<
 	WalkResult<
 		impl Iterator<Item = WalkedEntry>,
@@ -105,20 +198,32 @@ where
 	FilePathDBFetcherFut: Future<Output = Result<Vec<file_path_to_isolate::Data>, IndexerError>>,
 	ToRemoveDbFetcherFut: Future<Output = Result<Vec<file_path_just_pub_id::Data>, IndexerError>>,
 {
+	// Walking with extended-length paths, so we can go deeper than `MAX_PATH` on Windows
+	let root = to_extended_length(root.as_ref());
 	let root = root.as_ref();
 
 	let mut to_walk = VecDeque::with_capacity(TO_WALK_QUEUE_INITIAL_CAPACITY);
 	to_walk.push_back(ToWalkEntry {
 		path: root.to_path_buf(),
 		parent_dir_accepted_by_its_children: None,
+		remaining_depth: max_depth,
+		ancestors: fs
+			.metadata(root)
+			.await
+			.map(|metadata| vec![(metadata.file_path.inode, metadata.file_path.device)])
+			.unwrap_or_default(),
+		bundles_as_files,
 	});
 	let mut indexed_paths = HashSet::with_capacity(WALKER_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut errors = vec![];
+	let mut skipped = vec![];
+	let mut deferred = vec![];
 	let mut paths_buffer = Vec::with_capacity(WALKER_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut to_remove = vec![];
 
 	while let Some(ref entry) = to_walk.pop_front() {
 		let current_to_remove = inner_walk_single_dir(
+			fs,
 			root,
 			entry,
 			indexer_rules,
@@ -130,6 +235,8 @@ where
 				paths_buffer: &mut paths_buffer,
 				maybe_to_walk: Some(&mut to_walk),
 				errors: &mut errors,
+				skipped: &mut skipped,
+				deferred: &mut deferred,
 			},
 		)
 		.await;
@@ -145,10 +252,13 @@ where
 		to_walk,
 		to_remove: to_remove.into_iter().flatten(),
 		errors,
+		skipped,
+		deferred,
 	})
 }
 
 pub(super) async fn keep_walking<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+	fs: &impl Filesystem,
 	to_walk_entry: &ToWalkEntry,
 	indexer_rules: &[IndexerRule],
 	mut update_notifier: impl FnMut(&Path, usize),
@@ -173,8 +283,11 @@ where
 	let mut indexed_paths = HashSet::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut paths_buffer = Vec::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut errors = vec![];
+	let mut skipped = vec![];
+	let mut deferred = vec![];
 
 	let to_remove = inner_walk_single_dir(
+		fs,
 		to_walk_entry.path.clone(),
 		to_walk_entry,
 		indexer_rules,
@@ -186,6 +299,8 @@ where
 			paths_buffer: &mut paths_buffer,
 			maybe_to_walk: Some(&mut to_keep_walking),
 			errors: &mut errors,
+			skipped: &mut skipped,
+			deferred: &mut deferred,
 		},
 	)
 	.await;
@@ -195,10 +310,13 @@ where
 		to_walk: to_keep_walking,
 		to_remove: to_remove.into_iter(),
 		errors,
+		skipped,
+		deferred,
 	})
 }
 
 pub(super) async fn walk_single_dir<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+	fs: &impl Filesystem,
 	root: impl AsRef<Path>,
 	indexer_rules: &[IndexerRule],
 	mut update_notifier: impl FnMut(&Path, usize) + '_,
@@ -214,6 +332,7 @@ pub(super) async fn walk_single_dir<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
 		impl Iterator<Item = WalkedEntry>,
 		Vec<file_path_just_pub_id::Data>,
 		Vec<IndexerError>,
+		Vec<SkippedEntry>,
 	),
 	IndexerError,
 >
@@ -221,47 +340,38 @@ where
 	FilePathDBFetcherFut: Future<Output = Result<Vec<file_path_to_isolate::Data>, IndexerError>>,
 	ToRemoveDbFetcherFut: Future<Output = Result<Vec<file_path_just_pub_id::Data>, IndexerError>>,
 {
+	// Walking with extended-length paths, so we can go deeper than `MAX_PATH` on Windows
+	let root = to_extended_length(root.as_ref());
 	let root = root.as_ref();
 
 	let mut indexed_paths = HashSet::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 
 	if add_root {
-		let metadata = fs::metadata(root)
+		let metadata = fs
+			.metadata(root)
 			.await
 			.map_err(|e| FileIOError::from((root, e)))?;
 
-		let (inode, device) = {
-			#[cfg(target_family = "unix")]
-			{
-				get_inode_and_device(&metadata)
-			}
-
-			#[cfg(target_family = "windows")]
-			{
-				get_inode_and_device_from_path(&root).await
-			}
-		}?;
-
 		indexed_paths.insert(WalkingEntry {
 			iso_file_path: iso_file_path_factory(root, true)?,
-			maybe_metadata: Some(FilePathMetadata {
-				inode,
-				device,
-				size_in_bytes: metadata.len(),
-				created_at: metadata.created_or_now().into(),
-				modified_at: metadata.modified_or_now().into(),
-			}),
+			maybe_metadata: Some(metadata.file_path),
 		});
 	}
 
 	let mut paths_buffer = Vec::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut errors = vec![];
+	let mut skipped = vec![];
 
 	let to_remove = inner_walk_single_dir(
+		fs,
 		root,
 		&ToWalkEntry {
 			path: root.to_path_buf(),
 			parent_dir_accepted_by_its_children: None,
+			remaining_depth: None,
+			// Nothing is walked below the directory
+			ancestors: vec![],
+			bundles_as_files: false,
 		},
 		indexer_rules,
 		&mut update_notifier,
@@ -272,6 +382,9 @@ where
 			paths_buffer: &mut paths_buffer,
 			maybe_to_walk: None,
 			errors: &mut errors,
+			skipped: &mut skipped,
+			// Nothing is deferred without somewhere to walk to
+			deferred: &mut vec![],
 		},
 	)
 	.await;
@@ -280,6 +393,7 @@ where
 		filter_existing_paths(indexed_paths, file_paths_db_fetcher).await?,
 		to_remove,
 		errors,
+		skipped,
 	))
 }
 
@@ -325,13 +439,19 @@ struct WorkingTable<'a> {
 	paths_buffer: &'a mut Vec<WalkingEntry>,
 	maybe_to_walk: Option<&'a mut VecDeque<ToWalkEntry>>,
 	errors: &'a mut Vec<IndexerError>,
+	skipped: &'a mut Vec<SkippedEntry>,
+	deferred: &'a mut Vec<IsolatedFilePathData<'static>>,
 }
 
 async fn inner_walk_single_dir<ToRemoveDbFetcherFut>(
+	fs: &impl Filesystem,
 	root: impl AsRef<Path>,
 	ToWalkEntry {
 		path,
 		parent_dir_accepted_by_its_children,
+		remaining_depth,
+		ancestors,
+		bundles_as_files,
 	}: &ToWalkEntry,
 	indexer_rules: &[IndexerRule],
 	update_notifier: &mut impl FnMut(&Path, usize),
@@ -345,6 +465,8 @@ async fn inner_walk_single_dir<ToRemoveDbFetcherFut>(
 		paths_buffer,
 		mut maybe_to_walk,
 		errors,
+		skipped,
+		deferred,
 	}: WorkingTable<'_>,
 ) -> Vec<file_path_just_pub_id::Data>
 where
@@ -355,7 +477,9 @@ where
 		return vec![];
 	};
 
-	let Ok(mut read_dir) = fs::read_dir(path).await
+	let Ok(entries) = fs
+		.read_dir(path)
+		.await
 		.map_err(|e| errors.push(FileIOError::from((path.clone(), e)).into()))
 		else {
 		return vec![];
@@ -363,16 +487,25 @@ where
 
 	let root = root.as_ref();
 
+	// Entries of the root directory are at depth 1
+	let depth = iso_file_path_to_walk
+		.materialized_path_for_children()
+		.map_or(0, |materialized_path| {
+			materialized_path.matches('/').count()
+		});
+
+	// The entries of the directories found here are a level deeper
+	let children_remaining_depth = remaining_depth.map(|depth| depth.saturating_sub(1));
+
 	// Just to make sure...
 	paths_buffer.clear();
 
 	let mut found_paths_counts = 0;
 
 	// Marking with a loop label here in case of rejection or erros, to continue with next entry
-	'entries: loop {
-		let entry = match read_dir.next_entry().await {
-			Ok(Some(entry)) => entry,
-			Ok(None) => break,
+	'entries: for entry in entries {
+		let current_path = match entry {
+			Ok(current_path) => current_path,
 			Err(e) => {
 				errors.push(FileIOError::from((path.clone(), e)).into());
 				continue;
@@ -386,7 +519,17 @@ where
 		// and we pass the current parent state to its children
 		let mut accept_by_children_dir = *parent_dir_accepted_by_its_children;
 
-		let current_path = entry.path();
+		if let Some(reason) = SkipReason::check_limits(&current_path, depth) {
+			trace!(
+				"Path {} skipped for exceeding the platform limits: {reason:?}",
+				current_path.display()
+			);
+			skipped.push(SkippedEntry {
+				path: strip_extended_length_prefix(&current_path).into_owned(),
+				reason,
+			});
+			continue 'entries;
+		}
 
 		// Just sending updates if we found more paths since the last loop
 		let current_found_paths_count = paths_buffer.len();
@@ -404,7 +547,8 @@ where
 			accept_by_children_dir
 		);
 
-		let Ok(rules_per_kind) = IndexerRule::apply_all(indexer_rules, &current_path).await
+		let Ok(rules_per_kind) = IndexerRule::apply_all(indexer_rules, fs, &current_path)
+			.await
 			.map_err(|e| errors.push(e.into()))
 			else {
 			continue 'entries;
@@ -422,37 +566,29 @@ where
 			continue 'entries;
 		}
 
-		let Ok(metadata) = entry
-			.metadata()
+		let Ok(metadata) = fs
+			.symlink_metadata(&current_path)
 			.await
-			.map_err(|e| errors.push(FileIOError::from((entry.path(), e)).into()))
+			.map_err(|e| errors.push(FileIOError::from((&current_path, e)).into()))
 			else {
 				continue 'entries;
 		};
 
-		// TODO: Hard ignoring symlinks for now, but this should be configurable
-		if metadata.is_symlink() {
-			continue 'entries;
-		}
-
-		let is_dir = metadata.is_dir();
-
-		let Ok((inode, device)) = {
-			#[cfg(target_family = "unix")]
-			{
-				get_inode_and_device(&metadata)
-			}
+		// Links are indexed as they are, `symlink_metadata` never telling they lead to a directory,
+		// so we don't walk into them
+		let is_dir = metadata.is_dir;
 
-			#[cfg(target_family = "windows")]
-			{
-				get_inode_and_device_from_path(&current_path).await
+		if is_dir {
+			let id = (metadata.file_path.inode, metadata.file_path.device);
+			if ancestors.contains(&id) {
+				trace!("Path {} skipped as it loops", current_path.display());
+				skipped.push(SkippedEntry {
+					path: strip_extended_length_prefix(&current_path).into_owned(),
+					reason: SkipReason::Loop,
+				});
+				continue 'entries;
 			}
-		}.map_err(|e| errors.push(e.into()))
-		else {
-			continue 'entries;
-		};
 
-		if is_dir {
 			// If it is a directory, first we check if we must reject it and its children entirely
 			if rules_per_kind
 				.get(&RuleKind::RejectIfChildrenDirectoriesArePresent)
@@ -484,12 +620,25 @@ where
 				}
 			}
 
-			// Then we mark this directory the be walked in too
+			// Then we mark this directory the be walked in too, unless it's as deep as we may go
 			if let Some(ref mut to_walk) = maybe_to_walk {
-				to_walk.push_back(ToWalkEntry {
-					path: entry.path(),
-					parent_dir_accepted_by_its_children: accept_by_children_dir,
-				});
+				if *bundles_as_files && is_bundle(&current_path) {
+					trace!("Bundle {} indexed as a file", current_path.display());
+				} else if children_remaining_depth == Some(0) {
+					if let Ok(iso_file_path) =
+						iso_file_path_factory(&current_path, true).map_err(|e| errors.push(e))
+					{
+						deferred.push(iso_file_path);
+					}
+				} else {
+					to_walk.push_back(ToWalkEntry {
+						path: current_path.clone(),
+						parent_dir_accepted_by_its_children: accept_by_children_dir,
+						remaining_depth: children_remaining_depth,
+						ancestors: ancestors.iter().copied().chain([id]).collect(),
+						bundles_as_files: *bundles_as_files,
+					});
+				}
 			}
 		}
 
@@ -513,13 +662,7 @@ where
 			};
 			paths_buffer.push(WalkingEntry {
 				iso_file_path,
-				maybe_metadata: Some(FilePathMetadata {
-					inode,
-					device,
-					size_in_bytes: metadata.len(),
-					created_at: metadata.created_or_now().into(),
-					modified_at: metadata.modified_or_now().into(),
-				}),
+				maybe_metadata: Some(metadata.file_path),
 			});
 
 			// If the ancestors directories wasn't indexed before, now we do
@@ -541,35 +684,16 @@ where
 				};
 				trace!("Indexing ancestor {}", ancestor.display());
 				if !indexed_paths.contains(&ancestor_iso_walking_entry) {
-					let Ok(metadata) = fs::metadata(ancestor)
+					let Ok(metadata) = fs
+						.metadata(ancestor)
 						.await
 						.map_err(|e| errors.push(FileIOError::from((&ancestor, e)).into()))
 						else {
 							// Checking the next ancestor, as this one we got an error
 							continue;
 					};
-					let Ok((inode, device)) = {
-						#[cfg(target_family = "unix")]
-						{
-							get_inode_and_device(&metadata)
-						}
-
-						#[cfg(target_family = "windows")]
-						{
-							get_inode_and_device_from_path(ancestor).await
-						}
-					}.map_err(|e| errors.push(e.into())) else {
-						// Checking the next ancestor, as this one we got an error
-						continue;
-					};
 
-					ancestor_iso_walking_entry.maybe_metadata = Some(FilePathMetadata {
-						inode,
-						device,
-						size_in_bytes: metadata.len(),
-						created_at: metadata.created_or_now().into(),
-						modified_at: metadata.modified_or_now().into(),
-					});
+					ancestor_iso_walking_entry.maybe_metadata = Some(metadata.file_path);
 
 					paths_buffer.push(ancestor_iso_walking_entry);
 				} else {
@@ -612,8 +736,10 @@ where
 mod tests {
 	use super::super::rules::RulePerKind;
 	use super::*;
+	use crate::util::vfs::{LocalFs, MemoryFs, MEMORY_FS_DEVICE};
 	use chrono::Utc;
 	use globset::{Glob, GlobSetBuilder};
+	use std::collections::HashMap;
 	use tempfile::{tempdir, TempDir};
 	use tokio::fs;
 	// use tracing_test::traced_test;
@@ -698,6 +824,7 @@ mod tests {
 			inode: 0,
 			device: 0,
 			size_in_bytes: 0,
+			allocated_size_in_bytes: 0,
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 		};
@@ -734,6 +861,7 @@ mod tests {
 		.collect::<HashSet<_>>();
 
 		let walk_result = walk(
+			&LocalFs,
 			root_path.to_path_buf(),
 			&[],
 			|_, _| {},
@@ -743,6 +871,8 @@ mod tests {
 				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
 			},
 			420,
+			None,
+			false,
 		)
 		.await
 		.unwrap();
@@ -768,6 +898,7 @@ mod tests {
 			inode: 0,
 			device: 0,
 			size_in_bytes: 0,
+			allocated_size_in_bytes: 0,
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 		};
@@ -798,6 +929,7 @@ mod tests {
 		)];
 
 		let walk_result = walk(
+			&LocalFs,
 			root_path.to_path_buf(),
 			only_photos_rule,
 			|_, _| {},
@@ -807,6 +939,8 @@ mod tests {
 				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
 			},
 			420,
+			None,
+			false,
 		)
 		.await
 		.unwrap();
@@ -832,6 +966,7 @@ mod tests {
 			inode: 0,
 			device: 0,
 			size_in_bytes: 0,
+			allocated_size_in_bytes: 0,
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 		};
@@ -871,6 +1006,7 @@ mod tests {
 		)];
 
 		let walk_result = walk(
+			&LocalFs,
 			root_path.to_path_buf(),
 			git_repos,
 			|_, _| {},
@@ -880,6 +1016,8 @@ mod tests {
 				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
 			},
 			420,
+			None,
+			false,
 		)
 		.await
 		.unwrap();
@@ -905,6 +1043,7 @@ mod tests {
 			inode: 0,
 			device: 0,
 			size_in_bytes: 0,
+			allocated_size_in_bytes: 0,
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 		};
@@ -962,6 +1101,7 @@ mod tests {
 		];
 
 		let walk_result = walk(
+			&LocalFs,
 			root_path.to_path_buf(),
 			git_repos_no_deps_no_build_dirs,
 			|_, _| {},
@@ -971,6 +1111,8 @@ mod tests {
 				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
 			},
 			420,
+			None,
+			false,
 		)
 		.await
 		.unwrap();
@@ -985,4 +1127,198 @@ mod tests {
 			panic!("difference: {:#?}", expected.difference(&actual));
 		}
 	}
+
+	#[test]
+	fn skip_entries_exceeding_limits() {
+		let location_path = Path::new("/location");
+
+		assert!(SkipReason::check_limits(&location_path.join("file.txt"), 1).is_none());
+		assert!(matches!(
+			SkipReason::check_limits(&location_path.join("file.txt"), MAX_PATH_DEPTH + 1),
+			Some(SkipReason::PathTooDeep { .. })
+		));
+		assert!(matches!(
+			SkipReason::check_limits(&location_path.join("a".repeat(MAX_NAME_LENGTH + 1)), 1),
+			Some(SkipReason::NameTooLong { length }) if length == MAX_NAME_LENGTH + 1
+		));
+
+		let too_long_path = (0..MAX_PATH_LENGTH / MAX_NAME_LENGTH + 1)
+			.fold(location_path.to_path_buf(), |path, _| {
+				path.join("a".repeat(MAX_NAME_LENGTH))
+			});
+		assert!(matches!(
+			SkipReason::check_limits(&too_long_path, 1),
+			Some(SkipReason::PathTooLong { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn walk_memory_fs() {
+		let build = || {
+			let fs = MemoryFs::default();
+			fs.add_file("/location/rust_project/.git/HEAD", "ref")
+				.add_file("/location/rust_project/src/main.rs", "fn main() {}")
+				.add_file("/location/notes/todo.txt", "nothing")
+				.add_symlink("/location/rust_project/latest", "/location/notes");
+			fs
+		};
+
+		let git_repos = &[IndexerRule::new(
+			"git repos".to_string(),
+			false,
+			vec![RulePerKind::AcceptIfChildrenDirectoriesArePresent(
+				[".git".to_string()].into_iter().collect(),
+			)],
+		)];
+
+		let walk_memory = |fs: MemoryFs| async move {
+			let walk_result = walk(
+				&fs,
+				"/location",
+				git_repos,
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|path, is_dir| {
+					IsolatedFilePathData::new(0, "/location", path, is_dir).map_err(Into::into)
+				},
+				420,
+				None,
+				false,
+			)
+			.await
+			.unwrap();
+
+			assert!(walk_result.errors.is_empty(), "{:#?}", walk_result.errors);
+
+			walk_result
+				.walked
+				.map(|entry| (entry.iso_file_path.to_string(), entry.metadata))
+				.collect::<HashMap<_, _>>()
+		};
+
+		let walked = walk_memory(build()).await;
+
+		let mut paths = walked.keys().cloned().collect::<Vec<_>>();
+		paths.sort();
+		assert_eq!(
+			paths,
+			[
+				"rust_project",
+				"rust_project/.git",
+				"rust_project/.git/HEAD",
+				// Indexed, but not followed into the notes
+				"rust_project/latest",
+				"rust_project/src",
+				"rust_project/src/main.rs",
+			]
+		);
+
+		let main = walked["rust_project/src/main.rs"];
+		assert_eq!(main.device, MEMORY_FS_DEVICE);
+		assert_eq!(main.size_in_bytes, 12);
+
+		// Same tree, same inodes and dates
+		let again = walk_memory(build()).await;
+		for (path, metadata) in &walked {
+			assert_eq!(again[path].inode, metadata.inode, "{path}");
+			assert_eq!(again[path].modified_at, metadata.modified_at, "{path}");
+		}
+	}
+
+	#[tokio::test]
+	async fn walk_up_to_max_depth() {
+		let fs = MemoryFs::default();
+		fs.add_file("/location/a.txt", "a")
+			.add_file("/location/photos/2023/beach.jpg", "beach")
+			.add_file("/location/photos/2023/summer/sea.jpg", "sea")
+			.add_file("/location/photos/cat.jpg", "cat");
+
+		let walk_up_to = |max_depth| {
+			let fs = &fs;
+			async move {
+				let walk_result = walk(
+					fs,
+					"/location",
+					&[],
+					|_, _| {},
+					|_| async { Ok(vec![]) },
+					|_, _| async { Ok(vec![]) },
+					|path, is_dir| {
+						IsolatedFilePathData::new(0, "/location", path, is_dir).map_err(Into::into)
+					},
+					u64::MAX,
+					max_depth,
+					false,
+				)
+				.await
+				.unwrap();
+
+				let mut walked = walk_result
+					.walked
+					.map(|entry| entry.iso_file_path.to_string())
+					.collect::<Vec<_>>();
+				walked.sort();
+
+				let mut deferred = walk_result
+					.deferred
+					.iter()
+					.map(ToString::to_string)
+					.collect::<Vec<_>>();
+				deferred.sort();
+
+				(walked, deferred)
+			}
+		};
+
+		let (walked, deferred) = walk_up_to(Some(1)).await;
+		assert_eq!(walked, ["a.txt", "photos"]);
+		assert_eq!(deferred, ["photos"]);
+
+		let (walked, deferred) = walk_up_to(Some(2)).await;
+		assert_eq!(walked, ["a.txt", "photos", "photos/2023", "photos/cat.jpg"]);
+		assert_eq!(deferred, ["photos/2023"]);
+
+		let (walked, deferred) = walk_up_to(None).await;
+		assert_eq!(walked.len(), 7);
+		assert!(deferred.is_empty());
+	}
+
+	#[tokio::test]
+	async fn walk_bundles_as_files() {
+		let fs = MemoryFs::default();
+		fs.add_file("/location/Safari.app/Contents/Info.plist", "<plist/>")
+			.add_file("/location/readme.md", "hi");
+
+		let walk_bundles = |bundles_as_files| {
+			let fs = &fs;
+			async move {
+				let mut walked = walk(
+					fs,
+					"/location",
+					&[],
+					|_, _| {},
+					|_| async { Ok(vec![]) },
+					|_, _| async { Ok(vec![]) },
+					|path, is_dir| {
+						IsolatedFilePathData::new(0, "/location", path, is_dir).map_err(Into::into)
+					},
+					u64::MAX,
+					None,
+					bundles_as_files,
+				)
+				.await
+				.unwrap()
+				.walked
+				.map(|entry| entry.iso_file_path.to_string())
+				.collect::<Vec<_>>();
+				walked.sort();
+
+				walked
+			}
+		};
+
+		assert_eq!(walk_bundles(true).await, ["Safari.app", "readme.md"]);
+		assert_eq!(walk_bundles(false).await.len(), 4);
+	}
 }
@@ -1,6 +1,7 @@
 use crate::{
 	job::JobManagerError,
 	library::Library,
+	object::file_identifier::FileIdentifierJobError,
 	prisma::location,
 	util::{db::MissingFieldError, error::FileIOError},
 };
@@ -107,6 +108,8 @@ pub enum LocationManagerError {
 	CorruptedLocationPubId(#[from] uuid::Error),
 	#[error("Job Manager error: (error: {0})")]
 	JobManager(#[from] JobManagerError),
+	#[error("File identifier error: (error: {0})")]
+	FileIdentifier(#[from] FileIdentifierJobError),
 	#[error("missing-field")]
 	MissingField(#[from] MissingFieldError),
 
@@ -0,0 +1,159 @@
+//! Writing a file usually fires a burst of content update events, and each of them has the file
+//! hashed again. So the updates of a file are held until it's quiet for a while, and only the last
+//! one is handled. Pinned directories are looked at often, so they wait much less.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use notify::{
+	event::{AccessKind, AccessMode, DataChange, ModifyKind},
+	Event, EventKind,
+};
+use tokio::time::Instant;
+
+use super::{HUNDRED_MILLIS, ONE_SECOND};
+
+/// How long a file must go without updates for them to be handled
+const UPDATE_DEBOUNCE: Duration = ONE_SECOND;
+/// The same, in pinned directories
+const PINNED_UPDATE_DEBOUNCE: Duration = HUNDRED_MILLIS;
+
+#[derive(Debug, Default)]
+pub(super) struct UpdatesDebouncer {
+	pending: HashMap<PathBuf, (Instant, Event)>,
+}
+
+impl UpdatesDebouncer {
+	/// Holds the event if it's a content update, and returns the events to handle right away. An
+	/// update held for one of the paths of another event is handled before it, to keep their order.
+	pub(super) fn push(&mut self, event: Event) -> Vec<Event> {
+		if is_content_update(&event.kind) && event.paths.len() == 1 {
+			self.pending
+				.insert(event.paths[0].clone(), (Instant::now(), event));
+			return vec![];
+		}
+
+		let mut events = event
+			.paths
+			.iter()
+			.filter_map(|path| self.pending.remove(path))
+			.map(|(_, update)| update)
+			.collect::<Vec<_>>();
+		events.push(event);
+
+		events
+	}
+
+	/// The held updates that waited long enough
+	pub(super) fn take_ready(&mut self, pinned_paths: &[PathBuf]) -> Vec<Event> {
+		let ready = self
+			.pending
+			.iter()
+			.filter(|(path, (updated_at, _))| updated_at.elapsed() >= debounce(pinned_paths, path))
+			.map(|(path, _)| path.clone())
+			.collect::<Vec<_>>();
+
+		ready
+			.into_iter()
+			.filter_map(|path| self.pending.remove(&path))
+			.map(|(_, event)| event)
+			.collect()
+	}
+}
+
+fn debounce(pinned_paths: &[PathBuf], path: &Path) -> Duration {
+	if pinned_paths.iter().any(|pinned| path.starts_with(pinned)) {
+		PINNED_UPDATE_DEBOUNCE
+	} else {
+		UPDATE_DEBOUNCE
+	}
+}
+
+/// The events each platform sends when the content of a file changes
+fn is_content_update(kind: &EventKind) -> bool {
+	matches!(
+		kind,
+		EventKind::Access(AccessKind::Close(AccessMode::Write))
+			| EventKind::Modify(ModifyKind::Data(DataChange::Content))
+			| EventKind::Modify(ModifyKind::Any)
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use notify::event::{CreateKind, RemoveKind};
+	use tokio::time;
+
+	fn event(kind: EventKind, path: &str) -> Event {
+		Event::new(kind).add_path(PathBuf::from(path))
+	}
+
+	fn update(path: &str) -> Event {
+		event(
+			EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+			path,
+		)
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn updates_wait_less_in_pinned_directories() {
+		let pinned_paths = [PathBuf::from("/home/user/Downloads")];
+		let mut debouncer = UpdatesDebouncer::default();
+
+		assert!(debouncer
+			.push(update("/home/user/Downloads/movie.mkv"))
+			.is_empty());
+		assert!(debouncer
+			.push(update("/home/user/Archive/old.zip"))
+			.is_empty());
+		assert!(debouncer
+			.push(update("/home/user/Archive/old.zip"))
+			.is_empty());
+
+		time::advance(PINNED_UPDATE_DEBOUNCE).await;
+		let ready = debouncer.take_ready(&pinned_paths);
+		assert_eq!(ready.len(), 1);
+		assert_eq!(
+			ready[0].paths[0],
+			Path::new("/home/user/Downloads/movie.mkv")
+		);
+
+		time::advance(UPDATE_DEBOUNCE).await;
+		// Only the last of the updates of the same file
+		let ready = debouncer.take_ready(&pinned_paths);
+		assert_eq!(ready.len(), 1);
+		assert_eq!(ready[0].paths[0], Path::new("/home/user/Archive/old.zip"));
+
+		assert!(debouncer.take_ready(&pinned_paths).is_empty());
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn held_update_goes_before_other_events_of_the_file() {
+		let mut debouncer = UpdatesDebouncer::default();
+
+		assert!(debouncer.push(update("/location/notes.txt")).is_empty());
+
+		let events = debouncer.push(event(
+			EventKind::Remove(RemoveKind::File),
+			"/location/notes.txt",
+		));
+		assert_eq!(events.len(), 2);
+		assert!(is_content_update(&events[0].kind));
+		assert_eq!(events[1].kind, EventKind::Remove(RemoveKind::File));
+
+		// Other files don't wait for anything
+		let events = debouncer.push(event(
+			EventKind::Create(CreateKind::File),
+			"/location/todo.txt",
+		));
+		assert_eq!(events.len(), 1);
+
+		time::advance(UPDATE_DEBOUNCE).await;
+		assert!(debouncer.take_ready(&[]).is_empty());
+	}
+}
@@ -0,0 +1,103 @@
+//! Spots the downloads completed in a location with downloads automation, see
+//! `location::downloads`. A file showing up in the root of the location is held until nothing
+//! happened to it for a few seconds, browsers touching a download a few more times once it's done.
+
+use crate::{
+	library::Library,
+	location::downloads::{is_partial_download, organize_download, DownloadOutcome},
+	prisma::{download_rule, location},
+};
+
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use notify::{Event, EventKind};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+/// How long a download must go untouched to be organized
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+/// How many times a download is tried again while the watcher is still adding it to the library
+const MAX_ATTEMPTS: u8 = 10;
+
+#[derive(Debug)]
+pub(super) struct DownloadsTracker {
+	location_path: PathBuf,
+	/// Empty while the automation is disabled
+	rules: Vec<download_rule::Data>,
+	pending: HashMap<PathBuf, (Instant, u8)>,
+}
+
+impl DownloadsTracker {
+	pub(super) fn new(location_path: PathBuf) -> Self {
+		Self {
+			location_path,
+			rules: vec![],
+			pending: HashMap::new(),
+		}
+	}
+
+	pub(super) fn set_rules(&mut self, rules: Vec<download_rule::Data>) {
+		if rules.is_empty() {
+			self.pending.clear();
+		}
+
+		self.rules = rules;
+	}
+
+	/// Renames count as arrivals too, it's how browsers finish a download
+	pub(super) fn push(&mut self, event: &Event) {
+		if self.rules.is_empty() {
+			return;
+		}
+
+		for path in &event.paths {
+			if matches!(event.kind, EventKind::Remove(_)) {
+				self.pending.remove(path);
+			} else if path.parent() == Some(&self.location_path) && !is_partial_download(path) {
+				let attempts = self.pending.get(path).map_or(0, |(_, attempts)| *attempts);
+				self.pending
+					.insert(path.clone(), (Instant::now(), attempts));
+			}
+		}
+	}
+
+	/// Organizes the downloads which settled
+	pub(super) async fn organize_settled(
+		&mut self,
+		location_id: location::id::Type,
+		library: &Library,
+	) {
+		let settled = self
+			.pending
+			.iter()
+			.filter(|(_, (updated_at, _))| updated_at.elapsed() >= SETTLE_TIME)
+			.map(|(path, (_, attempts))| (path.clone(), *attempts))
+			.collect::<Vec<_>>();
+
+		for (path, attempts) in settled {
+			self.pending.remove(&path);
+
+			match organize_download(
+				library,
+				(location_id, &self.location_path),
+				&self.rules,
+				&path,
+			)
+			.await
+			{
+				Ok(DownloadOutcome::NotIndexed) if attempts < MAX_ATTEMPTS => {
+					self.pending.insert(path, (Instant::now(), attempts + 1));
+				}
+				Ok(DownloadOutcome::NotIndexed) => warn!(
+					"Gave up on download '{}', it wasn't added to the library",
+					path.display()
+				),
+				Ok(_) => {}
+				Err(e) => error!(
+					"Failed to organize download: <path='{}', error='{e:#?}'>",
+					path.display()
+				),
+			}
+		}
+	}
+}
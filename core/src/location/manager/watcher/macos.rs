@@ -221,7 +221,8 @@ impl MacOsEventHandler<'_> {
 						&location_path,
 						&path,
 						meta.is_dir(),
-					)?,
+					)?
+					.normalized(self.library.config.file_name_normalization),
 					&self.library.db,
 				)
 				.await?
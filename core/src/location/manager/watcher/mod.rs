@@ -1,4 +1,14 @@
-use crate::{library::Library, prisma::location, util::db::maybe_missing};
+use crate::{
+	library::Library,
+	location::{
+		actions::new_file_actions,
+		downloads::active_download_rules,
+		nested::{is_in_nested_location, nested_location_paths},
+		pinned::pinned_directory_paths,
+	},
+	prisma::location,
+	util::db::maybe_missing,
+};
 
 use std::{
 	collections::HashSet,
@@ -7,7 +17,7 @@ use std::{
 };
 
 use async_trait::async_trait;
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use tokio::{
 	runtime::Handle,
 	select,
@@ -15,7 +25,7 @@ use tokio::{
 	task::{block_in_place, JoinHandle},
 	time::{interval_at, Instant, MissedTickBehavior},
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info_span, warn, Instrument};
 use uuid::Uuid;
 
 use super::LocationManagerError;
@@ -24,9 +34,15 @@ mod linux;
 mod macos;
 mod windows;
 
+mod debounce;
+mod downloads;
+mod new_files;
 mod utils;
 
-use utils::check_event;
+use debounce::UpdatesDebouncer;
+use downloads::DownloadsTracker;
+use new_files::NewFilesTracker;
+use utils::{check_event, settle_pending};
 
 #[cfg(target_os = "linux")]
 type Handler<'lib> = linux::LinuxEventHandler<'lib>;
@@ -44,6 +60,10 @@ type InstantAndPath = (Instant, PathBuf);
 
 const ONE_SECOND: Duration = Duration::from_secs(1);
 const HUNDRED_MILLIS: Duration = Duration::from_millis(100);
+/// How often the locations nested in the watched one, whose events are theirs, its pinned
+/// directories, its downloads automation rules and whether it has new file actions are fetched
+/// again
+const RELATED_PATHS_REFRESH: Duration = Duration::from_secs(10);
 
 #[async_trait]
 trait EventHandler<'lib> {
@@ -78,6 +98,8 @@ impl LocationWatcher {
 		let (ignore_path_tx, ignore_path_rx) = mpsc::unbounded_channel();
 		let (stop_tx, stop_rx) = oneshot::channel();
 
+		let path = maybe_missing(location.path, "location.path")?;
+
 		let watcher = RecommendedWatcher::new(
 			move |result| {
 				if !events_tx.is_closed() {
@@ -97,18 +119,28 @@ impl LocationWatcher {
 			Config::default(),
 		)?;
 
-		let handle = tokio::spawn(Self::handle_watch_events(
-			location.id,
-			Uuid::from_slice(&location.pub_id)?,
-			library,
-			events_rx,
-			ignore_path_rx,
-			stop_rx,
-		));
+		let span = info_span!(
+			"location_watcher",
+			library_id = %library.id,
+			location_id = location.id,
+		);
+
+		let handle = tokio::spawn(
+			Self::handle_watch_events(
+				location.id,
+				Uuid::from_slice(&location.pub_id)?,
+				PathBuf::from(&path),
+				library,
+				events_rx,
+				ignore_path_rx,
+				stop_rx,
+			)
+			.instrument(span),
+		);
 
 		Ok(Self {
 			id: location.id,
-			path: maybe_missing(location.path, "location.path")?,
+			path,
 			watcher,
 			ignore_path_tx,
 			handle: Some(handle),
@@ -119,6 +151,7 @@ impl LocationWatcher {
 	async fn handle_watch_events(
 		location_id: location::id::Type,
 		location_pub_id: Uuid,
+		location_path: PathBuf,
 		library: Library,
 		mut events_rx: mpsc::UnboundedReceiver<notify::Result<Event>>,
 		mut ignore_path_rx: mpsc::UnboundedReceiver<IgnorePath>,
@@ -127,6 +160,14 @@ impl LocationWatcher {
 		let mut event_handler = Handler::new(location_id, &library);
 
 		let mut paths_to_ignore = HashSet::new();
+		let mut nested_paths = vec![];
+		let mut pinned_paths = vec![];
+		let mut debouncer = UpdatesDebouncer::default();
+		let mut downloads = DownloadsTracker::new(location_path.clone());
+		let mut new_files = NewFilesTracker::default();
+
+		let mut related_paths_interval = interval_at(Instant::now(), RELATED_PATHS_REFRESH);
+		related_paths_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
 		let mut handler_interval = interval_at(Instant::now() + HUNDRED_MILLIS, HUNDRED_MILLIS);
 		// In case of doubt check: https://docs.rs/tokio/latest/tokio/time/enum.MissedTickBehavior.html
@@ -136,20 +177,29 @@ impl LocationWatcher {
 			select! {
 				Some(event) = events_rx.recv() => {
 					match event {
-						Ok(event) => {
-							if let Err(e) = Self::handle_single_event(
-								location_id,
-								location_pub_id,
-								event,
-								&mut event_handler,
-								&library,
-								&paths_to_ignore,
-							).await {
-								error!("Failed to handle location file system event: \
-									<id='{location_id}', error='{e:#?}'>",
-								);
+						// Ignored paths are checked before holding an update too, as they may not be
+						// ignored anymore once it's handled
+						Ok(event) if check_event(&event, &paths_to_ignore) => {
+							downloads.push(&event);
+							new_files.push(&event, &nested_paths);
+
+							for event in debouncer.push(event) {
+								if let Err(e) = Self::handle_single_event(
+									location_id,
+									location_pub_id,
+									event,
+									&mut event_handler,
+									&library,
+									&paths_to_ignore,
+									&nested_paths,
+								).await {
+									error!("Failed to handle location file system event: \
+										<id='{location_id}', error='{e:#?}'>",
+									);
+								}
 							}
 						}
+						Ok(_) => {}
 						Err(e) => {
 							error!("watch error: {:#?}", e);
 						}
@@ -165,7 +215,52 @@ impl LocationWatcher {
 				}
 
 				_ = handler_interval.tick() => {
+					for event in debouncer.take_ready(&pinned_paths) {
+						if let Err(e) = Self::handle_single_event(
+							location_id,
+							location_pub_id,
+							event,
+							&mut event_handler,
+							&library,
+							&paths_to_ignore,
+							&nested_paths,
+						).await {
+							error!("Failed to handle location file system event: \
+								<id='{location_id}', error='{e:#?}'>",
+							);
+						}
+					}
+
 					event_handler.tick().await;
+
+					downloads.organize_settled(location_id, &library).await;
+					new_files.run_settled(location_id, &library).await;
+				}
+
+				_ = related_paths_interval.tick() => {
+					match nested_location_paths(&library.db, &location_path).await {
+						Ok(paths) => nested_paths = paths,
+						Err(e) => error!("Failed to fetch nested locations: <id='{location_id}', error='{e:#?}'>"),
+					}
+
+					match pinned_directory_paths(&library.db, location_id, &location_path).await {
+						Ok(paths) => pinned_paths = paths,
+						Err(e) => error!("Failed to fetch pinned directories: <id='{location_id}', error='{e:#?}'>"),
+					}
+
+					match active_download_rules(&library.db, location_id).await {
+						Ok(rules) => downloads.set_rules(rules),
+						Err(e) => error!("Failed to fetch download rules: <id='{location_id}', error='{e:#?}'>"),
+					}
+
+					match new_file_actions(&library.db, location_id).await {
+						Ok(actions) => new_files.set_enabled(!actions.is_empty()),
+						Err(e) => error!("Failed to fetch new file actions: <id='{location_id}', error='{e:#?}'>"),
+					}
+
+					if let Err(e) = settle_pending(location_id, &location_path, &library).await {
+						error!("Failed to identify settled downloads: <id='{location_id}', error='{e:#?}'>");
+					}
 				}
 
 				_ = &mut stop_rx => {
@@ -183,11 +278,27 @@ impl LocationWatcher {
 		event_handler: &mut impl EventHandler<'lib>,
 		library: &'lib Library,
 		ignore_paths: &HashSet<PathBuf>,
+		nested_paths: &[PathBuf],
 	) -> Result<(), LocationManagerError> {
 		if !check_event(&event, ignore_paths) {
 			return Ok(());
 		}
 
+		// The watchers of the nested locations take care of them
+		if !nested_paths.is_empty()
+			&& event
+				.paths
+				.iter()
+				.all(|path| is_in_nested_location(nested_paths, path))
+		{
+			return Ok(());
+		}
+
+		library
+			.metrics()
+			.watcher_events
+			.inc(event_kind_label(&event.kind));
+
 		// let Some(location) = find_location(library, location_id)
 		// 	.include(location_with_indexer_rules::include())
 		// 	.exec()
@@ -265,6 +376,17 @@ impl Drop for LocationWatcher {
 	}
 }
 
+fn event_kind_label(kind: &EventKind) -> &'static str {
+	match kind {
+		EventKind::Any => "any",
+		EventKind::Access(_) => "access",
+		EventKind::Create(_) => "create",
+		EventKind::Modify(_) => "modify",
+		EventKind::Remove(_) => "remove",
+		EventKind::Other => "other",
+	}
+}
+
 /***************************************************************************************************
 * Some tests to validate our assumptions of events through different file systems				   *
 ****************************************************************************************************
@@ -0,0 +1,106 @@
+//! Gathers the files created in a location with new file actions, see `location::actions`. They're
+//! handed over in batches once nothing happened to them for a few seconds, so a photo still being
+//! copied isn't converted halfway through.
+
+use crate::{
+	library::Library,
+	location::{
+		actions::NewFileActionsJobInit, downloads::is_partial_download,
+		nested::is_in_nested_location,
+	},
+	prisma::location,
+};
+
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use notify::{
+	event::{ModifyKind, RenameMode},
+	Event, EventKind,
+};
+use tokio::time::Instant;
+use tracing::error;
+
+/// How long a new file must go untouched for its actions to run
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Default)]
+pub(super) struct NewFilesTracker {
+	/// Off while the location has no actions
+	enabled: bool,
+	pending: HashMap<PathBuf, Instant>,
+}
+
+impl NewFilesTracker {
+	pub(super) fn set_enabled(&mut self, enabled: bool) {
+		if !enabled {
+			self.pending.clear();
+		}
+
+		self.enabled = enabled;
+	}
+
+	/// Files moved into the location are new to it too, while writes only delay the ones already
+	/// gathered
+	pub(super) fn push(&mut self, event: &Event, nested_paths: &[PathBuf]) {
+		if !self.enabled {
+			return;
+		}
+
+		let (gone, arrived): (&[PathBuf], &[PathBuf]) = match event.kind {
+			EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+				(&event.paths, &[])
+			}
+			EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+				(&[], &event.paths)
+			}
+			// The first path of a rename is where the file was
+			EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+				event.paths.split_at(1)
+			}
+			_ => (&[], &[]),
+		};
+
+		for path in gone {
+			self.pending.remove(path);
+		}
+
+		for path in &event.paths {
+			if let Some(updated_at) = self.pending.get_mut(path) {
+				*updated_at = Instant::now();
+			} else if arrived.contains(path)
+				&& !is_partial_download(path)
+				&& !is_in_nested_location(nested_paths, path)
+			{
+				self.pending.insert(path.clone(), Instant::now());
+			}
+		}
+	}
+
+	/// Runs the actions of the location on the files which settled
+	pub(super) async fn run_settled(&mut self, location_id: location::id::Type, library: &Library) {
+		let settled = self
+			.pending
+			.iter()
+			.filter(|(_, updated_at)| updated_at.elapsed() >= SETTLE_TIME)
+			.map(|(path, _)| path.clone())
+			.collect::<Vec<_>>();
+
+		if settled.is_empty() {
+			return;
+		}
+
+		for path in &settled {
+			self.pending.remove(path);
+		}
+
+		if let Err(e) = library
+			.spawn_job(NewFileActionsJobInit {
+				location_id,
+				paths: settled,
+			})
+			.await
+		{
+			error!("Failed to run new file actions: <id='{location_id}', error='{e:#?}'>");
+		}
+	}
+}
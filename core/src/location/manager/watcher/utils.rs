@@ -5,7 +5,7 @@ use crate::{
 		delete_directory,
 		file_path_helper::{
 			check_existing_file_path, create_file_path, file_path_with_object,
-			filter_existing_file_path_params,
+			filter_existing_file_path_params, get_allocated_size,
 			isolated_file_path_data::extract_normalized_materialized_path_str,
 			loose_find_existing_file_path_params, FilePathError, FilePathMetadata,
 			IsolatedFilePathData, MetadataExt,
@@ -15,13 +15,23 @@ use crate::{
 		scan_location_sub_path,
 	},
 	object::{
-		file_identifier::FileMetadata,
-		preview::{can_generate_thumbnail_for_image, generate_image_thumbnail, get_thumbnail_path},
+		custom_kind::resolve_kind,
+		file_identifier::{
+			pending::{is_incomplete, pending_kind, pending_object_params, settle_pending_files},
+			FileMetadata,
+		},
+		os_metadata::apply_os_metadata,
+		preview::{
+			book::{extract_book_data_or_log, generate_book_thumbnail, is_book_extension},
+			can_generate_thumbnail_for_image,
+			font::{extract_font_data_or_log, generate_font_thumbnail, is_font_extension},
+			generate_image_thumbnail, get_thumbnail_path,
+		},
 		validation::hash::file_checksum,
 	},
-	prisma::{file_path, location, object},
+	prisma::{book_data, file_path, font_data, location, object},
 	sync,
-	util::{db::maybe_missing, error::FileIOError},
+	util::{db::maybe_missing, error::FileIOError, long_path::to_extended_length},
 };
 
 #[cfg(target_family = "unix")]
@@ -82,7 +92,8 @@ pub(super) async fn create_dir(
 		path.display()
 	);
 
-	let iso_file_path = IsolatedFilePathData::new(location.id, location_path, path, true)?;
+	let iso_file_path = IsolatedFilePathData::new(location.id, location_path, path, true)?
+		.normalized(library.config.file_name_normalization);
 
 	let (inode, device) = {
 		#[cfg(target_family = "unix")]
@@ -116,6 +127,7 @@ pub(super) async fn create_dir(
 			inode,
 			device,
 			size_in_bytes: metadata.len(),
+			allocated_size_in_bytes: get_allocated_size(path, metadata),
 			created_at: metadata.created_or_now().into(),
 			modified_at: metadata.modified_or_now().into(),
 		},
@@ -149,7 +161,8 @@ pub(super) async fn create_file(
 
 	let db = &library.db;
 
-	let iso_file_path = IsolatedFilePathData::new(location_id, &location_path, path, false)?;
+	let iso_file_path = IsolatedFilePathData::new(location_id, &location_path, path, false)?
+		.normalized(library.config.file_name_normalization);
 	let materialized_path = iso_file_path.materialized_path().to_string();
 	let extension = iso_file_path.extension().to_string();
 
@@ -175,31 +188,67 @@ pub(super) async fn create_file(
 		return Ok(());
 	};
 
+	let file_path_metadata = FilePathMetadata {
+		inode,
+		device,
+		size_in_bytes: metadata.len(),
+		allocated_size_in_bytes: get_allocated_size(path, metadata),
+		created_at: metadata.created_or_now().into(),
+		modified_at: metadata.modified_or_now().into(),
+	};
+
+	object::select!(object_just_id { id });
+
+	// Downloads in progress aren't hashed, they're identified once they settle
+	if is_incomplete(path, metadata) {
+		let created_file =
+			create_file_path(library, iso_file_path, None, file_path_metadata).await?;
+
+		info!("Created pending path: {}", &materialized_path);
+
+		let (_, db_params) = pending_object_params(
+			resolve_kind(db, pending_kind(path), &extension).await?,
+			Some(DateTime::<Local>::from(metadata.created_or_now()).into()),
+		);
+
+		let object = db
+			.object()
+			.create(Uuid::new_v4().as_bytes().to_vec(), db_params)
+			.select(object_just_id::select())
+			.exec()
+			.await?;
+
+		db.file_path()
+			.update(
+				file_path::pub_id::equals(created_file.pub_id),
+				vec![file_path::object::connect(object::id::equals(object.id))],
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "search.paths");
+
+		return Ok(());
+	}
+
 	// generate provisional object
 	let FileMetadata {
 		cas_id,
 		kind,
 		fs_metadata,
-	} = FileMetadata::new(&location_path, &iso_file_path).await?;
+		os_metadata,
+	} = FileMetadata::new(&location_path, &iso_file_path, library.memory_budget()).await?;
 
 	let created_file = create_file_path(
 		library,
 		iso_file_path,
 		Some(cas_id.clone()),
-		FilePathMetadata {
-			inode,
-			device,
-			size_in_bytes: metadata.len(),
-			created_at: metadata.created_or_now().into(),
-			modified_at: metadata.modified_or_now().into(),
-		},
+		file_path_metadata,
 	)
 	.await?;
 
 	info!("Created path: {}", &materialized_path);
 
-	object::select!(object_just_id { id });
-
 	let existing_object = db
 		.object()
 		.find_first(vec![object::file_paths::some(vec![
@@ -220,7 +269,7 @@ pub(super) async fn create_file(
 					object::date_created::set(Some(
 						DateTime::<Local>::from(fs_metadata.created_or_now()).into(),
 					)),
-					object::kind::set(Some(kind as i32)),
+					object::kind::set(Some(resolve_kind(db, kind, &extension).await?)),
 				],
 			)
 			.select(object_just_id::select())
@@ -230,19 +279,35 @@ pub(super) async fn create_file(
 
 	db.file_path()
 		.update(
-			file_path::pub_id::equals(created_file.pub_id),
+			file_path::pub_id::equals(created_file.pub_id.clone()),
 			vec![file_path::object::connect(object::id::equals(object.id))],
 		)
 		.exec()
 		.await?;
 
+	apply_os_metadata(
+		library,
+		vec![(
+			Uuid::from_slice(&created_file.pub_id).expect("file_path.pub_id is invalid!"),
+			os_metadata,
+		)],
+	)
+	.await?;
+
 	if !extension.is_empty() {
 		// Running in a detached task as thumbnail generation can take a while and we don't want to block the watcher
 		let path = path.to_path_buf();
 		let library = library.clone();
+		let object_id = object.id;
 
 		tokio::spawn(async move {
-			generate_thumbnail(&extension, &cas_id, path, &library).await;
+			generate_thumbnail(&extension, &cas_id, &path, &library).await;
+
+			if is_font_extension(&extension) {
+				extract_font_data_or_log(&library, &path, object_id).await;
+			} else if is_book_extension(&extension) {
+				extract_book_data_or_log(&library, &path, object_id).await;
+			}
 		});
 	}
 
@@ -251,13 +316,45 @@ pub(super) async fn create_file(
 	Ok(())
 }
 
+/// Identifies the downloads of the location that completed, see `file_identifier::pending`
+pub(super) async fn settle_pending(
+	location_id: location::id::Type,
+	location_path: &Path,
+	library: &Library,
+) -> Result<(), LocationManagerError> {
+	let settled = settle_pending_files(library, location_id, location_path).await?;
+
+	if settled.is_empty() {
+		return Ok(());
+	}
+
+	let library = library.clone();
+	tokio::spawn(async move {
+		for file in settled {
+			generate_thumbnail(&file.extension, &file.cas_id, &file.path, &library).await;
+
+			if is_font_extension(&file.extension) {
+				extract_font_data_or_log(&library, &file.path, file.object_id).await;
+			} else if is_book_extension(&file.extension) {
+				extract_book_data_or_log(&library, &file.path, file.object_id).await;
+			}
+		}
+
+		invalidate_query!(library, "search.paths");
+	});
+
+	invalidate_query!(library, "search.paths");
+
+	Ok(())
+}
+
 pub(super) async fn create_dir_or_file(
 	location_id: location::id::Type,
 	path: impl AsRef<Path>,
 	library: &Library,
 ) -> Result<Metadata, LocationManagerError> {
 	let path = path.as_ref();
-	let metadata = fs::metadata(path)
+	let metadata = fs::metadata(to_extended_length(path))
 		.await
 		.map_err(|e| FileIOError::from((path, e)))?;
 
@@ -281,7 +378,8 @@ pub(super) async fn file_creation_or_update(
 		.db
 		.file_path()
 		.find_first(filter_existing_file_path_params(
-			&IsolatedFilePathData::new(location_id, &location_path, full_path, false)?,
+			&IsolatedFilePathData::new(location_id, &location_path, full_path, false)?
+				.normalized(library.config.file_name_normalization),
 		))
 		// include object for orphan check
 		.include(file_path_with_object::include())
@@ -293,7 +391,7 @@ pub(super) async fn file_creation_or_update(
 		create_file(
 			location_id,
 			full_path,
-			&fs::metadata(full_path)
+			&fs::metadata(to_extended_length(full_path))
 				.await
 				.map_err(|e| FileIOError::from((full_path, e)))?,
 			library,
@@ -314,7 +412,8 @@ pub(super) async fn update_file(
 		.db
 		.file_path()
 		.find_first(filter_existing_file_path_params(
-			&IsolatedFilePathData::new(location_id, &location_path, full_path, false)?,
+			&IsolatedFilePathData::new(location_id, &location_path, full_path, false)?
+				.normalized(library.config.file_name_normalization),
 		))
 		// include object for orphan check
 		.include(file_path_with_object::include())
@@ -356,11 +455,27 @@ async fn inner_update_file(
 
 	let iso_file_path = IsolatedFilePathData::try_from(file_path)?;
 
+	// Hashing a file still being downloaded is wasted work, the periodic check for pending files
+	// identifies it once it's complete. A complete file being downloaded over keeps its old cas_id
+	// until a later write finds it complete.
+	let is_pending = file_path
+		.object
+		.as_ref()
+		.map_or(false, |object| object.pending == Some(true));
+	if is_pending
+		|| fs::metadata(full_path)
+			.await
+			.map_or(false, |metadata| is_incomplete(full_path, &metadata))
+	{
+		return Ok(());
+	}
+
 	let FileMetadata {
 		cas_id,
 		fs_metadata,
 		kind,
-	} = FileMetadata::new(&location_path, &iso_file_path).await?;
+		os_metadata,
+	} = FileMetadata::new(&location_path, &iso_file_path, library.memory_budget()).await?;
 
 	if let Some(old_cas_id) = &file_path.cas_id {
 		if old_cas_id != &cas_id {
@@ -376,6 +491,15 @@ async fn inner_update_file(
 						(size_in_bytes::NAME, json!(fs_metadata.len().to_string())),
 						size_in_bytes::set(Some(fs_metadata.len().to_string())),
 					),
+					{
+						let allocated_size =
+							get_allocated_size(full_path, &fs_metadata).to_string();
+
+						(
+							(allocated_size_in_bytes::NAME, json!(allocated_size)),
+							allocated_size_in_bytes::set(Some(allocated_size)),
+						)
+					},
 					{
 						let date = DateTime::<Local>::from(fs_metadata.modified_or_now()).into();
 
@@ -445,7 +569,27 @@ async fn inner_update_file(
 					}
 				}
 
-				let int_kind = kind as i32;
+				// the names and style of a font, or the title of a book, go with its content
+				let extension = file_path.extension.as_deref().unwrap_or_default();
+				if is_font_extension(extension) {
+					db.font_data()
+						.delete_many(vec![font_data::id::equals(object.id)])
+						.exec()
+						.await?;
+
+					extract_font_data_or_log(library, full_path, object.id).await;
+				} else if is_book_extension(extension) {
+					db.book_data()
+						.delete_many(vec![book_data::id::equals(object.id)])
+						.exec()
+						.await?;
+
+					extract_book_data_or_log(library, full_path, object.id).await;
+				}
+
+				let int_kind =
+					resolve_kind(db, kind, file_path.extension.as_deref().unwrap_or_default())
+						.await?;
 
 				if object.kind.map(|k| k != int_kind).unwrap_or_default() {
 					sync.write_op(
@@ -470,6 +614,16 @@ async fn inner_update_file(
 		}
 	}
 
+	// Attributes and Finder tags can change without touching the content
+	apply_os_metadata(
+		library,
+		vec![(
+			Uuid::from_slice(&file_path.pub_id).expect("file_path.pub_id is invalid!"),
+			os_metadata,
+		)],
+	)
+	.await?;
+
 	Ok(())
 }
 
@@ -493,7 +647,9 @@ pub(super) async fn rename(
 	// Renaming a file could potentially be a move to another directory, so we check if our parent changed
 	if old_path_materialized_str != new_path_materialized_str
 		&& !check_existing_file_path(
-			&IsolatedFilePathData::new(location_id, &location_path, new_path, true)?.parent(),
+			&IsolatedFilePathData::new(location_id, &location_path, new_path, true)?
+				.normalized(library.config.file_name_normalization)
+				.parent(),
 			db,
 		)
 		.await?
@@ -507,18 +663,21 @@ pub(super) async fn rename(
 	if let Some(file_path) = db
 		.file_path()
 		.find_first(loose_find_existing_file_path_params(
-			&IsolatedFilePathData::new(location_id, &location_path, old_path, false)?,
+			&IsolatedFilePathData::new(location_id, &location_path, old_path, false)?
+				.normalized(library.config.file_name_normalization),
 		))
 		.exec()
 		.await?
 	{
 		let is_dir = maybe_missing(file_path.is_dir, "file_path.is_dir")?;
 
-		let new = IsolatedFilePathData::new(location_id, &location_path, new_path, is_dir)?;
+		let new = IsolatedFilePathData::new(location_id, &location_path, new_path, is_dir)?
+			.normalized(library.config.file_name_normalization);
 
 		// If the renamed path is a directory, we have to update every successor
 		if is_dir {
-			let old = IsolatedFilePathData::new(location_id, &location_path, old_path, is_dir)?;
+			let old = IsolatedFilePathData::new(location_id, &location_path, old_path, is_dir)?
+				.normalized(library.config.file_name_normalization);
 			// TODO: Fetch all file_paths that will be updated and dispatch sync events
 
 			let updated = library
@@ -568,7 +727,8 @@ pub(super) async fn remove(
 	let Some(file_path) = library.db
 		.file_path()
 		.find_first(loose_find_existing_file_path_params(
-			&IsolatedFilePathData::new(location_id, &location_path, full_path, false)?,
+			&IsolatedFilePathData::new(location_id, &location_path, full_path, false)?
+				.normalized(library.config.file_name_normalization),
 		))
 		.exec()
 		.await? else {
@@ -584,8 +744,13 @@ pub(super) async fn remove_by_file_path(
 	file_path: &file_path::Data,
 	library: &Library,
 ) -> Result<(), LocationManagerError> {
+	// the content of compressed or tiered files lives somewhere else
+	if file_path.compressed_at.is_some() || file_path.tiered_at.is_some() {
+		return Ok(());
+	}
+
 	// check file still exists on disk
-	match fs::metadata(path.as_ref()).await {
+	match fs::metadata(to_extended_length(path.as_ref())).await {
 		Ok(_) => {
 			todo!("file has changed in some way, re-identify it")
 		}
@@ -656,12 +821,26 @@ async fn generate_thumbnail(
 
 	if let Ok(extension) = ImageExtension::from_str(extension) {
 		if can_generate_thumbnail_for_image(&extension) {
-			if let Err(e) = generate_image_thumbnail(path, &output_path).await {
+			if let Err(e) =
+				generate_image_thumbnail(path, &output_path, library.memory_budget()).await
+			{
 				error!("Failed to image thumbnail on location manager: {e:#?}");
 			}
 		}
 	}
 
+	if is_font_extension(extension) {
+		if let Err(e) = generate_font_thumbnail(path, &output_path, library.memory_budget()).await {
+			error!("Failed to font thumbnail on location manager: {e:#?}");
+		}
+	}
+
+	if is_book_extension(extension) {
+		if let Err(e) = generate_book_thumbnail(path, &output_path, library.memory_budget()).await {
+			error!("Failed to book thumbnail on location manager: {e:#?}");
+		}
+	}
+
 	#[cfg(feature = "ffmpeg")]
 	{
 		use crate::object::preview::{can_generate_thumbnail_for_video, generate_video_thumbnail};
@@ -669,7 +848,9 @@ async fn generate_thumbnail(
 
 		if let Ok(extension) = VideoExtension::from_str(extension) {
 			if can_generate_thumbnail_for_video(&extension) {
-				if let Err(e) = generate_video_thumbnail(path, &output_path).await {
+				if let Err(e) =
+					generate_video_thumbnail(path, &output_path, library.memory_budget()).await
+				{
 					error!("Failed to video thumbnail on location manager: {e:#?}");
 				}
 			}
@@ -695,7 +876,8 @@ pub(super) async fn extract_inode_and_device_from_path(
 		.db
 		.file_path()
 		.find_first(loose_find_existing_file_path_params(
-			&IsolatedFilePathData::new(location_id, location_path, path, false)?,
+			&IsolatedFilePathData::new(location_id, location_path, path, false)?
+				.normalized(library.config.file_name_normalization),
 		))
 		.select(file_path::select!({ inode device }))
 		.exec()
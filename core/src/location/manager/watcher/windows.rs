@@ -12,7 +12,7 @@ use crate::{
 	library::Library,
 	location::{file_path_helper::get_inode_and_device_from_path, manager::LocationManagerError},
 	prisma::location,
-	util::error::FileIOError,
+	util::{error::FileIOError, long_path::to_extended_length},
 };
 
 use std::{
@@ -103,7 +103,7 @@ impl<'lib> EventHandler<'lib> for WindowsEventHandler<'lib> {
 				let path = &paths[0];
 				// Windows emite events of update right after create events
 				if !self.recently_created_files.contains_key(path) {
-					let metadata = fs::metadata(path)
+					let metadata = fs::metadata(to_extended_length(path))
 						.await
 						.map_err(|e| FileIOError::from((path, e)))?;
 					if metadata.is_file() {
@@ -3,10 +3,16 @@ use crate::{
 	job::{Job, JobError, JobManagerError},
 	library::Library,
 	object::{
+		cas::generate_cas_id,
 		file_identifier::{self, file_identifier_job::FileIdentifierJobInit},
+		groups::FileGrouperJobInit,
+		mail::MailIndexerJobInit,
 		preview::{shallow_thumbnailer, thumbnailer_job::ThumbnailerJobInit},
+		projects::ProjectDetectorJobInit,
+		stacks::PhotoStackerJobInit,
+		xmp::XmpConflictStrategy,
 	},
-	prisma::{file_path, indexer_rules_in_location, location, node, object, PrismaClient},
+	prisma::{file_path, indexer_rules_in_location, location, node, object, SortOrder},
 	sync,
 	util::{
 		db::{chain_optional_iter, uuid_to_bytes},
@@ -29,16 +35,26 @@ use tokio::{fs, io};
 use tracing::{debug, info};
 use uuid::Uuid;
 
+pub mod actions;
+pub mod cleanup;
+pub mod downloads;
 mod error;
 pub mod file_path_helper;
 pub mod indexer;
 mod manager;
 mod metadata;
+pub mod nested;
+pub mod pinned;
+pub mod privacy;
+pub mod redaction;
+pub mod treemap;
 
 pub use error::LocationError;
+use file_path_helper::{file_path_for_thumbnailer, IsolatedFilePathData};
 use indexer::IndexerJobInit;
-pub use manager::{LocationManager, LocationManagerError};
-use metadata::SpacedriveLocationMetadataFile;
+pub use manager::{IgnoreEventsForPathGuard, LocationManager, LocationManagerError};
+use metadata::{LocationMetadataError, SpacedriveLocationMetadataFile};
+use nested::{find_overlapping_locations, take_over_from_parents, NestedLocationPolicy};
 
 // Location includes!
 location::include!(location_with_indexer_rules {
@@ -53,6 +69,14 @@ pub struct LocationCreateArgs {
 	pub path: PathBuf,
 	pub dry_run: bool,
 	pub indexer_rules_ids: Vec<i32>,
+	/// What to do if the location is inside another one, or around one
+	#[serde(default)]
+	#[specta(optional)]
+	pub nested_policy: NestedLocationPolicy,
+	/// How many levels of directories to index at once, the deeper ones are indexed as they're browsed
+	#[serde(default)]
+	#[specta(optional)]
+	pub index_depth: Option<i32>,
 }
 
 impl LocationCreateArgs {
@@ -108,6 +132,8 @@ impl LocationCreateArgs {
 			uuid,
 			&self.path,
 			&self.indexer_rules_ids,
+			self.nested_policy,
+			self.index_depth,
 			self.dry_run,
 		)
 		.await?;
@@ -133,6 +159,10 @@ impl LocationCreateArgs {
 				Err(err)?;
 			}
 
+			if self.nested_policy == NestedLocationPolicy::ExcludeFromParent {
+				take_over_from_parents(library, location.data.id).await?;
+			}
+
 			info!("Created location: {:?}", &location.data);
 
 			Ok(Some(location.data))
@@ -178,6 +208,8 @@ impl LocationCreateArgs {
 			uuid,
 			&self.path,
 			&self.indexer_rules_ids,
+			self.nested_policy,
+			self.index_depth,
 			self.dry_run,
 		)
 		.await?;
@@ -192,6 +224,10 @@ impl LocationCreateArgs {
 				.add(location.data.id, library.clone())
 				.await?;
 
+			if self.nested_policy == NestedLocationPolicy::ExcludeFromParent {
+				take_over_from_parents(library, location.data.id).await?;
+			}
+
 			info!(
 				"Added library (library_id = {}) to location: {:?}",
 				library.id, &location.data
@@ -217,6 +253,19 @@ pub struct LocationUpdateArgs {
 	pub generate_preview_media: Option<bool>,
 	pub sync_preview_media: Option<bool>,
 	pub hidden: Option<bool>,
+	pub compress_at_rest: Option<bool>,
+	pub compress_after_days: Option<i32>,
+	pub tier_to_location_id: Option<location::id::Type>,
+	pub tier_after_days: Option<i32>,
+	pub tier_min_size_in_mb: Option<i32>,
+	pub xmp_sidecars: Option<bool>,
+	pub xmp_conflict_strategy: Option<XmpConflictStrategy>,
+	pub sync_finder_tags: Option<bool>,
+	pub is_sensitive: Option<bool>,
+	/// Zero lifts the limit
+	pub index_depth: Option<i32>,
+	pub downloads_automation: Option<bool>,
+	pub bundles_as_files: Option<bool>,
 	pub indexer_rules_ids: Vec<i32>,
 }
 
@@ -224,6 +273,10 @@ impl LocationUpdateArgs {
 	pub async fn update(self, library: &Library) -> Result<(), LocationError> {
 		let Library { sync, db, .. } = &library;
 
+		if self.tier_to_location_id == Some(self.id) {
+			return Err(LocationError::TieringToItself(self.id));
+		}
+
 		let location = find_location(library, self.id)
 			.include(location_with_indexer_rules::include())
 			.exec()
@@ -258,6 +311,79 @@ impl LocationUpdateArgs {
 					location::hidden::set(Some(v)),
 				)
 			}),
+			self.compress_at_rest.map(|v| {
+				(
+					(location::compress_at_rest::NAME, json!(v)),
+					location::compress_at_rest::set(Some(v)),
+				)
+			}),
+			self.compress_after_days.map(|v| {
+				(
+					(location::compress_after_days::NAME, json!(v)),
+					location::compress_after_days::set(Some(v)),
+				)
+			}),
+			self.tier_to_location_id.map(|v| {
+				(
+					(location::tier_to_location_id::NAME, json!(v)),
+					location::tier_to_location_id::set(Some(v)),
+				)
+			}),
+			self.tier_after_days.map(|v| {
+				(
+					(location::tier_after_days::NAME, json!(v)),
+					location::tier_after_days::set(Some(v)),
+				)
+			}),
+			self.tier_min_size_in_mb.map(|v| {
+				(
+					(location::tier_min_size_in_mb::NAME, json!(v)),
+					location::tier_min_size_in_mb::set(Some(v)),
+				)
+			}),
+			self.xmp_sidecars.map(|v| {
+				(
+					(location::xmp_sidecars::NAME, json!(v)),
+					location::xmp_sidecars::set(Some(v)),
+				)
+			}),
+			self.xmp_conflict_strategy.map(|v| {
+				(
+					(location::xmp_conflict_strategy::NAME, json!(v as i32)),
+					location::xmp_conflict_strategy::set(Some(v as i32)),
+				)
+			}),
+			self.sync_finder_tags.map(|v| {
+				(
+					(location::sync_finder_tags::NAME, json!(v)),
+					location::sync_finder_tags::set(Some(v)),
+				)
+			}),
+			self.is_sensitive.map(|v| {
+				(
+					(location::is_sensitive::NAME, json!(v)),
+					location::is_sensitive::set(Some(v)),
+				)
+			}),
+			self.index_depth.map(|v| {
+				let v = (v > 0).then_some(v);
+				(
+					(location::index_depth::NAME, json!(v)),
+					location::index_depth::set(v),
+				)
+			}),
+			self.downloads_automation.map(|v| {
+				(
+					(location::downloads_automation::NAME, json!(v)),
+					location::downloads_automation::set(Some(v)),
+				)
+			}),
+			self.bundles_as_files.map(|v| {
+				(
+					(location::bundles_as_files::NAME, json!(v)),
+					location::bundles_as_files::set(Some(v)),
+				)
+			}),
 		]
 		.into_iter()
 		.flatten()
@@ -286,13 +412,11 @@ impl LocationUpdateArgs {
 			.await?;
 
 			if location.node_id == Some(library.node_local_id) {
-				if let Some(path) = &location.path {
+				if let (Some(path), Some(name)) = (&location.path, self.name) {
 					if let Some(mut metadata) =
 						SpacedriveLocationMetadataFile::try_load(path).await?
 					{
-						metadata
-							.update(library.id, self.name.expect("TODO"))
-							.await?;
+						metadata.update(library.id, name).await?;
 					}
 				}
 			}
@@ -370,35 +494,66 @@ async fn link_location_and_indexer_rules(
 pub async fn scan_location(
 	library: &Library,
 	location: location_with_indexer_rules::Data,
+) -> Result<(), JobManagerError> {
+	spawn_location_scan(library, location, false).await
+}
+
+/// Scans a whole location again when the user asks for it, which is held back by the schedule
+/// of the background jobs of the library like the other heavy jobs
+pub async fn full_rescan_location(
+	library: &Library,
+	location: location_with_indexer_rules::Data,
+) -> Result<(), JobManagerError> {
+	spawn_location_scan(library, location, true).await
+}
+
+async fn spawn_location_scan(
+	library: &Library,
+	location: location_with_indexer_rules::Data,
+	background: bool,
 ) -> Result<(), JobManagerError> {
 	if location.node_id != Some(library.node_local_id) {
 		return Ok(());
 	}
 
+	// Queued ahead of the rest of the location, which can take hours to crawl
+	pinned::scan_pinned_directories(library, &location).await?;
+
+	let location_id = location.id;
 	let location_base_data = location::Data::from(&location);
 
+	let job = Job::new_with_action(
+		IndexerJobInit {
+			location,
+			sub_path: None,
+		},
+		"scan_location",
+	);
+	let job = if background { job.as_background() } else { job };
+
 	library
 		.spawn_job(
-			Job::new_with_action(
-				IndexerJobInit {
-					location,
-					sub_path: None,
-				},
-				"scan_location",
-			)
-			.queue_next(FileIdentifierJobInit {
+			job.queue_next(FileIdentifierJobInit {
 				location: location_base_data.clone(),
 				sub_path: None,
 			})
 			.queue_next(ThumbnailerJobInit {
 				location: location_base_data,
 				sub_path: None,
-			}),
+			})
+			.queue_next(MailIndexerJobInit {
+				location_id,
+				sub_path: None,
+			})
+			.queue_next(PhotoStackerJobInit { location_id })
+			.queue_next(ProjectDetectorJobInit {
+				location_ids: vec![location_id],
+			})
+			.queue_next(FileGrouperJobInit { location_id }),
 		)
 		.await
 }
 
-#[cfg(feature = "location-watcher")]
 pub async fn scan_location_sub_path(
 	library: &Library,
 	location: location_with_indexer_rules::Data,
@@ -409,6 +564,7 @@ pub async fn scan_location_sub_path(
 		return Ok(());
 	}
 
+	let location_id = location.id;
 	let location_base_data = location::Data::from(&location);
 
 	library
@@ -426,6 +582,10 @@ pub async fn scan_location_sub_path(
 			})
 			.queue_next(ThumbnailerJobInit {
 				location: location_base_data,
+				sub_path: Some(sub_path.clone()),
+			})
+			.queue_next(MailIndexerJobInit {
+				location_id,
 				sub_path: Some(sub_path),
 			}),
 		)
@@ -445,13 +605,47 @@ pub async fn light_scan_location(
 
 	let location_base_data = location::Data::from(&location);
 
+	let deferred = is_deferred_dir(&library, &location, &sub_path).await?;
+
 	indexer::shallow(&location, &sub_path, &library).await?;
 	file_identifier::shallow(&location_base_data, &sub_path, &library).await?;
 	shallow_thumbnailer(&location_base_data, &sub_path, &library).await?;
 
+	// Browsed for the first time, so the rest of it is indexed now
+	if deferred {
+		match scan_location_sub_path(&library, location, &sub_path).await {
+			Ok(()) | Err(JobManagerError::AlreadyRunningJob { .. }) => {}
+			Err(e) => return Err(LocationError::from(e).into()),
+		}
+	}
+
 	Ok(())
 }
 
+async fn is_deferred_dir(
+	library: &Library,
+	location: &location_with_indexer_rules::Data,
+	sub_path: &Path,
+) -> Result<bool, LocationError> {
+	if location.index_depth.is_none() || sub_path == Path::new("") {
+		return Ok(false);
+	}
+
+	let location_path = location
+		.path
+		.as_ref()
+		.ok_or(LocationError::MissingPath(location.id))?;
+	let full_path =
+		file_path_helper::ensure_sub_path_is_in_location(location_path, sub_path).await?;
+
+	Ok(indexer::deferred::is_deferred(
+		&library.db,
+		&IsolatedFilePathData::new(location.id, location_path, &full_path, true)?
+			.normalized(library.config.file_name_normalization),
+	)
+	.await?)
+}
+
 pub async fn relink_location(
 	library: &Library,
 	location_path: impl AsRef<Path>,
@@ -490,6 +684,153 @@ pub async fn relink_location(
 	Ok(())
 }
 
+/// How many identified files are hashed in the new path of a location before relocating it
+const RELOCATION_SAMPLE_SIZE: i64 = 16;
+
+/// Points a location to the path its folder was moved or renamed to. Its file paths are relative
+/// to it so they stay as they are, but a sample of its identified files is hashed in the new path
+/// first, to make sure it's the same folder. Some of them may have changed since they were
+/// identified, so 3 out of 4 matching is enough.
+pub async fn relocate_location(
+	library: &Library,
+	location_id: location::id::Type,
+	new_path: impl AsRef<Path>,
+) -> Result<(), LocationError> {
+	let Library { db, sync, .. } = library;
+	let new_path = new_path.as_ref();
+
+	let location = find_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+
+	match fs::metadata(new_path).await {
+		Ok(metadata) if !metadata.is_dir() => {
+			return Err(LocationError::NotDirectory(new_path.to_path_buf()))
+		}
+		Ok(_) => {}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {
+			return Err(LocationError::PathNotFound(new_path.to_path_buf()))
+		}
+		Err(e) => {
+			return Err(LocationError::LocationPathFilesystemMetadataAccess(
+				FileIOError::from((new_path, e)),
+			))
+		}
+	}
+
+	let path = new_path.to_str().expect("Found non-UTF-8 path").to_string();
+
+	if location.path.as_ref() == Some(&path) {
+		return Ok(());
+	}
+
+	if db
+		.location()
+		.count(vec![location::path::equals(Some(path.clone()))])
+		.exec()
+		.await? > 0
+	{
+		return Err(LocationError::LocationAlreadyExists(new_path.to_path_buf()));
+	}
+
+	if !find_overlapping_locations(db, &path).await?.is_empty() {
+		return Err(LocationError::NestedLocation(new_path.to_path_buf()));
+	}
+
+	let identified_files = vec![
+		file_path::location_id::equals(Some(location_id)),
+		file_path::is_dir::equals(Some(false)),
+		file_path::cas_id::not(None),
+	];
+	let count = db
+		.file_path()
+		.count(identified_files.clone())
+		.exec()
+		.await?;
+	let sampled = count.min(RELOCATION_SAMPLE_SIZE);
+
+	let mut matched = 0;
+	for sample in 0..sampled {
+		// Spread over the whole location, not only its first files
+		let Some(file_path) = db
+			.file_path()
+			.find_many(identified_files.clone())
+			.order_by(file_path::id::order(SortOrder::Asc))
+			.skip(sample * count / sampled)
+			.take(1)
+			.select(file_path_for_thumbnailer::select())
+			.exec()
+			.await?
+			.pop()
+		else {
+			continue;
+		};
+
+		let cas_id = file_path.cas_id.clone();
+		let full_path = new_path.join(IsolatedFilePathData::try_from((location_id, file_path))?);
+
+		if let Ok(metadata) = fs::metadata(&full_path).await {
+			if let Ok(new_cas_id) = generate_cas_id(&full_path, metadata.len()).await {
+				if Some(new_cas_id) == cas_id {
+					matched += 1;
+				}
+			}
+		}
+	}
+
+	if matched * 4 < sampled * 3 {
+		return Err(LocationError::RelocationMismatch {
+			path: new_path.to_path_buf(),
+			matched,
+			sampled,
+		});
+	}
+
+	sync.write_op(
+		db,
+		sync.shared_update(
+			sync::location::SyncId {
+				pub_id: location.pub_id.clone(),
+			},
+			location::path::NAME,
+			json!(path),
+		),
+		db.location().update(
+			location::id::equals(location_id),
+			vec![location::path::set(Some(path))],
+		),
+	)
+	.await?;
+
+	// The metadata file moved along with the folder, but still has the old path
+	if let Some(mut metadata) = SpacedriveLocationMetadataFile::try_load(new_path).await? {
+		match metadata.relink(library.id, new_path).await {
+			Ok(()) | Err(LocationMetadataError::RelinkSamePath(_)) => {}
+			Err(e) => return Err(e.into()),
+		}
+	}
+
+	// So the watcher follows the new path
+	library
+		.location_manager()
+		.remove(location_id, library.clone())
+		.await?;
+	library
+		.location_manager()
+		.add(location_id, library.clone())
+		.await?;
+
+	info!(
+		"Location {location_id} relocated from {:?} to {new_path:?}",
+		location.path
+	);
+	invalidate_query!(library, "locations.list");
+	invalidate_query!(library, "locations.get");
+
+	Ok(())
+}
+
 #[derive(Debug)]
 pub struct CreatedLocationResult {
 	pub name: String,
@@ -501,6 +842,8 @@ async fn create_location(
 	location_pub_id: Uuid,
 	location_path: impl AsRef<Path>,
 	indexer_rules_ids: &[i32],
+	nested_policy: NestedLocationPolicy,
+	index_depth: Option<i32>,
 	dry_run: bool,
 ) -> Result<Option<CreatedLocationResult>, LocationError> {
 	let Library { db, sync, .. } = &library;
@@ -552,7 +895,11 @@ async fn create_location(
 		return Err(LocationError::LocationAlreadyExists(path));
 	}
 
-	if check_nested_location(&location_path, &library.db).await? {
+	if nested_policy == NestedLocationPolicy::Deny
+		&& !find_overlapping_locations(db, &location_path)
+			.await?
+			.is_empty()
+	{
 		return Err(LocationError::NestedLocation(path));
 	}
 
@@ -572,6 +919,9 @@ async fn create_location(
 		name = "Unknown".to_string()
 	}
 
+	// Zero or less means no limit
+	let index_depth = index_depth.filter(|depth| *depth > 0);
+
 	let location = sync
 		.write_op(
 			db,
@@ -582,6 +932,7 @@ async fn create_location(
 				[
 					(location::name::NAME, json!(&name)),
 					(location::path::NAME, json!(&location_path)),
+					(location::index_depth::NAME, json!(index_depth)),
 					(
 						location::node::NAME,
 						json!(sync::node::SyncId {
@@ -596,6 +947,7 @@ async fn create_location(
 					vec![
 						location::name::set(Some(name.clone())),
 						location::path::set(Some(location_path)),
+						location::index_depth::set(index_depth),
 						location::node::connect(node::id::equals(library.node_local_id)),
 					],
 				)
@@ -635,7 +987,15 @@ pub async fn delete_location(
 		.remove(location_id, library.clone())
 		.await?;
 
-	delete_directory(library, location_id, None).await?;
+	// The file paths are detached instead of deleted with the location, so a cleanup job can remove
+	// them in batches with everything that depends on them
+	db.file_path()
+		.update_many(
+			vec![file_path::location_id::equals(Some(location_id))],
+			vec![file_path::location_id::set(None)],
+		)
+		.exec()
+		.await?;
 
 	db.indexer_rules_in_location()
 		.delete_many(vec![indexer_rules_in_location::location_id::equals(
@@ -658,10 +1018,13 @@ pub async fn delete_location(
 		}
 	}
 
-	library.orphan_remover.invoke().await;
+	library
+		.spawn_job(cleanup::LocationCleanupJobInit { location_id })
+		.await?;
 
 	info!("Location {} deleted", location_id);
 	invalidate_query!(library, "locations.list");
+	invalidate_query!(library, "search.paths");
 
 	Ok(())
 }
@@ -725,9 +1088,25 @@ impl From<location_with_indexer_rules::Data> for location::Data {
 			sync_preview_media: data.sync_preview_media,
 			hidden: data.hidden,
 			date_created: data.date_created,
+			compress_at_rest: data.compress_at_rest,
+			compress_after_days: data.compress_after_days,
+			tier_to_location_id: data.tier_to_location_id,
+			tier_after_days: data.tier_after_days,
+			tier_min_size_in_mb: data.tier_min_size_in_mb,
+			xmp_sidecars: data.xmp_sidecars,
+			xmp_conflict_strategy: data.xmp_conflict_strategy,
+			sync_finder_tags: data.sync_finder_tags,
+			is_sensitive: data.is_sensitive,
+			is_private: data.is_private,
+			private_passphrase: data.private_passphrase,
+			index_depth: data.index_depth,
+			downloads_automation: data.downloads_automation,
+			bundles_as_files: data.bundles_as_files,
 			node: None,
 			file_paths: None,
 			indexer_rules: None,
+			pinned_directories: None,
+			download_rules: None,
 		}
 	}
 }
@@ -747,40 +1126,25 @@ impl From<&location_with_indexer_rules::Data> for location::Data {
 			sync_preview_media: data.sync_preview_media,
 			hidden: data.hidden,
 			date_created: data.date_created,
+			compress_at_rest: data.compress_at_rest,
+			compress_after_days: data.compress_after_days,
+			tier_to_location_id: data.tier_to_location_id,
+			tier_after_days: data.tier_after_days,
+			tier_min_size_in_mb: data.tier_min_size_in_mb,
+			xmp_sidecars: data.xmp_sidecars,
+			xmp_conflict_strategy: data.xmp_conflict_strategy,
+			sync_finder_tags: data.sync_finder_tags,
+			is_sensitive: data.is_sensitive,
+			is_private: data.is_private,
+			private_passphrase: data.private_passphrase.clone(),
+			index_depth: data.index_depth,
+			downloads_automation: data.downloads_automation,
+			bundles_as_files: data.bundles_as_files,
 			node: None,
 			file_paths: None,
 			indexer_rules: None,
+			pinned_directories: None,
+			download_rules: None,
 		}
 	}
 }
-
-async fn check_nested_location(
-	location_path: impl AsRef<Path>,
-	db: &PrismaClient,
-) -> Result<bool, QueryError> {
-	let location_path = location_path.as_ref();
-
-	let (parents_count, children_count) = db
-		._batch((
-			db.location().count(vec![location::path::in_vec(
-				location_path
-					.ancestors()
-					.skip(1) // skip the actual location_path, we only want the parents
-					.map(|p| {
-						p.to_str()
-							.map(str::to_string)
-							.expect("Found non-UTF-8 path")
-					})
-					.collect(),
-			)]),
-			db.location().count(vec![location::path::starts_with(
-				location_path
-					.to_str()
-					.map(str::to_string)
-					.expect("Found non-UTF-8 path"),
-			)]),
-		))
-		.await?;
-
-	Ok(parents_count > 0 || children_count > 0)
-}
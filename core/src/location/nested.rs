@@ -0,0 +1,376 @@
+//! Locations inside other locations.
+//!
+//! Adding `/home/user` and `/home/user/Pictures` as two locations would index the pictures twice,
+//! so by default a location can't be created inside another one, or around one. With
+//! [`NestedLocationPolicy::ExcludeFromParent`] it can, and the parent then leaves the nested
+//! subtree to the nested location: its indexer and its watcher skip it, and what the parent had
+//! already indexed there is handed over instead of being indexed again.
+//!
+//! [`split_location`] and [`merge_location`] turn a subtree into its own location and back.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	location::indexer::rules::{IndexerRule, IndexerRuleError, RulePerKind},
+	prisma::{location, PrismaClient},
+};
+
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use chrono::Utc;
+use prisma_client_rust::{raw, PrismaValue, QueryError};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tracing::info;
+
+use super::{
+	file_path_helper::{
+		ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
+		filter_existing_file_path_params, IsolatedFilePathData,
+	},
+	find_location, location_with_indexer_rules, LocationCreateArgs, LocationError,
+};
+
+/// What to do when a new location is inside an existing one, or an existing one is inside it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Type, Eq, PartialEq)]
+pub enum NestedLocationPolicy {
+	/// Refuse to create the location
+	#[default]
+	Deny,
+	/// Create it, and the outer location skips the subtree of the inner one
+	ExcludeFromParent,
+}
+
+/// The locations a path is inside of, and the ones inside of it
+#[derive(Serialize, Type, Debug, Default)]
+pub struct LocationOverlaps {
+	pub parents: Vec<location::Data>,
+	pub children: Vec<location::Data>,
+}
+
+impl LocationOverlaps {
+	pub fn is_empty(&self) -> bool {
+		self.parents.is_empty() && self.children.is_empty()
+	}
+}
+
+pub async fn find_overlapping_locations(
+	db: &PrismaClient,
+	path: impl AsRef<Path>,
+) -> Result<LocationOverlaps, QueryError> {
+	let path = path.as_ref();
+
+	let (parents, children) = db
+		._batch((
+			db.location().find_many(vec![location::path::in_vec(
+				path.ancestors()
+					.skip(1) // skip the path itself, we only want the parents
+					.map(path_to_string)
+					.collect(),
+			)]),
+			// With the separator, so `/home/user` doesn't take `/home/user2` as a child
+			db.location()
+				.find_many(vec![location::path::starts_with(children_prefix(path))]),
+		))
+		.await?;
+
+	Ok(LocationOverlaps { parents, children })
+}
+
+/// The paths of the locations inside of the location at `location_path`
+pub async fn nested_location_paths(
+	db: &PrismaClient,
+	location_path: impl AsRef<Path>,
+) -> Result<Vec<PathBuf>, QueryError> {
+	Ok(db
+		.location()
+		.find_many(vec![location::path::starts_with(children_prefix(
+			location_path.as_ref(),
+		))])
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|location| location.path.map(PathBuf::from))
+		.collect())
+}
+
+/// An indexer rule, never stored, rejecting the roots of `nested_paths` and everything below them
+pub fn exclude_nested_locations_rule(
+	nested_paths: &[PathBuf],
+) -> Result<Option<IndexerRule>, IndexerRuleError> {
+	if nested_paths.is_empty() {
+		return Ok(None);
+	}
+
+	let globs = nested_paths
+		.iter()
+		.map(|path| escape_glob(&path_to_string(path)))
+		.flat_map(|path| [format!("{path}/**"), path])
+		.collect::<Vec<_>>();
+
+	Ok(Some(IndexerRule {
+		id: None,
+		name: "Nested locations".to_string(),
+		default: false,
+		rules: vec![RulePerKind::new_reject_files_by_globs_str(globs)?],
+		date_created: Utc::now(),
+		date_modified: Utc::now(),
+	}))
+}
+
+/// Whether `path` is one of `nested_paths` or inside of one
+pub fn is_in_nested_location(nested_paths: &[PathBuf], path: impl AsRef<Path>) -> bool {
+	let path = path.as_ref();
+	nested_paths
+		.iter()
+		.any(|nested_path| path.starts_with(nested_path))
+}
+
+/// Moves what the parents of a new nested location had indexed inside of it to the new location,
+/// so it doesn't have to be indexed again. The file paths are moved with raw queries, like the
+/// renames of the watcher, so no sync operations are emitted for them.
+pub async fn take_over_from_parents(
+	library: &Library,
+	location_id: location::id::Type,
+) -> Result<(), LocationError> {
+	let location = find_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	let location_path = location
+		.path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingPath(location_id))?;
+
+	let overlaps = find_overlapping_locations(&library.db, &location_path).await?;
+
+	for parent in overlaps.parents {
+		let Some(parent_path) = parent.path.as_ref() else {
+			continue;
+		};
+
+		let root = IsolatedFilePathData::new(parent.id, parent_path, &location_path, true)?
+			.normalized(library.config.file_name_normalization);
+		let prefix = root
+			.materialized_path_for_children()
+			.expect("the root of a location is always a directory");
+
+		// The materialized paths keep their leading slash, SUBSTR counts characters from 1
+		let moved = library
+			.db
+			._execute_raw(raw!(
+				"UPDATE file_path \
+					SET location_id = {}, materialized_path = SUBSTR(materialized_path, {}) \
+					WHERE location_id = {} AND SUBSTR(materialized_path, 1, {}) = {}",
+				PrismaValue::Int(location_id as i64),
+				PrismaValue::Int(prefix.chars().count() as i64),
+				PrismaValue::Int(parent.id as i64),
+				PrismaValue::Int(prefix.chars().count() as i64),
+				PrismaValue::String(prefix.clone())
+			))
+			.exec()
+			.await?;
+
+		// The root of a location has no file path
+		library
+			.db
+			.file_path()
+			.delete_many(filter_existing_file_path_params(&root))
+			.exec()
+			.await?;
+
+		if moved > 0 {
+			info!(
+				"Moved {moved} file paths under '{prefix}' from location {} to location {location_id}",
+				parent.id
+			);
+		}
+	}
+
+	invalidate_query!(library, "search.paths");
+
+	Ok(())
+}
+
+/// Turns a directory of a location into a location of its own, with the same indexer rules. What
+/// was indexed there moves along, and the parent skips the directory from now on.
+pub async fn split_location(
+	library: &Library,
+	location_id: location::id::Type,
+	sub_path: impl AsRef<Path>,
+) -> Result<location::id::Type, LocationError> {
+	let location = find_location(library, location_id)
+		.include(location_with_indexer_rules::include())
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	let location_path = location
+		.path
+		.as_ref()
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingPath(location_id))?;
+
+	let path = ensure_sub_path_is_in_location(&location_path, &sub_path).await?;
+	ensure_sub_path_is_directory(&location_path, &sub_path).await?;
+
+	let nested = LocationCreateArgs {
+		path,
+		dry_run: false,
+		indexer_rules_ids: location
+			.indexer_rules
+			.iter()
+			.map(|rule| rule.indexer_rule.id)
+			.collect(),
+		nested_policy: NestedLocationPolicy::ExcludeFromParent,
+		index_depth: location.index_depth,
+	}
+	.create(library)
+	.await?
+	.expect("not a dry run, so a location is created");
+
+	info!("Split location {} out of location {location_id}", nested.id);
+
+	Ok(nested.id)
+}
+
+/// Folds a nested location back into the location it's inside of, and deletes it. Its file paths
+/// move to the parent, so the parent doesn't have to index them again, but the parent has no file
+/// path for the former root yet, so the caller should light scan the directory holding it. Returns
+/// the id of the parent.
+pub async fn merge_location(
+	library: &Library,
+	location_id: location::id::Type,
+) -> Result<location::id::Type, LocationError> {
+	let location = find_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	let location_path = location
+		.path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingPath(location_id))?;
+
+	// The closest parent, the one with the longest path
+	let parent = find_overlapping_locations(&library.db, &location_path)
+		.await?
+		.parents
+		.into_iter()
+		.max_by_key(|parent| parent.path.as_ref().map(String::len))
+		.ok_or_else(|| LocationError::NotNested(location_path.clone()))?;
+	let parent_path = parent
+		.path
+		.as_ref()
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingPath(parent.id))?;
+
+	let root = IsolatedFilePathData::new(parent.id, &parent_path, &location_path, true)?
+		.normalized(library.config.file_name_normalization);
+	let prefix = root
+		.materialized_path_for_children()
+		.expect("the root of a location is always a directory");
+
+	// Whatever the parent still has there was indexed twice, the nested location's copy is kept
+	super::delete_directory(library, parent.id, Some(prefix.clone())).await?;
+
+	library
+		.db
+		._execute_raw(raw!(
+			"UPDATE file_path \
+				SET location_id = {}, materialized_path = {} || SUBSTR(materialized_path, 2) \
+				WHERE location_id = {}",
+			PrismaValue::Int(parent.id as i64),
+			PrismaValue::String(prefix),
+			PrismaValue::Int(location_id as i64)
+		))
+		.exec()
+		.await?;
+
+	super::delete_location(library, location_id).await?;
+
+	info!("Merged location {location_id} into location {}", parent.id);
+	invalidate_query!(library, "locations.list");
+
+	Ok(parent.id)
+}
+
+fn children_prefix(path: &Path) -> String {
+	let path = path_to_string(path);
+	if path.ends_with(MAIN_SEPARATOR) {
+		path
+	} else {
+		format!("{path}{MAIN_SEPARATOR}")
+	}
+}
+
+fn path_to_string(path: &Path) -> String {
+	path.to_str()
+		.map(str::to_string)
+		.expect("Found non-UTF-8 path")
+}
+
+/// Paths can hold characters with a meaning in globs, so they're wrapped in a class of their own
+fn escape_glob(path: &str) -> String {
+	path.chars()
+		.fold(String::with_capacity(path.len()), |mut escaped, c| {
+			if matches!(c, '*' | '?' | '[' | ']' | '{' | '}') {
+				escaped.push('[');
+				escaped.push(c);
+				escaped.push(']');
+			} else {
+				escaped.push(c);
+			}
+			escaped
+		})
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+	use crate::util::vfs::LocalFs;
+
+	async fn is_accepted(rule: &IndexerRule, path: &str) -> bool {
+		rule.apply(&LocalFs, path)
+			.await
+			.unwrap()
+			.into_iter()
+			.all(|(_, accepted)| accepted)
+	}
+
+	#[tokio::test]
+	async fn exclude_nested_locations() {
+		assert!(exclude_nested_locations_rule(&[]).unwrap().is_none());
+
+		let rule = exclude_nested_locations_rule(&[
+			PathBuf::from("/home/user/Pictures"),
+			PathBuf::from("/home/user/[draft] {old}?*"),
+		])
+		.unwrap()
+		.unwrap();
+
+		assert!(!is_accepted(&rule, "/home/user/Pictures").await);
+		assert!(!is_accepted(&rule, "/home/user/Pictures/2023/beach.jpg").await);
+		assert!(!is_accepted(&rule, "/home/user/[draft] {old}?*").await);
+		assert!(!is_accepted(&rule, "/home/user/[draft] {old}?*/notes.txt").await);
+
+		assert!(is_accepted(&rule, "/home/user/Pictures2").await);
+		assert!(is_accepted(&rule, "/home/user/d {old}xx").await);
+		assert!(is_accepted(&rule, "/home/user/notes.txt").await);
+	}
+
+	#[test]
+	fn nested_paths_are_matched_by_component() {
+		let nested_paths = [PathBuf::from("/home/user/Pictures")];
+
+		assert!(is_in_nested_location(&nested_paths, "/home/user/Pictures"));
+		assert!(is_in_nested_location(
+			&nested_paths,
+			"/home/user/Pictures/beach.jpg"
+		));
+		assert!(!is_in_nested_location(
+			&nested_paths,
+			"/home/user/Pictures2"
+		));
+		assert!(!is_in_nested_location(&nested_paths, "/home/user"));
+	}
+}
@@ -0,0 +1,218 @@
+//! Directories pinned in a location, like Downloads or Desktop in a home folder.
+//!
+//! A scan of the location indexes, identifies and thumbnails its pinned directories first, with
+//! jobs that skip ahead of the ones waiting in the queue, so they're fresh long before a huge
+//! location is done being crawled. The watcher also handles their content updates with a shorter
+//! debounce than the rest of the location.
+
+use crate::{
+	invalidate_query,
+	job::{Job, JobError, JobManagerError},
+	library::Library,
+	object::{
+		file_identifier::file_identifier_job::FileIdentifierJobInit,
+		preview::thumbnailer_job::ThumbnailerJobInit,
+	},
+	prisma::{location, pinned_directory, PrismaClient, SortOrder},
+};
+
+use std::path::{Path, PathBuf};
+
+use prisma_client_rust::QueryError;
+use tracing::{info, warn};
+
+use super::{
+	file_path_helper::{
+		check_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
+		IsolatedFilePathData,
+	},
+	find_location,
+	indexer::{self, IndexerJobInit},
+	location_with_indexer_rules, LocationError,
+};
+
+pub async fn pinned_directories(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<Vec<pinned_directory::Data>, QueryError> {
+	db.pinned_directory()
+		.find_many(vec![pinned_directory::location_id::equals(location_id)])
+		.order_by(pinned_directory::path::order(SortOrder::Asc))
+		.exec()
+		.await
+}
+
+/// The full paths of the directories pinned in the location at `location_path`
+pub async fn pinned_directory_paths(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+	location_path: impl AsRef<Path>,
+) -> Result<Vec<PathBuf>, QueryError> {
+	let location_path = location_path.as_ref();
+
+	Ok(pinned_directories(db, location_id)
+		.await?
+		.into_iter()
+		.map(|pinned| location_path.join(pinned.path))
+		.collect())
+}
+
+/// Pins a directory of a location, given by its full path or by its path relative to the location
+pub async fn pin_directory(
+	library: &Library,
+	location_id: location::id::Type,
+	sub_path: impl AsRef<Path>,
+) -> Result<pinned_directory::Data, LocationError> {
+	let location = find_location(library, location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+	let location_path = location
+		.path
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingPath(location_id))?;
+
+	let full_path = ensure_sub_path_is_in_location(&location_path, &sub_path).await?;
+	ensure_sub_path_is_directory(&location_path, &sub_path).await?;
+
+	let iso_file_path = IsolatedFilePathData::new(location_id, &location_path, &full_path, true)?
+		.normalized(library.config.file_name_normalization);
+	if iso_file_path.is_root() {
+		return Err(LocationError::PinnedRoot(location_id));
+	}
+	let path = iso_file_path.to_string();
+
+	let pinned = library
+		.db
+		.pinned_directory()
+		.upsert(
+			pinned_directory::location_id_path(location_id, path.clone()),
+			pinned_directory::create(location::id::equals(location_id), path, vec![]),
+			vec![],
+		)
+		.exec()
+		.await?;
+
+	info!(
+		"Pinned directory '{}' of location {location_id}",
+		full_path.display()
+	);
+	invalidate_query!(library, "locations.pinnedDirectories");
+
+	Ok(pinned)
+}
+
+pub async fn unpin_directory(
+	library: &Library,
+	pinned_directory_id: pinned_directory::id::Type,
+) -> Result<(), LocationError> {
+	library
+		.db
+		.pinned_directory()
+		.delete_many(vec![pinned_directory::id::equals(pinned_directory_id)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "locations.pinnedDirectories");
+
+	Ok(())
+}
+
+/// Scans a pinned directory ahead of everything waiting in the queue. The indexer job only walks
+/// directories it already knows, so one that isn't indexed yet gets a light scan first.
+pub async fn scan_pinned_directory(
+	library: &Library,
+	location: &location_with_indexer_rules::Data,
+	pinned: &pinned_directory::Data,
+) -> Result<(), JobError> {
+	if location.node_id != Some(library.node_local_id) {
+		return Ok(());
+	}
+
+	let location_path = location
+		.path
+		.as_ref()
+		.map(PathBuf::from)
+		.ok_or(LocationError::MissingPath(location.id))?;
+	let full_path = location_path.join(&pinned.path);
+
+	let iso_file_path = IsolatedFilePathData::new(location.id, &location_path, &full_path, true)
+		.map_err(LocationError::from)?
+		.normalized(library.config.file_name_normalization);
+
+	if !check_file_path_exists::<JobError>(&iso_file_path, &library.db).await? {
+		indexer::shallow(location, &full_path, library).await?;
+	}
+
+	match spawn_pinned_scan(library, location, full_path).await {
+		Ok(()) | Err(JobManagerError::AlreadyRunningJob { .. }) => Ok(()),
+		Err(e) => Err(LocationError::from(e).into()),
+	}
+}
+
+/// Queues a priority scan for each pinned directory of the location that was already indexed, the
+/// others are left to the scan of the whole location
+pub async fn scan_pinned_directories(
+	library: &Library,
+	location: &location_with_indexer_rules::Data,
+) -> Result<(), JobManagerError> {
+	let Some(location_path) = location.path.as_ref().map(PathBuf::from) else {
+		return Ok(());
+	};
+
+	for pinned in pinned_directories(&library.db, location.id).await? {
+		let full_path = location_path.join(&pinned.path);
+
+		let iso_file_path =
+			match IsolatedFilePathData::new(location.id, &location_path, &full_path, true) {
+				Ok(iso_file_path) => {
+					iso_file_path.normalized(library.config.file_name_normalization)
+				}
+				Err(e) => {
+					warn!(
+						"Skipping pinned directory '{}': {e:#?}",
+						full_path.display()
+					);
+					continue;
+				}
+			};
+
+		if check_file_path_exists::<JobManagerError>(&iso_file_path, &library.db).await? {
+			match spawn_pinned_scan(library, location, full_path).await {
+				Ok(()) | Err(JobManagerError::AlreadyRunningJob { .. }) => {}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	Ok(())
+}
+
+async fn spawn_pinned_scan(
+	library: &Library,
+	location: &location_with_indexer_rules::Data,
+	sub_path: PathBuf,
+) -> Result<(), JobManagerError> {
+	let location_base_data = location::Data::from(location);
+
+	library
+		.spawn_job(
+			Job::new_with_action(
+				IndexerJobInit {
+					location: location.clone(),
+					sub_path: Some(sub_path.clone()),
+				},
+				"scan_pinned_directory",
+			)
+			.with_priority()
+			.queue_next(FileIdentifierJobInit {
+				location: location_base_data.clone(),
+				sub_path: Some(sub_path.clone()),
+			})
+			.queue_next(ThumbnailerJobInit {
+				location: location_base_data,
+				sub_path: Some(sub_path),
+			}),
+		)
+		.await
+}
@@ -0,0 +1,299 @@
+//! Private locations, whose file paths are left out of every query until they're unlocked.
+//!
+//! Privacy is a local setting, it isn't synced, as each device decides what it shows. Locations
+//! are unlocked with their own passphrase, as the key manager is disabled for now, and they stay
+//! unlocked until they're locked again or the node restarts.
+
+use crate::{
+	library::Library,
+	prisma::{file_path, location, object, PrismaClient},
+};
+
+use std::collections::HashSet;
+
+use prisma_client_rust::{or, QueryError};
+use rspc::ErrorCode;
+use sd_crypto::{
+	primitives::SALT_LEN,
+	types::{HashingAlgorithm, Params, Salt},
+	Protected,
+};
+use thiserror::Error;
+use tokio::{sync::RwLock, task::spawn_blocking};
+
+#[derive(Error, Debug)]
+pub enum LocationPrivacyError {
+	#[error("location not found <id='{0}'>")]
+	NotFound(location::id::Type),
+	#[error("location <id='{0}'> is already private")]
+	AlreadyPrivate(location::id::Type),
+	#[error("location <id='{0}'> isn't private")]
+	NotPrivate(location::id::Type),
+	#[error("location <id='{0}'> is locked")]
+	Locked(location::id::Type),
+	#[error("the passphrase can't be empty")]
+	EmptyPassphrase,
+	#[error("wrong passphrase")]
+	WrongPassphrase,
+	#[error("crypto error: {0}")]
+	Crypto(#[from] sd_crypto::Error),
+	#[error("failed to hash the passphrase: {0}")]
+	Join(#[from] tokio::task::JoinError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<LocationPrivacyError> for rspc::Error {
+	fn from(err: LocationPrivacyError) -> Self {
+		let code = match err {
+			LocationPrivacyError::NotFound(_) => ErrorCode::NotFound,
+			LocationPrivacyError::AlreadyPrivate(_)
+			| LocationPrivacyError::NotPrivate(_)
+			| LocationPrivacyError::EmptyPassphrase => ErrorCode::BadRequest,
+			LocationPrivacyError::WrongPassphrase => ErrorCode::Unauthorized,
+			LocationPrivacyError::Locked(_) => ErrorCode::Forbidden,
+			_ => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+#[derive(Debug, Default)]
+struct PrivacyState {
+	private: HashSet<location::id::Type>,
+	unlocked: HashSet<location::id::Type>,
+}
+
+/// Mirrors which locations are private, so every query doesn't have to look it up
+#[derive(Debug, Default)]
+pub struct PrivateLocations(RwLock<PrivacyState>);
+
+impl PrivateLocations {
+	pub async fn load(db: &PrismaClient) -> Result<Self, QueryError> {
+		let private = db
+			.location()
+			.find_many(vec![location::is_private::equals(Some(true))])
+			.select(location::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|location| location.id)
+			.collect();
+
+		Ok(Self(RwLock::new(PrivacyState {
+			private,
+			unlocked: HashSet::new(),
+		})))
+	}
+
+	/// Private locations which weren't unlocked
+	pub async fn locked(&self) -> Vec<location::id::Type> {
+		let state = self.0.read().await;
+
+		state.private.difference(&state.unlocked).copied().collect()
+	}
+
+	/// Private locations, unlocked or not
+	pub async fn is_private(&self, location_id: location::id::Type) -> bool {
+		self.0.read().await.private.contains(&location_id)
+	}
+
+	pub async fn is_locked(&self, location_id: location::id::Type) -> bool {
+		let state = self.0.read().await;
+
+		state.private.contains(&location_id) && !state.unlocked.contains(&location_id)
+	}
+
+	pub async fn ensure_unlocked(
+		&self,
+		location_id: location::id::Type,
+	) -> Result<(), LocationPrivacyError> {
+		if self.is_locked(location_id).await {
+			return Err(LocationPrivacyError::Locked(location_id));
+		}
+
+		Ok(())
+	}
+
+	pub async fn lock(&self, location_id: location::id::Type) {
+		self.0.write().await.unlocked.remove(&location_id);
+	}
+
+	/// Restricts file paths to the ones outside of locked locations, `None` if nothing is locked
+	pub async fn visible_file_paths(&self) -> Option<file_path::WhereParam> {
+		let locked = self.locked().await;
+
+		(!locked.is_empty()).then(|| {
+			or![
+				file_path::location_id::equals(None),
+				file_path::location_id::not_in_vec(locked)
+			]
+		})
+	}
+
+	/// Drops the file paths of an object which are in locked locations
+	pub async fn retain_visible(&self, file_paths: &mut Vec<file_path::Data>) {
+		let state = self.0.read().await;
+
+		file_paths.retain(|file_path| {
+			file_path.location_id.map_or(true, |location_id| {
+				!state.private.contains(&location_id) || state.unlocked.contains(&location_id)
+			})
+		});
+	}
+
+	/// Restricts objects to the ones with a file path outside of locked locations, or none at all
+	pub async fn visible_objects(&self) -> Option<object::WhereParam> {
+		self.visible_file_paths().await.map(|param| {
+			or![
+				object::file_paths::none(vec![]),
+				object::file_paths::some(vec![param])
+			]
+		})
+	}
+}
+
+pub async fn make_private(
+	library: &Library,
+	location_id: location::id::Type,
+	passphrase: String,
+) -> Result<(), LocationPrivacyError> {
+	if passphrase.is_empty() {
+		return Err(LocationPrivacyError::EmptyPassphrase);
+	}
+
+	let location = fetch_privacy(library, location_id).await?;
+	if location.is_private == Some(true) {
+		return Err(LocationPrivacyError::AlreadyPrivate(location_id));
+	}
+
+	let salt = Salt::generate();
+	let hash = hash_passphrase(passphrase, salt).await?;
+
+	let mut private_passphrase = salt.0.to_vec();
+	private_passphrase.extend_from_slice(hash.as_bytes());
+
+	library
+		.db
+		.location()
+		.update(
+			location::id::equals(location_id),
+			vec![
+				location::is_private::set(Some(true)),
+				location::private_passphrase::set(Some(private_passphrase)),
+			],
+		)
+		.exec()
+		.await?;
+
+	let mut state = library.private_locations.0.write().await;
+	state.private.insert(location_id);
+	state.unlocked.remove(&location_id);
+
+	Ok(())
+}
+
+pub async fn make_public(
+	library: &Library,
+	location_id: location::id::Type,
+	passphrase: String,
+) -> Result<(), LocationPrivacyError> {
+	check_passphrase(library, location_id, passphrase).await?;
+
+	library
+		.db
+		.location()
+		.update(
+			location::id::equals(location_id),
+			vec![
+				location::is_private::set(None),
+				location::private_passphrase::set(None),
+			],
+		)
+		.exec()
+		.await?;
+
+	let mut state = library.private_locations.0.write().await;
+	state.private.remove(&location_id);
+	state.unlocked.remove(&location_id);
+
+	Ok(())
+}
+
+pub async fn unlock(
+	library: &Library,
+	location_id: location::id::Type,
+	passphrase: String,
+) -> Result<(), LocationPrivacyError> {
+	check_passphrase(library, location_id, passphrase).await?;
+
+	library
+		.private_locations
+		.0
+		.write()
+		.await
+		.unlocked
+		.insert(location_id);
+
+	Ok(())
+}
+
+location::select!(location_privacy { is_private private_passphrase });
+
+async fn fetch_privacy(
+	library: &Library,
+	location_id: location::id::Type,
+) -> Result<location_privacy::Data, LocationPrivacyError> {
+	library
+		.db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.select(location_privacy::select())
+		.exec()
+		.await?
+		.ok_or(LocationPrivacyError::NotFound(location_id))
+}
+
+async fn check_passphrase(
+	library: &Library,
+	location_id: location::id::Type,
+	passphrase: String,
+) -> Result<(), LocationPrivacyError> {
+	let location = fetch_privacy(library, location_id).await?;
+
+	let (Some(true), Some(private_passphrase)) = (location.is_private, location.private_passphrase)
+	else {
+		return Err(LocationPrivacyError::NotPrivate(location_id));
+	};
+
+	if private_passphrase.len() != SALT_LEN + blake3::OUT_LEN {
+		return Err(LocationPrivacyError::WrongPassphrase);
+	}
+
+	let (salt, expected) = private_passphrase.split_at(SALT_LEN);
+	let salt = Salt::try_from(salt.to_vec())?;
+	let expected = blake3::Hash::from(
+		<[u8; blake3::OUT_LEN]>::try_from(expected).expect("length checked above"),
+	);
+
+	// `blake3::Hash` equality is constant time
+	if hash_passphrase(passphrase, salt).await? != expected {
+		return Err(LocationPrivacyError::WrongPassphrase);
+	}
+
+	Ok(())
+}
+
+pub(crate) async fn hash_passphrase(
+	passphrase: String,
+	salt: Salt,
+) -> Result<blake3::Hash, LocationPrivacyError> {
+	let hashing_algorithm = HashingAlgorithm::Argon2id(Params::Standard);
+	let key = spawn_blocking(move || {
+		hashing_algorithm.hash(Protected::new(passphrase.into_bytes()), salt, None)
+	})
+	.await??;
+
+	Ok(blake3::Hash::from(*key.expose()))
+}
@@ -0,0 +1,123 @@
+//! Redaction mode, so a library can be demoed or screen shared without leaking the content of its
+//! sensitive locations. The listings mask the names of their files and point at blurred thumbnails,
+//! so the client never gets the real ones while the mode is enabled.
+//!
+//! Directory names are left as they are, so the explorer can still navigate these locations.
+
+use crate::{library::Library, object::preview::get_thumb_key, prisma::location};
+
+use std::{collections::HashSet, io, ops::Deref};
+
+use image::{imageops::FilterType, GenericImageView};
+use prisma_client_rust::QueryError;
+use webp::{Decoder, Encoder};
+
+/// Thumbnail keys starting with this are served blurred by the custom URI endpoint
+pub const REDACTED_THUMBNAIL: &str = "redacted";
+
+const MASK: char = '•';
+const MAX_MASK_LEN: usize = 12;
+
+/// Width of the image the thumbnail is shrunk to before scaling it back up
+const BLUR_WIDTH: u32 = 12;
+const BLURRED_THUMBNAIL_QUALITY: f32 = 30.0;
+
+/// The locations redacted by the listings, none unless redaction mode is enabled
+#[derive(Debug, Default)]
+pub struct Redaction(HashSet<location::id::Type>);
+
+impl Redaction {
+	pub async fn fetch(library: &Library) -> Result<Self, QueryError> {
+		if !library.config().get().await.redaction_mode {
+			return Ok(Self::default());
+		}
+
+		Ok(Self(
+			library
+				.db
+				.location()
+				.find_many(vec![location::is_sensitive::equals(Some(true))])
+				.select(location::select!({ id }))
+				.exec()
+				.await?
+				.into_iter()
+				.map(|location| location.id)
+				.collect(),
+		))
+	}
+
+	pub fn is_redacted(&self, location_id: Option<location::id::Type>) -> bool {
+		location_id.map_or(false, |location_id| self.0.contains(&location_id))
+	}
+
+	/// Masks the name of a file in a redacted location
+	pub fn redact_name(
+		&self,
+		location_id: Option<location::id::Type>,
+		is_dir: Option<bool>,
+		name: &mut Option<String>,
+	) {
+		if self.is_redacted(location_id) && is_dir != Some(true) {
+			if let Some(name) = name {
+				*name = mask(name);
+			}
+		}
+	}
+
+	pub fn thumbnail_key(&self, redacted: bool, cas_id: &str) -> Vec<String> {
+		let mut key = get_thumb_key(cas_id);
+		if redacted {
+			key.insert(0, REDACTED_THUMBNAIL.to_string());
+		}
+
+		key
+	}
+}
+
+fn mask(name: &str) -> String {
+	std::iter::repeat(MASK)
+		.take(name.chars().count().clamp(1, MAX_MASK_LEN))
+		.collect()
+}
+
+/// Blurs a WebP thumbnail beyond recognition, by shrinking it down and scaling it back up
+pub fn blur_thumbnail(webp: &[u8]) -> io::Result<Vec<u8>> {
+	let img = Decoder::new(webp)
+		.decode()
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid WebP thumbnail"))?
+		.to_image();
+
+	let (w, h) = img.dimensions();
+	let img = img
+		.resize(BLUR_WIDTH, BLUR_WIDTH, FilterType::Triangle)
+		.resize_exact(w, h, FilterType::Triangle);
+
+	let encoder = Encoder::from_image(&img)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+	Ok(encoder.encode(BLURRED_THUMBNAIL_QUALITY).deref().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn masks_names() {
+		let redaction = Redaction(HashSet::from([1]));
+
+		let mut name = Some("tax return 2023".to_string());
+		redaction.redact_name(Some(2), None, &mut name);
+		assert_eq!(name.as_deref(), Some("tax return 2023"));
+
+		redaction.redact_name(Some(1), Some(true), &mut name);
+		assert_eq!(name.as_deref(), Some("tax return 2023"));
+
+		redaction.redact_name(Some(1), Some(false), &mut name);
+		assert_eq!(name.as_deref(), Some("••••••••••••"));
+
+		let mut name = Some("cv".to_string());
+		redaction.redact_name(Some(1), None, &mut name);
+		assert_eq!(name.as_deref(), Some("••"));
+	}
+}
@@ -0,0 +1,286 @@
+//! Where the space of a location goes, as a tree of its directories for the treemap and sunburst
+//! charts. Directories are stored with their own size only, the size of everything below them is
+//! rolled up from the files when the tree is built.
+//!
+//! Each node lists its largest subdirectories and the largest files right in it, and sums up the
+//! rest in `other_size_in_bytes`, so a chart draws a handful of slices per node however many entries
+//! the directory holds.
+
+use crate::{
+	library::Library,
+	prisma::{file_path, location},
+};
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use super::{
+	file_path_helper::{file_path_for_treemap, IsolatedFilePathData},
+	find_location,
+	redaction::Redaction,
+	LocationError,
+};
+
+const DEFAULT_DEPTH: u8 = 3;
+const MAX_DEPTH: u8 = 8;
+const MAX_CHILDREN_PER_NODE: usize = 50;
+const LARGEST_FILES_PER_NODE: usize = 10;
+
+#[derive(Deserialize, Type, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TreemapArgs {
+	pub location_id: location::id::Type,
+	/// How many levels of directories below the root of the location, 3 by default
+	#[specta(optional)]
+	pub depth: Option<u8>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct TreemapNode {
+	/// `None` for the root of the location
+	pub file_path_id: Option<file_path::id::Type>,
+	pub name: String,
+	/// Everything below the directory
+	pub size_in_bytes: String,
+	pub files_count: i32,
+	/// The largest subdirectories, largest first. Empty at the depth requested.
+	pub children: Vec<TreemapNode>,
+	/// The largest files right in the directory, largest first
+	pub largest_files: Vec<TreemapFile>,
+	/// What `children` and `largest_files` leave out
+	pub other_size_in_bytes: String,
+	#[serde(skip)]
+	size: u64,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct TreemapFile {
+	pub file_path_id: file_path::id::Type,
+	pub name: String,
+	pub size_in_bytes: String,
+}
+
+pub async fn location_treemap(
+	library: &Library,
+	TreemapArgs { location_id, depth }: TreemapArgs,
+) -> Result<TreemapNode, LocationError> {
+	let location = find_location(library, location_id)
+		.select(location::select!({ name }))
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+
+	let mut file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![file_path::location_id::equals(Some(location_id))])
+		.select(file_path_for_treemap::select())
+		.exec()
+		.await?;
+
+	let redaction = Redaction::fetch(library).await?;
+	for file_path in &mut file_paths {
+		redaction.redact_name(Some(location_id), file_path.is_dir, &mut file_path.name);
+	}
+
+	Ok(Tree::new(&file_paths).node(
+		None,
+		location.name.unwrap_or_default(),
+		"/",
+		depth.unwrap_or(DEFAULT_DEPTH).min(MAX_DEPTH),
+	))
+}
+
+struct Tree<'a> {
+	/// Entries by the materialized path of the directory holding them
+	entries: HashMap<&'a str, Vec<&'a file_path_for_treemap::Data>>,
+	/// Size and files count of everything below each directory, by the same key
+	totals: HashMap<String, (u64, i32)>,
+}
+
+impl<'a> Tree<'a> {
+	/// The file paths must hold everything below the root
+	fn new(file_paths: &'a [file_path_for_treemap::Data]) -> Self {
+		let mut entries = HashMap::<&str, Vec<_>>::new();
+		for file_path in file_paths {
+			if let Some(materialized_path) = &file_path.materialized_path {
+				entries
+					.entry(materialized_path.as_str())
+					.or_default()
+					.push(file_path);
+			}
+		}
+
+		let mut directories = file_paths
+			.iter()
+			.filter_map(named_directory_children_path)
+			.collect::<Vec<_>>();
+		// Deepest first, so subdirectories are summed up before their parents
+		directories.sort_by_key(|path| Reverse(path.matches('/').count()));
+		directories.push("/".to_string());
+
+		let mut totals = HashMap::with_capacity(directories.len());
+		for directory in directories {
+			let total = entries.get(directory.as_str()).into_iter().flatten().fold(
+				(0, 0),
+				|(size, count), entry| {
+					let (entry_size, entry_count) =
+						if let Some(children_path) = named_directory_children_path(entry) {
+							totals.get(&children_path).copied().unwrap_or_default()
+						} else if entry.is_dir == Some(true) {
+							(0, 0)
+						} else {
+							(size_of(entry), 1)
+						};
+
+					(size + entry_size, count + entry_count)
+				},
+			);
+
+			totals.insert(directory, total);
+		}
+
+		Self { entries, totals }
+	}
+
+	fn node(
+		&self,
+		file_path_id: Option<file_path::id::Type>,
+		name: String,
+		children_key: &str,
+		depth: u8,
+	) -> TreemapNode {
+		let entries = self
+			.entries
+			.get(children_key)
+			.map(Vec::as_slice)
+			.unwrap_or_default();
+		let (size, files_count) = self.totals.get(children_key).copied().unwrap_or_default();
+
+		let mut children = if depth > 0 {
+			entries
+				.iter()
+				.filter_map(|directory| {
+					Some(self.node(
+						Some(directory.id),
+						directory.name.clone().unwrap_or_default(),
+						&named_directory_children_path(directory)?,
+						depth - 1,
+					))
+				})
+				.collect::<Vec<_>>()
+		} else {
+			vec![]
+		};
+		children.sort_by_key(|child| Reverse(child.size));
+		children.truncate(MAX_CHILDREN_PER_NODE);
+
+		let mut files = entries
+			.iter()
+			.filter(|entry| entry.is_dir != Some(true))
+			.map(|file| (size_of(file), *file))
+			.collect::<Vec<_>>();
+		files.sort_by_key(|(size, _)| Reverse(*size));
+		files.truncate(LARGEST_FILES_PER_NODE);
+
+		let shown = children.iter().map(|child| child.size).sum::<u64>()
+			+ files.iter().map(|(size, _)| size).sum::<u64>();
+
+		TreemapNode {
+			file_path_id,
+			name,
+			size_in_bytes: size.to_string(),
+			files_count,
+			children,
+			largest_files: files
+				.into_iter()
+				.map(|(size, file)| TreemapFile {
+					file_path_id: file.id,
+					name: IsolatedFilePathData::try_from(file)
+						.map(|file_path| file_path.full_name())
+						.unwrap_or_default(),
+					size_in_bytes: size.to_string(),
+				})
+				.collect(),
+			other_size_in_bytes: size.saturating_sub(shown).to_string(),
+			size,
+		}
+	}
+}
+
+/// Where the entries of directories other than the root of the location are, the root having no
+/// name
+fn named_directory_children_path(file_path: &file_path_for_treemap::Data) -> Option<String> {
+	let iso_file_path = IsolatedFilePathData::try_from(file_path).ok()?;
+	if iso_file_path.is_root() {
+		return None;
+	}
+
+	iso_file_path.materialized_path_for_children()
+}
+
+fn size_of(file_path: &file_path_for_treemap::Data) -> u64 {
+	file_path
+		.size_in_bytes
+		.as_deref()
+		.and_then(|size| size.parse().ok())
+		.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(
+		id: i32,
+		materialized_path: &str,
+		name: &str,
+		size: Option<u64>,
+	) -> file_path_for_treemap::Data {
+		let (name, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+
+		file_path_for_treemap::Data {
+			id,
+			location_id: Some(1),
+			materialized_path: Some(materialized_path.to_string()),
+			is_dir: Some(size.is_none()),
+			name: Some(name.to_string()),
+			extension: Some(extension.to_string()),
+			size_in_bytes: Some(size.unwrap_or(4096).to_string()),
+		}
+	}
+
+	#[test]
+	fn sizes_roll_up() {
+		let file_paths = [
+			entry(1, "/", "", None),
+			entry(2, "/", "photos", None),
+			entry(3, "/photos/", "2023", None),
+			entry(4, "/photos/2023/", "beach.jpg", Some(300)),
+			entry(5, "/photos/2023/", "dog.jpg", Some(200)),
+			entry(6, "/photos/", "cover.png", Some(50)),
+			entry(7, "/", "notes.txt", Some(10)),
+			entry(8, "/", "empty", None),
+		];
+
+		let root = Tree::new(&file_paths).node(None, "Home".to_string(), "/", 1);
+
+		assert_eq!(root.size_in_bytes, "560");
+		assert_eq!(root.files_count, 4);
+		assert_eq!(root.largest_files[0].name, "notes.txt");
+		assert_eq!(root.other_size_in_bytes, "0");
+
+		let photos = &root.children[0];
+		assert_eq!(photos.name, "photos");
+		assert_eq!(photos.size_in_bytes, "550");
+		assert_eq!(photos.files_count, 3);
+		// Past the depth requested, the subdirectory is only accounted for
+		assert!(photos.children.is_empty());
+		assert_eq!(photos.other_size_in_bytes, "500");
+
+		assert_eq!(root.children[1].name, "empty");
+		assert_eq!(root.children[1].size_in_bytes, "0");
+	}
+}
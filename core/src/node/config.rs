@@ -9,7 +9,13 @@ use std::{
 use tokio::sync::{RwLock, RwLockWriteGuard};
 use uuid::Uuid;
 
-use crate::util::migrator::{Migrate, MigratorError};
+use crate::{
+	object::{malware_scan::MalwareScanner, nsfw::NsfwClassifier},
+	p2p::{AddressBookEntry, Inbox},
+	util::migrator::{Migrate, MigratorError},
+};
+
+use super::ResourceProfile;
 
 /// NODE_STATE_CONFIG_NAME is the name of the file which stores the NodeState
 pub const NODE_STATE_CONFIG_NAME: &str = "node_state.sdconfig";
@@ -29,6 +35,86 @@ pub struct NodeConfig {
 	// TODO: These will probs be replaced by your Spacedrive account in the near future.
 	pub p2p_email: Option<String>,
 	pub p2p_img_url: Option<String>,
+	/// Memory in MiB shared by the IO heavy subsystems, derived from the system memory when unset.
+	#[serde(default)]
+	pub memory_budget_mb: Option<u32>,
+	/// resource_profile controls how much of the machine the node is allowed to use.
+	#[serde(default)]
+	pub resource_profile: ResourceProfile,
+	/// low_power_on_battery automatically switches to the low power profile while running on battery.
+	#[serde(default = "default_low_power_on_battery")]
+	pub low_power_on_battery: bool,
+	/// metrics_enabled exposes the node metrics in the Prometheus text format on the local `metrics` endpoint.
+	#[serde(default)]
+	pub metrics_enabled: bool,
+	/// share_links_base_url is the public HTTPS origin share links are built with. It must route `share/*` and `inbox/*` to the custom URI endpoint of this node, directly or through a relay.
+	#[serde(default)]
+	pub share_links_base_url: Option<String>,
+	/// redaction_mode masks the file names and blurs the thumbnails of sensitive locations in listings, for screen sharing.
+	#[serde(default)]
+	pub redaction_mode: bool,
+	/// custom_uri_limits caps what each client can ask from the custom URI endpoint, which serves file contents.
+	#[serde(default)]
+	pub custom_uri_limits: CustomUriLimits,
+	/// storage_alerts are the thresholds past which the volumes backing locations raise an alert.
+	#[serde(default)]
+	pub storage_alerts: StorageAlertThresholds,
+	/// p2p_address_book holds the peers reached at a known address instead of being discovered on the local network.
+	#[serde(default)]
+	pub p2p_address_book: Vec<AddressBookEntry>,
+	/// p2p_inboxes are the directories of locations other devices can drop files into.
+	#[serde(default)]
+	pub p2p_inboxes: Vec<Inbox>,
+	/// p2p_receive_inbox is the inbox Spacedrops from paired devices land in without being accepted, advertised to peers.
+	#[serde(default)]
+	pub p2p_receive_inbox: Option<Uuid>,
+	/// malware_scanner scans imported and received files before they reach a location. Files aren't scanned without one.
+	#[serde(default)]
+	pub malware_scanner: Option<MalwareScanner>,
+	/// nsfw_classifier scores photos and videos on this device, so NSFW ones can be hidden from restricted devices.
+	#[serde(default)]
+	pub nsfw_classifier: Option<NsfwClassifier>,
+}
+
+/// Limits of each client of the custom URI endpoint, `None` leaves them unlimited
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Type)]
+pub struct CustomUriLimits {
+	pub requests_per_minute: Option<u32>,
+	/// File contents and share link downloads being served at the same time
+	pub max_concurrent_streams: Option<u32>,
+	/// Tell clients apart by their `X-Forwarded-For` header, only safe behind a reverse proxy setting it
+	#[serde(default)]
+	pub trust_forwarded_for: bool,
+}
+
+/// When a volume backing a location is running out of space, `None` disables the alert
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Type)]
+pub struct StorageAlertThresholds {
+	/// Less than this percentage of the volume is free
+	pub min_free_percent: Option<u8>,
+	/// The volume is forecast to be full within this many days
+	pub full_within_days: Option<u32>,
+	/// The drive behind the volume is hotter than this
+	#[serde(default = "default_max_temperature_celsius")]
+	pub max_temperature_celsius: Option<u8>,
+}
+
+impl Default for StorageAlertThresholds {
+	fn default() -> Self {
+		Self {
+			min_free_percent: Some(10),
+			full_within_days: Some(14),
+			max_temperature_celsius: default_max_temperature_celsius(),
+		}
+	}
+}
+
+fn default_max_temperature_celsius() -> Option<u8> {
+	Some(60)
+}
+
+fn default_low_power_on_battery() -> bool {
+	true
 }
 
 // A version of [NodeConfig] that is safe to share with the frontend
@@ -43,6 +129,16 @@ pub struct SanitisedNodeConfig {
 	// TODO: These will probs be replaced by your Spacedrive account in the near future.
 	pub p2p_email: Option<String>,
 	pub p2p_img_url: Option<String>,
+	pub memory_budget_mb: Option<u32>,
+	pub resource_profile: ResourceProfile,
+	pub low_power_on_battery: bool,
+	pub metrics_enabled: bool,
+	pub share_links_base_url: Option<String>,
+	pub redaction_mode: bool,
+	pub custom_uri_limits: CustomUriLimits,
+	pub storage_alerts: StorageAlertThresholds,
+	pub malware_scanner: Option<MalwareScanner>,
+	pub nsfw_classifier: Option<NsfwClassifier>,
 }
 
 impl From<NodeConfig> for SanitisedNodeConfig {
@@ -53,6 +149,16 @@ impl From<NodeConfig> for SanitisedNodeConfig {
 			p2p_port: value.p2p_port,
 			p2p_email: value.p2p_email,
 			p2p_img_url: value.p2p_img_url,
+			memory_budget_mb: value.memory_budget_mb,
+			resource_profile: value.resource_profile,
+			low_power_on_battery: value.low_power_on_battery,
+			metrics_enabled: value.metrics_enabled,
+			share_links_base_url: value.share_links_base_url,
+			redaction_mode: value.redaction_mode,
+			custom_uri_limits: value.custom_uri_limits,
+			storage_alerts: value.storage_alerts,
+			malware_scanner: value.malware_scanner,
+			nsfw_classifier: value.nsfw_classifier,
 		}
 	}
 }
@@ -78,6 +184,19 @@ impl Migrate for NodeConfig {
 			keypair: Keypair::generate(),
 			p2p_email: None,
 			p2p_img_url: None,
+			memory_budget_mb: None,
+			resource_profile: ResourceProfile::default(),
+			low_power_on_battery: true,
+			metrics_enabled: false,
+			share_links_base_url: None,
+			redaction_mode: false,
+			custom_uri_limits: CustomUriLimits::default(),
+			storage_alerts: StorageAlertThresholds::default(),
+			p2p_address_book: vec![],
+			p2p_inboxes: vec![],
+			p2p_receive_inbox: None,
+			malware_scanner: None,
+			nsfw_classifier: None,
 		})
 	}
 
@@ -109,6 +228,19 @@ impl Default for NodeConfig {
 			keypair: Keypair::generate(),
 			p2p_email: None,
 			p2p_img_url: None,
+			memory_budget_mb: None,
+			resource_profile: ResourceProfile::default(),
+			low_power_on_battery: true,
+			metrics_enabled: false,
+			share_links_base_url: None,
+			redaction_mode: false,
+			custom_uri_limits: CustomUriLimits::default(),
+			storage_alerts: StorageAlertThresholds::default(),
+			p2p_address_book: vec![],
+			p2p_inboxes: vec![],
+			p2p_receive_inbox: None,
+			malware_scanner: None,
+			nsfw_classifier: None,
 		}
 	}
 }
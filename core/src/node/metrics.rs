@@ -0,0 +1,292 @@
+use std::{
+	collections::BTreeMap,
+	fmt::{self, Write},
+	sync::{
+		atomic::{AtomicI64, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+	time::{Duration, Instant},
+};
+
+/// Upper bounds (in seconds) of the buckets used for database query latencies
+const DB_QUERY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// `Metrics` holds the counters the node keeps about itself. They are only kept in memory and
+/// never leave the machine, unless the user opts in to expose them on the local metrics endpoint,
+/// which renders them in the Prometheus text format so self-hosters can monitor headless nodes.
+#[derive(Default)]
+pub struct Metrics {
+	/// Job runs that ended, by their final status
+	pub job_runs: LabeledCounter,
+	/// Job steps executed, by job name
+	pub job_steps: LabeledCounter,
+	/// File system events received by the location watcher, by kind
+	pub watcher_events: LabeledCounter,
+	/// Latency of database writes, by operation
+	pub db_query_duration: Histogram,
+	/// Thumbnails waiting to be generated by the thumbnailer job
+	pub thumbnailer_queue_depth: Gauge,
+	/// Bytes sent to other nodes, by kind of transfer
+	pub p2p_bytes_sent: LabeledCounter,
+	/// Bytes received from other nodes, by kind of transfer
+	pub p2p_bytes_received: LabeledCounter,
+	/// Bytes compression kept off the network, by kind of transfer
+	pub p2p_bytes_saved: LabeledCounter,
+	/// Spacedrop transfers, by direction
+	pub p2p_spacedrops: LabeledCounter,
+}
+
+impl Metrics {
+	pub fn new() -> Arc<Self> {
+		Arc::new(Self::default())
+	}
+
+	/// Renders all metrics in the Prometheus text exposition format
+	pub fn render(&self) -> String {
+		let mut out = String::new();
+		self.write_to(&mut out)
+			.expect("writing to a String can't fail");
+		out
+	}
+
+	fn write_to(&self, out: &mut String) -> fmt::Result {
+		self.job_runs.write_to(
+			out,
+			"spacedrive_job_runs_total",
+			"Job runs that ended, by status",
+			"status",
+		)?;
+		self.job_steps.write_to(
+			out,
+			"spacedrive_job_steps_total",
+			"Job steps executed, by job",
+			"job",
+		)?;
+		self.watcher_events.write_to(
+			out,
+			"spacedrive_watcher_events_total",
+			"File system events received by the location watcher, by kind",
+			"kind",
+		)?;
+		self.db_query_duration.write_to(
+			out,
+			"spacedrive_db_query_duration_seconds",
+			"Latency of database writes, by operation",
+			"operation",
+		)?;
+		self.thumbnailer_queue_depth.write_to(
+			out,
+			"spacedrive_thumbnailer_queue_depth",
+			"Thumbnails waiting to be generated",
+		)?;
+		self.p2p_bytes_sent.write_to(
+			out,
+			"spacedrive_p2p_bytes_sent_total",
+			"Bytes sent to other nodes, by kind",
+			"kind",
+		)?;
+		self.p2p_bytes_received.write_to(
+			out,
+			"spacedrive_p2p_bytes_received_total",
+			"Bytes received from other nodes, by kind",
+			"kind",
+		)?;
+		self.p2p_bytes_saved.write_to(
+			out,
+			"spacedrive_p2p_bytes_saved_total",
+			"Bytes compression kept off the network, by kind",
+			"kind",
+		)?;
+		self.p2p_spacedrops.write_to(
+			out,
+			"spacedrive_p2p_spacedrops_total",
+			"Spacedrop transfers, by direction",
+			"direction",
+		)
+	}
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+	pub fn set(&self, value: i64) {
+		self.0.store(value, Ordering::Relaxed);
+	}
+
+	pub fn get(&self) -> i64 {
+		self.0.load(Ordering::Relaxed)
+	}
+
+	fn write_to(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+		write_header(out, name, help, "gauge")?;
+		writeln!(out, "{name} {}", self.get())
+	}
+}
+
+/// A counter partitioned by the value of a single label
+#[derive(Default)]
+pub struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+	pub fn inc(&self, label: &str) {
+		self.inc_by(label, 1);
+	}
+
+	pub fn inc_by(&self, label: &str, value: u64) {
+		let mut counters = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+		if let Some(count) = counters.get_mut(label) {
+			*count += value;
+		} else {
+			counters.insert(label.to_string(), value);
+		}
+	}
+
+	pub fn get(&self, label: &str) -> u64 {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(label)
+			.copied()
+			.unwrap_or(0)
+	}
+
+	fn write_to(&self, out: &mut String, name: &str, help: &str, label_name: &str) -> fmt::Result {
+		write_header(out, name, help, "counter")?;
+
+		for (label, count) in self.0.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+			writeln!(
+				out,
+				"{name}{{{label_name}=\"{}\"}} {count}",
+				escape_label_value(label)
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Default, Clone, Copy)]
+struct HistogramData {
+	buckets: [u64; DB_QUERY_BUCKETS.len()],
+	sum: f64,
+	count: u64,
+}
+
+/// A histogram partitioned by the value of a single label
+#[derive(Default)]
+pub struct Histogram(Mutex<BTreeMap<String, HistogramData>>);
+
+impl Histogram {
+	pub fn observe(&self, label: &str, duration: Duration) {
+		let seconds = duration.as_secs_f64();
+		let mut histograms = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+		let data = histograms.entry(label.to_string()).or_default();
+
+		for (count, upper_bound) in data.buckets.iter_mut().zip(DB_QUERY_BUCKETS) {
+			if seconds <= upper_bound {
+				*count += 1;
+			}
+		}
+		data.sum += seconds;
+		data.count += 1;
+	}
+
+	/// Starts a timer that records the elapsed time under `label` when dropped
+	pub fn start_timer<'a>(&'a self, label: &'a str) -> HistogramTimer<'a> {
+		HistogramTimer {
+			histogram: self,
+			label,
+			start: Instant::now(),
+		}
+	}
+
+	fn write_to(&self, out: &mut String, name: &str, help: &str, label_name: &str) -> fmt::Result {
+		write_header(out, name, help, "histogram")?;
+
+		for (label, data) in self.0.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+			let label = escape_label_value(label);
+
+			for (count, upper_bound) in data.buckets.iter().zip(DB_QUERY_BUCKETS) {
+				writeln!(
+					out,
+					"{name}_bucket{{{label_name}=\"{label}\",le=\"{upper_bound}\"}} {count}"
+				)?;
+			}
+			writeln!(
+				out,
+				"{name}_bucket{{{label_name}=\"{label}\",le=\"+Inf\"}} {}",
+				data.count
+			)?;
+			writeln!(out, "{name}_sum{{{label_name}=\"{label}\"}} {}", data.sum)?;
+			writeln!(
+				out,
+				"{name}_count{{{label_name}=\"{label}\"}} {}",
+				data.count
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+pub struct HistogramTimer<'a> {
+	histogram: &'a Histogram,
+	label: &'a str,
+	start: Instant,
+}
+
+impl Drop for HistogramTimer<'_> {
+	fn drop(&mut self) {
+		self.histogram.observe(self.label, self.start.elapsed());
+	}
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) -> fmt::Result {
+	writeln!(out, "# HELP {name} {help}")?;
+	writeln!(out, "# TYPE {name} {kind}")
+}
+
+fn escape_label_value(value: &str) -> String {
+	value
+		.replace('\\', r"\\")
+		.replace('"', r#"\""#)
+		.replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn renders_prometheus_text_format() {
+		let metrics = Metrics::default();
+		metrics.job_steps.inc("indexer");
+		metrics.job_steps.inc_by("indexer", 2);
+		metrics.thumbnailer_queue_depth.set(42);
+		metrics
+			.db_query_duration
+			.observe("write_ops", Duration::from_millis(20));
+
+		let rendered = metrics.render();
+
+		assert!(rendered.contains("# TYPE spacedrive_job_steps_total counter\n"));
+		assert!(rendered.contains("spacedrive_job_steps_total{job=\"indexer\"} 3\n"));
+		assert!(rendered.contains("spacedrive_thumbnailer_queue_depth 42\n"));
+		assert!(rendered.contains(
+			"spacedrive_db_query_duration_seconds_bucket{operation=\"write_ops\",le=\"0.01\"} 0\n"
+		));
+		assert!(rendered.contains(
+			"spacedrive_db_query_duration_seconds_bucket{operation=\"write_ops\",le=\"0.025\"} 1\n"
+		));
+		assert!(rendered
+			.contains("spacedrive_db_query_duration_seconds_count{operation=\"write_ops\"} 1\n"));
+	}
+
+	#[test]
+	fn label_values_are_escaped() {
+		assert_eq!(escape_label_value(r#"a"b\c"#), r#"a\"b\\c"#);
+	}
+}
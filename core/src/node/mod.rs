@@ -3,8 +3,16 @@ use serde::{Deserialize, Serialize};
 use specta::Type;
 
 mod config;
+mod metrics;
+mod power;
+mod resources;
+mod shell;
 
 pub use config::*;
+pub use metrics::*;
+pub use power::*;
+pub use resources::*;
+pub use shell::*;
 
 #[allow(clippy::upper_case_acronyms)]
 #[repr(u8)]
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+/// Where the machine running this node is currently getting its power from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub enum PowerSource {
+	Ac,
+	Battery,
+	/// Desktops without a battery, mobile devices and platforms we can't query end up here
+	Unknown,
+}
+
+impl PowerSource {
+	/// Queries the platform power APIs, this is blocking so call it from a blocking context
+	#[allow(unreachable_code)]
+	pub fn current() -> Self {
+		#[cfg(target_os = "linux")]
+		return linux::current();
+
+		#[cfg(target_os = "macos")]
+		return macos::current();
+
+		#[cfg(target_os = "windows")]
+		return windows::current();
+
+		Self::Unknown
+	}
+
+	pub fn is_battery(&self) -> bool {
+		matches!(self, Self::Battery)
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+	use super::PowerSource;
+
+	use std::fs;
+
+	const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+	/// Every power supply exposes its `type`, mains adapters also expose if they're `online`
+	pub fn current() -> PowerSource {
+		let Ok(entries) = fs::read_dir(POWER_SUPPLY_DIR) else {
+			return PowerSource::Unknown;
+		};
+
+		let mut has_battery = false;
+
+		for entry in entries.flatten() {
+			let path = entry.path();
+			let Ok(kind) = fs::read_to_string(path.join("type")) else {
+				continue;
+			};
+
+			match kind.trim() {
+				"Mains" | "USB"
+					if fs::read_to_string(path.join("online"))
+						.map(|online| online.trim() == "1")
+						.unwrap_or(false) =>
+				{
+					return PowerSource::Ac;
+				}
+				"Battery" => has_battery = true,
+				_ => {}
+			}
+		}
+
+		if has_battery {
+			PowerSource::Battery
+		} else {
+			PowerSource::Unknown
+		}
+	}
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+	use super::PowerSource;
+
+	use std::process::Command;
+
+	/// `pmset -g ps` prints "Now drawing from 'AC Power'" or "Now drawing from 'Battery Power'"
+	pub fn current() -> PowerSource {
+		let Ok(output) = Command::new("pmset").args(["-g", "ps"]).output() else {
+			return PowerSource::Unknown;
+		};
+
+		let output = String::from_utf8_lossy(&output.stdout);
+
+		if output.contains("'AC Power'") {
+			PowerSource::Ac
+		} else if output.contains("'Battery Power'") {
+			PowerSource::Battery
+		} else {
+			PowerSource::Unknown
+		}
+	}
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+	use super::PowerSource;
+
+	#[repr(C)]
+	#[allow(non_snake_case)]
+	struct SYSTEM_POWER_STATUS {
+		ACLineStatus: u8,
+		BatteryFlag: u8,
+		BatteryLifePercent: u8,
+		SystemStatusFlag: u8,
+		BatteryLifeTime: u32,
+		BatteryFullLifeTime: u32,
+	}
+
+	#[link(name = "kernel32")]
+	extern "system" {
+		fn GetSystemPowerStatus(status: *mut SYSTEM_POWER_STATUS) -> i32;
+	}
+
+	const AC_LINE_OFFLINE: u8 = 0;
+	const AC_LINE_ONLINE: u8 = 1;
+
+	pub fn current() -> PowerSource {
+		let mut status = SYSTEM_POWER_STATUS {
+			ACLineStatus: u8::MAX,
+			BatteryFlag: 0,
+			BatteryLifePercent: 0,
+			SystemStatusFlag: 0,
+			BatteryLifeTime: 0,
+			BatteryFullLifeTime: 0,
+		};
+
+		// SAFETY: `status` is a valid and properly aligned `SYSTEM_POWER_STATUS`
+		if unsafe { GetSystemPowerStatus(&mut status) } == 0 {
+			return PowerSource::Unknown;
+		}
+
+		match status.ACLineStatus {
+			AC_LINE_ONLINE => PowerSource::Ac,
+			AC_LINE_OFFLINE => PowerSource::Battery,
+			_ => PowerSource::Unknown,
+		}
+	}
+}
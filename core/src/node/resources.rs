@@ -0,0 +1,270 @@
+use std::{
+	num::NonZeroUsize,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread::available_parallelism,
+	time::Duration,
+};
+
+use chrono::{Local, Timelike};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use sysinfo::{CpuExt, System, SystemExt};
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::{error, info};
+
+use super::{NodeConfigManager, PowerSource};
+
+/// How often we check if the machine was plugged in or out and if it's idle
+const SYSTEM_STATE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// The machine is considered idle while the global CPU usage stays below this percentage
+const IDLE_CPU_USAGE_THRESHOLD: f32 = 20.0;
+/// Consecutive samples below [`IDLE_CPU_USAGE_THRESHOLD`] needed for the machine to be idle, so a
+/// short lull between two busy minutes doesn't count
+const IDLE_SAMPLES_REQUIRED: u32 = 3;
+
+/// ResourceProfile is a single switch that controls how much of the machine the node is allowed to use.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub enum ResourceProfile {
+	LowPower,
+	#[default]
+	Balanced,
+	Performance,
+}
+
+/// A daily window of local hours. `start_hour` is inclusive and `end_hour` is exclusive,
+/// windows where `start_hour > end_hour` wrap around midnight (e.g. 22h to 6h).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub struct ScheduleWindow {
+	pub start_hour: u8,
+	pub end_hour: u8,
+}
+
+impl ScheduleWindow {
+	pub fn contains(&self, hour: u8) -> bool {
+		match self.start_hour.cmp(&self.end_hour) {
+			std::cmp::Ordering::Less => (self.start_hour..self.end_hour).contains(&hour),
+			std::cmp::Ordering::Greater => hour >= self.start_hour || hour < self.end_hour,
+			// An empty window would never open, so we treat it as the whole day
+			std::cmp::Ordering::Equal => true,
+		}
+	}
+
+	pub fn contains_now(&self) -> bool {
+		self.contains(Local::now().hour() as u8)
+	}
+
+	pub fn is_valid(&self) -> bool {
+		self.start_hour < 24 && self.end_hour < 24
+	}
+}
+
+/// The concrete limits a [`ResourceProfile`] translates to on the current machine
+#[derive(Debug, Clone, Copy, Serialize, Type, Eq, PartialEq)]
+pub struct ResourceLimits {
+	/// How many CPU bound tasks (like thumbnail generation) can run at once
+	pub cpu_workers: u32,
+	/// How many files the IO heavy subsystems (like the file identifier) can read at once
+	pub io_concurrency: u32,
+	/// How many thumbnails are generated in a single thumbnailer step
+	pub thumbnail_batch_size: u32,
+	/// How many videos can be transcoded at once, across libraries, as each one keeps a hardware
+	/// encoder or several cores busy
+	pub concurrent_transcodes: u32,
+	/// Background jobs only run inside this window, `None` means they can run at any time
+	pub background_jobs_window: Option<ScheduleWindow>,
+}
+
+impl ResourceProfile {
+	pub fn limits(self) -> ResourceLimits {
+		let cpus = available_parallelism().map_or(1, NonZeroUsize::get) as u32;
+
+		match self {
+			Self::LowPower => ResourceLimits {
+				cpu_workers: 1,
+				io_concurrency: 4,
+				thumbnail_batch_size: 4,
+				concurrent_transcodes: 1,
+				background_jobs_window: Some(ScheduleWindow {
+					start_hour: 0,
+					end_hour: 6,
+				}),
+			},
+			Self::Balanced => ResourceLimits {
+				cpu_workers: (cpus / 2).max(1),
+				io_concurrency: 16,
+				thumbnail_batch_size: 16,
+				concurrent_transcodes: 1,
+				background_jobs_window: None,
+			},
+			Self::Performance => ResourceLimits {
+				cpu_workers: cpus,
+				io_concurrency: 64,
+				thumbnail_batch_size: 32,
+				concurrent_transcodes: 2,
+				background_jobs_window: None,
+			},
+		}
+	}
+}
+
+/// Tells if the machine is idle from the CPU usage sampled at each poll
+#[derive(Debug, Default)]
+struct IdleSamples {
+	primed: bool,
+	consecutive: u32,
+}
+
+impl IdleSamples {
+	fn record(&mut self, cpu_usage: f32) -> bool {
+		// The CPU usage is computed between two refreshes, so the first one is always 0
+		if !self.primed {
+			self.primed = true;
+			return false;
+		}
+
+		if cpu_usage < IDLE_CPU_USAGE_THRESHOLD {
+			self.consecutive = self.consecutive.saturating_add(1);
+		} else {
+			self.consecutive = 0;
+		}
+
+		self.consecutive >= IDLE_SAMPLES_REQUIRED
+	}
+}
+
+/// ResourceManager resolves the resource profile the node should be running with right now,
+/// taking into account the user's choice, if the machine is running on battery and if it's idle.
+pub struct ResourceManager {
+	config: Arc<NodeConfigManager>,
+	on_battery: Arc<AtomicBool>,
+	idle: Arc<AtomicBool>,
+}
+
+impl ResourceManager {
+	pub(crate) fn new(config: Arc<NodeConfigManager>) -> Arc<Self> {
+		let on_battery = Arc::new(AtomicBool::new(false));
+		let idle = Arc::new(AtomicBool::new(false));
+
+		tokio::spawn({
+			let on_battery = Arc::clone(&on_battery);
+			let idle = Arc::clone(&idle);
+			async move {
+				let mut interval = interval(SYSTEM_STATE_POLL_INTERVAL);
+				let mut system = System::new();
+				let mut idle_samples = IdleSamples::default();
+
+				loop {
+					interval.tick().await;
+
+					system.refresh_cpu();
+					idle.store(
+						idle_samples.record(system.global_cpu_info().cpu_usage()),
+						Ordering::Relaxed,
+					);
+
+					match spawn_blocking(PowerSource::current).await {
+						Ok(power_source) => {
+							let is_battery = power_source.is_battery();
+							if on_battery.swap(is_battery, Ordering::Relaxed) != is_battery {
+								info!("Power source changed to {power_source:?}");
+							}
+						}
+						Err(e) => error!("Failed to query the power source: {e:#?}"),
+					}
+				}
+			}
+		});
+
+		Arc::new(Self {
+			config,
+			on_battery,
+			idle,
+		})
+	}
+
+	pub fn on_battery(&self) -> bool {
+		self.on_battery.load(Ordering::Relaxed)
+	}
+
+	pub fn is_idle(&self) -> bool {
+		self.idle.load(Ordering::Relaxed)
+	}
+
+	/// The profile currently in effect, which can be a downgrade from the configured one
+	pub async fn profile(&self) -> ResourceProfile {
+		let config = self.config.get().await;
+
+		if config.low_power_on_battery && self.on_battery() {
+			ResourceProfile::LowPower
+		} else {
+			config.resource_profile
+		}
+	}
+
+	pub async fn limits(&self) -> ResourceLimits {
+		self.profile().await.limits()
+	}
+
+	/// Checks if background jobs are allowed to run right now
+	pub async fn background_jobs_allowed(&self) -> bool {
+		self.limits()
+			.await
+			.background_jobs_window
+			.map_or(true, |window| window.contains_now())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn schedule_window_contains() {
+		let daytime = ScheduleWindow {
+			start_hour: 9,
+			end_hour: 17,
+		};
+		assert!(daytime.contains(9));
+		assert!(daytime.contains(16));
+		assert!(!daytime.contains(17));
+		assert!(!daytime.contains(3));
+
+		let overnight = ScheduleWindow {
+			start_hour: 22,
+			end_hour: 6,
+		};
+		assert!(overnight.contains(23));
+		assert!(overnight.contains(0));
+		assert!(overnight.contains(5));
+		assert!(!overnight.contains(6));
+		assert!(!overnight.contains(12));
+
+		let whole_day = ScheduleWindow {
+			start_hour: 4,
+			end_hour: 4,
+		};
+		assert!((0..24).all(|hour| whole_day.contains(hour)));
+	}
+
+	#[test]
+	fn idle_after_consecutive_quiet_samples() {
+		let mut samples = IdleSamples::default();
+
+		// The first reading is always 0, it says nothing about the machine
+		assert!(!samples.record(0.0));
+
+		assert!(!samples.record(5.0));
+		assert!(!samples.record(5.0));
+		assert!(samples.record(5.0));
+		assert!(samples.record(5.0));
+
+		// A busy sample starts the count over
+		assert!(!samples.record(90.0));
+		assert!(!samples.record(5.0));
+		assert!(!samples.record(5.0));
+		assert!(samples.record(5.0));
+	}
+}
@@ -0,0 +1,332 @@
+//! Integration with the OS file manager, for shell extensions like a "Spacedrive" context menu.
+//!
+//! Shell extensions can't reach our rspc router, so the node listens on a local socket (a named
+//! pipe on Windows) only reachable by the current user. Each connection sends one JSON request per
+//! line, like `{"path":"/home/user/photo.jpg","action":{"type":"Tag","tag_id":1}}`, and gets one
+//! JSON response line back for each.
+
+use crate::{
+	api::CoreEvent,
+	invalidate_query,
+	library::{Library, LibraryManager},
+	location::file_path_helper::{
+		filter_existing_file_path_params, FilePathError, IsolatedFilePathData,
+	},
+	object::{os_metadata::write_object_finder_tags_or_log, xmp::write_object_sidecars_or_log},
+	prisma::{file_path, location, object, tag, tag_on_object},
+	util::error::FileIOError,
+	Node,
+};
+
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use prisma_client_rust::QueryError;
+use sd_p2p::PeerId;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	fs,
+	io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+#[cfg(unix)]
+const SOCKET_FILE_NAME: &str = "shell.sock";
+#[cfg(windows)]
+const PIPE_NAME: &str = r"\\.\pipe\spacedrive-shell";
+
+#[derive(Error, Debug)]
+pub enum ShellError {
+	#[error("path isn't in any location of the node: <path='{}'>", .0.display())]
+	NotInLocation(Box<Path>),
+	#[error("path wasn't indexed yet: <path='{}'>", .0.display())]
+	NotIndexed(Box<Path>),
+	#[error("file wasn't identified yet: <path='{}'>", .0.display())]
+	NotIdentified(Box<Path>),
+	#[error("tag not found: <id='{0}'>")]
+	TagNotFound(tag::id::Type),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error(transparent)]
+	FilePath(#[from] FilePathError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<ShellError> for rspc::Error {
+	fn from(error: ShellError) -> Self {
+		let code = match error {
+			ShellError::NotInLocation(_)
+			| ShellError::NotIndexed(_)
+			| ShellError::NotIdentified(_)
+			| ShellError::TagNotFound(_) => rspc::ErrorCode::NotFound,
+			_ => rspc::ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, error.to_string(), error)
+	}
+}
+
+/// Where an OS path lives in our libraries
+#[derive(Serialize, Debug, Clone, Type)]
+pub struct ResolvedOsPath {
+	pub library_id: Uuid,
+	pub location_id: location::id::Type,
+	pub file_path_id: file_path::id::Type,
+	pub object_id: Option<object::id::Type>,
+}
+
+#[derive(Deserialize, Debug, Type)]
+#[serde(tag = "type")]
+pub enum ShellAction {
+	/// Opens the path in the app
+	Reveal,
+	Tag {
+		tag_id: tag::id::Type,
+	},
+	SendToDevice {
+		peer_id: PeerId,
+	},
+}
+
+#[derive(Deserialize, Debug)]
+struct ShellRequest {
+	path: PathBuf,
+	action: ShellAction,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ShellResponse {
+	Ok(ResolvedOsPath),
+	Error(String),
+}
+
+/// Finds the library, location and file path of an absolute OS path, looking only at the
+/// locations of this node
+pub async fn resolve_os_path(
+	library_manager: &LibraryManager,
+	path: impl AsRef<Path>,
+) -> Result<(Library, ResolvedOsPath), ShellError> {
+	let path = path.as_ref();
+
+	let metadata = fs::metadata(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	for library in library_manager.get_all_libraries().await {
+		let locations = library
+			.db
+			.location()
+			.find_many(vec![location::node_id::equals(Some(library.node_local_id))])
+			.select(location::select!({ id path }))
+			.exec()
+			.await?;
+
+		// The deepest location wins, in case of a location inside of another one
+		let Some((location_id, location_path)) = locations
+			.into_iter()
+			.filter_map(|location| location.path.map(|path| (location.id, PathBuf::from(path))))
+			.filter(|(_, location_path)| path.starts_with(location_path))
+			.max_by_key(|(_, location_path)| location_path.components().count())
+		else {
+			continue;
+		};
+
+		let iso_file_path =
+			IsolatedFilePathData::new(location_id, &location_path, path, metadata.is_dir())?;
+
+		let file_path = library
+			.db
+			.file_path()
+			.find_first(filter_existing_file_path_params(&iso_file_path))
+			.select(file_path::select!({ id object_id }))
+			.exec()
+			.await?
+			.ok_or_else(|| ShellError::NotIndexed(path.into()))?;
+
+		let resolved = ResolvedOsPath {
+			library_id: library.id,
+			location_id,
+			file_path_id: file_path.id,
+			object_id: file_path.object_id,
+		};
+
+		return Ok((library, resolved));
+	}
+
+	Err(ShellError::NotInLocation(path.into()))
+}
+
+pub async fn apply_shell_action(
+	node: &Node,
+	path: impl AsRef<Path>,
+	action: ShellAction,
+) -> Result<ResolvedOsPath, ShellError> {
+	let path = path.as_ref();
+	let (library, resolved) = resolve_os_path(&node.library_manager, path).await?;
+
+	match action {
+		ShellAction::Reveal => library.emit(CoreEvent::RevealPath(resolved.clone())),
+		ShellAction::Tag { tag_id } => {
+			let object_id = resolved
+				.object_id
+				.ok_or_else(|| ShellError::NotIdentified(path.into()))?;
+
+			tag_object(&library, tag_id, object_id).await?;
+		}
+		ShellAction::SendToDevice { peer_id } => {
+			let p2p = node.p2p.clone();
+			let path = path.to_path_buf();
+
+			// Spacedrop waits for the other device to accept, which shell extensions can't wait for
+			tokio::spawn(async move {
+				if p2p.big_bad_spacedrop(peer_id, path.clone(), &[]).await.is_err() {
+					error!("Failed to send '{}' to peer '{peer_id}'", path.display());
+				}
+			});
+		}
+	}
+
+	Ok(resolved)
+}
+
+async fn tag_object(
+	library: &Library,
+	tag_id: tag::id::Type,
+	object_id: object::id::Type,
+) -> Result<(), ShellError> {
+	let Library { db, .. } = library;
+
+	db.tag()
+		.find_unique(tag::id::equals(tag_id))
+		.select(tag::select!({ id }))
+		.exec()
+		.await?
+		.ok_or(ShellError::TagNotFound(tag_id))?;
+
+	if db
+		.tag_on_object()
+		.find_unique(tag_on_object::tag_id_object_id(tag_id, object_id))
+		.exec()
+		.await?
+		.is_some()
+	{
+		return Ok(());
+	}
+
+	db.tag_on_object()
+		.create_unchecked(tag_id, object_id, vec![])
+		.exec()
+		.await?;
+
+	write_object_finder_tags_or_log(library, vec![object_id]).await;
+	write_object_sidecars_or_log(library, vec![object_id]).await;
+
+	invalidate_query!(library, "tags.getForObject");
+
+	Ok(())
+}
+
+async fn handle_connection(node: Arc<Node>, stream: impl AsyncRead + AsyncWrite) {
+	let (reader, mut writer) = tokio::io::split(stream);
+	let mut lines = BufReader::new(reader).lines();
+
+	loop {
+		let line = match lines.next_line().await {
+			Ok(Some(line)) => line,
+			Ok(None) => break,
+			Err(e) => {
+				warn!("Failed to read from shell integration client: {e:#?}");
+				break;
+			}
+		};
+
+		let response = match serde_json::from_str::<ShellRequest>(&line) {
+			Ok(ShellRequest { path, action }) => {
+				debug!(
+					"Shell integration request: {action:?} on '{}'",
+					path.display()
+				);
+
+				match apply_shell_action(&node, path, action).await {
+					Ok(resolved) => ShellResponse::Ok(resolved),
+					Err(e) => ShellResponse::Error(e.to_string()),
+				}
+			}
+			Err(e) => ShellResponse::Error(format!("invalid request: {e}")),
+		};
+
+		let mut response =
+			serde_json::to_vec(&response).expect("responses are always serializable");
+		response.push(b'\n');
+
+		if let Err(e) = writer.write_all(&response).await {
+			warn!("Failed to reply to shell integration client: {e:#?}");
+			break;
+		}
+	}
+}
+
+#[cfg(unix)]
+pub(crate) async fn listen(node: Arc<Node>) -> Result<(), FileIOError> {
+	use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+	use tokio::net::UnixListener;
+
+	let socket_path = node.data_dir.join(SOCKET_FILE_NAME);
+
+	// A previous run could have left it behind if it didn't shut down cleanly
+	if let Err(e) = fs::remove_file(&socket_path).await {
+		if e.kind() != std::io::ErrorKind::NotFound {
+			return Err(FileIOError::from((&socket_path, e)));
+		}
+	}
+
+	let listener =
+		UnixListener::bind(&socket_path).map_err(|e| FileIOError::from((&socket_path, e)))?;
+
+	fs::set_permissions(&socket_path, Permissions::from_mode(0o600))
+		.await
+		.map_err(|e| FileIOError::from((&socket_path, e)))?;
+
+	loop {
+		match listener.accept().await {
+			Ok((stream, _)) => {
+				tokio::spawn(handle_connection(node.clone(), stream));
+			}
+			Err(e) => error!("Failed to accept shell integration connection: {e:#?}"),
+		}
+	}
+}
+
+#[cfg(windows)]
+pub(crate) async fn listen(node: Arc<Node>) -> Result<(), FileIOError> {
+	use tokio::net::windows::named_pipe::ServerOptions;
+
+	let create_pipe = |first_pipe_instance| {
+		ServerOptions::new()
+			.first_pipe_instance(first_pipe_instance)
+			.reject_remote_clients(true)
+			.create(PIPE_NAME)
+			.map_err(|e| FileIOError::from((PIPE_NAME, e)))
+	};
+
+	let mut server = create_pipe(true)?;
+
+	loop {
+		if let Err(e) = server.connect().await {
+			error!("Failed to accept shell integration connection: {e:#?}");
+			continue;
+		}
+
+		let client = server;
+		server = create_pipe(false)?;
+
+		tokio::spawn(handle_connection(node.clone(), client));
+	}
+}
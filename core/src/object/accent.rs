@@ -0,0 +1,159 @@
+//! Colors and emojis marking files, folders and tags, so the way a library is organized at a glance
+//! is the same on every client. Accents are synced with what they mark and come along with it in
+//! listings.
+//!
+//! Colors of files and folders are hex, like the ones of tags, and are kept uppercase so clients
+//! comparing them don't have to. Emojis are a single one, sequences joined by zero width joiners
+//! included.
+
+use crate::{invalidate_query, library::Library, prisma::file_path, sync};
+
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::Deserialize;
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+
+/// Enough for the longest emoji sequences, like families and flags of subdivisions
+const MAX_EMOJI_CHARS: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum AccentError {
+	#[error("colors are hex, like `#FFAA00`: {0}")]
+	InvalidColor(String),
+	#[error("accents take a single emoji: {0}")]
+	InvalidEmoji(String),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<AccentError> for rspc::Error {
+	fn from(err: AccentError) -> Self {
+		let code = match err {
+			AccentError::InvalidColor(_) | AccentError::InvalidEmoji(_) => ErrorCode::BadRequest,
+			AccentError::Database(_) => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+/// Checks a color is `#RGB`, `#RRGGBB` or `#RRGGBBAA`, uppercasing it
+pub fn normalize_color(color: &str) -> Result<String, AccentError> {
+	let digits = color.trim().strip_prefix('#').unwrap_or_default();
+
+	if ![3, 6, 8].contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+		return Err(AccentError::InvalidColor(color.to_string()));
+	}
+
+	Ok(format!("#{}", digits.to_ascii_uppercase()))
+}
+
+/// Checks an emoji is one short sequence, which letters, digits and spaces can't be part of
+pub fn normalize_emoji(emoji: &str) -> Result<String, AccentError> {
+	let emoji = emoji.trim();
+	let chars = emoji.chars().count();
+
+	if chars == 0
+		|| chars > MAX_EMOJI_CHARS
+		|| emoji
+			.chars()
+			.any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control())
+	{
+		return Err(AccentError::InvalidEmoji(emoji.to_string()));
+	}
+
+	Ok(emoji.to_string())
+}
+
+/// The accent of a file or a folder, each part left out clearing it
+#[derive(Deserialize, Type, Debug, Default)]
+pub struct Accent {
+	#[specta(optional)]
+	pub color: Option<String>,
+	#[specta(optional)]
+	pub emoji: Option<String>,
+}
+
+impl Accent {
+	fn normalized(self) -> Result<Self, AccentError> {
+		Ok(Self {
+			color: self.color.as_deref().map(normalize_color).transpose()?,
+			emoji: self.emoji.as_deref().map(normalize_emoji).transpose()?,
+		})
+	}
+}
+
+/// Marks file paths with an accent, returning how many there were
+pub async fn set_file_path_accents(
+	library: &Library,
+	file_path_ids: Vec<file_path::id::Type>,
+	accent: Accent,
+) -> Result<usize, AccentError> {
+	let Accent { color, emoji } = accent.normalized()?;
+
+	let Library { db, sync, .. } = library;
+
+	let file_paths = db
+		.file_path()
+		.find_many(vec![file_path::id::in_vec(file_path_ids)])
+		.select(file_path::select!({ id pub_id }))
+		.exec()
+		.await?;
+
+	if file_paths.is_empty() {
+		return Ok(0);
+	}
+
+	let (sync_ops, queries) = file_paths
+		.iter()
+		.map(|file_path| {
+			let sync_id = || sync::file_path::SyncId {
+				pub_id: file_path.pub_id.clone(),
+			};
+
+			(
+				[
+					sync.shared_update(sync_id(), file_path::color::NAME, json!(color)),
+					sync.shared_update(sync_id(), file_path::emoji::NAME, json!(emoji)),
+				],
+				db.file_path().update(
+					file_path::id::equals(file_path.id),
+					vec![
+						file_path::color::set(color.clone()),
+						file_path::emoji::set(emoji.clone()),
+					],
+				),
+			)
+		})
+		.unzip::<_, _, Vec<_>, Vec<_>>();
+
+	sync.write_ops(db, (sync_ops.into_iter().flatten().collect(), queries))
+		.await?;
+
+	invalidate_query!(library, "search.paths");
+
+	Ok(file_paths.len())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn accents_are_checked() {
+		assert_eq!(normalize_color("#ffaa00").unwrap(), "#FFAA00");
+		assert_eq!(normalize_color(" #fa0 ").unwrap(), "#FA0");
+		assert!(normalize_color("ffaa00").is_err());
+		assert!(normalize_color("#ffaa0").is_err());
+		assert!(normalize_color("#gggggg").is_err());
+
+		assert_eq!(normalize_emoji("📁").unwrap(), "📁");
+		// Family, joined by zero width joiners
+		assert!(normalize_emoji("👨\u{200D}👩\u{200D}👧").is_ok());
+		assert!(normalize_emoji("").is_err());
+		assert!(normalize_emoji("abc").is_err());
+		assert!(normalize_emoji("📁 📂").is_err());
+	}
+}
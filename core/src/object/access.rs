@@ -0,0 +1,154 @@
+//! When objects were last opened on this device and how many times, which recents and the frecent
+//! listing are built from. The access history never leaves the device, it isn't synced with the
+//! object like the rest of its columns.
+//!
+//! Libraries can turn tracking off with `LibraryConfig::track_access`, which stops recording opens
+//! but keeps the history already there until it's purged.
+
+use crate::{invalidate_query, library::Library, prisma::object};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use prisma_client_rust::{not, Direction, QueryError};
+
+/// Only the objects opened most recently are ranked by frecency
+const FRECENT_CANDIDATES: i64 = 500;
+
+/// Records an object being opened, unless the library doesn't track access
+pub async fn record_access(
+	library: &Library,
+	object_id: object::id::Type,
+) -> Result<(), QueryError> {
+	if !library.config.track_access {
+		return Ok(());
+	}
+
+	let db = &library.db;
+
+	let open_count = db
+		.object()
+		.find_unique(object::id::equals(object_id))
+		.select(object::select!({ open_count }))
+		.exec()
+		.await?
+		.and_then(|object| object.open_count)
+		.unwrap_or(0);
+
+	db.object()
+		.update(
+			object::id::equals(object_id),
+			vec![
+				object::date_accessed::set(Some(Utc::now().into())),
+				object::open_count::set(Some(open_count.saturating_add(1))),
+			],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.frecent");
+
+	Ok(())
+}
+
+/// Forgets when the objects were opened and how often, every object's when `object_ids` is `None`,
+/// returning how many objects were purged
+pub async fn purge_access(
+	library: &Library,
+	object_ids: Option<Vec<object::id::Type>>,
+) -> Result<u32, QueryError> {
+	let purged = library
+		.db
+		.object()
+		.update_many(
+			object_ids.map(object::id::in_vec).into_iter().collect(),
+			vec![
+				object::date_accessed::set(None),
+				object::open_count::set(None),
+			],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+	invalidate_query!(library, "search.frecent");
+
+	Ok(purged as u32)
+}
+
+object::select!(object_for_frecency {
+	id
+	date_accessed
+	open_count
+});
+
+/// The objects both opened often and lately, the highest ranked first
+pub async fn frecent_object_ids(
+	library: &Library,
+	mut params: Vec<object::WhereParam>,
+	take: usize,
+) -> Result<Vec<object::id::Type>, QueryError> {
+	params.push(not![object::date_accessed::equals(None)]);
+
+	let now = Utc::now();
+
+	let mut objects = library
+		.db
+		.object()
+		.find_many(params)
+		.order_by(object::date_accessed::order(Direction::Desc))
+		.take(FRECENT_CANDIDATES)
+		.select(object_for_frecency::select())
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|object| {
+			Some((
+				object.id,
+				frecency(object.open_count, object.date_accessed?, now),
+			))
+		})
+		.collect::<Vec<_>>();
+
+	objects.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+	Ok(objects.into_iter().take(take).map(|(id, _)| id).collect())
+}
+
+/// Opens weighted by how long ago the last one was, in the buckets browsers rank history with
+fn frecency(
+	open_count: Option<i32>,
+	date_accessed: DateTime<FixedOffset>,
+	now: DateTime<Utc>,
+) -> f64 {
+	let weight = match (now - date_accessed.with_timezone(&Utc)).num_days() {
+		i64::MIN..=4 => 100.0,
+		5..=14 => 70.0,
+		15..=31 => 50.0,
+		32..=90 => 30.0,
+		_ => 10.0,
+	};
+
+	// Objects opened before open counts were kept have been opened at least once
+	f64::from(open_count.unwrap_or(1).max(1)) * weight
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use chrono::Duration;
+
+	#[test]
+	fn frecency_favours_recent_and_frequent() {
+		let now = Utc::now();
+		let ago = |days| (now - Duration::days(days)).into();
+
+		assert!(frecency(Some(1), ago(1), now) > frecency(Some(1), ago(20), now));
+		assert!(frecency(Some(3), ago(20), now) > frecency(Some(1), ago(1), now));
+		assert_eq!(
+			frecency(None, ago(200), now),
+			frecency(Some(1), ago(200), now)
+		);
+	}
+}
@@ -20,6 +20,15 @@ const_assert!((HEADER_OR_FOOTER_SIZE * 2 + SAMPLE_COUNT * SAMPLE_SIZE) < MINIMUM
 // Asserting that the sample size is larger than header/footer size, as the same buffer is used for both
 const_assert!(SAMPLE_SIZE > HEADER_OR_FOOTER_SIZE);
 
+/// Amount of memory that [`generate_cas_id`] keeps loaded at once for a file of the given size
+pub const fn cas_id_memory_usage(size: u64) -> u64 {
+	if size <= MINIMUM_FILE_SIZE {
+		size
+	} else {
+		SAMPLE_SIZE
+	}
+}
+
 pub async fn generate_cas_id(path: impl AsRef<Path>, size: u64) -> Result<String, io::Error> {
 	let mut hasher = Hasher::new();
 	hasher.update(&size.to_le_bytes());
@@ -0,0 +1,197 @@
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+
+use super::{open_catalog, CatalogEntry, CatalogImportError};
+
+/// Tags used by digiKam itself, for color and pick labels among other things, live under this one
+const INTERNAL_TAGS_ROOT: &str = "_Digikam_Internal_Tags_";
+const COLOR_LABEL_PREFIX: &str = "Color Label ";
+
+// Status 1 means visible, other statuses are for removed or obsolete images
+const IMAGES_QUERY: &str = "
+	SELECT i.id, ar.identifier, ar.specificPath, a.relativePath, i.name, i.fileSize, ii.rating
+	FROM Images i
+	JOIN Albums a ON i.album = a.id
+	JOIN AlbumRoots ar ON a.albumRoot = ar.id
+	LEFT JOIN ImageInformation ii ON ii.imageid = i.id
+	WHERE i.status = 1";
+
+const TAGS_QUERY: &str = "SELECT id, pid, name FROM Tags";
+
+const IMAGE_TAGS_QUERY: &str = "SELECT imageid, tagid FROM ImageTags";
+
+const HISTORY_QUERY: &str = "
+	SELECT imageid, history
+	FROM ImageHistory
+	WHERE history IS NOT NULL AND history <> ''";
+
+pub(super) fn read_catalog(path: &Path) -> Result<Vec<CatalogEntry>, CatalogImportError> {
+	let connection = open_catalog(path)?;
+	let sqlite_error = |source| CatalogImportError::Sqlite {
+		path: path.into(),
+		source,
+	};
+
+	let mut entries = HashMap::new();
+
+	let mut statement = connection.prepare(IMAGES_QUERY).map_err(sqlite_error)?;
+	let mut rows = statement.query([]).map_err(sqlite_error)?;
+	while let Some(row) = rows.next().map_err(sqlite_error)? {
+		let id: i64 = row.get(0).map_err(sqlite_error)?;
+		let identifier: String = row.get(1).map_err(sqlite_error)?;
+		let specific_path: Option<String> = row.get(2).map_err(sqlite_error)?;
+		let relative_path: String = row.get(3).map_err(sqlite_error)?;
+		let name: String = row.get(4).map_err(sqlite_error)?;
+		let size: Option<i64> = row.get(5).map_err(sqlite_error)?;
+		// -1 means the image wasn't rated
+		let rating: Option<i32> = row.get(6).map_err(sqlite_error)?;
+
+		entries.insert(
+			id,
+			CatalogEntry {
+				path: image_path(
+					&identifier,
+					specific_path.as_deref().unwrap_or_default(),
+					&relative_path,
+					&name,
+				),
+				size: size.and_then(|size| size.try_into().ok()),
+				rating: rating.filter(|rating| *rating >= 0),
+				..Default::default()
+			},
+		);
+	}
+
+	let mut tags = HashMap::new();
+	let mut statement = connection.prepare(TAGS_QUERY).map_err(sqlite_error)?;
+	let mut rows = statement.query([]).map_err(sqlite_error)?;
+	while let Some(row) = rows.next().map_err(sqlite_error)? {
+		let id: i64 = row.get(0).map_err(sqlite_error)?;
+		let parent_id: i64 = row.get(1).map_err(sqlite_error)?;
+		let name: String = row.get(2).map_err(sqlite_error)?;
+
+		tags.insert(id, (parent_id, name));
+	}
+
+	let mut statement = connection
+		.prepare(IMAGE_TAGS_QUERY)
+		.map_err(sqlite_error)?;
+	let mut rows = statement.query([]).map_err(sqlite_error)?;
+	while let Some(row) = rows.next().map_err(sqlite_error)? {
+		let image_id: i64 = row.get(0).map_err(sqlite_error)?;
+		let tag_id: i64 = row.get(1).map_err(sqlite_error)?;
+
+		let (Some(entry), Some((_, name))) = (entries.get_mut(&image_id), tags.get(&tag_id)) else {
+			continue;
+		};
+
+		if is_internal_tag(&tags, tag_id) {
+			if let Some(color) = name.strip_prefix(COLOR_LABEL_PREFIX) {
+				if color != "None" {
+					entry.label = Some(color.to_string());
+				}
+			}
+		} else {
+			entry.tags.push(name.clone());
+		}
+	}
+
+	let mut statement = connection.prepare(HISTORY_QUERY).map_err(sqlite_error)?;
+	let mut rows = statement.query([]).map_err(sqlite_error)?;
+	while let Some(row) = rows.next().map_err(sqlite_error)? {
+		let image_id: i64 = row.get(0).map_err(sqlite_error)?;
+		let history: String = row.get(1).map_err(sqlite_error)?;
+
+		if let Some(entry) = entries.get_mut(&image_id) {
+			entry.edits = Some(json!({ "tool": "digikam", "history": history }).to_string());
+		}
+	}
+
+	Ok(entries.into_values().collect())
+}
+
+fn is_internal_tag(tags: &HashMap<i64, (i64, String)>, mut tag_id: i64) -> bool {
+	// Parent ids are checked against the number of tags, in case of a broken hierarchy with cycles
+	for _ in 0..=tags.len() {
+		match tags.get(&tag_id) {
+			Some((_, name)) if name == INTERNAL_TAGS_ROOT => return true,
+			Some((parent_id, _)) => tag_id = *parent_id,
+			None => return false,
+		}
+	}
+
+	false
+}
+
+/// Album roots are identified like `volumeid:?path=%2Fhome%2Fuser%2FPictures` when they are a
+/// plain directory, or by the uuid of their volume, which we can't map to a mount point. In the
+/// latter case we keep the path inside the volume, to be matched by name and size later on.
+fn image_path(identifier: &str, specific_path: &str, relative_path: &str, name: &str) -> PathBuf {
+	let root = identifier
+		.split_once('?')
+		.and_then(|(_, query)| {
+			query
+				.split('&')
+				.find_map(|parameter| parameter.strip_prefix("path="))
+		})
+		.map(|root| percent_decode_str(root).decode_utf8_lossy().into_owned())
+		.unwrap_or_default();
+
+	[
+		root.as_str(),
+		specific_path.trim_start_matches('/'),
+		relative_path.trim_start_matches('/'),
+		name,
+	]
+	.into_iter()
+	.filter(|part| !part.is_empty())
+	.fold(PathBuf::new(), |path, part| path.join(part))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn album_root_paths() {
+		assert_eq!(
+			image_path(
+				"volumeid:?path=%2Fhome%2Fuser%2FPictures",
+				"/",
+				"/2019/Trip",
+				"IMG_0001.jpg"
+			),
+			PathBuf::from("/home/user/Pictures/2019/Trip/IMG_0001.jpg")
+		);
+		assert_eq!(
+			image_path(
+				"volumeid:?uuid=3a1f2c&fileuuid=b71e",
+				"/Photos",
+				"/",
+				"IMG_0002.jpg"
+			),
+			PathBuf::from("Photos/IMG_0002.jpg")
+		);
+	}
+
+	#[test]
+	fn internal_tags() {
+		let tags = HashMap::from([
+			(1, (0, INTERNAL_TAGS_ROOT.to_string())),
+			(2, (1, "Color Label Red".to_string())),
+			(3, (0, "Holidays".to_string())),
+			(4, (3, "Beach".to_string())),
+			(5, (6, "Cycle".to_string())),
+			(6, (5, "Cycle".to_string())),
+		]);
+
+		assert!(is_internal_tag(&tags, 2));
+		assert!(!is_internal_tag(&tags, 4));
+		assert!(!is_internal_tag(&tags, 5));
+	}
+}
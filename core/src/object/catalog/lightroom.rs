@@ -0,0 +1,98 @@
+use std::{collections::HashMap, path::Path};
+
+use serde_json::json;
+
+use super::{open_catalog, CatalogEntry, CatalogImportError};
+
+const IMAGES_QUERY: &str = "
+	SELECT i.id_local,
+		rf.absolutePath || fo.pathFromRoot || f.baseName
+			|| CASE WHEN f.extension <> '' THEN '.' || f.extension ELSE '' END,
+		i.rating,
+		i.colorLabels
+	FROM Adobe_images i
+	JOIN AgLibraryFile f ON i.rootFile = f.id_local
+	JOIN AgLibraryFolder fo ON f.folder = fo.id_local
+	JOIN AgLibraryRootFolder rf ON fo.rootFolder = rf.id_local";
+
+const KEYWORDS_QUERY: &str = "
+	SELECT ki.image, k.name
+	FROM AgLibraryKeywordImage ki
+	JOIN AgLibraryKeyword k ON ki.tag = k.id_local
+	WHERE k.name IS NOT NULL";
+
+// Smart collections are queries over the catalog, only regular collections are imported
+const COLLECTIONS_QUERY: &str = "
+	SELECT ci.image, c.name
+	FROM AgLibraryCollectionImage ci
+	JOIN AgLibraryCollection c ON ci.collection = c.id_local
+	WHERE c.creationId = 'com.adobe.ag.library.collection' AND c.name IS NOT NULL";
+
+const DEVELOP_SETTINGS_QUERY: &str = "
+	SELECT image, text
+	FROM Adobe_imageDevelopSettings
+	WHERE hasDevelopAdjustmentsEx > 0 AND text IS NOT NULL";
+
+pub(super) fn read_catalog(path: &Path) -> Result<Vec<CatalogEntry>, CatalogImportError> {
+	let connection = open_catalog(path)?;
+	let sqlite_error = |source| CatalogImportError::Sqlite {
+		path: path.into(),
+		source,
+	};
+
+	let mut entries = HashMap::new();
+
+	let mut statement = connection.prepare(IMAGES_QUERY).map_err(sqlite_error)?;
+	let mut rows = statement.query([]).map_err(sqlite_error)?;
+	while let Some(row) = rows.next().map_err(sqlite_error)? {
+		let id: i64 = row.get(0).map_err(sqlite_error)?;
+		let path: String = row.get(1).map_err(sqlite_error)?;
+		let rating: Option<f64> = row.get(2).map_err(sqlite_error)?;
+		let label: Option<String> = row.get(3).map_err(sqlite_error)?;
+
+		entries.insert(
+			id,
+			CatalogEntry {
+				path: path.into(),
+				rating: rating.map(|rating| rating.round() as i32),
+				label: label.filter(|label| !label.is_empty()),
+				..Default::default()
+			},
+		);
+	}
+
+	for (query, field) in [
+		(KEYWORDS_QUERY, Field::Tags),
+		(COLLECTIONS_QUERY, Field::Collections),
+		(DEVELOP_SETTINGS_QUERY, Field::Edits),
+	] {
+		let mut statement = connection.prepare(query).map_err(sqlite_error)?;
+		let mut rows = statement.query([]).map_err(sqlite_error)?;
+		while let Some(row) = rows.next().map_err(sqlite_error)? {
+			let id: i64 = row.get(0).map_err(sqlite_error)?;
+			let value: String = row.get(1).map_err(sqlite_error)?;
+
+			let Some(entry) = entries.get_mut(&id) else {
+				continue;
+			};
+
+			match field {
+				Field::Tags => entry.tags.push(value),
+				Field::Collections => entry.collections.push(value),
+				Field::Edits => {
+					entry.edits = Some(
+						json!({ "tool": "lightroom", "develop_settings": value }).to_string(),
+					)
+				}
+			}
+		}
+	}
+
+	Ok(entries.into_values().collect())
+}
+
+enum Field {
+	Tags,
+	Collections,
+	Edits,
+}
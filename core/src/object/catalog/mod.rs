@@ -0,0 +1,450 @@
+//! Importers for the catalogs of photo management tools, so years of organization aren't lost when
+//! moving to Spacedrive. Catalog entries are matched to the objects of a location by their path,
+//! falling back to their name and size when the catalog was made with the files somewhere else.
+//!
+//! | Catalog      | Tags     | Collections | Ratings | Color labels | Edits            |
+//! |--------------|----------|-------------|---------|--------------|------------------|
+//! | Lightroom    | Keywords | Spaces      | Yes     | Labels       | Develop settings |
+//! | digiKam      | Tags     | -           | Yes     | Labels       | Version history  |
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{file_path_helper::IsolatedFilePathData, find_location, LocationError},
+	object::{label::find_or_create_label, tag::find_or_create_tag},
+	prisma::{
+		file_path, label, label_on_object, location, object, object_in_space, space, tag,
+		tag_on_object,
+	},
+	util::db::maybe_missing,
+};
+
+use std::{
+	collections::{HashMap, HashSet},
+	hash::Hash,
+	path::{Path, PathBuf},
+};
+
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::task::spawn_blocking;
+use tracing::{info, trace};
+use uuid::Uuid;
+
+mod digikam;
+mod lightroom;
+
+const ENTRIES_PER_STEP: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum CatalogImportError {
+	#[error("failed to read catalog <path='{}'>: {source}", .path.display())]
+	Sqlite {
+		path: Box<Path>,
+		source: rusqlite::Error,
+	},
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+pub enum CatalogKind {
+	/// A Lightroom Classic `.lrcat` catalog
+	Lightroom,
+	/// A digiKam `digikam4.db` database
+	Digikam,
+}
+
+/// What a catalog knows about a single file
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CatalogEntry {
+	path: PathBuf,
+	size: Option<u64>,
+	tags: Vec<String>,
+	collections: Vec<String>,
+	rating: Option<i32>,
+	label: Option<String>,
+	edits: Option<String>,
+}
+
+pub struct CatalogImporterJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct CatalogImporterJobInit {
+	pub location_id: location::id::Type,
+	pub catalog_path: PathBuf,
+	pub kind: CatalogKind,
+}
+
+impl JobInitData for CatalogImporterJobInit {
+	type Job = CatalogImporterJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CatalogImporterJobReport {
+	entries_count: usize,
+	matched_count: usize,
+	unmatched_count: usize,
+	created_tags_count: usize,
+	created_spaces_count: usize,
+	created_labels_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CatalogImporterJobData {
+	location_path: PathBuf,
+	tag_ids: HashMap<String, tag::id::Type>,
+	space_ids: HashMap<String, space::id::Type>,
+	label_ids: HashMap<String, label::id::Type>,
+	report: CatalogImporterJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for CatalogImporterJob {
+	type Init = CatalogImporterJobInit;
+	type Data = CatalogImporterJobData;
+	type Step = Vec<CatalogEntry>;
+
+	const NAME: &'static str = "catalog_importer";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location = find_location(&ctx.library, state.init.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+
+		let entries = spawn_blocking({
+			let catalog_path = state.init.catalog_path.clone();
+			let kind = state.init.kind;
+			move || match kind {
+				CatalogKind::Lightroom => lightroom::read_catalog(&catalog_path),
+				CatalogKind::Digikam => digikam::read_catalog(&catalog_path),
+			}
+		})
+		.await??;
+
+		state.data = Some(CatalogImporterJobData {
+			location_path: maybe_missing(&location.path, "location.path")?.into(),
+			tag_ids: HashMap::new(),
+			space_ids: HashMap::new(),
+			label_ids: HashMap::new(),
+			report: CatalogImporterJobReport {
+				entries_count: entries.len(),
+				..Default::default()
+			},
+		});
+
+		let mut entries = entries.into_iter().peekable();
+		while entries.peek().is_some() {
+			state
+				.steps
+				.push_back(entries.by_ref().take(ENTRIES_PER_STEP).collect());
+		}
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+		let entries = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		for entry in entries {
+			let maybe_object_id =
+				find_object(&ctx.library, location_id, &data.location_path, entry).await?;
+
+			let Some(object_id) = maybe_object_id else {
+				trace!("No match for catalog entry {}", entry.path.display());
+				data.report.unmatched_count += 1;
+				continue;
+			};
+
+			apply_entry(&ctx.library, data, object_id, entry).await?;
+			data.report.matched_count += 1;
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing catalog importer job: {report:?}");
+
+		invalidate_query!(ctx.library, "search.paths");
+		invalidate_query!(ctx.library, "search.objects");
+		invalidate_query!(ctx.library, "tags.list");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+fn open_catalog(path: &Path) -> Result<Connection, CatalogImportError> {
+	// Read only, so we can't ever damage a catalog, even if its tool is running
+	Connection::open_with_flags(
+		path,
+		OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+	)
+	.map_err(|source| CatalogImportError::Sqlite {
+		path: path.into(),
+		source,
+	})
+}
+
+async fn find_object(
+	library: &Library,
+	location_id: location::id::Type,
+	location_path: &Path,
+	entry: &CatalogEntry,
+) -> Result<Option<object::id::Type>, JobError> {
+	if entry.path.starts_with(location_path) {
+		let iso_file_path =
+			IsolatedFilePathData::new(location_id, location_path, &entry.path, false)
+				.map_err(LocationError::from)?;
+
+		if let Some(file_path) = library
+			.db
+			.file_path()
+			.find_unique((&iso_file_path).into())
+			.select(file_path::select!({ object_id }))
+			.exec()
+			.await?
+		{
+			return Ok(file_path.object_id);
+		}
+	}
+
+	// The files may have been moved since the catalog was made, so we look for a single file with
+	// the same name and size in the location, as it's very likely to be the same file
+	let (Some(name), extension) = split_file_name(&entry.path) else {
+		return Ok(None);
+	};
+
+	let mut params = vec![
+		file_path::location_id::equals(Some(location_id)),
+		file_path::is_dir::equals(Some(false)),
+		file_path::name::equals(Some(name)),
+		file_path::extension::equals(Some(extension)),
+	];
+	if let Some(size) = entry.size {
+		params.push(file_path::size_in_bytes::equals(Some(size.to_string())));
+	}
+
+	let candidates = library
+		.db
+		.file_path()
+		.find_many(params)
+		.select(file_path::select!({ object_id }))
+		.take(2)
+		.exec()
+		.await?;
+
+	Ok(match candidates.as_slice() {
+		[file_path] => file_path.object_id,
+		_ => None,
+	})
+}
+
+fn split_file_name(path: &Path) -> (Option<String>, String) {
+	let extension = path
+		.extension()
+		.and_then(|extension| extension.to_str())
+		.unwrap_or_default()
+		.to_lowercase();
+	let name = path
+		.file_stem()
+		.and_then(|name| name.to_str())
+		.map(str::to_string);
+
+	(name, extension)
+}
+
+async fn apply_entry(
+	library: &Library,
+	data: &mut CatalogImporterJobData,
+	object_id: object::id::Type,
+	entry: &CatalogEntry,
+) -> Result<(), JobError> {
+	let Library { db, .. } = library;
+
+	let mut object_params = vec![];
+	if let Some(rating) = entry.rating {
+		object_params.push(object::rating::set(Some(rating.clamp(0, 5))));
+	}
+	if let Some(edits) = &entry.edits {
+		object_params.push(object::edit_metadata::set(Some(edits.clone())));
+	}
+	if !object_params.is_empty() {
+		db.object()
+			.update(object::id::equals(object_id), object_params)
+			.exec()
+			.await?;
+	}
+
+	if !entry.tags.is_empty() {
+		let existing = db
+			.tag_on_object()
+			.find_many(vec![tag_on_object::object_id::equals(object_id)])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|tag_on_object| tag_on_object.tag_id)
+			.collect::<HashSet<_>>();
+
+		let mut new_tag_ids = vec![];
+		for name in &entry.tags {
+			let tag_id = tag_id_for(library, data, name).await?;
+			if !existing.contains(&tag_id) && !new_tag_ids.contains(&tag_id) {
+				new_tag_ids.push(tag_id);
+			}
+		}
+
+		db.tag_on_object()
+			.create_many(
+				new_tag_ids
+					.into_iter()
+					.map(|tag_id| tag_on_object::CreateUnchecked {
+						tag_id,
+						object_id,
+						_params: vec![],
+					})
+					.collect(),
+			)
+			.exec()
+			.await?;
+	}
+
+	if !entry.collections.is_empty() {
+		let existing = db
+			.object_in_space()
+			.find_many(vec![object_in_space::object_id::equals(object_id)])
+			.exec()
+			.await?
+			.into_iter()
+			.map(|object_in_space| object_in_space.space_id)
+			.collect::<HashSet<_>>();
+
+		let mut new_space_ids = vec![];
+		for name in &entry.collections {
+			let space_id = match data.space_ids.get(name) {
+				Some(space_id) => *space_id,
+				None => {
+					let space_id = match db
+						.space()
+						.find_first(vec![space::name::equals(Some(name.clone()))])
+						.exec()
+						.await?
+					{
+						Some(space) => space.id,
+						None => {
+							data.report.created_spaces_count += 1;
+							db.space()
+								.create(
+									Uuid::new_v4().as_bytes().to_vec(),
+									vec![space::name::set(Some(name.clone()))],
+								)
+								.exec()
+								.await?
+								.id
+						}
+					};
+
+					data.space_ids.insert(name.clone(), space_id);
+					space_id
+				}
+			};
+
+			if !existing.contains(&space_id) && !new_space_ids.contains(&space_id) {
+				new_space_ids.push(space_id);
+			}
+		}
+
+		db.object_in_space()
+			.create_many(
+				new_space_ids
+					.into_iter()
+					.map(|space_id| object_in_space::CreateUnchecked {
+						space_id,
+						object_id,
+						_params: vec![],
+					})
+					.collect(),
+			)
+			.exec()
+			.await?;
+	}
+
+	if let Some(name) = &entry.label {
+		let label_id = match data.label_ids.get(name) {
+			Some(label_id) => *label_id,
+			None => {
+				let (label_id, created) = find_or_create_label(db, name).await?;
+				if created {
+					data.report.created_labels_count += 1;
+				}
+
+				data.label_ids.insert(name.clone(), label_id);
+				label_id
+			}
+		};
+
+		if db
+			.label_on_object()
+			.find_unique(label_on_object::label_id_object_id(label_id, object_id))
+			.exec()
+			.await?
+			.is_none()
+		{
+			db.label_on_object()
+				.create_unchecked(label_id, object_id, vec![])
+				.exec()
+				.await?;
+		}
+	}
+
+	Ok(())
+}
+
+async fn tag_id_for(
+	library: &Library,
+	data: &mut CatalogImporterJobData,
+	name: &str,
+) -> Result<tag::id::Type, JobError> {
+	if let Some(tag_id) = data.tag_ids.get(name) {
+		return Ok(*tag_id);
+	}
+
+	let (tag_id, created) = find_or_create_tag(library, name).await?;
+	if created {
+		data.report.created_tags_count += 1;
+	}
+
+	data.tag_ids.insert(name.to_string(), tag_id);
+
+	Ok(tag_id)
+}
@@ -0,0 +1,812 @@
+//! Suggestions of what to remove to free space, found by a job going through the index: huge files
+//! nobody opens, old downloads, copies of the same file, empty directories and old screenshots.
+//! They're ranked by the space removing them would free, and each one becomes deletion jobs, one
+//! for each location its files are in, with a single call.
+//!
+//! Only the locations of this device are analyzed, as only their files can be deleted from here.
+//! Files are "not opened" when no open was recorded lately, which with access tracking turned off
+//! means files not modified lately either, see `object::access`. A file only shows up in the first
+//! suggestion it fits, so applying two of them never deletes it twice.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::file_path_helper::{file_path_for_cleanup, IsolatedFilePathData},
+	object::fs::delete::FileDeleterJobInit,
+	prisma::{cleanup_suggestion, file_path, location, PrismaClient},
+	util::db::chain_optional_iter,
+};
+
+use sd_file_ext::kind::ObjectKind;
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::Path,
+};
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use int_enum::IntEnum;
+use prisma_client_rust::{or, raw, PrismaValue, QueryError};
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tracing::info;
+
+const HUGE_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+const UNOPENED_DAYS: i64 = 180;
+const OLD_DOWNLOAD_DAYS: i64 = 90;
+const STALE_SCREENSHOT_DAYS: i64 = 30;
+/// Copies of smaller files aren't worth going through
+const MIN_DUPLICATE_SIZE: u64 = 1024 * 1024;
+/// Huge files and duplicate sets are suggested one by one, only the largest ones are kept
+const MAX_SUGGESTIONS_PER_KIND: usize = 100;
+const SAMPLE_LEN: usize = 10;
+
+/// Names screenshots and screen recordings get on macOS, Windows, GNOME and KDE, in a few languages
+const SCREENSHOT_PREFIXES: &[&str] = &[
+	"screenshot",
+	"screen shot",
+	"screen recording",
+	"capture d’écran",
+	"capture d'écran",
+	"bildschirmfoto",
+	"schermafbeelding",
+	"captura de pantalla",
+	"istantanea schermo",
+];
+
+#[derive(Error, Debug)]
+pub enum CleanupError {
+	#[error("cleanup suggestion not found <id='{0}'>")]
+	NotFound(cleanup_suggestion::id::Type),
+	#[error("the files of the cleanup suggestion are all gone")]
+	NothingLeft,
+	#[error("invalid file path ids of cleanup suggestion: {0}")]
+	InvalidFilePaths(#[from] serde_json::Error),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<CleanupError> for rspc::Error {
+	fn from(err: CleanupError) -> Self {
+		match err {
+			CleanupError::JobManager(e) => e.into(),
+			CleanupError::NotFound(_) | CleanupError::NothingLeft => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			CleanupError::InvalidFilePaths(_) | CleanupError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(IntEnum, Serialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum CleanupKind {
+	/// A file of a gigabyte or more not opened nor modified for half a year
+	HugeUnopened = 0,
+	/// The files of a downloads directory or location not opened for three months
+	OldDownloads = 1,
+	/// Every copy of a file but the oldest one
+	Duplicates = 2,
+	/// Directories with nothing in them but other empty directories
+	EmptyDirectories = 3,
+	/// Screenshots and screen recordings not opened for a month
+	StaleScreenshots = 4,
+}
+
+/// A suggestion before it's stored
+#[derive(Debug, PartialEq)]
+struct NewSuggestion {
+	kind: CleanupKind,
+	location_id: Option<location::id::Type>,
+	file_path_ids: Vec<file_path::id::Type>,
+	reclaimable: u64,
+}
+
+pub struct CleanupAnalyzerJob {}
+
+/// `CleanupAnalyzerJobInit` looks for suggestions in these locations, or in every location of this
+/// node when none are given. Their previous suggestions are replaced.
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct CleanupAnalyzerJobInit {
+	#[serde(default)]
+	#[specta(optional)]
+	pub location_ids: Vec<location::id::Type>,
+}
+
+impl JobInitData for CleanupAnalyzerJobInit {
+	type Job = CleanupAnalyzerJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CleanupAnalyzerJobReport {
+	suggestions_count: usize,
+	reclaimable_size_in_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CleanupAnalyzerJobData {
+	location_ids: Vec<location::id::Type>,
+	/// Files already part of a suggestion
+	suggested: HashSet<file_path::id::Type>,
+	report: CleanupAnalyzerJobReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CleanupAnalyzerJobStep {
+	Location(location::id::Type),
+	/// Copies are found across locations, once every location is done
+	Duplicates,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for CleanupAnalyzerJob {
+	type Init = CleanupAnalyzerJobInit;
+	type Data = CleanupAnalyzerJobData;
+	type Step = CleanupAnalyzerJobStep;
+
+	const NAME: &'static str = "cleanup_analyzer";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+
+		let location_ids = db
+			.location()
+			.find_many(chain_optional_iter(
+				[location::node_id::equals(Some(ctx.library.node_local_id))],
+				[(!state.init.location_ids.is_empty())
+					.then(|| location::id::in_vec(state.init.location_ids.clone()))],
+			))
+			.select(location::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|location| location.id)
+			.collect::<Vec<_>>();
+
+		db.cleanup_suggestion()
+			.delete_many(vec![cleanup_suggestion::location_id::in_vec(
+				location_ids.clone(),
+			)])
+			.exec()
+			.await?;
+		db.cleanup_suggestion()
+			.delete_many(vec![cleanup_suggestion::location_id::equals(None)])
+			.exec()
+			.await?;
+
+		state.steps = location_ids
+			.iter()
+			.copied()
+			.map(CleanupAnalyzerJobStep::Location)
+			.chain([CleanupAnalyzerJobStep::Duplicates])
+			.collect();
+
+		state.data = Some(CleanupAnalyzerJobData {
+			location_ids,
+			suggested: HashSet::new(),
+			report: CleanupAnalyzerJobReport::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+
+		let suggestions = match state.steps[0] {
+			CleanupAnalyzerJobStep::Location(location_id) => {
+				location_suggestions(db, location_id, Utc::now()).await?
+			}
+			CleanupAnalyzerJobStep::Duplicates => {
+				let data = extract_job_data!(state);
+				duplicate_suggestions(db, &data.location_ids, &data.suggested).await?
+			}
+		};
+
+		let data = extract_job_data_mut!(state);
+		for suggestion in &suggestions {
+			data.suggested.extend(&suggestion.file_path_ids);
+			data.report.reclaimable_size_in_bytes += suggestion.reclaimable;
+		}
+		data.report.suggestions_count += suggestions.len();
+
+		db.cleanup_suggestion()
+			.create_many(
+				suggestions
+					.into_iter()
+					.map(|suggestion| {
+						cleanup_suggestion::create_unchecked(
+							suggestion.kind.int_value(),
+							json!(suggestion.file_path_ids).to_string(),
+							suggestion.reclaimable.to_string(),
+							vec![cleanup_suggestion::location_id::set(suggestion.location_id)],
+						)
+					})
+					.collect(),
+			)
+			.exec()
+			.await?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing cleanup analyzer job: {report:?}");
+
+		invalidate_query!(ctx.library, "cleanup.suggestions");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+impl file_path_for_cleanup::Data {
+	fn size(&self) -> u64 {
+		self.size_in_bytes
+			.as_deref()
+			.and_then(|size| size.parse().ok())
+			.unwrap_or_default()
+	}
+
+	/// Not opened since `since`, nor modified when no open was ever recorded
+	fn unopened_since(&self, since: DateTime<Utc>) -> bool {
+		let since = DateTime::<FixedOffset>::from(since);
+
+		match self.object.as_ref().and_then(|object| object.date_accessed) {
+			Some(date_accessed) => date_accessed < since,
+			None => self.date_modified.map_or(true, |date| date < since),
+		}
+	}
+
+	fn created_before(&self, before: DateTime<Utc>) -> bool {
+		self.date_created
+			.or(self.date_modified)
+			.map_or(false, |date| date < DateTime::<FixedOffset>::from(before))
+	}
+
+	fn is_screenshot(&self) -> bool {
+		let name = self.name.as_deref().unwrap_or_default().to_lowercase();
+		let kind = self.object.as_ref().and_then(|object| object.kind);
+
+		SCREENSHOT_PREFIXES
+			.iter()
+			.any(|prefix| name.starts_with(prefix))
+			&& (kind == Some(ObjectKind::Image as i32)
+				|| kind == Some(ObjectKind::Video as i32)
+				|| matches!(
+					self.extension.as_deref().map(str::to_lowercase).as_deref(),
+					Some("png" | "jpg" | "jpeg" | "heic" | "webp" | "mov" | "mp4")
+				))
+	}
+}
+
+async fn location_suggestions(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+	now: DateTime<Utc>,
+) -> Result<Vec<NewSuggestion>, QueryError> {
+	let Some(location) = db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.select(location::select!({ path downloads_automation }))
+		.exec()
+		.await?
+	else {
+		return Ok(vec![]);
+	};
+
+	let downloads_location = location.downloads_automation == Some(true)
+		|| location.path.as_deref().map_or(false, |path| {
+			Path::new(path)
+				.file_name()
+				.map_or(false, |name| name.eq_ignore_ascii_case("downloads"))
+		});
+
+	let file_paths = db
+		.file_path()
+		.find_many(vec![file_path::location_id::equals(Some(location_id))])
+		.select(file_path_for_cleanup::select())
+		.exec()
+		.await?;
+
+	Ok(analyze_location(
+		location_id,
+		&file_paths,
+		downloads_location,
+		now,
+	))
+}
+
+/// The suggestions for the files of a location, `downloads_location` when all of it is downloads
+fn analyze_location(
+	location_id: location::id::Type,
+	file_paths: &[file_path_for_cleanup::Data],
+	downloads_location: bool,
+	now: DateTime<Utc>,
+) -> Vec<NewSuggestion> {
+	let mut suggestions = Vec::new();
+	let mut huge = Vec::new();
+	let mut screenshots = NewSuggestion {
+		kind: CleanupKind::StaleScreenshots,
+		location_id: Some(location_id),
+		file_path_ids: vec![],
+		reclaimable: 0,
+	};
+	let mut downloads = NewSuggestion {
+		kind: CleanupKind::OldDownloads,
+		location_id: Some(location_id),
+		file_path_ids: vec![],
+		reclaimable: 0,
+	};
+
+	for file_path in file_paths
+		.iter()
+		.filter(|file_path| file_path.is_dir != Some(true))
+	{
+		let size = file_path.size();
+		let in_downloads = downloads_location
+			|| file_path
+				.materialized_path
+				.as_deref()
+				.map_or(false, |path| path.to_lowercase().starts_with("/downloads/"));
+
+		let suggestion = if size >= HUGE_FILE_SIZE
+			&& file_path.unopened_since(now - Duration::days(UNOPENED_DAYS))
+		{
+			huge.push(NewSuggestion {
+				kind: CleanupKind::HugeUnopened,
+				location_id: Some(location_id),
+				file_path_ids: vec![file_path.id],
+				reclaimable: size,
+			});
+			continue;
+		} else if file_path.is_screenshot()
+			&& file_path.created_before(now - Duration::days(STALE_SCREENSHOT_DAYS))
+			&& file_path.unopened_since(now - Duration::days(STALE_SCREENSHOT_DAYS))
+		{
+			&mut screenshots
+		} else if in_downloads
+			&& file_path.created_before(now - Duration::days(OLD_DOWNLOAD_DAYS))
+			&& file_path.unopened_since(now - Duration::days(OLD_DOWNLOAD_DAYS))
+		{
+			&mut downloads
+		} else {
+			continue;
+		};
+
+		suggestion.file_path_ids.push(file_path.id);
+		suggestion.reclaimable += size;
+	}
+
+	huge.sort_by(|a, b| b.reclaimable.cmp(&a.reclaimable));
+	huge.truncate(MAX_SUGGESTIONS_PER_KIND);
+	suggestions.extend(huge);
+
+	let empty_directories = empty_directories(location_id, file_paths);
+	suggestions.extend(
+		[
+			screenshots,
+			downloads,
+			NewSuggestion {
+				kind: CleanupKind::EmptyDirectories,
+				location_id: Some(location_id),
+				file_path_ids: empty_directories,
+				reclaimable: 0,
+			},
+		]
+		.into_iter()
+		.filter(|suggestion| !suggestion.file_path_ids.is_empty()),
+	);
+
+	suggestions
+}
+
+/// Directories holding nothing but empty directories, only the topmost ones as deleting them
+/// deletes the ones inside
+fn empty_directories(
+	location_id: location::id::Type,
+	file_paths: &[file_path_for_cleanup::Data],
+) -> Vec<file_path::id::Type> {
+	// The root of the location is never suggested
+	let children_path = |file_path: &file_path_for_cleanup::Data| {
+		IsolatedFilePathData::try_from((location_id, file_path))
+			.ok()
+			.filter(|iso_file_path| !iso_file_path.is_root())
+			.and_then(|iso_file_path| iso_file_path.materialized_path_for_children())
+	};
+
+	let mut children = HashMap::<&str, Vec<&file_path_for_cleanup::Data>>::new();
+	for file_path in file_paths {
+		if let Some(path) = file_path.materialized_path.as_deref() {
+			children.entry(path).or_default().push(file_path);
+		}
+	}
+
+	let mut directories = file_paths
+		.iter()
+		.filter_map(|directory| Some((directory, children_path(directory)?)))
+		.collect::<Vec<_>>();
+	// The deepest first, so the emptiness of subdirectories is known before their parent's
+	directories.sort_by_key(|(_, path)| std::cmp::Reverse(path.matches('/').count()));
+
+	let mut empty = HashSet::new();
+	for (_, path) in &directories {
+		let is_empty = children.get(path.as_str()).map_or(true, |entries| {
+			entries
+				.iter()
+				.all(|entry| children_path(entry).map_or(false, |path| empty.contains(&path)))
+		});
+
+		if is_empty {
+			empty.insert(path.clone());
+		}
+	}
+
+	directories
+		.into_iter()
+		.filter(|(directory, path)| {
+			empty.contains(path)
+				&& !directory
+					.materialized_path
+					.as_ref()
+					.map_or(false, |parent| empty.contains(parent))
+		})
+		.map(|(directory, _)| directory.id)
+		.collect()
+}
+
+#[derive(Deserialize)]
+struct DuplicatedObject {
+	object_id: i32,
+}
+
+file_path::select!(file_path_for_duplicates {
+	id
+	object_id
+	size_in_bytes
+	date_created
+});
+
+/// Copies of the same files in the locations, keeping the oldest copy of each
+async fn duplicate_suggestions(
+	db: &PrismaClient,
+	location_ids: &[location::id::Type],
+	suggested: &HashSet<file_path::id::Type>,
+) -> Result<Vec<NewSuggestion>, QueryError> {
+	let object_ids = db
+		._query_raw::<DuplicatedObject>(raw!(
+			"SELECT object_id FROM file_path \
+			WHERE is_dir = 0 AND object_id IS NOT NULL \
+				AND CAST(size_in_bytes AS INTEGER) >= {} \
+				AND location_id IN (SELECT value FROM json_each({})) \
+			GROUP BY object_id HAVING COUNT(*) > 1",
+			PrismaValue::Int(MIN_DUPLICATE_SIZE as i64),
+			PrismaValue::String(json!(location_ids).to_string())
+		))
+		.exec()
+		.await?
+		.into_iter()
+		.map(|object| object.object_id)
+		.collect::<Vec<_>>();
+
+	if object_ids.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let file_paths = db
+		.file_path()
+		.find_many(vec![
+			file_path::object_id::in_vec(object_ids),
+			file_path::location_id::in_vec(location_ids.to_vec()),
+			file_path::is_dir::equals(Some(false)),
+		])
+		.select(file_path_for_duplicates::select())
+		.exec()
+		.await?;
+
+	let mut by_object = HashMap::<_, Vec<_>>::new();
+	for file_path in &file_paths {
+		by_object
+			.entry(file_path.object_id)
+			.or_default()
+			.push(file_path);
+	}
+
+	let mut suggestions = by_object
+		.into_values()
+		.filter_map(|mut copies| {
+			copies.sort_by_key(|copy| (copy.date_created, copy.id));
+			let size = copies[0]
+				.size_in_bytes
+				.as_deref()
+				.and_then(|size| size.parse::<u64>().ok())
+				.unwrap_or_default();
+
+			let file_path_ids = copies[1..]
+				.iter()
+				.map(|copy| copy.id)
+				.filter(|id| !suggested.contains(id))
+				.collect::<Vec<_>>();
+
+			(!file_path_ids.is_empty()).then(|| NewSuggestion {
+				kind: CleanupKind::Duplicates,
+				location_id: None,
+				reclaimable: size * file_path_ids.len() as u64,
+				file_path_ids,
+			})
+		})
+		.collect::<Vec<_>>();
+
+	suggestions.sort_by(|a, b| b.reclaimable.cmp(&a.reclaimable));
+	suggestions.truncate(MAX_SUGGESTIONS_PER_KIND);
+
+	Ok(suggestions)
+}
+
+file_path::select!(file_path_for_cleanup_sample {
+	id
+	location_id
+	materialized_path
+	name
+	extension
+	is_dir
+	size_in_bytes
+});
+
+#[derive(Serialize, Type, Debug)]
+pub struct CleanupSuggestion {
+	pub id: cleanup_suggestion::id::Type,
+	pub kind: CleanupKind,
+	/// `None` when the files are in different locations, like copies of a file
+	pub location_id: Option<location::id::Type>,
+	pub reclaimable_size_in_bytes: String,
+	pub files_count: u32,
+	/// The first few files, to show what the suggestion is about
+	pub sample: Vec<file_path_for_cleanup_sample::Data>,
+	pub date_created: DateTime<FixedOffset>,
+}
+
+/// The suggestions of the last analysis, the ones freeing the most space first. Suggestions in
+/// locked private locations are left out.
+pub async fn cleanup_suggestions(
+	library: &Library,
+) -> Result<Vec<CleanupSuggestion>, CleanupError> {
+	let db = &library.db;
+	let locked = library.private_locations.locked().await;
+	let visible = library.private_locations.visible_file_paths().await;
+
+	let mut suggestions = Vec::new();
+
+	for suggestion in db
+		.cleanup_suggestion()
+		.find_many(vec![or![
+			cleanup_suggestion::location_id::equals(None),
+			cleanup_suggestion::location_id::not_in_vec(locked)
+		]])
+		.exec()
+		.await?
+	{
+		let file_path_ids =
+			serde_json::from_str::<Vec<file_path::id::Type>>(&suggestion.file_path_ids)?;
+
+		let sample = db
+			.file_path()
+			.find_many(chain_optional_iter(
+				[file_path::id::in_vec(
+					file_path_ids.iter().take(SAMPLE_LEN).copied().collect(),
+				)],
+				[visible.clone()],
+			))
+			.select(file_path_for_cleanup_sample::select())
+			.exec()
+			.await?;
+
+		let Ok(kind) = CleanupKind::from_int(suggestion.kind) else {
+			continue;
+		};
+
+		suggestions.push((
+			suggestion
+				.reclaimable_size_in_bytes
+				.parse::<u64>()
+				.unwrap_or_default(),
+			CleanupSuggestion {
+				id: suggestion.id,
+				kind,
+				location_id: suggestion.location_id,
+				reclaimable_size_in_bytes: suggestion.reclaimable_size_in_bytes,
+				files_count: file_path_ids.len() as u32,
+				sample,
+				date_created: suggestion.date_created,
+			},
+		));
+	}
+
+	suggestions.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+	Ok(suggestions
+		.into_iter()
+		.map(|(_, suggestion)| suggestion)
+		.collect())
+}
+
+/// Deletes the files of a suggestion, which are gone from the suggestions then. Files deleted or
+/// moved since the analysis are skipped, returning how many deletion jobs were started.
+pub async fn apply_cleanup_suggestion(
+	library: &Library,
+	id: cleanup_suggestion::id::Type,
+) -> Result<u32, CleanupError> {
+	let db = &library.db;
+
+	let suggestion = db
+		.cleanup_suggestion()
+		.find_unique(cleanup_suggestion::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(CleanupError::NotFound(id))?;
+
+	let file_path_ids = serde_json::from_str(&suggestion.file_path_ids)?;
+
+	let file_paths = db
+		.file_path()
+		.find_many(chain_optional_iter(
+			[
+				file_path::id::in_vec(file_path_ids),
+				file_path::location::is(vec![location::node_id::equals(Some(
+					library.node_local_id,
+				))]),
+			],
+			[library.private_locations.visible_file_paths().await],
+		))
+		.select(file_path::select!({ id location_id }))
+		.exec()
+		.await?;
+
+	let mut by_location = HashMap::<_, Vec<_>>::new();
+	for file_path in file_paths {
+		if let Some(location_id) = file_path.location_id {
+			by_location
+				.entry(location_id)
+				.or_default()
+				.push(file_path.id);
+		}
+	}
+
+	if by_location.is_empty() {
+		dismiss_cleanup_suggestion(library, id).await?;
+		return Err(CleanupError::NothingLeft);
+	}
+
+	let jobs = by_location.len() as u32;
+	for (location_id, file_path_ids) in by_location {
+		library
+			.spawn_job(FileDeleterJobInit {
+				location_id,
+				file_path_ids,
+				with_groups: false,
+			})
+			.await?;
+	}
+
+	dismiss_cleanup_suggestion(library, id).await?;
+
+	Ok(jobs)
+}
+
+/// Forgets a suggestion until the next analysis
+pub async fn dismiss_cleanup_suggestion(
+	library: &Library,
+	id: cleanup_suggestion::id::Type,
+) -> Result<(), QueryError> {
+	library
+		.db
+		.cleanup_suggestion()
+		.delete_many(vec![cleanup_suggestion::id::equals(id)])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "cleanup.suggestions");
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(
+		id: i32,
+		path: &str,
+		name: &str,
+		is_dir: bool,
+		size: u64,
+		days_old: i64,
+	) -> file_path_for_cleanup::Data {
+		let date = Some((Utc::now() - Duration::days(days_old)).into());
+
+		file_path_for_cleanup::Data {
+			id,
+			is_dir: Some(is_dir),
+			materialized_path: Some(path.to_string()),
+			name: Some(name.to_string()),
+			extension: Some(if is_dir { "" } else { "png" }.to_string()),
+			size_in_bytes: Some(size.to_string()),
+			date_created: date,
+			date_modified: date,
+			object: None,
+		}
+	}
+
+	#[test]
+	fn files_go_to_the_first_suggestion_they_fit() {
+		let file_paths = [
+			entry(1, "/", "movie", false, 2 * HUGE_FILE_SIZE, 365),
+			entry(2, "/Downloads/", "Screenshot 2023-01-01", false, 10, 365),
+			entry(3, "/Downloads/", "setup", false, 20, 365),
+			entry(4, "/Downloads/", "recent", false, 30, 1),
+			entry(5, "/", "Downloads", true, 0, 365),
+		];
+
+		let suggestions = analyze_location(1, &file_paths, false, Utc::now());
+		let ids = |kind| {
+			suggestions
+				.iter()
+				.filter(|suggestion| suggestion.kind == kind)
+				.flat_map(|suggestion| suggestion.file_path_ids.clone())
+				.collect::<Vec<_>>()
+		};
+
+		assert_eq!(ids(CleanupKind::HugeUnopened), vec![1]);
+		assert_eq!(ids(CleanupKind::StaleScreenshots), vec![2]);
+		assert_eq!(ids(CleanupKind::OldDownloads), vec![3]);
+		assert!(ids(CleanupKind::EmptyDirectories).is_empty());
+	}
+
+	#[test]
+	fn only_topmost_empty_directories_are_suggested() {
+		let file_paths = [
+			entry(1, "/", "old", true, 0, 10),
+			entry(2, "/old/", "nested", true, 0, 10),
+			entry(3, "/old/nested/", "deeper", true, 0, 10),
+			entry(4, "/", "photos", true, 0, 10),
+			entry(5, "/photos/", "empty", true, 0, 10),
+			entry(6, "/photos/", "cat", false, 10, 10),
+		];
+
+		let mut empty = empty_directories(1, &file_paths);
+		empty.sort_unstable();
+
+		assert_eq!(empty, vec![1, 5]);
+	}
+}
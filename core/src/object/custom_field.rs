@@ -0,0 +1,618 @@
+//! Metadata fields users define for the objects of a library, for what Spacedrive has no field for,
+//! like the accession number or provenance of an item in an archive. A field has a type, text,
+//! number, date or one of a list of values, and objects have at most one value for each field.
+//!
+//! Fields and their values are synced. The value of a field for an object is identified by both of
+//! them, see [`value_pub_id`], so two devices setting it at the same time update the same record
+//! and the last one wins, like with any other field.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	prisma::{custom_field, custom_field_value, object, PrismaClient, SortOrder},
+	sync,
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub enum CustomFieldError {
+	#[error("custom field not found <id='{0}'>")]
+	NotFound(custom_field::id::Type),
+	#[error("a custom field needs a name")]
+	MissingName,
+	#[error("an enum field needs at least one option")]
+	MissingOptions,
+	#[error("value doesn't match the type of the field <expected='{0:?}'>")]
+	TypeMismatch(CustomFieldType),
+	#[error("value isn't one of the options of the field <value='{0}'>")]
+	UnknownOption(String),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<CustomFieldError> for rspc::Error {
+	fn from(err: CustomFieldError) -> Self {
+		match err {
+			CustomFieldError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			CustomFieldError::MissingName
+			| CustomFieldError::MissingOptions
+			| CustomFieldError::TypeMismatch(_)
+			| CustomFieldError::UnknownOption(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			CustomFieldError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[repr(i32)]
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomFieldType {
+	Text = 0,
+	Number = 1,
+	Date = 2,
+	/// One of the options of the field
+	Enum = 3,
+}
+
+impl CustomFieldType {
+	fn from_db(value: Option<i32>) -> Self {
+		match value {
+			Some(1) => Self::Number,
+			Some(2) => Self::Date,
+			Some(3) => Self::Enum,
+			_ => Self::Text,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "value")]
+pub enum CustomFieldValue {
+	Text(String),
+	Number(f64),
+	Date(DateTime<Utc>),
+	Enum(String),
+}
+
+impl CustomFieldValue {
+	fn field_type(&self) -> CustomFieldType {
+		match self {
+			Self::Text(_) => CustomFieldType::Text,
+			Self::Number(_) => CustomFieldType::Number,
+			Self::Date(_) => CustomFieldType::Date,
+			Self::Enum(_) => CustomFieldType::Enum,
+		}
+	}
+
+	/// The value in the columns of a value record, only the one of its type is set
+	fn columns(&self) -> (Option<String>, Option<f64>, Option<DateTime<Utc>>) {
+		match self {
+			Self::Text(text) | Self::Enum(text) => (Some(text.clone()), None, None),
+			Self::Number(number) => (None, Some(*number), None),
+			Self::Date(date) => (None, None, Some(*date)),
+		}
+	}
+
+	pub(super) fn from_record(
+		field_type: CustomFieldType,
+		value: &custom_field_value::Data,
+	) -> Option<Self> {
+		Some(match field_type {
+			CustomFieldType::Text => Self::Text(value.text.clone()?),
+			CustomFieldType::Number => Self::Number(value.number?),
+			CustomFieldType::Date => Self::Date(value.date?.into()),
+			CustomFieldType::Enum => Self::Enum(value.text.clone()?),
+		})
+	}
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct CustomField {
+	pub id: custom_field::id::Type,
+	pub name: String,
+	pub field_type: CustomFieldType,
+	/// The values an enum field can take
+	pub options: Vec<String>,
+}
+
+impl From<custom_field::Data> for CustomField {
+	fn from(data: custom_field::Data) -> Self {
+		Self {
+			id: data.id,
+			name: data.name.unwrap_or_default(),
+			field_type: CustomFieldType::from_db(data.field_type),
+			options: data
+				.options
+				.and_then(|options| serde_json::from_str(&options).ok())
+				.unwrap_or_default(),
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ObjectCustomField {
+	pub field: CustomField,
+	pub value: CustomFieldValue,
+}
+
+/// The pub_id of the value of a field for an object
+fn value_pub_id(field_pub_id: &[u8], object_pub_id: &[u8]) -> Vec<u8> {
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(field_pub_id);
+	hasher.update(object_pub_id);
+
+	let mut bytes = [0; 16];
+	bytes.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+
+	Uuid::from_bytes(bytes).as_bytes().to_vec()
+}
+
+pub async fn list_custom_fields(db: &PrismaClient) -> Result<Vec<CustomField>, QueryError> {
+	Ok(db
+		.custom_field()
+		.find_many(vec![])
+		.order_by(custom_field::name::order(SortOrder::Asc))
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+pub async fn object_custom_fields(
+	db: &PrismaClient,
+	object_id: object::id::Type,
+) -> Result<Vec<ObjectCustomField>, QueryError> {
+	Ok(db
+		.custom_field_value()
+		.find_many(vec![custom_field_value::object_id::equals(Some(object_id))])
+		.with(custom_field_value::field::fetch())
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|mut value| {
+			let field = CustomField::from(value.field.take().flatten().map(|field| *field)?);
+			let value = CustomFieldValue::from_record(field.field_type, &value)?;
+
+			Some(ObjectCustomField { field, value })
+		})
+		.collect())
+}
+
+#[derive(Type, Deserialize)]
+pub struct CustomFieldCreateArgs {
+	pub name: String,
+	pub field_type: CustomFieldType,
+	#[serde(default)]
+	pub options: Vec<String>,
+}
+
+impl CustomFieldCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<CustomField, CustomFieldError> {
+		let Library { db, sync, .. } = library;
+
+		let name = normalize_name(self.name)?;
+		let options = normalize_options(self.field_type, self.options)?;
+		let options = (!options.is_empty()).then(|| json!(options).to_string());
+
+		let pub_id = Uuid::new_v4().as_bytes().to_vec();
+		let date_created = Utc::now();
+
+		let created = sync
+			.write_op(
+				db,
+				sync.unique_shared_create(
+					sync::custom_field::SyncId {
+						pub_id: pub_id.clone(),
+					},
+					[
+						(custom_field::name::NAME, json!(name)),
+						(
+							custom_field::field_type::NAME,
+							json!(self.field_type as i32),
+						),
+						(custom_field::options::NAME, json!(options)),
+						(custom_field::date_created::NAME, json!(date_created)),
+					],
+				),
+				db.custom_field().create(
+					pub_id,
+					vec![
+						custom_field::name::set(Some(name)),
+						custom_field::field_type::set(Some(self.field_type as i32)),
+						custom_field::options::set(options),
+						custom_field::date_created::set(Some(date_created.into())),
+					],
+				),
+			)
+			.await?;
+
+		invalidate_query!(library, "customFields.list");
+
+		Ok(created.into())
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct CustomFieldUpdateArgs {
+	pub id: custom_field::id::Type,
+	#[specta(optional)]
+	pub name: Option<String>,
+	/// Replaces the options of an enum field, the values of objects which aren't one of them
+	/// anymore are kept
+	#[specta(optional)]
+	pub options: Option<Vec<String>>,
+}
+
+impl CustomFieldUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<CustomField, CustomFieldError> {
+		let Library { db, sync, .. } = library;
+
+		let field = find_custom_field(db, self.id).await?;
+		let field_type = CustomFieldType::from_db(field.field_type);
+
+		let name = self.name.map(normalize_name).transpose()?;
+		let options = self
+			.options
+			.map(|options| normalize_options(field_type, options))
+			.transpose()?
+			.map(|options| json!(options).to_string());
+
+		let (ops, params): (Vec<_>, Vec<_>) = [
+			name.map(|v| {
+				(
+					(custom_field::name::NAME, json!(v)),
+					custom_field::name::set(Some(v)),
+				)
+			}),
+			options.map(|v| {
+				(
+					(custom_field::options::NAME, json!(v)),
+					custom_field::options::set(Some(v)),
+				)
+			}),
+		]
+		.into_iter()
+		.flatten()
+		.map(|((k, v), param)| {
+			(
+				sync.shared_update(
+					sync::custom_field::SyncId {
+						pub_id: field.pub_id.clone(),
+					},
+					k,
+					v,
+				),
+				param,
+			)
+		})
+		.unzip();
+
+		if ops.is_empty() {
+			return Ok(field.into());
+		}
+
+		let updated = sync
+			.write_ops(
+				db,
+				(
+					ops,
+					db.custom_field()
+						.update(custom_field::id::equals(self.id), params),
+				),
+			)
+			.await?;
+
+		invalidate_query!(library, "customFields.list");
+		invalidate_query!(library, "customFields.getForObject");
+
+		Ok(updated.into())
+	}
+}
+
+/// Deletes a custom field along with its values
+pub async fn delete_custom_field(
+	library: &Library,
+	id: custom_field::id::Type,
+) -> Result<(), CustomFieldError> {
+	let Library { db, sync, .. } = library;
+
+	let field = find_custom_field(db, id).await?;
+
+	sync.write_op(
+		db,
+		sync.shared_delete(sync::custom_field::SyncId {
+			pub_id: field.pub_id,
+		}),
+		db.custom_field().delete(custom_field::id::equals(id)),
+	)
+	.await?;
+
+	invalidate_query!(library, "customFields.list");
+	invalidate_query!(library, "customFields.getForObject");
+	invalidate_query!(library, "search.objects");
+	invalidate_query!(library, "search.paths");
+
+	Ok(())
+}
+
+#[derive(Type, Deserialize)]
+pub struct SetCustomFieldArgs {
+	pub field_id: custom_field::id::Type,
+	pub object_ids: Vec<object::id::Type>,
+	/// `None` clears the field on the objects
+	pub value: Option<CustomFieldValue>,
+}
+
+impl SetCustomFieldArgs {
+	pub async fn set(self, library: &Library) -> Result<(), CustomFieldError> {
+		let Library { db, sync, .. } = library;
+
+		let field = find_custom_field(db, self.field_id).await?;
+		let field_type = CustomFieldType::from_db(field.field_type);
+
+		if let Some(value) = &self.value {
+			if value.field_type() != field_type {
+				return Err(CustomFieldError::TypeMismatch(field_type));
+			}
+
+			if let CustomFieldValue::Enum(option) = value {
+				if !CustomField::from(field.clone()).options.contains(option) {
+					return Err(CustomFieldError::UnknownOption(option.clone()));
+				}
+			}
+		}
+
+		let objects = db
+			.object()
+			.find_many(vec![object::id::in_vec(self.object_ids)])
+			.select(object::select!({ id pub_id }))
+			.exec()
+			.await?;
+
+		if objects.is_empty() {
+			return Ok(());
+		}
+
+		match self.value {
+			Some(value) => {
+				let (text, number, date) = value.columns();
+
+				sync.write_ops(
+					db,
+					objects
+						.into_iter()
+						.map(|object| {
+							let pub_id = value_pub_id(&field.pub_id, &object.pub_id);
+
+							(
+								sync.unique_shared_create(
+									sync::custom_field_value::SyncId {
+										pub_id: pub_id.clone(),
+									},
+									[
+										(
+											custom_field_value::field::NAME,
+											json!(sync::custom_field::SyncId {
+												pub_id: field.pub_id.clone(),
+											}),
+										),
+										(
+											custom_field_value::object::NAME,
+											json!(sync::object::SyncId {
+												pub_id: object.pub_id,
+											}),
+										),
+										(custom_field_value::text::NAME, json!(text)),
+										(custom_field_value::number::NAME, json!(number)),
+										(custom_field_value::date::NAME, json!(date)),
+									],
+								),
+								db.custom_field_value().upsert(
+									custom_field_value::pub_id::equals(pub_id.clone()),
+									custom_field_value::create(
+										pub_id,
+										vec![
+											custom_field_value::field::connect(
+												custom_field::id::equals(field.id),
+											),
+											custom_field_value::object::connect(
+												object::id::equals(object.id),
+											),
+											custom_field_value::text::set(text.clone()),
+											custom_field_value::number::set(number),
+											custom_field_value::date::set(date.map(Into::into)),
+										],
+									),
+									vec![
+										custom_field_value::text::set(text.clone()),
+										custom_field_value::number::set(number),
+										custom_field_value::date::set(date.map(Into::into)),
+									],
+								),
+							)
+						})
+						.unzip::<_, _, Vec<_>, Vec<_>>(),
+				)
+				.await?;
+			}
+			None => {
+				let values = db
+					.custom_field_value()
+					.find_many(vec![
+						custom_field_value::field_id::equals(Some(field.id)),
+						custom_field_value::object_id::in_vec(
+							objects.into_iter().map(|object| object.id).collect(),
+						),
+					])
+					.select(custom_field_value::select!({ id pub_id }))
+					.exec()
+					.await?;
+
+				if values.is_empty() {
+					return Ok(());
+				}
+
+				let (ops, ids): (Vec<_>, Vec<_>) = values
+					.into_iter()
+					.map(|value| {
+						(
+							sync.shared_delete(sync::custom_field_value::SyncId {
+								pub_id: value.pub_id,
+							}),
+							value.id,
+						)
+					})
+					.unzip();
+
+				sync.write_ops(
+					db,
+					(
+						ops,
+						db.custom_field_value()
+							.delete_many(vec![custom_field_value::id::in_vec(ids)]),
+					),
+				)
+				.await?;
+			}
+		}
+
+		invalidate_query!(library, "customFields.getForObject");
+		invalidate_query!(library, "search.objects");
+		invalidate_query!(library, "search.paths");
+
+		Ok(())
+	}
+}
+
+/// A condition on the value of a field, for searching objects by it
+#[derive(Deserialize, Type, Debug)]
+#[serde(tag = "type", content = "value")]
+pub enum CustomFieldCondition {
+	/// The object has a value for the field
+	IsSet,
+	Equals(CustomFieldValue),
+	/// Part of a text or enum value
+	Contains(String),
+	/// One of these options of an enum field
+	OneOf(Vec<String>),
+	NumberBetween {
+		min: Option<f64>,
+		max: Option<f64>,
+	},
+	DateBetween {
+		from: Option<DateTime<Utc>>,
+		to: Option<DateTime<Utc>>,
+	},
+}
+
+#[derive(Deserialize, Type, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomFieldFilter {
+	pub field_id: custom_field::id::Type,
+	pub condition: CustomFieldCondition,
+}
+
+impl CustomFieldFilter {
+	pub fn to_param(self) -> object::WhereParam {
+		use custom_field_value::*;
+
+		let mut params = vec![field_id::equals(Some(self.field_id))];
+
+		match self.condition {
+			CustomFieldCondition::IsSet => {}
+			CustomFieldCondition::Equals(value) => {
+				let (text_value, number_value, date_value) = value.columns();
+
+				params.extend([
+					text::equals(text_value),
+					number::equals(number_value),
+					date::equals(date_value.map(Into::into)),
+				]);
+			}
+			CustomFieldCondition::Contains(search) => {
+				params.push(text::contains(search));
+			}
+			CustomFieldCondition::OneOf(options) => {
+				params.push(text::in_vec(options));
+			}
+			CustomFieldCondition::NumberBetween { min, max } => {
+				params.extend(
+					[min.map(number::gte), max.map(number::lte)]
+						.into_iter()
+						.flatten(),
+				);
+			}
+			CustomFieldCondition::DateBetween { from, to } => {
+				params.extend(
+					[
+						from.map(|from| date::gte(from.into())),
+						to.map(|to| date::lte(to.into())),
+					]
+					.into_iter()
+					.flatten(),
+				);
+			}
+		}
+
+		object::custom_field_values::some(params)
+	}
+}
+
+async fn find_custom_field(
+	db: &PrismaClient,
+	id: custom_field::id::Type,
+) -> Result<custom_field::Data, CustomFieldError> {
+	db.custom_field()
+		.find_unique(custom_field::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(CustomFieldError::NotFound(id))
+}
+
+fn normalize_name(name: String) -> Result<String, CustomFieldError> {
+	let name = name.trim();
+
+	if name.is_empty() {
+		return Err(CustomFieldError::MissingName);
+	}
+
+	Ok(name.to_string())
+}
+
+/// Trimmed and without duplicates, in the order given. Only enum fields have options.
+fn normalize_options(
+	field_type: CustomFieldType,
+	options: Vec<String>,
+) -> Result<Vec<String>, CustomFieldError> {
+	if field_type != CustomFieldType::Enum {
+		return Ok(vec![]);
+	}
+
+	let mut normalized = Vec::<String>::with_capacity(options.len());
+	for option in options {
+		let option = option.trim();
+		if !option.is_empty() && !normalized.iter().any(|other| other == option) {
+			normalized.push(option.to_string());
+		}
+	}
+
+	if normalized.is_empty() {
+		return Err(CustomFieldError::MissingOptions);
+	}
+
+	Ok(normalized)
+}
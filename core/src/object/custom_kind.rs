@@ -0,0 +1,459 @@
+//! Kinds users define for the files `ObjectKind` has no name for, like CAD drawings, sample packs
+//! or ROMs, so they don't all end up as `Unknown`. A custom kind is a name, an icon and the
+//! extensions of its files.
+//!
+//! Custom kinds are kept in `object.kind` next to the built-in ones, offset by
+//! [`CUSTOM_KIND_OFFSET`], so searching by kind and download rules work the same for both. Only the
+//! objects the identifier couldn't tell the kind of get a custom kind, an extension `ObjectKind`
+//! knows keeps its kind.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	prisma::{custom_kind, custom_kind_extension, file_path, object, PrismaClient},
+	sync,
+};
+
+use sd_file_ext::kind::ObjectKind;
+
+use std::collections::{BTreeSet, HashMap};
+
+use prisma_client_rust::{operator::or, QueryError};
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Far above the values of `ObjectKind`, which may still get new variants
+pub const CUSTOM_KIND_OFFSET: i32 = 1000;
+
+#[derive(Error, Debug)]
+pub enum CustomKindError {
+	#[error("custom kind not found <id='{0}'>")]
+	NotFound(custom_kind::id::Type),
+	#[error("a custom kind needs a name")]
+	MissingName,
+	#[error("extension already belongs to another kind <extension='{0}'>")]
+	ExtensionTaken(String),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<CustomKindError> for rspc::Error {
+	fn from(err: CustomKindError) -> Self {
+		match err {
+			CustomKindError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			CustomKindError::MissingName | CustomKindError::ExtensionTaken(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			CustomKindError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+custom_kind::include!(custom_kind_with_extensions { extensions });
+
+#[derive(Serialize, Type, Debug)]
+pub struct CustomKind {
+	pub id: custom_kind::id::Type,
+	/// What objects of this kind hold in `kind`, to search them by
+	pub kind: i32,
+	pub name: String,
+	pub icon: Option<String>,
+	pub extensions: Vec<String>,
+}
+
+impl From<custom_kind_with_extensions::Data> for CustomKind {
+	fn from(data: custom_kind_with_extensions::Data) -> Self {
+		Self {
+			id: data.id,
+			kind: kind_value(data.id),
+			name: data.name,
+			icon: data.icon,
+			extensions: data
+				.extensions
+				.into_iter()
+				.map(|extension| extension.extension)
+				.collect(),
+		}
+	}
+}
+
+/// The value of `object.kind` for the objects of a custom kind
+pub fn kind_value(id: custom_kind::id::Type) -> i32 {
+	CUSTOM_KIND_OFFSET + id
+}
+
+pub async fn list_custom_kinds(db: &PrismaClient) -> Result<Vec<CustomKind>, QueryError> {
+	Ok(db
+		.custom_kind()
+		.find_many(vec![])
+		.include(custom_kind_with_extensions::include())
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+/// The custom kinds by extension, loaded once for a batch of files
+pub struct CustomKinds(HashMap<String, i32>);
+
+impl CustomKinds {
+	pub async fn load(db: &PrismaClient) -> Result<Self, QueryError> {
+		Ok(Self(
+			db.custom_kind_extension()
+				.find_many(vec![])
+				.exec()
+				.await?
+				.into_iter()
+				.map(|extension| (extension.extension, kind_value(extension.kind_id)))
+				.collect(),
+		))
+	}
+
+	/// The value of `object.kind` for a file of this kind and extension
+	pub fn resolve(&self, kind: ObjectKind, extension: &str) -> i32 {
+		match kind {
+			ObjectKind::Unknown => self
+				.0
+				.get(&extension.to_lowercase())
+				.copied()
+				.unwrap_or(kind as i32),
+			kind => kind as i32,
+		}
+	}
+}
+
+/// Like [`CustomKinds::resolve`] for a single file, only going to the database when `ObjectKind`
+/// doesn't know the file
+pub async fn resolve_kind(
+	db: &PrismaClient,
+	kind: ObjectKind,
+	extension: &str,
+) -> Result<i32, QueryError> {
+	if kind != ObjectKind::Unknown || extension.is_empty() {
+		return Ok(kind as i32);
+	}
+
+	Ok(db
+		.custom_kind_extension()
+		.find_unique(custom_kind_extension::extension::equals(
+			extension.to_lowercase(),
+		))
+		.exec()
+		.await?
+		.map_or(kind as i32, |extension| kind_value(extension.kind_id)))
+}
+
+#[derive(Type, Deserialize)]
+pub struct CustomKindCreateArgs {
+	pub name: String,
+	/// Name of an icon of the interface
+	pub icon: Option<String>,
+	pub extensions: Vec<String>,
+}
+
+impl CustomKindCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<CustomKind, CustomKindError> {
+		let db = &library.db;
+
+		let name = self.name.trim().to_string();
+		if name.is_empty() {
+			return Err(CustomKindError::MissingName);
+		}
+
+		let extensions = normalize_extensions(self.extensions);
+		ensure_extensions_available(db, None, &extensions).await?;
+
+		let created = db
+			.custom_kind()
+			.create(
+				Uuid::new_v4().as_bytes().to_vec(),
+				name,
+				vec![custom_kind::icon::set(self.icon)],
+			)
+			.exec()
+			.await?;
+
+		set_extensions(db, created.id, &extensions).await?;
+		classify(library, created.id, extensions).await?;
+
+		invalidate_query!(library, "kinds.list");
+
+		find_custom_kind(db, created.id).await
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct CustomKindUpdateArgs {
+	pub id: custom_kind::id::Type,
+	#[specta(optional)]
+	pub name: Option<String>,
+	#[specta(optional)]
+	pub icon: Option<String>,
+	/// Replaces the extensions of the kind
+	#[specta(optional)]
+	pub extensions: Option<Vec<String>>,
+}
+
+impl CustomKindUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<CustomKind, CustomKindError> {
+		let db = &library.db;
+
+		let name = match self.name.map(|name| name.trim().to_string()) {
+			Some(name) if name.is_empty() => return Err(CustomKindError::MissingName),
+			name => name,
+		};
+
+		find_custom_kind(db, self.id).await?;
+
+		db.custom_kind()
+			.update(
+				custom_kind::id::equals(self.id),
+				[
+					name.map(custom_kind::name::set),
+					self.icon.map(Some).map(custom_kind::icon::set),
+				]
+				.into_iter()
+				.flatten()
+				.collect(),
+			)
+			.exec()
+			.await?;
+
+		if let Some(extensions) = self.extensions {
+			let extensions = normalize_extensions(extensions);
+			ensure_extensions_available(db, Some(self.id), &extensions).await?;
+
+			set_extensions(db, self.id, &extensions).await?;
+
+			// Objects which lost their extension are only told apart by classifying again
+			declassify(library, self.id).await?;
+			classify(library, self.id, extensions).await?;
+		}
+
+		invalidate_query!(library, "kinds.list");
+
+		find_custom_kind(db, self.id).await
+	}
+}
+
+/// Deletes a custom kind, its objects are left without a kind again
+pub async fn delete_custom_kind(
+	library: &Library,
+	id: custom_kind::id::Type,
+) -> Result<(), CustomKindError> {
+	find_custom_kind(&library.db, id).await?;
+
+	declassify(library, id).await?;
+
+	library
+		.db
+		.custom_kind()
+		.delete(custom_kind::id::equals(id))
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "kinds.list");
+
+	Ok(())
+}
+
+async fn find_custom_kind(
+	db: &PrismaClient,
+	id: custom_kind::id::Type,
+) -> Result<CustomKind, CustomKindError> {
+	db.custom_kind()
+		.find_unique(custom_kind::id::equals(id))
+		.include(custom_kind_with_extensions::include())
+		.exec()
+		.await?
+		.map(Into::into)
+		.ok_or(CustomKindError::NotFound(id))
+}
+
+/// Lowercased, without their leading dot and without duplicates
+fn normalize_extensions(extensions: Vec<String>) -> Vec<String> {
+	extensions
+		.into_iter()
+		.map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+		.filter(|extension| !extension.is_empty())
+		.collect::<BTreeSet<_>>()
+		.into_iter()
+		.collect()
+}
+
+async fn ensure_extensions_available(
+	db: &PrismaClient,
+	id: Option<custom_kind::id::Type>,
+	extensions: &[String],
+) -> Result<(), CustomKindError> {
+	let taken = db
+		.custom_kind_extension()
+		.find_first(
+			[
+				Some(custom_kind_extension::extension::in_vec(
+					extensions.to_vec(),
+				)),
+				id.map(custom_kind_extension::kind_id::not),
+			]
+			.into_iter()
+			.flatten()
+			.collect(),
+		)
+		.exec()
+		.await?;
+
+	match taken {
+		Some(taken) => Err(CustomKindError::ExtensionTaken(taken.extension)),
+		None => Ok(()),
+	}
+}
+
+async fn set_extensions(
+	db: &PrismaClient,
+	id: custom_kind::id::Type,
+	extensions: &[String],
+) -> Result<(), QueryError> {
+	db._batch((
+		db.custom_kind_extension()
+			.delete_many(vec![custom_kind_extension::kind_id::equals(id)]),
+		db.custom_kind_extension().create_many(
+			extensions
+				.iter()
+				.map(|extension| {
+					custom_kind_extension::create_unchecked(extension.clone(), id, vec![])
+				})
+				.collect(),
+		),
+	))
+	.await?;
+
+	Ok(())
+}
+
+/// Gives the custom kind to the objects without a kind that have a file with one of its extensions
+async fn classify(
+	library: &Library,
+	id: custom_kind::id::Type,
+	extensions: Vec<String>,
+) -> Result<(), QueryError> {
+	if extensions.is_empty() {
+		return Ok(());
+	}
+
+	set_kind(
+		library,
+		vec![
+			or(vec![
+				object::kind::equals(Some(ObjectKind::Unknown as i32)),
+				object::kind::equals(None),
+			]),
+			object::file_paths::some(vec![file_path::extension::in_vec(extensions)]),
+		],
+		kind_value(id),
+	)
+	.await
+}
+
+/// Takes the custom kind back from its objects
+async fn declassify(library: &Library, id: custom_kind::id::Type) -> Result<(), QueryError> {
+	set_kind(
+		library,
+		vec![object::kind::equals(Some(kind_value(id)))],
+		ObjectKind::Unknown as i32,
+	)
+	.await
+}
+
+async fn set_kind(
+	library @ Library { db, sync, .. }: &Library,
+	filter: Vec<object::WhereParam>,
+	kind: i32,
+) -> Result<(), QueryError> {
+	object::select!(object_ids { id pub_id });
+
+	let objects = db
+		.object()
+		.find_many(filter)
+		.select(object_ids::select())
+		.exec()
+		.await?;
+
+	if objects.is_empty() {
+		return Ok(());
+	}
+
+	let (ids, ops): (Vec<_>, Vec<_>) = objects
+		.into_iter()
+		.map(|object| {
+			(
+				object.id,
+				sync.shared_update(
+					sync::object::SyncId {
+						pub_id: object.pub_id,
+					},
+					object::kind::NAME,
+					json!(kind),
+				),
+			)
+		})
+		.unzip();
+
+	sync.write_ops(
+		db,
+		(
+			ops,
+			db.object().update_many(
+				vec![object::id::in_vec(ids)],
+				vec![object::kind::set(Some(kind))],
+			),
+		),
+	)
+	.await?;
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn custom_kinds_only_name_unknown_files() {
+		let kinds = CustomKinds(HashMap::from([("nes".to_string(), kind_value(3))]));
+
+		assert_eq!(kinds.resolve(ObjectKind::Unknown, "NES"), 1003);
+		assert_eq!(
+			kinds.resolve(ObjectKind::Unknown, "xyz"),
+			ObjectKind::Unknown as i32
+		);
+		assert_eq!(
+			kinds.resolve(ObjectKind::Image, "nes"),
+			ObjectKind::Image as i32
+		);
+	}
+
+	#[test]
+	fn extensions_are_normalized() {
+		assert_eq!(
+			normalize_extensions(vec![
+				".STL".to_string(),
+				"stl".to_string(),
+				" step ".to_string(),
+				"".to_string()
+			]),
+			vec!["step".to_string(), "stl".to_string()]
+		);
+	}
+}
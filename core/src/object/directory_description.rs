@@ -0,0 +1,261 @@
+//! What a directory holds, told by a note written on it or by a README already inside of it, so an
+//! archive folder can say what's in there without opening it.
+//!
+//! Notes are synced with the directory's file path and come along with it in listings. READMEs are
+//! only looked for when a directory has no note, the first of `README.md`, `README.markdown`,
+//! `README.txt` and so on which is indexed, its beginning read like any text preview.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	location::{file_path_helper::IsolatedFilePathData, privacy::LocationPrivacyError},
+	object::preview::text::{text_preview, TextPreview, TextPreviewError, DEFAULT_PREVIEW_KB},
+	prisma::file_path,
+	sync,
+	util::db::{maybe_missing, MissingFieldError},
+};
+
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::Serialize;
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+
+/// Notes are meant to be a few paragraphs, READMEs being there for anything longer
+pub const MAX_NOTE_LEN: usize = 4096;
+
+/// Extensions of READMEs, the ones preferred first
+const README_EXTENSIONS: [&str; 6] = ["md", "markdown", "txt", "rst", "org", ""];
+
+#[derive(Error, Debug)]
+pub enum DirectoryDescriptionError {
+	#[error("directory not found <id='{0}'>")]
+	NotFound(file_path::id::Type),
+	#[error("only directories have a description")]
+	NotADirectory,
+	#[error("notes are {MAX_NOTE_LEN} characters at most")]
+	NoteTooLong,
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	Preview(#[from] TextPreviewError),
+	#[error("missing field: {0}")]
+	MissingField(#[from] MissingFieldError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<DirectoryDescriptionError> for rspc::Error {
+	fn from(err: DirectoryDescriptionError) -> Self {
+		match err {
+			DirectoryDescriptionError::Privacy(e) => e.into(),
+			DirectoryDescriptionError::Preview(e) => e.into(),
+			_ => {
+				let code = match err {
+					DirectoryDescriptionError::NotFound(_) => ErrorCode::NotFound,
+					DirectoryDescriptionError::NotADirectory
+					| DirectoryDescriptionError::NoteTooLong => ErrorCode::BadRequest,
+					_ => ErrorCode::InternalServerError,
+				};
+
+				rspc::Error::with_cause(code, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct Readme {
+	pub file_path_id: file_path::id::Type,
+	/// The full name of the file, like `README.md`
+	pub name: String,
+	pub preview: TextPreview,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct DirectoryDescription {
+	pub note: Option<String>,
+	/// Only looked for when there's no note
+	pub readme: Option<Readme>,
+}
+
+/// Finds a directory of a location which isn't locked away
+async fn find_directory(
+	library: &Library,
+	id: file_path::id::Type,
+) -> Result<file_path::Data, DirectoryDescriptionError> {
+	let directory = library
+		.db
+		.file_path()
+		.find_unique(file_path::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(DirectoryDescriptionError::NotFound(id))?;
+
+	library
+		.private_locations
+		.ensure_unlocked(maybe_missing(
+			directory.location_id,
+			"file_path.location_id",
+		)?)
+		.await?;
+
+	if directory.is_dir != Some(true) {
+		return Err(DirectoryDescriptionError::NotADirectory);
+	}
+
+	Ok(directory)
+}
+
+/// Where a file named `name` and `extension` comes among READMEs, `None` if it isn't one
+fn readme_rank(name: &str, extension: &str) -> Option<usize> {
+	if !name.eq_ignore_ascii_case("readme") {
+		return None;
+	}
+
+	README_EXTENSIONS
+		.iter()
+		.position(|known| known.eq_ignore_ascii_case(extension))
+}
+
+/// The note of a directory, or the README inside of it when it has none
+pub async fn directory_description(
+	library: &Library,
+	id: file_path::id::Type,
+) -> Result<DirectoryDescription, DirectoryDescriptionError> {
+	let directory = find_directory(library, id).await?;
+
+	if directory.description.is_some() {
+		return Ok(DirectoryDescription {
+			note: directory.description,
+			readme: None,
+		});
+	}
+
+	let location_id = maybe_missing(directory.location_id, "file_path.location_id")?;
+	let Some(children_path) =
+		IsolatedFilePathData::try_from(&directory)?.materialized_path_for_children()
+	else {
+		return Err(DirectoryDescriptionError::NotADirectory);
+	};
+
+	// Extensions are compared without case by the database, names aren't
+	let readme = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::materialized_path::equals(Some(children_path)),
+			file_path::is_dir::equals(Some(false)),
+			file_path::extension::in_vec(
+				README_EXTENSIONS
+					.iter()
+					.map(|extension| Some(extension.to_string()))
+					.collect(),
+			),
+		])
+		.select(file_path::select!({ id name extension }))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|file_path| {
+			let name = file_path.name.unwrap_or_default();
+			let extension = file_path.extension.unwrap_or_default();
+
+			readme_rank(&name, &extension).map(|rank| (rank, file_path.id, name, extension))
+		})
+		.min_by_key(|(rank, ..)| *rank);
+
+	let Some((_, file_path_id, name, extension)) = readme else {
+		return Ok(DirectoryDescription {
+			note: None,
+			readme: None,
+		});
+	};
+
+	// A README which can't be read here is like no README at all
+	let preview = match text_preview(library, location_id, file_path_id, DEFAULT_PREVIEW_KB).await {
+		Ok(preview) => preview,
+		Err(
+			TextPreviewError::Binary | TextPreviewError::Redacted | TextPreviewError::Unavailable,
+		) => {
+			return Ok(DirectoryDescription {
+				note: None,
+				readme: None,
+			})
+		}
+		Err(e) => return Err(e.into()),
+	};
+
+	Ok(DirectoryDescription {
+		note: None,
+		readme: Some(Readme {
+			file_path_id,
+			name: if extension.is_empty() {
+				name
+			} else {
+				format!("{name}.{extension}")
+			},
+			preview,
+		}),
+	})
+}
+
+/// Writes the note of a directory, a blank one clearing it so its README shows again
+pub async fn set_directory_note(
+	library: &Library,
+	id: file_path::id::Type,
+	note: Option<String>,
+) -> Result<(), DirectoryDescriptionError> {
+	let note = note
+		.map(|note| note.trim().to_string())
+		.filter(|note| !note.is_empty());
+
+	if note
+		.as_ref()
+		.map_or(false, |note| note.chars().count() > MAX_NOTE_LEN)
+	{
+		return Err(DirectoryDescriptionError::NoteTooLong);
+	}
+
+	let directory = find_directory(library, id).await?;
+
+	let Library { db, sync, .. } = library;
+
+	sync.write_op(
+		db,
+		sync.shared_update(
+			sync::file_path::SyncId {
+				pub_id: directory.pub_id,
+			},
+			file_path::description::NAME,
+			json!(note),
+		),
+		db.file_path().update(
+			file_path::id::equals(id),
+			vec![file_path::description::set(note)],
+		),
+	)
+	.await?;
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "directories.description");
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn readmes_are_found_whatever_their_case() {
+		assert_eq!(readme_rank("README", "md"), Some(0));
+		assert_eq!(readme_rank("readme", "MD"), Some(0));
+		assert_eq!(readme_rank("ReadMe", "txt"), Some(2));
+		assert_eq!(readme_rank("README", ""), Some(5));
+		assert_eq!(readme_rank("README", "pdf"), None);
+		assert_eq!(readme_rank("NOTES", "md"), None);
+	}
+}
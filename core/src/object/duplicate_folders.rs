@@ -0,0 +1,575 @@
+//! Whole directory trees found more than once, like the same photo backup copied to three drives.
+//!
+//! Each directory gets a digest of the names of its entries along with the cas_ids of its files and
+//! the digests of its subdirectories, so two directories share a digest when everything below them
+//! matches. A directory with a file that isn't identified yet has no digest, and neither do its
+//! parents, until the file identifier catches up.
+
+use crate::{
+	extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{file_path_for_folder_digest, IsolatedFilePathData},
+		privacy::LocationPrivacyError,
+	},
+	object::fs::delete::FileDeleterJobInit,
+	prisma::{file_path, folder_digest, location, PrismaClient},
+	util::db::MissingFieldError,
+};
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	hash::Hash,
+};
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+const DIGESTS_PER_BATCH: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum DuplicateFoldersError {
+	#[error("directory not found <file_path_id='{0}'>")]
+	NotFound(file_path::id::Type),
+	#[error("file path isn't a directory <file_path_id='{0}'>")]
+	NotADirectory(file_path::id::Type),
+	#[error("directory isn't a duplicate of the one kept anymore <file_path_id='{0}'>")]
+	NotADuplicate(file_path::id::Type),
+	#[error("the directory kept can't be removed too")]
+	KeptDirectoryRemoved,
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("missing-field: {0}")]
+	MissingField(#[from] MissingFieldError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<DuplicateFoldersError> for rspc::Error {
+	fn from(err: DuplicateFoldersError) -> Self {
+		match err {
+			DuplicateFoldersError::Privacy(e) => e.into(),
+			DuplicateFoldersError::JobManager(e) => e.into(),
+			DuplicateFoldersError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			DuplicateFoldersError::NotADirectory(_)
+			| DuplicateFoldersError::NotADuplicate(_)
+			| DuplicateFoldersError::KeptDirectoryRemoved => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryDigest {
+	pub digest: Vec<u8>,
+	pub parent_digest: Option<Vec<u8>>,
+	pub size_in_bytes: u64,
+	pub files_count: i32,
+}
+
+/// Digests of the directories among these file paths, which must hold everything below them. The
+/// ones without any file below them are left out, empty trees all look alike.
+pub fn digest_directories(
+	file_paths: &[file_path_for_folder_digest::Data],
+) -> HashMap<file_path::id::Type, DirectoryDigest> {
+	let mut children = HashMap::<&str, Vec<&file_path_for_folder_digest::Data>>::new();
+	for file_path in file_paths {
+		if let Some(materialized_path) = &file_path.materialized_path {
+			children
+				.entry(materialized_path.as_str())
+				.or_default()
+				.push(file_path);
+		}
+	}
+
+	// Along with the materialized path of their children. The root of a location, when it's
+	// there, has no name and nothing to compare it to
+	let mut directories = file_paths
+		.iter()
+		.filter_map(|file_path| {
+			let iso_file_path = IsolatedFilePathData::try_from(file_path).ok()?;
+			if iso_file_path.is_root() {
+				return None;
+			}
+
+			iso_file_path
+				.materialized_path_for_children()
+				.map(|children_path| (file_path, children_path))
+		})
+		.collect::<Vec<_>>();
+	// Deepest first, so subdirectories are digested before their parents
+	directories.sort_by_key(|(directory, _)| {
+		std::cmp::Reverse(
+			directory
+				.materialized_path
+				.as_deref()
+				.map_or(0, |path| path.matches('/').count()),
+		)
+	});
+
+	// By the materialized path of the children of each directory, `None` for incomplete ones
+	let mut digests = HashMap::<String, Option<(Vec<u8>, u64, i32)>>::new();
+
+	for (_, children_path) in &directories {
+		let mut entries = BTreeMap::new();
+		let mut size_in_bytes = 0;
+		let mut files_count = 0;
+		let mut complete = true;
+
+		for child in children.get(children_path.as_str()).into_iter().flatten() {
+			let Ok(child_path) = IsolatedFilePathData::try_from(*child) else {
+				complete = false;
+				continue;
+			};
+			let name = child_path.full_name();
+
+			if let Some(grandchildren_path) = child_path.materialized_path_for_children() {
+				match digests.get(&grandchildren_path) {
+					Some(Some((digest, size, count))) => {
+						entries.insert(name, (b'd', digest.clone()));
+						size_in_bytes += size;
+						files_count += count;
+					}
+					_ => complete = false,
+				}
+			} else if let Some(cas_id) = &child.cas_id {
+				entries.insert(name, (b'f', cas_id.as_bytes().to_vec()));
+				size_in_bytes += child
+					.size_in_bytes
+					.as_deref()
+					.and_then(|size| size.parse::<u64>().ok())
+					.unwrap_or_default();
+				files_count += 1;
+			} else {
+				complete = false;
+			}
+		}
+
+		let digest = complete.then(|| {
+			let mut hasher = blake3::Hasher::new();
+			for (name, (kind, hash)) in &entries {
+				hasher.update(name.as_bytes());
+				hasher.update(&[0, *kind]);
+				hasher.update(hash);
+				hasher.update(&[0]);
+			}
+
+			(
+				hasher.finalize().as_bytes().to_vec(),
+				size_in_bytes,
+				files_count,
+			)
+		});
+
+		digests.insert(children_path.clone(), digest);
+	}
+
+	directories
+		.into_iter()
+		.filter_map(|(directory, children_path)| {
+			let (digest, size_in_bytes, files_count) = digests.get(&children_path)?.clone()?;
+
+			(files_count > 0).then(|| {
+				(
+					directory.id,
+					DirectoryDigest {
+						digest,
+						parent_digest: directory
+							.materialized_path
+							.as_ref()
+							.and_then(|path| digests.get(path))
+							.and_then(|parent| parent.as_ref())
+							.map(|(digest, _, _)| digest.clone()),
+						size_in_bytes,
+						files_count,
+					},
+				)
+			})
+		})
+		.collect()
+}
+
+pub struct DuplicateFoldersJob {}
+
+/// `DuplicateFoldersJobInit` digests the directories of these locations, or of every location of
+/// this node when none are given
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct DuplicateFoldersJobInit {
+	pub location_ids: Vec<location::id::Type>,
+}
+
+impl JobInitData for DuplicateFoldersJobInit {
+	type Job = DuplicateFoldersJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DuplicateFoldersJobReport {
+	digested_directories_count: usize,
+	duplicate_groups_count: usize,
+	reclaimable_size_in_bytes: u64,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for DuplicateFoldersJob {
+	type Init = DuplicateFoldersJobInit;
+	type Data = DuplicateFoldersJobReport;
+	type Step = location::id::Type;
+
+	const NAME: &'static str = "duplicate_folders";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		state.steps = if state.init.location_ids.is_empty() {
+			ctx.library
+				.db
+				.location()
+				.find_many(vec![location::node_id::equals(Some(
+					ctx.library.node_local_id,
+				))])
+				.select(location::select!({ id }))
+				.exec()
+				.await?
+				.into_iter()
+				.map(|location| location.id)
+				.collect()
+		} else {
+			state.init.location_ids.iter().copied().collect()
+		};
+
+		state.data = Some(DuplicateFoldersJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.steps[0];
+		let db = &ctx.library.db;
+
+		let file_paths = db
+			.file_path()
+			.find_many(vec![file_path::location_id::equals(Some(location_id))])
+			.select(file_path_for_folder_digest::select())
+			.exec()
+			.await?;
+
+		let digests = spawn_blocking(move || digest_directories(&file_paths)).await?;
+
+		db.folder_digest()
+			.delete_many(vec![folder_digest::file_path::is(vec![
+				file_path::location_id::equals(Some(location_id)),
+			])])
+			.exec()
+			.await?;
+
+		let digested_count = digests.len();
+		let date_computed = Utc::now();
+		let digests = digests.into_iter().collect::<Vec<_>>();
+
+		for chunk in digests.chunks(DIGESTS_PER_BATCH) {
+			db.folder_digest()
+				.create_many(
+					chunk
+						.iter()
+						.map(|(file_path_id, digest)| {
+							folder_digest::create_unchecked(
+								*file_path_id,
+								digest.digest.clone(),
+								digest.size_in_bytes.to_string(),
+								digest.files_count,
+								date_computed.into(),
+								vec![folder_digest::parent_digest::set(
+									digest.parent_digest.clone(),
+								)],
+							)
+						})
+						.collect(),
+				)
+				.exec()
+				.await?;
+		}
+
+		info!("Digested {digested_count} directories of location {location_id}");
+
+		extract_job_data_mut!(state).digested_directories_count += digested_count;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let groups = duplicate_folder_groups(&ctx.library.db, None, None).await?;
+
+		let report = extract_job_data_mut!(state);
+		report.duplicate_groups_count = groups.len();
+		report.reclaimable_size_in_bytes = groups.iter().map(|group| group.reclaimable).sum();
+
+		info!("Finalizing duplicate folders job: {report:?}");
+
+		invalidate_query!(ctx.library, "files.duplicateFolders");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+folder_digest::include!(folder_digest_with_file_path {
+	file_path: select { id location_id materialized_path name }
+});
+
+#[derive(Serialize, Type, Debug)]
+pub struct DuplicateFolderGroup {
+	/// Hex encoded
+	pub digest: String,
+	pub size_in_bytes: String,
+	pub files_count: i32,
+	/// What removing every copy but one would free
+	pub reclaimable_size_in_bytes: String,
+	#[serde(skip)]
+	reclaimable: u64,
+	pub folders: Vec<folder_digest_with_file_path::file_path::Data>,
+}
+
+/// The groups of directories sharing a digest, the ones saving the most space first. Duplicates
+/// inside directories which are duplicates themselves only show up as their parents.
+pub async fn duplicate_folder_groups(
+	db: &PrismaClient,
+	location_id: Option<location::id::Type>,
+	visible: Option<file_path::WhereParam>,
+) -> Result<Vec<DuplicateFolderGroup>, QueryError> {
+	let digests = db
+		.folder_digest()
+		.find_many(
+			visible
+				.map(|visible| folder_digest::file_path::is(vec![visible]))
+				.into_iter()
+				.collect(),
+		)
+		.include(folder_digest_with_file_path::include())
+		.exec()
+		.await?;
+
+	let mut by_digest = HashMap::<&[u8], Vec<&folder_digest_with_file_path::Data>>::new();
+	for digest in &digests {
+		by_digest.entry(&digest.digest).or_default().push(digest);
+	}
+	let is_duplicated =
+		|digest: &[u8]| by_digest.get(digest).map_or(false, |group| group.len() > 1);
+
+	let mut groups = by_digest
+		.iter()
+		.filter(|(_, folders)| {
+			folders.len() > 1
+				&& folders
+					.iter()
+					.any(|folder| !folder.parent_digest.as_deref().map_or(false, is_duplicated))
+				&& location_id.map_or(true, |location_id| {
+					folders
+						.iter()
+						.any(|folder| folder.file_path.location_id == Some(location_id))
+				})
+		})
+		.map(|(digest, folders)| {
+			let size_in_bytes = folders[0].size_in_bytes.parse::<u64>().unwrap_or_default();
+
+			let reclaimable = size_in_bytes * (folders.len() as u64 - 1);
+
+			DuplicateFolderGroup {
+				digest: hex::encode(digest),
+				size_in_bytes: size_in_bytes.to_string(),
+				files_count: folders[0].files_count,
+				reclaimable_size_in_bytes: reclaimable.to_string(),
+				reclaimable,
+				folders: folders
+					.iter()
+					.map(|folder| folder.file_path.clone())
+					.collect(),
+			}
+		})
+		.collect::<Vec<_>>();
+
+	groups.sort_by(|a, b| b.reclaimable.cmp(&a.reclaimable));
+
+	Ok(groups)
+}
+
+/// Deletes duplicates of the `keep` directory. Their digests are computed again first, in case
+/// something changed in them since the last digest job.
+pub async fn dedupe_folders(
+	library: &Library,
+	keep: file_path::id::Type,
+	remove: Vec<file_path::id::Type>,
+) -> Result<(), DuplicateFoldersError> {
+	if remove.contains(&keep) {
+		return Err(DuplicateFoldersError::KeptDirectoryRemoved);
+	}
+
+	let kept_digest = current_digest(library, keep).await?.0;
+
+	let mut by_location = HashMap::<location::id::Type, Vec<file_path::id::Type>>::new();
+	for file_path_id in remove {
+		let (digest, location_id) = current_digest(library, file_path_id).await?;
+		if digest != kept_digest {
+			return Err(DuplicateFoldersError::NotADuplicate(file_path_id));
+		}
+
+		by_location
+			.entry(location_id)
+			.or_default()
+			.push(file_path_id);
+	}
+
+	for (location_id, file_path_ids) in by_location {
+		library
+			.spawn_job(FileDeleterJobInit {
+				location_id,
+				file_path_ids,
+				with_groups: false,
+			})
+			.await?;
+	}
+
+	Ok(())
+}
+
+/// The digest of a directory from what's in the library right now, with its location
+async fn current_digest(
+	library: &Library,
+	file_path_id: file_path::id::Type,
+) -> Result<(Vec<u8>, location::id::Type), DuplicateFoldersError> {
+	let db = &library.db;
+
+	let directory = db
+		.file_path()
+		.find_unique(file_path::id::equals(file_path_id))
+		.select(file_path_for_folder_digest::select())
+		.exec()
+		.await?
+		.ok_or(DuplicateFoldersError::NotFound(file_path_id))?;
+
+	let Some(children_path) =
+		IsolatedFilePathData::try_from(&directory)?.materialized_path_for_children()
+	else {
+		return Err(DuplicateFoldersError::NotADirectory(file_path_id));
+	};
+	let location_id = directory
+		.location_id
+		.ok_or(DuplicateFoldersError::NotFound(file_path_id))?;
+
+	library
+		.private_locations
+		.ensure_unlocked(location_id)
+		.await?;
+
+	let mut file_paths = db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::materialized_path::starts_with(children_path),
+		])
+		.select(file_path_for_folder_digest::select())
+		.exec()
+		.await?;
+	file_paths.push(directory);
+
+	digest_directories(&file_paths)
+		.remove(&file_path_id)
+		.map(|digest| (digest.digest, location_id))
+		.ok_or(DuplicateFoldersError::NotADuplicate(file_path_id))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(
+		id: i32,
+		materialized_path: &str,
+		name: &str,
+		cas_id: Option<&str>,
+	) -> file_path_for_folder_digest::Data {
+		file_path_for_folder_digest::Data {
+			id,
+			location_id: Some(1),
+			materialized_path: Some(materialized_path.to_string()),
+			is_dir: Some(cas_id.is_none()),
+			name: Some(name.to_string()),
+			extension: Some(String::new()),
+			cas_id: cas_id.map(str::to_string),
+			size_in_bytes: Some("10".to_string()),
+		}
+	}
+
+	#[test]
+	fn duplicated_trees() {
+		let file_paths = vec![
+			entry(1, "/", "photos", None),
+			entry(2, "/photos/", "2019", None),
+			entry(3, "/photos/2019/", "a", Some("cas-a")),
+			entry(4, "/photos/", "b", Some("cas-b")),
+			entry(5, "/", "backup", None),
+			entry(6, "/backup/", "2019", None),
+			entry(7, "/backup/2019/", "a", Some("cas-a")),
+			entry(8, "/backup/", "b", Some("cas-b")),
+			// Same content, another name
+			entry(9, "/", "other", None),
+			entry(10, "/other/", "c", Some("cas-b")),
+			entry(11, "/", "empty", None),
+		];
+
+		let digests = digest_directories(&file_paths);
+
+		assert_eq!(digests[&1].digest, digests[&5].digest);
+		assert_eq!(digests[&2].digest, digests[&6].digest);
+		assert_eq!(digests[&2].parent_digest, Some(digests[&1].digest.clone()));
+		assert_eq!(digests[&1].parent_digest, None);
+		assert_eq!(digests[&1].size_in_bytes, 20);
+		assert_eq!(digests[&1].files_count, 2);
+		assert_ne!(digests[&9].digest, digests[&1].digest);
+		assert!(!digests.contains_key(&11));
+	}
+
+	#[test]
+	fn unidentified_files_leave_no_digest() {
+		let mut file_paths = vec![
+			entry(1, "/", "photos", None),
+			entry(2, "/photos/", "2019", None),
+			entry(3, "/photos/2019/", "a", Some("cas-a")),
+			entry(4, "/photos/2019/", "b", None),
+		];
+		file_paths[3].is_dir = Some(false);
+
+		assert!(digest_directories(&file_paths).is_empty());
+	}
+}
@@ -8,6 +8,7 @@ use crate::{
 		ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
 		file_path_for_file_identifier, IsolatedFilePathData,
 	},
+	object::special::bundle_directories,
 	prisma::{file_path, location, PrismaClient, SortOrder},
 	util::db::{chain_optional_iter, maybe_missing},
 };
@@ -17,11 +18,13 @@ use std::{
 	path::{Path, PathBuf},
 };
 
+use prisma_client_rust::operator::or;
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
 use super::{
-	process_identifier_file_paths, FileIdentifierJobError, FileIdentifierReport, CHUNK_SIZE,
+	pending::settle_pending_files, process_identifier_file_paths, FileIdentifierJobError,
+	FileIdentifierReport, CHUNK_SIZE,
 };
 
 pub struct FileIdentifierJob {}
@@ -55,6 +58,10 @@ pub struct FileIdentifierJobState {
 
 impl JobInitData for FileIdentifierJobInit {
 	type Job = FileIdentifierJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location.id)
+	}
 }
 
 #[async_trait::async_trait]
@@ -93,7 +100,8 @@ impl StatefulJob for FileIdentifierJob {
 
 			let sub_iso_file_path =
 				IsolatedFilePathData::new(location_id, location_path, &full_path, true)
-					.map_err(FileIdentifierJobError::from)?;
+					.map_err(FileIdentifierJobError::from)?
+					.normalized(ctx.library.config.file_name_normalization);
 
 			ensure_file_path_exists(
 				sub_path,
@@ -108,6 +116,11 @@ impl StatefulJob for FileIdentifierJob {
 			None
 		};
 
+		// Downloads that finished since the last run are identified right away, see `pending`
+		let total_pending_settled = settle_pending_files(&ctx.library, location_id, location_path)
+			.await?
+			.len();
+
 		let orphan_count =
 			count_orphan_file_paths(db, location_id, &maybe_sub_iso_file_path).await?;
 
@@ -116,6 +129,7 @@ impl StatefulJob for FileIdentifierJob {
 			report: FileIdentifierReport {
 				location_path: location_path.to_path_buf(),
 				total_orphan_paths: orphan_count,
+				total_pending_settled,
 				..Default::default()
 			},
 			cursor: 0,
@@ -194,18 +208,20 @@ impl StatefulJob for FileIdentifierJob {
 			});
 		}
 
-		let (total_objects_created, total_objects_linked) = process_identifier_file_paths(
-			location,
-			&file_paths,
-			step_number,
-			cursor,
-			&ctx.library,
-			report.total_orphan_paths,
-		)
-		.await?;
+		let (total_objects_created, total_objects_linked, total_objects_pending) =
+			process_identifier_file_paths(
+				location,
+				&file_paths,
+				step_number,
+				cursor,
+				&ctx.library,
+				report.total_orphan_paths,
+			)
+			.await?;
 
 		report.total_objects_created += total_objects_created;
 		report.total_objects_linked += total_objects_linked;
+		report.total_objects_pending += total_objects_pending;
 
 		ctx.progress(vec![
 			JobReportUpdate::CompletedTaskCount(step_number),
@@ -236,7 +252,10 @@ fn orphan_path_filters(
 	chain_optional_iter(
 		[
 			file_path::object_id::equals(None),
-			file_path::is_dir::equals(Some(false)),
+			or(vec![
+				file_path::is_dir::equals(Some(false)),
+				bundle_directories(),
+			]),
 			file_path::location_id::equals(Some(location_id)),
 		],
 		[
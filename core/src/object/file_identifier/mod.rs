@@ -4,13 +4,20 @@ use crate::{
 	location::file_path_helper::{
 		file_path_for_file_identifier, FilePathError, IsolatedFilePathData,
 	},
-	object::{cas::generate_cas_id, object_for_file_identifier},
+	object::{
+		cas::{cas_id_memory_usage, generate_cas_id},
+		custom_kind::CustomKinds,
+		object_for_file_identifier,
+		os_metadata::{apply_os_metadata, OsMetadata},
+		special::{identify_special, is_finder_alias},
+	},
 	prisma::{file_path, location, object, PrismaClient},
 	sync,
 	sync::SyncManager,
 	util::{
-		db::{maybe_missing, uuid_to_bytes},
+		db::{maybe_missing, uuid_to_bytes, MissingFieldError},
 		error::FileIOError,
+		memory_budget::MemoryBudget,
 	},
 };
 
@@ -22,7 +29,7 @@ use std::{
 	path::{Path, PathBuf},
 };
 
-use futures::future::join_all;
+use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
@@ -31,10 +38,13 @@ use tracing::{error, info};
 use uuid::Uuid;
 
 pub mod file_identifier_job;
+pub mod pending;
 mod shallow;
 
 pub use shallow::*;
 
+use pending::{is_incomplete, pending_kind, pending_object_params};
+
 // we break these jobs into chunks of 100 to improve performance
 const CHUNK_SIZE: usize = 100;
 
@@ -48,6 +58,8 @@ pub enum FileIdentifierJobError {
 	FilePathError(#[from] FilePathError),
 	#[error("database error: {0}")]
 	Database(#[from] prisma_client_rust::QueryError),
+	#[error(transparent)]
+	MissingField(#[from] MissingFieldError),
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +67,7 @@ pub struct FileMetadata {
 	pub cas_id: String,
 	pub kind: ObjectKind,
 	pub fs_metadata: std::fs::Metadata,
+	pub os_metadata: OsMetadata,
 }
 
 impl FileMetadata {
@@ -62,34 +75,60 @@ impl FileMetadata {
 	pub async fn new(
 		location_path: impl AsRef<Path>,
 		iso_file_path: &IsolatedFilePathData<'_>, // TODO: use dedicated CreateUnchecked type
+		memory_budget: &MemoryBudget,
 	) -> Result<FileMetadata, FileIOError> {
 		let path = location_path.as_ref().join(iso_file_path);
 
-		let fs_metadata = fs::metadata(&path)
+		let fs_metadata = fs::symlink_metadata(&path)
 			.await
 			.map_err(|e| FileIOError::from((&path, e)))?;
 
-		assert!(
-			!fs_metadata.is_dir(),
-			"We can't generate cas_id for directories"
-		);
+		// Links and bundles, the only directories identified
+		if let Some((kind, cas_id)) = identify_special(&path, &fs_metadata)
+			.await
+			.map_err(|e| FileIOError::from((&path, e)))?
+		{
+			let os_metadata = OsMetadata::read(&path, &fs_metadata).await;
+
+			info!("Analyzed special entry: {path:?} {cas_id:?} {kind:?}");
+
+			return Ok(FileMetadata {
+				cas_id,
+				kind,
+				fs_metadata,
+				os_metadata,
+			});
+		}
 
 		// derive Object kind
-		let kind = Extension::resolve_conflicting(&path, false)
+		let mut kind = Extension::resolve_conflicting(&path, false)
 			.await
 			.map(Into::into)
 			.unwrap_or(ObjectKind::Unknown);
 
+		if kind == ObjectKind::Unknown && is_finder_alias(&path).await {
+			kind = ObjectKind::Alias;
+		}
+
+		// Holding the permit until the cas_id is generated, so we don't load more file buffers
+		// than the node can afford
+		let _permit = memory_budget
+			.acquire(cas_id_memory_usage(fs_metadata.len()))
+			.await;
+
 		let cas_id = generate_cas_id(&path, fs_metadata.len())
 			.await
 			.map_err(|e| FileIOError::from((&path, e)))?;
 
+		let os_metadata = OsMetadata::read(&path, &fs_metadata).await;
+
 		info!("Analyzed file: {path:?} {cas_id:?} {kind:?}");
 
 		Ok(FileMetadata {
 			cas_id,
 			kind,
 			fs_metadata,
+			os_metadata,
 		})
 	}
 }
@@ -101,20 +140,40 @@ pub struct FileIdentifierReport {
 	total_objects_created: usize,
 	total_objects_linked: usize,
 	total_objects_ignored: usize,
+	total_objects_pending: usize,
+	total_pending_settled: usize,
 }
 
 async fn identifier_job_step(
-	Library { db, sync, .. }: &Library,
+	library @ Library { db, sync, .. }: &Library,
 	location: &location::Data,
 	file_paths: &[file_path_for_file_identifier::Data],
-) -> Result<(usize, usize), JobError> {
+) -> Result<(usize, usize, usize), JobError> {
+	let memory_budget = library.memory_budget();
+	let io_concurrency = library.resources().limits().await.io_concurrency as usize;
 	let location_path = maybe_missing(&location.path, "location.path").map(Path::new)?;
 
-	let file_path_metas = join_all(file_paths.iter().map(|file_path| async move {
+	let custom_kinds = CustomKinds::load(db).await?;
+
+	// Files still being downloaded aren't hashed, they get a pending object instead
+	let (pending_file_paths, file_paths) =
+		partition_incomplete(location_path, location.id, file_paths).await;
+
+	let total_pending = create_pending_objects(
+		library,
+		location_path,
+		location.id,
+		&pending_file_paths,
+		&custom_kinds,
+	)
+	.await?;
+
+	let file_path_metas = stream::iter(file_paths.into_iter().map(|file_path| async move {
 		// NOTE: `file_path`'s `materialized_path` begins with a `/` character so we remove it to join it with `location.path`
 		let meta = FileMetadata::new(
 			&location_path,
 			&IsolatedFilePathData::try_from((location.id, file_path))?,
+			memory_budget,
 		)
 		.await?;
 
@@ -124,6 +183,8 @@ async fn identifier_job_step(
 			(meta, file_path),
 		)) as Result<_, JobError>
 	}))
+	.buffer_unordered(io_concurrency)
+	.collect::<Vec<_>>()
 	.await
 	.into_iter()
 	.flat_map(|data| {
@@ -166,6 +227,12 @@ async fn identifier_job_step(
 	)
 	.await?;
 
+	// Applied once every file path is connected to its object, for Finder tags to reach them
+	let os_metadata_entries = file_path_metas
+		.iter()
+		.map(|(pub_id, (meta, _))| (*pub_id, meta.os_metadata.clone()))
+		.collect();
+
 	// Retrieves objects that are already connected to file paths with the same id
 	let existing_objects = db
 		.object()
@@ -246,7 +313,8 @@ async fn identifier_job_step(
 						pub_id: uuid_to_bytes(object_pub_id),
 					};
 
-					let kind = meta.kind as i32;
+					let kind = custom_kinds
+						.resolve(meta.kind, fp.extension.as_deref().unwrap_or_default());
 
 					let (sync_params, db_params): (Vec<_>, Vec<_>) = [
 						(
@@ -312,7 +380,112 @@ async fn identifier_job_step(
 		0
 	};
 
-	Ok((total_created, updated_file_paths.len()))
+	apply_os_metadata(library, os_metadata_entries).await?;
+
+	Ok((total_created, updated_file_paths.len(), total_pending))
+}
+
+async fn partition_incomplete<'a>(
+	location_path: &Path,
+	location_id: location::id::Type,
+	file_paths: &'a [file_path_for_file_identifier::Data],
+) -> (
+	Vec<&'a file_path_for_file_identifier::Data>,
+	Vec<&'a file_path_for_file_identifier::Data>,
+) {
+	let mut pending = vec![];
+	let mut complete = vec![];
+
+	for file_path in file_paths {
+		let is_pending = match IsolatedFilePathData::try_from((location_id, file_path)) {
+			Ok(iso_file_path) => {
+				let path = location_path.join(&iso_file_path);
+
+				fs::symlink_metadata(&path)
+					.await
+					.map_or(false, |metadata| is_incomplete(&path, &metadata))
+			}
+			// Left for the identifier to report
+			Err(_) => false,
+		};
+
+		if is_pending {
+			pending.push(file_path);
+		} else {
+			complete.push(file_path);
+		}
+	}
+
+	(pending, complete)
+}
+
+async fn create_pending_objects(
+	Library { db, sync, .. }: &Library,
+	location_path: &Path,
+	location_id: location::id::Type,
+	file_paths: &[&file_path_for_file_identifier::Data],
+	custom_kinds: &CustomKinds,
+) -> Result<usize, JobError> {
+	if file_paths.is_empty() {
+		return Ok(0);
+	}
+
+	info!("Deferring {} incomplete files", file_paths.len());
+
+	let (object_create_args, file_path_update_args): (Vec<_>, Vec<_>) = file_paths
+		.iter()
+		.map(|fp| {
+			let object_pub_id = Uuid::new_v4();
+
+			let extension = fp.extension.as_deref().unwrap_or_default();
+			let kind = IsolatedFilePathData::try_from((location_id, *fp))
+				.map_or(ObjectKind::Unknown, |iso_file_path| {
+					pending_kind(location_path.join(iso_file_path))
+				});
+
+			let (sync_params, db_params) =
+				pending_object_params(custom_kinds.resolve(kind, extension), fp.date_created);
+
+			(
+				(
+					sync.unique_shared_create(
+						sync::object::SyncId {
+							pub_id: uuid_to_bytes(object_pub_id),
+						},
+						sync_params,
+					),
+					object::create_unchecked(uuid_to_bytes(object_pub_id), db_params),
+				),
+				file_path_object_connect_ops(
+					// SAFETY: This should never happen
+					Uuid::from_slice(&fp.pub_id).expect("file_path.pub_id is invalid!"),
+					object_pub_id,
+					sync,
+					db,
+				),
+			)
+		})
+		.unzip();
+
+	let total_pending = sync
+		.write_ops(db, {
+			let (sync, db_params): (Vec<_>, Vec<_>) = object_create_args.into_iter().unzip();
+
+			(sync, db.object().create_many(db_params))
+		})
+		.await?;
+
+	sync.write_ops(db, {
+		let (crdt_ops, db_ops): (Vec<_>, Vec<_>) = file_path_update_args
+			.into_iter()
+			.map(|(crdt_op, db_op)| (crdt_op, db_op.select(file_path::select!({ pub_id }))))
+			.unzip();
+
+		(crdt_ops, db_ops)
+	})
+	.await?;
+
+	Ok(total_pending as usize)
 }
 
 fn file_path_object_connect_ops<'db>(
@@ -349,7 +522,7 @@ async fn process_identifier_file_paths(
 	cursor: &mut file_path::id::Type,
 	library: &Library,
 	orphan_count: usize,
-) -> Result<(usize, usize), JobError> {
+) -> Result<(usize, usize, usize), JobError> {
 	info!(
 		"Processing {:?} orphan Paths. ({} completed of {})",
 		file_paths.len(),
@@ -0,0 +1,323 @@
+//! Files still being written by a browser or a torrent client change under our feet: their cas_ids
+//! will never match the finished file, and their thumbnails come out broken. They get an Object
+//! marked as `pending`, with a kind guessed from their name and no cas_id, and are identified for
+//! real once they settle.
+
+use crate::{
+	library::Library,
+	location::{
+		downloads::is_partial_download,
+		file_path_helper::{file_path_with_object, IsolatedFilePathData},
+	},
+	object::{custom_kind::resolve_kind, fs::sparse::is_sparse},
+	prisma::{file_path, location, object},
+	sync,
+};
+
+use sd_file_ext::{extensions::Extension, kind::ObjectKind, magic::ExtensionPossibility};
+
+use std::{
+	ffi::OsStr,
+	fs::Metadata,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset};
+use prisma_client_rust::operator::or;
+use serde_json::{json, Value};
+use tokio::fs;
+use tracing::{debug, error};
+
+use super::{FileIdentifierJobError, FileMetadata};
+
+/// Sparse files left untouched for this long are meant to be sparse, like VM images, or belong
+/// to downloads that stalled, either way they're worth identifying
+const SETTLE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// The smallest piece size torrent clients use, the holes they leave start at multiples of it
+#[cfg(target_os = "linux")]
+const TORRENT_PIECE_ALIGNMENT: u64 = 16 * 1024;
+
+/// Tells if a file is most likely still being downloaded, so it isn't worth hashing yet
+pub fn is_incomplete(path: impl AsRef<Path>, metadata: &Metadata) -> bool {
+	let path = path.as_ref();
+
+	if !metadata.is_file() {
+		return false;
+	}
+
+	if is_partial_download(path) {
+		return true;
+	}
+
+	let recently_modified = metadata
+		.modified()
+		.ok()
+		.and_then(|modified| modified.elapsed().ok())
+		.map_or(true, |elapsed| elapsed < SETTLE_WINDOW);
+
+	recently_modified && is_sparse(path, metadata) && has_piece_aligned_hole(path, metadata)
+}
+
+/// Torrent clients allocate the whole file upfront and fill it piece by piece, so the first hole
+/// of a torrent in progress starts at a piece boundary
+#[cfg(target_os = "linux")]
+fn has_piece_aligned_hole(path: &Path, metadata: &Metadata) -> bool {
+	use std::os::fd::AsRawFd;
+
+	let Ok(file) = std::fs::File::open(path) else {
+		return false;
+	};
+
+	// SAFETY: the descriptor is valid while `file` lives, and seeking doesn't touch any memory
+	let first_hole = unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_HOLE) };
+
+	// Every file ends with an implicit hole, only the ones before the end count
+	first_hole >= 0
+		&& (first_hole as u64) < metadata.len()
+		&& first_hole as u64 % TORRENT_PIECE_ALIGNMENT == 0
+}
+
+/// Without a way to find the holes themselves, being sparse and recently modified has to do
+#[cfg(not(target_os = "linux"))]
+fn has_piece_aligned_hole(_: &Path, _: &Metadata) -> bool {
+	true
+}
+
+/// Guesses the kind of a file from its name alone, as its content isn't there yet.
+/// A `movie.mkv.part` is going to be a video.
+pub fn pending_kind(path: impl AsRef<Path>) -> ObjectKind {
+	let path = path.as_ref();
+
+	let path = if is_partial_download(path) {
+		path.file_stem().map(Path::new).unwrap_or(path)
+	} else {
+		path
+	};
+
+	match path
+		.extension()
+		.and_then(OsStr::to_str)
+		.and_then(Extension::from_str)
+	{
+		Some(ExtensionPossibility::Known(extension)) => extension.into(),
+		_ => ObjectKind::Unknown,
+	}
+}
+
+/// A pending file that was identified, it still needs its thumbnail and extracted metadata
+#[derive(Debug)]
+pub struct SettledFile {
+	pub path: PathBuf,
+	pub extension: String,
+	pub cas_id: String,
+	pub object_id: object::id::Type,
+}
+
+/// Identifies the files of a location that were pending and are now complete.
+///
+/// The file path gets its cas_id, and is linked to an object already holding the same content when
+/// there is one, leaving the pending object for the orphan remover. Otherwise the pending object
+/// stays, and just stops being pending.
+pub async fn settle_pending_files(
+	library @ Library { db, sync, .. }: &Library,
+	location_id: location::id::Type,
+	location_path: impl AsRef<Path>,
+) -> Result<Vec<SettledFile>, FileIdentifierJobError> {
+	let location_path = location_path.as_ref();
+
+	let pending_file_paths = db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::object::is(vec![object::pending::equals(Some(true))]),
+		])
+		.include(file_path_with_object::include())
+		.exec()
+		.await?;
+
+	let mut settled = Vec::new();
+	let mut relinked_any = false;
+
+	for file_path in pending_file_paths {
+		let Some(object) = &file_path.object else {
+			continue;
+		};
+
+		let iso_file_path = IsolatedFilePathData::try_from(&file_path)?;
+		let path = location_path.join(&iso_file_path);
+
+		let metadata = match fs::symlink_metadata(&path).await {
+			Ok(metadata) => metadata,
+			Err(e) => {
+				// Gone or renamed, the watcher or the next scan takes care of it
+				debug!("Skipping pending file {}: {e}", path.display());
+				continue;
+			}
+		};
+
+		if is_incomplete(&path, &metadata) {
+			continue;
+		}
+
+		let FileMetadata {
+			cas_id,
+			kind,
+			os_metadata: _,
+			fs_metadata: _,
+		} = match FileMetadata::new(location_path, &iso_file_path, library.memory_budget()).await {
+			Ok(meta) => meta,
+			Err(e) => {
+				error!("Failed to identify settled file: {e:#?}");
+				continue;
+			}
+		};
+
+		let extension = file_path.extension.clone().unwrap_or_default();
+
+		sync.write_op(
+			db,
+			sync.shared_update(
+				sync::file_path::SyncId {
+					pub_id: file_path.pub_id.clone(),
+				},
+				file_path::cas_id::NAME,
+				json!(&cas_id),
+			),
+			db.file_path().update(
+				file_path::pub_id::equals(file_path.pub_id.clone()),
+				vec![file_path::cas_id::set(Some(cas_id.clone()))],
+			),
+		)
+		.await?;
+
+		let existing_object = db
+			.object()
+			.find_first(vec![
+				object::id::not(object.id),
+				or(vec![
+					object::pending::equals(None),
+					object::pending::equals(Some(false)),
+				]),
+				object::file_paths::some(vec![file_path::cas_id::equals(Some(cas_id.clone()))]),
+			])
+			.select(object::select!({ id pub_id }))
+			.exec()
+			.await?;
+
+		let object_id = if let Some(existing_object) = existing_object {
+			sync.write_op(
+				db,
+				sync.shared_update(
+					sync::file_path::SyncId {
+						pub_id: file_path.pub_id.clone(),
+					},
+					file_path::object::NAME,
+					json!(sync::object::SyncId {
+						pub_id: existing_object.pub_id.clone()
+					}),
+				),
+				db.file_path().update(
+					file_path::pub_id::equals(file_path.pub_id.clone()),
+					vec![file_path::object::connect(object::pub_id::equals(
+						existing_object.pub_id.clone(),
+					))],
+				),
+			)
+			.await?;
+
+			relinked_any = true;
+
+			existing_object.id
+		} else {
+			let kind = resolve_kind(db, kind, &extension).await?;
+
+			let (sync_params, db_params): (Vec<_>, Vec<_>) = [
+				(
+					(object::pending::NAME, json!(false)),
+					object::pending::set(Some(false)),
+				),
+				(
+					(object::kind::NAME, json!(kind)),
+					object::kind::set(Some(kind)),
+				),
+			]
+			.into_iter()
+			.unzip();
+
+			sync.write_ops(
+				db,
+				(
+					sync_params
+						.into_iter()
+						.map(|(field, value)| {
+							sync.shared_update(
+								sync::object::SyncId {
+									pub_id: object.pub_id.clone(),
+								},
+								field,
+								value,
+							)
+						})
+						.collect(),
+					db.object().update(object::id::equals(object.id), db_params),
+				),
+			)
+			.await?;
+
+			object.id
+		};
+
+		debug!("Pending file settled: {}", path.display());
+
+		settled.push(SettledFile {
+			path,
+			extension,
+			cas_id,
+			object_id,
+		});
+	}
+
+	if relinked_any {
+		library.orphan_remover.invoke().await;
+	}
+
+	Ok(settled)
+}
+
+/// Builds the params of a pending object, created in place of identifying an incomplete file
+pub fn pending_object_params(
+	kind: i32,
+	date_created: Option<DateTime<FixedOffset>>,
+) -> (Vec<(&'static str, Value)>, Vec<object::SetParam>) {
+	[
+		(
+			(object::date_created::NAME, json!(date_created)),
+			object::date_created::set(date_created),
+		),
+		(
+			(object::kind::NAME, json!(kind)),
+			object::kind::set(Some(kind)),
+		),
+		(
+			(object::pending::NAME, json!(true)),
+			object::pending::set(Some(true)),
+		),
+	]
+	.into_iter()
+	.unzip()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pending_kind_looks_past_partial_extensions() {
+		assert_eq!(pending_kind("movie.mkv.part"), ObjectKind::Video);
+		assert_eq!(pending_kind("album.flac.!ut"), ObjectKind::Audio);
+		assert_eq!(pending_kind("photo.png"), ObjectKind::Image);
+		assert_eq!(pending_kind("download.part"), ObjectKind::Unknown);
+	}
+}
@@ -6,16 +6,21 @@ use crate::{
 		ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
 		file_path_for_file_identifier, IsolatedFilePathData,
 	},
+	object::special::bundle_directories,
 	prisma::{file_path, location, PrismaClient, SortOrder},
 	util::db::{chain_optional_iter, maybe_missing},
 };
 
 use std::path::{Path, PathBuf};
 
+use prisma_client_rust::operator::or;
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
-use super::{process_identifier_file_paths, FileIdentifierJobError, CHUNK_SIZE};
+use super::{
+	pending::settle_pending_files, process_identifier_file_paths, FileIdentifierJobError,
+	CHUNK_SIZE,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct ShallowFileIdentifierJobState {
@@ -45,7 +50,8 @@ pub async fn shallow(
 
 		let sub_iso_file_path =
 			IsolatedFilePathData::new(location_id, location_path, &full_path, true)
-				.map_err(FileIdentifierJobError::from)?;
+				.map_err(FileIdentifierJobError::from)?
+				.normalized(library.config.file_name_normalization);
 
 		ensure_file_path_exists(
 			&sub_path,
@@ -61,6 +67,8 @@ pub async fn shallow(
 			.map_err(FileIdentifierJobError::from)?
 	};
 
+	settle_pending_files(library, location_id, location_path).await?;
+
 	let orphan_count = count_orphan_file_paths(db, location_id, &sub_iso_file_path).await?;
 
 	if orphan_count == 0 {
@@ -122,7 +130,10 @@ fn orphan_path_filters(
 	chain_optional_iter(
 		[
 			file_path::object_id::equals(None),
-			file_path::is_dir::equals(Some(false)),
+			or(vec![
+				file_path::is_dir::equals(Some(false)),
+				bundle_directories(),
+			]),
 			file_path::location_id::equals(Some(location_id)),
 			file_path::materialized_path::equals(Some(
 				sub_iso_file_path
@@ -0,0 +1,559 @@
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{find_location, light_scan_location, location_with_indexer_rules, LocationError},
+	prisma::{file_path, location},
+	util::{error::FileIOError, long_path::to_extended_length},
+};
+
+use sd_crypto::{
+	crypto::Encryptor,
+	header::{file::FileHeader, keyslot::Keyslot},
+	primitives::{LATEST_FILE_HEADER, LATEST_KEYSLOT},
+	types::{Algorithm, HashingAlgorithm, Key, Params, Salt},
+	Protected,
+};
+
+use std::{
+	ffi::OsString,
+	fs::File,
+	hash::Hash,
+	io,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sevenz_rust::{SevenZArchiveEntry, SevenZWriter};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, sync::mpsc, task::spawn_blocking};
+use tracing::{error, trace, warn};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use super::{
+	error::FileSystemJobsError, fetch_source_and_target_location_paths, get_many_files_datas,
+	ignore_events_for, BYTES_EXT,
+};
+
+const ENCRYPTION_ALGORITHM: Algorithm = Algorithm::XChaCha20Poly1305;
+const PARTIAL_FILE_EXT: &str = ".part";
+
+/// Error type for archive creation and extraction jobs errors
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("zip error: {0}")]
+	Zip(#[from] zip::result::ZipError),
+	#[error("7z error: {0}")]
+	SevenZip(#[from] sevenz_rust::Error),
+	#[error("rar error: {0}")]
+	Rar(#[from] unrar::error::UnrarError),
+	#[error("the archive password isn't stored with the job, so it can't be resumed")]
+	MissingPassword,
+	#[error("unsupported archive format: <path='{}'>", .0.display())]
+	UnsupportedFormat(Box<Path>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type, Eq, PartialEq)]
+pub enum ArchiveFormat {
+	Zip,
+	SevenZip,
+	TarZstd,
+}
+
+impl ArchiveFormat {
+	pub fn extension(self) -> &'static str {
+		match self {
+			Self::Zip => "zip",
+			Self::SevenZip => "7z",
+			Self::TarZstd => "tar.zst",
+		}
+	}
+}
+
+pub struct ArchiveCreatorJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ArchiveCreatorJobInit {
+	pub source_location_id: location::id::Type,
+	pub sources_file_path_ids: Vec<file_path::id::Type>,
+	pub target_location_id: location::id::Type,
+	pub target_location_relative_directory_path: PathBuf,
+	/// The archive file name, without the extension
+	pub name: String,
+	pub format: ArchiveFormat,
+	/// The password is never persisted, so a job creating an encrypted archive can't be resumed
+	#[serde(skip_serializing, default)]
+	pub password: Option<String>,
+}
+
+impl JobInitData for ArchiveCreatorJobInit {
+	type Job = ArchiveCreatorJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.source_location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveEntry {
+	full_path: PathBuf,
+	/// Path inside the archive, always using `/` as separator
+	name: String,
+	is_dir: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveCreatorJobData {
+	archive_path: PathBuf,
+	/// The file being written, only renamed to `archive_path` when it's complete
+	pending_path: PathBuf,
+	entries: Vec<ArchiveEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ArchiveCreatorJobStep {
+	Pack,
+	Encrypt,
+	Register,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ArchiveCreatorJob {
+	type Init = ArchiveCreatorJobInit;
+	type Data = ArchiveCreatorJobData;
+	type Step = ArchiveCreatorJobStep;
+
+	const NAME: &'static str = "archive_creator";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let (sources_location_path, targets_location_path) =
+			fetch_source_and_target_location_paths(
+				db,
+				state.init.source_location_id,
+				state.init.target_location_id,
+			)
+			.await?;
+
+		let target_directory = to_extended_length(
+			&targets_location_path.join(&state.init.target_location_relative_directory_path),
+		)
+		.into_owned();
+
+		let archive_path = target_directory.join(archive_file_name(
+			&state.init.name,
+			state.init.format,
+			state.init.password.is_some(),
+		));
+
+		match fs::metadata(&archive_path).await {
+			Ok(_) => {
+				return Err(
+					FileSystemJobsError::WouldOverwrite(archive_path.into_boxed_path()).into(),
+				)
+			}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+			Err(e) => return Err(FileIOError::from((archive_path, e)).into()),
+		}
+
+		let mut entries = vec![];
+		for file_data in get_many_files_datas(
+			db,
+			&sources_location_path,
+			&state.init.sources_file_path_ids,
+		)
+		.await?
+		{
+			let name = file_data
+				.full_path
+				.file_name()
+				.and_then(|name| name.to_str())
+				.ok_or(JobError::OsStr)?
+				.to_string();
+
+			collect_entries(file_data.full_path, name, &mut entries).await?;
+		}
+
+		state.steps = [ArchiveCreatorJobStep::Pack]
+			.into_iter()
+			.chain(
+				state
+					.init
+					.password
+					.is_some()
+					.then_some(ArchiveCreatorJobStep::Encrypt),
+			)
+			.chain([ArchiveCreatorJobStep::Register])
+			.collect();
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(
+			entries.len() + state.steps.len() - 1,
+		)]);
+
+		state.data = Some(ArchiveCreatorJobData {
+			// Packing always writes the plain archive, which gets encrypted in a later step if needed
+			pending_path: with_partial_extension(&target_directory.join(archive_file_name(
+				&state.init.name,
+				state.init.format,
+				false,
+			))),
+			archive_path,
+			entries,
+		});
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		match state.steps[0] {
+			ArchiveCreatorJobStep::Pack => {
+				let data = extract_job_data!(state);
+
+				let _guard = ignore_events_for(
+					&ctx.library,
+					state.init.target_location_id,
+					&data.pending_path,
+				)
+				.await;
+
+				ctx.progress(vec![JobReportUpdate::Message(format!(
+					"Packing {} items",
+					data.entries.len()
+				))]);
+
+				let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+
+				let packing = spawn_blocking({
+					let format = state.init.format;
+					let pending_path = data.pending_path.clone();
+					let entries = data.entries.clone();
+					move || pack(format, &pending_path, &entries, progress_tx)
+				});
+
+				while let Some(packed_count) = progress_rx.recv().await {
+					ctx.progress(vec![JobReportUpdate::CompletedTaskCount(packed_count)]);
+				}
+
+				if let Err(e) = packing.await? {
+					remove_partial_file(&data.pending_path).await;
+					return Err(FileSystemJobsError::from(e).into());
+				}
+			}
+
+			ArchiveCreatorJobStep::Encrypt => {
+				let password = state
+					.init
+					.password
+					.clone()
+					.ok_or(FileSystemJobsError::from(ArchiveError::MissingPassword))?;
+
+				let data = extract_job_data_mut!(state);
+
+				// The packed archive name lacks the bytes extension, so the names never clash
+				let encrypted_path = with_partial_extension(&data.archive_path);
+
+				let _guard =
+					ignore_events_for(&ctx.library, state.init.target_location_id, &encrypted_path)
+						.await;
+
+				ctx.progress(vec![JobReportUpdate::Message(
+					"Encrypting archive".to_string(),
+				)]);
+
+				if let Err(e) = encrypt(&data.pending_path, &encrypted_path, password).await {
+					remove_partial_file(&encrypted_path).await;
+					return Err(e);
+				}
+
+				remove_partial_file(&data.pending_path).await;
+				data.pending_path = encrypted_path;
+			}
+
+			ArchiveCreatorJobStep::Register => {
+				let data = extract_job_data!(state);
+
+				fs::rename(&data.pending_path, &data.archive_path)
+					.await
+					.map_err(|e| FileIOError::from((&data.pending_path, e)))?;
+
+				trace!("Created archive {}", data.archive_path.display());
+
+				let location = find_location(&ctx.library, state.init.target_location_id)
+					.include(location_with_indexer_rules::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(state.init.target_location_id))?;
+
+				// The archive is already on disk, the watcher or the next scan will pick it up
+				if let Err(e) = light_scan_location(
+					ctx.library.clone(),
+					location,
+					&state.init.target_location_relative_directory_path,
+				)
+				.await
+				{
+					error!(
+						"Failed to register the archive {}: {e:#?}",
+						data.archive_path.display()
+					);
+				}
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			extract_job_data!(state).entries.len() + state.step_number,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::to_value(&state.init)?))
+	}
+}
+
+fn archive_file_name(name: &str, format: ArchiveFormat, encrypted: bool) -> String {
+	format!(
+		"{name}.{}{}",
+		format.extension(),
+		if encrypted { BYTES_EXT } else { "" }
+	)
+}
+
+fn with_partial_extension(path: &Path) -> PathBuf {
+	let mut path = OsString::from(path);
+	path.push(PARTIAL_FILE_EXT);
+	path.into()
+}
+
+async fn remove_partial_file(path: &Path) {
+	if let Err(e) = fs::remove_file(path).await {
+		if e.kind() != io::ErrorKind::NotFound {
+			warn!("Failed to remove partial file {}: {e}", path.display());
+		}
+	}
+}
+
+/// Lists every file and directory to be archived, with each directory before its children
+async fn collect_entries(
+	root_path: PathBuf,
+	root_name: String,
+	entries: &mut Vec<ArchiveEntry>,
+) -> Result<(), JobError> {
+	let mut to_visit = vec![(root_path, root_name)];
+
+	while let Some((full_path, name)) = to_visit.pop() {
+		let metadata = fs::symlink_metadata(&full_path)
+			.await
+			.map_err(|e| FileIOError::from((&full_path, e)))?;
+
+		if metadata.is_dir() {
+			let mut read_dir = fs::read_dir(&full_path)
+				.await
+				.map_err(|e| FileIOError::from((&full_path, e)))?;
+
+			while let Some(children_entry) = read_dir
+				.next_entry()
+				.await
+				.map_err(|e| FileIOError::from((&full_path, e)))?
+			{
+				let children_name = format!(
+					"{name}/{}",
+					children_entry.file_name().to_str().ok_or(JobError::OsStr)?
+				);
+
+				to_visit.push((children_entry.path(), children_name));
+			}
+
+			entries.push(ArchiveEntry {
+				full_path,
+				name,
+				is_dir: true,
+			});
+		} else if metadata.is_file() {
+			entries.push(ArchiveEntry {
+				full_path,
+				name,
+				is_dir: false,
+			});
+		} else {
+			warn!(
+				"Skipping {} as only files and directories can be archived",
+				full_path.display()
+			);
+		}
+	}
+
+	Ok(())
+}
+
+/// Writes the archive, sending the number of packed entries after each one
+fn pack(
+	format: ArchiveFormat,
+	archive_path: &Path,
+	entries: &[ArchiveEntry],
+	progress_tx: mpsc::UnboundedSender<usize>,
+) -> Result<(), ArchiveError> {
+	let file = File::create(archive_path).map_err(|e| FileIOError::from((archive_path, e)))?;
+
+	let open = |entry: &ArchiveEntry| {
+		File::open(&entry.full_path).map_err(|e| FileIOError::from((&entry.full_path, e)))
+	};
+
+	match format {
+		ArchiveFormat::Zip => {
+			let mut zip = ZipWriter::new(file);
+			let options = FileOptions::default()
+				.compression_method(CompressionMethod::Deflated)
+				.large_file(true);
+
+			for (i, entry) in entries.iter().enumerate() {
+				if entry.is_dir {
+					zip.add_directory(&entry.name, options)?;
+				} else {
+					zip.start_file(&entry.name, options)?;
+					io::copy(&mut open(entry)?, &mut zip)
+						.map_err(|e| FileIOError::from((&entry.full_path, e)))?;
+				}
+
+				progress_tx.send(i + 1).ok();
+			}
+
+			zip.finish()?;
+		}
+
+		ArchiveFormat::SevenZip => {
+			let mut writer = SevenZWriter::new(file)?;
+
+			for (i, entry) in entries.iter().enumerate() {
+				let source = if entry.is_dir {
+					None
+				} else {
+					Some(open(entry)?)
+				};
+
+				writer.push_archive_entry(
+					SevenZArchiveEntry::from_path(&entry.full_path, entry.name.clone()),
+					source,
+				)?;
+
+				progress_tx.send(i + 1).ok();
+			}
+
+			writer
+				.finish()
+				.map_err(|e| FileIOError::from((archive_path, e)))?;
+		}
+
+		ArchiveFormat::TarZstd => {
+			let encoder = zstd::stream::write::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)
+				.map_err(|e| FileIOError::from((archive_path, e)))?;
+
+			let mut builder = tar::Builder::new(encoder);
+			builder.follow_symlinks(false);
+
+			for (i, entry) in entries.iter().enumerate() {
+				if entry.is_dir {
+					builder.append_dir(&entry.name, &entry.full_path)
+				} else {
+					builder.append_path_with_name(&entry.full_path, &entry.name)
+				}
+				.map_err(|e| FileIOError::from((&entry.full_path, e)))?;
+
+				progress_tx.send(i + 1).ok();
+			}
+
+			builder
+				.into_inner()
+				.and_then(|encoder| encoder.finish())
+				.map_err(|e| FileIOError::from((archive_path, e)))?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Encrypts the archive into a file with a `sd-crypto` header, holding a single keyslot
+/// for the password
+async fn encrypt(source: &Path, target: &Path, password: String) -> Result<(), JobError> {
+	let reader = fs::File::open(source)
+		.await
+		.map_err(|e| FileIOError::from((source, e)))?;
+	let mut writer = fs::File::create(target)
+		.await
+		.map_err(|e| FileIOError::from((target, e)))?;
+
+	let hashing_algorithm = HashingAlgorithm::Argon2id(Params::Standard);
+	let content_salt = Salt::generate();
+	let hashed_password = spawn_blocking(move || {
+		hashing_algorithm.hash(Protected::new(password.into_bytes()), content_salt, None)
+	})
+	.await??;
+
+	let master_key = Key::generate();
+
+	let header = FileHeader::new(
+		LATEST_FILE_HEADER,
+		ENCRYPTION_ALGORITHM,
+		vec![
+			Keyslot::new(
+				LATEST_KEYSLOT,
+				ENCRYPTION_ALGORITHM,
+				hashing_algorithm,
+				content_salt,
+				hashed_password,
+				master_key.clone(),
+			)
+			.await?,
+		],
+	)?;
+
+	header.write(&mut writer).await?;
+
+	Encryptor::new(master_key, header.nonce, header.algorithm)?
+		.encrypt_streams(reader, &mut writer, &header.generate_aad())
+		.await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn archive_names() {
+		assert_eq!(
+			archive_file_name("photos", ArchiveFormat::Zip, false),
+			"photos.zip"
+		);
+		assert_eq!(
+			archive_file_name("photos", ArchiveFormat::TarZstd, true),
+			"photos.tar.zst.bytes"
+		);
+		assert_eq!(
+			with_partial_extension(Path::new("/tmp/photos.7z")),
+			PathBuf::from("/tmp/photos.7z.part")
+		);
+	}
+}
@@ -0,0 +1,434 @@
+//! Links pointing to something which isn't there anymore: symbolic links, Windows shortcuts and
+//! Finder aliases. A scan goes through the links of locations and keeps a report of the broken ones,
+//! which can be deleted in bulk or, for symbolic links, pointed somewhere else.
+//!
+//! Shortcuts and aliases hold paths of the system which made them, so they're only checked on
+//! Windows and macOS respectively. Targets which can't be reached for another reason than being
+//! missing, like a permission error, don't count as broken.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::privacy::LocationPrivacyError,
+	object::special::{alias_target, retarget_link, shortcut_target},
+	prisma::{broken_link, file_path, location, object},
+	util::{db::chain_optional_iter, error::FileIOError},
+};
+
+use sd_file_ext::kind::ObjectKind;
+
+use std::{
+	collections::HashMap,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use int_enum::IntEnum;
+use prisma_client_rust::{operator::or, QueryError};
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::fs;
+use tracing::info;
+
+use super::{
+	delete::FileDeleterJobInit, error::FileSystemJobsError, get_location_path_from_location_id,
+	get_many_files_datas,
+};
+
+/// Aliases and shortcuts are small, anything larger isn't one
+const MAX_LINK_FILE_SIZE: u64 = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum BrokenLinkError {
+	#[error("broken link not found <id='{0}'>")]
+	NotFound(broken_link::id::Type),
+	#[error("only symbolic links can be pointed somewhere else")]
+	RetargetUnsupported,
+	#[error("the new target of the link doesn't exist: {}", .0.display())]
+	TargetNotFound(PathBuf),
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	FileSystem(#[from] FileSystemJobsError),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<BrokenLinkError> for rspc::Error {
+	fn from(err: BrokenLinkError) -> Self {
+		match err {
+			BrokenLinkError::Privacy(e) => e.into(),
+			BrokenLinkError::JobManager(e) => e.into(),
+			BrokenLinkError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			BrokenLinkError::RetargetUnsupported | BrokenLinkError::TargetNotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			BrokenLinkError::FileSystem(_)
+			| BrokenLinkError::FileIO(_)
+			| BrokenLinkError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(IntEnum, Serialize, Deserialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum LinkKind {
+	Symlink = 0,
+	/// A Windows `.lnk` file
+	Shortcut = 1,
+	FinderAlias = 2,
+}
+
+/// Where a link points to, when it's a link at all
+async fn link_target(path: &Path) -> Result<Option<(LinkKind, PathBuf)>, FileIOError> {
+	let metadata = fs::symlink_metadata(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	if metadata.is_symlink() {
+		let target = fs::read_link(path)
+			.await
+			.map_err(|e| FileIOError::from((path, e)))?;
+
+		// Relative targets are relative to the directory of the link
+		let resolved = path
+			.parent()
+			.map_or_else(|| target.clone(), |parent| parent.join(&target));
+
+		return Ok(Some((LinkKind::Symlink, resolved)));
+	}
+
+	let is_shortcut = cfg!(target_os = "windows")
+		&& path
+			.extension()
+			.map_or(false, |extension| extension.eq_ignore_ascii_case("lnk"));
+	let is_alias = cfg!(target_os = "macos") && !is_shortcut;
+
+	if !metadata.is_file() || metadata.len() > MAX_LINK_FILE_SIZE || !(is_shortcut || is_alias) {
+		return Ok(None);
+	}
+
+	let content = fs::read(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	Ok(if is_shortcut {
+		shortcut_target(&content).map(|target| (LinkKind::Shortcut, PathBuf::from(target)))
+	} else {
+		alias_target(&content).map(|target| (LinkKind::FinderAlias, target))
+	})
+}
+
+/// If nothing is where the link points to
+async fn is_missing(target: &Path) -> bool {
+	matches!(fs::metadata(target).await, Err(e) if e.kind() == ErrorKind::NotFound)
+}
+
+pub struct BrokenLinkScannerJob {}
+
+/// `BrokenLinkScannerJobInit` checks the links of these locations, or of every location of this
+/// node when none are given
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct BrokenLinkScannerJobInit {
+	#[serde(default)]
+	#[specta(optional)]
+	pub location_ids: Vec<location::id::Type>,
+}
+
+impl JobInitData for BrokenLinkScannerJobInit {
+	type Job = BrokenLinkScannerJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BrokenLinkScannerJobReport {
+	checked_links_count: usize,
+	broken_links_count: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for BrokenLinkScannerJob {
+	type Init = BrokenLinkScannerJobInit;
+	type Data = BrokenLinkScannerJobReport;
+	type Step = location::id::Type;
+
+	const NAME: &'static str = "broken_link_scanner";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		state.steps = ctx
+			.library
+			.db
+			.location()
+			.find_many(chain_optional_iter(
+				[location::node_id::equals(Some(ctx.library.node_local_id))],
+				[(!state.init.location_ids.is_empty())
+					.then(|| location::id::in_vec(state.init.location_ids.clone()))],
+			))
+			.select(location::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|location| location.id)
+			.collect();
+
+		state.data = Some(BrokenLinkScannerJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.steps[0];
+		let db = &ctx.library.db;
+
+		// Symbolic links are identified as aliases, and so are Finder aliases. Shortcuts are
+		// identified by their extension, which is case insensitive in the database.
+		let candidate_ids = db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(location_id)),
+				or(vec![
+					file_path::object::is(vec![object::kind::equals(Some(
+						ObjectKind::Alias as i32,
+					))]),
+					file_path::extension::equals(Some("lnk".to_string())),
+				]),
+			])
+			.select(file_path::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|file_path| file_path.id)
+			.collect::<Vec<_>>();
+
+		let location_path = get_location_path_from_location_id(db, location_id).await?;
+
+		let mut broken = vec![];
+		for file_data in get_many_files_datas(db, &location_path, &candidate_ids).await? {
+			// Files gone since they were indexed are the indexer's business
+			let Ok(Some((kind, target))) = link_target(&file_data.full_path).await else {
+				continue;
+			};
+
+			if is_missing(&target).await {
+				broken.push(broken_link::create_unchecked(
+					file_data.file_path.id,
+					kind.int_value(),
+					target.to_string_lossy().to_string(),
+					vec![],
+				));
+			}
+		}
+
+		let broken_count = broken.len();
+
+		db._batch((
+			db.broken_link()
+				.delete_many(vec![broken_link::file_path::is(vec![
+					file_path::location_id::equals(Some(location_id)),
+				])]),
+			db.broken_link().create_many(broken),
+		))
+		.await?;
+
+		info!(
+			"Found {broken_count} broken links out of {} in location {location_id}",
+			candidate_ids.len()
+		);
+
+		let report = extract_job_data_mut!(state);
+		report.checked_links_count += candidate_ids.len();
+		report.broken_links_count += broken_count;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data!(state);
+
+		info!("Finalizing broken link scanner job: {report:?}");
+
+		invalidate_query!(ctx.library, "files.brokenLinks");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+broken_link::include!(broken_link_with_file_path { file_path });
+
+#[derive(Deserialize, Type, Debug, Default)]
+pub struct BrokenLinksFilter {
+	#[specta(optional)]
+	pub location_id: Option<location::id::Type>,
+	#[specta(optional)]
+	pub kind: Option<LinkKind>,
+	/// Part of the path the links point to, like a drive which is gone
+	#[specta(optional)]
+	pub target: Option<String>,
+}
+
+/// The broken links found by the last scans, leaving out the ones of locked private locations
+pub async fn broken_links(
+	library: &Library,
+	filter: BrokenLinksFilter,
+) -> Result<Vec<broken_link_with_file_path::Data>, QueryError> {
+	let file_path_params = [
+		filter
+			.location_id
+			.map(|location_id| file_path::location_id::equals(Some(location_id))),
+		library.private_locations.visible_file_paths().await,
+	]
+	.into_iter()
+	.flatten()
+	.collect();
+
+	library
+		.db
+		.broken_link()
+		.find_many(chain_optional_iter(
+			[broken_link::file_path::is(file_path_params)],
+			[
+				filter
+					.kind
+					.map(|kind| broken_link::kind::equals(kind.int_value())),
+				filter.target.map(broken_link::target::contains),
+			],
+		))
+		.include(broken_link_with_file_path::include())
+		.exec()
+		.await
+}
+
+/// Deletes the links, with a deletion job for each location they're in, returning how many jobs
+/// were started
+pub async fn delete_broken_links(
+	library: &Library,
+	ids: Vec<broken_link::id::Type>,
+) -> Result<u32, BrokenLinkError> {
+	let links = library
+		.db
+		.broken_link()
+		.find_many(vec![broken_link::id::in_vec(ids)])
+		.include(broken_link_with_file_path::include())
+		.exec()
+		.await?;
+
+	let mut by_location = HashMap::<_, Vec<_>>::new();
+	for link in links {
+		if let Some(location_id) = link.file_path.location_id {
+			library
+				.private_locations
+				.ensure_unlocked(location_id)
+				.await?;
+
+			by_location
+				.entry(location_id)
+				.or_default()
+				.push(link.file_path_id);
+		}
+	}
+
+	let jobs = by_location.len() as u32;
+	for (location_id, file_path_ids) in by_location {
+		library
+			.spawn_job(FileDeleterJobInit {
+				location_id,
+				file_path_ids,
+				with_groups: false,
+			})
+			.await?;
+	}
+
+	Ok(jobs)
+}
+
+/// Points a broken symbolic link to `target`, which is relative to the directory of the link unless
+/// it's absolute
+pub async fn retarget_broken_link(
+	library: &Library,
+	id: broken_link::id::Type,
+	target: PathBuf,
+) -> Result<(), BrokenLinkError> {
+	let db = &library.db;
+
+	let link = db
+		.broken_link()
+		.find_unique(broken_link::id::equals(id))
+		.include(broken_link_with_file_path::include())
+		.exec()
+		.await?
+		.ok_or(BrokenLinkError::NotFound(id))?;
+
+	if !matches!(LinkKind::from_int(link.kind), Ok(LinkKind::Symlink)) {
+		return Err(BrokenLinkError::RetargetUnsupported);
+	}
+
+	let location_id = link
+		.file_path
+		.location_id
+		.ok_or(BrokenLinkError::NotFound(id))?;
+	library
+		.private_locations
+		.ensure_unlocked(location_id)
+		.await?;
+
+	let location_path = get_location_path_from_location_id(db, location_id).await?;
+	let Some(file_data) = get_many_files_datas(db, location_path, &[link.file_path_id])
+		.await?
+		.pop()
+	else {
+		return Err(BrokenLinkError::NotFound(id));
+	};
+	let path = file_data.full_path;
+
+	let resolved = path
+		.parent()
+		.map_or_else(|| target.clone(), |parent| parent.join(&target));
+	if is_missing(&resolved).await {
+		return Err(BrokenLinkError::TargetNotFound(target));
+	}
+
+	retarget_link(&path, &target)
+		.await
+		.map_err(|e| FileIOError::from((&path, e)))?;
+
+	db.broken_link()
+		.delete(broken_link::id::equals(id))
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "files.brokenLinks");
+
+	Ok(())
+}
@@ -0,0 +1,454 @@
+//! Compression at rest for "cold storage" locations. Files that weren't accessed for a while are
+//! compressed with zstd into a store managed by us, keeping their `file_path` around with
+//! `compressed_at` set, so they can still be browsed. Their content is decompressed on demand.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{file_path_for_compressor, get_allocated_size, IsolatedFilePathData},
+		find_location, LocationError,
+	},
+	prisma::{file_path, location},
+	util::{db::maybe_missing, error::FileIOError},
+};
+
+use std::{
+	fs::File,
+	hash::Hash,
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{fs, task::spawn_blocking};
+use tracing::{error, info, trace};
+
+const COMPRESSED_STORE_DIR: &str = "compressed";
+const DECOMPRESSED_CACHE_DIR: &str = "decompressed";
+const COMPRESSION_LEVEL: i32 = 9;
+
+pub const DEFAULT_COMPRESS_AFTER_DAYS: i32 = 180;
+
+/// Compressing tiny files isn't worth the extra round trip to read them
+const MIN_COMPRESSIBLE_SIZE: u64 = 64 * 1024;
+
+/// These formats are already compressed, so zstd would only waste time on them
+const ALREADY_COMPRESSED_EXTENSIONS: [&str; 30] = [
+	"7z", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "heif", "jpeg", "jpg", "m4a",
+	"m4v", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "pptx", "rar", "webm", "webp", "xlsx",
+	"xz", "zip", "zst", "bytes",
+];
+
+pub struct FileCompressorJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct FileCompressorJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for FileCompressorJobInit {
+	type Job = FileCompressorJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileCompressorJobReport {
+	compressed_count: usize,
+	saved_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileCompressorJobData {
+	location_path: PathBuf,
+	report: FileCompressorJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FileCompressorJob {
+	type Init = FileCompressorJobInit;
+	type Data = FileCompressorJobData;
+	type Step = file_path_for_compressor::Data;
+
+	const NAME: &'static str = "file_compressor";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location = find_location(&ctx.library, state.init.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+
+		state.data = Some(FileCompressorJobData {
+			location_path: maybe_missing(&location.path, "location.path")?.into(),
+			report: FileCompressorJobReport::default(),
+		});
+
+		if location.compress_at_rest != Some(true) {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "Compression at rest is disabled for this location".to_string(),
+			});
+		}
+
+		let cutoff = Utc::now()
+			- Duration::days(
+				location
+					.compress_after_days
+					.unwrap_or(DEFAULT_COMPRESS_AFTER_DAYS)
+					.into(),
+			);
+
+		state.steps = db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(state.init.location_id)),
+				file_path::is_dir::equals(Some(false)),
+				file_path::compressed_at::equals(None),
+				file_path::tiered_at::equals(None),
+				file_path::date_modified::lt(cutoff.into()),
+			])
+			.select(file_path_for_compressor::select())
+			.exec()
+			.await?
+			.into_iter()
+			.filter(|file_path| {
+				// Files never opened through Spacedrive only have their modification date to go by
+				let is_cold = file_path
+					.object
+					.as_ref()
+					.and_then(|object| object.date_accessed)
+					.map_or(true, |date_accessed| date_accessed < cutoff);
+
+				is_cold && is_worth_compressing(file_path)
+			})
+			.collect();
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+		let file_path = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		let full_path = data
+			.location_path
+			.join(IsolatedFilePathData::try_from((location_id, file_path))?);
+		let store_path = compressed_file_path(&ctx.library, &file_path.pub_id);
+
+		let result = async {
+			let _guard = ctx
+				.library
+				.location_manager()
+				.temporary_ignore_events_for_path(location_id, ctx.library.clone(), &full_path)
+				.await
+				.map_err(|e| {
+					error!(
+						"Failed to make location manager ignore the path {}; Error: {e:#?}",
+						full_path.display()
+					);
+				})
+				.ok();
+
+			let original_size = fs::metadata(&full_path)
+				.await
+				.map_err(|e| FileIOError::from((&full_path, e)))?
+				.len();
+
+			let compressed_size = spawn_blocking({
+				let full_path = full_path.clone();
+				let store_path = store_path.clone();
+				move || compress_file(&full_path, &store_path)
+			})
+			.await??;
+
+			// The database goes first, so a failure here leaves the original file untouched
+			ctx.library
+				.db
+				.file_path()
+				.update(
+					file_path::id::equals(file_path.id),
+					vec![
+						file_path::compressed_at::set(Some(Utc::now().into())),
+						file_path::allocated_size_in_bytes::set(Some(compressed_size.to_string())),
+					],
+				)
+				.exec()
+				.await?;
+
+			fs::remove_file(&full_path)
+				.await
+				.map_err(|e| FileIOError::from((&full_path, e)))?;
+
+			trace!(
+				"Compressed {} from {original_size} to {compressed_size} bytes",
+				full_path.display()
+			);
+
+			Ok::<_, JobError>(original_size.saturating_sub(compressed_size))
+		}
+		.await;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		match result {
+			Ok(saved_bytes) => {
+				data.report.compressed_count += 1;
+				data.report.saved_bytes += saved_bytes;
+				Ok(())
+			}
+			Err(e) => {
+				remove_if_exists(&with_partial_extension(&store_path)).await;
+
+				Err(JobError::StepCompletedWithErrors(vec![format!(
+					"Failed to compress {}: {e}",
+					full_path.display()
+				)]))
+			}
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing file compressor job: {report:?}");
+
+		invalidate_query!(ctx.library, "search.paths");
+		invalidate_query!(ctx.library, "library.statistics");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+fn is_worth_compressing(file_path: &file_path_for_compressor::Data) -> bool {
+	let size = file_path
+		.size_in_bytes
+		.as_deref()
+		.and_then(|size| size.parse::<u64>().ok())
+		.unwrap_or_default();
+
+	let extension = file_path
+		.extension
+		.as_deref()
+		.unwrap_or_default()
+		.to_lowercase();
+
+	size >= MIN_COMPRESSIBLE_SIZE && !ALREADY_COMPRESSED_EXTENSIONS.contains(&extension.as_str())
+}
+
+/// Where the compressed content of a file path is kept, named after its `pub_id`
+pub fn compressed_file_path(library: &Library, pub_id: &[u8]) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(COMPRESSED_STORE_DIR)
+		.join(library.id.to_string())
+		.join(format!("{}.zst", hex::encode(pub_id)))
+}
+
+/// Decompresses the content of a compressed file path into a cache, so it can be served
+/// without restoring the file in its location. Returns the path of the decompressed file.
+pub async fn decompress_to_cache(library: &Library, pub_id: &[u8]) -> Result<PathBuf, FileIOError> {
+	let cache_path = library
+		.config()
+		.data_directory()
+		.join(DECOMPRESSED_CACHE_DIR)
+		.join(library.id.to_string())
+		.join(hex::encode(pub_id));
+
+	if fs::metadata(&cache_path).await.is_err() {
+		let store_path = compressed_file_path(library, pub_id);
+
+		spawn_blocking({
+			let cache_path = cache_path.clone();
+			move || decompress_file(&store_path, &cache_path)
+		})
+		.await
+		.map_err(|e| FileIOError::from((&cache_path, io::Error::new(io::ErrorKind::Other, e))))??;
+	}
+
+	Ok(cache_path)
+}
+
+/// Brings the content of a compressed file path back to its location
+pub async fn restore_compressed_file(
+	library: &Library,
+	location_path: impl AsRef<Path>,
+	file_path: &file_path::Data,
+) -> Result<(), JobError> {
+	let location_id = maybe_missing(file_path.location_id, "file_path.location_id")?;
+	let full_path = location_path
+		.as_ref()
+		.join(IsolatedFilePathData::try_from(file_path)?);
+	let store_path = compressed_file_path(library, &file_path.pub_id);
+
+	let _guard = library
+		.location_manager()
+		.temporary_ignore_events_for_path(location_id, library.clone(), &full_path)
+		.await
+		.map_err(|e| {
+			error!(
+				"Failed to make location manager ignore the path {}; Error: {e:#?}",
+				full_path.display()
+			);
+		})
+		.ok();
+
+	spawn_blocking({
+		let full_path = full_path.clone();
+		let store_path = store_path.clone();
+		move || decompress_file(&store_path, &full_path)
+	})
+	.await??;
+
+	let metadata = fs::metadata(&full_path)
+		.await
+		.map_err(|e| FileIOError::from((&full_path, e)))?;
+
+	library
+		.db
+		.file_path()
+		.update(
+			file_path::pub_id::equals(file_path.pub_id.clone()),
+			vec![
+				file_path::compressed_at::set(None),
+				file_path::allocated_size_in_bytes::set(Some(
+					get_allocated_size(&full_path, &metadata).to_string(),
+				)),
+			],
+		)
+		.exec()
+		.await?;
+
+	remove_if_exists(&store_path).await;
+	remove_if_exists(
+		&library
+			.config()
+			.data_directory()
+			.join(DECOMPRESSED_CACHE_DIR)
+			.join(library.id.to_string())
+			.join(hex::encode(&file_path.pub_id)),
+	)
+	.await;
+
+	Ok(())
+}
+
+/// Returns the size of the compressed file
+fn compress_file(source: &Path, target: &Path) -> Result<u64, FileIOError> {
+	write_atomically(target, |target_file| {
+		let source_file = File::open(source).map_err(|e| FileIOError::from((source, e)))?;
+
+		zstd::stream::copy_encode(source_file, target_file, COMPRESSION_LEVEL)
+			.map_err(|e| FileIOError::from((target, e)))
+	})?;
+
+	std::fs::metadata(target)
+		.map(|metadata| metadata.len())
+		.map_err(|e| FileIOError::from((target, e)))
+}
+
+fn decompress_file(source: &Path, target: &Path) -> Result<(), FileIOError> {
+	write_atomically(target, |target_file| {
+		let source_file = File::open(source).map_err(|e| FileIOError::from((source, e)))?;
+
+		zstd::stream::copy_decode(source_file, target_file)
+			.map_err(|e| FileIOError::from((target, e)))
+	})
+}
+
+/// Writes to a partial file next to `target`, which is only renamed to `target` when complete
+fn write_atomically(
+	target: &Path,
+	write: impl FnOnce(&mut File) -> Result<(), FileIOError>,
+) -> Result<(), FileIOError> {
+	if let Some(parent) = target.parent() {
+		std::fs::create_dir_all(parent).map_err(|e| FileIOError::from((parent, e)))?;
+	}
+
+	let partial_path = with_partial_extension(target);
+
+	let result = File::create(&partial_path)
+		.map_err(|e| FileIOError::from((&partial_path, e)))
+		.and_then(|mut partial_file| {
+			write(&mut partial_file)?;
+			partial_file
+				.sync_all()
+				.map_err(|e| FileIOError::from((&partial_path, e)))
+		})
+		.and_then(|()| {
+			std::fs::rename(&partial_path, target).map_err(|e| FileIOError::from((target, e)))
+		});
+
+	if result.is_err() {
+		std::fs::remove_file(&partial_path).ok();
+	}
+
+	result
+}
+
+fn with_partial_extension(path: &Path) -> PathBuf {
+	let mut path = path.as_os_str().to_owned();
+	path.push(".part");
+	path.into()
+}
+
+async fn remove_if_exists(path: &Path) {
+	if let Err(e) = fs::remove_file(path).await {
+		if e.kind() != io::ErrorKind::NotFound {
+			error!("Failed to remove {}: {e}", path.display());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use tempfile::tempdir;
+
+	#[test]
+	fn compression_round_trip() {
+		let dir = tempdir().unwrap();
+		let original = dir.path().join("notes.txt");
+		let compressed = dir.path().join("store").join("notes.zst");
+		let restored = dir.path().join("restored.txt");
+
+		let content = "cold storage ".repeat(10_000);
+		std::fs::write(&original, &content).unwrap();
+
+		let compressed_size = compress_file(&original, &compressed).unwrap();
+		assert!(compressed_size < content.len() as u64);
+		assert!(!with_partial_extension(&compressed).exists());
+
+		decompress_file(&compressed, &restored).unwrap();
+		assert_eq!(std::fs::read_to_string(&restored).unwrap(), content);
+	}
+}
@@ -0,0 +1,620 @@
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{find_location, light_scan_location, location_with_indexer_rules, LocationError},
+	object::preview::estimate_image_thumbnail_memory,
+	prisma::{file_path, location, PrismaClient},
+	util::{error::FileIOError, long_path::to_extended_length},
+};
+
+use std::{
+	collections::BTreeSet,
+	fs::File,
+	io::{BufReader, Cursor},
+	path::{Path, PathBuf},
+};
+
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageOutputFormat};
+use img_parts::{DynImage, ImageEXIF};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, io, task::block_in_place};
+use tracing::trace;
+
+use super::{
+	error::FileSystemJobsError, extract::available_path, get_location_path_from_location_id,
+	get_many_files_datas, ConflictPolicy, FileData,
+};
+
+/// Photos decoded by libheif, which already applies their rotation
+const HEIF_EXTENSIONS: [&str; 5] = ["heic", "heif", "heics", "heifs", "avif"];
+/// Camera RAW photos, developed with default settings
+const RAW_EXTENSIONS: [&str; 11] = [
+	"cr2", "cr3", "nef", "nrw", "arw", "dng", "raf", "orf", "rw2", "pef", "srw",
+];
+/// The EXIF tag holding how a photo must be rotated and flipped to be shown upright
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+#[derive(Error, Debug)]
+pub enum MediaConversionError {
+	#[error("unsupported image format: <path='{}'>", .0.display())]
+	UnsupportedFormat(Box<Path>),
+	#[cfg(all(feature = "heif", not(target_os = "linux")))]
+	#[error(transparent)]
+	Heif(#[from] sd_heif::HeifError),
+	#[error("failed to develop RAW photo: {0}")]
+	Raw(String),
+	#[error(transparent)]
+	Image(#[from] image::ImageError),
+	#[error("failed to write metadata to converted image: {0}")]
+	Metadata(#[from] img_parts::Error),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type, Eq, PartialEq)]
+pub enum ImageConversionFormat {
+	Jpeg,
+	Png,
+	WebP,
+}
+
+impl ImageConversionFormat {
+	pub fn extension(self) -> &'static str {
+		match self {
+			Self::Jpeg => "jpg",
+			Self::Png => "png",
+			Self::WebP => "webp",
+		}
+	}
+
+	/// PNG is lossless, the quality doesn't apply to it
+	fn default_quality(self) -> u8 {
+		match self {
+			Self::Jpeg => 90,
+			Self::Png | Self::WebP => 80,
+		}
+	}
+}
+
+/// How images are converted, with everything but the format left as is by default
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+pub struct ImageConversionOptions {
+	pub format: ImageConversionFormat,
+	/// From 1 to 100, defaults to 90 for JPEG and 80 for WebP
+	#[serde(default)]
+	#[specta(optional)]
+	pub quality: Option<u8>,
+	/// Images larger than this on either side are scaled down to fit, keeping their aspect ratio
+	#[serde(default)]
+	#[specta(optional)]
+	pub max_dimension: Option<u32>,
+	/// Leaves the EXIF metadata, like the camera or the GPS position, out of the converted images
+	#[serde(default)]
+	#[specta(optional)]
+	pub strip_metadata: bool,
+}
+
+impl ImageConversionOptions {
+	pub fn new(format: ImageConversionFormat) -> Self {
+		Self {
+			format,
+			quality: None,
+			max_dimension: None,
+			strip_metadata: false,
+		}
+	}
+}
+
+pub struct MediaConverterJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct MediaConverterJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+	pub options: ImageConversionOptions,
+	/// Where the converted images are written, next to their originals when empty
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_id: Option<location::id::Type>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_relative_directory_path: Option<PathBuf>,
+	#[serde(default)]
+	pub conflict_policy: ConflictPolicy,
+}
+
+impl JobInitData for MediaConverterJobInit {
+	type Job = MediaConverterJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ConversionReport {
+	converted: usize,
+	/// Images left out as something already existed at their target path
+	skipped_conflicts: usize,
+	failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MediaConverterJobData {
+	report: ConversionReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MediaConverterJobStep {
+	Convert {
+		source: FileData,
+		target_directory: PathBuf,
+	},
+	/// Adds the converted images to the target location, which may not be watched
+	Register {
+		location_id: location::id::Type,
+		sub_path: PathBuf,
+	},
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for MediaConverterJob {
+	type Init = MediaConverterJobInit;
+	type Data = MediaConverterJobData;
+	type Step = MediaConverterJobStep;
+
+	const NAME: &'static str = "media_converter";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
+
+		let target = resolve_target_directory(
+			db,
+			state.init.target_location_id,
+			&state.init.target_location_relative_directory_path,
+		)
+		.await?;
+
+		let sources = get_many_files_datas(db, &location_path, &state.init.file_path_ids).await?;
+
+		// Images converted next to their originals are seen by the watcher
+		let mut directories_to_register = BTreeSet::new();
+
+		state.steps = sources
+			.into_iter()
+			.filter(|source| source.file_path.is_dir != Some(true))
+			.map(|source| {
+				let target_directory = match &target {
+					Some((location_id, sub_path, target_directory)) => {
+						directories_to_register.insert((*location_id, sub_path.clone()));
+						target_directory.clone()
+					}
+					None => source
+						.full_path
+						.parent()
+						.map(Path::to_path_buf)
+						.unwrap_or_default(),
+				};
+
+				MediaConverterJobStep::Convert {
+					source,
+					target_directory,
+				}
+			})
+			.collect();
+
+		state.steps.extend(
+			directories_to_register
+				.into_iter()
+				.map(|(location_id, sub_path)| MediaConverterJobStep::Register {
+					location_id,
+					sub_path,
+				}),
+		);
+
+		state.data = Some(MediaConverterJobData {
+			report: ConversionReport::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let mut errors = vec![];
+
+		match &state.steps[0] {
+			MediaConverterJobStep::Convert {
+				source,
+				target_directory,
+			} => {
+				let options = state.init.options;
+				let conflict_policy = state.init.conflict_policy;
+				let source_path = source.full_path.clone();
+				let target_directory = target_directory.clone();
+				let data = extract_job_data_mut!(state);
+
+				ctx.progress(vec![JobReportUpdate::Message(format!(
+					"Converting {}",
+					source_path.display()
+				))]);
+
+				let target = source_path
+					.file_stem()
+					.map(|stem| {
+						target_directory
+							.join(stem)
+							.with_extension(options.format.extension())
+					})
+					.ok_or(JobError::OsStr)?;
+
+				match resolve_conflict(&source_path, target, conflict_policy).await? {
+					Some(target) => {
+						let _permit = ctx
+							.library
+							.memory_budget()
+							.acquire(estimate_image_thumbnail_memory(&source_path).await)
+							.await;
+
+						match convert_image(&source_path, &target, options).await {
+							Ok(()) => {
+								trace!(
+									"Converted {} to {}",
+									source_path.display(),
+									target.display()
+								);
+								data.report.converted += 1;
+							}
+							Err(e) => {
+								data.report.failed += 1;
+								errors.push(format!(
+									"Failed to convert {}: {e}",
+									source_path.display()
+								));
+							}
+						}
+					}
+					None => {
+						trace!("Skipping {} as its target exists", source_path.display());
+						data.report.skipped_conflicts += 1;
+					}
+				}
+			}
+
+			MediaConverterJobStep::Register {
+				location_id,
+				sub_path,
+			} => {
+				let location = find_location(&ctx.library, *location_id)
+					.include(location_with_indexer_rules::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(*location_id))?;
+
+				light_scan_location(ctx.library.clone(), location, sub_path).await?;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(JobError::StepCompletedWithErrors(errors))
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::json!({
+			"init": state.init,
+			"report": extract_job_data!(state).report,
+		})))
+	}
+}
+
+/// The location, relative path and full path of the directory chosen to write converted files
+/// into, created if needed. `None` when they're written next to their originals.
+pub(super) async fn resolve_target_directory(
+	db: &PrismaClient,
+	target_location_id: Option<location::id::Type>,
+	sub_path: &Option<PathBuf>,
+) -> Result<Option<(location::id::Type, PathBuf, PathBuf)>, FileSystemJobsError> {
+	let Some(target_location_id) = target_location_id else {
+		return Ok(None);
+	};
+
+	let sub_path = sub_path.clone().unwrap_or_default();
+	let target_directory = to_extended_length(
+		&get_location_path_from_location_id(db, target_location_id)
+			.await?
+			.join(&sub_path),
+	)
+	.into_owned();
+
+	fs::create_dir_all(&target_directory)
+		.await
+		.map_err(|e| FileIOError::from((&target_directory, e)))?;
+
+	Ok(Some((target_location_id, sub_path, target_directory)))
+}
+
+/// Where a converted file must be written by the conflict policy, `None` when it's skipped. A file
+/// is never converted over itself.
+pub(super) async fn resolve_conflict(
+	source: &Path,
+	target: PathBuf,
+	conflict_policy: ConflictPolicy,
+) -> Result<Option<PathBuf>, FileSystemJobsError> {
+	let metadata = match fs::symlink_metadata(&target).await {
+		Ok(metadata) => metadata,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(target)),
+		Err(e) => return Err(FileIOError::from((target, e)).into()),
+	};
+
+	Ok(match conflict_policy {
+		ConflictPolicy::KeepBoth => Some(available_path(&target)),
+		ConflictPolicy::Overwrite if !metadata.is_dir() && target != source => {
+			fs::remove_file(&target)
+				.await
+				.map_err(|e| FileIOError::from((&target, e)))?;
+			Some(target)
+		}
+		ConflictPolicy::Skip | ConflictPolicy::Overwrite => None,
+	})
+}
+
+/// Converts an image to another format, decoding HEIF photos and developing RAW ones. The
+/// converted image is always upright, its metadata saying so when it's kept.
+pub(crate) async fn convert_image(
+	source: &Path,
+	target: &Path,
+	options: ImageConversionOptions,
+) -> Result<(), MediaConversionError> {
+	let encoded = block_in_place(|| {
+		let (img, exif) = decode_upright(source)?;
+		encode_image(fit_within(img, options.max_dimension), exif, options)
+	})?;
+
+	fs::write(target, encoded)
+		.await
+		.map_err(|e| FileIOError::from((target, e)).into())
+}
+
+/// Decodes an image and turns it upright, along with its raw EXIF data
+pub(crate) fn decode_upright(
+	source: &Path,
+) -> Result<(DynamicImage, Option<Vec<u8>>), MediaConversionError> {
+	let extension = source
+		.extension()
+		.and_then(|extension| extension.to_str())
+		.unwrap_or_default()
+		.to_lowercase();
+
+	let exif = read_exif(source);
+
+	let mut img = if HEIF_EXTENSIONS.contains(&extension.as_str()) {
+		decode_heif(source)?
+	} else if RAW_EXTENSIONS.contains(&extension.as_str()) {
+		let developed =
+			imagepipe::simple_decode_8bit(source, 0, 0).map_err(MediaConversionError::Raw)?;
+
+		image::RgbImage::from_raw(
+			developed.width as u32,
+			developed.height as u32,
+			developed.data,
+		)
+		.map(DynamicImage::ImageRgb8)
+		.ok_or_else(|| MediaConversionError::Raw("bad image size".to_string()))?
+	} else {
+		image::open(source)?
+	};
+
+	// RAW photos come out of the pipeline already turned upright as well
+	if !HEIF_EXTENSIONS.contains(&extension.as_str())
+		&& !RAW_EXTENSIONS.contains(&extension.as_str())
+	{
+		img = apply_orientation(img, exif.as_deref().and_then(orientation).unwrap_or(1));
+	}
+
+	Ok((img, exif))
+}
+
+/// Scales an image down to fit a square of `max_dimension`, keeping its aspect ratio
+pub(crate) fn fit_within(img: DynamicImage, max_dimension: Option<u32>) -> DynamicImage {
+	match max_dimension {
+		Some(max_dimension) if img.width() > max_dimension || img.height() > max_dimension => {
+			img.resize(max_dimension, max_dimension, FilterType::Lanczos3)
+		}
+		_ => img,
+	}
+}
+
+/// Encodes an upright image in the format of the options, with its EXIF data unless stripped
+pub(crate) fn encode_image(
+	img: DynamicImage,
+	exif: Option<Vec<u8>>,
+	options: ImageConversionOptions,
+) -> Result<Vec<u8>, MediaConversionError> {
+	let quality = options
+		.quality
+		.unwrap_or_else(|| options.format.default_quality())
+		.clamp(1, 100);
+
+	let mut encoded = vec![];
+	match options.format {
+		ImageConversionFormat::Jpeg => JpegEncoder::new_with_quality(&mut encoded, quality)
+			.encode_image(&DynamicImage::ImageRgb8(img.to_rgb8()))?,
+		ImageConversionFormat::Png => {
+			img.write_to(&mut Cursor::new(&mut encoded), ImageOutputFormat::Png)?
+		}
+		ImageConversionFormat::WebP => {
+			let rgba = img.to_rgba8();
+			encoded = webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+				.encode(quality as f32)
+				.to_vec();
+		}
+	}
+
+	match exif.filter(|_| !options.strip_metadata) {
+		Some(mut exif) => {
+			reset_orientation(&mut exif);
+
+			match DynImage::from_bytes(encoded.clone().into())? {
+				Some(mut image) => {
+					image.set_exif(Some(exif.into()));
+					Ok(image.encoder().bytes().to_vec())
+				}
+				None => Ok(encoded),
+			}
+		}
+		None => Ok(encoded),
+	}
+}
+
+#[cfg(all(feature = "heif", not(target_os = "linux")))]
+fn decode_heif(path: &Path) -> Result<DynamicImage, MediaConversionError> {
+	sd_heif::heif_to_dynamic_image(path).map_err(Into::into)
+}
+
+#[cfg(not(all(feature = "heif", not(target_os = "linux"))))]
+fn decode_heif(path: &Path) -> Result<DynamicImage, MediaConversionError> {
+	Err(MediaConversionError::UnsupportedFormat(
+		path.to_path_buf().into_boxed_path(),
+	))
+}
+
+/// The raw EXIF data of a photo, as a TIFF structure
+fn read_exif(path: &Path) -> Option<Vec<u8>> {
+	let file = File::open(path).ok()?;
+
+	exif::Reader::new()
+		.read_from_container(&mut BufReader::new(file))
+		.ok()
+		.map(|exif| exif.buf().to_vec())
+}
+
+fn orientation(exif: &[u8]) -> Option<u16> {
+	exif::Reader::new()
+		.read_raw(exif.to_vec())
+		.ok()?
+		.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+		.value
+		.get_uint(0)
+		.and_then(|value| u16::try_from(value).ok())
+}
+
+/// Turns an image upright, from the EXIF orientation values
+fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+	match orientation {
+		2 => img.fliph(),
+		3 => img.rotate180(),
+		4 => img.flipv(),
+		5 => img.rotate90().fliph(),
+		6 => img.rotate90(),
+		7 => img.rotate270().fliph(),
+		8 => img.rotate270(),
+		_ => img,
+	}
+}
+
+/// Sets the orientation of the first IFD of some EXIF data to upright, as the pixels of converted
+/// images already are
+fn reset_orientation(exif: &mut [u8]) {
+	let read_u16 = |bytes: &[u8], little_endian: bool| {
+		let bytes = [bytes[0], bytes[1]];
+		if little_endian {
+			u16::from_le_bytes(bytes)
+		} else {
+			u16::from_be_bytes(bytes)
+		}
+	};
+
+	let little_endian = match exif.get(..2) {
+		Some(b"II") => true,
+		Some(b"MM") => false,
+		_ => return,
+	};
+
+	let Some(ifd_offset) = exif.get(4..8).map(|bytes| {
+		let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+		if little_endian {
+			u32::from_le_bytes(bytes)
+		} else {
+			u32::from_be_bytes(bytes)
+		}
+	}) else {
+		return;
+	};
+
+	let ifd_offset = ifd_offset as usize;
+	let Some(entries_count) = exif.get(ifd_offset..ifd_offset + 2) else {
+		return;
+	};
+
+	for entry in 0..read_u16(entries_count, little_endian) as usize {
+		let entry_offset = ifd_offset + 2 + entry * 12;
+		let Some(tag) = exif.get(entry_offset..entry_offset + 2) else {
+			return;
+		};
+
+		if read_u16(tag, little_endian) == EXIF_ORIENTATION_TAG {
+			// A single SHORT, stored in the value field of the entry itself
+			let value = if little_endian {
+				1u16.to_le_bytes()
+			} else {
+				1u16.to_be_bytes()
+			};
+
+			if let Some(field) = exif.get_mut(entry_offset + 8..entry_offset + 10) {
+				field.copy_from_slice(&value);
+			}
+
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn orientation_is_reset_in_place() {
+		// Little endian TIFF header, then an IFD with a single orientation entry set to 6
+		let mut exif = vec![
+			b'I', b'I', 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, // header
+			0x01, 0x00, // one entry
+			0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00,
+			0x00, // orientation
+			0x00, 0x00, 0x00, 0x00, // no next IFD
+		];
+
+		assert_eq!(orientation(&exif), Some(6));
+		reset_orientation(&mut exif);
+		assert_eq!(orientation(&exif), Some(1));
+	}
+}
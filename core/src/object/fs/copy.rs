@@ -1,27 +1,44 @@
 use crate::{
-	extract_job_data, invalidate_query,
+	extract_job_data, extract_job_data_mut, invalidate_query,
 	job::{
-		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, JournalOperation, StatefulJob,
+		WorkerContext,
 	},
 	library::Library,
 	location::file_path_helper::IsolatedFilePathData,
+	object::{
+		groups::with_group_members,
+		special::{copy_link, is_bundle},
+	},
 	prisma::{file_path, location},
 	util::{
 		db::{maybe_missing, MissingFieldError},
 		error::FileIOError,
+		long_path::to_extended_length,
 	},
+	volume::capabilities::{volumes_of, VolumeCapabilities},
 };
 
-use std::{hash::Hash, path::PathBuf};
+use std::{
+	fs::File,
+	hash::Hash,
+	path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use tokio::{fs, io};
+use tokio::{fs, io, task::spawn_blocking};
 use tracing::{trace, warn};
 
 use super::{
-	construct_target_filename, error::FileSystemJobsError, fetch_source_and_target_location_paths,
-	get_file_data_from_isolated_file_path, get_many_files_datas, FileData,
+	construct_target_filename, copy_tree_staged,
+	disk_image::{DiskImage, DiskImageError, ImageEntry},
+	error::FileSystemJobsError,
+	fetch_source_and_target_location_paths, get_file_data_from_isolated_file_path,
+	get_many_files_datas,
+	scrub::{scrub_file, MetadataField, ScrubReport},
+	sparse::copy_file,
+	FileData,
 };
 
 pub struct FileCopierJob {}
@@ -29,6 +46,50 @@ pub struct FileCopierJob {}
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileCopierJobState {
 	sources_location_path: PathBuf,
+	#[serde(default)]
+	strategy: CopyStrategy,
+	#[serde(default)]
+	scrubbed: ScrubReport,
+}
+
+/// How files are copied, picked from what the filesystems of the source and the target support
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct CopyStrategy {
+	/// Skip over the holes of sparse files ourselves, instead of leaving the copy to the platform,
+	/// which clones the data and its holes on volumes supporting it
+	keep_holes: bool,
+	copy_xattrs: bool,
+	max_name_length: Option<u32>,
+}
+
+impl Default for CopyStrategy {
+	fn default() -> Self {
+		Self {
+			keep_holes: true,
+			copy_xattrs: false,
+			max_name_length: None,
+		}
+	}
+}
+
+impl CopyStrategy {
+	fn new(source: Option<&VolumeCapabilities>, target: Option<&VolumeCapabilities>) -> Self {
+		let Some(target) = target else {
+			return Self::default();
+		};
+
+		let same_volume = source.map_or(false, |source| source.mount_point == target.mount_point);
+
+		Self {
+			keep_holes: target.capabilities.sparse_files
+				&& !(same_volume && target.capabilities.reflink),
+			// Copies made on Linux or by skipping holes leave the extended attributes behind
+			copy_xattrs: cfg!(any(target_os = "linux", target_os = "macos"))
+				&& target.capabilities.xattr
+				&& source.map_or(false, |source| source.capabilities.xattr),
+			max_name_length: Some(target.capabilities.max_name_length),
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Hash, Type)]
@@ -38,16 +99,36 @@ pub struct FileCopierJobInit {
 	pub sources_file_path_ids: Vec<file_path::id::Type>,
 	pub target_location_relative_directory_path: PathBuf,
 	pub target_file_name_suffix: Option<String>,
+	/// Paths inside the disk image given as the only source, to copy them out of it instead of
+	/// copying the image
+	#[serde(default)]
+	#[specta(optional)]
+	pub disk_image_entries: Vec<String>,
+	/// Also acts on the other files of their groups, see `object::groups`
+	#[serde(default)]
+	#[specta(optional)]
+	pub with_groups: bool,
+	/// Metadata scrubbed out of the copies of files, see `scrub`
+	#[serde(default)]
+	#[specta(optional)]
+	pub scrub_metadata: Vec<MetadataField>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileCopierJobStep {
 	pub source_file_data: FileData,
 	pub target_full_path: PathBuf,
+	/// Set when copying out of a disk image, the source file data being the image
+	#[serde(default)]
+	pub image_entry: Option<ImageEntry>,
 }
 
 impl JobInitData for FileCopierJobInit {
 	type Job = FileCopierJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.source_location_id)
+	}
 }
 
 #[async_trait::async_trait]
@@ -77,32 +158,55 @@ impl StatefulJob for FileCopierJob {
 			)
 			.await?;
 
-		state.steps = get_many_files_datas(
-			db,
-			&sources_location_path,
-			&state.init.sources_file_path_ids,
-		)
-		.await?
-		.into_iter()
-		.flat_map(|file_data| {
-			// add the currently viewed subdirectory to the location root
-			let mut full_target_path =
-				targets_location_path.join(&state.init.target_location_relative_directory_path);
-
-			full_target_path.push(construct_target_filename(
-				&file_data,
-				&state.init.target_file_name_suffix,
-			)?);
-
-			Ok::<_, MissingFieldError>(FileCopierJobStep {
-				source_file_data: file_data,
-				target_full_path: full_target_path,
-			})
-		})
-		.collect();
+		let sources_file_path_ids = if state.init.with_groups {
+			with_group_members(db, &state.init.sources_file_path_ids).await?
+		} else {
+			state.init.sources_file_path_ids.clone()
+		};
+
+		let files_datas =
+			get_many_files_datas(db, &sources_location_path, &sources_file_path_ids).await?;
+
+		state.steps = if state.init.disk_image_entries.is_empty() {
+			files_datas
+				.into_iter()
+				.flat_map(|file_data| {
+					// add the currently viewed subdirectory to the location root
+					let mut full_target_path = targets_location_path
+						.join(&state.init.target_location_relative_directory_path);
+
+					full_target_path.push(construct_target_filename(
+						&file_data,
+						&state.init.target_file_name_suffix,
+					)?);
+
+					Ok::<_, MissingFieldError>(FileCopierJobStep {
+						source_file_data: file_data,
+						target_full_path: to_extended_length(&full_target_path).into_owned(),
+						image_entry: None,
+					})
+				})
+				.collect()
+		} else {
+			// Entries are copied out of one image at a time
+			let Ok([image_file_data]) = <[_; 1]>::try_from(files_datas) else {
+				return Err(FileSystemJobsError::from(DiskImageError::ManyImages).into());
+			};
+
+			image_entries_steps(
+				image_file_data,
+				state.init.disk_image_entries.clone(),
+				&targets_location_path.join(&state.init.target_location_relative_directory_path),
+			)
+			.await?
+		};
+
+		let volumes = volumes_of(&[&sources_location_path, &targets_location_path]).await;
 
 		state.data = Some(FileCopierJobState {
 			sources_location_path,
+			strategy: CopyStrategy::new(volumes[0].as_ref(), volumes[1].as_ref()),
+			scrubbed: ScrubReport::default(),
 		});
 
 		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
@@ -118,14 +222,77 @@ impl StatefulJob for FileCopierJob {
 		let FileCopierJobStep {
 			source_file_data,
 			target_full_path,
+			image_entry,
 		} = &state.steps[0];
 
+		if let Some(image_entry) = image_entry.clone() {
+			return copy_image_entry(ctx, state, image_entry).await;
+		}
+
 		let data = extract_job_data!(state);
+		let mut errors = vec![];
 
-		if maybe_missing(source_file_data.file_path.is_dir, "file_path.is_dir")? {
-			fs::create_dir_all(target_full_path)
-				.await
-				.map_err(|e| FileIOError::from((target_full_path, e)))?;
+		if let Some(max_name_length) = data.strategy.max_name_length {
+			if target_full_path
+				.file_name()
+				.map_or(false, |name| name.len() > max_name_length as usize)
+			{
+				return Err(FileSystemJobsError::NameTooLong(
+					target_full_path.clone().into_boxed_path(),
+				)
+				.into());
+			}
+		}
+
+		let is_dir = maybe_missing(source_file_data.file_path.is_dir, "file_path.is_dir")?;
+
+		// Bundles are copied whole, as their contents may not be indexed
+		if is_dir && is_bundle(&source_file_data.full_path) {
+			match fs::symlink_metadata(target_full_path).await {
+				Ok(_) => {
+					warn!(
+						"Skipping {} as it would be overwritten",
+						target_full_path.display()
+					);
+				}
+				Err(e) if e.kind() == io::ErrorKind::NotFound => {
+					ctx.journal()
+						.await?
+						.execute(
+							state.step_number,
+							JournalOperation::CopyTree {
+								source: source_file_data.full_path.clone(),
+								target: target_full_path.clone(),
+							},
+							|| async {
+								copy_tree_staged(
+									&source_file_data.full_path,
+									target_full_path,
+									data.strategy.keep_holes,
+								)
+								.await
+								.map_err(JobError::from)
+							},
+						)
+						.await?;
+				}
+				Err(e) => return Err(FileIOError::from((target_full_path, e)).into()),
+			}
+		} else if is_dir {
+			ctx.journal()
+				.await?
+				.execute(
+					state.step_number,
+					JournalOperation::CreateDir {
+						path: target_full_path.clone(),
+					},
+					|| async {
+						fs::create_dir_all(target_full_path)
+							.await
+							.map_err(|e| FileIOError::from((target_full_path, e)).into())
+					},
+				)
+				.await?;
 
 			let mut read_dir = fs::read_dir(&source_file_data.full_path)
 				.await
@@ -147,6 +314,7 @@ impl StatefulJob for FileCopierJob {
 				// Currently not supporting file_name suffixes children files in a directory being copied
 				state.steps.push_back(FileCopierJobStep {
 					target_full_path: target_children_full_path,
+					image_entry: None,
 					source_file_data: get_file_data_from_isolated_file_path(
 						&ctx.library.db,
 						&data.sources_location_path,
@@ -160,7 +328,8 @@ impl StatefulJob for FileCopierJob {
 								.map_err(|e| FileIOError::from((&children_path, e)))?
 								.is_dir(),
 						)
-						.map_err(FileSystemJobsError::from)?,
+						.map_err(FileSystemJobsError::from)?
+						.normalized(ctx.library.config.file_name_normalization),
 					)
 					.await?,
 				});
@@ -177,7 +346,7 @@ impl StatefulJob for FileCopierJob {
 				.into());
 			}
 
-			match fs::metadata(target_full_path).await {
+			match fs::symlink_metadata(target_full_path).await {
 				Ok(_) => {
 					// only skip as it could be half way through a huge directory copy and run into an issue
 					warn!(
@@ -192,9 +361,75 @@ impl StatefulJob for FileCopierJob {
 						target_full_path.display()
 					);
 
-					fs::copy(&source_file_data.full_path, &target_full_path)
-						.await
-						.map_err(|e| FileIOError::from((target_full_path, e)))?;
+					ctx.journal()
+						.await?
+						.execute(
+							state.step_number,
+							JournalOperation::Copy {
+								source: source_file_data.full_path.clone(),
+								target: target_full_path.clone(),
+							},
+							|| async {
+								let is_link = fs::symlink_metadata(&source_file_data.full_path)
+									.await
+									.map_err(|e| {
+										FileIOError::from((&source_file_data.full_path, e))
+									})?
+									.is_symlink();
+
+								// Links are copied as links, never as what they point to
+								if is_link {
+									return copy_link(
+										&source_file_data.full_path,
+										target_full_path,
+									)
+									.await
+									.map_err(|e| FileIOError::from((target_full_path, e)).into());
+								}
+
+								if data.strategy.keep_holes {
+									copy_file(&source_file_data.full_path, &target_full_path).await
+								} else {
+									fs::copy(&source_file_data.full_path, &target_full_path).await
+								}
+								.map_err(|e| FileIOError::from((target_full_path, e)))?;
+
+								#[cfg(any(target_os = "linux", target_os = "macos"))]
+								if data.strategy.copy_xattrs {
+									if let Err(e) =
+										copy_xattrs(&source_file_data.full_path, target_full_path)
+									{
+										// Some namespaces need privileges, the data itself is copied
+										warn!(
+											"Failed to copy the extended attributes of {}: {e}",
+											source_file_data.full_path.display()
+										);
+									}
+								}
+
+								Ok(())
+							},
+						)
+						.await?;
+
+					if !state.init.scrub_metadata.is_empty() {
+						match scrub_file(target_full_path, &state.init.scrub_metadata).await {
+							Ok(removed) => extract_job_data_mut!(state)
+								.scrubbed
+								.record(removed.as_deref()),
+							Err(e) => {
+								// A copy which couldn't be scrubbed mustn't be shared as it is
+								fs::remove_file(target_full_path)
+									.await
+									.map_err(|e| FileIOError::from((target_full_path, e)))?;
+
+								errors.push(format!(
+									"Left out {} as its metadata couldn't be scrubbed: {e}",
+									source_file_data.full_path.display()
+								));
+							}
+						}
+					}
 				}
 				Err(e) => return Err(FileIOError::from((target_full_path, e)).into()),
 			}
@@ -204,12 +439,153 @@ impl StatefulJob for FileCopierJob {
 			state.step_number + 1,
 		)]);
 
-		Ok(())
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(JobError::StepCompletedWithErrors(errors))
+		}
 	}
 
 	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
 		invalidate_query!(ctx.library, "search.paths");
 
-		Ok(Some(serde_json::to_value(&state.init)?))
+		Ok(Some(serde_json::json!({
+			"init": state.init,
+			"scrubbed": extract_job_data!(state).scrubbed,
+		})))
+	}
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn copy_xattrs(source: &Path, target: &Path) -> io::Result<()> {
+	for name in xattr::list(source)? {
+		if let Some(value) = xattr::get(source, &name)? {
+			xattr::set(target, &name, &value)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// The steps copying the given entries of a disk image into `target_directory`
+async fn image_entries_steps(
+	image_file_data: FileData,
+	entries_paths: Vec<String>,
+	target_directory: &Path,
+) -> Result<Vec<FileCopierJobStep>, JobError> {
+	let entries = spawn_blocking({
+		let image_path = image_file_data.full_path.clone();
+		move || {
+			let mut image = DiskImage::open(image_path)?;
+			entries_paths
+				.iter()
+				.map(|path| image.entry(path))
+				.collect::<Result<Vec<_>, _>>()
+		}
+	})
+	.await?
+	.map_err(FileSystemJobsError::from)?;
+
+	Ok(entries
+		.into_iter()
+		.map(|entry| FileCopierJobStep {
+			source_file_data: image_file_data.clone(),
+			target_full_path: to_extended_length(&target_directory.join(&entry.name)).into_owned(),
+			image_entry: Some(entry),
+		})
+		.collect())
+}
+
+/// Copies an entry of a disk image, queueing the entries of a directory as steps of their own
+async fn copy_image_entry(
+	ctx: &mut WorkerContext,
+	state: &mut JobState<FileCopierJob>,
+	image_entry: ImageEntry,
+) -> Result<(), JobError> {
+	let image_path = state.steps[0].source_file_data.full_path.clone();
+	let target_full_path = state.steps[0].target_full_path.clone();
+
+	if image_entry.is_dir {
+		ctx.journal()
+			.await?
+			.execute(
+				state.step_number,
+				JournalOperation::CreateDir {
+					path: target_full_path.clone(),
+				},
+				|| async {
+					fs::create_dir_all(&target_full_path)
+						.await
+						.map_err(|e| FileIOError::from((&target_full_path, e)).into())
+				},
+			)
+			.await?;
+
+		let children = spawn_blocking({
+			let image_path = image_path.clone();
+			move || DiskImage::open(image_path)?.read_dir(&image_entry.path)
+		})
+		.await?
+		.map_err(FileSystemJobsError::from)?;
+
+		let image_file_data = state.steps[0].source_file_data.clone();
+		for child in children {
+			state.steps.push_back(FileCopierJobStep {
+				source_file_data: image_file_data.clone(),
+				target_full_path: target_full_path.join(&child.name),
+				image_entry: Some(child),
+			});
+		}
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+	} else {
+		match fs::metadata(&target_full_path).await {
+			Ok(_) => {
+				warn!(
+					"Skipping {} as it would be overwritten",
+					target_full_path.display()
+				);
+			}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				trace!(
+					"Copying '{}' out of {} to {}",
+					image_entry.path,
+					image_path.display(),
+					target_full_path.display()
+				);
+
+				// The sizes of the image and the target never match, so a copy interrupted by a
+				// crash is always removed and done again
+				ctx.journal()
+					.await?
+					.execute(
+						state.step_number,
+						JournalOperation::Copy {
+							source: image_path.clone(),
+							target: target_full_path.clone(),
+						},
+						|| async {
+							spawn_blocking(move || {
+								let mut target = File::create(&target_full_path)
+									.map_err(|e| FileIOError::from((&target_full_path, e)))?;
+
+								DiskImage::open(image_path)?
+									.copy_file(&image_entry.path, &mut target)
+									.map(|_| ())
+							})
+							.await?
+							.map_err(|e| FileSystemJobsError::from(e).into())
+						},
+					)
+					.await?;
+			}
+			Err(e) => return Err(FileIOError::from((target_full_path, e)).into()),
+		}
 	}
+
+	ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+		state.step_number + 1,
+	)]);
+
+	Ok(())
 }
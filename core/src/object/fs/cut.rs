@@ -1,12 +1,17 @@
 use crate::{
 	extract_job_data, invalidate_query,
 	job::{
-		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, JournalOperation, StatefulJob,
+		WorkerContext,
 	},
 	library::Library,
-	object::fs::{construct_target_filename, error::FileSystemJobsError},
+	object::{
+		fs::{construct_target_filename, error::FileSystemJobsError},
+		groups::with_group_members,
+	},
 	prisma::{file_path, location},
-	util::error::FileIOError,
+	util::{error::FileIOError, long_path::to_extended_length},
+	volume::capabilities::volumes_of,
 };
 
 use std::{hash::Hash, path::PathBuf};
@@ -16,7 +21,9 @@ use specta::Type;
 use tokio::{fs, io};
 use tracing::{trace, warn};
 
-use super::{fetch_source_and_target_location_paths, get_many_files_datas, FileData};
+use super::{
+	fetch_source_and_target_location_paths, get_many_files_datas, move_across_volumes, FileData,
+};
 
 pub struct FileCutterJob {}
 
@@ -26,15 +33,29 @@ pub struct FileCutterJobInit {
 	pub target_location_id: location::id::Type,
 	pub sources_file_path_ids: Vec<file_path::id::Type>,
 	pub target_location_relative_directory_path: PathBuf,
+	/// Also acts on the other files of their groups, see `object::groups`
+	#[serde(default)]
+	#[specta(optional)]
+	pub with_groups: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileCutterJobState {
 	full_target_directory_path: PathBuf,
+	/// Renames can't cross volumes, so such moves are copies removing the source afterwards
+	#[serde(default)]
+	across_volumes: bool,
+	/// If the target volume supports sparse files, for moves across volumes
+	#[serde(default)]
+	keep_holes: bool,
 }
 
 impl JobInitData for FileCutterJobInit {
 	type Job = FileCutterJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.source_location_id)
+	}
 }
 
 #[async_trait::async_trait]
@@ -64,19 +85,32 @@ impl StatefulJob for FileCutterJob {
 			)
 			.await?;
 
+		let volumes = volumes_of(&[&sources_location_path, &targets_location_path]).await;
+		let (across_volumes, keep_holes) = match (&volumes[0], &volumes[1]) {
+			(Some(source), Some(target)) => (
+				source.mount_point != target.mount_point,
+				target.capabilities.sparse_files,
+			),
+			_ => (false, false),
+		};
+
 		targets_location_path.push(&state.init.target_location_relative_directory_path);
 
 		state.data = Some(FileCutterJobState {
 			full_target_directory_path: targets_location_path,
+			across_volumes,
+			keep_holes,
 		});
 
-		state.steps = get_many_files_datas(
-			db,
-			&sources_location_path,
-			&state.init.sources_file_path_ids,
-		)
-		.await?
-		.into();
+		let sources_file_path_ids = if state.init.with_groups {
+			with_group_members(db, &state.init.sources_file_path_ids).await?
+		} else {
+			state.init.sources_file_path_ids.clone()
+		};
+
+		state.steps = get_many_files_datas(db, &sources_location_path, &sources_file_path_ids)
+			.await?
+			.into();
 
 		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
 
@@ -92,9 +126,12 @@ impl StatefulJob for FileCutterJob {
 
 		let step = &state.steps[0];
 
-		let full_output = data
-			.full_target_directory_path
-			.join(construct_target_filename(step, &None)?);
+		let full_output = to_extended_length(
+			&data
+				.full_target_directory_path
+				.join(construct_target_filename(step, &None)?),
+		)
+		.into_owned();
 
 		if step.full_path.parent().ok_or(JobError::Path)?
 			== full_output.parent().ok_or(JobError::Path)?
@@ -121,9 +158,27 @@ impl StatefulJob for FileCutterJob {
 					full_output.display()
 				);
 
-				fs::rename(&step.full_path, &full_output)
-					.await
-					.map_err(|e| FileIOError::from((&step.full_path, e)))?;
+				let (source, target) = (step.full_path.clone(), full_output.clone());
+				let operation = if data.across_volumes {
+					JournalOperation::MoveAcrossVolumes { source, target }
+				} else {
+					JournalOperation::Move { source, target }
+				};
+
+				ctx.journal()
+					.await?
+					.execute(state.step_number, operation, || async {
+						if data.across_volumes {
+							move_across_volumes(&step.full_path, &full_output, data.keep_holes)
+								.await
+								.map_err(JobError::from)
+						} else {
+							fs::rename(&step.full_path, &full_output)
+								.await
+								.map_err(|e| FileIOError::from((&step.full_path, e)).into())
+						}
+					})
+					.await?;
 
 				ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
 					state.step_number + 1,
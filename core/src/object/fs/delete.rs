@@ -1,9 +1,11 @@
 use crate::{
 	invalidate_query,
 	job::{
-		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, JournalOperation, StatefulJob,
+		WorkerContext,
 	},
 	library::Library,
+	object::{groups::with_group_members, special::remove_file_or_link},
 	prisma::{file_path, location},
 	util::{db::maybe_missing, error::FileIOError},
 };
@@ -22,10 +24,18 @@ pub struct FileDeleterJob {}
 pub struct FileDeleterJobInit {
 	pub location_id: location::id::Type,
 	pub file_path_ids: Vec<file_path::id::Type>,
+	/// Also acts on the other files of their groups, see `object::groups`
+	#[serde(default)]
+	#[specta(optional)]
+	pub with_groups: bool,
 }
 
 impl JobInitData for FileDeleterJobInit {
 	type Job = FileDeleterJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
 }
 
 #[async_trait::async_trait]
@@ -47,10 +57,16 @@ impl StatefulJob for FileDeleterJob {
 	) -> Result<(), JobError> {
 		let Library { db, .. } = &ctx.library;
 
+		let file_path_ids = if state.init.with_groups {
+			with_group_members(db, &state.init.file_path_ids).await?
+		} else {
+			state.init.file_path_ids.clone()
+		};
+
 		state.steps = get_many_files_datas(
 			db,
 			get_location_path_from_location_id(db, state.init.location_id).await?,
-			&state.init.file_path_ids,
+			&file_path_ids,
 		)
 		.await?
 		.into_iter()
@@ -71,12 +87,26 @@ impl StatefulJob for FileDeleterJob {
 		// need to handle stuff such as querying prisma for all paths of a file, and deleting all of those if requested (with a checkbox in the ui)
 		// maybe a files.countOccurances/and or files.getPath(location_id, path_id) to show how many of these files would be deleted (and where?)
 
-		if maybe_missing(step.file_path.is_dir, "file_path.is_dir")? {
-			fs::remove_dir_all(&step.full_path).await
-		} else {
-			fs::remove_file(&step.full_path).await
-		}
-		.map_err(|e| FileIOError::from((&step.full_path, e)))?;
+		let is_dir = maybe_missing(step.file_path.is_dir, "file_path.is_dir")?;
+
+		ctx.journal()
+			.await?
+			.execute(
+				state.step_number,
+				JournalOperation::Delete {
+					path: step.full_path.clone(),
+					is_dir,
+				},
+				|| async {
+					if is_dir {
+						fs::remove_dir_all(&step.full_path).await
+					} else {
+						remove_file_or_link(&step.full_path).await
+					}
+					.map_err(|e| FileIOError::from((&step.full_path, e)).into())
+				},
+			)
+			.await?;
 
 		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
 			state.step_number + 1,
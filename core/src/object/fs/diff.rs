@@ -0,0 +1,600 @@
+//! Comparing two directories, like a backup with the directory it was made from. Both sides are
+//! compared from what's indexed of them, so they may be in locations of other devices of the
+//! library. Files are the same when their checksums match, or their cas_ids and sizes, or, when
+//! neither side is identified yet, their sizes and modification dates.
+//!
+//! A file missing from one side and found at another path of it with the same content is reported
+//! as moved rather than as removed and added. Directories only on one side are reported once, not
+//! along with everything in them.
+
+use crate::{
+	extract_job_data, extract_job_data_mut,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{check_file_path_exists, IsolatedFilePathData},
+		LocationError,
+	},
+	prisma::{file_path, location, PrismaClient},
+};
+
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	path::PathBuf,
+};
+
+use chrono::{DateTime, FixedOffset};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tracing::info;
+
+use super::{copy::FileCopierJobInit, error::FileSystemJobsError};
+
+/// Each list of a diff kept in the job report is cut at this many entries
+const MAX_LISTED_ENTRIES: usize = 10_000;
+
+#[derive(Error, Debug)]
+pub enum DirectoryDiffError {
+	#[error("directory not found <location_id='{0}', path='{1}'>")]
+	DirectoryNotFound(location::id::Type, String),
+	#[error("a directory can't be compared with itself")]
+	SameDirectory,
+	#[error("files can only be copied between locations of this device <location_id='{0}'>")]
+	NotOnThisDevice(location::id::Type),
+	#[error(transparent)]
+	Location(#[from] LocationError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<DirectoryDiffError> for rspc::Error {
+	fn from(err: DirectoryDiffError) -> Self {
+		match err {
+			DirectoryDiffError::Location(e) => e.into(),
+			DirectoryDiffError::JobManager(e) => e.into(),
+			DirectoryDiffError::DirectoryNotFound(..) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			DirectoryDiffError::SameDirectory | DirectoryDiffError::NotOnThisDevice(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			DirectoryDiffError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// A directory of a location, its root when `path` is empty
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Type)]
+pub struct DiffSide {
+	pub location_id: location::id::Type,
+	/// Relative to the location
+	pub path: String,
+}
+
+impl DiffSide {
+	/// The materialized path of the entries of the directory, checking it's indexed
+	async fn children_path(&self, db: &PrismaClient) -> Result<String, DirectoryDiffError> {
+		find_location_by_id(db, self.location_id).await?;
+
+		let path = self.path.trim_matches('/');
+		if path.is_empty() {
+			return Ok("/".to_string());
+		}
+
+		let directory = format!("{path}/");
+		let iso_file_path = IsolatedFilePathData::from_relative_str(self.location_id, &directory);
+
+		if !check_file_path_exists::<LocationError>(&iso_file_path, db).await? {
+			return Err(DirectoryDiffError::DirectoryNotFound(
+				self.location_id,
+				self.path.clone(),
+			));
+		}
+
+		Ok(iso_file_path
+			.materialized_path_for_children()
+			.unwrap_or_else(|| "/".to_string()))
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiffEntry {
+	pub file_path_id: file_path::id::Type,
+	/// Relative to the directory compared
+	pub path: String,
+	pub is_dir: bool,
+	pub size_in_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModifiedEntry {
+	pub path: String,
+	pub left: DiffEntry,
+	pub right: DiffEntry,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MovedEntry {
+	pub left: DiffEntry,
+	pub right: DiffEntry,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct DirectoryDiff {
+	/// Only on the right
+	pub added: Vec<DiffEntry>,
+	/// Only on the left
+	pub removed: Vec<DiffEntry>,
+	pub modified: Vec<ModifiedEntry>,
+	pub moved: Vec<MovedEntry>,
+	pub unchanged_count: usize,
+	/// Some lists were cut at `MAX_LISTED_ENTRIES`
+	pub truncated: bool,
+}
+
+file_path::select!(file_path_for_diff {
+	id
+	materialized_path
+	name
+	extension
+	is_dir
+	cas_id
+	integrity_checksum
+	size_in_bytes
+	date_modified
+});
+
+struct Entry {
+	data: file_path_for_diff::Data,
+	path: String,
+}
+
+impl Entry {
+	fn is_dir(&self) -> bool {
+		self.data.is_dir == Some(true)
+	}
+
+	fn size(&self) -> u64 {
+		self.data
+			.size_in_bytes
+			.as_deref()
+			.and_then(|size| size.parse().ok())
+			.unwrap_or_default()
+	}
+
+	/// What identifies the content of a file, `None` until it's identified
+	fn content_key(&self) -> Option<(&str, u64)> {
+		self.data
+			.integrity_checksum
+			.as_deref()
+			.or(self.data.cas_id.as_deref())
+			.map(|hash| (hash, self.size()))
+	}
+
+	fn to_diff_entry(&self) -> DiffEntry {
+		DiffEntry {
+			file_path_id: self.data.id,
+			path: self.path.clone(),
+			is_dir: self.is_dir(),
+			size_in_bytes: self.size(),
+		}
+	}
+}
+
+fn same_content(left: &Entry, right: &Entry) -> bool {
+	let (l, r) = (&left.data, &right.data);
+
+	if let (Some(l), Some(r)) = (&l.integrity_checksum, &r.integrity_checksum) {
+		return l == r;
+	}
+
+	if let (Some(l), Some(r)) = (&l.cas_id, &r.cas_id) {
+		return l == r && left.size() == right.size();
+	}
+
+	left.size() == right.size() && same_date(l.date_modified, r.date_modified)
+}
+
+fn same_date(left: Option<DateTime<FixedOffset>>, right: Option<DateTime<FixedOffset>>) -> bool {
+	matches!((left, right), (Some(l), Some(r)) if l.timestamp() == r.timestamp())
+}
+
+/// Everything below a directory, by path relative to it
+async fn entries_below(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+	children_path: &str,
+) -> Result<BTreeMap<String, Entry>, QueryError> {
+	Ok(db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::materialized_path::starts_with(children_path.to_string()),
+		])
+		.select(file_path_for_diff::select())
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|data| {
+			let parent = data
+				.materialized_path
+				.as_deref()?
+				.get(children_path.len()..)?;
+			let name = data.name.as_deref().filter(|name| !name.is_empty())?;
+			let path = match data.extension.as_deref() {
+				Some(extension) if !extension.is_empty() && data.is_dir != Some(true) => {
+					format!("{parent}{name}.{extension}")
+				}
+				_ => format!("{parent}{name}"),
+			};
+
+			Some((path.clone(), Entry { data, path }))
+		})
+		.collect())
+}
+
+/// Compares the directories of both sides
+pub async fn diff_directories(
+	db: &PrismaClient,
+	left: &DiffSide,
+	right: &DiffSide,
+) -> Result<DirectoryDiff, DirectoryDiffError> {
+	let left_children = left.children_path(db).await?;
+	let right_children = right.children_path(db).await?;
+
+	if left.location_id == right.location_id && left_children == right_children {
+		return Err(DirectoryDiffError::SameDirectory);
+	}
+
+	let left_entries = entries_below(db, left.location_id, &left_children).await?;
+	let right_entries = entries_below(db, right.location_id, &right_children).await?;
+
+	Ok(compare(&left_entries, &right_entries))
+}
+
+fn compare(left: &BTreeMap<String, Entry>, right: &BTreeMap<String, Entry>) -> DirectoryDiff {
+	let mut diff = DirectoryDiff::default();
+
+	for (path, l) in left {
+		let Some(r) = right.get(path) else {
+			continue;
+		};
+
+		if l.is_dir() || r.is_dir() {
+			if l.is_dir() == r.is_dir() {
+				diff.unchanged_count += 1;
+			} else {
+				diff.modified.push(ModifiedEntry {
+					path: path.clone(),
+					left: l.to_diff_entry(),
+					right: r.to_diff_entry(),
+				});
+			}
+		} else if same_content(l, r) {
+			diff.unchanged_count += 1;
+		} else {
+			diff.modified.push(ModifiedEntry {
+				path: path.clone(),
+				left: l.to_diff_entry(),
+				right: r.to_diff_entry(),
+			});
+		}
+	}
+
+	let only_left = left
+		.iter()
+		.filter(|(path, _)| !right.contains_key(*path))
+		.collect::<BTreeMap<_, _>>();
+	let only_right = right
+		.iter()
+		.filter(|(path, _)| !left.contains_key(*path))
+		.collect::<BTreeMap<_, _>>();
+
+	// Each file only on the right can be the new place of a single one only on the left
+	let mut added_by_content = HashMap::<_, Vec<&Entry>>::new();
+	for entry in only_right.values().filter(|entry| !entry.is_dir()) {
+		if let Some(key) = entry.content_key() {
+			added_by_content.entry(key).or_default().push(entry);
+		}
+	}
+
+	let mut moved = HashSet::new();
+	for entry in only_left.values().filter(|entry| !entry.is_dir()) {
+		let Some(to) = entry
+			.content_key()
+			.and_then(|key| added_by_content.get_mut(&key))
+			.and_then(Vec::pop)
+		else {
+			continue;
+		};
+
+		moved.insert(&entry.path);
+		moved.insert(&to.path);
+		diff.moved.push(MovedEntry {
+			left: entry.to_diff_entry(),
+			right: to.to_diff_entry(),
+		});
+	}
+
+	diff.removed = top_most(&only_left, &moved);
+	diff.added = top_most(&only_right, &moved);
+
+	for len in [
+		diff.added.len(),
+		diff.removed.len(),
+		diff.modified.len(),
+		diff.moved.len(),
+	] {
+		diff.truncated |= len > MAX_LISTED_ENTRIES;
+	}
+	diff.added.truncate(MAX_LISTED_ENTRIES);
+	diff.removed.truncate(MAX_LISTED_ENTRIES);
+	diff.modified.truncate(MAX_LISTED_ENTRIES);
+	diff.moved.truncate(MAX_LISTED_ENTRIES);
+
+	diff
+}
+
+/// The entries only on one side, leaving out the ones moved and the ones inside a directory only
+/// on that side too
+fn top_most(only: &BTreeMap<&String, &Entry>, moved: &HashSet<&String>) -> Vec<DiffEntry> {
+	let paths = only
+		.keys()
+		.map(|path| path.as_str())
+		.collect::<HashSet<_>>();
+
+	only.values()
+		.filter(|entry| !moved.contains(&entry.path))
+		.filter(|entry| {
+			!entry
+				.path
+				.match_indices('/')
+				.any(|(i, _)| paths.contains(&entry.path[..i]))
+		})
+		.map(|entry| entry.to_diff_entry())
+		.collect()
+}
+
+pub struct DirectoryDiffJob {}
+
+/// `DirectoryDiffJobInit` compares two directories, the diff being the metadata of the job
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct DirectoryDiffJobInit {
+	pub left: DiffSide,
+	pub right: DiffSide,
+}
+
+impl JobInitData for DirectoryDiffJobInit {
+	type Job = DirectoryDiffJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.left.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DirectoryDiffJobData {
+	diff: DirectoryDiff,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for DirectoryDiffJob {
+	type Init = DirectoryDiffJobInit;
+	type Data = DirectoryDiffJobData;
+	type Step = ();
+
+	const NAME: &'static str = "directory_diff";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		state.steps = [()].into_iter().collect();
+		state.data = Some(DirectoryDiffJobData::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(1)]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let diff = diff_directories(&ctx.library.db, &state.init.left, &state.init.right)
+			.await
+			.map_err(FileSystemJobsError::from)?;
+
+		extract_job_data_mut!(state).diff = diff;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(1)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, _: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let diff = &extract_job_data!(state).diff;
+
+		info!(
+			"Finalizing directory diff job: {} added, {} removed, {} modified, {} moved",
+			diff.added.len(),
+			diff.removed.len(),
+			diff.modified.len(),
+			diff.moved.len()
+		);
+
+		Ok(Some(serde_json::to_value(diff)?))
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Type)]
+pub enum DiffDirection {
+	LeftToRight,
+	RightToLeft,
+}
+
+/// Copies what's missing on one side from the other, the files moved excepted, with a copier job
+/// for each directory receiving files. Returns how many jobs were started.
+pub async fn copy_missing(
+	library: &Library,
+	left: DiffSide,
+	right: DiffSide,
+	direction: DiffDirection,
+) -> Result<u32, DirectoryDiffError> {
+	let (source, target) = match direction {
+		DiffDirection::LeftToRight => (left, right),
+		DiffDirection::RightToLeft => (right, left),
+	};
+
+	for location_id in [source.location_id, target.location_id] {
+		let location = find_location_by_id(&library.db, location_id).await?;
+		if location.node_id != Some(library.node_local_id) {
+			return Err(DirectoryDiffError::NotOnThisDevice(location_id));
+		}
+	}
+
+	let diff = diff_directories(&library.db, &source, &target).await?;
+
+	// What's missing on the target is what the diff from the source calls removed, and each entry
+	// goes in a directory already there, as only the top most ones are listed
+	let mut by_directory = BTreeMap::<PathBuf, Vec<file_path::id::Type>>::new();
+	for entry in diff.removed {
+		let parent = entry.path.rsplit_once('/').map_or("", |(parent, _)| parent);
+
+		by_directory
+			.entry(PathBuf::from(target.path.trim_matches('/')).join(parent))
+			.or_default()
+			.push(entry.file_path_id);
+	}
+
+	let jobs = by_directory.len() as u32;
+
+	for (directory, sources_file_path_ids) in by_directory {
+		library
+			.spawn_job(FileCopierJobInit {
+				source_location_id: source.location_id,
+				target_location_id: target.location_id,
+				sources_file_path_ids,
+				target_location_relative_directory_path: directory,
+				target_file_name_suffix: None,
+				disk_image_entries: vec![],
+				with_groups: false,
+				scrub_metadata: vec![],
+			})
+			.await?;
+	}
+
+	Ok(jobs)
+}
+
+async fn find_location_by_id(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<location::Data, DirectoryDiffError> {
+	Ok(db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(id: i32, path: &str, is_dir: bool, cas_id: Option<&str>, size: u64) -> Entry {
+		let (materialized_path, name) = match path.rsplit_once('/') {
+			Some((parent, name)) => (format!("/{parent}/"), name),
+			None => ("/".to_string(), path),
+		};
+
+		Entry {
+			data: file_path_for_diff::Data {
+				id,
+				materialized_path: Some(materialized_path),
+				name: Some(name.to_string()),
+				extension: None,
+				is_dir: Some(is_dir),
+				cas_id: cas_id.map(str::to_string),
+				integrity_checksum: None,
+				size_in_bytes: Some(size.to_string()),
+				date_modified: None,
+			},
+			path: path.to_string(),
+		}
+	}
+
+	fn tree(entries: Vec<Entry>) -> BTreeMap<String, Entry> {
+		entries
+			.into_iter()
+			.map(|entry| (entry.path.clone(), entry))
+			.collect()
+	}
+
+	#[test]
+	fn added_removed_modified_and_moved() {
+		let left = tree(vec![
+			entry(1, "same", false, Some("a"), 1),
+			entry(2, "changed", false, Some("b"), 2),
+			entry(3, "old name", false, Some("c"), 3),
+			entry(4, "gone", true, None, 0),
+			entry(5, "gone/inside", false, Some("d"), 4),
+		]);
+		let right = tree(vec![
+			entry(11, "same", false, Some("a"), 1),
+			entry(12, "changed", false, Some("e"), 5),
+			entry(13, "new name", false, Some("c"), 3),
+			entry(14, "new", false, Some("f"), 6),
+		]);
+
+		let diff = compare(&left, &right);
+
+		assert_eq!(diff.unchanged_count, 1);
+		assert_eq!(
+			diff.modified
+				.iter()
+				.map(|m| m.path.as_str())
+				.collect::<Vec<_>>(),
+			["changed"]
+		);
+		assert_eq!(
+			diff.moved
+				.iter()
+				.map(|m| (m.left.path.as_str(), m.right.path.as_str()))
+				.collect::<Vec<_>>(),
+			[("old name", "new name")]
+		);
+		assert_eq!(
+			diff.removed
+				.iter()
+				.map(|e| e.path.as_str())
+				.collect::<Vec<_>>(),
+			["gone"]
+		);
+		assert_eq!(
+			diff.added
+				.iter()
+				.map(|e| e.path.as_str())
+				.collect::<Vec<_>>(),
+			["new"]
+		);
+		assert!(!diff.truncated);
+	}
+}
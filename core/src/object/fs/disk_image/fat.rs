@@ -0,0 +1,102 @@
+//! FAT12, FAT16 and FAT32, the filesystems of floppy images and of most USB drive images
+
+use std::io::{self, Write};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use fatfs::{Dir, DirEntry, FileSystem, FsOptions};
+
+use super::{join_entry_path, split_entry_path, ImageEntry, ImageSlice};
+
+pub struct FatImage(FileSystem<ImageSlice>);
+
+/// A boot sector starts with a jump instruction, and says which FAT it is at the start of its
+/// FAT12/16 or its FAT32 extended fields
+pub(super) fn is_boot_sector(sector: &[u8]) -> bool {
+	sector.len() >= 512
+		&& sector[510..512] == [0x55, 0xAA]
+		&& matches!(sector[0], 0xEB | 0xE9)
+		&& (&sector[54..57] == b"FAT" || &sector[82..87] == b"FAT32")
+}
+
+impl FatImage {
+	pub(super) fn open(slice: ImageSlice) -> io::Result<Self> {
+		FileSystem::new(slice, FsOptions::new()).map(Self)
+	}
+
+	pub(super) fn read_dir(&mut self, path: &str) -> io::Result<Vec<ImageEntry>> {
+		let mut entries = vec![];
+
+		for entry in self.open_dir(path)?.iter() {
+			let entry = entry?;
+			let name = entry.file_name();
+			if name == "." || name == ".." {
+				continue;
+			}
+
+			entries.push(ImageEntry {
+				path: join_entry_path(path, &name),
+				is_dir: entry.is_dir(),
+				size: if entry.is_dir() { 0 } else { entry.len() },
+				date_modified: date_modified(&entry),
+				name,
+			});
+		}
+
+		Ok(entries)
+	}
+
+	pub(super) fn copy_file(&mut self, path: &str, target: &mut impl Write) -> io::Result<u64> {
+		let (parent, name) = split_entry_path(path).ok_or(io::ErrorKind::InvalidInput)?;
+
+		let entry = find_entry(&self.open_dir(parent)?, name)?;
+		if entry.is_dir() {
+			return Err(io::ErrorKind::InvalidInput.into());
+		}
+
+		io::copy(&mut entry.to_file(), target)
+	}
+
+	fn open_dir(&self, path: &str) -> io::Result<Dir<'_, ImageSlice>> {
+		let mut dir = self.0.root_dir();
+
+		for name in path.split('/').filter(|name| !name.is_empty()) {
+			let entry = find_entry(&dir, name)?;
+			if !entry.is_dir() {
+				return Err(io::ErrorKind::InvalidInput.into());
+			}
+			dir = entry.to_dir();
+		}
+
+		Ok(dir)
+	}
+}
+
+/// FAT names are case insensitive
+fn find_entry<'a>(dir: &Dir<'a, ImageSlice>, name: &str) -> io::Result<DirEntry<'a, ImageSlice>> {
+	for entry in dir.iter() {
+		let entry = entry?;
+		if entry.file_name().eq_ignore_ascii_case(name) {
+			return Ok(entry);
+		}
+	}
+
+	Err(io::ErrorKind::NotFound.into())
+}
+
+/// FAT dates have no time zone, they're taken as UTC
+fn date_modified(entry: &DirEntry<'_, ImageSlice>) -> Option<DateTime<Utc>> {
+	let modified = entry.modified();
+
+	NaiveDate::from_ymd_opt(
+		modified.date.year as i32,
+		modified.date.month as u32,
+		modified.date.day as u32,
+	)?
+	.and_hms_milli_opt(
+		modified.time.hour as u32,
+		modified.time.min as u32,
+		modified.time.sec as u32,
+		modified.time.millis as u32,
+	)
+	.map(|date| Utc.from_utc_datetime(&date))
+}
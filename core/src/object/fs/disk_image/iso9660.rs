@@ -0,0 +1,397 @@
+//! ISO 9660, the filesystem of CDs, DVDs and most installer images. When the image has Joliet
+//! descriptors their directories are read instead, as they keep the long Unicode names.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+
+use super::{join_entry_path, ImageEntry};
+
+/// The volume descriptors start at the 16th block, and they're always 2048 bytes long
+pub(super) const DESCRIPTORS_OFFSET: u64 = 16 * DESCRIPTOR_SIZE as u64;
+pub(super) const STANDARD_IDENTIFIER: &[u8; 5] = b"CD001";
+
+const DESCRIPTOR_SIZE: usize = 2048;
+/// Images don't have many descriptors, this only bounds the search in a broken one
+const MAX_DESCRIPTORS: u64 = 64;
+/// Directories are read whole, this bounds the allocation for a broken one
+const MAX_DIRECTORY_SIZE: u32 = 64 * 1024 * 1024;
+
+const PRIMARY_DESCRIPTOR: u8 = 1;
+const SUPPLEMENTARY_DESCRIPTOR: u8 = 2;
+const TERMINATOR_DESCRIPTOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 0b10;
+/// Set on every record of a file split in several extents but the last one
+const FLAG_MULTI_EXTENT: u8 = 0b1000_0000;
+
+pub struct Iso9660Image<R> {
+	reader: R,
+	block_size: u64,
+	joliet: bool,
+	root: Record,
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+	name: String,
+	is_dir: bool,
+	/// The first block and the length of each part of the data
+	extents: Vec<(u32, u32)>,
+	continues: bool,
+	date_modified: Option<DateTime<Utc>>,
+}
+
+impl Record {
+	fn size(&self) -> u64 {
+		self.extents.iter().map(|(_, len)| *len as u64).sum()
+	}
+}
+
+impl<R: Read + Seek> Iso9660Image<R> {
+	pub(super) fn open(mut reader: R) -> io::Result<Self> {
+		let mut primary = None;
+		let mut joliet = None;
+
+		for idx in 0..MAX_DESCRIPTORS {
+			let mut descriptor = [0; DESCRIPTOR_SIZE];
+			reader.seek(SeekFrom::Start(
+				DESCRIPTORS_OFFSET + idx * DESCRIPTOR_SIZE as u64,
+			))?;
+			reader.read_exact(&mut descriptor)?;
+
+			if &descriptor[1..6] != STANDARD_IDENTIFIER {
+				return Err(invalid_data("volume descriptor without its identifier"));
+			}
+
+			match descriptor[0] {
+				PRIMARY_DESCRIPTOR if primary.is_none() => primary = Some(descriptor),
+				SUPPLEMENTARY_DESCRIPTOR if joliet.is_none() && is_joliet(&descriptor) => {
+					joliet = Some(descriptor)
+				}
+				TERMINATOR_DESCRIPTOR => break,
+				_ => {}
+			}
+		}
+
+		let (descriptor, is_joliet) = match (joliet, primary) {
+			(Some(descriptor), _) => (descriptor, true),
+			(None, Some(descriptor)) => (descriptor, false),
+			(None, None) => return Err(invalid_data("no primary volume descriptor")),
+		};
+
+		let block_size = match u16::from_le_bytes([descriptor[128], descriptor[129]]) {
+			0 => DESCRIPTOR_SIZE as u64,
+			block_size => block_size as u64,
+		};
+
+		let root = parse_record(&descriptor[156..190], is_joliet)
+			.ok_or_else(|| invalid_data("malformed root directory record"))?;
+
+		Ok(Self {
+			reader,
+			block_size,
+			joliet: is_joliet,
+			root,
+		})
+	}
+
+	pub(super) fn read_dir(&mut self, path: &str) -> io::Result<Vec<ImageEntry>> {
+		let directory = self.find(path)?;
+		if !directory.is_dir {
+			return Err(io::ErrorKind::InvalidInput.into());
+		}
+
+		Ok(self
+			.records(&directory)?
+			.into_iter()
+			.map(|record| ImageEntry {
+				path: join_entry_path(path, &record.name),
+				size: if record.is_dir { 0 } else { record.size() },
+				name: record.name,
+				is_dir: record.is_dir,
+				date_modified: record.date_modified,
+			})
+			.collect())
+	}
+
+	pub(super) fn copy_file(&mut self, path: &str, target: &mut impl Write) -> io::Result<u64> {
+		let file = self.find(path)?;
+		if file.is_dir {
+			return Err(io::ErrorKind::InvalidInput.into());
+		}
+
+		let mut copied = 0;
+		for (block, len) in file.extents {
+			self.reader
+				.seek(SeekFrom::Start(block as u64 * self.block_size))?;
+			let extent_copied = io::copy(&mut (&mut self.reader).take(len as u64), target)?;
+			if extent_copied < len as u64 {
+				return Err(io::ErrorKind::UnexpectedEof.into());
+			}
+			copied += extent_copied;
+		}
+
+		Ok(copied)
+	}
+
+	fn find(&mut self, path: &str) -> io::Result<Record> {
+		let mut record = self.root.clone();
+
+		for name in path.split('/').filter(|name| !name.is_empty()) {
+			if !record.is_dir {
+				return Err(io::ErrorKind::NotFound.into());
+			}
+
+			record = self
+				.records(&record)?
+				.into_iter()
+				.find(|child| child.name == name)
+				.ok_or(io::ErrorKind::NotFound)?;
+		}
+
+		Ok(record)
+	}
+
+	fn records(&mut self, directory: &Record) -> io::Result<Vec<Record>> {
+		let Some(&(block, len)) = directory.extents.first() else {
+			return Ok(vec![]);
+		};
+		if len > MAX_DIRECTORY_SIZE {
+			return Err(invalid_data("directory too big"));
+		}
+
+		let mut data = vec![0; len as usize];
+		self.reader
+			.seek(SeekFrom::Start(block as u64 * self.block_size))?;
+		self.reader.read_exact(&mut data)?;
+
+		let block_size = self.block_size as usize;
+		let mut records = Vec::<Record>::new();
+		let mut offset = 0;
+		while offset < data.len() {
+			let record_len = data[offset] as usize;
+			if record_len == 0 {
+				// Records never cross blocks, the rest of this one is padding
+				offset = (offset / block_size + 1) * block_size;
+				continue;
+			}
+
+			let record = parse_record(&data[offset..], self.joliet)
+				.ok_or_else(|| invalid_data("malformed directory record"))?;
+			offset += record_len;
+
+			if record.name.is_empty() {
+				continue;
+			}
+
+			match records.last_mut() {
+				Some(previous) if previous.continues && previous.name == record.name => {
+					previous.extents.extend(record.extents);
+					previous.continues = record.continues;
+				}
+				_ => records.push(record),
+			}
+		}
+
+		Ok(records)
+	}
+}
+
+/// Joliet descriptors are supplementary ones with a UCS-2 escape sequence
+fn is_joliet(descriptor: &[u8]) -> bool {
+	matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E")
+}
+
+/// A directory record, with an empty name for the records of the directory itself and its parent
+fn parse_record(record: &[u8], joliet: bool) -> Option<Record> {
+	let len = *record.first()? as usize;
+	if len < 34 || record.len() < len {
+		return None;
+	}
+
+	let name_len = record[32] as usize;
+	if 33 + name_len > len {
+		return None;
+	}
+	let raw_name = &record[33..33 + name_len];
+	let flags = record[25];
+
+	let name = match raw_name {
+		[0] | [1] => String::new(),
+		_ if joliet => decode_ucs2(raw_name),
+		_ => String::from_utf8_lossy(raw_name).into_owned(),
+	};
+
+	let is_dir = flags & FLAG_DIRECTORY != 0;
+
+	Some(Record {
+		name: if is_dir { name } else { strip_version(name) },
+		is_dir,
+		extents: vec![(
+			u32::from_le_bytes([record[2], record[3], record[4], record[5]]),
+			u32::from_le_bytes([record[10], record[11], record[12], record[13]]),
+		)],
+		continues: flags & FLAG_MULTI_EXTENT != 0,
+		date_modified: parse_date(&record[18..25]),
+	})
+}
+
+fn decode_ucs2(raw_name: &[u8]) -> String {
+	char::decode_utf16(
+		raw_name
+			.chunks_exact(2)
+			.map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+	)
+	.map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+	.collect()
+}
+
+/// File names end with a `;1` version, and with a `.` when they have no extension
+fn strip_version(mut name: String) -> String {
+	if let Some(idx) = name.rfind(';') {
+		name.truncate(idx);
+	}
+	if name.ends_with('.') {
+		name.pop();
+	}
+
+	name
+}
+
+/// Years since 1900, month, day, hour, minute, second and the offset from UTC in 15 minutes
+fn parse_date(date: &[u8]) -> Option<DateTime<Utc>> {
+	FixedOffset::east_opt(date[6] as i8 as i32 * 15 * 60)?
+		.with_ymd_and_hms(
+			1900 + date[0] as i32,
+			date[1] as u32,
+			date[2] as u32,
+			date[3] as u32,
+			date[4] as u32,
+			date[5] as u32,
+		)
+		.single()
+		.map(|date| date.with_timezone(&Utc))
+}
+
+fn invalid_data(reason: &'static str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	use std::io::Cursor;
+
+	const BLOCK: usize = 2048;
+
+	fn record(name: &[u8], block: u32, len: u32, flags: u8) -> Vec<u8> {
+		let record_len = 33 + name.len() + (name.len() + 1) % 2;
+		let mut record = vec![0; record_len];
+		record[0] = record_len as u8;
+		record[2..6].copy_from_slice(&block.to_le_bytes());
+		record[10..14].copy_from_slice(&len.to_le_bytes());
+		// 2023-07-13 14:22:36 UTC
+		record[18..25].copy_from_slice(&[123, 7, 13, 14, 22, 36, 0]);
+		record[25] = flags;
+		record[32] = name.len() as u8;
+		record[33..33 + name.len()].copy_from_slice(name);
+		record
+	}
+
+	fn directory(block: u32, parent: u32, children: &[Vec<u8>]) -> Vec<u8> {
+		let mut data = record(&[0], block, BLOCK as u32, FLAG_DIRECTORY);
+		data.extend(record(&[1], parent, BLOCK as u32, FLAG_DIRECTORY));
+		for child in children {
+			data.extend(child);
+		}
+		data.resize(BLOCK, 0);
+		data
+	}
+
+	/// Blocks: 16 primary descriptor, 17 terminator, 18 root, 19 `BOOT`, 20 and 21 file contents
+	fn image() -> Vec<u8> {
+		let mut image = vec![0; 22 * BLOCK];
+
+		let mut primary = vec![0; BLOCK];
+		primary[0] = PRIMARY_DESCRIPTOR;
+		primary[1..6].copy_from_slice(STANDARD_IDENTIFIER);
+		primary[128..130].copy_from_slice(&(BLOCK as u16).to_le_bytes());
+		primary[156..190].copy_from_slice(&record(&[0], 18, BLOCK as u32, FLAG_DIRECTORY));
+		image[16 * BLOCK..17 * BLOCK].copy_from_slice(&primary);
+
+		image[17 * BLOCK] = TERMINATOR_DESCRIPTOR;
+		image[17 * BLOCK + 1..17 * BLOCK + 6].copy_from_slice(STANDARD_IDENTIFIER);
+
+		image[18 * BLOCK..19 * BLOCK].copy_from_slice(&directory(
+			18,
+			18,
+			&[
+				record(b"BOOT", 19, BLOCK as u32, FLAG_DIRECTORY),
+				record(b"README.TXT;1", 20, 6, 0),
+			],
+		));
+		image[19 * BLOCK..20 * BLOCK].copy_from_slice(&directory(
+			19,
+			18,
+			&[
+				// A file in two extents
+				record(b"KERNEL.;1", 20, 6, FLAG_MULTI_EXTENT),
+				record(b"KERNEL.;1", 21, 5, 0),
+			],
+		));
+
+		image[20 * BLOCK..20 * BLOCK + 6].copy_from_slice(b"hello ");
+		image[21 * BLOCK..21 * BLOCK + 5].copy_from_slice(b"world");
+
+		image
+	}
+
+	#[test]
+	fn read_directories_and_files() {
+		let mut image = Iso9660Image::open(Cursor::new(image())).unwrap();
+
+		let root = image.read_dir("/").unwrap();
+		assert_eq!(
+			root.iter()
+				.map(|entry| (entry.path.as_str(), entry.is_dir, entry.size))
+				.collect::<Vec<_>>(),
+			[("/BOOT", true, 0), ("/README.TXT", false, 6)]
+		);
+		assert_eq!(
+			root[1].date_modified,
+			Utc.with_ymd_and_hms(2023, 7, 13, 14, 22, 36).single()
+		);
+
+		let boot = image.read_dir("/BOOT").unwrap();
+		assert_eq!(boot.len(), 1);
+		assert_eq!(boot[0].path, "/BOOT/KERNEL");
+		assert_eq!(boot[0].size, 11);
+
+		let mut contents = vec![];
+		assert_eq!(image.copy_file("/BOOT/KERNEL", &mut contents).unwrap(), 11);
+		assert_eq!(contents, b"hello world");
+
+		assert_eq!(
+			image.read_dir("/README.TXT").unwrap_err().kind(),
+			io::ErrorKind::InvalidInput
+		);
+		assert_eq!(
+			image.read_dir("/EFI").unwrap_err().kind(),
+			io::ErrorKind::NotFound
+		);
+	}
+
+	#[test]
+	fn joliet_names() {
+		let name = "Résumé 2023.pdf;1"
+			.encode_utf16()
+			.flat_map(u16::to_be_bytes)
+			.collect::<Vec<_>>();
+
+		let parsed = parse_record(&record(&name, 20, 1, 0), true).unwrap();
+		assert_eq!(parsed.name, "Résumé 2023.pdf");
+	}
+}
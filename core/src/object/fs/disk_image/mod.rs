@@ -0,0 +1,585 @@
+//! Read-only browsing of disk images, like ISO installers or raw USB and floppy images, as if they
+//! were directories.
+//!
+//! The filesystem inside an image is found by its signature, not by the extension of the image:
+//! ISO 9660 (with Joliet names when present) and FAT are read, at the start of the image or in the
+//! first partition of an MBR partitioned one. DMG images are only read when their data isn't
+//! compressed and holds one of these filesystems, HFS+ and APFS aren't supported.
+//!
+//! Nothing is indexed when an image is found by the indexer. The entries of a directory of the
+//! image are stored the first time it's opened, see [`browse`], and copying them out goes through
+//! the [`FileCopierJob`](super::copy::FileCopierJob).
+
+use crate::{
+	library::Library,
+	location::file_path_helper::IsolatedFilePathData,
+	prisma::{disk_image_entry, file_path, PrismaClient, SortOrder},
+	util::{error::FileIOError, long_path::to_extended_length},
+};
+
+use std::{
+	fs::File,
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::spawn_blocking;
+use tracing::debug;
+
+use super::{error::FileSystemJobsError, get_location_path_from_location_id};
+
+mod fat;
+mod iso9660;
+
+use fat::FatImage;
+use iso9660::Iso9660Image;
+
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Error, Debug)]
+pub enum DiskImageError {
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("no supported filesystem in disk image: <path='{}'>", .0.display())]
+	UnsupportedFilesystem(Box<Path>),
+	#[error("malformed disk image: <path='{}', reason='{1}'>", .0.display())]
+	Malformed(Box<Path>, &'static str),
+	#[error("entry not found in disk image: <path='{0}'>")]
+	EntryNotFound(String),
+	#[error("entry of disk image isn't a directory: <path='{0}'>")]
+	NotADirectory(String),
+	#[error("entry of disk image isn't a file: <path='{0}'>")]
+	NotAFile(String),
+	#[error("entries can only be copied out of one disk image at a time")]
+	ManyImages,
+	#[error("the file isn't a disk image: <id='{0}'>")]
+	NotADiskImage(file_path::id::Type),
+	#[error("disk image reader task failed: {0}")]
+	JoinTask(#[from] tokio::task::JoinError),
+}
+
+/// Whether a file is offered to be browsed as a disk image
+pub fn is_disk_image(extension: &str) -> bool {
+	["iso", "img", "dmg", "cdr"]
+		.iter()
+		.any(|image_extension| extension.eq_ignore_ascii_case(image_extension))
+}
+
+/// A file or directory inside a disk image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageEntry {
+	/// The path inside the image, starting with a `/`
+	pub path: String,
+	pub name: String,
+	pub is_dir: bool,
+	pub size: u64,
+	pub date_modified: Option<DateTime<Utc>>,
+}
+
+/// An opened disk image. Every method blocks on the disk, so they're called from blocking tasks.
+pub struct DiskImage {
+	image_path: PathBuf,
+	filesystem: Filesystem,
+}
+
+enum Filesystem {
+	Iso9660(Iso9660Image<ImageSlice>),
+	Fat(FatImage),
+}
+
+impl DiskImage {
+	pub fn open(image_path: impl AsRef<Path>) -> Result<Self, DiskImageError> {
+		let image_path = image_path.as_ref();
+		let file = File::open(image_path).map_err(|e| FileIOError::from((image_path, e)))?;
+		let image_len = file
+			.metadata()
+			.map_err(|e| FileIOError::from((image_path, e)))?
+			.len();
+		let mut slice = ImageSlice::new(file, 0, image_len);
+
+		let start = find_filesystem(&mut slice)
+			.map_err(|e| FileIOError::from((image_path, e)))?
+			.ok_or_else(|| DiskImageError::UnsupportedFilesystem(image_path.into()))?;
+
+		debug!(
+			"Found {:?} filesystem at byte {} of disk image '{}'",
+			start.kind,
+			start.offset,
+			image_path.display()
+		);
+
+		let slice = ImageSlice::new(
+			slice.into_inner(),
+			start.offset,
+			image_len.saturating_sub(start.offset),
+		);
+
+		let filesystem = match start.kind {
+			FilesystemKind::Iso9660 => Iso9660Image::open(slice).map(Filesystem::Iso9660),
+			FilesystemKind::Fat => FatImage::open(slice).map(Filesystem::Fat),
+		}
+		.map_err(|e| read_error(image_path, e))?;
+
+		Ok(Self {
+			image_path: image_path.to_path_buf(),
+			filesystem,
+		})
+	}
+
+	/// The entries of the directory at `path` inside the image
+	pub fn read_dir(&mut self, path: &str) -> Result<Vec<ImageEntry>, DiskImageError> {
+		let path = normalize_entry_path(path)?;
+
+		match &mut self.filesystem {
+			Filesystem::Iso9660(image) => image.read_dir(&path),
+			Filesystem::Fat(image) => image.read_dir(&path),
+		}
+		.map_err(|e| self.entry_error(e, path, DiskImageError::NotADirectory))
+	}
+
+	/// The entry at `path` inside the image
+	pub fn entry(&mut self, path: &str) -> Result<ImageEntry, DiskImageError> {
+		let path = normalize_entry_path(path)?;
+		let (parent, name) =
+			split_entry_path(&path).ok_or_else(|| DiskImageError::EntryNotFound(path.clone()))?;
+
+		self.read_dir(parent)?
+			.into_iter()
+			.find(|entry| entry.name == name)
+			.ok_or(DiskImageError::EntryNotFound(path))
+	}
+
+	/// Writes the contents of the file at `path` inside the image to `target`
+	pub fn copy_file(
+		&mut self,
+		path: &str,
+		target: &mut impl Write,
+	) -> Result<u64, DiskImageError> {
+		let path = normalize_entry_path(path)?;
+
+		match &mut self.filesystem {
+			Filesystem::Iso9660(image) => image.copy_file(&path, target),
+			Filesystem::Fat(image) => image.copy_file(&path, target),
+		}
+		.map_err(|e| self.entry_error(e, path, DiskImageError::NotAFile))
+	}
+
+	/// The readers report a missing entry as [`io::ErrorKind::NotFound`], and an entry of the wrong
+	/// kind, a file to list or a directory to copy, as [`io::ErrorKind::InvalidInput`]
+	fn entry_error(
+		&self,
+		e: io::Error,
+		path: String,
+		wrong_kind: impl FnOnce(String) -> DiskImageError,
+	) -> DiskImageError {
+		match e.kind() {
+			io::ErrorKind::NotFound => DiskImageError::EntryNotFound(path),
+			io::ErrorKind::InvalidInput => wrong_kind(path),
+			_ => read_error(&self.image_path, e),
+		}
+	}
+}
+
+/// A window over part of an image file, so the readers see their filesystem starting at byte 0
+struct ImageSlice {
+	file: File,
+	start: u64,
+	len: u64,
+	position: u64,
+}
+
+impl ImageSlice {
+	fn new(file: File, start: u64, len: u64) -> Self {
+		Self {
+			file,
+			start,
+			len,
+			position: 0,
+		}
+	}
+
+	fn into_inner(self) -> File {
+		self.file
+	}
+
+	fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+		self.seek(SeekFrom::Start(offset))?;
+		self.read_exact(buf)
+	}
+}
+
+impl Read for ImageSlice {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let remaining = self.len.saturating_sub(self.position);
+		let max = buf
+			.len()
+			.min(usize::try_from(remaining).unwrap_or(usize::MAX));
+		if max == 0 {
+			return Ok(0);
+		}
+
+		self.file
+			.seek(SeekFrom::Start(self.start + self.position))?;
+		let read = self.file.read(&mut buf[..max])?;
+		self.position += read as u64;
+
+		Ok(read)
+	}
+}
+
+impl Seek for ImageSlice {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+			SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+		}
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek out of the image"))?;
+
+		self.position = position;
+
+		Ok(position)
+	}
+}
+
+/// The FAT reader wants a writable disk, but images are never written to
+impl Write for ImageSlice {
+	fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+		Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			"disk images are read-only",
+		))
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilesystemKind {
+	Iso9660,
+	Fat,
+}
+
+struct FilesystemStart {
+	kind: FilesystemKind,
+	offset: u64,
+}
+
+/// Looks for a filesystem at the start of the image, in the data of a DMG, and in the first
+/// partition of an MBR partition table
+fn find_filesystem(image: &mut ImageSlice) -> io::Result<Option<FilesystemStart>> {
+	let mut offsets = vec![0];
+	offsets.extend(dmg_data_fork_offset(image)?);
+
+	for offset in offsets {
+		if let Some(kind) = filesystem_at(image, offset)? {
+			return Ok(Some(FilesystemStart { kind, offset }));
+		}
+
+		if let Some(partition_offset) = first_mbr_partition_offset(image, offset)? {
+			if let Some(kind) = filesystem_at(image, partition_offset)? {
+				return Ok(Some(FilesystemStart {
+					kind,
+					offset: partition_offset,
+				}));
+			}
+		}
+	}
+
+	Ok(None)
+}
+
+fn filesystem_at(image: &mut ImageSlice, offset: u64) -> io::Result<Option<FilesystemKind>> {
+	let mut descriptor = [0; 6];
+	if read_if_in_image(image, offset + iso9660::DESCRIPTORS_OFFSET, &mut descriptor)?
+		&& &descriptor[1..] == iso9660::STANDARD_IDENTIFIER
+	{
+		return Ok(Some(FilesystemKind::Iso9660));
+	}
+
+	let mut boot_sector = [0; SECTOR_SIZE as usize];
+	if read_if_in_image(image, offset, &mut boot_sector)? && fat::is_boot_sector(&boot_sector) {
+		return Ok(Some(FilesystemKind::Fat));
+	}
+
+	Ok(None)
+}
+
+fn first_mbr_partition_offset(image: &mut ImageSlice, offset: u64) -> io::Result<Option<u64>> {
+	let mut mbr = [0; SECTOR_SIZE as usize];
+	if !read_if_in_image(image, offset, &mut mbr)? || mbr[510..] != [0x55, 0xAA] {
+		return Ok(None);
+	}
+
+	// The first entry of the partition table, its type at byte 4 and its first sector at byte 8
+	let entry = &mbr[446..462];
+	let first_sector = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+
+	Ok((entry[4] != 0 && first_sector != 0).then(|| offset + first_sector as u64 * SECTOR_SIZE))
+}
+
+/// The data of a DMG comes first, and a trailer at the end of the file says where it starts
+fn dmg_data_fork_offset(image: &mut ImageSlice) -> io::Result<Option<u64>> {
+	let Some(trailer_offset) = image.len.checked_sub(SECTOR_SIZE) else {
+		return Ok(None);
+	};
+
+	let mut trailer = [0; SECTOR_SIZE as usize];
+	image.read_at(trailer_offset, &mut trailer)?;
+
+	Ok((&trailer[..4] == b"koly").then(|| {
+		u64::from_be_bytes(
+			trailer[0x18..0x20]
+				.try_into()
+				.expect("slice with the size of an u64"),
+		)
+	}))
+}
+
+fn read_if_in_image(image: &mut ImageSlice, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+	if offset + buf.len() as u64 > image.len {
+		return Ok(false);
+	}
+
+	image.read_at(offset, buf)?;
+
+	Ok(true)
+}
+
+fn read_error(image_path: &Path, e: io::Error) -> DiskImageError {
+	if e.kind() == io::ErrorKind::InvalidData {
+		DiskImageError::Malformed(image_path.into(), "unreadable filesystem structures")
+	} else {
+		FileIOError::from((image_path, e)).into()
+	}
+}
+
+/// Paths inside images always start with a `/`, have no trailing `/` and no `.` or `..`
+fn normalize_entry_path(path: &str) -> Result<String, DiskImageError> {
+	let mut normalized = String::with_capacity(path.len() + 1);
+
+	for component in path
+		.split(['/', '\\'])
+		.filter(|c| !c.is_empty() && *c != ".")
+	{
+		if component == ".." {
+			return Err(DiskImageError::EntryNotFound(path.to_string()));
+		}
+		normalized.push('/');
+		normalized.push_str(component);
+	}
+
+	if normalized.is_empty() {
+		normalized.push('/');
+	}
+
+	Ok(normalized)
+}
+
+/// The parent path and the name of a normalized entry path, `None` for the root
+fn split_entry_path(path: &str) -> Option<(&str, &str)> {
+	let (parent, name) = path.rsplit_once('/')?;
+	(!name.is_empty()).then_some((if parent.is_empty() { "/" } else { parent }, name))
+}
+
+fn join_entry_path(parent: &str, name: &str) -> String {
+	if parent == "/" {
+		format!("/{name}")
+	} else {
+		format!("{parent}/{name}")
+	}
+}
+
+/// The entries of a directory of a disk image, stored the first time it's opened. The stored
+/// entries of an image are dropped when the image changes.
+pub async fn browse(
+	library: &Library,
+	file_path_id: file_path::id::Type,
+	path: &str,
+) -> Result<Vec<disk_image_entry::Data>, FileSystemJobsError> {
+	let Library { db, .. } = library;
+	let path = normalize_entry_path(path)?;
+
+	let image_file_path = db
+		.file_path()
+		.find_unique(file_path::id::equals(file_path_id))
+		.exec()
+		.await?
+		.ok_or(FileSystemJobsError::FilePathIdNotFound(file_path_id))?;
+
+	if image_file_path.is_dir.unwrap_or(true)
+		|| !image_file_path
+			.extension
+			.as_deref()
+			.map_or(false, is_disk_image)
+	{
+		return Err(DiskImageError::NotADiskImage(file_path_id).into());
+	}
+
+	let location_path = get_location_path_from_location_id(
+		db,
+		image_file_path
+			.location_id
+			.ok_or(FileSystemJobsError::FilePathIdNotFound(file_path_id))?,
+	)
+	.await?;
+	let image_path =
+		to_extended_length(&location_path.join(IsolatedFilePathData::try_from(&image_file_path)?))
+			.into_owned();
+
+	let root = ensure_root(db, &image_file_path).await?;
+
+	let directory = if path == "/" {
+		root
+	} else {
+		db.disk_image_entry()
+			.find_unique(disk_image_entry::file_path_id_path(
+				file_path_id,
+				path.clone(),
+			))
+			.exec()
+			.await?
+			.ok_or_else(|| DiskImageError::EntryNotFound(path.clone()))?
+	};
+
+	if !directory.is_dir {
+		return Err(DiskImageError::NotADirectory(path).into());
+	}
+
+	if directory.date_indexed.is_none() {
+		index_directory(db, file_path_id, image_path, &path).await?;
+	}
+
+	Ok(db
+		.disk_image_entry()
+		.find_many(vec![
+			disk_image_entry::file_path_id::equals(file_path_id),
+			disk_image_entry::parent_path::equals(path),
+		])
+		.order_by(disk_image_entry::is_dir::order(SortOrder::Desc))
+		.order_by(disk_image_entry::name::order(SortOrder::Asc))
+		.exec()
+		.await?)
+}
+
+/// The stored root of the image, which keeps the modification date of the image they were read from
+async fn ensure_root(
+	db: &PrismaClient,
+	image_file_path: &file_path::Data,
+) -> Result<disk_image_entry::Data, FileSystemJobsError> {
+	let root = db
+		.disk_image_entry()
+		.find_unique(disk_image_entry::file_path_id_path(
+			image_file_path.id,
+			"/".to_string(),
+		))
+		.exec()
+		.await?;
+
+	match root {
+		Some(root) if root.date_modified == image_file_path.date_modified => return Ok(root),
+		Some(_) => {
+			debug!(
+				"Disk image of file path {} changed, dropping its stored entries",
+				image_file_path.id
+			);
+			db.disk_image_entry()
+				.delete_many(vec![disk_image_entry::file_path_id::equals(
+					image_file_path.id,
+				)])
+				.exec()
+				.await?;
+		}
+		None => {}
+	}
+
+	Ok(db
+		.disk_image_entry()
+		.create(
+			file_path::id::equals(image_file_path.id),
+			"/".to_string(),
+			String::new(),
+			String::new(),
+			true,
+			"0".to_string(),
+			vec![disk_image_entry::date_modified::set(
+				image_file_path.date_modified,
+			)],
+		)
+		.exec()
+		.await?)
+}
+
+async fn index_directory(
+	db: &PrismaClient,
+	file_path_id: file_path::id::Type,
+	image_path: PathBuf,
+	path: &str,
+) -> Result<(), FileSystemJobsError> {
+	let entries = spawn_blocking({
+		let path = path.to_string();
+		move || DiskImage::open(image_path)?.read_dir(&path)
+	})
+	.await
+	.map_err(DiskImageError::from)??;
+
+	debug!(
+		"Indexing {} entries of '{path}' in the disk image of file path {file_path_id}",
+		entries.len()
+	);
+
+	db._batch((
+		db.disk_image_entry().create_many(
+			entries
+				.into_iter()
+				.map(|entry| {
+					disk_image_entry::create_unchecked(
+						file_path_id,
+						entry.path,
+						path.to_string(),
+						entry.name,
+						entry.is_dir,
+						entry.size.to_string(),
+						vec![disk_image_entry::date_modified::set(
+							entry.date_modified.map(Into::into),
+						)],
+					)
+				})
+				.collect(),
+		),
+		db.disk_image_entry().update(
+			disk_image_entry::file_path_id_path(file_path_id, path.to_string()),
+			vec![disk_image_entry::date_indexed::set(Some(Utc::now().into()))],
+		),
+	))
+	.await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn entry_paths() {
+		assert_eq!(normalize_entry_path("").unwrap(), "/");
+		assert_eq!(normalize_entry_path("/").unwrap(), "/");
+		assert_eq!(normalize_entry_path("boot//grub/").unwrap(), "/boot/grub");
+		assert_eq!(normalize_entry_path("\\EFI\\.\\BOOT").unwrap(), "/EFI/BOOT");
+		assert!(normalize_entry_path("/boot/../../etc").is_err());
+
+		assert_eq!(split_entry_path("/"), None);
+		assert_eq!(split_entry_path("/boot"), Some(("/", "boot")));
+		assert_eq!(split_entry_path("/boot/grub"), Some(("/boot", "grub")));
+
+		assert_eq!(join_entry_path("/", "boot"), "/boot");
+		assert_eq!(join_entry_path("/boot", "grub"), "/boot/grub");
+	}
+}
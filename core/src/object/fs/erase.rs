@@ -1,10 +1,12 @@
 use crate::{
 	extract_job_data_mut, invalidate_query,
 	job::{
-		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, JournalOperation, StatefulJob,
+		WorkerContext,
 	},
 	library::Library,
 	location::file_path_helper::IsolatedFilePathData,
+	object::{groups::with_group_members, special::remove_file_or_link},
 	prisma::{file_path, location},
 	util::{db::maybe_missing, error::FileIOError},
 };
@@ -36,10 +38,18 @@ pub struct FileEraserJobInit {
 	#[specta(type = String)]
 	#[serde_as(as = "DisplayFromStr")]
 	pub passes: usize,
+	/// Also acts on the other files of their groups, see `object::groups`
+	#[serde(default)]
+	#[specta(optional)]
+	pub with_groups: bool,
 }
 
 impl JobInitData for FileEraserJobInit {
 	type Job = FileEraserJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
 }
 
 #[derive(Serialize, Deserialize)]
@@ -69,7 +79,13 @@ impl StatefulJob for FileEraserJob {
 
 		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
 
-		state.steps = get_many_files_datas(db, &location_path, &state.init.file_path_ids)
+		let file_path_ids = if state.init.with_groups {
+			with_group_members(db, &state.init.file_path_ids).await?
+		} else {
+			state.init.file_path_ids.clone()
+		};
+
+		state.steps = get_many_files_datas(db, &location_path, &file_path_ids)
 			.await?
 			.into();
 
@@ -123,7 +139,8 @@ impl StatefulJob for FileEraserJob {
 								.map_err(|e| FileIOError::from((&children_path, e)))?
 								.is_dir(),
 						)
-						.map_err(FileSystemJobsError::from)?,
+						.map_err(FileSystemJobsError::from)?
+						.normalized(ctx.library.config.file_name_normalization),
 					)
 					.await?,
 				);
@@ -133,33 +150,60 @@ impl StatefulJob for FileEraserJob {
 			data.diretories_to_remove
 				.push(state.steps[0].full_path.clone());
 		} else {
-			let mut file = OpenOptions::new()
-				.read(true)
-				.write(true)
-				.open(&step.full_path)
-				.await
-				.map_err(|e| FileIOError::from((&step.full_path, e)))?;
-			let file_len = file
-				.metadata()
-				.await
-				.map_err(|e| FileIOError::from((&step.full_path, e)))?
-				.len();
-
-			sd_crypto::fs::erase::erase(&mut file, file_len as usize, state.init.passes).await?;
-
-			file.set_len(0)
-				.await
-				.map_err(|e| FileIOError::from((&step.full_path, e)))?;
-			file.flush()
-				.await
-				.map_err(|e| FileIOError::from((&step.full_path, e)))?;
-			drop(file);
-
-			trace!("Erasing file: {}", step.full_path.display());
-
-			fs::remove_file(&step.full_path)
-				.await
-				.map_err(|e| FileIOError::from((&step.full_path, e)))?;
+			ctx.journal()
+				.await?
+				.execute(
+					state.step_number,
+					JournalOperation::Erase {
+						path: step.full_path.clone(),
+					},
+					|| async {
+						// Erasing a link only removes it, what it points to isn't ours to erase
+						if fs::symlink_metadata(&step.full_path)
+							.await
+							.map_err(|e| FileIOError::from((&step.full_path, e)))?
+							.is_symlink()
+						{
+							return remove_file_or_link(&step.full_path)
+								.await
+								.map_err(|e| FileIOError::from((&step.full_path, e)).into());
+						}
+
+						let mut file = OpenOptions::new()
+							.read(true)
+							.write(true)
+							.open(&step.full_path)
+							.await
+							.map_err(|e| FileIOError::from((&step.full_path, e)))?;
+						let file_len = file
+							.metadata()
+							.await
+							.map_err(|e| FileIOError::from((&step.full_path, e)))?
+							.len();
+
+						sd_crypto::fs::erase::erase(
+							&mut file,
+							file_len as usize,
+							state.init.passes,
+						)
+						.await?;
+
+						file.set_len(0)
+							.await
+							.map_err(|e| FileIOError::from((&step.full_path, e)))?;
+						file.flush()
+							.await
+							.map_err(|e| FileIOError::from((&step.full_path, e)))?;
+						drop(file);
+
+						trace!("Erasing file: {}", step.full_path.display());
+
+						fs::remove_file(&step.full_path)
+							.await
+							.map_err(|e| FileIOError::from((&step.full_path, e)).into())
+					},
+				)
+				.await?;
 		}
 
 		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
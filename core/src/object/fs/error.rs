@@ -9,6 +9,11 @@ use std::path::Path;
 use prisma_client_rust::QueryError;
 use thiserror::Error;
 
+use super::{
+	archive::ArchiveError, diff::DirectoryDiffError, disk_image::DiskImageError,
+	mirror::FolderMirrorError, pdf::PdfError, versions::FileVersionError,
+};
+
 /// Error type for file system related jobs errors
 #[derive(Error, Debug)]
 pub enum FileSystemJobsError {
@@ -28,6 +33,20 @@ pub enum FileSystemJobsError {
 	MatchingSrcDest(Box<Path>),
 	#[error("action would overwrite another file: {}", .0.display())]
 	WouldOverwrite(Box<Path>),
+	#[error("name too long for the target volume: {}", .0.display())]
+	NameTooLong(Box<Path>),
 	#[error("missing-field: {0}")]
 	MissingField(#[from] MissingFieldError),
+	#[error(transparent)]
+	Archive(#[from] ArchiveError),
+	#[error(transparent)]
+	DiskImage(#[from] DiskImageError),
+	#[error(transparent)]
+	Pdf(#[from] PdfError),
+	#[error(transparent)]
+	DirectoryDiff(#[from] DirectoryDiffError),
+	#[error(transparent)]
+	FolderMirror(#[from] FolderMirrorError),
+	#[error(transparent)]
+	FileVersion(#[from] FileVersionError),
 }
@@ -0,0 +1,767 @@
+//! Exporting photos resized, converted and watermarked, like previews sent to a client. How they're
+//! exported is saved in profiles, so the same settings can be used for every shoot.
+//!
+//! The settings of a profile are copied into the job when it's spawned, changing the profile
+//! afterwards doesn't affect exports already running.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::{find_location, light_scan_location, location_with_indexer_rules, LocationError},
+	object::preview::{
+		estimate_image_thumbnail_memory,
+		font::{read_font, FontError},
+	},
+	prisma::{export_profile, file_path, location, PrismaClient},
+	util::error::FileIOError,
+};
+
+use std::{
+	collections::BTreeSet,
+	path::{Path, PathBuf},
+};
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use chrono::Utc;
+use image::{imageops, imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+use tracing::trace;
+
+use super::{
+	convert::{
+		decode_upright, encode_image, fit_within, resolve_conflict, resolve_target_directory,
+		ImageConversionFormat, ImageConversionOptions, MediaConversionError,
+	},
+	get_location_path_from_location_id, get_many_files_datas,
+	scrub::{scrub_exif, MetadataField, ScrubReport},
+	ConflictPolicy, FileData,
+};
+
+/// Of the width of the photo, when the profile doesn't say
+const DEFAULT_WATERMARK_SIZE: u8 = 20;
+const DEFAULT_WATERMARK_OPACITY: u8 = 60;
+/// Space between a watermark and the edges of the photo, in hundredths of its shortest side
+const WATERMARK_MARGIN: u32 = 2;
+/// The size text is measured at, before being scaled to the width of the watermark
+const TEXT_REFERENCE_SIZE: f32 = 100.0;
+
+#[derive(Error, Debug)]
+pub enum ExportProfileError {
+	#[error("export profile not found <id='{0}'>")]
+	NotFound(export_profile::id::Type),
+	#[error("an export profile needs a name")]
+	MissingName,
+	#[error("watermarks need some text and absolute paths to their image or font")]
+	InvalidWatermark,
+	#[error("invalid settings of export profile <id='{0}'>: {1}")]
+	InvalidSettings(export_profile::id::Type, serde_json::Error),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<ExportProfileError> for rspc::Error {
+	fn from(err: ExportProfileError) -> Self {
+		match err {
+			ExportProfileError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			ExportProfileError::MissingName | ExportProfileError::InvalidWatermark => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ExportProfileError::JobManager(e) => e.into(),
+			ExportProfileError::InvalidSettings(..) | ExportProfileError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum ImageExportError {
+	#[error(transparent)]
+	Conversion(#[from] MediaConversionError),
+	#[error("failed to load watermark: {0}")]
+	Watermark(#[from] image::ImageError),
+	#[error("failed to load watermark font: {0}")]
+	Font(#[from] FontError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+#[serde(tag = "type")]
+pub enum ResizePreset {
+	/// Keeps the size of the photos
+	Original,
+	/// 1024 pixels on the longest side
+	Small,
+	/// 2048 pixels on the longest side
+	Medium,
+	/// 3840 pixels on the longest side
+	Large,
+	Custom {
+		max_dimension: u32,
+	},
+}
+
+impl ResizePreset {
+	fn max_dimension(self) -> Option<u32> {
+		match self {
+			Self::Original => None,
+			Self::Small => Some(1024),
+			Self::Medium => Some(2048),
+			Self::Large => Some(3840),
+			Self::Custom { max_dimension } => Some(max_dimension.max(1)),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+pub enum WatermarkPosition {
+	TopLeft,
+	TopRight,
+	BottomLeft,
+	BottomRight,
+	Center,
+}
+
+impl WatermarkPosition {
+	/// Where the top left corner of a watermark goes on a photo
+	fn place(self, photo: (u32, u32), watermark: (u32, u32), margin: u32) -> (i64, i64) {
+		let (photo_width, photo_height) = (photo.0 as i64, photo.1 as i64);
+		let (width, height) = (watermark.0 as i64, watermark.1 as i64);
+		let margin = margin as i64;
+
+		match self {
+			Self::TopLeft => (margin, margin),
+			Self::TopRight => (photo_width - width - margin, margin),
+			Self::BottomLeft => (margin, photo_height - height - margin),
+			Self::BottomRight => (photo_width - width - margin, photo_height - height - margin),
+			Self::Center => ((photo_width - width) / 2, (photo_height - height) / 2),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Type)]
+#[serde(tag = "type")]
+pub enum WatermarkContent {
+	/// A logo, its transparency kept
+	Image { path: PathBuf },
+	/// Text set in white in the given font
+	Text { text: String, font_path: PathBuf },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Type)]
+pub struct Watermark {
+	pub content: WatermarkContent,
+	pub position: WatermarkPosition,
+	/// The width of the watermark in percent of the width of the photo, 20 by default
+	#[serde(default)]
+	#[specta(optional)]
+	pub size: Option<u8>,
+	/// From 1 to 100, 60 by default
+	#[serde(default)]
+	#[specta(optional)]
+	pub opacity: Option<u8>,
+}
+
+impl Watermark {
+	fn is_valid(&self) -> bool {
+		match &self.content {
+			WatermarkContent::Image { path } => path.is_absolute(),
+			WatermarkContent::Text { text, font_path } => {
+				!text.trim().is_empty() && font_path.is_absolute()
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Type)]
+pub struct ExportSettings {
+	pub resize: ResizePreset,
+	pub format: ImageConversionFormat,
+	/// From 1 to 100, the default of the format when empty
+	#[serde(default)]
+	#[specta(optional)]
+	pub quality: Option<u8>,
+	/// Leaves the EXIF metadata, like the GPS position, out of the exported photos
+	#[serde(default)]
+	#[specta(optional)]
+	pub strip_metadata: bool,
+	/// Only leaves some of the EXIF metadata out, when it isn't stripped whole
+	#[serde(default)]
+	#[specta(optional)]
+	pub scrub_metadata: Vec<MetadataField>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub watermark: Option<Watermark>,
+}
+
+impl ExportSettings {
+	fn conversion_options(&self) -> ImageConversionOptions {
+		ImageConversionOptions {
+			format: self.format,
+			quality: self.quality,
+			max_dimension: self.resize.max_dimension(),
+			strip_metadata: self.strip_metadata,
+		}
+	}
+
+	fn validate(&self) -> Result<(), ExportProfileError> {
+		match &self.watermark {
+			Some(watermark) if !watermark.is_valid() => Err(ExportProfileError::InvalidWatermark),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ExportProfile {
+	pub id: export_profile::id::Type,
+	pub name: String,
+	pub settings: ExportSettings,
+}
+
+impl TryFrom<export_profile::Data> for ExportProfile {
+	type Error = ExportProfileError;
+
+	fn try_from(data: export_profile::Data) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: data.id,
+			settings: serde_json::from_str(&data.settings)
+				.map_err(|e| ExportProfileError::InvalidSettings(data.id, e))?,
+			name: data.name,
+		})
+	}
+}
+
+pub async fn list_export_profiles(
+	db: &PrismaClient,
+) -> Result<Vec<ExportProfile>, ExportProfileError> {
+	db.export_profile()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(TryInto::try_into)
+		.collect()
+}
+
+#[derive(Type, Deserialize)]
+pub struct ExportProfileCreateArgs {
+	pub name: String,
+	pub settings: ExportSettings,
+}
+
+impl ExportProfileCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<ExportProfile, ExportProfileError> {
+		let name = self.name.trim().to_string();
+		if name.is_empty() {
+			return Err(ExportProfileError::MissingName);
+		}
+
+		self.settings.validate()?;
+
+		let created = library
+			.db
+			.export_profile()
+			.create(name, serialize_settings(&self.settings), vec![])
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "exportProfiles.list");
+
+		created.try_into()
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct ExportProfileUpdateArgs {
+	pub id: export_profile::id::Type,
+	#[specta(optional)]
+	pub name: Option<String>,
+	#[specta(optional)]
+	pub settings: Option<ExportSettings>,
+}
+
+impl ExportProfileUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<ExportProfile, ExportProfileError> {
+		let name = match self.name.map(|name| name.trim().to_string()) {
+			Some(name) if name.is_empty() => return Err(ExportProfileError::MissingName),
+			name => name,
+		};
+
+		if let Some(settings) = &self.settings {
+			settings.validate()?;
+		}
+
+		find_export_profile(&library.db, self.id).await?;
+
+		let updated = library
+			.db
+			.export_profile()
+			.update(
+				export_profile::id::equals(self.id),
+				[
+					name.map(export_profile::name::set),
+					self.settings
+						.as_ref()
+						.map(serialize_settings)
+						.map(export_profile::settings::set),
+					Some(export_profile::date_modified::set(Utc::now().into())),
+				]
+				.into_iter()
+				.flatten()
+				.collect(),
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "exportProfiles.list");
+
+		updated.try_into()
+	}
+}
+
+pub async fn delete_export_profile(
+	library: &Library,
+	id: export_profile::id::Type,
+) -> Result<(), ExportProfileError> {
+	find_export_profile(&library.db, id).await?;
+
+	library
+		.db
+		.export_profile()
+		.delete(export_profile::id::equals(id))
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "exportProfiles.list");
+
+	Ok(())
+}
+
+async fn find_export_profile(
+	db: &PrismaClient,
+	id: export_profile::id::Type,
+) -> Result<ExportProfile, ExportProfileError> {
+	db.export_profile()
+		.find_unique(export_profile::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(ExportProfileError::NotFound(id))?
+		.try_into()
+}
+
+fn serialize_settings(settings: &ExportSettings) -> String {
+	serde_json::to_string(settings).expect("export settings are always serializable")
+}
+
+#[derive(Type, Deserialize)]
+pub struct ImageExportArgs {
+	pub profile_id: export_profile::id::Type,
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+	/// Where the photos are exported, next to their originals when empty
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_id: Option<location::id::Type>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_relative_directory_path: Option<PathBuf>,
+	#[serde(default)]
+	pub conflict_policy: ConflictPolicy,
+}
+
+impl ImageExportArgs {
+	pub async fn spawn(self, library: &Library) -> Result<(), ExportProfileError> {
+		let profile = find_export_profile(&library.db, self.profile_id).await?;
+
+		library
+			.spawn_job(ImageExporterJobInit {
+				location_id: self.location_id,
+				file_path_ids: self.file_path_ids,
+				settings: profile.settings,
+				target_location_id: self.target_location_id,
+				target_location_relative_directory_path: self
+					.target_location_relative_directory_path,
+				conflict_policy: self.conflict_policy,
+			})
+			.await
+			.map_err(Into::into)
+	}
+}
+
+pub struct ImageExporterJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ImageExporterJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+	pub settings: ExportSettings,
+	pub target_location_id: Option<location::id::Type>,
+	pub target_location_relative_directory_path: Option<PathBuf>,
+	pub conflict_policy: ConflictPolicy,
+}
+
+impl JobInitData for ImageExporterJobInit {
+	type Job = ImageExporterJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ExportReport {
+	exported: usize,
+	/// Photos left out as something already existed at their target path
+	skipped_conflicts: usize,
+	failed: usize,
+	#[serde(default)]
+	scrubbed: ScrubReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageExporterJobData {
+	report: ExportReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ImageExporterJobStep {
+	Export {
+		source: FileData,
+		target_directory: PathBuf,
+	},
+	/// Adds the exported photos to the target location, which may not be watched
+	Register {
+		location_id: location::id::Type,
+		sub_path: PathBuf,
+	},
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ImageExporterJob {
+	type Init = ImageExporterJobInit;
+	type Data = ImageExporterJobData;
+	type Step = ImageExporterJobStep;
+
+	const NAME: &'static str = "image_exporter";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
+
+		let target = resolve_target_directory(
+			db,
+			state.init.target_location_id,
+			&state.init.target_location_relative_directory_path,
+		)
+		.await?;
+
+		let sources = get_many_files_datas(db, &location_path, &state.init.file_path_ids).await?;
+
+		// Photos exported next to their originals are seen by the watcher
+		let mut directories_to_register = BTreeSet::new();
+
+		state.steps = sources
+			.into_iter()
+			.filter(|source| source.file_path.is_dir != Some(true))
+			.map(|source| {
+				let target_directory = match &target {
+					Some((location_id, sub_path, target_directory)) => {
+						directories_to_register.insert((*location_id, sub_path.clone()));
+						target_directory.clone()
+					}
+					None => source
+						.full_path
+						.parent()
+						.map(Path::to_path_buf)
+						.unwrap_or_default(),
+				};
+
+				ImageExporterJobStep::Export {
+					source,
+					target_directory,
+				}
+			})
+			.collect();
+
+		state.steps.extend(
+			directories_to_register
+				.into_iter()
+				.map(|(location_id, sub_path)| ImageExporterJobStep::Register {
+					location_id,
+					sub_path,
+				}),
+		);
+
+		state.data = Some(ImageExporterJobData {
+			report: ExportReport::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let mut errors = vec![];
+
+		match &state.steps[0] {
+			ImageExporterJobStep::Export {
+				source,
+				target_directory,
+			} => {
+				let settings = state.init.settings.clone();
+				let conflict_policy = state.init.conflict_policy;
+				let source_path = source.full_path.clone();
+				let target_directory = target_directory.clone();
+				let data = extract_job_data_mut!(state);
+
+				ctx.progress(vec![JobReportUpdate::Message(format!(
+					"Exporting {}",
+					source_path.display()
+				))]);
+
+				let target = source_path
+					.file_stem()
+					.map(|stem| {
+						target_directory
+							.join(stem)
+							.with_extension(settings.format.extension())
+					})
+					.ok_or(JobError::OsStr)?;
+
+				match resolve_conflict(&source_path, target, conflict_policy).await? {
+					Some(target) => {
+						let _permit = ctx
+							.library
+							.memory_budget()
+							.acquire(estimate_image_thumbnail_memory(&source_path).await)
+							.await;
+
+						match export_image(&source_path, &target, &settings).await {
+							Ok(removed) => {
+								trace!(
+									"Exported {} to {}",
+									source_path.display(),
+									target.display()
+								);
+								data.report.exported += 1;
+								data.report.scrubbed.record(Some(&removed));
+							}
+							Err(e) => {
+								data.report.failed += 1;
+								errors.push(format!(
+									"Failed to export {}: {e}",
+									source_path.display()
+								));
+							}
+						}
+					}
+					None => {
+						trace!("Skipping {} as its target exists", source_path.display());
+						data.report.skipped_conflicts += 1;
+					}
+				}
+			}
+
+			ImageExporterJobStep::Register {
+				location_id,
+				sub_path,
+			} => {
+				let location = find_location(&ctx.library, *location_id)
+					.include(location_with_indexer_rules::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(*location_id))?;
+
+				light_scan_location(ctx.library.clone(), location, sub_path).await?;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(JobError::StepCompletedWithErrors(errors))
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::json!({
+			"init": state.init,
+			"report": extract_job_data!(state).report,
+		})))
+	}
+}
+
+async fn export_image(
+	source: &Path,
+	target: &Path,
+	settings: &ExportSettings,
+) -> Result<Vec<MetadataField>, ImageExportError> {
+	let options = settings.conversion_options();
+
+	let (encoded, removed) = block_in_place(|| -> Result<_, ImageExportError> {
+		let (img, mut exif) = decode_upright(source)?;
+		let mut img = fit_within(img, options.max_dimension);
+
+		if let Some(watermark) = &settings.watermark {
+			img = apply_watermark(img, watermark)?;
+		}
+
+		// Exported photos are encoded anew, without the XMP of the originals
+		let removed = match exif.as_mut() {
+			Some(exif) if !options.strip_metadata => scrub_exif(exif, &settings.scrub_metadata),
+			_ => vec![],
+		};
+
+		Ok((encode_image(img, exif, options)?, removed))
+	})?;
+
+	fs::write(target, encoded)
+		.await
+		.map_err(|e| MediaConversionError::from(FileIOError::from((target, e))))?;
+
+	Ok(removed)
+}
+
+fn apply_watermark(
+	img: DynamicImage,
+	watermark: &Watermark,
+) -> Result<DynamicImage, ImageExportError> {
+	let mut photo = img.into_rgba8();
+	let width = (photo.width()
+		* watermark
+			.size
+			.unwrap_or(DEFAULT_WATERMARK_SIZE)
+			.clamp(1, 100) as u32
+		/ 100)
+		.max(1);
+
+	let mut mark = match &watermark.content {
+		WatermarkContent::Image { path } => image::open(path)?
+			.resize(width, u32::MAX, FilterType::Lanczos3)
+			.into_rgba8(),
+		WatermarkContent::Text { text, font_path } => {
+			render_text(&read_font(font_path)?, text.trim(), width)?
+		}
+	};
+
+	let opacity = watermark
+		.opacity
+		.unwrap_or(DEFAULT_WATERMARK_OPACITY)
+		.clamp(1, 100) as u16;
+	for pixel in mark.pixels_mut() {
+		pixel[3] = (pixel[3] as u16 * opacity / 100) as u8;
+	}
+
+	let margin = photo.width().min(photo.height()) * WATERMARK_MARGIN / 100;
+	let (x, y) = watermark
+		.position
+		.place(photo.dimensions(), mark.dimensions(), margin);
+	imageops::overlay(&mut photo, &mark, x, y);
+
+	Ok(DynamicImage::ImageRgba8(photo))
+}
+
+/// Sets a line of white text on a transparent image, as wide as `width`
+fn render_text(font_data: &[u8], text: &str, width: u32) -> Result<RgbaImage, FontError> {
+	let font = FontRef::try_from_slice(font_data).map_err(|_| FontError::NothingToRender)?;
+
+	let reference = font.as_scaled(PxScale::from(TEXT_REFERENCE_SIZE));
+	let reference_width = text
+		.chars()
+		.map(|c| reference.h_advance(reference.glyph_id(c)))
+		.sum::<f32>();
+	if reference_width <= 0.0 {
+		return Err(FontError::NothingToRender);
+	}
+
+	let size = TEXT_REFERENCE_SIZE * width as f32 / reference_width;
+	let scaled = font.as_scaled(PxScale::from(size));
+	let height = ((scaled.ascent() - scaled.descent()).ceil() as u32).max(1);
+
+	let mut mark = RgbaImage::new(width, height);
+
+	let mut x = 0.0;
+	let mut previous = None;
+	for c in text.chars() {
+		let glyph_id = scaled.glyph_id(c);
+		if let Some(previous) = previous {
+			x += scaled.kern(previous, glyph_id);
+		}
+		previous = Some(glyph_id);
+
+		let glyph = glyph_id.with_scale_and_position(size, point(x, scaled.ascent()));
+		x += scaled.h_advance(glyph_id);
+
+		let Some(outlined) = font.outline_glyph(glyph) else {
+			continue;
+		};
+
+		let bounds = outlined.px_bounds();
+		outlined.draw(|glyph_x, glyph_y, coverage| {
+			let (x, y) = (
+				bounds.min.x as i64 + glyph_x as i64,
+				bounds.min.y as i64 + glyph_y as i64,
+			);
+
+			// Kerning may push the last glyph a little past the measured width
+			if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+				let pixel = mark.get_pixel_mut(x as u32, y as u32);
+				let alpha = (255.0 * coverage.min(1.0)) as u8;
+				*pixel = Rgba([255, 255, 255, alpha.max(pixel[3])]);
+			}
+		});
+	}
+
+	Ok(mark)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn watermarks_are_placed_within_margins() {
+		let photo = (1000, 800);
+		let mark = (200, 50);
+
+		assert_eq!(WatermarkPosition::TopLeft.place(photo, mark, 16), (16, 16));
+		assert_eq!(
+			WatermarkPosition::BottomRight.place(photo, mark, 16),
+			(784, 734)
+		);
+		assert_eq!(WatermarkPosition::Center.place(photo, mark, 16), (400, 375));
+	}
+}
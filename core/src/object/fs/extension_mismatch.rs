@@ -0,0 +1,702 @@
+//! Files whose extension disagrees with their content, like a `.jpg` which is a PNG or an `.mp4`
+//! which is a Matroska video, found by looking at the first bytes of files with an extension we
+//! know the signature of. A scan keeps a report of them per location, and fixing renames them to the
+//! usual extension of their content.
+//!
+//! Renaming keeps the file path, only its name and extension change, so the object, its tags and
+//! everything else attached to it stay. Files with an extension we know nothing of, or whose
+//! content isn't recognized, are never reported.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::privacy::LocationPrivacyError,
+	prisma::{extension_mismatch, file_path, location, object},
+	sync,
+	util::{
+		db::{chain_optional_iter, maybe_missing},
+		error::FileIOError,
+	},
+};
+
+use sd_file_ext::{extensions::Extension, kind::ObjectKind};
+
+use std::{
+	collections::{HashMap, HashSet},
+	path::Path,
+};
+
+use once_cell::sync::Lazy;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	fs::{self, File},
+	io::AsyncReadExt,
+};
+use tracing::{info, warn};
+
+use super::{
+	get_location_path_from_location_id, get_many_files_datas, ignore_events_for, FileData,
+};
+
+/// Enough for every signature below, the doctype of Matroska files being the farthest one
+const HEADER_SIZE: u64 = 64;
+
+/// Extensions of a kind of content, its usual one first
+type Extensions = &'static [&'static str];
+
+const JPEG: Extensions = &["jpg", "jpeg", "jpe", "jfif"];
+const PNG: Extensions = &["png", "apng"];
+const GIF: Extensions = &["gif"];
+const WEBP: Extensions = &["webp"];
+const BMP: Extensions = &["bmp", "dib"];
+/// Most camera raw formats are TIFF underneath
+const TIFF: Extensions = &[
+	"tiff", "tif", "dng", "cr2", "nef", "nrw", "arw", "srw", "pef", "3fr", "erf", "kdc", "dcr",
+	"mos", "iiq",
+];
+const JXL: Extensions = &["jxl"];
+const HEIF: Extensions = &["heic", "heif", "hif"];
+const AVIF: Extensions = &["avif"];
+const CR3: Extensions = &["cr3"];
+const PSD: Extensions = &["psd", "psb"];
+const PDF: Extensions = &["pdf", "ai"];
+/// Office documents, ebooks and packages are zip archives too
+const ZIP: Extensions = &[
+	"zip", "docx", "xlsx", "pptx", "odt", "ods", "odp", "odg", "epub", "jar", "apk", "aab", "ipa",
+	"xpi", "cbz", "whl", "nupkg", "vsix", "kmz", "xps", "appx", "msix", "3mf", "sketch", "pages",
+	"numbers", "key",
+];
+const GZIP: Extensions = &["gz", "tgz", "svgz"];
+const BZIP2: Extensions = &["bz2", "tbz2"];
+const XZ: Extensions = &["xz", "txz"];
+const ZSTD: Extensions = &["zst", "tzst"];
+const SEVEN_ZIP: Extensions = &["7z"];
+const RAR: Extensions = &["rar", "cbr"];
+const MP3: Extensions = &["mp3"];
+/// ID3 tags come first in MP3 files mostly, but other audio files can have them too
+const ID3: Extensions = &["mp3", "aac", "flac"];
+const AAC: Extensions = &["aac"];
+const FLAC: Extensions = &["flac"];
+const OGG: Extensions = &["ogg", "oga", "ogv", "opus", "spx", "ogx"];
+const WAV: Extensions = &["wav", "wave"];
+const AVI: Extensions = &["avi"];
+const MKV: Extensions = &["mkv", "mka", "mk3d"];
+/// WebM is a subset of Matroska, so it's fine in a `.mkv`
+const WEBM: Extensions = &["webm", "mkv"];
+const MOV: Extensions = &["mov", "qt"];
+const M4A: Extensions = &["m4a", "m4b", "m4p", "mp4"];
+const M4V: Extensions = &["m4v", "mp4"];
+const THREE_GP: Extensions = &["3gp", "3g2", "mp4"];
+/// Players open MP4 brands under any of these
+const MP4: Extensions = &[
+	"mp4", "m4v", "m4a", "m4b", "mov", "f4v", "f4a", "3gp", "3g2",
+];
+
+const ALL: &[Extensions] = &[
+	JPEG, PNG, GIF, WEBP, BMP, TIFF, JXL, HEIF, AVIF, CR3, PSD, PDF, ZIP, GZIP, BZIP2, XZ, ZSTD,
+	SEVEN_ZIP, RAR, MP3, ID3, AAC, FLAC, OGG, WAV, AVI, MKV, WEBM, MOV, M4A, M4V, THREE_GP, MP4,
+];
+
+/// Every extension a signature is known for, the ones worth looking into
+static KNOWN_EXTENSIONS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+	ALL.iter()
+		.flat_map(|extensions| extensions.iter().copied())
+		.collect()
+});
+
+/// What the first bytes of a file say it is
+fn sniff(header: &[u8]) -> Option<Extensions> {
+	let starts = |signature: &[u8]| header.starts_with(signature);
+	let at = |offset: usize, signature: &[u8]| {
+		header
+			.get(offset..offset + signature.len())
+			.map_or(false, |bytes| bytes == signature)
+	};
+
+	Some(match header {
+		_ if starts(&[0xFF, 0xD8, 0xFF]) => JPEG,
+		_ if starts(b"\x89PNG\r\n\x1A\n") => PNG,
+		_ if starts(b"GIF87a") || starts(b"GIF89a") => GIF,
+		_ if starts(b"RIFF") && at(8, b"WEBP") => WEBP,
+		_ if starts(b"RIFF") && at(8, b"WAVE") => WAV,
+		_ if starts(b"RIFF") && at(8, b"AVI ") => AVI,
+		// The size of the header following is what makes two bytes tell a bitmap
+		_ if starts(b"BM")
+			&& [12, 40, 52, 56, 64, 108, 124]
+				.iter()
+				.any(|size: &u8| at(14, &[*size, 0, 0, 0])) =>
+		{
+			BMP
+		}
+		_ if starts(b"II*\0") || starts(b"MM\0*") => TIFF,
+		_ if starts(&[0xFF, 0x0A]) || starts(b"\0\0\0\x0CJXL \r\n\x87\n") => JXL,
+		_ if starts(b"8BPS") => PSD,
+		_ if starts(b"%PDF-") => PDF,
+		_ if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") => ZIP,
+		_ if starts(&[0x1F, 0x8B]) => GZIP,
+		_ if starts(b"BZh") => BZIP2,
+		_ if starts(&[0xFD, b'7', b'z', b'X', b'Z', 0]) => XZ,
+		_ if starts(&[0x28, 0xB5, 0x2F, 0xFD]) => ZSTD,
+		_ if starts(&[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]) => SEVEN_ZIP,
+		_ if starts(b"Rar!\x1A\x07") => RAR,
+		_ if starts(b"ID3") => ID3,
+		_ if starts(b"fLaC") => FLAC,
+		_ if starts(b"OggS") => OGG,
+		[0xFF, second, ..] if second & 0xF6 == 0xF0 => AAC,
+		[0xFF, second, ..] if second & 0xE0 == 0xE0 && second & 0x06 != 0 => MP3,
+		_ if starts(&[0x1A, 0x45, 0xDF, 0xA3]) => matroska_doc_type(header)?,
+		_ if at(4, b"ftyp") => iso_brand(header.get(8..12)?)?,
+		_ => return None,
+	})
+}
+
+/// Matroska and WebM share their container, the doctype of its header telling which one it is
+fn matroska_doc_type(header: &[u8]) -> Option<Extensions> {
+	let position = header.windows(2).position(|id| id == [0x42, 0x82])?;
+	// A single byte size, its first bit being the marker of its length
+	let size = (*header.get(position + 2)? & 0x7F) as usize;
+	let doc_type = header.get(position + 3..position + 3 + size)?;
+
+	match doc_type {
+		b"webm" => Some(WEBM),
+		b"matroska" => Some(MKV),
+		_ => None,
+	}
+}
+
+/// ISO media files, from MP4 to HEIC, tell what they are by their major brand
+fn iso_brand(brand: &[u8]) -> Option<Extensions> {
+	Some(match brand {
+		b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1" => HEIF,
+		b"avif" | b"avis" => AVIF,
+		b"crx " => CR3,
+		b"qt  " => MOV,
+		b"M4A " | b"M4B " | b"M4P " => M4A,
+		b"M4V " | b"M4VH" | b"M4VP" => M4V,
+		[b'3', b'g', ..] => THREE_GP,
+		b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1" | b"dash"
+		| b"MSNV" | b"f4v " | b"F4V " | b"mmp4" => MP4,
+		_ => return None,
+	})
+}
+
+/// The usual extension of the content of a file, when its own extension isn't one the content has
+fn mismatch(extension: &str, header: &[u8]) -> Option<&'static str> {
+	let extension = extension.to_lowercase();
+	if !KNOWN_EXTENSIONS.contains(extension.as_str()) {
+		return None;
+	}
+
+	sniff(header)
+		.filter(|extensions| !extensions.iter().any(|known| *known == extension))
+		.map(|extensions| extensions[0])
+}
+
+async fn read_header(path: &Path) -> Result<Vec<u8>, FileIOError> {
+	let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+
+	File::open(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?
+		.take(HEADER_SIZE)
+		.read_to_end(&mut header)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	Ok(header)
+}
+
+#[derive(Error, Debug)]
+pub enum ExtensionMismatchError {
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<ExtensionMismatchError> for rspc::Error {
+	fn from(err: ExtensionMismatchError) -> Self {
+		match err {
+			ExtensionMismatchError::Privacy(e) => e.into(),
+			ExtensionMismatchError::JobManager(e) => e.into(),
+			ExtensionMismatchError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+pub struct ExtensionMismatchScannerJob {}
+
+/// `ExtensionMismatchScannerJobInit` looks into the files of these locations, or of every location
+/// of this node when none are given
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ExtensionMismatchScannerJobInit {
+	#[serde(default)]
+	#[specta(optional)]
+	pub location_ids: Vec<location::id::Type>,
+}
+
+impl JobInitData for ExtensionMismatchScannerJobInit {
+	type Job = ExtensionMismatchScannerJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ExtensionMismatchScannerJobReport {
+	checked_files_count: usize,
+	mismatches_count: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ExtensionMismatchScannerJob {
+	type Init = ExtensionMismatchScannerJobInit;
+	type Data = ExtensionMismatchScannerJobReport;
+	type Step = location::id::Type;
+
+	const NAME: &'static str = "extension_mismatch_scanner";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		state.steps = ctx
+			.library
+			.db
+			.location()
+			.find_many(chain_optional_iter(
+				[location::node_id::equals(Some(ctx.library.node_local_id))],
+				[(!state.init.location_ids.is_empty())
+					.then(|| location::id::in_vec(state.init.location_ids.clone()))],
+			))
+			.select(location::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|location| location.id)
+			.collect();
+
+		state.data = Some(ExtensionMismatchScannerJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.steps[0];
+		let db = &ctx.library.db;
+
+		// Extensions are lowercase in the database
+		let candidate_ids = db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(location_id)),
+				file_path::is_dir::equals(Some(false)),
+				file_path::extension::in_vec(
+					KNOWN_EXTENSIONS
+						.iter()
+						.map(|extension| Some(extension.to_string()))
+						.collect(),
+				),
+			])
+			.select(file_path::select!({ id }))
+			.exec()
+			.await?
+			.into_iter()
+			.map(|file_path| file_path.id)
+			.collect::<Vec<_>>();
+
+		let location_path = get_location_path_from_location_id(db, location_id).await?;
+
+		let mut mismatches = vec![];
+		for file_data in get_many_files_datas(db, &location_path, &candidate_ids).await? {
+			// Files gone since they were indexed are the indexer's business
+			let Ok(header) = read_header(&file_data.full_path).await else {
+				continue;
+			};
+
+			let extension = file_data.file_path.extension.as_deref().unwrap_or_default();
+			if let Some(detected) = mismatch(extension, &header) {
+				mismatches.push(extension_mismatch::create_unchecked(
+					file_data.file_path.id,
+					detected.to_string(),
+					vec![],
+				));
+			}
+		}
+
+		let mismatches_count = mismatches.len();
+
+		db._batch((
+			db.extension_mismatch()
+				.delete_many(vec![extension_mismatch::file_path::is(vec![
+					file_path::location_id::equals(Some(location_id)),
+				])]),
+			db.extension_mismatch().create_many(mismatches),
+		))
+		.await?;
+
+		info!(
+			"Found {mismatches_count} extension mismatches out of {} files in location {location_id}",
+			candidate_ids.len()
+		);
+
+		let report = extract_job_data_mut!(state);
+		report.checked_files_count += candidate_ids.len();
+		report.mismatches_count += mismatches_count;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data!(state);
+
+		info!("Finalizing extension mismatch scanner job: {report:?}");
+
+		invalidate_query!(ctx.library, "files.extensionMismatches");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+pub struct ExtensionFixerJob {}
+
+/// `ExtensionFixerJobInit` renames files of a location to the usual extension of their content,
+/// leaving the ones whose extension already fits it
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ExtensionFixerJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+}
+
+impl JobInitData for ExtensionFixerJobInit {
+	type Job = ExtensionFixerJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ExtensionFixerJobReport {
+	renamed_files_count: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ExtensionFixerJob {
+	type Init = ExtensionFixerJobInit;
+	type Data = ExtensionFixerJobReport;
+	type Step = FileData;
+
+	const NAME: &'static str = "extension_fixer";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+
+		state.steps = get_many_files_datas(
+			db,
+			get_location_path_from_location_id(db, state.init.location_id).await?,
+			&state.init.file_path_ids,
+		)
+		.await?
+		.into_iter()
+		.filter(|file_data| file_data.file_path.is_dir == Some(false))
+		.collect();
+
+		state.data = Some(ExtensionFixerJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let library = ctx.library.clone();
+
+		// The content could have changed since the scan, so it's looked into again
+		let header = read_header(&step.full_path).await?;
+		let extension = step.file_path.extension.as_deref().unwrap_or_default();
+
+		if let Some(detected) = mismatch(extension, &header) {
+			fix_extension(&library, state.init.location_id, step, detected).await?;
+
+			extract_job_data_mut!(state).renamed_files_count += 1;
+		} else {
+			warn!(
+				"Extension of {} fits its content, leaving it",
+				step.full_path.display()
+			);
+		}
+
+		library
+			.db
+			.extension_mismatch()
+			.delete_many(vec![extension_mismatch::file_path_id::equals(
+				step.file_path.id,
+			)])
+			.exec()
+			.await?;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data!(state);
+
+		info!("Finalizing extension fixer job: {report:?}");
+
+		invalidate_query!(ctx.library, "search.paths");
+		invalidate_query!(ctx.library, "files.extensionMismatches");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Renames a file to `extension`, updating its file path in place so the object stays attached, and
+/// the kind of the object when the content turns out to be another kind of file
+async fn fix_extension(
+	library: &Library,
+	location_id: location::id::Type,
+	FileData {
+		file_path,
+		full_path,
+	}: &FileData,
+	extension: &str,
+) -> Result<(), JobError> {
+	let name = maybe_missing(&file_path.name, "file_path.name")?;
+
+	// Another file could have the name already, like a converted copy
+	let mut target = full_path.with_file_name(format!("{name}.{extension}"));
+	let mut counter = 1;
+	while fs::symlink_metadata(&target).await.is_ok() {
+		target = full_path.with_file_name(format!("{name} ({counter}).{extension}"));
+		counter += 1;
+	}
+	let new_name = target
+		.file_stem()
+		.map(|stem| stem.to_string_lossy().to_string())
+		.unwrap_or_else(|| name.clone());
+
+	let _guards = (
+		ignore_events_for(library, location_id, full_path).await,
+		ignore_events_for(library, location_id, &target).await,
+	);
+
+	fs::rename(full_path, &target)
+		.await
+		.map_err(|e| FileIOError::from((full_path, e)))?;
+
+	let Library { db, sync, .. } = library;
+
+	if let Err(e) = db
+		.file_path()
+		.update(
+			file_path::id::equals(file_path.id),
+			vec![
+				file_path::name::set(Some(new_name)),
+				file_path::extension::set(Some(extension.to_string())),
+			],
+		)
+		.exec()
+		.await
+	{
+		// Back to the name the database still has
+		fs::rename(&target, full_path)
+			.await
+			.map_err(|e| FileIOError::from((&target, e)))?;
+		return Err(e.into());
+	}
+
+	let kind = Extension::resolve_conflicting(&target, false)
+		.await
+		.map(ObjectKind::from)
+		.unwrap_or(ObjectKind::Unknown) as i32;
+
+	if let Some(object) = &file_path.object {
+		if object.kind != Some(kind) {
+			sync.write_op(
+				db,
+				sync.shared_update(
+					sync::object::SyncId {
+						pub_id: object.pub_id.clone(),
+					},
+					object::kind::NAME,
+					json!(kind),
+				),
+				db.object().update(
+					object::id::equals(object.id),
+					vec![object::kind::set(Some(kind))],
+				),
+			)
+			.await?;
+		}
+	}
+
+	Ok(())
+}
+
+extension_mismatch::include!(extension_mismatch_with_file_path { file_path });
+
+#[derive(Deserialize, Type, Debug, Default)]
+pub struct ExtensionMismatchesFilter {
+	#[specta(optional)]
+	pub location_id: Option<location::id::Type>,
+	/// The usual extension of the content, like `png`
+	#[specta(optional)]
+	pub detected_extension: Option<String>,
+}
+
+/// The mismatches found by the last scans, leaving out the ones of locked private locations
+pub async fn extension_mismatches(
+	library: &Library,
+	filter: ExtensionMismatchesFilter,
+) -> Result<Vec<extension_mismatch_with_file_path::Data>, QueryError> {
+	let file_path_params = [
+		filter
+			.location_id
+			.map(|location_id| file_path::location_id::equals(Some(location_id))),
+		library.private_locations.visible_file_paths().await,
+	]
+	.into_iter()
+	.flatten()
+	.collect();
+
+	library
+		.db
+		.extension_mismatch()
+		.find_many(chain_optional_iter(
+			[extension_mismatch::file_path::is(file_path_params)],
+			[filter.detected_extension.map(|extension| {
+				extension_mismatch::detected_extension::equals(extension.to_lowercase())
+			})],
+		))
+		.include(extension_mismatch_with_file_path::include())
+		.exec()
+		.await
+}
+
+/// Fixes the extensions of the mismatches, with a renaming job for each location they're in,
+/// returning how many jobs were started
+pub async fn fix_extension_mismatches(
+	library: &Library,
+	ids: Vec<extension_mismatch::id::Type>,
+) -> Result<u32, ExtensionMismatchError> {
+	let mismatches = library
+		.db
+		.extension_mismatch()
+		.find_many(vec![extension_mismatch::id::in_vec(ids)])
+		.include(extension_mismatch_with_file_path::include())
+		.exec()
+		.await?;
+
+	let mut by_location = HashMap::<_, Vec<_>>::new();
+	for mismatch in mismatches {
+		if let Some(location_id) = mismatch.file_path.location_id {
+			library
+				.private_locations
+				.ensure_unlocked(location_id)
+				.await?;
+
+			by_location
+				.entry(location_id)
+				.or_default()
+				.push(mismatch.file_path_id);
+		}
+	}
+
+	let jobs = by_location.len() as u32;
+	for (location_id, file_path_ids) in by_location {
+		library
+			.spawn_job(ExtensionFixerJobInit {
+				location_id,
+				file_path_ids,
+			})
+			.await?;
+	}
+
+	Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn content_disagreeing_with_extension_is_found() {
+		let png = b"\x89PNG\r\n\x1A\n\0\0\0\rIHDR";
+		assert_eq!(mismatch("jpg", png), Some("png"));
+		assert_eq!(mismatch("PNG", png), None);
+		// Unknown extensions are left alone, whatever they hold
+		assert_eq!(mismatch("bin", png), None);
+
+		let mut matroska = vec![0x1A, 0x45, 0xDF, 0xA3, 0xA3, 0x42, 0x86, 0x81, 0x01];
+		matroska.extend_from_slice(&[0x42, 0x82, 0x88]);
+		matroska.extend_from_slice(b"matroska");
+		assert_eq!(mismatch("mp4", &matroska), Some("mkv"));
+		assert_eq!(mismatch("mkv", &matroska), None);
+
+		let mut webm = vec![0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x82, 0x84];
+		webm.extend_from_slice(b"webm");
+		assert_eq!(mismatch("mkv", &webm), None);
+		assert_eq!(mismatch("mp4", &webm), Some("webm"));
+
+		let heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+		assert_eq!(mismatch("jpg", heic), Some("heic"));
+		let mp4 = b"\0\0\0\x20ftypisom\0\0\x02\0isomiso2avc1mp41";
+		assert_eq!(mismatch("mov", mp4), None);
+		assert_eq!(
+			mismatch("mp4", b"\0\0\0\x14ftypqt  \0\0\x02\0qt  "),
+			Some("mov")
+		);
+
+		// Office documents are zip archives
+		assert_eq!(mismatch("docx", b"PK\x03\x04\x14\0\x06\0"), None);
+		assert_eq!(mismatch("jpg", b"PK\x03\x04\x14\0\x06\0"), Some("zip"));
+
+		// An ID3 tag could come before any kind of audio
+		assert_eq!(mismatch("flac", b"ID3\x04\0\0\0\0\x01\0"), None);
+		assert_eq!(mismatch("mp3", b"not a signature at all"), None);
+	}
+}
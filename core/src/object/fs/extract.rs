@@ -0,0 +1,678 @@
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		find_location, location_with_indexer_rules, scan_location, scan_location_sub_path,
+		LocationError,
+	},
+	prisma::{file_path, location},
+	util::{error::FileIOError, long_path::to_extended_length},
+};
+
+use std::{
+	fs::{self, File, OpenOptions},
+	hash::Hash,
+	io::{self, Read},
+	path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sevenz_rust::{Password, SevenZReader};
+use specta::Type;
+use tokio::{sync::mpsc, task::spawn_blocking};
+use tracing::{trace, warn};
+use zip::ZipArchive;
+
+use super::{
+	archive::ArchiveError, error::FileSystemJobsError, fetch_source_and_target_location_paths,
+	get_many_files_datas, ConflictPolicy,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExtractableFormat {
+	Zip,
+	SevenZip,
+	Rar,
+	Tar,
+	TarZstd,
+}
+
+impl ExtractableFormat {
+	pub fn from_file_name(name: &str) -> Option<Self> {
+		let name = name.to_lowercase();
+
+		[
+			(".tar.zst", Self::TarZstd),
+			(".tzst", Self::TarZstd),
+			(".tar", Self::Tar),
+			(".zip", Self::Zip),
+			(".7z", Self::SevenZip),
+			(".rar", Self::Rar),
+		]
+		.into_iter()
+		.find_map(|(extension, format)| name.ends_with(extension).then_some(format))
+	}
+}
+
+pub struct ArchiveExtractorJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ArchiveExtractorJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_id: file_path::id::Type,
+	pub target_location_id: location::id::Type,
+	pub target_location_relative_directory_path: PathBuf,
+	#[serde(default)]
+	pub conflict_policy: ConflictPolicy,
+}
+
+impl JobInitData for ArchiveExtractorJobInit {
+	type Job = ArchiveExtractorJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ExtractionReport {
+	extracted: usize,
+	/// Files left out as something already existed at their path
+	skipped_conflicts: usize,
+	/// Entries that were never written, like links or paths escaping the target directory
+	rejected: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveExtractorJobData {
+	archive_path: PathBuf,
+	format: ExtractableFormat,
+	target_directory: PathBuf,
+	entries_count: usize,
+	report: ExtractionReport,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ArchiveExtractorJobStep {
+	Extract,
+	Register,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ArchiveExtractorJob {
+	type Init = ArchiveExtractorJobInit;
+	type Data = ArchiveExtractorJobData;
+	type Step = ArchiveExtractorJobStep;
+
+	const NAME: &'static str = "archive_extractor";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let (sources_location_path, targets_location_path) =
+			fetch_source_and_target_location_paths(
+				db,
+				state.init.location_id,
+				state.init.target_location_id,
+			)
+			.await?;
+
+		let archive_path =
+			get_many_files_datas(db, &sources_location_path, &[state.init.file_path_id])
+				.await?
+				.pop()
+				.ok_or(FileSystemJobsError::FilePathIdNotFound(
+					state.init.file_path_id,
+				))?
+				.full_path;
+
+		let format = archive_path
+			.file_name()
+			.and_then(|name| name.to_str())
+			.and_then(ExtractableFormat::from_file_name)
+			.ok_or_else(|| {
+				FileSystemJobsError::from(ArchiveError::UnsupportedFormat(
+					archive_path.clone().into_boxed_path(),
+				))
+			})?;
+
+		let target_directory = to_extended_length(
+			&targets_location_path.join(&state.init.target_location_relative_directory_path),
+		)
+		.into_owned();
+
+		let entries_count = spawn_blocking({
+			let archive_path = archive_path.clone();
+			move || count_entries(format, &archive_path)
+		})
+		.await?
+		.map_err(FileSystemJobsError::from)?;
+
+		state.steps = [
+			ArchiveExtractorJobStep::Extract,
+			ArchiveExtractorJobStep::Register,
+		]
+		.into_iter()
+		.collect();
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(entries_count + 1)]);
+
+		state.data = Some(ArchiveExtractorJobData {
+			archive_path,
+			format,
+			target_directory,
+			entries_count,
+			report: ExtractionReport::default(),
+		});
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		match state.steps[0] {
+			ArchiveExtractorJobStep::Extract => {
+				let data = extract_job_data_mut!(state);
+
+				trace!(
+					"Extracting {} to {}",
+					data.archive_path.display(),
+					data.target_directory.display()
+				);
+
+				let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+
+				let extraction = spawn_blocking({
+					let format = data.format;
+					let archive_path = data.archive_path.clone();
+					let target_directory = data.target_directory.clone();
+					let conflict_policy = state.init.conflict_policy;
+					move || {
+						extract(
+							format,
+							&archive_path,
+							&target_directory,
+							conflict_policy,
+							progress_tx,
+						)
+					}
+				});
+
+				while let Some(processed_count) = progress_rx.recv().await {
+					ctx.progress(vec![JobReportUpdate::CompletedTaskCount(processed_count)]);
+				}
+
+				data.report = extraction.await?.map_err(FileSystemJobsError::from)?;
+
+				if !data.report.rejected.is_empty() {
+					return Err(JobError::StepCompletedWithErrors(
+						data.report
+							.rejected
+							.iter()
+							.map(|name| format!("Archive entry not extracted: {name}"))
+							.collect(),
+					));
+				}
+			}
+
+			ArchiveExtractorJobStep::Register => {
+				let location = find_location(&ctx.library, state.init.target_location_id)
+					.include(location_with_indexer_rules::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(state.init.target_location_id))?;
+
+				// Archives usually have nested directories, so a shallow scan isn't enough here
+				let sub_path = &state.init.target_location_relative_directory_path;
+				if let Err(e) = if sub_path.as_os_str().is_empty() {
+					scan_location(&ctx.library, location).await
+				} else {
+					scan_location_sub_path(&ctx.library, location, sub_path).await
+				} {
+					// A scan already running for this location will find the files as well
+					warn!("Failed to scan the extracted files: {e}");
+				}
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			extract_job_data!(state).entries_count + state.step_number,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::json!({
+			"init": state.init,
+			"report": extract_job_data!(state).report,
+		})))
+	}
+}
+
+fn count_entries(format: ExtractableFormat, archive_path: &Path) -> Result<usize, ArchiveError> {
+	Ok(match format {
+		ExtractableFormat::Zip => ZipArchive::new(open(archive_path)?)?.len(),
+		ExtractableFormat::SevenZip => SevenZReader::open(archive_path, Password::empty())?
+			.archive()
+			.files
+			.len(),
+		ExtractableFormat::Rar => unrar::Archive::new(archive_path)
+			.open_for_listing()?
+			.count(),
+		// Tar files have no index, so this reads through the whole archive
+		ExtractableFormat::Tar | ExtractableFormat::TarZstd => open_tar(format, archive_path)?
+			.entries()
+			.map_err(|e| FileIOError::from((archive_path, e)))?
+			.count(),
+	})
+}
+
+fn extract(
+	format: ExtractableFormat,
+	archive_path: &Path,
+	target_directory: &Path,
+	conflict_policy: ConflictPolicy,
+	progress_tx: mpsc::UnboundedSender<usize>,
+) -> Result<ExtractionReport, ArchiveError> {
+	let mut extractor = Extractor {
+		canonical_target_directory: target_directory
+			.canonicalize()
+			.map_err(|e| FileIOError::from((target_directory, e)))?,
+		target_directory,
+		conflict_policy,
+		report: ExtractionReport::default(),
+		processed_count: 0,
+		progress_tx,
+	};
+
+	match format {
+		ExtractableFormat::Zip => {
+			let mut archive = ZipArchive::new(open(archive_path)?)?;
+
+			for i in 0..archive.len() {
+				let mut file = archive.by_index(i)?;
+				let name = file.name().to_string();
+
+				if let Some(target) = extractor.target_for(&name, file.is_dir())? {
+					extractor.write_file(&mut file, &target)?;
+				}
+
+				extractor.entry_done();
+			}
+		}
+
+		ExtractableFormat::SevenZip => {
+			let mut failure = None;
+
+			SevenZReader::open(archive_path, Password::empty())?.for_each_entries(
+				|entry, reader| {
+					let result = extractor
+						.target_for(entry.name(), entry.is_directory())
+						.and_then(|maybe_target| match maybe_target {
+							Some(target) => extractor.write_file(reader, &target),
+							// Entries are decompressed in sequence, so the data must still be consumed
+							None => io::copy(reader, &mut io::sink())
+								.map(|_| ())
+								.map_err(|e| FileIOError::from((archive_path, e)).into()),
+						});
+
+					extractor.entry_done();
+
+					result.map(|()| true).or_else(|e| {
+						failure = Some(e);
+						Ok(false)
+					})
+				},
+			)?;
+
+			if let Some(e) = failure {
+				return Err(e);
+			}
+		}
+
+		ExtractableFormat::Rar => {
+			let mut archive = unrar::Archive::new(archive_path).open_for_processing()?;
+
+			while let Some(header) = archive.read_header()? {
+				let (name, is_dir) = {
+					let entry = header.entry();
+					(
+						entry.filename.to_string_lossy().into_owned(),
+						entry.is_directory(),
+					)
+				};
+
+				archive = match extractor.target_for(&name, is_dir)? {
+					Some(target) => {
+						let archive = header.extract_to(&target)?;
+						extractor.report.extracted += 1;
+						archive
+					}
+					None => header.skip()?,
+				};
+
+				extractor.entry_done();
+			}
+		}
+
+		ExtractableFormat::Tar | ExtractableFormat::TarZstd => {
+			let mut archive = open_tar(format, archive_path)?;
+
+			for entry in archive
+				.entries()
+				.map_err(|e| FileIOError::from((archive_path, e)))?
+			{
+				let mut entry = entry.map_err(|e| FileIOError::from((archive_path, e)))?;
+				let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+				let entry_type = entry.header().entry_type();
+
+				if entry_type.is_dir() || entry_type.is_file() {
+					if let Some(target) = extractor.target_for(&name, entry_type.is_dir())? {
+						extractor.write_file(&mut entry, &target)?;
+					}
+				} else {
+					extractor.reject(name, "only files and directories are extracted");
+				}
+
+				extractor.entry_done();
+			}
+		}
+	}
+
+	Ok(extractor.report)
+}
+
+struct Extractor<'a> {
+	target_directory: &'a Path,
+	canonical_target_directory: PathBuf,
+	conflict_policy: ConflictPolicy,
+	report: ExtractionReport,
+	processed_count: usize,
+	progress_tx: mpsc::UnboundedSender<usize>,
+}
+
+impl Extractor<'_> {
+	/// Returns where an entry must be written, creating its parent directories, or `None` when
+	/// there's nothing to write, as with directories and skipped entries
+	fn target_for(&mut self, name: &str, is_dir: bool) -> Result<Option<PathBuf>, ArchiveError> {
+		let Some(relative_path) = sanitize_entry_path(name) else {
+			self.reject(name.to_string(), "its path leaves the target directory");
+			return Ok(None);
+		};
+
+		let target = self.target_directory.join(relative_path);
+
+		let directory = if is_dir {
+			target.as_path()
+		} else {
+			target.parent().unwrap_or(self.target_directory)
+		};
+
+		if !self.create_directories(directory)? {
+			self.reject(name.to_string(), "its path leaves the target directory");
+			return Ok(None);
+		}
+
+		if is_dir {
+			return Ok(None);
+		}
+
+		let metadata = match fs::symlink_metadata(&target) {
+			Ok(metadata) => metadata,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(target)),
+			Err(e) => return Err(FileIOError::from((target, e)).into()),
+		};
+
+		match self.conflict_policy {
+			ConflictPolicy::Overwrite if !metadata.is_dir() => {
+				fs::remove_file(&target).map_err(|e| FileIOError::from((&target, e)))?;
+				Ok(Some(target))
+			}
+			ConflictPolicy::KeepBoth => Ok(Some(available_path(&target))),
+			ConflictPolicy::Skip | ConflictPolicy::Overwrite => {
+				trace!("Skipping {} as it already exists", target.display());
+				self.report.skipped_conflicts += 1;
+				Ok(None)
+			}
+		}
+	}
+
+	/// Creates `directory` unless a symlink, already in the target directory or extracted from the
+	/// archive, would send it somewhere else, which is checked before anything is created.
+	/// Returns whether the directory is in the target directory.
+	fn create_directories(&self, directory: &Path) -> Result<bool, FileIOError> {
+		let Ok(relative_path) = directory.strip_prefix(self.target_directory) else {
+			return Ok(false);
+		};
+
+		let mut existing = self.target_directory.to_path_buf();
+		let mut components = relative_path.components().peekable();
+		while let Some(component) = components.peek() {
+			let candidate = existing.join(component);
+			if fs::symlink_metadata(&candidate).is_err() {
+				break;
+			}
+			existing = candidate;
+			components.next();
+		}
+
+		if !existing
+			.canonicalize()
+			.map_err(|e| FileIOError::from((&existing, e)))?
+			.starts_with(&self.canonical_target_directory)
+		{
+			return Ok(false);
+		}
+
+		let mut current = existing;
+		for component in components {
+			current.push(component);
+
+			match fs::create_dir(&current) {
+				Ok(()) => {}
+				// Created since we looked, it must be a real directory to be trusted
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+					let metadata = fs::symlink_metadata(&current)
+						.map_err(|e| FileIOError::from((&current, e)))?;
+					if !metadata.is_dir() {
+						return Ok(false);
+					}
+				}
+				Err(e) => return Err(FileIOError::from((current, e))),
+			}
+		}
+
+		Ok(true)
+	}
+
+	fn write_file(&mut self, reader: &mut dyn Read, target: &Path) -> Result<(), ArchiveError> {
+		// Never follow whatever showed up at the target since we checked it
+		let mut file = OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(target)
+			.map_err(|e| FileIOError::from((target, e)))?;
+
+		io::copy(reader, &mut file).map_err(|e| FileIOError::from((target, e)))?;
+
+		self.report.extracted += 1;
+
+		Ok(())
+	}
+
+	fn reject(&mut self, name: String, reason: &str) {
+		warn!("Not extracting archive entry {name} as {reason}");
+		self.report.rejected.push(name);
+	}
+
+	fn entry_done(&mut self) {
+		self.processed_count += 1;
+		self.progress_tx.send(self.processed_count).ok();
+	}
+}
+
+fn open(archive_path: &Path) -> Result<File, FileIOError> {
+	File::open(archive_path).map_err(|e| FileIOError::from((archive_path, e)))
+}
+
+fn open_tar(
+	format: ExtractableFormat,
+	archive_path: &Path,
+) -> Result<tar::Archive<Box<dyn Read>>, FileIOError> {
+	let file = open(archive_path)?;
+
+	let reader: Box<dyn Read> = if format == ExtractableFormat::TarZstd {
+		Box::new(
+			zstd::stream::read::Decoder::new(file)
+				.map_err(|e| FileIOError::from((archive_path, e)))?,
+		)
+	} else {
+		Box::new(file)
+	};
+
+	Ok(tar::Archive::new(reader))
+}
+
+/// Turns a path stored in an archive into a relative path, or `None` if it's absolute or goes up
+/// with `..`, as writing it would escape the target directory
+fn sanitize_entry_path(name: &str) -> Option<PathBuf> {
+	// Archives made on Windows may use backslashes as separators
+	let name = name.replace('\\', "/");
+
+	let mut path = PathBuf::new();
+	for component in Path::new(&name).components() {
+		match component {
+			Component::Normal(part) => path.push(part),
+			Component::CurDir => {}
+			Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+		}
+	}
+
+	(!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Finds a free path for a file, adding a counter to its name like `photo (1).jpg`
+pub(crate) fn available_path(path: &Path) -> PathBuf {
+	let file_name = path
+		.file_name()
+		.map(|name| name.to_string_lossy().into_owned())
+		.unwrap_or_default();
+	let (stem, extension) = split_file_name(&file_name);
+
+	let mut counter = 1;
+	loop {
+		let candidate = path.with_file_name(format!("{stem} ({counter}){extension}"));
+		if fs::symlink_metadata(&candidate).is_err() {
+			return candidate;
+		}
+		counter += 1;
+	}
+}
+
+/// Splits a file name at its first dot, keeping compound extensions like `.tar.gz` together,
+/// while a leading dot belongs to the name of hidden files
+fn split_file_name(file_name: &str) -> (&str, &str) {
+	match file_name.char_indices().skip(1).find(|(_, c)| *c == '.') {
+		Some((i, _)) => file_name.split_at(i),
+		None => (file_name, ""),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn entry_paths_stay_in_target() {
+		for name in [
+			"../evil.sh",
+			"/etc/passwd",
+			"a/../../b",
+			"a\\..\\..\\b",
+			"",
+			"./",
+		] {
+			assert_eq!(sanitize_entry_path(name), None, "{name}");
+		}
+
+		assert_eq!(
+			sanitize_entry_path("a/./b.txt"),
+			Some(PathBuf::from("a").join("b.txt"))
+		);
+		assert_eq!(
+			sanitize_entry_path("dir\\file.txt"),
+			Some(PathBuf::from("dir").join("file.txt"))
+		);
+	}
+
+	#[test]
+	fn split_names() {
+		assert_eq!(split_file_name("photo.jpg"), ("photo", ".jpg"));
+		assert_eq!(split_file_name("backup.tar.gz"), ("backup", ".tar.gz"));
+		assert_eq!(split_file_name(".bashrc"), (".bashrc", ""));
+		assert_eq!(split_file_name("README"), ("README", ""));
+	}
+
+	#[test]
+	fn formats() {
+		assert_eq!(
+			ExtractableFormat::from_file_name("Photos.TAR.ZST"),
+			Some(ExtractableFormat::TarZstd)
+		);
+		assert_eq!(
+			ExtractableFormat::from_file_name("photos.tar"),
+			Some(ExtractableFormat::Tar)
+		);
+		assert_eq!(ExtractableFormat::from_file_name("photos.tar.gz"), None);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn directories_are_never_created_through_symlinks() {
+		let dir = tempfile::tempdir().unwrap();
+		let target_directory = dir.path().join("target");
+		let outside = dir.path().join("outside");
+		fs::create_dir(&target_directory).unwrap();
+		fs::create_dir(&outside).unwrap();
+		std::os::unix::fs::symlink(&outside, target_directory.join("link")).unwrap();
+
+		let (progress_tx, _progress_rx) = mpsc::unbounded_channel();
+		let mut extractor = Extractor {
+			canonical_target_directory: target_directory.canonicalize().unwrap(),
+			target_directory: &target_directory,
+			conflict_policy: ConflictPolicy::Skip,
+			report: ExtractionReport::default(),
+			processed_count: 0,
+			progress_tx,
+		};
+
+		assert_eq!(
+			extractor.target_for("link/a/b/file.txt", false).unwrap(),
+			None
+		);
+		assert!(!outside.join("a").exists());
+
+		assert_eq!(
+			extractor.target_for("inside/file.txt", false).unwrap(),
+			Some(target_directory.join("inside").join("file.txt"))
+		);
+		assert!(target_directory.join("inside").is_dir());
+	}
+}
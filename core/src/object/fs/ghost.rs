@@ -0,0 +1,315 @@
+//! A ghost is a `file_path` whose metadata, tags and thumbnail we know, but whose content isn't
+//! reachable from this device right now. Its location may be on another device or on a drive that
+//! isn't mounted, or it may have been tiered to one of those. Ghosts are still listed, and their
+//! content can be retrieved into a local cache from any reachable copy of the same object, which is
+//! kept within a budget, see [`super::offline`].
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::file_path_helper::IsolatedFilePathData,
+	prisma::{file_path, location, object},
+	util::error::FileIOError,
+};
+
+use std::{
+	collections::HashMap,
+	hash::Hash,
+	path::{Path, PathBuf},
+};
+
+use prisma_client_rust::QueryError;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::fs;
+use tracing::{info, trace, warn};
+
+use super::{offline::enforce_budget, sparse};
+
+const RETRIEVED_CACHE_DIR: &str = "retrieved";
+
+/// The locations whose content can be read from this device right now, with their paths
+pub struct ReachableLocations(HashMap<location::id::Type, PathBuf>);
+
+impl ReachableLocations {
+	pub async fn fetch(library: &Library) -> Result<Self, QueryError> {
+		// Only local locations with their path mounted are ever marked as online
+		let online = library.location_manager().get_online().await;
+
+		Ok(Self(
+			library
+				.db
+				.location()
+				.find_many(vec![location::pub_id::in_vec(online.into_iter().collect())])
+				.select(location::select!({ id path }))
+				.exec()
+				.await?
+				.into_iter()
+				.filter_map(|location| location.path.map(|path| (location.id, path.into())))
+				.collect(),
+		))
+	}
+
+	/// Compressed files are never ghosts, as their content lives in our own managed store
+	pub fn is_ghost(
+		&self,
+		location_id: Option<location::id::Type>,
+		tiered_to_location_id: Option<location::id::Type>,
+		is_compressed: bool,
+	) -> bool {
+		!is_compressed
+			&& !tiered_to_location_id
+				.or(location_id)
+				.map_or(false, |location_id| self.0.contains_key(&location_id))
+	}
+
+	/// Where the content of a file path can be read, if it's reachable
+	pub(crate) fn content_path(&self, file_path: &file_path::Data) -> Option<PathBuf> {
+		if file_path.compressed_at.is_some() {
+			return None;
+		}
+
+		let location_path = self
+			.0
+			.get(&file_path.tiered_to_location_id.or(file_path.location_id)?)?;
+
+		IsolatedFilePathData::try_from(file_path)
+			.ok()
+			.map(|iso_file_path| location_path.join(iso_file_path))
+	}
+
+	/// Finds a copy of the object on a reachable location, to retrieve the content of a ghost
+	pub async fn find_source(
+		&self,
+		library: &Library,
+		object_id: object::id::Type,
+	) -> Result<Option<PathBuf>, QueryError> {
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::object_id::equals(Some(object_id)),
+				file_path::is_dir::equals(Some(false)),
+				file_path::compressed_at::equals(None),
+			])
+			.exec()
+			.await?;
+
+		for path in file_paths
+			.iter()
+			.filter_map(|file_path| self.content_path(file_path))
+		{
+			if fs::metadata(&path).await.is_ok() {
+				return Ok(Some(path));
+			}
+		}
+
+		Ok(None)
+	}
+}
+
+/// Where the retrieved content of the ghosts of a library is kept
+pub(crate) fn retrieved_cache_dir(library: &Library) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(RETRIEVED_CACHE_DIR)
+		.join(library.id.to_string())
+}
+
+/// Where the retrieved content of a ghost is kept, named after its `pub_id`
+pub fn retrieved_file_path(library: &Library, pub_id: &[u8]) -> PathBuf {
+	retrieved_cache_dir(library).join(hex::encode(pub_id))
+}
+
+pub struct FileRetrieverJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct FileRetrieverJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+}
+
+impl JobInitData for FileRetrieverJobInit {
+	type Job = FileRetrieverJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileRetrieverJobReport {
+	retrieved_count: usize,
+	unavailable: Vec<String>,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FileRetrieverJob {
+	type Init = FileRetrieverJobInit;
+	type Data = FileRetrieverJobReport;
+	type Step = file_path::Data;
+
+	const NAME: &'static str = "file_retriever";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let reachable = ReachableLocations::fetch(&ctx.library).await?;
+
+		let mut steps = Vec::with_capacity(state.init.file_path_ids.len());
+		for file_path in ctx
+			.library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(state.init.location_id)),
+				file_path::id::in_vec(state.init.file_path_ids.clone()),
+				file_path::is_dir::equals(Some(false)),
+			])
+			.exec()
+			.await?
+		{
+			let is_ghost = reachable.is_ghost(
+				file_path.location_id,
+				file_path.tiered_to_location_id,
+				file_path.compressed_at.is_some(),
+			);
+
+			// Ghosts retrieved before are already available
+			if is_ghost
+				&& fs::metadata(retrieved_file_path(&ctx.library, &file_path.pub_id))
+					.await
+					.is_err()
+			{
+				steps.push(file_path);
+			}
+		}
+
+		state.steps = steps.into();
+		state.data = Some(FileRetrieverJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let file_path = &state.steps[0];
+		let report = extract_job_data_mut!(state);
+
+		let result = retrieve(&ctx.library, file_path).await;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		let name = file_path.name.as_deref().unwrap_or_default();
+		match result {
+			Ok(true) => {
+				report.retrieved_count += 1;
+				Ok(())
+			}
+			Ok(false) => {
+				report.unavailable.push(name.to_string());
+				Err(JobError::StepCompletedWithErrors(vec![format!(
+					"No reachable copy of {name} was found"
+				)]))
+			}
+			Err(e) => {
+				report.unavailable.push(name.to_string());
+				Err(JobError::StepCompletedWithErrors(vec![format!(
+					"Failed to retrieve {name}: {e}"
+				)]))
+			}
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data!(state);
+
+		info!("Finalizing file retriever job: {report:?}");
+
+		if report.retrieved_count > 0 {
+			if let Err(e) = enforce_budget(&ctx.library).await {
+				warn!("Failed to evict the offline cache: {e:#?}");
+			}
+		}
+
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Copies the content of a ghost from a reachable copy into the retrieved cache.
+/// Returns `false` if there is no reachable copy.
+async fn retrieve(library: &Library, file_path: &file_path::Data) -> Result<bool, JobError> {
+	let Some(object_id) = file_path.object_id else {
+		// Without an object we can't tell which other files have the same content
+		return Ok(false);
+	};
+
+	let Some(source) = ReachableLocations::fetch(library)
+		.await?
+		.find_source(library, object_id)
+		.await?
+	else {
+		return Ok(false);
+	};
+
+	let cache_path = retrieved_file_path(library, &file_path.pub_id);
+	copy_into_cache(&source, &cache_path).await?;
+
+	trace!(
+		"Retrieved the content of {} from {}",
+		cache_path.display(),
+		source.display()
+	);
+
+	Ok(true)
+}
+
+async fn copy_into_cache(source: &Path, cache_path: &Path) -> Result<(), FileIOError> {
+	if let Some(parent) = cache_path.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.map_err(|e| FileIOError::from((parent, e)))?;
+	}
+
+	let partial_path = {
+		let mut path = cache_path.as_os_str().to_owned();
+		path.push(".part");
+		PathBuf::from(path)
+	};
+
+	let result = async {
+		sparse::copy_file(source, &partial_path)
+			.await
+			.map_err(|e| FileIOError::from((&partial_path, e)))?;
+
+		fs::rename(&partial_path, cache_path)
+			.await
+			.map_err(|e| FileIOError::from((cache_path, e)))
+	}
+	.await;
+
+	if result.is_err() {
+		fs::remove_file(&partial_path).await.ok();
+	}
+
+	result
+}
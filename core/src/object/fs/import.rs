@@ -0,0 +1,633 @@
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, JournalOperation, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{
+			create_file_path, ensure_file_path_exists, ensure_sub_path_is_directory,
+			ensure_sub_path_is_in_location, filter_existing_file_path_params, get_allocated_size,
+			get_inode_and_device_from_path, FilePathMetadata, IsolatedFilePathData, MetadataExt,
+		},
+		LocationError,
+	},
+	object::{
+		custom_kind::resolve_kind,
+		file_identifier::FileMetadata,
+		malware_scan::{
+			record_scan_or_log, report_detection, scan_ingested, MalwareDetection,
+			MalwareScanResult, ScanVerdict,
+		},
+		os_metadata::apply_os_metadata,
+		validation::hash::file_checksum,
+	},
+	prisma::{file_path, location, object},
+	util::{error::FileIOError, long_path::to_extended_length},
+};
+
+use std::{
+	hash::Hash,
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::fs;
+use tracing::{trace, warn};
+use uuid::Uuid;
+
+use super::{
+	error::FileSystemJobsError, extract::available_path, get_location_path_from_location_id,
+	ignore_events_for, sparse::copy_file, ConflictPolicy,
+};
+
+#[cfg(target_family = "unix")]
+const EXDEV: i32 = 18;
+#[cfg(target_family = "windows")]
+const EXDEV: i32 = 17; // ERROR_NOT_SAME_DEVICE
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Brings files from outside of any location, like the ones dropped onto the app, into a directory
+/// of a location. They are indexed and identified right away, so they can be selected as soon as
+/// the job is done.
+pub struct ImportExternalFilesJob {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type, PartialEq, Eq)]
+pub enum ImportMode {
+	Copy,
+	Move,
+}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ImportExternalFilesJobInit {
+	pub location_id: location::id::Type,
+	pub target_location_relative_directory_path: PathBuf,
+	pub sources: Vec<PathBuf>,
+	pub mode: ImportMode,
+	pub conflict_policy: ConflictPolicy,
+	/// Checks each copy against the checksum of its source, before the source is removed on moves.
+	/// Copies that don't match are quarantined and their sources are kept.
+	#[serde(default)]
+	#[specta(optional)]
+	pub verify: bool,
+}
+
+impl JobInitData for ImportExternalFilesJobInit {
+	type Job = ImportExternalFilesJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportExternalFilesJobStep {
+	source: PathBuf,
+	target: PathBuf,
+	/// Only the dropped paths are reported back, not their children
+	is_top_level: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImportExternalFilesJobReport {
+	/// The file paths created for the dropped paths, in the order they were given
+	file_path_ids: Vec<file_path::id::Type>,
+	imported_count: usize,
+	skipped: Vec<PathBuf>,
+	quarantined: Vec<QuarantinedFile>,
+	#[serde(default)]
+	infected: Vec<InfectedFile>,
+}
+
+/// A copy that didn't match its source, kept aside for inspection
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuarantinedFile {
+	source: PathBuf,
+	quarantined_path: PathBuf,
+	source_checksum: String,
+	copy_checksum: String,
+}
+
+/// A copy the malware scanner flagged, see `object::malware_scan`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfectedFile {
+	source: PathBuf,
+	quarantined_path: PathBuf,
+	signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportExternalFilesJobData {
+	location_path: PathBuf,
+	/// Moved directories are removed once all of their children were moved out of them
+	moved_directories: Vec<PathBuf>,
+	report: ImportExternalFilesJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ImportExternalFilesJob {
+	type Init = ImportExternalFilesJobInit;
+	type Data = ImportExternalFilesJobData;
+	type Step = ImportExternalFilesJobStep;
+
+	const NAME: &'static str = "import_external_files";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
+		let relative_path = &state.init.target_location_relative_directory_path;
+
+		let target_directory = if relative_path != Path::new("") {
+			let full_path = ensure_sub_path_is_in_location(&location_path, relative_path)
+				.await
+				.map_err(LocationError::from)?;
+			ensure_sub_path_is_directory(&location_path, relative_path)
+				.await
+				.map_err(LocationError::from)?;
+
+			let iso_file_path =
+				IsolatedFilePathData::new(state.init.location_id, &location_path, &full_path, true)
+					.map_err(LocationError::from)?
+					.normalized(ctx.library.config.file_name_normalization);
+
+			// Imported files must have their parent directory indexed, to be shown in the explorer
+			ensure_file_path_exists(
+				relative_path,
+				&iso_file_path,
+				db,
+				FileSystemJobsError::FilePathNotFound,
+			)
+			.await?;
+
+			full_path
+		} else {
+			location_path.clone()
+		};
+
+		let mut report = ImportExternalFilesJobReport::default();
+
+		for source in &state.init.sources {
+			if source.starts_with(&location_path) {
+				// Already in the location, the regular copy and cut jobs are the way to go for those
+				warn!(
+					"Skipping import of {}, as it's in the location",
+					source.display()
+				);
+				report.skipped.push(source.clone());
+				continue;
+			}
+
+			let Some(file_name) = source.file_name() else {
+				report.skipped.push(source.clone());
+				continue;
+			};
+
+			let target = target_directory.join(file_name);
+
+			let target = match (
+				fs::symlink_metadata(&target).await,
+				state.init.conflict_policy,
+			) {
+				(Err(e), _) if e.kind() == io::ErrorKind::NotFound => target,
+				(Err(e), _) => return Err(FileIOError::from((&target, e)).into()),
+				(Ok(_), ConflictPolicy::KeepBoth) => available_path(&target),
+				(Ok(metadata), ConflictPolicy::Overwrite) if !metadata.is_dir() => target,
+				(Ok(_), ConflictPolicy::Skip | ConflictPolicy::Overwrite) => {
+					report.skipped.push(source.clone());
+					continue;
+				}
+			};
+
+			state.steps.push_back(ImportExternalFilesJobStep {
+				source: source.clone(),
+				target: to_extended_length(&target).into_owned(),
+				is_top_level: true,
+			});
+		}
+
+		state.data = Some(ImportExternalFilesJobData {
+			location_path,
+			moved_directories: vec![],
+			report,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let ImportExternalFilesJobStep {
+			source,
+			target,
+			is_top_level,
+		} = state.steps[0].clone();
+		let location_id = state.init.location_id;
+		let mode = state.init.mode;
+		let verify = state.init.verify;
+		let library = ctx.library.clone();
+		let data = extract_job_data_mut!(state);
+
+		let source_metadata = fs::metadata(&source)
+			.await
+			.map_err(|e| FileIOError::from((&source, e)))?;
+
+		let _guard = ignore_events_for(&library, location_id, &target).await;
+		let mut scan_result = None;
+
+		if source_metadata.is_dir() {
+			ctx.journal()
+				.await?
+				.execute(
+					state.step_number,
+					JournalOperation::CreateDir {
+						path: target.clone(),
+					},
+					|| async {
+						fs::create_dir_all(&target)
+							.await
+							.map_err(|e| FileIOError::from((&target, e)).into())
+					},
+				)
+				.await?;
+
+			let mut read_dir = fs::read_dir(&source)
+				.await
+				.map_err(|e| FileIOError::from((&source, e)))?;
+
+			while let Some(children_entry) = read_dir
+				.next_entry()
+				.await
+				.map_err(|e| FileIOError::from((&source, e)))?
+			{
+				state.steps.push_back(ImportExternalFilesJobStep {
+					source: children_entry.path(),
+					target: target.join(children_entry.file_name()),
+					is_top_level: false,
+				});
+			}
+
+			if mode == ImportMode::Move {
+				data.moved_directories.push(source.clone());
+			}
+
+			ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+		} else {
+			trace!("Importing {} to {}", source.display(), target.display());
+
+			let operation = match mode {
+				ImportMode::Copy => JournalOperation::Copy {
+					source: source.clone(),
+					target: target.clone(),
+				},
+				ImportMode::Move => JournalOperation::Move {
+					source: source.clone(),
+					target: target.clone(),
+				},
+			};
+
+			let quarantine_dir = quarantine_directory(&library);
+			let mut quarantined = None;
+
+			{
+				let (source, target, quarantined, quarantine_dir) =
+					(&source, &target, &mut quarantined, &quarantine_dir);
+
+				ctx.journal()
+					.await?
+					.execute(state.step_number, operation, || async move {
+						*quarantined =
+							import_file(source, target, mode, verify, quarantine_dir).await?;
+
+						Ok(())
+					})
+					.await?;
+			}
+
+			if let Some(quarantined) = quarantined {
+				warn!(
+					"Copy of {} didn't match its source, quarantined at {}",
+					source.display(),
+					quarantined.quarantined_path.display()
+				);
+
+				data.report.quarantined.push(quarantined);
+
+				ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+					state.step_number + 1,
+				)]);
+
+				return Ok(());
+			}
+
+			// The user brought these files in themselves, so they aren't held back when the
+			// scanner can't be reached
+			match scan_ingested(&library, &target).await {
+				Some(Ok(MalwareScanResult {
+					verdict: ScanVerdict::Infected { signature },
+					..
+				})) => {
+					let quarantined_path = quarantine(&target, &quarantine_dir).await?;
+
+					report_detection(
+						&library,
+						MalwareDetection {
+							library_id: library.id,
+							name: file_name_of(&source),
+							signature: signature.clone(),
+							quarantined_path: Some(quarantined_path.clone()),
+						},
+					);
+
+					data.report.infected.push(InfectedFile {
+						source,
+						quarantined_path,
+						signature,
+					});
+
+					ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+						state.step_number + 1,
+					)]);
+
+					return Ok(());
+				}
+				Some(Ok(result)) => scan_result = Some(result),
+				Some(Err(e)) => warn!("Failed to scan {} for malware: {e}", target.display()),
+				None => {}
+			}
+		}
+
+		let file_path_id =
+			index_imported_path(&library, location_id, &data.location_path, &target).await?;
+
+		if let Some(scan_result) = scan_result {
+			record_scan_or_log(
+				&library,
+				vec![file_path::id::equals(file_path_id)],
+				&scan_result,
+			)
+			.await;
+		}
+
+		data.report.imported_count += 1;
+		if is_top_level {
+			data.report.file_path_ids.push(file_path_id);
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let data = extract_job_data!(state);
+
+		// Deepest first, as the parents can only be removed after their children.
+		// Directories holding sources of quarantined copies aren't empty, so they're kept.
+		for directory in data.moved_directories.iter().rev() {
+			if let Err(e) = fs::remove_dir(directory).await {
+				warn!(
+					"Failed to remove moved directory {}: {e:#?}",
+					directory.display()
+				);
+			}
+		}
+
+		invalidate_query!(ctx.library, "search.paths");
+		invalidate_query!(ctx.library, "search.objects");
+
+		Ok(Some(serde_json::to_value(&data.report)?))
+	}
+}
+
+/// Where copies that failed verification are kept, see `ImportExternalFilesJobInit::verify`
+pub fn quarantine_directory(library: &Library) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(QUARANTINE_DIR)
+		.join(library.id.to_string())
+}
+
+/// Imports a file, returning where its copy was quarantined if it failed verification
+async fn import_file(
+	source: &Path,
+	target: &Path,
+	mode: ImportMode,
+	verify: bool,
+	quarantine_dir: &Path,
+) -> Result<Option<QuarantinedFile>, FileIOError> {
+	if mode == ImportMode::Move {
+		match fs::rename(source, target).await {
+			// Nothing was copied, so there's nothing to verify
+			Ok(()) => return Ok(None),
+			// Files dropped from another volume can't be renamed, so they're copied and removed
+			Err(e) if e.raw_os_error() == Some(EXDEV) => {}
+			Err(e) => return Err(FileIOError::from((source, e))),
+		}
+	}
+
+	copy_file(source, target)
+		.await
+		.map_err(|e| FileIOError::from((target, e)))?;
+
+	if verify {
+		let source_checksum = file_checksum(source)
+			.await
+			.map_err(|e| FileIOError::from((source, e)))?;
+		let copy_checksum = file_checksum(target)
+			.await
+			.map_err(|e| FileIOError::from((target, e)))?;
+
+		if source_checksum != copy_checksum {
+			// The source stays where it is, being the only good copy
+			return quarantine(target, quarantine_dir)
+				.await
+				.map(|quarantined_path| {
+					Some(QuarantinedFile {
+						source: source.to_path_buf(),
+						quarantined_path,
+						source_checksum,
+						copy_checksum,
+					})
+				});
+		}
+	}
+
+	if mode == ImportMode::Move {
+		fs::remove_file(source)
+			.await
+			.map_err(|e| FileIOError::from((source, e)))?;
+	}
+
+	Ok(None)
+}
+
+fn file_name_of(path: &Path) -> String {
+	path.file_name()
+		.map(|name| name.to_string_lossy().into_owned())
+		.unwrap_or_default()
+}
+
+/// Takes a bad copy out of the location, into the quarantine directory
+async fn quarantine(path: &Path, quarantine_dir: &Path) -> Result<PathBuf, FileIOError> {
+	fs::create_dir_all(quarantine_dir)
+		.await
+		.map_err(|e| FileIOError::from((quarantine_dir, e)))?;
+
+	let quarantined_path = available_path(
+		&quarantine_dir.join(path.file_name().unwrap_or_else(|| "quarantined".as_ref())),
+	);
+
+	match fs::rename(path, &quarantined_path).await {
+		Ok(()) => {}
+		Err(e) if e.raw_os_error() == Some(EXDEV) => {
+			fs::copy(path, &quarantined_path)
+				.await
+				.map_err(|e| FileIOError::from((&quarantined_path, e)))?;
+			fs::remove_file(path)
+				.await
+				.map_err(|e| FileIOError::from((path, e)))?;
+		}
+		Err(e) => return Err(FileIOError::from((path, e))),
+	}
+
+	Ok(quarantined_path)
+}
+
+/// Creates the file path of an imported file, identifying it right away instead of waiting for the
+/// file identifier, like the location watcher does
+async fn index_imported_path(
+	library: &Library,
+	location_id: location::id::Type,
+	location_path: &Path,
+	path: &Path,
+) -> Result<file_path::id::Type, JobError> {
+	let Library { db, .. } = library;
+
+	let metadata = fs::metadata(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	let iso_file_path =
+		IsolatedFilePathData::new(location_id, location_path, path, metadata.is_dir())
+			.map_err(LocationError::from)?
+			.normalized(library.config.file_name_normalization);
+
+	// An overwritten file leaves its old file path behind, its object is cleaned up if orphaned
+	if db
+		.file_path()
+		.delete_many(filter_existing_file_path_params(&iso_file_path))
+		.exec()
+		.await?
+		> 0
+	{
+		library.orphan_remover.invoke().await;
+	}
+
+	let (inode, device) = get_inode_and_device_from_path(path)
+		.await
+		.map_err(LocationError::from)?;
+
+	let file_path_metadata = FilePathMetadata {
+		inode,
+		device,
+		size_in_bytes: metadata.len(),
+		allocated_size_in_bytes: get_allocated_size(path, &metadata),
+		created_at: metadata.created_or_now().into(),
+		modified_at: metadata.modified_or_now().into(),
+	};
+
+	if metadata.is_dir() {
+		return create_file_path(library, iso_file_path, None, file_path_metadata)
+			.await
+			.map(|file_path| file_path.id)
+			.map_err(|e| LocationError::from(e).into());
+	}
+
+	let FileMetadata {
+		cas_id,
+		kind,
+		fs_metadata,
+		os_metadata,
+	} = FileMetadata::new(location_path, &iso_file_path, library.memory_budget()).await?;
+	let extension = iso_file_path.extension().to_string();
+
+	let created_file = create_file_path(
+		library,
+		iso_file_path,
+		Some(cas_id.clone()),
+		file_path_metadata,
+	)
+	.await
+	.map_err(LocationError::from)?;
+
+	object::select!(object_just_id { id });
+
+	let existing_object = db
+		.object()
+		.find_first(vec![object::file_paths::some(vec![
+			file_path::cas_id::equals(Some(cas_id)),
+			file_path::pub_id::not(created_file.pub_id.clone()),
+		])])
+		.select(object_just_id::select())
+		.exec()
+		.await?;
+
+	let object = if let Some(object) = existing_object {
+		object
+	} else {
+		db.object()
+			.create(
+				Uuid::new_v4().as_bytes().to_vec(),
+				vec![
+					object::date_created::set(Some(
+						DateTime::<Local>::from(fs_metadata.created_or_now()).into(),
+					)),
+					object::kind::set(Some(resolve_kind(db, kind, &extension).await?)),
+				],
+			)
+			.select(object_just_id::select())
+			.exec()
+			.await?
+	};
+
+	db.file_path()
+		.update(
+			file_path::id::equals(created_file.id),
+			vec![file_path::object::connect(object::id::equals(object.id))],
+		)
+		.exec()
+		.await?;
+
+	apply_os_metadata(
+		library,
+		vec![(
+			Uuid::from_slice(&created_file.pub_id).expect("file_path.pub_id is invalid!"),
+			os_metadata,
+		)],
+	)
+	.await?;
+
+	Ok(created_file.id)
+}
@@ -0,0 +1,1206 @@
+//! Folder mirrors keep two directories the same, like a working folder and its copy on a backup
+//! drive. One way mirrors make the right side a copy of the left one, two way mirrors carry the
+//! changes made on either side to the other. They run when asked to, or on their own every
+//! `interval_minutes`, see [`run_scheduled_mirrors`].
+//!
+//! Each run compares both sides with what they had in common after the previous one, the baseline
+//! of the mirror, which tells a file changed on one side from a file changed on both, a conflict,
+//! and a file deleted from one side from a file added to the other. Files are compared by their
+//! size and modification date as found on disk, not in the index, so both sides have to be in
+//! locations of this device, network shares added as locations included. Only files are mirrored,
+//! directories are created along with them.
+//!
+//! The files a run overwrites or deletes can be kept as versions for a while, see
+//! [`super::versions`].
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobManagerError, JobReportUpdate, JobResult, JobState, StatefulJob,
+		WorkerContext,
+	},
+	library::Library,
+	location::{find_location, location_with_indexer_rules, scan_location_sub_path, LocationError},
+	prisma::{file_version, folder_mirror, location, PrismaClient},
+	util::{db::maybe_missing, error::FileIOError},
+	Node,
+};
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::{Component, Path, PathBuf},
+	sync::Arc,
+	time::UNIX_EPOCH,
+};
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, time::interval};
+use tracing::{debug, error, info, trace, warn};
+
+use super::{
+	diff::DiffSide,
+	error::FileSystemJobsError,
+	extract::available_path,
+	ignore_events_for, sparse,
+	versions::{keep_version, prune_file_versions, remove_file_versions, FileVersionError},
+};
+
+/// Scheduled mirrors run at most this often
+const MIN_INTERVAL_MINUTES: u32 = 5;
+const SCHEDULE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// The actions a dry run lists in its report are cut at this many
+const MAX_PLANNED_ACTIONS: usize = 10_000;
+/// Added to the name of a file while it's being copied, so a run cut short never leaves half a file
+const PARTIAL_SUFFIX: &str = ".sd-mirror";
+
+#[derive(Error, Debug)]
+pub enum FolderMirrorError {
+	#[error("folder mirror not found <id='{0}'>")]
+	NotFound(folder_mirror::id::Type),
+	#[error("a folder mirror needs a name")]
+	MissingName,
+	#[error("mirrored directories can't be the same or inside one another")]
+	Overlapping,
+	#[error("mirrored paths have to be relative to their location: '{0}'")]
+	InvalidPath(String),
+	#[error("mirrors can't run more often than every {MIN_INTERVAL_MINUTES} minutes")]
+	IntervalTooShort,
+	#[error("folder mirrors only work between locations of this device <location_id='{0}'>")]
+	NotOnThisDevice(location::id::Type),
+	#[error("invalid settings of folder mirror <id='{0}'>: {1}")]
+	InvalidSettings(folder_mirror::id::Type, serde_json::Error),
+	#[error(transparent)]
+	Location(#[from] LocationError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error(transparent)]
+	FileVersion(#[from] FileVersionError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<FolderMirrorError> for rspc::Error {
+	fn from(err: FolderMirrorError) -> Self {
+		match err {
+			FolderMirrorError::Location(e) => e.into(),
+			FolderMirrorError::JobManager(e) => e.into(),
+			FolderMirrorError::FileVersion(e) => e.into(),
+			FolderMirrorError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			FolderMirrorError::MissingName
+			| FolderMirrorError::Overlapping
+			| FolderMirrorError::InvalidPath(_)
+			| FolderMirrorError::IntervalTooShort
+			| FolderMirrorError::NotOnThisDevice(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			FolderMirrorError::InvalidSettings(..) | FolderMirrorError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Type, PartialEq, Eq)]
+pub enum MirrorMode {
+	/// The right side is made a copy of the left one
+	#[default]
+	OneWay,
+	/// Changes made on either side are carried to the other
+	TwoWay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Type, PartialEq, Eq)]
+pub enum MirrorDeletions {
+	/// Files deleted from one side stay on the other, two way mirrors copying them back
+	#[default]
+	Keep,
+	/// Files deleted from one side are deleted from the other, unless they were changed there
+	Propagate,
+}
+
+/// What to do with a file changed on both sides since the previous run
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Type, PartialEq, Eq)]
+pub enum MirrorConflictPolicy {
+	/// The most recently modified version wins
+	#[default]
+	Newer,
+	PreferLeft,
+	PreferRight,
+	/// The most recently modified version wins, the other one being kept next to it with a counter
+	/// added to its name
+	KeepBoth,
+	/// Both versions are left as they are and reported on every run, until one is picked by hand
+	Skip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct MirrorSettings {
+	pub left: DiffSide,
+	pub right: DiffSide,
+	#[serde(default)]
+	pub mode: MirrorMode,
+	#[serde(default)]
+	pub deletions: MirrorDeletions,
+	#[serde(default)]
+	pub conflicts: MirrorConflictPolicy,
+	/// Runs on its own this often, only when asked to when `None`
+	#[serde(default)]
+	pub interval_minutes: Option<u32>,
+	/// The files a run overwrites or deletes are kept this many days, not at all when `None`
+	#[serde(default)]
+	pub keep_versions_days: Option<u32>,
+}
+
+impl MirrorSettings {
+	async fn validate(&self, library: &Library) -> Result<(), FolderMirrorError> {
+		for side in [&self.left, &self.right] {
+			if Path::new(side.path.trim_matches('/'))
+				.components()
+				.any(|component| !matches!(component, Component::Normal(_)))
+			{
+				return Err(FolderMirrorError::InvalidPath(side.path.clone()));
+			}
+
+			let location = find_location(library, side.location_id)
+				.exec()
+				.await?
+				.ok_or(LocationError::IdNotFound(side.location_id))?;
+
+			if location.node_id != Some(library.node_local_id) {
+				return Err(FolderMirrorError::NotOnThisDevice(side.location_id));
+			}
+		}
+
+		if self.left.location_id == self.right.location_id {
+			let left = Path::new(self.left.path.trim_matches('/'));
+			let right = Path::new(self.right.path.trim_matches('/'));
+
+			if left.starts_with(right) || right.starts_with(left) {
+				return Err(FolderMirrorError::Overlapping);
+			}
+		}
+
+		match self.interval_minutes {
+			Some(minutes) if minutes < MIN_INTERVAL_MINUTES => {
+				Err(FolderMirrorError::IntervalTooShort)
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct FolderMirror {
+	pub id: folder_mirror::id::Type,
+	pub name: String,
+	pub settings: MirrorSettings,
+	pub last_run_at: Option<DateTime<FixedOffset>>,
+}
+
+impl TryFrom<folder_mirror::Data> for FolderMirror {
+	type Error = FolderMirrorError;
+
+	fn try_from(data: folder_mirror::Data) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: data.id,
+			settings: serde_json::from_str(&data.settings)
+				.map_err(|e| FolderMirrorError::InvalidSettings(data.id, e))?,
+			name: data.name,
+			last_run_at: data.last_run_at,
+		})
+	}
+}
+
+pub async fn list_folder_mirrors(
+	db: &PrismaClient,
+) -> Result<Vec<FolderMirror>, FolderMirrorError> {
+	db.folder_mirror()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(TryInto::try_into)
+		.collect()
+}
+
+#[derive(Type, Deserialize)]
+pub struct FolderMirrorCreateArgs {
+	pub name: String,
+	pub settings: MirrorSettings,
+}
+
+impl FolderMirrorCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<FolderMirror, FolderMirrorError> {
+		let name = self.name.trim().to_string();
+		if name.is_empty() {
+			return Err(FolderMirrorError::MissingName);
+		}
+
+		self.settings.validate(library).await?;
+
+		let created = library
+			.db
+			.folder_mirror()
+			.create(name, serialize_settings(&self.settings), vec![])
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "folderMirrors.list");
+
+		created.try_into()
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct FolderMirrorUpdateArgs {
+	pub id: folder_mirror::id::Type,
+	#[specta(optional)]
+	pub name: Option<String>,
+	#[specta(optional)]
+	pub settings: Option<MirrorSettings>,
+}
+
+impl FolderMirrorUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<FolderMirror, FolderMirrorError> {
+		let name = match self.name.map(|name| name.trim().to_string()) {
+			Some(name) if name.is_empty() => return Err(FolderMirrorError::MissingName),
+			name => name,
+		};
+
+		let mirror = find_folder_mirror(&library.db, self.id).await?;
+
+		let mut params = vec![folder_mirror::date_modified::set(Utc::now().into())];
+		params.extend(name.map(folder_mirror::name::set));
+
+		if let Some(settings) = &self.settings {
+			settings.validate(library).await?;
+
+			// What the baseline holds is only true of the directories it was made from
+			if settings.left != mirror.settings.left || settings.right != mirror.settings.right {
+				params.push(folder_mirror::baseline::set(None));
+			}
+
+			params.push(folder_mirror::settings::set(serialize_settings(settings)));
+		}
+
+		let updated = library
+			.db
+			.folder_mirror()
+			.update(folder_mirror::id::equals(self.id), params)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "folderMirrors.list");
+
+		updated.try_into()
+	}
+}
+
+pub async fn delete_folder_mirror(
+	library: &Library,
+	id: folder_mirror::id::Type,
+) -> Result<(), FolderMirrorError> {
+	find_folder_mirror(&library.db, id).await?;
+
+	remove_file_versions(library, vec![file_version::mirror_id::equals(id)]).await?;
+
+	library
+		.db
+		.folder_mirror()
+		.delete(folder_mirror::id::equals(id))
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "folderMirrors.list");
+
+	Ok(())
+}
+
+async fn find_folder_mirror(
+	db: &PrismaClient,
+	id: folder_mirror::id::Type,
+) -> Result<FolderMirror, FolderMirrorError> {
+	db.folder_mirror()
+		.find_unique(folder_mirror::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(FolderMirrorError::NotFound(id))?
+		.try_into()
+}
+
+fn serialize_settings(settings: &MirrorSettings) -> String {
+	serde_json::to_string(settings).expect("mirror settings are always serializable")
+}
+
+/// Runs the mirrors whose interval went by since they last ran, in every library
+pub(crate) async fn run_scheduled_mirrors(node: Arc<Node>) {
+	let mut interval = interval(SCHEDULE_CHECK_INTERVAL);
+
+	loop {
+		interval.tick().await;
+
+		for library in node.library_manager.get_all_libraries().await {
+			if let Err(e) = spawn_due_mirrors(&library).await {
+				warn!(
+					"Failed to run the scheduled folder mirrors of library {}: {e:#?}",
+					library.id
+				);
+			}
+		}
+	}
+}
+
+async fn spawn_due_mirrors(library: &Library) -> Result<(), FolderMirrorError> {
+	let now = Utc::now();
+
+	for mirror in list_folder_mirrors(&library.db).await? {
+		let Some(minutes) = mirror.settings.interval_minutes else {
+			continue;
+		};
+
+		if mirror.last_run_at.map_or(false, |last_run_at| {
+			now.signed_duration_since(last_run_at) < Duration::minutes(minutes.into())
+		}) {
+			continue;
+		}
+
+		match library
+			.spawn_job(FolderMirrorJobInit {
+				mirror_id: mirror.id,
+				dry_run: false,
+			})
+			.await
+		{
+			Ok(()) => debug!("Running scheduled folder mirror <id='{}'>", mirror.id),
+			// Runs taking longer than the interval are left to finish
+			Err(JobManagerError::AlreadyRunningJob { .. }) => {}
+			Err(e) => return Err(e.into()),
+		}
+	}
+
+	Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MirrorSide {
+	Left,
+	Right,
+}
+
+impl MirrorSide {
+	fn other(self) -> Self {
+		match self {
+			Self::Left => Self::Right,
+			Self::Right => Self::Left,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+	size: u64,
+	/// Seconds since the Unix epoch
+	modified: i64,
+}
+
+/// A file both sides had the same after a run, as each side had it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct SyncedFile {
+	left: FileState,
+	right: FileState,
+}
+
+/// The files of a side, by path relative to its directory
+type Tree = BTreeMap<String, FileState>;
+type Baseline = BTreeMap<String, SyncedFile>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum MirrorAction {
+	Copy {
+		path: String,
+		from: MirrorSide,
+		/// The file replaced is renamed rather than overwritten, see
+		/// [`MirrorConflictPolicy::KeepBoth`]
+		keep_replaced: bool,
+	},
+	Delete {
+		path: String,
+		side: MirrorSide,
+	},
+}
+
+#[derive(Debug, Default)]
+struct MirrorPlan {
+	actions: Vec<MirrorAction>,
+	/// Changed on both sides and left alone
+	conflicts: Vec<String>,
+	/// The baseline of the next run, before the actions are done
+	baseline: Baseline,
+}
+
+/// Works out what has to be done for both sides to be mirrored
+fn plan(settings: &MirrorSettings, left: &Tree, right: &Tree, baseline: &Baseline) -> MirrorPlan {
+	let two_way = settings.mode == MirrorMode::TwoWay;
+	let propagate = settings.deletions == MirrorDeletions::Propagate;
+
+	let mut plan = MirrorPlan::default();
+
+	let paths = left
+		.keys()
+		.chain(right.keys())
+		.chain(baseline.keys())
+		.collect::<BTreeSet<_>>();
+
+	for path in paths {
+		let synced = baseline.get(path);
+		let (l, r) = (left.get(path), right.get(path));
+		let left_changed = l != synced.map(|synced| &synced.left);
+		let right_changed = r != synced.map(|synced| &synced.right);
+
+		let copy = |from| MirrorAction::Copy {
+			path: path.clone(),
+			from,
+			keep_replaced: false,
+		};
+		let delete = |side| MirrorAction::Delete {
+			path: path.clone(),
+			side,
+		};
+
+		let action = match (l, r) {
+			(None, None) => None,
+			// Deleted from the right, unless it was changed on the left since, which wins
+			(Some(_), None) if synced.is_some() && !left_changed && two_way && propagate => {
+				Some(delete(MirrorSide::Left))
+			}
+			(Some(_), None) => Some(copy(MirrorSide::Left)),
+			(None, Some(_)) if synced.is_some() && !right_changed => {
+				if propagate {
+					Some(delete(MirrorSide::Right))
+				} else if two_way {
+					Some(copy(MirrorSide::Right))
+				} else {
+					None
+				}
+			}
+			// New on the right, or changed there since it was deleted from the left
+			(None, Some(_)) => two_way.then(|| copy(MirrorSide::Right)),
+			(Some(l), Some(r)) => {
+				let in_sync = match synced {
+					Some(_) => !left_changed && !right_changed,
+					// Without a baseline, files copied with their dates kept are already mirrored
+					None => l == r,
+				};
+
+				if in_sync {
+					plan.baseline.insert(
+						path.clone(),
+						SyncedFile {
+							left: *l,
+							right: *r,
+						},
+					);
+					continue;
+				}
+
+				if synced.is_some() && !right_changed {
+					Some(copy(MirrorSide::Left))
+				} else if synced.is_some() && !left_changed && two_way {
+					Some(copy(MirrorSide::Right))
+				} else {
+					// Changed on both sides, or on the right of a one way mirror, which would undo it
+					match resolve(settings.conflicts, l, r) {
+						Some(MirrorSide::Right) if !two_way => {
+							// One way mirrors never write to the left, the right version is kept
+							if settings.conflicts == MirrorConflictPolicy::KeepBoth {
+								Some(MirrorAction::Copy {
+									path: path.clone(),
+									from: MirrorSide::Left,
+									keep_replaced: true,
+								})
+							} else {
+								plan.baseline.insert(
+									path.clone(),
+									SyncedFile {
+										left: *l,
+										right: *r,
+									},
+								);
+								continue;
+							}
+						}
+						Some(from) => Some(MirrorAction::Copy {
+							path: path.clone(),
+							from,
+							keep_replaced: settings.conflicts == MirrorConflictPolicy::KeepBoth,
+						}),
+						None => {
+							plan.conflicts.push(path.clone());
+							None
+						}
+					}
+				}
+			}
+		};
+
+		// Files left as they are drop out of the baseline, but the ones with something to do keep
+		// their entry until it's done, so a failure is retried as the same change
+		if let Some(action) = action {
+			if let Some(synced) = synced {
+				plan.baseline.insert(path.clone(), *synced);
+			}
+			plan.actions.push(action);
+		} else if l.is_some() && r.is_some() {
+			// Conflicts left alone are conflicts again on the next run
+			plan.baseline
+				.extend(synced.map(|synced| (path.clone(), *synced)));
+		}
+	}
+
+	plan
+}
+
+/// The side whose version wins a conflict, `None` when it's left for the user
+fn resolve(
+	policy: MirrorConflictPolicy,
+	left: &FileState,
+	right: &FileState,
+) -> Option<MirrorSide> {
+	match policy {
+		MirrorConflictPolicy::PreferLeft => Some(MirrorSide::Left),
+		MirrorConflictPolicy::PreferRight => Some(MirrorSide::Right),
+		MirrorConflictPolicy::Newer | MirrorConflictPolicy::KeepBoth => {
+			Some(if right.modified > left.modified {
+				MirrorSide::Right
+			} else {
+				MirrorSide::Left
+			})
+		}
+		MirrorConflictPolicy::Skip => None,
+	}
+}
+
+async fn file_state(path: &Path) -> Result<FileState, FileIOError> {
+	let metadata = fs::metadata(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	Ok(FileState {
+		size: metadata.len(),
+		modified: metadata
+			.modified()
+			.ok()
+			.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+			.map_or(0, |since| since.as_secs() as i64),
+	})
+}
+
+/// Every file below a directory, an empty tree when it doesn't exist yet. Symbolic links are left
+/// out, as well as what runs and moves cut short left behind.
+async fn walk(root: &Path) -> Result<Tree, FileIOError> {
+	let mut tree = Tree::new();
+
+	if fs::metadata(root).await.is_err() {
+		return Ok(tree);
+	}
+
+	let mut pending = vec![(root.to_path_buf(), String::new())];
+
+	while let Some((directory, prefix)) = pending.pop() {
+		let mut read_dir = fs::read_dir(&directory)
+			.await
+			.map_err(|e| FileIOError::from((&directory, e)))?;
+
+		while let Some(entry) = read_dir
+			.next_entry()
+			.await
+			.map_err(|e| FileIOError::from((&directory, e)))?
+		{
+			let name = entry.file_name().to_string_lossy().into_owned();
+			if name.ends_with(PARTIAL_SUFFIX) || name.ends_with(".sd-moving") {
+				continue;
+			}
+
+			let path = entry.path();
+			let file_type = entry
+				.file_type()
+				.await
+				.map_err(|e| FileIOError::from((&path, e)))?;
+			let relative = format!("{prefix}{name}");
+
+			if file_type.is_dir() {
+				pending.push((path, format!("{relative}/")));
+			} else if file_type.is_file() {
+				tree.insert(relative, file_state(&path).await?);
+			}
+		}
+	}
+
+	Ok(tree)
+}
+
+pub struct FolderMirrorJob {}
+
+/// `FolderMirrorJobInit` runs a folder mirror, only listing what it would do in a dry run
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct FolderMirrorJobInit {
+	pub mirror_id: folder_mirror::id::Type,
+	#[serde(default)]
+	pub dry_run: bool,
+}
+
+impl JobInitData for FolderMirrorJobInit {
+	type Job = FolderMirrorJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FolderMirrorJobReport {
+	mirror_id: folder_mirror::id::Type,
+	dry_run: bool,
+	/// What a dry run would have done
+	planned: Vec<MirrorAction>,
+	planned_truncated: bool,
+	copied_count: usize,
+	copied_bytes: u64,
+	deleted_count: usize,
+	versions_kept: usize,
+	versions_removed: usize,
+	conflicts: Vec<String>,
+	failed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FolderMirrorJobData {
+	settings: MirrorSettings,
+	left_root: PathBuf,
+	right_root: PathBuf,
+	baseline: Baseline,
+	/// Sides written to, which are scanned once done
+	changed_sides: BTreeSet<MirrorSide>,
+	report: FolderMirrorJobReport,
+}
+
+impl FolderMirrorJobData {
+	fn root(&self, side: MirrorSide) -> &Path {
+		match side {
+			MirrorSide::Left => &self.left_root,
+			MirrorSide::Right => &self.right_root,
+		}
+	}
+
+	fn side(&self, side: MirrorSide) -> &DiffSide {
+		match side {
+			MirrorSide::Left => &self.settings.left,
+			MirrorSide::Right => &self.settings.right,
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FolderMirrorJob {
+	type Init = FolderMirrorJobInit;
+	type Data = FolderMirrorJobData;
+	type Step = MirrorAction;
+
+	const NAME: &'static str = "folder_mirror";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+		let FolderMirrorJobInit { mirror_id, dry_run } = state.init;
+
+		let mirror = db
+			.folder_mirror()
+			.find_unique(folder_mirror::id::equals(mirror_id))
+			.exec()
+			.await?
+			.ok_or(FolderMirrorError::NotFound(mirror_id))
+			.map_err(FileSystemJobsError::from)?;
+
+		if !dry_run {
+			// Set first, so a scheduled mirror failing to start isn't retried every minute
+			db.folder_mirror()
+				.update(
+					folder_mirror::id::equals(mirror_id),
+					vec![folder_mirror::last_run_at::set(Some(Utc::now().into()))],
+				)
+				.exec()
+				.await?;
+		}
+
+		let settings = serde_json::from_str::<MirrorSettings>(&mirror.settings).map_err(|e| {
+			FileSystemJobsError::from(FolderMirrorError::InvalidSettings(mirror_id, e))
+		})?;
+		let baseline = mirror
+			.baseline
+			.as_deref()
+			.and_then(|baseline| serde_json::from_str::<Baseline>(baseline).ok())
+			.unwrap_or_default();
+
+		let mut roots = Vec::with_capacity(2);
+		for side in [&settings.left, &settings.right] {
+			let location = find_location(&ctx.library, side.location_id)
+				.exec()
+				.await?
+				.ok_or(LocationError::IdNotFound(side.location_id))?;
+
+			if location.node_id != Some(ctx.library.node_local_id) {
+				return Err(JobError::EarlyFinish {
+					name: <Self as StatefulJob>::NAME.to_string(),
+					reason: format!(
+						"The location <id='{}'> of the mirror isn't on this device",
+						side.location_id
+					),
+				});
+			}
+
+			let location_path = PathBuf::from(maybe_missing(&location.path, "location.path")?);
+
+			// An unplugged drive would look like it had everything deleted
+			if fs::metadata(&location_path).await.is_err() {
+				return Err(JobError::EarlyFinish {
+					name: <Self as StatefulJob>::NAME.to_string(),
+					reason: format!("The location at {} is offline", location_path.display()),
+				});
+			}
+
+			roots.push(location_path.join(side.path.trim_matches('/')));
+		}
+		let right_root = roots.pop().expect("both roots were pushed");
+		let left_root = roots.pop().expect("both roots were pushed");
+
+		let (left, right) = (walk(&left_root).await?, walk(&right_root).await?);
+		let plan = plan(&settings, &left, &right, &baseline);
+
+		info!(
+			"Folder mirror <id='{mirror_id}'> has {} actions and {} conflicts",
+			plan.actions.len(),
+			plan.conflicts.len()
+		);
+
+		let mut report = FolderMirrorJobReport {
+			mirror_id,
+			dry_run,
+			conflicts: plan.conflicts,
+			..Default::default()
+		};
+
+		if dry_run {
+			report.planned_truncated = plan.actions.len() > MAX_PLANNED_ACTIONS;
+			report.planned = plan.actions;
+			report.planned.truncate(MAX_PLANNED_ACTIONS);
+		} else {
+			state.steps = plan.actions.into_iter().collect();
+		}
+
+		state.data = Some(FolderMirrorJobData {
+			settings,
+			left_root,
+			right_root,
+			baseline: plan.baseline,
+			changed_sides: BTreeSet::new(),
+			report,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let action = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		let result = match action {
+			MirrorAction::Copy {
+				path,
+				from,
+				keep_replaced,
+			} => copy(&ctx.library, data, path, *from, *keep_replaced).await,
+			MirrorAction::Delete { path, side } => delete(&ctx.library, data, path, *side).await,
+		};
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		result.map_err(|e| {
+			let message = format!("Failed to mirror {action:?}: {e}");
+			data.report.failed.push(message.clone());
+
+			JobError::StepCompletedWithErrors(vec![message])
+		})
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let data = extract_job_data_mut!(state);
+
+		if let (false, Some(days)) = (data.report.dry_run, data.settings.keep_versions_days) {
+			match prune_file_versions(
+				&ctx.library,
+				data.report.mirror_id,
+				Utc::now() - Duration::days(days.into()),
+			)
+			.await
+			{
+				Ok(removed) => data.report.versions_removed = removed,
+				Err(e) => warn!("Failed to remove the expired file versions: {e}"),
+			}
+		}
+
+		let report = &data.report;
+
+		info!("Finalizing folder mirror job: {report:?}");
+
+		if !report.dry_run {
+			ctx.library
+				.db
+				.folder_mirror()
+				.update(
+					folder_mirror::id::equals(report.mirror_id),
+					vec![folder_mirror::baseline::set(Some(serde_json::to_string(
+						&data.baseline,
+					)?))],
+				)
+				.exec()
+				.await?;
+
+			invalidate_query!(ctx.library, "folderMirrors.list");
+		}
+
+		for side in &data.changed_sides {
+			let DiffSide {
+				location_id,
+				path: sub_path,
+			} = data.side(*side);
+			let location_id = *location_id;
+
+			match find_location(&ctx.library, location_id)
+				.include(location_with_indexer_rules::include())
+				.exec()
+				.await
+			{
+				Ok(Some(location)) => {
+					if let Err(e) =
+						scan_location_sub_path(&ctx.library, location, sub_path.trim_matches('/'))
+							.await
+					{
+						warn!("Failed to scan the mirrored directory: {e}");
+					}
+				}
+				Ok(None) => warn!("Mirrored location <id='{location_id}'> was removed"),
+				Err(e) => error!("Failed to fetch the mirrored location: {e:#?}"),
+			}
+		}
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Copies a file over to the other side, through a partial file replacing the target once complete
+async fn copy(
+	library: &Library,
+	data: &mut FolderMirrorJobData,
+	path: &str,
+	from: MirrorSide,
+	keep_replaced: bool,
+) -> Result<(), JobError> {
+	let to = from.other();
+	let source = data.root(from).join(path);
+	let target = data.root(to).join(path);
+	let target_location_id = data.side(to).location_id;
+
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.map_err(|e| FileIOError::from((parent, e)))?;
+	}
+
+	let partial_path = {
+		let mut name = target.file_name().unwrap_or_default().to_owned();
+		name.push(PARTIAL_SUFFIX);
+		target.with_file_name(name)
+	};
+
+	let _guards = (
+		ignore_events_for(library, target_location_id, &partial_path).await,
+		ignore_events_for(library, target_location_id, &target).await,
+	);
+
+	if fs::metadata(&target).await.is_ok() {
+		if keep_replaced {
+			let kept = available_path(&target);
+			fs::rename(&target, &kept)
+				.await
+				.map_err(|e| FileIOError::from((&kept, e)))?;
+		} else {
+			keep_replaced_version(library, data, path, to, &target).await?;
+		}
+	}
+
+	let copied = async {
+		let size = sparse::copy_file(&source, &partial_path)
+			.await
+			.map_err(|e| FileIOError::from((&partial_path, e)))?;
+
+		fs::rename(&partial_path, &target)
+			.await
+			.map_err(|e| FileIOError::from((&target, e)))?;
+
+		Ok::<_, FileIOError>(size)
+	}
+	.await;
+
+	let size = match copied {
+		Ok(size) => size,
+		Err(e) => {
+			fs::remove_file(&partial_path).await.ok();
+			return Err(e.into());
+		}
+	};
+
+	trace!("Mirrored {} to {}", source.display(), target.display());
+
+	let (source_state, target_state) = (file_state(&source).await?, file_state(&target).await?);
+	let (left, right) = match from {
+		MirrorSide::Left => (source_state, target_state),
+		MirrorSide::Right => (target_state, source_state),
+	};
+	data.baseline
+		.insert(path.to_string(), SyncedFile { left, right });
+
+	data.changed_sides.insert(to);
+	data.report.copied_count += 1;
+	data.report.copied_bytes += size;
+
+	Ok(())
+}
+
+/// Deletes a file from a side, along with the directories it leaves empty
+async fn delete(
+	library: &Library,
+	data: &mut FolderMirrorJobData,
+	path: &str,
+	side: MirrorSide,
+) -> Result<(), JobError> {
+	let root = data.root(side).to_path_buf();
+	let target = root.join(path);
+
+	keep_replaced_version(library, data, path, side, &target).await?;
+
+	fs::remove_file(&target)
+		.await
+		.map_err(|e| FileIOError::from((&target, e)))?;
+
+	let mut directory = target.parent();
+	while let Some(parent) = directory.filter(|parent| *parent != root) {
+		// Fails on the first directory with something left in it
+		if fs::remove_dir(parent).await.is_err() {
+			break;
+		}
+		directory = parent.parent();
+	}
+
+	data.baseline.remove(path);
+	data.changed_sides.insert(side);
+	data.report.deleted_count += 1;
+
+	Ok(())
+}
+
+/// Keeps the file about to be overwritten or deleted as a version when the mirror keeps them, a
+/// file which can't be kept being left alone
+async fn keep_replaced_version(
+	library: &Library,
+	data: &mut FolderMirrorJobData,
+	path: &str,
+	side: MirrorSide,
+	target: &Path,
+) -> Result<(), JobError> {
+	if data.settings.keep_versions_days.is_none() {
+		return Ok(());
+	}
+
+	let DiffSide {
+		location_id,
+		path: sub_path,
+	} = data.side(side);
+
+	keep_version(
+		library,
+		data.report.mirror_id,
+		*location_id,
+		&Path::new(sub_path.trim_matches('/')).join(path),
+		target,
+	)
+	.await
+	.map_err(FileSystemJobsError::from)?;
+
+	data.report.versions_kept += 1;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn settings(mode: MirrorMode, deletions: MirrorDeletions) -> MirrorSettings {
+		let side = |path: &str| DiffSide {
+			location_id: 1,
+			path: path.to_string(),
+		};
+
+		MirrorSettings {
+			left: side("left"),
+			right: side("right"),
+			mode,
+			deletions,
+			conflicts: MirrorConflictPolicy::Newer,
+			interval_minutes: None,
+			keep_versions_days: None,
+		}
+	}
+
+	fn state(size: u64, modified: i64) -> FileState {
+		FileState { size, modified }
+	}
+
+	fn tree(files: &[(&str, FileState)]) -> Tree {
+		files
+			.iter()
+			.map(|(path, state)| (path.to_string(), *state))
+			.collect()
+	}
+
+	fn synced(files: &[(&str, FileState)]) -> Baseline {
+		files
+			.iter()
+			.map(|(path, state)| {
+				(
+					path.to_string(),
+					SyncedFile {
+						left: *state,
+						right: *state,
+					},
+				)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn two_way_carries_changes_and_deletions() {
+		let baseline = synced(&[
+			("same", state(1, 1)),
+			("edited on left", state(2, 2)),
+			("deleted on right", state(3, 3)),
+			("edited on both", state(4, 4)),
+		]);
+		let left = tree(&[
+			("same", state(1, 1)),
+			("edited on left", state(5, 10)),
+			("deleted on right", state(3, 3)),
+			("edited on both", state(6, 20)),
+		]);
+		let right = tree(&[
+			("same", state(1, 1)),
+			("edited on left", state(2, 2)),
+			("edited on both", state(7, 30)),
+			("new on right", state(8, 8)),
+		]);
+
+		let plan = plan(
+			&settings(MirrorMode::TwoWay, MirrorDeletions::Propagate),
+			&left,
+			&right,
+			&baseline,
+		);
+
+		let copy = |path: &str, from| MirrorAction::Copy {
+			path: path.to_string(),
+			from,
+			keep_replaced: false,
+		};
+
+		assert_eq!(
+			plan.actions,
+			[
+				MirrorAction::Delete {
+					path: "deleted on right".to_string(),
+					side: MirrorSide::Left,
+				},
+				copy("edited on both", MirrorSide::Right),
+				copy("edited on left", MirrorSide::Left),
+				copy("new on right", MirrorSide::Right),
+			]
+		);
+		assert!(plan.conflicts.is_empty());
+		assert!(plan.baseline.contains_key("same"));
+	}
+
+	#[test]
+	fn one_way_restores_the_right_side() {
+		let baseline = synced(&[
+			("deleted on right", state(1, 1)),
+			("deleted on left", state(2, 2)),
+		]);
+		let left = tree(&[("deleted on right", state(1, 1))]);
+		let right = tree(&[("deleted on left", state(2, 2)), ("extra", state(3, 3))]);
+
+		let keep = plan(
+			&settings(MirrorMode::OneWay, MirrorDeletions::Keep),
+			&left,
+			&right,
+			&baseline,
+		);
+		assert_eq!(
+			keep.actions,
+			[MirrorAction::Copy {
+				path: "deleted on right".to_string(),
+				from: MirrorSide::Left,
+				keep_replaced: false,
+			}]
+		);
+
+		let propagate = plan(
+			&settings(MirrorMode::OneWay, MirrorDeletions::Propagate),
+			&left,
+			&right,
+			&baseline,
+		);
+		assert_eq!(
+			propagate.actions,
+			[
+				MirrorAction::Delete {
+					path: "deleted on left".to_string(),
+					side: MirrorSide::Right,
+				},
+				MirrorAction::Copy {
+					path: "deleted on right".to_string(),
+					from: MirrorSide::Left,
+					keep_replaced: false,
+				},
+			]
+		);
+	}
+}
@@ -1,22 +1,55 @@
 use crate::{
+	library::Library,
 	location::{
 		file_path_helper::{file_path_with_object, IsolatedFilePathData},
-		LocationError,
+		IgnoreEventsForPathGuard, LocationError,
 	},
+	object::{fs::sparse::copy_file, special::copy_link},
 	prisma::{file_path, location, PrismaClient},
-	util::db::{maybe_missing, MissingFieldError},
+	util::{
+		db::{maybe_missing, MissingFieldError},
+		error::FileIOError,
+		long_path::to_extended_length,
+	},
 };
 
-use std::path::{Path, PathBuf};
+use std::{
+	ffi::OsString,
+	path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::fs;
+use tracing::error;
 
+pub mod archive;
+pub mod broken_links;
+pub mod compress;
+pub mod convert;
 pub mod create;
 pub mod delete;
+pub mod diff;
+pub mod disk_image;
 pub mod erase;
+pub mod export;
+pub mod extension_mismatch;
+pub mod extract;
+pub mod ghost;
+pub mod import;
+pub mod mirror;
+pub mod offline;
+pub mod pdf;
+pub mod scrub;
+pub mod template;
+pub mod versions;
 
 pub mod copy;
 pub mod cut;
+pub mod sparse;
+pub mod tiering;
+pub mod time_shift;
+pub mod transcode;
 
 // pub mod decrypt;
 // pub mod encrypt;
@@ -25,7 +58,7 @@ pub mod error;
 
 use error::FileSystemJobsError;
 
-// pub const BYTES_EXT: &str = ".bytes";
+pub const BYTES_EXT: &str = ".bytes";
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum ObjectType {
@@ -33,6 +66,16 @@ pub enum ObjectType {
 	Directory,
 }
 
+/// What to do when a file operation finds something already at its target path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, Type, Eq, PartialEq)]
+pub enum ConflictPolicy {
+	#[default]
+	Skip,
+	Overwrite,
+	/// Keeps the existing file, writing the new one with a counter added to its name
+	KeepBoth,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileData {
 	pub file_path: file_path_with_object::Data,
@@ -82,7 +125,10 @@ pub async fn get_many_files_datas(
 			.ok_or(FileSystemJobsError::FilePathIdNotFound(*file_path_id))
 			.and_then(|path_data| {
 				Ok(FileData {
-					full_path: location_path.join(IsolatedFilePathData::try_from(&path_data)?),
+					full_path: to_extended_length(
+						&location_path.join(IsolatedFilePathData::try_from(&path_data)?),
+					)
+					.into_owned(),
 					file_path: path_data,
 				})
 			})
@@ -109,9 +155,12 @@ pub async fn get_file_data_from_isolated_file_path(
 		})
 		.and_then(|path_data| {
 			Ok(FileData {
-				full_path: location_path
-					.as_ref()
-					.join(IsolatedFilePathData::try_from(&path_data)?),
+				full_path: to_extended_length(
+					&location_path
+						.as_ref()
+						.join(IsolatedFilePathData::try_from(&path_data)?),
+				)
+				.into_owned(),
 				file_path: path_data,
 			})
 		})
@@ -172,3 +221,111 @@ fn construct_target_filename(
 		)
 	})
 }
+
+/// Keeps the location watcher from indexing a file while we are still writing it
+async fn ignore_events_for<'a>(
+	library: &'a Library,
+	location_id: location::id::Type,
+	path: &Path,
+) -> Option<IgnoreEventsForPathGuard<'a>> {
+	library
+		.location_manager()
+		.temporary_ignore_events_for_path(location_id, library.clone(), path)
+		.await
+		.map_or_else(
+			|e| {
+				error!(
+					"Failed to make location manager ignore the path {}; Error: {e:#?}",
+					path.display()
+				);
+				None
+			},
+			Some,
+		)
+}
+
+/// Where a whole tree is copied to until the copy is complete, next to its target
+pub(crate) fn staging_path(target: &Path) -> PathBuf {
+	let mut name = OsString::from(".");
+	name.push(target.file_name().unwrap_or_default());
+	name.push(".sd-moving");
+
+	target.with_file_name(name)
+}
+
+/// Moves a file or a whole directory to another volume, where it can't just be renamed. The target
+/// only appears once everything was copied, see
+/// [`JournalOperation::MoveAcrossVolumes`](crate::job::JournalOperation::MoveAcrossVolumes).
+pub(crate) async fn move_across_volumes(
+	source: &Path,
+	target: &Path,
+	keep_holes: bool,
+) -> Result<(), FileIOError> {
+	copy_tree_staged(source, target, keep_holes).await?;
+
+	if fs::symlink_metadata(source)
+		.await
+		.map_err(|e| FileIOError::from((source, e)))?
+		.is_dir()
+	{
+		fs::remove_dir_all(source).await
+	} else {
+		fs::remove_file(source).await
+	}
+	.map_err(|e| FileIOError::from((source, e)))
+}
+
+/// Copies a file or a whole directory, which only appears at `target` once complete, see
+/// [`JournalOperation::CopyTree`](crate::job::JournalOperation::CopyTree)
+pub(crate) async fn copy_tree_staged(
+	source: &Path,
+	target: &Path,
+	keep_holes: bool,
+) -> Result<(), FileIOError> {
+	let staging = staging_path(target);
+	copy_tree(source, &staging, keep_holes).await?;
+
+	fs::rename(&staging, target)
+		.await
+		.map_err(|e| FileIOError::from((target, e)))
+}
+
+async fn copy_tree(source: &Path, target: &Path, keep_holes: bool) -> Result<(), FileIOError> {
+	let mut pending = vec![(source.to_path_buf(), target.to_path_buf())];
+
+	while let Some((source, target)) = pending.pop() {
+		let metadata = fs::symlink_metadata(&source)
+			.await
+			.map_err(|e| FileIOError::from((&source, e)))?;
+
+		if metadata.is_dir() {
+			fs::create_dir(&target)
+				.await
+				.map_err(|e| FileIOError::from((&target, e)))?;
+
+			let mut read_dir = fs::read_dir(&source)
+				.await
+				.map_err(|e| FileIOError::from((&source, e)))?;
+			while let Some(entry) = read_dir
+				.next_entry()
+				.await
+				.map_err(|e| FileIOError::from((&source, e)))?
+			{
+				pending.push((entry.path(), target.join(entry.file_name())));
+			}
+
+			continue;
+		}
+
+		if metadata.is_symlink() {
+			copy_link(&source, &target).await
+		} else if keep_holes {
+			copy_file(&source, &target).await.map(|_| ())
+		} else {
+			fs::copy(&source, &target).await.map(|_| ())
+		}
+		.map_err(|e| FileIOError::from((&target, e)))?;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,530 @@
+//! Keeping files of remote locations on this device, like Files On-Demand. Ghosts are retrieved into
+//! a cache when they're opened, see [`super::ghost`], and that cache is kept within the budget of
+//! the library by evicting what was used the longest ago.
+//!
+//! Files and directories can be pinned offline, which retrieves their content ahead of time, again
+//! for the files added to pinned directories later on, and keeps it out of eviction.
+
+use crate::{
+	invalidate_query,
+	job::JobManagerError,
+	library::Library,
+	location::LocationError,
+	prisma::{file_path, location, offline_pin, PrismaClient},
+	util::error::FileIOError,
+	Node,
+};
+
+use std::{
+	collections::HashMap,
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, UNIX_EPOCH},
+};
+
+use prisma_client_rust::{operator::or, QueryError};
+use rspc::ErrorCode;
+use serde::Serialize;
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, time::interval};
+use tracing::{debug, trace, warn};
+
+use super::ghost::{
+	retrieved_cache_dir, retrieved_file_path, FileRetrieverJobInit, ReachableLocations,
+};
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum OfflineError {
+	#[error(transparent)]
+	Location(#[from] LocationError),
+	#[error(transparent)]
+	JobManager(#[from] JobManagerError),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<OfflineError> for rspc::Error {
+	fn from(err: OfflineError) -> Self {
+		match err {
+			OfflineError::Location(e) => e.into(),
+			OfflineError::JobManager(e) => e.into(),
+			OfflineError::FileIO(_) | OfflineError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+file_path::select!(file_path_for_offline {
+	id
+	pub_id
+	location_id
+	materialized_path
+	name
+	extension
+	is_dir
+	tiered_to_location_id
+	compressed_at
+	object: select { date_accessed }
+});
+
+impl file_path_for_offline::Data {
+	/// What pins this file path, see `OfflinePin::path`
+	fn pin_path(&self) -> Option<String> {
+		let materialized_path = self.materialized_path.as_deref()?;
+		let name = self.name.as_deref()?;
+
+		Some(match self.extension.as_deref() {
+			_ if self.is_dir == Some(true) => format!("{materialized_path}{name}/"),
+			Some(extension) if !extension.is_empty() => {
+				format!("{materialized_path}{name}.{extension}")
+			}
+			_ => format!("{materialized_path}{name}"),
+		})
+	}
+
+	fn is_pinned(&self, pins: &[String]) -> bool {
+		let (Some(materialized_path), Some(pin_path)) =
+			(self.materialized_path.as_deref(), self.pin_path())
+		else {
+			return false;
+		};
+
+		pins.iter().any(|pin| {
+			if pin.ends_with('/') {
+				materialized_path.starts_with(pin.as_str())
+			} else {
+				*pin == pin_path
+			}
+		})
+	}
+}
+
+/// The pins of every location, by location
+async fn pins_by_location(
+	db: &PrismaClient,
+) -> Result<HashMap<location::id::Type, Vec<String>>, QueryError> {
+	let mut pins = HashMap::<_, Vec<_>>::new();
+
+	for pin in db.offline_pin().find_many(vec![]).exec().await? {
+		pins.entry(pin.location_id).or_default().push(pin.path);
+	}
+
+	Ok(pins)
+}
+
+pub async fn list_offline_pins(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<Vec<offline_pin::Data>, QueryError> {
+	db.offline_pin()
+		.find_many(vec![offline_pin::location_id::equals(location_id)])
+		.exec()
+		.await
+}
+
+async fn file_paths_for_offline(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+	file_path_ids: Vec<file_path::id::Type>,
+) -> Result<Vec<file_path_for_offline::Data>, QueryError> {
+	db.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::id::in_vec(file_path_ids),
+		])
+		.select(file_path_for_offline::select())
+		.exec()
+		.await
+}
+
+/// Pins files and directories of a location offline, retrieving the content of the ones that are
+/// ghosts right away
+pub async fn pin_offline(
+	library: &Library,
+	location_id: location::id::Type,
+	file_path_ids: Vec<file_path::id::Type>,
+) -> Result<(), OfflineError> {
+	let db = &library.db;
+
+	db.location()
+		.find_unique(location::id::equals(location_id))
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(location_id))?;
+
+	let paths = file_paths_for_offline(db, location_id, file_path_ids)
+		.await?
+		.iter()
+		.filter_map(file_path_for_offline::Data::pin_path)
+		.collect::<Vec<_>>();
+
+	db._batch(
+		paths
+			.into_iter()
+			.map(|path| {
+				db.offline_pin().upsert(
+					offline_pin::location_id_path(location_id, path.clone()),
+					offline_pin::create(location::id::equals(location_id), path, vec![]),
+					vec![],
+				)
+			})
+			.collect::<Vec<_>>(),
+	)
+	.await?;
+
+	invalidate_query!(library, "files.offlinePins");
+
+	retrieve_pinned(library, location_id).await
+}
+
+/// Unpins files and directories of a location, their content staying in the cache until evicted
+pub async fn unpin_offline(
+	library: &Library,
+	location_id: location::id::Type,
+	file_path_ids: Vec<file_path::id::Type>,
+) -> Result<(), OfflineError> {
+	let paths = file_paths_for_offline(&library.db, location_id, file_path_ids)
+		.await?
+		.iter()
+		.filter_map(file_path_for_offline::Data::pin_path)
+		.collect();
+
+	library
+		.db
+		.offline_pin()
+		.delete_many(vec![
+			offline_pin::location_id::equals(location_id),
+			offline_pin::path::in_vec(paths),
+		])
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "files.offlinePins");
+
+	Ok(())
+}
+
+/// Retrieves the pinned ghosts of a location whose content isn't in the cache yet
+pub async fn retrieve_pinned(
+	library: &Library,
+	location_id: location::id::Type,
+) -> Result<(), OfflineError> {
+	let pins = pins_by_location(&library.db)
+		.await?
+		.remove(&location_id)
+		.unwrap_or_default();
+	if pins.is_empty() {
+		return Ok(());
+	}
+
+	let reachable = ReachableLocations::fetch(library).await?;
+
+	// Pinned files are found below their directory, and filtered down to the pinned ones
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::is_dir::equals(Some(false)),
+			or(pins
+				.iter()
+				.map(|pin| {
+					let directory = &pin[..=pin.rfind('/').unwrap_or_default()];
+					file_path::materialized_path::starts_with(directory.to_string())
+				})
+				.collect()),
+		])
+		.select(file_path_for_offline::select())
+		.exec()
+		.await?;
+
+	let mut file_path_ids = vec![];
+	for file_path in file_paths {
+		let is_ghost = reachable.is_ghost(
+			file_path.location_id,
+			file_path.tiered_to_location_id,
+			file_path.compressed_at.is_some(),
+		);
+
+		if is_ghost
+			&& file_path.is_pinned(&pins)
+			&& fs::metadata(retrieved_file_path(library, &file_path.pub_id))
+				.await
+				.is_err()
+		{
+			file_path_ids.push(file_path.id);
+		}
+	}
+
+	if file_path_ids.is_empty() {
+		return Ok(());
+	}
+
+	debug!(
+		"Retrieving {} pinned files of location {location_id}",
+		file_path_ids.len()
+	);
+
+	match library
+		.spawn_job(FileRetrieverJobInit {
+			location_id,
+			file_path_ids,
+		})
+		.await
+	{
+		// The next maintenance picks up whatever the running retrieval doesn't cover
+		Ok(()) | Err(JobManagerError::AlreadyRunningJob { .. }) => Ok(()),
+		Err(e) => Err(e.into()),
+	}
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+	path: PathBuf,
+	size: u64,
+	/// Seconds since the Unix epoch
+	last_used: i64,
+	pinned: bool,
+	/// Its file path is gone, so nothing can open it anymore
+	orphan: bool,
+}
+
+async fn cache_entries(library: &Library) -> Result<Vec<CacheEntry>, OfflineError> {
+	let cache_dir = retrieved_cache_dir(library);
+
+	let mut files = vec![];
+	let mut read_dir = match fs::read_dir(&cache_dir).await {
+		Ok(read_dir) => read_dir,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(FileIOError::from((&cache_dir, e)).into()),
+	};
+	while let Some(entry) = read_dir
+		.next_entry()
+		.await
+		.map_err(|e| FileIOError::from((&cache_dir, e)))?
+	{
+		// Retrievals still going on are left alone
+		let Some(pub_id) = entry
+			.file_name()
+			.to_str()
+			.and_then(|name| hex::decode(name).ok())
+		else {
+			continue;
+		};
+
+		let path = entry.path();
+		let metadata = entry
+			.metadata()
+			.await
+			.map_err(|e| FileIOError::from((&path, e)))?;
+		let retrieved_at = metadata
+			.modified()
+			.ok()
+			.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+			.map_or(0, |since| since.as_secs() as i64);
+
+		files.push((pub_id, path, metadata.len(), retrieved_at));
+	}
+
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![file_path::pub_id::in_vec(
+			files.iter().map(|(pub_id, ..)| pub_id.clone()).collect(),
+		)])
+		.select(file_path_for_offline::select())
+		.exec()
+		.await?
+		.into_iter()
+		.map(|file_path| (file_path.pub_id.clone(), file_path))
+		.collect::<HashMap<_, _>>();
+
+	let pins = pins_by_location(&library.db).await?;
+
+	Ok(files
+		.into_iter()
+		.map(|(pub_id, path, size, retrieved_at)| {
+			let file_path = file_paths.get(&pub_id);
+			let opened_at = file_path
+				.and_then(|file_path| file_path.object.as_ref()?.date_accessed)
+				.map(|date_accessed| date_accessed.timestamp());
+
+			CacheEntry {
+				path,
+				size,
+				last_used: opened_at.map_or(retrieved_at, |opened_at| opened_at.max(retrieved_at)),
+				pinned: file_path.map_or(false, |file_path| {
+					file_path
+						.location_id
+						.and_then(|location_id| pins.get(&location_id))
+						.map_or(false, |pins| file_path.is_pinned(pins))
+				}),
+				orphan: file_path.is_none(),
+			}
+		})
+		.collect())
+}
+
+/// The entries to evict for the cache to fit in the budget, the orphans and then the ones used the
+/// longest ago. Pinned entries are never evicted, even when they alone don't fit.
+fn evictions(entries: &[CacheEntry], budget: u64) -> Vec<usize> {
+	let mut evicted = entries
+		.iter()
+		.enumerate()
+		.filter(|(_, entry)| entry.orphan)
+		.map(|(i, _)| i)
+		.collect::<Vec<_>>();
+
+	let mut total = entries
+		.iter()
+		.filter(|entry| !entry.orphan)
+		.map(|entry| entry.size)
+		.sum::<u64>();
+
+	let mut candidates = entries
+		.iter()
+		.enumerate()
+		.filter(|(_, entry)| !entry.orphan && !entry.pinned)
+		.collect::<Vec<_>>();
+	candidates.sort_by_key(|(_, entry)| entry.last_used);
+
+	for (i, entry) in candidates {
+		if total <= budget {
+			break;
+		}
+
+		total -= entry.size;
+		evicted.push(i);
+	}
+
+	evicted
+}
+
+/// Evicts what doesn't fit in the budget of the library from its cache
+pub async fn enforce_budget(library: &Library) -> Result<(), OfflineError> {
+	let entries = cache_entries(library).await?;
+	let budget = u64::from(library.config.offline_cache_budget_in_mb) * BYTES_PER_MB;
+
+	let evicted = evictions(&entries, budget);
+	if evicted.is_empty() {
+		return Ok(());
+	}
+
+	for i in &evicted {
+		let entry = &entries[*i];
+		match fs::remove_file(&entry.path).await {
+			Ok(()) => trace!("Evicted {} from the offline cache", entry.path.display()),
+			// It may have been evicted by another run already
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => return Err(FileIOError::from((&entry.path, e)).into()),
+		}
+	}
+
+	debug!(
+		"Evicted {} files from the offline cache of library {}",
+		evicted.len(),
+		library.id
+	);
+
+	invalidate_query!(library, "files.offlineCacheUsage");
+
+	Ok(())
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct OfflineCacheUsage {
+	pub used_in_mb: u32,
+	pub pinned_in_mb: u32,
+	pub budget_in_mb: u32,
+}
+
+pub async fn offline_cache_usage(library: &Library) -> Result<OfflineCacheUsage, OfflineError> {
+	let entries = cache_entries(library).await?;
+	let in_mb = |bytes: u64| (bytes / BYTES_PER_MB).try_into().unwrap_or(u32::MAX);
+
+	Ok(OfflineCacheUsage {
+		used_in_mb: in_mb(entries.iter().map(|entry| entry.size).sum()),
+		pinned_in_mb: in_mb(
+			entries
+				.iter()
+				.filter(|entry| entry.pinned)
+				.map(|entry| entry.size)
+				.sum(),
+		),
+		budget_in_mb: library.config.offline_cache_budget_in_mb,
+	})
+}
+
+/// Retrieves what was pinned or added to pinned directories, and evicts what doesn't fit anymore,
+/// in every library now and then
+pub(crate) async fn maintain_offline_caches(node: Arc<Node>) {
+	let mut interval = interval(MAINTENANCE_INTERVAL);
+
+	loop {
+		interval.tick().await;
+
+		for library in node.library_manager.get_all_libraries().await {
+			let pinned_locations = match pins_by_location(&library.db).await {
+				Ok(pins) => pins.into_keys().collect::<Vec<_>>(),
+				Err(e) => {
+					warn!(
+						"Failed to fetch the offline pins of library {}: {e:#?}",
+						library.id
+					);
+					continue;
+				}
+			};
+
+			for location_id in pinned_locations {
+				if let Err(e) = retrieve_pinned(&library, location_id).await {
+					warn!("Failed to retrieve the pinned files of location {location_id}: {e:#?}");
+				}
+			}
+
+			if let Err(e) = enforce_budget(&library).await {
+				warn!(
+					"Failed to evict the offline cache of library {}: {e:#?}",
+					library.id
+				);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(size: u64, last_used: i64, pinned: bool, orphan: bool) -> CacheEntry {
+		CacheEntry {
+			path: PathBuf::new(),
+			size,
+			last_used,
+			pinned,
+			orphan,
+		}
+	}
+
+	#[test]
+	fn evicts_orphans_then_least_recently_used() {
+		let entries = [
+			entry(10, 1, false, true),
+			entry(10, 5, false, false),
+			entry(10, 2, false, false),
+			entry(10, 1, true, false),
+			entry(10, 9, false, false),
+		];
+
+		assert_eq!(evictions(&entries, 40), [0]);
+		assert_eq!(evictions(&entries, 25), [0, 2, 1]);
+		// Pinned entries stay even past the budget
+		assert_eq!(evictions(&entries, 0), [0, 2, 1, 4]);
+	}
+}
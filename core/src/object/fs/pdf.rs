@@ -0,0 +1,646 @@
+//! Merging PDFs into one, splitting them by page ranges and rotating their pages. The originals
+//! are left untouched, the edited documents being written as new files.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{find_location, light_scan_location, location_with_indexer_rules, LocationError},
+	prisma::{file_path, location, PrismaClient},
+	util::error::FileIOError,
+};
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::{Path, PathBuf},
+};
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+use tracing::trace;
+
+use super::{
+	convert::{resolve_conflict, resolve_target_directory},
+	error::FileSystemJobsError,
+	get_location_path_from_location_id, get_many_files_datas, ConflictPolicy, FileData,
+};
+
+/// Attributes a page may inherit from the page tree, lost when it's moved to another document
+const INHERITABLE_ATTRIBUTES: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+/// Deeper page trees are taken as malformed, as their parents may loop
+const MAX_PAGE_TREE_DEPTH: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum PdfError {
+	#[error("failed to read or write PDF: {0}")]
+	Pdf(#[from] lopdf::Error),
+	#[error("encrypted PDFs can't be edited: <path='{}'>", .0.display())]
+	Encrypted(Box<Path>),
+	#[error("PDF has no pages: <path='{}'>", .0.display())]
+	NoPages(Box<Path>),
+	#[error("page {page} is out of range, the document has {count} pages")]
+	PageOutOfRange { page: u32, count: u32 },
+	#[error("merging needs at least two PDFs")]
+	NotEnoughDocuments,
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+}
+
+/// Pages from `start` to `end`, both included and counted from 1
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+pub struct PageRange {
+	pub start: u32,
+	pub end: u32,
+}
+
+impl PageRange {
+	fn validate(self, count: u32) -> Result<Self, PdfError> {
+		for page in [self.start, self.end] {
+			if page == 0 || page > count {
+				return Err(PdfError::PageOutOfRange { page, count });
+			}
+		}
+
+		if self.start > self.end {
+			return Err(PdfError::PageOutOfRange {
+				page: self.start,
+				count,
+			});
+		}
+
+		Ok(self)
+	}
+
+	/// How the part of a split document is named after its original
+	fn file_name(self, stem: &str) -> String {
+		if self.start == self.end {
+			format!("{stem} (page {}).pdf", self.start)
+		} else {
+			format!("{stem} (pages {}-{}).pdf", self.start, self.end)
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+pub enum PageRotation {
+	Clockwise,
+	UpsideDown,
+	Counterclockwise,
+}
+
+impl PageRotation {
+	fn degrees(self) -> i64 {
+		match self {
+			Self::Clockwise => 90,
+			Self::UpsideDown => 180,
+			Self::Counterclockwise => 270,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Hash, Type)]
+#[serde(tag = "type")]
+pub enum PdfOperation {
+	/// Joins the documents in the order they're given into `name`.pdf
+	Merge {
+		file_path_ids: Vec<file_path::id::Type>,
+		name: String,
+	},
+	/// Writes a document for each range, or for each page when there are none
+	Split {
+		file_path_id: file_path::id::Type,
+		#[serde(default)]
+		ranges: Vec<PageRange>,
+	},
+	/// Rotates the given pages, or all of them when there are none
+	Rotate {
+		file_path_id: file_path::id::Type,
+		#[serde(default)]
+		pages: Vec<u32>,
+		rotation: PageRotation,
+	},
+}
+
+pub struct PdfEditorJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct PdfEditorJobInit {
+	pub location_id: location::id::Type,
+	pub operation: PdfOperation,
+	/// Where the edited documents are written, next to their originals when empty
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_id: Option<location::id::Type>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_relative_directory_path: Option<PathBuf>,
+	#[serde(default)]
+	pub conflict_policy: ConflictPolicy,
+}
+
+impl JobInitData for PdfEditorJobInit {
+	type Job = PdfEditorJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PdfEditReport {
+	written: Vec<PathBuf>,
+	/// Documents left out as something already existed at their target path
+	skipped_conflicts: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PdfEditorJobData {
+	report: PdfEditReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PdfEditorJobStep {
+	Merge {
+		sources: Vec<FileData>,
+		target: PathBuf,
+	},
+	Split {
+		source: FileData,
+		range: PageRange,
+		target: PathBuf,
+	},
+	Rotate {
+		source: FileData,
+		pages: Vec<u32>,
+		rotation: PageRotation,
+		target: PathBuf,
+	},
+	/// Adds the edited documents to the target location, which may not be watched
+	Register {
+		location_id: location::id::Type,
+		sub_path: PathBuf,
+	},
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for PdfEditorJob {
+	type Init = PdfEditorJobInit;
+	type Data = PdfEditorJobData;
+	type Step = PdfEditorJobStep;
+
+	const NAME: &'static str = "pdf_editor";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
+
+		let target = resolve_target_directory(
+			db,
+			state.init.target_location_id,
+			&state.init.target_location_relative_directory_path,
+		)
+		.await?;
+
+		let target_directory = |source: &FileData| match &target {
+			Some((_, _, target_directory)) => target_directory.clone(),
+			None => source
+				.full_path
+				.parent()
+				.map(Path::to_path_buf)
+				.unwrap_or_default(),
+		};
+
+		let file_stem = |source: &FileData| {
+			source
+				.full_path
+				.file_stem()
+				.and_then(|stem| stem.to_str())
+				.map(str::to_string)
+				.ok_or(JobError::OsStr)
+		};
+
+		state.steps = match &state.init.operation {
+			PdfOperation::Merge {
+				file_path_ids,
+				name,
+			} => {
+				if file_path_ids.len() < 2 {
+					return Err(FileSystemJobsError::from(PdfError::NotEnoughDocuments).into());
+				}
+
+				let sources = get_many_files_datas(db, &location_path, file_path_ids).await?;
+				let name = match name.trim() {
+					"" => "Merged",
+					name => name,
+				};
+				let target = target_directory(&sources[0]).join(format!("{name}.pdf"));
+
+				[PdfEditorJobStep::Merge { sources, target }].into()
+			}
+			PdfOperation::Split {
+				file_path_id,
+				ranges,
+			} => {
+				let source = get_single_file_data(db, &location_path, *file_path_id).await?;
+				let path = source.full_path.clone();
+				let count = block_in_place(|| load_document(&path))
+					.map_err(FileSystemJobsError::from)?
+					.get_pages()
+					.len() as u32;
+
+				let ranges = if ranges.is_empty() {
+					(1..=count)
+						.map(|page| PageRange {
+							start: page,
+							end: page,
+						})
+						.collect()
+				} else {
+					ranges
+						.iter()
+						.map(|range| range.validate(count))
+						.collect::<Result<Vec<_>, _>>()
+						.map_err(FileSystemJobsError::from)?
+				};
+
+				let stem = file_stem(&source)?;
+				let directory = target_directory(&source);
+
+				ranges
+					.into_iter()
+					.map(|range| PdfEditorJobStep::Split {
+						source: source.clone(),
+						range,
+						target: directory.join(range.file_name(&stem)),
+					})
+					.collect()
+			}
+			PdfOperation::Rotate {
+				file_path_id,
+				pages,
+				rotation,
+			} => {
+				let source = get_single_file_data(db, &location_path, *file_path_id).await?;
+				let target = target_directory(&source)
+					.join(format!("{} (rotated).pdf", file_stem(&source)?));
+
+				[PdfEditorJobStep::Rotate {
+					source,
+					pages: pages.clone(),
+					rotation: *rotation,
+					target,
+				}]
+				.into()
+			}
+		};
+
+		// Documents written next to their originals are seen by the watcher
+		if let Some((location_id, sub_path, _)) = target {
+			state.steps.push_back(PdfEditorJobStep::Register {
+				location_id,
+				sub_path,
+			});
+		}
+
+		state.data = Some(PdfEditorJobData {
+			report: PdfEditReport::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let conflict_policy = state.init.conflict_policy;
+
+		let (source, document, target) = match &state.steps[0] {
+			PdfEditorJobStep::Merge { sources, target } => {
+				ctx.progress(vec![JobReportUpdate::Message(format!(
+					"Merging {} documents",
+					sources.len()
+				))]);
+
+				let document = block_in_place(|| {
+					merge_documents(
+						sources
+							.iter()
+							.map(|source| load_document(&source.full_path))
+							.collect::<Result<_, _>>()?,
+					)
+				})
+				.map_err(FileSystemJobsError::from)?;
+
+				(&sources[0].full_path, document, target)
+			}
+
+			PdfEditorJobStep::Split {
+				source,
+				range,
+				target,
+			} => {
+				ctx.progress(vec![JobReportUpdate::Message(format!(
+					"Splitting {}",
+					source.full_path.display()
+				))]);
+
+				let document = block_in_place(|| {
+					let mut document = load_document(&source.full_path)?;
+					let count = document.get_pages().len() as u32;
+					let range = range.validate(count)?;
+
+					document.delete_pages(
+						&(1..=count)
+							.filter(|page| *page < range.start || *page > range.end)
+							.collect::<Vec<_>>(),
+					);
+					document.prune_objects();
+
+					Ok::<_, PdfError>(document)
+				})
+				.map_err(FileSystemJobsError::from)?;
+
+				(&source.full_path, document, target)
+			}
+
+			PdfEditorJobStep::Rotate {
+				source,
+				pages,
+				rotation,
+				target,
+			} => {
+				ctx.progress(vec![JobReportUpdate::Message(format!(
+					"Rotating {}",
+					source.full_path.display()
+				))]);
+
+				let document = block_in_place(|| {
+					let mut document = load_document(&source.full_path)?;
+					rotate_pages(&mut document, pages, *rotation)?;
+
+					Ok::<_, PdfError>(document)
+				})
+				.map_err(FileSystemJobsError::from)?;
+
+				(&source.full_path, document, target)
+			}
+
+			PdfEditorJobStep::Register {
+				location_id,
+				sub_path,
+			} => {
+				let location = find_location(&ctx.library, *location_id)
+					.include(location_with_indexer_rules::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(*location_id))?;
+
+				light_scan_location(ctx.library.clone(), location, sub_path).await?;
+
+				ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+					state.step_number + 1,
+				)]);
+
+				return Ok(());
+			}
+		};
+
+		let source = source.clone();
+		let target = target.clone();
+		let data = extract_job_data_mut!(state);
+
+		match resolve_conflict(&source, target.clone(), conflict_policy).await? {
+			Some(target) => {
+				save_document(document, &target)
+					.await
+					.map_err(FileSystemJobsError::from)?;
+				trace!("Wrote {}", target.display());
+				data.report.written.push(target);
+			}
+			None => {
+				trace!("Skipping {} as it exists", target.display());
+				data.report.skipped_conflicts += 1;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::json!({
+			"init": state.init,
+			"report": extract_job_data!(state).report,
+		})))
+	}
+}
+
+async fn get_single_file_data(
+	db: &PrismaClient,
+	location_path: &Path,
+	file_path_id: file_path::id::Type,
+) -> Result<FileData, FileSystemJobsError> {
+	Ok(get_many_files_datas(db, location_path, &[file_path_id])
+		.await?
+		.remove(0))
+}
+
+fn load_document(path: &Path) -> Result<Document, PdfError> {
+	let document = Document::load(path)?;
+
+	if document.is_encrypted() {
+		return Err(PdfError::Encrypted(path.to_path_buf().into_boxed_path()));
+	}
+
+	if document.get_pages().is_empty() {
+		return Err(PdfError::NoPages(path.to_path_buf().into_boxed_path()));
+	}
+
+	Ok(document)
+}
+
+async fn save_document(mut document: Document, target: &Path) -> Result<(), PdfError> {
+	let mut bytes = vec![];
+	block_in_place(|| {
+		document.compress();
+		document.save_to(&mut bytes)
+	})
+	.map_err(|e| FileIOError::from((target, e)))?;
+
+	fs::write(target, bytes)
+		.await
+		.map_err(|e| FileIOError::from((target, e)).into())
+}
+
+/// Looks an attribute up on a page, then on its parents in the page tree
+fn inherited_attribute<'a>(
+	document: &'a Document,
+	page: &'a Dictionary,
+	key: &[u8],
+) -> Option<&'a Object> {
+	let mut node = page;
+
+	for _ in 0..MAX_PAGE_TREE_DEPTH {
+		if let Ok(value) = node.get(key) {
+			return Some(value);
+		}
+
+		node = document
+			.get_dictionary(node.get(b"Parent").ok()?.as_reference().ok()?)
+			.ok()?;
+	}
+
+	None
+}
+
+fn rotate_pages(
+	document: &mut Document,
+	pages: &[u32],
+	rotation: PageRotation,
+) -> Result<(), PdfError> {
+	let all_pages = document.get_pages();
+	let count = all_pages.len() as u32;
+
+	let page_ids = if pages.is_empty() {
+		all_pages.into_values().collect::<Vec<_>>()
+	} else {
+		pages
+			.iter()
+			.collect::<BTreeSet<_>>()
+			.into_iter()
+			.map(|page| {
+				all_pages
+					.get(page)
+					.copied()
+					.ok_or(PdfError::PageOutOfRange { page: *page, count })
+			})
+			.collect::<Result<_, _>>()?
+	};
+
+	for page_id in page_ids {
+		let current = inherited_attribute(document, document.get_dictionary(page_id)?, b"Rotate")
+			.and_then(|rotate| rotate.as_i64().ok())
+			.unwrap_or(0);
+
+		document
+			.get_object_mut(page_id)?
+			.as_dict_mut()?
+			.set("Rotate", (current + rotation.degrees()).rem_euclid(360));
+	}
+
+	Ok(())
+}
+
+/// Joins documents into a new one, moving the pages of all of them under the page tree of the
+/// first. Outlines are left out, as they point at the pages of the original documents.
+fn merge_documents(documents: Vec<Document>) -> Result<Document, PdfError> {
+	let mut merged = Document::with_version("1.5");
+	let mut pages = Vec::<(ObjectId, Dictionary)>::new();
+	let mut objects = BTreeMap::new();
+	let mut max_id = 1;
+
+	for mut document in documents {
+		document.renumber_objects_with(max_id);
+		max_id = document.max_id + 1;
+
+		for page_id in document.get_pages().into_values() {
+			let mut page = document.get_dictionary(page_id)?.clone();
+
+			for key in INHERITABLE_ATTRIBUTES {
+				if !page.has(key) {
+					if let Some(value) = inherited_attribute(&document, &page, key).cloned() {
+						page.set(key, value);
+					}
+				}
+			}
+
+			pages.push((page_id, page));
+		}
+
+		objects.extend(document.objects);
+	}
+
+	let mut catalog = None;
+	let mut page_tree = None;
+
+	for (object_id, object) in objects {
+		match object.type_name().unwrap_or_default() {
+			b"Catalog" => {
+				if catalog.is_none() {
+					catalog = Some((object_id, object.as_dict()?.clone()));
+				}
+			}
+			b"Pages" => {
+				if page_tree.is_none() {
+					page_tree = Some((object_id, object.as_dict()?.clone()));
+				}
+			}
+			b"Page" | b"Outlines" | b"Outline" => {}
+			_ => {
+				merged.objects.insert(object_id, object);
+			}
+		}
+	}
+
+	let (Some((catalog_id, mut catalog)), Some((page_tree_id, mut page_tree))) =
+		(catalog, page_tree)
+	else {
+		return Err(lopdf::Error::ObjectNotFound.into());
+	};
+
+	page_tree.set("Count", pages.len() as i64);
+	page_tree.set(
+		"Kids",
+		pages
+			.iter()
+			.map(|(page_id, _)| Object::Reference(*page_id))
+			.collect::<Vec<_>>(),
+	);
+	for key in INHERITABLE_ATTRIBUTES {
+		page_tree.remove(key);
+	}
+	page_tree.remove(b"Parent");
+
+	for (page_id, mut page) in pages {
+		page.set("Parent", page_tree_id);
+		merged.objects.insert(page_id, Object::Dictionary(page));
+	}
+
+	catalog.set("Pages", page_tree_id);
+	catalog.remove(b"Outlines");
+
+	merged
+		.objects
+		.insert(page_tree_id, Object::Dictionary(page_tree));
+	merged
+		.objects
+		.insert(catalog_id, Object::Dictionary(catalog));
+	merged.trailer.set("Root", catalog_id);
+	merged.max_id = max_id;
+	merged.renumber_objects();
+
+	Ok(merged)
+}
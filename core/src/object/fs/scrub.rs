@@ -0,0 +1,571 @@
+//! Scrubbing what a photo tells about who took it and where out of the copies leaving a library, on
+//! export, Spacedrop and copy. Originals are never touched, only the copies made of them.
+//!
+//! Each kind of metadata is asked for on its own: the GPS position, the serial numbers of the
+//! camera and lens along with the name of their owner, and the editing history XMP keeps of the
+//! documents a photo was made from. Everything is scrubbed in place, EXIF values zeroed and XMP
+//! blanked with spaces, so the rest of the file stays as it was. JPEG, PNG, WebP and TIFF based
+//! photos, raw ones included, can be scrubbed, other files are left as they are and counted as
+//! such in the reports.
+
+use crate::{
+	library::Library,
+	location::privacy::LocationPrivacyError,
+	prisma::{file_path, location},
+	util::error::FileIOError,
+};
+
+use std::{ops::Range, path::Path};
+
+use flate2::Crc;
+use img_parts::{DynImage, ImageEXIF};
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+
+use super::{error::FileSystemJobsError, get_location_path_from_location_id, get_many_files_datas};
+
+const GPS_IFD_TAG: u16 = 0x8825;
+const EXIF_IFD_TAG: u16 = 0x8769;
+/// CameraOwnerName, BodySerialNumber, LensSerialNumber, the CameraSerialNumber of DNGs and the
+/// MakerNote, where most cameras keep their serial number
+const SERIAL_NUMBER_TAGS: [u16; 5] = [0xA430, 0xA431, 0xA435, 0xC62F, 0x927C];
+
+/// The properties of XMP telling what a photo was made from and how
+const XMP_HISTORY_PROPERTIES: [&str; 6] = [
+	"xmpMM:History",
+	"xmpMM:DerivedFrom",
+	"xmpMM:Ingredients",
+	"xmpMM:Pantry",
+	"xmpMM:OriginalDocumentID",
+	"photoshop:DocumentAncestors",
+];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Error, Debug)]
+pub enum MetadataScrubError {
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	FileSystem(#[from] FileSystemJobsError),
+	#[error("failed to read the metadata: {0}")]
+	Image(#[from] img_parts::Error),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+}
+
+impl From<MetadataScrubError> for rspc::Error {
+	fn from(err: MetadataScrubError) -> Self {
+		match err {
+			MetadataScrubError::Privacy(e) => e.into(),
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq, Type)]
+pub enum MetadataField {
+	Gps,
+	/// The serial numbers of the camera and lens, their owner and the maker notes
+	SerialNumbers,
+	XmpHistory,
+}
+
+/// How many files had each kind of metadata scrubbed
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Type)]
+pub struct ScrubReport {
+	pub gps: u32,
+	pub serial_numbers: u32,
+	pub xmp_history: u32,
+	/// Files of formats which can't be scrubbed, left as they are
+	pub unsupported: u32,
+}
+
+impl ScrubReport {
+	/// Counts a file, `None` being one which couldn't be scrubbed
+	pub fn record(&mut self, removed: Option<&[MetadataField]>) {
+		let Some(removed) = removed else {
+			self.unsupported += 1;
+			return;
+		};
+
+		for field in removed {
+			match field {
+				MetadataField::Gps => self.gps += 1,
+				MetadataField::SerialNumbers => self.serial_numbers += 1,
+				MetadataField::XmpHistory => self.xmp_history += 1,
+			}
+		}
+	}
+}
+
+/// An entry of an IFD and where its value is, out of line or in the entry itself
+struct IfdEntry {
+	offset: usize,
+	tag: u16,
+	value: Range<usize>,
+}
+
+/// EXIF data, or a whole TIFF file, walked without being parsed so it can be patched in place
+struct Tiff<'a> {
+	bytes: &'a mut [u8],
+	little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+	fn new(bytes: &'a mut [u8]) -> Option<Self> {
+		let little_endian = match bytes.get(..4) {
+			Some(b"II*\0") => true,
+			Some(b"MM\0*") => false,
+			_ => return None,
+		};
+
+		Some(Self {
+			bytes,
+			little_endian,
+		})
+	}
+
+	fn read_u16(&self, offset: usize) -> Option<u16> {
+		let bytes = self.bytes.get(offset..offset.checked_add(2)?)?;
+		let bytes = [bytes[0], bytes[1]];
+
+		Some(if self.little_endian {
+			u16::from_le_bytes(bytes)
+		} else {
+			u16::from_be_bytes(bytes)
+		})
+	}
+
+	fn read_u32(&self, offset: usize) -> Option<u32> {
+		let bytes = self.bytes.get(offset..offset.checked_add(4)?)?;
+		let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+
+		Some(if self.little_endian {
+			u32::from_le_bytes(bytes)
+		} else {
+			u32::from_be_bytes(bytes)
+		})
+	}
+
+	fn first_ifd(&self) -> Option<usize> {
+		self.read_u32(4).map(|offset| offset as usize)
+	}
+
+	/// The entries of an IFD, up to the first one which doesn't make sense
+	fn entries(&self, ifd: usize) -> Vec<IfdEntry> {
+		let Some(count) = self.read_u16(ifd) else {
+			return vec![];
+		};
+
+		(0..count as usize)
+			.map_while(|entry| {
+				let offset = ifd + 2 + entry * 12;
+				let tag = self.read_u16(offset)?;
+				let size = value_size(self.read_u16(offset + 2)?)?
+					.checked_mul(self.read_u32(offset + 4)? as usize)?;
+
+				let start = if size <= 4 {
+					offset + 8
+				} else {
+					self.read_u32(offset + 8)? as usize
+				};
+				let value = start..start.checked_add(size)?;
+
+				(value.end <= self.bytes.len()).then_some(IfdEntry { offset, tag, value })
+			})
+			.collect()
+	}
+
+	/// Where the IFD an entry of `entries` points to is
+	fn sub_ifd(&self, entries: &[IfdEntry], tag: u16) -> Option<usize> {
+		let entry = entries.iter().find(|entry| entry.tag == tag)?;
+		self.read_u32(entry.value.start)
+			.map(|offset| offset as usize)
+	}
+
+	/// Zeroes some bytes, returning whether there was anything other than zeroes
+	fn clear(&mut self, range: Range<usize>) -> bool {
+		let Some(bytes) = self.bytes.get_mut(range) else {
+			return false;
+		};
+
+		let cleared = bytes.iter().any(|byte| *byte != 0);
+		bytes.fill(0);
+
+		cleared
+	}
+
+	/// Empties the GPS IFD, leaving the pointer to it for readers to find an IFD without entries
+	fn scrub_gps(&mut self) -> bool {
+		let Some(ifd0) = self.first_ifd() else {
+			return false;
+		};
+		let Some(gps_ifd) = self.sub_ifd(&self.entries(ifd0), GPS_IFD_TAG) else {
+			return false;
+		};
+
+		let entries = self.entries(gps_ifd);
+		if entries.is_empty() {
+			return false;
+		}
+
+		for entry in &entries {
+			self.clear(entry.value.clone());
+		}
+
+		// Zeroing the count makes the next IFD offset be read from the zeroed first entry
+		let last_entry_end = entries[entries.len() - 1].offset + 12;
+		self.clear(gps_ifd..last_entry_end);
+
+		true
+	}
+
+	fn scrub_serial_numbers(&mut self) -> bool {
+		let Some(ifd0) = self.first_ifd() else {
+			return false;
+		};
+
+		let ifd0_entries = self.entries(ifd0);
+		let exif_ifd = self.sub_ifd(&ifd0_entries, EXIF_IFD_TAG);
+
+		let mut scrubbed = false;
+		for entries in [Some(ifd0_entries), exif_ifd.map(|ifd| self.entries(ifd))]
+			.into_iter()
+			.flatten()
+		{
+			for entry in entries {
+				if SERIAL_NUMBER_TAGS.contains(&entry.tag) {
+					scrubbed |= self.clear(entry.value);
+				}
+			}
+		}
+
+		scrubbed
+	}
+}
+
+/// The size of a value of each TIFF type, `None` for the unknown ones
+fn value_size(value_type: u16) -> Option<usize> {
+	match value_type {
+		1 | 2 | 6 | 7 => Some(1),
+		3 | 8 => Some(2),
+		4 | 9 | 11 => Some(4),
+		5 | 10 | 12 => Some(8),
+		_ => None,
+	}
+}
+
+/// Scrubs the GPS position and serial numbers asked for out of some EXIF data, in place
+pub fn scrub_exif(exif: &mut [u8], fields: &[MetadataField]) -> Vec<MetadataField> {
+	let Some(mut tiff) = Tiff::new(exif) else {
+		return vec![];
+	};
+
+	let mut removed = vec![];
+	if fields.contains(&MetadataField::Gps) && tiff.scrub_gps() {
+		removed.push(MetadataField::Gps);
+	}
+	if fields.contains(&MetadataField::SerialNumbers) && tiff.scrub_serial_numbers() {
+		removed.push(MetadataField::SerialNumbers);
+	}
+
+	removed
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+	haystack
+		.get(from..)?
+		.windows(needle.len())
+		.position(|window| window == needle)
+		.map(|position| from + position)
+}
+
+/// Where a property is in an XMP packet, as an element with everything inside of it or as an
+/// attribute
+fn xmp_property_range(packet: &[u8], name: &str) -> Option<Range<usize>> {
+	let open = format!("<{name}");
+	let mut from = 0;
+	while let Some(start) = find(packet, open.as_bytes(), from) {
+		from = start + open.len();
+
+		// Not another property starting with the same name
+		if !matches!(
+			packet.get(from),
+			Some(b' ' | b'\t' | b'\r' | b'\n' | b'>' | b'/')
+		) {
+			continue;
+		}
+
+		let tag_end = find(packet, b">", from)? + 1;
+		if packet[tag_end - 2] == b'/' {
+			return Some(start..tag_end);
+		}
+
+		let close = format!("</{name}>");
+		return find(packet, close.as_bytes(), tag_end).map(|end| start..end + close.len());
+	}
+
+	for quote in [b'"', b'\''] {
+		let attribute = [name.as_bytes(), b"=", &[quote]].concat();
+		let mut from = 0;
+		while let Some(start) = find(packet, &attribute, from) {
+			from = start + attribute.len();
+
+			if !matches!(
+				start.checked_sub(1).and_then(|before| packet.get(before)),
+				Some(b' ' | b'\t' | b'\r' | b'\n')
+			) {
+				continue;
+			}
+
+			let end = find(packet, &[quote], from)? + 1;
+			return Some(start..end);
+		}
+	}
+
+	None
+}
+
+/// Blanks the editing history of the XMP packets of a file with spaces, keeping its length
+fn scrub_xmp_history(bytes: &mut [u8]) -> bool {
+	let mut scrubbed = false;
+
+	let mut from = 0;
+	while let Some(start) = find(bytes, b"<x:xmpmeta", from) {
+		let Some(end) = find(bytes, b"</x:xmpmeta>", start) else {
+			break;
+		};
+
+		let packet = &mut bytes[start..end];
+		for name in XMP_HISTORY_PROPERTIES {
+			while let Some(range) = xmp_property_range(packet, name) {
+				packet[range].fill(b' ');
+				scrubbed = true;
+			}
+		}
+
+		from = end;
+	}
+
+	scrubbed
+}
+
+/// Recomputes the checksums of the text chunks of a PNG, after their contents changed in place
+fn fix_png_text_checksums(bytes: &mut [u8]) {
+	let mut offset = PNG_SIGNATURE.len();
+	while let Some(length) = bytes.get(offset..offset + 4) {
+		let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+		let Some(data_end) = (offset + 8).checked_add(length) else {
+			return;
+		};
+		if data_end + 4 > bytes.len() {
+			return;
+		}
+
+		if bytes[offset + 4..offset + 8] == *b"iTXt" {
+			let mut crc = Crc::new();
+			crc.update(&bytes[offset + 4..data_end]);
+			bytes[data_end..data_end + 4].copy_from_slice(&crc.sum().to_be_bytes());
+		}
+
+		offset = data_end + 4;
+	}
+}
+
+/// Scrubs the metadata asked for out of the bytes of a file, returning what was there and removed,
+/// or `None` when its format can't be scrubbed
+pub fn scrub_bytes(
+	mut bytes: Vec<u8>,
+	fields: &[MetadataField],
+) -> Result<Option<(Vec<u8>, Vec<MetadataField>)>, MetadataScrubError> {
+	let mut removed = if Tiff::new(&mut bytes).is_some() {
+		scrub_exif(&mut bytes, fields)
+	} else {
+		let Some(mut image) = DynImage::from_bytes(bytes.clone().into())? else {
+			return Ok(None);
+		};
+
+		match image.exif() {
+			Some(exif) => {
+				let mut exif = exif.to_vec();
+				let removed = scrub_exif(&mut exif, fields);
+
+				if !removed.is_empty() {
+					image.set_exif(Some(exif.into()));
+					bytes = image.encoder().bytes().to_vec();
+				}
+
+				removed
+			}
+			None => vec![],
+		}
+	};
+
+	if fields.contains(&MetadataField::XmpHistory) && scrub_xmp_history(&mut bytes) {
+		if bytes.starts_with(PNG_SIGNATURE) {
+			fix_png_text_checksums(&mut bytes);
+		}
+
+		removed.push(MetadataField::XmpHistory);
+	}
+
+	Ok(Some((bytes, removed)))
+}
+
+/// Scrubs a copy in place, returning what was removed or `None` when it can't be scrubbed. Never
+/// call this on an original.
+pub async fn scrub_file(
+	path: impl AsRef<Path>,
+	fields: &[MetadataField],
+) -> Result<Option<Vec<MetadataField>>, MetadataScrubError> {
+	let path = path.as_ref();
+
+	// Writing through a link would change what it points to
+	if !fs::symlink_metadata(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?
+		.is_file()
+	{
+		return Ok(None);
+	}
+
+	let bytes = fs::read(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	let Some((bytes, removed)) = block_in_place(|| scrub_bytes(bytes, fields))? else {
+		return Ok(None);
+	};
+
+	if !removed.is_empty() {
+		fs::write(path, bytes)
+			.await
+			.map_err(|e| FileIOError::from((path, e)))?;
+	}
+
+	Ok(Some(removed))
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ScrubPreview {
+	pub file_path_id: file_path::id::Type,
+	/// What would be removed from copies of the file, `None` when it can't be scrubbed
+	pub removed: Option<Vec<MetadataField>>,
+}
+
+/// What scrubbing copies of files would remove, without copying nor scrubbing anything, to be
+/// shown before sharing them
+pub async fn preview_scrub(
+	library: &Library,
+	location_id: location::id::Type,
+	file_path_ids: &[file_path::id::Type],
+	fields: &[MetadataField],
+) -> Result<Vec<ScrubPreview>, MetadataScrubError> {
+	library
+		.private_locations
+		.ensure_unlocked(location_id)
+		.await?;
+
+	let location_path = get_location_path_from_location_id(&library.db, location_id).await?;
+
+	let mut previews = vec![];
+	for file_data in get_many_files_datas(&library.db, location_path, file_path_ids).await? {
+		let removed = if file_data.file_path.is_dir == Some(true) {
+			None
+		} else {
+			let bytes = fs::read(&file_data.full_path)
+				.await
+				.map_err(|e| FileIOError::from((&file_data.full_path, e)))?;
+
+			block_in_place(|| scrub_bytes(bytes, fields))?.map(|(_, removed)| removed)
+		};
+
+		previews.push(ScrubPreview {
+			file_path_id: file_data.file_path.id,
+			removed,
+		});
+	}
+
+	Ok(previews)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A little endian TIFF with a BodySerialNumber and a GPS IFD holding a latitude
+	fn exif() -> Vec<u8> {
+		let mut exif = b"II*\0".to_vec();
+		exif.extend(8u32.to_le_bytes());
+
+		// IFD0 at 8: GPS pointer, BodySerialNumber "SN123456\0" at 38
+		exif.extend(2u16.to_le_bytes());
+		exif.extend([0x25, 0x88, 4, 0, 1, 0, 0, 0]);
+		exif.extend(47u32.to_le_bytes());
+		exif.extend([0x31, 0xA4, 2, 0, 9, 0, 0, 0]);
+		exif.extend(38u32.to_le_bytes());
+		exif.extend(0u32.to_le_bytes());
+		exif.extend(b"SN123456\0");
+
+		// GPS IFD at 47: GPSLatitude, three RATIONALs at 65
+		exif.extend(1u16.to_le_bytes());
+		exif.extend([0x02, 0x00, 5, 0, 3, 0, 0, 0]);
+		exif.extend(65u32.to_le_bytes());
+		exif.extend(0u32.to_le_bytes());
+		for value in [48u32, 1, 51, 1, 30, 1] {
+			exif.extend(value.to_le_bytes());
+		}
+
+		exif
+	}
+
+	#[test]
+	fn only_the_fields_asked_for_are_scrubbed() {
+		let mut scrubbed = exif();
+		assert_eq!(
+			scrub_exif(&mut scrubbed, &[MetadataField::Gps]),
+			vec![MetadataField::Gps]
+		);
+		assert_eq!(&scrubbed[38..46], b"SN123456");
+		assert!(scrubbed[47..].iter().all(|byte| *byte == 0));
+
+		assert_eq!(
+			scrub_exif(&mut scrubbed, &[MetadataField::SerialNumbers]),
+			vec![MetadataField::SerialNumbers]
+		);
+		assert!(scrubbed[38..47].iter().all(|byte| *byte == 0));
+		// The structure of IFD0 is left as it was
+		assert_eq!(scrubbed[..38], exif()[..38]);
+
+		// Nothing left to remove
+		assert!(scrub_exif(
+			&mut scrubbed,
+			&[MetadataField::Gps, MetadataField::SerialNumbers]
+		)
+		.is_empty());
+	}
+
+	#[test]
+	fn xmp_history_is_blanked() {
+		let packet = concat!(
+			r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description "#,
+			r#"xmpMM:OriginalDocumentID="xmp.did:1" xmp:Rating="3">"#,
+			r#"<xmpMM:History><rdf:Seq><rdf:li stEvt:action="saved"/></rdf:Seq></xmpMM:History>"#,
+			r#"<xmpMM:DerivedFrom stRef:documentID="xmp.did:0"/>"#,
+			r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+		);
+
+		let mut bytes = packet.as_bytes().to_vec();
+		assert!(scrub_xmp_history(&mut bytes));
+		assert_eq!(bytes.len(), packet.len());
+
+		let scrubbed = String::from_utf8(bytes).unwrap();
+		assert!(scrubbed.contains(r#"xmp:Rating="3""#));
+		assert!(!scrubbed.contains("xmpMM"));
+		assert!(scrubbed.ends_with("</rdf:Description></rdf:RDF></x:xmpmeta>"));
+	}
+}
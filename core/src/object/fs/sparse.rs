@@ -0,0 +1,160 @@
+//! Sparse files (like VM images or torrents still downloading) have holes that take no space on
+//! disk. A regular copy reads those holes as zeros and writes them out, so the copy can be much
+//! bigger on disk than the original. Here we skip the zeroed blocks instead, leaving holes behind.
+
+#[cfg(not(target_os = "windows"))]
+use crate::location::file_path_helper::get_allocated_size;
+
+use std::{fs::Metadata, io::SeekFrom, path::Path};
+
+use tokio::{
+	fs,
+	io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::trace;
+
+const SPARSE_COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copies a file like [`tokio::fs::copy`], but keeps the holes of sparse files as holes
+pub async fn copy_file(source: impl AsRef<Path>, target: impl AsRef<Path>) -> io::Result<u64> {
+	let (source, target) = (source.as_ref(), target.as_ref());
+
+	let metadata = fs::metadata(source).await?;
+
+	if !is_sparse(source, &metadata) {
+		return fs::copy(source, target).await;
+	}
+
+	trace!(
+		"Copying sparse file {} to {}",
+		source.display(),
+		target.display()
+	);
+
+	let mut source_file = fs::File::open(source).await?;
+	let mut target_file = fs::OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(target)
+		.await?;
+
+	#[cfg(target_os = "windows")]
+	if let Err(e) = mark_sparse(&target_file) {
+		// The filesystem doesn't support sparse files, the holes will be filled with zeros
+		tracing::warn!("Failed to mark {} as a sparse file: {e}", target.display());
+	}
+
+	let mut buffer = vec![0; SPARSE_COPY_BUFFER_SIZE];
+	loop {
+		let read = source_file.read(&mut buffer).await?;
+		if read == 0 {
+			break;
+		}
+
+		if buffer[..read].iter().all(|byte| *byte == 0) {
+			target_file.seek(SeekFrom::Current(read as i64)).await?;
+		} else {
+			target_file.write_all(&buffer[..read]).await?;
+		}
+	}
+
+	// Seeking past the end doesn't grow the file, so trailing holes only exist after this.
+	// It's also the last step, so a target with the source length is always a complete copy.
+	target_file.flush().await?;
+	target_file.set_len(metadata.len()).await?;
+
+	fs::set_permissions(target, metadata.permissions()).await?;
+
+	Ok(metadata.len())
+}
+
+/// Tells if a file has holes, comparing the space it takes on disk with its length
+pub fn is_sparse(path: impl AsRef<Path>, metadata: &Metadata) -> bool {
+	#[cfg(target_os = "windows")]
+	{
+		use std::os::windows::fs::MetadataExt;
+
+		use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_SPARSE_FILE;
+
+		// Compressed files also take less space than their length, so we rely on the attribute
+		let _ = path;
+		metadata.is_file() && metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0
+	}
+
+	#[cfg(not(target_os = "windows"))]
+	{
+		metadata.is_file() && get_allocated_size(path, metadata) < metadata.len()
+	}
+}
+
+#[cfg(target_os = "windows")]
+fn mark_sparse(file: &fs::File) -> io::Result<()> {
+	use std::{os::windows::io::AsRawHandle, ptr};
+
+	use windows_sys::Win32::System::{Ioctl::FSCTL_SET_SPARSE, IO::DeviceIoControl};
+
+	let mut bytes_returned = 0;
+
+	// SAFETY: the handle is valid while `file` lives, and this control code takes no buffers
+	let succeeded = unsafe {
+		DeviceIoControl(
+			file.as_raw_handle() as _,
+			FSCTL_SET_SPARSE,
+			ptr::null(),
+			0,
+			ptr::null_mut(),
+			0,
+			&mut bytes_returned,
+			ptr::null_mut(),
+		)
+	};
+
+	if succeeded == 0 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+	use super::*;
+
+	use tempfile::tempdir;
+
+	#[tokio::test]
+	async fn copy_keeps_holes() {
+		let dir = tempdir().unwrap();
+		let source = dir.path().join("sparse.img");
+		let target = dir.path().join("copy.img");
+
+		let len = 64 * 1024 * 1024;
+		{
+			let mut file = fs::File::create(&source).await.unwrap();
+			file.write_all(b"header").await.unwrap();
+			file.set_len(len).await.unwrap();
+		}
+
+		let source_metadata = fs::metadata(&source).await.unwrap();
+		if !is_sparse(&source, &source_metadata) {
+			// The filesystem running the tests doesn't support sparse files
+			return;
+		}
+
+		assert_eq!(copy_file(&source, &target).await.unwrap(), len);
+
+		let target_metadata = fs::metadata(&target).await.unwrap();
+		assert_eq!(target_metadata.len(), len);
+		assert!(get_allocated_size(&target, &target_metadata) < len);
+
+		let mut header = [0; 6];
+		fs::File::open(&target)
+			.await
+			.unwrap()
+			.read_exact(&mut header)
+			.await
+			.unwrap();
+		assert_eq!(&header, b"header");
+	}
+}
@@ -0,0 +1,489 @@
+//! Templates new files and folders are created from, like a blank document or the skeleton of a
+//! project, so "New > X" in the explorer makes something already structured. A template is a copy of
+//! a file or a whole folder kept in the data directory, made from a file of a location or blank.
+//!
+//! Names are given `{variables}` when created: `{date}`, `{time}`, `{year}`, `{month}` and `{day}`
+//! are always there, and the ones given when creating from the template come on top of them. They
+//! are substituted in the name of what's created and in the names of everything inside of it.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	location::{
+		file_path_helper::{
+			ensure_sub_path_is_directory, ensure_sub_path_is_in_location, IsolatedFilePathData,
+		},
+		find_location, light_scan_location, location_with_indexer_rules,
+		privacy::LocationPrivacyError,
+		LocationError,
+	},
+	object::special::copy_link,
+	prisma::{file_path, file_template, location, PrismaClient},
+	util::{db::maybe_missing, error::FileIOError},
+};
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::fs;
+use tracing::error;
+
+use super::{
+	copy_tree_staged, error::FileSystemJobsError, extract::available_path,
+	get_location_path_from_location_id, get_many_files_datas, ignore_events_for, staging_path,
+};
+
+const TEMPLATE_STORE_DIR: &str = "templates";
+
+#[derive(Error, Debug)]
+pub enum FileTemplateError {
+	#[error("file template not found <id='{0}'>")]
+	NotFound(file_template::id::Type),
+	#[error("a file template needs a name")]
+	MissingName,
+	#[error("invalid file name: {0}")]
+	InvalidFileName(String),
+	#[error("variables can't hold path separators: {0}")]
+	InvalidVariable(String),
+	#[error("location not found <id='{0}'>")]
+	LocationNotFound(location::id::Type),
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	Location(#[from] LocationError),
+	#[error(transparent)]
+	FileSystem(#[from] FileSystemJobsError),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<FileTemplateError> for rspc::Error {
+	fn from(err: FileTemplateError) -> Self {
+		match err {
+			FileTemplateError::Privacy(e) => e.into(),
+			FileTemplateError::Location(e) => e.into(),
+			_ => {
+				let code = match err {
+					FileTemplateError::NotFound(_)
+					| FileTemplateError::LocationNotFound(_)
+					| FileTemplateError::FileSystem(FileSystemJobsError::FilePathIdNotFound(_)) => {
+						ErrorCode::NotFound
+					}
+					FileTemplateError::MissingName
+					| FileTemplateError::InvalidFileName(_)
+					| FileTemplateError::InvalidVariable(_) => ErrorCode::BadRequest,
+					_ => ErrorCode::InternalServerError,
+				};
+
+				rspc::Error::with_cause(code, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// Where the content of a template is kept, a file or a directory named after its id
+fn template_path(library: &Library, id: file_template::id::Type) -> PathBuf {
+	library
+		.config()
+		.data_directory()
+		.join(TEMPLATE_STORE_DIR)
+		.join(library.id.to_string())
+		.join(id.to_string())
+}
+
+/// Replaces the `{variables}` of a name, leaving the ones it doesn't know as they are
+fn substitute(name: &str, variables: &HashMap<String, String>, now: DateTime<Local>) -> String {
+	let mut substituted = String::with_capacity(name.len());
+	let mut rest = name;
+
+	while let Some(start) = rest.find('{') {
+		let Some(len) = rest[start..].find('}') else {
+			break;
+		};
+
+		let variable = &rest[start + 1..start + len];
+		let value = variables.get(variable).cloned().or_else(|| {
+			let format = match variable {
+				"date" => "%Y-%m-%d",
+				// Colons can't be in file names on Windows
+				"time" => "%H-%M-%S",
+				"year" => "%Y",
+				"month" => "%m",
+				"day" => "%d",
+				_ => return None,
+			};
+
+			Some(now.format(format).to_string())
+		});
+
+		substituted.push_str(&rest[..start]);
+		match value {
+			Some(value) => substituted.push_str(&value),
+			None => substituted.push_str(&rest[start..=start + len]),
+		}
+		rest = &rest[start + len + 1..];
+	}
+
+	substituted.push_str(rest);
+	substituted
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct FileTemplate {
+	pub id: file_template::id::Type,
+	pub name: String,
+	/// The name of what's created, before its variables are substituted
+	pub file_name: String,
+	pub is_dir: bool,
+}
+
+impl From<file_template::Data> for FileTemplate {
+	fn from(data: file_template::Data) -> Self {
+		Self {
+			id: data.id,
+			name: data.name,
+			file_name: data.file_name,
+			is_dir: data.is_dir,
+		}
+	}
+}
+
+pub async fn list_file_templates(db: &PrismaClient) -> Result<Vec<FileTemplate>, QueryError> {
+	Ok(db
+		.file_template()
+		.find_many(vec![])
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+#[derive(Type, Deserialize)]
+pub enum FileTemplateSource {
+	/// An empty file or folder
+	Blank { is_dir: bool },
+	/// A copy of a file or a folder of a location, as it is now
+	FilePath {
+		location_id: location::id::Type,
+		file_path_id: file_path::id::Type,
+	},
+}
+
+#[derive(Type, Deserialize)]
+pub struct FileTemplateCreateArgs {
+	pub name: String,
+	/// Needed for blank templates, the name of the file or folder it's made from otherwise
+	#[specta(optional)]
+	pub file_name: Option<String>,
+	pub source: FileTemplateSource,
+}
+
+impl FileTemplateCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<FileTemplate, FileTemplateError> {
+		let name = self.name.trim().to_string();
+		if name.is_empty() {
+			return Err(FileTemplateError::MissingName);
+		}
+
+		let (source, is_dir) = match self.source {
+			FileTemplateSource::Blank { is_dir } => (None, is_dir),
+			FileTemplateSource::FilePath {
+				location_id,
+				file_path_id,
+			} => {
+				library
+					.private_locations
+					.ensure_unlocked(location_id)
+					.await?;
+
+				let location_path =
+					get_location_path_from_location_id(&library.db, location_id).await?;
+				let file_data = get_many_files_datas(&library.db, location_path, &[file_path_id])
+					.await?
+					.pop()
+					.ok_or(FileSystemJobsError::FilePathIdNotFound(file_path_id))?;
+
+				let is_dir = file_data.file_path.is_dir == Some(true);
+				(Some(file_data.full_path), is_dir)
+			}
+		};
+
+		let file_name = match self.file_name.map(|name| name.trim().to_string()) {
+			Some(file_name) => file_name,
+			None => source
+				.as_deref()
+				.and_then(Path::file_name)
+				.map(|name| name.to_string_lossy().to_string())
+				.unwrap_or_default(),
+		};
+		if file_name.is_empty() || file_name.contains(['/', '\\']) {
+			return Err(FileTemplateError::InvalidFileName(file_name));
+		}
+
+		let created = library
+			.db
+			.file_template()
+			.create(name, file_name, is_dir, vec![])
+			.exec()
+			.await?;
+
+		let store_path = template_path(library, created.id);
+		if let Err(e) = store_template(source.as_deref(), &store_path, is_dir).await {
+			library
+				.db
+				.file_template()
+				.delete(file_template::id::equals(created.id))
+				.exec()
+				.await?;
+
+			return Err(e.into());
+		}
+
+		invalidate_query!(library, "fileTemplates.list");
+
+		Ok(created.into())
+	}
+}
+
+async fn store_template(
+	source: Option<&Path>,
+	store_path: &Path,
+	is_dir: bool,
+) -> Result<(), FileIOError> {
+	if let Some(parent) = store_path.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.map_err(|e| FileIOError::from((parent, e)))?;
+	}
+
+	match source {
+		Some(source) => copy_tree_staged(source, store_path, false).await,
+		None if is_dir => fs::create_dir(store_path)
+			.await
+			.map_err(|e| FileIOError::from((store_path, e))),
+		None => fs::write(store_path, [])
+			.await
+			.map_err(|e| FileIOError::from((store_path, e))),
+	}
+}
+
+pub async fn delete_file_template(
+	library: &Library,
+	id: file_template::id::Type,
+) -> Result<(), FileTemplateError> {
+	let template = find_file_template(&library.db, id).await?;
+
+	library
+		.db
+		.file_template()
+		.delete(file_template::id::equals(id))
+		.exec()
+		.await?;
+
+	let store_path = template_path(library, id);
+	if let Err(e) = if template.is_dir {
+		fs::remove_dir_all(&store_path).await
+	} else {
+		fs::remove_file(&store_path).await
+	} {
+		error!(
+			"Failed to remove the content of file template {}: {e:#?}",
+			store_path.display()
+		);
+	}
+
+	invalidate_query!(library, "fileTemplates.list");
+
+	Ok(())
+}
+
+#[derive(Type, Deserialize)]
+pub struct CreateFromTemplateArgs {
+	pub template_id: file_template::id::Type,
+	pub location_id: location::id::Type,
+	/// The directory to create in, relative to the location, the location itself when empty
+	pub path: PathBuf,
+	/// Substituted in names on top of the built-in date and time ones
+	#[serde(default)]
+	#[specta(optional)]
+	pub variables: HashMap<String, String>,
+}
+
+impl CreateFromTemplateArgs {
+	/// Creates a file or folder from the template, returning its name, which gets a counter when
+	/// the directory already has one with it
+	pub async fn create(self, library: &Library) -> Result<String, FileTemplateError> {
+		if let Some(value) = self
+			.variables
+			.values()
+			.find(|value| value.contains(['/', '\\']))
+		{
+			return Err(FileTemplateError::InvalidVariable(value.clone()));
+		}
+
+		let template = find_file_template(&library.db, self.template_id).await?;
+
+		library
+			.private_locations
+			.ensure_unlocked(self.location_id)
+			.await?;
+
+		let location = find_location(library, self.location_id)
+			.include(location_with_indexer_rules::include())
+			.exec()
+			.await?
+			.ok_or(FileTemplateError::LocationNotFound(self.location_id))?;
+		let location_path = maybe_missing(&location.path, "location.path")
+			.map_err(LocationError::from)?
+			.clone();
+
+		let directory = ensure_sub_path_is_in_location(&location_path, &self.path)
+			.await
+			.map_err(LocationError::from)?;
+		ensure_sub_path_is_directory(&location_path, &self.path)
+			.await
+			.map_err(LocationError::from)?;
+
+		let now = Local::now();
+		let file_name = substitute(&template.file_name, &self.variables, now);
+		if !IsolatedFilePathData::accept_file_name(&file_name, library.config.file_name_policy) {
+			return Err(FileTemplateError::InvalidFileName(file_name));
+		}
+
+		let mut target = directory.join(&file_name);
+		if fs::symlink_metadata(&target).await.is_ok() {
+			target = available_path(&target);
+		}
+
+		{
+			let staging = staging_path(&target);
+			let _guards = (
+				ignore_events_for(library, location.id, &staging).await,
+				ignore_events_for(library, location.id, &target).await,
+			);
+
+			copy_template(
+				&template_path(library, template.id),
+				&staging,
+				&self.variables,
+				now,
+			)
+			.await?;
+
+			fs::rename(&staging, &target)
+				.await
+				.map_err(|e| FileIOError::from((&target, e)))?;
+		}
+
+		// It's already on disk, the watcher or the next scan will pick it up otherwise
+		if let Err(e) = light_scan_location(library.clone(), location, &self.path).await {
+			error!(
+				"Failed to register {} created from a template: {e:#?}",
+				target.display()
+			);
+		}
+
+		Ok(target
+			.file_name()
+			.map(|name| name.to_string_lossy().to_string())
+			.unwrap_or(file_name))
+	}
+}
+
+/// Copies the content of a template, substituting the variables in the names of everything inside
+async fn copy_template(
+	source: &Path,
+	target: &Path,
+	variables: &HashMap<String, String>,
+	now: DateTime<Local>,
+) -> Result<(), FileIOError> {
+	let mut pending = vec![(source.to_path_buf(), target.to_path_buf())];
+
+	while let Some((source, target)) = pending.pop() {
+		let metadata = fs::symlink_metadata(&source)
+			.await
+			.map_err(|e| FileIOError::from((&source, e)))?;
+
+		if !metadata.is_dir() {
+			if metadata.is_symlink() {
+				copy_link(&source, &target).await
+			} else {
+				fs::copy(&source, &target).await.map(|_| ())
+			}
+			.map_err(|e| FileIOError::from((&target, e)))?;
+
+			continue;
+		}
+
+		fs::create_dir(&target)
+			.await
+			.map_err(|e| FileIOError::from((&target, e)))?;
+
+		let mut read_dir = fs::read_dir(&source)
+			.await
+			.map_err(|e| FileIOError::from((&source, e)))?;
+		while let Some(entry) = read_dir
+			.next_entry()
+			.await
+			.map_err(|e| FileIOError::from((&source, e)))?
+		{
+			let name = substitute(&entry.file_name().to_string_lossy(), variables, now);
+			pending.push((entry.path(), target.join(name)));
+		}
+	}
+
+	Ok(())
+}
+
+async fn find_file_template(
+	db: &PrismaClient,
+	id: file_template::id::Type,
+) -> Result<file_template::Data, FileTemplateError> {
+	db.file_template()
+		.find_unique(file_template::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(FileTemplateError::NotFound(id))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use chrono::TimeZone;
+
+	#[test]
+	fn variables_are_substituted_in_names() {
+		let now = Local.with_ymd_and_hms(2023, 8, 4, 9, 30, 0).unwrap();
+		let variables = HashMap::from([
+			("client".to_string(), "Acme".to_string()),
+			// Given variables win over the built-in ones
+			("year".to_string(), "FY24".to_string()),
+		]);
+
+		assert_eq!(
+			substitute("{client} - {date} {time}.docx", &variables, now),
+			"Acme - 2023-08-04 09-30-00.docx"
+		);
+		assert_eq!(
+			substitute("Report {year}-{month}", &variables, now),
+			"Report FY24-08"
+		);
+		assert_eq!(
+			substitute("{unknown} {client", &variables, now),
+			"{unknown} {client"
+		);
+		assert_eq!(substitute("plain.txt", &variables, now), "plain.txt");
+	}
+}
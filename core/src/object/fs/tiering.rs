@@ -0,0 +1,338 @@
+//! Storage tiering moves cold files from a fast location to a designated archive location, like an
+//! external drive or a NAS share. Each file is moved under the same relative path it had, and its
+//! `file_path` is kept behind with `tiered_at` and `tiered_to_location_id` set, pointing to it.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{file_path_for_compressor, IsolatedFilePathData},
+		find_location, location_with_indexer_rules, scan_location, LocationError,
+	},
+	prisma::{file_path, location},
+	util::{db::maybe_missing, error::FileIOError},
+};
+
+use std::{
+	hash::Hash,
+	io,
+	path::{Path, PathBuf},
+};
+
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::fs;
+use tracing::{error, info, trace, warn};
+
+use super::{ignore_events_for, sparse};
+
+pub const DEFAULT_TIER_AFTER_DAYS: i32 = 180;
+
+pub struct FileTieringJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct FileTieringJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for FileTieringJobInit {
+	type Job = FileTieringJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileTieringJobReport {
+	target_location_id: Option<location::id::Type>,
+	candidates_count: usize,
+	tiered_count: usize,
+	tiered_bytes: u64,
+	skipped: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileTieringJobData {
+	location_path: PathBuf,
+	target_location_path: PathBuf,
+	report: FileTieringJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FileTieringJob {
+	type Init = FileTieringJobInit;
+	type Data = FileTieringJobData;
+	type Step = file_path_for_compressor::Data;
+
+	const NAME: &'static str = "file_tiering";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location = find_location(&ctx.library, state.init.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+
+		state.data = Some(FileTieringJobData {
+			location_path: maybe_missing(&location.path, "location.path")?.into(),
+			target_location_path: PathBuf::new(),
+			report: FileTieringJobReport::default(),
+		});
+
+		let Some(target_location_id) = location.tier_to_location_id else {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "No tiering target was set for this location".to_string(),
+			});
+		};
+
+		let target_location = find_location(&ctx.library, target_location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(target_location_id))?;
+
+		// The files are moved through the local filesystem, so both ends must be on this node
+		if target_location.node_id != Some(ctx.library.node_local_id) {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "The tiering target location isn't on this device".to_string(),
+			});
+		}
+
+		let data = extract_job_data_mut!(state);
+		data.target_location_path = maybe_missing(&target_location.path, "location.path")?.into();
+		data.report.target_location_id = Some(target_location_id);
+
+		let cutoff = Utc::now()
+			- Duration::days(
+				location
+					.tier_after_days
+					.unwrap_or(DEFAULT_TIER_AFTER_DAYS)
+					.into(),
+			);
+		let min_size = location
+			.tier_min_size_in_mb
+			.map_or(0, |size| size.max(0) as u64 * 1024 * 1024);
+
+		state.steps = db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(state.init.location_id)),
+				file_path::is_dir::equals(Some(false)),
+				file_path::compressed_at::equals(None),
+				file_path::tiered_at::equals(None),
+				file_path::date_modified::lt(cutoff.into()),
+			])
+			.select(file_path_for_compressor::select())
+			.exec()
+			.await?
+			.into_iter()
+			.filter(|file_path| {
+				// Files never opened through Spacedrive only have their modification date to go by
+				let is_cold = file_path
+					.object
+					.as_ref()
+					.and_then(|object| object.date_accessed)
+					.map_or(true, |date_accessed| date_accessed < cutoff);
+
+				is_cold && file_size(file_path) >= min_size
+			})
+			.collect();
+
+		extract_job_data_mut!(state).report.candidates_count = state.steps.len();
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+		let file_path = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+		let target_location_id = data
+			.report
+			.target_location_id
+			.expect("steps are only created with a target location");
+
+		let iso_file_path = IsolatedFilePathData::try_from((location_id, file_path))?;
+		let full_path = data.location_path.join(&iso_file_path);
+		let target_path = data.target_location_path.join(&iso_file_path);
+
+		let result = move_file(
+			&ctx.library,
+			(location_id, &full_path),
+			(target_location_id, &target_path),
+			file_path.id,
+		)
+		.await;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		match result {
+			Ok(size) => {
+				trace!("Moved {} to {}", full_path.display(), target_path.display());
+
+				data.report.tiered_count += 1;
+				data.report.tiered_bytes += size;
+
+				Ok(())
+			}
+			Err(e) => {
+				let message = format!("Failed to move {}: {e}", full_path.display());
+				data.report.skipped.push(message.clone());
+
+				Err(JobError::StepCompletedWithErrors(vec![message]))
+			}
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing file tiering job: {report:?}");
+
+		if let Some(target_location_id) = report
+			.target_location_id
+			.filter(|_| report.tiered_count > 0)
+		{
+			// The moved files are spread all over the target location, so we scan it whole
+			match find_location(&ctx.library, target_location_id)
+				.include(location_with_indexer_rules::include())
+				.exec()
+				.await
+			{
+				Ok(Some(location)) => {
+					if let Err(e) = scan_location(&ctx.library, location).await {
+						// A scan already running for this location will find the files as well
+						warn!("Failed to scan the tiering target location: {e}");
+					}
+				}
+				Ok(None) => {
+					warn!("Tiering target location <id='{target_location_id}'> was removed")
+				}
+				Err(e) => error!("Failed to fetch the tiering target location: {e:#?}"),
+			}
+		}
+
+		invalidate_query!(ctx.library, "search.paths");
+		invalidate_query!(ctx.library, "library.statistics");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+fn file_size(file_path: &file_path_for_compressor::Data) -> u64 {
+	file_path
+		.size_in_bytes
+		.as_deref()
+		.and_then(|size| size.parse().ok())
+		.unwrap_or_default()
+}
+
+/// Moves a file to the target location, keeping its `file_path` behind as a pointer to it.
+/// Returns the size of the moved file.
+async fn move_file(
+	library: &Library,
+	(location_id, source_path): (location::id::Type, &Path),
+	(target_location_id, target_path): (location::id::Type, &Path),
+	file_path_id: file_path::id::Type,
+) -> Result<u64, JobError> {
+	// We never overwrite anything already at the target location
+	if fs::metadata(target_path).await.is_ok() {
+		return Err(FileIOError::from((
+			target_path,
+			io::Error::from(io::ErrorKind::AlreadyExists),
+		))
+		.into());
+	}
+
+	if let Some(parent) = target_path.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.map_err(|e| FileIOError::from((parent, e)))?;
+	}
+
+	let partial_path = {
+		let mut path = target_path.as_os_str().to_owned();
+		path.push(".part");
+		PathBuf::from(path)
+	};
+
+	let _guards = (
+		ignore_events_for(library, location_id, source_path).await,
+		ignore_events_for(library, target_location_id, &partial_path).await,
+		ignore_events_for(library, target_location_id, target_path).await,
+	);
+
+	let copy_result = async {
+		// Cold files are the ones most likely to be sparse, like old VM images
+		let size = sparse::copy_file(source_path, &partial_path)
+			.await
+			.map_err(|e| FileIOError::from((&partial_path, e)))?;
+
+		fs::rename(&partial_path, target_path)
+			.await
+			.map_err(|e| FileIOError::from((target_path, e)))?;
+
+		Ok::<_, FileIOError>(size)
+	}
+	.await;
+
+	let size = match copy_result {
+		Ok(size) => size,
+		Err(e) => {
+			fs::remove_file(&partial_path).await.ok();
+			return Err(e.into());
+		}
+	};
+
+	// The database goes first, so a failure here leaves the original file untouched
+	if let Err(e) = library
+		.db
+		.file_path()
+		.update(
+			file_path::id::equals(file_path_id),
+			vec![
+				file_path::tiered_at::set(Some(Utc::now().into())),
+				file_path::tiered_to_location_id::set(Some(target_location_id)),
+				file_path::allocated_size_in_bytes::set(Some("0".to_string())),
+				// The inode can be reused by a new file in this location once the original is gone
+				file_path::inode::set(None),
+				file_path::device::set(None),
+			],
+		)
+		.exec()
+		.await
+	{
+		fs::remove_file(target_path).await.ok();
+		return Err(e.into());
+	}
+
+	fs::remove_file(source_path)
+		.await
+		.map_err(|e| FileIOError::from((source_path, e)))?;
+
+	Ok(size)
+}
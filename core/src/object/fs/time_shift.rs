@@ -0,0 +1,400 @@
+//! Shifts the capture dates of photos by a fixed offset, for the ones shot with the clock of the
+//! camera set wrong or left in another time zone. The dates of the media data are shifted, and the
+//! EXIF dates embedded in the photos too when asked to.
+//!
+//! EXIF dates are text of a fixed length, so they're shifted in place, leaving everything else of
+//! the EXIF data as it was. Only JPEG, PNG and WebP photos get their EXIF data written, the dates of
+//! the others are only shifted in the library. A preview tells the dates photos would get first.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::privacy::LocationPrivacyError,
+	object::stacks::read_date_taken,
+	prisma::{file_path, location, media_data},
+	util::error::FileIOError,
+};
+
+use std::{collections::HashMap, path::Path};
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime};
+use img_parts::{DynImage, ImageEXIF};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	fs,
+	task::{spawn_blocking, JoinError},
+};
+use tracing::{info, warn};
+
+use super::{
+	error::FileSystemJobsError, get_location_path_from_location_id, get_many_files_datas,
+	staging_path, FileData,
+};
+
+const EXIF_DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+#[derive(Error, Debug)]
+pub enum TimeShiftError {
+	#[error(transparent)]
+	Privacy(#[from] LocationPrivacyError),
+	#[error(transparent)]
+	FileSystem(#[from] FileSystemJobsError),
+	#[error("failed to read the capture date: {0}")]
+	Join(#[from] JoinError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<TimeShiftError> for rspc::Error {
+	fn from(err: TimeShiftError) -> Self {
+		match err {
+			TimeShiftError::Privacy(e) => e.into(),
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// Shifts the dates of some EXIF data in place, returning how many of them were shifted
+fn shift_exif_dates(exif: &mut [u8], offset: Duration) -> usize {
+	let Ok(fields) = exif::Reader::new().read_raw(exif.to_vec()) else {
+		return 0;
+	};
+
+	// The same date is usually in a few fields, each one shifted once
+	let mut shifted = HashMap::new();
+	for tag in [
+		exif::Tag::DateTime,
+		exif::Tag::DateTimeOriginal,
+		exif::Tag::DateTimeDigitized,
+	] {
+		let Some(exif::Value::Ascii(values)) = fields
+			.get_field(tag, exif::In::PRIMARY)
+			.map(|field| &field.value)
+		else {
+			continue;
+		};
+		let Some(date) = values.first() else {
+			continue;
+		};
+
+		let new_date = std::str::from_utf8(date)
+			.ok()
+			.and_then(|date| NaiveDateTime::parse_from_str(date, EXIF_DATE_FORMAT).ok())
+			.and_then(|date| date.checked_add_signed(offset))
+			.map(|date| date.format(EXIF_DATE_FORMAT).to_string().into_bytes())
+			// Dates past year 9999 wouldn't fit
+			.filter(|new_date| new_date.len() == date.len());
+
+		if let Some(new_date) = new_date {
+			shifted.insert(date.clone(), new_date);
+		}
+	}
+
+	// Every occurrence is found before any is replaced, so a date shifted to another one isn't
+	// shifted twice
+	let occurrences = shifted
+		.iter()
+		.flat_map(|(date, new_date)| {
+			exif.windows(date.len())
+				.enumerate()
+				.filter(move |(_, window)| window == date)
+				.map(move |(position, _)| (position, new_date))
+		})
+		.collect::<Vec<_>>();
+
+	for (position, new_date) in &occurrences {
+		exif[*position..*position + new_date.len()].copy_from_slice(new_date);
+	}
+
+	occurrences.len()
+}
+
+/// Writes the shifted EXIF dates of a photo, `false` if it has none or its format can't be written
+async fn write_shifted_exif(path: &Path, offset: Duration) -> Result<bool, FileIOError> {
+	let bytes = fs::read(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	let shifted = spawn_blocking(move || {
+		let mut image = DynImage::from_bytes(bytes.into()).ok()??;
+		let mut exif = image.exif()?.to_vec();
+
+		(shift_exif_dates(&mut exif, offset) > 0).then(|| {
+			image.set_exif(Some(exif.into()));
+			image.encoder().bytes()
+		})
+	})
+	.await
+	.map_err(|e| FileIOError::from((path, std::io::Error::new(std::io::ErrorKind::Other, e))))?;
+
+	let Some(shifted) = shifted else {
+		return Ok(false);
+	};
+
+	// Written next to the photo first, so a failure halfway leaves it whole
+	let staging = staging_path(path);
+	fs::write(&staging, shifted)
+		.await
+		.map_err(|e| FileIOError::from((&staging, e)))?;
+	fs::rename(&staging, path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	Ok(true)
+}
+
+/// The capture date of a photo, from its media data or else read from the photo
+async fn date_taken<E: From<QueryError> + From<JoinError>>(
+	library: &Library,
+	file_data: &FileData,
+) -> Result<Option<DateTime<FixedOffset>>, E> {
+	let Some(object) = &file_data.file_path.object else {
+		return Ok(None);
+	};
+
+	let stored = library
+		.db
+		.media_data()
+		.find_unique(media_data::id::equals(object.id))
+		.select(media_data::select!({ date_taken }))
+		.exec()
+		.await?
+		.and_then(|media_data| media_data.date_taken);
+
+	if stored.is_some() {
+		return Ok(stored);
+	}
+
+	let path = file_data.full_path.clone();
+	Ok(spawn_blocking(move || read_date_taken(&path)).await?)
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct TimeShiftPreview {
+	pub file_path_id: file_path::id::Type,
+	/// `None` for photos without a capture date, which are left as they are
+	pub date_taken: Option<DateTime<FixedOffset>>,
+	pub shifted_date_taken: Option<DateTime<FixedOffset>>,
+}
+
+/// The dates photos would get from shifting them, without shifting anything
+pub async fn preview_time_shift(
+	library: &Library,
+	location_id: location::id::Type,
+	file_path_ids: &[file_path::id::Type],
+	offset_seconds: i32,
+) -> Result<Vec<TimeShiftPreview>, TimeShiftError> {
+	library
+		.private_locations
+		.ensure_unlocked(location_id)
+		.await?;
+
+	let location_path = get_location_path_from_location_id(&library.db, location_id).await?;
+	let offset = Duration::seconds(offset_seconds.into());
+
+	let mut previews = vec![];
+	for file_data in get_many_files_datas(&library.db, location_path, file_path_ids).await? {
+		let date_taken = date_taken::<TimeShiftError>(library, &file_data).await?;
+
+		previews.push(TimeShiftPreview {
+			file_path_id: file_data.file_path.id,
+			shifted_date_taken: date_taken.and_then(|date| date.checked_add_signed(offset)),
+			date_taken,
+		});
+	}
+
+	Ok(previews)
+}
+
+pub struct PhotoTimeShifterJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct PhotoTimeShifterJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+	/// Added to the capture dates, negative to move them back
+	pub offset_seconds: i32,
+	/// Shifts the EXIF dates embedded in the photos too
+	#[serde(default)]
+	#[specta(optional)]
+	pub write_exif: bool,
+}
+
+impl JobInitData for PhotoTimeShifterJobInit {
+	type Job = PhotoTimeShifterJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PhotoTimeShifterJobReport {
+	shifted_count: usize,
+	/// Photos without a capture date
+	skipped_count: usize,
+	exif_written_count: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for PhotoTimeShifterJob {
+	type Init = PhotoTimeShifterJobInit;
+	type Data = PhotoTimeShifterJobReport;
+	type Step = FileData;
+
+	const NAME: &'static str = "photo_time_shifter";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+
+		state.steps = get_many_files_datas(
+			db,
+			get_location_path_from_location_id(db, state.init.location_id).await?,
+			&state.init.file_path_ids,
+		)
+		.await?
+		.into_iter()
+		.filter(|file_data| file_data.file_path.is_dir == Some(false))
+		.collect();
+
+		state.data = Some(PhotoTimeShifterJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let step = &state.steps[0];
+		let offset = Duration::seconds(state.init.offset_seconds.into());
+
+		let shifted = date_taken::<JobError>(&ctx.library, step)
+			.await?
+			.and_then(|date| date.checked_add_signed(offset));
+
+		let (Some(shifted), Some(object)) = (shifted, &step.file_path.object) else {
+			warn!(
+				"{} has no capture date to shift, leaving it",
+				step.full_path.display()
+			);
+			extract_job_data_mut!(state).skipped_count += 1;
+
+			ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+				state.step_number + 1,
+			)]);
+
+			return Ok(());
+		};
+
+		// The watcher picks up the rewritten photo like any other change
+		let exif_written =
+			state.init.write_exif && write_shifted_exif(&step.full_path, offset).await?;
+
+		ctx.library
+			.db
+			.media_data()
+			.upsert(
+				media_data::id::equals(object.id),
+				media_data::create_unchecked(
+					object.id,
+					vec![media_data::date_taken::set(Some(shifted))],
+				),
+				vec![media_data::date_taken::set(Some(shifted))],
+			)
+			.exec()
+			.await?;
+
+		let report = extract_job_data_mut!(state);
+		report.shifted_count += 1;
+		if exif_written {
+			report.exif_written_count += 1;
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data!(state);
+
+		info!("Finalizing photo time shifter job: {report:?}");
+
+		if report.shifted_count > 0 {
+			invalidate_query!(ctx.library, "search.paths");
+			invalidate_query!(ctx.library, "search.mediaTimeline");
+		}
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Little endian TIFF header, then an IFD with a `DateTime` entry, pointing to an Exif IFD with a
+	/// `DateTimeOriginal` entry
+	fn exif_with_dates(date_time: &[u8; 19], date_time_original: &[u8; 19]) -> Vec<u8> {
+		let mut exif = vec![
+			b'I', b'I', 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, // header
+			0x02, 0x00, // two entries
+			0x32, 0x01, 0x02, 0x00, 0x14, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00,
+			0x00, // DateTime
+			0x69, 0x87, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x26, 0x00, 0x00,
+			0x00, // Exif IFD pointer
+			0x00, 0x00, 0x00, 0x00, // no next IFD
+			0x01, 0x00, // Exif IFD, one entry
+			0x03, 0x90, 0x02, 0x00, 0x14, 0x00, 0x00, 0x00, 0x4C, 0x00, 0x00,
+			0x00, // DateTimeOriginal
+			0x00, 0x00, 0x00, 0x00, // no next IFD
+		];
+		exif.extend_from_slice(date_time);
+		exif.push(0);
+		exif.extend_from_slice(date_time_original);
+		exif.push(0);
+
+		exif
+	}
+
+	#[test]
+	fn exif_dates_are_shifted_in_place() {
+		let mut exif = exif_with_dates(b"2023:08:04 09:30:00", b"2023:08:04 09:30:00");
+		assert_eq!(shift_exif_dates(&mut exif, Duration::hours(-2)), 2);
+		assert_eq!(
+			exif,
+			exif_with_dates(b"2023:08:04 07:30:00", b"2023:08:04 07:30:00")
+		);
+
+		// Shifted to the other one, which is shifted just once too
+		let mut exif = exif_with_dates(b"2023:08:04 09:30:00", b"2023:08:04 10:30:00");
+		assert_eq!(shift_exif_dates(&mut exif, Duration::hours(1)), 2);
+		assert_eq!(
+			exif,
+			exif_with_dates(b"2023:08:04 10:30:00", b"2023:08:04 11:30:00")
+		);
+
+		assert_eq!(shift_exif_dates(&mut [0; 8], Duration::hours(1)), 0);
+	}
+}
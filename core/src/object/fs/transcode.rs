@@ -0,0 +1,731 @@
+//! Video transcoding through the `ffmpeg` and `ffprobe` programs, which have to be installed. The
+//! hardware encoders of the machine are tried first (VideoToolbox on macOS, NVENC with NVIDIA
+//! GPUs and VA-API on Linux), falling back to the software ones when they fail to start.
+//!
+//! Only a few transcodes run at once across libraries, see
+//! [`ResourceLimits::concurrent_transcodes`](crate::node::ResourceLimits::concurrent_transcodes),
+//! the other jobs waiting for their turn. Progress is counted in seconds of video, so the estimated
+//! completion of the job follows the actual encoding speed.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{find_location, light_scan_location, location_with_indexer_rules, LocationError},
+	prisma::{file_path, location},
+	util::error::FileIOError,
+};
+
+use std::{
+	collections::{BTreeSet, HashSet},
+	ffi::OsString,
+	path::{Path, PathBuf},
+	process::Stdio,
+	sync::atomic::{AtomicU32, Ordering},
+	time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	fs,
+	io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+	process::Command,
+	sync::OnceCell,
+	time::sleep,
+};
+use tracing::{debug, trace, warn};
+
+use super::{
+	convert::{resolve_conflict, resolve_target_directory},
+	get_location_path_from_location_id, get_many_files_datas, ConflictPolicy, FileData,
+};
+
+const TRANSCODED_EXTENSION: &str = "mp4";
+/// Added to the name of a file while it's being written, the watcher holds such files as pending
+const IN_PROGRESS_EXTENSION: &str = "part";
+#[cfg(target_os = "linux")]
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+/// How often a job waiting for another transcode to finish checks again
+const TRANSCODE_SLOT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Transcodes running on this node, limited by the resource profile
+static RUNNING_TRANSCODES: AtomicU32 = AtomicU32::new(0);
+/// The encoders the installed ffmpeg was built with
+static AVAILABLE_ENCODERS: Lazy<OnceCell<HashSet<String>>> = Lazy::new(OnceCell::new);
+
+#[derive(Error, Debug)]
+pub enum TranscodeError {
+	#[error("ffmpeg isn't installed or couldn't be started: {0}")]
+	FfmpegNotFound(std::io::Error),
+	#[error("no encoder available for {0:?}")]
+	NoEncoder(VideoCodec),
+	#[error("ffmpeg failed with the {encoder} encoder: {message}")]
+	Failed {
+		encoder: &'static str,
+		message: String,
+	},
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type, Eq, PartialEq)]
+pub enum VideoCodec {
+	H264,
+	H265,
+	Av1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, Type, Eq, PartialEq)]
+pub enum TranscodeQuality {
+	/// Visually lossless, for archiving
+	High,
+	#[default]
+	Balanced,
+	/// For sharing or watching on small screens
+	Small,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, Type)]
+pub struct TranscodePreset {
+	pub codec: VideoCodec,
+	#[serde(default)]
+	#[specta(optional)]
+	pub quality: TranscodeQuality,
+	/// Taller videos are scaled down to this height, keeping their aspect ratio
+	#[serde(default)]
+	#[specta(optional)]
+	pub max_height: Option<u32>,
+	/// Never uses the hardware encoders, which are faster but give bigger files for the same quality
+	#[serde(default)]
+	#[specta(optional)]
+	pub software_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Encoder {
+	VideoToolbox,
+	Nvenc,
+	Vaapi,
+	Software,
+}
+
+impl Encoder {
+	/// The hardware encoders worth trying on this platform, the software one always comes last
+	fn candidates() -> &'static [Self] {
+		if cfg!(target_os = "macos") {
+			&[Self::VideoToolbox, Self::Software]
+		} else if cfg!(target_os = "linux") {
+			&[Self::Nvenc, Self::Vaapi, Self::Software]
+		} else {
+			&[Self::Nvenc, Self::Software]
+		}
+	}
+
+	fn ffmpeg_name(self, codec: VideoCodec) -> Option<&'static str> {
+		use VideoCodec::*;
+
+		match (self, codec) {
+			(Self::Software, H264) => Some("libx264"),
+			(Self::Software, H265) => Some("libx265"),
+			(Self::Software, Av1) => Some("libsvtav1"),
+			(Self::VideoToolbox, H264) => Some("h264_videotoolbox"),
+			(Self::VideoToolbox, H265) => Some("hevc_videotoolbox"),
+			(Self::VideoToolbox, Av1) => None,
+			(Self::Nvenc, H264) => Some("h264_nvenc"),
+			(Self::Nvenc, H265) => Some("hevc_nvenc"),
+			(Self::Nvenc, Av1) => Some("av1_nvenc"),
+			(Self::Vaapi, H264) => Some("h264_vaapi"),
+			(Self::Vaapi, H265) => Some("hevc_vaapi"),
+			(Self::Vaapi, Av1) => Some("av1_vaapi"),
+		}
+	}
+
+	/// The rate control arguments giving the quality asked for, each encoder having its own scale
+	fn quality_args(self, codec: VideoCodec, quality: TranscodeQuality) -> Vec<String> {
+		// Constant rate factors, lower is better
+		let crf = match (codec, quality) {
+			(VideoCodec::H264, TranscodeQuality::High) => 18,
+			(VideoCodec::H264, TranscodeQuality::Balanced) => 23,
+			(VideoCodec::H264, TranscodeQuality::Small) => 28,
+			(VideoCodec::H265, TranscodeQuality::High) => 20,
+			(VideoCodec::H265, TranscodeQuality::Balanced) => 26,
+			(VideoCodec::H265, TranscodeQuality::Small) => 30,
+			(VideoCodec::Av1, TranscodeQuality::High) => 24,
+			(VideoCodec::Av1, TranscodeQuality::Balanced) => 32,
+			(VideoCodec::Av1, TranscodeQuality::Small) => 40,
+		}
+		.to_string();
+		let crf = crf.as_str();
+
+		let args =
+			|args: &[&str]| -> Vec<String> { args.iter().map(ToString::to_string).collect() };
+
+		match self {
+			Self::Software if codec == VideoCodec::Av1 => args(&["-crf", crf, "-preset", "8"]),
+			Self::Software => args(&["-crf", crf, "-preset", "medium"]),
+			Self::Nvenc => args(&["-rc", "vbr", "-cq", crf, "-b:v", "0", "-preset", "p5"]),
+			Self::Vaapi => args(&["-qp", crf]),
+			// VideoToolbox goes from 1 to 100, higher is better
+			Self::VideoToolbox => args(&[
+				"-q:v",
+				match quality {
+					TranscodeQuality::High => "70",
+					TranscodeQuality::Balanced => "55",
+					TranscodeQuality::Small => "40",
+				},
+			]),
+		}
+	}
+
+	fn scale_filter(self, height: u32) -> String {
+		if self == Self::Vaapi {
+			format!("scale_vaapi=w=-2:h={height}")
+		} else {
+			format!("scale=-2:{height}")
+		}
+	}
+}
+
+pub struct VideoTranscoderJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct VideoTranscoderJobInit {
+	pub location_id: location::id::Type,
+	pub file_path_ids: Vec<file_path::id::Type>,
+	pub preset: TranscodePreset,
+	/// Where the transcoded videos are written, next to their originals when empty
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_id: Option<location::id::Type>,
+	#[serde(default)]
+	#[specta(optional)]
+	pub target_location_relative_directory_path: Option<PathBuf>,
+	#[serde(default)]
+	pub conflict_policy: ConflictPolicy,
+}
+
+impl JobInitData for VideoTranscoderJobInit {
+	type Job = VideoTranscoderJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TranscodeReport {
+	transcoded: usize,
+	/// Videos left out as something already existed at their target path
+	skipped_conflicts: usize,
+	failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VideoTranscoderJobData {
+	report: TranscodeReport,
+	/// Seconds of video done by the previous steps
+	completed_seconds: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum VideoTranscoderJobStep {
+	Transcode {
+		source: FileData,
+		target_directory: PathBuf,
+		/// Seconds of video, used to report progress, at least 1
+		seconds: usize,
+		height: Option<u32>,
+	},
+	/// Adds the transcoded videos to the target location, which may not be watched
+	Register {
+		location_id: location::id::Type,
+		sub_path: PathBuf,
+	},
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for VideoTranscoderJob {
+	type Init = VideoTranscoderJobInit;
+	type Data = VideoTranscoderJobData;
+	type Step = VideoTranscoderJobStep;
+
+	const NAME: &'static str = "video_transcoder";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, .. } = &ctx.library;
+
+		let location_path = get_location_path_from_location_id(db, state.init.location_id).await?;
+
+		let target = resolve_target_directory(
+			db,
+			state.init.target_location_id,
+			&state.init.target_location_relative_directory_path,
+		)
+		.await?;
+
+		let mut directories_to_register = BTreeSet::new();
+		let mut total_seconds = 0;
+
+		for source in get_many_files_datas(db, &location_path, &state.init.file_path_ids).await? {
+			if source.file_path.is_dir == Some(true) {
+				continue;
+			}
+
+			let target_directory = match &target {
+				Some((location_id, sub_path, target_directory)) => {
+					directories_to_register.insert((*location_id, sub_path.clone()));
+					target_directory.clone()
+				}
+				None => source
+					.full_path
+					.parent()
+					.map(Path::to_path_buf)
+					.unwrap_or_default(),
+			};
+
+			let (duration, height) = probe(&source.full_path).await;
+			let seconds = duration.map_or(1, |duration| (duration.ceil() as usize).max(1));
+			total_seconds += seconds;
+
+			state.steps.push_back(VideoTranscoderJobStep::Transcode {
+				source,
+				target_directory,
+				seconds,
+				height,
+			});
+		}
+
+		state.steps.extend(
+			directories_to_register
+				.into_iter()
+				.map(|(location_id, sub_path)| VideoTranscoderJobStep::Register {
+					location_id,
+					sub_path,
+				}),
+		);
+
+		state.data = Some(VideoTranscoderJobData {
+			report: TranscodeReport::default(),
+			completed_seconds: 0,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(total_seconds)]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let mut errors = vec![];
+
+		match &state.steps[0] {
+			VideoTranscoderJobStep::Transcode {
+				source,
+				target_directory,
+				seconds,
+				height,
+			} => {
+				let preset = state.init.preset;
+				let conflict_policy = state.init.conflict_policy;
+				let source_path = source.full_path.clone();
+				let (seconds, height) = (*seconds, *height);
+
+				let target = source_path
+					.file_stem()
+					.map(|stem| {
+						target_directory
+							.join(stem)
+							.with_extension(TRANSCODED_EXTENSION)
+					})
+					.ok_or(JobError::OsStr)?;
+
+				let data = extract_job_data_mut!(state);
+
+				match resolve_conflict(&source_path, target, conflict_policy).await? {
+					Some(target) => {
+						let _slot = TranscodeSlot::acquire(ctx).await;
+
+						match transcode(ctx, &source_path, &target, preset, height, |done| {
+							data.completed_seconds + done.min(seconds)
+						})
+						.await
+						{
+							Ok(()) => data.report.transcoded += 1,
+							Err(e) => {
+								data.report.failed += 1;
+								errors.push(format!(
+									"Failed to transcode {}: {e}",
+									source_path.display()
+								));
+							}
+						}
+					}
+					None => {
+						trace!("Skipping {} as its target exists", source_path.display());
+						data.report.skipped_conflicts += 1;
+					}
+				}
+
+				data.completed_seconds += seconds;
+				ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+					data.completed_seconds,
+				)]);
+			}
+
+			VideoTranscoderJobStep::Register {
+				location_id,
+				sub_path,
+			} => {
+				let location = find_location(&ctx.library, *location_id)
+					.include(location_with_indexer_rules::include())
+					.exec()
+					.await?
+					.ok_or(LocationError::IdNotFound(*location_id))?;
+
+				light_scan_location(ctx.library.clone(), location, sub_path).await?;
+			}
+		}
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(JobError::StepCompletedWithErrors(errors))
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::json!({
+			"init": state.init,
+			"report": extract_job_data!(state).report,
+		})))
+	}
+}
+
+/// One of the transcodes allowed to run at once, given back when dropped
+struct TranscodeSlot;
+
+impl TranscodeSlot {
+	async fn acquire(ctx: &WorkerContext) -> Self {
+		let mut waiting = false;
+
+		loop {
+			let limit = ctx.library.resources().limits().await.concurrent_transcodes;
+
+			if RUNNING_TRANSCODES
+				.fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+					(running < limit).then_some(running + 1)
+				})
+				.is_ok()
+			{
+				return Self;
+			}
+
+			if !waiting {
+				ctx.progress(vec![JobReportUpdate::Message(
+					"Waiting for other transcodes to finish".to_string(),
+				)]);
+				waiting = true;
+			}
+
+			sleep(TRANSCODE_SLOT_POLL_INTERVAL).await;
+		}
+	}
+}
+
+impl Drop for TranscodeSlot {
+	fn drop(&mut self) {
+		RUNNING_TRANSCODES.fetch_sub(1, Ordering::AcqRel);
+	}
+}
+
+/// Transcodes a video with the first encoder that works, reporting the seconds of video done
+/// through `progress`, which turns them into the completed task count of the job
+async fn transcode(
+	ctx: &WorkerContext,
+	source: &Path,
+	target: &Path,
+	preset: TranscodePreset,
+	height: Option<u32>,
+	progress: impl Fn(usize) -> usize,
+) -> Result<(), TranscodeError> {
+	let available = available_encoders().await?;
+
+	let candidates = Encoder::candidates()
+		.iter()
+		.filter(|encoder| !preset.software_only || **encoder == Encoder::Software)
+		.filter_map(|encoder| {
+			encoder
+				.ffmpeg_name(preset.codec)
+				.filter(|name| available.contains(*name))
+				.map(|name| (*encoder, name))
+		})
+		.collect::<Vec<_>>();
+
+	// Written under another name until complete, so it's never taken for a finished video
+	let mut in_progress_name = target.file_name().unwrap_or_default().to_os_string();
+	in_progress_name.push(".");
+	in_progress_name.push(IN_PROGRESS_EXTENSION);
+	let in_progress = target.with_file_name(in_progress_name);
+
+	let mut last_error = TranscodeError::NoEncoder(preset.codec);
+
+	for (encoder, name) in candidates {
+		ctx.progress(vec![JobReportUpdate::Message(format!(
+			"Transcoding {} with {name}",
+			source.display()
+		))]);
+
+		match run_ffmpeg(
+			ctx,
+			ffmpeg_args(source, &in_progress, preset, encoder, name, height),
+			name,
+			&progress,
+		)
+		.await
+		{
+			Ok(()) => {
+				return fs::rename(&in_progress, target)
+					.await
+					.map_err(|e| FileIOError::from((target, e)).into());
+			}
+			Err(e) => {
+				if fs::remove_file(&in_progress).await.is_err() {
+					debug!(
+						"No partial transcode to remove at {}",
+						in_progress.display()
+					);
+				}
+
+				// A hardware encoder built into ffmpeg may have no device behind it
+				if encoder != Encoder::Software {
+					warn!("Falling back from the {name} encoder: {e}");
+				}
+
+				last_error = e;
+			}
+		}
+	}
+
+	Err(last_error)
+}
+
+fn ffmpeg_args(
+	source: &Path,
+	target: &Path,
+	preset: TranscodePreset,
+	encoder: Encoder,
+	encoder_name: &str,
+	height: Option<u32>,
+) -> Vec<OsString> {
+	let mut args = ["-hide_banner", "-nostdin", "-v", "error", "-nostats"]
+		.into_iter()
+		.map(OsString::from)
+		.collect::<Vec<_>>();
+
+	#[cfg(target_os = "linux")]
+	if encoder == Encoder::Vaapi {
+		args.extend(["-vaapi_device", VAAPI_DEVICE].map(OsString::from));
+	}
+
+	args.extend(["-progress", "pipe:1", "-i"].map(OsString::from));
+	args.push(source.into());
+	args.extend(
+		["-map", "0:v:0", "-map", "0:a?", "-c:v", encoder_name]
+			.into_iter()
+			.map(OsString::from),
+	);
+	args.extend(
+		encoder
+			.quality_args(preset.codec, preset.quality)
+			.into_iter()
+			.map(OsString::from),
+	);
+
+	let scale = preset
+		.max_height
+		.filter(|max_height| height.map_or(true, |height| height > *max_height))
+		.map(|max_height| encoder.scale_filter(max_height));
+
+	let filters = if encoder == Encoder::Vaapi {
+		Some(
+			["format=nv12", "hwupload"]
+				.into_iter()
+				.map(ToString::to_string)
+				.chain(scale)
+				.collect::<Vec<_>>()
+				.join(","),
+		)
+	} else {
+		scale
+	};
+
+	if let Some(filters) = filters {
+		args.extend([OsString::from("-vf"), OsString::from(filters)]);
+	}
+
+	// Apple players only take H.265 in MP4 with this tag
+	if preset.codec == VideoCodec::H265 {
+		args.extend(["-tag:v", "hvc1"].map(OsString::from));
+	}
+
+	args.extend(
+		[
+			"-c:a",
+			"aac",
+			"-b:a",
+			"192k",
+			"-movflags",
+			"+faststart",
+			"-f",
+			"mp4",
+		]
+		.into_iter()
+		.map(OsString::from),
+	);
+	args.push(target.into());
+
+	args
+}
+
+async fn run_ffmpeg(
+	ctx: &WorkerContext,
+	args: Vec<OsString>,
+	encoder_name: &'static str,
+	progress: impl Fn(usize) -> usize,
+) -> Result<(), TranscodeError> {
+	let mut child = Command::new("ffmpeg")
+		.args(args)
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		// Canceling the job drops the step, which must not leave ffmpeg running
+		.kill_on_drop(true)
+		.spawn()
+		.map_err(TranscodeError::FfmpegNotFound)?;
+
+	let mut stderr = child.stderr.take().expect("stderr is piped");
+	let stderr_task = tokio::spawn(async move {
+		let mut output = String::new();
+		stderr.read_to_string(&mut output).await.ok();
+		output
+	});
+
+	if let Some(stdout) = child.stdout.take() {
+		let mut lines = BufReader::new(stdout).lines();
+
+		while let Ok(Some(line)) = lines.next_line().await {
+			// `out_time_us` is in microseconds, despite what the `out_time_ms` alias says
+			if let Some(done) = line
+				.strip_prefix("out_time_us=")
+				.and_then(|micros| micros.trim().parse::<u64>().ok())
+			{
+				ctx.progress(vec![JobReportUpdate::CompletedTaskCount(progress(
+					(done / 1_000_000) as usize,
+				))]);
+			}
+		}
+	}
+
+	let status = child.wait().await.map_err(TranscodeError::FfmpegNotFound)?;
+	let stderr = stderr_task.await.unwrap_or_default();
+
+	if status.success() {
+		Ok(())
+	} else {
+		Err(TranscodeError::Failed {
+			encoder: encoder_name,
+			message: stderr
+				.lines()
+				.last()
+				.map_or_else(|| status.to_string(), ToString::to_string),
+		})
+	}
+}
+
+async fn available_encoders() -> Result<&'static HashSet<String>, TranscodeError> {
+	AVAILABLE_ENCODERS
+		.get_or_try_init(|| async {
+			let output = Command::new("ffmpeg")
+				.args(["-hide_banner", "-encoders"])
+				.output()
+				.await
+				.map_err(TranscodeError::FfmpegNotFound)?;
+
+			// Lines look like ` V....D libx264  libx264 H.264 / AVC ...`
+			let encoders = String::from_utf8_lossy(&output.stdout)
+				.lines()
+				.filter_map(|line| line.split_whitespace().nth(1))
+				.map(ToString::to_string)
+				.collect::<HashSet<_>>();
+
+			// VA-API is only worth trying with a render device to run on
+			#[cfg(target_os = "linux")]
+			let encoders = {
+				let mut encoders = encoders;
+				if fs::metadata(VAAPI_DEVICE).await.is_err() {
+					encoders.retain(|name| !name.ends_with("_vaapi"));
+				}
+				encoders
+			};
+
+			Ok(encoders)
+		})
+		.await
+}
+
+/// The duration in seconds and the height of a video, when ffprobe can tell
+async fn probe(path: &Path) -> (Option<f64>, Option<u32>) {
+	let output = match Command::new("ffprobe")
+		.args([
+			"-v",
+			"error",
+			"-select_streams",
+			"v:0",
+			"-show_entries",
+			"stream=height:format=duration",
+			"-of",
+			"json",
+		])
+		.arg(path)
+		.output()
+		.await
+	{
+		Ok(output) => output,
+		Err(e) => {
+			debug!("Failed to run ffprobe for {}: {e}", path.display());
+			return (None, None);
+		}
+	};
+
+	let Ok(probed) = serde_json::from_slice::<Value>(&output.stdout) else {
+		return (None, None);
+	};
+
+	(
+		probed["format"]["duration"]
+			.as_str()
+			.and_then(|duration| duration.parse().ok()),
+		probed["streams"][0]["height"]
+			.as_u64()
+			.and_then(|height| u32::try_from(height).ok()),
+	)
+}
@@ -0,0 +1,297 @@
+//! Versions of the files a folder mirror overwrites or deletes, kept for
+//! [`MirrorSettings::keep_versions_days`](super::mirror::MirrorSettings::keep_versions_days).
+//! Their contents go in a chunk store of the library, so what the versions of a file have in
+//! common is only stored once.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	location::{find_location, LocationError},
+	prisma::{file_version, folder_mirror, location, PrismaClient},
+	util::{
+		db::{maybe_missing, MissingFieldError},
+		error::FileIOError,
+	},
+};
+
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use sd_chunk_store::{ChunkStore, ChunkStoreError};
+use thiserror::Error;
+use tokio::{
+	fs,
+	task::{spawn_blocking, JoinError},
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::extract::available_path;
+
+/// Directory of the node data holding a chunk store for each library
+const VERSIONS_DIR_NAME: &str = "versions";
+
+#[derive(Error, Debug)]
+pub enum FileVersionError {
+	#[error("file version not found <id='{0}'>")]
+	NotFound(file_version::id::Type),
+	#[error(transparent)]
+	ChunkStore(#[from] ChunkStoreError),
+	#[error(transparent)]
+	Location(#[from] LocationError),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("missing-field: {0}")]
+	MissingField(#[from] MissingFieldError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+	#[error(transparent)]
+	JoinTask(#[from] JoinError),
+}
+
+impl From<FileVersionError> for rspc::Error {
+	fn from(err: FileVersionError) -> Self {
+		match err {
+			FileVersionError::Location(e) => e.into(),
+			FileVersionError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+/// The chunk store of a library, opened when first needed
+#[derive(Debug)]
+pub struct FileVersions {
+	root: PathBuf,
+	store: OnceCell<Arc<ChunkStore>>,
+}
+
+impl FileVersions {
+	pub(crate) fn new(data_dir: &Path, library_id: Uuid) -> Self {
+		Self {
+			root: data_dir
+				.join(VERSIONS_DIR_NAME)
+				.join(library_id.to_string()),
+			store: OnceCell::new(),
+		}
+	}
+
+	pub(crate) fn root(&self) -> &Path {
+		&self.root
+	}
+
+	fn store(&self) -> Result<Arc<ChunkStore>, ChunkStoreError> {
+		self.store
+			.get_or_try_init(|| ChunkStore::open(&self.root).map(Arc::new))
+			.cloned()
+	}
+
+	/// Runs `f` on the store away from the async runtime, as the store works with blocking IO
+	async fn run<T: Send + 'static>(
+		&self,
+		f: impl FnOnce(&ChunkStore) -> Result<T, ChunkStoreError> + Send + 'static,
+	) -> Result<T, FileVersionError> {
+		let store = self.store()?;
+		Ok(spawn_blocking(move || f(&store)).await??)
+	}
+}
+
+/// Keeps the file at `full_path` as a version, `path` being where it is in its location
+pub(crate) async fn keep_version(
+	library: &Library,
+	mirror_id: folder_mirror::id::Type,
+	location_id: location::id::Type,
+	path: &Path,
+	full_path: &Path,
+) -> Result<(), FileVersionError> {
+	let name = Uuid::new_v4().to_string();
+
+	let (manifest, report) = library
+		.file_versions
+		.run({
+			let name = name.clone();
+			let full_path = full_path.to_path_buf();
+			move |store| store.put_file(&name, full_path)
+		})
+		.await?;
+
+	debug!(
+		"Kept a version of {}, {} new chunks and {} reused",
+		full_path.display(),
+		report.new_chunks,
+		report.reused_chunks
+	);
+
+	let created = library
+		.db
+		.file_version()
+		.create(
+			name.clone(),
+			folder_mirror::id::equals(mirror_id),
+			location_id,
+			path.to_string_lossy().into_owned(),
+			manifest.size.to_be_bytes().to_vec(),
+			vec![],
+		)
+		.exec()
+		.await;
+
+	if let Err(e) = created {
+		// Without its row nothing would ever remove it
+		if let Err(e) = library
+			.file_versions
+			.run(move |store| store.remove(&name))
+			.await
+		{
+			warn!("Failed to remove a version left without its row: {e}");
+		}
+
+		return Err(e.into());
+	}
+
+	invalidate_query!(library, "folderMirrors.versions");
+
+	Ok(())
+}
+
+pub async fn list_file_versions(
+	db: &PrismaClient,
+	mirror_id: folder_mirror::id::Type,
+) -> Result<Vec<file_version::Data>, FileVersionError> {
+	Ok(db
+		.file_version()
+		.find_many(vec![file_version::mirror_id::equals(mirror_id)])
+		.order_by(file_version::date_kept::order(
+			prisma_client_rust::Direction::Desc,
+		))
+		.exec()
+		.await?)
+}
+
+/// Writes a version back where the file was, next to it when something took its place
+pub async fn restore_file_version(
+	library: &Library,
+	id: file_version::id::Type,
+) -> Result<(), FileVersionError> {
+	let version = library
+		.db
+		.file_version()
+		.find_unique(file_version::id::equals(id))
+		.exec()
+		.await?
+		.ok_or(FileVersionError::NotFound(id))?;
+
+	let location = find_location(library, version.location_id)
+		.exec()
+		.await?
+		.ok_or(LocationError::IdNotFound(version.location_id))?;
+	let location_path = PathBuf::from(maybe_missing(location.path, "location.path")?);
+
+	let mut target = location_path.join(&version.path);
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.map_err(|e| FileIOError::from((parent, e)))?;
+	}
+	if fs::symlink_metadata(&target).await.is_ok() {
+		target = available_path(&target);
+	}
+
+	let file = fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(&target)
+		.await
+		.map_err(|e| FileIOError::from((&target, e)))?
+		.into_std()
+		.await;
+
+	let restored = library
+		.file_versions
+		.run(move |store| store.get(&version.name, file))
+		.await;
+
+	if restored.is_err() {
+		fs::remove_file(&target).await.ok();
+	}
+
+	restored.map(|_| ())
+}
+
+/// Removes the versions matching `params`, then the chunks no other version needs
+pub(crate) async fn remove_file_versions(
+	library: &Library,
+	params: Vec<file_version::WhereParam>,
+) -> Result<usize, FileVersionError> {
+	let versions = library
+		.db
+		.file_version()
+		.find_many(params)
+		.select(file_version::select!({ id name }))
+		.exec()
+		.await?;
+
+	if versions.is_empty() {
+		return Ok(0);
+	}
+
+	let (ids, names): (Vec<_>, Vec<_>) = versions
+		.into_iter()
+		.map(|version| (version.id, version.name))
+		.unzip();
+
+	library
+		.db
+		.file_version()
+		.delete_many(vec![file_version::id::in_vec(ids)])
+		.exec()
+		.await?;
+
+	let count = names.len();
+	let garbage = library
+		.file_versions
+		.run(move |store| {
+			for name in &names {
+				match store.remove(name) {
+					Ok(()) | Err(ChunkStoreError::ManifestNotFound(_)) => {}
+					Err(e) => return Err(e),
+				}
+			}
+
+			store.collect_garbage()
+		})
+		.await?;
+
+	debug!(
+		"Removed {count} file versions and {} chunks",
+		garbage.removed_chunks
+	);
+
+	invalidate_query!(library, "folderMirrors.versions");
+
+	Ok(count)
+}
+
+/// Removes the versions of a mirror kept before `kept_before`
+pub(crate) async fn prune_file_versions(
+	library: &Library,
+	mirror_id: folder_mirror::id::Type,
+	kept_before: DateTime<Utc>,
+) -> Result<usize, FileVersionError> {
+	remove_file_versions(
+		library,
+		vec![
+			file_version::mirror_id::equals(mirror_id),
+			file_version::date_kept::lt(kept_before.into()),
+		],
+	)
+	.await
+}
@@ -0,0 +1,525 @@
+//! File groups: files which belong together, like a movie with its subtitles and poster, the JPEG a
+//! camera writes along each RAW, or the sidecars of a photo, listed by the explorer as one item.
+//!
+//! Files are grouped within their directory by the rules of the library. A group is led by its
+//! primary file, and its companions are named after it: `Movie.mkv` leads `Movie.srt`,
+//! `Movie.en.srt` and `Movie-poster.jpg`. Some companions are named the same whatever they go with,
+//! like a `poster.jpg`, and only join a primary alone in its directory. Rules are applied in order
+//! and a file is in one group at most, but a later rule may add companions to an earlier group.
+//!
+//! File operations asked to act on whole groups take every member of the groups of their files.
+
+use crate::{
+	extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	location::file_path_helper::file_path_for_grouping,
+	prisma::{file_group, file_group_member, file_path, location, PrismaClient, SortOrder},
+};
+
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use prisma_client_rust::QueryError;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tracing::info;
+
+/// Characters separating the name of a primary from the rest of the name of its companions
+const NAME_SEPARATORS: [char; 2] = ['.', '-'];
+const GROUPS_PER_STEP: usize = 100;
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct FileGroupingRule {
+	pub name: String,
+	/// Extensions of the files leading groups, or every extension but the companion ones when empty
+	pub primary_extensions: Vec<String>,
+	/// Extensions of the files following a primary they're named after
+	pub companion_extensions: Vec<String>,
+	/// Names, without extension, of companions following the only primary of their directory
+	#[serde(default)]
+	pub shared_names: Vec<String>,
+}
+
+impl FileGroupingRule {
+	fn new(
+		name: &str,
+		primary_extensions: &[&str],
+		companion_extensions: &[&str],
+		shared_names: &[&str],
+	) -> Self {
+		let owned = |values: &[&str]| values.iter().map(ToString::to_string).collect();
+
+		Self {
+			name: name.to_string(),
+			primary_extensions: owned(primary_extensions),
+			companion_extensions: owned(companion_extensions),
+			shared_names: owned(shared_names),
+		}
+	}
+
+	fn is_primary(&self, file: &GroupedFile) -> bool {
+		if self.primary_extensions.is_empty() {
+			!contains_ignore_case(&self.companion_extensions, &file.extension)
+		} else {
+			contains_ignore_case(&self.primary_extensions, &file.extension)
+		}
+	}
+
+	fn is_companion(&self, file: &GroupedFile) -> bool {
+		contains_ignore_case(&self.companion_extensions, &file.extension)
+	}
+
+	fn is_shared(&self, file: &GroupedFile) -> bool {
+		contains_ignore_case(&self.shared_names, &file.name)
+	}
+}
+
+/// The rules of new libraries: movies and episodes with their subtitles and artwork, RAW photos
+/// with the JPEG or HEIC shot along them, and the sidecars of any file.
+pub fn default_grouping_rules() -> Vec<FileGroupingRule> {
+	vec![
+		FileGroupingRule::new(
+			"Video with subtitles and artwork",
+			&["mkv", "mp4", "m4v", "avi", "mov", "webm", "wmv", "ts"],
+			&[
+				"srt", "ass", "ssa", "vtt", "sub", "idx", "sup", "nfo", "jpg", "jpeg", "png",
+			],
+			&["poster", "fanart", "folder", "cover", "banner", "landscape"],
+		),
+		FileGroupingRule::new(
+			"RAW with JPEG",
+			&[
+				"cr2", "cr3", "nef", "nrw", "arw", "dng", "raf", "orf", "rw2", "pef", "srw",
+			],
+			&["jpg", "jpeg", "heic", "heif"],
+			&[],
+		),
+		FileGroupingRule::new("Sidecars", &[], &["xmp", "aae", "thm", "pp3", "dop"], &[]),
+	]
+}
+
+fn contains_ignore_case(values: &[String], value: &str) -> bool {
+	values.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+#[derive(Debug, Clone)]
+struct GroupedFile {
+	id: file_path::id::Type,
+	name: String,
+	extension: String,
+}
+
+impl From<file_path_for_grouping::Data> for GroupedFile {
+	fn from(file_path: file_path_for_grouping::Data) -> Self {
+		Self {
+			id: file_path.id,
+			name: file_path.name.unwrap_or_default(),
+			extension: file_path.extension.unwrap_or_default(),
+		}
+	}
+}
+
+/// If `file` is named like `primary`, or like it followed by a separator and anything
+fn is_named_after(file: &GroupedFile, primary: &GroupedFile) -> bool {
+	let (name, primary_name) = (file.name.to_lowercase(), primary.name.to_lowercase());
+
+	name.strip_prefix(&primary_name).map_or(false, |rest| {
+		rest.is_empty() || rest.starts_with(NAME_SEPARATORS)
+	})
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComputedGroup {
+	rule: String,
+	primary: file_path::id::Type,
+	companions: Vec<file_path::id::Type>,
+}
+
+/// Groups the files of a single directory
+fn group_directory(files: &[GroupedFile], rules: &[FileGroupingRule]) -> Vec<ComputedGroup> {
+	let mut groups = Vec::<ComputedGroup>::new();
+	// The group each primary leads, and the files following one
+	let mut leading = HashMap::new();
+	let mut following = HashSet::new();
+
+	for rule in rules {
+		let primaries = files
+			.iter()
+			.filter(|file| !file.name.is_empty() && rule.is_primary(file))
+			.collect::<Vec<_>>();
+
+		for file in files {
+			if file.name.is_empty()
+				|| leading.contains_key(&file.id)
+				|| following.contains(&file.id)
+				|| !rule.is_companion(file)
+			{
+				continue;
+			}
+
+			let candidates = primaries
+				.iter()
+				.filter(|primary| primary.id != file.id && !following.contains(&primary.id))
+				.collect::<Vec<_>>();
+
+			// `Movie.Part2.srt` follows `Movie.Part2.mkv` rather than `Movie.mkv`
+			let primary = candidates
+				.iter()
+				.filter(|primary| is_named_after(file, primary))
+				.max_by_key(|primary| primary.name.len())
+				.or_else(|| {
+					(candidates.len() == 1 && rule.is_shared(file)).then(|| &candidates[0])
+				});
+
+			let Some(primary) = primary else {
+				continue;
+			};
+
+			following.insert(file.id);
+
+			match leading.get(&primary.id) {
+				Some(&index) => groups[index].companions.push(file.id),
+				None => {
+					leading.insert(primary.id, groups.len());
+					groups.push(ComputedGroup {
+						rule: rule.name.clone(),
+						primary: primary.id,
+						companions: vec![file.id],
+					});
+				}
+			}
+		}
+	}
+
+	groups
+}
+
+pub struct FileGrouperJob {}
+
+/// `FileGrouperJobInit` regroups the files of a location by the grouping rules of the library
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct FileGrouperJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for FileGrouperJobInit {
+	type Job = FileGrouperJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileGrouperJobReport {
+	groups_count: usize,
+	files_grouped: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for FileGrouperJob {
+	type Init = FileGrouperJobInit;
+	type Data = FileGrouperJobReport;
+	type Step = Vec<ComputedGroup>;
+
+	const NAME: &'static str = "file_grouper";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+		let location_id = state.init.location_id;
+
+		db._batch((
+			db.file_group_member()
+				.delete_many(vec![file_group_member::file_path::is(vec![
+					file_path::location_id::equals(Some(location_id)),
+				])]),
+			// Along the groups of this location, the ones emptied by deleted files elsewhere
+			db.file_group()
+				.delete_many(vec![file_group::members::none(vec![])]),
+		))
+		.await?;
+
+		let file_paths = db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(location_id)),
+				file_path::is_dir::equals(Some(false)),
+			])
+			.order_by(file_path::materialized_path::order(SortOrder::Asc))
+			.select(file_path_for_grouping::select())
+			.exec()
+			.await?;
+
+		let rules = &ctx.library.config.file_grouping_rules;
+
+		state.steps = file_paths
+			.into_iter()
+			.group_by(|file_path| file_path.materialized_path.clone())
+			.into_iter()
+			.flat_map(|(_, directory)| {
+				group_directory(&directory.map(GroupedFile::from).collect::<Vec<_>>(), rules)
+			})
+			.chunks(GROUPS_PER_STEP)
+			.into_iter()
+			.map(Iterator::collect)
+			.collect();
+
+		state.data = Some(FileGrouperJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let db = &ctx.library.db;
+		let groups = &state.steps[0];
+
+		let mut members = vec![];
+		for group in groups {
+			let created = db
+				.file_group()
+				.create(group.rule.clone(), vec![])
+				.select(file_group::select!({ id }))
+				.exec()
+				.await?;
+
+			members.push(file_group_member::create_unchecked(
+				group.primary,
+				created.id,
+				vec![file_group_member::is_primary::set(true)],
+			));
+			members.extend(group.companions.iter().map(|companion| {
+				file_group_member::create_unchecked(*companion, created.id, vec![])
+			}));
+		}
+
+		let files_grouped = members.len();
+		db.file_group_member().create_many(members).exec().await?;
+
+		let report = extract_job_data_mut!(state);
+		report.groups_count += groups.len();
+		report.files_grouped += files_grouped;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data_mut!(state);
+
+		info!(
+			"Grouped {} files in {} groups in location {}",
+			report.files_grouped, report.groups_count, state.init.location_id
+		);
+
+		invalidate_query!(ctx.library, "search.paths");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Leaves out the companions of groups which still have their primary, so each group is listed as
+/// its primary file
+pub fn companions_hidden() -> file_path::WhereParam {
+	file_path::group_member::is_not(vec![
+		file_group_member::is_primary::equals(false),
+		file_group_member::group::is(vec![file_group::members::some(vec![
+			file_group_member::is_primary::equals(true),
+		])]),
+	])
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct FileGroupMemberSummary {
+	pub file_path_id: file_path::id::Type,
+	pub name: Option<String>,
+	pub extension: Option<String>,
+	pub is_primary: bool,
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct FileGroupSummary {
+	pub id: file_group::id::Type,
+	pub rule: String,
+	/// The primary first
+	pub members: Vec<FileGroupMemberSummary>,
+}
+
+file_group::include!(file_group_with_members {
+	members: include { file_path: select { name extension } }
+});
+
+/// The groups of the file paths which are in one
+pub async fn groups_of(
+	db: &PrismaClient,
+	file_path_ids: Vec<file_path::id::Type>,
+) -> Result<HashMap<file_path::id::Type, FileGroupSummary>, QueryError> {
+	let groups = db
+		.file_group()
+		.find_many(vec![file_group::members::some(vec![
+			file_group_member::file_path_id::in_vec(file_path_ids.clone()),
+		])])
+		.include(file_group_with_members::include())
+		.exec()
+		.await?;
+
+	let requested = file_path_ids.into_iter().collect::<HashSet<_>>();
+
+	Ok(groups
+		.into_iter()
+		.flat_map(|group| {
+			let mut members = group
+				.members
+				.into_iter()
+				.map(|member| FileGroupMemberSummary {
+					file_path_id: member.file_path_id,
+					name: member.file_path.name,
+					extension: member.file_path.extension,
+					is_primary: member.is_primary,
+				})
+				.collect::<Vec<_>>();
+			members.sort_by_key(|member| (!member.is_primary, member.file_path_id));
+
+			let summary = FileGroupSummary {
+				id: group.id,
+				rule: group.rule,
+				members,
+			};
+
+			summary
+				.members
+				.iter()
+				.map(|member| member.file_path_id)
+				.filter(|file_path_id| requested.contains(file_path_id))
+				.map(|file_path_id| (file_path_id, summary.clone()))
+				.collect::<Vec<_>>()
+		})
+		.collect())
+}
+
+/// These file paths followed by the others of their groups
+pub async fn with_group_members(
+	db: &PrismaClient,
+	file_path_ids: &[file_path::id::Type],
+) -> Result<Vec<file_path::id::Type>, QueryError> {
+	let members = db
+		.file_group_member()
+		.find_many(vec![file_group_member::group::is(vec![
+			file_group::members::some(vec![file_group_member::file_path_id::in_vec(
+				file_path_ids.to_vec(),
+			)]),
+		])])
+		.select(file_group_member::select!({ file_path_id }))
+		.exec()
+		.await?;
+
+	Ok(file_path_ids
+		.iter()
+		.copied()
+		.chain(members.into_iter().map(|member| member.file_path_id))
+		.unique()
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn files(names: &[&str]) -> Vec<GroupedFile> {
+		names
+			.iter()
+			.enumerate()
+			.map(|(id, name)| {
+				let (name, extension) = name.rsplit_once('.').unwrap_or((*name, ""));
+				GroupedFile {
+					id: id as file_path::id::Type,
+					name: name.to_string(),
+					extension: extension.to_string(),
+				}
+			})
+			.collect()
+	}
+
+	fn group(rule: &str, primary: i32, companions: &[i32]) -> ComputedGroup {
+		ComputedGroup {
+			rule: rule.to_string(),
+			primary,
+			companions: companions.to_vec(),
+		}
+	}
+
+	#[test]
+	fn movie_with_subtitles_and_poster() {
+		let groups = group_directory(
+			&files(&[
+				"Movie.mkv",
+				"Movie.en.srt",
+				"Movie.Part2.mkv",
+				"Movie.Part2.srt",
+				"poster.jpg",
+				"notes.txt",
+			]),
+			&default_grouping_rules(),
+		);
+
+		// The poster is left alone, as it can't be told which movie it's for
+		assert_eq!(
+			groups,
+			[
+				group("Video with subtitles and artwork", 0, &[1]),
+				group("Video with subtitles and artwork", 2, &[3]),
+			]
+		);
+
+		let groups = group_directory(
+			&files(&["Movie.mkv", "Movie.srt", "Poster.jpg"]),
+			&default_grouping_rules(),
+		);
+		assert_eq!(
+			groups,
+			[group("Video with subtitles and artwork", 0, &[1, 2])]
+		);
+	}
+
+	#[test]
+	fn raw_pairs_take_their_sidecars() {
+		let groups = group_directory(
+			&files(&[
+				"IMG_0001.CR2",
+				"IMG_0001.JPG",
+				"IMG_0001.CR2.xmp",
+				"IMG_0002.jpg",
+				"IMG_0002.xmp",
+				"IMG_00021.jpg",
+			]),
+			&default_grouping_rules(),
+		);
+
+		assert_eq!(
+			groups,
+			[
+				group("RAW with JPEG", 0, &[1, 2]),
+				group("Sidecars", 3, &[4]),
+			]
+		);
+	}
+}
@@ -0,0 +1,30 @@
+use prisma_client_rust::QueryError;
+use uuid::Uuid;
+
+use crate::prisma::{label, PrismaClient};
+
+/// Finds a label by its name, creating it if there is none. Also tells if it had to be created.
+pub async fn find_or_create_label(
+	db: &PrismaClient,
+	name: &str,
+) -> Result<(label::id::Type, bool), QueryError> {
+	if let Some(label) = db
+		.label()
+		.find_first(vec![label::name::equals(Some(name.to_string()))])
+		.exec()
+		.await?
+	{
+		return Ok((label.id, false));
+	}
+
+	let label = db
+		.label()
+		.create(
+			Uuid::new_v4().as_bytes().to_vec(),
+			vec![label::name::set(Some(name.to_string()))],
+		)
+		.exec()
+		.await?;
+
+	Ok((label.id, true))
+}
@@ -0,0 +1,140 @@
+//! mbox files, one message after another, each starting with a `From ` line. The `From ` lines of
+//! the messages themselves were escaped with a `>`, which is removed, as mboxrd readers do.
+
+use std::io::{self, BufRead};
+
+/// Messages bigger than this are cut, they're attachments past what's worth indexing
+const MAX_MESSAGE_SIZE: usize = 128 * 1024 * 1024;
+
+pub struct RawMessage {
+	/// Where the `From ` line of the message starts in the file
+	pub offset: u64,
+	pub data: Vec<u8>,
+}
+
+pub struct MboxReader<R> {
+	reader: R,
+	/// How far the reader went
+	position: u64,
+	/// The offset of the `From ` line already read for the next message
+	next_offset: Option<u64>,
+	line: Vec<u8>,
+}
+
+impl<R: BufRead> MboxReader<R> {
+	pub fn new(reader: R) -> Self {
+		Self {
+			reader,
+			position: 0,
+			next_offset: None,
+			line: vec![],
+		}
+	}
+
+	fn read_line(&mut self) -> io::Result<bool> {
+		self.line.clear();
+		let read = self.reader.read_until(b'\n', &mut self.line)?;
+		self.position += read as u64;
+
+		Ok(read > 0)
+	}
+
+	fn next_message(&mut self) -> io::Result<Option<RawMessage>> {
+		let offset = match self.next_offset.take() {
+			Some(offset) => offset,
+			None => loop {
+				// Whatever comes before the first `From ` line isn't a message
+				let offset = self.position;
+				if !self.read_line()? {
+					return Ok(None);
+				}
+				if self.line.starts_with(b"From ") {
+					break offset;
+				}
+			},
+		};
+
+		let mut data = Vec::new();
+		let mut previous_blank = false;
+
+		loop {
+			let line_offset = self.position;
+			if !self.read_line()? {
+				break;
+			}
+
+			if previous_blank && self.line.starts_with(b"From ") {
+				self.next_offset = Some(line_offset);
+				break;
+			}
+
+			previous_blank = matches!(self.line.as_slice(), b"\n" | b"\r\n");
+
+			if data.len() < MAX_MESSAGE_SIZE {
+				let line = if is_escaped_from_line(&self.line) {
+					&self.line[1..]
+				} else {
+					&self.line[..]
+				};
+				data.extend_from_slice(line);
+			}
+		}
+
+		// The blank line before the next `From ` line separates the messages
+		if data.ends_with(b"\r\n\r\n") {
+			data.truncate(data.len() - 2);
+		} else if data.ends_with(b"\n\n") {
+			data.pop();
+		}
+
+		Ok(Some(RawMessage { offset, data }))
+	}
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+	type Item = io::Result<RawMessage>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_message().transpose()
+	}
+}
+
+fn is_escaped_from_line(line: &[u8]) -> bool {
+	let unquoted = line.iter().position(|byte| *byte != b'>').unwrap_or(0);
+	unquoted > 0 && line[unquoted..].starts_with(b"From ")
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn split_messages() {
+		let mbox = "From alice@example.com Tue Jul  4 10:52:37 2023\n\
+			Subject: first\n\
+			\n\
+			>From the start, it was fine.\n\
+			\n\
+			From bob@example.com Tue Jul  4 11:00:00 2023\n\
+			Subject: second\n\
+			\n\
+			bye\n";
+
+		let messages = MboxReader::new(mbox.as_bytes())
+			.collect::<Result<Vec<_>, _>>()
+			.unwrap();
+
+		assert_eq!(messages.len(), 2);
+		assert_eq!(messages[0].offset, 0);
+		assert_eq!(
+			String::from_utf8_lossy(&messages[0].data),
+			"Subject: first\n\nFrom the start, it was fine.\n"
+		);
+		assert_eq!(messages[1].offset, mbox.find("From bob").unwrap() as u64);
+		assert_eq!(
+			String::from_utf8_lossy(&messages[1].data),
+			"Subject: second\n\nbye\n"
+		);
+	}
+}
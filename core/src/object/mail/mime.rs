@@ -0,0 +1,203 @@
+//! A few headers of a message, and the names and sizes of its attachments, as indexed by the mail
+//! indexer. The parsing itself is left to `mail-parser`.
+
+use chrono::{DateTime, FixedOffset};
+use mail_parser::{Addr, Group, HeaderValue, Message, MessagePart, MimeHeaders, PartType};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ParsedMessage {
+	pub message_id: Option<String>,
+	pub subject: Option<String>,
+	pub sender: Option<String>,
+	pub recipients: Option<String>,
+	pub date: Option<DateTime<FixedOffset>>,
+	pub attachments: Vec<ParsedAttachment>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsedAttachment {
+	pub name: String,
+	pub mime_type: Option<String>,
+	pub size: u64,
+}
+
+pub fn parse_message(raw: &[u8]) -> ParsedMessage {
+	let Some(message) = Message::parse(raw) else {
+		return ParsedMessage::default();
+	};
+
+	let recipients = [message.to(), message.cc()]
+		.into_iter()
+		.filter_map(format_addresses)
+		.collect::<Vec<_>>();
+
+	ParsedMessage {
+		message_id: message
+			.message_id()
+			.filter(|id| !id.is_empty())
+			.map(str::to_string),
+		subject: message.subject().map(str::to_string),
+		sender: format_addresses(message.from()),
+		recipients: (!recipients.is_empty()).then(|| recipients.join(", ")),
+		date: message
+			.date()
+			.and_then(|date| DateTime::parse_from_rfc3339(&date.to_rfc3339()).ok()),
+		// Parts nested in multiparts are flattened by the parser, while attached messages are
+		// kept whole, like any other attachment
+		attachments: message.parts.iter().filter_map(attachment).collect(),
+	}
+}
+
+fn attachment(part: &MessagePart) -> Option<ParsedAttachment> {
+	if let PartType::Multipart(_) = part.body {
+		return None;
+	}
+
+	let name = match part.attachment_name() {
+		Some(name) if !name.is_empty() => name.to_string(),
+		_ if part
+			.content_disposition()
+			.map_or(false, |disposition| disposition.is_attachment()) =>
+		{
+			"attachment".to_string()
+		}
+		_ => return None,
+	};
+
+	Some(ParsedAttachment {
+		name,
+		mime_type: Some(part.content_type().map_or_else(
+			|| "text/plain".to_string(),
+			|content_type| match content_type.subtype() {
+				Some(subtype) => format!("{}/{subtype}", content_type.ctype()),
+				None => content_type.ctype().to_string(),
+			},
+		)),
+		size: part.contents().len() as u64,
+	})
+}
+
+fn format_addresses(value: &HeaderValue) -> Option<String> {
+	let addresses = match value {
+		HeaderValue::Address(addr) => vec![format_address(addr)],
+		HeaderValue::AddressList(addrs) => addrs.iter().map(format_address).collect(),
+		HeaderValue::Group(group) => format_group(group),
+		HeaderValue::GroupList(groups) => groups.iter().flat_map(format_group).collect(),
+		HeaderValue::Text(text) => vec![text.to_string()],
+		_ => vec![],
+	};
+
+	let addresses = addresses
+		.into_iter()
+		.filter(|address| !address.is_empty())
+		.collect::<Vec<_>>();
+
+	(!addresses.is_empty()).then(|| addresses.join(", "))
+}
+
+/// The members of a group, or its name when they aren't disclosed
+fn format_group(group: &Group) -> Vec<String> {
+	if group.addresses.is_empty() {
+		return group.name.iter().map(|name| name.to_string()).collect();
+	}
+
+	group.addresses.iter().map(format_address).collect()
+}
+
+fn format_address(addr: &Addr) -> String {
+	match (addr.name.as_deref(), addr.address.as_deref()) {
+		(Some(name), Some(address)) => format!("{name} <{address}>"),
+		(Some(value), None) | (None, Some(value)) => value.to_string(),
+		(None, None) => String::new(),
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	const MESSAGE: &str = "From: =?UTF-8?Q?Ren=C3=A9e?= <renee@example.com>\r
+To: team@example.com\r
+Cc: =?ISO-8859-1?B?Sm/jbw==?= <joao@example.com>\r
+Subject: =?UTF-8?B?UXVhcnRlcmx5?=\r
+ =?UTF-8?B?IHJlcG9ydA==?= attached\r
+Date: Tue, 4 Jul 2023 10:52:37 +0200 (CEST)\r
+Message-ID: <1234@example.com>\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+This is a multi-part message in MIME format.\r
+--outer\r
+Content-Type: multipart/alternative; boundary=inner\r
+\r
+--inner\r
+Content-Type: text/plain\r
+\r
+See attached.\r
+--inner\r
+Content-Type: text/html\r
+\r
+<p>See attached.</p>\r
+--inner--\r
+--outer\r
+Content-Type: application/pdf; name=\"report.pdf\"\r
+Content-Disposition: attachment;\r
+ filename*=utf-8''Q2%20r%C3%A9sum%C3%A9.pdf\r
+Content-Transfer-Encoding: base64\r
+\r
+aGVsbG8g\r
+d29ybGQ=\r
+--outer\r
+Content-Type: text/csv\r
+Content-Disposition: attachment; filename=\"numbers.csv\"\r
+\r
+1,2,3\r
+--outer--\r
+";
+
+	#[test]
+	fn headers_and_attachments() {
+		let message = parse_message(MESSAGE.as_bytes());
+
+		assert_eq!(message.message_id.as_deref(), Some("1234@example.com"));
+		assert_eq!(
+			message.subject.as_deref(),
+			Some("Quarterly report attached")
+		);
+		assert_eq!(message.sender.as_deref(), Some("Renée <renee@example.com>"));
+		assert_eq!(
+			message.recipients.as_deref(),
+			Some("team@example.com, João <joao@example.com>")
+		);
+		assert_eq!(
+			message.date,
+			DateTime::parse_from_rfc3339("2023-07-04T10:52:37+02:00").ok()
+		);
+
+		assert_eq!(
+			message.attachments,
+			[
+				ParsedAttachment {
+					name: "Q2 résumé.pdf".to_string(),
+					mime_type: Some("application/pdf".to_string()),
+					size: 11,
+				},
+				ParsedAttachment {
+					name: "numbers.csv".to_string(),
+					mime_type: Some("text/csv".to_string()),
+					size: 5,
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn plain_message() {
+		let message = parse_message(b"Subject: hello\n\nno attachments here\n");
+
+		assert_eq!(message.subject.as_deref(), Some("hello"));
+		assert!(message.sender.is_none());
+		assert!(message.attachments.is_empty());
+	}
+}
@@ -0,0 +1,427 @@
+//! Mail exports indexed message by message, so an attachment lost in a decade of mail can be found
+//! by its name, the sender or the subject of the message carrying it. Messages stay inside their
+//! archive, only their headers and the names of their attachments are kept in the library.
+//!
+//! mbox files (`.mbox`, and the `.mbx` of Eudora and old Outlook Express exports), single `.eml`
+//! messages and Outlook `.pst` and `.ost` stores are read. The messages of an Outlook store are
+//! known by their node in it instead of where they start in the file.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{
+			ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
+			file_path_for_mail_indexer, FilePathError, IsolatedFilePathData,
+		},
+		find_location, LocationError,
+	},
+	prisma::{
+		file_path, location, mail_archive, mail_attachment, mail_message, PrismaClient, SortOrder,
+	},
+	util::{
+		db::{chain_optional_iter, maybe_missing, MissingFieldError},
+		error::FileIOError,
+	},
+};
+
+use std::{
+	fs::File,
+	hash::Hash,
+	io::{BufReader, Read},
+	path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use prisma_client_rust::{or, QueryError};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::task::{spawn_blocking, JoinError};
+use tracing::{debug, info};
+
+mod mbox;
+mod mime;
+mod pst;
+
+use mbox::{MboxReader, RawMessage};
+use mime::{parse_message, ParsedMessage};
+use pst::PstReader;
+
+const MBOX_EXTENSIONS: [&str; 2] = ["mbox", "mbx"];
+const EML_EXTENSION: &str = "eml";
+/// Outlook stores, read by [`PstReader`]
+const OUTLOOK_EXTENSIONS: [&str; 2] = ["pst", "ost"];
+/// Messages are written a chunk at a time, an archive can hold hundreds of thousands of them
+const MESSAGES_PER_BATCH: usize = 500;
+
+#[derive(Error, Debug)]
+pub enum MailError {
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error(transparent)]
+	FilePath(#[from] FilePathError),
+	#[error(transparent)]
+	MissingField(#[from] MissingFieldError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+	#[error("failed to join tokio spawn blocking: {0}")]
+	JoinTask(#[from] JoinError),
+}
+
+pub struct MailIndexerJob {}
+
+/// `MailIndexerJobInit` indexes the messages of the mail archives of a location, or of the ones
+/// below `sub_path`. Archives which didn't change since they were last indexed are skipped.
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct MailIndexerJobInit {
+	pub location_id: location::id::Type,
+	pub sub_path: Option<PathBuf>,
+}
+
+impl JobInitData for MailIndexerJobInit {
+	type Job = MailIndexerJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MailIndexerJobReport {
+	archives_count: usize,
+	messages_count: usize,
+	attachments_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MailIndexerJobData {
+	location_path: PathBuf,
+	report: MailIndexerJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for MailIndexerJob {
+	type Init = MailIndexerJobInit;
+	type Data = MailIndexerJobData;
+	type Step = file_path_for_mail_indexer::Data;
+
+	const NAME: &'static str = "mail_indexer";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+
+		let location = find_location(&ctx.library, location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(location_id))?;
+		let location_path = PathBuf::from(maybe_missing(location.path, "location.path")?);
+
+		let materialized_path_for_children = if let Some(ref sub_path) = state.init.sub_path {
+			let full_path = ensure_sub_path_is_in_location(&location_path, sub_path)
+				.await
+				.map_err(LocationError::from)?;
+			ensure_sub_path_is_directory(&location_path, sub_path)
+				.await
+				.map_err(LocationError::from)?;
+
+			IsolatedFilePathData::new(location_id, &location_path, full_path, true)
+				.map_err(LocationError::from)?
+				.normalized(ctx.library.config.file_name_normalization)
+				.materialized_path_for_children()
+		} else {
+			None
+		};
+
+		let archives = ctx
+			.library
+			.db
+			.file_path()
+			.find_many(chain_optional_iter(
+				[
+					file_path::location_id::equals(Some(location_id)),
+					file_path::is_dir::equals(Some(false)),
+					file_path::extension::in_vec(
+						MBOX_EXTENSIONS
+							.iter()
+							.chain(&[EML_EXTENSION])
+							.chain(&OUTLOOK_EXTENSIONS)
+							.flat_map(|extension| [extension.to_string(), extension.to_uppercase()])
+							.map(Some)
+							.collect(),
+					),
+				],
+				[materialized_path_for_children.map(file_path::materialized_path::starts_with)],
+			))
+			.select(file_path_for_mail_indexer::select())
+			.exec()
+			.await?;
+
+		state.steps = archives
+			.into_iter()
+			.filter(|file_path| {
+				file_path.date_modified.is_none()
+					|| file_path.mail_archive.as_ref().map_or(true, |archive| {
+						archive.date_modified != file_path.date_modified
+					})
+			})
+			.collect();
+
+		state.data = Some(MailIndexerJobData {
+			location_path,
+			report: Default::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+		let file_path = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		let result = index_archive(
+			&ctx.library.db,
+			(location_id, &data.location_path),
+			file_path,
+		)
+		.await;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		match result {
+			Ok((messages_count, attachments_count)) => {
+				data.report.archives_count += 1;
+				data.report.messages_count += messages_count;
+				data.report.attachments_count += attachments_count;
+
+				Ok(())
+			}
+			Err(e) => Err(JobError::StepCompletedWithErrors(vec![e.to_string()])),
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing mail indexer job: {report:?}");
+
+		invalidate_query!(ctx.library, "search.mail");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Reads the messages of an archive, replacing the ones indexed before. Returns how many messages
+/// and attachments were found.
+async fn index_archive(
+	db: &PrismaClient,
+	(location_id, location_path): (location::id::Type, &Path),
+	file_path: &file_path_for_mail_indexer::Data,
+) -> Result<(usize, usize), MailError> {
+	let full_path = location_path.join(IsolatedFilePathData::try_from((location_id, file_path))?);
+	let extension = maybe_missing(&file_path.extension, "file_path.extension")?.to_lowercase();
+
+	let messages = spawn_blocking({
+		let full_path = full_path.clone();
+		move || read_archive(&full_path, &extension)
+	})
+	.await??;
+
+	debug!(
+		"Indexing {} messages of mail archive '{}'",
+		messages.len(),
+		full_path.display()
+	);
+
+	db.mail_archive()
+		.delete_many(vec![mail_archive::file_path_id::equals(file_path.id)])
+		.exec()
+		.await?;
+
+	let archive = db
+		.mail_archive()
+		.create(
+			file_path::id::equals(file_path.id),
+			vec![
+				mail_archive::date_modified::set(file_path.date_modified),
+				mail_archive::date_indexed::set(Some(Utc::now().into())),
+			],
+		)
+		.exec()
+		.await?;
+
+	let messages_count = messages.len();
+	let mut attachments_count = 0;
+
+	for chunk in messages.chunks(MESSAGES_PER_BATCH) {
+		let created = db
+			._batch(
+				chunk
+					.iter()
+					.map(|(offset, message)| {
+						db.mail_message().create(
+							mail_archive::id::equals(archive.id),
+							*offset as i64,
+							vec![
+								mail_message::message_id::set(message.message_id.clone()),
+								mail_message::subject::set(message.subject.clone()),
+								mail_message::sender::set(message.sender.clone()),
+								mail_message::recipients::set(message.recipients.clone()),
+								mail_message::date::set(message.date),
+							],
+						)
+					})
+					// Same workaround as in `get_many_files_datas` for the lifetimes of `_batch`
+					.collect::<Vec<_>>(),
+			)
+			.await?;
+
+		let attachments = created
+			.iter()
+			.zip(chunk)
+			.flat_map(|(created, (_, message))| {
+				message.attachments.iter().map(|attachment| {
+					mail_attachment::create_unchecked(
+						created.id,
+						attachment.name.clone(),
+						attachment.size.to_string(),
+						vec![
+							mail_attachment::extension::set(
+								Path::new(&attachment.name)
+									.extension()
+									.map(|extension| extension.to_string_lossy().to_lowercase()),
+							),
+							mail_attachment::mime_type::set(attachment.mime_type.clone()),
+						],
+					)
+				})
+			})
+			.collect::<Vec<_>>();
+
+		attachments_count += attachments.len();
+
+		if !attachments.is_empty() {
+			db.mail_attachment().create_many(attachments).exec().await?;
+		}
+	}
+
+	Ok((messages_count, attachments_count))
+}
+
+/// The headers and attachments of every message of an archive, with where each message starts, or
+/// its node for Outlook stores
+fn read_archive(path: &Path, extension: &str) -> Result<Vec<(u64, ParsedMessage)>, MailError> {
+	let file = File::open(path).map_err(|e| FileIOError::from((path, e)))?;
+
+	if extension == EML_EXTENSION {
+		let mut raw = vec![];
+		BufReader::new(file)
+			.read_to_end(&mut raw)
+			.map_err(|e| FileIOError::from((path, e)))?;
+
+		return Ok(vec![(0, parse_message(&raw))]);
+	}
+
+	if OUTLOOK_EXTENSIONS.contains(&extension) {
+		return PstReader::new(BufReader::new(file))
+			.and_then(|mut store| store.messages())
+			.map_err(|e| FileIOError::from((path, e)).into());
+	}
+
+	MboxReader::new(BufReader::new(file))
+		.map(|message| {
+			message
+				.map(|RawMessage { offset, data }| (offset, parse_message(&data)))
+				.map_err(|e| FileIOError::from((path, e)).into())
+		})
+		.collect()
+}
+
+mail_message::include!(mail_message_for_search {
+	attachments
+	archive: select {
+		file_path: select { id location_id materialized_path name extension }
+	}
+});
+
+#[derive(Deserialize, Type, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MailSearchArgs {
+	/// Words to find in the subject, the sender or the name of an attachment
+	#[serde(default)]
+	pub search: String,
+	#[specta(optional)]
+	pub location_id: Option<location::id::Type>,
+	/// Only messages with attachments, the ones matching the search if it matched one
+	#[serde(default)]
+	pub attachments_only: bool,
+	#[specta(optional)]
+	pub take: Option<i32>,
+}
+
+/// Messages matching every word of the search, newest first. `visible` restricts the archives they
+/// come from, to keep private locations out of the results.
+pub async fn search_messages(
+	library: &Library,
+	args: MailSearchArgs,
+	visible: Option<file_path::WhereParam>,
+) -> Result<Vec<mail_message_for_search::Data>, QueryError> {
+	let words = args.search.split_whitespace().collect::<Vec<_>>();
+
+	let archive_file_path = args
+		.location_id
+		.map(|location_id| file_path::location_id::equals(Some(location_id)))
+		.into_iter()
+		.chain(visible)
+		.collect();
+
+	let params = chain_optional_iter(
+		words.iter().map(|word| {
+			or![
+				mail_message::subject::contains(word.to_string()),
+				mail_message::sender::contains(word.to_string()),
+				mail_message::attachments::some(vec![mail_attachment::name::contains(
+					word.to_string()
+				)]),
+			]
+		}),
+		[
+			Some(mail_message::archive::is(vec![
+				mail_archive::file_path::is(archive_file_path),
+			])),
+			args.attachments_only
+				.then(|| mail_message::attachments::some(vec![])),
+		],
+	);
+
+	library
+		.db
+		.mail_message()
+		.find_many(params)
+		.order_by(mail_message::date::order(SortOrder::Desc))
+		.take(args.take.unwrap_or(100) as i64)
+		.include(mail_message_for_search::include())
+		.exec()
+		.await
+}
@@ -0,0 +1,956 @@
+//! Outlook stores, `.pst` files and the `.ost` caches of the Outlook versions sharing their format,
+//! as described in `[MS-PST]`, from which the names of the structures below come. Only what the
+//! mail indexer keeps is read: messages are found in the node B-tree of the store instead of by
+//! walking its folders, and their attachments in the subnodes of each message. The 4K page stores
+//! of the caches of recent Outlook versions aren't supported.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	io::{self, Read, Seek, SeekFrom},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use tracing::warn;
+
+use super::mime::{ParsedAttachment, ParsedMessage};
+
+/// `dwMagic`, followed by the CRC of the header and `wMagicClient`
+const HEADER_MAGIC: &[u8; 4] = b"!BDN";
+/// Enough of the header to reach `bCryptMethod` whatever the format
+const HEADER_SIZE: u64 = 514;
+
+const PAGE_SIZE: usize = 512;
+const PAGE_TYPE_BLOCK_BTREE: u8 = 0x80;
+const PAGE_TYPE_NODE_BTREE: u8 = 0x81;
+
+/// Set in the ids of the blocks which list other blocks, those aren't encoded
+const BID_INTERNAL: u64 = 0x2;
+const BLOCK_TYPE_DATA_TREE: u8 = 0x01;
+const BLOCK_TYPE_SUB_NODE_TREE: u8 = 0x02;
+
+/// The type of a node is kept in the low bits of its id, heap allocations share the same space
+const NID_TYPE_MASK: u32 = 0x1F;
+const NID_TYPE_HID: u32 = 0x00;
+const NID_TYPE_NORMAL_MESSAGE: u32 = 0x04;
+const NID_TYPE_ATTACHMENT: u32 = 0x05;
+
+const HEAP_SIGNATURE: u8 = 0xEC;
+const HEAP_CLIENT_PROPERTY_CONTEXT: u8 = 0xBC;
+const HEAP_TREE_SIGNATURE: u8 = 0xB5;
+
+const PT_INTEGER32: u16 = 0x0003;
+const PT_STRING8: u16 = 0x001E;
+const PT_UNICODE: u16 = 0x001F;
+const PT_SYSTIME: u16 = 0x0040;
+
+const PID_TAG_SUBJECT: u16 = 0x0037;
+const PID_TAG_CLIENT_SUBMIT_TIME: u16 = 0x0039;
+const PID_TAG_SENT_REPRESENTING_NAME: u16 = 0x0042;
+const PID_TAG_SENT_REPRESENTING_EMAIL_ADDRESS: u16 = 0x0065;
+const PID_TAG_SENDER_NAME: u16 = 0x0C1A;
+const PID_TAG_SENDER_EMAIL_ADDRESS: u16 = 0x0C1F;
+const PID_TAG_DISPLAY_CC: u16 = 0x0E03;
+const PID_TAG_DISPLAY_TO: u16 = 0x0E04;
+const PID_TAG_MESSAGE_DELIVERY_TIME: u16 = 0x0E06;
+const PID_TAG_ATTACH_SIZE: u16 = 0x0E20;
+const PID_TAG_INTERNET_MESSAGE_ID: u16 = 0x1035;
+const PID_TAG_DISPLAY_NAME: u16 = 0x3001;
+const PID_TAG_ATTACH_FILENAME: u16 = 0x3704;
+const PID_TAG_ATTACH_LONG_FILENAME: u16 = 0x3707;
+const PID_TAG_ATTACH_MIME_TAG: u16 = 0x370E;
+const PID_TAG_SENDER_SMTP_ADDRESS: u16 = 0x5D01;
+const PID_TAG_SENT_REPRESENTING_SMTP_ADDRESS: u16 = 0x5D02;
+
+/// Seconds between 1601-01-01, where `FILETIME`s start, and the Unix epoch
+const FILETIME_UNIX_EPOCH: i64 = 11_644_473_600;
+
+/// The three substitution tables of the encodings of `[MS-PST]` 5.1, the last one reversing the
+/// first one
+#[rustfmt::skip]
+const ENCODING_TABLES: [u8; 768] = [
+	0x41, 0x36, 0x13, 0x62, 0xA8, 0x21, 0x6E, 0xBB, 0xF4, 0x16, 0xCC, 0x04, 0x7F, 0x64, 0xE8, 0x5D,
+	0x1E, 0xF2, 0xCB, 0x2A, 0x74, 0xC5, 0x5E, 0x35, 0xD2, 0x95, 0x47, 0x9E, 0x96, 0x2D, 0x9A, 0x88,
+	0x4C, 0x7D, 0x84, 0x3F, 0xDB, 0xAC, 0x31, 0xB6, 0x48, 0x5F, 0xF6, 0xC4, 0xD8, 0x39, 0x8B, 0xE7,
+	0x23, 0x3B, 0x38, 0x8E, 0xC8, 0xC1, 0xDF, 0x25, 0xB1, 0x20, 0xA5, 0x46, 0x60, 0x4E, 0x9C, 0xFB,
+	0xAA, 0xD3, 0x56, 0x51, 0x45, 0x7C, 0x55, 0x00, 0x07, 0xC9, 0x2B, 0x9D, 0x85, 0x9B, 0x09, 0xA0,
+	0x8F, 0xAD, 0xB3, 0x0F, 0x63, 0xAB, 0x89, 0x4B, 0xD7, 0xA7, 0x15, 0x5A, 0x71, 0x66, 0x42, 0xBF,
+	0x26, 0x4A, 0x6B, 0x98, 0xFA, 0xEA, 0x77, 0x53, 0xB2, 0x70, 0x05, 0x2C, 0xFD, 0x59, 0x3A, 0x86,
+	0x7E, 0xCE, 0x06, 0xEB, 0x82, 0x78, 0x57, 0xC7, 0x8D, 0x43, 0xAF, 0xB4, 0x1C, 0xD4, 0x5B, 0xCD,
+	0xE2, 0xE9, 0x27, 0x4F, 0xC3, 0x08, 0x72, 0x80, 0xCF, 0xB0, 0xEF, 0xF5, 0x28, 0x6D, 0xBE, 0x30,
+	0x4D, 0x34, 0x92, 0xD5, 0x0E, 0x3C, 0x22, 0x32, 0xE5, 0xE4, 0xF9, 0x9F, 0xC2, 0xD1, 0x0A, 0x81,
+	0x12, 0xE1, 0xEE, 0x91, 0x83, 0x76, 0xE3, 0x97, 0xE6, 0x61, 0x8A, 0x17, 0x79, 0xA4, 0xB7, 0xDC,
+	0x90, 0x7A, 0x5C, 0x8C, 0x02, 0xA6, 0xCA, 0x69, 0xDE, 0x50, 0x1A, 0x11, 0x93, 0xB9, 0x52, 0x87,
+	0x58, 0xFC, 0xED, 0x1D, 0x37, 0x49, 0x1B, 0x6A, 0xE0, 0x29, 0x33, 0x99, 0xBD, 0x6C, 0xD9, 0x94,
+	0xF3, 0x40, 0x54, 0x6F, 0xF0, 0xC6, 0x73, 0xB8, 0xD6, 0x3E, 0x65, 0x18, 0x44, 0x1F, 0xDD, 0x67,
+	0x10, 0xF1, 0x0C, 0x19, 0xEC, 0xAE, 0x03, 0xA1, 0x14, 0x7B, 0xA9, 0x0B, 0xFF, 0xF8, 0xA3, 0xC0,
+	0xA2, 0x01, 0xF7, 0x2E, 0xBC, 0x24, 0x68, 0x75, 0x0D, 0xFE, 0xBA, 0x2F, 0xB5, 0xD0, 0xDA, 0x3D,
+	0x14, 0x53, 0x0F, 0x56, 0xB3, 0xC8, 0x7A, 0x9C, 0xEB, 0x65, 0x48, 0x17, 0x16, 0x15, 0x9F, 0x02,
+	0xCC, 0x54, 0x7C, 0x83, 0x00, 0x0D, 0x0C, 0x0B, 0xA2, 0x62, 0xA8, 0x76, 0xDB, 0xD9, 0xED, 0xC7,
+	0xC5, 0xA4, 0xDC, 0xAC, 0x85, 0x74, 0xD6, 0xD0, 0xA7, 0x9B, 0xAE, 0x9A, 0x96, 0x71, 0x66, 0xC3,
+	0x63, 0x99, 0xB8, 0xDD, 0x73, 0x92, 0x8E, 0x84, 0x7D, 0xA5, 0x5E, 0xD1, 0x5D, 0x93, 0xB1, 0x57,
+	0x51, 0x50, 0x80, 0x89, 0x52, 0x94, 0x4F, 0x4E, 0x0A, 0x6B, 0xBC, 0x8D, 0x7F, 0x6E, 0x47, 0x46,
+	0x41, 0x40, 0x44, 0x01, 0x11, 0xCB, 0x03, 0x3F, 0xF7, 0xF4, 0xE1, 0xA9, 0x8F, 0x3C, 0x3A, 0xF9,
+	0xFB, 0xF0, 0x19, 0x30, 0x82, 0x09, 0x2E, 0xC9, 0x9D, 0xA0, 0x86, 0x49, 0xEE, 0x6F, 0x4D, 0x6D,
+	0xC4, 0x2D, 0x81, 0x34, 0x25, 0x87, 0x1B, 0x88, 0xAA, 0xFC, 0x06, 0xA1, 0x12, 0x38, 0xFD, 0x4C,
+	0x42, 0x72, 0x64, 0x13, 0x37, 0x24, 0x6A, 0x75, 0x77, 0x43, 0xFF, 0xE6, 0xB4, 0x4B, 0x36, 0x5C,
+	0xE4, 0xD8, 0x35, 0x3D, 0x45, 0xB9, 0x2C, 0xEC, 0xB7, 0x31, 0x2B, 0x29, 0x07, 0x68, 0xA3, 0x0E,
+	0x69, 0x7B, 0x18, 0x9E, 0x21, 0x39, 0xBE, 0x28, 0x1A, 0x5B, 0x78, 0xF5, 0x23, 0xCA, 0x2A, 0xB0,
+	0xAF, 0x3E, 0xFE, 0x04, 0x8C, 0xE7, 0xE5, 0x98, 0x32, 0x95, 0xD3, 0xF6, 0x4A, 0xE8, 0xA6, 0xEA,
+	0xE9, 0xF3, 0xD5, 0x2F, 0x70, 0x20, 0xF2, 0x1F, 0x05, 0x67, 0xAD, 0x55, 0x10, 0xCE, 0xCD, 0xE3,
+	0x27, 0x3B, 0xDA, 0xBA, 0xD7, 0xC2, 0x26, 0xD4, 0x91, 0x1D, 0xD2, 0x1C, 0x22, 0x33, 0xF8, 0xFA,
+	0xF1, 0x5A, 0xEF, 0xCF, 0x90, 0xB6, 0x8B, 0xB5, 0xBD, 0xC0, 0xBF, 0x08, 0x97, 0x1E, 0x6C, 0xE2,
+	0x61, 0xE0, 0xC6, 0xC1, 0x59, 0xAB, 0xBB, 0x58, 0xDE, 0x5F, 0xDF, 0x60, 0x79, 0x7E, 0xB2, 0x8A,
+	0x47, 0xF1, 0xB4, 0xE6, 0x0B, 0x6A, 0x72, 0x48, 0x85, 0x4E, 0x9E, 0xEB, 0xE2, 0xF8, 0x94, 0x53,
+	0xE0, 0xBB, 0xA0, 0x02, 0xE8, 0x5A, 0x09, 0xAB, 0xDB, 0xE3, 0xBA, 0xC6, 0x7C, 0xC3, 0x10, 0xDD,
+	0x39, 0x05, 0x96, 0x30, 0xF5, 0x37, 0x60, 0x82, 0x8C, 0xC9, 0x13, 0x4A, 0x6B, 0x1D, 0xF3, 0xFB,
+	0x8F, 0x26, 0x97, 0xCA, 0x91, 0x17, 0x01, 0xC4, 0x32, 0x2D, 0x6E, 0x31, 0x95, 0xFF, 0xD9, 0x23,
+	0xD1, 0x00, 0x5E, 0x79, 0xDC, 0x44, 0x3B, 0x1A, 0x28, 0xC5, 0x61, 0x57, 0x20, 0x90, 0x3D, 0x83,
+	0xB9, 0x43, 0xBE, 0x67, 0xD2, 0x46, 0x42, 0x76, 0xC0, 0x6D, 0x5B, 0x7E, 0xB2, 0x0F, 0x16, 0x29,
+	0x3C, 0xA9, 0x03, 0x54, 0x0D, 0xDA, 0x5D, 0xDF, 0xF6, 0xB7, 0xC7, 0x62, 0xCD, 0x8D, 0x06, 0xD3,
+	0x69, 0x5C, 0x86, 0xD6, 0x14, 0xF7, 0xA5, 0x66, 0x75, 0xAC, 0xB1, 0xE9, 0x45, 0x21, 0x70, 0x0C,
+	0x87, 0x9F, 0x74, 0xA4, 0x22, 0x4C, 0x6F, 0xBF, 0x1F, 0x56, 0xAA, 0x2E, 0xB3, 0x78, 0x33, 0x50,
+	0xB0, 0xA3, 0x92, 0xBC, 0xCF, 0x19, 0x1C, 0xA7, 0x63, 0xCB, 0x1E, 0x4D, 0x3E, 0x4B, 0x1B, 0x9B,
+	0x4F, 0xE7, 0xF0, 0xEE, 0xAD, 0x3A, 0xB5, 0x59, 0x04, 0xEA, 0x40, 0x55, 0x25, 0x51, 0xE5, 0x7A,
+	0x89, 0x38, 0x68, 0x52, 0x7B, 0xFC, 0x27, 0xAE, 0xD7, 0xBD, 0xFA, 0x07, 0xF4, 0xCC, 0x8E, 0x5F,
+	0xEF, 0x35, 0x9C, 0x84, 0x2B, 0x15, 0xD5, 0x77, 0x34, 0x49, 0xB6, 0x12, 0x0A, 0x7F, 0x71, 0x88,
+	0xFD, 0x9D, 0x18, 0x41, 0x7D, 0x93, 0xD8, 0x58, 0x2C, 0xCE, 0xFE, 0x24, 0xAF, 0xDE, 0xB8, 0x36,
+	0xC8, 0xA1, 0x80, 0xA6, 0x99, 0x98, 0xA8, 0x2F, 0x0E, 0x81, 0x65, 0x73, 0xE4, 0xC2, 0xA2, 0x8A,
+	0xD4, 0xE1, 0x11, 0xD0, 0x08, 0x8B, 0x2A, 0xF2, 0xED, 0x9A, 0x64, 0x3F, 0xC1, 0x6C, 0xF9, 0xEC,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Format {
+	Ansi,
+	Unicode,
+}
+
+impl Format {
+	/// Bytes taken by block ids, byte indexes and the node ids of the B-trees
+	fn id_size(self) -> usize {
+		match self {
+			Self::Ansi => 4,
+			Self::Unicode => 8,
+		}
+	}
+}
+
+/// `bCryptMethod`, how the data blocks are encoded
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CryptMethod {
+	None,
+	Permute,
+	Cyclic,
+}
+
+impl CryptMethod {
+	fn decode(self, data: &mut [u8], key: u32) {
+		let (r, rest) = ENCODING_TABLES.split_at(256);
+		let (s, i) = rest.split_at(256);
+
+		match self {
+			Self::None => {}
+			Self::Permute => data.iter_mut().for_each(|b| *b = i[usize::from(*b)]),
+			Self::Cyclic => {
+				let mut key = (key ^ (key >> 16)) as u16;
+
+				for b in data.iter_mut() {
+					let [low, high] = key.to_le_bytes();
+
+					let mut value = b.wrapping_add(low);
+					value = r[usize::from(value)].wrapping_add(high);
+					value = s[usize::from(value)].wrapping_sub(high);
+					*b = i[usize::from(value)].wrapping_sub(low);
+
+					key = key.wrapping_add(1);
+				}
+			}
+		}
+	}
+}
+
+/// A node of the node B-tree or of a subnode tree, they're described the same way
+#[derive(Clone, Copy, Debug)]
+struct Node {
+	id: u32,
+	data: u64,
+	sub_nodes: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PropertyValue {
+	Integer(i64),
+	/// A `FILETIME`, in 100 nanoseconds since 1601
+	Time(i64),
+	String(String),
+}
+
+type Properties = BTreeMap<u16, PropertyValue>;
+
+pub struct PstReader<R> {
+	reader: R,
+	format: Format,
+	crypt_method: CryptMethod,
+	/// Where the root pages of the node and block B-trees are
+	node_btree: u64,
+	block_btree: u64,
+	/// The intermediate pages of the block B-tree, read for every block
+	block_btree_pages: HashMap<u64, BTreePage>,
+}
+
+#[derive(Clone)]
+struct BTreePage {
+	/// 0 for the leaves
+	level: u8,
+	entries: Vec<Vec<u8>>,
+}
+
+impl<R: Read + Seek> PstReader<R> {
+	pub fn new(mut reader: R) -> io::Result<Self> {
+		let mut header = vec![];
+		reader.by_ref().take(HEADER_SIZE).read_to_end(&mut header)?;
+
+		if header.get(..4) != Some(HEADER_MAGIC.as_slice()) {
+			return Err(invalid_data("not an Outlook store"));
+		}
+
+		// `wVer`, 36 being the 4K page stores
+		let (format, node_btree, block_btree, crypt_method) = match read_u16(&header, 10)? {
+			14 | 15 => (Format::Ansi, 188, 196, 461),
+			23 => (Format::Unicode, 224, 240, 513),
+			version => {
+				return Err(invalid_data(format!(
+					"unsupported Outlook store version {version}"
+				)))
+			}
+		};
+
+		let crypt_method = match header.get(crypt_method) {
+			Some(0) => CryptMethod::None,
+			Some(1) => CryptMethod::Permute,
+			Some(2) => CryptMethod::Cyclic,
+			_ => return Err(invalid_data("unsupported Outlook store encoding")),
+		};
+
+		let mut pst = Self {
+			reader,
+			format,
+			crypt_method,
+			node_btree: 0,
+			block_btree: 0,
+			block_btree_pages: HashMap::new(),
+		};
+		pst.node_btree = pst.read_id(&header, node_btree)?;
+		pst.block_btree = pst.read_id(&header, block_btree)?;
+
+		Ok(pst)
+	}
+
+	/// The headers and attachments of every message of the store, with its node id standing for
+	/// where it is. Messages which can't be read are skipped.
+	pub fn messages(&mut self) -> io::Result<Vec<(u64, ParsedMessage)>> {
+		let mut nodes = vec![];
+		self.read_nodes(self.node_btree, None, &mut nodes)?;
+
+		Ok(nodes
+			.into_iter()
+			.filter(|node| node.id & NID_TYPE_MASK == NID_TYPE_NORMAL_MESSAGE)
+			.filter_map(|node| match self.read_message(node) {
+				Ok(message) => Some((u64::from(node.id), message)),
+				Err(e) => {
+					warn!("Skipping unreadable Outlook message {:#x}: {e}", node.id);
+					None
+				}
+			})
+			.collect())
+	}
+
+	fn read_message(&mut self, node: Node) -> io::Result<ParsedMessage> {
+		let sub_nodes = self.read_sub_nodes(node.sub_nodes)?;
+		let properties = self.read_properties(node, &sub_nodes)?;
+
+		let attachments = sub_nodes
+			.values()
+			.filter(|sub_node| sub_node.id & NID_TYPE_MASK == NID_TYPE_ATTACHMENT)
+			.map(|&sub_node| {
+				let attachment_sub_nodes = self.read_sub_nodes(sub_node.sub_nodes)?;
+				self.read_properties(sub_node, &attachment_sub_nodes)
+					.map(|properties| parsed_attachment(&properties))
+			})
+			.collect::<io::Result<_>>()?;
+
+		Ok(parsed_message(&properties, attachments))
+	}
+
+	/// The leaves of the node B-tree below the page at `offset`
+	fn read_nodes(
+		&mut self,
+		offset: u64,
+		level: Option<u8>,
+		nodes: &mut Vec<Node>,
+	) -> io::Result<()> {
+		let page = self.read_btree_page(offset, PAGE_TYPE_NODE_BTREE, level)?;
+		let id_size = self.format.id_size();
+
+		for entry in &page.entries {
+			if page.level > 0 {
+				let child = self.read_id(entry, 2 * id_size)?;
+				self.read_nodes(child, Some(page.level - 1), nodes)?;
+			} else {
+				nodes.push(Node {
+					id: self.read_id(entry, 0)? as u32,
+					data: self.read_id(entry, id_size)?,
+					sub_nodes: self.read_id(entry, 2 * id_size)?,
+				});
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Where a block is and how big it is, from the block B-tree
+	fn find_block(&mut self, id: u64) -> io::Result<(u64, u64, u16)> {
+		// The lowest bit of block ids is left for the clients, they're looked up without it
+		let key = id & !1;
+		let id_size = self.format.id_size();
+
+		let mut offset = self.block_btree;
+		let mut level = None;
+
+		loop {
+			let page = match self.block_btree_pages.get(&offset) {
+				Some(page) => page.clone(),
+				None => {
+					let page = self.read_btree_page(offset, PAGE_TYPE_BLOCK_BTREE, level)?;
+					if page.level > 0 {
+						self.block_btree_pages.insert(offset, page.clone());
+					}
+					page
+				}
+			};
+
+			if page.level == 0 {
+				for entry in &page.entries {
+					let block = self.read_id(entry, 0)?;
+					if block & !1 == key {
+						return Ok((
+							block,
+							self.read_id(entry, id_size)?,
+							read_u16(entry, 2 * id_size)?,
+						));
+					}
+				}
+
+				return Err(invalid_data(format!("missing block {id:#x}")));
+			}
+
+			// Entries are sorted by the first key below them
+			let mut child = None;
+			for entry in &page.entries {
+				if self.read_id(entry, 0)? > key {
+					break;
+				}
+				child = Some(self.read_id(entry, 2 * id_size)?);
+			}
+
+			offset = child.ok_or_else(|| invalid_data(format!("missing block {id:#x}")))?;
+			level = Some(page.level - 1);
+		}
+	}
+
+	fn read_btree_page(
+		&mut self,
+		offset: u64,
+		page_type: u8,
+		level: Option<u8>,
+	) -> io::Result<BTreePage> {
+		let mut page = [0; PAGE_SIZE];
+		self.reader.seek(SeekFrom::Start(offset))?;
+		self.reader.read_exact(&mut page)?;
+
+		// `rgentries` is followed by `cEnt`, `cEntMax`, `cbEnt` and `cLevel`, then comes the
+		// page trailer starting with `ptype`
+		let (entries_size, trailer) = match self.format {
+			Format::Ansi => (496, 500),
+			Format::Unicode => (488, 496),
+		};
+		let count = usize::from(page[entries_size]);
+		let entry_size = usize::from(page[entries_size + 2]);
+		let page_level = page[entries_size + 3];
+
+		if page[trailer] != page_type
+			|| level.is_some_and(|level| level != page_level)
+			|| entry_size < 3 * self.format.id_size()
+			|| count * entry_size > entries_size
+		{
+			return Err(invalid_data(format!("invalid B-tree page at {offset:#x}")));
+		}
+
+		Ok(BTreePage {
+			level: page_level,
+			entries: page[..count * entry_size]
+				.chunks_exact(entry_size)
+				.map(<[u8]>::to_vec)
+				.collect(),
+		})
+	}
+
+	fn read_block(&mut self, id: u64) -> io::Result<Vec<u8>> {
+		let (block, offset, size) = self.find_block(id)?;
+
+		let mut data = vec![0; usize::from(size)];
+		self.reader.seek(SeekFrom::Start(offset))?;
+		self.reader.read_exact(&mut data)?;
+
+		if block & BID_INTERNAL == 0 {
+			self.crypt_method.decode(&mut data, block as u32);
+		}
+
+		Ok(data)
+	}
+
+	/// The data blocks of a node, listed by XBLOCKs and XXBLOCKs when they're more than one
+	fn read_data_tree(&mut self, id: u64) -> io::Result<Vec<Vec<u8>>> {
+		let block = self.read_block(id)?;
+		if id & BID_INTERNAL == 0 {
+			return Ok(vec![block]);
+		}
+
+		let invalid = || invalid_data(format!("invalid data tree {id:#x}"));
+		let (level, children) =
+			self.internal_block_entries(&block, BLOCK_TYPE_DATA_TREE, 8, |_| 1)?;
+
+		let mut blocks = vec![];
+		for child in children {
+			let child = self.read_id(child, 0)?;
+
+			match level {
+				1 if child & BID_INTERNAL == 0 => blocks.push(self.read_block(child)?),
+				2 if child & BID_INTERNAL != 0 => {
+					let block = self.read_block(child)?;
+					let (level, children) =
+						self.internal_block_entries(&block, BLOCK_TYPE_DATA_TREE, 8, |_| 1)?;
+					if level != 1 {
+						return Err(invalid());
+					}
+
+					for child in children {
+						let child = self.read_id(child, 0)?;
+						if child & BID_INTERNAL != 0 {
+							return Err(invalid());
+						}
+						blocks.push(self.read_block(child)?);
+					}
+				}
+				_ => return Err(invalid()),
+			}
+		}
+
+		Ok(blocks)
+	}
+
+	/// The subnodes of a node, listed by SLBLOCKs below an SIBLOCK when they're many
+	fn read_sub_nodes(&mut self, id: u64) -> io::Result<BTreeMap<u32, Node>> {
+		let mut nodes = BTreeMap::new();
+		if id != 0 {
+			self.read_sub_node_block(id, None, &mut nodes)?;
+		}
+
+		Ok(nodes)
+	}
+
+	fn read_sub_node_block(
+		&mut self,
+		id: u64,
+		level: Option<u8>,
+		nodes: &mut BTreeMap<u32, Node>,
+	) -> io::Result<()> {
+		let block = self.read_block(id)?;
+		let id_size = self.format.id_size();
+		// The header of the Unicode blocks is padded
+		let header_size = match self.format {
+			Format::Ansi => 4,
+			Format::Unicode => 8,
+		};
+
+		// SLENTRYs in the leaves, SIENTRYs without the subnodes of the subnodes above them
+		let (block_level, entries) =
+			self.internal_block_entries(&block, BLOCK_TYPE_SUB_NODE_TREE, header_size, |level| {
+				if level == 0 {
+					3
+				} else {
+					2
+				}
+			})?;
+
+		match (block_level, level) {
+			(0, None | Some(0)) => {
+				for entry in entries {
+					let node = Node {
+						id: self.read_id(entry, 0)? as u32,
+						data: self.read_id(entry, id_size)?,
+						sub_nodes: self.read_id(entry, 2 * id_size)?,
+					};
+					nodes.insert(node.id, node);
+				}
+			}
+			(1, None) => {
+				for entry in entries {
+					let child = self.read_id(entry, id_size)?;
+					self.read_sub_node_block(child, Some(0), nodes)?;
+				}
+			}
+			_ => return Err(invalid_data(format!("invalid subnode tree {id:#x}"))),
+		}
+
+		Ok(())
+	}
+
+	/// The level of an internal block and its entries, as many block ids long as `entry_ids` gives
+	/// for the level
+	fn internal_block_entries<'a>(
+		&self,
+		block: &'a [u8],
+		block_type: u8,
+		header_size: usize,
+		entry_ids: impl Fn(u8) -> usize,
+	) -> io::Result<(u8, Vec<&'a [u8]>)> {
+		if block.first() != Some(&block_type) {
+			return Err(invalid_data("invalid internal block"));
+		}
+		let level = *block
+			.get(1)
+			.ok_or_else(|| invalid_data("invalid internal block"))?;
+		let count = usize::from(read_u16(block, 2)?);
+		let entry_size = entry_ids(level) * self.format.id_size();
+
+		let entries = block
+			.get(header_size..)
+			.and_then(|entries| entries.get(..count * entry_size))
+			.ok_or_else(|| invalid_data("invalid internal block"))?;
+
+		Ok((level, entries.chunks_exact(entry_size).collect()))
+	}
+
+	/// The properties of a property context node, the ones the mail indexer needs at least
+	fn read_properties(
+		&mut self,
+		node: Node,
+		sub_nodes: &BTreeMap<u32, Node>,
+	) -> io::Result<Properties> {
+		let heap = Heap {
+			blocks: self.read_data_tree(node.data)?,
+		};
+
+		let mut properties = Properties::new();
+		for (id, (property_type, value)) in heap.property_records()? {
+			let value = match property_type {
+				PT_INTEGER32 => PropertyValue::Integer(i64::from(value as i32)),
+				PT_STRING8 | PT_UNICODE | PT_SYSTIME => {
+					// An allocation of the heap, a subnode when too big for it
+					let data = match value {
+						0 => vec![],
+						hid if hid & NID_TYPE_MASK == NID_TYPE_HID => {
+							heap.allocation(hid)?.to_vec()
+						}
+						nid => match sub_nodes.get(&nid) {
+							Some(sub_node) => self.read_data_tree(sub_node.data)?.concat(),
+							None => return Err(invalid_data(format!("missing subnode {nid:#x}"))),
+						},
+					};
+
+					match decode_value(property_type, &data) {
+						Some(value) => value,
+						None => continue,
+					}
+				}
+				_ => continue,
+			};
+
+			properties.insert(id, value);
+		}
+
+		Ok(properties)
+	}
+
+	fn read_id(&self, data: &[u8], offset: usize) -> io::Result<u64> {
+		match self.format {
+			Format::Ansi => read_u32(data, offset).map(u64::from),
+			Format::Unicode => read_u64(data, offset),
+		}
+	}
+}
+
+/// A heap-on-node, each of its blocks a page of allocations
+struct Heap {
+	blocks: Vec<Vec<u8>>,
+}
+
+impl Heap {
+	fn allocation(&self, hid: u32) -> io::Result<&[u8]> {
+		let missing = || invalid_data(format!("missing heap allocation {hid:#x}"));
+
+		let block = self.blocks.get((hid >> 16) as usize).ok_or_else(missing)?;
+		let index = ((hid >> 5) & 0x7FF) as usize;
+
+		// Every page starts with where its page map is, `cAlloc` and `cFree` then `rgibAlloc`
+		let page_map = usize::from(read_u16(block, 0)?);
+		let count = usize::from(read_u16(block, page_map)?);
+		if index == 0 || index > count {
+			return Err(missing());
+		}
+
+		let start = usize::from(read_u16(block, page_map + 2 + 2 * index)?);
+		let end = usize::from(read_u16(block, page_map + 4 + 2 * index)?);
+
+		block.get(start..end).ok_or_else(missing)
+	}
+
+	/// The records of the BTH with its header at `hid`, keys followed by their data
+	fn tree_records(&self, hid: u32, key_size: usize, data_size: usize) -> io::Result<Vec<&[u8]>> {
+		let header = self.allocation(hid)?;
+
+		if header.get(..3)
+			!= Some([HEAP_TREE_SIGNATURE, key_size as u8, data_size as u8].as_slice())
+		{
+			return Err(invalid_data("invalid heap tree"));
+		}
+		let levels = header.get(3).copied().unwrap_or_default();
+		let root = read_u32(header, 4)?;
+		if root == 0 {
+			return Ok(vec![]);
+		}
+
+		let mut hids = vec![root];
+		for _ in 0..levels {
+			hids = hids
+				.into_iter()
+				.map(|hid| {
+					self.allocation(hid)?
+						.chunks_exact(key_size + 4)
+						.map(|record| read_u32(record, key_size))
+						.collect::<io::Result<Vec<_>>>()
+				})
+				.collect::<io::Result<Vec<_>>>()?
+				.concat();
+		}
+
+		Ok(hids
+			.into_iter()
+			.map(|hid| self.allocation(hid))
+			.collect::<io::Result<Vec<_>>>()?
+			.into_iter()
+			.flat_map(|records| records.chunks_exact(key_size + data_size))
+			.collect())
+	}
+
+	/// The `wPropType` and `dwValueHnid` of the properties of a property context
+	fn property_records(&self) -> io::Result<BTreeMap<u16, (u16, u32)>> {
+		let header = self.blocks.first().map(Vec::as_slice).unwrap_or_default();
+		if header.get(2..4) != Some([HEAP_SIGNATURE, HEAP_CLIENT_PROPERTY_CONTEXT].as_slice()) {
+			return Err(invalid_data("not a property context"));
+		}
+
+		self.tree_records(read_u32(header, 4)?, 2, 6)?
+			.into_iter()
+			.map(|record| {
+				Ok((
+					read_u16(record, 0)?,
+					(read_u16(record, 2)?, read_u32(record, 4)?),
+				))
+			})
+			.collect()
+	}
+}
+
+fn decode_value(property_type: u16, data: &[u8]) -> Option<PropertyValue> {
+	match property_type {
+		PT_SYSTIME => read_u64(data, 0)
+			.ok()
+			.map(|time| PropertyValue::Time(time as i64)),
+		PT_UNICODE => {
+			let units = data
+				.chunks_exact(2)
+				.map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+				.take_while(|&unit| unit != 0)
+				.collect::<Vec<_>>();
+
+			Some(PropertyValue::String(String::from_utf16_lossy(&units)))
+		}
+		// The code page of the store isn't known, Latin-1 is the closest guess
+		PT_STRING8 => Some(PropertyValue::String(
+			data.iter()
+				.take_while(|&&b| b != 0)
+				.map(|&b| char::from(b))
+				.collect(),
+		)),
+		_ => None,
+	}
+}
+
+fn string(properties: &Properties, id: u16) -> Option<&str> {
+	match properties.get(&id) {
+		Some(PropertyValue::String(value)) if !value.is_empty() => Some(value),
+		_ => None,
+	}
+}
+
+fn time(properties: &Properties, id: u16) -> Option<DateTime<Utc>> {
+	match properties.get(&id) {
+		Some(&PropertyValue::Time(time)) => Utc
+			.timestamp_opt(
+				time.div_euclid(10_000_000) - FILETIME_UNIX_EPOCH,
+				(time.rem_euclid(10_000_000) * 100) as u32,
+			)
+			.single(),
+		_ => None,
+	}
+}
+
+/// A name and an address, formatted like the addresses of mbox messages. Exchange addresses aren't
+/// email addresses, the SMTP one is used instead when there's one.
+fn format_address(properties: &Properties, name: u16, address: u16, smtp: u16) -> Option<String> {
+	let address = string(properties, smtp)
+		.or_else(|| string(properties, address).filter(|address| address.contains('@')));
+
+	match (string(properties, name), address) {
+		(Some(name), Some(address)) if name != address => Some(format!("{name} <{address}>")),
+		(_, Some(value)) | (Some(value), None) => Some(value.to_string()),
+		(None, None) => None,
+	}
+}
+
+fn parsed_message(properties: &Properties, attachments: Vec<ParsedAttachment>) -> ParsedMessage {
+	// Display lists are separated by semicolons, mbox ones by commas
+	let recipients = [PID_TAG_DISPLAY_TO, PID_TAG_DISPLAY_CC]
+		.into_iter()
+		.filter_map(|id| string(properties, id))
+		.flat_map(|names| names.split(';'))
+		.map(str::trim)
+		.filter(|name| !name.is_empty())
+		.collect::<Vec<_>>();
+
+	ParsedMessage {
+		message_id: string(properties, PID_TAG_INTERNET_MESSAGE_ID)
+			.map(|id| id.trim().trim_start_matches('<').trim_end_matches('>'))
+			.filter(|id| !id.is_empty())
+			.map(str::to_string),
+		// A subject can start with 0x01 and the length of its prefix, like "RE: "
+		subject: string(properties, PID_TAG_SUBJECT).map(|subject| {
+			match subject.strip_prefix('\u{1}') {
+				Some(subject) => subject.chars().skip(1).collect(),
+				None => subject.to_string(),
+			}
+		}),
+		sender: format_address(
+			properties,
+			PID_TAG_SENDER_NAME,
+			PID_TAG_SENDER_EMAIL_ADDRESS,
+			PID_TAG_SENDER_SMTP_ADDRESS,
+		)
+		.or_else(|| {
+			format_address(
+				properties,
+				PID_TAG_SENT_REPRESENTING_NAME,
+				PID_TAG_SENT_REPRESENTING_EMAIL_ADDRESS,
+				PID_TAG_SENT_REPRESENTING_SMTP_ADDRESS,
+			)
+		}),
+		recipients: (!recipients.is_empty()).then(|| recipients.join(", ")),
+		date: time(properties, PID_TAG_MESSAGE_DELIVERY_TIME)
+			.or_else(|| time(properties, PID_TAG_CLIENT_SUBMIT_TIME))
+			.map(Into::into),
+		attachments,
+	}
+}
+
+fn parsed_attachment(properties: &Properties) -> ParsedAttachment {
+	ParsedAttachment {
+		name: [
+			PID_TAG_ATTACH_LONG_FILENAME,
+			PID_TAG_ATTACH_FILENAME,
+			PID_TAG_DISPLAY_NAME,
+		]
+		.into_iter()
+		.find_map(|id| string(properties, id))
+		.unwrap_or("attachment")
+		.to_string(),
+		mime_type: string(properties, PID_TAG_ATTACH_MIME_TAG).map(str::to_string),
+		size: match properties.get(&PID_TAG_ATTACH_SIZE) {
+			Some(&PropertyValue::Integer(size)) => size.max(0) as u64,
+			_ => 0,
+		},
+	}
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> io::Result<[u8; N]> {
+	data.get(offset..offset + N)
+		.and_then(|bytes| bytes.try_into().ok())
+		.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+	read_bytes(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+	read_bytes(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+	read_bytes(data, offset).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	/// A page of a heap with its allocations, the first one starting with the heap header
+	fn heap_page(user_root: Option<u32>, allocations: &[&[u8]]) -> Vec<u8> {
+		let mut page = vec![0; if user_root.is_some() { 12 } else { 2 }];
+		let mut offsets = vec![];
+		for allocation in allocations {
+			offsets.push(page.len() as u16);
+			page.extend_from_slice(allocation);
+		}
+		offsets.push(page.len() as u16);
+
+		let page_map = page.len() as u16;
+		page[..2].copy_from_slice(&page_map.to_le_bytes());
+		if let Some(user_root) = user_root {
+			page[2..4].copy_from_slice(&[HEAP_SIGNATURE, HEAP_CLIENT_PROPERTY_CONTEXT]);
+			page[4..8].copy_from_slice(&user_root.to_le_bytes());
+		}
+
+		page.extend_from_slice(&(allocations.len() as u16).to_le_bytes());
+		page.extend_from_slice(&[0, 0]);
+		offsets
+			.into_iter()
+			.for_each(|offset| page.extend_from_slice(&offset.to_le_bytes()));
+
+		page
+	}
+
+	fn record(key: u16, data: &[&[u8]]) -> Vec<u8> {
+		[key.to_le_bytes().as_slice()]
+			.into_iter()
+			.chain(data.iter().copied())
+			.collect::<Vec<_>>()
+			.concat()
+	}
+
+	#[test]
+	fn property_context() {
+		let subject = "Quarterly report"
+			.encode_utf16()
+			.flat_map(u16::to_le_bytes)
+			.collect::<Vec<_>>();
+
+		// A BTH with an index level, the subject on the second page
+		let heap = Heap {
+			blocks: vec![
+				heap_page(
+					Some(0x20),
+					&[
+						&[HEAP_TREE_SIGNATURE, 2, 6, 1, 0x40, 0, 0, 0],
+						&record(0x0037, &[&0x60u32.to_le_bytes()]),
+						&[
+							record(
+								PID_TAG_SUBJECT,
+								&[&PT_UNICODE.to_le_bytes(), &0x1_0020u32.to_le_bytes()],
+							),
+							record(
+								PID_TAG_ATTACH_SIZE,
+								&[&PT_INTEGER32.to_le_bytes(), &1234u32.to_le_bytes()],
+							),
+						]
+						.concat(),
+					],
+				),
+				heap_page(None, &[&subject]),
+			],
+		};
+
+		let records = heap.property_records().unwrap();
+		assert_eq!(
+			records.into_iter().collect::<Vec<_>>(),
+			[
+				(PID_TAG_SUBJECT, (PT_UNICODE, 0x1_0020)),
+				(PID_TAG_ATTACH_SIZE, (PT_INTEGER32, 1234)),
+			]
+		);
+		assert_eq!(
+			decode_value(PT_UNICODE, heap.allocation(0x1_0020).unwrap()),
+			Some(PropertyValue::String("Quarterly report".to_string()))
+		);
+		assert!(heap.allocation(0x1_0040).is_err());
+	}
+
+	#[test]
+	fn decode_blocks() {
+		let mut permuted = [
+			173, 120, 74, 6, 130, 234, 6, 253, 67, 76, 6, 234, 126, 134, 6, 130,
+		];
+		CryptMethod::Permute.decode(&mut permuted, 0x1004);
+		assert_eq!(&permuted, b"Quarterly report");
+
+		let mut cyclic = [
+			164, 249, 51, 218, 103, 28, 101, 32, 134, 113, 25, 40, 23, 22, 46, 106,
+		];
+		CryptMethod::Cyclic.decode(&mut cyclic, 0x1004);
+		assert_eq!(&cyclic, b"Quarterly report");
+	}
+
+	#[test]
+	fn message_properties() {
+		let properties = Properties::from([
+			(
+				PID_TAG_SUBJECT,
+				PropertyValue::String("\u{1}\u{4}RE: Quarterly report".to_string()),
+			),
+			(
+				PID_TAG_INTERNET_MESSAGE_ID,
+				PropertyValue::String("<1234@example.com>".to_string()),
+			),
+			(
+				PID_TAG_SENDER_NAME,
+				PropertyValue::String("Renée".to_string()),
+			),
+			(
+				PID_TAG_SENDER_EMAIL_ADDRESS,
+				PropertyValue::String("/O=EXCHANGE/CN=RENEE".to_string()),
+			),
+			(
+				PID_TAG_SENDER_SMTP_ADDRESS,
+				PropertyValue::String("renee@example.com".to_string()),
+			),
+			(
+				PID_TAG_DISPLAY_TO,
+				PropertyValue::String("Bob; Carol <carol@example.com>".to_string()),
+			),
+			(PID_TAG_DISPLAY_CC, PropertyValue::String(String::new())),
+			(
+				PID_TAG_CLIENT_SUBMIT_TIME,
+				PropertyValue::Time(133_329_343_570_000_000),
+			),
+		]);
+
+		let message = parsed_message(&properties, vec![]);
+
+		assert_eq!(message.message_id.as_deref(), Some("1234@example.com"));
+		assert_eq!(message.subject.as_deref(), Some("RE: Quarterly report"));
+		assert_eq!(message.sender.as_deref(), Some("Renée <renee@example.com>"));
+		assert_eq!(
+			message.recipients.as_deref(),
+			Some("Bob, Carol <carol@example.com>")
+		);
+		assert_eq!(
+			message.date,
+			DateTime::parse_from_rfc3339("2023-07-04T08:52:37Z").ok()
+		);
+	}
+}
@@ -0,0 +1,304 @@
+//! Scanning of the files brought in from outside, before they're let into a location. The scanner is
+//! configured on the node, either a clamd daemon or any program following the exit codes of
+//! `clamscan`. Without one, files come in unscanned as they always did.
+//!
+//! The verdict is kept on the object of the file as JSON, so it's synced with it and other devices
+//! know a file was scanned. Detections are quarantined and announced with a `CoreEvent`.
+
+use crate::{
+	api::CoreEvent,
+	library::Library,
+	prisma::{file_path, object},
+	sync,
+};
+
+use std::{
+	io,
+	path::{Path, PathBuf},
+	process::Stdio,
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::QueryError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	fs::File,
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::TcpStream,
+	process::Command,
+	time::{timeout, Duration},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Files are streamed to clamd in chunks, though larger files than its `StreamMaxLength`, 25 MB by
+/// default, fail to be scanned whatever their size
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+const SCAN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Error, Debug)]
+pub enum MalwareScanError {
+	#[error("failed to reach the scanner: {0}")]
+	Io(#[from] io::Error),
+	#[error("the scanner failed: {0}")]
+	Scanner(String),
+	#[error("the scanner didn't answer in time")]
+	Timeout,
+	#[error("unix sockets aren't supported on this platform")]
+	UnsupportedSocket,
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum MalwareScanner {
+	/// A clamd daemon, at the path of its unix socket or a `host:port` address
+	ClamAv { address: String },
+	/// A program given the path of the file after its arguments, exiting with 0 for clean files
+	/// and 1 for infected ones
+	Command { program: PathBuf, args: Vec<String> },
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "verdict")]
+pub enum ScanVerdict {
+	Clean,
+	Infected { signature: String },
+}
+
+/// What's kept in `object.malware_scan`
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct MalwareScanResult {
+	#[serde(flatten)]
+	pub verdict: ScanVerdict,
+	pub scanned_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct MalwareDetection {
+	pub library_id: Uuid,
+	/// The name the file came in with
+	pub name: String,
+	pub signature: String,
+	/// `None` when the file was deleted instead
+	pub quarantined_path: Option<PathBuf>,
+}
+
+impl MalwareScanner {
+	pub async fn scan(&self, path: &Path) -> Result<ScanVerdict, MalwareScanError> {
+		timeout(SCAN_TIMEOUT, async {
+			match self {
+				Self::ClamAv { address } => clamd_scan(address, path).await,
+				Self::Command { program, args } => command_scan(program, args, path).await,
+			}
+		})
+		.await
+		.map_err(|_| MalwareScanError::Timeout)?
+	}
+}
+
+/// Scans a file being brought in with the scanner of the node, `None` when there's none
+pub async fn scan_ingested(
+	library: &Library,
+	path: &Path,
+) -> Option<Result<MalwareScanResult, MalwareScanError>> {
+	let scanner = library.config().get().await.malware_scanner?;
+
+	Some(scanner.scan(path).await.map(|verdict| MalwareScanResult {
+		verdict,
+		scanned_at: Utc::now(),
+	}))
+}
+
+/// Keeps the result on the object of the file path, if it was identified
+pub async fn record_scan(
+	library: &Library,
+	file_path_where: Vec<file_path::WhereParam>,
+	result: &MalwareScanResult,
+) -> Result<(), QueryError> {
+	let Library { db, sync, .. } = library;
+
+	let Some(object) = db
+		.file_path()
+		.find_first(file_path_where)
+		.select(file_path::select!({ object: select { id pub_id } }))
+		.exec()
+		.await?
+		.and_then(|file_path| file_path.object)
+	else {
+		return Ok(());
+	};
+
+	let value = serde_json::to_string(result).expect("scan results are always serializable");
+
+	sync.write_op(
+		db,
+		sync.shared_update(
+			sync::object::SyncId {
+				pub_id: object.pub_id,
+			},
+			object::malware_scan::NAME,
+			json!(&value),
+		),
+		db.object().update(
+			object::id::equals(object.id),
+			vec![object::malware_scan::set(Some(value))],
+		),
+	)
+	.await?;
+
+	Ok(())
+}
+
+pub async fn record_scan_or_log(
+	library: &Library,
+	file_path_where: Vec<file_path::WhereParam>,
+	result: &MalwareScanResult,
+) {
+	if let Err(e) = record_scan(library, file_path_where, result).await {
+		warn!("Failed to record malware scan result: {e:#?}");
+	}
+}
+
+pub fn report_detection(library: &Library, detection: MalwareDetection) {
+	warn!(
+		"Malware '{}' found in '{}', {}",
+		detection.signature,
+		detection.name,
+		match &detection.quarantined_path {
+			Some(path) => format!("quarantined at {}", path.display()),
+			None => "deleted".to_string(),
+		}
+	);
+
+	library.emit(CoreEvent::MalwareDetected(detection));
+}
+
+async fn clamd_scan(address: &str, path: &Path) -> Result<ScanVerdict, MalwareScanError> {
+	if Path::new(address).is_absolute() {
+		#[cfg(unix)]
+		return clamd_instream(tokio::net::UnixStream::connect(address).await?, path).await;
+		#[cfg(not(unix))]
+		return Err(MalwareScanError::UnsupportedSocket);
+	}
+
+	clamd_instream(TcpStream::connect(address).await?, path).await
+}
+
+/// Streams the file to clamd, which doesn't need access to it that way
+async fn clamd_instream(
+	mut stream: impl AsyncRead + AsyncWrite + Unpin,
+	path: &Path,
+) -> Result<ScanVerdict, MalwareScanError> {
+	let mut file = File::open(path).await?;
+	let mut buf = vec![0; CLAMD_CHUNK_SIZE];
+
+	stream.write_all(b"zINSTREAM\0").await?;
+	loop {
+		let read = file.read(&mut buf).await?;
+		stream.write_all(&(read as u32).to_be_bytes()).await?;
+		if read == 0 {
+			break;
+		}
+		stream.write_all(&buf[..read]).await?;
+	}
+	stream.flush().await?;
+
+	let mut reply = Vec::new();
+	stream.read_to_end(&mut reply).await?;
+
+	parse_clamd_reply(&String::from_utf8_lossy(&reply))
+}
+
+fn parse_clamd_reply(reply: &str) -> Result<ScanVerdict, MalwareScanError> {
+	let reply = reply.trim_end_matches(['\0', '\n']);
+	let result = reply
+		.strip_prefix("stream:")
+		.map(str::trim)
+		.ok_or_else(|| MalwareScanError::Scanner(reply.to_string()))?;
+
+	if result == "OK" {
+		Ok(ScanVerdict::Clean)
+	} else if let Some(signature) = result.strip_suffix(" FOUND") {
+		Ok(ScanVerdict::Infected {
+			signature: signature.to_string(),
+		})
+	} else {
+		Err(MalwareScanError::Scanner(result.to_string()))
+	}
+}
+
+async fn command_scan(
+	program: &Path,
+	args: &[String],
+	path: &Path,
+) -> Result<ScanVerdict, MalwareScanError> {
+	let output = Command::new(program)
+		.args(args)
+		.arg(path)
+		.stdin(Stdio::null())
+		.kill_on_drop(true)
+		.output()
+		.await?;
+
+	match output.status.code() {
+		Some(0) => Ok(ScanVerdict::Clean),
+		Some(1) => Ok(ScanVerdict::Infected {
+			signature: command_signature(&String::from_utf8_lossy(&output.stdout)),
+		}),
+		_ => Err(MalwareScanError::Scanner(
+			String::from_utf8_lossy(&output.stderr).trim().to_string(),
+		)),
+	}
+}
+
+/// `clamscan` prints `<path>: <signature> FOUND`, other programs get their first line kept
+fn command_signature(stdout: &str) -> String {
+	stdout
+		.lines()
+		.find_map(|line| {
+			let (_, found) = line.rsplit_once(": ")?;
+			found.strip_suffix(" FOUND")
+		})
+		.or_else(|| stdout.lines().map(str::trim).find(|line| !line.is_empty()))
+		.unwrap_or("unknown")
+		.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn clamd_replies() {
+		assert_eq!(
+			parse_clamd_reply("stream: OK\0").unwrap(),
+			ScanVerdict::Clean
+		);
+		assert_eq!(
+			parse_clamd_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+			ScanVerdict::Infected {
+				signature: "Win.Test.EICAR_HDB-1".into()
+			}
+		);
+		assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+		assert!(parse_clamd_reply("stream: Can't allocate memory ERROR\0").is_err());
+	}
+
+	#[test]
+	fn command_signatures() {
+		assert_eq!(
+			command_signature(
+				"/tmp/eicar.com: Win.Test.EICAR_HDB-1 FOUND\n\n----------- SCAN SUMMARY -----------\n"
+			),
+			"Win.Test.EICAR_HDB-1"
+		);
+		assert_eq!(
+			command_signature("\n  Trojan.Generic  \n"),
+			"Trojan.Generic"
+		);
+		assert_eq!(command_signature(""), "unknown");
+	}
+}
@@ -0,0 +1,811 @@
+//! Exporting the metadata of objects to CSV or JSON, to curate it in a spreadsheet, and importing
+//! it back. Each row is a file, with its path and hashes to match it by, and the metadata of its
+//! object: note, rating, favorite, tags and custom fields, the latter as `field:<name>` columns in
+//! CSV and under `custom_fields` in JSON.
+//!
+//! Importing only touches the metadata present in the file: a column which isn't there leaves the
+//! objects as they are, while an empty cell, or a `null` in JSON, clears the value. The tags of a
+//! row replace those of its object, tags which don't exist yet are created.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	location::file_path_helper::{filter_existing_file_path_params, IsolatedFilePathData},
+	object::{
+		custom_field::{
+			list_custom_fields, CustomField, CustomFieldError, CustomFieldType, CustomFieldValue,
+			SetCustomFieldArgs,
+		},
+		os_metadata::write_object_finder_tags_or_log,
+		patch::{FieldPatch, ObjectPatch, ObjectPatchError},
+		tag::find_or_create_tag,
+		xmp::write_object_sidecars_or_log,
+	},
+	prisma::{file_path, location, object, tag_on_object, SortOrder},
+	sync,
+	util::error::FileIOError,
+};
+
+use std::{
+	borrow::Cow,
+	collections::{BTreeMap, HashMap, HashSet},
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use specta::Type;
+use thiserror::Error;
+use tokio::fs;
+
+/// File paths are fetched a batch at a time, so huge libraries aren't queried at once
+const EXPORT_BATCH_SIZE: i64 = 1000;
+const TAG_SEPARATOR: char = ';';
+const CUSTOM_FIELD_COLUMN_PREFIX: &str = "field:";
+const CUSTOM_FIELDS_KEY: &str = "custom_fields";
+
+#[derive(Error, Debug)]
+pub enum MetadataIoError {
+	#[error("a metadata file must have a '{0}' column to match rows by")]
+	MissingMatchColumn(&'static str),
+	#[error("invalid CSV: {0}")]
+	Csv(#[from] csv::Error),
+	#[error("invalid JSON: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("a JSON metadata file must be an array of objects")]
+	InvalidJson,
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error(transparent)]
+	CustomField(#[from] CustomFieldError),
+	#[error(transparent)]
+	Patch(#[from] ObjectPatchError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<MetadataIoError> for rspc::Error {
+	fn from(err: MetadataIoError) -> Self {
+		match err {
+			MetadataIoError::MissingMatchColumn(_)
+			| MetadataIoError::Csv(_)
+			| MetadataIoError::Json(_)
+			| MetadataIoError::InvalidJson => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			MetadataIoError::CustomField(err) => err.into(),
+			MetadataIoError::Patch(err) => err.into(),
+			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Copy)]
+pub enum MetadataFormat {
+	Csv,
+	Json,
+}
+
+/// How the rows of an imported file are matched with objects
+#[derive(Deserialize, Type, Debug, Clone, Copy)]
+pub enum MetadataMatch {
+	/// The full path of a file, in a location of this device
+	Path,
+	/// The content hash of a file, which finds it wherever it was moved
+	CasId,
+}
+
+impl MetadataMatch {
+	fn column(self) -> &'static str {
+		match self {
+			Self::Path => "path",
+			Self::CasId => "cas_id",
+		}
+	}
+}
+
+file_path::select!(file_path_for_metadata_export {
+	id
+	materialized_path
+	is_dir
+	name
+	extension
+	cas_id
+	integrity_checksum
+	location: select { id path }
+	object: select {
+		note
+		rating
+		favorite
+		tags: select { tag: select { name } }
+		custom_field_values
+	}
+});
+
+#[derive(Serialize, Type, Debug)]
+pub struct MetadataExport {
+	/// The CSV or JSON file, left to the client to save where the user wants it
+	pub contents: String,
+	/// How many files were exported
+	pub exported: u32,
+}
+
+/// Exports the metadata of the objects matching the filter, file paths of locked private
+/// locations are left out
+pub async fn export_metadata(
+	library: &Library,
+	filter: Vec<object::WhereParam>,
+	format: MetadataFormat,
+) -> Result<MetadataExport, MetadataIoError> {
+	let fields = list_custom_fields(&library.db).await?;
+	let visible_file_paths = library.private_locations.visible_file_paths().await;
+
+	let header = [
+		"path",
+		"cas_id",
+		"integrity_checksum",
+		"note",
+		"rating",
+		"favorite",
+		"tags",
+	]
+	.into_iter()
+	.map(str::to_string)
+	.chain(
+		fields
+			.iter()
+			.map(|field| format!("{CUSTOM_FIELD_COLUMN_PREFIX}{}", field.name)),
+	)
+	.collect::<Vec<_>>();
+
+	let mut buffer = match format {
+		MetadataFormat::Csv => csv_line(&header)?,
+		MetadataFormat::Json => b"[".to_vec(),
+	};
+
+	let mut exported = 0;
+	let mut cursor = None;
+	loop {
+		let file_paths = library
+			.db
+			.file_path()
+			.find_many(
+				[
+					Some(file_path::object::is(filter.clone())),
+					visible_file_paths.clone(),
+					cursor.map(file_path::id::gt),
+				]
+				.into_iter()
+				.flatten()
+				.collect(),
+			)
+			.order_by(file_path::id::order(SortOrder::Asc))
+			.take(EXPORT_BATCH_SIZE)
+			.select(file_path_for_metadata_export::select())
+			.exec()
+			.await?;
+
+		let Some(last) = file_paths.last() else {
+			break;
+		};
+		cursor = Some(last.id);
+
+		for file_path in file_paths {
+			let row = export_row(&file_path, &fields);
+
+			match format {
+				MetadataFormat::Csv => buffer.extend(csv_line(&csv_record(&row, &fields))?),
+				MetadataFormat::Json => {
+					if exported > 0 {
+						buffer.push(b',');
+					}
+					serde_json::to_writer(&mut buffer, &row)?;
+				}
+			}
+
+			exported += 1;
+		}
+	}
+
+	if let MetadataFormat::Json = format {
+		buffer.push(b']');
+	}
+
+	Ok(MetadataExport {
+		// Both formats are written from strings
+		contents: String::from_utf8(buffer).expect("exported metadata is valid UTF-8"),
+		exported,
+	})
+}
+
+fn export_row(
+	file_path: &file_path_for_metadata_export::Data,
+	fields: &[CustomField],
+) -> Map<String, Value> {
+	let path = file_path.location.as_ref().and_then(|location| {
+		let location_path = location.path.as_ref()?;
+		let iso_file_path = IsolatedFilePathData::from_db_data(
+			location.id,
+			file_path.is_dir.unwrap_or_default(),
+			Cow::Borrowed(file_path.materialized_path.as_deref()?),
+			Cow::Borrowed(file_path.name.as_deref()?),
+			Cow::Borrowed(file_path.extension.as_deref().unwrap_or_default()),
+		);
+
+		Some(
+			Path::new(location_path)
+				.join(iso_file_path)
+				.to_string_lossy()
+				.to_string(),
+		)
+	});
+
+	let mut row = Map::new();
+	row.insert("path".into(), json!(path));
+	row.insert("cas_id".into(), json!(file_path.cas_id));
+	row.insert(
+		"integrity_checksum".into(),
+		json!(file_path.integrity_checksum),
+	);
+
+	let Some(object) = &file_path.object else {
+		return row;
+	};
+
+	row.insert("note".into(), json!(object.note));
+	row.insert("rating".into(), json!(object.rating));
+	row.insert("favorite".into(), json!(object.favorite));
+	row.insert(
+		"tags".into(),
+		json!(object
+			.tags
+			.iter()
+			.filter_map(|tag_on_object| tag_on_object.tag.name.clone())
+			.collect::<Vec<_>>()),
+	);
+
+	let values = object
+		.custom_field_values
+		.iter()
+		.filter_map(|value| {
+			let field = fields
+				.iter()
+				.find(|field| Some(field.id) == value.field_id)?;
+
+			Some((
+				field.name.clone(),
+				match CustomFieldValue::from_record(field.field_type, value)? {
+					CustomFieldValue::Text(text) | CustomFieldValue::Enum(text) => json!(text),
+					CustomFieldValue::Number(number) => json!(number),
+					CustomFieldValue::Date(date) => json!(date.to_rfc3339()),
+				},
+			))
+		})
+		.collect::<Map<_, _>>();
+	row.insert(CUSTOM_FIELDS_KEY.into(), Value::Object(values));
+
+	row
+}
+
+/// The cells of a row, in the order of the header
+fn csv_record(row: &Map<String, Value>, fields: &[CustomField]) -> Vec<String> {
+	let cell = |value: Option<&Value>| match value {
+		None | Some(Value::Null) => String::new(),
+		Some(Value::String(text)) => text.clone(),
+		Some(Value::Array(values)) => values
+			.iter()
+			.filter_map(Value::as_str)
+			.collect::<Vec<_>>()
+			.join(&format!("{TAG_SEPARATOR} ")),
+		Some(value) => value.to_string(),
+	};
+
+	let custom_fields = row.get(CUSTOM_FIELDS_KEY).and_then(Value::as_object);
+
+	[
+		"path",
+		"cas_id",
+		"integrity_checksum",
+		"note",
+		"rating",
+		"favorite",
+		"tags",
+	]
+	.into_iter()
+	.map(|column| cell(row.get(column)))
+	.chain(
+		fields
+			.iter()
+			.map(|field| cell(custom_fields.and_then(|values| values.get(&field.name)))),
+	)
+	.collect()
+}
+
+fn csv_line(record: &[String]) -> Result<Vec<u8>, csv::Error> {
+	let mut writer = csv::WriterBuilder::new()
+		.has_headers(false)
+		.from_writer(vec![]);
+	writer.write_record(record)?;
+
+	writer
+		.into_inner()
+		.map_err(|e| csv::Error::from(e.into_error()))
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct MetadataImportArgs {
+	pub path: PathBuf,
+	pub format: MetadataFormat,
+	pub match_by: MetadataMatch,
+}
+
+#[derive(Serialize, Type, Debug, Default)]
+pub struct MetadataImportReport {
+	pub rows: u32,
+	pub updated_objects: u32,
+	/// The rows, counting from 1 after the header, which matched no file
+	pub unmatched_rows: Vec<u32>,
+	/// The rows which weren't imported because of an invalid value, with why
+	pub invalid_rows: Vec<(u32, String)>,
+}
+
+/// What a row of an imported file changes
+#[derive(Default)]
+struct RowChanges {
+	patch: ObjectPatch,
+	/// Replace the tags of the object, by name
+	tags: Option<Vec<String>>,
+	/// `None` clears the field
+	custom_fields: Vec<(CustomField, Option<CustomFieldValue>)>,
+}
+
+impl MetadataImportArgs {
+	pub async fn import(self, library: &Library) -> Result<MetadataImportReport, MetadataIoError> {
+		let contents = fs::read(&self.path)
+			.await
+			.map_err(|e| FileIOError::from((&self.path, e)))?;
+
+		let rows = match self.format {
+			MetadataFormat::Csv => parse_csv(&contents)?,
+			MetadataFormat::Json => parse_json(&contents)?,
+		};
+
+		let match_column = self.match_by.column();
+		if !rows.is_empty() && !rows.iter().any(|row| row.contains_key(match_column)) {
+			return Err(MetadataIoError::MissingMatchColumn(match_column));
+		}
+
+		let fields = list_custom_fields(&library.db).await?;
+		let object_ids = match_rows(library, &rows, self.match_by).await?;
+
+		let mut report = MetadataImportReport {
+			rows: rows.len() as u32,
+			..Default::default()
+		};
+
+		let mut changes = Vec::with_capacity(rows.len());
+		for (index, row) in rows.into_iter().enumerate() {
+			let row_number = index as u32 + 1;
+
+			let Some(ids) = object_ids.get(&index).filter(|ids| !ids.is_empty()) else {
+				report.unmatched_rows.push(row_number);
+				continue;
+			};
+
+			match parse_changes(row, &fields) {
+				Ok(row_changes) => changes.push((ids.clone(), row_changes)),
+				Err(e) => report.invalid_rows.push((row_number, e)),
+			}
+		}
+
+		report.updated_objects = changes
+			.iter()
+			.flat_map(|(ids, _)| ids)
+			.collect::<HashSet<_>>()
+			.len() as u32;
+
+		apply_changes(library, changes).await?;
+
+		Ok(report)
+	}
+}
+
+fn parse_csv(contents: &[u8]) -> Result<Vec<Map<String, Value>>, MetadataIoError> {
+	let mut reader = csv::Reader::from_reader(contents);
+	let header = reader.headers()?.clone();
+
+	reader
+		.records()
+		.map(|record| {
+			let record = record?;
+
+			let mut row = Map::new();
+			let mut custom_fields = Map::new();
+			for (column, cell) in header.iter().zip(record.iter()) {
+				let cell = cell.trim();
+				let value = match column {
+					_ if cell.is_empty() => Value::Null,
+					"tags" => json!(cell
+						.split(TAG_SEPARATOR)
+						.map(str::trim)
+						.filter(|tag| !tag.is_empty())
+						.collect::<Vec<_>>()),
+					_ => json!(cell),
+				};
+
+				match column.strip_prefix(CUSTOM_FIELD_COLUMN_PREFIX) {
+					Some(name) => custom_fields.insert(name.to_string(), value),
+					None => row.insert(column.to_string(), value),
+				};
+			}
+
+			if !custom_fields.is_empty() {
+				row.insert(CUSTOM_FIELDS_KEY.into(), Value::Object(custom_fields));
+			}
+
+			Ok(row)
+		})
+		.collect()
+}
+
+fn parse_json(contents: &[u8]) -> Result<Vec<Map<String, Value>>, MetadataIoError> {
+	let Value::Array(rows) = serde_json::from_slice(contents)? else {
+		return Err(MetadataIoError::InvalidJson);
+	};
+
+	rows.into_iter()
+		.map(|row| match row {
+			Value::Object(row) => Ok(row),
+			_ => Err(MetadataIoError::InvalidJson),
+		})
+		.collect()
+}
+
+/// The objects each row is about, by the index of the row
+async fn match_rows(
+	library: &Library,
+	rows: &[Map<String, Value>],
+	match_by: MetadataMatch,
+) -> Result<HashMap<usize, Vec<object::id::Type>>, QueryError> {
+	let keys = rows.iter().enumerate().filter_map(|(index, row)| {
+		row.get(match_by.column())
+			.and_then(Value::as_str)
+			.map(|key| (index, key))
+	});
+
+	match match_by {
+		MetadataMatch::CasId => {
+			let keys = keys.collect::<Vec<_>>();
+
+			let mut objects_by_cas_id = HashMap::<_, Vec<_>>::new();
+			for file_path in library
+				.db
+				.file_path()
+				.find_many(vec![file_path::cas_id::in_vec(
+					keys.iter().map(|(_, key)| key.to_string()).collect(),
+				)])
+				.select(file_path::select!({ cas_id object_id }))
+				.exec()
+				.await?
+			{
+				if let (Some(cas_id), Some(object_id)) = (file_path.cas_id, file_path.object_id) {
+					objects_by_cas_id.entry(cas_id).or_default().push(object_id);
+				}
+			}
+
+			Ok(keys
+				.into_iter()
+				.filter_map(|(index, key)| {
+					let mut ids = objects_by_cas_id.get(key)?.clone();
+					ids.sort_unstable();
+					ids.dedup();
+
+					Some((index, ids))
+				})
+				.collect())
+		}
+		MetadataMatch::Path => {
+			let locations = library
+				.db
+				.location()
+				.find_many(vec![location::node_id::equals(Some(library.node_local_id))])
+				.select(location::select!({ id path }))
+				.exec()
+				.await?;
+
+			let mut matched = HashMap::new();
+			for (index, key) in keys {
+				let path = Path::new(key);
+
+				// The deepest location holding the file, as locations can be nested
+				let Some((location_id, location_path)) = locations
+					.iter()
+					.filter_map(|location| Some((location.id, location.path.as_deref()?)))
+					.filter(|(_, location_path)| path.starts_with(location_path))
+					.max_by_key(|(_, location_path)| location_path.len())
+				else {
+					continue;
+				};
+
+				let Ok(iso_file_path) =
+					IsolatedFilePathData::new(location_id, location_path, path, false)
+				else {
+					continue;
+				};
+				let iso_file_path =
+					iso_file_path.normalized(library.config.file_name_normalization);
+
+				if let Some(object_id) = library
+					.db
+					.file_path()
+					.find_first(filter_existing_file_path_params(&iso_file_path))
+					.select(file_path::select!({ object_id }))
+					.exec()
+					.await?
+					.and_then(|file_path| file_path.object_id)
+				{
+					matched.insert(index, vec![object_id]);
+				}
+			}
+
+			Ok(matched)
+		}
+	}
+}
+
+fn parse_changes(
+	mut row: Map<String, Value>,
+	fields: &[CustomField],
+) -> Result<RowChanges, String> {
+	fn patch<T>(
+		row: &mut Map<String, Value>,
+		column: &str,
+		parse: impl FnOnce(Value) -> Option<T>,
+	) -> Result<Option<FieldPatch<T>>, String> {
+		match row.remove(column) {
+			None => Ok(None),
+			Some(Value::Null) => Ok(Some(FieldPatch::Clear)),
+			Some(value) => parse(value.clone())
+				.map(|value| Some(FieldPatch::Set(value)))
+				.ok_or_else(|| format!("invalid {column}: {value}")),
+		}
+	}
+
+	let mut changes = RowChanges {
+		patch: ObjectPatch {
+			note: patch(&mut row, "note", |value| match value {
+				Value::String(note) => Some(note),
+				value => Some(value.to_string()),
+			})?,
+			rating: patch(&mut row, "rating", |value| match value {
+				Value::Number(rating) => rating.as_i64().map(|rating| rating as i32),
+				Value::String(rating) => rating.parse().ok(),
+				_ => None,
+			})?,
+			favorite: patch(&mut row, "favorite", |value| match value {
+				Value::Bool(favorite) => Some(favorite),
+				Value::String(favorite) => match favorite.to_lowercase().as_str() {
+					"true" | "yes" | "1" => Some(true),
+					"false" | "no" | "0" => Some(false),
+					_ => None,
+				},
+				_ => None,
+			})?,
+			..Default::default()
+		},
+		..Default::default()
+	};
+	changes.patch.validate().map_err(|e| e.to_string())?;
+
+	changes.tags = match row.remove("tags") {
+		None => None,
+		Some(Value::Null) => Some(vec![]),
+		Some(Value::Array(tags)) => Some(
+			tags.into_iter()
+				.map(|tag| match tag {
+					Value::String(tag) => Ok(tag.trim().to_string()),
+					tag => Err(format!("invalid tag: {tag}")),
+				})
+				.filter(|tag| !matches!(tag, Ok(tag) if tag.is_empty()))
+				.collect::<Result<_, _>>()?,
+		),
+		Some(tags) => return Err(format!("invalid tags: {tags}")),
+	};
+
+	if let Some(Value::Object(values)) = row.remove(CUSTOM_FIELDS_KEY) {
+		for (name, value) in values {
+			let field = fields
+				.iter()
+				.find(|field| field.name == name)
+				.ok_or_else(|| format!("unknown custom field: {name}"))?;
+
+			let value = match value {
+				Value::Null => None,
+				value => Some(
+					parse_custom_field_value(field, &value)
+						.ok_or_else(|| format!("invalid value for {name}: {value}"))?,
+				),
+			};
+
+			changes.custom_fields.push((field.clone(), value));
+		}
+	}
+
+	Ok(changes)
+}
+
+fn parse_custom_field_value(field: &CustomField, value: &Value) -> Option<CustomFieldValue> {
+	Some(match (field.field_type, value) {
+		(CustomFieldType::Text, Value::String(text)) => CustomFieldValue::Text(text.clone()),
+		(CustomFieldType::Text, value) => CustomFieldValue::Text(value.to_string()),
+		(CustomFieldType::Number, Value::Number(number)) => {
+			CustomFieldValue::Number(number.as_f64()?)
+		}
+		(CustomFieldType::Number, Value::String(number)) => {
+			CustomFieldValue::Number(number.trim().parse().ok()?)
+		}
+		(CustomFieldType::Date, Value::String(date)) => CustomFieldValue::Date(parse_date(date)?),
+		(CustomFieldType::Enum, Value::String(option)) if field.options.contains(option) => {
+			CustomFieldValue::Enum(option.clone())
+		}
+		_ => return None,
+	})
+}
+
+/// RFC 3339 timestamps, or plain dates as spreadsheets usually have them
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+	if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+		return Some(date.into());
+	}
+
+	NaiveDate::parse_from_str(date, "%Y-%m-%d")
+		.ok()
+		.and_then(|date| date.and_hms_opt(0, 0, 0))
+		.map(|date| Utc.from_utc_datetime(&date))
+}
+
+async fn apply_changes(
+	library: &Library,
+	changes: Vec<(Vec<object::id::Type>, RowChanges)>,
+) -> Result<(), MetadataIoError> {
+	let Library { db, sync, .. } = library;
+
+	if changes.is_empty() {
+		return Ok(());
+	}
+
+	let object_ids = changes
+		.iter()
+		.flat_map(|(ids, _)| ids.iter().copied())
+		.collect::<HashSet<_>>();
+	let pub_ids = db
+		.object()
+		.find_many(vec![object::id::in_vec(object_ids.into_iter().collect())])
+		.select(object::select!({ id pub_id }))
+		.exec()
+		.await?
+		.into_iter()
+		.map(|object| (object.id, object.pub_id))
+		.collect::<HashMap<_, _>>();
+
+	let mut sidecars_to_write = HashSet::new();
+	let mut tags_by_object = HashMap::new();
+	let mut custom_field_groups = BTreeMap::<_, (CustomField, Option<_>, Vec<_>)>::new();
+	let (mut ops, mut updates) = (vec![], vec![]);
+
+	for (ids, row_changes) in changes {
+		if row_changes.patch.rating.is_some() || row_changes.tags.is_some() {
+			sidecars_to_write.extend(ids.iter().copied());
+		}
+
+		if let Some(tags) = row_changes.tags {
+			for &id in &ids {
+				tags_by_object.insert(id, tags.clone());
+			}
+		}
+
+		// Objects getting the same value are set together
+		for (field, value) in row_changes.custom_fields {
+			let key = (field.id, serde_json::to_string(&value)?);
+			custom_field_groups
+				.entry(key)
+				.or_insert_with(|| (field, value, vec![]))
+				.2
+				.extend(ids.iter().copied());
+		}
+
+		let (sync_params, db_params): (Vec<_>, Vec<_>) = row_changes
+			.patch
+			.fields()
+			.into_iter()
+			.map(|(field, value, param)| ((field, value), param))
+			.unzip();
+		if db_params.is_empty() {
+			continue;
+		}
+
+		ops.extend(
+			ids.iter()
+				.filter_map(|id| pub_ids.get(id))
+				.flat_map(|pub_id| {
+					sync_params.iter().map(|(field, value)| {
+						sync.shared_update(
+							sync::object::SyncId {
+								pub_id: pub_id.clone(),
+							},
+							field,
+							value.clone(),
+						)
+					})
+				}),
+		);
+		updates.push(
+			db.object()
+				.update_many(vec![object::id::in_vec(ids)], db_params),
+		);
+	}
+
+	if !updates.is_empty() {
+		sync.write_ops(db, (ops, updates)).await?;
+	}
+
+	if !tags_by_object.is_empty() {
+		let mut tag_ids = HashMap::new();
+		for name in tags_by_object.values().flatten() {
+			if !tag_ids.contains_key(name) {
+				let (tag_id, _) = find_or_create_tag(library, name).await?;
+				tag_ids.insert(name.clone(), tag_id);
+			}
+		}
+
+		db._batch((
+			db.tag_on_object()
+				.delete_many(vec![tag_on_object::object_id::in_vec(
+					tags_by_object.keys().copied().collect(),
+				)]),
+			db.tag_on_object().create_many(
+				tags_by_object
+					.iter()
+					.flat_map(|(&object_id, tags)| {
+						tags.iter()
+							.map(|name| tag_ids[name])
+							.collect::<HashSet<_>>()
+							.into_iter()
+							.map(move |tag_id| tag_on_object::CreateUnchecked {
+								tag_id,
+								object_id,
+								_params: vec![],
+							})
+					})
+					.collect(),
+			),
+		))
+		.await?;
+
+		write_object_finder_tags_or_log(library, tags_by_object.into_keys().collect()).await;
+		invalidate_query!(library, "tags.list");
+		invalidate_query!(library, "tags.getForObject");
+	}
+
+	for (field, value, object_ids) in custom_field_groups.into_values() {
+		SetCustomFieldArgs {
+			field_id: field.id,
+			object_ids,
+			value,
+		}
+		.set(library)
+		.await?;
+	}
+
+	if !sidecars_to_write.is_empty() {
+		write_object_sidecars_or_log(library, sidecars_to_write.into_iter().collect()).await;
+	}
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+
+	Ok(())
+}
@@ -3,13 +3,34 @@ use crate::prisma::{file_path, object};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
+pub mod accent;
+pub mod access;
 pub mod cas;
+pub mod catalog;
+pub mod cleanup_suggestions;
+pub mod custom_field;
+pub mod custom_kind;
+pub mod directory_description;
+pub mod duplicate_folders;
 pub mod file_identifier;
 pub mod fs;
+pub mod groups;
+pub mod label;
+pub mod mail;
+pub mod malware_scan;
+pub mod metadata_io;
+pub mod nsfw;
 pub mod orphan_remover;
+pub mod os_metadata;
+pub mod patch;
+pub mod playback;
 pub mod preview;
+pub mod projects;
+pub mod special;
+pub mod stacks;
 pub mod tag;
 pub mod validation;
+pub mod xmp;
 
 // Objects are primarily created by the identifier from Paths
 // Some Objects are purely virtual, unless they have one or more associated Paths, which refer to a file found in a Location
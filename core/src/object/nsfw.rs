@@ -0,0 +1,529 @@
+//! NSFW content of shared libraries, like a family library with the devices of children in it.
+//!
+//! Photos and videos are scored by a classifier program running on this device, given their
+//! thumbnail, which is small and always a WebP image whatever the original. Nothing is sent
+//! anywhere. Objects scoring over the threshold of the classifier are flagged, unless the flag was
+//! already set or cleared by hand, which always wins. The score and the flag are synced with the
+//! object, so a single device of the library needs a classifier.
+//!
+//! Devices of the library can be restricted, which leaves flagged objects out of the listings on
+//! them. Restrictions are synced too, and lifting one takes the PIN it was set with, so it can't be
+//! lifted from the restricted device without knowing it.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::privacy::{hash_passphrase, LocationPrivacyError},
+	object::preview::get_thumbnail_path,
+	prisma::{content_restriction, file_path, location, node, object},
+	sync,
+	util::db::uuid_to_bytes,
+};
+
+use sd_crypto::{primitives::SALT_LEN, types::Salt};
+use sd_file_ext::kind::ObjectKind;
+
+use std::{
+	path::{Path, PathBuf},
+	process::Stdio,
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use prisma_client_rust::{or, QueryError};
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, process::Command};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const OBJECTS_PER_STEP: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum NsfwError {
+	#[error("device not found in the library <id='{0}'>")]
+	NodeNotFound(Uuid),
+	#[error("device is already restricted <id='{0}'>")]
+	AlreadyRestricted(Uuid),
+	#[error("device isn't restricted <id='{0}'>")]
+	NotRestricted(Uuid),
+	#[error("the PIN can't be empty")]
+	EmptyPin,
+	#[error("wrong PIN")]
+	WrongPin,
+	#[error("this device is restricted, so it can't change which objects are NSFW")]
+	Restricted,
+	#[error("failed to hash the PIN: {0}")]
+	Hashing(#[from] LocationPrivacyError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<NsfwError> for rspc::Error {
+	fn from(err: NsfwError) -> Self {
+		let code = match err {
+			NsfwError::NodeNotFound(_) | NsfwError::NotRestricted(_) => ErrorCode::NotFound,
+			NsfwError::AlreadyRestricted(_) | NsfwError::EmptyPin => ErrorCode::BadRequest,
+			NsfwError::WrongPin => ErrorCode::Unauthorized,
+			NsfwError::Restricted => ErrorCode::Forbidden,
+			_ => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+/// A program given the path of a WebP image after its arguments, printing a score from 0 to 1
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq)]
+pub struct NsfwClassifier {
+	pub program: PathBuf,
+	pub args: Vec<String>,
+	/// Objects scoring this or more are flagged
+	pub threshold: f64,
+}
+
+impl NsfwClassifier {
+	async fn score(&self, path: &Path) -> Result<f64, String> {
+		let output = Command::new(&self.program)
+			.args(&self.args)
+			.arg(path)
+			.stdin(Stdio::null())
+			.kill_on_drop(true)
+			.output()
+			.await
+			.map_err(|e| e.to_string())?;
+
+		if !output.status.success() {
+			return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+		}
+
+		parse_score(&String::from_utf8_lossy(&output.stdout))
+			.ok_or_else(|| "the classifier didn't print a score from 0 to 1".to_string())
+	}
+}
+
+fn parse_score(stdout: &str) -> Option<f64> {
+	stdout
+		.split_whitespace()
+		.next()?
+		.parse::<f64>()
+		.ok()
+		.filter(|score| (0.0..=1.0).contains(score))
+}
+
+pub struct NsfwClassifierJob {}
+
+/// `NsfwClassifierJobInit` scores the photos and videos of a location which weren't scored yet
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct NsfwClassifierJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for NsfwClassifierJobInit {
+	type Job = NsfwClassifierJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct NsfwClassifierJobReport {
+	scored: usize,
+	flagged: usize,
+	/// Without a thumbnail yet or failing to be scored, they're tried again on the next run
+	skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NsfwClassifierJobData {
+	classifier: NsfwClassifier,
+	report: NsfwClassifierJobReport,
+}
+
+object::select!(object_for_nsfw_classifier {
+	id
+	pub_id
+	nsfw
+	file_paths: select { cas_id }
+});
+
+#[async_trait::async_trait]
+impl StatefulJob for NsfwClassifierJob {
+	type Init = NsfwClassifierJobInit;
+	type Data = NsfwClassifierJobData;
+	type Step = Vec<object_for_nsfw_classifier::Data>;
+
+	const NAME: &'static str = "nsfw_classifier";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Some(classifier) = ctx.library.config().get().await.nsfw_classifier else {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "No NSFW classifier is configured on this device".to_string(),
+			});
+		};
+
+		let objects = ctx
+			.library
+			.db
+			.object()
+			.find_many(vec![
+				object::kind::in_vec(vec![ObjectKind::Image as i32, ObjectKind::Video as i32]),
+				object::nsfw_score::equals(None),
+				object::file_paths::some(vec![file_path::location_id::equals(Some(
+					state.init.location_id,
+				))]),
+			])
+			.select(object_for_nsfw_classifier::select())
+			.exec()
+			.await?;
+
+		state.steps = objects
+			.chunks(OBJECTS_PER_STEP)
+			.map(<[_]>::to_vec)
+			.collect();
+
+		state.data = Some(NsfwClassifierJobData {
+			classifier,
+			report: NsfwClassifierJobReport::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let Library { db, sync, .. } = &ctx.library;
+
+		let objects = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		let mut scores = Vec::with_capacity(objects.len());
+
+		for object in objects {
+			let Some(thumbnail) = object
+				.file_paths
+				.iter()
+				.filter_map(|file_path| file_path.cas_id.as_deref())
+				.map(|cas_id| get_thumbnail_path(&ctx.library, cas_id))
+				.next()
+			else {
+				data.report.skipped += 1;
+				continue;
+			};
+
+			if fs::metadata(&thumbnail).await.is_err() {
+				data.report.skipped += 1;
+				continue;
+			}
+
+			match data.classifier.score(&thumbnail).await {
+				Ok(score) => scores.push((object, score)),
+				Err(e) => {
+					warn!("Failed to classify object <id='{}'>: {e}", object.id);
+					data.report.skipped += 1;
+				}
+			}
+		}
+
+		let threshold = data.classifier.threshold;
+
+		let (ops, queries): (Vec<_>, Vec<_>) = scores
+			.into_iter()
+			.map(|(object, score)| {
+				let sync_id = || sync::object::SyncId {
+					pub_id: object.pub_id.clone(),
+				};
+
+				let mut ops =
+					vec![sync.shared_update(sync_id(), object::nsfw_score::NAME, json!(score))];
+				let mut params = vec![object::nsfw_score::set(Some(score))];
+
+				data.report.scored += 1;
+
+				// A flag set or cleared by hand is kept
+				if object.nsfw.is_none() {
+					let flagged = score >= threshold;
+					if flagged {
+						data.report.flagged += 1;
+					}
+
+					ops.push(sync.shared_update(sync_id(), object::nsfw::NAME, json!(flagged)));
+					params.push(object::nsfw::set(Some(flagged)));
+				}
+
+				(
+					ops,
+					db.object().update(object::id::equals(object.id), params),
+				)
+			})
+			.unzip();
+
+		if !queries.is_empty() {
+			sync.write_ops(db, (ops.into_iter().flatten().collect(), queries))
+				.await?;
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing NSFW classifier job: {report:?}");
+
+		if report.scored > 0 {
+			invalidate_query!(ctx.library, "search.paths");
+			invalidate_query!(ctx.library, "search.objects");
+		}
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ContentRestrictionInfo {
+	pub node_id: Uuid,
+	pub node_name: Option<String>,
+	pub date_created: Option<DateTime<FixedOffset>>,
+}
+
+/// Whether this device is restricted in the library
+pub async fn is_restricted(library: &Library) -> Result<bool, QueryError> {
+	let node_id = library.config().get().await.id;
+
+	library
+		.db
+		.content_restriction()
+		.count(vec![content_restriction::pub_id::equals(uuid_to_bytes(
+			node_id,
+		))])
+		.exec()
+		.await
+		.map(|count| count > 0)
+}
+
+/// Restricts objects to the ones which aren't flagged, `None` unless this device is restricted
+pub async fn visible_objects(library: &Library) -> Result<Option<object::WhereParam>, QueryError> {
+	Ok(is_restricted(library).await?.then(|| {
+		or![
+			object::nsfw::equals(None),
+			object::nsfw::equals(Some(false))
+		]
+	}))
+}
+
+/// Restricts file paths to the ones without a flagged object, `None` unless this device is
+/// restricted
+pub async fn visible_file_paths(
+	library: &Library,
+) -> Result<Option<file_path::WhereParam>, QueryError> {
+	Ok(visible_objects(library).await?.map(|param| {
+		or![
+			file_path::object_id::equals(None),
+			file_path::object::is(vec![param])
+		]
+	}))
+}
+
+pub async fn list_restrictions(
+	library: &Library,
+) -> Result<Vec<ContentRestrictionInfo>, QueryError> {
+	let db = &library.db;
+
+	let restrictions = db
+		.content_restriction()
+		.find_many(vec![])
+		.select(content_restriction::select!({ pub_id date_created }))
+		.exec()
+		.await?;
+
+	let nodes = db
+		.node()
+		.find_many(vec![node::pub_id::in_vec(
+			restrictions
+				.iter()
+				.map(|restriction| restriction.pub_id.clone())
+				.collect(),
+		)])
+		.select(node::select!({ pub_id name }))
+		.exec()
+		.await?;
+
+	Ok(restrictions
+		.into_iter()
+		.filter_map(|restriction| {
+			Some(ContentRestrictionInfo {
+				node_id: Uuid::from_slice(&restriction.pub_id).ok()?,
+				node_name: nodes
+					.iter()
+					.find(|node| node.pub_id == restriction.pub_id)
+					.map(|node| node.name.clone()),
+				date_created: restriction.date_created,
+			})
+		})
+		.collect())
+}
+
+pub async fn restrict(library: &Library, node_id: Uuid, pin: String) -> Result<(), NsfwError> {
+	let Library { db, sync, .. } = library;
+
+	if pin.is_empty() {
+		return Err(NsfwError::EmptyPin);
+	}
+
+	let pub_id = uuid_to_bytes(node_id);
+
+	if db
+		.node()
+		.count(vec![node::pub_id::equals(pub_id.clone())])
+		.exec()
+		.await?
+		== 0
+	{
+		return Err(NsfwError::NodeNotFound(node_id));
+	}
+
+	if find_restriction(library, node_id).await?.is_some() {
+		return Err(NsfwError::AlreadyRestricted(node_id));
+	}
+
+	let salt = Salt::generate();
+	let mut pin_hash = salt.0.to_vec();
+	pin_hash.extend_from_slice(hash_passphrase(pin, salt).await?.as_bytes());
+
+	let date_created = Utc::now();
+
+	sync.write_op(
+		db,
+		sync.unique_shared_create(
+			sync::content_restriction::SyncId {
+				pub_id: pub_id.clone(),
+			},
+			[
+				(content_restriction::pin::NAME, json!(pin_hash)),
+				(content_restriction::date_created::NAME, json!(date_created)),
+			],
+		),
+		db.content_restriction().upsert(
+			content_restriction::pub_id::equals(pub_id.clone()),
+			content_restriction::create(
+				pub_id,
+				vec![
+					content_restriction::pin::set(Some(pin_hash.clone())),
+					content_restriction::date_created::set(Some(date_created.into())),
+				],
+			),
+			vec![
+				content_restriction::pin::set(Some(pin_hash)),
+				content_restriction::date_created::set(Some(date_created.into())),
+			],
+		),
+	)
+	.await?;
+
+	invalidate_restrictions(library);
+
+	Ok(())
+}
+
+pub async fn lift_restriction(
+	library: &Library,
+	node_id: Uuid,
+	pin: String,
+) -> Result<(), NsfwError> {
+	let Library { db, sync, .. } = library;
+
+	let restriction = find_restriction(library, node_id)
+		.await?
+		.ok_or(NsfwError::NotRestricted(node_id))?;
+
+	let pin_hash = restriction.pin.unwrap_or_default();
+	if pin_hash.len() != SALT_LEN + blake3::OUT_LEN {
+		return Err(NsfwError::WrongPin);
+	}
+
+	let (salt, expected) = pin_hash.split_at(SALT_LEN);
+	let salt = Salt::try_from(salt.to_vec()).map_err(LocationPrivacyError::from)?;
+	let expected = blake3::Hash::from(
+		<[u8; blake3::OUT_LEN]>::try_from(expected).expect("length checked above"),
+	);
+
+	// `blake3::Hash` equality is constant time
+	if hash_passphrase(pin, salt).await? != expected {
+		return Err(NsfwError::WrongPin);
+	}
+
+	sync.write_op(
+		db,
+		sync.shared_delete(sync::content_restriction::SyncId {
+			pub_id: restriction.pub_id.clone(),
+		}),
+		db.content_restriction()
+			.delete(content_restriction::pub_id::equals(restriction.pub_id)),
+	)
+	.await?;
+
+	invalidate_restrictions(library);
+
+	Ok(())
+}
+
+async fn find_restriction(
+	library: &Library,
+	node_id: Uuid,
+) -> Result<Option<content_restriction::Data>, QueryError> {
+	library
+		.db
+		.content_restriction()
+		.find_unique(content_restriction::pub_id::equals(uuid_to_bytes(node_id)))
+		.exec()
+		.await
+}
+
+fn invalidate_restrictions(library: &Library) {
+	invalidate_query!(library, "objects.contentRestrictions");
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+	invalidate_query!(library, "search.mediaTimeline");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scores_are_the_first_word() {
+		assert_eq!(parse_score("0.93\n"), Some(0.93));
+		assert_eq!(parse_score("  0 unsafe=0.0 safe=1.0"), Some(0.0));
+		assert_eq!(parse_score("1"), Some(1.0));
+
+		for stdout in ["", "unsafe", "1.5", "-0.1", "NaN"] {
+			assert_eq!(parse_score(stdout), None, "{stdout:?}");
+		}
+	}
+}
@@ -0,0 +1,509 @@
+//! Organization done in the OS file manager: Windows file attributes and macOS Finder tags.
+//!
+//! Both are read when files are identified, attributes going to their file paths and Finder tags
+//! becoming regular tags on their objects. Locations with `sync_finder_tags` enabled also get our
+//! tags written back as Finder tags.
+
+use crate::{
+	library::Library,
+	location::file_path_helper::IsolatedFilePathData,
+	object::tag::find_or_create_tag,
+	prisma::{file_path, location, object, tag, tag_on_object},
+	sync,
+	util::{db::uuid_to_bytes, error::FileIOError},
+};
+
+use std::{
+	borrow::Cow,
+	collections::{HashMap, HashSet},
+	fs::Metadata,
+	path::Path,
+};
+
+use prisma_client_rust::QueryError;
+use serde_json::json;
+use thiserror::Error;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// Bits of `file_path.os_attributes`
+pub mod os_attributes {
+	pub const READ_ONLY: i32 = 1 << 0;
+	pub const HIDDEN: i32 = 1 << 1;
+	pub const SYSTEM: i32 = 1 << 2;
+	pub const ARCHIVE: i32 = 1 << 3;
+}
+
+#[cfg(target_os = "macos")]
+const FINDER_TAGS_XATTR: &str = "com.apple.metadata:_kMDItemUserTags";
+
+#[derive(Error, Debug)]
+pub enum OsMetadataError {
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OsMetadata {
+	pub attributes: i32,
+	pub finder_tags: Vec<FinderTag>,
+}
+
+impl OsMetadata {
+	/// Failing to read Finder tags isn't worth failing the identification of a file for
+	pub async fn read(path: impl AsRef<Path>, fs_metadata: &Metadata) -> Self {
+		let path = path.as_ref();
+
+		let finder_tags = read_finder_tags(path).await.unwrap_or_else(|e| {
+			warn!("Failed to read Finder tags: {e:#?}");
+			vec![]
+		});
+
+		Self {
+			attributes: attributes_from_metadata(fs_metadata),
+			finder_tags,
+		}
+	}
+}
+
+#[cfg(target_family = "windows")]
+fn attributes_from_metadata(fs_metadata: &Metadata) -> i32 {
+	use std::os::windows::fs::MetadataExt;
+	use windows_sys::Win32::Storage::FileSystem::{
+		FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY,
+		FILE_ATTRIBUTE_SYSTEM,
+	};
+
+	let windows_attributes = fs_metadata.file_attributes();
+
+	[
+		(FILE_ATTRIBUTE_READONLY, os_attributes::READ_ONLY),
+		(FILE_ATTRIBUTE_HIDDEN, os_attributes::HIDDEN),
+		(FILE_ATTRIBUTE_SYSTEM, os_attributes::SYSTEM),
+		(FILE_ATTRIBUTE_ARCHIVE, os_attributes::ARCHIVE),
+	]
+	.into_iter()
+	.filter(|(windows_attribute, _)| windows_attributes & windows_attribute != 0)
+	.fold(0, |attributes, (_, attribute)| attributes | attribute)
+}
+
+#[cfg(not(target_family = "windows"))]
+fn attributes_from_metadata(fs_metadata: &Metadata) -> i32 {
+	if fs_metadata.permissions().readonly() {
+		os_attributes::READ_ONLY
+	} else {
+		0
+	}
+}
+
+/// Finder has a fixed palette, tags without a color use `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinderColor {
+	None = 0,
+	Gray = 1,
+	Green = 2,
+	Purple = 3,
+	Blue = 4,
+	Yellow = 5,
+	Red = 6,
+	Orange = 7,
+}
+
+impl FinderColor {
+	const PALETTE: [(Self, &'static str); 7] = [
+		(Self::Gray, "#8E8E93"),
+		(Self::Green, "#34C759"),
+		(Self::Purple, "#AF52DE"),
+		(Self::Blue, "#007AFF"),
+		(Self::Yellow, "#FFCC00"),
+		(Self::Red, "#FF3B30"),
+		(Self::Orange, "#FF9500"),
+	];
+
+	fn from_index(index: u8) -> Self {
+		Self::PALETTE
+			.iter()
+			.find(|(color, _)| *color as u8 == index)
+			.map_or(Self::None, |(color, _)| *color)
+	}
+
+	/// The color given to tags created from a Finder tag
+	pub fn hex(self) -> Option<&'static str> {
+		Self::PALETTE
+			.iter()
+			.find(|(color, _)| *color == self)
+			.map(|(_, hex)| *hex)
+	}
+
+	/// Only our own palette colors round trip, as Finder can't show any other
+	pub fn from_hex(hex: &str) -> Self {
+		Self::PALETTE
+			.iter()
+			.find(|(_, palette_hex)| palette_hex.eq_ignore_ascii_case(hex))
+			.map_or(Self::None, |(color, _)| *color)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FinderTag {
+	pub name: String,
+	pub color: FinderColor,
+}
+
+impl FinderTag {
+	/// Finder stores tags as their name, optionally followed by a new line and a color index
+	pub fn parse(value: &str) -> Self {
+		match value.rsplit_once('\n') {
+			Some((name, index)) => Self {
+				name: name.to_string(),
+				color: index
+					.parse()
+					.map_or(FinderColor::None, FinderColor::from_index),
+			},
+			None => Self {
+				name: value.to_string(),
+				color: FinderColor::None,
+			},
+		}
+	}
+
+	pub fn to_finder_string(&self) -> String {
+		format!("{}\n{}", self.name, self.color as u8)
+	}
+}
+
+#[cfg(target_os = "macos")]
+async fn read_finder_tags(path: &Path) -> Result<Vec<FinderTag>, FileIOError> {
+	let path = path.to_path_buf();
+
+	tokio::task::spawn_blocking(move || {
+		let Some(data) =
+			xattr::get(&path, FINDER_TAGS_XATTR).map_err(|e| FileIOError::from((&path, e)))?
+		else {
+			return Ok(vec![]);
+		};
+
+		plist::from_bytes::<Vec<String>>(&data)
+			.map(|tags| tags.iter().map(|tag| FinderTag::parse(tag)).collect())
+			.map_err(|e| {
+				FileIOError::from((
+					&path,
+					std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+				))
+			})
+	})
+	.await
+	.expect("reading Finder tags panicked")
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn read_finder_tags(_: &Path) -> Result<Vec<FinderTag>, FileIOError> {
+	Ok(vec![])
+}
+
+#[cfg(target_os = "macos")]
+async fn write_finder_tags(path: &Path, tags: Vec<FinderTag>) -> Result<(), FileIOError> {
+	let path = path.to_path_buf();
+
+	tokio::task::spawn_blocking(move || {
+		let io_error = |e| FileIOError::from((&path, e));
+
+		if tags.is_empty() {
+			if xattr::get(&path, FINDER_TAGS_XATTR)
+				.map_err(io_error)?
+				.is_some()
+			{
+				xattr::remove(&path, FINDER_TAGS_XATTR).map_err(io_error)?;
+			}
+
+			return Ok(());
+		}
+
+		let mut data = vec![];
+		plist::to_writer_binary(
+			&mut data,
+			&tags
+				.iter()
+				.map(FinderTag::to_finder_string)
+				.collect::<Vec<_>>(),
+		)
+		.map_err(|e| io_error(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+
+		xattr::set(&path, FINDER_TAGS_XATTR, &data).map_err(io_error)
+	})
+	.await
+	.expect("writing Finder tags panicked")
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn write_finder_tags(_: &Path, _: Vec<FinderTag>) -> Result<(), FileIOError> {
+	Ok(())
+}
+
+/// Stores the attributes of identified file paths, and tags their objects with their Finder tags
+pub async fn apply_os_metadata(
+	library: &Library,
+	entries: Vec<(Uuid, OsMetadata)>,
+) -> Result<(), QueryError> {
+	let Library { db, sync, .. } = library;
+
+	if entries.is_empty() {
+		return Ok(());
+	}
+
+	sync.write_ops(
+		db,
+		entries
+			.iter()
+			.map(|(pub_id, metadata)| {
+				(
+					sync.shared_update(
+						sync::file_path::SyncId {
+							pub_id: uuid_to_bytes(*pub_id),
+						},
+						file_path::os_attributes::NAME,
+						json!(metadata.attributes),
+					),
+					db.file_path().update(
+						file_path::pub_id::equals(uuid_to_bytes(*pub_id)),
+						vec![file_path::os_attributes::set(Some(metadata.attributes))],
+					),
+				)
+			})
+			.unzip::<_, _, Vec<_>, Vec<_>>(),
+	)
+	.await?;
+
+	let tagged = entries
+		.into_iter()
+		.filter(|(_, metadata)| !metadata.finder_tags.is_empty())
+		.map(|(pub_id, metadata)| (uuid_to_bytes(pub_id), metadata.finder_tags))
+		.collect::<HashMap<_, _>>();
+
+	if tagged.is_empty() {
+		return Ok(());
+	}
+
+	let object_ids = db
+		.file_path()
+		.find_many(vec![file_path::pub_id::in_vec(
+			tagged.keys().cloned().collect(),
+		)])
+		.select(file_path::select!({ pub_id object_id }))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|file_path| file_path.object_id.map(|id| (file_path.pub_id, id)))
+		.collect::<HashMap<_, _>>();
+
+	let mut tag_ids = HashMap::new();
+	let mut wanted = HashSet::new();
+	for (pub_id, finder_tags) in &tagged {
+		let Some(&object_id) = object_ids.get(pub_id) else {
+			continue;
+		};
+
+		for finder_tag in finder_tags {
+			let tag_id = match tag_ids.get(&finder_tag.name) {
+				Some(&tag_id) => tag_id,
+				None => {
+					let tag_id = tag_id_for_finder_tag(library, finder_tag).await?;
+					tag_ids.insert(finder_tag.name.clone(), tag_id);
+					tag_id
+				}
+			};
+
+			wanted.insert((tag_id, object_id));
+		}
+	}
+
+	let existing = db
+		.tag_on_object()
+		.find_many(vec![
+			tag_on_object::tag_id::in_vec(tag_ids.values().copied().collect()),
+			tag_on_object::object_id::in_vec(object_ids.values().copied().collect()),
+		])
+		.exec()
+		.await?
+		.into_iter()
+		.map(|tag_on_object| (tag_on_object.tag_id, tag_on_object.object_id))
+		.collect::<HashSet<_>>();
+
+	db.tag_on_object()
+		.create_many(
+			wanted
+				.difference(&existing)
+				.map(|&(tag_id, object_id)| tag_on_object::CreateUnchecked {
+					tag_id,
+					object_id,
+					_params: vec![],
+				})
+				.collect(),
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+async fn tag_id_for_finder_tag(
+	library: &Library,
+	finder_tag: &FinderTag,
+) -> Result<tag::id::Type, QueryError> {
+	let Library { db, sync, .. } = library;
+
+	let (tag_id, created) = find_or_create_tag(library, &finder_tag.name).await?;
+
+	if let (true, Some(hex)) = (created, finder_tag.color.hex()) {
+		let tag = db
+			.tag()
+			.find_unique(tag::id::equals(tag_id))
+			.select(tag::select!({ pub_id }))
+			.exec()
+			.await?;
+
+		if let Some(tag) = tag {
+			sync.write_op(
+				db,
+				sync.shared_update(
+					sync::tag::SyncId { pub_id: tag.pub_id },
+					tag::color::NAME,
+					json!(hex),
+				),
+				db.tag().update(
+					tag::id::equals(tag_id),
+					vec![tag::color::set(Some(hex.to_string()))],
+				),
+			)
+			.await?;
+		}
+	}
+
+	Ok(tag_id)
+}
+
+/// Writes the tags of these objects as Finder tags on their files, in every location with
+/// `sync_finder_tags` enabled. Does nothing outside of macOS.
+pub async fn write_object_finder_tags(
+	library: &Library,
+	object_ids: Vec<object::id::Type>,
+) -> Result<(), OsMetadataError> {
+	if !cfg!(target_os = "macos") {
+		return Ok(());
+	}
+
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::object_id::in_vec(object_ids),
+			file_path::is_dir::equals(Some(false)),
+			file_path::location::is(vec![location::sync_finder_tags::equals(Some(true))]),
+		])
+		.include(file_path::include!({
+			location: select { id path }
+			object: select {
+				tags: select {
+					tag: select { name color }
+				}
+			}
+		}))
+		.exec()
+		.await?;
+
+	for file_path in file_paths {
+		let Some(location) = file_path.location else {
+			continue;
+		};
+		let (Some(location_path), Some(materialized_path), Some(name), Some(extension)) = (
+			location.path,
+			&file_path.materialized_path,
+			&file_path.name,
+			&file_path.extension,
+		) else {
+			continue;
+		};
+
+		let full_path = Path::new(&location_path).join(IsolatedFilePathData::from_db_data(
+			location.id,
+			false,
+			Cow::Borrowed(materialized_path),
+			Cow::Borrowed(name),
+			Cow::Borrowed(extension),
+		));
+
+		let finder_tags = file_path
+			.object
+			.map(|object| {
+				object
+					.tags
+					.into_iter()
+					.filter_map(|tag_on_object| {
+						let tag = tag_on_object.tag;
+						tag.name.map(|name| FinderTag {
+							name,
+							color: tag
+								.color
+								.as_deref()
+								.map_or(FinderColor::None, FinderColor::from_hex),
+						})
+					})
+					.collect()
+			})
+			.unwrap_or_default();
+
+		write_finder_tags(&full_path, finder_tags).await?;
+	}
+
+	Ok(())
+}
+
+/// Logs instead of failing, as Finder tags are a best effort on top of our own tags
+pub async fn write_object_finder_tags_or_log(library: &Library, object_ids: Vec<object::id::Type>) {
+	if let Err(e) = write_object_finder_tags(library, object_ids).await {
+		error!("Failed to write Finder tags: {e:#?}");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn finder_tag_strings() {
+		assert_eq!(
+			FinderTag::parse("Important\n6"),
+			FinderTag {
+				name: "Important".to_string(),
+				color: FinderColor::Red,
+			}
+		);
+		assert_eq!(
+			FinderTag::parse("Work"),
+			FinderTag {
+				name: "Work".to_string(),
+				color: FinderColor::None,
+			}
+		);
+		assert_eq!(FinderTag::parse("Multi\nline\n2").name, "Multi\nline");
+
+		let tag = FinderTag {
+			name: "Blue things".to_string(),
+			color: FinderColor::Blue,
+		};
+		assert_eq!(FinderTag::parse(&tag.to_finder_string()), tag);
+	}
+
+	#[test]
+	fn finder_colors() {
+		assert_eq!(FinderColor::from_hex("#ff3b30"), FinderColor::Red);
+		assert_eq!(FinderColor::from_hex("#123456"), FinderColor::None);
+		assert_eq!(FinderColor::None.hex(), None);
+		assert_eq!(
+			FinderColor::Orange.hex().map(FinderColor::from_hex),
+			Some(FinderColor::Orange)
+		);
+	}
+}
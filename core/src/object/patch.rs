@@ -0,0 +1,259 @@
+//! Editing the metadata of many objects at once, like the selection in the explorer, with a patch
+//! which only touches the fields it names. All the objects get the same values, so each field is
+//! a single query however many objects there are, and a sync operation for each object.
+
+use crate::{
+	invalidate_query,
+	library::Library,
+	object::{
+		nsfw::is_restricted, os_metadata::write_object_finder_tags_or_log,
+		xmp::write_object_sidecars_or_log,
+	},
+	prisma::{object, tag_on_object},
+	sync,
+};
+
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use specta::Type;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ObjectPatchError {
+	#[error("ratings go from 0 to 5 <rating='{0}'>")]
+	InvalidRating(i32),
+	#[error("a tag can't be both added and removed <id='{0}'>")]
+	ConflictingTag(i32),
+	#[error("this device is restricted, so it can't change which objects are NSFW")]
+	Restricted,
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<ObjectPatchError> for rspc::Error {
+	fn from(err: ObjectPatchError) -> Self {
+		match err {
+			ObjectPatchError::InvalidRating(_) | ObjectPatchError::ConflictingTag(_) => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+			}
+			ObjectPatchError::Restricted => {
+				rspc::Error::with_cause(ErrorCode::Forbidden, err.to_string(), err)
+			}
+			ObjectPatchError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+/// A change to a single field, fields without one are left as they are
+#[derive(Deserialize, Type, Debug, Clone)]
+#[serde(tag = "op", content = "value")]
+pub enum FieldPatch<T> {
+	Set(T),
+	Clear,
+}
+
+impl<T> FieldPatch<T> {
+	fn into_value(self) -> Option<T> {
+		match self {
+			Self::Set(value) => Some(value),
+			Self::Clear => None,
+		}
+	}
+}
+
+#[derive(Deserialize, Type, Debug, Clone, Default)]
+pub struct ObjectPatch {
+	#[serde(default)]
+	pub note: Option<FieldPatch<String>>,
+	#[serde(default)]
+	pub rating: Option<FieldPatch<i32>>,
+	#[serde(default)]
+	pub favorite: Option<FieldPatch<bool>>,
+	#[serde(default)]
+	pub important: Option<FieldPatch<bool>>,
+	#[serde(default)]
+	pub hidden: Option<FieldPatch<bool>>,
+	/// Takes precedence over what the classifier decides, see `object::nsfw`
+	#[serde(default)]
+	pub nsfw: Option<FieldPatch<bool>>,
+	#[serde(default)]
+	pub add_tags: Vec<i32>,
+	#[serde(default)]
+	pub remove_tags: Vec<i32>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ObjectPatchReport {
+	pub objects: u32,
+	/// The fields set or cleared, not counting tags
+	pub fields: u32,
+}
+
+impl ObjectPatch {
+	pub(super) fn validate(&self) -> Result<(), ObjectPatchError> {
+		if let Some(FieldPatch::Set(rating)) = self.rating {
+			if !(0..=5).contains(&rating) {
+				return Err(ObjectPatchError::InvalidRating(rating));
+			}
+		}
+
+		if let Some(&tag_id) = self
+			.add_tags
+			.iter()
+			.find(|tag_id| self.remove_tags.contains(tag_id))
+		{
+			return Err(ObjectPatchError::ConflictingTag(tag_id));
+		}
+
+		Ok(())
+	}
+
+	/// The fields to update, both as sync values and database params
+	pub(super) fn fields(self) -> Vec<(&'static str, Value, object::SetParam)> {
+		[
+			self.note.map(|patch| {
+				let v = patch.into_value();
+				(object::note::NAME, json!(v), object::note::set(v))
+			}),
+			self.rating.map(|patch| {
+				let v = patch.into_value();
+				(object::rating::NAME, json!(v), object::rating::set(v))
+			}),
+			self.favorite.map(|patch| {
+				let v = patch.into_value();
+				(object::favorite::NAME, json!(v), object::favorite::set(v))
+			}),
+			self.important.map(|patch| {
+				let v = patch.into_value();
+				(object::important::NAME, json!(v), object::important::set(v))
+			}),
+			self.hidden.map(|patch| {
+				let v = patch.into_value();
+				(object::hidden::NAME, json!(v), object::hidden::set(v))
+			}),
+			self.nsfw.map(|patch| {
+				let v = patch.into_value();
+				(object::nsfw::NAME, json!(v), object::nsfw::set(v))
+			}),
+		]
+		.into_iter()
+		.flatten()
+		.collect()
+	}
+}
+
+/// Applies the patch to all the objects, the fields in a single batch and the tags in another
+pub async fn patch_objects(
+	library: &Library,
+	object_ids: Vec<object::id::Type>,
+	patch: ObjectPatch,
+) -> Result<ObjectPatchReport, ObjectPatchError> {
+	let Library { db, sync, .. } = library;
+
+	patch.validate()?;
+
+	// Otherwise the restriction could be lifted by clearing the flags
+	if patch.nsfw.is_some() && is_restricted(library).await? {
+		return Err(ObjectPatchError::Restricted);
+	}
+
+	let objects = db
+		.object()
+		.find_many(vec![object::id::in_vec(object_ids)])
+		.select(object::select!({ id pub_id }))
+		.exec()
+		.await?;
+
+	if objects.is_empty() {
+		return Ok(ObjectPatchReport {
+			objects: 0,
+			fields: 0,
+		});
+	}
+
+	let ids = objects.iter().map(|object| object.id).collect::<Vec<_>>();
+	let rating_changed = patch.rating.is_some();
+	let tags_changed = !patch.add_tags.is_empty() || !patch.remove_tags.is_empty();
+
+	let tag_ids = [patch.add_tags.as_slice(), patch.remove_tags.as_slice()].concat();
+	let tags_to_add = patch
+		.add_tags
+		.iter()
+		.flat_map(|&tag_id| {
+			ids.iter()
+				.map(move |&object_id| tag_on_object::CreateUnchecked {
+					tag_id,
+					object_id,
+					_params: vec![],
+				})
+		})
+		.collect::<Vec<_>>();
+
+	let fields = patch.fields();
+	let report = ObjectPatchReport {
+		objects: objects.len() as u32,
+		fields: fields.len() as u32,
+	};
+
+	if !fields.is_empty() {
+		let (ops, params): (Vec<_>, Vec<_>) = fields
+			.into_iter()
+			.map(|(field, value, param)| {
+				(
+					objects
+						.iter()
+						.map(|object| {
+							sync.shared_update(
+								sync::object::SyncId {
+									pub_id: object.pub_id.clone(),
+								},
+								field,
+								value.clone(),
+							)
+						})
+						.collect::<Vec<_>>(),
+					param,
+				)
+			})
+			.unzip();
+
+		sync.write_ops(
+			db,
+			(
+				ops.into_iter().flatten().collect(),
+				db.object()
+					.update_many(vec![object::id::in_vec(ids.clone())], params),
+			),
+		)
+		.await?;
+	}
+
+	if tags_changed {
+		// Removing the added tags first keeps adding them to objects which already have them from
+		// failing
+		db._batch((
+			db.tag_on_object().delete_many(vec![
+				tag_on_object::tag_id::in_vec(tag_ids),
+				tag_on_object::object_id::in_vec(ids.clone()),
+			]),
+			db.tag_on_object().create_many(tags_to_add),
+		))
+		.await?;
+
+		write_object_finder_tags_or_log(library, ids.clone()).await;
+		invalidate_query!(library, "tags.getForObject");
+	}
+
+	if rating_changed || tags_changed {
+		write_object_sidecars_or_log(library, ids).await;
+	}
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+
+	Ok(report)
+}
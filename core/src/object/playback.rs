@@ -0,0 +1,203 @@
+//! Where playback of videos and audio stopped, so it can be resumed, on another device too. Players
+//! report their position as they go and the position is synced with the object, the latest report
+//! from any device winning.
+//!
+//! Reports barely moving the position aren't written, which keeps a player reporting every few
+//! seconds from flooding the sync log. Nothing is kept to resume from the very beginning, and
+//! playback reaching the end, where credits roll, finishes the object and clears its position.
+
+use crate::{invalidate_query, library::Library, prisma::object, sync};
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde_json::json;
+use thiserror::Error;
+
+/// Reports less than this far from the position written last aren't written
+const MIN_POSITION_STEP: f64 = 10.0;
+/// Stopping before this is like not having started
+const MIN_RESUME_POSITION: f64 = 10.0;
+/// Playback past this share of the duration is finished
+const FINISHED_RATIO: f64 = 0.95;
+
+#[derive(Error, Debug)]
+pub enum PlaybackError {
+	#[error("object not found <id='{0}'>")]
+	NotFound(object::id::Type),
+	#[error("playback positions and durations are seconds from 0")]
+	InvalidPosition,
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<PlaybackError> for rspc::Error {
+	fn from(err: PlaybackError) -> Self {
+		let code = match err {
+			PlaybackError::NotFound(_) => ErrorCode::NotFound,
+			PlaybackError::InvalidPosition => ErrorCode::BadRequest,
+			PlaybackError::Database(_) => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+/// What a report of the position does to the one stored
+#[derive(Debug, PartialEq)]
+enum Progress {
+	Resumable(f64),
+	/// Just started or finished, there's nothing to resume from
+	Cleared,
+	Unchanged,
+}
+
+fn progress(stored: Option<f64>, position: f64, duration: Option<f64>) -> Progress {
+	let finished = duration.map_or(false, |duration| position >= duration * FINISHED_RATIO);
+
+	if finished || position < MIN_RESUME_POSITION {
+		if stored.is_some() {
+			Progress::Cleared
+		} else {
+			Progress::Unchanged
+		}
+	} else if stored.map_or(false, |stored| {
+		(position - stored).abs() < MIN_POSITION_STEP
+	}) {
+		Progress::Unchanged
+	} else {
+		Progress::Resumable(position)
+	}
+}
+
+/// Records where playback of an object is, `duration` being how long the whole media plays for when
+/// the player knows it. Returns the position stored to resume from.
+pub async fn record_playback(
+	library: &Library,
+	object_id: object::id::Type,
+	position: f64,
+	duration: Option<f64>,
+) -> Result<Option<f64>, PlaybackError> {
+	let valid = |seconds: f64| seconds.is_finite() && seconds >= 0.0;
+	if !valid(position) || !duration.map_or(true, valid) {
+		return Err(PlaybackError::InvalidPosition);
+	}
+
+	let Library { db, sync, .. } = library;
+
+	let object = db
+		.object()
+		.find_unique(object::id::equals(object_id))
+		.select(object::select!({ pub_id playback_position playback_duration }))
+		.exec()
+		.await?
+		.ok_or(PlaybackError::NotFound(object_id))?;
+
+	let resume_from = match progress(object.playback_position, position, duration) {
+		Progress::Unchanged => return Ok(object.playback_position),
+		Progress::Resumable(position) => Some(position),
+		Progress::Cleared => None,
+	};
+
+	// A duration the player doesn't know doesn't erase the one another player reported
+	let duration = duration.or(object.playback_duration);
+	let date_played = Utc::now();
+
+	let sync_id = || sync::object::SyncId {
+		pub_id: object.pub_id.clone(),
+	};
+
+	sync.write_ops(
+		db,
+		(
+			vec![
+				sync.shared_update(
+					sync_id(),
+					object::playback_position::NAME,
+					json!(resume_from),
+				),
+				sync.shared_update(sync_id(), object::playback_duration::NAME, json!(duration)),
+				sync.shared_update(sync_id(), object::date_played::NAME, json!(date_played)),
+			],
+			db.object().update(
+				object::id::equals(object_id),
+				vec![
+					object::playback_position::set(resume_from),
+					object::playback_duration::set(duration),
+					object::date_played::set(Some(date_played.into())),
+				],
+			),
+		),
+	)
+	.await?;
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+
+	Ok(resume_from)
+}
+
+/// Forgets where playback of an object stopped, like marking it as watched
+pub async fn clear_playback(
+	library: &Library,
+	object_id: object::id::Type,
+) -> Result<(), PlaybackError> {
+	let Library { db, sync, .. } = library;
+
+	let object = db
+		.object()
+		.find_unique(object::id::equals(object_id))
+		.select(object::select!({ pub_id }))
+		.exec()
+		.await?
+		.ok_or(PlaybackError::NotFound(object_id))?;
+
+	sync.write_op(
+		db,
+		sync.shared_update(
+			sync::object::SyncId {
+				pub_id: object.pub_id,
+			},
+			object::playback_position::NAME,
+			json!(None::<f64>),
+		),
+		db.object().update(
+			object::id::equals(object_id),
+			vec![object::playback_position::set(None)],
+		),
+	)
+	.await?;
+
+	invalidate_query!(library, "search.paths");
+	invalidate_query!(library, "search.objects");
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn positions_worth_resuming_are_kept() {
+		assert_eq!(
+			progress(None, 600.0, Some(7200.0)),
+			Progress::Resumable(600.0)
+		);
+		assert_eq!(
+			progress(Some(600.0), 605.0, Some(7200.0)),
+			Progress::Unchanged
+		);
+		assert_eq!(
+			progress(Some(600.0), 300.0, None),
+			Progress::Resumable(300.0)
+		);
+		// Credits rolling
+		assert_eq!(
+			progress(Some(6800.0), 6900.0, Some(7200.0)),
+			Progress::Cleared
+		);
+		assert_eq!(progress(Some(600.0), 2.0, None), Progress::Cleared);
+		assert_eq!(progress(None, 2.0, None), Progress::Unchanged);
+	}
+}
@@ -0,0 +1,629 @@
+//! Ebooks and comics, so they can be browsed by their covers and sorted by title, author or series.
+//!
+//! An EPUB is a zip holding a package document, which lists its metadata and which of its images
+//! is the cover. Comic archives are zips (CBZ) or rars (CBR) of page images, sometimes with a
+//! `ComicInfo.xml` as written by ComicRack and most comic managers. Their cover is the first page.
+
+use crate::{
+	library::Library,
+	prisma::{book_data, object, PrismaClient},
+	util::memory_budget::MemoryBudget,
+};
+
+use sd_file_ext::extensions::{BookExtension, Extension};
+
+use std::{
+	cmp::Ordering,
+	error::Error,
+	fs::File,
+	io::{self, Read, Seek},
+	path::Path,
+};
+
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use quick_xml::{
+	events::{BytesStart, Event},
+	Reader,
+};
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+use tracing::error;
+use zip::ZipArchive;
+
+use super::thumbnail::encode_thumbnail;
+
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+const COMIC_INFO_NAME: &str = "comicinfo.xml";
+const PAGE_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "gif", "bmp"];
+/// Covers are decoded whole, and are usually a few megapixels
+const BOOK_THUMBNAIL_MEMORY: u64 = 64 * 1024 * 1024;
+const AUTHORS_SEPARATOR: &str = " & ";
+
+pub(super) static FILTERED_BOOK_EXTENSIONS: Lazy<Vec<Extension>> = Lazy::new(|| {
+	[BookExtension::Epub, BookExtension::Cbz, BookExtension::Cbr]
+		.into_iter()
+		.map(Extension::Book)
+		.collect()
+});
+
+#[derive(Error, Debug)]
+pub enum BookError {
+	#[error("not an ebook or comic archive: <extension='{0}'>")]
+	UnsupportedFormat(String),
+	#[error("invalid EPUB: {0}")]
+	InvalidEpub(&'static str),
+	#[error("entry not found in archive: <name='{0}'>")]
+	MissingEntry(String),
+	#[error("book has no cover")]
+	NoCover,
+	#[error(transparent)]
+	Zip(#[from] zip::result::ZipError),
+	#[error(transparent)]
+	Rar(#[from] unrar::error::UnrarError),
+	#[error(transparent)]
+	Xml(#[from] quick_xml::Error),
+	#[error(transparent)]
+	Io(#[from] io::Error),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BookMetadata {
+	pub title: Option<String>,
+	pub authors: Vec<String>,
+	pub series: Option<String>,
+	/// The number of the book or issue within its series, which may be like 1.5
+	pub series_index: Option<f64>,
+	pub publisher: Option<String>,
+	pub language: Option<String>,
+	/// Only comics have pages, the text of ebooks flows to the screen
+	pub pages_count: Option<u32>,
+}
+
+pub fn is_book_extension(extension: &str) -> bool {
+	FILTERED_BOOK_EXTENSIONS
+		.iter()
+		.any(|book| book.to_string() == extension)
+}
+
+pub fn read_book_metadata(path: impl AsRef<Path>) -> Result<BookMetadata, BookError> {
+	read_book(path.as_ref(), false).map(|(metadata, _)| metadata)
+}
+
+pub fn read_book_cover(path: impl AsRef<Path>) -> Result<Vec<u8>, BookError> {
+	read_book(path.as_ref(), true)?.1.ok_or(BookError::NoCover)
+}
+
+fn read_book(path: &Path, with_cover: bool) -> Result<(BookMetadata, Option<Vec<u8>>), BookError> {
+	let extension = path
+		.extension()
+		.and_then(|extension| extension.to_str())
+		.unwrap_or_default()
+		.to_lowercase();
+
+	let open_zip = || -> Result<_, BookError> { Ok(ZipArchive::new(File::open(path)?)?) };
+
+	match extension.as_str() {
+		"epub" => read_epub(open_zip()?, with_cover),
+		"cbz" => {
+			let mut archive = open_zip()?;
+			let names = archive.file_names().map(ToString::to_string).collect();
+
+			read_comic(names, with_cover, |name| read_zip_entry(&mut archive, name))
+		}
+		"cbr" => {
+			let names = unrar::Archive::new(path)
+				.open_for_listing()?
+				.filter(|entry| entry.as_ref().map_or(true, |entry| !entry.is_directory()))
+				.map(|entry| entry.map(|entry| entry.filename.to_string_lossy().into_owned()))
+				.collect::<Result<Vec<_>, _>>()?;
+
+			read_comic(names, with_cover, |name| read_rar_entry(path, name))
+		}
+		_ => Err(BookError::UnsupportedFormat(extension)),
+	}
+}
+
+fn read_zip_entry<R: Read + Seek>(
+	archive: &mut ZipArchive<R>,
+	name: &str,
+) -> Result<Vec<u8>, BookError> {
+	let mut entry = archive.by_name(name).map_err(|e| match e {
+		zip::result::ZipError::FileNotFound => BookError::MissingEntry(name.to_string()),
+		e => e.into(),
+	})?;
+
+	let mut data = Vec::with_capacity(entry.size() as usize);
+	entry.read_to_end(&mut data)?;
+
+	Ok(data)
+}
+
+/// Rars can't be read at random, so this goes through the archive up to the entry
+fn read_rar_entry(path: &Path, name: &str) -> Result<Vec<u8>, BookError> {
+	let mut archive = unrar::Archive::new(path).open_for_processing()?;
+
+	while let Some(header) = archive.read_header()? {
+		archive = if header.entry().filename.to_string_lossy() == name {
+			return Ok(header.read()?.0);
+		} else {
+			header.skip()?
+		};
+	}
+
+	Err(BookError::MissingEntry(name.to_string()))
+}
+
+fn read_epub<R: Read + Seek>(
+	mut archive: ZipArchive<R>,
+	with_cover: bool,
+) -> Result<(BookMetadata, Option<Vec<u8>>), BookError> {
+	let container = read_zip_entry(&mut archive, CONTAINER_PATH)?;
+	let package_path = package_path(&String::from_utf8_lossy(&container))?.ok_or(
+		BookError::InvalidEpub("container doesn't point to a package"),
+	)?;
+
+	let package = parse_package(&String::from_utf8_lossy(&read_zip_entry(
+		&mut archive,
+		&package_path,
+	)?))?;
+
+	let cover = match package.cover_href {
+		Some(href) if with_cover => Some(read_zip_entry(
+			&mut archive,
+			&resolve_href(&package_path, &href),
+		)?),
+		_ => None,
+	};
+
+	Ok((package.metadata, cover))
+}
+
+fn package_path(container: &str) -> Result<Option<String>, BookError> {
+	let mut reader = Reader::from_str(container);
+
+	loop {
+		match reader.read_event()? {
+			Event::Start(element) | Event::Empty(element)
+				if element.local_name().as_ref() == b"rootfile" =>
+			{
+				return attribute(&element, b"full-path");
+			}
+			Event::Eof => return Ok(None),
+			_ => {}
+		}
+	}
+}
+
+/// Hrefs are relative to the package document and percent encoded
+fn resolve_href(package_path: &str, href: &str) -> String {
+	let href = percent_decode_str(href).decode_utf8_lossy();
+
+	match package_path.rsplit_once('/') {
+		Some((directory, _)) => {
+			let mut segments = directory.split('/').collect::<Vec<_>>();
+			for segment in href.split('/') {
+				match segment {
+					".." => {
+						segments.pop();
+					}
+					"." => {}
+					segment => segments.push(segment),
+				}
+			}
+
+			segments.join("/")
+		}
+		None => href.into_owned(),
+	}
+}
+
+#[derive(Debug, Default)]
+struct Package {
+	metadata: BookMetadata,
+	cover_href: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct ManifestItem {
+	id: String,
+	href: String,
+	media_type: String,
+	properties: String,
+}
+
+fn parse_package(xml: &str) -> Result<Package, BookError> {
+	let mut reader = Reader::from_str(xml);
+	let mut metadata = BookMetadata::default();
+	let mut manifest = vec![];
+	// EPUB 2 names the manifest item of the cover in a `<meta name="cover">`
+	let mut cover_id = None;
+	// Elements being read, with the `property` and `opf:role` attributes which tell apart the
+	// `<meta>` and `<dc:creator>` we want
+	let mut stack = Vec::<(Vec<u8>, Option<String>)>::new();
+
+	loop {
+		match reader.read_event()? {
+			Event::Start(element) => {
+				let qualifier = match element.local_name().as_ref() {
+					b"meta" => attribute(&element, b"property")?,
+					b"creator" => attribute(&element, b"role")?,
+					_ => None,
+				};
+				stack.push((element.local_name().as_ref().to_vec(), qualifier));
+			}
+			Event::Empty(element) => match element.local_name().as_ref() {
+				b"meta" => {
+					let content = attribute(&element, b"content")?;
+					match attribute(&element, b"name")?.as_deref() {
+						Some("cover") => cover_id = content,
+						Some("calibre:series") => metadata.series = content,
+						Some("calibre:series_index") => {
+							metadata.series_index = content.and_then(|index| index.parse().ok())
+						}
+						_ => {}
+					}
+				}
+				b"item" => manifest.push(ManifestItem {
+					id: attribute(&element, b"id")?.unwrap_or_default(),
+					href: attribute(&element, b"href")?.unwrap_or_default(),
+					media_type: attribute(&element, b"media-type")?.unwrap_or_default(),
+					properties: attribute(&element, b"properties")?.unwrap_or_default(),
+				}),
+				_ => {}
+			},
+			Event::End(_) => {
+				stack.pop();
+			}
+			Event::Text(text) => {
+				let text = text.unescape()?;
+				let text = text.trim();
+				if text.is_empty() {
+					continue;
+				}
+
+				let Some((name, qualifier)) = stack.last() else {
+					continue;
+				};
+
+				match (name.as_slice(), qualifier.as_deref()) {
+					(b"title", _) if metadata.title.is_none() => {
+						metadata.title = Some(text.to_string())
+					}
+					// Contributors like editors and illustrators are creators too
+					(b"creator", None | Some("aut")) => metadata.authors.push(text.to_string()),
+					(b"publisher", _) if metadata.publisher.is_none() => {
+						metadata.publisher = Some(text.to_string())
+					}
+					(b"language", _) if metadata.language.is_none() => {
+						metadata.language = Some(text.to_string())
+					}
+					(b"meta", Some("belongs-to-collection")) if metadata.series.is_none() => {
+						metadata.series = Some(text.to_string())
+					}
+					(b"meta", Some("group-position")) if metadata.series_index.is_none() => {
+						metadata.series_index = text.parse().ok()
+					}
+					_ => {}
+				}
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+	}
+
+	// EPUB 3 marks the cover in the manifest, and some books only name their cover file as such
+	let cover_href = manifest
+		.iter()
+		.find(|item| {
+			item.properties
+				.split_whitespace()
+				.any(|p| p == "cover-image")
+		})
+		.or_else(|| {
+			cover_id
+				.as_ref()
+				.and_then(|cover_id| manifest.iter().find(|item| &item.id == cover_id))
+		})
+		.or_else(|| {
+			manifest.iter().find(|item| {
+				item.media_type.starts_with("image/")
+					&& (item.id.to_lowercase().contains("cover")
+						|| item.href.to_lowercase().contains("cover"))
+			})
+		})
+		.map(|item| item.href.clone());
+
+	Ok(Package {
+		metadata,
+		cover_href,
+	})
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>, BookError> {
+	for attribute in element.attributes() {
+		let attribute = attribute.map_err(quick_xml::Error::from)?;
+		if attribute.key.local_name().as_ref() == name {
+			return Ok(Some(attribute.unescape_value()?.into_owned()));
+		}
+	}
+
+	Ok(None)
+}
+
+fn read_comic(
+	names: Vec<String>,
+	with_cover: bool,
+	mut read: impl FnMut(&str) -> Result<Vec<u8>, BookError>,
+) -> Result<(BookMetadata, Option<Vec<u8>>), BookError> {
+	let mut pages = names
+		.iter()
+		.filter(|name| is_page(name))
+		.map(String::as_str)
+		.collect::<Vec<_>>();
+	pages.sort_by(|a, b| natural_cmp(a, b));
+
+	let (mut metadata, cover_page) = match names
+		.iter()
+		.find(|name| name.eq_ignore_ascii_case(COMIC_INFO_NAME))
+	{
+		Some(name) => parse_comic_info(&String::from_utf8_lossy(&read(name)?))?,
+		None => (BookMetadata::default(), None),
+	};
+
+	metadata.pages_count = metadata.pages_count.or(Some(pages.len() as u32));
+
+	let cover = match cover_page
+		.and_then(|page| pages.get(page))
+		.or_else(|| pages.first())
+	{
+		Some(page) if with_cover => Some(read(page)?),
+		_ => None,
+	};
+
+	Ok((metadata, cover))
+}
+
+/// Images in the archive, leaving out the resource forks macOS zips some archives with
+fn is_page(name: &str) -> bool {
+	let file_name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+
+	!name.starts_with("__MACOSX")
+		&& !file_name.starts_with('.')
+		&& file_name.rsplit_once('.').map_or(false, |(_, extension)| {
+			PAGE_EXTENSIONS
+				.iter()
+				.any(|page| page.eq_ignore_ascii_case(extension))
+		})
+}
+
+/// Sorts `page2.jpg` before `page10.jpg`, as not every comic pads its page numbers
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+	fn chunks(name: &str) -> Vec<(Option<u64>, String)> {
+		let mut chunks = vec![];
+		let mut chars = name.chars().peekable();
+
+		while let Some(first) = chars.peek().copied() {
+			let is_number = first.is_ascii_digit();
+			let mut chunk = String::new();
+			while let Some(c) = chars.next_if(|c| c.is_ascii_digit() == is_number) {
+				chunk.extend(c.to_lowercase());
+			}
+
+			chunks.push(if is_number {
+				(chunk.parse().ok(), String::new())
+			} else {
+				(None, chunk)
+			});
+		}
+
+		chunks
+	}
+
+	chunks(a).cmp(&chunks(b)).then_with(|| a.cmp(b))
+}
+
+/// Reads the metadata and the index of the cover page from a `ComicInfo.xml`
+fn parse_comic_info(xml: &str) -> Result<(BookMetadata, Option<usize>), BookError> {
+	let mut reader = Reader::from_str(xml);
+	let mut metadata = BookMetadata::default();
+	let mut cover_page = None;
+	let mut current = Vec::new();
+
+	loop {
+		match reader.read_event()? {
+			Event::Start(element) => current = element.local_name().as_ref().to_vec(),
+			Event::Empty(element) if element.local_name().as_ref() == b"Page" => {
+				if attribute(&element, b"Type")?.as_deref() == Some("FrontCover") {
+					cover_page = cover_page
+						.or(attribute(&element, b"Image")?.and_then(|page| page.parse().ok()));
+				}
+			}
+			Event::End(_) => current.clear(),
+			Event::Text(text) => {
+				let text = text.unescape()?.trim().to_string();
+				if text.is_empty() {
+					continue;
+				}
+
+				match current.as_slice() {
+					b"Title" => metadata.title = Some(text),
+					b"Series" => metadata.series = Some(text),
+					b"Number" => metadata.series_index = text.parse().ok(),
+					b"Writer" => {
+						metadata.authors = text
+							.split(',')
+							.map(str::trim)
+							.filter(|author| !author.is_empty())
+							.map(ToString::to_string)
+							.collect()
+					}
+					b"Publisher" => metadata.publisher = Some(text),
+					b"LanguageISO" => metadata.language = Some(text),
+					b"PageCount" => metadata.pages_count = text.parse().ok(),
+					_ => {}
+				}
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+	}
+
+	Ok((metadata, cover_page))
+}
+
+pub async fn generate_book_thumbnail<P: AsRef<Path>>(
+	file_path: P,
+	output_path: P,
+	memory_budget: &MemoryBudget,
+) -> Result<(), Box<dyn Error>> {
+	let _permit = memory_budget.acquire(BOOK_THUMBNAIL_MEMORY).await;
+
+	let webp = block_in_place(|| -> Result<Vec<u8>, Box<dyn Error>> {
+		encode_thumbnail(&image::load_from_memory(&read_book_cover(file_path)?)?)
+	})?;
+
+	fs::write(output_path, &webp).await.map_err(Into::into)
+}
+
+/// Extracts the metadata of a book for its object, unless it already was
+pub async fn extract_book_data(
+	db: &PrismaClient,
+	path: impl AsRef<Path>,
+	object_id: object::id::Type,
+) -> Result<(), Box<dyn Error>> {
+	if db
+		.book_data()
+		.find_unique(book_data::id::equals(object_id))
+		.exec()
+		.await?
+		.is_some()
+	{
+		return Ok(());
+	}
+
+	let metadata = block_in_place(|| read_book_metadata(path))?;
+
+	let params = vec![
+		book_data::title::set(metadata.title),
+		book_data::authors::set(
+			(!metadata.authors.is_empty()).then(|| metadata.authors.join(AUTHORS_SEPARATOR)),
+		),
+		book_data::series::set(metadata.series),
+		book_data::series_index::set(metadata.series_index),
+		book_data::publisher::set(metadata.publisher),
+		book_data::language::set(metadata.language),
+		book_data::pages_count::set(metadata.pages_count.map(|count| count as i32)),
+	];
+
+	db.book_data()
+		.upsert(
+			book_data::id::equals(object_id),
+			book_data::create_unchecked(object_id, params.clone()),
+			params,
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+pub async fn extract_book_data_or_log(
+	library: &Library,
+	path: impl AsRef<Path>,
+	object_id: object::id::Type,
+) {
+	let path = path.as_ref();
+
+	if let Err(e) = extract_book_data(&library.db, path, object_id).await {
+		error!(
+			"Failed to extract the metadata of book {}: {e:#?}",
+			path.display()
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn epub_package() {
+		let package = parse_package(
+			r##"<?xml version="1.0" encoding="UTF-8"?>
+			<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+				<metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+					<dc:title>The Fellowship of the Ring</dc:title>
+					<dc:creator opf:role="aut">J. R. R. Tolkien</dc:creator>
+					<dc:creator opf:role="ill">Alan Lee</dc:creator>
+					<dc:language>en</dc:language>
+					<dc:publisher>Allen &amp; Unwin</dc:publisher>
+					<meta property="belongs-to-collection" id="series">The Lord of the Rings</meta>
+					<meta refines="#series" property="group-position">1</meta>
+					<meta name="cover" content="old-cover"/>
+				</metadata>
+				<manifest>
+					<item id="old-cover" href="images/old.jpg" media-type="image/jpeg"/>
+					<item id="cover" href="images/cover%20art.jpg" media-type="image/jpeg" properties="cover-image"/>
+				</manifest>
+			</package>"##,
+		)
+		.unwrap();
+
+		assert_eq!(
+			package.metadata,
+			BookMetadata {
+				title: Some("The Fellowship of the Ring".to_string()),
+				authors: vec!["J. R. R. Tolkien".to_string()],
+				series: Some("The Lord of the Rings".to_string()),
+				series_index: Some(1.0),
+				publisher: Some("Allen & Unwin".to_string()),
+				language: Some("en".to_string()),
+				pages_count: None,
+			}
+		);
+		assert_eq!(
+			resolve_href("OEBPS/content.opf", &package.cover_href.unwrap()),
+			"OEBPS/images/cover art.jpg"
+		);
+		assert_eq!(
+			resolve_href("OEBPS/text/content.opf", "../cover.png"),
+			"OEBPS/cover.png"
+		);
+	}
+
+	#[test]
+	fn comic_pages_and_info() {
+		let names = [
+			"Issue 1/page10.jpg",
+			"Issue 1/page2.jpg",
+			"Issue 1/page1.JPG",
+			"__MACOSX/Issue 1/._page1.JPG",
+			"Issue 1/.DS_Store",
+			"ComicInfo.xml",
+		]
+		.map(ToString::to_string)
+		.to_vec();
+
+		let (metadata, cover) = read_comic(names, true, |name| match name {
+			"ComicInfo.xml" => Ok(br#"<ComicInfo>
+					<Series>Saga</Series>
+					<Number>1</Number>
+					<Writer>Brian K. Vaughan, Fiona Staples</Writer>
+					<Pages>
+						<Page Image="0" Type="InnerCover"/>
+						<Page Image="1" Type="FrontCover"/>
+					</Pages>
+				</ComicInfo>"#
+				.to_vec()),
+			page => Ok(page.as_bytes().to_vec()),
+		})
+		.unwrap();
+
+		assert_eq!(metadata.series.as_deref(), Some("Saga"));
+		assert_eq!(metadata.series_index, Some(1.0));
+		assert_eq!(metadata.authors, ["Brian K. Vaughan", "Fiona Staples"]);
+		assert_eq!(metadata.pages_count, Some(3));
+		assert_eq!(cover.as_deref(), Some(&b"Issue 1/page2.jpg"[..]));
+	}
+}
@@ -0,0 +1,481 @@
+//! Fonts, so a collection can be browsed by how it looks. The thumbnail of a font is a specimen set
+//! in it, and its names, style and the scripts it covers are kept in `font_data`.
+//!
+//! WOFF files are TrueType or OpenType fonts with each table compressed on its own, they're unpacked
+//! back to a plain font first. WOFF2 also transforms the glyph tables, it isn't supported yet.
+
+use crate::{
+	library::Library,
+	prisma::{font_data, object, PrismaClient},
+	util::memory_budget::MemoryBudget,
+};
+
+use sd_file_ext::extensions::{Extension, FontExtension};
+
+use std::{
+	error::Error,
+	io::{self, Read},
+	ops::Deref,
+	path::Path,
+};
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, Rgba, RgbaImage};
+use once_cell::sync::Lazy;
+use thiserror::Error;
+use tokio::{fs, task::block_in_place};
+use tracing::error;
+use ttf_parser::{name_id, Face, FaceParsingError};
+use webp::Encoder;
+
+use super::thumbnail::THUMBNAIL_QUALITY;
+
+const SPECIMEN_WIDTH: u32 = 512;
+const SPECIMEN_HEIGHT: u32 = 384;
+const SPECIMEN_MARGIN: f32 = 24.0;
+/// The lines of the specimen and their size in pixels
+const SPECIMEN_LINES: [(&str, f32); 4] = [
+	("Aa Gg", 128.0),
+	("The quick brown fox", 40.0),
+	("jumps over the lazy dog", 40.0),
+	("0123456789", 40.0),
+];
+/// Fonts without latin letters are shown with the first characters they have
+const FALLBACK_SPECIMEN_CHARS: usize = 8;
+/// Fonts are small, the specimen being rendered is most of what a thumbnail takes
+const FONT_THUMBNAIL_MEMORY: u64 = 16 * 1024 * 1024;
+
+const WOFF_SIGNATURE: &[u8; 4] = b"wOFF";
+const WOFF_HEADER_LENGTH: usize = 44;
+const WOFF_TABLE_ENTRY_LENGTH: usize = 20;
+const SFNT_HEADER_LENGTH: usize = 12;
+const SFNT_TABLE_RECORD_LENGTH: usize = 16;
+
+/// A few characters of each script, a font covers a script when it has a glyph for all of them
+const SCRIPT_SAMPLES: [(&str, &[char]); 11] = [
+	("latin", &['a', 'z', 'A', 'Z']),
+	("greek", &['α', 'ω', 'Σ']),
+	("cyrillic", &['а', 'я', 'Ж']),
+	("arabic", &['ا', 'ب', 'ي']),
+	("hebrew", &['א', 'ש']),
+	("devanagari", &['क', 'ह']),
+	("thai", &['ก', 'ฮ']),
+	("han", &['中', '字']),
+	("kana", &['あ', 'ア']),
+	("hangul", &['한', '글']),
+	("symbols", &['→', '★']),
+];
+
+pub(super) static FILTERED_FONT_EXTENSIONS: Lazy<Vec<Extension>> = Lazy::new(|| {
+	[FontExtension::Ttf, FontExtension::Otf, FontExtension::Woff]
+		.into_iter()
+		.map(Extension::Font)
+		.collect()
+});
+
+#[derive(Error, Debug)]
+pub enum FontError {
+	#[error("invalid font: {0}")]
+	Parse(#[from] FaceParsingError),
+	#[error("invalid WOFF font")]
+	InvalidWoff,
+	#[error("font has no glyph to render a specimen with")]
+	NothingToRender,
+	#[error(transparent)]
+	Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FontMetadata {
+	pub family: Option<String>,
+	/// The style within the family, like "Bold Italic"
+	pub subfamily: Option<String>,
+	pub full_name: Option<String>,
+	/// From 100 for thin to 900 for black
+	pub weight: u16,
+	pub italic: bool,
+	pub monospaced: bool,
+	pub glyphs_count: u16,
+	pub scripts: Vec<&'static str>,
+}
+
+pub fn is_font_extension(extension: &str) -> bool {
+	FILTERED_FONT_EXTENSIONS
+		.iter()
+		.any(|font| font.to_string() == extension)
+}
+
+/// Reads a font file, unpacking WOFF fonts
+pub fn read_font(path: impl AsRef<Path>) -> Result<Vec<u8>, FontError> {
+	let data = std::fs::read(path)?;
+
+	if data.starts_with(WOFF_SIGNATURE) {
+		woff_to_sfnt(&data)
+	} else {
+		Ok(data)
+	}
+}
+
+fn woff_to_sfnt(woff: &[u8]) -> Result<Vec<u8>, FontError> {
+	let read_u16 = |at: usize| -> Result<u16, FontError> {
+		woff.get(at..at + 2)
+			.map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+			.ok_or(FontError::InvalidWoff)
+	};
+	let read_u32 = |at: usize| -> Result<u32, FontError> {
+		woff.get(at..at + 4)
+			.map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+			.ok_or(FontError::InvalidWoff)
+	};
+
+	let flavor = read_u32(4)?;
+	let tables_count = read_u16(12)?;
+
+	// Powers of two the sfnt header carries to speed up binary searches of the table records
+	let mut search_range = 1;
+	let mut entry_selector = 0;
+	while search_range * 2 <= tables_count {
+		search_range *= 2;
+		entry_selector += 1;
+	}
+	search_range *= SFNT_TABLE_RECORD_LENGTH as u16;
+
+	let mut sfnt = Vec::with_capacity(read_u32(16)? as usize);
+	sfnt.extend_from_slice(&flavor.to_be_bytes());
+	sfnt.extend_from_slice(&tables_count.to_be_bytes());
+	sfnt.extend_from_slice(&search_range.to_be_bytes());
+	sfnt.extend_from_slice(&entry_selector.to_be_bytes());
+	sfnt.extend_from_slice(
+		&(tables_count * SFNT_TABLE_RECORD_LENGTH as u16 - search_range).to_be_bytes(),
+	);
+
+	let mut tables = Vec::with_capacity(tables_count as usize);
+	for index in 0..tables_count as usize {
+		let entry = WOFF_HEADER_LENGTH + index * WOFF_TABLE_ENTRY_LENGTH;
+		let (tag, offset, compressed_length, length, checksum) = (
+			read_u32(entry)?,
+			read_u32(entry + 4)? as usize,
+			read_u32(entry + 8)? as usize,
+			read_u32(entry + 12)? as usize,
+			read_u32(entry + 16)?,
+		);
+
+		let compressed = woff
+			.get(offset..offset + compressed_length)
+			.ok_or(FontError::InvalidWoff)?;
+
+		let table = if compressed_length < length {
+			let mut table = Vec::with_capacity(length);
+			ZlibDecoder::new(compressed)
+				.take(length as u64)
+				.read_to_end(&mut table)?;
+			table
+		} else {
+			compressed.to_vec()
+		};
+
+		if table.len() != length {
+			return Err(FontError::InvalidWoff);
+		}
+
+		tables.push((tag, checksum, table));
+	}
+
+	let mut table_offset = SFNT_HEADER_LENGTH + tables.len() * SFNT_TABLE_RECORD_LENGTH;
+	for (tag, checksum, table) in &tables {
+		sfnt.extend_from_slice(&tag.to_be_bytes());
+		sfnt.extend_from_slice(&checksum.to_be_bytes());
+		sfnt.extend_from_slice(&(table_offset as u32).to_be_bytes());
+		sfnt.extend_from_slice(&(table.len() as u32).to_be_bytes());
+
+		table_offset += padded(table.len());
+	}
+
+	// Tables start at 4 bytes boundaries
+	for (_, _, table) in &tables {
+		sfnt.extend_from_slice(table);
+		sfnt.resize(padded(sfnt.len()), 0);
+	}
+
+	Ok(sfnt)
+}
+
+fn padded(length: usize) -> usize {
+	(length + 3) & !3
+}
+
+pub fn font_metadata(data: &[u8]) -> Result<FontMetadata, FontError> {
+	let face = Face::parse(data, 0)?;
+
+	// The typographic names group every style of a family, where the legacy ones stop at four
+	let name = |ids: &[u16]| {
+		ids.iter().find_map(|id| {
+			face.names()
+				.into_iter()
+				.filter(|name| name.name_id == *id)
+				.find_map(|name| name.to_string())
+		})
+	};
+
+	Ok(FontMetadata {
+		family: name(&[name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY]),
+		subfamily: name(&[name_id::TYPOGRAPHIC_SUBFAMILY, name_id::SUBFAMILY]),
+		full_name: name(&[name_id::FULL_NAME]),
+		weight: face.weight().to_number(),
+		italic: face.is_italic(),
+		monospaced: face.is_monospaced(),
+		glyphs_count: face.number_of_glyphs(),
+		scripts: SCRIPT_SAMPLES
+			.iter()
+			.filter(|(_, samples)| {
+				samples
+					.iter()
+					.all(|sample| face.glyph_index(*sample).is_some())
+			})
+			.map(|(script, _)| *script)
+			.collect(),
+	})
+}
+
+/// Sets a few lines of text in the font, black on white
+pub fn render_specimen(data: &[u8]) -> Result<RgbaImage, FontError> {
+	let font = FontRef::try_from_slice(data).map_err(|_| FontError::NothingToRender)?;
+	let has_glyph = |c: char| font.glyph_id(c).0 != 0;
+
+	let fallback;
+	let lines = if "AaGg".chars().all(has_glyph) {
+		SPECIMEN_LINES.to_vec()
+	} else {
+		let face = Face::parse(data, 0)?;
+		fallback = face
+			.tables()
+			.cmap
+			.into_iter()
+			.flat_map(|cmap| cmap.subtables)
+			.filter(|subtable| subtable.is_unicode())
+			.flat_map(|subtable| {
+				let mut codepoints = vec![];
+				subtable.codepoints(|codepoint| codepoints.push(codepoint));
+				codepoints
+			})
+			.filter_map(char::from_u32)
+			.filter(|c| !c.is_whitespace() && !c.is_control() && has_glyph(*c))
+			.take(FALLBACK_SPECIMEN_CHARS)
+			.collect::<String>();
+
+		if fallback.is_empty() {
+			return Err(FontError::NothingToRender);
+		}
+
+		vec![(fallback.as_str(), SPECIMEN_LINES[0].1)]
+	};
+
+	let mut image =
+		RgbaImage::from_pixel(SPECIMEN_WIDTH, SPECIMEN_HEIGHT, Rgba([255, 255, 255, 255]));
+
+	let mut baseline = SPECIMEN_MARGIN;
+	for (text, size) in lines {
+		let scaled = font.as_scaled(PxScale::from(size));
+		baseline += scaled.ascent();
+
+		let mut x = SPECIMEN_MARGIN;
+		let mut previous = None;
+		for c in text.chars() {
+			let glyph_id = scaled.glyph_id(c);
+			if let Some(previous) = previous {
+				x += scaled.kern(previous, glyph_id);
+			}
+			previous = Some(glyph_id);
+
+			let glyph = glyph_id.with_scale_and_position(size, point(x, baseline));
+			x += scaled.h_advance(glyph_id);
+
+			let Some(outlined) = font.outline_glyph(glyph) else {
+				continue;
+			};
+
+			let bounds = outlined.px_bounds();
+			outlined.draw(|glyph_x, glyph_y, coverage| {
+				let (x, y) = (
+					bounds.min.x as i64 + glyph_x as i64,
+					bounds.min.y as i64 + glyph_y as i64,
+				);
+
+				// Glyphs running past the edges are cut
+				if x >= 0 && y >= 0 && (x as u32) < SPECIMEN_WIDTH && (y as u32) < SPECIMEN_HEIGHT {
+					let pixel = image.get_pixel_mut(x as u32, y as u32);
+					let shade = (255.0 * (1.0 - coverage.min(1.0))) as u8;
+					let shade = shade.min(pixel[0]);
+					*pixel = Rgba([shade, shade, shade, 255]);
+				}
+			});
+		}
+
+		baseline += -scaled.descent() + scaled.line_gap();
+	}
+
+	Ok(image)
+}
+
+pub async fn generate_font_thumbnail<P: AsRef<Path>>(
+	file_path: P,
+	output_path: P,
+	memory_budget: &MemoryBudget,
+) -> Result<(), Box<dyn Error>> {
+	let _permit = memory_budget.acquire(FONT_THUMBNAIL_MEMORY).await;
+
+	let webp = block_in_place(|| -> Result<Vec<u8>, Box<dyn Error>> {
+		let specimen = render_specimen(&read_font(file_path)?)?;
+
+		Ok(Encoder::from_image(&DynamicImage::ImageRgba8(specimen))?
+			.encode(THUMBNAIL_QUALITY)
+			.deref()
+			.to_owned())
+	})?;
+
+	fs::write(output_path, &webp).await.map_err(Into::into)
+}
+
+/// Extracts the metadata of a font for its object, unless it already was
+pub async fn extract_font_data(
+	db: &PrismaClient,
+	path: impl AsRef<Path>,
+	object_id: object::id::Type,
+) -> Result<(), Box<dyn Error>> {
+	if db
+		.font_data()
+		.find_unique(font_data::id::equals(object_id))
+		.exec()
+		.await?
+		.is_some()
+	{
+		return Ok(());
+	}
+
+	let metadata = block_in_place(|| font_metadata(&read_font(path)?))?;
+
+	let params = vec![
+		font_data::family::set(metadata.family),
+		font_data::subfamily::set(metadata.subfamily),
+		font_data::full_name::set(metadata.full_name),
+		font_data::weight::set(Some(metadata.weight as i32)),
+		font_data::italic::set(Some(metadata.italic)),
+		font_data::monospaced::set(Some(metadata.monospaced)),
+		font_data::glyphs_count::set(Some(metadata.glyphs_count as i32)),
+		font_data::scripts::set(Some(metadata.scripts.join(","))),
+	];
+
+	db.font_data()
+		.upsert(
+			font_data::id::equals(object_id),
+			font_data::create_unchecked(object_id, params.clone()),
+			params,
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+pub async fn extract_font_data_or_log(
+	library: &Library,
+	path: impl AsRef<Path>,
+	object_id: object::id::Type,
+) {
+	let path = path.as_ref();
+
+	if let Err(e) = extract_font_data(&library.db, path, object_id).await {
+		error!(
+			"Failed to extract the metadata of font {}: {e:#?}",
+			path.display()
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Write;
+
+	use flate2::{write::ZlibEncoder, Compression};
+
+	/// A WOFF font of a fake sfnt, holding a compressed table and one stored as is
+	fn woff(tables: &[([u8; 4], &[u8], bool)]) -> Vec<u8> {
+		let mut entries = vec![];
+		let mut data = vec![];
+		let data_offset = WOFF_HEADER_LENGTH + tables.len() * WOFF_TABLE_ENTRY_LENGTH;
+
+		for (tag, table, compress) in tables {
+			let stored = if *compress {
+				let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+				encoder.write_all(table).unwrap();
+				encoder.finish().unwrap()
+			} else {
+				table.to_vec()
+			};
+
+			entries.extend_from_slice(tag);
+			entries.extend_from_slice(&((data_offset + data.len()) as u32).to_be_bytes());
+			entries.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+			entries.extend_from_slice(&(table.len() as u32).to_be_bytes());
+			entries.extend_from_slice(&0u32.to_be_bytes());
+
+			data.extend_from_slice(&stored);
+			data.resize(padded(data.len()), 0);
+		}
+
+		let mut woff = WOFF_SIGNATURE.to_vec();
+		woff.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+		woff.extend_from_slice(&0u32.to_be_bytes());
+		woff.extend_from_slice(&(tables.len() as u16).to_be_bytes());
+		woff.resize(WOFF_HEADER_LENGTH, 0);
+		woff.extend_from_slice(&entries);
+		woff.extend_from_slice(&data);
+
+		woff
+	}
+
+	#[test]
+	fn woff_tables_are_unpacked() {
+		let glyphs = [7u8; 300];
+		let sfnt = woff_to_sfnt(&woff(&[
+			(*b"glyf", &glyphs, true),
+			(*b"name", b"abc", false),
+		]))
+		.unwrap();
+
+		assert_eq!(&sfnt[..4], &0x0001_0000u32.to_be_bytes());
+		assert_eq!(u16::from_be_bytes([sfnt[4], sfnt[5]]), 2);
+
+		let record = |index: usize| {
+			let at = SFNT_HEADER_LENGTH + index * SFNT_TABLE_RECORD_LENGTH;
+			let field = |offset: usize| {
+				u32::from_be_bytes(sfnt[at + offset..at + offset + 4].try_into().unwrap()) as usize
+			};
+
+			(&sfnt[at..at + 4], field(8), field(12))
+		};
+
+		let (tag, offset, length) = record(0);
+		assert_eq!(tag, b"glyf");
+		assert_eq!(&sfnt[offset..offset + length], &glyphs);
+
+		let (tag, offset, length) = record(1);
+		assert_eq!(tag, b"name");
+		assert_eq!(offset % 4, 0);
+		assert_eq!(&sfnt[offset..offset + length], b"abc");
+	}
+
+	#[test]
+	fn truncated_woff_is_refused() {
+		let mut truncated = woff(&[(*b"glyf", &[1; 64], true)]);
+		truncated.truncate(WOFF_HEADER_LENGTH + 8);
+
+		assert!(matches!(
+			woff_to_sfnt(&truncated),
+			Err(FontError::InvalidWoff)
+		));
+	}
+}
@@ -1,4 +1,7 @@
+pub mod book;
+pub mod font;
 mod media_data;
+pub mod text;
 mod thumbnail;
 
 pub use media_data::*;
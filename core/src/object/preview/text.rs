@@ -0,0 +1,363 @@
+//! Previews of text and code files, the beginning of their content decoded to a string along with
+//! the language to highlight it as, so clients don't each guess encodings or read whole logs.
+//!
+//! Files with a byte order mark are decoded as it says. Without one, UTF-16 is recognized by the
+//! zero bytes of mostly ASCII text, then UTF-8 is tried, and anything else is read as Latin-1, which
+//! decodes any byte. Files with zero bytes in them otherwise are binary and have no preview.
+
+use crate::{
+	library::Library,
+	location::redaction::Redaction,
+	object::fs::{
+		compress::decompress_to_cache,
+		ghost::{retrieved_file_path, ReachableLocations},
+	},
+	prisma::{file_path, location},
+	util::error::FileIOError,
+};
+
+use std::path::PathBuf;
+
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::Serialize;
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, io::AsyncReadExt};
+
+pub const DEFAULT_PREVIEW_KB: u32 = 64;
+pub const MAX_PREVIEW_KB: u32 = 1024;
+/// How much of the content the encoding is guessed from
+const SNIFF_LEN: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum TextPreviewError {
+	#[error("file not found <id='{0}'>")]
+	NotFound(file_path::id::Type),
+	#[error("directories have no text preview")]
+	IsDirectory,
+	#[error("the file isn't text")]
+	Binary,
+	#[error("the file is in a sensitive location while redaction mode is on")]
+	Redacted,
+	#[error("the content of the file isn't on this device")]
+	Unavailable,
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<TextPreviewError> for rspc::Error {
+	fn from(err: TextPreviewError) -> Self {
+		let code = match err {
+			TextPreviewError::NotFound(_) | TextPreviewError::Unavailable => ErrorCode::NotFound,
+			TextPreviewError::IsDirectory | TextPreviewError::Binary => ErrorCode::BadRequest,
+			TextPreviewError::Redacted => ErrorCode::Forbidden,
+			TextPreviewError::FileIO(_) | TextPreviewError::Database(_) => {
+				ErrorCode::InternalServerError
+			}
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
+
+#[derive(Serialize, Type, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+	Utf8,
+	Utf16Le,
+	Utf16Be,
+	Latin1,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct TextPreview {
+	pub text: String,
+	pub encoding: TextEncoding,
+	/// The language to highlight the text as, named like highlight.js and Shiki name them
+	pub language: Option<&'static str>,
+	/// Only the beginning of the file is in `text`
+	pub truncated: bool,
+}
+
+/// The beginning of a text file of a location, `max_kb` kilobytes of it at most
+pub async fn text_preview(
+	library: &Library,
+	location_id: location::id::Type,
+	file_path_id: file_path::id::Type,
+	max_kb: u32,
+) -> Result<TextPreview, TextPreviewError> {
+	let file_path = library
+		.db
+		.file_path()
+		.find_unique(file_path::id::equals(file_path_id))
+		.exec()
+		.await?
+		.filter(|file_path| file_path.location_id == Some(location_id))
+		.ok_or(TextPreviewError::NotFound(file_path_id))?;
+
+	if file_path.is_dir == Some(true) {
+		return Err(TextPreviewError::IsDirectory);
+	}
+
+	if Redaction::fetch(library)
+		.await?
+		.is_redacted(file_path.location_id)
+	{
+		return Err(TextPreviewError::Redacted);
+	}
+
+	let path = content_path(library, &file_path).await?;
+	let max_len = max_kb.clamp(1, MAX_PREVIEW_KB) as usize * 1024;
+
+	let mut file = fs::File::open(&path)
+		.await
+		.map_err(|e| FileIOError::from((&path, e)))?;
+	let size = file
+		.metadata()
+		.await
+		.map_err(|e| FileIOError::from((&path, e)))?
+		.len();
+
+	let mut bytes = Vec::with_capacity(max_len.min(size as usize));
+	(&mut file)
+		.take(max_len as u64)
+		.read_to_end(&mut bytes)
+		.await
+		.map_err(|e| FileIOError::from((&path, e)))?;
+
+	let truncated = size > max_len as u64;
+	let (text, encoding) = decode(&bytes, !truncated).ok_or(TextPreviewError::Binary)?;
+
+	let name = file_path.name.as_deref().unwrap_or_default();
+	let extension = file_path.extension.as_deref().unwrap_or_default();
+
+	Ok(TextPreview {
+		language: language(name, extension, text.lines().next().unwrap_or_default()),
+		text,
+		encoding,
+		truncated,
+	})
+}
+
+/// Where the content of a file can be read from this device, wherever it's kept
+async fn content_path(
+	library: &Library,
+	file_path: &file_path::Data,
+) -> Result<PathBuf, TextPreviewError> {
+	if file_path.compressed_at.is_some() {
+		return Ok(decompress_to_cache(library, &file_path.pub_id).await?);
+	}
+
+	if let Some(path) = ReachableLocations::fetch(library)
+		.await?
+		.content_path(file_path)
+	{
+		return Ok(path);
+	}
+
+	let retrieved = retrieved_file_path(library, &file_path.pub_id);
+	if fs::metadata(&retrieved).await.is_ok() {
+		Ok(retrieved)
+	} else {
+		Err(TextPreviewError::Unavailable)
+	}
+}
+
+/// Decodes the beginning of a file, `None` when it's binary. Unless the file is `complete`, a
+/// character cut at the end is dropped.
+fn decode(bytes: &[u8], complete: bool) -> Option<(String, TextEncoding)> {
+	let (encoding, content) = match bytes {
+		[0xEF, 0xBB, 0xBF, rest @ ..] => (TextEncoding::Utf8, rest),
+		[0xFF, 0xFE, rest @ ..] => (TextEncoding::Utf16Le, rest),
+		[0xFE, 0xFF, rest @ ..] => (TextEncoding::Utf16Be, rest),
+		_ => (sniff(bytes, complete)?, bytes),
+	};
+
+	let mut text = match encoding {
+		TextEncoding::Utf8 => String::from_utf8_lossy(content).into_owned(),
+		TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+			let units = content.chunks_exact(2).map(|pair| {
+				let pair = [pair[0], pair[1]];
+				if encoding == TextEncoding::Utf16Le {
+					u16::from_le_bytes(pair)
+				} else {
+					u16::from_be_bytes(pair)
+				}
+			});
+
+			char::decode_utf16(units)
+				.map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+				.collect()
+		}
+		TextEncoding::Latin1 => content.iter().map(|byte| *byte as char).collect(),
+	};
+
+	if !complete && text.ends_with(char::REPLACEMENT_CHARACTER) {
+		text.pop();
+	}
+
+	Some((text, encoding))
+}
+
+/// Guesses the encoding of text without a byte order mark
+fn sniff(bytes: &[u8], complete: bool) -> Option<TextEncoding> {
+	let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+	let pairs = sample.len() / 2;
+
+	if pairs > 0 {
+		let even_zeros = sample.iter().step_by(2).filter(|b| **b == 0).count();
+		let odd_zeros = sample
+			.iter()
+			.skip(1)
+			.step_by(2)
+			.filter(|b| **b == 0)
+			.count();
+
+		// ASCII characters take a zero byte before or after them
+		if even_zeros * 10 > pairs * 3 && odd_zeros * 10 < pairs {
+			return Some(TextEncoding::Utf16Be);
+		}
+		if odd_zeros * 10 > pairs * 3 && even_zeros * 10 < pairs {
+			return Some(TextEncoding::Utf16Le);
+		}
+	}
+
+	if sample.contains(&0) {
+		return None;
+	}
+
+	match std::str::from_utf8(bytes) {
+		Ok(_) => Some(TextEncoding::Utf8),
+		// Cut in the middle of a character, but valid up to there
+		Err(e) if e.error_len().is_none() && !complete => Some(TextEncoding::Utf8),
+		Err(_) => Some(TextEncoding::Latin1),
+	}
+}
+
+/// The language of a file, from its extension, its name or its shebang line
+fn language(name: &str, extension: &str, first_line: &str) -> Option<&'static str> {
+	let by_extension = match extension.to_lowercase().as_str() {
+		"rs" => Some("rust"),
+		"ts" | "mts" | "cts" => Some("typescript"),
+		"tsx" => Some("tsx"),
+		"js" | "mjs" | "cjs" => Some("javascript"),
+		"jsx" => Some("jsx"),
+		"vue" => Some("vue"),
+		"astro" => Some("astro"),
+		"php" => Some("php"),
+		"py" | "pyw" => Some("python"),
+		"rb" => Some("ruby"),
+		"sh" | "bash" => Some("bash"),
+		"zsh" => Some("zsh"),
+		"fish" => Some("fish"),
+		"ps1" => Some("powershell"),
+		"html" | "htm" => Some("html"),
+		"css" => Some("css"),
+		"sass" => Some("sass"),
+		"scss" => Some("scss"),
+		"less" => Some("less"),
+		"c" | "h" => Some("c"),
+		"cpp" | "cc" | "cxx" | "hpp" | "hh" | "hxx" => Some("cpp"),
+		"cs" => Some("csharp"),
+		"java" => Some("java"),
+		"kt" | "kts" => Some("kotlin"),
+		"scala" => Some("scala"),
+		"go" => Some("go"),
+		"dart" => Some("dart"),
+		"swift" => Some("swift"),
+		"lua" => Some("lua"),
+		"pl" | "pm" => Some("perl"),
+		"sql" => Some("sql"),
+		"md" | "markdown" => Some("markdown"),
+		"mdx" => Some("mdx"),
+		"json" => Some("json"),
+		"jsonc" | "json5" => Some("jsonc"),
+		"yaml" | "yml" => Some("yaml"),
+		"toml" => Some("toml"),
+		"xml" | "svg" | "plist" => Some("xml"),
+		"ini" | "cfg" | "conf" => Some("ini"),
+		"csv" => Some("csv"),
+		"diff" | "patch" => Some("diff"),
+		"graphql" | "gql" => Some("graphql"),
+		"prisma" => Some("prisma"),
+		_ => None,
+	};
+
+	by_extension
+		.or_else(|| match name {
+			"Dockerfile" | "Containerfile" => Some("dockerfile"),
+			"Makefile" | "GNUmakefile" => Some("makefile"),
+			"CMakeLists" => Some("cmake"),
+			_ => None,
+		})
+		.or_else(|| shebang_language(first_line))
+}
+
+/// The language of a script from its interpreter, like `#!/usr/bin/env python3`
+fn shebang_language(first_line: &str) -> Option<&'static str> {
+	let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+	let mut interpreter = words.next()?.rsplit('/').next()?;
+	if interpreter == "env" {
+		interpreter = words.find(|word| !word.starts_with('-'))?;
+	}
+
+	match interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+		"sh" | "bash" | "dash" => Some("bash"),
+		"zsh" => Some("zsh"),
+		"fish" => Some("fish"),
+		"python" => Some("python"),
+		"node" | "deno" | "bun" => Some("javascript"),
+		"ruby" => Some("ruby"),
+		"perl" => Some("perl"),
+		"php" => Some("php"),
+		"lua" => Some("lua"),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encodings_are_detected() {
+		assert_eq!(
+			decode("héllo".as_bytes(), true),
+			Some(("héllo".to_string(), TextEncoding::Utf8))
+		);
+		assert_eq!(
+			decode(&[0xFF, 0xFE, b'h', 0, b'i', 0], true),
+			Some(("hi".to_string(), TextEncoding::Utf16Le))
+		);
+		assert_eq!(
+			decode(&[0, b'h', 0, b'i', 0, b'!', 0, b'\n'], true),
+			Some(("hi!\n".to_string(), TextEncoding::Utf16Be))
+		);
+		assert_eq!(
+			decode(b"caf\xe9", true),
+			Some(("café".to_string(), TextEncoding::Latin1))
+		);
+		// A character cut by the preview length
+		assert_eq!(
+			decode(&"héllo".as_bytes()[..2], false),
+			Some(("h".to_string(), TextEncoding::Utf8))
+		);
+		assert_eq!(
+			decode(&[0x89, b'P', b'N', b'G', 0, 0, 0, 0x0D, 0x49], true),
+			None
+		);
+	}
+
+	#[test]
+	fn languages_are_identified() {
+		assert_eq!(language("main", "rs", ""), Some("rust"));
+		assert_eq!(language("Dockerfile", "", ""), Some("dockerfile"));
+		assert_eq!(
+			language("build", "", "#!/usr/bin/env -S python3 -u"),
+			Some("python")
+		);
+		assert_eq!(language("notes", "txt", "hello"), None);
+	}
+}
@@ -5,7 +5,10 @@ use crate::{
 	library::Library,
 	location::file_path_helper::{file_path_for_thumbnailer, FilePathError, IsolatedFilePathData},
 	prisma::location,
-	util::{db::maybe_missing, error::FileIOError, version_manager::VersionManagerError},
+	util::{
+		db::maybe_missing, error::FileIOError, memory_budget::MemoryBudget,
+		version_manager::VersionManagerError,
+	},
 };
 
 use std::{
@@ -19,6 +22,7 @@ use sd_file_ext::extensions::{Extension, ImageExtension};
 #[cfg(feature = "ffmpeg")]
 use sd_file_ext::extensions::VideoExtension;
 
+use futures::{stream, StreamExt};
 use image::{self, imageops, DynamicImage, GenericImageView};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
@@ -29,6 +33,11 @@ use webp::Encoder;
 
 use self::thumbnailer_job::ThumbnailerJob;
 
+use super::{
+	book::{extract_book_data_or_log, generate_book_thumbnail},
+	font::{extract_font_data_or_log, generate_font_thumbnail},
+};
+
 mod directory;
 mod shallow;
 mod shard;
@@ -39,9 +48,16 @@ pub use shallow::*;
 pub use shard::*;
 
 const THUMBNAIL_SIZE_FACTOR: f32 = 0.2;
-const THUMBNAIL_QUALITY: f32 = 30.0;
+pub(super) const THUMBNAIL_QUALITY: f32 = 30.0;
 pub const THUMBNAIL_CACHE_DIR_NAME: &str = "thumbnails";
 
+/// Decoded images are kept as RGBA8 buffers while generating thumbnails
+const DECODED_BYTES_PER_PIXEL: u64 = 4;
+/// Used when we can't read the image dimensions from its header
+const FALLBACK_IMAGE_THUMBNAIL_MEMORY: u64 = 64 * 1024 * 1024;
+#[cfg(feature = "ffmpeg")]
+const VIDEO_THUMBNAIL_MEMORY: u64 = 64 * 1024 * 1024;
+
 /// This does not check if a thumbnail exists, it just returns the path that it would exist at
 pub fn get_thumbnail_path(library: &Library, cas_id: &str) -> PathBuf {
 	library
@@ -114,6 +130,8 @@ enum ThumbnailerJobStepKind {
 	Image,
 	#[cfg(feature = "ffmpeg")]
 	Video,
+	Font,
+	Book,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -126,10 +144,29 @@ pub struct ThumbnailerJobStep {
 #[cfg(all(feature = "heif", not(target_os = "linux")))]
 const HEIF_EXTENSIONS: [&str; 7] = ["heif", "heifs", "heic", "heics", "avif", "avci", "avcs"];
 
+/// Rough estimate of the memory needed to decode an image and generate its thumbnail
+pub(crate) async fn estimate_image_thumbnail_memory(file_path: &Path) -> u64 {
+	let file_size = fs::metadata(file_path)
+		.await
+		.map(|metadata| metadata.len())
+		.unwrap_or(0);
+
+	// Only the image header is read to get its dimensions
+	match block_in_place(|| image::image_dimensions(file_path)) {
+		Ok((w, h)) => file_size + w as u64 * h as u64 * DECODED_BYTES_PER_PIXEL,
+		Err(_) => file_size.max(FALLBACK_IMAGE_THUMBNAIL_MEMORY),
+	}
+}
+
 pub async fn generate_image_thumbnail<P: AsRef<Path>>(
 	file_path: P,
 	output_path: P,
+	memory_budget: &MemoryBudget,
 ) -> Result<(), Box<dyn Error>> {
+	let _permit = memory_budget
+		.acquire(estimate_image_thumbnail_memory(file_path.as_ref()).await)
+		.await;
+
 	// Webp creation has blocking code
 	let webp = block_in_place(|| -> Result<Vec<u8>, Box<dyn Error>> {
 		#[cfg(all(feature = "heif", not(target_os = "linux")))]
@@ -152,36 +189,44 @@ pub async fn generate_image_thumbnail<P: AsRef<Path>>(
 		#[cfg(not(all(feature = "heif", not(target_os = "linux"))))]
 		let img = image::open(file_path)?;
 
-		let (w, h) = img.dimensions();
-		// Optionally, resize the existing photo and convert back into DynamicImage
-		let img = DynamicImage::ImageRgba8(imageops::resize(
-			&img,
-			// FIXME : Think of a better heuristic to get the thumbnail size
-			(w as f32 * THUMBNAIL_SIZE_FACTOR) as u32,
-			(h as f32 * THUMBNAIL_SIZE_FACTOR) as u32,
-			imageops::FilterType::Triangle,
-		));
-		// Create the WebP encoder for the above image
-		let encoder = Encoder::from_image(&img)?;
-
-		// Encode the image at a specified quality 0-100
-
-		// Type WebPMemory is !Send, which makes the Future in this function !Send,
-		// this make us `deref` to have a `&[u8]` and then `to_owned` to make a Vec<u8>
-		// which implies on a unwanted clone...
-		Ok(encoder.encode(THUMBNAIL_QUALITY).deref().to_owned())
+		encode_thumbnail(&img)
 	})?;
 
 	fs::write(output_path, &webp).await.map_err(Into::into)
 }
 
+/// Scales an image down and encodes it as WebP
+pub(super) fn encode_thumbnail(img: &DynamicImage) -> Result<Vec<u8>, Box<dyn Error>> {
+	let (w, h) = img.dimensions();
+	// Optionally, resize the existing photo and convert back into DynamicImage
+	let img = DynamicImage::ImageRgba8(imageops::resize(
+		img,
+		// FIXME : Think of a better heuristic to get the thumbnail size
+		(w as f32 * THUMBNAIL_SIZE_FACTOR) as u32,
+		(h as f32 * THUMBNAIL_SIZE_FACTOR) as u32,
+		imageops::FilterType::Triangle,
+	));
+	// Create the WebP encoder for the above image
+	let encoder = Encoder::from_image(&img)?;
+
+	// Encode the image at a specified quality 0-100
+
+	// Type WebPMemory is !Send, which makes the Future in this function !Send,
+	// this make us `deref` to have a `&[u8]` and then `to_owned` to make a Vec<u8>
+	// which implies on a unwanted clone...
+	Ok(encoder.encode(THUMBNAIL_QUALITY).deref().to_owned())
+}
+
 #[cfg(feature = "ffmpeg")]
 pub async fn generate_video_thumbnail<P: AsRef<Path>>(
 	file_path: P,
 	output_path: P,
+	memory_budget: &MemoryBudget,
 ) -> Result<(), Box<dyn Error>> {
 	use sd_ffmpeg::to_thumbnail;
 
+	let _permit = memory_budget.acquire(VIDEO_THUMBNAIL_MEMORY).await;
+
 	to_thumbnail(file_path, output_path, 256, THUMBNAIL_QUALITY).await?;
 
 	Ok(())
@@ -216,6 +261,8 @@ fn finalize_thumbnailer(data: &ThumbnailerJobState, ctx: &mut WorkerContext) ->
 		data.report.path.display()
 	);
 
+	ctx.library.metrics().thumbnailer_queue_depth.set(0);
+
 	if data.report.thumbnails_created > 0 {
 		invalidate_query!(ctx.library, "search.paths");
 	}
@@ -227,14 +274,12 @@ async fn process_step(
 	state: &mut JobState<ThumbnailerJob>,
 	ctx: &mut WorkerContext,
 ) -> Result<(), JobError> {
-	let step = &state.steps[0];
+	let batch = &state.steps[0];
+	let cpu_workers = ctx.library.resources().limits().await.cpu_workers as usize;
 
 	ctx.progress(vec![JobReportUpdate::Message(format!(
-		"Processing {}",
-		maybe_missing(
-			&step.file_path.materialized_path,
-			"file_path.materialized_path"
-		)?
+		"Processing {} files",
+		batch.len()
 	))]);
 
 	let data = state
@@ -242,29 +287,48 @@ async fn process_step(
 		.as_mut()
 		.expect("critical error: missing data on job state");
 
-	let step_result = inner_process_step(
-		step,
-		&data.location_path,
-		&data.thumbnail_dir,
-		&state.init.location,
-		&ctx.library,
-	)
+	let step_results = stream::iter(batch.iter().map(|step| {
+		inner_process_step(
+			step,
+			&data.location_path,
+			&data.thumbnail_dir,
+			&state.init.location,
+			&ctx.library,
+		)
+	}))
+	.buffer_unordered(cpu_workers)
+	.collect::<Vec<_>>()
 	.await;
 
+	let mut maybe_error = None;
+	for step_result in step_results {
+		match step_result {
+			Ok(true) => data.report.thumbnails_created += 1,
+			Ok(false) => data.report.thumbnails_skipped += 1,
+			Err(e) => {
+				error!("Error processing thumbnail: {e:#?}");
+				maybe_error.get_or_insert(e);
+			}
+		}
+	}
+
 	ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
-		state.step_number + 1,
+		(data.report.thumbnails_created + data.report.thumbnails_skipped) as usize,
 	)]);
 
-	match step_result {
-		Ok(thumbnail_was_created) => {
-			if thumbnail_was_created {
-				data.report.thumbnails_created += 1;
-			} else {
-				data.report.thumbnails_skipped += 1;
-			}
-			Ok(())
-		}
-		Err(e) => Err(e),
+	// The current batch is removed from the queue right after this step
+	ctx.library.metrics().thumbnailer_queue_depth.set(
+		state
+			.steps
+			.iter()
+			.skip(1)
+			.map(|batch| batch.len() as i64)
+			.sum(),
+	);
+
+	match maybe_error {
+		Some(e) => Err(e),
+		None => Ok(()),
 	}
 }
 
@@ -283,6 +347,17 @@ pub async fn inner_process_step(
 	let path = location_path.join(IsolatedFilePathData::try_from((location.id, file_path))?);
 	trace!("image_file {:?}", file_path);
 
+	// Fonts and books thumbnailed before their metadata was extracted still need it
+	match (kind, file_path.object_id) {
+		(ThumbnailerJobStepKind::Font, Some(object_id)) => {
+			extract_font_data_or_log(library, &path, object_id).await
+		}
+		(ThumbnailerJobStepKind::Book, Some(object_id)) => {
+			extract_book_data_or_log(library, &path, object_id).await
+		}
+		_ => {}
+	}
+
 	// get cas_id, if none found skip
 	let Some(cas_id) = &file_path.cas_id else {
 		warn!(
@@ -315,16 +390,34 @@ pub async fn inner_process_step(
 
 			match kind {
 				ThumbnailerJobStepKind::Image => {
-					if let Err(e) = generate_image_thumbnail(&path, &output_path).await {
+					if let Err(e) =
+						generate_image_thumbnail(&path, &output_path, library.memory_budget()).await
+					{
 						error!("Error generating thumb for image {:#?}", e);
 					}
 				}
 				#[cfg(feature = "ffmpeg")]
 				ThumbnailerJobStepKind::Video => {
-					if let Err(e) = generate_video_thumbnail(&path, &output_path).await {
+					if let Err(e) =
+						generate_video_thumbnail(&path, &output_path, library.memory_budget()).await
+					{
 						error!("Error generating thumb for video: {:?} {:#?}", &path, e);
 					}
 				}
+				ThumbnailerJobStepKind::Font => {
+					if let Err(e) =
+						generate_font_thumbnail(&path, &output_path, library.memory_budget()).await
+					{
+						error!("Error generating thumb for font: {:?} {:#?}", &path, e);
+					}
+				}
+				ThumbnailerJobStepKind::Book => {
+					if let Err(e) =
+						generate_book_thumbnail(&path, &output_path, library.memory_budget()).await
+					{
+						error!("Error generating thumb for book: {:?} {:#?}", &path, e);
+					}
+				}
 			}
 
 			info!("Emitting new thumbnail event");
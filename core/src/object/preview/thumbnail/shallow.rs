@@ -9,7 +9,7 @@ use crate::{
 		ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
 		file_path_for_thumbnailer, IsolatedFilePathData,
 	},
-	object::preview::thumbnail,
+	object::preview::{book::FILTERED_BOOK_EXTENSIONS, font::FILTERED_FONT_EXTENSIONS, thumbnail},
 	prisma::{file_path, location, PrismaClient},
 	util::error::FileIOError,
 };
@@ -47,7 +47,8 @@ pub async fn shallow_thumbnailer(
 
 		let sub_iso_file_path =
 			IsolatedFilePathData::new(location_id, &location_path, &full_path, true)
-				.map_err(ThumbnailerError::from)?;
+				.map_err(ThumbnailerError::from)?
+				.normalized(library.config.file_name_normalization);
 
 		ensure_file_path_exists(
 			&sub_path,
@@ -88,6 +89,28 @@ pub async fn shallow_thumbnailer(
 
 	info!("Found {:?} image files", image_files.len());
 
+	let font_files = get_files_by_extensions(
+		&library.db,
+		location_id,
+		&iso_file_path,
+		&FILTERED_FONT_EXTENSIONS,
+		ThumbnailerJobStepKind::Font,
+	)
+	.await?;
+
+	info!("Found {:?} font files", font_files.len());
+
+	let book_files = get_files_by_extensions(
+		&library.db,
+		location_id,
+		&iso_file_path,
+		&FILTERED_BOOK_EXTENSIONS,
+		ThumbnailerJobStepKind::Book,
+	)
+	.await?;
+
+	info!("Found {:?} book files", book_files.len());
+
 	#[cfg(feature = "ffmpeg")]
 	let video_files = {
 		// query database for all video files in this location that need thumbnails
@@ -107,6 +130,8 @@ pub async fn shallow_thumbnailer(
 
 	let all_files = [
 		image_files,
+		font_files,
+		book_files,
 		#[cfg(feature = "ffmpeg")]
 		video_files,
 	]
@@ -8,7 +8,10 @@ use crate::{
 		ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
 		file_path_for_thumbnailer, IsolatedFilePathData,
 	},
-	object::preview::thumbnail::directory::init_thumbnail_dir,
+	object::preview::{
+		book::FILTERED_BOOK_EXTENSIONS, font::FILTERED_FONT_EXTENSIONS,
+		thumbnail::directory::init_thumbnail_dir,
+	},
 	prisma::{file_path, location, PrismaClient},
 };
 
@@ -16,6 +19,7 @@ use std::{collections::VecDeque, hash::Hash, path::PathBuf};
 
 use sd_file_ext::extensions::Extension;
 
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use tracing::info;
@@ -47,13 +51,17 @@ impl Hash for ThumbnailerJobInit {
 
 impl JobInitData for ThumbnailerJobInit {
 	type Job = ThumbnailerJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location.id)
+	}
 }
 
 #[async_trait::async_trait]
 impl StatefulJob for ThumbnailerJob {
 	type Init = ThumbnailerJobInit;
 	type Data = ThumbnailerJobState;
-	type Step = ThumbnailerJobStep;
+	type Step = Vec<ThumbnailerJobStep>;
 
 	const NAME: &'static str = "thumbnailer";
 
@@ -87,7 +95,8 @@ impl StatefulJob for ThumbnailerJob {
 
 			let sub_iso_file_path =
 				IsolatedFilePathData::new(location_id, &location_path, &full_path, true)
-					.map_err(ThumbnailerError::from)?;
+					.map_err(ThumbnailerError::from)?
+					.normalized(ctx.library.config.file_name_normalization);
 
 			ensure_file_path_exists(
 				sub_path,
@@ -118,6 +127,24 @@ impl StatefulJob for ThumbnailerJob {
 		.await?;
 		info!("Found {:?} image files", image_files.len());
 
+		let font_files = get_files_by_extensions(
+			db,
+			&iso_file_path,
+			&FILTERED_FONT_EXTENSIONS,
+			ThumbnailerJobStepKind::Font,
+		)
+		.await?;
+		info!("Found {:?} font files", font_files.len());
+
+		let book_files = get_files_by_extensions(
+			db,
+			&iso_file_path,
+			&FILTERED_BOOK_EXTENSIONS,
+			ThumbnailerJobStepKind::Book,
+		)
+		.await?;
+		info!("Found {:?} book files", book_files.len());
+
 		#[cfg(feature = "ffmpeg")]
 		let all_files = {
 			// query database for all video files in this location that need thumbnails
@@ -132,11 +159,19 @@ impl StatefulJob for ThumbnailerJob {
 
 			image_files
 				.into_iter()
+				.chain(font_files.into_iter())
+				.chain(book_files.into_iter())
 				.chain(video_files.into_iter())
 				.collect::<VecDeque<_>>()
 		};
 		#[cfg(not(feature = "ffmpeg"))]
-		let all_files = { image_files.into_iter().collect::<VecDeque<_>>() };
+		let all_files = {
+			image_files
+				.into_iter()
+				.chain(font_files.into_iter())
+				.chain(book_files.into_iter())
+				.collect::<VecDeque<_>>()
+		};
 
 		ctx.progress(vec![
 			JobReportUpdate::TaskCount(all_files.len()),
@@ -153,7 +188,21 @@ impl StatefulJob for ThumbnailerJob {
 				thumbnails_skipped: 0,
 			},
 		});
-		state.steps.extend(all_files);
+
+		ctx.library
+			.metrics()
+			.thumbnailer_queue_depth
+			.set(all_files.len() as i64);
+
+		// Thumbnails are generated in batches, so they can be processed concurrently
+		let thumbnail_batch_size = ctx.library.resources().limits().await.thumbnail_batch_size;
+		state.steps.extend(
+			all_files
+				.into_iter()
+				.chunks(thumbnail_batch_size as usize)
+				.into_iter()
+				.map(Iterator::collect),
+		);
 
 		Ok(())
 	}
@@ -0,0 +1,561 @@
+//! Programming projects, so a repository shows up as one item with a summary of what's in it
+//! instead of thousands of source files.
+//!
+//! A project is a directory holding a marker at its root: a git repository or the manifest of a
+//! package manager. Projects nested in another one, like the crates of a Cargo workspace or git
+//! submodules, are part of the outermost one. `.git` directories are usually left out by the
+//! "No Hidden" indexer rule, so they're looked for on disk too.
+//!
+//! Languages are told apart by extension, leaving out the dependencies and build outputs vendored
+//! in the project, which would otherwise dwarf its own code.
+
+use crate::{
+	extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	location::file_path_helper::{file_path_for_treemap, IsolatedFilePathData},
+	prisma::{file_path, location, project, PrismaClient, SortOrder},
+	util::db::maybe_missing,
+};
+
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap},
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use prisma_client_rust::QueryError;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use strum::{Display, EnumString};
+use tokio::task::spawn_blocking;
+use tracing::{debug, info};
+
+/// Directories of dependencies and build outputs, which aren't the code of the project
+const VENDORED_DIRECTORIES: [&str; 8] = [
+	"node_modules",
+	"target",
+	"vendor",
+	"dist",
+	"build",
+	".git",
+	"__pycache__",
+	".venv",
+];
+
+#[derive(
+	Serialize,
+	Deserialize,
+	Type,
+	Display,
+	EnumString,
+	Debug,
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	PartialOrd,
+	Ord,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ProjectMarker {
+	Git,
+	Cargo,
+	Npm,
+	Go,
+	Python,
+	Maven,
+	Gradle,
+}
+
+impl ProjectMarker {
+	/// The marker of a project root holding an entry with this name
+	fn from_entry_name(name: &str, is_dir: bool) -> Option<Self> {
+		Some(match (name, is_dir) {
+			(".git", _) => Self::Git,
+			("Cargo.toml", false) => Self::Cargo,
+			("package.json", false) => Self::Npm,
+			("go.mod", false) => Self::Go,
+			("pyproject.toml" | "setup.py", false) => Self::Python,
+			("pom.xml", false) => Self::Maven,
+			("build.gradle" | "build.gradle.kts", false) => Self::Gradle,
+			_ => return None,
+		})
+	}
+}
+
+fn language_of(extension: &str) -> Option<&'static str> {
+	Some(match extension {
+		"rs" => "Rust",
+		"ts" | "tsx" | "mts" | "cts" => "TypeScript",
+		"js" | "jsx" | "mjs" | "cjs" => "JavaScript",
+		"py" => "Python",
+		"go" => "Go",
+		"java" => "Java",
+		"kt" | "kts" => "Kotlin",
+		"swift" => "Swift",
+		"c" | "h" => "C",
+		"cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "C++",
+		"m" | "mm" => "Objective-C",
+		"cs" => "C#",
+		"rb" => "Ruby",
+		"php" => "PHP",
+		"dart" => "Dart",
+		"zig" => "Zig",
+		"lua" => "Lua",
+		"sh" | "bash" | "zsh" => "Shell",
+		"sql" => "SQL",
+		"html" | "htm" => "HTML",
+		"css" | "scss" | "sass" | "less" => "CSS",
+		"vue" => "Vue",
+		"svelte" => "Svelte",
+		_ => return None,
+	})
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct LanguageShare {
+	pub language: String,
+	pub size_in_bytes: String,
+	pub files_count: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedProject {
+	pub file_path_id: file_path::id::Type,
+	/// Relative to the location, empty for the root of the location
+	pub relative_path: String,
+	pub markers: BTreeSet<ProjectMarker>,
+	/// Largest first
+	pub languages: Vec<LanguageShare>,
+	pub size_in_bytes: u64,
+	pub files_count: i32,
+}
+
+/// The outermost projects among the file paths of a location, which must hold everything below
+/// them. `is_git_root` tells the directories with a `.git` the index doesn't know about.
+pub fn detect_projects(
+	file_paths: &[file_path_for_treemap::Data],
+	is_git_root: impl Fn(&str) -> bool,
+) -> Vec<DetectedProject> {
+	// By the materialized path of their children
+	let mut directories = file_paths
+		.iter()
+		.filter_map(|file_path| {
+			IsolatedFilePathData::try_from(file_path)
+				.ok()?
+				.materialized_path_for_children()
+				.map(|children_path| (children_path, file_path.id))
+		})
+		.collect::<Vec<_>>();
+	// Shallowest first, so the projects holding other projects are found first
+	directories.sort_by_key(|(path, _)| path.matches('/').count());
+
+	let mut markers = HashMap::<&str, BTreeSet<ProjectMarker>>::new();
+	for file_path in file_paths {
+		let Ok(iso_file_path) = IsolatedFilePathData::try_from(file_path) else {
+			continue;
+		};
+
+		if let (Some(parent), Some(marker)) = (
+			file_path.materialized_path.as_deref(),
+			ProjectMarker::from_entry_name(
+				&iso_file_path.full_name(),
+				file_path.is_dir == Some(true),
+			),
+		) {
+			markers.entry(parent).or_default().insert(marker);
+		}
+	}
+
+	let mut roots = Vec::<(String, file_path::id::Type, BTreeSet<ProjectMarker>)>::new();
+	for (path, id) in directories {
+		if roots
+			.iter()
+			.any(|(root, _, _)| path.starts_with(root.as_str()))
+		{
+			continue;
+		}
+
+		let mut found = markers.remove(path.as_str()).unwrap_or_default();
+		if !found.contains(&ProjectMarker::Git) && is_git_root(relative_path(&path)) {
+			found.insert(ProjectMarker::Git);
+		}
+
+		if !found.is_empty() {
+			roots.push((path, id, found));
+		}
+	}
+
+	roots
+		.into_iter()
+		.map(|(path, file_path_id, markers)| {
+			let mut languages = BTreeMap::<&str, (u64, i32)>::new();
+			let mut size_in_bytes = 0;
+			let mut files_count = 0;
+
+			for file_path in file_paths.iter().filter(|file_path| {
+				file_path.is_dir == Some(false)
+					&& file_path
+						.materialized_path
+						.as_deref()
+						.and_then(|parent| parent.strip_prefix(path.as_str()))
+						.map_or(false, |below| !is_vendored(below))
+			}) {
+				let size = file_path
+					.size_in_bytes
+					.as_deref()
+					.and_then(|size| size.parse::<u64>().ok())
+					.unwrap_or_default();
+
+				size_in_bytes += size;
+				files_count += 1;
+
+				if let Some(language) = file_path.extension.as_deref().and_then(language_of) {
+					let (language_size, language_count) = languages.entry(language).or_default();
+					*language_size += size;
+					*language_count += 1;
+				}
+			}
+
+			let mut languages = languages.into_iter().collect::<Vec<_>>();
+			languages.sort_by(|(_, (a, _)), (_, (b, _))| b.cmp(a));
+
+			DetectedProject {
+				file_path_id,
+				relative_path: relative_path(&path).to_string(),
+				markers,
+				languages: languages
+					.into_iter()
+					.map(|(language, (size, count))| LanguageShare {
+						language: language.to_string(),
+						size_in_bytes: size.to_string(),
+						files_count: count,
+					})
+					.collect(),
+				size_in_bytes,
+				files_count,
+			}
+		})
+		.collect()
+}
+
+fn relative_path(children_path: &str) -> &str {
+	children_path.trim_matches('/')
+}
+
+/// If a directory below a project root, given by its path from there, holds vendored files
+fn is_vendored(below_root: &str) -> bool {
+	below_root
+		.split('/')
+		.any(|component| VENDORED_DIRECTORIES.contains(&component))
+}
+
+/// When the commit checked out in a repository was made
+fn last_commit_date(repository_path: &Path) -> Option<DateTime<Utc>> {
+	let repository = gix::open(repository_path)
+		.map_err(|e| debug!("Failed to open git repository {repository_path:?}: {e}"))
+		.ok()?;
+
+	let time = repository.head_commit().ok()?.time().ok()?;
+
+	Utc.timestamp_opt(i64::from(time.seconds), 0).single()
+}
+
+pub struct ProjectDetectorJob {}
+
+/// `ProjectDetectorJobInit` looks for the projects of these locations, or of every location of
+/// this node when none are given
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct ProjectDetectorJobInit {
+	pub location_ids: Vec<location::id::Type>,
+}
+
+impl JobInitData for ProjectDetectorJobInit {
+	type Job = ProjectDetectorJob;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ProjectDetectorJobReport {
+	projects_count: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for ProjectDetectorJob {
+	type Init = ProjectDetectorJobInit;
+	type Data = ProjectDetectorJobReport;
+	type Step = location::id::Type;
+
+	const NAME: &'static str = "project_detector";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		state.steps = if state.init.location_ids.is_empty() {
+			ctx.library
+				.db
+				.location()
+				.find_many(vec![location::node_id::equals(Some(
+					ctx.library.node_local_id,
+				))])
+				.select(location::select!({ id }))
+				.exec()
+				.await?
+				.into_iter()
+				.map(|location| location.id)
+				.collect()
+		} else {
+			state.init.location_ids.iter().copied().collect()
+		};
+
+		state.data = Some(ProjectDetectorJobReport::default());
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.steps[0];
+		let db = &ctx.library.db;
+
+		let Some(location) = db
+			.location()
+			.find_unique(location::id::equals(location_id))
+			.select(location::select!({ path }))
+			.exec()
+			.await?
+		else {
+			return Ok(());
+		};
+		let location_path = PathBuf::from(maybe_missing(location.path, "location.path")?);
+
+		let file_paths = db
+			.file_path()
+			.find_many(vec![file_path::location_id::equals(Some(location_id))])
+			.select(file_path_for_treemap::select())
+			.exec()
+			.await?;
+
+		let (projects, location_path) = spawn_blocking(move || {
+			let projects = detect_projects(&file_paths, |relative_path| {
+				location_path.join(relative_path).join(".git").exists()
+			});
+
+			(projects, location_path)
+		})
+		.await?;
+
+		let mut last_commits = HashMap::new();
+		for project in projects
+			.iter()
+			.filter(|project| project.markers.contains(&ProjectMarker::Git))
+		{
+			let root = location_path.join(&project.relative_path);
+			if let Some(date) = spawn_blocking(move || last_commit_date(&root)).await? {
+				last_commits.insert(project.file_path_id, date);
+			}
+		}
+
+		let date_scanned = Utc::now();
+		db._batch((
+			db.project().delete_many(vec![project::file_path::is(vec![
+				file_path::location_id::equals(Some(location_id)),
+			])]),
+			db.project().create_many(
+				projects
+					.iter()
+					.map(|project| {
+						project::create_unchecked(
+							project.file_path_id,
+							project
+								.markers
+								.iter()
+								.map(ToString::to_string)
+								.collect::<Vec<_>>()
+								.join(","),
+							serde_json::to_string(&project.languages)
+								.expect("languages always serialize"),
+							project.size_in_bytes.to_string(),
+							project.files_count,
+							date_scanned.into(),
+							vec![project::last_commit::set(
+								last_commits
+									.get(&project.file_path_id)
+									.map(|date| (*date).into()),
+							)],
+						)
+					})
+					.collect(),
+			),
+		))
+		.await?;
+
+		info!(
+			"Found {} projects in location {location_id}",
+			projects.len()
+		);
+
+		extract_job_data_mut!(state).projects_count += projects.len();
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = extract_job_data_mut!(state);
+
+		info!("Finalizing project detector job: {report:?}");
+
+		invalidate_query!(ctx.library, "locations.projects");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+project::include!(project_with_file_path {
+	file_path: select { id location_id materialized_path name }
+});
+
+#[derive(Serialize, Type, Debug)]
+pub struct ProjectSummary {
+	pub id: project::id::Type,
+	pub directory: project_with_file_path::file_path::Data,
+	pub markers: Vec<ProjectMarker>,
+	/// Largest first
+	pub languages: Vec<LanguageShare>,
+	pub size_in_bytes: String,
+	pub files_count: i32,
+	pub last_commit: Option<DateTime<FixedOffset>>,
+	pub date_scanned: DateTime<FixedOffset>,
+}
+
+impl From<project_with_file_path::Data> for ProjectSummary {
+	fn from(project: project_with_file_path::Data) -> Self {
+		Self {
+			id: project.id,
+			directory: project.file_path,
+			markers: project
+				.markers
+				.split(',')
+				.filter_map(|marker| ProjectMarker::from_str(marker).ok())
+				.collect(),
+			languages: serde_json::from_str(&project.languages).unwrap_or_default(),
+			size_in_bytes: project.size_in_bytes,
+			files_count: project.files_count,
+			last_commit: project.last_commit,
+			date_scanned: project.date_scanned,
+		}
+	}
+}
+
+/// The projects found by the last detection, the most recently committed to first
+pub async fn list_projects(
+	db: &PrismaClient,
+	location_id: Option<location::id::Type>,
+	visible: Option<file_path::WhereParam>,
+) -> Result<Vec<ProjectSummary>, QueryError> {
+	Ok(db
+		.project()
+		.find_many(vec![project::file_path::is(
+			[
+				location_id.map(Some).map(file_path::location_id::equals),
+				visible,
+			]
+			.into_iter()
+			.flatten()
+			.collect(),
+		)])
+		.order_by(project::last_commit::order(SortOrder::Desc))
+		.include(project_with_file_path::include())
+		.exec()
+		.await?
+		.into_iter()
+		.map(Into::into)
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(
+		id: i32,
+		parent: Option<&str>,
+		name: &str,
+		extension: &str,
+		is_dir: bool,
+		size: u64,
+	) -> file_path_for_treemap::Data {
+		file_path_for_treemap::Data {
+			id,
+			location_id: Some(1),
+			materialized_path: parent.map(str::to_string),
+			is_dir: Some(is_dir),
+			name: Some(name.to_string()),
+			extension: Some(extension.to_string()),
+			size_in_bytes: Some(size.to_string()),
+		}
+	}
+
+	#[test]
+	fn outermost_projects_with_their_languages() {
+		let file_paths = [
+			entry(1, Some("/"), "", "", true, 0),
+			entry(2, Some("/"), "spacedrive", "", true, 0),
+			entry(3, Some("/spacedrive/"), "Cargo", "toml", false, 10),
+			entry(4, Some("/spacedrive/"), "core", "", true, 0),
+			entry(5, Some("/spacedrive/core/"), "Cargo", "toml", false, 10),
+			entry(6, Some("/spacedrive/core/"), "lib", "rs", false, 300),
+			entry(7, Some("/spacedrive/"), "target", "", true, 0),
+			entry(
+				8,
+				Some("/spacedrive/target/"),
+				"generated",
+				"rs",
+				false,
+				9000,
+			),
+			entry(9, Some("/spacedrive/"), "app", "tsx", false, 100),
+			entry(10, Some("/"), "notes", "", true, 0),
+			entry(11, Some("/notes/"), "todo", "txt", false, 5),
+			entry(12, Some("/"), "website", "", true, 0),
+			entry(13, Some("/website/"), "index", "js", false, 50),
+		];
+
+		let projects = detect_projects(&file_paths, |path| path == "website");
+
+		assert_eq!(projects.len(), 2);
+
+		let spacedrive = &projects[0];
+		assert_eq!(spacedrive.file_path_id, 2);
+		assert_eq!(spacedrive.markers, BTreeSet::from([ProjectMarker::Cargo]));
+		// The build outputs are left out
+		assert_eq!(spacedrive.size_in_bytes, 420);
+		assert_eq!(spacedrive.files_count, 4);
+		assert_eq!(spacedrive.languages[0].language, "Rust");
+		assert_eq!(spacedrive.languages[0].size_in_bytes, "300");
+		assert_eq!(spacedrive.languages[1].language, "TypeScript");
+
+		let website = &projects[1];
+		assert_eq!(website.relative_path, "website");
+		assert_eq!(website.markers, BTreeSet::from([ProjectMarker::Git]));
+	}
+}
@@ -0,0 +1,402 @@
+//! Entries which aren't plain files or directories to the user. Links point somewhere else and are
+//! never followed, or a link to one of its ancestors would walk a directory forever. Bundles are
+//! directories macOS shows and opens as a single file, like apps or photo libraries.
+//!
+//! Symbolic links and, on Windows, junctions and directory symlinks are all links, as the standard
+//! library reports every name surrogate reparse point as a symlink. Finder aliases are regular
+//! files holding a bookmark, told apart by their header. The targets of aliases and of Windows
+//! shortcuts are read from their content, see `object::fs::broken_links`.
+
+use crate::prisma::file_path;
+
+use std::{
+	fs::Metadata,
+	path::{Path, PathBuf},
+};
+
+use blake3::Hasher;
+use prisma_client_rust::operator::{and, or};
+use sd_file_ext::kind::ObjectKind;
+use tokio::{
+	fs,
+	io::{self, AsyncReadExt},
+};
+
+/// Extensions of the directories shown as a single file by macOS
+pub const BUNDLE_EXTENSIONS: &[&str] = &[
+	"app",
+	"appex",
+	"bundle",
+	"docset",
+	"fcpbundle",
+	"framework",
+	"imovielibrary",
+	"kext",
+	"logicx",
+	"mdimporter",
+	"musiclibrary",
+	"photoslibrary",
+	"playground",
+	"plugin",
+	"prefPane",
+	"qlgenerator",
+	"rtfd",
+	"saver",
+	"sparsebundle",
+	"xcodeproj",
+	"xcworkspace",
+	"xpc",
+];
+
+/// Bookmark data, which Finder aliases hold, starts with `book`, a length, and `mark`
+const ALIAS_HEADER_LENGTH: usize = 12;
+const BOOKMARK_TOC_MAGIC: usize = 0xFFFF_FFFE;
+/// The key of the components of the target path in the table of contents of a bookmark
+const BOOKMARK_PATH_KEY: usize = 0x1004;
+const BOOKMARK_STRING: usize = 0x0101;
+const BOOKMARK_ARRAY: usize = 0x0601;
+
+/// Windows shortcuts start with the size of their header
+const SHORTCUT_HEADER_LEN: usize = 0x4C;
+const SHORTCUT_HAS_ID_LIST: usize = 0x1;
+const SHORTCUT_HAS_LINK_INFO: usize = 0x2;
+const LINK_INFO_LOCAL_PATH: usize = 0x1;
+
+/// If a directory with this name is a bundle
+pub fn is_bundle(path: impl AsRef<Path>) -> bool {
+	path.as_ref()
+		.extension()
+		.and_then(|extension| extension.to_str())
+		.map_or(false, |extension| {
+			BUNDLE_EXTENSIONS
+				.iter()
+				.any(|bundle| bundle.eq_ignore_ascii_case(extension))
+		})
+}
+
+/// Matches the `file_path`s of bundles. Directories are stored without an extension, but their
+/// name keeps it.
+pub fn bundle_directories() -> file_path::WhereParam {
+	and(vec![
+		file_path::is_dir::equals(Some(true)),
+		or(BUNDLE_EXTENSIONS
+			.iter()
+			.map(|extension| file_path::name::ends_with(format!(".{extension}")))
+			.collect()),
+	])
+}
+
+pub async fn is_finder_alias(path: impl AsRef<Path>) -> bool {
+	let mut header = [0; ALIAS_HEADER_LENGTH];
+
+	let Ok(mut file) = fs::File::open(path).await else {
+		return false;
+	};
+
+	file.read_exact(&mut header).await.is_ok() && is_alias_header(&header)
+}
+
+fn is_alias_header(header: &[u8; ALIAS_HEADER_LENGTH]) -> bool {
+	header.starts_with(b"book") && &header[8..] == b"mark"
+}
+
+/// Where the bookmark held by a Finder alias points to, from the components of the target path
+/// stored in it
+pub fn alias_target(bookmark: &[u8]) -> Option<PathBuf> {
+	let u32_at = |offset: usize| {
+		bookmark
+			.get(offset..offset + 4)
+			.map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+	};
+
+	if !is_alias_header(bookmark.get(..ALIAS_HEADER_LENGTH)?.try_into().ok()?) {
+		return None;
+	}
+
+	// Offsets are from the end of the header, starting with the one of the table of contents
+	let header_len = u32_at(ALIAS_HEADER_LENGTH)?;
+	let toc = header_len + u32_at(header_len)?;
+	if u32_at(toc + 4)? != BOOKMARK_TOC_MAGIC {
+		return None;
+	}
+
+	let item = |offset: usize| {
+		let start = header_len + offset;
+		let len = u32_at(start)?;
+		Some((
+			u32_at(start + 4)?,
+			bookmark.get(start + 8..start + 8 + len)?,
+		))
+	};
+
+	let path_offset = (0..u32_at(toc + 16)?).find_map(|i| {
+		let entry = toc + 20 + i * 12;
+		(u32_at(entry)? == BOOKMARK_PATH_KEY).then(|| u32_at(entry + 4))?
+	})?;
+
+	let (BOOKMARK_ARRAY, components) = item(path_offset)? else {
+		return None;
+	};
+
+	let mut path = PathBuf::from("/");
+	for offset in components.chunks_exact(4) {
+		let offset = u32::from_le_bytes([offset[0], offset[1], offset[2], offset[3]]) as usize;
+		let (BOOKMARK_STRING, name) = item(offset)? else {
+			return None;
+		};
+		path.push(std::str::from_utf8(name).ok()?);
+	}
+
+	Some(path)
+}
+
+/// Where a Windows shortcut (`.lnk`) points to, from the local path in its link info. Shortcuts to
+/// network shares or without link info have no path to check.
+pub fn shortcut_target(shortcut: &[u8]) -> Option<String> {
+	let u16_at = |offset: usize| {
+		shortcut
+			.get(offset..offset + 2)
+			.map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+	};
+	let u32_at = |offset: usize| {
+		shortcut
+			.get(offset..offset + 4)
+			.map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+	};
+	let ansi_at = |offset: usize| {
+		let bytes = shortcut.get(offset..)?;
+		let end = bytes.iter().position(|byte| *byte == 0)?;
+		Some(
+			bytes[..end]
+				.iter()
+				.map(|byte| *byte as char)
+				.collect::<String>(),
+		)
+	};
+	let unicode_at = |offset: usize| {
+		let units = shortcut
+			.get(offset..)?
+			.chunks_exact(2)
+			.map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+			.take_while(|unit| *unit != 0)
+			.collect::<Vec<_>>();
+		String::from_utf16(&units).ok()
+	};
+
+	if u32_at(0)? != SHORTCUT_HEADER_LEN {
+		return None;
+	}
+
+	let flags = u32_at(0x14)?;
+	let mut link_info = SHORTCUT_HEADER_LEN;
+	if flags & SHORTCUT_HAS_ID_LIST != 0 {
+		link_info += 2 + u16_at(link_info)?;
+	}
+	if flags & SHORTCUT_HAS_LINK_INFO == 0 || u32_at(link_info + 8)? & LINK_INFO_LOCAL_PATH == 0 {
+		return None;
+	}
+
+	// Headers of 0x24 bytes or more have UTF-16 versions of the paths
+	let (base, suffix) = match u32_at(link_info + 28) {
+		Some(offset) if u32_at(link_info + 4)? >= 0x24 && offset != 0 => (
+			unicode_at(link_info + offset)?,
+			unicode_at(link_info + u32_at(link_info + 32)?)?,
+		),
+		_ => (
+			ansi_at(link_info + u32_at(link_info + 16)?)?,
+			ansi_at(link_info + u32_at(link_info + 24)?)?,
+		),
+	};
+
+	Some(base + &suffix)
+}
+
+/// The kind and cas_id of the entries which can't be hashed like regular files, `None` for
+/// regular files. `metadata` must not follow links.
+pub async fn identify_special(
+	path: impl AsRef<Path>,
+	metadata: &Metadata,
+) -> io::Result<Option<(ObjectKind, String)>> {
+	let path = path.as_ref();
+
+	if metadata.is_symlink() {
+		// Links to the same place are the same object
+		let mut hasher = Hasher::new();
+		hasher.update(b"link");
+		hasher.update(fs::read_link(path).await?.to_string_lossy().as_bytes());
+
+		return Ok(Some((ObjectKind::Alias, cas_id(hasher))));
+	}
+
+	if metadata.is_dir() {
+		return Ok(Some((ObjectKind::Package, bundle_cas_id(path).await?)));
+	}
+
+	Ok(None)
+}
+
+/// Hashes the paths and sizes of everything in the bundle, sorted, so copies of a bundle are the
+/// same object without reading all of their contents
+async fn bundle_cas_id(bundle: &Path) -> io::Result<String> {
+	let mut entries = vec![];
+	let mut pending = vec![bundle.to_path_buf()];
+
+	while let Some(directory) = pending.pop() {
+		let mut read_dir = fs::read_dir(&directory).await?;
+		while let Some(entry) = read_dir.next_entry().await? {
+			let path = entry.path();
+			let metadata = entry.metadata().await?;
+
+			if metadata.is_dir() {
+				pending.push(path.clone());
+			}
+
+			entries.push((relative(bundle, path), metadata.len()));
+		}
+	}
+
+	entries.sort();
+
+	let mut hasher = Hasher::new();
+	hasher.update(b"bundle");
+	for (path, size) in entries {
+		hasher.update(path.to_string_lossy().as_bytes());
+		hasher.update(&size.to_le_bytes());
+	}
+
+	Ok(cas_id(hasher))
+}
+
+fn relative(base: &Path, path: PathBuf) -> PathBuf {
+	path.strip_prefix(base)
+		.map(Path::to_path_buf)
+		.unwrap_or(path)
+}
+
+fn cas_id(hasher: Hasher) -> String {
+	hasher.finalize().to_hex()[..16].to_string()
+}
+
+/// Removes a file, or a link itself and never what it points to. Links to directories on Windows
+/// must be removed as directories.
+pub async fn remove_file_or_link(path: impl AsRef<Path>) -> io::Result<()> {
+	let path = path.as_ref();
+
+	#[cfg(target_os = "windows")]
+	{
+		use std::os::windows::fs::MetadataExt;
+
+		use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+
+		if fs::symlink_metadata(path).await?.file_attributes() & FILE_ATTRIBUTE_DIRECTORY != 0 {
+			return fs::remove_dir(path).await;
+		}
+	}
+
+	fs::remove_file(path).await
+}
+
+/// Creates a link at `target` pointing where the link at `source` does
+pub async fn copy_link(source: impl AsRef<Path>, target: impl AsRef<Path>) -> io::Result<()> {
+	let link = fs::read_link(source.as_ref()).await?;
+
+	#[cfg(target_family = "unix")]
+	{
+		fs::symlink(link, target).await
+	}
+
+	#[cfg(target_os = "windows")]
+	{
+		use std::os::windows::fs::MetadataExt;
+
+		use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+
+		if fs::symlink_metadata(source).await?.file_attributes() & FILE_ATTRIBUTE_DIRECTORY != 0 {
+			fs::symlink_dir(link, target).await
+		} else {
+			fs::symlink_file(link, target).await
+		}
+	}
+}
+
+/// Points the link at `path` to `target` instead. On Unix the new link replaces the old one in a
+/// single rename. On Windows, a link to a directory is a directory link, so `target` has to exist
+/// to tell.
+pub async fn retarget_link(path: impl AsRef<Path>, target: impl AsRef<Path>) -> io::Result<()> {
+	let (path, target) = (path.as_ref(), target.as_ref());
+
+	#[cfg(target_family = "unix")]
+	{
+		let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+		temp_name.push(".sd-relink");
+		let temp_path = path.with_file_name(temp_name);
+
+		fs::symlink(target, &temp_path).await?;
+		if let Err(e) = fs::rename(&temp_path, path).await {
+			fs::remove_file(&temp_path).await.ok();
+			return Err(e);
+		}
+
+		Ok(())
+	}
+
+	#[cfg(target_os = "windows")]
+	{
+		let resolved = path
+			.parent()
+			.map_or(target.to_path_buf(), |parent| parent.join(target));
+		let is_dir = fs::metadata(resolved).await?.is_dir();
+
+		remove_file_or_link(path).await?;
+
+		if is_dir {
+			fs::symlink_dir(target, path).await
+		} else {
+			fs::symlink_file(target, path).await
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bundles_by_extension() {
+		assert!(is_bundle("/Applications/Safari.app"));
+		assert!(is_bundle("/Users/me/Pictures/Photos Library.photoslibrary"));
+		assert!(is_bundle("/Library/PreferencePanes/Java.PREFPANE"));
+		assert!(!is_bundle("/Users/me/app"));
+		assert!(!is_bundle("/Users/me/notes.txt"));
+	}
+
+	#[test]
+	fn alias_headers() {
+		assert!(is_alias_header(b"book\x00\x02\x00\x00mark"));
+		assert!(!is_alias_header(b"bookmarks.ht"));
+	}
+
+	#[test]
+	fn alias_targets() {
+		let u32s = |values: &[u32]| {
+			values
+				.iter()
+				.flat_map(|value| value.to_le_bytes())
+				.collect::<Vec<_>>()
+		};
+
+		// Header of 16 bytes, then the table of contents offset, two strings, the path array and
+		// the table of contents, offsets being from the end of the header
+		let mut bookmark = b"book\0\0\0\0mark".to_vec();
+		bookmark.extend(u32s(&[16, 48]));
+		bookmark.extend(u32s(&[5, 0x0101]));
+		bookmark.extend(b"Users\0\0\0");
+		bookmark.extend(u32s(&[2, 0x0101]));
+		bookmark.extend(b"me\0\0");
+		bookmark.extend(u32s(&[8, 0x0601, 4, 20]));
+		bookmark.extend(u32s(&[32, 0xFFFF_FFFE, 1, 0, 1, 0x1004, 32, 0]));
+
+		assert_eq!(alias_target(&bookmark), Some(PathBuf::from("/Users/me")));
+		assert_eq!(alias_target(b"bookmarks.html"), None);
+	}
+}
@@ -0,0 +1,599 @@
+//! Photo stacks: the shots of a burst, or the same scene taken a few times in a row, grouped so the
+//! media timeline shows a single one of them.
+//!
+//! Photos are put in the same stack when they were taken within a few seconds of each other and
+//! their perceptual hashes, computed from their thumbnails, are a few bits apart. The date comes
+//! from the EXIF data of the photo, or the modification date of its file when it has none. The first
+//! shot of a stack stands for it until the user picks another one, and may archive the others.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{file_path_for_photo_stacker, IsolatedFilePathData},
+		find_location, LocationError,
+	},
+	object::preview::get_thumbnail_path,
+	prisma::{
+		file_path, location, media_data, object, photo_stack, photo_stack_item, PrismaClient,
+		SortOrder,
+	},
+	util::db::maybe_missing,
+};
+
+use sd_file_ext::kind::ObjectKind;
+
+use std::{
+	collections::{HashMap, HashSet},
+	fs::{self, File},
+	hash::Hash,
+	io::BufReader,
+	ops::Range,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::task::spawn_blocking;
+use tracing::{debug, info};
+
+mod phash;
+
+use phash::{difference_hash, distance};
+
+const PHOTOS_PER_STEP: usize = 100;
+/// Longest time between two shots of the same burst
+const BURST_INTERVAL_SECS: i64 = 5;
+/// Most bits by which the perceptual hashes of two shots of the same burst may differ
+const MAX_HASH_DISTANCE: u32 = 10;
+
+#[derive(Error, Debug)]
+pub enum PhotoStackError {
+	#[error("photo isn't in a stack <object_id='{0}'>")]
+	NotStacked(object::id::Type),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<PhotoStackError> for rspc::Error {
+	fn from(err: PhotoStackError) -> Self {
+		match err {
+			PhotoStackError::NotStacked(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
+			PhotoStackError::Database(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err)
+			}
+		}
+	}
+}
+
+pub struct PhotoStackerJob {}
+
+/// `PhotoStackerJobInit` reads the capture date and computes the perceptual hash of the photos of a
+/// location which don't have one yet, then stacks the bursts among all of its photos.
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct PhotoStackerJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for PhotoStackerJobInit {
+	type Job = PhotoStackerJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PhotoStackerJobReport {
+	photos_analyzed: usize,
+	stacks_created: usize,
+	photos_stacked: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PhotoStackerJobData {
+	location_path: PathBuf,
+	report: PhotoStackerJobReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PhotoStackerJobStep {
+	Analyze(Vec<file_path_for_photo_stacker::Data>),
+	/// Always the last step, once every photo was analyzed
+	Stack,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for PhotoStackerJob {
+	type Init = PhotoStackerJobInit;
+	type Data = PhotoStackerJobData;
+	type Step = PhotoStackerJobStep;
+
+	const NAME: &'static str = "photo_stacker";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+
+		let location = find_location(&ctx.library, location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(location_id))?;
+		let location_path = PathBuf::from(maybe_missing(location.path, "location.path")?);
+
+		let file_paths = ctx
+			.library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(location_id)),
+				file_path::is_dir::equals(Some(false)),
+				file_path::object::is(vec![
+					object::kind::equals(Some(ObjectKind::Image as i32)),
+					object::media_data::is_not(vec![media_data::perceptual_hash::not(None)]),
+				]),
+			])
+			.select(file_path_for_photo_stacker::select())
+			.exec()
+			.await?;
+
+		// Copies of a photo share its object, a single one of them is analyzed
+		let mut object_ids = HashSet::with_capacity(file_paths.len());
+		let file_paths = file_paths
+			.into_iter()
+			.filter(|file_path| {
+				file_path
+					.object_id
+					.map_or(false, |object_id| object_ids.insert(object_id))
+			})
+			.collect::<Vec<_>>();
+
+		state.steps = file_paths
+			.chunks(PHOTOS_PER_STEP)
+			.map(|chunk| PhotoStackerJobStep::Analyze(chunk.to_vec()))
+			.chain([PhotoStackerJobStep::Stack])
+			.collect();
+
+		state.data = Some(PhotoStackerJobData {
+			location_path,
+			report: PhotoStackerJobReport::default(),
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+		let step = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		match step {
+			PhotoStackerJobStep::Analyze(file_paths) => {
+				data.report.photos_analyzed +=
+					analyze_photos(&ctx.library, (location_id, &data.location_path), file_paths)
+						.await?;
+			}
+			PhotoStackerJobStep::Stack => {
+				let (stacks_created, photos_stacked) =
+					stack_photos(&ctx.library.db, location_id).await?;
+
+				data.report.stacks_created += stacks_created;
+				data.report.photos_stacked += photos_stacked;
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing photo stacker job: {report:?}");
+
+		if report.photos_stacked > 0 {
+			invalidate_query!(ctx.library, "search.mediaTimeline");
+		}
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Stores the capture date and perceptual hash of some photos. Photos without a thumbnail yet get
+/// their date alone, and are analyzed again on the next run. Returns how many photos were hashed.
+async fn analyze_photos(
+	library: &Library,
+	(location_id, location_path): (location::id::Type, &Path),
+	file_paths: &[file_path_for_photo_stacker::Data],
+) -> Result<usize, JobError> {
+	let photos = file_paths
+		.iter()
+		.filter_map(|file_path| {
+			let iso_file_path = IsolatedFilePathData::try_from((location_id, file_path)).ok()?;
+
+			Some((
+				file_path.object_id?,
+				location_path.join(iso_file_path),
+				file_path
+					.cas_id
+					.as_deref()
+					.map(|cas_id| get_thumbnail_path(library, cas_id)),
+				file_path.date_modified,
+			))
+		})
+		.collect::<Vec<_>>();
+
+	let analyzed = spawn_blocking(move || {
+		photos
+			.into_iter()
+			.map(|(object_id, path, thumbnail_path, date_modified)| {
+				(
+					object_id,
+					read_date_taken(&path).or(date_modified),
+					thumbnail_path.and_then(|path| hash_thumbnail(&path)),
+				)
+			})
+			.collect::<Vec<_>>()
+	})
+	.await?;
+
+	let hashed_count = analyzed
+		.iter()
+		.filter(|(_, _, perceptual_hash)| perceptual_hash.is_some())
+		.count();
+
+	let db = &library.db;
+	db._batch(
+		analyzed
+			.into_iter()
+			.map(|(object_id, date_taken, perceptual_hash)| {
+				let perceptual_hash = perceptual_hash.map(|hash| hash.to_vec());
+
+				db.media_data().upsert(
+					media_data::id::equals(object_id),
+					media_data::create_unchecked(
+						object_id,
+						vec![
+							media_data::date_taken::set(date_taken),
+							media_data::perceptual_hash::set(perceptual_hash.clone()),
+						],
+					),
+					vec![
+						media_data::date_taken::set(date_taken),
+						media_data::perceptual_hash::set(perceptual_hash),
+					],
+				)
+			})
+			// Same workaround as in `get_many_files_datas` for the lifetimes of `_batch`
+			.collect::<Vec<_>>(),
+	)
+	.await?;
+
+	Ok(hashed_count)
+}
+
+/// When the photo was shot, down to the fraction of a second cameras record for bursts. Without
+/// an offset the time is taken as UTC, which is as good when comparing shots of the same camera.
+pub(crate) fn read_date_taken(path: &Path) -> Option<DateTime<FixedOffset>> {
+	let file = File::open(path).ok()?;
+	let exif = exif::Reader::new()
+		.read_from_container(&mut BufReader::new(file))
+		.ok()?;
+
+	let ascii = |tag| match exif
+		.get_field(tag, exif::In::PRIMARY)
+		.map(|field| &field.value)
+	{
+		Some(exif::Value::Ascii(values)) => values.first().map(Vec::as_slice),
+		_ => None,
+	};
+
+	let mut date = exif::DateTime::from_ascii(ascii(exif::Tag::DateTimeOriginal)?).ok()?;
+	if let Some(subsec) = ascii(exif::Tag::SubSecTimeOriginal) {
+		date.parse_subsec(subsec).ok();
+	}
+	if let Some(offset) = ascii(exif::Tag::OffsetTimeOriginal) {
+		date.parse_offset(offset).ok();
+	}
+
+	let naive = NaiveDate::from_ymd_opt(date.year.into(), date.month.into(), date.day.into())?
+		.and_hms_nano_opt(
+			date.hour.into(),
+			date.minute.into(),
+			date.second.into(),
+			date.nanosecond.unwrap_or_default(),
+		)?;
+	let offset = FixedOffset::east_opt(i32::from(date.offset.unwrap_or_default()) * 60)?;
+
+	naive.and_local_timezone(offset).single()
+}
+
+fn hash_thumbnail(path: &Path) -> Option<[u8; 8]> {
+	let bytes = fs::read(path).ok()?;
+	let image = webp::Decoder::new(&bytes).decode()?.to_image();
+
+	Some(difference_hash(&image))
+}
+
+media_data::select!(media_data_for_stacking {
+	id
+	date_taken
+	perceptual_hash
+	object: select {
+		photo_stack_item: select { stack_id }
+	}
+});
+
+/// Stacks the bursts among the photos of a location. Photos joining a burst already stacked are
+/// added to its stack, so the best shot picked for it stays. Returns how many stacks were created
+/// and how many photos were added to a stack.
+async fn stack_photos(
+	db: &PrismaClient,
+	location_id: location::id::Type,
+) -> Result<(usize, usize), QueryError> {
+	let rows = db
+		.media_data()
+		.find_many(vec![
+			media_data::date_taken::not(None),
+			media_data::perceptual_hash::not(None),
+			media_data::object::is(vec![object::file_paths::some(vec![
+				file_path::location_id::equals(Some(location_id)),
+			])]),
+		])
+		.order_by(media_data::date_taken::order(SortOrder::Asc))
+		.select(media_data_for_stacking::select())
+		.exec()
+		.await?;
+
+	let photos = rows
+		.iter()
+		.filter_map(|row| {
+			Some((
+				row.id,
+				row.date_taken?,
+				row.perceptual_hash.as_deref()?,
+				row.object
+					.as_ref()
+					.and_then(|object| object.photo_stack_item.as_ref())
+					.map(|item| item.stack_id),
+			))
+		})
+		.collect::<Vec<_>>();
+
+	let mut stacks_created = 0;
+	let mut photos_stacked = 0;
+
+	for burst in bursts(
+		&photos
+			.iter()
+			.map(|(_, date_taken, hash, _)| (*date_taken, *hash))
+			.collect::<Vec<_>>(),
+	) {
+		let members = &photos[burst];
+
+		let stack_ids = members
+			.iter()
+			.filter_map(|(_, _, _, stack_id)| *stack_id)
+			.collect::<HashSet<_>>();
+
+		let (stack_id, is_new) = match stack_ids.len() {
+			0 => (db.photo_stack().create(vec![]).exec().await?.id, true),
+			1 => (stack_ids.into_iter().next().expect("one stack"), false),
+			// The burst bridges stacks made on earlier runs, merging them would drop the best shot
+			// picked for one of them
+			_ => continue,
+		};
+
+		let items = members
+			.iter()
+			.enumerate()
+			.filter(|(_, (_, _, _, stack_id))| stack_id.is_none())
+			.map(|(i, (object_id, _, _, _))| {
+				photo_stack_item::create_unchecked(
+					*object_id,
+					stack_id,
+					vec![photo_stack_item::is_best::set(is_new && i == 0)],
+				)
+			})
+			.collect::<Vec<_>>();
+
+		debug!(
+			"Adding {} photos to stack {stack_id} of location {location_id}",
+			items.len()
+		);
+
+		stacks_created += usize::from(is_new);
+		photos_stacked += items.len();
+
+		if !items.is_empty() {
+			db.photo_stack_item().create_many(items).exec().await?;
+		}
+	}
+
+	Ok((stacks_created, photos_stacked))
+}
+
+/// The ranges of photos, sorted by the date they were taken, which form a burst of two shots or more
+fn bursts(photos: &[(DateTime<FixedOffset>, &[u8])]) -> Vec<Range<usize>> {
+	let mut bursts = vec![];
+	let mut start = 0;
+
+	for i in 1..=photos.len() {
+		let continues = photos.get(i).map_or(false, |(date_taken, hash)| {
+			let (previous_date_taken, previous_hash) = photos[i - 1];
+
+			*date_taken - previous_date_taken <= Duration::seconds(BURST_INTERVAL_SECS)
+				&& distance(previous_hash, hash).map_or(false, |bits| bits <= MAX_HASH_DISTANCE)
+		});
+
+		if !continues {
+			if i - start > 1 {
+				bursts.push(start..i);
+			}
+			start = i;
+		}
+	}
+
+	bursts
+}
+
+/// Makes a photo the one standing for its stack. The other shots of the stack are hidden when
+/// `archive_rest` is set, they stay on disk and in the stack.
+pub async fn pick_best_shot(
+	library: &Library,
+	object_id: object::id::Type,
+	archive_rest: bool,
+) -> Result<(), PhotoStackError> {
+	let db = &library.db;
+
+	let stack_id = db
+		.photo_stack_item()
+		.find_unique(photo_stack_item::object_id::equals(object_id))
+		.exec()
+		.await?
+		.ok_or(PhotoStackError::NotStacked(object_id))?
+		.stack_id;
+
+	db._batch((
+		db.photo_stack_item().update_many(
+			vec![photo_stack_item::stack_id::equals(stack_id)],
+			vec![photo_stack_item::is_best::set(false)],
+		),
+		db.photo_stack_item().update(
+			photo_stack_item::object_id::equals(object_id),
+			vec![photo_stack_item::is_best::set(true)],
+		),
+		db.object().update_many(
+			vec![object::id::equals(object_id)],
+			vec![object::hidden::set(Some(false))],
+		),
+	))
+	.await?;
+
+	if archive_rest {
+		db.object()
+			.update_many(
+				vec![
+					object::photo_stack_item::is(vec![photo_stack_item::stack_id::equals(
+						stack_id,
+					)]),
+					object::id::not(object_id),
+				],
+				vec![object::hidden::set(Some(true))],
+			)
+			.exec()
+			.await?;
+
+		invalidate_query!(library, "search.objects");
+	}
+
+	invalidate_query!(library, "search.mediaTimeline");
+
+	Ok(())
+}
+
+/// Leaves out the shots of a stack other than its best one
+pub fn best_shots_only() -> object::WhereParam {
+	object::photo_stack_item::is_not(vec![photo_stack_item::is_best::equals(false)])
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PhotoStackSummary {
+	pub id: photo_stack::id::Type,
+	/// Every shot of the stack, oldest first
+	pub object_ids: Vec<object::id::Type>,
+}
+
+photo_stack_item::include!(photo_stack_item_with_stack {
+	stack: include { items }
+});
+
+/// The stacks of the photos which are in one
+pub async fn stacks_of(
+	db: &PrismaClient,
+	object_ids: Vec<object::id::Type>,
+) -> Result<HashMap<object::id::Type, PhotoStackSummary>, QueryError> {
+	let items = db
+		.photo_stack_item()
+		.find_many(vec![photo_stack_item::object_id::in_vec(object_ids)])
+		.include(photo_stack_item_with_stack::include())
+		.exec()
+		.await?;
+
+	Ok(items
+		.into_iter()
+		.map(|item| {
+			let mut object_ids = item
+				.stack
+				.items
+				.iter()
+				.map(|item| item.object_id)
+				.collect::<Vec<_>>();
+			object_ids.sort_unstable();
+
+			(
+				item.object_id,
+				PhotoStackSummary {
+					id: item.stack_id,
+					object_ids,
+				},
+			)
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bursts_need_close_dates_and_hashes() {
+		let at = |secs: u32| {
+			DateTime::parse_from_rfc3339(&format!("2023-07-16T12:00:{secs:02}Z"))
+				.expect("valid date")
+		};
+		let hash = 0u64.to_be_bytes();
+		let alike = 0b111u64.to_be_bytes();
+		let other = u64::MAX.to_be_bytes();
+
+		let photos = [
+			(at(0), &hash[..]),
+			(at(2), &alike[..]),
+			(at(4), &hash[..]),
+			// Same scene, but shot long after
+			(at(30), &hash[..]),
+			(at(31), &other[..]),
+			(at(40), &hash[..]),
+			(at(41), &alike[..]),
+		];
+
+		assert_eq!(bursts(&photos), vec![0..3, 5..7]);
+		assert!(bursts(&[]).is_empty());
+	}
+}
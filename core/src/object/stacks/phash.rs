@@ -0,0 +1,60 @@
+//! Difference hashes: the image is shrunk to 9x8 grey pixels and each bit says whether a pixel is
+//! brighter than the one on its right. Resizing, recompressing or a slight change of exposure flip
+//! a handful of bits at most, while another picture flips about half of them.
+
+use image::{imageops::FilterType, DynamicImage};
+
+const HASH_WIDTH: u32 = 8;
+const HASH_HEIGHT: u32 = 8;
+
+pub fn difference_hash(image: &DynamicImage) -> [u8; 8] {
+	let grey = image
+		.resize_exact(HASH_WIDTH + 1, HASH_HEIGHT, FilterType::Triangle)
+		.into_luma8();
+
+	let mut hash = 0u64;
+	for y in 0..HASH_HEIGHT {
+		for x in 0..HASH_WIDTH {
+			hash <<= 1;
+			if grey.get_pixel(x, y)[0] > grey.get_pixel(x + 1, y)[0] {
+				hash |= 1;
+			}
+		}
+	}
+
+	hash.to_be_bytes()
+}
+
+/// How many bits differ, `None` if one of them isn't a hash
+pub fn distance(a: &[u8], b: &[u8]) -> Option<u32> {
+	let a = u64::from_be_bytes(a.try_into().ok()?);
+	let b = u64::from_be_bytes(b.try_into().ok()?);
+
+	Some((a ^ b).count_ones())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use image::{GrayImage, Luma};
+
+	fn gradient(width: u32, height: u32, brightness: f32, flip: bool) -> DynamicImage {
+		DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+			let x = if flip { width - 1 - x } else { x };
+			let value = (x * 200 / width + y * 50 / height) as f32 * brightness;
+			Luma([value.min(255.0) as u8])
+		}))
+	}
+
+	#[test]
+	fn alike_images_are_close() {
+		let original = difference_hash(&gradient(640, 480, 1.0, false));
+		let smaller_and_brighter = difference_hash(&gradient(320, 240, 1.1, false));
+		let mirrored = difference_hash(&gradient(640, 480, 1.0, true));
+
+		assert!(distance(&original, &smaller_and_brighter).unwrap() <= 4);
+		assert!(distance(&original, &mirrored).unwrap() > 32);
+		assert_eq!(distance(&original, &[0; 4]), None);
+	}
+}
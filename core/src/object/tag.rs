@@ -1,10 +1,33 @@
 use prisma_client_rust::QueryError;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use specta::Type;
 
+use std::{collections::BTreeSet, path::PathBuf};
+
 use uuid::Uuid;
 
-use crate::prisma::{tag, PrismaClient};
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{
+			ensure_sub_path_is_directory, ensure_sub_path_is_in_location, IsolatedFilePathData,
+		},
+		LocationError,
+	},
+	object::{
+		fs::get_location_path_from_location_id, os_metadata::write_object_finder_tags_or_log,
+		xmp::write_object_sidecars_or_log,
+	},
+	prisma::{file_path, location, object, tag, tag_on_object, PrismaClient},
+	sync,
+};
+
+const OBJECTS_PER_STEP: usize = 500;
 
 #[derive(Type, Deserialize)]
 pub struct Tag {
@@ -34,3 +57,192 @@ impl Tag {
 		Ok(())
 	}
 }
+
+/// Finds a tag by its name, creating it if there is none. Also tells if it had to be created.
+pub async fn find_or_create_tag(
+	library: &Library,
+	name: &str,
+) -> Result<(tag::id::Type, bool), QueryError> {
+	let Library { db, sync, .. } = library;
+
+	if let Some(tag) = db
+		.tag()
+		.find_first(vec![tag::name::equals(Some(name.to_string()))])
+		.exec()
+		.await?
+	{
+		return Ok((tag.id, false));
+	}
+
+	let pub_id = Uuid::new_v4().as_bytes().to_vec();
+
+	let tag = sync
+		.write_op(
+			db,
+			sync.unique_shared_create(
+				sync::tag::SyncId {
+					pub_id: pub_id.clone(),
+				},
+				[(tag::name::NAME, json!(name))],
+			),
+			db.tag()
+				.create(pub_id, vec![tag::name::set(Some(name.to_string()))]),
+		)
+		.await?;
+
+	Ok((tag.id, true))
+}
+
+pub struct TagAssignerJob {}
+
+/// `TagAssignerJobInit` tags every object of a location, or of a directory of it and its
+/// descendants, creating the tag if there's none with this name
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct TagAssignerJobInit {
+	pub location_id: location::id::Type,
+	pub sub_path: Option<PathBuf>,
+	pub tag_name: String,
+}
+
+impl JobInitData for TagAssignerJobInit {
+	type Job = TagAssignerJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagAssignerJobData {
+	tag_id: tag::id::Type,
+	tagged_count: usize,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for TagAssignerJob {
+	type Init = TagAssignerJobInit;
+	type Data = TagAssignerJobData;
+	type Step = Vec<object::id::Type>;
+
+	const NAME: &'static str = "tag_assigner";
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let library = &ctx.library;
+		let location_id = state.init.location_id;
+
+		let sub_materialized_path = match &state.init.sub_path {
+			Some(sub_path) if sub_path != &PathBuf::new() => {
+				let location_path =
+					get_location_path_from_location_id(&library.db, location_id).await?;
+
+				let full_path = ensure_sub_path_is_in_location(&location_path, sub_path)
+					.await
+					.map_err(LocationError::from)?;
+				ensure_sub_path_is_directory(&location_path, sub_path)
+					.await
+					.map_err(LocationError::from)?;
+
+				Some(
+					IsolatedFilePathData::new(location_id, &location_path, &full_path, true)
+						.map_err(LocationError::from)?
+						.normalized(library.config.file_name_normalization)
+						.materialized_path_for_children()
+						.expect("sub path was checked to be a directory"),
+				)
+			}
+			_ => None,
+		};
+
+		let (tag_id, created) = find_or_create_tag(library, &state.init.tag_name).await?;
+		if created {
+			invalidate_query!(library, "tags.list");
+		}
+
+		let object_ids = library
+			.db
+			.file_path()
+			.find_many(
+				[
+					Some(file_path::location_id::equals(Some(location_id))),
+					Some(file_path::object_id::not(None)),
+					sub_materialized_path.map(file_path::materialized_path::starts_with),
+				]
+				.into_iter()
+				.flatten()
+				.collect(),
+			)
+			.select(file_path::select!({ object_id }))
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|file_path| file_path.object_id)
+			.collect::<BTreeSet<_>>()
+			.into_iter()
+			.collect::<Vec<_>>();
+
+		state.steps = object_ids
+			.chunks(OBJECTS_PER_STEP)
+			.map(<[_]>::to_vec)
+			.collect();
+
+		state.data = Some(TagAssignerJobData {
+			tag_id,
+			tagged_count: 0,
+		});
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let library = &ctx.library;
+		let object_ids = state.steps[0].clone();
+		let data = extract_job_data_mut!(state);
+
+		library
+			.db
+			._batch(
+				object_ids
+					.iter()
+					.map(|&object_id| {
+						library.db.tag_on_object().upsert(
+							tag_on_object::tag_id_object_id(data.tag_id, object_id),
+							tag_on_object::create_unchecked(data.tag_id, object_id, vec![]),
+							vec![],
+						)
+					})
+					.collect::<Vec<_>>(),
+			)
+			.await?;
+
+		data.tagged_count += object_ids.len();
+
+		write_object_finder_tags_or_log(library, object_ids.clone()).await;
+		write_object_sidecars_or_log(library, object_ids).await;
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		Ok(())
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		invalidate_query!(ctx.library, "tags.getForObject");
+
+		Ok(Some(serde_json::to_value(extract_job_data!(state))?))
+	}
+}
@@ -40,6 +40,10 @@ pub struct ObjectValidatorJobInit {
 
 impl JobInitData for ObjectValidatorJobInit {
 	type Job = ObjectValidatorJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
 }
 
 #[async_trait::async_trait]
@@ -49,6 +53,7 @@ impl StatefulJob for ObjectValidatorJob {
 	type Step = file_path_for_object_validator::Data;
 
 	const NAME: &'static str = "object_validator";
+	const IS_BACKGROUND: bool = true;
 
 	fn new() -> Self {
 		Self {}
@@ -67,6 +72,8 @@ impl StatefulJob for ObjectValidatorJob {
 					file_path::location_id::equals(Some(state.init.location_id)),
 					file_path::is_dir::equals(Some(false)),
 					file_path::integrity_checksum::equals(None),
+					file_path::compressed_at::equals(None),
+					file_path::tiered_at::equals(None),
 				])
 				.select(file_path_for_object_validator::select())
 				.exec()
@@ -0,0 +1,586 @@
+//! XMP sidecars let the tags, ratings and labels set in Spacedrive be seen by tools like Lightroom
+//! or darktable, and the other way around. It's enabled per location, with a strategy to settle
+//! the disagreements between a sidecar and our library.
+//!
+//! We read sidecars named `IMG_0001.CR2.xmp` (darktable) or `IMG_0001.xmp` (Adobe). New ones are
+//! named the Adobe way, unless another file in the same directory shares the name of the image.
+
+use crate::{
+	extract_job_data, extract_job_data_mut, invalidate_query,
+	job::{
+		JobError, JobInitData, JobReportUpdate, JobResult, JobState, StatefulJob, WorkerContext,
+	},
+	library::Library,
+	location::{
+		file_path_helper::{file_path_for_xmp_sidecar, FilePathError, IsolatedFilePathData},
+		find_location, LocationError,
+	},
+	object::{label::find_or_create_label, tag::find_or_create_tag},
+	prisma::{
+		file_path, label, label_on_object, location, object, tag, tag_on_object, PrismaClient,
+	},
+	util::{
+		db::{maybe_missing, MissingFieldError},
+		error::FileIOError,
+	},
+};
+
+use std::{
+	hash::Hash,
+	io,
+	path::{Path, PathBuf},
+};
+
+use prisma_client_rust::QueryError;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::fs;
+use tracing::{error, info, trace};
+
+mod sidecar;
+
+pub use sidecar::XmpMetadata;
+
+const XMP_EXTENSION: &str = "xmp";
+const FILE_PATHS_PER_STEP: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum XmpError {
+	#[error("invalid XMP sidecar <path='{}'>: {source}", .path.display())]
+	Xml {
+		path: Box<Path>,
+		source: quick_xml::Error,
+	},
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error(transparent)]
+	FilePath(#[from] FilePathError),
+	#[error(transparent)]
+	MissingField(#[from] MissingFieldError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+/// What to do when a sidecar and our library disagree about a file
+#[repr(i32)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Type, Eq, PartialEq)]
+pub enum XmpConflictStrategy {
+	/// Tags from both are kept, while ratings and labels already in the library win
+	#[default]
+	Merge = 0,
+	PreferSidecar = 1,
+	PreferLibrary = 2,
+}
+
+impl XmpConflictStrategy {
+	pub fn from_db(value: Option<i32>) -> Self {
+		match value {
+			Some(1) => Self::PreferSidecar,
+			Some(2) => Self::PreferLibrary,
+			_ => Self::Merge,
+		}
+	}
+
+	fn resolve(self, library: &XmpMetadata, sidecar: Option<&XmpMetadata>) -> XmpMetadata {
+		let Some(sidecar) = sidecar else {
+			return library.clone();
+		};
+
+		let mut resolved = match self {
+			Self::PreferSidecar => sidecar.clone(),
+			Self::PreferLibrary => library.clone(),
+			Self::Merge => XmpMetadata {
+				tags: library
+					.tags
+					.iter()
+					.chain(&sidecar.tags)
+					.cloned()
+					.collect(),
+				rating: library.rating.or(sidecar.rating),
+				label: library.label.clone().or_else(|| sidecar.label.clone()),
+			},
+		};
+
+		normalize_tags(&mut resolved);
+		resolved
+	}
+}
+
+pub struct XmpSidecarSyncJob {}
+
+#[derive(Serialize, Deserialize, Hash, Type)]
+pub struct XmpSidecarSyncJobInit {
+	pub location_id: location::id::Type,
+}
+
+impl JobInitData for XmpSidecarSyncJobInit {
+	type Job = XmpSidecarSyncJob;
+
+	fn location_id(&self) -> Option<location::id::Type> {
+		Some(self.location_id)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct XmpSidecarSyncJobReport {
+	read_count: usize,
+	written_count: usize,
+	updated_objects_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct XmpSidecarSyncJobData {
+	location_path: PathBuf,
+	strategy: XmpConflictStrategy,
+	report: XmpSidecarSyncJobReport,
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for XmpSidecarSyncJob {
+	type Init = XmpSidecarSyncJobInit;
+	type Data = XmpSidecarSyncJobData;
+	type Step = Vec<file_path_for_xmp_sidecar::Data>;
+
+	const NAME: &'static str = "xmp_sidecar_sync";
+	const IS_BACKGROUND: bool = true;
+
+	fn new() -> Self {
+		Self {}
+	}
+
+	async fn init(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location = find_location(&ctx.library, state.init.location_id)
+			.exec()
+			.await?
+			.ok_or(LocationError::IdNotFound(state.init.location_id))?;
+
+		state.data = Some(XmpSidecarSyncJobData {
+			location_path: maybe_missing(&location.path, "location.path")?.into(),
+			strategy: XmpConflictStrategy::from_db(location.xmp_conflict_strategy),
+			report: XmpSidecarSyncJobReport::default(),
+		});
+
+		if location.xmp_sidecars != Some(true) {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "XMP sidecars are disabled for this location".to_string(),
+			});
+		}
+
+		let file_paths = ctx
+			.library
+			.db
+			.file_path()
+			.find_many(vec![
+				file_path::location_id::equals(Some(state.init.location_id)),
+				file_path::is_dir::equals(Some(false)),
+				file_path::object_id::not(None),
+				file_path::extension::not(Some(XMP_EXTENSION.to_string())),
+			])
+			.select(file_path_for_xmp_sidecar::select())
+			.exec()
+			.await?;
+
+		state.steps = file_paths
+			.chunks(FILE_PATHS_PER_STEP)
+			.map(<[_]>::to_vec)
+			.collect();
+
+		ctx.progress(vec![JobReportUpdate::TaskCount(state.steps.len())]);
+
+		Ok(())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &mut WorkerContext,
+		state: &mut JobState<Self>,
+	) -> Result<(), JobError> {
+		let location_id = state.init.location_id;
+		let file_paths = &state.steps[0];
+		let data = extract_job_data_mut!(state);
+
+		let mut errors = vec![];
+		for file_path in file_paths {
+			match sync_sidecar(
+				&ctx.library,
+				(location_id, &data.location_path),
+				file_path,
+				data.strategy,
+			)
+			.await
+			{
+				Ok(outcome) => {
+					data.report.read_count += outcome.read as usize;
+					data.report.written_count += outcome.written as usize;
+					data.report.updated_objects_count += outcome.updated_object as usize;
+				}
+				Err(e) => errors.push(e.to_string()),
+			}
+		}
+
+		ctx.progress(vec![JobReportUpdate::CompletedTaskCount(
+			state.step_number + 1,
+		)]);
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(JobError::StepCompletedWithErrors(errors))
+		}
+	}
+
+	async fn finalize(&mut self, ctx: &mut WorkerContext, state: &mut JobState<Self>) -> JobResult {
+		let report = &extract_job_data!(state).report;
+
+		info!("Finalizing XMP sidecar sync job: {report:?}");
+
+		invalidate_query!(ctx.library, "search.paths");
+		invalidate_query!(ctx.library, "search.objects");
+		invalidate_query!(ctx.library, "tags.list");
+
+		Ok(Some(serde_json::to_value(report)?))
+	}
+}
+
+/// Writes the library metadata of these objects to the sidecars of their files, in every location
+/// with sidecars enabled. Used right after the metadata is changed in Spacedrive.
+pub async fn write_object_sidecars(
+	library: &Library,
+	object_ids: Vec<object::id::Type>,
+) -> Result<(), XmpError> {
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::object_id::in_vec(object_ids),
+			file_path::is_dir::equals(Some(false)),
+			file_path::location::is(vec![location::xmp_sidecars::equals(Some(true))]),
+		])
+		.include(file_path::include!({ location: select { id path } }))
+		.exec()
+		.await?;
+
+	for file_path in file_paths {
+		let Some(location) = &file_path.location else {
+			continue;
+		};
+		let Some(location_path) = &location.path else {
+			continue;
+		};
+
+		sync_sidecar(
+			library,
+			(location.id, Path::new(location_path)),
+			&file_path_for_xmp_sidecar::Data {
+				id: file_path.id,
+				materialized_path: file_path.materialized_path.clone(),
+				is_dir: file_path.is_dir,
+				name: file_path.name.clone(),
+				extension: file_path.extension.clone(),
+				object_id: file_path.object_id,
+			},
+			XmpConflictStrategy::PreferLibrary,
+		)
+		.await?;
+	}
+
+	Ok(())
+}
+
+/// Logs instead of failing, as sidecars are a best effort on top of the library metadata
+pub async fn write_object_sidecars_or_log(library: &Library, object_ids: Vec<object::id::Type>) {
+	if let Err(e) = write_object_sidecars(library, object_ids).await {
+		error!("Failed to write XMP sidecars: {e:#?}");
+	}
+}
+
+#[derive(Default)]
+struct SyncOutcome {
+	read: bool,
+	written: bool,
+	updated_object: bool,
+}
+
+async fn sync_sidecar(
+	library: &Library,
+	(location_id, location_path): (location::id::Type, &Path),
+	file_path: &file_path_for_xmp_sidecar::Data,
+	strategy: XmpConflictStrategy,
+) -> Result<SyncOutcome, XmpError> {
+	let Some(object_id) = file_path.object_id else {
+		return Ok(SyncOutcome::default());
+	};
+
+	let full_path = location_path.join(IsolatedFilePathData::try_from((location_id, file_path))?);
+
+	let mut existing = None;
+	for sidecar_path in sidecar_paths(&full_path) {
+		match fs::read_to_string(&sidecar_path).await {
+			Ok(xml) => {
+				existing = Some((sidecar_path, xml));
+				break;
+			}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+			Err(e) => return Err(FileIOError::from((sidecar_path, e)).into()),
+		}
+	}
+
+	let sidecar = existing
+		.as_ref()
+		.map(|(path, xml)| {
+			sidecar::parse(xml)
+				.map(|mut metadata| {
+					normalize_tags(&mut metadata);
+					metadata
+				})
+				.map_err(|source| XmpError::Xml {
+					path: path.as_path().into(),
+					source,
+				})
+		})
+		.transpose()?;
+
+	let current = library_metadata(&library.db, object_id).await?;
+	let resolved = strategy.resolve(&current, sidecar.as_ref());
+
+	let mut outcome = SyncOutcome {
+		read: sidecar.is_some(),
+		..Default::default()
+	};
+
+	if resolved != current {
+		update_library(library, object_id, &current, &resolved).await?;
+		outcome.updated_object = true;
+	}
+
+	let needs_writing = match &sidecar {
+		Some(sidecar) => *sidecar != resolved,
+		// No need to litter the location with sidecars for files without any metadata
+		None => resolved != XmpMetadata::default(),
+	};
+
+	if needs_writing {
+		let (sidecar_path, xml) = match existing {
+			Some((path, xml)) => (path, Some(xml)),
+			None => (new_sidecar_path(library, location_id, file_path, &full_path).await?, None),
+		};
+
+		let updated = sidecar::update(xml.as_deref(), &resolved).map_err(|source| XmpError::Xml {
+			path: sidecar_path.as_path().into(),
+			source,
+		})?;
+
+		fs::write(&sidecar_path, updated)
+			.await
+			.map_err(|e| FileIOError::from((&sidecar_path, e)))?;
+
+		trace!("Wrote XMP sidecar {}", sidecar_path.display());
+		outcome.written = true;
+	}
+
+	Ok(outcome)
+}
+
+fn sidecar_paths(full_path: &Path) -> [PathBuf; 2] {
+	let mut darktable_path = full_path.as_os_str().to_owned();
+	darktable_path.push(".");
+	darktable_path.push(XMP_EXTENSION);
+
+	[
+		darktable_path.into(),
+		full_path.with_extension(XMP_EXTENSION),
+	]
+}
+
+async fn new_sidecar_path(
+	library: &Library,
+	location_id: location::id::Type,
+	file_path: &file_path_for_xmp_sidecar::Data,
+	full_path: &Path,
+) -> Result<PathBuf, XmpError> {
+	// Like a RAW and a JPEG from the same shot, which would end up sharing a sidecar
+	let shares_name = library
+		.db
+		.file_path()
+		.count(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::materialized_path::equals(file_path.materialized_path.clone()),
+			file_path::name::equals(file_path.name.clone()),
+			file_path::id::not(file_path.id),
+			file_path::extension::not(Some(XMP_EXTENSION.to_string())),
+		])
+		.exec()
+		.await? > 0;
+
+	let [darktable_path, adobe_path] = sidecar_paths(full_path);
+
+	Ok(if shares_name {
+		darktable_path
+	} else {
+		adobe_path
+	})
+}
+
+fn normalize_tags(metadata: &mut XmpMetadata) {
+	metadata.tags.sort();
+	metadata.tags.dedup();
+}
+
+async fn library_metadata(
+	db: &PrismaClient,
+	object_id: object::id::Type,
+) -> Result<XmpMetadata, QueryError> {
+	let (object, tags, labels) = db
+		._batch((
+			db.object()
+				.find_unique(object::id::equals(object_id))
+				.select(object::select!({ rating })),
+			db.tag()
+				.find_many(vec![tag::tag_objects::some(vec![
+					tag_on_object::object_id::equals(object_id),
+				])])
+				.select(tag::select!({ name })),
+			db.label()
+				.find_many(vec![label::label_objects::some(vec![
+					label_on_object::object_id::equals(object_id),
+				])])
+				.select(label::select!({ name })),
+		))
+		.await?;
+
+	let mut metadata = XmpMetadata {
+		tags: tags.into_iter().filter_map(|tag| tag.name).collect(),
+		rating: object.and_then(|object| object.rating),
+		// XMP only has room for a single label
+		label: labels.into_iter().find_map(|label| label.name),
+	};
+	normalize_tags(&mut metadata);
+
+	Ok(metadata)
+}
+
+async fn update_library(
+	library: &Library,
+	object_id: object::id::Type,
+	current: &XmpMetadata,
+	resolved: &XmpMetadata,
+) -> Result<(), QueryError> {
+	let Library { db, .. } = library;
+
+	if current.rating != resolved.rating {
+		db.object()
+			.update(
+				object::id::equals(object_id),
+				vec![object::rating::set(resolved.rating)],
+			)
+			.exec()
+			.await?;
+	}
+
+	let removed_tags = current
+		.tags
+		.iter()
+		.filter(|name| !resolved.tags.contains(name))
+		.cloned()
+		.collect::<Vec<_>>();
+	if !removed_tags.is_empty() {
+		db.tag_on_object()
+			.delete_many(vec![
+				tag_on_object::object_id::equals(object_id),
+				tag_on_object::tag::is(vec![tag::name::in_vec(removed_tags)]),
+			])
+			.exec()
+			.await?;
+	}
+
+	let mut added_tag_ids = vec![];
+	for name in resolved
+		.tags
+		.iter()
+		.filter(|name| !current.tags.contains(name))
+	{
+		added_tag_ids.push(find_or_create_tag(library, name).await?.0);
+	}
+	if !added_tag_ids.is_empty() {
+		db.tag_on_object()
+			.create_many(
+				added_tag_ids
+					.into_iter()
+					.map(|tag_id| tag_on_object::CreateUnchecked {
+						tag_id,
+						object_id,
+						_params: vec![],
+					})
+					.collect(),
+			)
+			.exec()
+			.await?;
+	}
+
+	if current.label != resolved.label {
+		if let Some(name) = &current.label {
+			db.label_on_object()
+				.delete_many(vec![
+					label_on_object::object_id::equals(object_id),
+					label_on_object::label::is(vec![label::name::equals(Some(name.clone()))]),
+				])
+				.exec()
+				.await?;
+		}
+
+		if let Some(name) = &resolved.label {
+			let (label_id, _) = find_or_create_label(db, name).await?;
+			db.label_on_object()
+				.create_unchecked(label_id, object_id, vec![])
+				.exec()
+				.await?;
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn conflict_strategies() {
+		let library = XmpMetadata {
+			tags: vec!["Beach".to_string()],
+			rating: Some(4),
+			label: None,
+		};
+		let sidecar = XmpMetadata {
+			tags: vec!["Holidays".to_string(), "Beach".to_string()],
+			rating: Some(2),
+			label: Some("Red".to_string()),
+		};
+
+		assert_eq!(
+			XmpConflictStrategy::Merge.resolve(&library, Some(&sidecar)),
+			XmpMetadata {
+				tags: vec!["Beach".to_string(), "Holidays".to_string()],
+				rating: Some(4),
+				label: Some("Red".to_string()),
+			}
+		);
+		assert_eq!(
+			XmpConflictStrategy::PreferLibrary.resolve(&library, Some(&sidecar)),
+			library
+		);
+		assert_eq!(
+			XmpConflictStrategy::PreferSidecar
+				.resolve(&library, Some(&sidecar))
+				.rating,
+			Some(2)
+		);
+		assert_eq!(XmpConflictStrategy::Merge.resolve(&library, None), library);
+	}
+}
@@ -0,0 +1,300 @@
+//! Reading and writing the few XMP properties we share with other tools. Sidecars are rewritten
+//! event by event, so everything else in them (like Lightroom develop settings) is kept untouched.
+//!
+//! Namespace prefixes are matched literally, as every tool we know of uses the standard ones.
+
+use std::io::Cursor;
+
+use quick_xml::{
+	events::{BytesEnd, BytesStart, BytesText, Event},
+	Reader, Writer,
+};
+
+const DESCRIPTION: &[u8] = b"rdf:Description";
+const RATING: &[u8] = b"xmp:Rating";
+const LABEL: &[u8] = b"xmp:Label";
+const SUBJECT: &[u8] = b"dc:subject";
+const LIST_ITEM: &[u8] = b"rdf:li";
+
+const XMP_NAMESPACE: &str = "http://ns.adobe.com/xap/1.0/";
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+
+const EMPTY_PACKET: &str = concat!(
+	"<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+	"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+	" <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+	"  <rdf:Description rdf:about=\"\"/>\n",
+	" </rdf:RDF>\n",
+	"</x:xmpmeta>\n",
+	"<?xpacket end=\"w\"?>\n",
+);
+
+/// The metadata kept in sync between our library and XMP sidecars
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct XmpMetadata {
+	pub tags: Vec<String>,
+	pub rating: Option<i32>,
+	pub label: Option<String>,
+}
+
+pub fn parse(xml: &str) -> Result<XmpMetadata, quick_xml::Error> {
+	let mut reader = Reader::from_str(xml);
+	let mut metadata = XmpMetadata::default();
+	let mut stack = Vec::<Vec<u8>>::new();
+
+	loop {
+		match reader.read_event()? {
+			Event::Start(element) => {
+				if element.name().as_ref() == DESCRIPTION {
+					read_description_attributes(&element, &mut metadata)?;
+				}
+				stack.push(element.name().as_ref().to_vec());
+			}
+			Event::Empty(element) if element.name().as_ref() == DESCRIPTION => {
+				read_description_attributes(&element, &mut metadata)?;
+			}
+			Event::End(_) => {
+				stack.pop();
+			}
+			Event::Text(text) => {
+				let text = text.unescape()?;
+				let text = text.trim();
+				if text.is_empty() {
+					continue;
+				}
+
+				match stack.last().map(Vec::as_slice) {
+					Some(RATING) => metadata.rating = parse_rating(text),
+					Some(LABEL) => metadata.label = Some(text.to_string()),
+					Some(LIST_ITEM) if stack.iter().any(|name| name == SUBJECT) => {
+						metadata.tags.push(text.to_string())
+					}
+					_ => {}
+				}
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+	}
+
+	Ok(metadata)
+}
+
+fn read_description_attributes(
+	element: &BytesStart,
+	metadata: &mut XmpMetadata,
+) -> Result<(), quick_xml::Error> {
+	for attribute in element.attributes() {
+		let attribute = attribute?;
+		match attribute.key.as_ref() {
+			RATING => metadata.rating = parse_rating(&attribute.unescape_value()?),
+			LABEL => metadata.label = Some(attribute.unescape_value()?.into_owned()),
+			_ => {}
+		}
+	}
+
+	Ok(())
+}
+
+/// Lightroom writes -1 for rejected photos, which isn't a rating for us
+fn parse_rating(value: &str) -> Option<i32> {
+	value
+		.trim()
+		.parse::<f64>()
+		.ok()
+		.map(|rating| rating.round() as i32)
+		.filter(|rating| (0..=5).contains(rating))
+}
+
+/// Writes our metadata into a sidecar, or into a new one if `xml` is `None`
+pub fn update(xml: Option<&str>, metadata: &XmpMetadata) -> Result<String, quick_xml::Error> {
+	let mut reader = Reader::from_str(xml.unwrap_or(EMPTY_PACKET));
+	let mut writer = Writer::new(Cursor::new(Vec::new()));
+
+	// Our properties are dropped wherever they are, and written again into the first description
+	let mut skipped_depth = 0;
+	let mut depth = 0;
+	let mut description_depth = None;
+	let mut found_description = false;
+
+	loop {
+		let event = reader.read_event()?;
+
+		if skipped_depth > 0 {
+			match event {
+				Event::Start(_) => skipped_depth += 1,
+				Event::End(_) => skipped_depth -= 1,
+				Event::Eof => break,
+				_ => {}
+			}
+			continue;
+		}
+
+		match event {
+			Event::Start(element) if is_ours(&element) => skipped_depth = 1,
+			Event::Empty(element) if is_ours(&element) => {}
+			Event::Start(element)
+				if !found_description && element.name().as_ref() == DESCRIPTION =>
+			{
+				found_description = true;
+				depth += 1;
+				description_depth = Some(depth);
+				writer.write_event(Event::Start(description_start(&element, metadata)?))?;
+			}
+			Event::Empty(element)
+				if !found_description && element.name().as_ref() == DESCRIPTION =>
+			{
+				found_description = true;
+				writer.write_event(Event::Start(description_start(&element, metadata)?))?;
+				write_subject(&mut writer, &metadata.tags)?;
+				writer.write_event(Event::End(BytesEnd::new("rdf:Description")))?;
+			}
+			Event::Start(element) => {
+				depth += 1;
+				writer.write_event(Event::Start(element))?;
+			}
+			Event::End(element) => {
+				if description_depth == Some(depth) {
+					description_depth = None;
+					write_subject(&mut writer, &metadata.tags)?;
+				}
+				depth -= 1;
+				writer.write_event(Event::End(element))?;
+			}
+			Event::Eof => break,
+			event => writer.write_event(event)?,
+		}
+	}
+
+	if !found_description {
+		// Not really XMP, we can't tell where our properties should go
+		return update(None, metadata);
+	}
+
+	Ok(String::from_utf8_lossy(&writer.into_inner().into_inner()).into_owned())
+}
+
+fn is_ours(element: &BytesStart) -> bool {
+	matches!(element.name().as_ref(), RATING | LABEL | SUBJECT)
+}
+
+fn description_start(
+	element: &BytesStart,
+	metadata: &XmpMetadata,
+) -> Result<BytesStart<'static>, quick_xml::Error> {
+	let mut description = BytesStart::new("rdf:Description");
+	let mut has_xmp_namespace = false;
+	let mut has_dc_namespace = false;
+
+	for attribute in element.attributes() {
+		let attribute = attribute?;
+		match attribute.key.as_ref() {
+			RATING | LABEL => continue,
+			b"xmlns:xmp" => has_xmp_namespace = true,
+			b"xmlns:dc" => has_dc_namespace = true,
+			_ => {}
+		}
+		description.push_attribute(attribute);
+	}
+
+	if !has_xmp_namespace {
+		description.push_attribute(("xmlns:xmp", XMP_NAMESPACE));
+	}
+	if !has_dc_namespace {
+		description.push_attribute(("xmlns:dc", DC_NAMESPACE));
+	}
+	if let Some(rating) = metadata.rating {
+		description.push_attribute(("xmp:Rating", rating.to_string().as_str()));
+	}
+	if let Some(label) = &metadata.label {
+		description.push_attribute(("xmp:Label", label.as_str()));
+	}
+
+	Ok(description)
+}
+
+fn write_subject(
+	writer: &mut Writer<Cursor<Vec<u8>>>,
+	tags: &[String],
+) -> Result<(), quick_xml::Error> {
+	if tags.is_empty() {
+		return Ok(());
+	}
+
+	writer.write_event(Event::Start(BytesStart::new("dc:subject")))?;
+	writer.write_event(Event::Start(BytesStart::new("rdf:Bag")))?;
+	for tag in tags {
+		writer.write_event(Event::Start(BytesStart::new("rdf:li")))?;
+		writer.write_event(Event::Text(BytesText::new(tag)))?;
+		writer.write_event(Event::End(BytesEnd::new("rdf:li")))?;
+	}
+	writer.write_event(Event::End(BytesEnd::new("rdf:Bag")))?;
+	writer.write_event(Event::End(BytesEnd::new("dc:subject")))?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LIGHTROOM_SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:crs="http://ns.adobe.com/camera-raw-settings/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+   xmp:Rating="3"
+   crs:Exposure2012="+0.50">
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>Beach</rdf:li>
+     <rdf:li>Holidays &amp; Trips</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+   <xmp:Label>Red</xmp:Label>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+	#[test]
+	fn reads_lightroom_sidecar() {
+		assert_eq!(
+			parse(LIGHTROOM_SIDECAR).unwrap(),
+			XmpMetadata {
+				tags: vec!["Beach".to_string(), "Holidays & Trips".to_string()],
+				rating: Some(3),
+				label: Some("Red".to_string()),
+			}
+		);
+	}
+
+	#[test]
+	fn updates_keeping_other_properties() {
+		let metadata = XmpMetadata {
+			tags: vec!["Family".to_string()],
+			rating: Some(5),
+			label: None,
+		};
+
+		let updated = update(Some(LIGHTROOM_SIDECAR), &metadata).unwrap();
+
+		assert_eq!(parse(&updated).unwrap(), metadata);
+		assert!(updated.contains(r#"crs:Exposure2012="+0.50""#));
+		assert!(!updated.contains("Beach"));
+	}
+
+	#[test]
+	fn creates_new_sidecar() {
+		let metadata = XmpMetadata {
+			tags: vec!["<Tag>".to_string()],
+			rating: Some(1),
+			label: Some("Blue".to_string()),
+		};
+
+		let created = update(None, &metadata).unwrap();
+
+		assert_eq!(parse(&created).unwrap(), metadata);
+		assert!(created.starts_with("<?xpacket"));
+	}
+}
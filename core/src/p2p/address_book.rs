@@ -0,0 +1,324 @@
+//! Peers reached at an address given by the user instead of being discovered on the local network,
+//! so headless servers on other subnets, or behind a VPN, can be paired and synced with.
+
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use rspc::ErrorCode;
+use sd_p2p::{Manager, PeerId};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{io::AsyncWriteExt, net::lookup_host, sync::Mutex, time::timeout};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{node::NodeConfigManager, util::migrator::MigratorError};
+
+use super::{Header, PeerMetadata};
+
+/// How often the peers of the address book are checked, which also picks up changes to their DNS
+pub(super) const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum AddressBookError {
+	#[error("address book entry not found: <id='{0}'>")]
+	NotFound(Uuid),
+	#[error("peer '{0}' is already in the address book")]
+	Duplicate(PeerId),
+	#[error("the host of a peer can't be empty")]
+	EmptyHost,
+	#[error("failed to save the address book: {0}")]
+	Config(#[from] MigratorError),
+}
+
+impl From<AddressBookError> for rspc::Error {
+	fn from(e: AddressBookError) -> Self {
+		match e {
+			AddressBookError::NotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, e.to_string(), e)
+			}
+			AddressBookError::Duplicate(_) | AddressBookError::EmptyHost => {
+				rspc::Error::with_cause(ErrorCode::BadRequest, e.to_string(), e)
+			}
+			AddressBookError::Config(_) => {
+				rspc::Error::with_cause(ErrorCode::InternalServerError, e.to_string(), e)
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct AddressBookEntry {
+	pub id: Uuid,
+	pub name: String,
+	/// A hostname or an IP address
+	pub host: String,
+	pub port: u16,
+	/// The identity key of the peer, which it must prove to have when we connect to it
+	pub peer_id: PeerId,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct AddressBookEntryArgs {
+	pub name: String,
+	pub host: String,
+	pub port: u16,
+	pub peer_id: PeerId,
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+#[serde(tag = "status")]
+pub enum PeerHealth {
+	/// Not checked since the node started or the entry was changed
+	Unknown,
+	Online {
+		latency_ms: u32,
+		checked_at: DateTime<Utc>,
+	},
+	/// The host couldn't be resolved or the peer didn't answer in time
+	Unreachable {
+		error: String,
+		checked_at: DateTime<Utc>,
+	},
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct AddressBookPeer {
+	pub entry: AddressBookEntry,
+	pub health: PeerHealth,
+}
+
+/// The entries are kept in the node config, their health only in memory
+pub struct AddressBook {
+	node_config: Arc<NodeConfigManager>,
+	manager: Arc<Manager<PeerMetadata>>,
+	health: Mutex<HashMap<Uuid, PeerHealth>>,
+}
+
+impl AddressBook {
+	pub(super) fn new(
+		node_config: Arc<NodeConfigManager>,
+		manager: Arc<Manager<PeerMetadata>>,
+	) -> Arc<Self> {
+		Arc::new(Self {
+			node_config,
+			manager,
+			health: Mutex::default(),
+		})
+	}
+
+	pub async fn list(&self) -> Vec<AddressBookPeer> {
+		let health = self.health.lock().await;
+
+		self.node_config
+			.get()
+			.await
+			.p2p_address_book
+			.into_iter()
+			.map(|entry| AddressBookPeer {
+				health: health
+					.get(&entry.id)
+					.cloned()
+					.unwrap_or(PeerHealth::Unknown),
+				entry,
+			})
+			.collect()
+	}
+
+	pub async fn add(
+		self: &Arc<Self>,
+		args: AddressBookEntryArgs,
+	) -> Result<AddressBookEntry, AddressBookError> {
+		let entry = AddressBookEntry {
+			id: Uuid::new_v4(),
+			name: args.name,
+			host: validate_host(args.host)?,
+			port: args.port,
+			peer_id: args.peer_id,
+		};
+
+		let mut result = Ok(());
+		self.node_config
+			.write(|mut config| {
+				if config
+					.p2p_address_book
+					.iter()
+					.any(|other| other.peer_id == entry.peer_id)
+				{
+					result = Err(AddressBookError::Duplicate(entry.peer_id));
+				} else {
+					config.p2p_address_book.push(entry.clone());
+				}
+			})
+			.await?;
+		result?;
+
+		self.spawn_check(entry.clone());
+
+		Ok(entry)
+	}
+
+	pub async fn update(
+		self: &Arc<Self>,
+		id: Uuid,
+		args: AddressBookEntryArgs,
+	) -> Result<AddressBookEntry, AddressBookError> {
+		let host = validate_host(args.host)?;
+
+		let mut result = Err(AddressBookError::NotFound(id));
+		let mut previous_peer_id = None;
+		self.node_config
+			.write(|mut config| {
+				if config
+					.p2p_address_book
+					.iter()
+					.any(|other| other.id != id && other.peer_id == args.peer_id)
+				{
+					result = Err(AddressBookError::Duplicate(args.peer_id));
+					return;
+				}
+
+				if let Some(entry) = config
+					.p2p_address_book
+					.iter_mut()
+					.find(|entry| entry.id == id)
+				{
+					previous_peer_id = Some(entry.peer_id);
+
+					entry.name = args.name;
+					entry.host = host;
+					entry.port = args.port;
+					entry.peer_id = args.peer_id;
+
+					result = Ok(entry.clone());
+				}
+			})
+			.await?;
+		let entry = result?;
+
+		if let Some(previous_peer_id) = previous_peer_id {
+			self.manager.remove_known_peer(previous_peer_id).await;
+		}
+		self.health.lock().await.remove(&id);
+		self.spawn_check(entry.clone());
+
+		Ok(entry)
+	}
+
+	pub async fn remove(&self, id: Uuid) -> Result<(), AddressBookError> {
+		let mut removed = None;
+		self.node_config
+			.write(|mut config| {
+				if let Some(index) = config
+					.p2p_address_book
+					.iter()
+					.position(|entry| entry.id == id)
+				{
+					removed = Some(config.p2p_address_book.remove(index));
+				}
+			})
+			.await?;
+		let removed = removed.ok_or(AddressBookError::NotFound(id))?;
+
+		self.manager.remove_known_peer(removed.peer_id).await;
+		self.health.lock().await.remove(&id);
+
+		Ok(())
+	}
+
+	/// Removes the entry of a peer if there's one, as when it's retired
+	pub(super) async fn remove_peer(&self, peer_id: PeerId) -> Result<(), AddressBookError> {
+		let mut removed = None;
+		self.node_config
+			.write(|mut config| {
+				if let Some(index) = config
+					.p2p_address_book
+					.iter()
+					.position(|entry| entry.peer_id == peer_id)
+				{
+					removed = Some(config.p2p_address_book.remove(index));
+				}
+			})
+			.await?;
+
+		if let Some(removed) = removed {
+			self.health.lock().await.remove(&removed.id);
+		}
+
+		Ok(())
+	}
+
+	/// Checks all peers of the address book at once, so a dead one doesn't hold up the others
+	pub(super) async fn check_all(&self) {
+		let entries = self.node_config.get().await.p2p_address_book;
+
+		futures::future::join_all(entries.into_iter().map(|entry| self.check(entry))).await;
+	}
+
+	fn spawn_check(self: &Arc<Self>, entry: AddressBookEntry) {
+		let this = self.clone();
+		tokio::spawn(async move { this.check(entry).await });
+	}
+
+	/// Resolves the address of the peer, so streams to it can dial it, and pings it
+	async fn check(&self, entry: AddressBookEntry) {
+		let start = Instant::now();
+
+		let result = timeout(HEALTH_CHECK_TIMEOUT, async {
+			let addresses = lookup_host((entry.host.as_str(), entry.port))
+				.await
+				.map_err(|e| format!("couldn't resolve '{}': {e}", entry.host))?
+				.collect::<Vec<SocketAddr>>();
+
+			self.manager.add_known_peer(entry.peer_id, addresses).await;
+
+			let mut stream = self
+				.manager
+				.stream(entry.peer_id)
+				.await
+				.map_err(|()| "couldn't connect to the peer".to_string())?;
+
+			stream
+				.write_all(&Header::Ping.to_bytes())
+				.await
+				.map_err(|e| e.to_string())
+		})
+		.await
+		.unwrap_or_else(|_| Err("the peer didn't answer in time".to_string()));
+
+		let checked_at = Utc::now();
+		let health = match result {
+			Ok(()) => PeerHealth::Online {
+				latency_ms: start.elapsed().as_millis() as u32,
+				checked_at,
+			},
+			Err(error) => {
+				debug!(
+					"Peer '{}' of the address book is unreachable: {error}",
+					entry.peer_id
+				);
+
+				PeerHealth::Unreachable { error, checked_at }
+			}
+		};
+
+		self.health.lock().await.insert(entry.id, health);
+	}
+}
+
+fn validate_host(host: String) -> Result<String, AddressBookError> {
+	let host = host.trim();
+
+	if host.is_empty() {
+		return Err(AddressBookError::EmptyHost);
+	}
+
+	Ok(host.to_string())
+}
@@ -0,0 +1,117 @@
+//! Negotiating compression with peers and keeping track of how much it saves on the connection to
+//! each of them. Peers advertise they can decode compressed frames in their [`PeerMetadata`], so
+//! we only ever send them to peers which said so.
+
+use std::{collections::HashMap, sync::Arc};
+
+use sd_p2p::{
+	compression::{Compression, CompressionStats},
+	Manager, PeerId,
+};
+use serde::Serialize;
+use specta::Type;
+use tokio::sync::Mutex;
+
+use crate::{custom_uri::mime_type_of, node::Metrics};
+
+use super::PeerMetadata;
+
+/// What we can decode, advertised to other peers
+pub(super) const SUPPORTED_COMPRESSION: Compression = Compression::Zstd;
+
+/// The compression to use for what we send to a peer, `None` when it can't decode any
+pub(super) async fn negotiated_compression(
+	manager: &Manager<PeerMetadata>,
+	peer_id: PeerId,
+) -> Option<Compression> {
+	manager
+		.get_discovered_peers()
+		.await
+		.into_iter()
+		.find(|peer| peer.peer_id == peer_id)
+		.filter(|peer| peer.metadata.compression)
+		.map(|_| SUPPORTED_COMPRESSION)
+}
+
+/// The compression to use for a file, skipping formats which already are compressed
+pub(super) fn compression_for_extension(
+	compression: Compression,
+	extension: Option<&str>,
+) -> Compression {
+	compression.for_mime(
+		extension
+			.map(str::to_lowercase)
+			.and_then(|extension| mime_type_of(&extension)),
+	)
+}
+
+#[derive(Debug, Default)]
+pub struct PeerStats {
+	pub sent: CompressionStats,
+	pub received: CompressionStats,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PeerCompressionStats {
+	pub peer_id: PeerId,
+	pub bytes_sent: u64,
+	pub wire_bytes_sent: u64,
+	pub bytes_received: u64,
+	pub wire_bytes_received: u64,
+	pub bytes_saved: u64,
+}
+
+/// Traffic with each peer since the node started, before and after compression
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionStats(Arc<Mutex<HashMap<PeerId, Arc<PeerStats>>>>);
+
+impl ConnectionStats {
+	async fn peer(&self, peer_id: PeerId) -> Arc<PeerStats> {
+		self.0.lock().await.entry(peer_id).or_default().clone()
+	}
+
+	/// Adds a finished transfer to the totals of the peer and the metrics of the node
+	pub(super) async fn record_sent(
+		&self,
+		peer_id: PeerId,
+		kind: &str,
+		transfer: &CompressionStats,
+		metrics: &Metrics,
+	) {
+		self.peer(peer_id).await.sent.merge(transfer);
+
+		metrics.p2p_bytes_sent.inc_by(kind, transfer.wire_bytes());
+		metrics.p2p_bytes_saved.inc_by(kind, transfer.saved_bytes());
+	}
+
+	pub(super) async fn record_received(
+		&self,
+		peer_id: PeerId,
+		kind: &str,
+		transfer: &CompressionStats,
+		metrics: &Metrics,
+	) {
+		self.peer(peer_id).await.received.merge(transfer);
+
+		metrics
+			.p2p_bytes_received
+			.inc_by(kind, transfer.wire_bytes());
+		metrics.p2p_bytes_saved.inc_by(kind, transfer.saved_bytes());
+	}
+
+	pub async fn list(&self) -> Vec<PeerCompressionStats> {
+		self.0
+			.lock()
+			.await
+			.iter()
+			.map(|(peer_id, stats)| PeerCompressionStats {
+				peer_id: *peer_id,
+				bytes_sent: stats.sent.raw_bytes(),
+				wire_bytes_sent: stats.sent.wire_bytes(),
+				bytes_received: stats.received.raw_bytes(),
+				wire_bytes_received: stats.received.wire_bytes(),
+				bytes_saved: stats.sent.saved_bytes() + stats.received.saved_bytes(),
+			})
+			.collect()
+	}
+}
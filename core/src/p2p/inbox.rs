@@ -0,0 +1,696 @@
+//! Inboxes let other devices drop files into a directory of a location without anyone accepting
+//! each of them, be it paired devices Spacedropping to this node while it's in receive mode, or
+//! people uploading through the link of an inbox.
+//!
+//! Files are staged in the data directory until they're scanned against the policy of the inbox,
+//! and by the malware scanner of the node if there's one, so nothing reaches the location before
+//! that. The ones passing are moved into the directory of the inbox and indexed, the others are
+//! quarantined or deleted.
+
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use sd_file_ext::{extensions::Extension, magic::ExtensionPossibility};
+use sd_p2p::PeerId;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{fs, sync::broadcast};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+	library::{Library, LibraryManager},
+	location::{
+		file_path_helper::{filter_existing_file_path_params, IsolatedFilePathData},
+		find_location, light_scan_location, location_with_indexer_rules,
+	},
+	node::NodeConfigManager,
+	object::{
+		fs::{extract::available_path, import::quarantine_directory},
+		malware_scan::{
+			record_scan_or_log, report_detection, scan_ingested, MalwareDetection,
+			MalwareScanResult, ScanVerdict,
+		},
+	},
+	prisma::location,
+	util::{error::FileIOError, migrator::MigratorError},
+};
+
+use super::{peer_trust_level, P2PEvent};
+
+const STAGING_DIR: &str = "inbox";
+/// Under the quarantine of the library, apart from the copies imports quarantine
+const QUARANTINE_DIR: &str = "inbox";
+
+#[derive(Error, Debug)]
+pub enum InboxError {
+	#[error("inbox not found: <id='{0}'>")]
+	NotFound(Uuid),
+	#[error("location not found: <id='{0}'>")]
+	LocationNotFound(location::id::Type),
+	#[error("the location of an inbox must be on this device")]
+	RemoteLocation,
+	#[error("'{0}' isn't a directory of the location")]
+	InvalidDirectory(String),
+	#[error("library not found: <id='{0}'>")]
+	LibraryNotFound(Uuid),
+	#[error("failed to save the inboxes: {0}")]
+	Config(#[from] MigratorError),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<InboxError> for rspc::Error {
+	fn from(e: InboxError) -> Self {
+		let code = match e {
+			InboxError::NotFound(_)
+			| InboxError::LocationNotFound(_)
+			| InboxError::LibraryNotFound(_) => ErrorCode::NotFound,
+			InboxError::RemoteLocation | InboxError::InvalidDirectory(_) => ErrorCode::BadRequest,
+			_ => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, e.to_string(), e)
+	}
+}
+
+/// Why a file isn't received at all, decided before it's transferred
+#[derive(Error, Debug)]
+pub enum InboxRefusal {
+	#[error("inbox not found")]
+	NotFound,
+	#[error("invalid file name")]
+	InvalidName,
+	#[error("the file is larger than the {0} MB the inbox accepts")]
+	TooLarge(u32),
+	#[error("files of type '{0}' aren't accepted")]
+	NotAllowed(String),
+	#[error(transparent)]
+	Staging(#[from] FileIOError),
+}
+
+/// Why a received file didn't pass the scan
+#[derive(Error, Serialize, Type, Debug, Clone)]
+pub enum ScanFinding {
+	#[error("files of type '{0}' aren't accepted")]
+	NotAllowed(String),
+	#[error("the content doesn't match the '{0}' extension")]
+	ContentMismatch(String),
+	#[error("malware found: '{0}'")]
+	Malware(String),
+	/// Files from other people aren't let in unscanned when there's a scanner
+	#[error("the malware scan failed: {0}")]
+	ScanFailed(String),
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, Default)]
+pub struct InboxPolicy {
+	/// Larger files are refused before they're transferred
+	pub max_file_size_mb: Option<u32>,
+	/// Extensions without the dot, any file is accepted when empty
+	pub allowed_extensions: Vec<String>,
+	/// Files failing the scan are quarantined for review instead of being deleted, which also
+	/// lets files of other types in to be quarantined
+	pub auto_quarantine: bool,
+}
+
+impl InboxPolicy {
+	fn refusal(&self, name: &str, size: u64) -> Option<InboxRefusal> {
+		if let Some(max_file_size_mb) = self.max_file_size_mb {
+			if size > u64::from(max_file_size_mb) * 1024 * 1024 {
+				return Some(InboxRefusal::TooLarge(max_file_size_mb));
+			}
+		}
+
+		let extension = extension_of(name);
+		if !self.auto_quarantine && !self.allows(&extension) {
+			return Some(InboxRefusal::NotAllowed(extension));
+		}
+
+		None
+	}
+
+	fn allows(&self, extension: &str) -> bool {
+		self.allowed_extensions.is_empty()
+			|| self.allowed_extensions.iter().any(|allowed| {
+				allowed
+					.trim_start_matches('.')
+					.eq_ignore_ascii_case(extension)
+			})
+	}
+
+	async fn scan(&self, path: &Path, name: &str) -> Result<(), ScanFinding> {
+		let extension = extension_of(name);
+		if !self.allows(&extension) {
+			return Err(ScanFinding::NotAllowed(extension));
+		}
+
+		// Only extensions telling a single type apart by their magic bytes can be checked
+		if matches!(
+			Extension::from_str(&extension),
+			Some(ExtensionPossibility::Known(_))
+		) && Extension::resolve_conflicting(path, true).await.is_none()
+		{
+			return Err(ScanFinding::ContentMismatch(extension));
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct Inbox {
+	pub id: Uuid,
+	pub name: String,
+	pub library_id: Uuid,
+	pub location_id: location::id::Type,
+	/// The directory files land in, relative to the location
+	pub sub_path: String,
+	pub policy: InboxPolicy,
+	/// Lets people upload to `inbox/<token>` on the custom URI endpoint, `None` when only paired
+	/// devices can drop files
+	pub upload_token: Option<String>,
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct InboxArgs {
+	pub name: String,
+	pub location_id: location::id::Type,
+	pub sub_path: String,
+	pub policy: InboxPolicy,
+	pub allow_uploads: bool,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct InboxInfo {
+	pub inbox: Inbox,
+	/// Spacedrops from paired devices land in this inbox
+	pub receiving: bool,
+	/// Where the inbox takes uploads, when they're allowed and there's a base URL for links
+	pub upload_url: Option<String>,
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum InboxArrival {
+	Indexed { path: PathBuf },
+	Quarantined { path: PathBuf, finding: ScanFinding },
+	Deleted { finding: ScanFinding },
+}
+
+/// A file being received into an inbox, staged until it's scanned
+pub struct InboxDrop {
+	inbox: Inbox,
+	name: String,
+	staged_path: PathBuf,
+}
+
+impl InboxDrop {
+	pub fn staged_path(&self) -> &Path {
+		&self.staged_path
+	}
+}
+
+/// What happens to a Spacedrop, decided before anything is transferred
+pub(super) enum SpacedropDecision {
+	/// Not in receive mode, or the device isn't paired with the library of the inbox
+	Prompt,
+	Refuse(InboxRefusal),
+	Accept(InboxDrop),
+}
+
+/// The inboxes are kept in the node config
+pub struct Inboxes {
+	node_config: Arc<NodeConfigManager>,
+	library_manager: Arc<LibraryManager>,
+	events: broadcast::Sender<P2PEvent>,
+}
+
+impl Inboxes {
+	pub(super) fn new(
+		node_config: Arc<NodeConfigManager>,
+		library_manager: Arc<LibraryManager>,
+		events: broadcast::Sender<P2PEvent>,
+	) -> Arc<Self> {
+		Arc::new(Self {
+			node_config,
+			library_manager,
+			events,
+		})
+	}
+
+	pub async fn list(&self, library_id: Uuid) -> Vec<InboxInfo> {
+		let config = self.node_config.get().await;
+
+		config
+			.p2p_inboxes
+			.into_iter()
+			.filter(|inbox| inbox.library_id == library_id)
+			.map(|inbox| InboxInfo {
+				receiving: config.p2p_receive_inbox == Some(inbox.id),
+				upload_url: config
+					.share_links_base_url
+					.as_ref()
+					.zip(inbox.upload_token.as_ref())
+					.map(|(base_url, token)| format!("{base_url}/inbox/{token}")),
+				inbox,
+			})
+			.collect()
+	}
+
+	pub async fn create(&self, library: &Library, args: InboxArgs) -> Result<Inbox, InboxError> {
+		let inbox = Inbox {
+			id: Uuid::new_v4(),
+			library_id: library.id,
+			upload_token: args
+				.allow_uploads
+				.then(|| Uuid::new_v4().simple().to_string()),
+			sub_path: validate_directory(library, args.location_id, args.sub_path).await?,
+			name: args.name,
+			location_id: args.location_id,
+			policy: args.policy,
+		};
+
+		self.node_config
+			.write(|mut config| config.p2p_inboxes.push(inbox.clone()))
+			.await?;
+
+		Ok(inbox)
+	}
+
+	pub async fn update(
+		&self,
+		library: &Library,
+		id: Uuid,
+		args: InboxArgs,
+	) -> Result<Inbox, InboxError> {
+		let sub_path = validate_directory(library, args.location_id, args.sub_path).await?;
+
+		let mut result = Err(InboxError::NotFound(id));
+		self.node_config
+			.write(|mut config| {
+				if let Some(inbox) = config
+					.p2p_inboxes
+					.iter_mut()
+					.find(|inbox| inbox.id == id && inbox.library_id == library.id)
+				{
+					inbox.name = args.name;
+					inbox.location_id = args.location_id;
+					inbox.sub_path = sub_path;
+					inbox.policy = args.policy;
+					// The link keeps working as long as uploads stay allowed
+					inbox.upload_token = args.allow_uploads.then(|| {
+						inbox
+							.upload_token
+							.take()
+							.unwrap_or_else(|| Uuid::new_v4().simple().to_string())
+					});
+
+					result = Ok(inbox.clone());
+				}
+			})
+			.await?;
+
+		result
+	}
+
+	pub async fn delete(&self, library_id: Uuid, id: Uuid) -> Result<(), InboxError> {
+		let mut found = false;
+		self.node_config
+			.write(|mut config| {
+				let count = config.p2p_inboxes.len();
+				config
+					.p2p_inboxes
+					.retain(|inbox| inbox.id != id || inbox.library_id != library_id);
+				found = config.p2p_inboxes.len() != count;
+
+				if found && config.p2p_receive_inbox == Some(id) {
+					config.p2p_receive_inbox = None;
+				}
+			})
+			.await?;
+
+		found.then_some(()).ok_or(InboxError::NotFound(id))
+	}
+
+	/// Puts the node in receive mode with the inbox, or out of it with `None`
+	pub async fn set_receiving(&self, id: Option<Uuid>) -> Result<(), InboxError> {
+		let mut result = Ok(());
+		self.node_config
+			.write(|mut config| match id {
+				Some(id) if !config.p2p_inboxes.iter().any(|inbox| inbox.id == id) => {
+					result = Err(InboxError::NotFound(id));
+				}
+				id => config.p2p_receive_inbox = id,
+			})
+			.await?;
+
+		result
+	}
+
+	pub(super) async fn for_spacedrop(
+		&self,
+		peer_id: PeerId,
+		name: &str,
+		size: u64,
+	) -> SpacedropDecision {
+		let config = self.node_config.get().await;
+		let Some(inbox) = config
+			.p2p_receive_inbox
+			.and_then(|id| config.p2p_inboxes.into_iter().find(|inbox| inbox.id == id))
+		else {
+			return SpacedropDecision::Prompt;
+		};
+
+		let Some(library) = self.library_manager.get_library(inbox.library_id).await else {
+			return SpacedropDecision::Prompt;
+		};
+
+		match peer_trust_level(&library, peer_id).await {
+			Ok(Some(_)) => {}
+			Ok(None) => return SpacedropDecision::Prompt,
+			Err(e) => {
+				error!("Failed to check if peer '{peer_id}' can drop files into an inbox: {e}");
+				return SpacedropDecision::Prompt;
+			}
+		}
+
+		match stage(&library, inbox, name, size).await {
+			Ok(drop) => SpacedropDecision::Accept(drop),
+			Err(refusal) => SpacedropDecision::Refuse(refusal),
+		}
+	}
+
+	/// Whether there's an inbox taking uploads with this token
+	pub(crate) async fn has_upload_token(&self, token: &str) -> bool {
+		self.node_config
+			.get()
+			.await
+			.p2p_inboxes
+			.iter()
+			.any(|inbox| inbox.upload_token.as_deref() == Some(token))
+	}
+
+	pub(crate) async fn for_upload(
+		&self,
+		token: &str,
+		name: &str,
+		size: u64,
+	) -> Result<InboxDrop, InboxRefusal> {
+		let inbox = self
+			.node_config
+			.get()
+			.await
+			.p2p_inboxes
+			.into_iter()
+			.find(|inbox| inbox.upload_token.as_deref() == Some(token))
+			.ok_or(InboxRefusal::NotFound)?;
+
+		let library = self
+			.library_manager
+			.get_library(inbox.library_id)
+			.await
+			.ok_or(InboxRefusal::NotFound)?;
+
+		stage(&library, inbox, name, size).await
+	}
+
+	/// Scans the staged file once it's fully written, then moves it to where it belongs
+	pub(crate) async fn receive(&self, drop: InboxDrop) -> Result<InboxArrival, InboxError> {
+		let library = self
+			.library_manager
+			.get_library(drop.inbox.library_id)
+			.await
+			.ok_or(InboxError::LibraryNotFound(drop.inbox.library_id))?;
+
+		let result = file_arrived(&library, &drop).await;
+		// Whatever happened, the staged copy isn't kept around
+		fs::remove_file(&drop.staged_path).await.ok();
+
+		let arrival = result?;
+		info!(
+			"Received '{}' into inbox '{}': {arrival:?}",
+			drop.name, drop.inbox.name
+		);
+
+		self.events
+			.send(P2PEvent::InboxArrival {
+				inbox_id: drop.inbox.id,
+				name: drop.name,
+				arrival: arrival.clone(),
+			})
+			.ok();
+
+		Ok(arrival)
+	}
+}
+
+async fn validate_directory(
+	library: &Library,
+	location_id: location::id::Type,
+	sub_path: String,
+) -> Result<String, InboxError> {
+	let location = library
+		.db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.select(location::select!({ path node_id }))
+		.exec()
+		.await?
+		.ok_or(InboxError::LocationNotFound(location_id))?;
+
+	let location_path = match (location.path, location.node_id) {
+		(Some(path), Some(node_id)) if node_id == library.node_local_id => path,
+		_ => return Err(InboxError::RemoteLocation),
+	};
+
+	let sub_path = sub_path.trim_matches('/').to_string();
+	let is_directory = Path::new(&sub_path)
+		.components()
+		.all(|component| matches!(component, std::path::Component::Normal(_)))
+		&& fs::metadata(Path::new(&location_path).join(&sub_path))
+			.await
+			.map_or(false, |metadata| metadata.is_dir());
+
+	if is_directory {
+		Ok(sub_path)
+	} else {
+		Err(InboxError::InvalidDirectory(sub_path))
+	}
+}
+
+async fn stage(
+	library: &Library,
+	inbox: Inbox,
+	name: &str,
+	size: u64,
+) -> Result<InboxDrop, InboxRefusal> {
+	let name = file_name(name).ok_or(InboxRefusal::InvalidName)?;
+
+	if let Some(refusal) = inbox.policy.refusal(&name, size) {
+		return Err(refusal);
+	}
+
+	let staging_directory = library
+		.config()
+		.data_directory()
+		.join(STAGING_DIR)
+		.join(inbox.id.to_string());
+	fs::create_dir_all(&staging_directory)
+		.await
+		.map_err(|e| FileIOError::from((&staging_directory, e)))?;
+
+	Ok(InboxDrop {
+		// The scan checks the content against the extension, so the staged file keeps it
+		staged_path: staging_directory.join(format!("{}-{name}", Uuid::new_v4().simple())),
+		inbox,
+		name,
+	})
+}
+
+/// The malware scan of a file which passed the policy
+async fn malware_scan(
+	library: &Library,
+	drop: &InboxDrop,
+) -> Result<Option<MalwareScanResult>, ScanFinding> {
+	match scan_ingested(library, &drop.staged_path).await {
+		None => Ok(None),
+		Some(Ok(MalwareScanResult {
+			verdict: ScanVerdict::Infected { signature },
+			..
+		})) => Err(ScanFinding::Malware(signature)),
+		Some(Ok(result)) => Ok(Some(result)),
+		Some(Err(e)) => Err(ScanFinding::ScanFailed(e.to_string())),
+	}
+}
+
+async fn file_arrived(library: &Library, drop: &InboxDrop) -> Result<InboxArrival, InboxError> {
+	let scan = match drop.inbox.policy.scan(&drop.staged_path, &drop.name).await {
+		Ok(()) => malware_scan(library, drop).await,
+		Err(finding) => Err(finding),
+	};
+
+	let finding = match scan {
+		Ok(scan_result) => {
+			let location = find_location(library, drop.inbox.location_id)
+				.include(location_with_indexer_rules::include())
+				.exec()
+				.await?
+				.ok_or(InboxError::LocationNotFound(drop.inbox.location_id))?;
+			let location_id = location.id;
+			let location_path =
+				PathBuf::from(location.path.as_deref().ok_or(InboxError::RemoteLocation)?);
+			let directory = location_path.join(&drop.inbox.sub_path);
+
+			let path = move_file(&drop.staged_path, &directory.join(&drop.name)).await?;
+
+			// The file is already in place, the watcher or the next scan will pick it up
+			if let Err(e) = light_scan_location(library.clone(), location, &directory).await {
+				error!(
+					"Failed to index '{}' received into an inbox: {e:#?}",
+					path.display()
+				);
+			}
+
+			if let Some(scan_result) = scan_result {
+				match IsolatedFilePathData::new(location_id, &location_path, &path, false) {
+					Ok(iso_file_path) => {
+						let iso_file_path =
+							iso_file_path.normalized(library.config.file_name_normalization);
+						record_scan_or_log(
+							library,
+							filter_existing_file_path_params(&iso_file_path),
+							&scan_result,
+						)
+						.await;
+					}
+					Err(e) => error!("Failed to record the scan of '{}': {e:#?}", path.display()),
+				}
+			}
+
+			return Ok(InboxArrival::Indexed { path });
+		}
+		Err(finding) => finding,
+	};
+
+	let (arrival, quarantined_path) = if drop.inbox.policy.auto_quarantine {
+		let directory = quarantine_directory(library).join(QUARANTINE_DIR);
+		fs::create_dir_all(&directory)
+			.await
+			.map_err(|e| FileIOError::from((&directory, e)))?;
+
+		let path = move_file(&drop.staged_path, &directory.join(&drop.name)).await?;
+
+		(
+			InboxArrival::Quarantined {
+				path: path.clone(),
+				finding: finding.clone(),
+			},
+			Some(path),
+		)
+	} else {
+		(
+			InboxArrival::Deleted {
+				finding: finding.clone(),
+			},
+			None,
+		)
+	};
+
+	if let ScanFinding::Malware(signature) = finding {
+		report_detection(
+			library,
+			MalwareDetection {
+				library_id: library.id,
+				name: drop.name.clone(),
+				signature,
+				quarantined_path,
+			},
+		);
+	}
+
+	Ok(arrival)
+}
+
+/// Moves the file next to the ones with the same name instead of replacing them, copying it when
+/// the target is on another volume
+async fn move_file(from: &Path, to: &Path) -> Result<PathBuf, FileIOError> {
+	let to = if fs::symlink_metadata(to).await.is_ok() {
+		available_path(to)
+	} else {
+		to.to_path_buf()
+	};
+
+	if fs::rename(from, &to).await.is_err() {
+		fs::copy(from, &to)
+			.await
+			.map_err(|e| FileIOError::from((&to, e)))?;
+	}
+
+	Ok(to)
+}
+
+/// The name sent along with a file may come from any platform, only its last component is kept
+fn file_name(name: &str) -> Option<String> {
+	let name = name.rsplit(['/', '\\']).next()?.trim();
+
+	(!name.is_empty() && name != "." && name != ".." && !name.contains('\0'))
+		.then(|| name.to_string())
+}
+
+fn extension_of(name: &str) -> String {
+	Path::new(name)
+		.extension()
+		.map(|extension| extension.to_string_lossy().to_lowercase())
+		.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_keep_their_last_component() {
+		assert_eq!(file_name("photo.jpg").as_deref(), Some("photo.jpg"));
+		assert_eq!(file_name("../../etc/passwd").as_deref(), Some("passwd"));
+		assert_eq!(file_name("C:\\Users\\me\\a.txt").as_deref(), Some("a.txt"));
+
+		for name in ["", "dir/", "..", "a/.", "a\0b"] {
+			assert_eq!(file_name(name), None, "{name:?}");
+		}
+	}
+
+	#[test]
+	fn policy_refuses_before_transfer() {
+		let policy = InboxPolicy {
+			max_file_size_mb: Some(1),
+			allowed_extensions: vec!["jpg".into(), ".PNG".into()],
+			auto_quarantine: false,
+		};
+
+		assert!(policy.refusal("a.jpg", 1024).is_none());
+		assert!(policy.refusal("a.png", 1024).is_none());
+		assert!(matches!(
+			policy.refusal("a.jpg", 2 * 1024 * 1024),
+			Some(InboxRefusal::TooLarge(1))
+		));
+		assert!(matches!(
+			policy.refusal("a.exe", 1024),
+			Some(InboxRefusal::NotAllowed(extension)) if extension == "exe"
+		));
+
+		// Quarantined instead, once received and scanned
+		let policy = InboxPolicy {
+			auto_quarantine: true,
+			..policy
+		};
+		assert!(policy.refusal("a.exe", 1024).is_none());
+	}
+}
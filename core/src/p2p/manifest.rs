@@ -0,0 +1,754 @@
+//! Manifests describe a selection of files by their content, so another node can fetch exactly
+//! those files from the node which published them and check it got them unaltered, like when
+//! sharing a dataset which has to be reproducible.
+//!
+//! A manifest is a JSON file signed with the identity key of the node publishing it. Each entry
+//! names a file by the BLAKE3 hash of its whole content, the same hash as the integrity checksum
+//! of file paths, so the publisher serves whichever copy of the content it has. Anyone holding the
+//! manifest can fetch its files, without being paired with the library. Like share links,
+//! published manifests only live in memory, publishing the file again after a restart serves them
+//! anew.
+
+use std::{
+	collections::{HashMap, HashSet},
+	ffi::OsString,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use prisma_client_rust::QueryError;
+use rspc::ErrorCode;
+use sd_crypto::header::file::FileHeader;
+use sd_p2p::{compression::CompressionStats, Keypair, Manager, PeerId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	fs::{self, File},
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+	sync::Mutex,
+};
+use uuid::Uuid;
+
+use crate::{
+	library::{Library, LibraryManager},
+	object::{fs::ghost::ReachableLocations, validation::hash::file_checksum},
+	prisma::file_path,
+	sync,
+	util::{
+		db::{maybe_missing, MissingFieldError},
+		error::FileIOError,
+	},
+};
+
+use super::{
+	request_content, send_file, Header, ManifestFileRequest, PeerMetadata, RemoteFileError,
+};
+
+const MANIFEST_VERSION: u8 = 1;
+/// Keeps a manifest signature from being mistaken for any other signature by the same key
+const SIGNATURE_CONTEXT: &[u8] = b"spacedrive share manifest v1";
+/// Files are fetched in chunks of this size, so large ones are never held in memory whole
+const FETCH_CHUNK_LEN: u64 = 8 * 1024 * 1024;
+const PARTIAL_FILE_EXT: &str = ".part";
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+	#[error("unsupported manifest version '{0}'")]
+	UnsupportedVersion(u8),
+	#[error("the manifest isn't signed by its publisher")]
+	InvalidSignature,
+	#[error("invalid path in manifest: '{0}'")]
+	InvalidPath(String),
+	#[error("invalid checksum in manifest: '{0}'")]
+	InvalidChecksum(String),
+	#[error("invalid size in manifest: '{0}'")]
+	InvalidSize(String),
+	#[error("two files of the manifest have the same path: '{0}'")]
+	DuplicatePath(String),
+	#[error("no files to put in the manifest")]
+	Empty,
+	#[error("the manifest wasn't published by this node")]
+	NotPublisher,
+	#[error("file path not found: <id='{0}'>")]
+	FilePathNotFound(file_path::id::Type),
+	#[error("the content of '{0}' isn't reachable from this device")]
+	Unreachable(String),
+	#[error("the content received for '{0}' doesn't match the manifest")]
+	Mismatch(String),
+	#[error("'{}' isn't a valid manifest: {}", .0.display(), .1)]
+	Parse(PathBuf, serde_json::Error),
+	#[error(transparent)]
+	FileIO(#[from] FileIOError),
+	#[error(transparent)]
+	RemoteFile(#[from] RemoteFileError),
+	#[error(transparent)]
+	MissingField(#[from] MissingFieldError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<ManifestError> for rspc::Error {
+	fn from(e: ManifestError) -> Self {
+		let code = match e {
+			ManifestError::FilePathNotFound(_) => ErrorCode::NotFound,
+			ManifestError::UnsupportedVersion(_)
+			| ManifestError::InvalidSignature
+			| ManifestError::InvalidPath(_)
+			| ManifestError::InvalidChecksum(_)
+			| ManifestError::InvalidSize(_)
+			| ManifestError::DuplicatePath(_)
+			| ManifestError::Empty
+			| ManifestError::NotPublisher
+			| ManifestError::Unreachable(_)
+			| ManifestError::Parse(..) => ErrorCode::BadRequest,
+			ManifestError::Mismatch(_) => ErrorCode::Conflict,
+			_ => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, e.to_string(), e)
+	}
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+	/// Relative to the directory the selection was made in, with `/` separators
+	pub path: String,
+	pub size_in_bytes: String,
+	/// The hex BLAKE3 hash of the whole content
+	pub checksum: String,
+	/// The hex header of files encrypted by Spacedrive, holding the algorithm and keyslots needed
+	/// to decrypt them once fetched
+	pub encryption_header: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct ShareManifest {
+	pub version: u8,
+	pub id: Uuid,
+	pub name: String,
+	pub library_id: Uuid,
+	pub created_at: DateTime<Utc>,
+	/// The hex identity key of the publishing node, the peer the files are fetched from
+	pub publisher: String,
+	pub entries: Vec<ManifestEntry>,
+	/// The hex signature of everything above by the publisher
+	pub signature: String,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ManifestSummary {
+	pub id: Uuid,
+	pub name: String,
+	pub publisher: PeerId,
+	pub created_at: DateTime<Utc>,
+	pub files: u32,
+	pub size_in_bytes: String,
+	pub encrypted_files: u32,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ManifestFetchReport {
+	pub fetched: u32,
+	/// Files already at the destination with the right content
+	pub skipped: u32,
+	pub size_in_bytes: String,
+}
+
+impl ShareManifest {
+	/// Entries must be sorted by path, so the same selection always gives the same manifest
+	pub fn sign(
+		keypair: &Keypair,
+		library_id: Uuid,
+		name: String,
+		entries: Vec<ManifestEntry>,
+	) -> Self {
+		let mut manifest = Self {
+			version: MANIFEST_VERSION,
+			id: Uuid::new_v4(),
+			name,
+			library_id,
+			created_at: Utc::now(),
+			publisher: hex::encode(keypair.public_key()),
+			entries,
+			signature: String::new(),
+		};
+		manifest.signature = hex::encode(keypair.sign(&manifest.signed_bytes()));
+
+		manifest
+	}
+
+	/// Checks the manifest is well formed and signed, returning the peer which published it
+	pub fn verify(&self) -> Result<PeerId, ManifestError> {
+		if self.version != MANIFEST_VERSION {
+			return Err(ManifestError::UnsupportedVersion(self.version));
+		}
+
+		let mut paths = HashSet::with_capacity(self.entries.len());
+		for entry in &self.entries {
+			validate_path(&entry.path)?;
+
+			if !paths.insert(entry.path.as_str()) {
+				return Err(ManifestError::DuplicatePath(entry.path.clone()));
+			}
+
+			entry.checksum()?;
+			entry.size()?;
+		}
+
+		let public_key = hex::decode(&self.publisher)
+			.ok()
+			.and_then(|public_key| <[u8; 32]>::try_from(public_key).ok())
+			.ok_or(ManifestError::InvalidSignature)?;
+		let signature =
+			hex::decode(&self.signature).map_err(|_| ManifestError::InvalidSignature)?;
+
+		PeerId::from_signature(&public_key, &self.signed_bytes(), &signature)
+			.ok_or(ManifestError::InvalidSignature)
+	}
+
+	pub fn summary(&self) -> Result<ManifestSummary, ManifestError> {
+		let publisher = self.verify()?;
+
+		Ok(ManifestSummary {
+			id: self.id,
+			name: self.name.clone(),
+			publisher,
+			created_at: self.created_at,
+			files: self.entries.len() as u32,
+			size_in_bytes: self
+				.entries
+				.iter()
+				.map(ManifestEntry::size)
+				.sum::<Result<u64, _>>()?
+				.to_string(),
+			encrypted_files: self
+				.entries
+				.iter()
+				.filter(|entry| entry.encryption_header.is_some())
+				.count() as u32,
+		})
+	}
+
+	pub async fn read(path: impl AsRef<Path>) -> Result<Self, ManifestError> {
+		let path = path.as_ref();
+		let bytes = fs::read(path)
+			.await
+			.map_err(|e| FileIOError::from((path, e)))?;
+
+		serde_json::from_slice(&bytes).map_err(|e| ManifestError::Parse(path.to_path_buf(), e))
+	}
+
+	pub async fn write(&self, path: impl AsRef<Path>) -> Result<(), ManifestError> {
+		let path = path.as_ref();
+		let bytes = serde_json::to_vec_pretty(self).expect("manifests are always serializable");
+
+		fs::write(path, bytes)
+			.await
+			.map_err(|e| FileIOError::from((path, e)).into())
+	}
+
+	fn signed_bytes(&self) -> Vec<u8> {
+		let mut bytes = SIGNATURE_CONTEXT.to_vec();
+		serde_json::to_writer(
+			&mut bytes,
+			&(
+				self.version,
+				&self.id,
+				&self.name,
+				&self.library_id,
+				&self.created_at,
+				&self.publisher,
+				&self.entries,
+			),
+		)
+		.expect("manifests are always serializable");
+
+		bytes
+	}
+}
+
+impl ManifestEntry {
+	fn checksum(&self) -> Result<blake3::Hash, ManifestError> {
+		blake3::Hash::from_hex(&self.checksum)
+			.map_err(|_| ManifestError::InvalidChecksum(self.checksum.clone()))
+	}
+
+	fn size(&self) -> Result<u64, ManifestError> {
+		self.size_in_bytes
+			.parse()
+			.map_err(|_| ManifestError::InvalidSize(self.size_in_bytes.clone()))
+	}
+}
+
+/// Paths come from whoever made the manifest, so they must stay inside the destination they're
+/// fetched to, whatever the platform
+fn validate_path(path: &str) -> Result<(), ManifestError> {
+	let is_valid = !path.is_empty()
+		&& !path.contains(['\\', ':', '\0'])
+		&& path
+			.split('/')
+			.all(|name| !name.is_empty() && name != "." && name != "..");
+
+	if is_valid {
+		Ok(())
+	} else {
+		Err(ManifestError::InvalidPath(path.to_string()))
+	}
+}
+
+struct PublishedManifest {
+	name: String,
+	library_id: Uuid,
+	checksums: HashSet<String>,
+	published_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct PublishedManifestInfo {
+	pub id: Uuid,
+	pub name: String,
+	pub library_id: Uuid,
+	pub files: u32,
+	pub published_at: DateTime<Utc>,
+}
+
+/// The manifests whose files this node serves to anyone asking
+#[derive(Default, Clone)]
+pub struct PublishedManifests(Arc<Mutex<HashMap<Uuid, PublishedManifest>>>);
+
+impl PublishedManifests {
+	pub(super) async fn publish(&self, manifest: &ShareManifest) {
+		self.0.lock().await.insert(
+			manifest.id,
+			PublishedManifest {
+				name: manifest.name.clone(),
+				library_id: manifest.library_id,
+				checksums: manifest
+					.entries
+					.iter()
+					.map(|entry| entry.checksum.clone())
+					.collect(),
+				published_at: Utc::now(),
+			},
+		);
+	}
+
+	pub async fn revoke(&self, id: Uuid) -> bool {
+		self.0.lock().await.remove(&id).is_some()
+	}
+
+	pub async fn list(&self) -> Vec<PublishedManifestInfo> {
+		self.0
+			.lock()
+			.await
+			.iter()
+			.map(|(id, manifest)| PublishedManifestInfo {
+				id: *id,
+				name: manifest.name.clone(),
+				library_id: manifest.library_id,
+				files: manifest.checksums.len() as u32,
+				published_at: manifest.published_at,
+			})
+			.collect()
+	}
+
+	/// The library to serve the content from, if it's part of the manifest
+	async fn library_serving(&self, id: Uuid, checksum: &str) -> Option<Uuid> {
+		self.0
+			.lock()
+			.await
+			.get(&id)
+			.filter(|manifest| manifest.checksums.contains(checksum))
+			.map(|manifest| manifest.library_id)
+	}
+}
+
+/// Describes the files, and the files in the directories, among the file paths. Files without an
+/// integrity checksum yet get one, which is synced like the ones the validator computes.
+pub(super) async fn create_manifest(
+	library: &Library,
+	keypair: &Keypair,
+	name: String,
+	file_path_ids: Vec<file_path::id::Type>,
+) -> Result<ShareManifest, ManifestError> {
+	let Library { db, sync, .. } = library;
+
+	let selected = db
+		.file_path()
+		.find_many(vec![file_path::id::in_vec(file_path_ids.clone())])
+		.exec()
+		.await?;
+
+	if let Some(&missing) = file_path_ids
+		.iter()
+		.find(|&&id| !selected.iter().any(|file_path| file_path.id == id))
+	{
+		return Err(ManifestError::FilePathNotFound(missing));
+	}
+
+	// With the materialized path they're relative to
+	let mut files = Vec::new();
+	for file_path in selected {
+		let base =
+			maybe_missing(&file_path.materialized_path, "file_path.materialized_path")?.clone();
+
+		if file_path.is_dir.unwrap_or(false) {
+			let children_path = format!(
+				"{base}{}/",
+				maybe_missing(&file_path.name, "file_path.name")?
+			);
+
+			files.extend(
+				db.file_path()
+					.find_many(vec![
+						file_path::location_id::equals(file_path.location_id),
+						file_path::materialized_path::starts_with(children_path),
+						file_path::is_dir::equals(Some(false)),
+					])
+					.exec()
+					.await?
+					.into_iter()
+					.map(|child| (child, base.clone())),
+			);
+		} else {
+			files.push((file_path, base));
+		}
+	}
+
+	if files.is_empty() {
+		return Err(ManifestError::Empty);
+	}
+
+	let reachable = ReachableLocations::fetch(library).await?;
+
+	let mut entries = Vec::with_capacity(files.len());
+	for (file_path, base) in files {
+		let relative_path = relative_path(&file_path, &base)?;
+		let path = reachable
+			.content_path(&file_path)
+			.ok_or_else(|| ManifestError::Unreachable(relative_path.clone()))?;
+
+		let size = fs::metadata(&path)
+			.await
+			.map_err(|e| FileIOError::from((&path, e)))?
+			.len();
+
+		let checksum = match file_path.integrity_checksum {
+			Some(checksum) => checksum,
+			None => {
+				let checksum = file_checksum(&path)
+					.await
+					.map_err(|e| FileIOError::from((&path, e)))?;
+
+				sync.write_op(
+					db,
+					sync.shared_update(
+						sync::file_path::SyncId {
+							pub_id: file_path.pub_id.clone(),
+						},
+						file_path::integrity_checksum::NAME,
+						json!(&checksum),
+					),
+					db.file_path().update(
+						file_path::id::equals(file_path.id),
+						vec![file_path::integrity_checksum::set(Some(checksum.clone()))],
+					),
+				)
+				.await?;
+
+				checksum
+			}
+		};
+
+		entries.push(ManifestEntry {
+			path: relative_path,
+			size_in_bytes: size.to_string(),
+			checksum,
+			encryption_header: encryption_header(&path).await,
+		});
+	}
+
+	entries.sort_by(|a, b| a.path.cmp(&b.path));
+	if let Some(pair) = entries.windows(2).find(|pair| pair[0].path == pair[1].path) {
+		return Err(ManifestError::DuplicatePath(pair[0].path.clone()));
+	}
+
+	Ok(ShareManifest::sign(keypair, library.id, name, entries))
+}
+
+fn relative_path(file_path: &file_path::Data, base: &str) -> Result<String, ManifestError> {
+	let materialized_path =
+		maybe_missing(&file_path.materialized_path, "file_path.materialized_path")?;
+	let name = maybe_missing(&file_path.name, "file_path.name")?;
+	let directory = materialized_path
+		.strip_prefix(base)
+		.unwrap_or(materialized_path);
+
+	Ok(match file_path.extension.as_deref() {
+		Some(extension) if !extension.is_empty() => format!("{directory}{name}.{extension}"),
+		_ => format!("{directory}{name}"),
+	})
+}
+
+async fn encryption_header(path: &Path) -> Option<String> {
+	let mut file = File::open(path).await.ok()?;
+	let (header, _) = FileHeader::from_reader(&mut file).await.ok()?;
+
+	header.to_bytes().ok().map(hex::encode)
+}
+
+/// Run by the publisher, which only serves the content of the manifests it published
+pub(super) async fn serve_manifest_file_request(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	request: ManifestFileRequest,
+	compressed: bool,
+	manifests: &PublishedManifests,
+	library_manager: &LibraryManager,
+	stats: &CompressionStats,
+) -> Result<(), RemoteFileError> {
+	send_file(
+		stream,
+		open_manifest_file(&request, manifests, library_manager).await,
+		request.range,
+		compressed,
+		stats,
+	)
+	.await
+}
+
+async fn open_manifest_file(
+	request: &ManifestFileRequest,
+	manifests: &PublishedManifests,
+	library_manager: &LibraryManager,
+) -> Result<(File, u64, Option<String>), RemoteFileError> {
+	let checksum = blake3::Hash::from(request.checksum).to_hex().to_string();
+
+	let library_id = manifests
+		.library_serving(request.manifest_id, &checksum)
+		.await
+		.ok_or(RemoteFileError::NotFound)?;
+	let library = library_manager
+		.get_library(library_id)
+		.await
+		.ok_or(RemoteFileError::NotFound)?;
+
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::integrity_checksum::equals(Some(checksum)),
+			file_path::is_dir::equals(Some(false)),
+		])
+		.exec()
+		.await?;
+
+	let reachable = ReachableLocations::fetch(&library).await?;
+	for file_path in file_paths {
+		if let Some(location_id) = file_path.location_id {
+			if library.private_locations.is_locked(location_id).await {
+				continue;
+			}
+		}
+
+		let Some(path) = reachable.content_path(&file_path) else {
+			continue;
+		};
+
+		// The content may have changed since it was hashed, which the requester finds out
+		if let Ok(file) = File::open(&path).await {
+			let total_size = file.metadata().await?.len();
+
+			return Ok((file, total_size, file_path.extension));
+		}
+	}
+
+	Err(RemoteFileError::NotFound)
+}
+
+/// Run by the node holding the manifest, fetching its files from the publisher into the
+/// destination, where the ones already there are kept
+pub(super) async fn fetch_manifest(
+	manager: &Manager<PeerMetadata>,
+	manifest: &ShareManifest,
+	destination: &Path,
+	stats: &CompressionStats,
+) -> Result<ManifestFetchReport, ManifestError> {
+	let publisher = manifest.verify()?;
+
+	let (mut fetched, mut skipped, mut size_in_bytes) = (0, 0, 0);
+	for entry in &manifest.entries {
+		let target = entry
+			.path
+			.split('/')
+			.fold(destination.to_path_buf(), |path, name| path.join(name));
+
+		if file_checksum(&target).await.ok().as_deref() == Some(entry.checksum.as_str()) {
+			skipped += 1;
+			continue;
+		}
+
+		fetch_entry(manager, publisher, manifest.id, entry, &target, stats).await?;
+
+		fetched += 1;
+		size_in_bytes += entry.size()?;
+	}
+
+	Ok(ManifestFetchReport {
+		fetched,
+		skipped,
+		size_in_bytes: size_in_bytes.to_string(),
+	})
+}
+
+/// Writes next to the target and only moves it there once the content is checked
+async fn fetch_entry(
+	manager: &Manager<PeerMetadata>,
+	publisher: PeerId,
+	manifest_id: Uuid,
+	entry: &ManifestEntry,
+	target: &Path,
+	stats: &CompressionStats,
+) -> Result<(), ManifestError> {
+	let checksum = entry.checksum()?;
+	let size = entry.size()?;
+
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.map_err(|e| FileIOError::from((parent, e)))?;
+	}
+
+	let partial = PathBuf::from({
+		let mut partial = OsString::from(target);
+		partial.push(PARTIAL_FILE_EXT);
+		partial
+	});
+	let mut file = File::create(&partial)
+		.await
+		.map_err(|e| FileIOError::from((&partial, e)))?;
+
+	let mut hasher = blake3::Hasher::new();
+	let mut received = 0;
+	while received < size {
+		let chunk = request_content(
+			manager,
+			publisher,
+			Header::ManifestFile(ManifestFileRequest {
+				manifest_id,
+				checksum: *checksum.as_bytes(),
+				range: Some((received, FETCH_CHUNK_LEN)),
+			}),
+			stats,
+		)
+		.await?;
+
+		if chunk.total_size != size || chunk.data.is_empty() {
+			break;
+		}
+
+		hasher.update(&chunk.data);
+		file.write_all(&chunk.data)
+			.await
+			.map_err(|e| FileIOError::from((&partial, e)))?;
+		received += chunk.data.len() as u64;
+	}
+
+	file.flush()
+		.await
+		.map_err(|e| FileIOError::from((&partial, e)))?;
+	drop(file);
+
+	if received != size || hasher.finalize() != checksum {
+		fs::remove_file(&partial).await.ok();
+
+		return Err(ManifestError::Mismatch(entry.path.clone()));
+	}
+
+	fs::rename(&partial, target)
+		.await
+		.map_err(|e| FileIOError::from((target, e)).into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(path: &str, content: &[u8]) -> ManifestEntry {
+		ManifestEntry {
+			path: path.to_string(),
+			size_in_bytes: content.len().to_string(),
+			checksum: blake3::hash(content).to_hex().to_string(),
+			encryption_header: None,
+		}
+	}
+
+	#[test]
+	fn signed_manifest_verifies() {
+		let keypair = Keypair::generate();
+		let manifest = ShareManifest::sign(
+			&keypair,
+			Uuid::new_v4(),
+			"Dataset".into(),
+			vec![entry("a.csv", b"a"), entry("images/b.png", b"b")],
+		);
+
+		let roundtrip: ShareManifest =
+			serde_json::from_slice(&serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+		assert_eq!(roundtrip.verify().unwrap(), keypair.peer_id());
+	}
+
+	#[test]
+	fn tampered_manifest_is_rejected() {
+		let keypair = Keypair::generate();
+		let mut manifest = ShareManifest::sign(
+			&keypair,
+			Uuid::new_v4(),
+			"Dataset".into(),
+			vec![entry("a.csv", b"a")],
+		);
+		manifest.entries[0].checksum = blake3::hash(b"other").to_hex().to_string();
+
+		assert!(matches!(
+			manifest.verify(),
+			Err(ManifestError::InvalidSignature)
+		));
+
+		let mut manifest = ShareManifest::sign(
+			&keypair,
+			Uuid::new_v4(),
+			"Dataset".into(),
+			vec![entry("a.csv", b"a")],
+		);
+		manifest.publisher = hex::encode(Keypair::generate().public_key());
+
+		assert!(matches!(
+			manifest.verify(),
+			Err(ManifestError::InvalidSignature)
+		));
+	}
+
+	#[test]
+	fn paths_stay_inside_the_destination() {
+		for path in ["a.csv", "images/b.png", "a/b/c"] {
+			assert!(validate_path(path).is_ok(), "{path}");
+		}
+
+		for path in [
+			"",
+			"/etc/passwd",
+			"../a",
+			"a/../../b",
+			"a//b",
+			"./a",
+			"a/",
+			"C:/a",
+			"a\\..\\b",
+		] {
+			assert!(validate_path(path).is_err(), "{path}");
+		}
+	}
+}
@@ -1,11 +1,31 @@
 #![allow(clippy::unwrap_used, clippy::panic)] // TODO: Remove once this is fully stablised
 
+mod address_book;
+mod compression;
+mod inbox;
+mod manifest;
 mod p2p_manager;
+mod pairing;
 mod peer_metadata;
 mod protocol;
+mod remote_file;
+mod retirement;
+mod security;
+mod share_links;
+mod trust;
 
+pub use address_book::*;
+pub use compression::*;
+pub use inbox::*;
+pub use manifest::*;
 pub use p2p_manager::*;
+pub use pairing::*;
 pub use peer_metadata::*;
 pub use protocol::*;
+pub use remote_file::*;
+pub use retirement::*;
+pub use security::*;
+pub use share_links::*;
+pub use trust::*;
 
 pub(super) const SPACEDRIVE_APP_ID: &str = "spacedrive";
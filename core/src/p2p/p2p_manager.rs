@@ -1,7 +1,7 @@
 use std::{
 	borrow::Cow,
 	collections::HashMap,
-	path::PathBuf,
+	path::{Path, PathBuf},
 	str::FromStr,
 	sync::{
 		atomic::{AtomicU16, Ordering},
@@ -13,18 +13,22 @@ use std::{
 use chrono::Utc;
 use futures::Stream;
 use sd_p2p::{
-	spaceblock::{BlockSize, SpaceblockRequest, Transfer},
+	compression::{CompressionStats, Frame},
+	spaceblock::{
+		use_delta, BlockSize, DeltaError, Signature, SpaceblockRequest, Transfer, ACCEPT_DELTA,
+		ACCEPT_FULL,
+	},
 	spacetime::SpaceTimeStream,
 	spacetunnel::{Identity, Tunnel},
-	Event, Manager, ManagerError, MetadataManager, PeerId,
+	Event, Keypair, Manager, ManagerError, MetadataManager, PeerId,
 };
-use sd_prisma::prisma::node;
+use sd_prisma::prisma::{file_path, node};
 use sd_sync::CRDTOperation;
 use serde::Serialize;
 use specta::Type;
 use tokio::{
-	fs::File,
-	io::{AsyncReadExt, AsyncWriteExt, BufReader},
+	fs::{self, File},
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
 	sync::{broadcast, oneshot, Mutex},
 	time::sleep,
 };
@@ -33,15 +37,32 @@ use uuid::Uuid;
 
 use crate::{
 	library::{Library, LibraryManager, SubscriberEvent},
-	node::{NodeConfig, NodeConfigManager, Platform},
+	node::{Metrics, NodeConfig, NodeConfigManager, Platform},
+	object::fs::scrub::{scrub_file, MetadataField},
 	p2p::{NodeInformation, OperatingSystem, SyncRequestError, SPACEDRIVE_APP_ID},
 	sync::SyncMessage,
 };
 
-use super::{Header, PeerMetadata};
+use super::{
+	address_book::HEALTH_CHECK_INTERVAL, compression_for_extension, create_manifest,
+	fetch_manifest, initiate_qr_pairing, is_relayed, negotiated_compression, peer_trust_level,
+	request_file, respond_to_qr_pairing, retire_node, serve_file_request,
+	serve_manifest_file_request, supports_tunnel, AddressBook, ConnectionStats, FileRequest,
+	Handover, Header, InboxArrival, Inboxes, ManifestError, ManifestFetchReport, PeerMetadata,
+	PendingQrPairings, PublishedManifests, QrPairingCode, QrPairingError, QrPairingPayload,
+	RemoteFileChunk, RemoteFileError, RetirementError, RetirementReport, ShareLinks, ShareManifest,
+	SpacedropDecision, TrustLevel, VerifiedPeers, QR_PAIRING_TIMEOUT, SUPPORTED_COMPRESSION,
+};
 
 /// The amount of time to wait for a Spacedrop request to be accepted or rejected before it's automatically rejected
 const SPACEDROP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bounds what a peer can make us allocate for a batch of sync operations once decompressed
+const MAX_SYNC_PAYLOAD_LEN: u32 = 64 * 1024 * 1024;
+
+/// What a Spacedrop goes through, a [`Tunnel`] when the sender set one up
+trait SpacedropStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SpacedropStream for T {}
 
 /// TODO: P2P event for the frontend
 #[derive(Debug, Clone, Type, Serialize)]
@@ -56,6 +77,16 @@ pub enum P2PEvent {
 		peer_id: PeerId,
 		name: String,
 	},
+	Paired {
+		peer_id: PeerId,
+		library_id: Uuid,
+		name: String,
+	},
+	InboxArrival {
+		inbox_id: Uuid,
+		name: String,
+		arrival: InboxArrival,
+	},
 	// TODO: Expire peer + connection/disconnect
 }
 
@@ -65,24 +96,44 @@ pub struct P2PManager {
 	spacedrop_pairing_reqs: Arc<Mutex<HashMap<Uuid, oneshot::Sender<Option<String>>>>>,
 	pub metadata_manager: Arc<MetadataManager<PeerMetadata>>,
 	pub spacedrop_progress: Arc<Mutex<HashMap<Uuid, broadcast::Sender<u8>>>>,
+	/// Temporary HTTPS links for Spacedropping to people who don't run Spacedrive
+	pub share_links: ShareLinks,
+	/// Manifests whose files are served to whoever holds them
+	pub published_manifests: PublishedManifests,
+	qr_pairings: PendingQrPairings,
+	/// Traffic with each peer, to show what compression saves
+	pub connection_stats: ConnectionStats,
+	/// Peers which proved their identity through a tunnel
+	pub verified_peers: VerifiedPeers,
+	/// Peers reached at an address given by the user
+	pub address_book: Arc<AddressBook>,
+	/// Directories other devices can drop files into
+	pub inboxes: Arc<Inboxes>,
 	pairing_id: AtomicU16,
+	keypair: Keypair,
 	library_manager: Arc<LibraryManager>,
+	metrics: Arc<Metrics>,
 }
 
 impl P2PManager {
 	pub async fn new(
 		node_config: Arc<NodeConfigManager>,
 		library_manager: Arc<LibraryManager>,
+		metrics: Arc<Metrics>,
 	) -> Result<Arc<Self>, ManagerError> {
-		let (config, keypair) = {
+		let (config, keypair, port) = {
 			let config = node_config.get().await;
-			(Self::config_to_metadata(&config), config.keypair)
+			(
+				Self::config_to_metadata(&config),
+				config.keypair,
+				config.p2p_port.and_then(|port| u16::try_from(port).ok()),
+			)
 		};
 
 		let metadata_manager = MetadataManager::new(config);
 
 		let (manager, mut stream) =
-			Manager::new(SPACEDRIVE_APP_ID, &keypair, metadata_manager.clone()).await?;
+			Manager::new(SPACEDRIVE_APP_ID, &keypair, port, metadata_manager.clone()).await?;
 
 		info!(
 			"Node '{}' is now online listening at addresses: {:?}",
@@ -95,12 +146,24 @@ impl P2PManager {
 
 		let spacedrop_pairing_reqs = Arc::new(Mutex::new(HashMap::new()));
 		let spacedrop_progress = Arc::new(Mutex::new(HashMap::new()));
+		let qr_pairings = PendingQrPairings::default();
+		let connection_stats = ConnectionStats::default();
+		let verified_peers = VerifiedPeers::default();
+		let published_manifests = PublishedManifests::default();
+		let inboxes = Inboxes::new(node_config.clone(), library_manager.clone(), tx.clone());
 
 		tokio::spawn({
 			let events = tx.clone();
 			let spacedrop_pairing_reqs = spacedrop_pairing_reqs.clone();
 			let spacedrop_progress = spacedrop_progress.clone();
+			let qr_pairings = qr_pairings.clone();
+			let connection_stats = connection_stats.clone();
+			let verified_peers = verified_peers.clone();
+			let published_manifests = published_manifests.clone();
+			let inboxes = inboxes.clone();
+			let keypair = keypair.clone();
 			let library_manager = library_manager.clone();
+			let metrics = metrics.clone();
 
 			async move {
 				let mut shutdown = false;
@@ -128,17 +191,28 @@ impl P2PManager {
 							let events = events.clone();
 							let spacedrop_pairing_reqs = spacedrop_pairing_reqs.clone();
 							let spacedrop_progress = spacedrop_progress.clone();
+							let qr_pairings = qr_pairings.clone();
+							let connection_stats = connection_stats.clone();
+							let verified_peers = verified_peers.clone();
+							let published_manifests = published_manifests.clone();
+							let inboxes = inboxes.clone();
+							let keypair = keypair.clone();
 							let library_manager = library_manager.clone();
+							let metrics = metrics.clone();
 
 							tokio::spawn(async move {
-								let header = Header::from_stream(&mut event.stream).await.unwrap();
+								let (header, tunneled) = Header::from_stream(&mut event.stream)
+									.await
+									.unwrap()
+									.unwrap_tunneled();
+								let (header, compressed) = header.unwrap_compressed();
 
 								match header {
 									Header::Ping => {
 										debug!("Received ping from peer '{}'", event.peer_id);
 									}
 									Header::Spacedrop(req) => {
-										let mut stream = match event.stream {
+										let stream = match event.stream {
 											SpaceTimeStream::Unicast(stream) => stream,
 											_ => {
 												// TODO: Return an error to the remote client
@@ -146,12 +220,51 @@ impl P2PManager {
 												return;
 											}
 										};
+										let mut stream: Box<dyn SpacedropStream> = if tunneled {
+											match Tunnel::responder(stream, &keypair, event.peer_id)
+												.await
+											{
+												Ok(tunnel) => {
+													verified_peers.record(event.peer_id).await;
+													Box::new(tunnel)
+												}
+												Err(e) => {
+													error!("Failed to set up a tunnel for the Spacedrop from peer '{}': {e}", event.peer_id);
+													return;
+												}
+											}
+										} else {
+											Box::new(stream)
+										};
 										let id = Uuid::new_v4();
 										let (tx, rx) = oneshot::channel();
 
 										info!("spacedrop({id}): received from peer '{}' for file '{}' with file length '{}'", event.peer_id, req.name, req.size);
 
-										spacedrop_pairing_reqs.lock().await.insert(id, tx);
+										// In receive mode, files from paired devices go straight to the inbox
+										let (prompt, inbox_drop) = match inboxes
+											.for_spacedrop(event.peer_id, &req.name, req.size)
+											.await
+										{
+											SpacedropDecision::Prompt => {
+												spacedrop_pairing_reqs.lock().await.insert(id, tx);
+												(true, None)
+											}
+											SpacedropDecision::Refuse(refusal) => {
+												info!("spacedrop({id}): refused by the inbox: {refusal}");
+												tx.send(None).ok();
+												(false, None)
+											}
+											SpacedropDecision::Accept(drop) => {
+												tx.send(Some(
+													drop.staged_path()
+														.to_string_lossy()
+														.into_owned(),
+												))
+												.ok();
+												(false, Some(drop))
+											}
+										};
 
 										let (process_tx, _) = broadcast::channel(100);
 										spacedrop_progress
@@ -159,13 +272,14 @@ impl P2PManager {
 											.await
 											.insert(id, process_tx.clone());
 
-										if events
-											.send(P2PEvent::SpacedropRequest {
-												id,
-												peer_id: event.peer_id,
-												name: req.name.clone(),
-											})
-											.is_err()
+										if prompt
+											&& events
+												.send(P2PEvent::SpacedropRequest {
+													id,
+													peer_id: event.peer_id,
+													name: req.name.clone(),
+												})
+												.is_err()
 										{
 											// No frontend's are active
 
@@ -181,15 +295,54 @@ impl P2PManager {
 													Ok(Some(file_path)) => {
 														info!("spacedrop({id}): accepted saving to '{:?}'", file_path);
 
-														stream.write_all(&[1]).await.unwrap();
-
-														let f = File::create(file_path).await.unwrap();
-
-														Transfer::new(&req, |percent| {
+														let file_path = PathBuf::from(file_path);
+														let on_progress = |percent| {
 															process_tx.send(percent).ok();
-														}).receive(&mut stream, f).await;
+														};
+
+														// A file we already have an older version of only needs what changed
+														let existing_size = fs::metadata(&file_path)
+															.await
+															.ok()
+															.filter(|metadata| metadata.is_file())
+															.map(|metadata| metadata.len())
+															.filter(|existing_size| use_delta(req.size, *existing_size));
+
+														let stats = CompressionStats::default();
+														match existing_size {
+															Some(existing_size) => {
+																if let Err(e) = receive_spacedrop_delta(&mut stream, &req, &file_path, existing_size, on_progress).await {
+																	error!("spacedrop({id}): delta transfer failed: {e}");
+																	return;
+																}
+																stats.record_raw(req.size);
+															}
+															None => {
+																stream.write_all(&[ACCEPT_FULL]).await.unwrap();
+																stream.flush().await.unwrap();
+
+																let f = File::create(file_path).await.unwrap();
+
+																let transfer = Transfer::new(&req, on_progress);
+																if compressed {
+																	transfer.with_compression(SUPPORTED_COMPRESSION, &stats).receive(&mut stream, f).await;
+																} else {
+																	transfer.receive(&mut stream, f).await;
+																	stats.record_raw(req.size);
+																}
+															}
+														}
+
+														metrics.p2p_spacedrops.inc("incoming");
+														connection_stats.record_received(event.peer_id, "spacedrop", &stats, &metrics).await;
 
 														info!("spacedrop({id}): complete");
+
+														if let Some(drop) = inbox_drop {
+															if let Err(e) = inboxes.receive(drop).await {
+																error!("spacedrop({id}): failed to receive into the inbox: {e}");
+															}
+														}
 													}
 													Ok(None) => {
 														info!("spacedrop({id}): rejected");
@@ -276,18 +429,74 @@ impl P2PManager {
 											}
 										};
 
-										let mut stream = Tunnel::from_stream(stream).await.unwrap();
+										let mut stream = match Tunnel::responder(
+											stream,
+											&keypair,
+											event.peer_id,
+										)
+										.await
+										{
+											Ok(tunnel) => tunnel,
+											Err(e) => {
+												error!("Failed to set up a tunnel for the sync messages from peer '{}': {e}", event.peer_id);
+												return;
+											}
+										};
+										verified_peers.record(event.peer_id).await;
+
+										let Some(library) = library_manager.get_library(library_id).await else {
+											warn!("error ingesting sync messages. no library by id '{library_id}' found!");
+											return;
+										};
+
+										match peer_trust_level(&library, event.peer_id).await {
+											Ok(Some(trust_level))
+												if trust_level.can_send_sync() => {}
+											Ok(_) => {
+												warn!("Ignoring sync messages for library '{library_id}' from peer '{}' as it isn't trusted to send them!", event.peer_id);
+												return;
+											}
+											Err(e) => {
+												error!("Failed to get the trust level of peer '{}': {e:?}", event.peer_id);
+												return;
+											}
+										}
 
-										let mut len = [0; 4];
-										stream
-											.read_exact(&mut len)
+										let stats = CompressionStats::default();
+										let buf = if compressed {
+											let frame = Frame::from_stream(
+												&mut stream,
+												MAX_SYNC_PAYLOAD_LEN,
+											)
 											.await
-											.map_err(SyncRequestError::PayloadLenIoError)
 											.unwrap();
-										let len = u32::from_le_bytes(len);
+											stats.record(&frame);
+
+											frame.decode().unwrap()
+										} else {
+											let mut len = [0; 4];
+											stream
+												.read_exact(&mut len)
+												.await
+												.map_err(SyncRequestError::PayloadLenIoError)
+												.unwrap();
+											let len = u32::from_le_bytes(len);
+
+											let mut buf = vec![0; len as usize]; // TODO: Designed for easily being able to be DOS the current Node
+											stream.read_exact(&mut buf).await.unwrap();
+											stats.record_raw(4 + len as u64);
+
+											buf
+										};
 
-										let mut buf = vec![0; len as usize]; // TODO: Designed for easily being able to be DOS the current Node
-										stream.read_exact(&mut buf).await.unwrap();
+										connection_stats
+											.record_received(
+												event.peer_id,
+												"sync",
+												&stats,
+												&metrics,
+											)
+											.await;
 
 										let mut buf: &[u8] = &buf;
 										let operations: Vec<CRDTOperation> =
@@ -295,11 +504,6 @@ impl P2PManager {
 
 										debug!("ingesting sync events for library '{library_id}': {operations:?}");
 
-										let Some(library) = library_manager.get_library(library_id).await else {
-											warn!("error ingesting sync messages. no library by id '{library_id}' found!");
-											return;
-										};
-
 										for op in operations {
 											library.sync.ingest_op(op).await.unwrap_or_else(
 												|err| {
@@ -311,6 +515,115 @@ impl P2PManager {
 											);
 										}
 									}
+									Header::QrPair(library_id) => {
+										let mut stream = match event.stream {
+											SpaceTimeStream::Unicast(stream) => stream,
+											_ => {
+												// TODO: Return an error to the remote client
+												error!("Received QR code pairing request from peer '{}' but it's not a unicast stream!", event.peer_id);
+												return;
+											}
+										};
+
+										match respond_to_qr_pairing(
+											&mut stream,
+											event.peer_id,
+											library_id,
+											&library_manager,
+											&qr_pairings,
+										)
+										.await
+										{
+											Ok(name) => {
+												events
+													.send(P2PEvent::Paired {
+														peer_id: event.peer_id,
+														library_id,
+														name,
+													})
+													.map_err(|_| {
+														error!("Failed to send event to p2p event stream!")
+													})
+													.ok();
+											}
+											Err(e) => warn!(
+												"QR code pairing with '{}' failed: {e}",
+												event.peer_id
+											),
+										}
+									}
+									Header::Compressed(_) | Header::Tunneled(_) => {
+										unreachable!(
+											"`Header::unwrap_tunneled` and `Header::unwrap_compressed` remove the wrappers"
+										)
+									}
+									Header::File(request) => {
+										let mut stream = match event.stream {
+											SpaceTimeStream::Unicast(stream) => stream,
+											_ => {
+												// TODO: Return an error to the remote client
+												error!("Received file request from peer '{}' but it's not a unicast stream!", event.peer_id);
+												return;
+											}
+										};
+
+										let stats = CompressionStats::default();
+										if let Err(e) = serve_file_request(
+											&mut stream,
+											event.peer_id,
+											request,
+											compressed,
+											&library_manager,
+											&stats,
+										)
+										.await
+										{
+											warn!(
+												"Failed to stream file to peer '{}': {e}",
+												event.peer_id
+											);
+										}
+
+										connection_stats
+											.record_sent(event.peer_id, "file", &stats, &metrics)
+											.await;
+									}
+									Header::ManifestFile(request) => {
+										let mut stream = match event.stream {
+											SpaceTimeStream::Unicast(stream) => stream,
+											_ => {
+												// TODO: Return an error to the remote client
+												error!("Received manifest file request from peer '{}' but it's not a unicast stream!", event.peer_id);
+												return;
+											}
+										};
+
+										let stats = CompressionStats::default();
+										if let Err(e) = serve_manifest_file_request(
+											&mut stream,
+											request,
+											compressed,
+											&published_manifests,
+											&library_manager,
+											&stats,
+										)
+										.await
+										{
+											warn!(
+												"Failed to stream manifest file to peer '{}': {e}",
+												event.peer_id
+											);
+										}
+
+										connection_stats
+											.record_sent(
+												event.peer_id,
+												"manifest",
+												&stats,
+												&metrics,
+											)
+											.await;
+									}
 								}
 							});
 						}
@@ -334,14 +647,25 @@ impl P2PManager {
 		// https://docs.rs/ctrlc/latest/ctrlc/
 		// https://docs.rs/system_shutdown/latest/system_shutdown/
 
+		let address_book = AddressBook::new(node_config, manager.clone());
+
 		let this = Arc::new(Self {
 			events: (tx, rx),
 			manager,
 			spacedrop_pairing_reqs,
 			metadata_manager,
 			spacedrop_progress,
+			share_links: ShareLinks::default(),
+			published_manifests,
+			qr_pairings,
+			connection_stats,
+			verified_peers,
+			address_book,
+			inboxes,
 			pairing_id: AtomicU16::new(0),
+			keypair,
 			library_manager: library_manager.clone(),
+			metrics,
 		});
 
 		library_manager
@@ -363,6 +687,16 @@ impl P2PManager {
 			})
 			.await;
 
+		tokio::spawn({
+			let address_book = this.address_book.clone();
+			async move {
+				loop {
+					address_book.check_all().await;
+					sleep(HEALTH_CHECK_INTERVAL).await;
+				}
+			}
+		});
+
 		// TODO: Probs remove this once connection timeout/keepalive are working correctly
 		tokio::spawn({
 			let this = this.clone();
@@ -384,10 +718,15 @@ impl P2PManager {
 			version: Some(env!("CARGO_PKG_VERSION").to_string()),
 			email: config.p2p_email.clone(),
 			img_url: config.p2p_img_url.clone(),
+			compression: true,
+			tunnel: true,
+			inbox: config
+				.p2p_receive_inbox
+				.and_then(|id| config.p2p_inboxes.iter().find(|inbox| inbox.id == id))
+				.map(|inbox| inbox.name.clone()),
 		}
 	}
 
-	#[allow(unused)] // TODO: Should probs be using this
 	pub async fn update_metadata(&self, node_config_manager: &NodeConfigManager) {
 		self.metadata_manager
 			.update(Self::config_to_metadata(&node_config_manager.get().await));
@@ -466,22 +805,141 @@ impl P2PManager {
 		pairing_id
 	}
 
+	/// Starts showing a QR code which pairs the node scanning it with this one for the library
+	pub async fn start_qr_pairing(
+		&self,
+		library_id: Uuid,
+		trust_level: TrustLevel,
+	) -> QrPairingCode {
+		let payload = self
+			.qr_pairings
+			.start(self.manager.peer_id(), library_id, trust_level)
+			.await;
+
+		QrPairingCode {
+			code: payload.to_string(),
+			expires_in_secs: QR_PAIRING_TIMEOUT.as_secs(),
+		}
+	}
+
+	pub async fn cancel_qr_pairing(&self, library_id: Uuid) {
+		self.qr_pairings.cancel(library_id).await;
+	}
+
+	/// Pairs with the node showing the scanned QR code, returning its name
+	pub async fn accept_qr_pairing(&self, code: &str) -> Result<String, QrPairingError> {
+		let payload = code.parse::<QrPairingPayload>()?;
+		let (peer_id, library_id) = (payload.peer_id, payload.library_id);
+
+		let name = initiate_qr_pairing(&self.manager, &self.library_manager, payload).await?;
+
+		self.events
+			.0
+			.send(P2PEvent::Paired {
+				peer_id,
+				library_id,
+				name: name.clone(),
+			})
+			.map_err(|_| error!("Failed to send event to p2p event stream!"))
+			.ok();
+
+		Ok(name)
+	}
+
+	/// Removes a device from the library for good, handing its locations over
+	pub async fn retire_node(
+		&self,
+		library: &Library,
+		node_id: i32,
+		handover: Handover,
+	) -> Result<RetirementReport, RetirementError> {
+		retire_node(
+			&self.manager,
+			&self.address_book,
+			&self.library_manager,
+			library,
+			node_id,
+			handover,
+		)
+		.await
+	}
+
+	/// Streams the content of a file from the device owning its location
+	pub async fn request_file(
+		&self,
+		peer_id: PeerId,
+		request: FileRequest,
+	) -> Result<RemoteFileChunk, RemoteFileError> {
+		let stats = CompressionStats::default();
+		let chunk = request_file(&self.manager, peer_id, request, &stats).await?;
+
+		self.connection_stats
+			.record_received(peer_id, "file", &stats, &self.metrics)
+			.await;
+
+		Ok(chunk)
+	}
+
+	/// Describes the files and directories in a manifest signed by this node, and serves them
+	pub async fn create_manifest(
+		&self,
+		library: &Library,
+		name: String,
+		file_path_ids: Vec<file_path::id::Type>,
+	) -> Result<ShareManifest, ManifestError> {
+		let manifest = create_manifest(library, &self.keypair, name, file_path_ids).await?;
+		self.published_manifests.publish(&manifest).await;
+
+		Ok(manifest)
+	}
+
+	/// Serves the files of a manifest this node created before, like after a restart
+	pub async fn publish_manifest(&self, manifest: &ShareManifest) -> Result<(), ManifestError> {
+		if manifest.verify()? != self.manager.peer_id() {
+			return Err(ManifestError::NotPublisher);
+		}
+
+		self.published_manifests.publish(manifest).await;
+
+		Ok(())
+	}
+
+	/// Fetches the files of a manifest from its publisher
+	pub async fn fetch_manifest(
+		&self,
+		manifest: &ShareManifest,
+		destination: &Path,
+	) -> Result<ManifestFetchReport, ManifestError> {
+		let stats = CompressionStats::default();
+		let result = fetch_manifest(&self.manager, manifest, destination, &stats).await;
+
+		if let Ok(publisher) = manifest.verify() {
+			self.connection_stats
+				.record_received(publisher, "manifest", &stats, &self.metrics)
+				.await;
+		}
+
+		result
+	}
+
 	pub async fn broadcast_sync_events(
 		&self,
 		library_id: Uuid,
 		_identity: &Identity,
 		event: Vec<CRDTOperation>,
 	) {
-		let mut buf = match rmp_serde::to_vec_named(&event) {
+		let buf = match rmp_serde::to_vec_named(&event) {
 			Ok(buf) => buf,
 			Err(e) => {
 				error!("Failed to serialize sync event: {:?}", e);
 				return;
 			}
 		};
-		let mut head_buf = Header::Sync(library_id).to_bytes(); // Max Sync payload is like 4GB
-		head_buf.extend_from_slice(&(buf.len() as u32).to_le_bytes());
-		head_buf.append(&mut buf);
+		let mut payload = (buf.len() as u32).to_le_bytes().to_vec(); // Max Sync payload is like 4GB
+		payload.extend_from_slice(&buf);
+
+		// For peers which can decode it, compressed once as operations are sent to all of them
+		let mut compressed_frame = None;
 
 		// TODO: Determine which clients we share that library with
 
@@ -497,6 +955,7 @@ impl P2PManager {
 			.await
 			.unwrap()
 			.into_iter()
+			.filter(|n| TrustLevel::from_db(n.trust_level).can_receive_sync())
 			.map(|n| {
 				PeerId::from_str(&n.node_peer_id.expect("Node was missing 'node_peer_id'!"))
 					.unwrap()
@@ -510,11 +969,48 @@ impl P2PManager {
 
 		// TODO: Do in parallel
 		for peer_id in target_nodes {
-			let stream = self.manager.stream(peer_id).await.map_err(|_| ()).unwrap(); // TODO: handle providing incorrect peer id
+			let mut stream = self.manager.stream(peer_id).await.map_err(|_| ()).unwrap(); // TODO: handle providing incorrect peer id
 
-			let mut tunnel = Tunnel::from_stream(stream).await.unwrap();
+			let compression = negotiated_compression(&self.manager, peer_id).await;
+			let header = match compression {
+				Some(_) => Header::Compressed(Box::new(Header::Sync(library_id))),
+				None => Header::Sync(library_id),
+			};
+			stream.write_all(&header.to_bytes()).await.unwrap();
+
+			// Operations only ever leave the node encrypted for the peer, whatever relays the connection
+			let mut tunnel =
+				match Tunnel::initiator(stream, &self.keypair, peer_id).await {
+					Ok(tunnel) => tunnel,
+					Err(e) => {
+						error!("Failed to set up a tunnel to send sync messages to peer '{peer_id}': {e}");
+						continue;
+					}
+				};
+			self.verified_peers.record(peer_id).await;
+
+			let stats = CompressionStats::default();
+			match compression {
+				Some(compression) => {
+					let (bytes, frame) = compressed_frame.get_or_insert_with(|| {
+						let frame = Frame::encode(&buf, compression).unwrap();
+						(frame.to_bytes(), frame)
+					});
+
+					tunnel.write_all(bytes).await.unwrap();
+					stats.record(frame);
+				}
+				None => {
+					tunnel.write_all(&payload).await.unwrap();
+					stats.record_raw(payload.len() as u64);
+				}
+			}
+			// Ends the tunnel with its final frame, so the peer knows nothing was cut off
+			tunnel.shutdown().await.unwrap();
 
-			tunnel.write_all(&head_buf).await.unwrap();
+			self.connection_stats
+				.record_sent(peer_id, "sync", &stats, &self.metrics)
+				.await;
 		}
 	}
 
@@ -522,20 +1018,71 @@ impl P2PManager {
 		self.manager.broadcast(Header::Ping.to_bytes()).await;
 	}
 
-	// TODO: Proper error handling
+	/// Spacedrops a file, or a copy of it with the metadata asked for scrubbed out of it
 	pub async fn big_bad_spacedrop(
 		&self,
 		peer_id: PeerId,
 		path: PathBuf,
+		scrub_metadata: &[MetadataField],
 	) -> Result<Option<Uuid>, ()> {
+		if scrub_metadata.is_empty() {
+			return self.send_spacedrop(peer_id, path).await;
+		}
+
+		// The copy keeps the name of the file, which is what the peer is asked to accept
+		let directory = std::env::temp_dir().join(format!("spacedrop-{}", Uuid::new_v4()));
+		let copy = directory.join(path.file_name().ok_or(())?);
+
+		let result = async {
+			fs::create_dir_all(&directory)
+				.await
+				.map_err(|e| error!("Failed to create '{}': {e}", directory.display()))?;
+			fs::copy(&path, &copy)
+				.await
+				.map_err(|e| error!("Failed to copy '{}' to scrub it: {e}", path.display()))?;
+
+			match scrub_file(&copy, scrub_metadata).await {
+				Ok(removed) => debug!(
+					"Scrubbed {removed:?} out of '{}' before Spacedropping it",
+					path.display()
+				),
+				Err(e) => {
+					error!(
+						"Refusing to Spacedrop '{}' as its metadata couldn't be scrubbed: {e}",
+						path.display()
+					);
+					return Err(());
+				}
+			}
+
+			self.send_spacedrop(peer_id, copy.clone()).await
+		}
+		.await;
+
+		if let Err(e) = fs::remove_dir_all(&directory).await {
+			warn!("Failed to remove '{}': {e}", directory.display());
+		}
+
+		result
+	}
+
+	// TODO: Proper error handling
+	async fn send_spacedrop(&self, peer_id: PeerId, path: PathBuf) -> Result<Option<Uuid>, ()> {
 		let id = Uuid::new_v4();
 		let (tx, _) = broadcast::channel(25);
+		let compression = negotiated_compression(&self.manager, peer_id).await;
+		let tunneled = supports_tunnel(&self.manager, peer_id).await;
 		let mut stream = self.manager.stream(peer_id).await.map_err(|_| ())?; // TODO: handle providing incorrect peer id
 
+		if !tunneled && is_relayed(&self.manager, peer_id).await {
+			error!("Refusing to Spacedrop to peer '{peer_id}' through a relay as it can't set up an end-to-end encrypted tunnel");
+			return Err(());
+		}
+
 		let file = File::open(&path).await.map_err(|_| ())?;
 		let metadata = file.metadata().await.map_err(|_| ())?;
 
-		let header = Header::Spacedrop(SpaceblockRequest {
+		let req = SpaceblockRequest {
 			name: path
 				.file_name()
 				.map(|v| v.to_string_lossy())
@@ -543,34 +1090,94 @@ impl P2PManager {
 				.to_string(),
 			size: metadata.len(),
 			block_size: BlockSize::from_size(metadata.len()), // TODO: This should be dynamic
-		});
+		};
+		let header = match compression {
+			Some(_) => Header::Compressed(Box::new(Header::Spacedrop(req.clone()))),
+			None => Header::Spacedrop(req.clone()),
+		};
+		let header = if tunneled {
+			Header::Tunneled(Box::new(header))
+		} else {
+			header
+		};
 		stream.write_all(&header.to_bytes()).await.map_err(|_| ())?;
 
+		// The name and size of the file are in the header, its content only goes through the tunnel
+		let mut stream: Box<dyn SpacedropStream> = if tunneled {
+			let tunnel = Tunnel::initiator(stream, &self.keypair, peer_id)
+				.await
+				.map_err(|e| {
+					error!("Failed to set up a tunnel for the Spacedrop to peer '{peer_id}': {e}")
+				})?;
+			self.verified_peers.record(peer_id).await;
+
+			Box::new(tunnel)
+		} else {
+			Box::new(stream)
+		};
+
 		debug!("Waiting for Spacedrop to be accepted from peer '{peer_id}'");
 		let mut buf = [0; 1];
 		// TODO: Add timeout so the connection is dropped if they never response
 		stream.read_exact(&mut buf).await.map_err(|_| ())?;
-		if buf[0] != 1 {
-			debug!("Spacedrop was rejected from peer '{peer_id}'");
-			return Ok(None);
-		}
+		let signature =
+			match buf[0] {
+				ACCEPT_FULL => None,
+				ACCEPT_DELTA => Some(Signature::from_stream(&mut stream).await.map_err(|e| {
+					error!("Invalid Spacedrop signature from peer '{peer_id}': {e}")
+				})?),
+				_ => {
+					debug!("Spacedrop was rejected from peer '{peer_id}'");
+					return Ok(None);
+				}
+			};
 
 		debug!("Starting Spacedrop to peer '{peer_id}'");
 		let i = Instant::now();
 
 		let file = BufReader::new(file);
 		self.spacedrop_progress.lock().await.insert(id, tx.clone());
-		Transfer::new(
-			&match header {
-				Header::Spacedrop(req) => req,
-				_ => unreachable!(),
-			},
-			|percent| {
-				tx.send(percent).ok();
+		let stats = CompressionStats::default();
+		let transfer = Transfer::new(&req, |percent| {
+			tx.send(percent).ok();
+		});
+
+		match signature {
+			Some(signature) => {
+				debug!("Peer '{peer_id}' has an older version, sending a delta");
+
+				transfer
+					.send_delta(&mut stream, file, &signature)
+					.await
+					.map_err(|e| {
+						error!("Failed to send Spacedrop delta to peer '{peer_id}': {e}")
+					})?;
+				stats.record_raw(req.size);
+			}
+			None => match compression {
+				Some(compression) => {
+					let compression = compression_for_extension(
+						compression,
+						path.extension().and_then(|extension| extension.to_str()),
+					);
+
+					transfer
+						.with_compression(compression, &stats)
+						.send(&mut stream, file)
+						.await;
+				}
+				None => {
+					transfer.send(&mut stream, file).await;
+					stats.record_raw(req.size);
+				}
 			},
-		)
-		.send(&mut stream, file)
-		.await;
+		}
+		stream.shutdown().await.map_err(|_| ())?;
+
+		self.metrics.p2p_spacedrops.inc("outgoing");
+		self.connection_stats
+			.record_sent(peer_id, "spacedrop", &stats, &self.metrics)
+			.await;
 
 		debug!(
 			"Finished Spacedrop to peer '{peer_id}' after '{:?}",
@@ -595,3 +1202,41 @@ impl P2PManager {
 		self.manager.shutdown().await;
 	}
 }
+
+/// Rebuilds a Spacedropped file from the version already at `path`, which is replaced once the
+/// new one was received whole
+async fn receive_spacedrop_delta(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	req: &SpaceblockRequest,
+	path: &Path,
+	existing_size: u64,
+	on_progress: impl Fn(u8),
+) -> Result<(), DeltaError> {
+	let mut old = File::open(path).await?;
+	let signature = Signature::compute(&mut BufReader::new(&mut old), existing_size).await?;
+
+	stream.write_all(&[ACCEPT_DELTA]).await?;
+	stream.write_all(&signature.to_bytes()).await?;
+	stream.flush().await?;
+
+	let mut part_path = path.as_os_str().to_owned();
+	part_path.push(".part");
+	let part_path = PathBuf::from(part_path);
+
+	let result = Transfer::new(req, on_progress)
+		.receive_delta(
+			stream,
+			&mut old,
+			BufWriter::new(File::create(&part_path).await?),
+			&signature,
+		)
+		.await;
+
+	match result {
+		Ok(()) => fs::rename(&part_path, path).await.map_err(Into::into),
+		Err(e) => {
+			fs::remove_file(&part_path).await.ok();
+			Err(e)
+		}
+	}
+}
@@ -0,0 +1,430 @@
+//! Pairing by scanning a QR code, so phones can be paired without typing anything.
+//!
+//! The node showing the QR code registers a one-time secret for a few minutes and puts it in the
+//! payload, next to its peer id and the library to pair. The scanning node dials that peer and both
+//! sides prove they know the secret, bound to both library identities, before saving the other one.
+
+use std::{
+	collections::HashMap,
+	fmt,
+	str::FromStr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use prisma_client_rust::QueryError;
+use sd_crypto::types::Key;
+use sd_p2p::{spacetunnel::RemoteIdentity, Manager, PeerId};
+use sd_prisma::prisma::node;
+use serde::Serialize;
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	sync::Mutex,
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::{
+	library::{Library, LibraryManager},
+	node::Platform,
+};
+
+use super::{Header, NodeInformation, NodeInformationError, PeerMetadata, TrustLevel};
+
+/// How long a QR code can be scanned for
+pub const QR_PAIRING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const PAYLOAD_PREFIX: &str = "spacedrive-pair:1:";
+const ACK: u8 = 1;
+
+type Secret = [u8; 32];
+
+#[derive(Error, Debug)]
+pub enum QrPairingError {
+	#[error("invalid pairing code")]
+	InvalidPayload,
+	#[error("pairing code expired or was already used")]
+	Expired,
+	#[error("library not found: <id='{0}'>")]
+	LibraryNotFound(Uuid),
+	#[error("the other device failed to authenticate")]
+	AuthenticationFailed,
+	#[error("couldn't connect to peer '{0}'")]
+	Unreachable(PeerId),
+	#[error("io error during pairing: {0}")]
+	Io(#[from] std::io::Error),
+	#[error(transparent)]
+	NodeInformation(#[from] NodeInformationError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+impl From<QrPairingError> for rspc::Error {
+	fn from(error: QrPairingError) -> Self {
+		let code = match error {
+			QrPairingError::InvalidPayload | QrPairingError::Expired => rspc::ErrorCode::BadRequest,
+			QrPairingError::LibraryNotFound(_) => rspc::ErrorCode::NotFound,
+			QrPairingError::AuthenticationFailed => rspc::ErrorCode::Unauthorized,
+			_ => rspc::ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, error.to_string(), error)
+	}
+}
+
+/// What's encoded in the QR code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrPairingPayload {
+	pub peer_id: PeerId,
+	pub library_id: Uuid,
+	secret: Secret,
+}
+
+impl fmt::Display for QrPairingPayload {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{PAYLOAD_PREFIX}{}:{}:{}",
+			self.peer_id,
+			self.library_id.simple(),
+			hex::encode(self.secret)
+		)
+	}
+}
+
+impl FromStr for QrPairingPayload {
+	type Err = QrPairingError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts = s
+			.strip_prefix(PAYLOAD_PREFIX)
+			.ok_or(QrPairingError::InvalidPayload)?
+			.split(':');
+
+		let (Some(peer_id), Some(library_id), Some(secret), None) =
+			(parts.next(), parts.next(), parts.next(), parts.next())
+		else {
+			return Err(QrPairingError::InvalidPayload);
+		};
+
+		let mut decoded_secret = Secret::default();
+		hex::decode_to_slice(secret, &mut decoded_secret)
+			.map_err(|_| QrPairingError::InvalidPayload)?;
+
+		Ok(Self {
+			peer_id: PeerId::from_str(peer_id).map_err(|_| QrPairingError::InvalidPayload)?,
+			library_id: Uuid::parse_str(library_id).map_err(|_| QrPairingError::InvalidPayload)?,
+			secret: decoded_secret,
+		})
+	}
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct QrPairingCode {
+	/// The text to render as a QR code
+	pub code: String,
+	pub expires_in_secs: u64,
+}
+
+#[derive(Debug)]
+struct PendingQrPairing {
+	secret: Secret,
+	/// Given to the node scanning the QR code
+	trust_level: TrustLevel,
+	expires_at: Instant,
+}
+
+/// The secrets of the QR codes currently shown by this node, at most one per library
+#[derive(Debug, Default, Clone)]
+pub struct PendingQrPairings(Arc<Mutex<HashMap<Uuid, PendingQrPairing>>>);
+
+impl PendingQrPairings {
+	pub async fn start(
+		&self,
+		peer_id: PeerId,
+		library_id: Uuid,
+		trust_level: TrustLevel,
+	) -> QrPairingPayload {
+		let secret = *Key::generate().expose();
+
+		// A new QR code replaces the previous one for the same library
+		self.0.lock().await.insert(
+			library_id,
+			PendingQrPairing {
+				secret,
+				trust_level,
+				expires_at: Instant::now() + QR_PAIRING_TIMEOUT,
+			},
+		);
+
+		QrPairingPayload {
+			peer_id,
+			library_id,
+			secret,
+		}
+	}
+
+	pub async fn cancel(&self, library_id: Uuid) {
+		self.0.lock().await.remove(&library_id);
+	}
+
+	async fn get(&self, library_id: Uuid) -> Option<(Secret, TrustLevel)> {
+		let mut pending = self.0.lock().await;
+		pending.retain(|_, pairing| pairing.expires_at > Instant::now());
+		pending
+			.get(&library_id)
+			.map(|pairing| (pairing.secret, pairing.trust_level))
+	}
+
+	/// Removes the secret once it was used, unless it was replaced in the meantime
+	async fn consume(&self, library_id: Uuid, secret: &Secret) {
+		let mut pending = self.0.lock().await;
+		if matches!(pending.get(&library_id), Some(pairing) if &pairing.secret == secret) {
+			pending.remove(&library_id);
+		}
+	}
+}
+
+/// Binds the secret to who is proving it and to both identities, so proofs can't be replayed by
+/// the other side or relayed to a different node
+fn proof(
+	secret: &Secret,
+	role: &[u8],
+	own_identity: &RemoteIdentity,
+	remote_identity: &RemoteIdentity,
+) -> blake3::Hash {
+	let mut hasher = blake3::Hasher::new_keyed(secret);
+	hasher.update(role);
+	hasher.update(&own_identity.to_bytes());
+	hasher.update(&remote_identity.to_bytes());
+	hasher.finalize()
+}
+
+async fn read_proof(stream: &mut (impl AsyncRead + Unpin)) -> Result<blake3::Hash, QrPairingError> {
+	let mut buf = [0u8; blake3::OUT_LEN];
+	stream.read_exact(&mut buf).await?;
+	Ok(blake3::Hash::from(buf))
+}
+
+fn local_information(library: &Library) -> NodeInformation {
+	NodeInformation {
+		pub_id: library.config.node_id,
+		name: library.config.name.clone(),
+		public_key: library.identity.to_remote_identity(),
+		platform: Platform::current(),
+	}
+}
+
+async fn save_paired_node(
+	library: &Library,
+	remote_info: NodeInformation,
+	peer_id: PeerId,
+	trust_level: Option<TrustLevel>,
+) -> Result<(), QueryError> {
+	let mut params = vec![
+		node::identity::set(Some(remote_info.public_key.to_bytes().to_vec())),
+		node::node_peer_id::set(Some(peer_id.to_string())),
+	];
+	if let Some(trust_level) = trust_level {
+		params.push(node::trust_level::set(Some(trust_level as i32)));
+	}
+
+	// Pairing again with a known node just refreshes it, keeping its trust level unless given
+	library
+		.db
+		.node()
+		.upsert(
+			node::pub_id::equals(remote_info.pub_id.as_bytes().to_vec()),
+			node::create(
+				remote_info.pub_id.as_bytes().to_vec(),
+				remote_info.name.clone(),
+				remote_info.platform as i32,
+				Utc::now().into(),
+				params.clone(),
+			),
+			[
+				params,
+				vec![
+					node::name::set(remote_info.name),
+					node::platform::set(remote_info.platform as i32),
+				],
+			]
+			.concat(),
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+/// Run by the node which scanned the QR code
+pub(super) async fn initiate_qr_pairing(
+	manager: &Manager<PeerMetadata>,
+	library_manager: &LibraryManager,
+	payload: QrPairingPayload,
+) -> Result<String, QrPairingError> {
+	let QrPairingPayload {
+		peer_id,
+		library_id,
+		secret,
+	} = payload;
+
+	let library = library_manager
+		.get_library(library_id)
+		.await
+		.ok_or(QrPairingError::LibraryNotFound(library_id))?;
+
+	info!("Starting QR code pairing with '{peer_id}' for library '{library_id}'");
+
+	let mut stream = manager
+		.stream(peer_id)
+		.await
+		.map_err(|()| QrPairingError::Unreachable(peer_id))?;
+
+	stream
+		.write_all(&Header::QrPair(library_id).to_bytes())
+		.await?;
+
+	let info = local_information(&library);
+	stream.write_all(&info.to_bytes()).await?;
+
+	let remote_info = NodeInformation::from_stream(&mut stream).await?;
+	debug!("Received nodeinfo from the remote node: {remote_info:?}");
+
+	stream
+		.write_all(
+			proof(
+				&secret,
+				b"initiator",
+				&info.public_key,
+				&remote_info.public_key,
+			)
+			.as_bytes(),
+		)
+		.await?;
+
+	// `blake3::Hash` equality is constant time
+	if read_proof(&mut stream).await?
+		!= proof(
+			&secret,
+			b"responder",
+			&remote_info.public_key,
+			&info.public_key,
+		) {
+		return Err(QrPairingError::AuthenticationFailed);
+	}
+
+	stream.write_all(&[ACK]).await?;
+
+	let name = remote_info.name.clone();
+	save_paired_node(&library, remote_info, peer_id, None).await?;
+
+	info!("Paired with '{name}' for library '{library_id}' using a QR code");
+
+	Ok(name)
+}
+
+/// Run by the node showing the QR code, when the scanning node connects to it
+pub(super) async fn respond_to_qr_pairing(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	peer_id: PeerId,
+	library_id: Uuid,
+	library_manager: &LibraryManager,
+	pending: &PendingQrPairings,
+) -> Result<String, QrPairingError> {
+	let (secret, trust_level) = pending
+		.get(library_id)
+		.await
+		.ok_or(QrPairingError::Expired)?;
+
+	let library = library_manager
+		.get_library(library_id)
+		.await
+		.ok_or(QrPairingError::LibraryNotFound(library_id))?;
+
+	let remote_info = NodeInformation::from_stream(stream).await?;
+	debug!("Received nodeinfo from the remote node: {remote_info:?}");
+
+	let info = local_information(&library);
+	stream.write_all(&info.to_bytes()).await?;
+
+	if read_proof(stream).await?
+		!= proof(
+			&secret,
+			b"initiator",
+			&remote_info.public_key,
+			&info.public_key,
+		) {
+		return Err(QrPairingError::AuthenticationFailed);
+	}
+
+	pending.consume(library_id, &secret).await;
+
+	stream
+		.write_all(
+			proof(
+				&secret,
+				b"responder",
+				&info.public_key,
+				&remote_info.public_key,
+			)
+			.as_bytes(),
+		)
+		.await?;
+
+	// Only save the other node once it also authenticated us
+	if stream.read_u8().await? != ACK {
+		return Err(QrPairingError::AuthenticationFailed);
+	}
+
+	let name = remote_info.name.clone();
+	save_paired_node(&library, remote_info, peer_id, Some(trust_level)).await?;
+
+	info!("Paired with '{name}' for library '{library_id}' using a QR code, trusting it with {trust_level:?}");
+
+	Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sd_p2p::{spacetunnel::Identity, Keypair};
+
+	#[test]
+	fn payload_round_trip() {
+		let payload = QrPairingPayload {
+			peer_id: Keypair::generate().peer_id(),
+			library_id: Uuid::new_v4(),
+			secret: *Key::generate().expose(),
+		};
+
+		assert_eq!(
+			QrPairingPayload::from_str(&payload.to_string()).unwrap(),
+			payload
+		);
+		assert!(QrPairingPayload::from_str("spacedrive-pair:1:nope").is_err());
+	}
+
+	#[test]
+	fn proofs_are_bound_to_roles_and_identities() {
+		let secret = *Key::generate().expose();
+		let a = Identity::new().to_remote_identity();
+		let b = Identity::new().to_remote_identity();
+
+		assert_eq!(
+			proof(&secret, b"initiator", &a, &b),
+			proof(&secret, b"initiator", &a, &b)
+		);
+		assert_ne!(
+			proof(&secret, b"initiator", &a, &b),
+			proof(&secret, b"responder", &a, &b)
+		);
+		assert_ne!(
+			proof(&secret, b"initiator", &a, &b),
+			proof(&secret, b"initiator", &b, &a)
+		);
+	}
+}
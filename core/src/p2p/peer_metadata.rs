@@ -11,6 +11,12 @@ pub struct PeerMetadata {
 	pub(super) version: Option<String>,
 	pub(super) email: Option<String>,
 	pub(super) img_url: Option<String>,
+	/// Whether the peer can decode compressed frames, see `super::compression`
+	pub(super) compression: bool,
+	/// Whether the peer can set up end-to-end encrypted tunnels, see `super::security`
+	pub(super) tunnel: bool,
+	/// The inbox Spacedrops from paired devices land in without being accepted, see `super::inbox`
+	pub(super) inbox: Option<String>,
 }
 
 impl Metadata for PeerMetadata {
@@ -29,6 +35,15 @@ impl Metadata for PeerMetadata {
 		if let Some(img_url) = self.img_url {
 			map.insert("img_url".to_owned(), img_url);
 		}
+		if self.compression {
+			map.insert("compression".to_owned(), "zstd".to_owned());
+		}
+		if self.tunnel {
+			map.insert("tunnel".to_owned(), "v1".to_owned());
+		}
+		if let Some(inbox) = self.inbox {
+			map.insert("inbox".to_owned(), inbox);
+		}
 		map
 	}
 
@@ -51,6 +66,11 @@ impl Metadata for PeerMetadata {
 			version: data.get("version").map(|v| v.to_owned()),
 			email: data.get("email").map(|v| v.to_owned()),
 			img_url: data.get("img_url").map(|v| v.to_owned()),
+			compression: data
+				.get("compression")
+				.map_or(false, |v| v.split(',').any(|c| c == "zstd")),
+			tunnel: data.get("tunnel").map_or(false, |v| v == "v1"),
+			inbox: data.get("inbox").map(|v| v.to_owned()),
 		})
 	}
 }
@@ -19,6 +19,118 @@ pub enum Header {
 	Spacedrop(SpaceblockRequest),
 	Pair(Uuid),
 	Sync(Uuid),
+	QrPair(Uuid),
+	File(FileRequest),
+	ManifestFile(ManifestFileRequest),
+	/// What's sent after the wrapped header is in compressed frames, as the receiver advertised
+	/// it can decode them
+	Compressed(Box<Header>),
+	/// What's sent after the wrapped header goes through a [`Tunnel`](sd_p2p::spacetunnel::Tunnel),
+	/// which may wrap a [`Header::Compressed`] in turn
+	Tunneled(Box<Header>),
+}
+
+/// Asks the device owning a location for the content of one of its files
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileRequest {
+	pub library_id: Uuid,
+	pub file_path_pub_id: Uuid,
+	/// The start and length of the requested bytes, the whole file if `None`
+	pub range: Option<(u64, u64)>,
+}
+
+impl FileRequest {
+	pub async fn from_stream(
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, FileRequestError> {
+		let mut library_id = [0u8; 16];
+		stream.read_exact(&mut library_id).await?;
+
+		let mut file_path_pub_id = [0u8; 16];
+		stream.read_exact(&mut file_path_pub_id).await?;
+
+		let range = match stream.read_u8().await? {
+			0 => None,
+			_ => Some((stream.read_u64_le().await?, stream.read_u64_le().await?)),
+		};
+
+		Ok(Self {
+			library_id: Uuid::from_bytes(library_id),
+			file_path_pub_id: Uuid::from_bytes(file_path_pub_id),
+			range,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(16 + 16 + 1 + 16);
+		buf.extend_from_slice(self.library_id.as_bytes());
+		buf.extend_from_slice(self.file_path_pub_id.as_bytes());
+
+		match self.range {
+			Some((start, length)) => {
+				buf.push(1);
+				buf.extend_from_slice(&start.to_le_bytes());
+				buf.extend_from_slice(&length.to_le_bytes());
+			}
+			None => buf.push(0),
+		}
+
+		buf
+	}
+}
+
+#[derive(Debug, Error)]
+#[error("io error reading file request: {0}")]
+pub struct FileRequestError(#[from] std::io::Error);
+
+/// Asks the publisher of a [`ShareManifest`](super::ShareManifest) for the content of one of its
+/// entries, by the BLAKE3 hash of the content
+#[derive(Debug, PartialEq, Eq)]
+pub struct ManifestFileRequest {
+	pub manifest_id: Uuid,
+	pub checksum: [u8; 32],
+	/// The start and length of the requested bytes, the whole file if `None`
+	pub range: Option<(u64, u64)>,
+}
+
+impl ManifestFileRequest {
+	pub async fn from_stream(
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, FileRequestError> {
+		let mut manifest_id = [0u8; 16];
+		stream.read_exact(&mut manifest_id).await?;
+
+		let mut checksum = [0u8; 32];
+		stream.read_exact(&mut checksum).await?;
+
+		let range = match stream.read_u8().await? {
+			0 => None,
+			_ => Some((stream.read_u64_le().await?, stream.read_u64_le().await?)),
+		};
+
+		Ok(Self {
+			manifest_id: Uuid::from_bytes(manifest_id),
+			checksum,
+			range,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(16 + 32 + 1 + 16);
+		buf.extend_from_slice(self.manifest_id.as_bytes());
+		buf.extend_from_slice(&self.checksum);
+
+		match self.range {
+			Some((start, length)) => {
+				buf.push(1);
+				buf.extend_from_slice(&start.to_le_bytes());
+				buf.extend_from_slice(&length.to_le_bytes());
+			}
+			None => buf.push(0),
+		}
+
+		buf
+	}
 }
 
 #[derive(Debug, Error)]
@@ -41,6 +153,8 @@ pub enum HeaderError {
 	SpacedropRequestError(#[from] SpacedropRequestError),
 	#[error("error reading sync request: {0}")]
 	SyncRequestError(#[from] SyncRequestError),
+	#[error("error reading file request: {0}")]
+	FileRequestError(#[from] FileRequestError),
 	#[error("invalid request. Spacedrop requires a unicast stream!")]
 	SpacedropOverMulticastIsForbidden,
 }
@@ -52,6 +166,46 @@ impl Header {
 			.await
 			.map_err(HeaderError::DiscriminatorIoError)?;
 
+		match discriminator {
+			7 => {
+				let discriminator = stream
+					.read_u8()
+					.await
+					.map_err(HeaderError::DiscriminatorIoError)?;
+
+				Ok(Self::Tunneled(Box::new(
+					Self::from_compressible(discriminator, stream).await?,
+				)))
+			}
+			d => Self::from_compressible(d, stream).await,
+		}
+	}
+
+	async fn from_compressible(
+		discriminator: u8,
+		stream: &mut SpaceTimeStream,
+	) -> Result<Self, HeaderError> {
+		match discriminator {
+			6 => {
+				let discriminator = stream
+					.read_u8()
+					.await
+					.map_err(HeaderError::DiscriminatorIoError)?;
+
+				Ok(Self::Compressed(Box::new(
+					Self::from_discriminator(discriminator, stream).await?,
+				)))
+			}
+			d => Self::from_discriminator(d, stream).await,
+		}
+	}
+
+	/// Each wrapper is only read once and in order, so this doesn't read [`Header::Compressed`]
+	/// nor [`Header::Tunneled`]
+	async fn from_discriminator(
+		discriminator: u8,
+		stream: &mut SpaceTimeStream,
+	) -> Result<Self, HeaderError> {
 		match discriminator {
 			0 => match stream {
 				SpaceTimeStream::Unicast(stream) => Ok(Self::Spacedrop(
@@ -82,6 +236,21 @@ impl Header {
 					Uuid::from_slice(&uuid).map_err(SyncRequestError::ErrorDecodingLibraryId)?,
 				))
 			}
+			4 => {
+				let mut uuid = [0u8; 16];
+				stream
+					.read_exact(&mut uuid)
+					.await
+					.map_err(SyncRequestError::LibraryIdIoError)?;
+
+				Ok(Self::QrPair(
+					Uuid::from_slice(&uuid).map_err(SyncRequestError::ErrorDecodingLibraryId)?,
+				))
+			}
+			5 => Ok(Self::File(FileRequest::from_stream(stream).await?)),
+			8 => Ok(Self::ManifestFile(
+				ManifestFileRequest::from_stream(stream).await?,
+			)),
 			d => Err(HeaderError::InvalidDiscriminator(d)),
 		}
 	}
@@ -104,6 +273,47 @@ impl Header {
 				bytes.extend_from_slice(uuid.as_bytes());
 				bytes
 			}
+			Self::QrPair(library_id) => {
+				let mut bytes = vec![4];
+				bytes.extend_from_slice(library_id.as_bytes());
+				bytes
+			}
+			Self::File(request) => {
+				let mut bytes = vec![5];
+				bytes.extend_from_slice(&request.to_bytes());
+				bytes
+			}
+			Self::ManifestFile(request) => {
+				let mut bytes = vec![8];
+				bytes.extend_from_slice(&request.to_bytes());
+				bytes
+			}
+			Self::Compressed(header) => {
+				let mut bytes = vec![6];
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
+			Self::Tunneled(header) => {
+				let mut bytes = vec![7];
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
+		}
+	}
+
+	/// The header without its [`Header::Tunneled`] wrapper, and whether it had one
+	pub fn unwrap_tunneled(self) -> (Self, bool) {
+		match self {
+			Self::Tunneled(header) => (*header, true),
+			header => (header, false),
+		}
+	}
+
+	/// The header without its [`Header::Compressed`] wrapper, and whether it had one
+	pub fn unwrap_compressed(self) -> (Self, bool) {
+		match self {
+			Self::Compressed(header) => (*header, true),
+			header => (header, false),
 		}
 	}
 }
@@ -247,6 +457,38 @@ mod tests {
 		assert_eq!(original, info);
 	}
 
+	#[tokio::test]
+	async fn test_file_request() {
+		for range in [None, Some((1024, 4096))] {
+			let original = FileRequest {
+				library_id: Uuid::new_v4(),
+				file_path_pub_id: Uuid::new_v4(),
+				range,
+			};
+
+			let mut cursor = std::io::Cursor::new(original.to_bytes());
+			let request = FileRequest::from_stream(&mut cursor).await.unwrap();
+
+			assert_eq!(original, request);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_manifest_file_request() {
+		for range in [None, Some((1024, 4096))] {
+			let original = ManifestFileRequest {
+				manifest_id: Uuid::new_v4(),
+				checksum: *blake3::hash(b"content").as_bytes(),
+				range,
+			};
+
+			let mut cursor = std::io::Cursor::new(original.to_bytes());
+			let request = ManifestFileRequest::from_stream(&mut cursor).await.unwrap();
+
+			assert_eq!(original, request);
+		}
+	}
+
 	// TODO: Unit test it because binary protocols are error prone
 	// #[test]
 	// fn test_proto() {
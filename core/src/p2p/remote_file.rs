@@ -0,0 +1,256 @@
+//! Streaming the content of files from the device owning their location, so previews of remote
+//! files work without having to sync or retrieve them first.
+
+use std::io::SeekFrom;
+
+use prisma_client_rust::QueryError;
+use sd_p2p::{
+	compression::{CompressionStats, Frame, FrameError},
+	Manager, PeerId,
+};
+use thiserror::Error;
+use tokio::{
+	fs::File,
+	io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{library::LibraryManager, object::fs::ghost::ReachableLocations, prisma::file_path};
+
+use super::{
+	compression_for_extension, negotiated_compression, peer_trust_level, FileRequest, Header,
+	PeerMetadata, SUPPORTED_COMPRESSION,
+};
+
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_FORBIDDEN: u8 = 2;
+
+/// Content is sent in frames of this size when compressed
+const FRAME_LEN: u64 = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum RemoteFileError {
+	#[error("file isn't available on the remote device")]
+	NotFound,
+	#[error("the remote device doesn't trust us with this library")]
+	Forbidden,
+	#[error("couldn't connect to peer '{0}'")]
+	Unreachable(PeerId),
+	#[error("io error streaming remote file: {0}")]
+	Io(#[from] io::Error),
+	#[error("error receiving compressed remote file: {0}")]
+	Frame(#[from] FrameError),
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+}
+
+/// A range of bytes of a remote file
+#[derive(Debug)]
+pub struct RemoteFileChunk {
+	pub total_size: u64,
+	pub start: u64,
+	pub data: Vec<u8>,
+}
+
+/// Run by the device asking for the content
+pub(super) async fn request_file(
+	manager: &Manager<PeerMetadata>,
+	peer_id: PeerId,
+	request: FileRequest,
+	stats: &CompressionStats,
+) -> Result<RemoteFileChunk, RemoteFileError> {
+	request_content(manager, peer_id, Header::File(request), stats).await
+}
+
+/// Sends a request for content, be it a [`Header::File`] or a [`Header::ManifestFile`], and
+/// receives the answer
+pub(super) async fn request_content(
+	manager: &Manager<PeerMetadata>,
+	peer_id: PeerId,
+	request: Header,
+	stats: &CompressionStats,
+) -> Result<RemoteFileChunk, RemoteFileError> {
+	let compressed = negotiated_compression(manager, peer_id).await.is_some();
+
+	let mut stream = manager
+		.stream(peer_id)
+		.await
+		.map_err(|()| RemoteFileError::Unreachable(peer_id))?;
+
+	let header = if compressed {
+		Header::Compressed(Box::new(request))
+	} else {
+		request
+	};
+	stream.write_all(&header.to_bytes()).await?;
+
+	match stream.read_u8().await? {
+		STATUS_OK => {}
+		STATUS_FORBIDDEN => return Err(RemoteFileError::Forbidden),
+		_ => return Err(RemoteFileError::NotFound),
+	}
+
+	let total_size = stream.read_u64_le().await?;
+	let start = stream.read_u64_le().await?;
+	let length = stream.read_u64_le().await?;
+
+	let mut data = Vec::with_capacity(length as usize);
+	if compressed {
+		while (data.len() as u64) < length {
+			let frame = Frame::from_stream(&mut stream, FRAME_LEN as u32).await?;
+			stats.record(&frame);
+			data.extend_from_slice(&frame.decode()?);
+		}
+	} else {
+		(&mut stream).take(length).read_to_end(&mut data).await?;
+		stats.record_raw(data.len() as u64);
+	}
+
+	if data.len() as u64 != length {
+		return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+	}
+
+	Ok(RemoteFileChunk {
+		total_size,
+		start,
+		data,
+	})
+}
+
+/// Run by the device owning the location of the file, only for peers which can read the library.
+/// The content is sent in frames when the request came wrapped in [`Header::Compressed`].
+pub(super) async fn serve_file_request(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	peer_id: PeerId,
+	request: FileRequest,
+	compressed: bool,
+	library_manager: &LibraryManager,
+	stats: &CompressionStats,
+) -> Result<(), RemoteFileError> {
+	send_file(
+		stream,
+		open_requested_file(peer_id, &request, library_manager).await,
+		request.range,
+		compressed,
+		stats,
+	)
+	.await
+}
+
+/// Answers a request for content with the opened file, its size and extension, or why it can't
+/// be sent
+pub(super) async fn send_file(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	file: Result<(File, u64, Option<String>), RemoteFileError>,
+	range: Option<(u64, u64)>,
+	compressed: bool,
+	stats: &CompressionStats,
+) -> Result<(), RemoteFileError> {
+	let (mut file, total_size, extension) = match file {
+		Ok(file) => file,
+		Err(e) => {
+			let status = match e {
+				RemoteFileError::Forbidden => STATUS_FORBIDDEN,
+				_ => STATUS_NOT_FOUND,
+			};
+			stream.write_u8(status).await?;
+
+			return match e {
+				RemoteFileError::NotFound | RemoteFileError::Forbidden => Ok(()),
+				e => Err(e),
+			};
+		}
+	};
+
+	let (start, length) = match range {
+		Some((start, length)) => {
+			let start = start.min(total_size);
+			(start, length.min(total_size - start))
+		}
+		None => (0, total_size),
+	};
+
+	file.seek(SeekFrom::Start(start)).await?;
+
+	let mut header = vec![STATUS_OK];
+	header.extend_from_slice(&total_size.to_le_bytes());
+	header.extend_from_slice(&start.to_le_bytes());
+	header.extend_from_slice(&length.to_le_bytes());
+	stream.write_all(&header).await?;
+
+	if compressed {
+		let compression = compression_for_extension(SUPPORTED_COMPRESSION, extension.as_deref());
+		let mut buf = vec![0; FRAME_LEN.min(length) as usize];
+		let mut remaining = length;
+
+		while remaining > 0 {
+			let len = FRAME_LEN.min(remaining) as usize;
+			file.read_exact(&mut buf[..len]).await?;
+
+			let frame = Frame::encode(&buf[..len], compression)?;
+			stream.write_all(&frame.to_bytes()).await?;
+			stats.record(&frame);
+
+			remaining -= len as u64;
+		}
+	} else {
+		let sent = io::copy(&mut file.take(length), stream).await?;
+		stats.record_raw(sent);
+	}
+	stream.flush().await?;
+
+	Ok(())
+}
+
+async fn open_requested_file(
+	peer_id: PeerId,
+	request: &FileRequest,
+	library_manager: &LibraryManager,
+) -> Result<(File, u64, Option<String>), RemoteFileError> {
+	let library = library_manager
+		.get_library(request.library_id)
+		.await
+		.ok_or(RemoteFileError::NotFound)?;
+
+	if !peer_trust_level(&library, peer_id)
+		.await?
+		.map_or(false, |trust_level| trust_level.can_receive_sync())
+	{
+		return Err(RemoteFileError::Forbidden);
+	}
+
+	let file_path = library
+		.db
+		.file_path()
+		.find_unique(file_path::pub_id::equals(
+			request.file_path_pub_id.as_bytes().to_vec(),
+		))
+		.exec()
+		.await?
+		.ok_or(RemoteFileError::NotFound)?;
+
+	if file_path.is_dir.unwrap_or(false) {
+		return Err(RemoteFileError::NotFound);
+	}
+
+	if let Some(location_id) = file_path.location_id {
+		if library.private_locations.is_locked(location_id).await {
+			return Err(RemoteFileError::NotFound);
+		}
+	}
+
+	// Only locations mounted on this device are reachable, so we never proxy someone else's files
+	let path = ReachableLocations::fetch(&library)
+		.await?
+		.content_path(&file_path)
+		.ok_or(RemoteFileError::NotFound)?;
+
+	let file = File::open(&path).await.map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => RemoteFileError::NotFound,
+		_ => e.into(),
+	})?;
+
+	let total_size = file.metadata().await?.len();
+
+	Ok((file, total_size, file_path.extension))
+}
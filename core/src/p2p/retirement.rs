@@ -0,0 +1,211 @@
+//! Retiring a paired device which was lost, sold or replaced, so it doesn't stay around as a peer
+//! which will never show up again.
+//!
+//! Retiring is done by each device of the library for itself, as the devices paired with a
+//! library aren't synced. The locations of the retired device are handed over to another device or
+//! archived, its operations are kept in the log as they're still needed by peers catching up, but
+//! attributed to this device, and then it's removed from the library. Removing it revokes the
+//! identity key it paired with, so it can't sync or fetch files anymore unless it's paired again.
+
+use prisma_client_rust::QueryError;
+use sd_p2p::{Manager, PeerId};
+use sd_prisma::prisma::{location, node, shared_operation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+	invalidate_query,
+	library::{Library, LibraryManager},
+	sync,
+};
+
+use super::{peer_trust_level, AddressBook, AddressBookError, PeerMetadata};
+
+#[derive(Error, Debug)]
+pub enum RetirementError {
+	#[error("paired device not found: <id='{0}'>")]
+	NotFound(node::id::Type),
+	#[error("a device can't retire itself")]
+	RetiringItself,
+	#[error("locations can't be handed over to the device being retired")]
+	HandoverToRetired,
+	#[error("database error: {0}")]
+	Database(#[from] QueryError),
+	#[error(transparent)]
+	AddressBook(#[from] AddressBookError),
+}
+
+impl From<RetirementError> for rspc::Error {
+	fn from(e: RetirementError) -> Self {
+		let code = match e {
+			RetirementError::NotFound(_) => rspc::ErrorCode::NotFound,
+			RetirementError::RetiringItself | RetirementError::HandoverToRetired => {
+				rspc::ErrorCode::BadRequest
+			}
+			_ => rspc::ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, e.to_string(), e)
+	}
+}
+
+/// What happens to the locations of a retired device
+#[derive(Deserialize, Type, Debug, Clone, Copy)]
+#[serde(tag = "type")]
+pub enum Handover {
+	/// The locations, with all their metadata, now belong to another device, like when a drive
+	/// moves to a new computer
+	TransferTo { node_id: node::id::Type },
+	/// The locations are kept for their metadata only, without belonging to any device
+	Archive,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct RetirementReport {
+	pub name: String,
+	pub locations_handed_over: u32,
+	/// Operations made by the retired device, which are now attributed to this one
+	pub operations_reassigned: u32,
+}
+
+pub(super) async fn retire_node(
+	manager: &Manager<PeerMetadata>,
+	address_book: &AddressBook,
+	library_manager: &LibraryManager,
+	library: &Library,
+	node_id: node::id::Type,
+	handover: Handover,
+) -> Result<RetirementReport, RetirementError> {
+	let Library { db, sync, .. } = library;
+
+	if node_id == library.node_local_id {
+		return Err(RetirementError::RetiringItself);
+	}
+
+	let retired = db
+		.node()
+		.find_unique(node::id::equals(node_id))
+		.exec()
+		.await?
+		.ok_or(RetirementError::NotFound(node_id))?;
+
+	let target = match handover {
+		Handover::TransferTo { node_id: target_id } if target_id == node_id => {
+			return Err(RetirementError::HandoverToRetired);
+		}
+		Handover::TransferTo { node_id: target_id } => Some(
+			db.node()
+				.find_unique(node::id::equals(target_id))
+				.exec()
+				.await?
+				.ok_or(RetirementError::NotFound(target_id))?,
+		),
+		Handover::Archive => None,
+	};
+
+	let locations = db
+		.location()
+		.find_many(vec![location::node_id::equals(Some(node_id))])
+		.select(location::select!({ id pub_id }))
+		.exec()
+		.await?;
+
+	for location in &locations {
+		let sync_id = || sync::location::SyncId {
+			pub_id: location.pub_id.clone(),
+		};
+
+		match &target {
+			Some(target) => {
+				sync.write_op(
+					db,
+					sync.shared_update(
+						sync_id(),
+						location::node::NAME,
+						json!(sync::node::SyncId {
+							pub_id: target.pub_id.clone()
+						}),
+					),
+					db.location().update(
+						location::id::equals(location.id),
+						vec![location::node::connect(node::id::equals(target.id))],
+					),
+				)
+				.await?;
+			}
+			None => {
+				sync.write_op(
+					db,
+					sync.shared_update(sync_id(), location::is_archived::NAME, json!(true)),
+					db.location().update(
+						location::id::equals(location.id),
+						vec![
+							location::is_archived::set(Some(true)),
+							location::node::disconnect(),
+						],
+					),
+				)
+				.await?;
+			}
+		}
+	}
+
+	// The operations keep their timestamps, so which one wins doesn't change. Peers which retired
+	// the device too would otherwise refuse them, as they come from a device they don't know.
+	// The jobs of the device go with it.
+	let (operations_reassigned, _) = db
+		._batch((
+			db.shared_operation().update_many(
+				vec![shared_operation::node_id::equals(node_id)],
+				vec![shared_operation::node_id::set(library.node_local_id)],
+			),
+			db.node().delete(node::id::equals(node_id)),
+		))
+		.await?;
+
+	if let Some(peer_id) = retired
+		.node_peer_id
+		.as_deref()
+		.and_then(|peer_id| peer_id.parse::<PeerId>().ok())
+	{
+		forget_peer(manager, address_book, library_manager, peer_id).await?;
+	}
+
+	invalidate_query!(library, "p2p.pairedNodes");
+	invalidate_query!(library, "locations.list");
+
+	info!(
+		"Retired device '{}' from library '{}', handing over {} locations",
+		retired.name,
+		library.id,
+		locations.len()
+	);
+
+	Ok(RetirementReport {
+		name: retired.name,
+		locations_handed_over: locations.len() as u32,
+		operations_reassigned: operations_reassigned as u32,
+	})
+}
+
+/// Stops reaching out to the peer, unless it's still paired with another library
+async fn forget_peer(
+	manager: &Manager<PeerMetadata>,
+	address_book: &AddressBook,
+	library_manager: &LibraryManager,
+	peer_id: PeerId,
+) -> Result<(), RetirementError> {
+	for library in library_manager.get_all_libraries().await {
+		if peer_trust_level(&library, peer_id).await?.is_some() {
+			return Ok(());
+		}
+	}
+
+	address_book.remove_peer(peer_id).await?;
+	manager.remove_known_peer(peer_id).await;
+
+	Ok(())
+}
@@ -0,0 +1,94 @@
+//! What protects the data sent to each peer. Sync operations always go through an end-to-end
+//! encrypted [`Tunnel`](sd_p2p::spacetunnel::Tunnel), as do Spacedrops to peers advertising they
+//! can set one up, so a relay forwarding a connection can't read them. Setting up a tunnel proves
+//! the peer owns the identity key of its [`PeerId`], which users can check by comparing
+//! fingerprints between their devices.
+
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use sd_p2p::{spacetunnel::TUNNEL_CIPHER, ConnectionPath, Manager, PeerId};
+use serde::Serialize;
+use specta::Type;
+use tokio::sync::Mutex;
+
+use super::PeerMetadata;
+
+/// What the connections between peers are encrypted with, by libp2p
+const TRANSPORT_CIPHER: &str = "QUIC (TLS 1.3)";
+
+/// Whether a peer advertised it can set up tunnels
+pub(super) async fn supports_tunnel(manager: &Manager<PeerMetadata>, peer_id: PeerId) -> bool {
+	manager
+		.get_discovered_peers()
+		.await
+		.into_iter()
+		.any(|peer| peer.peer_id == peer_id && peer.metadata.tunnel)
+}
+
+pub(super) async fn is_relayed(manager: &Manager<PeerMetadata>, peer_id: PeerId) -> bool {
+	manager
+		.connections()
+		.await
+		.get(&peer_id)
+		.map_or(false, |paths| paths.iter().any(|path| path.relayed))
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct ConnectionSecurity {
+	pub peer_id: PeerId,
+	/// Short hash of the identity key of the peer, to compare with what the peer shows for itself
+	pub fingerprint: String,
+	pub paths: Vec<ConnectionPath>,
+	pub relayed: bool,
+	pub transport_cipher: String,
+	/// The encryption of sync and Spacedrop data inside the connection, `None` until a tunnel was
+	/// set up with the peer
+	pub end_to_end_cipher: Option<String>,
+	/// When the peer last proved it owns its identity key by setting up a tunnel
+	pub verified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct SecuritySummary {
+	/// The fingerprint of this node, for comparing on the devices it's connected to
+	pub fingerprint: String,
+	pub connections: Vec<ConnectionSecurity>,
+}
+
+/// When each peer last set up a tunnel with us, since the node started
+#[derive(Debug, Default, Clone)]
+pub struct VerifiedPeers(Arc<Mutex<HashMap<PeerId, DateTime<Utc>>>>);
+
+impl VerifiedPeers {
+	pub(super) async fn record(&self, peer_id: PeerId) {
+		self.0.lock().await.insert(peer_id, Utc::now());
+	}
+
+	/// The security of the connections currently open
+	pub async fn summary(&self, manager: &Manager<PeerMetadata>) -> SecuritySummary {
+		let verified = self.0.lock().await;
+
+		SecuritySummary {
+			fingerprint: manager.peer_id().fingerprint(),
+			connections: manager
+				.connections()
+				.await
+				.into_iter()
+				.map(|(peer_id, paths)| {
+					let verified_at = verified.get(&peer_id).copied();
+
+					ConnectionSecurity {
+						peer_id,
+						fingerprint: peer_id.fingerprint(),
+						relayed: paths.iter().any(|path| path.relayed),
+						paths,
+						transport_cipher: TRANSPORT_CIPHER.to_string(),
+						end_to_end_cipher: verified_at.map(|_| TUNNEL_CIPHER.to_string()),
+						verified_at,
+					}
+				})
+				.collect(),
+		}
+	}
+}
@@ -0,0 +1,418 @@
+//! Temporary download links, so files can be shared with people who don't run Spacedrive.
+//!
+//! Links only live in memory, so restarting the node revokes all of them. They are served by the
+//! custom URI endpoint under `share/<token>` and TLS is expected to be terminated at the origin set
+//! in `share_links_base_url`, be it the node itself behind a reverse proxy or a relay.
+//!
+//! Links point at file paths of the library's locations, which are resolved again for each
+//! download, so files of private locations can't be shared. The password of a link is only ever
+//! sent in a header or a form, and exchanged for a session kept in a cookie, so it never ends up
+//! in a URL.
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sd_crypto::types::Salt;
+use serde::Serialize;
+use specta::Type;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+	location::privacy::hash_passphrase,
+	prisma::{file_path, location},
+};
+
+/// Links can't outlive this, no matter what the user asked for
+pub const MAX_SHARE_LINK_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Wrong passwords allowed before the link is locked for a while, counting the ones being checked
+const MAX_PASSWORD_ATTEMPTS: u32 = 5;
+const PASSWORD_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Sessions kept for each link, the oldest ones have to enter the password again
+const MAX_SESSIONS: usize = 32;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ShareLinkError {
+	#[error("share link not found, expired or used up")]
+	NotFound,
+	#[error("share link requires a password")]
+	PasswordRequired,
+	#[error("wrong password for share link")]
+	WrongPassword,
+	#[error("too many wrong passwords for share link, try again later")]
+	TooManyAttempts,
+	#[error("failed to check the password of share link")]
+	Hashing,
+}
+
+/// A file shared by a link, it's resolved to its path when downloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedFile {
+	pub library_id: Uuid,
+	pub location_id: location::id::Type,
+	pub file_path_id: file_path::id::Type,
+	pub name: String,
+}
+
+/// The password of a link, salted and hashed like the passphrases of private locations
+#[derive(Clone, Copy)]
+struct LinkPassword {
+	salt: Salt,
+	hash: blake3::Hash,
+}
+
+impl fmt::Debug for LinkPassword {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("LinkPassword")
+	}
+}
+
+#[derive(Debug)]
+struct ShareLink {
+	files: Vec<SharedFile>,
+	created_at: DateTime<Utc>,
+	expires_at: DateTime<Utc>,
+	password: Option<LinkPassword>,
+	/// Hashes of the sessions given for the password, newest last
+	sessions: Vec<blake3::Hash>,
+	/// Wrong passwords and passwords being checked
+	password_attempts: u32,
+	locked_until: Option<DateTime<Utc>>,
+	/// Each file download counts, `None` means only the expiration limits the link
+	max_downloads: Option<u32>,
+	downloads: u32,
+}
+
+impl ShareLink {
+	fn is_valid(&self, now: DateTime<Utc>) -> bool {
+		now < self.expires_at
+			&& self
+				.max_downloads
+				.map_or(true, |max_downloads| self.downloads < max_downloads)
+	}
+
+	fn check_session(&self, session: Option<&str>) -> Result<(), ShareLinkError> {
+		if self.password.is_none() {
+			return Ok(());
+		}
+
+		let session = session.ok_or(ShareLinkError::PasswordRequired)?;
+		let hash = blake3::hash(session.as_bytes());
+
+		// `blake3::Hash` equality is constant time
+		if !self.sessions.iter().any(|session| *session == hash) {
+			return Err(ShareLinkError::PasswordRequired);
+		}
+
+		Ok(())
+	}
+
+	fn start_password_attempt(&mut self, now: DateTime<Utc>) -> Result<(), ShareLinkError> {
+		if let Some(locked_until) = self.locked_until {
+			if now < locked_until {
+				return Err(ShareLinkError::TooManyAttempts);
+			}
+
+			self.locked_until = None;
+			self.password_attempts = 0;
+		}
+
+		if self.password_attempts >= MAX_PASSWORD_ATTEMPTS {
+			return Err(ShareLinkError::TooManyAttempts);
+		}
+		self.password_attempts += 1;
+
+		Ok(())
+	}
+
+	fn finish_password_attempt(&mut self, correct: bool, now: DateTime<Utc>) {
+		if correct {
+			self.password_attempts = self.password_attempts.saturating_sub(1);
+		} else if self.password_attempts >= MAX_PASSWORD_ATTEMPTS {
+			self.locked_until = Some(
+				now + chrono::Duration::from_std(PASSWORD_LOCKOUT)
+					.expect("lockout is a few minutes"),
+			);
+		}
+	}
+
+	fn info(&self, token: &str) -> ShareLinkInfo {
+		ShareLinkInfo {
+			token: token.to_string(),
+			file_names: self.files.iter().map(|file| file.name.clone()).collect(),
+			created_at: self.created_at,
+			expires_at: self.expires_at,
+			has_password: self.password.is_some(),
+			max_downloads: self.max_downloads,
+			downloads: self.downloads,
+		}
+	}
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct ShareLinkInfo {
+	pub token: String,
+	pub file_names: Vec<String>,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: DateTime<Utc>,
+	pub has_password: bool,
+	pub max_downloads: Option<u32>,
+	pub downloads: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct ShareLinks(Mutex<HashMap<String, ShareLink>>);
+
+impl ShareLinks {
+	pub async fn create(
+		&self,
+		files: Vec<SharedFile>,
+		expires_in: Duration,
+		password: Option<String>,
+		max_downloads: Option<u32>,
+	) -> Result<ShareLinkInfo, ShareLinkError> {
+		let password = match password {
+			Some(password) => {
+				let salt = Salt::generate();
+				Some(LinkPassword {
+					salt,
+					hash: hash_password(password, salt).await?,
+				})
+			}
+			None => None,
+		};
+
+		let token = Uuid::new_v4().simple().to_string();
+		let created_at = Utc::now();
+
+		let link = ShareLink {
+			files,
+			created_at,
+			expires_at: created_at
+				+ chrono::Duration::from_std(expires_in.min(MAX_SHARE_LINK_LIFETIME))
+					.expect("lifetime is bounded by MAX_SHARE_LINK_LIFETIME"),
+			password,
+			sessions: Vec::new(),
+			password_attempts: 0,
+			locked_until: None,
+			max_downloads,
+			downloads: 0,
+		};
+
+		let info = link.info(&token);
+
+		let mut links = self.0.lock().await;
+		prune(&mut links, created_at);
+		links.insert(token, link);
+
+		Ok(info)
+	}
+
+	pub async fn revoke(&self, token: &str) -> bool {
+		self.0.lock().await.remove(token).is_some()
+	}
+
+	pub async fn list(&self) -> Vec<ShareLinkInfo> {
+		let mut links = self.0.lock().await;
+		prune(&mut links, Utc::now());
+
+		links.iter().map(|(token, link)| link.info(token)).collect()
+	}
+
+	/// Checks the password of a link and gives a session to access it with, links without a
+	/// password don't need one. Wrong passwords lock the link for a while once there are too many
+	/// of them.
+	pub async fn start_session(
+		&self,
+		token: &str,
+		password: String,
+	) -> Result<Option<String>, ShareLinkError> {
+		let expected = {
+			let mut links = self.0.lock().await;
+			prune(&mut links, Utc::now());
+
+			let link = links.get_mut(token).ok_or(ShareLinkError::NotFound)?;
+			let Some(expected) = link.password else {
+				return Ok(None);
+			};
+			link.start_password_attempt(Utc::now())?;
+
+			expected
+		};
+
+		// Hashing takes a while, so the other links aren't held up by it
+		let hashed = hash_password(password, expected.salt).await;
+
+		let mut links = self.0.lock().await;
+		let link = links.get_mut(token).ok_or(ShareLinkError::NotFound)?;
+
+		// `blake3::Hash` equality is constant time
+		let correct = hashed
+			.as_ref()
+			.map_or(false, |hashed| *hashed == expected.hash);
+		link.finish_password_attempt(correct, Utc::now());
+		hashed?;
+
+		if !correct {
+			return Err(ShareLinkError::WrongPassword);
+		}
+
+		let session = Uuid::new_v4().simple().to_string();
+		if link.sessions.len() >= MAX_SESSIONS {
+			link.sessions.remove(0);
+		}
+		link.sessions.push(blake3::hash(session.as_bytes()));
+
+		Ok(Some(session))
+	}
+
+	/// Names of the files shared by a link, without counting as a download
+	pub async fn file_names(
+		&self,
+		token: &str,
+		session: Option<&str>,
+	) -> Result<Vec<String>, ShareLinkError> {
+		let mut links = self.0.lock().await;
+		prune(&mut links, Utc::now());
+
+		let link = links.get(token).ok_or(ShareLinkError::NotFound)?;
+		link.check_session(session)?;
+
+		Ok(link.info(token).file_names)
+	}
+
+	/// Counts a download of the file at `index` and returns it
+	pub async fn take_download(
+		&self,
+		token: &str,
+		index: usize,
+		session: Option<&str>,
+	) -> Result<SharedFile, ShareLinkError> {
+		let mut links = self.0.lock().await;
+		prune(&mut links, Utc::now());
+
+		let link = links.get_mut(token).ok_or(ShareLinkError::NotFound)?;
+		link.check_session(session)?;
+
+		let file = link
+			.files
+			.get(index)
+			.cloned()
+			.ok_or(ShareLinkError::NotFound)?;
+
+		link.downloads += 1;
+
+		Ok(file)
+	}
+}
+
+fn prune(links: &mut HashMap<String, ShareLink>, now: DateTime<Utc>) {
+	links.retain(|_, link| link.is_valid(now));
+}
+
+async fn hash_password(password: String, salt: Salt) -> Result<blake3::Hash, ShareLinkError> {
+	hash_passphrase(password, salt).await.map_err(|e| {
+		error!("Failed to hash the password of a share link: {e:#?}");
+		ShareLinkError::Hashing
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn shared_file(file_path_id: file_path::id::Type, name: &str) -> SharedFile {
+		SharedFile {
+			library_id: Uuid::nil(),
+			location_id: 1,
+			file_path_id,
+			name: name.to_string(),
+		}
+	}
+
+	#[tokio::test]
+	async fn download_limits() {
+		let links = ShareLinks::default();
+		let info = links
+			.create(
+				vec![shared_file(1, "a.txt"), shared_file(2, "b.txt")],
+				Duration::from_secs(60),
+				None,
+				Some(2),
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(info.file_names, vec!["a.txt", "b.txt"]);
+		assert_eq!(
+			links.take_download(&info.token, 2, None).await,
+			Err(ShareLinkError::NotFound)
+		);
+		assert_eq!(
+			links.file_names(&info.token, None).await,
+			Ok(vec!["a.txt".to_string(), "b.txt".to_string()])
+		);
+
+		for index in 0..2 {
+			assert_eq!(
+				links.take_download(&info.token, index, None).await,
+				Ok(shared_file(index as i32 + 1, ["a.txt", "b.txt"][index]))
+			);
+		}
+
+		assert_eq!(
+			links.take_download(&info.token, 0, None).await,
+			Err(ShareLinkError::NotFound)
+		);
+		assert!(links.list().await.is_empty());
+	}
+
+	#[test]
+	fn password_attempts() {
+		let now = Utc::now();
+		let mut link = ShareLink {
+			files: vec![shared_file(1, "a.txt")],
+			created_at: now,
+			expires_at: now + chrono::Duration::minutes(1),
+			password: Some(LinkPassword {
+				salt: Salt::generate(),
+				hash: blake3::hash(b"hunter2"),
+			}),
+			sessions: vec![blake3::hash(b"session")],
+			password_attempts: 0,
+			locked_until: None,
+			max_downloads: None,
+			downloads: 0,
+		};
+
+		assert_eq!(
+			link.check_session(None),
+			Err(ShareLinkError::PasswordRequired)
+		);
+		assert_eq!(
+			link.check_session(Some("other session")),
+			Err(ShareLinkError::PasswordRequired)
+		);
+		assert_eq!(link.check_session(Some("session")), Ok(()));
+
+		// Passwords being checked count too, so they can't be guessed in parallel
+		for _ in 0..MAX_PASSWORD_ATTEMPTS {
+			assert_eq!(link.start_password_attempt(now), Ok(()));
+		}
+		assert_eq!(
+			link.start_password_attempt(now),
+			Err(ShareLinkError::TooManyAttempts)
+		);
+
+		link.finish_password_attempt(false, now);
+		assert_eq!(
+			link.start_password_attempt(now + chrono::Duration::minutes(1)),
+			Err(ShareLinkError::TooManyAttempts)
+		);
+
+		let later = now + chrono::Duration::from_std(PASSWORD_LOCKOUT).unwrap();
+		assert_eq!(link.start_password_attempt(later), Ok(()));
+	}
+}
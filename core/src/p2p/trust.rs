@@ -0,0 +1,58 @@
+use prisma_client_rust::QueryError;
+use sd_p2p::PeerId;
+use sd_prisma::prisma::node;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::library::Library;
+
+/// How much a paired device is trusted with a library
+#[repr(i32)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Type, Eq, PartialEq)]
+pub enum TrustLevel {
+	/// Changes flow both ways
+	#[default]
+	FullSync = 0,
+	/// The device gets our changes, but we ignore the ones it sends
+	ReadOnly = 1,
+	/// The device can only Spacedrop files to us, without any access to the library
+	SpacedropOnly = 2,
+}
+
+impl TrustLevel {
+	pub fn from_db(value: Option<i32>) -> Self {
+		match value {
+			Some(1) => Self::ReadOnly,
+			Some(2) => Self::SpacedropOnly,
+			_ => Self::FullSync,
+		}
+	}
+
+	/// If we ingest the sync operations sent by the device
+	pub fn can_send_sync(self) -> bool {
+		matches!(self, Self::FullSync)
+	}
+
+	/// If we send our sync operations to the device
+	pub fn can_receive_sync(self) -> bool {
+		matches!(self, Self::FullSync | Self::ReadOnly)
+	}
+}
+
+/// The trust level of a peer in a library, `None` if it isn't paired with it
+pub async fn peer_trust_level(
+	library: &Library,
+	peer_id: PeerId,
+) -> Result<Option<TrustLevel>, QueryError> {
+	Ok(library
+		.db
+		.node()
+		.find_first(vec![
+			node::node_peer_id::equals(Some(peer_id.to_string())),
+			node::id::not(library.node_local_id),
+		])
+		.select(node::select!({ trust_level }))
+		.exec()
+		.await?
+		.map(|node| TrustLevel::from_db(node.trust_level)))
+}
@@ -0,0 +1,71 @@
+//! Keeps the operation log of each library from growing forever by compacting what's older than
+//! its retention, see [`sd_sync::compaction`].
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::time::interval;
+use tracing::{debug, warn};
+
+use crate::Node;
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How long the operations of a library are kept as they were made
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, PartialEq, Eq)]
+pub struct SyncLogRetention {
+	/// Operations are compacted once they're older than this, so peers syncing at least this often
+	/// still get every change. `None` never compacts the log.
+	pub compact_after_days: Option<u32>,
+}
+
+impl Default for SyncLogRetention {
+	fn default() -> Self {
+		Self {
+			compact_after_days: Some(30),
+		}
+	}
+}
+
+impl SyncLogRetention {
+	pub fn max_age(&self) -> Option<Duration> {
+		self.compact_after_days
+			.map(|days| Duration::from_secs(days as u64 * SECONDS_PER_DAY))
+	}
+}
+
+#[derive(Debug, Default, Serialize, Type)]
+pub struct CompactionReport {
+	pub removed_operations: u32,
+	/// Creations whose values were all or partly overwritten later, which were replaced by what's
+	/// left of them
+	pub rewritten_operations: u32,
+}
+
+/// Compacts the logs of all libraries with a retention now and then
+pub(crate) async fn compact_logs(node: Arc<Node>) {
+	let mut interval = interval(COMPACTION_INTERVAL);
+
+	loop {
+		interval.tick().await;
+
+		for library in node.library_manager.get_all_libraries().await {
+			let Some(max_age) = library.config.sync_log_retention.max_age() else {
+				continue;
+			};
+
+			match library.sync.compact(max_age).await {
+				Ok(report) => debug!(
+					"Compacted the sync log of library {}: {report:?}",
+					library.id
+				),
+				Err(e) => warn!(
+					"Failed to compact the sync log of library {}: {e:#?}",
+					library.id
+				),
+			}
+		}
+	}
+}
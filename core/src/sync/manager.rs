@@ -1,17 +1,24 @@
 #![allow(clippy::unwrap_used, clippy::panic)] // TODO: Brendan remove this once you've got error handling here
 
-use crate::prisma::*;
+use crate::{node::Metrics, prisma::*};
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::HashMap,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use sd_sync::*;
+use sd_sync::{compaction, *};
 
 use serde_json::{json, to_vec, Value};
 use tokio::sync::broadcast::{self, Receiver, Sender};
 use uhlc::{HLCBuilder, HLC, NTP64};
 use uuid::Uuid;
 
-use super::ModelSyncData;
+use super::{CompactionReport, ModelSyncData};
+
+/// Operations are removed and rewritten in batches, to stay below the variable limit of SQLite
+const COMPACTION_BATCH_SIZE: usize = 500;
 
 #[derive(Clone)]
 pub enum SyncMessage {
@@ -24,11 +31,16 @@ pub struct SyncManager {
 	node: Uuid,
 	_clocks: HashMap<Uuid, NTP64>,
 	clock: HLC,
+	metrics: Arc<Metrics>,
 	pub tx: Sender<SyncMessage>,
 }
 
 impl SyncManager {
-	pub fn new(db: &Arc<PrismaClient>, node: Uuid) -> (Self, Receiver<SyncMessage>) {
+	pub fn new(
+		db: &Arc<PrismaClient>,
+		node: Uuid,
+		metrics: Arc<Metrics>,
+	) -> (Self, Receiver<SyncMessage>) {
 		let (tx, rx) = broadcast::channel(64);
 
 		(
@@ -37,6 +49,7 @@ impl SyncManager {
 				node,
 				clock: HLCBuilder::new().with_id(node.into()).build(),
 				_clocks: Default::default(),
+				metrics,
 				tx,
 			},
 			rx,
@@ -48,6 +61,8 @@ impl SyncManager {
 		tx: &PrismaClient,
 		(_ops, queries): (Vec<CRDTOperation>, I),
 	) -> prisma_client_rust::Result<<I as prisma_client_rust::BatchItemParent>::ReturnValue> {
+		let _timer = self.metrics.db_query_duration.start_timer("write_ops");
+
 		#[cfg(feature = "sync-messages")]
 		let res = {
 			let shared = _ops
@@ -95,6 +110,8 @@ impl SyncManager {
 		op: CRDTOperation,
 		query: Q,
 	) -> prisma_client_rust::Result<<Q as prisma_client_rust::BatchItemParent>::ReturnValue> {
+		let _timer = self.metrics.db_query_duration.start_timer("write_op");
+
 		#[cfg(feature = "sync-messages")]
 		let ret = {
 			let ret = match &op.typ {
@@ -135,10 +152,17 @@ impl SyncManager {
 	}
 
 	pub async fn get_ops(&self) -> prisma_client_rust::Result<Vec<CRDTOperation>> {
+		self.find_ops(vec![]).await
+	}
+
+	async fn find_ops(
+		&self,
+		filters: Vec<shared_operation::WhereParam>,
+	) -> prisma_client_rust::Result<Vec<CRDTOperation>> {
 		Ok(self
 			.db
 			.shared_operation()
-			.find_many(vec![])
+			.find_many(filters)
 			.order_by(shared_operation::timestamp::order(SortOrder::Asc))
 			.include(shared_operation::include!({ node: select {
                 pub_id
@@ -161,6 +185,60 @@ impl SyncManager {
 			.collect())
 	}
 
+	/// Compacts the operations older than `max_age`, leaving the rest of the log as is
+	pub async fn compact(&self, max_age: Duration) -> prisma_client_rust::Result<CompactionReport> {
+		let _timer = self.metrics.db_query_duration.start_timer("compact_ops");
+
+		let cutoff = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.saturating_sub(max_age);
+
+		let compaction = compaction::compact(
+			self.find_ops(vec![shared_operation::timestamp::lt(
+				NTP64::from(cutoff).0 as i64,
+			)])
+			.await?,
+		);
+
+		let report = CompactionReport {
+			removed_operations: compaction.removed.len() as u32,
+			rewritten_operations: compaction.rewritten.len() as u32,
+		};
+
+		for ids in compaction.removed.chunks(COMPACTION_BATCH_SIZE) {
+			self.db
+				.shared_operation()
+				.delete_many(vec![shared_operation::id::in_vec(
+					ids.iter().map(|id| id.as_bytes().to_vec()).collect(),
+				)])
+				.exec()
+				.await?;
+		}
+
+		for ops in compaction.rewritten.chunks(COMPACTION_BATCH_SIZE) {
+			self.db
+				._batch(
+					ops.iter()
+						.filter_map(|op| match &op.typ {
+							CRDTOperationType::Shared(shared_op) => {
+								Some(self.db.shared_operation().update(
+									shared_operation::id::equals(op.id.as_bytes().to_vec()),
+									vec![shared_operation::data::set(
+										to_vec(&shared_op.data).unwrap(),
+									)],
+								))
+							}
+							_ => None,
+						})
+						.collect::<Vec<_>>(),
+				)
+				.await?;
+		}
+
+		Ok(report)
+	}
+
 	pub async fn ingest_op(&self, op: CRDTOperation) -> prisma_client_rust::Result<()> {
 		let db = &self.db;
 
@@ -302,6 +380,195 @@ impl SyncManager {
 						.await?;
 				}
 			},
+			ModelSyncData::CustomField(id, shared_op) => match shared_op {
+				SharedOperationData::Create(data) => {
+					let data: Vec<_> = data
+						.into_iter()
+						.flat_map(|(field, value)| {
+							custom_field::SetParam::deserialize(&field, value)
+						})
+						.collect();
+
+					db.custom_field()
+						.upsert(
+							custom_field::pub_id::equals(id.pub_id.clone()),
+							custom_field::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Update { field, value } => {
+					let data = vec![custom_field::SetParam::deserialize(&field, value).unwrap()];
+
+					db.custom_field()
+						.upsert(
+							custom_field::pub_id::equals(id.pub_id.clone()),
+							custom_field::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				// Its values go with it
+				SharedOperationData::Delete => {
+					db.custom_field()
+						.delete_many(vec![custom_field::pub_id::equals(id.pub_id)])
+						.exec()
+						.await?;
+				}
+			},
+			ModelSyncData::CustomFieldValue(id, shared_op) => match shared_op {
+				SharedOperationData::Create(data) => {
+					let data: Vec<_> = data
+						.into_iter()
+						.flat_map(|(field, value)| {
+							custom_field_value::SetParam::deserialize(&field, value)
+						})
+						.collect();
+
+					db.custom_field_value()
+						.upsert(
+							custom_field_value::pub_id::equals(id.pub_id.clone()),
+							custom_field_value::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Update { field, value } => {
+					let data =
+						vec![custom_field_value::SetParam::deserialize(&field, value).unwrap()];
+
+					db.custom_field_value()
+						.upsert(
+							custom_field_value::pub_id::equals(id.pub_id.clone()),
+							custom_field_value::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				// Already gone when its field was deleted first
+				SharedOperationData::Delete => {
+					db.custom_field_value()
+						.delete_many(vec![custom_field_value::pub_id::equals(id.pub_id)])
+						.exec()
+						.await?;
+				}
+			},
+			ModelSyncData::ContentRestriction(id, shared_op) => match shared_op {
+				SharedOperationData::Create(data) => {
+					let data: Vec<_> = data
+						.into_iter()
+						.flat_map(|(field, value)| {
+							content_restriction::SetParam::deserialize(&field, value)
+						})
+						.collect();
+
+					db.content_restriction()
+						.upsert(
+							content_restriction::pub_id::equals(id.pub_id.clone()),
+							content_restriction::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Update { field, value } => {
+					let data =
+						vec![content_restriction::SetParam::deserialize(&field, value).unwrap()];
+
+					db.content_restriction()
+						.upsert(
+							content_restriction::pub_id::equals(id.pub_id.clone()),
+							content_restriction::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Delete => {
+					db.content_restriction()
+						.delete_many(vec![content_restriction::pub_id::equals(id.pub_id)])
+						.exec()
+						.await?;
+				}
+			},
+			ModelSyncData::SessionState(id, shared_op) => match shared_op {
+				SharedOperationData::Create(data) => {
+					let data: Vec<_> = data
+						.into_iter()
+						.flat_map(|(field, value)| {
+							session_state::SetParam::deserialize(&field, value)
+						})
+						.collect();
+
+					db.session_state()
+						.upsert(
+							session_state::pub_id::equals(id.pub_id.clone()),
+							session_state::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Update { field, value } => {
+					let data = vec![session_state::SetParam::deserialize(&field, value).unwrap()];
+
+					db.session_state()
+						.upsert(
+							session_state::pub_id::equals(id.pub_id.clone()),
+							session_state::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Delete => {
+					db.session_state()
+						.delete_many(vec![session_state::pub_id::equals(id.pub_id)])
+						.exec()
+						.await?;
+				}
+			},
+			ModelSyncData::SidebarItem(id, shared_op) => match shared_op {
+				SharedOperationData::Create(data) => {
+					let data: Vec<_> = data
+						.into_iter()
+						.flat_map(|(field, value)| {
+							sidebar_item::SetParam::deserialize(&field, value)
+						})
+						.collect();
+
+					db.sidebar_item()
+						.upsert(
+							sidebar_item::pub_id::equals(id.pub_id.clone()),
+							sidebar_item::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Update { field, value } => {
+					let data = vec![sidebar_item::SetParam::deserialize(&field, value).unwrap()];
+
+					db.sidebar_item()
+						.upsert(
+							sidebar_item::pub_id::equals(id.pub_id.clone()),
+							sidebar_item::create(id.pub_id, data.clone()),
+							data,
+						)
+						.exec()
+						.await?;
+				}
+				SharedOperationData::Delete => {
+					db.sidebar_item()
+						.delete_many(vec![sidebar_item::pub_id::equals(id.pub_id)])
+						.exec()
+						.await?;
+				}
+			},
 		}
 
 		if let CRDTOperationType::Shared(shared_op) = op.typ {
@@ -379,4 +646,18 @@ impl SyncManager {
 			},
 		}))
 	}
+
+	pub fn shared_delete<
+		TSyncId: SyncId<ModelTypes = TModel>,
+		TModel: SyncType<Marker = SharedSyncType>,
+	>(
+		&self,
+		id: TSyncId,
+	) -> CRDTOperation {
+		self.new_op(CRDTOperationType::Shared(SharedOperation {
+			model: TModel::MODEL.to_string(),
+			record_id: json!(id),
+			data: SharedOperationData::Delete,
+		}))
+	}
 }
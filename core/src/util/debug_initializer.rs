@@ -13,6 +13,7 @@ use crate::{
 		delete_location, scan_location, LocationCreateArgs, LocationError, LocationManagerError,
 	},
 	node::NodeConfig,
+	object::groups::default_grouping_rules,
 	prisma::location,
 	util::AbortOnDrop,
 };
@@ -123,6 +124,13 @@ impl InitConfig {
 								description: lib.description,
 								identity: Identity::new().to_bytes(),
 								node_id: node_pub_id,
+								job_schedule: Default::default(),
+								file_name_normalization: Default::default(),
+								file_name_policy: Default::default(),
+								file_grouping_rules: default_grouping_rules(),
+								sync_log_retention: Default::default(),
+								track_access: true,
+								offline_cache_budget_in_mb: 10 * 1024,
 							},
 							node_cfg.clone(),
 						)
@@ -171,6 +179,8 @@ impl InitConfig {
 					path: loc.path.clone().into(),
 					dry_run: false,
 					indexer_rules_ids: Vec::new(),
+					nested_policy: Default::default(),
+					index_depth: None,
 				}
 				.create(&library)
 				.await?;
@@ -0,0 +1,352 @@
+use crate::prisma::location;
+
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt::{self, Write},
+	sync::{Mutex, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tracing::{
+	collect::Collect,
+	field::{Field, Visit},
+	span, Event, Level, Metadata,
+};
+use tracing_subscriber::{
+	registry::LookupSpan,
+	subscribe::{Context, Subscribe},
+};
+use uuid::Uuid;
+
+/// How many records are kept for each library (and for the node itself), older ones are dropped first
+const LOG_BUFFER_CAPACITY: usize = 2000;
+
+/// The in memory buffer holding the most recent logs of every library,
+/// it's filled by the [`LogBufferSubscriber`] registered in `Node::init_logger`
+pub static LOG_BUFFER: Lazy<LogBuffer> = Lazy::new(LogBuffer::default);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+	Trace,
+	Debug,
+	Info,
+	Warn,
+	Error,
+}
+
+impl From<&Level> for LogLevel {
+	fn from(level: &Level) -> Self {
+		match *level {
+			Level::TRACE => Self::Trace,
+			Level::DEBUG => Self::Debug,
+			Level::INFO => Self::Info,
+			Level::WARN => Self::Warn,
+			Level::ERROR => Self::Error,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct LogRecord {
+	pub timestamp: DateTime<Utc>,
+	pub level: LogLevel,
+	/// The module path (tracing target) the log was emitted from
+	pub module: String,
+	pub message: String,
+	pub library_id: Option<Uuid>,
+	pub job_id: Option<Uuid>,
+	pub location_id: Option<location::id::Type>,
+}
+
+#[derive(Debug, Default, Deserialize, Type)]
+pub struct LogFilter {
+	/// Only logs at this level or more severe are returned
+	pub level: Option<LogLevel>,
+	/// Only logs emitted from modules starting with this path are returned
+	pub module: Option<String>,
+	pub job_id: Option<Uuid>,
+	pub location_id: Option<location::id::Type>,
+	/// Also return logs that don't belong to any library, like the P2P ones
+	#[serde(default)]
+	pub include_node_logs: bool,
+	/// Maximum number of records to return, the most recent ones are kept
+	pub limit: Option<u32>,
+}
+
+impl LogFilter {
+	fn matches(&self, record: &LogRecord) -> bool {
+		self.level.map_or(true, |level| record.level >= level)
+			&& self
+				.module
+				.as_ref()
+				.map_or(true, |module| record.module.starts_with(module.as_str()))
+			&& self.job_id.map_or(true, |id| record.job_id == Some(id))
+			&& self
+				.location_id
+				.map_or(true, |id| record.location_id == Some(id))
+	}
+}
+
+/// Ring buffers of log records, keyed by library id. Logs emitted outside of a library
+/// (like the P2P ones) are kept under `None`.
+#[derive(Default)]
+pub struct LogBuffer(Mutex<HashMap<Option<Uuid>, VecDeque<LogRecord>>>);
+
+impl LogBuffer {
+	fn push(&self, record: LogRecord) {
+		let mut buffers = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+		let buffer = buffers.entry(record.library_id).or_default();
+
+		if buffer.len() == LOG_BUFFER_CAPACITY {
+			buffer.pop_front();
+		}
+		buffer.push_back(record);
+	}
+
+	/// Returns the logs of a library matching the filter, oldest first
+	pub fn query(&self, library_id: Uuid, filter: &LogFilter) -> Vec<LogRecord> {
+		let buffers = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+		let mut records = buffers
+			.get(&Some(library_id))
+			.into_iter()
+			.chain(
+				filter
+					.include_node_logs
+					.then(|| buffers.get(&None))
+					.flatten(),
+			)
+			.flatten()
+			.filter(|record| filter.matches(record))
+			.cloned()
+			.collect::<Vec<_>>();
+
+		records.sort_by_key(|record| record.timestamp);
+
+		if let Some(limit) = filter.limit {
+			let excess = records.len().saturating_sub(limit as usize);
+			records.drain(..excess);
+		}
+
+		records
+	}
+
+	/// Drops every log of a library, used when the library is deleted
+	pub fn remove_library(&self, library_id: Uuid) {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&Some(library_id));
+	}
+}
+
+/// Ids extracted from span and event fields, used to know who a log belongs to.
+/// Spans are expected to use `library_id`, `job_id` and `location_id` as field names.
+#[derive(Debug, Default, Clone, Copy)]
+struct LogContext {
+	library_id: Option<Uuid>,
+	job_id: Option<Uuid>,
+	location_id: Option<location::id::Type>,
+}
+
+impl LogContext {
+	fn record_value(&mut self, field: &Field, value: &str) {
+		match field.name() {
+			"library_id" => self.library_id = Uuid::parse_str(value).ok(),
+			"job_id" => self.job_id = Uuid::parse_str(value).ok(),
+			"location_id" => self.location_id = value.parse().ok(),
+			_ => {}
+		}
+	}
+
+	/// Fills the ids we don't know yet with the ones from a parent span
+	fn inherit(&mut self, parent: &Self) {
+		self.library_id = self.library_id.or(parent.library_id);
+		self.job_id = self.job_id.or(parent.job_id);
+		self.location_id = self.location_id.or(parent.location_id);
+	}
+}
+
+impl Visit for LogContext {
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.record_value(field, &value.to_string());
+	}
+
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.record_value(field, &value.to_string());
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.record_value(field, value);
+	}
+
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		self.record_value(field, &format!("{value:?}"));
+	}
+}
+
+#[derive(Default)]
+struct EventVisitor {
+	message: String,
+	context: LogContext,
+}
+
+impl Visit for EventVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		if field.name() == "message" {
+			let _ = write!(self.message, "{value:?}");
+		} else {
+			self.context.record_debug(field, value);
+			let _ = write!(self.message, " {}={value:?}", field.name());
+		}
+	}
+}
+
+/// Tracing subscriber that copies every relevant event into the [`LOG_BUFFER`]
+pub struct LogBufferSubscriber;
+
+impl LogBufferSubscriber {
+	/// Our own debug logs are useful for bug reports, but dependencies are only kept from info up
+	fn should_capture(metadata: &Metadata<'_>) -> bool {
+		let level = LogLevel::from(metadata.level());
+
+		level >= LogLevel::Info
+			|| (level == LogLevel::Debug && metadata.target().starts_with("sd_"))
+	}
+}
+
+impl<C> Subscribe<C> for LogBufferSubscriber
+where
+	C: Collect + for<'lookup> LookupSpan<'lookup>,
+{
+	fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, C>) {
+		let mut log_ctx = LogContext::default();
+		attrs.record(&mut log_ctx);
+
+		if let Some(span) = ctx.span(id) {
+			span.extensions_mut().insert(log_ctx);
+		}
+	}
+
+	fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, C>) {
+		if let Some(span) = ctx.span(id) {
+			if let Some(log_ctx) = span.extensions_mut().get_mut::<LogContext>() {
+				values.record(log_ctx);
+			}
+		}
+	}
+
+	fn on_event(&self, event: &Event<'_>, ctx: Context<'_, C>) {
+		let metadata = event.metadata();
+		if !Self::should_capture(metadata) {
+			return;
+		}
+
+		let mut visitor = EventVisitor::default();
+		event.record(&mut visitor);
+
+		// The scope goes from the innermost span to the root one
+		if let Some(scope) = ctx.event_scope(event) {
+			for span in scope {
+				if let Some(parent) = span.extensions().get::<LogContext>() {
+					visitor.context.inherit(parent);
+				}
+			}
+		}
+
+		LOG_BUFFER.push(LogRecord {
+			timestamp: Utc::now(),
+			level: metadata.level().into(),
+			module: metadata.target().to_string(),
+			message: visitor.message,
+			library_id: visitor.context.library_id,
+			job_id: visitor.context.job_id,
+			location_id: visitor.context.location_id,
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record(library_id: Option<Uuid>, level: LogLevel, module: &str) -> LogRecord {
+		LogRecord {
+			timestamp: Utc::now(),
+			level,
+			module: module.to_string(),
+			message: String::new(),
+			library_id,
+			job_id: None,
+			location_id: None,
+		}
+	}
+
+	#[test]
+	fn query_filters_and_limits_records() {
+		let buffer = LogBuffer::default();
+		let library_id = Uuid::new_v4();
+
+		buffer.push(record(Some(library_id), LogLevel::Debug, "sd_core::job"));
+		buffer.push(record(
+			Some(library_id),
+			LogLevel::Warn,
+			"sd_core::location",
+		));
+		buffer.push(record(Some(library_id), LogLevel::Error, "sd_core::job"));
+		buffer.push(record(None, LogLevel::Error, "sd_core::p2p"));
+		buffer.push(record(
+			Some(Uuid::new_v4()),
+			LogLevel::Error,
+			"sd_core::job",
+		));
+
+		let records = buffer.query(
+			library_id,
+			&LogFilter {
+				level: Some(LogLevel::Warn),
+				..Default::default()
+			},
+		);
+		assert_eq!(records.len(), 2);
+
+		let records = buffer.query(
+			library_id,
+			&LogFilter {
+				module: Some("sd_core::job".to_string()),
+				include_node_logs: true,
+				limit: Some(1),
+				..Default::default()
+			},
+		);
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].level, LogLevel::Error);
+
+		let records = buffer.query(
+			library_id,
+			&LogFilter {
+				include_node_logs: true,
+				..Default::default()
+			},
+		);
+		assert_eq!(records.len(), 4);
+	}
+
+	#[test]
+	fn old_records_are_dropped() {
+		let buffer = LogBuffer::default();
+		let library_id = Uuid::new_v4();
+
+		for _ in 0..LOG_BUFFER_CAPACITY + 10 {
+			buffer.push(record(Some(library_id), LogLevel::Info, "sd_core"));
+		}
+
+		assert_eq!(
+			buffer.query(library_id, &LogFilter::default()).len(),
+			LOG_BUFFER_CAPACITY
+		);
+	}
+}
@@ -0,0 +1,113 @@
+//! Windows limits paths to `MAX_PATH` (260) characters, unless they're written in the extended-length
+//! form, with a `\\?\` prefix (or `\\?\UNC\` for network shares). We access the filesystem with
+//! extended-length paths so deep trees can be indexed, and strip the prefix back whenever a path is
+//! compared against a location path or stored in the database. On other platforms these are no-ops.
+
+use std::{borrow::Cow, path::Path};
+
+const VERBATIM_PREFIX: &str = r"\\?\";
+const VERBATIM_UNC_PREFIX: &str = r"\\?\UNC\";
+const UNC_PREFIX: &str = r"\\";
+
+/// Converts an absolute path to its extended-length form, to be used on filesystem calls.
+/// The path must not have `.` or `..` components, as Windows doesn't resolve them on these paths.
+pub fn to_extended_length(path: &Path) -> Cow<'_, Path> {
+	#[cfg(target_os = "windows")]
+	if let Some(extended) = path.to_str().and_then(add_extended_length_prefix_str) {
+		return Cow::Owned(extended.into());
+	}
+
+	Cow::Borrowed(path)
+}
+
+/// Converts an extended-length path back to its usual form, so paths received with and without
+/// the prefix can be compared to each other
+pub fn strip_extended_length_prefix(path: &Path) -> Cow<'_, Path> {
+	#[cfg(target_os = "windows")]
+	if let Some(stripped) = path.to_str().and_then(strip_extended_length_prefix_str) {
+		return Cow::Owned(stripped.into());
+	}
+
+	Cow::Borrowed(path)
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn add_extended_length_prefix_str(path: &str) -> Option<String> {
+	if path.starts_with(VERBATIM_PREFIX) {
+		None
+	} else if let Some(share_path) = path.strip_prefix(UNC_PREFIX) {
+		Some(format!(
+			"{VERBATIM_UNC_PREFIX}{}",
+			share_path.replace('/', "\\")
+		))
+	} else if is_drive_absolute(path) {
+		Some(format!("{VERBATIM_PREFIX}{}", path.replace('/', "\\")))
+	} else {
+		// Relative paths can't be extended-length
+		None
+	}
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn strip_extended_length_prefix_str(path: &str) -> Option<String> {
+	if let Some(share_path) = path.strip_prefix(VERBATIM_UNC_PREFIX) {
+		Some(format!("{UNC_PREFIX}{share_path}"))
+	} else {
+		// Paths like `\\?\Volume{GUID}\` have no usual form, so we leave them alone
+		path.strip_prefix(VERBATIM_PREFIX)
+			.filter(|drive_path| is_drive_absolute(drive_path))
+			.map(str::to_string)
+	}
+}
+
+fn is_drive_absolute(path: &str) -> bool {
+	matches!(
+		path.as_bytes(),
+		[drive, b':', b'\\' | b'/', ..] if drive.is_ascii_alphabetic()
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn add_prefix() {
+		assert_eq!(
+			add_extended_length_prefix_str(r"C:\Users\spacedrive\dir").as_deref(),
+			Some(r"\\?\C:\Users\spacedrive\dir")
+		);
+		assert_eq!(
+			add_extended_length_prefix_str("D:/location/dir").as_deref(),
+			Some(r"\\?\D:\location\dir")
+		);
+		assert_eq!(
+			add_extended_length_prefix_str(r"\\server\share\location").as_deref(),
+			Some(r"\\?\UNC\server\share\location")
+		);
+		assert_eq!(
+			add_extended_length_prefix_str(r"\\?\C:\already\extended"),
+			None
+		);
+		assert_eq!(add_extended_length_prefix_str(r"relative\dir"), None);
+	}
+
+	#[test]
+	fn strip_prefix() {
+		assert_eq!(
+			strip_extended_length_prefix_str(r"\\?\C:\Users\spacedrive").as_deref(),
+			Some(r"C:\Users\spacedrive")
+		);
+		assert_eq!(
+			strip_extended_length_prefix_str(r"\\?\UNC\server\share\location").as_deref(),
+			Some(r"\\server\share\location")
+		);
+		assert_eq!(
+			strip_extended_length_prefix_str(
+				r"\\?\Volume{b75e2c83-0000-0000-0000-602f00000000}\dir"
+			),
+			None
+		);
+		assert_eq!(strip_extended_length_prefix_str(r"C:\Users"), None);
+	}
+}
@@ -0,0 +1,197 @@
+use std::sync::{
+	atomic::{AtomicU32, Ordering},
+	Arc,
+};
+
+use sysinfo::{System, SystemExt};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+/// Bounds for the automatically derived budget, used when the node config doesn't set one
+const MIN_AUTOMATIC_BUDGET: u64 = 64 * MIB;
+const MAX_AUTOMATIC_BUDGET: u64 = 1024 * MIB;
+/// Fraction of the total system memory used for the automatic budget
+const AUTOMATIC_BUDGET_DIVISOR: u64 = 8;
+
+/// `MemoryBudget` is a node wide pool of memory that IO heavy subsystems (like the file identifier
+/// and the thumbnailer) must borrow from before loading file buffers into memory.
+/// When the budget is exhausted, borrowers wait until some memory is given back, which
+/// effectively throttles their concurrency on low-RAM devices.
+///
+/// Internally the budget is a semaphore where each permit represents one KiB.
+pub struct MemoryBudget {
+	semaphore: Arc<Semaphore>,
+	capacity_kib: AtomicU32,
+	resized: Notify,
+}
+
+/// A chunk of memory borrowed from a [`MemoryBudget`], it's given back when dropped
+#[derive(Debug)]
+pub struct MemoryPermit(#[allow(dead_code)] OwnedSemaphorePermit);
+
+impl MemoryBudget {
+	/// Creates a new budget with the given capacity in MiB, or an automatic one
+	/// derived from the system memory if `None` is provided
+	pub fn new(capacity_mb: Option<u32>) -> Arc<Self> {
+		let capacity_kib = bytes_to_kib(capacity_from_config(capacity_mb));
+
+		debug!("Initializing memory budget with {capacity_kib} KiB");
+
+		Arc::new(Self {
+			semaphore: Arc::new(Semaphore::new(capacity_kib as usize)),
+			capacity_kib: AtomicU32::new(capacity_kib),
+			resized: Notify::new(),
+		})
+	}
+
+	/// Total capacity of this budget in bytes
+	pub fn capacity(&self) -> u64 {
+		self.capacity_kib.load(Ordering::Acquire) as u64 * KIB
+	}
+
+	/// Memory currently available to be borrowed in bytes
+	pub fn available(&self) -> u64 {
+		self.semaphore.available_permits() as u64 * KIB
+	}
+
+	/// Borrows `bytes` from the budget, waiting until enough memory is available.
+	/// Requests larger than the whole budget are clamped to its capacity, so a single big
+	/// file will wait until it has the whole budget for itself instead of waiting forever.
+	/// The clamp is taken again whenever the budget is resized while waiting.
+	pub async fn acquire(&self, bytes: u64) -> MemoryPermit {
+		let kib = bytes_to_kib(bytes);
+
+		loop {
+			// Registered before reading the capacity, so a resize in between can't be missed
+			let resized = self.resized.notified();
+			let clamped_kib = kib.min(self.capacity_kib.load(Ordering::Acquire));
+
+			tokio::select! {
+				permit = Arc::clone(&self.semaphore).acquire_many_owned(clamped_kib) => {
+					return MemoryPermit(permit.expect("semaphore is never closed"));
+				}
+				_ = resized => {}
+			}
+		}
+	}
+
+	/// Changes the capacity of the budget, memory already borrowed is not affected, but when
+	/// shrinking, new borrowers will wait until the budget fits the new capacity
+	pub async fn resize(&self, capacity_mb: Option<u32>) {
+		let new_capacity_kib = bytes_to_kib(capacity_from_config(capacity_mb));
+		let old_capacity_kib = self.capacity_kib.swap(new_capacity_kib, Ordering::AcqRel);
+
+		debug!("Resizing memory budget from {old_capacity_kib} KiB to {new_capacity_kib} KiB");
+
+		// Borrowers waiting for more than the new capacity must clamp their request again
+		self.resized.notify_waiters();
+
+		if new_capacity_kib > old_capacity_kib {
+			self.semaphore
+				.add_permits((new_capacity_kib - old_capacity_kib) as usize);
+		} else if new_capacity_kib < old_capacity_kib {
+			// We take the permits out of circulation for good
+			self.semaphore
+				.acquire_many(old_capacity_kib - new_capacity_kib)
+				.await
+				.expect("semaphore is never closed")
+				.forget();
+		}
+	}
+}
+
+fn capacity_from_config(capacity_mb: Option<u32>) -> u64 {
+	capacity_mb
+		.map(|mb| mb as u64 * MIB)
+		.unwrap_or_else(automatic_capacity)
+}
+
+fn automatic_capacity() -> u64 {
+	let mut system = System::new();
+	system.refresh_memory();
+
+	(system.total_memory() / AUTOMATIC_BUDGET_DIVISOR)
+		.clamp(MIN_AUTOMATIC_BUDGET, MAX_AUTOMATIC_BUDGET)
+}
+
+fn bytes_to_kib(bytes: u64) -> u32 {
+	// Rounding up, as a partial KiB still needs memory
+	u32::try_from(bytes.saturating_add(KIB - 1) / KIB).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn permits_are_returned_on_drop() {
+		let budget = MemoryBudget::new(Some(1));
+		assert_eq!(budget.capacity(), MIB);
+
+		let permit = budget.acquire(512 * KIB).await;
+		assert_eq!(budget.available(), 512 * KIB);
+
+		drop(permit);
+		assert_eq!(budget.available(), MIB);
+	}
+
+	#[tokio::test]
+	async fn oversized_requests_are_clamped_to_capacity() {
+		let budget = MemoryBudget::new(Some(1));
+
+		let permit = budget.acquire(10 * MIB).await;
+		assert_eq!(budget.available(), 0);
+
+		drop(permit);
+		assert_eq!(budget.available(), MIB);
+	}
+
+	#[tokio::test]
+	async fn resize_changes_available_memory() {
+		let budget = MemoryBudget::new(Some(2));
+
+		budget.resize(Some(1)).await;
+		assert_eq!(budget.capacity(), MIB);
+		assert_eq!(budget.available(), MIB);
+
+		budget.resize(Some(4)).await;
+		assert_eq!(budget.available(), 4 * MIB);
+	}
+
+	#[tokio::test]
+	async fn waiters_are_clamped_again_when_shrinking() {
+		let budget = MemoryBudget::new(Some(2));
+		let held = budget.acquire(2 * MIB).await;
+
+		let waiter = tokio::spawn({
+			let budget = Arc::clone(&budget);
+			async move { budget.acquire(2 * MIB).await }
+		});
+		tokio::task::yield_now().await;
+
+		let resize = tokio::spawn({
+			let budget = Arc::clone(&budget);
+			async move { budget.resize(Some(1)).await }
+		});
+		tokio::task::yield_now().await;
+
+		drop(held);
+
+		let timeout = std::time::Duration::from_secs(5);
+		tokio::time::timeout(timeout, resize)
+			.await
+			.expect("resize never finished")
+			.unwrap();
+		let permit = tokio::time::timeout(timeout, waiter)
+			.await
+			.expect("waiter never got its memory")
+			.unwrap();
+		assert_eq!(budget.available(), 0);
+
+		drop(permit);
+		assert_eq!(budget.available(), MIB);
+	}
+}
@@ -3,9 +3,14 @@ pub mod db;
 #[cfg(debug_assertions)]
 pub mod debug_initializer;
 pub mod error;
+pub mod log_buffer;
+pub mod long_path;
 mod maybe_undefined;
+pub mod memory_budget;
 pub mod migrator;
+pub mod rate_limit;
 pub mod version_manager;
+pub mod vfs;
 
 pub use abort_on_drop::*;
 pub use maybe_undefined::*;
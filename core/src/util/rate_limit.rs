@@ -0,0 +1,173 @@
+//! Per client limits for endpoints anything on the network can reach, so a single client can't
+//! saturate the disk or the bandwidth of the node.
+
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+/// Clients are only forgotten once the map grows past this, as long as they're idle
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+	/// Too many requests, with how long to wait until the next one is allowed
+	Requests { retry_after: Duration },
+	/// Too many streams at the same time
+	Streams,
+}
+
+#[derive(Debug)]
+struct ClientState {
+	/// Token bucket of requests, refilled continuously
+	tokens: f64,
+	last_refill: Instant,
+	streams: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct ClientLimiter(Mutex<HashMap<String, ClientState>>);
+
+impl ClientLimiter {
+	/// Counts a request of `client` against `requests_per_minute`
+	pub fn check_request(
+		&self,
+		client: &str,
+		requests_per_minute: u32,
+	) -> Result<(), LimitExceeded> {
+		let capacity = f64::from(requests_per_minute);
+		let per_second = capacity / 60.0;
+		let now = Instant::now();
+
+		let mut clients = self.0.lock().unwrap_or_else(|e| e.into_inner());
+		prune(&mut clients, now, per_second, capacity);
+
+		let state = clients
+			.entry(client.to_string())
+			.or_insert_with(|| ClientState {
+				tokens: capacity,
+				last_refill: now,
+				streams: 0,
+			});
+
+		state.tokens = (state.tokens
+			+ now.duration_since(state.last_refill).as_secs_f64() * per_second)
+			.min(capacity);
+		state.last_refill = now;
+
+		if state.tokens < 1.0 {
+			return Err(LimitExceeded::Requests {
+				retry_after: Duration::from_secs_f64(
+					(1.0 - state.tokens) / per_second.max(f64::EPSILON),
+				),
+			});
+		}
+
+		state.tokens -= 1.0;
+
+		Ok(())
+	}
+
+	/// Opens a stream for `client`, which stays open until the guard is dropped
+	pub fn open_stream<'a>(
+		&'a self,
+		client: &str,
+		max_concurrent_streams: u32,
+	) -> Result<StreamGuard<'a>, LimitExceeded> {
+		let now = Instant::now();
+
+		let mut clients = self.0.lock().unwrap_or_else(|e| e.into_inner());
+		// Clients only counted here have no requests to refill, so they're forgotten once idle
+		prune(&mut clients, now, 0.0, f64::MAX);
+
+		let state = clients
+			.entry(client.to_string())
+			.or_insert_with(|| ClientState {
+				tokens: f64::MAX,
+				last_refill: now,
+				streams: 0,
+			});
+
+		if state.streams >= max_concurrent_streams {
+			return Err(LimitExceeded::Streams);
+		}
+
+		state.streams += 1;
+
+		Ok(StreamGuard {
+			limiter: self,
+			client: client.to_string(),
+		})
+	}
+}
+
+fn prune(clients: &mut HashMap<String, ClientState>, now: Instant, per_second: f64, capacity: f64) {
+	if clients.len() < MAX_TRACKED_CLIENTS {
+		return;
+	}
+
+	clients.retain(|_, state| {
+		state.streams > 0
+			|| state.tokens + now.duration_since(state.last_refill).as_secs_f64() * per_second
+				< capacity
+	});
+}
+
+pub struct StreamGuard<'a> {
+	limiter: &'a ClientLimiter,
+	client: String,
+}
+
+impl Drop for StreamGuard<'_> {
+	fn drop(&mut self) {
+		let mut clients = self.limiter.0.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(state) = clients.get_mut(&self.client) {
+			state.streams = state.streams.saturating_sub(1);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn limits_requests_per_client() {
+		let limiter = ClientLimiter::default();
+
+		assert!(limiter.check_request("a", 2).is_ok());
+		assert!(limiter.check_request("a", 2).is_ok());
+		assert!(matches!(
+			limiter.check_request("a", 2),
+			Err(LimitExceeded::Requests { retry_after }) if retry_after <= Duration::from_secs(30)
+		));
+		assert!(limiter.check_request("b", 2).is_ok());
+	}
+
+	#[test]
+	fn limits_concurrent_streams() {
+		let limiter = ClientLimiter::default();
+
+		let first = limiter.open_stream("a", 1);
+		assert!(first.is_ok());
+		assert!(matches!(
+			limiter.open_stream("a", 1),
+			Err(LimitExceeded::Streams)
+		));
+
+		drop(first);
+		assert!(limiter.open_stream("a", 1).is_ok());
+	}
+
+	#[test]
+	fn forgets_idle_streaming_clients() {
+		let limiter = ClientLimiter::default();
+
+		for client in 0..MAX_TRACKED_CLIENTS * 2 {
+			drop(limiter.open_stream(&client.to_string(), 1));
+		}
+
+		assert!(limiter.0.lock().unwrap_or_else(|e| e.into_inner()).len() <= MAX_TRACKED_CLIENTS);
+	}
+}
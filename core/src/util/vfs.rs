@@ -0,0 +1,604 @@
+//! Filesystem access behind a trait, so the indexer can walk an in-memory tree in tests instead of
+//! the disk, with the same inodes, sizes and dates on every run.
+//!
+//! [`LocalFs`] is what the jobs use, [`MemoryFs`] is the in-memory tree. `MemoryFs` also records
+//! its changes as [`VfsEvent`]s, which convert to the events the location watcher receives, so
+//! watcher code can be fed the same sequences a real filesystem would produce.
+//!
+//! Only the indexer goes through here for now, the file operation jobs still use `tokio::fs`.
+
+use crate::location::file_path_helper::{get_allocated_size, FilePathMetadata, MetadataExt};
+
+#[cfg(target_family = "unix")]
+use crate::location::file_path_helper::get_inode_and_device;
+
+#[cfg(target_family = "windows")]
+use crate::location::file_path_helper::get_inode_and_device_from_path;
+
+use std::{
+	collections::BTreeMap,
+	io,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use tokio::fs;
+
+#[derive(Debug, Clone)]
+pub struct VfsMetadata {
+	pub is_dir: bool,
+	pub is_symlink: bool,
+	pub file_path: FilePathMetadata,
+}
+
+#[async_trait]
+pub trait Filesystem: Send + Sync {
+	/// Metadata of `path`, following symlinks
+	async fn metadata(&self, path: &Path) -> io::Result<VfsMetadata>;
+
+	/// Metadata of `path` itself, even if it's a symlink
+	async fn symlink_metadata(&self, path: &Path) -> io::Result<VfsMetadata>;
+
+	/// Paths of the entries of a directory, an entry which couldn't be read doesn't fail the others
+	async fn read_dir(&self, path: &Path) -> io::Result<Vec<io::Result<PathBuf>>>;
+
+	async fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+	async fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+	async fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+	async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+	async fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+	async fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The filesystem of this device
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFs;
+
+impl LocalFs {
+	async fn convert(path: &Path, metadata: std::fs::Metadata) -> io::Result<VfsMetadata> {
+		let (inode, device) = {
+			#[cfg(target_family = "unix")]
+			{
+				get_inode_and_device(&metadata)
+			}
+
+			#[cfg(target_family = "windows")]
+			{
+				get_inode_and_device_from_path(path).await
+			}
+		}
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+		Ok(VfsMetadata {
+			is_dir: metadata.is_dir(),
+			is_symlink: metadata.is_symlink(),
+			file_path: FilePathMetadata {
+				inode,
+				device,
+				size_in_bytes: metadata.len(),
+				allocated_size_in_bytes: get_allocated_size(path, &metadata),
+				created_at: metadata.created_or_now().into(),
+				modified_at: metadata.modified_or_now().into(),
+			},
+		})
+	}
+}
+
+#[async_trait]
+impl Filesystem for LocalFs {
+	async fn metadata(&self, path: &Path) -> io::Result<VfsMetadata> {
+		Self::convert(path, fs::metadata(path).await?).await
+	}
+
+	async fn symlink_metadata(&self, path: &Path) -> io::Result<VfsMetadata> {
+		Self::convert(path, fs::symlink_metadata(path).await?).await
+	}
+
+	async fn read_dir(&self, path: &Path) -> io::Result<Vec<io::Result<PathBuf>>> {
+		let mut read_dir = fs::read_dir(path).await?;
+		let mut entries = vec![];
+
+		loop {
+			match read_dir.next_entry().await {
+				Ok(Some(entry)) => entries.push(Ok(entry.path())),
+				Ok(None) => break,
+				Err(e) => {
+					entries.push(Err(e));
+					// The same error would come back over and over
+					break;
+				}
+			}
+		}
+
+		Ok(entries)
+	}
+
+	async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+		fs::read(path).await
+	}
+
+	async fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+		fs::write(path, contents).await
+	}
+
+	async fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+		fs::create_dir_all(path).await
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		fs::rename(from, to).await
+	}
+
+	async fn remove_file(&self, path: &Path) -> io::Result<()> {
+		fs::remove_file(path).await
+	}
+
+	async fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+		fs::remove_dir_all(path).await
+	}
+}
+
+/// A change made to a [`MemoryFs`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VfsEvent {
+	Created { path: PathBuf, is_dir: bool },
+	Modified { path: PathBuf },
+	Renamed { from: PathBuf, to: PathBuf },
+	Removed { path: PathBuf, is_dir: bool },
+}
+
+#[cfg(feature = "location-watcher")]
+impl From<VfsEvent> for notify::Event {
+	fn from(event: VfsEvent) -> Self {
+		use notify::{
+			event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode},
+			Event, EventKind,
+		};
+
+		match event {
+			VfsEvent::Created { path, is_dir } => Event::new(EventKind::Create(if is_dir {
+				CreateKind::Folder
+			} else {
+				CreateKind::File
+			}))
+			.add_path(path),
+			VfsEvent::Modified { path } => {
+				Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content))).add_path(path)
+			}
+			VfsEvent::Renamed { from, to } => {
+				Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+					.add_path(from)
+					.add_path(to)
+			}
+			VfsEvent::Removed { path, is_dir } => Event::new(EventKind::Remove(if is_dir {
+				RemoveKind::Folder
+			} else {
+				RemoveKind::File
+			}))
+			.add_path(path),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+enum MemoryNode {
+	Dir,
+	File(Vec<u8>),
+	Symlink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+struct MemoryEntry {
+	node: MemoryNode,
+	inode: u64,
+	created_at: DateTime<Utc>,
+	modified_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct MemoryState {
+	entries: BTreeMap<PathBuf, MemoryEntry>,
+	next_inode: u64,
+	/// Every change ticks the clock by a second, so dates only depend on the order of the changes
+	clock: DateTime<Utc>,
+	events: Vec<VfsEvent>,
+}
+
+impl MemoryState {
+	fn tick(&mut self) -> (u64, DateTime<Utc>) {
+		self.next_inode += 1;
+		self.clock = self.clock + Duration::seconds(1);
+
+		(self.next_inode, self.clock)
+	}
+
+	fn get(&self, path: &Path) -> io::Result<&MemoryEntry> {
+		self.entries.get(path).ok_or_else(|| not_found(path))
+	}
+
+	fn ensure_parent_dir(&self, path: &Path) -> io::Result<()> {
+		match path.parent() {
+			None => Ok(()),
+			Some(parent) => match self.get(parent)?.node {
+				MemoryNode::Dir => Ok(()),
+				_ => Err(io::Error::new(
+					io::ErrorKind::Other,
+					format!("not a directory: {}", parent.display()),
+				)),
+			},
+		}
+	}
+
+	fn insert(&mut self, path: &Path, node: MemoryNode) {
+		let is_dir = matches!(node, MemoryNode::Dir);
+		let (inode, now) = self.tick();
+
+		self.entries.insert(
+			path.to_path_buf(),
+			MemoryEntry {
+				node,
+				inode,
+				created_at: now,
+				modified_at: now,
+			},
+		);
+		self.events.push(VfsEvent::Created {
+			path: path.to_path_buf(),
+			is_dir,
+		});
+	}
+
+	/// Follows symlinks, returning the path they lead to with its entry
+	fn resolve<'a>(&'a self, path: &Path) -> io::Result<(&'a Path, &'a MemoryEntry)> {
+		let (mut path, mut entry) = self
+			.entries
+			.get_key_value(path)
+			.ok_or_else(|| not_found(path))?;
+
+		// Bounded, so symlink loops fail instead of hanging
+		for _ in 0..40 {
+			match &entry.node {
+				MemoryNode::Symlink(target) => {
+					(path, entry) = self
+						.entries
+						.get_key_value(target.as_path())
+						.ok_or_else(|| not_found(target))?;
+				}
+				_ => return Ok((path, entry)),
+			}
+		}
+
+		Err(io::Error::new(
+			io::ErrorKind::Other,
+			format!("too many levels of symbolic links: {}", path.display()),
+		))
+	}
+}
+
+/// An in-memory filesystem, with deterministic inodes and dates
+#[derive(Debug)]
+pub struct MemoryFs(Mutex<MemoryState>);
+
+/// The device of every entry of a [`MemoryFs`]
+pub const MEMORY_FS_DEVICE: u64 = 1;
+
+impl Default for MemoryFs {
+	fn default() -> Self {
+		let clock = Utc
+			.timestamp_opt(1_600_000_000, 0)
+			.single()
+			.expect("valid timestamp");
+
+		let mut state = MemoryState {
+			entries: BTreeMap::new(),
+			next_inode: 0,
+			clock,
+			events: vec![],
+		};
+		state.insert(Path::new("/"), MemoryNode::Dir);
+		state.events.clear();
+
+		Self(Mutex::new(state))
+	}
+}
+
+impl MemoryFs {
+	fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Creates a file, and its missing parent directories
+	pub fn add_file(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> &Self {
+		let path = path.as_ref();
+		let mut state = self.state();
+
+		add_missing_dirs(&mut state, path.parent());
+		state.insert(path, MemoryNode::File(contents.into()));
+
+		self
+	}
+
+	/// Creates a directory, and its missing parents
+	pub fn add_dir(&self, path: impl AsRef<Path>) -> &Self {
+		add_missing_dirs(&mut self.state(), Some(path.as_ref()));
+
+		self
+	}
+
+	pub fn add_symlink(&self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> &Self {
+		let path = path.as_ref();
+		let mut state = self.state();
+
+		add_missing_dirs(&mut state, path.parent());
+		state.insert(path, MemoryNode::Symlink(target.as_ref().to_path_buf()));
+
+		self
+	}
+
+	/// The changes made since the last call, oldest first
+	pub fn take_events(&self) -> Vec<VfsEvent> {
+		std::mem::take(&mut self.state().events)
+	}
+}
+
+fn add_missing_dirs(state: &mut MemoryState, path: Option<&Path>) {
+	let Some(path) = path else {
+		return;
+	};
+
+	let mut missing = path
+		.ancestors()
+		.take_while(|ancestor| !state.entries.contains_key(*ancestor))
+		.map(Path::to_path_buf)
+		.collect::<Vec<_>>();
+
+	while let Some(dir) = missing.pop() {
+		state.insert(&dir, MemoryNode::Dir);
+	}
+}
+
+fn not_found(path: &Path) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::NotFound,
+		format!("no such file or directory: {}", path.display()),
+	)
+}
+
+fn memory_metadata(entry: &MemoryEntry) -> VfsMetadata {
+	let size = match &entry.node {
+		MemoryNode::File(contents) => contents.len() as u64,
+		MemoryNode::Dir | MemoryNode::Symlink(_) => 0,
+	};
+
+	VfsMetadata {
+		is_dir: matches!(entry.node, MemoryNode::Dir),
+		is_symlink: matches!(entry.node, MemoryNode::Symlink(_)),
+		file_path: FilePathMetadata {
+			inode: entry.inode,
+			device: MEMORY_FS_DEVICE,
+			size_in_bytes: size,
+			allocated_size_in_bytes: size,
+			created_at: entry.created_at,
+			modified_at: entry.modified_at,
+		},
+	}
+}
+
+#[async_trait]
+impl Filesystem for MemoryFs {
+	async fn metadata(&self, path: &Path) -> io::Result<VfsMetadata> {
+		self.state()
+			.resolve(path)
+			.map(|(_, entry)| memory_metadata(entry))
+	}
+
+	async fn symlink_metadata(&self, path: &Path) -> io::Result<VfsMetadata> {
+		self.state().get(path).map(memory_metadata)
+	}
+
+	async fn read_dir(&self, path: &Path) -> io::Result<Vec<io::Result<PathBuf>>> {
+		let state = self.state();
+		let (dir, entry) = state.resolve(path)?;
+		if !matches!(entry.node, MemoryNode::Dir) {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				format!("not a directory: {}", path.display()),
+			));
+		}
+
+		// Listed under the path they were asked with, like a real filesystem does for symlinks
+		Ok(state
+			.entries
+			.keys()
+			.filter(|entry_path| entry_path.parent() == Some(dir))
+			.map(|entry_path| Ok(path.join(entry_path.strip_prefix(dir).expect("child of dir"))))
+			.collect())
+	}
+
+	async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+		match &self.state().resolve(path)?.1.node {
+			MemoryNode::File(contents) => Ok(contents.clone()),
+			_ => Err(io::Error::new(
+				io::ErrorKind::Other,
+				format!("is a directory: {}", path.display()),
+			)),
+		}
+	}
+
+	async fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+		let mut state = self.state();
+		state.ensure_parent_dir(path)?;
+
+		match state.entries.get(path).map(|entry| &entry.node) {
+			Some(MemoryNode::File(_)) => {
+				let (_, now) = state.tick();
+				let entry = state.entries.get_mut(path).expect("checked above");
+				entry.node = MemoryNode::File(contents.to_vec());
+				entry.modified_at = now;
+				state.events.push(VfsEvent::Modified {
+					path: path.to_path_buf(),
+				});
+			}
+			Some(_) => {
+				return Err(io::Error::new(
+					io::ErrorKind::Other,
+					format!("is a directory: {}", path.display()),
+				))
+			}
+			None => state.insert(path, MemoryNode::File(contents.to_vec())),
+		}
+
+		Ok(())
+	}
+
+	async fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+		let mut state = self.state();
+		if let Some(entry) = state.entries.get(path) {
+			return match entry.node {
+				MemoryNode::Dir => Ok(()),
+				_ => Err(io::Error::new(
+					io::ErrorKind::AlreadyExists,
+					format!("file exists: {}", path.display()),
+				)),
+			};
+		}
+
+		add_missing_dirs(&mut state, Some(path));
+
+		Ok(())
+	}
+
+	async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		let mut state = self.state();
+		state.get(from)?;
+		state.ensure_parent_dir(to)?;
+
+		// Moving a directory moves everything inside of it too
+		let moved = state
+			.entries
+			.keys()
+			.filter(|path| path.starts_with(from))
+			.cloned()
+			.collect::<Vec<_>>();
+
+		for old_path in moved {
+			let entry = state.entries.remove(&old_path).expect("listed above");
+			let new_path = to.join(old_path.strip_prefix(from).expect("filtered above"));
+			state.entries.insert(new_path, entry);
+		}
+
+		state.events.push(VfsEvent::Renamed {
+			from: from.to_path_buf(),
+			to: to.to_path_buf(),
+		});
+
+		Ok(())
+	}
+
+	async fn remove_file(&self, path: &Path) -> io::Result<()> {
+		let mut state = self.state();
+		if matches!(state.get(path)?.node, MemoryNode::Dir) {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				format!("is a directory: {}", path.display()),
+			));
+		}
+
+		state.entries.remove(path);
+		state.events.push(VfsEvent::Removed {
+			path: path.to_path_buf(),
+			is_dir: false,
+		});
+
+		Ok(())
+	}
+
+	async fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+		let mut state = self.state();
+		state.get(path)?;
+
+		state
+			.entries
+			.retain(|entry_path, _| !entry_path.starts_with(path));
+		state.events.push(VfsEvent::Removed {
+			path: path.to_path_buf(),
+			is_dir: true,
+		});
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn memory_fs_is_deterministic() {
+		let build = || {
+			let fs = MemoryFs::default();
+			fs.add_file("/photos/2023/beach.jpg", "jpg")
+				.add_symlink("/photos/latest", "/photos/2023");
+			fs
+		};
+
+		let (first, second) = (build(), build());
+		let path = Path::new("/photos/2023/beach.jpg");
+		let (first_meta, second_meta) = (
+			first.metadata(path).await.unwrap(),
+			second.metadata(path).await.unwrap(),
+		);
+		assert_eq!(first_meta.file_path.inode, second_meta.file_path.inode);
+		assert_eq!(
+			first_meta.file_path.created_at,
+			second_meta.file_path.created_at
+		);
+		assert_eq!(first_meta.file_path.size_in_bytes, 3);
+
+		assert!(
+			first
+				.metadata(Path::new("/photos/latest"))
+				.await
+				.unwrap()
+				.is_dir
+		);
+		assert!(
+			first
+				.symlink_metadata(Path::new("/photos/latest"))
+				.await
+				.unwrap()
+				.is_symlink
+		);
+
+		first
+			.rename(Path::new("/photos/2023"), Path::new("/photos/old"))
+			.await
+			.unwrap();
+		assert!(first.metadata(path).await.is_err());
+		assert_eq!(
+			first
+				.read(Path::new("/photos/old/beach.jpg"))
+				.await
+				.unwrap(),
+			b"jpg"
+		);
+
+		let events = first.take_events();
+		assert_eq!(
+			events.last(),
+			Some(&VfsEvent::Renamed {
+				from: "/photos/2023".into(),
+				to: "/photos/old".into(),
+			})
+		);
+		assert!(first.take_events().is_empty());
+	}
+}
@@ -0,0 +1,309 @@
+//! What the filesystem of a volume can do, so file operations can pick how to go about it instead
+//! of finding out halfway through a job.
+//!
+//! Capabilities are probed by writing a few throwaway files in a directory of the volume, and kept
+//! for as long as the node runs as they don't change while the volume stays mounted. When nothing
+//! can be written there, we fall back on what the filesystem usually supports, going by its name.
+//! Cloning can't be tried without calling into each platform, so reflink support always comes
+//! from the filesystem name.
+
+#[cfg(not(target_os = "windows"))]
+use crate::object::fs::sparse::is_sparse;
+
+use std::{
+	collections::HashMap,
+	fs, io,
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::task::spawn_blocking;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::{backing_mount_point, get_volumes, Volume, VolumeError};
+
+/// Names longer than this are refused by most platforms whatever the filesystem allows
+const MAX_PROBED_NAME_LENGTH: u32 = 255;
+/// Large enough to span many blocks on any filesystem, so a hole is never allocated
+#[cfg(not(target_os = "windows"))]
+const SPARSE_PROBE_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// Probed capabilities by mount point
+static PROBED: Lazy<Mutex<HashMap<String, FsCapabilities>>> = Lazy::new(Default::default);
+
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct FsCapabilities {
+	pub file_system: Option<String>,
+	/// `photo.jpg` and `Photo.jpg` are different files
+	pub case_sensitive: bool,
+	/// Copies can share the blocks of the original until either is changed
+	pub reflink: bool,
+	/// Extended attributes can be set on files
+	pub xattr: bool,
+	/// In bytes
+	pub max_name_length: u32,
+	/// Zeroed ranges of files can be left unallocated
+	pub sparse_files: bool,
+	/// `false` when the volume couldn't be written to, and these are the usual capabilities of
+	/// its filesystem
+	pub probed: bool,
+}
+
+/// A volume and what its filesystem can do
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct VolumeCapabilities {
+	pub mount_point: String,
+	pub capabilities: FsCapabilities,
+}
+
+#[derive(Deserialize, Type, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeCapabilitiesArgs {
+	pub mount_point: String,
+}
+
+/// The capabilities of a volume of this node, probed at its mount point
+pub async fn volume_capabilities(
+	VolumeCapabilitiesArgs { mount_point }: VolumeCapabilitiesArgs,
+) -> Result<FsCapabilities, VolumeError> {
+	let volume = list_volumes()
+		.await?
+		.into_iter()
+		.find(|volume| volume.mount_point == mount_point)
+		.ok_or(VolumeError::NotFound(mount_point))?;
+
+	Ok(capabilities(&volume, PathBuf::from(&volume.mount_point)).await)
+}
+
+/// The volumes holding these paths, `None` for the paths on no volume we know of
+pub async fn volumes_of(paths: &[&Path]) -> Vec<Option<VolumeCapabilities>> {
+	let volumes = match list_volumes().await {
+		Ok(volumes) => volumes,
+		Err(e) => {
+			warn!("Failed to list the volumes to find their capabilities: {e:#?}");
+			vec![]
+		}
+	};
+
+	let mut found = Vec::with_capacity(paths.len());
+	for path in paths {
+		let volume = backing_mount_point(
+			path,
+			volumes.iter().map(|volume| volume.mount_point.as_str()),
+		)
+		.and_then(|mount_point| {
+			volumes
+				.iter()
+				.find(|volume| volume.mount_point == mount_point)
+		});
+
+		found.push(match volume {
+			Some(volume) => Some(VolumeCapabilities {
+				mount_point: volume.mount_point.clone(),
+				// Jobs point at directories of locations, where we're much more likely to be
+				// allowed to write than at the root of the volume
+				capabilities: capabilities(volume, probe_directory(path)).await,
+			}),
+			None => None,
+		});
+	}
+
+	found
+}
+
+async fn list_volumes() -> Result<Vec<Volume>, VolumeError> {
+	spawn_blocking(get_volumes).await?
+}
+
+/// The closest existing directory to a path, which may not have been created yet
+fn probe_directory(path: &Path) -> PathBuf {
+	path.ancestors()
+		.find(|ancestor| ancestor.is_dir())
+		.unwrap_or(path)
+		.to_path_buf()
+}
+
+async fn capabilities(volume: &Volume, directory: PathBuf) -> FsCapabilities {
+	if let Some(capabilities) = PROBED
+		.lock()
+		.unwrap_or_else(|e| e.into_inner())
+		.get(&volume.mount_point)
+	{
+		return capabilities.clone();
+	}
+
+	let file_system = volume.file_system.clone();
+	let probed = spawn_blocking({
+		let file_system = file_system.clone();
+		let directory = directory.clone();
+		move || probe(&directory, file_system)
+	})
+	.await;
+
+	match probed {
+		Ok(Ok(capabilities)) => {
+			PROBED
+				.lock()
+				.unwrap_or_else(|e| e.into_inner())
+				.insert(volume.mount_point.clone(), capabilities.clone());
+
+			capabilities
+		}
+		Ok(Err(e)) => {
+			debug!(
+				"Failed to probe the capabilities of volume '{}' in {}: {e}",
+				volume.mount_point,
+				directory.display()
+			);
+			known_capabilities(file_system)
+		}
+		Err(e) => {
+			warn!(
+				"Failed to join the probe of volume '{}': {e:#?}",
+				volume.mount_point
+			);
+			known_capabilities(file_system)
+		}
+	}
+}
+
+fn probe(directory: &Path, file_system: Option<String>) -> io::Result<FsCapabilities> {
+	let probe_dir = directory.join(format!(".sd-fs-probe-{}", Uuid::new_v4()));
+	fs::create_dir(&probe_dir)?;
+
+	let probed = probe_in(&probe_dir, file_system);
+
+	if let Err(e) = fs::remove_dir_all(&probe_dir) {
+		warn!(
+			"Failed to remove filesystem probe directory {}: {e}",
+			probe_dir.display()
+		);
+	}
+
+	probed
+}
+
+fn probe_in(probe_dir: &Path, file_system: Option<String>) -> io::Result<FsCapabilities> {
+	let known = known_capabilities(file_system);
+
+	let file = probe_dir.join("probe");
+	fs::File::create(&file)?;
+
+	let case_sensitive = !probe_dir.join("PROBE").exists();
+
+	#[cfg(any(target_os = "linux", target_os = "macos"))]
+	let xattr = xattr::set(&file, "user.spacedrive.probe", b"1").is_ok();
+	#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+	let xattr = known.xattr;
+
+	// Windows only tells a file is sparse once it's flagged so, which plain files never are
+	#[cfg(not(target_os = "windows"))]
+	let sparse_files = {
+		let sparse = probe_dir.join("sparse");
+		fs::File::create(&sparse)?.set_len(SPARSE_PROBE_LENGTH)?;
+		is_sparse(&sparse, &fs::metadata(&sparse)?)
+	};
+	#[cfg(target_os = "windows")]
+	let sparse_files = known.sparse_files;
+
+	Ok(FsCapabilities {
+		case_sensitive,
+		xattr,
+		max_name_length: probe_max_name_length(probe_dir),
+		sparse_files,
+		probed: true,
+		..known
+	})
+}
+
+/// Binary searches the longest name a file can be created with
+fn probe_max_name_length(probe_dir: &Path) -> u32 {
+	let fits = |length: u32| {
+		let path = probe_dir.join("n".repeat(length as usize));
+		let created = fs::File::create(&path).is_ok();
+		if created {
+			fs::remove_file(&path).ok();
+		}
+		created
+	};
+
+	if fits(MAX_PROBED_NAME_LENGTH) {
+		return MAX_PROBED_NAME_LENGTH;
+	}
+
+	let (mut fitting, mut too_long) = (1, MAX_PROBED_NAME_LENGTH);
+	while too_long - fitting > 1 {
+		let length = fitting + (too_long - fitting) / 2;
+		if fits(length) {
+			fitting = length;
+		} else {
+			too_long = length;
+		}
+	}
+
+	fitting
+}
+
+/// The defaults of the filesystem, which a volume may still have been formatted without
+fn known_capabilities(file_system: Option<String>) -> FsCapabilities {
+	let name = file_system.as_deref().unwrap_or_default().to_lowercase();
+
+	let (case_sensitive, reflink, xattr, sparse_files) = match name.as_str() {
+		"apfs" => (false, true, true, true),
+		"hfs" | "hfs+" | "hfsplus" => (false, false, true, false),
+		"btrfs" | "xfs" | "bcachefs" => (true, true, true, true),
+		"ext2" | "ext3" | "ext4" | "zfs" | "f2fs" | "tmpfs" => (true, false, true, true),
+		"refs" => (false, true, false, true),
+		"ntfs" | "ntfs3" | "fuseblk" => (false, false, false, true),
+		"vfat" | "fat" | "fat16" | "fat32" | "msdos" | "exfat" => (false, false, false, false),
+		_ => (
+			cfg!(not(any(target_os = "windows", target_os = "macos"))),
+			false,
+			false,
+			false,
+		),
+	};
+
+	FsCapabilities {
+		file_system,
+		case_sensitive,
+		reflink,
+		xattr,
+		max_name_length: MAX_PROBED_NAME_LENGTH,
+		sparse_files,
+		probed: false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use tempfile::tempdir;
+
+	#[test]
+	fn probes_leave_nothing_behind() {
+		let dir = tempdir().unwrap();
+
+		let capabilities = probe(dir.path(), Some("ext4".to_string())).unwrap();
+		assert!(capabilities.probed);
+		assert!(capabilities.max_name_length > 0);
+		assert!(capabilities.max_name_length <= MAX_PROBED_NAME_LENGTH);
+
+		assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn unknown_filesystems_support_little() {
+		let capabilities = known_capabilities(Some("weirdfs".to_string()));
+		assert!(!capabilities.reflink);
+		assert!(!capabilities.sparse_files);
+		assert!(!capabilities.probed);
+
+		assert!(known_capabilities(Some("APFS".to_string())).reflink);
+	}
+}
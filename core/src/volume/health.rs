@@ -0,0 +1,347 @@
+//! Health of the drives backing locations, from their SMART attributes or NVMe health log. Bit rot
+//! found by the object validator is much more telling next to a drive reallocating sectors.
+//!
+//! The data is read with `smartctl` from smartmontools, which must be installed and usually needs
+//! elevated permissions to talk to the drive. When it can't be read the volume simply has no health
+//! history. Only Linux is supported for now, where the volumes are named after their device.
+
+use crate::{
+	api::CoreEvent,
+	library::Library,
+	node::StorageAlertThresholds,
+	prisma::{location, volume, volume_health, PrismaClient, SortOrder},
+};
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::{Volume, VolumeError, SAMPLES_KEPT_DAYS};
+
+/// What's read from a drive, `None` where the drive doesn't report it
+#[derive(Serialize, Type, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriveHealth {
+	/// The whole disk, not the partition the volume is on
+	pub device: String,
+	/// The drive's own overall assessment
+	pub passed: Option<bool>,
+	pub temperature_celsius: Option<i32>,
+	/// SMART attribute 5 of ATA drives
+	pub reallocated_sectors: Option<i32>,
+	/// SMART attribute 197 of ATA drives
+	pub pending_sectors: Option<i32>,
+	/// From the health log of NVMe drives
+	pub media_errors: Option<i32>,
+	/// Estimate of the endurance used by NVMe drives, can go over 100
+	pub percentage_used: Option<i32>,
+	pub power_on_hours: Option<i32>,
+}
+
+impl DriveHealth {
+	/// Sectors the drive gave up on or is about to
+	fn bad_sectors(&self) -> Option<i32> {
+		[
+			self.reallocated_sectors,
+			self.pending_sectors,
+			self.media_errors,
+		]
+		.into_iter()
+		.flatten()
+		.reduce(|total, count| total + count)
+	}
+}
+
+#[derive(Serialize, Type, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DriveHealthAlertKind {
+	/// The drive's overall assessment failed, it's likely to die soon
+	FailedAssessment,
+	/// More sectors were reallocated, are pending or had media errors since the last reading
+	BadSectors,
+	/// Hotter than `StorageAlertThresholds::max_temperature_celsius`
+	HighTemperature,
+}
+
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct DriveHealthAlert {
+	pub kind: DriveHealthAlertKind,
+	pub library_id: Uuid,
+	pub volume_name: String,
+	pub mount_point: String,
+	/// The locations of the library on the volume
+	pub location_ids: Vec<location::id::Type>,
+	pub health: DriveHealth,
+}
+
+/// Health of the drives behind these volumes, by mount point
+pub(super) async fn read_drives_health(volumes: &[Volume]) -> HashMap<String, DriveHealth> {
+	let mut by_device = HashMap::<String, Option<DriveHealth>>::new();
+	let mut drives = HashMap::new();
+
+	for volume in volumes {
+		let Some(device) = whole_disk(&volume.name) else {
+			continue;
+		};
+
+		// Partitions of a single drive share its reading
+		if !by_device.contains_key(&device) {
+			let health = read_drive_health(&device).await;
+			by_device.insert(device.clone(), health);
+		}
+
+		if let Some(Some(health)) = by_device.get(&device) {
+			drives.insert(volume.mount_point.clone(), health.clone());
+		}
+	}
+
+	drives
+}
+
+async fn read_drive_health(device: &str) -> Option<DriveHealth> {
+	// The exit status is a bit mask which also flags failing drives, the output tells if the
+	// drive could be read at all
+	let output = tokio::process::Command::new("smartctl")
+		.args(["--json=c", "--all", device])
+		.output()
+		.await
+		.map_err(|e| debug!("Failed to run smartctl for '{device}': {e}"))
+		.ok()?;
+
+	let health = parse_smartctl(device, &serde_json::from_slice(&output.stdout).ok()?);
+	if health.is_none() {
+		debug!("No health data for '{device}', smartctl may lack permissions");
+	}
+
+	health
+}
+
+fn parse_smartctl(device: &str, report: &Value) -> Option<DriveHealth> {
+	let int = |value: &Value| value.as_i64().and_then(|value| i32::try_from(value).ok());
+	let ata_attribute = |id: i64| {
+		report["ata_smart_attributes"]["table"]
+			.as_array()?
+			.iter()
+			.find(|attribute| attribute["id"].as_i64() == Some(id))
+			.and_then(|attribute| int(&attribute["raw"]["value"]))
+	};
+	let nvme_log = &report["nvme_smart_health_information_log"];
+
+	let health = DriveHealth {
+		device: device.to_string(),
+		passed: report["smart_status"]["passed"].as_bool(),
+		temperature_celsius: int(&report["temperature"]["current"]),
+		reallocated_sectors: ata_attribute(5),
+		pending_sectors: ata_attribute(197),
+		media_errors: int(&nvme_log["media_errors"]),
+		percentage_used: int(&nvme_log["percentage_used"]),
+		power_on_hours: int(&report["power_on_time"]["hours"]),
+	};
+
+	(health.passed.is_some() || health.temperature_celsius.is_some()).then_some(health)
+}
+
+/// The disk holding a partition, `/dev/sda` for `/dev/sda1` and `/dev/nvme0n1` for
+/// `/dev/nvme0n1p1`. `None` for volumes which aren't named after their device.
+#[cfg(target_os = "linux")]
+fn whole_disk(device: &str) -> Option<String> {
+	let name = device.strip_prefix("/dev/")?;
+
+	// NVMe and MMC disk names end with a digit already, their partitions get a `p` before their
+	// number
+	let name = if name.starts_with("nvme") || name.starts_with("mmcblk") {
+		name.rsplit_once('p')
+			.filter(|(disk, partition)| {
+				disk.ends_with(|c: char| c.is_ascii_digit())
+					&& !partition.is_empty()
+					&& partition.chars().all(|c| c.is_ascii_digit())
+			})
+			.map_or(name, |(disk, _)| disk)
+	} else {
+		name.trim_end_matches(|c: char| c.is_ascii_digit())
+	};
+
+	(!name.is_empty()).then(|| format!("/dev/{name}"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn whole_disk(_: &str) -> Option<String> {
+	None
+}
+
+/// Stores the reading of the drive behind a volume and raises the alerts it calls for
+pub(super) async fn record_health(
+	library: &Library,
+	volume: &volume::Data,
+	location_ids: &[location::id::Type],
+	health: &DriveHealth,
+	thresholds: StorageAlertThresholds,
+	raised: &mut HashSet<(Uuid, volume::id::Type, DriveHealthAlertKind)>,
+) -> Result<(), VolumeError> {
+	let db = &library.db;
+
+	let previous = db
+		.volume_health()
+		.find_first(vec![volume_health::volume_id::equals(volume.id)])
+		.order_by(volume_health::date_captured::order(SortOrder::Desc))
+		.exec()
+		.await?;
+
+	db.volume_health()
+		.create(
+			volume::id::equals(volume.id),
+			health.device.clone(),
+			vec![
+				volume_health::passed::set(health.passed),
+				volume_health::temperature_celsius::set(health.temperature_celsius),
+				volume_health::reallocated_sectors::set(health.reallocated_sectors),
+				volume_health::pending_sectors::set(health.pending_sectors),
+				volume_health::media_errors::set(health.media_errors),
+				volume_health::percentage_used::set(health.percentage_used),
+				volume_health::power_on_hours::set(health.power_on_hours),
+			],
+		)
+		.exec()
+		.await?;
+
+	let previous_bad_sectors = previous
+		.map(|previous| {
+			[
+				previous.reallocated_sectors,
+				previous.pending_sectors,
+				previous.media_errors,
+			]
+			.into_iter()
+			.flatten()
+			.sum::<i32>()
+		})
+		.unwrap_or_default();
+
+	for kind in [
+		DriveHealthAlertKind::FailedAssessment,
+		DriveHealthAlertKind::BadSectors,
+		DriveHealthAlertKind::HighTemperature,
+	] {
+		let crossed = !location_ids.is_empty()
+			&& match kind {
+				DriveHealthAlertKind::FailedAssessment => health.passed == Some(false),
+				DriveHealthAlertKind::BadSectors => health
+					.bad_sectors()
+					.map_or(false, |bad_sectors| bad_sectors > previous_bad_sectors),
+				DriveHealthAlertKind::HighTemperature => thresholds
+					.max_temperature_celsius
+					.zip(health.temperature_celsius)
+					.map_or(false, |(max, temperature)| temperature > max.into()),
+			};
+
+		let key = (library.id, volume.id, kind);
+		if !crossed {
+			raised.remove(&key);
+		} else if raised.insert(key) {
+			warn!(
+				"Drive '{}' behind volume '{}' of library {} reports {kind:?}: {health:?}",
+				health.device, volume.mount_point, library.id
+			);
+
+			library.emit(CoreEvent::DriveHealthAlert(DriveHealthAlert {
+				kind,
+				library_id: library.id,
+				volume_name: volume.name.clone(),
+				mount_point: volume.mount_point.clone(),
+				location_ids: location_ids.to_vec(),
+				health: health.clone(),
+			}));
+		}
+	}
+
+	Ok(())
+}
+
+pub(super) async fn prune_health(db: &PrismaClient) -> Result<(), VolumeError> {
+	db.volume_health()
+		.delete_many(vec![volume_health::date_captured::lt(
+			(Utc::now() - Duration::days(SAMPLES_KEPT_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+#[derive(Deserialize, Type, Debug)]
+pub struct VolumeHealthArgs {
+	pub volume_id: volume::id::Type,
+}
+
+/// The readings of the drive behind a volume, oldest first
+pub async fn volume_health_history(
+	db: &PrismaClient,
+	VolumeHealthArgs { volume_id }: VolumeHealthArgs,
+) -> Result<Vec<volume_health::Data>, VolumeError> {
+	Ok(db
+		.volume_health()
+		.find_many(vec![volume_health::volume_id::equals(volume_id)])
+		.order_by(volume_health::date_captured::order(SortOrder::Asc))
+		.exec()
+		.await?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use serde_json::json;
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn partitions_to_disks() {
+		assert_eq!(whole_disk("/dev/sda1").as_deref(), Some("/dev/sda"));
+		assert_eq!(whole_disk("/dev/sdb").as_deref(), Some("/dev/sdb"));
+		assert_eq!(
+			whole_disk("/dev/nvme0n1p2").as_deref(),
+			Some("/dev/nvme0n1")
+		);
+		assert_eq!(whole_disk("/dev/nvme0n1").as_deref(), Some("/dev/nvme0n1"));
+		assert_eq!(
+			whole_disk("/dev/mmcblk0p1").as_deref(),
+			Some("/dev/mmcblk0")
+		);
+		assert_eq!(whole_disk("overlay"), None);
+	}
+
+	#[test]
+	fn smartctl_reports() {
+		let ata = json!({
+			"smart_status": { "passed": true },
+			"temperature": { "current": 41 },
+			"power_on_time": { "hours": 12000 },
+			"ata_smart_attributes": { "table": [
+				{ "id": 5, "name": "Reallocated_Sector_Ct", "raw": { "value": 8 } },
+				{ "id": 197, "name": "Current_Pending_Sector", "raw": { "value": 0 } },
+			] },
+		});
+		let health = parse_smartctl("/dev/sda", &ata).expect("readable report");
+		assert_eq!(health.reallocated_sectors, Some(8));
+		assert_eq!(health.bad_sectors(), Some(8));
+		assert_eq!(health.power_on_hours, Some(12000));
+
+		let nvme = json!({
+			"smart_status": { "passed": false },
+			"temperature": { "current": 74 },
+			"nvme_smart_health_information_log": { "media_errors": 3, "percentage_used": 104 },
+		});
+		let health = parse_smartctl("/dev/nvme0n1", &nvme).expect("readable report");
+		assert_eq!(health.passed, Some(false));
+		assert_eq!(health.bad_sectors(), Some(3));
+		assert_eq!(health.reallocated_sectors, None);
+
+		// What smartctl prints when it isn't allowed to open the device
+		let denied = json!({
+			"smartctl": { "messages": [{ "string": "Permission denied", "severity": "error" }] },
+		});
+		assert_eq!(parse_smartctl("/dev/sda", &denied), None);
+	}
+}
@@ -0,0 +1,578 @@
+use crate::{
+	api::CoreEvent,
+	library::Library,
+	node::StorageAlertThresholds,
+	prisma::{
+		location,
+		volume::{self, *},
+		volume_sample, PrismaClient, SortOrder,
+	},
+	Node,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use specta::Type;
+use std::{
+	collections::{HashMap, HashSet},
+	fmt::Display,
+	path::Path,
+	process::Command,
+	sync::Arc,
+};
+use sysinfo::{DiskExt, System, SystemExt};
+use thiserror::Error;
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+pub mod capabilities;
+pub mod health;
+
+use health::{prune_health, read_drives_health, record_health, DriveHealth, DriveHealthAlertKind};
+
+/// How often the free space of the volumes is sampled
+const SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const SAMPLES_KEPT_DAYS: i64 = 90;
+/// The forecast follows the trend of the last weeks, older habits don't tell much about the next ones
+const FORECAST_WINDOW_DAYS: i64 = 30;
+/// Samples spanning less than this make for wild forecasts
+const MIN_FORECAST_SPAN_HOURS: i64 = 24;
+/// Further than this, a volume isn't going to be full any time soon
+const MAX_FORECAST_DAYS: i64 = 10 * 365;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum DiskType {
+	SSD,
+	HDD,
+	Removable,
+}
+
+impl Display for DiskType {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::SSD => "SSD",
+			Self::HDD => "HDD",
+			Self::Removable => "Removable",
+		})
+	}
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct Volume {
+	pub name: String,
+	pub mount_point: String,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub total_capacity: u64,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub available_capacity: u64,
+	pub is_removable: bool,
+	pub disk_type: Option<DiskType>,
+	pub file_system: Option<String>,
+	pub is_root_filesystem: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum VolumeError {
+	#[error("Database error: {0}")]
+	DatabaseErr(#[from] prisma_client_rust::QueryError),
+	#[error("FromUtf8Error: {0}")]
+	FromUtf8Error(#[from] std::string::FromUtf8Error),
+	#[error("volume not found: <mount_point='{0}'>")]
+	NotFound(String),
+	#[error("failed to join volumes listing: {0}")]
+	Join(#[from] tokio::task::JoinError),
+}
+
+impl From<VolumeError> for rspc::Error {
+	fn from(e: VolumeError) -> Self {
+		match e {
+			VolumeError::NotFound(_) => {
+				rspc::Error::with_cause(rspc::ErrorCode::NotFound, e.to_string(), e)
+			}
+			_ => rspc::Error::with_cause(rspc::ErrorCode::InternalServerError, e.to_string(), e),
+		}
+	}
+}
+
+pub async fn save_volume(library: &Library) -> Result<(), VolumeError> {
+	upsert_volumes(library, get_volumes()?).await.map(|_| ())
+}
+
+async fn upsert_volumes(
+	library: &Library,
+	volumes: Vec<Volume>,
+) -> Result<Vec<volume::Data>, VolumeError> {
+	let mut saved = Vec::with_capacity(volumes.len());
+
+	// enter all volumes associate with this client add to db
+	for volume in volumes {
+		let params = vec![
+			disk_type::set(volume.disk_type.map(|t| t.to_string())),
+			filesystem::set(volume.file_system.clone()),
+			total_bytes_capacity::set(volume.total_capacity.to_string()),
+			total_bytes_available::set(volume.available_capacity.to_string()),
+		];
+
+		let volume = library
+			.db
+			.volume()
+			.upsert(
+				node_id_mount_point_name(
+					library.node_local_id,
+					volume.mount_point.to_string(),
+					volume.name.to_string(),
+				),
+				volume::create(
+					library.node_local_id,
+					volume.name,
+					volume.mount_point,
+					params.clone(),
+				),
+				params,
+			)
+			.exec()
+			.await?;
+
+		saved.push(volume);
+	}
+	// cleanup: remove all unmodified volumes associate with this client
+
+	Ok(saved)
+}
+
+#[derive(Serialize, Type, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StorageAlertKind {
+	/// Less free space than `StorageAlertThresholds::min_free_percent`
+	LowSpace,
+	/// Forecast to be full within `StorageAlertThresholds::full_within_days`
+	FullSoon,
+}
+
+/// Raised once when a volume backing locations crosses a threshold, and again only after it got
+/// back under it
+#[derive(Serialize, Type, Debug, Clone)]
+pub struct StorageAlert {
+	pub kind: StorageAlertKind,
+	pub library_id: Uuid,
+	pub volume_name: String,
+	pub mount_point: String,
+	/// The locations of the library on the volume
+	pub location_ids: Vec<location::id::Type>,
+	pub free_percent: u8,
+	pub full_at: Option<DateTime<Utc>>,
+}
+
+/// Samples the free space of the volumes of this node, and the health of their drives, into every
+/// library, and raises the alerts of the volumes backing their locations
+pub(crate) async fn monitor_storage(node: Arc<Node>) {
+	let mut interval = interval(SAMPLE_INTERVAL);
+	let mut raised = RaisedAlerts::default();
+
+	loop {
+		interval.tick().await;
+
+		let volumes = match spawn_blocking(get_volumes).await {
+			Ok(Ok(volumes)) => volumes,
+			Ok(Err(e)) => {
+				error!("Failed to list the volumes: {e:#?}");
+				continue;
+			}
+			Err(e) => {
+				error!("Failed to join the volumes listing: {e:#?}");
+				continue;
+			}
+		};
+
+		let drives = read_drives_health(&volumes).await;
+		let thresholds = node.config.get().await.storage_alerts;
+
+		for library in node.library_manager.get_all_libraries().await {
+			if let Err(e) = sample_volumes(
+				&library,
+				volumes.clone(),
+				&drives,
+				thresholds,
+				&mut raised,
+			)
+			.await
+			{
+				warn!(
+					"Failed to sample the volumes of library {}: {e:#?}",
+					library.id
+				);
+			}
+		}
+	}
+}
+
+/// The alerts raised and not cleared yet, by library and volume
+#[derive(Default)]
+struct RaisedAlerts {
+	storage: HashSet<(Uuid, volume::id::Type, StorageAlertKind)>,
+	health: HashSet<(Uuid, volume::id::Type, DriveHealthAlertKind)>,
+}
+
+async fn sample_volumes(
+	library: &Library,
+	volumes: Vec<Volume>,
+	drives: &HashMap<String, DriveHealth>,
+	thresholds: StorageAlertThresholds,
+	raised: &mut RaisedAlerts,
+) -> Result<(), VolumeError> {
+	let db = &library.db;
+	let volumes = upsert_volumes(library, volumes).await?;
+	let now = Utc::now();
+
+	db.volume_sample()
+		.create_many(
+			volumes
+				.iter()
+				.map(|volume| {
+					volume_sample::create_unchecked(
+						volume.id,
+						volume.total_bytes_capacity.clone(),
+						volume.total_bytes_available.clone(),
+						vec![volume_sample::date_captured::set(now.into())],
+					)
+				})
+				.collect(),
+		)
+		.exec()
+		.await?;
+
+	db.volume_sample()
+		.delete_many(vec![volume_sample::date_captured::lt(
+			(now - Duration::days(SAMPLES_KEPT_DAYS)).into(),
+		)])
+		.exec()
+		.await?;
+	prune_health(db).await?;
+
+	let locations = db
+		.location()
+		.find_many(vec![location::node_id::equals(Some(library.node_local_id))])
+		.select(location::select!({ id path }))
+		.exec()
+		.await?;
+
+	for volume in &volumes {
+		let location_ids = locations
+			.iter()
+			.filter(|location| {
+				location.path.as_deref().and_then(|path| {
+					backing_mount_point(
+						path,
+						volumes.iter().map(|volume| volume.mount_point.as_str()),
+					)
+				}) == Some(volume.mount_point.as_str())
+			})
+			.map(|location| location.id)
+			.collect::<Vec<_>>();
+
+		let capacity = parse_bytes(&volume.total_bytes_capacity);
+		let available = parse_bytes(&volume.total_bytes_available);
+		let free_percent = if capacity == 0 {
+			100
+		} else {
+			(available as u128 * 100 / capacity as u128) as u8
+		};
+		let full_at = volume_full_at(db, volume.id).await?;
+
+		for kind in [StorageAlertKind::LowSpace, StorageAlertKind::FullSoon] {
+			let crossed = !location_ids.is_empty()
+				&& match kind {
+					StorageAlertKind::LowSpace => thresholds
+						.min_free_percent
+						.map_or(false, |min_free_percent| free_percent < min_free_percent),
+					StorageAlertKind::FullSoon => thresholds
+						.full_within_days
+						.zip(full_at)
+						.map_or(false, |(days, full_at)| {
+							full_at - now <= Duration::days(days.into())
+						}),
+				};
+
+			let key = (library.id, volume.id, kind);
+			if !crossed {
+				raised.storage.remove(&key);
+			} else if raised.storage.insert(key) {
+				warn!(
+					"Volume '{}' of library {} is running out of space: {kind:?}",
+					volume.mount_point, library.id
+				);
+
+				library.emit(CoreEvent::StorageAlert(StorageAlert {
+					kind,
+					library_id: library.id,
+					volume_name: volume.name.clone(),
+					mount_point: volume.mount_point.clone(),
+					location_ids: location_ids.clone(),
+					free_percent,
+					full_at,
+				}));
+			}
+		}
+
+		if let Some(health) = drives.get(&volume.mount_point) {
+			record_health(
+				library,
+				volume,
+				&location_ids,
+				health,
+				thresholds,
+				&mut raised.health,
+			)
+			.await?;
+		}
+	}
+
+	Ok(())
+}
+
+/// The mount point of the volume holding a path, the deepest one when volumes are mounted inside
+/// others
+fn backing_mount_point<'a>(
+	path: impl AsRef<Path>,
+	mount_points: impl Iterator<Item = &'a str>,
+) -> Option<&'a str> {
+	mount_points
+		.filter(|mount_point| path.as_ref().starts_with(mount_point))
+		.max_by_key(|mount_point| mount_point.len())
+}
+
+fn parse_bytes(bytes: &str) -> u64 {
+	bytes.parse().unwrap_or_default()
+}
+
+volume_sample::select!(volume_sample_for_forecast {
+	total_bytes_capacity
+	total_bytes_available
+	date_captured
+});
+
+async fn forecast_samples(
+	db: &PrismaClient,
+	volume_id: volume::id::Type,
+) -> Result<Vec<volume_sample_for_forecast::Data>, VolumeError> {
+	Ok(db
+		.volume_sample()
+		.find_many(vec![
+			volume_sample::volume_id::equals(volume_id),
+			volume_sample::date_captured::gte(
+				(Utc::now() - Duration::days(FORECAST_WINDOW_DAYS)).into(),
+			),
+		])
+		.order_by(volume_sample::date_captured::order(SortOrder::Asc))
+		.select(volume_sample_for_forecast::select())
+		.exec()
+		.await?)
+}
+
+async fn volume_full_at(
+	db: &PrismaClient,
+	volume_id: volume::id::Type,
+) -> Result<Option<DateTime<Utc>>, VolumeError> {
+	Ok(full_at(&sample_points(
+		&forecast_samples(db, volume_id).await?,
+	)))
+}
+
+fn sample_points(samples: &[volume_sample_for_forecast::Data]) -> Vec<(DateTime<Utc>, u64)> {
+	samples
+		.iter()
+		.map(|sample| {
+			(
+				sample.date_captured.into(),
+				parse_bytes(&sample.total_bytes_available),
+			)
+		})
+		.collect()
+}
+
+/// When the free space runs out if it keeps going down like it did, fitting a line through the
+/// samples. `None` while it's steady or growing.
+fn full_at(samples: &[(DateTime<Utc>, u64)]) -> Option<DateTime<Utc>> {
+	let (first, _) = samples.first()?;
+	let (last, last_available) = samples.last()?;
+
+	if *last - *first < Duration::hours(MIN_FORECAST_SPAN_HOURS) {
+		return None;
+	}
+
+	let points = samples
+		.iter()
+		.map(|(date, available)| ((*date - *first).num_seconds() as f64, *available as f64))
+		.collect::<Vec<_>>();
+	let count = points.len() as f64;
+	let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+	let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+	let covariance = points
+		.iter()
+		.map(|(x, y)| (x - mean_x) * (y - mean_y))
+		.sum::<f64>();
+	let variance = points
+		.iter()
+		.map(|(x, _)| (x - mean_x).powi(2))
+		.sum::<f64>();
+
+	// Bytes per second
+	let slope = covariance / variance;
+	if !slope.is_finite() || slope >= 0.0 {
+		return None;
+	}
+
+	let seconds_left = *last_available as f64 / -slope;
+	(seconds_left < Duration::days(MAX_FORECAST_DAYS).num_seconds() as f64)
+		.then(|| *last + Duration::seconds(seconds_left as i64))
+}
+
+#[derive(Serialize, Type, Debug)]
+pub struct VolumeForecast {
+	pub volume_id: volume::id::Type,
+	pub name: String,
+	pub mount_point: String,
+	pub total_bytes_capacity: String,
+	pub total_bytes_available: String,
+	/// Over the last weeks, oldest first
+	pub samples: Vec<volume_sample_for_forecast::Data>,
+	/// `None` while the free space is steady or growing, or too few samples were taken yet
+	pub full_at: Option<DateTime<Utc>>,
+}
+
+/// The free space trend of the volumes of this node
+pub async fn storage_forecasts(library: &Library) -> Result<Vec<VolumeForecast>, VolumeError> {
+	let volumes = library
+		.db
+		.volume()
+		.find_many(vec![volume::node_id::equals(library.node_local_id)])
+		.exec()
+		.await?;
+
+	let mut forecasts = Vec::with_capacity(volumes.len());
+	for volume in volumes {
+		let samples = forecast_samples(&library.db, volume.id).await?;
+
+		forecasts.push(VolumeForecast {
+			volume_id: volume.id,
+			name: volume.name,
+			mount_point: volume.mount_point,
+			total_bytes_capacity: volume.total_bytes_capacity,
+			total_bytes_available: volume.total_bytes_available,
+			full_at: full_at(&sample_points(&samples)),
+			samples,
+		});
+	}
+
+	Ok(forecasts)
+}
+
+// TODO: Error handling in this function
+pub fn get_volumes() -> Result<Vec<Volume>, VolumeError> {
+	System::new_all()
+		.disks()
+		.iter()
+		.filter_map(|disk| {
+			let mut total_capacity = disk.total_space();
+			let mount_point = disk.mount_point().to_str().unwrap_or("/").to_string();
+			let available_capacity = disk.available_space();
+			let name = disk.name().to_str().unwrap_or("Volume").to_string();
+			let is_removable = disk.is_removable();
+
+			let file_system = String::from_utf8(disk.file_system().to_vec())
+				.unwrap_or_else(|_| "Err".to_string());
+
+			let disk_type = match disk.type_() {
+				sysinfo::DiskType::SSD => DiskType::SSD,
+				sysinfo::DiskType::HDD => DiskType::HDD,
+				_ => DiskType::Removable,
+			};
+
+			if total_capacity < available_capacity && cfg!(target_os = "windows") {
+				let mut caption = mount_point.clone();
+				caption.pop();
+				let wmic_process = Command::new("cmd")
+					.args([
+						"/C",
+						&format!("wmic logical disk where Caption='{caption}' get Size"),
+					])
+					.output()
+					.expect("failed to execute process");
+				let wmic_process_output = String::from_utf8(wmic_process.stdout).ok()?;
+				let parsed_size =
+					wmic_process_output.split("\r\r\n").collect::<Vec<&str>>()[1].to_string();
+
+				if let Ok(n) = parsed_size.trim().parse::<u64>() {
+					total_capacity = n;
+				}
+			}
+
+			(!mount_point.starts_with("/System")).then_some(Ok(Volume {
+				name,
+				is_root_filesystem: mount_point == "/",
+				mount_point,
+				total_capacity,
+				available_capacity,
+				is_removable,
+				disk_type: Some(disk_type),
+				file_system: Some(file_system),
+			}))
+		})
+		.collect::<Result<Vec<_>, _>>()
+}
+
+// #[test]
+// fn test_get_volumes() {
+//   let volumes = get_volumes()?;
+//   dbg!(&volumes);
+//   assert!(volumes.len() > 0);
+// }
+
+// Adapted from: https://github.com/kimlimjustin/xplorer/blob/f4f3590d06783d64949766cc2975205a3b689a56/src-tauri/src/drives.rs
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn forecasts_follow_the_trend() {
+		let start = Utc::now();
+		let day = |days: i64, available: u64| (start + Duration::days(days), available);
+
+		// Down 10 GB a day with 70 GB left, give or take some noise
+		let gb = 1_000_000_000;
+		let shrinking = [
+			day(0, 100 * gb),
+			day(1, 91 * gb),
+			day(2, 79 * gb),
+			day(3, 70 * gb),
+		];
+		let days_left = (full_at(&shrinking).expect("shrinking") - start).num_days();
+		assert!((9..=11).contains(&days_left), "{days_left}");
+
+		assert_eq!(full_at(&[day(0, 100 * gb), day(1, 101 * gb)]), None);
+		// A single day of samples isn't enough
+		assert_eq!(full_at(&[day(0, 100 * gb)]), None);
+		assert_eq!(full_at(&[]), None);
+	}
+
+	#[test]
+	fn deepest_mount_point_backs_a_path() {
+		let mount_points = ["/", "/mnt/data", "/mnt/data/backups"];
+
+		assert_eq!(
+			backing_mount_point("/mnt/data/backups/2023", mount_points.into_iter()),
+			Some("/mnt/data/backups")
+		);
+		assert_eq!(
+			backing_mount_point("/mnt/database", mount_points.into_iter()),
+			Some("/")
+		);
+		assert_eq!(backing_mount_point("C:\\Users", ["D:\\"].into_iter()), None);
+	}
+}
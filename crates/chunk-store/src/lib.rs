@@ -0,0 +1,581 @@
+//! A deduplicating store for file contents, meant for backups and file versions. Contents are cut
+//! into chunks with FastCDC, where boundaries depend on the data itself, so inserting a few bytes
+//! in a file only changes the chunks around them. Each chunk is kept once, compressed and named by
+//! its BLAKE3 hash, however many stored contents share it.
+//!
+//! A stored content is described by a [`Manifest`] saved under a name chosen by the caller, like
+//! the id of a backup. Chunks no manifest refers to anymore are removed by
+//! [`ChunkStore::collect_garbage`].
+//!
+//! ```text
+//! <root>/chunks/<first two hex digits>/<hash>
+//! <root>/manifests/<name>.json
+//! ```
+
+#![warn(clippy::unwrap_used, clippy::panic)]
+
+use std::{
+	collections::HashSet,
+	fmt,
+	fs::{self, File},
+	io::{self, BufReader, Read, Write},
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		RwLock, RwLockReadGuard, RwLockWriteGuard,
+	},
+};
+
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+/// Chunks are small, compressing harder isn't worth the time
+const COMPRESSION_LEVEL: i32 = 3;
+
+const CHUNKS_DIR: &str = "chunks";
+const MANIFESTS_DIR: &str = "manifests";
+const MANIFEST_EXTENSION: &str = "json";
+/// Files being written, renamed once complete so a crash never leaves a truncated chunk behind
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Tells apart the files written at the same time, as two contents may share a new chunk
+static TEMP_FILES_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Error, Debug)]
+pub enum ChunkStoreError {
+	#[error("io error at {}: {source}", .path.display())]
+	Io { path: Box<Path>, source: io::Error },
+	#[error("failed to chunk content: {0}")]
+	Chunking(#[from] fastcdc::v2020::Error),
+	#[error("manifest names may only hold letters, digits, dots, dashes and underscores: '{0}'")]
+	InvalidName(String),
+	#[error("manifest not found: '{0}'")]
+	ManifestNotFound(String),
+	#[error("invalid manifest '{0}': {1}")]
+	InvalidManifest(String, serde_json::Error),
+	#[error("chunk not found: {0}")]
+	ChunkNotFound(ChunkId),
+	#[error("chunk doesn't match its hash: {0}")]
+	CorruptedChunk(ChunkId),
+}
+
+pub type Result<T> = std::result::Result<T, ChunkStoreError>;
+
+trait IoContext<T> {
+	fn at(self, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+	fn at(self, path: &Path) -> Result<T> {
+		self.map_err(|source| ChunkStoreError::Io {
+			path: path.into(),
+			source,
+		})
+	}
+}
+
+/// The BLAKE3 hash of the uncompressed content of a chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ChunkId([u8; 32]);
+
+impl ChunkId {
+	fn of(data: &[u8]) -> Self {
+		Self(*blake3::hash(data).as_bytes())
+	}
+}
+
+impl fmt::Display for ChunkId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&hex::encode(self.0))
+	}
+}
+
+impl From<ChunkId> for String {
+	fn from(id: ChunkId) -> Self {
+		id.to_string()
+	}
+}
+
+impl TryFrom<String> for ChunkId {
+	type Error = hex::FromHexError;
+
+	fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+		let mut bytes = [0; 32];
+		hex::decode_to_slice(value, &mut bytes)?;
+		Ok(Self(bytes))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+	pub id: ChunkId,
+	pub length: u32,
+}
+
+/// The chunks a content is made of, in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+	pub size: u64,
+	pub chunks: Vec<ChunkRef>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PutReport {
+	/// Chunks the store didn't have yet
+	pub new_chunks: usize,
+	/// Chunks already stored for another content, or repeated within this one
+	pub reused_chunks: usize,
+	/// Compressed size of the new chunks
+	pub written_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GarbageReport {
+	pub removed_chunks: usize,
+	pub freed_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+	pub checked_chunks: usize,
+	/// Chunks whose content doesn't match their hash anymore, or can't be decompressed
+	pub corrupted_chunks: Vec<ChunkId>,
+	/// Manifests referring to chunks which aren't in the store, along with the chunks
+	pub missing_chunks: Vec<(String, ChunkId)>,
+	/// Manifests which can't be read
+	pub invalid_manifests: Vec<String>,
+}
+
+impl VerifyReport {
+	pub fn is_healthy(&self) -> bool {
+		self.corrupted_chunks.is_empty()
+			&& self.missing_chunks.is_empty()
+			&& self.invalid_manifests.is_empty()
+	}
+}
+
+/// A chunk store rooted in a directory. Storing and reading may happen concurrently, collecting
+/// garbage waits for them so a chunk isn't removed while a manifest referring to it is written.
+#[derive(Debug)]
+pub struct ChunkStore {
+	root: PathBuf,
+	lock: RwLock<()>,
+}
+
+impl ChunkStore {
+	/// Opens the store in `root`, creating it if needed
+	pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+		let root = root.into();
+
+		for dir in [CHUNKS_DIR, MANIFESTS_DIR] {
+			let path = root.join(dir);
+			fs::create_dir_all(&path).at(&path)?;
+		}
+
+		Ok(Self {
+			root,
+			lock: RwLock::new(()),
+		})
+	}
+
+	pub fn root(&self) -> &Path {
+		&self.root
+	}
+
+	/// Stores a content under `name`, replacing what was stored under it before
+	pub fn put(&self, name: &str, content: impl Read) -> Result<(Manifest, PutReport)> {
+		let manifest_path = self.manifest_path(name)?;
+		let _guard = self.read_lock();
+
+		let mut manifest = Manifest {
+			size: 0,
+			chunks: vec![],
+		};
+		let mut report = PutReport::default();
+
+		for chunk in StreamCDC::new(content, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+			let chunk = chunk?;
+			let id = ChunkId::of(&chunk.data);
+
+			match self.write_chunk(id, &chunk.data)? {
+				Some(written) => {
+					report.new_chunks += 1;
+					report.written_bytes += written;
+				}
+				None => report.reused_chunks += 1,
+			}
+
+			manifest.size += chunk.length as u64;
+			manifest.chunks.push(ChunkRef {
+				id,
+				length: chunk.length as u32,
+			});
+		}
+
+		write_atomically(
+			&manifest_path,
+			&serde_json::to_vec(&manifest).expect("manifests are always serializable"),
+		)?;
+
+		Ok((manifest, report))
+	}
+
+	pub fn put_file(&self, name: &str, path: impl AsRef<Path>) -> Result<(Manifest, PutReport)> {
+		let path = path.as_ref();
+		self.put(name, BufReader::new(File::open(path).at(path)?))
+	}
+
+	/// Writes the content stored under `name`, checking each chunk against its hash on the way
+	pub fn get(&self, name: &str, mut target: impl Write) -> Result<u64> {
+		let _guard = self.read_lock();
+		let manifest = self.read_manifest(name)?;
+
+		for chunk in &manifest.chunks {
+			let data = self.read_chunk(chunk.id)?;
+			target.write_all(&data).at(&self.chunk_path(chunk.id))?;
+		}
+
+		Ok(manifest.size)
+	}
+
+	pub fn manifest(&self, name: &str) -> Result<Manifest> {
+		let _guard = self.read_lock();
+		self.read_manifest(name)
+	}
+
+	/// The names contents are stored under
+	pub fn names(&self) -> Result<Vec<String>> {
+		let dir = self.root.join(MANIFESTS_DIR);
+		let mut names = vec![];
+
+		for entry in fs::read_dir(&dir).at(&dir)? {
+			let path = entry.at(&dir)?.path();
+			if path
+				.extension()
+				.map_or(false, |extension| extension == MANIFEST_EXTENSION)
+			{
+				if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+					names.push(name.to_string());
+				}
+			}
+		}
+
+		names.sort();
+
+		Ok(names)
+	}
+
+	/// Forgets the content stored under `name`, its chunks staying until garbage is collected
+	pub fn remove(&self, name: &str) -> Result<()> {
+		let path = self.manifest_path(name)?;
+
+		match fs::remove_file(&path) {
+			Ok(()) => Ok(()),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				Err(ChunkStoreError::ManifestNotFound(name.to_string()))
+			}
+			Err(e) => Err(e).at(&path),
+		}
+	}
+
+	/// Removes the chunks no manifest refers to, along with files left over by interrupted writes.
+	/// Nothing is removed when a manifest can't be read, as its chunks would be lost.
+	pub fn collect_garbage(&self) -> Result<GarbageReport> {
+		let _guard = self.write_lock();
+
+		let mut referenced = HashSet::new();
+		for name in self.names()? {
+			referenced.extend(
+				self.read_manifest(&name)?
+					.chunks
+					.iter()
+					.map(|chunk| chunk.id),
+			);
+		}
+
+		let mut report = GarbageReport::default();
+
+		self.for_each_chunk_file(|path, id| {
+			if id.map_or(false, |id| referenced.contains(&id)) {
+				return Ok(());
+			}
+
+			let size = fs::metadata(path).at(path)?.len();
+			fs::remove_file(path).at(path)?;
+
+			if id.is_some() {
+				report.removed_chunks += 1;
+			}
+			report.freed_bytes += size;
+
+			Ok(())
+		})?;
+
+		Ok(report)
+	}
+
+	/// Checks every chunk against its hash, and that every manifest has all its chunks
+	pub fn verify(&self) -> Result<VerifyReport> {
+		let _guard = self.read_lock();
+		let mut report = VerifyReport::default();
+
+		self.for_each_chunk_file(|_, id| {
+			let Some(id) = id else {
+				return Ok(());
+			};
+
+			report.checked_chunks += 1;
+
+			match self.read_chunk(id) {
+				Ok(_) => {}
+				Err(ChunkStoreError::CorruptedChunk(id)) => report.corrupted_chunks.push(id),
+				Err(e) => return Err(e),
+			}
+
+			Ok(())
+		})?;
+
+		for name in self.names()? {
+			match self.read_manifest(&name) {
+				Ok(manifest) => {
+					let mut seen = HashSet::new();
+					for chunk in manifest.chunks {
+						if seen.insert(chunk.id) && !self.chunk_path(chunk.id).exists() {
+							report.missing_chunks.push((name.clone(), chunk.id));
+						}
+					}
+				}
+				Err(ChunkStoreError::InvalidManifest(..)) => report.invalid_manifests.push(name),
+				Err(e) => return Err(e),
+			}
+		}
+
+		Ok(report)
+	}
+
+	fn read_lock(&self) -> RwLockReadGuard<'_, ()> {
+		// The lock guards no data, a panic while holding it leaves nothing inconsistent
+		self.lock.read().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn write_lock(&self) -> RwLockWriteGuard<'_, ()> {
+		self.lock.write().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn chunk_path(&self, id: ChunkId) -> PathBuf {
+		let hex = id.to_string();
+		self.root.join(CHUNKS_DIR).join(&hex[..2]).join(hex)
+	}
+
+	fn manifest_path(&self, name: &str) -> Result<PathBuf> {
+		if name.is_empty()
+			|| name.starts_with('.')
+			|| !name
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+		{
+			return Err(ChunkStoreError::InvalidName(name.to_string()));
+		}
+
+		Ok(self
+			.root
+			.join(MANIFESTS_DIR)
+			.join(format!("{name}.{MANIFEST_EXTENSION}")))
+	}
+
+	fn read_manifest(&self, name: &str) -> Result<Manifest> {
+		let path = self.manifest_path(name)?;
+
+		let data = match fs::read(&path) {
+			Ok(data) => data,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				return Err(ChunkStoreError::ManifestNotFound(name.to_string()))
+			}
+			Err(e) => return Err(e).at(&path),
+		};
+
+		serde_json::from_slice(&data)
+			.map_err(|e| ChunkStoreError::InvalidManifest(name.to_string(), e))
+	}
+
+	/// Writes a chunk unless it's already stored, returning its compressed size when it was written
+	fn write_chunk(&self, id: ChunkId, data: &[u8]) -> Result<Option<u64>> {
+		let path = self.chunk_path(id);
+		if path.exists() {
+			return Ok(None);
+		}
+
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).at(parent)?;
+		}
+
+		let compressed = zstd::bulk::compress(data, COMPRESSION_LEVEL).at(&path)?;
+		write_atomically(&path, &compressed)?;
+
+		Ok(Some(compressed.len() as u64))
+	}
+
+	fn read_chunk(&self, id: ChunkId) -> Result<Vec<u8>> {
+		let path = self.chunk_path(id);
+
+		let file = match File::open(&path) {
+			Ok(file) => file,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				return Err(ChunkStoreError::ChunkNotFound(id))
+			}
+			Err(e) => return Err(e).at(&path),
+		};
+
+		// Garbage in a chunk file is corruption as well, not an error of the store
+		match zstd::decode_all(BufReader::new(file)) {
+			Ok(data) if ChunkId::of(&data) == id => Ok(data),
+			_ => Err(ChunkStoreError::CorruptedChunk(id)),
+		}
+	}
+
+	/// Runs `f` on every file of the chunks directory, with the id of the chunk it holds, `None`
+	/// for leftovers of interrupted writes and anything else which isn't a chunk
+	fn for_each_chunk_file(
+		&self,
+		mut f: impl FnMut(&Path, Option<ChunkId>) -> Result<()>,
+	) -> Result<()> {
+		let chunks_dir = self.root.join(CHUNKS_DIR);
+
+		for prefix in fs::read_dir(&chunks_dir).at(&chunks_dir)? {
+			let prefix = prefix.at(&chunks_dir)?.path();
+			if !prefix.is_dir() {
+				continue;
+			}
+
+			for entry in fs::read_dir(&prefix).at(&prefix)? {
+				let path = entry.at(&prefix)?.path();
+
+				let id = path
+					.file_name()
+					.and_then(|name| name.to_str())
+					.and_then(|name| ChunkId::try_from(name.to_string()).ok())
+					.filter(|id| self.chunk_path(*id) == path);
+
+				f(&path, id)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Writes a file next to its destination then moves it in place, so readers never see it partial
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+	let temp_path = path.with_extension(format!(
+		"{}.{TEMP_EXTENSION}",
+		TEMP_FILES_COUNT.fetch_add(1, Ordering::Relaxed)
+	));
+
+	fs::write(&temp_path, data).at(&temp_path)?;
+	fs::rename(&temp_path, path).at(path)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+mod tests {
+	use super::*;
+
+	/// Bytes which don't repeat, so chunk boundaries fall where FastCDC finds them
+	fn content(len: usize, seed: u64) -> Vec<u8> {
+		let mut state = seed;
+		(0..len)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				(state >> 33) as u8
+			})
+			.collect()
+	}
+
+	#[test]
+	fn versions_share_chunks() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = ChunkStore::open(dir.path()).unwrap();
+
+		let original = content(1024 * 1024, 1);
+		let (_, first) = store.put("v1", original.as_slice()).unwrap();
+		assert_eq!(first.reused_chunks, 0);
+
+		// A few bytes inserted early on only change the chunks around them
+		let mut edited = original.clone();
+		edited.splice(100_000..100_000, *b"inserted");
+		let (manifest, second) = store.put("v2", edited.as_slice()).unwrap();
+		assert!(second.reused_chunks > second.new_chunks);
+		assert_eq!(manifest.size, edited.len() as u64);
+
+		let mut restored = vec![];
+		store.get("v2", &mut restored).unwrap();
+		assert_eq!(restored, edited);
+	}
+
+	#[test]
+	fn garbage_is_collected() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = ChunkStore::open(dir.path()).unwrap();
+
+		store.put("kept", content(300_000, 2).as_slice()).unwrap();
+		store
+			.put("removed", content(300_000, 3).as_slice())
+			.unwrap();
+		store.remove("removed").unwrap();
+
+		let report = store.collect_garbage().unwrap();
+		assert!(report.removed_chunks > 0);
+		assert_eq!(store.collect_garbage().unwrap(), GarbageReport::default());
+
+		let mut restored = vec![];
+		store.get("kept", &mut restored).unwrap();
+		assert_eq!(restored, content(300_000, 2));
+		assert!(store.verify().unwrap().is_healthy());
+	}
+
+	#[test]
+	fn corruption_is_found() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = ChunkStore::open(dir.path()).unwrap();
+
+		let (manifest, _) = store.put("file", content(200_000, 4).as_slice()).unwrap();
+		let [corrupted, missing, ..] = manifest.chunks.as_slice() else {
+			panic!("expected several chunks");
+		};
+
+		fs::write(store.chunk_path(corrupted.id), b"not a chunk").unwrap();
+		fs::remove_file(store.chunk_path(missing.id)).unwrap();
+
+		let report = store.verify().unwrap();
+		assert_eq!(report.corrupted_chunks, vec![corrupted.id]);
+		assert_eq!(
+			report.missing_chunks,
+			vec![("file".to_string(), missing.id)]
+		);
+
+		assert!(matches!(
+			store.get("file", io::sink()),
+			Err(ChunkStoreError::CorruptedChunk(id)) if id == corrupted.id
+		));
+	}
+
+	#[test]
+	fn names_are_checked() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = ChunkStore::open(dir.path()).unwrap();
+
+		for name in ["", "../escape", ".hidden", "a/b"] {
+			assert!(matches!(
+				store.put(name, io::empty()),
+				Err(ChunkStoreError::InvalidName(_))
+			));
+		}
+	}
+}
@@ -0,0 +1,4 @@
+fn main() {
+	uniffi::generate_scaffolding("src/sd_core.udl")
+		.expect("Failed to generate the FFI scaffolding");
+}
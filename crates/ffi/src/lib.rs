@@ -0,0 +1,290 @@
+//! Bindings of [`sd_core_sdk`] for Swift and Kotlin, generated by uniffi from `sd_core.udl`.
+//!
+//! The calls block on a runtime owned by the [`Core`], so apps should make them off their main
+//! thread. Passphrases are wrapped in [`Protected`] as soon as they cross into Rust, so they're
+//! zeroed once the call returns; the copy on the other side is up to the app.
+
+use std::sync::Mutex;
+
+use sd_crypto::Protected;
+use thiserror::Error;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+uniffi::include_scaffolding!("sd_core");
+
+#[derive(Error, Debug)]
+pub enum CoreError {
+	#[error("failed to start the core: {0}")]
+	Start(String),
+	#[error("{0}")]
+	Procedure(String),
+	#[error("{0}")]
+	Decode(String),
+	#[error("invalid id '{0}'")]
+	InvalidId(String),
+}
+
+impl From<sd_core_sdk::SdkError> for CoreError {
+	fn from(e: sd_core_sdk::SdkError) -> Self {
+		match e {
+			sd_core_sdk::SdkError::Start(_) => Self::Start(e.to_string()),
+			sd_core_sdk::SdkError::Procedure { .. } => Self::Procedure(e.to_string()),
+			sd_core_sdk::SdkError::Decode { .. } => Self::Decode(e.to_string()),
+		}
+	}
+}
+
+pub struct Library {
+	pub id: String,
+	pub name: String,
+	pub description: Option<String>,
+}
+
+impl From<sd_core_sdk::Library> for Library {
+	fn from(library: sd_core_sdk::Library) -> Self {
+		Self {
+			id: library.id.to_string(),
+			name: library.name,
+			description: library.description,
+		}
+	}
+}
+
+pub struct Location {
+	pub id: i32,
+	pub name: Option<String>,
+	pub path: Option<String>,
+}
+
+impl From<sd_core_sdk::Location> for Location {
+	fn from(location: sd_core_sdk::Location) -> Self {
+		Self {
+			id: location.id,
+			name: location.name,
+			path: location.path,
+		}
+	}
+}
+
+pub enum JobStatus {
+	Queued,
+	Running,
+	Completed,
+	Canceled,
+	Failed,
+	Paused,
+	CompletedWithErrors,
+}
+
+impl From<sd_core_sdk::JobStatus> for JobStatus {
+	fn from(status: sd_core_sdk::JobStatus) -> Self {
+		use sd_core_sdk::JobStatus::*;
+
+		match status {
+			Queued => Self::Queued,
+			Running => Self::Running,
+			Completed => Self::Completed,
+			Canceled => Self::Canceled,
+			Failed => Self::Failed,
+			Paused => Self::Paused,
+			CompletedWithErrors => Self::CompletedWithErrors,
+		}
+	}
+}
+
+pub struct Job {
+	pub id: String,
+	pub name: String,
+	pub status: JobStatus,
+	pub task_count: i32,
+	pub completed_task_count: i32,
+	pub message: String,
+	pub errors: Vec<String>,
+}
+
+impl From<sd_core_sdk::Job> for Job {
+	fn from(job: sd_core_sdk::Job) -> Self {
+		Self {
+			id: job.id.to_string(),
+			name: job.name,
+			status: job.status.into(),
+			task_count: job.task_count,
+			completed_task_count: job.completed_task_count,
+			message: job.message,
+			errors: job.errors,
+		}
+	}
+}
+
+pub struct SearchQuery {
+	pub location_id: Option<i32>,
+	pub text: Option<String>,
+	pub extension: Option<String>,
+	pub path: Option<String>,
+	pub take: Option<i32>,
+	pub cursor: Option<Vec<u8>>,
+}
+
+impl From<SearchQuery> for sd_core_sdk::SearchQuery {
+	fn from(query: SearchQuery) -> Self {
+		Self {
+			location_id: query.location_id,
+			text: query.text,
+			extension: query.extension,
+			path: query.path,
+			take: query.take,
+			cursor: query.cursor,
+		}
+	}
+}
+
+pub struct FileEntry {
+	pub id: i32,
+	pub location_id: Option<i32>,
+	pub path: Option<String>,
+	pub name: Option<String>,
+	pub extension: Option<String>,
+	pub is_dir: bool,
+	pub size_in_bytes: Option<u64>,
+	pub cas_id: Option<String>,
+}
+
+impl From<sd_core_sdk::FileEntry> for FileEntry {
+	fn from(entry: sd_core_sdk::FileEntry) -> Self {
+		Self {
+			id: entry.id,
+			location_id: entry.location_id,
+			path: entry.path,
+			name: entry.name,
+			extension: entry.extension,
+			is_dir: entry.is_dir,
+			size_in_bytes: entry.size_in_bytes,
+			cas_id: entry.cas_id,
+		}
+	}
+}
+
+pub struct SearchPage {
+	pub items: Vec<FileEntry>,
+	pub cursor: Option<Vec<u8>>,
+}
+
+pub struct Core {
+	runtime: Runtime,
+	core: sd_core_sdk::Core,
+	shut_down: Mutex<bool>,
+}
+
+impl Core {
+	pub fn new(data_dir: String) -> Result<Self, CoreError> {
+		let runtime = Runtime::new().map_err(|e| CoreError::Start(e.to_string()))?;
+		let core = runtime.block_on(sd_core_sdk::Core::start(data_dir))?;
+
+		Ok(Self {
+			runtime,
+			core,
+			shut_down: Mutex::new(false),
+		})
+	}
+
+	pub fn libraries(&self) -> Result<Vec<Library>, CoreError> {
+		let libraries = self.runtime.block_on(self.core.libraries())?;
+
+		Ok(libraries.into_iter().map(Into::into).collect())
+	}
+
+	pub fn create_library(&self, name: String) -> Result<Library, CoreError> {
+		Ok(self
+			.runtime
+			.block_on(self.core.create_library(name))?
+			.into())
+	}
+
+	pub fn locations(&self, library_id: String) -> Result<Vec<Location>, CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+		let locations = self.runtime.block_on(library.locations())?;
+
+		Ok(locations.into_iter().map(Into::into).collect())
+	}
+
+	pub fn add_location(&self, library_id: String, path: String) -> Result<(), CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+
+		Ok(self.runtime.block_on(library.add_location(path))?)
+	}
+
+	pub fn rescan_location(&self, library_id: String, location_id: i32) -> Result<(), CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+
+		Ok(self
+			.runtime
+			.block_on(library.rescan_location(location_id))?)
+	}
+
+	pub fn unlock_location(
+		&self,
+		library_id: String,
+		location_id: i32,
+		passphrase: String,
+	) -> Result<(), CoreError> {
+		let passphrase = Protected::new(passphrase);
+		let library = self.core.library(parse_id(library_id)?);
+
+		Ok(self
+			.runtime
+			.block_on(library.unlock_location(location_id, passphrase))?)
+	}
+
+	pub fn lock_location(&self, library_id: String, location_id: i32) -> Result<(), CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+
+		Ok(self.runtime.block_on(library.lock_location(location_id))?)
+	}
+
+	pub fn search(&self, library_id: String, query: SearchQuery) -> Result<SearchPage, CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+		let page = self.runtime.block_on(library.search(query.into()))?;
+
+		Ok(SearchPage {
+			items: page.items.into_iter().map(Into::into).collect(),
+			cursor: page.cursor,
+		})
+	}
+
+	pub fn jobs(&self, library_id: String) -> Result<Vec<Job>, CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+		let jobs = self.runtime.block_on(library.jobs())?;
+
+		Ok(jobs.into_iter().map(Into::into).collect())
+	}
+
+	pub fn pause_job(&self, library_id: String, job_id: String) -> Result<(), CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+
+		Ok(self
+			.runtime
+			.block_on(library.pause_job(parse_id(job_id)?))?)
+	}
+
+	pub fn resume_job(&self, library_id: String, job_id: String) -> Result<(), CoreError> {
+		let library = self.core.library(parse_id(library_id)?);
+
+		Ok(self
+			.runtime
+			.block_on(library.resume_job(parse_id(job_id)?))?)
+	}
+
+	/// Pauses the running jobs, so they resume on the next start. Only the first call does anything.
+	pub fn shutdown(&self) {
+		let mut shut_down = self.shut_down.lock().unwrap_or_else(|e| e.into_inner());
+		if !*shut_down {
+			self.runtime.block_on(self.core.shutdown());
+			*shut_down = true;
+		}
+	}
+}
+
+fn parse_id(id: String) -> Result<Uuid, CoreError> {
+	Uuid::parse_str(&id).map_err(|_| CoreError::InvalidId(id))
+}
@@ -278,6 +278,8 @@ extension_category_enum! {
 	BookExtension _ALL_BOOK_EXTENSIONS {
 		Azw = [0x52, 0x49, 0x46, 0x46],
 		Azw3 = [0x52, 0x49, 0x46, 0x46],
+		Cbr = [0x52, 0x61, 0x72, 0x21, 0x1A, 0x07],
+		Cbz = [0x50, 0x4B, 0x03, 0x04],
 		Epub = [0x50, 0x4B, 0x03, 0x04],
 		Mobi = [0x4D, 0x4F, 0x42, 0x49],
 	}
@@ -0,0 +1,308 @@
+//! Compression on the wire, so sync and file transfers stay practical over slow links. Data is
+//! sent in [`Frame`]s, each saying how it was encoded, so content which is already compressed,
+//! like most media, can go as is while the rest of the stream is compressed.
+//!
+//! Peers advertise the [`Compression`]s they can decode and the sender only uses one both support.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use thiserror::Error;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+/// Bytes of a frame besides its data: the encoding and both lengths
+const FRAME_HEADER_LEN: u64 = 1 + 4 + 4;
+/// Fast enough to not be the bottleneck of a transfer while still saving most of what can be
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+	#[error("io error reading frame: {0}")]
+	Io(#[from] io::Error),
+	#[error("invalid frame encoding '{0}'")]
+	InvalidEncoding(u8),
+	#[error("frame of '{len}' bytes is larger than the allowed '{max}'")]
+	TooLarge { len: u32, max: u32 },
+	#[error("error decompressing frame: {0}")]
+	Decompression(io::Error),
+	#[error("frame decompressed to '{received}' bytes instead of '{expected}'")]
+	LengthMismatch { expected: u32, received: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Compression {
+	None = 0,
+	Zstd = 1,
+}
+
+impl Compression {
+	/// How content of the given MIME type should be sent, as compressing what already is only
+	/// costs time
+	pub fn for_mime(self, mime: Option<&str>) -> Self {
+		match mime {
+			Some(mime) if is_compressed_mime(mime) => Self::None,
+			_ => self,
+		}
+	}
+}
+
+impl TryFrom<u8> for Compression {
+	type Error = FrameError;
+
+	fn try_from(value: u8) -> Result<Self, Self::Error> {
+		match value {
+			0 => Ok(Self::None),
+			1 => Ok(Self::Zstd),
+			v => Err(FrameError::InvalidEncoding(v)),
+		}
+	}
+}
+
+/// Whether content of a MIME type is compressed by its format, so zstd won't make it smaller
+pub fn is_compressed_mime(mime: &str) -> bool {
+	let essence = mime.split([';', ',']).next().unwrap_or_default().trim();
+
+	match essence.split_once('/') {
+		Some(("video", _)) => true,
+		Some(("audio", subtype)) => !matches!(
+			subtype,
+			"wav" | "x-wav" | "aiff" | "x-aiff" | "midi" | "x-midi"
+		),
+		Some(("image", subtype)) => !matches!(
+			subtype,
+			"bmp" | "svg+xml" | "tiff" | "vnd.microsoft.icon" | "x-portable-pixmap"
+		),
+		Some(("application", subtype)) => matches!(
+			subtype,
+			"pdf"
+				| "zip" | "gzip"
+				| "x-7z-compressed"
+				| "x-bzip2" | "x-xz"
+				| "zstd" | "vnd.rar"
+				| "epub+zip" | "java-archive"
+				| "vnd.openxmlformats-officedocument.wordprocessingml.document"
+				| "vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+				| "vnd.openxmlformats-officedocument.presentationml.presentation"
+		),
+		_ => false,
+	}
+}
+
+/// A piece of a stream, compressed or not
+#[derive(Debug)]
+pub struct Frame {
+	compression: Compression,
+	raw_len: u32,
+	data: Vec<u8>,
+}
+
+impl Frame {
+	/// Compresses `data`, keeping it as is when that doesn't make it smaller
+	pub fn encode(data: &[u8], compression: Compression) -> io::Result<Self> {
+		let raw_len = u32::try_from(data.len())
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame is too large"))?;
+
+		if compression == Compression::Zstd {
+			let compressed = zstd::bulk::compress(data, ZSTD_LEVEL)?;
+
+			if compressed.len() < data.len() {
+				return Ok(Self {
+					compression,
+					raw_len,
+					data: compressed,
+				});
+			}
+		}
+
+		Ok(Self {
+			compression: Compression::None,
+			raw_len,
+			data: data.to_vec(),
+		})
+	}
+
+	pub async fn from_stream(
+		stream: &mut (impl AsyncRead + Unpin),
+		max_len: u32,
+	) -> Result<Self, FrameError> {
+		let compression = Compression::try_from(stream.read_u8().await?)?;
+
+		let raw_len = stream.read_u32_le().await?;
+		if raw_len > max_len {
+			return Err(FrameError::TooLarge {
+				len: raw_len,
+				max: max_len,
+			});
+		}
+
+		// Data is only sent compressed when that makes it smaller
+		let len = stream.read_u32_le().await?;
+		if len > raw_len {
+			return Err(FrameError::TooLarge { len, max: raw_len });
+		}
+
+		let mut data = vec![0; len as usize];
+		stream.read_exact(&mut data).await?;
+
+		Ok(Self {
+			compression,
+			raw_len,
+			data,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(FRAME_HEADER_LEN as usize + self.data.len());
+		buf.push(self.compression as u8);
+		buf.extend_from_slice(&self.raw_len.to_le_bytes());
+		buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&self.data);
+		buf
+	}
+
+	pub fn decode(self) -> Result<Vec<u8>, FrameError> {
+		let data = match self.compression {
+			Compression::None => self.data,
+			Compression::Zstd => zstd::bulk::decompress(&self.data, self.raw_len as usize)
+				.map_err(FrameError::Decompression)?,
+		};
+
+		if data.len() != self.raw_len as usize {
+			return Err(FrameError::LengthMismatch {
+				expected: self.raw_len,
+				received: data.len(),
+			});
+		}
+
+		Ok(data)
+	}
+
+	/// Length of the content of the frame once decoded
+	pub fn raw_len(&self) -> u64 {
+		self.raw_len as u64
+	}
+
+	/// Length of the frame as sent over the network
+	pub fn wire_len(&self) -> u64 {
+		FRAME_HEADER_LEN + self.data.len() as u64
+	}
+}
+
+/// How much data went through a connection against how much of it was actually sent
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+	raw: AtomicU64,
+	wire: AtomicU64,
+}
+
+impl CompressionStats {
+	pub fn record(&self, frame: &Frame) {
+		self.raw.fetch_add(frame.raw_len(), Ordering::Relaxed);
+		self.wire.fetch_add(frame.wire_len(), Ordering::Relaxed);
+	}
+
+	/// Records data sent outside of frames, to or from a peer which can't decode them
+	pub fn record_raw(&self, len: u64) {
+		self.raw.fetch_add(len, Ordering::Relaxed);
+		self.wire.fetch_add(len, Ordering::Relaxed);
+	}
+
+	pub fn merge(&self, other: &Self) {
+		self.raw.fetch_add(other.raw_bytes(), Ordering::Relaxed);
+		self.wire.fetch_add(other.wire_bytes(), Ordering::Relaxed);
+	}
+
+	pub fn raw_bytes(&self) -> u64 {
+		self.raw.load(Ordering::Relaxed)
+	}
+
+	pub fn wire_bytes(&self) -> u64 {
+		self.wire.load(Ordering::Relaxed)
+	}
+
+	/// Zero when framing cost more than compression saved, as on a stream of media
+	pub fn saved_bytes(&self) -> u64 {
+		self.raw_bytes().saturating_sub(self.wire_bytes())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_frame_roundtrip() {
+		let text = b"Spacedrive ".repeat(1000);
+		let mut state = 0x2545_f491_4f6c_dd1d_u64;
+		let random = (0..4096)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 7;
+				state ^= state << 17;
+				state as u8
+			})
+			.collect::<Vec<_>>();
+
+		for (data, compression) in [
+			(&text, Compression::Zstd),
+			(&text, Compression::None),
+			(&random, Compression::Zstd),
+		] {
+			let stats = CompressionStats::default();
+			let frame = Frame::encode(data, compression).unwrap();
+			stats.record(&frame);
+
+			let bytes = frame.to_bytes();
+			assert_eq!(bytes.len() as u64, stats.wire_bytes());
+
+			let received = Frame::from_stream(&mut Cursor::new(bytes), data.len() as u32)
+				.await
+				.unwrap()
+				.decode()
+				.unwrap();
+			assert_eq!(&received, data);
+		}
+
+		let frame = Frame::encode(&text, Compression::Zstd).unwrap();
+		assert!(frame.wire_len() < text.len() as u64 / 10);
+
+		// Incompressible data goes as is instead of growing
+		let frame = Frame::encode(&random, Compression::Zstd).unwrap();
+		assert_eq!(frame.compression, Compression::None);
+	}
+
+	#[tokio::test]
+	async fn test_frame_too_large() {
+		let bytes = Frame::encode(&[0; 1024], Compression::Zstd)
+			.unwrap()
+			.to_bytes();
+
+		assert!(matches!(
+			Frame::from_stream(&mut Cursor::new(bytes), 512).await,
+			Err(FrameError::TooLarge {
+				len: 1024,
+				max: 512
+			})
+		));
+	}
+
+	#[test]
+	fn test_compressed_mimes() {
+		assert!(is_compressed_mime("video/mp4"));
+		assert!(is_compressed_mime("image/heif,image/heif-sequence"));
+		assert!(is_compressed_mime("audio/mpeg"));
+		assert!(!is_compressed_mime("audio/midi, audio/x-midi"));
+		assert!(is_compressed_mime("application/pdf"));
+		assert!(!is_compressed_mime("image/bmp"));
+		assert!(!is_compressed_mime("audio/wav"));
+		assert!(!is_compressed_mime("text/plain; charset=utf-8"));
+
+		assert_eq!(
+			Compression::Zstd.for_mime(Some("image/jpeg")),
+			Compression::None
+		);
+		assert_eq!(Compression::Zstd.for_mime(None), Compression::Zstd);
+	}
+}
@@ -1,5 +1,6 @@
 //! Rust Peer to Peer Networking Library
 
+pub mod compression;
 mod event;
 mod manager;
 mod manager_stream;
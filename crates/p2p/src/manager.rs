@@ -9,13 +9,13 @@ use std::{
 
 use libp2p::{core::muxing::StreamMuxerBox, swarm::SwarmBuilder, Transport};
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, RwLock};
 use tracing::{debug, error, warn};
 
 use crate::{
 	spacetime::{SpaceTime, UnicastStream},
-	DiscoveredPeer, Keypair, ManagerStream, ManagerStreamAction, Mdns, MdnsState, Metadata,
-	MetadataManager, PeerId,
+	ConnectionPath, DiscoveredPeer, Keypair, ManagerStream, ManagerStreamAction, Mdns, MdnsState,
+	Metadata, MetadataManager, PeerId,
 };
 
 /// Is the core component of the P2P system that holds the state and delegates actions to the other components
@@ -25,14 +25,20 @@ pub struct Manager<TMetadata: Metadata> {
 	pub(crate) peer_id: PeerId,
 	pub(crate) application_name: &'static [u8],
 	pub(crate) stream_id: AtomicU64,
+	/// Addresses of peers which were given to us instead of being discovered
+	pub(crate) known_peers: RwLock<HashMap<PeerId, Vec<SocketAddr>>>,
+	/// The connections currently open with each peer
+	pub(crate) connections: RwLock<HashMap<PeerId, Vec<ConnectionPath>>>,
 	event_stream_tx: mpsc::Sender<ManagerStreamAction<TMetadata>>,
 }
 
 impl<TMetadata: Metadata> Manager<TMetadata> {
 	/// create a new P2P manager. Please do your best to make the callback closures as fast as possible because they will slow the P2P event loop!
+	/// The manager listens on `port`, or on a random free port each time when it's `None`.
 	pub async fn new(
 		application_name: &'static str,
 		keypair: &Keypair,
+		port: Option<u16>,
 		metadata_manager: Arc<MetadataManager<TMetadata>>,
 	) -> Result<(Arc<Self>, ManagerStream<TMetadata>), ManagerError> {
 		application_name
@@ -56,6 +62,8 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 					.to_vec(),
 			)),
 			stream_id: AtomicU64::new(0),
+			known_peers: Default::default(),
+			connections: Default::default(),
 			peer_id,
 			event_stream_tx,
 		});
@@ -70,15 +78,16 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 			keypair.raw_peer_id(),
 		)
 		.build();
+		let port = port.unwrap_or(0);
 		{
 			let listener_id = swarm
-            .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().expect("Error passing libp2p multiaddr. This value is hardcoded so this should be impossible."))
+            .listen_on(format!("/ip4/0.0.0.0/udp/{port}/quic-v1").parse().expect("Error passing libp2p multiaddr. This value is hardcoded so this should be impossible."))
             .unwrap();
 			debug!("created ipv4 listener with id '{:?}'", listener_id);
 		}
 		{
 			let listener_id = swarm
-        .listen_on("/ip6/::/udp/0/quic-v1".parse().expect("Error passing libp2p multiaddr. This value is hardcoded so this should be impossible."))
+        .listen_on(format!("/ip6/::/udp/{port}/quic-v1").parse().expect("Error passing libp2p multiaddr. This value is hardcoded so this should be impossible."))
         .unwrap();
 			debug!("created ipv4 listener with id '{:?}'", listener_id);
 		}
@@ -122,6 +131,21 @@ impl<TMetadata: Metadata> Manager<TMetadata> {
 			.collect()
 	}
 
+	/// Remembers where a peer which can't be discovered on the local network, like a server on
+	/// another subnet, can be reached. Streams to it then dial these addresses.
+	pub async fn add_known_peer(&self, peer_id: PeerId, addresses: Vec<SocketAddr>) {
+		self.known_peers.write().await.insert(peer_id, addresses);
+	}
+
+	pub async fn remove_known_peer(&self, peer_id: PeerId) {
+		self.known_peers.write().await.remove(&peer_id);
+	}
+
+	/// How each connected peer is reached, a peer may have several connections at once
+	pub async fn connections(&self) -> HashMap<PeerId, Vec<ConnectionPath>> {
+		self.connections.read().await.clone()
+	}
+
 	pub async fn get_connected_peers(&self) -> Result<Vec<PeerId>, ()> {
 		let (tx, rx) = oneshot::channel();
 		self.emit(ManagerStreamAction::GetConnectedPeers(tx)).await;
@@ -22,7 +22,7 @@ use tracing::{debug, error, info, warn};
 use crate::{
 	quic_multiaddr_to_socketaddr, socketaddr_to_quic_multiaddr,
 	spacetime::{OutboundRequest, SpaceTime, UnicastStream},
-	Event, Manager, Mdns, Metadata, PeerId,
+	ConnectionPath, Event, Manager, Mdns, Metadata, PeerId,
 };
 
 /// TODO
@@ -107,7 +107,15 @@ where
 								return Some(event);
 							}
 						},
-						SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+						SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+							self.manager
+								.connections
+								.write()
+								.await
+								.entry(PeerId(peer_id))
+								.or_default()
+								.push(ConnectionPath::new(endpoint.get_remote_address()));
+
 							if let Some(streams) = self.on_establish_streams.remove(&peer_id) {
 								for event in streams {
 									self.swarm
@@ -121,10 +129,29 @@ where
 								}
 							}
 						},
-						SwarmEvent::ConnectionClosed { .. } => {},
+						SwarmEvent::ConnectionClosed { peer_id, endpoint, .. } => {
+							let mut connections = self.manager.connections.write().await;
+							if let Some(paths) = connections.get_mut(&PeerId(peer_id)) {
+								let closed = ConnectionPath::new(endpoint.get_remote_address());
+								if let Some(index) = paths.iter().position(|path| *path == closed) {
+									paths.remove(index);
+								}
+
+								if paths.is_empty() {
+									connections.remove(&PeerId(peer_id));
+								}
+							}
+						},
 						SwarmEvent::IncomingConnection { local_addr, .. } => debug!("incoming connection from '{}'", local_addr),
 						SwarmEvent::IncomingConnectionError { local_addr, error, .. } => warn!("handshake error with incoming connection from '{}': {}", local_addr, error),
-						SwarmEvent::OutgoingConnectionError { peer_id, error } => warn!("error establishing connection with '{:?}': {}", peer_id, error),
+						SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+							warn!("error establishing connection with '{:?}': {}", peer_id, error);
+
+							// Dropping the requests fails the streams waiting on the connection
+							if let Some(peer_id) = peer_id {
+								self.on_establish_streams.remove(&peer_id);
+							}
+						},
 						SwarmEvent::NewListenAddr { address, .. } => {
 							match quic_multiaddr_to_socketaddr(address) {
 								Ok(addr) => {
@@ -215,16 +242,26 @@ where
 			}
 			ManagerStreamAction::StartStream(peer_id, rx) => {
 				if !self.swarm.connected_peers().any(|v| *v == peer_id.0) {
-					let addresses = self
+					let mut addresses = self
 						.mdns
 						.state
 						.discovered
 						.read()
 						.await
 						.get(&peer_id)
-						.unwrap()
-						.addresses
-						.clone();
+						.map(|peer| peer.addresses.clone())
+						.unwrap_or_default();
+					if let Some(known_addresses) =
+						self.manager.known_peers.read().await.get(&peer_id)
+					{
+						addresses.extend_from_slice(known_addresses);
+					}
+
+					if addresses.is_empty() {
+						// Dropping `rx` fails the stream
+						warn!("can't start a stream with peer '{peer_id}' as there's no address to dial it at");
+						return None;
+					}
 
 					match self.swarm.dial(
 						DialOpts::peer_id(peer_id.0)
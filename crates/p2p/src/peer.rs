@@ -1,5 +1,7 @@
 use std::{net::SocketAddr, sync::Arc};
 
+use libp2p::{multiaddr::Protocol, Multiaddr};
+
 use crate::{Manager, ManagerStreamAction, Metadata, PeerId};
 
 /// Represents a discovered peer.
@@ -39,3 +41,25 @@ pub struct ConnectedPeer {
 	/// get the peer id of the discovered peer
 	pub peer_id: PeerId,
 }
+
+/// How a connection to a peer gets to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub struct ConnectionPath {
+	/// The address at the other end of the connection, which is the relay's when it's relayed
+	pub remote_address: String,
+	/// Whether the connection goes through a relay instead of straight to the peer
+	pub relayed: bool,
+}
+
+impl ConnectionPath {
+	pub(crate) fn new(address: &Multiaddr) -> Self {
+		Self {
+			remote_address: address.to_string(),
+			relayed: address
+				.iter()
+				.any(|protocol| matches!(protocol, Protocol::P2pCircuit)),
+		}
+	}
+}
@@ -0,0 +1,555 @@
+//! Delta transfers, sending only what changed in a file the receiver already has an older version
+//! of, like a VM image or a video project saved again. This is the rsync algorithm: the receiver
+//! sends a [`Signature`] of its file, a weak rolling checksum and a strong hash for each block, and
+//! the sender walks its file looking for those blocks at any offset. Matches are sent as block
+//! indexes, everything else as is.
+//!
+//! The receiver rebuilds the file from its old version and the instructions, then checks it against
+//! a hash of the whole file sent at the end, so a weak and strong hash collision can't go unnoticed.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+use tokio::io::{
+	self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+};
+use tracing::debug;
+
+use super::Transfer;
+
+/// Smaller files are sent whole, the signature not being worth a round trip
+pub const DELTA_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+const MIN_DELTA_BLOCK_SIZE: u32 = 4 * 1024;
+const MAX_DELTA_BLOCK_SIZE: u32 = 1024 * 1024;
+/// Bounds the memory a peer can make us allocate for a signature, enough for a 1 TiB file
+const MAX_SIGNATURE_BLOCKS: u32 = 1024 * 1024;
+/// Unmatched bytes are sent in pieces of at most this size
+const MAX_LITERAL_LEN: usize = 1024 * 1024;
+/// How much of the file is read at once when looking for matching blocks
+const READ_AHEAD: usize = 1024 * 1024;
+const STRONG_HASH_LEN: usize = 16;
+
+const OP_COPY: u8 = 0;
+const OP_DATA: u8 = 1;
+const OP_END: u8 = 2;
+
+#[derive(Debug, Error)]
+pub enum DeltaError {
+	#[error("io error during delta transfer: {0}")]
+	Io(#[from] io::Error),
+	#[error("invalid delta block size '{0}'")]
+	InvalidBlockSize(u32),
+	#[error("signature has too many blocks '{0}'")]
+	TooManyBlocks(u32),
+	#[error("invalid delta instruction '{0}'")]
+	InvalidInstruction(u8),
+	#[error("delta refers to block '{0}' which isn't in the signature")]
+	UnknownBlock(u32),
+	#[error("delta literal of '{0}' bytes is too long")]
+	LiteralTooLong(u32),
+	#[error("received '{received}' bytes instead of '{expected}'")]
+	SizeMismatch { expected: u64, received: u64 },
+	#[error("received file doesn't match the sent one")]
+	ChecksumMismatch,
+}
+
+/// Whether a file the receiver already has an older version of should be sent as a delta
+pub fn use_delta(size: u64, existing_size: u64) -> bool {
+	size >= DELTA_THRESHOLD && existing_size >= MIN_DELTA_BLOCK_SIZE as u64
+}
+
+/// About the square root of the size of the file, as rsync does, balancing the size of the
+/// signature against how much of a changed block is sent again
+fn delta_block_size(size: u64) -> u32 {
+	let root = (size as f64).sqrt() as u32;
+	root.checked_next_power_of_two()
+		.unwrap_or(MAX_DELTA_BLOCK_SIZE)
+		.clamp(MIN_DELTA_BLOCK_SIZE, MAX_DELTA_BLOCK_SIZE)
+}
+
+/// The rsync rolling checksum, which can be moved along a file one byte at a time
+#[derive(Debug, Clone, Copy)]
+struct RollingChecksum {
+	a: u32,
+	b: u32,
+}
+
+impl RollingChecksum {
+	fn new(block: &[u8]) -> Self {
+		let len = block.len() as u32;
+		let (mut a, mut b) = (0u32, 0u32);
+
+		for (i, byte) in block.iter().enumerate() {
+			a = a.wrapping_add(*byte as u32);
+			b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+		}
+
+		Self {
+			a: a & 0xffff,
+			b: b & 0xffff,
+		}
+	}
+
+	fn digest(self) -> u32 {
+		(self.b << 16) | self.a
+	}
+
+	/// Moves the window one byte forward, dropping `out` and taking `into`
+	fn roll(&mut self, out: u8, into: u8, len: usize) {
+		self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32) & 0xffff;
+		self.b = self
+			.b
+			.wrapping_sub((len as u32).wrapping_mul(out as u32))
+			.wrapping_add(self.a)
+			& 0xffff;
+	}
+}
+
+fn strong_hash(block: &[u8]) -> [u8; STRONG_HASH_LEN] {
+	let mut hash = [0; STRONG_HASH_LEN];
+	hash.copy_from_slice(&blake3::hash(block).as_bytes()[..STRONG_HASH_LEN]);
+	hash
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSignature {
+	weak: u32,
+	strong: [u8; STRONG_HASH_LEN],
+}
+
+/// The blocks of the file the receiver has, only full ones as the last one can't be matched
+/// anywhere but at the end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+	block_size: u32,
+	blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+	pub async fn compute(
+		file: &mut (impl AsyncRead + Unpin),
+		size: u64,
+	) -> Result<Self, DeltaError> {
+		let block_size = delta_block_size(size);
+		let mut buf = vec![0u8; block_size as usize];
+		let mut blocks = Vec::with_capacity((size / block_size as u64) as usize);
+
+		while blocks.len() < MAX_SIGNATURE_BLOCKS as usize
+			&& read_full(file, &mut buf).await? == buf.len()
+		{
+			blocks.push(BlockSignature {
+				weak: RollingChecksum::new(&buf).digest(),
+				strong: strong_hash(&buf),
+			});
+		}
+
+		Ok(Self { block_size, blocks })
+	}
+
+	pub fn block_size(&self) -> u32 {
+		self.block_size
+	}
+
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, DeltaError> {
+		let block_size = stream.read_u32_le().await?;
+		if !(MIN_DELTA_BLOCK_SIZE..=MAX_DELTA_BLOCK_SIZE).contains(&block_size) {
+			return Err(DeltaError::InvalidBlockSize(block_size));
+		}
+
+		let count = stream.read_u32_le().await?;
+		if count > MAX_SIGNATURE_BLOCKS {
+			return Err(DeltaError::TooManyBlocks(count));
+		}
+
+		let mut blocks = Vec::with_capacity(count as usize);
+		for _ in 0..count {
+			let weak = stream.read_u32_le().await?;
+			let mut strong = [0; STRONG_HASH_LEN];
+			stream.read_exact(&mut strong).await?;
+
+			blocks.push(BlockSignature { weak, strong });
+		}
+
+		Ok(Self { block_size, blocks })
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(8 + self.blocks.len() * (4 + STRONG_HASH_LEN));
+		buf.extend_from_slice(&self.block_size.to_le_bytes());
+		buf.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+
+		for block in &self.blocks {
+			buf.extend_from_slice(&block.weak.to_le_bytes());
+			buf.extend_from_slice(&block.strong);
+		}
+
+		buf
+	}
+
+	/// The blocks by weak checksum, for the sender to look them up as it goes
+	fn index(&self) -> HashMap<u32, Vec<u32>> {
+		let mut index = HashMap::<_, Vec<_>>::with_capacity(self.blocks.len());
+		for (i, block) in self.blocks.iter().enumerate() {
+			index.entry(block.weak).or_default().push(i as u32);
+		}
+
+		index
+	}
+}
+
+/// Reads until `buf` is full or the end of the file
+async fn read_full(
+	file: &mut (impl AsyncRead + Unpin),
+	buf: &mut [u8],
+) -> Result<usize, io::Error> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		match file.read(&mut buf[filled..]).await? {
+			0 => break,
+			read => filled += read,
+		}
+	}
+
+	Ok(filled)
+}
+
+impl<'a, F> Transfer<'a, F>
+where
+	F: Fn(u8) + 'a,
+{
+	/// Sends a file as the blocks of the receiver's version it can be rebuilt from and the bytes
+	/// which aren't in any of them
+	pub async fn send_delta(
+		&self,
+		stream: &mut (impl AsyncWrite + Unpin),
+		mut file: impl AsyncRead + Unpin,
+		signature: &Signature,
+	) -> Result<(), DeltaError> {
+		let block_size = signature.block_size as usize;
+		let index = signature.index();
+		let mut hasher = blake3::Hasher::new();
+
+		// `data[..literal_start]` was sent, `data[literal_start..start]` is waiting to be
+		let mut data = Vec::with_capacity(block_size + READ_AHEAD);
+		let mut literal_start = 0;
+		let mut start = 0;
+		let mut eof = false;
+		let mut rolling = None;
+		let mut sent = 0u64;
+		let mut matched_blocks = 0u64;
+
+		loop {
+			// Keeps a full window and the byte after it around, unless the file ends
+			if !eof && data.len() < start + block_size + 1 {
+				data.drain(..literal_start);
+				start -= literal_start;
+				literal_start = 0;
+
+				let wanted = start + block_size + READ_AHEAD;
+				let filled = data.len();
+				data.resize(wanted, 0);
+				let read = read_full(&mut file, &mut data[filled..]).await?;
+				data.truncate(filled + read);
+				eof = filled + read < wanted;
+			}
+
+			let end = start + block_size;
+			if end > data.len() {
+				// What's left is shorter than a block, it can't match
+				start = data.len();
+				break;
+			}
+
+			let window = &data[start..end];
+			let checksum = *rolling.get_or_insert_with(|| RollingChecksum::new(window));
+
+			let matched = index.get(&checksum.digest()).and_then(|candidates| {
+				let strong = strong_hash(window);
+				candidates
+					.iter()
+					.find(|i| signature.blocks[**i as usize].strong == strong)
+			});
+
+			if let Some(block) = matched {
+				sent += self
+					.send_literal(stream, &mut hasher, &data[literal_start..start])
+					.await?;
+
+				let mut op = vec![OP_COPY];
+				op.extend_from_slice(&block.to_le_bytes());
+				stream.write_all(&op).await?;
+
+				hasher.update(window);
+				sent += block_size as u64;
+				matched_blocks += 1;
+				self.progress(sent);
+
+				start = end;
+				literal_start = start;
+				rolling = None;
+				continue;
+			}
+
+			rolling = data.get(end).map(|next| {
+				let mut checksum = checksum;
+				checksum.roll(data[start], *next, block_size);
+				checksum
+			});
+			start += 1;
+
+			if start - literal_start >= MAX_LITERAL_LEN {
+				sent += self
+					.send_literal(stream, &mut hasher, &data[literal_start..start])
+					.await?;
+				literal_start = start;
+				self.progress(sent);
+			}
+		}
+
+		sent += self
+			.send_literal(stream, &mut hasher, &data[literal_start..start])
+			.await?;
+		self.progress(sent);
+
+		let mut op = vec![OP_END];
+		op.extend_from_slice(hasher.finalize().as_bytes());
+		stream.write_all(&op).await?;
+		stream.flush().await?;
+
+		debug!(
+			"Sent delta of '{}' reusing {matched_blocks} blocks of '{block_size}' bytes",
+			self.req.name
+		);
+
+		Ok(())
+	}
+
+	async fn send_literal(
+		&self,
+		stream: &mut (impl AsyncWrite + Unpin),
+		hasher: &mut blake3::Hasher,
+		literal: &[u8],
+	) -> Result<u64, DeltaError> {
+		for piece in literal.chunks(MAX_LITERAL_LEN) {
+			let mut op = Vec::with_capacity(5 + piece.len());
+			op.push(OP_DATA);
+			op.extend_from_slice(&(piece.len() as u32).to_le_bytes());
+			op.extend_from_slice(piece);
+			stream.write_all(&op).await?;
+
+			hasher.update(piece);
+		}
+
+		Ok(literal.len() as u64)
+	}
+
+	/// Rebuilds the sent file from `old`, the version `signature` was computed from, into `file`
+	pub async fn receive_delta(
+		&self,
+		stream: &mut (impl AsyncRead + Unpin),
+		old: &mut (impl AsyncRead + AsyncSeek + Unpin),
+		mut file: impl AsyncWrite + Unpin,
+		signature: &Signature,
+	) -> Result<(), DeltaError> {
+		let block_size = signature.block_size as usize;
+		let mut buf = vec![0u8; block_size.max(MAX_LITERAL_LEN)];
+		let mut hasher = blake3::Hasher::new();
+		let mut received = 0u64;
+
+		// TODO: Timeout if nothing is being received
+		loop {
+			let data = match stream.read_u8().await? {
+				OP_COPY => {
+					let block = stream.read_u32_le().await?;
+					if block as usize >= signature.blocks.len() {
+						return Err(DeltaError::UnknownBlock(block));
+					}
+
+					old.seek(io::SeekFrom::Start(block as u64 * block_size as u64))
+						.await?;
+					old.read_exact(&mut buf[..block_size]).await?;
+
+					&buf[..block_size]
+				}
+				OP_DATA => {
+					let len = stream.read_u32_le().await?;
+					if len as usize > MAX_LITERAL_LEN {
+						return Err(DeltaError::LiteralTooLong(len));
+					}
+
+					stream.read_exact(&mut buf[..len as usize]).await?;
+
+					&buf[..len as usize]
+				}
+				OP_END => {
+					let mut hash = [0; 32];
+					stream.read_exact(&mut hash).await?;
+
+					if received != self.req.size {
+						return Err(DeltaError::SizeMismatch {
+							expected: self.req.size,
+							received,
+						});
+					}
+
+					if hasher.finalize() != hash {
+						return Err(DeltaError::ChecksumMismatch);
+					}
+
+					file.flush().await?;
+
+					return Ok(());
+				}
+				op => return Err(DeltaError::InvalidInstruction(op)),
+			};
+
+			received += data.len() as u64;
+			if received > self.req.size {
+				return Err(DeltaError::SizeMismatch {
+					expected: self.req.size,
+					received,
+				});
+			}
+
+			hasher.update(data);
+			file.write_all(data).await?;
+			self.progress(received);
+		}
+	}
+
+	fn progress(&self, done: u64) {
+		(self.on_progress)((done * 100 / self.req.size.max(1)).min(100) as u8);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use tokio::{io::BufReader, sync::oneshot};
+
+	use super::{super::SpaceblockRequest, *};
+
+	/// Bytes which don't repeat, so blocks only match where they were copied
+	fn content(len: usize, seed: u64) -> Vec<u8> {
+		let mut state = seed;
+		(0..len)
+			.map(|_| {
+				state = state
+					.wrapping_mul(6364136223846793005)
+					.wrapping_add(1442695040888963407);
+				(state >> 33) as u8
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_rolling_checksum() {
+		let data = content(64, 1);
+		let mut rolling = RollingChecksum::new(&data[..16]);
+
+		for start in 1..=data.len() - 16 {
+			rolling.roll(data[start - 1], data[start + 15], 16);
+			assert_eq!(
+				rolling.digest(),
+				RollingChecksum::new(&data[start..start + 16]).digest()
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_signature() {
+		let old = content(100_000, 2);
+		let signature = Signature::compute(&mut Cursor::new(&old), old.len() as u64)
+			.await
+			.unwrap();
+		assert_eq!(
+			signature.blocks.len(),
+			old.len() / signature.block_size as usize
+		);
+
+		let decoded = Signature::from_stream(&mut Cursor::new(signature.to_bytes()))
+			.await
+			.unwrap();
+		assert_eq!(signature, decoded);
+	}
+
+	#[tokio::test]
+	async fn test_delta_transfer() {
+		let old = content(1024 * 1024, 3);
+
+		// Bytes inserted, overwritten and removed at a few places
+		let mut new = old.clone();
+		new.splice(10_000..10_000, content(3000, 4));
+		new[500_000..500_100].copy_from_slice(&content(100, 5));
+		new.drain(800_000..801_000);
+		new.extend_from_slice(b"appended");
+
+		let signature = Signature::compute(&mut Cursor::new(&old), old.len() as u64)
+			.await
+			.unwrap();
+		let req = SpaceblockRequest {
+			name: "Demo".to_string(),
+			size: new.len() as u64,
+			block_size: super::super::BlockSize::from_size(new.len() as u64),
+		};
+
+		let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+		let (tx, rx) = oneshot::channel();
+		tokio::spawn({
+			let req = req.clone();
+			let new = new.clone();
+			let signature = signature.clone();
+			async move {
+				let mut counted = CountingWriter(&mut client, 0);
+				Transfer::new(&req, |_| {})
+					.send_delta(&mut counted, BufReader::new(Cursor::new(new)), &signature)
+					.await
+					.unwrap();
+				tx.send(counted.1).unwrap();
+			}
+		});
+
+		let mut result = Vec::new();
+		Transfer::new(&req, |_| {})
+			.receive_delta(&mut server, &mut Cursor::new(&old), &mut result, &signature)
+			.await
+			.unwrap();
+		assert_eq!(result, new);
+
+		// Only the blocks around the changes are sent again
+		let sent = rx.await.unwrap();
+		assert!(sent < new.len() / 4, "sent {sent} bytes");
+	}
+
+	struct CountingWriter<W>(W, usize);
+
+	impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+		fn poll_write(
+			mut self: std::pin::Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+			buf: &[u8],
+		) -> std::task::Poll<Result<usize, io::Error>> {
+			let poll = std::pin::Pin::new(&mut self.0).poll_write(cx, buf);
+			if let std::task::Poll::Ready(Ok(written)) = poll {
+				self.1 += written;
+			}
+			poll
+		}
+
+		fn poll_flush(
+			mut self: std::pin::Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+		) -> std::task::Poll<Result<(), io::Error>> {
+			std::pin::Pin::new(&mut self.0).poll_flush(cx)
+		}
+
+		fn poll_shutdown(
+			mut self: std::pin::Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+		) -> std::task::Poll<Result<(), io::Error>> {
+			std::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+		}
+	}
+}
@@ -16,7 +16,20 @@ use tokio::{
 };
 use tracing::debug;
 
-use crate::spacetime::{SpaceTimeStream, UnicastStream};
+use crate::{
+	compression::{Compression, CompressionStats, Frame},
+	spacetime::{SpaceTimeStream, UnicastStream},
+};
+
+mod delta;
+
+pub use delta::*;
+
+/// Sent back by the receiver of a request to take the whole file
+pub const ACCEPT_FULL: u8 = 1;
+/// Sent back by the receiver of a request to only take what changed from the version of the file
+/// it has, followed by its [`Signature`]
+pub const ACCEPT_DELTA: u8 = 2;
 
 /// TODO
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -153,12 +166,61 @@ impl<'a> Block<'a> {
 			data: &[], // TODO: This is super cringe. Data should be decoded here but lifetimes and extra allocations become a major concern.
 		})
 	}
+
+	/// Like [`Block::to_bytes`] but with the data sent as a [`Frame`]
+	pub fn to_frame_bytes(
+		&self,
+		compression: Compression,
+		stats: &CompressionStats,
+	) -> std::io::Result<Vec<u8>> {
+		let frame = Frame::encode(self.data, compression)?;
+		stats.record(&frame);
+
+		let mut buf = Vec::new();
+		buf.extend_from_slice(&self.offset.to_le_bytes());
+		buf.extend_from_slice(&self.size.to_le_bytes());
+		buf.extend_from_slice(&frame.to_bytes());
+		Ok(buf)
+	}
+
+	/// Like [`Block::from_stream`] but with the data received as a [`Frame`]
+	pub async fn from_frame_stream(
+		stream: &mut (impl AsyncReadExt + Unpin),
+		data_buf: &mut [u8],
+		stats: &CompressionStats,
+	) -> Result<Block<'a>, ()> {
+		let offset = stream.read_u64_le().await.map_err(|_| ())?; // TODO: Error handling
+		let size = stream.read_u64_le().await.map_err(|_| ())?; // TODO: Error handling
+
+		if size > data_buf.len() as u64 {
+			return Err(()); // TODO: Error handling
+		}
+
+		// The frame can't decode to more than `size` bytes
+		let frame = Frame::from_stream(stream, size as u32)
+			.await
+			.map_err(|_| ())?; // TODO: Error handling
+		stats.record(&frame);
+
+		let data = frame.decode().map_err(|_| ())?; // TODO: Error handling
+		if data.len() as u64 != size {
+			return Err(()); // TODO: Error handling
+		}
+		data_buf[..data.len()].copy_from_slice(&data);
+
+		Ok(Self {
+			offset,
+			size,
+			data: &[],
+		})
+	}
 }
 
 /// TODO
 pub struct Transfer<'a, F> {
 	req: &'a SpaceblockRequest,
 	on_progress: F,
+	compression: Option<(Compression, &'a CompressionStats)>,
 }
 
 impl<'a, F> Transfer<'a, F>
@@ -166,7 +228,23 @@ where
 	F: Fn(u8) + 'a,
 {
 	pub fn new(req: &'a SpaceblockRequest, on_progress: F) -> Self {
-		Self { req, on_progress }
+		Self {
+			req,
+			on_progress,
+			compression: None,
+		}
+	}
+
+	/// Sends the data of blocks as [`Frame`]s, compressed unless `compression` is
+	/// [`Compression::None`]. Both sides must agree on it, as it changes how blocks are sent.
+	/// Delta transfers aren't affected.
+	pub fn with_compression(
+		mut self,
+		compression: Compression,
+		stats: &'a CompressionStats,
+	) -> Self {
+		self.compression = Some((compression, stats));
+		self
 	}
 
 	pub async fn send(
@@ -200,7 +278,11 @@ where
 				"Sending block at offset {} of size {}",
 				block.offset, block.size
 			);
-			stream.write_all(&block.to_bytes()).await.unwrap(); // TODO: Error handling
+			let bytes = match self.compression {
+				Some((compression, stats)) => block.to_frame_bytes(compression, stats).unwrap(), // TODO: Error handling
+				None => block.to_bytes(),
+			};
+			stream.write_all(&bytes).await.unwrap(); // TODO: Error handling
 		}
 	}
 
@@ -216,7 +298,11 @@ where
 		// TODO: Prevent loop being a DOS vector
 		loop {
 			// TODO: Timeout if nothing is being received
-			let block = Block::from_stream(stream, &mut data_buf).await.unwrap(); // TODO: Error handling
+			let block = match self.compression {
+				Some((_, stats)) => Block::from_frame_stream(stream, &mut data_buf, stats).await,
+				None => Block::from_stream(stream, &mut data_buf).await,
+			}
+			.unwrap(); // TODO: Error handling
 			offset += block.size;
 			(self.on_progress)(((self.req.size / offset) * 100) as u8); // SAFETY: Percent must be between 0 and 100
 
@@ -326,4 +412,41 @@ mod tests {
 			.await;
 		assert_eq!(result, data);
 	}
+
+	#[tokio::test]
+	async fn test_spaceblock_compressed_blocks() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+
+		let block_size = 1024u32;
+		let data = b"Spacedrive ".repeat(300);
+
+		let req = SpaceblockRequest {
+			name: "Demo".to_string(),
+			size: data.len() as u64,
+			block_size: BlockSize::dangerously_new(block_size),
+		};
+
+		tokio::spawn({
+			let req = req.clone();
+			let data = data.clone();
+			async move {
+				let stats = CompressionStats::default();
+				let file = BufReader::new(Cursor::new(data));
+				Transfer::new(&req, |_| {})
+					.with_compression(Compression::Zstd, &stats)
+					.send(&mut client, file)
+					.await;
+			}
+		});
+
+		let stats = CompressionStats::default();
+		let mut result = Vec::new();
+		Transfer::new(&req, |_| {})
+			.with_compression(Compression::Zstd, &stats)
+			.receive(&mut server, &mut result)
+			.await;
+		assert_eq!(result, data);
+		assert_eq!(stats.raw_bytes(), data.len() as u64);
+		assert!(stats.saved_bytes() > 0);
+	}
 }
@@ -1,64 +1,526 @@
 use std::{
 	io,
 	pin::Pin,
-	task::{Context, Poll},
+	task::{ready, Context, Poll},
 };
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use libp2p::identity::{self, ed25519};
+use rand_core::OsRng;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
-use crate::spacetime::UnicastStream;
+use crate::{spacetime::UnicastStream, Keypair, PeerId};
 
-pub struct Tunnel {
-	stream: UnicastStream,
+/// What the content of a tunnel is encrypted with, for showing to users
+pub const TUNNEL_CIPHER: &str = "X25519 + ChaCha20-Poly1305";
+
+const DISCRIMINATOR: u8 = b'T';
+/// Largest amount of data sealed at once
+const MAX_FRAME_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SIGNATURE_LEN: usize = 64;
+/// The first byte of every frame says if more frames follow it
+const FRAME_DATA: u8 = 0;
+const FRAME_FINAL: u8 = 1;
+
+const TRANSCRIPT_CONTEXT: &str = "spacedrive spacetunnel 2023-06 handshake transcript";
+const INITIATOR_KEY_CONTEXT: &str = "spacedrive spacetunnel 2023-06 initiator to responder";
+const RESPONDER_KEY_CONTEXT: &str = "spacedrive spacetunnel 2023-06 responder to initiator";
+
+#[derive(Debug, Error)]
+pub enum TunnelError {
+	#[error("io error during tunnel handshake: {0}")]
+	Io(#[from] io::Error),
+	#[error("invalid discriminator '{0}'. Is this stream actually a tunnel?")]
+	InvalidDiscriminator(u8),
+	#[error("the peer sent an invalid key")]
+	InvalidKey,
+	#[error("the peer couldn't prove it owns its identity key")]
+	InvalidSignature,
+	#[error("expected a tunnel with peer '{expected}' but it was set up by '{received}'")]
+	UnexpectedPeer { expected: PeerId, received: PeerId },
 }
 
-impl Tunnel {
-	// TODO: Proper errors
-	pub async fn from_stream(mut stream: UnicastStream) -> Result<Self, &'static str> {
-		let discriminator = stream
-			.read_u8()
-			.await
-			.map_err(|_| "Error reading discriminator. Is this stream actually a tunnel?")?;
-		if discriminator != b'T' {
-			return Err("Invalid discriminator. Is this stream actually a tunnel?");
+/// An encrypted stream between two peers, which anything relaying their connection can neither
+/// read nor tamper with. Setting it up proves each peer owns the identity key of its [`PeerId`],
+/// so a tunnel can't be set up by anyone else than the expected peer.
+///
+/// Both peers create an ephemeral X25519 key and sign the pair of them with their identity key.
+/// The shared secret of the ephemeral keys then gives a ChaCha20-Poly1305 key for each direction.
+///
+/// Shutting down a tunnel seals a final frame, and the stream ending before that frame is an error.
+/// So a relay cutting the connection short can't pass off what went through as everything.
+pub struct Tunnel<S = UnicastStream> {
+	stream: S,
+	remote_peer_id: PeerId,
+	sealer: Cipher,
+	opener: Cipher,
+	/// A sealed frame not entirely written to the stream yet
+	write_buf: Vec<u8>,
+	write_pos: usize,
+	/// If the final frame was sealed, nothing can be written after it
+	write_finished: bool,
+	/// A sealed frame not entirely read from the stream yet
+	read_buf: Vec<u8>,
+	/// The content of the last frame opened which wasn't read yet
+	plaintext: Vec<u8>,
+	plaintext_pos: usize,
+	/// If the final frame was opened, the tunnel has nothing more to read
+	read_finished: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Tunnel<S> {
+	/// Sets up a tunnel with `peer_id` on a stream we opened to it
+	pub async fn initiator(
+		stream: S,
+		keypair: &Keypair,
+		peer_id: PeerId,
+	) -> Result<Self, TunnelError> {
+		Self::handshake(stream, keypair, peer_id, true).await
+	}
+
+	/// Sets up a tunnel with `peer_id` on a stream it opened to us
+	pub async fn responder(
+		stream: S,
+		keypair: &Keypair,
+		peer_id: PeerId,
+	) -> Result<Self, TunnelError> {
+		Self::handshake(stream, keypair, peer_id, false).await
+	}
+
+	async fn handshake(
+		mut stream: S,
+		keypair: &Keypair,
+		peer_id: PeerId,
+		initiator: bool,
+	) -> Result<Self, TunnelError> {
+		let secret = EphemeralSecret::new(OsRng);
+		let ephemeral = PublicKey::from(&secret);
+
+		if initiator {
+			stream.write_u8(DISCRIMINATOR).await?;
+		} else {
+			let discriminator = stream.read_u8().await?;
+			if discriminator != DISCRIMINATOR {
+				return Err(TunnelError::InvalidDiscriminator(discriminator));
+			}
+		}
+
+		stream.write_all(ephemeral.as_bytes()).await?;
+		stream.flush().await?;
+
+		let mut remote_ephemeral = [0; 32];
+		stream.read_exact(&mut remote_ephemeral).await?;
+		let remote_ephemeral = PublicKey::from(remote_ephemeral);
+
+		let transcript = {
+			let (initiator_key, responder_key) = if initiator {
+				(&ephemeral, &remote_ephemeral)
+			} else {
+				(&remote_ephemeral, &ephemeral)
+			};
+
+			let mut hasher = blake3::Hasher::new_derive_key(TRANSCRIPT_CONTEXT);
+			hasher.update(initiator_key.as_bytes());
+			hasher.update(responder_key.as_bytes());
+			*hasher.finalize().as_bytes()
+		};
+
+		// Signing both ephemeral keys along with the role ties the proof to this very handshake
+		stream.write_all(&keypair.public_key()).await?;
+		stream
+			.write_all(&keypair.sign(&signed_message(&transcript, initiator)))
+			.await?;
+		stream.flush().await?;
+
+		let mut remote_identity = [0; 32];
+		stream.read_exact(&mut remote_identity).await?;
+		let mut signature = [0; SIGNATURE_LEN];
+		stream.read_exact(&mut signature).await?;
+
+		let remote_identity = ed25519::PublicKey::try_from_bytes(&remote_identity)
+			.map_err(|_| TunnelError::InvalidKey)?;
+		if !remote_identity.verify(&signed_message(&transcript, !initiator), &signature) {
+			return Err(TunnelError::InvalidSignature);
+		}
+
+		let remote_peer_id = PeerId(libp2p::PeerId::from_public_key(&identity::PublicKey::from(
+			remote_identity,
+		)));
+		if remote_peer_id != peer_id {
+			return Err(TunnelError::UnexpectedPeer {
+				expected: peer_id,
+				received: remote_peer_id,
+			});
+		}
+
+		let shared_secret = secret.diffie_hellman(&remote_ephemeral);
+		// A key of low order forces the shared secret to zero, which anyone could then compute
+		if shared_secret.as_bytes() == &[0; 32] {
+			return Err(TunnelError::InvalidKey);
+		}
+
+		let mut key_material = shared_secret.as_bytes().to_vec();
+		key_material.extend_from_slice(&transcript);
+		let initiator_cipher = Cipher::new(INITIATOR_KEY_CONTEXT, &key_material);
+		let responder_cipher = Cipher::new(RESPONDER_KEY_CONTEXT, &key_material);
+		let (sealer, opener) = if initiator {
+			(initiator_cipher, responder_cipher)
+		} else {
+			(responder_cipher, initiator_cipher)
+		};
+
+		Ok(Self {
+			stream,
+			remote_peer_id,
+			sealer,
+			opener,
+			write_buf: Vec::new(),
+			write_pos: 0,
+			write_finished: false,
+			read_buf: Vec::new(),
+			plaintext: Vec::new(),
+			plaintext_pos: 0,
+			read_finished: false,
+		})
+	}
+
+	/// The peer on the other end, which proved it owns the identity key of this [`PeerId`]
+	pub fn remote_peer_id(&self) -> PeerId {
+		self.remote_peer_id
+	}
+
+	/// Writes what's left of the last sealed frame
+	fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while self.write_pos < self.write_buf.len() {
+			let written = ready!(
+				Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+			)?;
+			if written == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+			self.write_pos += written;
+		}
+
+		self.write_buf.clear();
+		self.write_pos = 0;
+
+		Poll::Ready(Ok(()))
+	}
+
+	/// Seals `data` into a frame and queues it for writing
+	fn seal_frame(&mut self, kind: u8, data: &[u8]) -> io::Result<()> {
+		let mut frame = Vec::with_capacity(1 + data.len());
+		frame.push(kind);
+		frame.extend_from_slice(data);
+
+		let sealed = self.sealer.seal(&frame)?;
+		self.write_buf
+			.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+		self.write_buf.extend_from_slice(&sealed);
+
+		Ok(())
+	}
+
+	/// Reads from the stream until a whole frame was received, which is then opened. `false` means
+	/// the final frame was received, the stream ending before it is an error.
+	fn poll_read_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+		if self.read_finished {
+			return Poll::Ready(Ok(false));
 		}
 
-		// TODO: Do pairing
+		loop {
+			let expected = match self.read_buf.get(..4) {
+				Some(len) => {
+					let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+					if !(1 + TAG_LEN..=1 + MAX_FRAME_LEN + TAG_LEN).contains(&len) {
+						return Poll::Ready(Err(io::Error::new(
+							io::ErrorKind::InvalidData,
+							"invalid tunnel frame length",
+						)));
+					}
+
+					4 + len
+				}
+				None => 4,
+			};
+
+			if expected > 4 && self.read_buf.len() == expected {
+				let mut plaintext = self.opener.open(&self.read_buf[4..])?;
+				self.read_buf.clear();
+
+				let kind = plaintext.remove(0);
+				self.plaintext = plaintext;
+				self.plaintext_pos = 0;
+
+				return Poll::Ready(match kind {
+					FRAME_DATA => Ok(true),
+					FRAME_FINAL => {
+						self.read_finished = true;
+						Ok(false)
+					}
+					_ => Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"invalid tunnel frame kind",
+					)),
+				});
+			}
 
-		Ok(Self { stream })
+			let mut chunk = [0; 8 * 1024];
+			let missing = (expected - self.read_buf.len()).min(chunk.len());
+			let mut chunk = ReadBuf::new(&mut chunk[..missing]);
+			ready!(Pin::new(&mut self.stream).poll_read(cx, &mut chunk))?;
+
+			if chunk.filled().is_empty() {
+				return Poll::Ready(Err(io::Error::new(
+					io::ErrorKind::UnexpectedEof,
+					"tunnel ended before its final frame",
+				)));
+			}
+
+			self.read_buf.extend_from_slice(chunk.filled());
+		}
 	}
 }
 
-impl AsyncRead for Tunnel {
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Tunnel<S> {
 	fn poll_read(
 		self: Pin<&mut Self>,
 		cx: &mut Context<'_>,
 		buf: &mut ReadBuf<'_>,
 	) -> Poll<io::Result<()>> {
-		// TODO: Do decryption
+		let this = self.get_mut();
+
+		// A frame may hold no data, in which case the next one is read. The final one holds none.
+		while this.plaintext_pos == this.plaintext.len() {
+			if !ready!(this.poll_read_frame(cx))? {
+				return Poll::Ready(Ok(()));
+			}
+		}
+
+		let len = buf
+			.remaining()
+			.min(this.plaintext.len() - this.plaintext_pos);
+		buf.put_slice(&this.plaintext[this.plaintext_pos..this.plaintext_pos + len]);
+		this.plaintext_pos += len;
 
-		Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+		Poll::Ready(Ok(()))
 	}
 }
 
-impl AsyncWrite for Tunnel {
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Tunnel<S> {
 	fn poll_write(
 		self: Pin<&mut Self>,
 		cx: &mut Context<'_>,
 		buf: &[u8],
 	) -> Poll<io::Result<usize>> {
-		// TODO: Do encryption
+		let this = self.get_mut();
+		ready!(this.poll_write_pending(cx))?;
 
-		Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+		if this.write_finished {
+			return Poll::Ready(Err(io::Error::new(
+				io::ErrorKind::BrokenPipe,
+				"tunnel was shut down",
+			)));
+		}
+
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
+
+		let len = buf.len().min(MAX_FRAME_LEN);
+		this.seal_frame(FRAME_DATA, &buf[..len])?;
+
+		// The frame is ours to write now, what doesn't fit in the stream yet goes with the next write or flush
+		if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+			return Poll::Ready(Err(e));
+		}
+
+		Poll::Ready(Ok(len))
 	}
 
 	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-		Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+		let this = self.get_mut();
+		ready!(this.poll_write_pending(cx))?;
+
+		Pin::new(&mut this.stream).poll_flush(cx)
 	}
 
 	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-		Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+		let this = self.get_mut();
+		ready!(this.poll_write_pending(cx))?;
+
+		if !this.write_finished {
+			this.seal_frame(FRAME_FINAL, &[])?;
+			this.write_finished = true;
+			ready!(this.poll_write_pending(cx))?;
+		}
+
+		Pin::new(&mut this.stream).poll_shutdown(cx)
 	}
 }
 
-// TODO: Unit tests
+fn signed_message(transcript: &[u8; 32], initiator: bool) -> Vec<u8> {
+	let mut message = transcript.to_vec();
+	message.push(u8::from(initiator));
+	message
+}
+
+/// One direction of a tunnel. Nonces count the frames, so they're never reused with a key.
+struct Cipher {
+	cipher: ChaCha20Poly1305,
+	counter: u64,
+}
+
+impl Cipher {
+	fn new(context: &str, key_material: &[u8]) -> Self {
+		Self {
+			cipher: ChaCha20Poly1305::new(&blake3::derive_key(context, key_material).into()),
+			counter: 0,
+		}
+	}
+
+	fn next_nonce(&mut self) -> Nonce {
+		let mut nonce = [0; 12];
+		nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+		self.counter += 1;
+		nonce.into()
+	}
+
+	fn seal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+		let nonce = self.next_nonce();
+		self.cipher
+			.encrypt(&nonce, data)
+			.map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to seal tunnel frame"))
+	}
+
+	fn open(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+		let nonce = self.next_nonce();
+		self.cipher.decrypt(&nonce, data).map_err(|_| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				"tunnel frame was tampered with or is out of order",
+			)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::duplex;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_tunnel() {
+		let (a, b) = (Keypair::generate(), Keypair::generate());
+		let (a_stream, b_stream) = duplex(1024);
+
+		let (a_tunnel, b_tunnel) = tokio::join!(
+			Tunnel::initiator(a_stream, &a, b.peer_id()),
+			Tunnel::responder(b_stream, &b, a.peer_id())
+		);
+		let (mut a_tunnel, mut b_tunnel) = (a_tunnel.unwrap(), b_tunnel.unwrap());
+		assert_eq!(a_tunnel.remote_peer_id(), b.peer_id());
+		assert_eq!(b_tunnel.remote_peer_id(), a.peer_id());
+
+		let data = b"Spacedrive ".repeat(20_000);
+		let ((), received) = tokio::join!(
+			async {
+				a_tunnel.write_all(&data).await.unwrap();
+				a_tunnel.shutdown().await.unwrap();
+			},
+			async {
+				let mut received = Vec::new();
+				b_tunnel.read_to_end(&mut received).await.unwrap();
+				received
+			}
+		);
+		assert_eq!(received, data);
+	}
+
+	#[tokio::test]
+	async fn test_tunnel_with_unexpected_peer() {
+		let (a, b, c) = (
+			Keypair::generate(),
+			Keypair::generate(),
+			Keypair::generate(),
+		);
+		let (a_stream, b_stream) = duplex(1024);
+
+		let (a_tunnel, _) = tokio::join!(
+			Tunnel::initiator(a_stream, &a, c.peer_id()),
+			Tunnel::responder(b_stream, &b, a.peer_id())
+		);
+
+		assert!(matches!(
+			a_tunnel,
+			Err(TunnelError::UnexpectedPeer { expected, received })
+				if expected == c.peer_id() && received == b.peer_id()
+		));
+	}
+
+	#[tokio::test]
+	async fn test_tampered_tunnel() {
+		let (a, b) = (Keypair::generate(), Keypair::generate());
+		let (a_stream, mut relay_a) = duplex(1024);
+		let (mut relay_b, b_stream) = duplex(1024);
+
+		// A relay forwarding the handshake as is but flipping a bit of the data
+		let relay = tokio::spawn(async move {
+			let mut handshake = [0; 1 + 32 + 32 + SIGNATURE_LEN];
+			let mut reply = [0; 32 + 32 + SIGNATURE_LEN];
+			relay_a.read_exact(&mut handshake[..33]).await.unwrap();
+			relay_b.write_all(&handshake[..33]).await.unwrap();
+			relay_b.read_exact(&mut reply).await.unwrap();
+			relay_a.write_all(&reply).await.unwrap();
+			relay_a.read_exact(&mut handshake[33..]).await.unwrap();
+			relay_b.write_all(&handshake[33..]).await.unwrap();
+
+			let mut frame = Vec::new();
+			relay_a.read_to_end(&mut frame).await.unwrap();
+			let last = frame.len() - 1;
+			frame[last] ^= 1;
+			relay_b.write_all(&frame).await.unwrap();
+		});
+
+		let (a_tunnel, b_tunnel) = tokio::join!(
+			Tunnel::initiator(a_stream, &a, b.peer_id()),
+			Tunnel::responder(b_stream, &b, a.peer_id())
+		);
+		let (mut a_tunnel, mut b_tunnel) = (a_tunnel.unwrap(), b_tunnel.unwrap());
+
+		a_tunnel.write_all(b"sync operations").await.unwrap();
+		a_tunnel.shutdown().await.unwrap();
+		drop(a_tunnel);
+		relay.await.unwrap();
+
+		let mut received = Vec::new();
+		let err = b_tunnel.read_to_end(&mut received).await.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[tokio::test]
+	async fn test_truncated_tunnel() {
+		let (a, b) = (Keypair::generate(), Keypair::generate());
+		let (a_stream, b_stream) = duplex(1024);
+
+		let (a_tunnel, b_tunnel) = tokio::join!(
+			Tunnel::initiator(a_stream, &a, b.peer_id()),
+			Tunnel::responder(b_stream, &b, a.peer_id())
+		);
+		let (mut a_tunnel, mut b_tunnel) = (a_tunnel.unwrap(), b_tunnel.unwrap());
+
+		// The stream ends without the final frame, like a relay dropping the connection would
+		a_tunnel.write_all(b"sync operations").await.unwrap();
+		a_tunnel.flush().await.unwrap();
+		drop(a_tunnel);
+
+		let mut received = Vec::new();
+		let err = b_tunnel.read_to_end(&mut received).await.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+		assert_eq!(received, b"sync operations");
+	}
+}
@@ -25,6 +25,15 @@ impl Keypair {
 	pub fn inner(&self) -> libp2p::identity::Keypair {
 		self.0.clone().into()
 	}
+
+	pub fn public_key(&self) -> [u8; 32] {
+		self.0.public().to_bytes()
+	}
+
+	/// Signs with the identity key of the peer, checked with [`PeerId::from_signature`](crate::PeerId::from_signature)
+	pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+		self.0.sign(msg)
+	}
 }
 
 impl Serialize for Keypair {
@@ -1,5 +1,7 @@
 use std::{fmt::Display, str::FromStr};
 
+use libp2p::identity::ed25519;
+
 #[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[cfg_attr(feature = "specta", derive(specta::Type))]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -29,3 +31,24 @@ impl Display for PeerId {
 		write!(f, "{}", self.0)
 	}
 }
+
+impl PeerId {
+	/// A short hash of the identity key of the peer, which users can compare between two devices
+	/// to check nothing sits between them
+	pub fn fingerprint(&self) -> String {
+		blake3::hash(&self.0.to_bytes()).as_bytes()[..10]
+			.chunks(2)
+			.map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+			.collect::<Vec<_>>()
+			.join(" ")
+	}
+
+	/// The peer owning the public key, if the signature over the message was made with it
+	pub fn from_signature(public_key: &[u8; 32], msg: &[u8], signature: &[u8]) -> Option<Self> {
+		let public_key = ed25519::PublicKey::try_from_bytes(public_key).ok()?;
+
+		public_key
+			.verify(msg, signature)
+			.then(|| Self(libp2p::PeerId::from_public_key(&public_key.into())))
+	}
+}
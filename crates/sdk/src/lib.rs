@@ -0,0 +1,240 @@
+//! Embeds the Spacedrive core in Rust applications.
+//!
+//! The core's own types are generated from its database schema and change between releases, so
+//! this crate exposes a small, stable set of types on top of it instead. It calls the same
+//! procedures as our apps do, so everything it does goes through the same checks and keeps the
+//! clients in sync.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), sd_core_sdk::SdkError> {
+//! use sd_core_sdk::{Core, SearchQuery};
+//!
+//! let core = Core::start("./data").await?;
+//! let library = core.create_library("Photos").await?;
+//! let library = core.library(library.id);
+//!
+//! library.add_location("/home/me/Pictures").await?;
+//!
+//! let page = library
+//! 	.search(SearchQuery {
+//! 		extension: Some("jpg".into()),
+//! 		..Default::default()
+//! 	})
+//! 	.await?;
+//! println!("{} JPEGs", page.items.len());
+//!
+//! core.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{path::Path, sync::Arc};
+
+use sd_core::{
+	api::{
+		gateway::{self, GatewayError},
+		tokens::ProcedureKind,
+		Router,
+	},
+	Node, NodeError,
+};
+use sd_crypto::Protected;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use uuid::Uuid;
+
+mod types;
+
+pub use types::*;
+
+#[derive(Error, Debug)]
+pub enum SdkError {
+	#[error("failed to start the core: {0}")]
+	Start(#[from] NodeError),
+	#[error("'{key}' failed ({status}): {message}")]
+	Procedure {
+		key: &'static str,
+		/// HTTP like status of the error, like 404 when something wasn't found
+		status: u16,
+		message: String,
+	},
+	#[error("unexpected response from '{key}': {source}")]
+	Decode {
+		key: &'static str,
+		source: serde_json::Error,
+	},
+}
+
+/// A running instance of the core
+pub struct Core {
+	node: Arc<Node>,
+	router: Arc<Router>,
+}
+
+impl Core {
+	/// Starts the core, with its configuration, libraries and caches stored in `data_dir`
+	pub async fn start(data_dir: impl AsRef<Path>) -> Result<Self, SdkError> {
+		let (node, router) = Node::new(data_dir).await?;
+
+		Ok(Self { node, router })
+	}
+
+	pub async fn libraries(&self) -> Result<Vec<Library>, SdkError> {
+		let libraries: Vec<types::wire::LibraryConfigWrapped> = self
+			.call(ProcedureKind::Query, "library.list", Value::Null)
+			.await?;
+
+		Ok(libraries.into_iter().map(Into::into).collect())
+	}
+
+	pub async fn create_library(&self, name: impl Into<String>) -> Result<Library, SdkError> {
+		let library: types::wire::LibraryConfigWrapped = self
+			.call(
+				ProcedureKind::Mutation,
+				"library.create",
+				json!({ "name": name.into() }),
+			)
+			.await?;
+
+		Ok(library.into())
+	}
+
+	/// A library of the core, which isn't checked to exist until it's used
+	pub fn library(&self, id: Uuid) -> LibraryHandle<'_> {
+		LibraryHandle { core: self, id }
+	}
+
+	/// Waits for the running jobs to pause, so they resume on the next start
+	pub async fn shutdown(&self) {
+		self.node.shutdown().await;
+	}
+
+	async fn call<T: DeserializeOwned>(
+		&self,
+		kind: ProcedureKind,
+		key: &'static str,
+		input: Value,
+	) -> Result<T, SdkError> {
+		let result = gateway::call(self.node.clone(), &self.router, kind, key, input)
+			.await
+			.map_err(|GatewayError { status, message }| SdkError::Procedure {
+				key,
+				status,
+				message,
+			})?;
+
+		serde_json::from_value(result).map_err(|source| SdkError::Decode { key, source })
+	}
+}
+
+pub struct LibraryHandle<'a> {
+	core: &'a Core,
+	id: Uuid,
+}
+
+impl LibraryHandle<'_> {
+	pub fn id(&self) -> Uuid {
+		self.id
+	}
+
+	pub async fn locations(&self) -> Result<Vec<Location>, SdkError> {
+		self.call(ProcedureKind::Query, "locations.list", ()).await
+	}
+
+	pub async fn location(&self, id: i32) -> Result<Option<Location>, SdkError> {
+		self.call(ProcedureKind::Query, "locations.get", id).await
+	}
+
+	/// Adds a location and starts indexing it, which runs as a job
+	pub async fn add_location(&self, path: impl AsRef<Path>) -> Result<(), SdkError> {
+		self.call(
+			ProcedureKind::Mutation,
+			"locations.create",
+			json!({
+				"path": path.as_ref(),
+				"dry_run": false,
+				"indexer_rules_ids": [],
+			}),
+		)
+		.await
+	}
+
+	/// Indexes a location again from scratch
+	pub async fn rescan_location(&self, id: i32) -> Result<(), SdkError> {
+		self.call(ProcedureKind::Mutation, "locations.fullRescan", id)
+			.await
+	}
+
+	/// Unlocks a private location, so its files show up until it's locked again
+	pub async fn unlock_location(
+		&self,
+		id: i32,
+		passphrase: Protected<String>,
+	) -> Result<(), SdkError> {
+		self.call(
+			ProcedureKind::Mutation,
+			"locations.unlock",
+			json!({ "id": id, "passphrase": passphrase.expose() }),
+		)
+		.await
+	}
+
+	pub async fn lock_location(&self, id: i32) -> Result<(), SdkError> {
+		self.call(ProcedureKind::Mutation, "locations.lock", id)
+			.await
+	}
+
+	pub async fn search(&self, query: SearchQuery) -> Result<SearchPage, SdkError> {
+		let data: types::wire::SearchData = self
+			.call(
+				ProcedureKind::Query,
+				"search.paths",
+				types::wire::FilePathSearchArgs::from(query),
+			)
+			.await?;
+
+		Ok(SearchPage {
+			items: data
+				.items
+				.into_iter()
+				.filter_map(|item| match item {
+					types::wire::ExplorerItem::Path { item } => Some(item.into()),
+					types::wire::ExplorerItem::Other => None,
+				})
+				.collect(),
+			cursor: data.cursor,
+		})
+	}
+
+	/// The jobs of the library, most recent first
+	pub async fn jobs(&self) -> Result<Vec<Job>, SdkError> {
+		let groups: types::wire::JobGroups =
+			self.call(ProcedureKind::Query, "jobs.reports", ()).await?;
+
+		Ok(groups
+			.groups
+			.into_iter()
+			.flat_map(|group| group.jobs)
+			.collect())
+	}
+
+	pub async fn pause_job(&self, id: Uuid) -> Result<(), SdkError> {
+		self.call(ProcedureKind::Mutation, "jobs.pause", id).await
+	}
+
+	pub async fn resume_job(&self, id: Uuid) -> Result<(), SdkError> {
+		self.call(ProcedureKind::Mutation, "jobs.resume", id).await
+	}
+
+	async fn call<T: DeserializeOwned>(
+		&self,
+		kind: ProcedureKind,
+		key: &'static str,
+		arg: impl Serialize,
+	) -> Result<T, SdkError> {
+		self.core
+			.call(kind, key, json!({ "library_id": self.id, "arg": arg }))
+			.await
+	}
+}
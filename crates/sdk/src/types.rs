@@ -0,0 +1,201 @@
+//! The types of the SDK. They only change with a new major version, unlike the types of the core
+//! which follow its database schema.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Library {
+	pub id: Uuid,
+	pub name: String,
+	pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Location {
+	pub id: i32,
+	pub name: Option<String>,
+	pub path: Option<String>,
+	pub total_capacity: Option<i32>,
+	pub available_capacity: Option<i32>,
+	pub date_created: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum JobStatus {
+	Queued,
+	Running,
+	Completed,
+	Canceled,
+	Failed,
+	Paused,
+	CompletedWithErrors,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Job {
+	pub id: Uuid,
+	pub name: String,
+	pub status: JobStatus,
+	pub task_count: i32,
+	pub completed_task_count: i32,
+	/// What the job is currently doing, as shown to users
+	pub message: String,
+	#[serde(rename = "errors_text")]
+	pub errors: Vec<String>,
+	pub created_at: Option<DateTime<Utc>>,
+	pub completed_at: Option<DateTime<Utc>>,
+}
+
+/// A search of the file paths of a library, every filter left empty matches everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+	pub location_id: Option<i32>,
+	/// Matched against the names of the files
+	pub text: Option<String>,
+	pub extension: Option<String>,
+	/// Only the files in this directory, relative to the root of the location
+	pub path: Option<String>,
+	/// How many files to return at most
+	pub take: Option<i32>,
+	/// The cursor of the previous page, to continue from
+	pub cursor: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+	pub id: i32,
+	pub location_id: Option<i32>,
+	/// Directory of the file, relative to the root of its location
+	pub path: Option<String>,
+	pub name: Option<String>,
+	pub extension: Option<String>,
+	pub is_dir: bool,
+	pub size_in_bytes: Option<u64>,
+	/// Content hash of the file, `None` until it's identified
+	pub cas_id: Option<String>,
+	pub date_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchPage {
+	pub items: Vec<FileEntry>,
+	/// Passed in the next query to get the next page, `None` on the last one
+	pub cursor: Option<Vec<u8>>,
+}
+
+/// The way the core serializes these types, kept apart so the public types don't depend on it
+pub(crate) mod wire {
+	use super::*;
+
+	#[derive(Deserialize)]
+	pub struct LibraryConfigWrapped {
+		pub uuid: Uuid,
+		pub config: LibraryConfig,
+	}
+
+	#[derive(Deserialize)]
+	pub struct LibraryConfig {
+		pub name: String,
+		pub description: Option<String>,
+	}
+
+	impl From<LibraryConfigWrapped> for Library {
+		fn from(library: LibraryConfigWrapped) -> Self {
+			Self {
+				id: library.uuid,
+				name: library.config.name,
+				description: library.config.description,
+			}
+		}
+	}
+
+	#[derive(Deserialize)]
+	pub struct JobGroups {
+		pub groups: Vec<JobGroup>,
+	}
+
+	#[derive(Deserialize)]
+	pub struct JobGroup {
+		pub jobs: Vec<Job>,
+	}
+
+	#[derive(Serialize)]
+	pub struct FilePathSearchArgs {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		pub take: Option<i32>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		pub cursor: Option<Vec<u8>>,
+		pub filter: FilePathFilterArgs,
+	}
+
+	#[derive(Serialize)]
+	#[serde(rename_all = "camelCase")]
+	pub struct FilePathFilterArgs {
+		pub location_id: Option<i32>,
+		pub search: Option<String>,
+		pub extension: Option<String>,
+		pub path: Option<String>,
+	}
+
+	impl From<SearchQuery> for FilePathSearchArgs {
+		fn from(query: SearchQuery) -> Self {
+			Self {
+				take: query.take,
+				cursor: query.cursor,
+				filter: FilePathFilterArgs {
+					location_id: query.location_id,
+					search: query.text,
+					extension: query.extension,
+					path: query.path,
+				},
+			}
+		}
+	}
+
+	#[derive(Deserialize)]
+	pub struct SearchData {
+		pub cursor: Option<Vec<u8>>,
+		pub items: Vec<ExplorerItem>,
+	}
+
+	#[derive(Deserialize)]
+	#[serde(tag = "type")]
+	pub enum ExplorerItem {
+		Path {
+			item: FilePath,
+		},
+		#[serde(other)]
+		Other,
+	}
+
+	#[derive(Deserialize)]
+	pub struct FilePath {
+		pub id: i32,
+		pub location_id: Option<i32>,
+		pub materialized_path: Option<String>,
+		pub name: Option<String>,
+		pub extension: Option<String>,
+		pub is_dir: Option<bool>,
+		pub size_in_bytes: Option<String>,
+		pub cas_id: Option<String>,
+		pub date_modified: Option<DateTime<Utc>>,
+	}
+
+	impl From<FilePath> for FileEntry {
+		fn from(file_path: FilePath) -> Self {
+			Self {
+				id: file_path.id,
+				location_id: file_path.location_id,
+				path: file_path.materialized_path,
+				name: file_path.name,
+				extension: file_path.extension,
+				is_dir: file_path.is_dir.unwrap_or(false),
+				size_in_bytes: file_path.size_in_bytes.and_then(|size| size.parse().ok()),
+				cas_id: file_path.cas_id,
+				date_modified: file_path.date_modified,
+			}
+		}
+	}
+}
@@ -0,0 +1,205 @@
+//! Compaction of the operation log, which otherwise keeps every change ever made to a library.
+//!
+//! Shared operations are last writer wins for each field, so an operation whose every effect is
+//! overwritten by a later one can be dropped without changing what replaying the log results in:
+//! an update of a field which is updated again later, the values of a creation which are updated
+//! later and whatever happened to a record before it was deleted. What's left is a snapshot of the
+//! state at the time of the last operation compacted, made of the creation of each record, the last
+//! update of each of its fields and its deletion, which a peer that hasn't synced in a long time
+//! can still catch up from.
+
+use std::collections::{HashMap, HashSet};
+
+use uuid::Uuid;
+
+use crate::{CRDTOperation, CRDTOperationType, SharedOperationData};
+
+/// The changes to make to a log to compact it
+#[derive(Debug, Default)]
+pub struct Compaction {
+	/// Operations to remove from the log
+	pub removed: Vec<Uuid>,
+	/// Creations to replace in the log, without the values overwritten later
+	pub rewritten: Vec<CRDTOperation>,
+}
+
+/// Compacts a part of the log, which must hold every operation up to some point in time
+pub fn compact(ops: Vec<CRDTOperation>) -> Compaction {
+	let mut records = HashMap::<_, Vec<_>>::new();
+	for op in ops {
+		if let CRDTOperationType::Shared(shared_op) = &op.typ {
+			records
+				.entry((shared_op.model.clone(), shared_op.record_id.to_string()))
+				.or_default()
+				.push(op);
+		}
+	}
+
+	let mut compaction = Compaction::default();
+	for mut ops in records.into_values() {
+		ops.sort_by_key(|op| (op.timestamp, op.id));
+		compact_record(ops, &mut compaction);
+	}
+
+	compaction
+}
+
+/// Goes through the operations of a record from the latest, keeping track of what they overwrite
+fn compact_record(ops: Vec<CRDTOperation>, compaction: &mut Compaction) {
+	let mut overwritten_fields = HashSet::new();
+	let mut deleted = false;
+
+	for mut op in ops.into_iter().rev() {
+		let CRDTOperationType::Shared(shared_op) = &mut op.typ else {
+			continue;
+		};
+
+		if deleted {
+			compaction.removed.push(op.id);
+			continue;
+		}
+
+		match &mut shared_op.data {
+			SharedOperationData::Delete => deleted = true,
+			SharedOperationData::Update { field, .. } => {
+				if !overwritten_fields.insert(field.clone()) {
+					compaction.removed.push(op.id);
+				}
+			}
+			// The creation itself is kept even when all its values are overwritten, as it's what
+			// makes the record exist
+			SharedOperationData::Create(values) => {
+				let len = values.len();
+				values.retain(|field, _| !overwritten_fields.contains(field));
+				overwritten_fields.extend(values.keys().cloned());
+
+				if values.len() != len {
+					compaction.rewritten.push(op);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::{json, Map, Value};
+	use uhlc::NTP64;
+
+	use super::*;
+	use crate::SharedOperation;
+
+	fn op(timestamp: u64, record: &str, data: SharedOperationData) -> CRDTOperation {
+		CRDTOperation {
+			node: Uuid::nil(),
+			timestamp: NTP64(timestamp),
+			id: Uuid::new_v4(),
+			typ: CRDTOperationType::Shared(SharedOperation {
+				record_id: json!({ "pub_id": record }),
+				model: "Tag".to_string(),
+				data,
+			}),
+		}
+	}
+
+	fn create(timestamp: u64, record: &str, values: Value) -> CRDTOperation {
+		let Value::Object(values) = values else {
+			panic!("values must be an object");
+		};
+
+		op(timestamp, record, SharedOperationData::Create(values))
+	}
+
+	fn update(timestamp: u64, record: &str, field: &str, value: Value) -> CRDTOperation {
+		op(
+			timestamp,
+			record,
+			SharedOperationData::Update {
+				field: field.to_string(),
+				value,
+			},
+		)
+	}
+
+	/// Replays a log the way peers ingest it, into the state of each record
+	fn replay(ops: &[CRDTOperation]) -> HashMap<String, Option<Map<String, Value>>> {
+		let mut ops = ops.iter().collect::<Vec<_>>();
+		ops.sort_by_key(|op| (op.timestamp, op.id));
+
+		let mut state = HashMap::new();
+		for op in ops {
+			let CRDTOperationType::Shared(shared_op) = &op.typ else {
+				continue;
+			};
+			let record = state.entry(shared_op.record_id.to_string()).or_insert(None);
+
+			match &shared_op.data {
+				SharedOperationData::Create(values) => {
+					record.get_or_insert_with(Map::new).extend(values.clone());
+				}
+				SharedOperationData::Update { field, value } => {
+					record
+						.get_or_insert_with(Map::new)
+						.insert(field.clone(), value.clone());
+				}
+				SharedOperationData::Delete => *record = None,
+			}
+		}
+
+		state
+	}
+
+	fn apply(ops: &[CRDTOperation], compaction: Compaction) -> Vec<CRDTOperation> {
+		let mut rewritten = compaction
+			.rewritten
+			.into_iter()
+			.map(|op| (op.id, op))
+			.collect::<HashMap<_, _>>();
+
+		ops.iter()
+			.filter(|op| !compaction.removed.contains(&op.id))
+			.map(|op| rewritten.remove(&op.id).unwrap_or_else(|| op.clone()))
+			.collect()
+	}
+
+	#[test]
+	fn test_compaction_keeps_state() {
+		let ops = vec![
+			create(1, "a", json!({ "name": "Work", "color": "red" })),
+			update(2, "a", "name", json!("Job")),
+			update(3, "a", "name", json!("Office")),
+			update(4, "a", "color", json!("blue")),
+			create(1, "b", json!({ "name": "Old" })),
+			update(2, "b", "name", json!("Older")),
+			op(3, "b", SharedOperationData::Delete),
+			create(1, "c", json!({ "name": "Home" })),
+		];
+
+		let compaction = compact(ops.clone());
+		let compacted = apply(&ops, compaction);
+
+		assert_eq!(replay(&compacted), replay(&ops));
+		// The creation and last update of each field of 'a', the deletion of 'b' and 'c'
+		assert_eq!(compacted.len(), 5);
+	}
+
+	#[test]
+	fn test_compaction_keeps_newer_operations_winning() {
+		let old = vec![
+			create(1, "a", json!({ "name": "Work", "color": "red" })),
+			update(2, "a", "color", json!("green")),
+			update(3, "a", "color", json!("blue")),
+		];
+		// An operation younger than the compacted part of the log, from a peer syncing late
+		let late = update(2, "a", "name", json!("Job"));
+
+		let compacted = apply(&old, compact(old.clone()));
+
+		let mut full = old.clone();
+		full.push(late.clone());
+		let mut compacted_then_late = compacted;
+		compacted_then_late.push(late);
+
+		assert_eq!(replay(&compacted_then_late), replay(&full));
+	}
+}
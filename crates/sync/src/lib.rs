@@ -1,3 +1,4 @@
+pub mod compaction;
 mod crdt;
 
 pub use crdt::*;